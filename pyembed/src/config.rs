@@ -5,8 +5,10 @@
 //! Data structures for configuring a Python interpreter.
 
 use {
+    crate::payload_fetching::ExternalPayloadsConfig,
     libc::c_ulong,
     python3_sys as pyffi,
+    python_packed_resources::data::ResourceSet,
     std::ffi::{CString, OsString},
     std::path::PathBuf,
 };
@@ -51,6 +53,50 @@ pub struct ExtensionModule {
     pub init_func: unsafe extern "C" fn() -> *mut pyffi::PyObject,
 }
 
+/// Isolation settings for subinterpreters created by an application.
+///
+/// Fields mirror CPython's `PyInterpreterConfig`. The main interpreter
+/// doesn't use these settings: they are carried along so applications
+/// creating subinterpreters can apply them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SubinterpreterConfig {
+    /// Whether the subinterpreter shares the main interpreter's object allocator.
+    pub use_main_obmalloc: bool,
+
+    /// Whether `os.fork()` is allowed.
+    pub allow_fork: bool,
+
+    /// Whether `os.execv()` and similar are allowed.
+    pub allow_exec: bool,
+
+    /// Whether threads can be created.
+    pub allow_threads: bool,
+
+    /// Whether daemon threads can be created.
+    pub allow_daemon_threads: bool,
+
+    /// Whether importing extension modules not supporting subinterpreters fails.
+    pub check_multi_interp_extensions: bool,
+
+    /// Whether the subinterpreter has its own GIL.
+    pub own_gil: bool,
+}
+
+impl Default for SubinterpreterConfig {
+    /// Create an instance with the most isolated settings.
+    fn default() -> Self {
+        Self {
+            use_main_obmalloc: false,
+            allow_fork: false,
+            allow_exec: false,
+            allow_threads: true,
+            allow_daemon_threads: false,
+            check_multi_interp_extensions: true,
+            own_gil: true,
+        }
+    }
+}
+
 /// Holds the configuration of an embedded Python interpreter.
 ///
 /// Instances of this struct can be used to construct Python interpreters.
@@ -130,6 +176,11 @@ pub struct PythonConfig<'a> {
     /// hash seed.
     pub use_hash_seed: bool,
 
+    /// Seed for hashes of `str` and `bytes` objects.
+    ///
+    /// `None` uses a random seed. A seed of 0 disables hash randomization.
+    pub hash_seed: Option<u32>,
+
     /// Controls the level of the verbose mode for the interpreter.
     pub verbose: i32,
 
@@ -143,6 +194,18 @@ pub struct PythonConfig<'a> {
     /// meta path importer during interpreter initialization.
     pub packed_resources: &'a [u8],
 
+    /// Resource set of `packed_resources` to load.
+    ///
+    /// Generated configs of binaries with a recovery resource set obtain
+    /// this from `select_resource_set()` at startup.
+    pub resource_set: ResourceSet,
+
+    /// How payloads stored outside of `packed_resources` are obtained.
+    ///
+    /// Only needed if packed resources were written with a payload sink
+    /// storing payloads elsewhere.
+    pub external_payloads: Option<ExternalPayloadsConfig>,
+
     /// Extra extension modules to make available to the interpreter.
     ///
     /// The values will effectively be passed to ``PyImport_ExtendInitTab()``.
@@ -166,6 +229,14 @@ pub struct PythonConfig<'a> {
     /// of setting this attribute.
     pub sys_meipass: bool,
 
+    /// Directory, relative to the executable, reported as site-packages.
+    ///
+    /// If set, `sysconfig.get_paths()`, `sysconfig.get_path()` and
+    /// `site.getsitepackages()` are patched at initialization to report
+    /// this directory as the `purelib` and `platlib` paths. This helps
+    /// packages that locate their files relative to site-packages.
+    pub site_packages_shim: Option<String>,
+
     /// Which memory allocator to use for the raw domain.
     pub raw_allocator: PythonRawAllocator,
 
@@ -180,6 +251,32 @@ pub struct PythonConfig<'a> {
     /// loaded in ``sys.modules``.
     pub write_modules_directory_env: Option<String>,
 
+    /// Path template of a file to write import telemetry to on shutdown.
+    ///
+    /// Telemetry is only recorded if packed resources were built with import
+    /// telemetry enabled. The file counts imports of each module and reads
+    /// of each package resource. ``{pid}`` expands to the process ID.
+    pub import_telemetry_path: Option<String>,
+
+    /// Modules to import after the interpreter is initialized.
+    ///
+    /// Modules are imported in order before the run target is evaluated. If
+    /// any import fails, interpreter initialization fails.
+    pub preload_modules: Vec<String>,
+
+    /// Settings for subinterpreters created by the application.
+    ///
+    /// `Some` declares the application intends to use subinterpreters.
+    pub subinterpreter_config: Option<SubinterpreterConfig>,
+
+    /// Directory to extract embedded shared libraries to.
+    ///
+    /// `$ORIGIN` expands to the directory of the current executable and
+    /// `$CACHE_DIR` to the per-user cache directory. Shared libraries marked
+    /// for extraction are written to this directory and loaded before the
+    /// interpreter is initialized. `None` disables extraction.
+    pub shared_library_extraction_dir: Option<String>,
+
     /// Defines what code to run by default.
     ///
     pub run: PythonRunMode,
@@ -210,15 +307,23 @@ impl<'a> Default for PythonConfig<'a> {
             parser_debug: false,
             quiet: false,
             use_hash_seed: false,
+            hash_seed: None,
             verbose: 0,
             packed_resources: &[],
+            resource_set: ResourceSet::Main,
+            external_payloads: None,
             extra_extension_modules: vec![],
             argvb: false,
             sys_frozen: false,
             sys_meipass: false,
+            site_packages_shim: None,
             raw_allocator: PythonRawAllocator::default(),
             terminfo_resolution: TerminfoResolution::Dynamic,
             write_modules_directory_env: None,
+            import_telemetry_path: None,
+            preload_modules: vec![],
+            subinterpreter_config: None,
+            shared_library_extraction_dir: None,
             run: PythonRunMode::None,
         }
     }
@@ -589,6 +694,12 @@ pub struct OxidizedPythonInterpreterConfig<'a> {
     /// meta path importer during interpreter initialization.
     pub packed_resources: Option<&'a [u8]>,
 
+    /// Resource set of `packed_resources` to load.
+    pub resource_set: ResourceSet,
+
+    /// How payloads stored outside of `packed_resources` are obtained.
+    pub external_payloads: Option<ExternalPayloadsConfig>,
+
     /// Extra extension modules to make available to the interpreter.
     ///
     /// The values will effectively be passed to ``PyImport_ExtendInitTab()``.
@@ -612,6 +723,14 @@ pub struct OxidizedPythonInterpreterConfig<'a> {
     /// of setting this attribute.
     pub sys_meipass: bool,
 
+    /// Directory, relative to the executable, reported as site-packages.
+    ///
+    /// If set, `sysconfig.get_paths()`, `sysconfig.get_path()` and
+    /// `site.getsitepackages()` are patched at initialization to report
+    /// this directory as the `purelib` and `platlib` paths. This helps
+    /// packages that locate their files relative to site-packages.
+    pub site_packages_shim: Option<String>,
+
     /// How to resolve the `terminfo` database.
     pub terminfo_resolution: TerminfoResolution,
 
@@ -623,6 +742,32 @@ pub struct OxidizedPythonInterpreterConfig<'a> {
     /// loaded in ``sys.modules``.
     pub write_modules_directory_env: Option<String>,
 
+    /// Path template of a file to write import telemetry to on shutdown.
+    ///
+    /// Telemetry is only recorded if packed resources were built with import
+    /// telemetry enabled. The file counts imports of each module and reads
+    /// of each package resource. ``{pid}`` expands to the process ID.
+    pub import_telemetry_path: Option<String>,
+
+    /// Modules to import after the interpreter is initialized.
+    ///
+    /// Modules are imported in order before the run target is evaluated. If
+    /// any import fails, interpreter initialization fails.
+    pub preload_modules: Vec<String>,
+
+    /// Settings for subinterpreters created by the application.
+    ///
+    /// `Some` declares the application intends to use subinterpreters.
+    pub subinterpreter_config: Option<SubinterpreterConfig>,
+
+    /// Directory to extract embedded shared libraries to.
+    ///
+    /// `$ORIGIN` expands to the directory of the current executable and
+    /// `$CACHE_DIR` to the per-user cache directory. Shared libraries marked
+    /// for extraction are written to this directory and loaded before the
+    /// interpreter is initialized. `None` disables extraction.
+    pub shared_library_extraction_dir: Option<String>,
+
     /// Defines what code to run by default.
     ///
     pub run: PythonRunMode,
@@ -639,12 +784,19 @@ impl<'a> Default for OxidizedPythonInterpreterConfig<'a> {
             oxidized_importer: false,
             filesystem_importer: true,
             packed_resources: None,
+            resource_set: ResourceSet::Main,
+            external_payloads: None,
             extra_extension_modules: None,
             argvb: false,
             sys_frozen: false,
             sys_meipass: false,
+            site_packages_shim: None,
             terminfo_resolution: TerminfoResolution::Dynamic,
             write_modules_directory_env: None,
+            import_telemetry_path: None,
+            preload_modules: vec![],
+            subinterpreter_config: None,
+            shared_library_extraction_dir: None,
             run: PythonRunMode::Repl,
         }
     }
@@ -690,18 +842,26 @@ impl<'a> From<PythonConfig<'a>> for OxidizedPythonInterpreterConfig<'a> {
                 parser_debug: Some(config.parser_debug),
                 quiet: Some(config.quiet),
                 verbose: Some(config.verbose != 0),
+                hash_seed: config.hash_seed.map(c_ulong::from),
                 ..PythonInterpreterConfig::default()
             },
             raw_allocator: Some(config.raw_allocator),
             oxidized_importer: config.use_custom_importlib,
             filesystem_importer: config.filesystem_importer,
             packed_resources: Some(config.packed_resources),
+            resource_set: config.resource_set,
+            external_payloads: config.external_payloads,
             extra_extension_modules: Some(config.extra_extension_modules),
             argvb: config.argvb,
             sys_frozen: config.sys_frozen,
             sys_meipass: config.sys_meipass,
+            site_packages_shim: config.site_packages_shim,
             terminfo_resolution: config.terminfo_resolution,
             write_modules_directory_env: config.write_modules_directory_env,
+            import_telemetry_path: config.import_telemetry_path,
+            preload_modules: config.preload_modules,
+            subinterpreter_config: config.subinterpreter_config,
+            shared_library_extraction_dir: config.shared_library_extraction_dir,
             run: config.run,
         }
     }
@@ -445,6 +445,20 @@ impl ImporterState {
 
         unsafe { &mut *(ptr as *mut PythonResourcesState<u8>) }
     }
+
+    /// Add the resource of a name to the `PythonResourcesState` if not yet indexed.
+    pub fn index_resource(&self, py: Python, name: &str) -> PyResult<()> {
+        self.get_resources_state_mut()
+            .index_resource(name)
+            .map_err(|e| PyErr::new::<ImportError, _>(py, (e, name)))
+    }
+
+    /// Add all resources to the `PythonResourcesState` if not yet indexed.
+    pub fn index_all_resources(&self, py: Python) -> PyResult<()> {
+        self.get_resources_state_mut()
+            .index_all_resources()
+            .map_err(|e| PyErr::new::<ImportError, _>(py, e))
+    }
 }
 
 impl Drop for ImporterState {
@@ -584,6 +598,7 @@ impl OxidizedFinder {
     ) -> PyResult<PyObject> {
         let state = self.state(py);
         let key = fullname.to_string(py)?;
+        state.index_resource(py, &key)?;
 
         let module = match state
             .get_resources_state()
@@ -644,6 +659,7 @@ impl OxidizedFinder {
         let state = self.state(py);
         let name = spec.getattr(py, "name")?;
         let key = name.extract::<String>(py)?;
+        state.index_resource(py, &key)?;
 
         let entry = match state.get_resources_state().resources.get(&*key) {
             Some(entry) => entry,
@@ -691,6 +707,7 @@ impl OxidizedFinder {
         let state = self.state(py);
         let name = module.getattr(py, "__name__")?;
         let key = name.extract::<String>(py)?;
+        state.index_resource(py, &key)?;
 
         let mut entry = match state
             .get_resources_state()
@@ -704,6 +721,10 @@ impl OxidizedFinder {
             }
         };
 
+        if let Some(telemetry) = &state.get_resources_state().import_telemetry {
+            telemetry.record_module(&key);
+        }
+
         if let Some(bytecode) = entry.resolve_bytecode(
             py,
             state.optimize_level,
@@ -747,7 +768,13 @@ impl OxidizedFinder {
     /// found. The path is expected to be constructed using a module’s __file__
     /// attribute or an item from a package’s __path__.
     fn get_data_impl(&self, py: Python, path: &PyString) -> PyResult<PyObject> {
-        self.state(py)
+        let state = self.state(py);
+
+        // Which package the path belongs to is only known by looking at
+        // all packages.
+        state.index_all_resources(py)?;
+
+        state
             .get_resources_state()
             .resolve_resource_data_from_path(py, path)
     }
@@ -758,6 +785,7 @@ impl OxidizedFinder {
     fn get_code_impl(&self, py: Python, fullname: &PyString) -> PyResult<PyObject> {
         let state = self.state(py);
         let key = fullname.to_string(py)?;
+        state.index_resource(py, &key)?;
 
         let mut module = match state
             .get_resources_state()
@@ -786,6 +814,7 @@ impl OxidizedFinder {
     fn get_source_impl(&self, py: Python, fullname: &PyString) -> PyResult<PyObject> {
         let state = self.state(py);
         let key = fullname.to_string(py)?;
+        state.index_resource(py, &key)?;
 
         let module = match state
             .get_resources_state()
@@ -816,6 +845,7 @@ impl OxidizedFinder {
     fn get_filename_impl(&self, py: Python, fullname: &PyString) -> PyResult<PyObject> {
         let state = self.state(py);
         let key = fullname.to_string(py)?;
+        state.index_resource(py, &key)?;
 
         let make_error = |msg: &str| -> PyErr { PyErr::new::<ImportError, _>(py, (msg, &key)) };
 
@@ -836,6 +866,7 @@ impl OxidizedFinder {
     fn get_resource_reader_impl(&self, py: Python, fullname: &PyString) -> PyResult<PyObject> {
         let state = self.state(py);
         let key = fullname.to_string(py)?;
+        state.index_resource(py, &key)?;
 
         let entry = match state
             .get_resources_state()
@@ -886,7 +917,7 @@ impl OxidizedFinder {
             (None, None)
         };
 
-        super::package_metadata::find_distributions(py, state.clone(), path, name)
+        super::package_metadata::find_distributions(py, state.clone(), name, path)
     }
 }
 
@@ -895,6 +926,7 @@ impl OxidizedFinder {
     /// def iter_modules(prefix="")
     fn iter_modules_impl(&self, py: Python, prefix: Option<PyString>) -> PyResult<PyObject> {
         let state: &ImporterState = self.state(py);
+        state.index_all_resources(py)?;
         let resources_state = state.get_resources_state();
 
         let prefix = if let Some(prefix) = prefix {
@@ -1031,6 +1063,7 @@ fn oxidized_finder_new(
 
 impl OxidizedFinder {
     fn indexed_resources_impl(&self, py: Python) -> PyResult<PyObject> {
+        self.state(py).index_all_resources(py)?;
         let resources_state: &PythonResourcesState<u8> = self.state(py).get_resources_state();
 
         let mut resources = resources_state
@@ -1082,6 +1115,7 @@ impl OxidizedFinder {
         ignore_builtin: bool,
         ignore_frozen: bool,
     ) -> PyResult<PyObject> {
+        self.state(py).index_all_resources(py)?;
         let resources_state: &PythonResourcesState<u8> = self.state(py).get_resources_state();
 
         let data = resources_state
@@ -1123,12 +1157,14 @@ impl OxidizedResourceReader {
     fn open_resource_impl(&self, py: Python, resource: &PyString) -> PyResult<PyObject> {
         let state = self.state(py);
         let package = self.package(py);
+        let resource = resource.to_string(py)?;
+        let resources_state = state.get_resources_state();
+
+        if let Some(file) = resources_state.get_package_resource_file(py, &package, &resource)? {
+            if let Some(telemetry) = &resources_state.import_telemetry {
+                telemetry.record_resource(&package, &resource);
+            }
 
-        if let Some(file) = state.get_resources_state().get_package_resource_file(
-            py,
-            &package,
-            &resource.to_string(py)?,
-        )? {
             Ok(file)
         } else {
             Err(PyErr::new::<FileNotFoundError, _>(py, "resource not found"))
@@ -1250,6 +1286,7 @@ impl PyOxidizerTraversable {
     fn is_dir_impl(&self, py: Python) -> PyResult<PyObject> {
         let state = self.state(py);
         let path = self.path(py);
+        state.index_resource(py, path)?;
 
         // We are a directory if the current path is a known package.
         // TODO We may need to expand this definition in the future to cover
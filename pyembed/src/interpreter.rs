@@ -5,13 +5,21 @@
 //! Manage an embedded Python interpreter.
 
 use {
-    super::config::{MemoryAllocatorBackend, OxidizedPythonInterpreterConfig, TerminfoResolution},
+    super::config::{
+        MemoryAllocatorBackend, OxidizedPythonInterpreterConfig, SubinterpreterConfig,
+        TerminfoResolution,
+    },
     super::conversion::{osstr_to_pyobject, osstring_to_bytes},
     super::importer::{
         initialize_importer, PyInit_oxidized_importer, OXIDIZED_IMPORTER_NAME,
         OXIDIZED_IMPORTER_NAME_STR,
     },
+    super::library_extraction::{
+        extract_packed_shared_libraries, extract_shared_libraries, load_library,
+        resolve_extraction_dir,
+    },
     super::osutils::resolve_terminfo_dirs,
+    super::payload_fetching::resolve_external_payloads,
     super::pyalloc::{make_raw_rust_memory_allocator, RawAllocator},
     super::python_resources::PythonResourcesState,
     cpython::{
@@ -34,6 +42,42 @@ use {
 use super::pyalloc::make_raw_jemalloc_allocator;
 use python3_sys::PyMemAllocatorEx;
 
+/// Python code reporting `shim_path` as the site-packages directory.
+///
+/// Modules that aren't packaged are left alone.
+const SITE_PACKAGES_SHIM: &str = r#"
+try:
+    import sysconfig
+except ImportError:
+    sysconfig = None
+
+try:
+    import site
+except ImportError:
+    site = None
+
+if sysconfig is not None:
+    _get_paths = sysconfig.get_paths
+    _get_path = sysconfig.get_path
+
+    def get_paths(*args, **kwargs):
+        paths = _get_paths(*args, **kwargs)
+        paths["purelib"] = shim_path
+        paths["platlib"] = shim_path
+        return paths
+
+    def get_path(name, *args, **kwargs):
+        if name in ("purelib", "platlib"):
+            return shim_path
+        return _get_path(name, *args, **kwargs)
+
+    sysconfig.get_paths = get_paths
+    sysconfig.get_path = get_path
+
+if site is not None:
+    site.getsitepackages = lambda prefixes=None: [shim_path]
+"#;
+
 lazy_static! {
     static ref GLOBAL_INTERPRETER_GUARD: std::sync::Mutex<()> = std::sync::Mutex::new(());
 }
@@ -279,6 +323,22 @@ impl<'python, 'interpreter, 'resources> MainPythonInterpreter<'python, 'interpre
             .to_path_buf();
         let origin_string = origin.display().to_string();
 
+        // Shared libraries that can't be loaded from memory are extracted
+        // and loaded before anything attempts to resolve them. The oxidized
+        // importer extracts them from the resources it parses anyway.
+        if !self.config.oxidized_importer {
+            if let (Some(resources), Some(template)) = (
+                self.config.packed_resources,
+                &self.config.shared_library_extraction_dir,
+            ) {
+                load_libraries(extract_packed_shared_libraries(
+                    resources,
+                    self.config.resource_set,
+                    || resolve_extraction_dir(template, &origin),
+                ))?;
+            }
+        }
+
         set_pyimport_inittab(&self.config);
 
         // Pre-configure Python.
@@ -358,9 +418,41 @@ impl<'python, 'interpreter, 'resources> MainPythonInterpreter<'python, 'interpre
 
             if let Some(ref mut resources_state) = self.resources_state {
                 resources_state
-                    .load(self.config.packed_resources)
+                    .load_resource_set(self.config.packed_resources, self.config.resource_set)
                     .map_err(|err| NewInterpreterError::Simple(err))?;
 
+                // Resources with a lookup table are otherwise only parsed when
+                // imported. Payloads and libraries are needed up front.
+                if self.config.external_payloads.is_some()
+                    || self.config.shared_library_extraction_dir.is_some()
+                {
+                    resources_state
+                        .index_all_resources()
+                        .map_err(|err| NewInterpreterError::Simple(err))?;
+                }
+
+                resolve_external_payloads(
+                    &mut resources_state.resources,
+                    self.config.external_payloads.as_ref(),
+                    &origin,
+                )
+                .map_err(NewInterpreterError::Dynamic)?;
+
+                if let Some(template) = &self.config.shared_library_extraction_dir {
+                    load_libraries(extract_shared_libraries(
+                        resources_state.resources.values(),
+                        || resolve_extraction_dir(template, &origin),
+                    ))?;
+                }
+
+                // Without a path there is nowhere to write counters to, so
+                // don't count.
+                if self.config.import_telemetry_path.is_some() {
+                    resources_state
+                        .enable_import_telemetry()
+                        .map_err(|err| NewInterpreterError::Simple(err))?;
+                }
+
                 let oxidized_importer = py.import(OXIDIZED_IMPORTER_NAME_STR).map_err(|err| {
                     NewInterpreterError::new_from_pyerr(
                         py,
@@ -502,9 +594,43 @@ impl<'python, 'interpreter, 'resources> MainPythonInterpreter<'python, 'interpre
             }
         }
 
+        if let Some(shim) = &self.config.site_packages_shim {
+            let globals = PyDict::new(py);
+            let res = py
+                .import("builtins")
+                .and_then(|builtins| globals.set_item(py, "__builtins__", builtins))
+                .and_then(|_| {
+                    globals.set_item(py, "shim_path", origin.join(shim).display().to_string())
+                })
+                .and_then(|_| py.run(SITE_PACKAGES_SHIM, Some(&globals), None));
+
+            if let Err(err) = res {
+                return Err(NewInterpreterError::new_from_pyerr(
+                    py,
+                    err,
+                    "installing site-packages shim",
+                ));
+            }
+        }
+
+        for module in &self.config.preload_modules {
+            py.import(module).map_err(|err| {
+                NewInterpreterError::new_from_pyerr(
+                    py,
+                    err,
+                    &format!("preloading module {}", module),
+                )
+            })?;
+        }
+
         Ok(())
     }
 
+    /// Settings for subinterpreters the application creates, if any.
+    pub fn subinterpreter_config(&self) -> Option<&SubinterpreterConfig> {
+        self.config.subinterpreter_config.as_ref()
+    }
+
     /// Ensure the Python GIL is released.
     pub fn release_gil(&mut self) {
         if self.py.is_some() {
@@ -569,6 +695,10 @@ impl<'python, 'interpreter, 'resources> MainPythonInterpreter<'python, 'interpre
         if self.config.uses_py_runmain() {
             let res = unsafe { pyffi::Py_RunMain() };
 
+            // Py_RunMain() finalized the interpreter, so no more imports
+            // can be recorded.
+            self.write_import_telemetry();
+
             // Py_RunMain() finalizes the interpreter. So drop our refs and state.
             self.interpreter_guard = None;
             self.interpreter_state = InterpreterState::Finalized;
@@ -581,11 +711,57 @@ impl<'python, 'interpreter, 'resources> MainPythonInterpreter<'python, 'interpre
             0
         }
     }
+
+    /// Write recorded import telemetry to the configured path.
+    ///
+    /// Must only be called once the interpreter is finalized, so imports
+    /// performed during finalization are recorded. The telemetry is taken
+    /// out of the resources state, so it is written at most once.
+    ///
+    /// Does nothing unless import telemetry is enabled. Errors are printed
+    /// since they shouldn't change the outcome of running the application.
+    fn write_import_telemetry(&mut self) {
+        let telemetry = match self
+            .resources_state
+            .as_mut()
+            .and_then(|state| state.import_telemetry.take())
+        {
+            Some(telemetry) => telemetry,
+            None => return,
+        };
+        let template = match &self.config.import_telemetry_path {
+            Some(template) => template,
+            None => return,
+        };
+
+        let path = PathBuf::from(template.replace("{pid}", &std::process::id().to_string()));
+
+        if let Some(parent) = path.parent() {
+            let _ = fs::create_dir_all(parent);
+        }
+
+        if let Err(err) = fs::write(&path, telemetry.render()) {
+            eprintln!(
+                "error writing import telemetry to {}: {}",
+                path.display(),
+                err
+            );
+        }
+    }
 }
 
 static mut ORIGINAL_BUILTIN_EXTENSIONS: Option<Vec<pyffi::_inittab>> = None;
 static mut REPLACED_BUILTIN_EXTENSIONS: Option<Box<Vec<pyffi::_inittab>>> = None;
 
+/// Load extracted shared libraries into the process.
+fn load_libraries(paths: Result<Vec<PathBuf>, String>) -> Result<(), NewInterpreterError> {
+    for path in paths.map_err(NewInterpreterError::Dynamic)? {
+        load_library(&path).map_err(NewInterpreterError::Dynamic)?;
+    }
+
+    Ok(())
+}
+
 /// Set PyImport_Inittab from config options.
 ///
 /// CPython has buggy code around memory handling for PyImport_Inittab.
@@ -711,5 +887,7 @@ impl<'python, 'interpreter, 'resources> Drop
         }
 
         let _ = unsafe { pyffi::Py_FinalizeEx() };
+
+        self.write_import_telemetry();
     }
 }
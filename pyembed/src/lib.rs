@@ -92,12 +92,17 @@ mod importer;
 mod interpreter;
 #[cfg(not(library_mode = "extension"))]
 mod interpreter_config;
+#[cfg(not(library_mode = "extension"))]
+mod library_extraction;
 #[cfg(windows)]
 mod memory_dll;
 #[cfg(not(library_mode = "extension"))]
 mod osutils;
 #[allow(clippy::transmute_ptr_to_ptr, clippy::zero_ptr)]
 mod package_metadata;
+mod payload_decompression;
+#[cfg(not(library_mode = "extension"))]
+mod payload_fetching;
 #[cfg(not(library_mode = "extension"))]
 mod pyalloc;
 #[cfg(not(library_mode = "extension"))]
@@ -110,6 +115,8 @@ mod python_resource_types;
 mod python_resources;
 mod resource_scanning;
 #[cfg(not(library_mode = "extension"))]
+mod resource_set;
+#[cfg(not(library_mode = "extension"))]
 pub mod technotes;
 #[cfg(test)]
 mod test;
@@ -119,7 +126,15 @@ mod test;
 pub use crate::config::{
     Allocator, CheckHashPYCsMode, CoerceCLocale, ExtensionModule, OptimizationLevel,
     OxidizedPythonInterpreterConfig, PythonConfig, PythonInterpreterConfig,
-    PythonInterpreterProfile, PythonRawAllocator, PythonRunMode, TerminfoResolution,
+    PythonInterpreterProfile, PythonRawAllocator, PythonRunMode, SubinterpreterConfig,
+    TerminfoResolution,
+};
+
+#[cfg(not(library_mode = "extension"))]
+pub use {
+    crate::payload_fetching::{ExternalPayloadsConfig, PayloadFetcher},
+    crate::resource_set::{select_resource_set, RECOVERY_ENV},
+    python_packed_resources::data::ResourceSet,
 };
 
 #[cfg(not(library_mode = "extension"))]
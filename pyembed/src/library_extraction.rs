@@ -0,0 +1,265 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Extraction of embedded shared libraries to the filesystem.
+
+Some shared libraries can't be loaded from memory. e.g. libraries required
+by extension modules are resolved by the operating system's dynamic loader,
+which only knows about files. Such libraries can be embedded in packed
+resources and marked for extraction. Before the importer is initialized,
+we write them to a cache directory and load them into the process so
+that subsequent loads of dependent binaries resolve against them.
+
+Each library is written to a directory named after the SHA-256 of its
+content. So repeated runs of the same binary reuse previously extracted
+files and different binaries don't clobber each other's libraries. The
+content of a previously extracted file is verified before it is reused,
+as anything with write access to the cache directory could have changed
+it.
+
+The extraction directory is only resolved if there are libraries to
+extract, so binaries without any don't depend on e.g. `HOME` being set.
+*/
+
+use {
+    crate::payload_decompression::decompress_payloads,
+    python_packed_resources::data::{
+        Resource, ResourceFlavor, ResourceSet, EXTERNAL_PAYLOAD_DIGEST_LENGTH,
+    },
+    python_packed_resources::sink::{digest_hex, payload_digest},
+    std::collections::{BTreeMap, BTreeSet},
+    std::env,
+    std::fs,
+    std::path::{Path, PathBuf},
+};
+
+/// Obtain the per-user cache directory.
+fn cache_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else if cfg!(target_os = "macos") {
+        env::var_os("HOME").map(|home| PathBuf::from(home).join("Library").join("Caches"))
+    } else {
+        env::var_os("XDG_CACHE_HOME")
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+    }
+}
+
+/// Resolve the extraction directory from a path template.
+///
+/// `$ORIGIN` is replaced by `origin` and `$CACHE_DIR` by the per-user
+/// cache directory.
+pub(crate) fn resolve_extraction_dir(template: &str, origin: &Path) -> Result<PathBuf, String> {
+    let mut path = template.replace("$ORIGIN", &origin.display().to_string());
+
+    if path.contains("$CACHE_DIR") {
+        let cache = cache_dir()
+            .ok_or_else(|| "unable to resolve cache directory for $CACHE_DIR".to_string())?;
+        path = path.replace("$CACHE_DIR", &cache.display().to_string());
+    }
+
+    Ok(PathBuf::from(path))
+}
+
+/// Obtain the filename an extracted library is written to.
+///
+/// Names without an extension receive the platform's shared library suffix,
+/// as some loaders refuse or rewrite names without one.
+fn library_filename(name: &str) -> String {
+    if Path::new(name).extension().is_some() {
+        name.to_string()
+    } else {
+        format!("{}{}", name, env::consts::DLL_SUFFIX)
+    }
+}
+
+/// Whether a file exists and has content with the given SHA-256.
+fn has_digest(path: &Path, digest: &[u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH]) -> bool {
+    match fs::read(path) {
+        Ok(data) => payload_digest(&data) == *digest,
+        Err(_) => false,
+    }
+}
+
+/// Write a library to `dir`, reusing a previous extraction if its content matches.
+fn extract_library(dir: &Path, name: &str, data: &[u8]) -> Result<PathBuf, String> {
+    let digest = payload_digest(data);
+
+    let dir = dir.join(digest_hex(&digest));
+    let path = dir.join(library_filename(name));
+
+    if has_digest(&path, &digest) {
+        return Ok(path);
+    }
+
+    fs::create_dir_all(&dir).map_err(|e| format!("creating directory {}: {}", dir.display(), e))?;
+
+    // Write to a temporary file and rename so concurrent processes never
+    // observe a partially written library. The rename also replaces
+    // files having unexpected content.
+    let temp_path = dir.join(format!(
+        "{}.{}.tmp",
+        library_filename(name),
+        std::process::id()
+    ));
+    fs::write(&temp_path, data).map_err(|e| format!("writing {}: {}", temp_path.display(), e))?;
+
+    if let Err(e) = fs::rename(&temp_path, &path) {
+        let _ = fs::remove_file(&temp_path);
+
+        // Another process may have won the race and the destination may
+        // be in use. That's only fine if it has the content we wanted.
+        if !has_digest(&path, &digest) {
+            return Err(format!("renaming to {}: {}", path.display(), e));
+        }
+    }
+
+    Ok(path)
+}
+
+/// Extract shared libraries marked for extraction.
+///
+/// `dir` resolves the directory to extract to. It is only called if any
+/// library needs extracting.
+///
+/// Returns paths of extracted libraries ordered such that a library's
+/// dependencies come before it.
+pub(crate) fn extract_shared_libraries<'a: 'b, 'b>(
+    resources: impl Iterator<Item = &'b Resource<'a, u8>>,
+    dir: impl FnOnce() -> Result<PathBuf, String>,
+) -> Result<Vec<PathBuf>, String> {
+    let libraries = resources
+        .filter(|resource| {
+            resource.flavor == ResourceFlavor::SharedLibrary
+                && resource.extract_shared_library
+                && resource.in_memory_shared_library.is_some()
+        })
+        .map(|resource| (resource.name.as_ref(), resource))
+        .collect::<BTreeMap<_, _>>();
+
+    if libraries.is_empty() {
+        return Ok(vec![]);
+    }
+
+    fn visit<'a, 'b>(
+        name: &str,
+        libraries: &BTreeMap<&str, &'b Resource<'a, u8>>,
+        seen: &mut BTreeSet<String>,
+        order: &mut Vec<&'b Resource<'a, u8>>,
+    ) {
+        if !seen.insert(name.to_string()) {
+            return;
+        }
+
+        if let Some(resource) = libraries.get(name) {
+            if let Some(names) = &resource.shared_library_dependency_names {
+                for dependency in names {
+                    visit(dependency, libraries, seen, order);
+                }
+            }
+
+            order.push(resource);
+        }
+    }
+
+    let mut seen = BTreeSet::new();
+    let mut order = Vec::new();
+    for name in libraries.keys() {
+        visit(name, &libraries, &mut seen, &mut order);
+    }
+
+    let dir = dir()?;
+
+    order
+        .into_iter()
+        .map(|resource| {
+            extract_library(
+                &dir,
+                &resource.name,
+                resource.in_memory_shared_library.as_ref().unwrap(),
+            )
+        })
+        .collect()
+}
+
+/// Extract shared libraries marked for extraction in a resource set of packed resources.
+///
+/// Used when packed resources aren't otherwise parsed. e.g. when the
+/// oxidized importer is disabled.
+pub(crate) fn extract_packed_shared_libraries(
+    packed_resources: &[u8],
+    set: ResourceSet,
+    dir: impl FnOnce() -> Result<PathBuf, String>,
+) -> Result<Vec<PathBuf>, String> {
+    let mut resources = Vec::new();
+
+    for resource in python_packed_resources::parser::load_resources(packed_resources)? {
+        let mut resource = resource?;
+
+        if resource.resource_set == set {
+            decompress_payloads(&mut resource)?;
+            resources.push(resource);
+        }
+    }
+
+    extract_shared_libraries(resources.iter(), dir)
+}
+
+/// Load a shared library into the current process.
+///
+/// The library is never unloaded, so it remains available to binaries
+/// loaded later.
+#[cfg(unix)]
+pub(crate) fn load_library(path: &Path) -> Result<(), String> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let filename = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| format!("invalid library path {}", path.display()))?;
+
+    let handle = unsafe { libc::dlopen(filename.as_ptr(), libc::RTLD_NOW | libc::RTLD_GLOBAL) };
+
+    if handle.is_null() {
+        let error = unsafe { libc::dlerror() };
+        let message = if error.is_null() {
+            "unknown error".to_string()
+        } else {
+            unsafe { std::ffi::CStr::from_ptr(error) }
+                .to_string_lossy()
+                .to_string()
+        };
+
+        Err(format!("loading {}: {}", path.display(), message))
+    } else {
+        Ok(())
+    }
+}
+
+/// Load a shared library into the current process.
+///
+/// The library is never unloaded, so it remains available to binaries
+/// loaded later.
+#[cfg(windows)]
+pub(crate) fn load_library(path: &Path) -> Result<(), String> {
+    use std::os::windows::ffi::OsStrExt;
+
+    let filename = path
+        .as_os_str()
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect::<Vec<u16>>();
+
+    let handle = unsafe { winapi::um::libloaderapi::LoadLibraryW(filename.as_ptr()) };
+
+    if handle.is_null() {
+        Err(format!(
+            "loading {}: {}",
+            path.display(),
+            std::io::Error::last_os_error()
+        ))
+    } else {
+        Ok(())
+    }
+}
@@ -149,8 +149,14 @@ extern "C" fn custom_load_library(filename: LPCSTR, user_data: *mut c_void) -> H
             .unwrap()
     };
 
+    // Libraries marked for extraction were loaded from the filesystem during
+    // interpreter initialization and are resolved by the system loader.
     if let Some(entry) = resources_state.resources.get(name.as_ref()) {
-        if let Some(library_data) = &entry.in_memory_shared_library {
+        if let Some(library_data) = entry
+            .in_memory_shared_library
+            .as_ref()
+            .filter(|_| !entry.extract_shared_library)
+        {
             let res = unsafe { load_library_memory(resources_state, library_data) };
 
             // If we loaded a module, store its state. Otherwise return its failure (NULL).
@@ -175,51 +175,67 @@ impl OxidizedDistribution {
     }
 }
 
+/// Normalize a distribution name the way `importlib.metadata` does.
+///
+/// Runs of `-`, `_` and `.` become a single `_` and letters are lowercased.
+fn normalize_distribution_name(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        if c == '-' || c == '_' || c == '.' {
+            if !res.ends_with('_') {
+                res.push('_');
+            }
+        } else {
+            res.extend(c.to_lowercase());
+        }
+    }
+
+    res
+}
+
 /// Find package metadata distributions given search criteria.
+///
+/// Distributions are matched by normalized name, so distributions whose
+/// name differs in case or punctuation from the requested name are found.
+/// This includes distributions only installing data files.
 pub(crate) fn find_distributions(
     py: Python,
     state: Arc<Box<ImporterState>>,
     name: Option<PyObject>,
     _path: Option<PyObject>,
 ) -> PyResult<PyObject> {
+    state.index_all_resources(py)?;
     let resources = &state.get_resources_state().resources;
 
-    let distributions = if let Some(name) = name {
-        // Python normalizes the name. We do the same.
-        let name = name.str(py)?.to_string(py)?.to_string();
-        let name = name.to_lowercase().replace('-', "_");
-        let name_cow = Cow::Borrowed::<str>(&name);
-
-        if let Some(resource) = resources.get(&name_cow) {
-            if resource.is_package
-                && (resource.in_memory_distribution_resources.is_some()
-                    || resource.relative_path_distribution_resources.is_some())
-            {
-                vec![OxidizedDistribution::create_instance(py, state.clone(), name)?.into_object()]
-            } else {
-                vec![]
-            }
-        } else {
-            vec![]
+    // A name of None means all distributions.
+    let name = match name {
+        Some(name) if name != py.None() => {
+            Some(normalize_distribution_name(&name.str(py)?.to_string(py)?))
         }
-    } else {
-        // Return all distributions.
-        let mut distributions = Vec::new();
-
-        for (k, v) in resources.iter() {
-            if v.is_package
-                && (v.in_memory_distribution_resources.is_some()
-                    || v.relative_path_distribution_resources.is_some())
-            {
-                distributions.push(
-                    OxidizedDistribution::create_instance(py, state.clone(), k.to_string())?
-                        .into_object(),
-                );
+        _ => None,
+    };
+
+    let mut distributions = Vec::new();
+
+    for (k, v) in resources.iter() {
+        if !(v.is_package
+            && (v.in_memory_distribution_resources.is_some()
+                || v.relative_path_distribution_resources.is_some()))
+        {
+            continue;
+        }
+
+        if let Some(name) = &name {
+            if normalize_distribution_name(k) != *name {
+                continue;
             }
         }
 
-        distributions
-    };
+        distributions.push(
+            OxidizedDistribution::create_instance(py, state.clone(), k.to_string())?.into_object(),
+        );
+    }
 
     Ok(PyList::new(py, &distributions).into_object())
 }
@@ -0,0 +1,76 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Decompression of resource payloads stored compressed in packed resources.
+
+Producers can store individual payloads of packed resources as zstd frames
+to reduce the size of binaries. Packed resources flag these payloads and
+we decompress them when a resource is indexed, so the rest of the importer
+only ever sees decompressed data.
+*/
+
+use {
+    python_packed_resources::data::{Resource, ResourceField},
+    std::borrow::Cow,
+};
+
+/// Decompress a zstd frame, verifying the length of the decompressed data.
+fn decompress(data: &[u8], length: u64) -> Result<Vec<u8>, &'static str> {
+    let res = zstd::decode_all(data).map_err(|_| "failed decompressing payload")?;
+
+    if res.len() as u64 != length {
+        return Err("decompressed payload has unexpected length");
+    }
+
+    Ok(res)
+}
+
+/// Replace the compressed payloads of a resource by their decompressed data.
+///
+/// Fields holding a single payload that have no data yet, like external
+/// payloads not fetched yet, are left compressed to be decompressed once
+/// their data is set. Returns the number of decompressed payloads.
+pub(crate) fn decompress_payloads(resource: &mut Resource<u8>) -> Result<usize, &'static str> {
+    if resource.compressed_payloads.is_empty() {
+        return Ok(0);
+    }
+
+    let mut count = 0;
+    let mut pending = Vec::new();
+
+    for payload in std::mem::take(&mut resource.compressed_payloads) {
+        match payload.field {
+            ResourceField::InMemoryResourcesData | ResourceField::InMemoryDistributionResource => {
+                let entries = if payload.field == ResourceField::InMemoryResourcesData {
+                    resource.in_memory_package_resources.as_mut()
+                } else {
+                    resource.in_memory_distribution_resources.as_mut()
+                };
+
+                let data = entries
+                    .and_then(|entries| entries.get_mut(&*payload.name))
+                    .ok_or("compressed payload names a missing resource")?;
+
+                *data = Cow::Owned(decompress(data, payload.length)?);
+            }
+            field => {
+                let data = match resource.in_memory_payload(field) {
+                    Some(data) => decompress(data, payload.length)?,
+                    None => {
+                        pending.push(payload);
+                        continue;
+                    }
+                };
+
+                resource.set_in_memory_payload(field, Some(Cow::Owned(data)))?;
+            }
+        }
+
+        count += 1;
+    }
+
+    resource.compressed_payloads = pending;
+
+    Ok(count)
+}
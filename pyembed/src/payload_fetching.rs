@@ -0,0 +1,187 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Resolution of resource payloads stored outside of packed resources.
+
+Packed resources can reference payloads by digest and logical location
+instead of embedding them. e.g. a network-installed application may keep
+payloads in an object store and only embed the resources index. Before the
+importer is initialized, we obtain every external payload of the loaded
+resource set and attach it to its resource.
+
+A payload is read from the configured payload directory, then from the
+payload cache, and is otherwise obtained from the configured
+`PayloadFetcher`. Every payload's digest is verified before it is used and
+fetched payloads are written to the cache, so subsequent runs don't fetch
+them again.
+*/
+
+use {
+    crate::library_extraction::resolve_extraction_dir,
+    crate::payload_decompression::decompress_payloads,
+    python_packed_resources::data::{ExternalPayload, Resource},
+    python_packed_resources::sink::{digest_hex, payload_digest},
+    std::borrow::Cow,
+    std::collections::HashMap,
+    std::fs,
+    std::path::Path,
+    std::sync::Arc,
+};
+
+/// Obtains payloads that aren't available locally.
+///
+/// Implement this to fetch payloads from wherever the build stored them,
+/// e.g. an object store written to by an `ObjectStoreSink`.
+pub trait PayloadFetcher: Send + Sync {
+    /// Fetch the payload having a hex SHA-256 digest from a logical location.
+    fn fetch(&self, digest_hex: &str, location: &str) -> Result<Vec<u8>, String>;
+}
+
+/// Defines how payloads stored outside of packed resources are obtained.
+#[derive(Clone, Default)]
+pub struct ExternalPayloadsConfig {
+    /// Directory holding payloads at their logical location.
+    ///
+    /// `$ORIGIN` expands to the directory of the current executable and
+    /// `$CACHE_DIR` to the per-user cache directory.
+    pub directory: Option<String>,
+
+    /// Directory to cache fetched payloads in.
+    ///
+    /// Supports the same expansions as `directory`. `None` disables caching.
+    pub cache_dir: Option<String>,
+
+    /// Fetches payloads found in neither `directory` nor `cache_dir`.
+    ///
+    /// Generated configs leave this unset. Applications fetching payloads
+    /// over the network set it before creating the interpreter.
+    pub fetcher: Option<Arc<dyn PayloadFetcher>>,
+}
+
+impl std::fmt::Debug for ExternalPayloadsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ExternalPayloadsConfig")
+            .field("directory", &self.directory)
+            .field("cache_dir", &self.cache_dir)
+            .field("fetcher", &self.fetcher.as_ref().map(|_| "<fetcher>"))
+            .finish()
+    }
+}
+
+/// Read a payload from a file, if it exists and has the expected content.
+fn read_verified(path: &Path, payload: &ExternalPayload) -> Option<Vec<u8>> {
+    let data = fs::read(path).ok()?;
+
+    if data.len() as u64 == payload.length && payload_digest(&data) == payload.digest {
+        Some(data)
+    } else {
+        None
+    }
+}
+
+/// Write a fetched payload to the cache.
+///
+/// Failing to cache a payload doesn't prevent using it.
+fn write_cache(dir: &Path, name: &str, data: &[u8]) {
+    let path = dir.join(name);
+    let temp_path = dir.join(format!("{}.{}.tmp", name, std::process::id()));
+
+    if fs::create_dir_all(dir).is_err() || fs::write(&temp_path, data).is_err() {
+        return;
+    }
+
+    if fs::rename(&temp_path, &path).is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+}
+
+/// Obtain the data of an external payload.
+fn resolve_payload(
+    payload: &ExternalPayload,
+    config: &ExternalPayloadsConfig,
+    origin: &Path,
+) -> Result<Vec<u8>, String> {
+    let name = digest_hex(&payload.digest);
+
+    if let Some(template) = &config.directory {
+        let dir = resolve_extraction_dir(template, origin)?;
+        if let Some(data) = read_verified(&dir.join(payload.location.as_ref()), payload) {
+            return Ok(data);
+        }
+    }
+
+    let cache_dir = match &config.cache_dir {
+        Some(template) => Some(resolve_extraction_dir(template, origin)?),
+        None => None,
+    };
+
+    if let Some(dir) = &cache_dir {
+        if let Some(data) = read_verified(&dir.join(&name), payload) {
+            return Ok(data);
+        }
+    }
+
+    let fetcher = config.fetcher.as_ref().ok_or_else(|| {
+        format!(
+            "payload {} at {} is not available locally and no payload fetcher is configured",
+            name, payload.location
+        )
+    })?;
+
+    let data = fetcher
+        .fetch(&name, &payload.location)
+        .map_err(|e| format!("fetching payload {} at {}: {}", name, payload.location, e))?;
+
+    if data.len() as u64 != payload.length || payload_digest(&data) != payload.digest {
+        return Err(format!(
+            "fetched payload at {} does not match digest {}",
+            payload.location, name
+        ));
+    }
+
+    if let Some(dir) = &cache_dir {
+        write_cache(dir, &name, &data);
+    }
+
+    Ok(data)
+}
+
+/// Attach the data of external payloads to resources.
+///
+/// Returns the number of resolved payloads. Errors if resources have
+/// external payloads but `config` is `None`.
+pub(crate) fn resolve_external_payloads<'a>(
+    resources: &mut HashMap<Cow<'a, str>, Resource<'a, u8>>,
+    config: Option<&ExternalPayloadsConfig>,
+    origin: &Path,
+) -> Result<usize, String> {
+    let mut count = 0;
+
+    for resource in resources.values_mut() {
+        if resource.external_payloads.is_empty() {
+            continue;
+        }
+
+        let config = config.ok_or_else(|| {
+            format!(
+                "{} has external payloads but external payloads aren't configured",
+                resource.name
+            )
+        })?;
+
+        for payload in std::mem::take(&mut resource.external_payloads) {
+            let data = resolve_payload(&payload, config, origin)
+                .map_err(|e| format!("resolving payload of {}: {}", resource.name, e))?;
+
+            resource.set_in_memory_payload(payload.field, Some(Cow::Owned(data)))?;
+            count += 1;
+        }
+
+        // Payloads stored compressed could only be decompressed once fetched.
+        decompress_payloads(resource)
+            .map_err(|e| format!("decompressing payloads of {}: {}", resource.name, e))?;
+    }
+
+    Ok(count)
+}
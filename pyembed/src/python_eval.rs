@@ -124,6 +124,14 @@ pub fn run_module_as_main(py: Python, name: &str) -> PyResult<PyObject> {
 
     let importlib_util = py.import("importlib.util")?;
     let spec = importlib_util.call(py, "find_spec", (name,), None)?;
+
+    // Like `python -m`, packages are run via their __main__ module after
+    // the package itself is imported.
+    if spec.getattr(py, "submodule_search_locations")? != py.None() {
+        py.import(name)?;
+        return run_module_as_main(py, &format!("{}.__main__", name));
+    }
+
     let loader = spec.getattr(py, "loader")?;
     let code = loader.call_method(py, "get_code", (name,), None)?;
 
@@ -12,6 +12,7 @@ use {
         pyobject_optional_resources_map_to_pathbuf, pyobject_to_owned_bytes_optional,
         pyobject_to_pathbuf_optional,
     },
+    super::payload_decompression::decompress_payloads,
     anyhow::Result,
     cpython::exc::{ImportError, OSError, TypeError, ValueError},
     cpython::{
@@ -20,10 +21,14 @@ use {
         PyString, PyTuple, Python, PythonObject, ToPyObject,
     },
     python3_sys as pyffi,
-    python_packed_resources::data::{Resource, ResourceFlavor},
+    python_packed_resources::data::{
+        IndexEncoding, Resource, ResourceFlavor, ResourceSet, HEADER_V3, IMPORT_TELEMETRY_HEADER,
+        IMPORT_TELEMETRY_RESOURCE_NAME,
+    },
+    python_packed_resources::parser::{load_lookup_table, ResourcesLookupTable},
     std::borrow::Cow,
     std::cell::RefCell,
-    std::collections::HashMap,
+    std::collections::{BTreeMap, HashMap},
     std::ffi::CStr,
     std::iter::FromIterator,
     std::path::{Path, PathBuf},
@@ -345,6 +350,60 @@ impl<'a> ImportablePythonModule<'a, u8> {
     }
 }
 
+/// Counts imports and package resource reads.
+#[derive(Debug, Default)]
+pub(crate) struct ImportTelemetry {
+    modules: RefCell<BTreeMap<String, u64>>,
+    resources: RefCell<BTreeMap<(String, String), u64>>,
+}
+
+impl ImportTelemetry {
+    /// Record an import of a module.
+    pub fn record_module(&self, name: &str) {
+        *self
+            .modules
+            .borrow_mut()
+            .entry(name.to_string())
+            .or_default() += 1;
+    }
+
+    /// Record a read of a package resource.
+    pub fn record_resource(&self, package: &str, name: &str) {
+        *self
+            .resources
+            .borrow_mut()
+            .entry((package.to_string(), name.to_string()))
+            .or_default() += 1;
+    }
+
+    /// Render the counters in the import telemetry dump format.
+    pub fn render(&self) -> String {
+        let mut res = format!("{}\n", IMPORT_TELEMETRY_HEADER);
+
+        for (name, count) in self.modules.borrow().iter() {
+            res.push_str(&format!("module\t{}\t{}\n", count, name));
+        }
+        for ((package, name), count) in self.resources.borrow().iter() {
+            res.push_str(&format!("resource\t{}\t{}\t{}\n", count, package, name));
+        }
+
+        res
+    }
+}
+
+/// Packed resources with a lookup table whose resources are indexed on first use.
+#[derive(Debug)]
+struct LazyResources<'a> {
+    /// The packed resources data.
+    data: &'a [u8],
+
+    /// Lookup table of `data`.
+    table: ResourcesLookupTable<'a>,
+
+    /// Resource set whose resources are indexed.
+    set: ResourceSet,
+}
+
 /// Defines Python resources available for import.
 #[derive(Debug)]
 pub(crate) struct PythonResourcesState<'a, X>
@@ -361,6 +420,16 @@ where
 
     /// Named resources available for loading.
     pub resources: HashMap<Cow<'a, str>, Resource<'a, X>>,
+
+    /// Counters of imports, if import telemetry is enabled.
+    pub import_telemetry: Option<ImportTelemetry>,
+
+    /// Packed resources not yet added to `resources`, in the order loaded.
+    ///
+    /// Resources are only added to `resources` once indexed through
+    /// `index_resource()` or `index_all_resources()`. Resources of later
+    /// blobs take precedence over those of earlier ones.
+    lazy_resources: Vec<LazyResources<'a>>,
 }
 
 impl<'a> Default for PythonResourcesState<'a, u8> {
@@ -369,6 +438,8 @@ impl<'a> Default for PythonResourcesState<'a, u8> {
             current_exe: PathBuf::new(),
             origin: PathBuf::new(),
             resources: HashMap::new(),
+            import_telemetry: None,
+            lazy_resources: Vec::new(),
         }
     }
 }
@@ -386,15 +457,39 @@ impl<'a> PythonResourcesState<'a, u8> {
             current_exe: exe,
             origin,
             resources: Default::default(),
+            import_telemetry: None,
+            lazy_resources: Vec::new(),
         })
     }
 
+    /// Start counting imports if packed resources request import telemetry.
+    ///
+    /// Returns whether import telemetry is enabled.
+    pub fn enable_import_telemetry(&mut self) -> Result<bool, &'static str> {
+        self.index_resource(IMPORT_TELEMETRY_RESOURCE_NAME)?;
+
+        if self.resources.contains_key(IMPORT_TELEMETRY_RESOURCE_NAME) {
+            self.import_telemetry = Some(ImportTelemetry::default());
+        }
+
+        Ok(self.import_telemetry.is_some())
+    }
+
     /// Load state from the environment and by parsing data structures.
     pub fn load(&mut self, resources_data: Option<&'a [u8]>) -> Result<(), &'static str> {
+        self.load_resource_set(resources_data, ResourceSet::Main)
+    }
+
+    /// Load state from the environment and a resource set of packed resources.
+    pub fn load_resource_set(
+        &mut self,
+        resources_data: Option<&'a [u8]>,
+        set: ResourceSet,
+    ) -> Result<(), &'static str> {
         // Loading of builtin and frozen knows to mutate existing entries rather
         // than replace. So do these last.
         if let Some(data) = resources_data {
-            self.load_resources(data)?;
+            self.load_resources(data, set)?;
         }
         self.load_interpreter_builtin_modules()?;
         self.load_interpreter_frozen_modules()?;
@@ -415,6 +510,84 @@ impl<'a> PythonResourcesState<'a, u8> {
         Ok(())
     }
 
+    /// Find a resource in packed resources with a lookup table.
+    ///
+    /// The latest loaded packed resources defining the resource win.
+    fn find_lazy_resource(&self, name: &str) -> Result<Option<Resource<'a, u8>>, &'static str> {
+        for lazy in self.lazy_resources.iter().rev() {
+            if let Some(resource) = lazy.table.get_in_set(name, lazy.set)? {
+                return Ok(Some(resource));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Add the resource of a name from packed resources with a lookup table.
+    ///
+    /// Shared libraries the resource depends on are added as well. Does
+    /// nothing if the resource was added before or if all resources were
+    /// parsed when loaded.
+    pub fn index_resource(&mut self, name: &str) -> Result<(), &'static str> {
+        if self.lazy_resources.is_empty() {
+            return Ok(());
+        }
+
+        let mut pending = vec![Cow::Owned(name.to_string())];
+
+        while let Some(name) = pending.pop() {
+            if self.resources.contains_key(&*name) {
+                continue;
+            }
+
+            let mut resource = match self.find_lazy_resource(&name)? {
+                Some(resource) => resource,
+                None => continue,
+            };
+
+            // External payloads are resolved after loading by indexing all
+            // resources. So remaining ones can't be resolved.
+            if !resource.external_payloads.is_empty() {
+                return Err(
+                    "resource has external payloads but external payloads aren't configured",
+                );
+            }
+
+            decompress_payloads(&mut resource)?;
+
+            if let Some(names) = &resource.shared_library_dependency_names {
+                pending.extend(names.iter().cloned());
+            }
+
+            self.resources.insert(resource.name.clone(), resource);
+        }
+
+        Ok(())
+    }
+
+    /// Add all resources from packed resources with a lookup table.
+    ///
+    /// Resources added before are retained.
+    pub fn index_all_resources(&mut self) -> Result<(), &'static str> {
+        // Resources of later blobs win, so they are added first.
+        for lazy in std::mem::take(&mut self.lazy_resources).into_iter().rev() {
+            let resources = python_packed_resources::parser::load_resources(lazy.data)?;
+            self.resources.reserve(resources.expected_resources_count());
+
+            for resource in resources {
+                let mut resource = resource?;
+
+                if resource.resource_set == lazy.set && !self.resources.contains_key(&resource.name)
+                {
+                    decompress_payloads(&mut resource)?;
+                    self.resources.insert(resource.name.clone(), resource);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     /// Attempt to resolve an importable Python module.
     pub fn resolve_importable_module(
         &self,
@@ -705,6 +878,7 @@ impl<'a> PythonResourcesState<'a, u8> {
     /// Obtain a PyList of pkgutil.ModuleInfo for known resources.
     ///
     /// This is intended to be used as the implementation for Finder.iter_modules().
+    /// Resources must have been indexed with `index_all_resources()`.
     pub fn pkgutil_modules_infos(
         &self,
         py: Python,
@@ -759,6 +933,7 @@ impl<'a> PythonResourcesState<'a, u8> {
 
             // Module can be defined by embedded resources data. If exists, just
             // update the big.
+            self.index_resource(name_str)?;
             if let Some(mut entry) = self.resources.get_mut(name_str) {
                 entry.flavor = ResourceFlavor::BuiltinExtensionModule;
             } else {
@@ -795,6 +970,7 @@ impl<'a> PythonResourcesState<'a, u8> {
 
             // Module can be defined by embedded resources data. If exists, just
             // update the big.
+            self.index_resource(name_str)?;
             if let Some(mut entry) = self.resources.get_mut(name_str) {
                 entry.flavor = ResourceFlavor::FrozenModule;
             } else {
@@ -812,8 +988,39 @@ impl<'a> PythonResourcesState<'a, u8> {
         Ok(())
     }
 
-    /// Load resources by parsing a blob.
-    fn load_resources(&mut self, data: &'a [u8]) -> Result<(), &'static str> {
+    /// Load resources of a resource set by parsing a blob.
+    ///
+    /// Resources of blobs with a lookup table are only parsed once indexed.
+    /// Resources of later blobs replace those of earlier ones.
+    pub(crate) fn load_resources(
+        &mut self,
+        data: &'a [u8],
+        set: ResourceSet,
+    ) -> Result<(), &'static str> {
+        if data.starts_with(HEADER_V3) {
+            let table = load_lookup_table(data)?;
+
+            // Resources indexed before are replaced by the blob's, which are
+            // indexed from it when needed.
+            let mut replaced = Vec::new();
+            for name in self.resources.keys() {
+                if table.get_in_set(name, set)?.is_some() {
+                    replaced.push(name.clone());
+                }
+            }
+            for name in replaced {
+                self.resources.remove(&name);
+            }
+
+            self.lazy_resources.push(LazyResources { data, table, set });
+
+            return Ok(());
+        }
+
+        // Resources of later blobs replace earlier ones. So earlier ones
+        // must all be known.
+        self.index_all_resources()?;
+
         let resources = python_packed_resources::parser::load_resources(data)?;
 
         // Reserve space for expected number of incoming items so we can avoid extra
@@ -821,9 +1028,12 @@ impl<'a> PythonResourcesState<'a, u8> {
         self.resources.reserve(resources.expected_resources_count());
 
         for resource in resources {
-            let resource = resource?;
+            let mut resource = resource?;
 
-            self.resources.insert(resource.name.clone(), resource);
+            if resource.resource_set == set {
+                decompress_payloads(&mut resource)?;
+                self.resources.insert(resource.name.clone(), resource);
+            }
         }
 
         Ok(())
@@ -832,7 +1042,8 @@ impl<'a> PythonResourcesState<'a, u8> {
     /// Serialize resources contained in this data structure.
     ///
     /// `ignore_built` and `ignore_frozen` specify whether to ignore built-in
-    /// extension modules and frozen modules, respectively.
+    /// extension modules and frozen modules, respectively. Resources must
+    /// have been indexed with `index_all_resources()`.
     pub fn serialize_resources(
         &self,
         ignore_builtin: bool,
@@ -853,7 +1064,13 @@ impl<'a> PythonResourcesState<'a, u8> {
 
         let mut buffer = Vec::new();
 
-        python_packed_resources::writer::write_packed_resources_v1(&resources, &mut buffer, None)?;
+        // Resources may need fields of version 2, which is then written.
+        python_packed_resources::writer::write_packed_resources(
+            &resources,
+            &mut buffer,
+            None,
+            IndexEncoding::Sequential,
+        )?;
 
         Ok(buffer)
     }
@@ -1071,6 +1288,19 @@ py_class!(pub class OxidizedResource |py| {
         }
     }
 
+    @property def extract_shared_library(&self) -> PyResult<bool> {
+        Ok(self.resource(py).borrow().extract_shared_library)
+    }
+
+    @extract_shared_library.setter def set_extract_shared_library(&self, value: Option<bool>) -> PyResult<()> {
+        if let Some(value) = value {
+            self.resource(py).borrow_mut().extract_shared_library = value;
+            Ok(())
+        } else {
+            Err(PyErr::new::<TypeError, _>(py, "cannot delete extract_shared_library"))
+        }
+    }
+
     @property def shared_library_dependency_names(&self) -> PyResult<Option<Vec<String>>> {
         Ok(self.resource(py).borrow().shared_library_dependency_names.as_ref().map(|x| {
             Vec::from_iter(x.iter().map(|v| v.to_string()))
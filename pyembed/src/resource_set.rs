@@ -0,0 +1,71 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Selection of the resource set loaded at startup.
+
+Packed resources can hold a recovery resource set next to the main one,
+e.g. a small subset of the standard library and a diagnostics module for
+investigating an application that fails to start. The recovery set is
+loaded instead of the main set when the `PYEMBED_RECOVERY` environment
+variable is set or a marker file exists.
+
+Configs generated by PyOxidizer for binaries with a recovery set call
+`select_resource_set()` to populate `PythonConfig.resource_set`.
+*/
+
+use {
+    python_packed_resources::data::ResourceSet,
+    std::env,
+    std::ffi::OsStr,
+    std::path::{Path, PathBuf},
+};
+
+/// Environment variable selecting the recovery resource set.
+///
+/// Any value other than an empty string or `0` selects it.
+pub const RECOVERY_ENV: &str = "PYEMBED_RECOVERY";
+
+/// Select the resource set to load.
+///
+/// The recovery set is selected if `RECOVERY_ENV` asks for it or if
+/// `marker_file` exists. `$ORIGIN` in `marker_file` expands to the directory
+/// of the current executable.
+pub fn select_resource_set(marker_file: Option<&str>) -> ResourceSet {
+    let marker_file = marker_file.and_then(|template| {
+        if template.contains("$ORIGIN") {
+            let exe = env::current_exe().ok()?;
+            let origin = exe.parent()?;
+
+            Some(PathBuf::from(
+                template.replace("$ORIGIN", &origin.display().to_string()),
+            ))
+        } else {
+            Some(PathBuf::from(template))
+        }
+    });
+
+    resolve_resource_set(
+        env::var_os(RECOVERY_ENV)
+            .as_ref()
+            .map(|value| value.as_os_str()),
+        marker_file.as_deref(),
+    )
+}
+
+/// Select the resource set given the value of `RECOVERY_ENV` and a resolved marker file.
+pub(crate) fn resolve_resource_set(
+    env_value: Option<&OsStr>,
+    marker_file: Option<&Path>,
+) -> ResourceSet {
+    let requested = match env_value {
+        Some(value) => !value.is_empty() && value != "0",
+        None => false,
+    };
+
+    if requested || marker_file.map(|path| path.exists()).unwrap_or(false) {
+        ResourceSet::Recovery
+    } else {
+        ResourceSet::Main
+    }
+}
@@ -0,0 +1,106 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use {
+    crate::library_extraction::{
+        extract_packed_shared_libraries, extract_shared_libraries, resolve_extraction_dir,
+    },
+    anyhow::Result,
+    python_packed_resources::data::{Resource, ResourceFlavor, ResourceSet},
+    python_packed_resources::writer::write_packed_resources_v2,
+    std::borrow::Cow,
+    std::path::Path,
+};
+
+#[test]
+fn test_resolve_extraction_dir() -> Result<()> {
+    let origin = Path::new("/opt/app");
+
+    assert_eq!(
+        resolve_extraction_dir("$ORIGIN/lib", origin).unwrap(),
+        origin.join("lib")
+    );
+    assert_eq!(
+        resolve_extraction_dir("/tmp/libs", origin).unwrap(),
+        Path::new("/tmp/libs")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_extract_shared_libraries() -> Result<()> {
+    let library = |name: &str, data: &[u8], extract: bool, depends: &[&str]| Resource {
+        flavor: ResourceFlavor::SharedLibrary,
+        name: Cow::Owned(name.to_string()),
+        in_memory_shared_library: Some(Cow::Owned(data.to_vec())),
+        extract_shared_library: extract,
+        shared_library_dependency_names: Some(
+            depends.iter().map(|x| Cow::Owned(x.to_string())).collect(),
+        ),
+        ..Resource::default()
+    };
+
+    let resources = vec![
+        library("a.so", b"a", true, &["b.so"]),
+        library("b.so", b"b", true, &[]),
+        library("memory.so", b"memory", false, &[]),
+    ];
+
+    let mut data = Vec::new();
+    write_packed_resources_v2(&resources, &mut data, None)?;
+
+    let dir = std::env::temp_dir().join(format!("pyembed-test-{}", uuid::Uuid::new_v4()));
+
+    let paths =
+        extract_packed_shared_libraries(&data, ResourceSet::Main, || Ok(dir.clone())).unwrap();
+    assert_eq!(paths.len(), 2);
+
+    // Dependencies are ordered first.
+    assert_eq!(paths[0].file_name().unwrap(), "b.so");
+    assert_eq!(paths[1].file_name().unwrap(), "a.so");
+    assert_eq!(std::fs::read(&paths[0])?, b"b");
+    assert_eq!(std::fs::read(&paths[1])?, b"a");
+
+    // Libraries are stored in directories named after their SHA-256.
+    assert_eq!(
+        paths[0].parent().unwrap().file_name().unwrap(),
+        "3e23e8160039594a33894f6564e1b1348bbd7a0088d42c4acb73eeaed59c009d"
+    );
+    assert_eq!(paths[0].parent().unwrap().parent().unwrap(), dir);
+
+    // Extraction is reused.
+    assert_eq!(
+        extract_shared_libraries(resources.iter(), || Ok(dir.clone())).unwrap(),
+        paths
+    );
+
+    // Files with unexpected content are replaced rather than reused.
+    std::fs::write(&paths[1], b"x")?;
+    assert_eq!(
+        extract_shared_libraries(resources.iter(), || Ok(dir.clone())).unwrap(),
+        paths
+    );
+    assert_eq!(std::fs::read(&paths[1])?, b"a");
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_extract_shared_libraries_none() {
+    let resources = [Resource {
+        flavor: ResourceFlavor::SharedLibrary,
+        name: Cow::Borrowed("memory.so"),
+        in_memory_shared_library: Some(Cow::Borrowed(b"memory")),
+        ..Resource::default()
+    }];
+
+    // The directory isn't resolved if there is nothing to extract.
+    assert_eq!(
+        extract_shared_libraries(resources.iter(), || Err("unresolvable".to_string())),
+        Ok(vec![])
+    );
+}
@@ -4,3 +4,8 @@
 
 mod importer;
 mod interpreter_config;
+mod library_extraction;
+mod payload_decompression;
+mod payload_fetching;
+mod python_resources;
+mod resource_set;
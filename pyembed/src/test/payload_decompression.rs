@@ -0,0 +1,110 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use {
+    crate::payload_decompression::decompress_payloads,
+    crate::python_resources::PythonResourcesState,
+    anyhow::Result,
+    python_packed_resources::data::{
+        CompressedPayload, Resource, ResourceField, ResourceFlavor, ResourceSet,
+    },
+    python_packed_resources::writer::{write_packed_resources_v2, write_packed_resources_v3},
+    std::borrow::Cow,
+    std::collections::HashMap,
+    std::iter::FromIterator,
+};
+
+fn compressed_resource() -> Resource<'static, u8> {
+    let compress = |data: &[u8]| zstd::encode_all(data, 3).unwrap();
+
+    Resource {
+        flavor: ResourceFlavor::Module,
+        name: Cow::Borrowed("foo"),
+        is_package: true,
+        in_memory_source: Some(Cow::Owned(compress(b"import sys"))),
+        in_memory_package_resources: Some(HashMap::from_iter(vec![(
+            Cow::Borrowed("data.txt"),
+            Cow::Owned(compress(b"resource data")),
+        )])),
+        compressed_payloads: vec![
+            CompressedPayload {
+                field: ResourceField::InMemorySource,
+                name: Cow::Borrowed(""),
+                length: 10,
+            },
+            CompressedPayload {
+                field: ResourceField::InMemoryResourcesData,
+                name: Cow::Borrowed("data.txt"),
+                length: 13,
+            },
+        ],
+        ..Resource::default()
+    }
+}
+
+fn assert_decompressed(resource: &Resource<u8>) {
+    assert!(resource.compressed_payloads.is_empty());
+    assert_eq!(
+        resource.in_memory_source.as_ref().unwrap().as_ref(),
+        b"import sys"
+    );
+    assert_eq!(
+        resource.in_memory_package_resources.as_ref().unwrap()["data.txt"].as_ref(),
+        b"resource data"
+    );
+}
+
+#[test]
+fn test_decompress_payloads() -> Result<()> {
+    let mut resource = compressed_resource();
+    assert_eq!(decompress_payloads(&mut resource).unwrap(), 2);
+    assert_decompressed(&resource);
+
+    // Nothing is left to decompress.
+    assert_eq!(decompress_payloads(&mut resource).unwrap(), 0);
+
+    // Payloads without data yet are left for later.
+    let mut resource = compressed_resource();
+    resource.in_memory_source = None;
+    assert_eq!(decompress_payloads(&mut resource).unwrap(), 1);
+    assert_eq!(resource.compressed_payloads.len(), 1);
+
+    let mut resource = compressed_resource();
+    resource.compressed_payloads[0].length = 11;
+    assert_eq!(
+        decompress_payloads(&mut resource),
+        Err("decompressed payload has unexpected length")
+    );
+
+    let mut resource = compressed_resource();
+    resource.compressed_payloads[1].name = Cow::Borrowed("missing.txt");
+    assert_eq!(
+        decompress_payloads(&mut resource),
+        Err("compressed payload names a missing resource")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_load_compressed_resources() -> Result<()> {
+    let resources = vec![compressed_resource()];
+
+    let mut data = Vec::new();
+    write_packed_resources_v2(&resources, &mut data, None)?;
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&data, ResourceSet::Main).unwrap();
+    assert_decompressed(&state.resources["foo"]);
+
+    let mut data = Vec::new();
+    write_packed_resources_v3(&resources, &mut data, None)?;
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&data, ResourceSet::Main).unwrap();
+    state.index_resource("foo").unwrap();
+    assert_decompressed(&state.resources["foo"]);
+
+    Ok(())
+}
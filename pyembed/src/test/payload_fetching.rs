@@ -0,0 +1,109 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use {
+    crate::payload_fetching::{resolve_external_payloads, ExternalPayloadsConfig, PayloadFetcher},
+    anyhow::Result,
+    python_packed_resources::data::{ExternalPayload, Resource, ResourceField, ResourceFlavor},
+    python_packed_resources::sink::{digest_hex, payload_digest},
+    std::borrow::Cow,
+    std::collections::HashMap,
+    std::path::Path,
+    std::sync::atomic::{AtomicUsize, Ordering},
+    std::sync::Arc,
+};
+
+#[derive(Default)]
+struct CountingFetcher {
+    fetches: AtomicUsize,
+}
+
+impl PayloadFetcher for CountingFetcher {
+    fn fetch(&self, digest_hex: &str, location: &str) -> Result<Vec<u8>, String> {
+        self.fetches.fetch_add(1, Ordering::SeqCst);
+
+        if location == format!("objects/{}", digest_hex) {
+            Ok(b"import sys".to_vec())
+        } else {
+            Ok(b"tampered".to_vec())
+        }
+    }
+}
+
+fn resources(location: &str) -> HashMap<Cow<'static, str>, Resource<'static, u8>> {
+    let mut res = HashMap::new();
+    res.insert(
+        Cow::Borrowed("foo"),
+        Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::Borrowed("foo"),
+            external_payloads: vec![ExternalPayload {
+                field: ResourceField::InMemorySource,
+                length: 10,
+                digest: payload_digest(b"import sys"),
+                location: Cow::Owned(location.to_string()),
+            }],
+            ..Resource::default()
+        },
+    );
+
+    res
+}
+
+#[test]
+fn test_resolve_external_payloads() -> Result<()> {
+    let origin = Path::new("/nonexistent");
+    let dir = std::env::temp_dir().join(format!("pyembed-test-{}", uuid::Uuid::new_v4()));
+    let name = digest_hex(&payload_digest(b"import sys"));
+    let location = format!("objects/{}", name);
+
+    // Resources without external payloads don't need a config.
+    let mut empty = HashMap::new();
+    assert_eq!(resolve_external_payloads(&mut empty, None, origin), Ok(0));
+    assert!(resolve_external_payloads(&mut resources(&location), None, origin).is_err());
+
+    let fetcher = Arc::new(CountingFetcher::default());
+    let config = ExternalPayloadsConfig {
+        directory: None,
+        cache_dir: Some(dir.display().to_string()),
+        fetcher: Some(fetcher.clone()),
+    };
+
+    let mut fetched = resources(&location);
+    assert_eq!(
+        resolve_external_payloads(&mut fetched, Some(&config), origin),
+        Ok(1)
+    );
+    assert_eq!(
+        fetched["foo"].in_memory_source.as_deref(),
+        Some(&b"import sys"[..])
+    );
+    assert!(fetched["foo"].external_payloads.is_empty());
+    assert_eq!(fetcher.fetches.load(Ordering::SeqCst), 1);
+
+    // Fetched payloads are cached.
+    assert_eq!(std::fs::read(dir.join(&name))?, b"import sys");
+    resolve_external_payloads(&mut resources(&location), Some(&config), origin).unwrap();
+    assert_eq!(fetcher.fetches.load(Ordering::SeqCst), 1);
+
+    // Payloads not matching their digest are rejected.
+    std::fs::remove_dir_all(&dir)?;
+    let err =
+        resolve_external_payloads(&mut resources("elsewhere"), Some(&config), origin).unwrap_err();
+    assert!(err.contains("does not match digest"));
+    assert!(!dir.join(&name).exists());
+
+    // Payloads are read from the payload directory without fetching.
+    std::fs::create_dir_all(dir.join("objects"))?;
+    std::fs::write(dir.join(&location), b"import sys")?;
+    let config = ExternalPayloadsConfig {
+        directory: Some(dir.display().to_string()),
+        ..ExternalPayloadsConfig::default()
+    };
+    resolve_external_payloads(&mut resources(&location), Some(&config), origin).unwrap();
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
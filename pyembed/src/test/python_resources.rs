@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use {
+    crate::python_resources::PythonResourcesState,
+    anyhow::Result,
+    python_packed_resources::data::{Resource, ResourceFlavor, ResourceSet},
+    python_packed_resources::writer::{write_packed_resources_v1, write_packed_resources_v3},
+    std::borrow::Cow,
+};
+
+#[test]
+fn test_load_resource_set_lookup_table() -> Result<()> {
+    let resources = vec![
+        Resource {
+            flavor: ResourceFlavor::Extension,
+            name: Cow::from("ext"),
+            shared_library_dependency_names: Some(vec![Cow::from("libfoo")]),
+            ..Resource::default()
+        },
+        Resource {
+            flavor: ResourceFlavor::SharedLibrary,
+            name: Cow::from("libfoo"),
+            in_memory_shared_library: Some(Cow::from(b"lib".to_vec())),
+            ..Resource::default()
+        },
+        Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from("os"),
+            in_memory_source: Some(Cow::from(b"main os".to_vec())),
+            ..Resource::default()
+        },
+        Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from("os"),
+            in_memory_source: Some(Cow::from(b"recovery os".to_vec())),
+            resource_set: ResourceSet::Recovery,
+            ..Resource::default()
+        },
+    ];
+
+    let mut data = Vec::new();
+    write_packed_resources_v3(&resources, &mut data, None)?;
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&data, ResourceSet::Recovery).unwrap();
+
+    // Nothing is parsed until indexed.
+    assert!(state.resources.is_empty());
+
+    state.index_resource("os").unwrap();
+    state.index_resource("missing").unwrap();
+    assert_eq!(state.resources.len(), 1);
+    assert_eq!(
+        state.resources["os"]
+            .in_memory_source
+            .as_ref()
+            .unwrap()
+            .as_ref(),
+        b"recovery os"
+    );
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&data, ResourceSet::Main).unwrap();
+
+    // Dependencies of extensions are indexed with them.
+    state.index_resource("ext").unwrap();
+    assert!(state.resources.contains_key("libfoo"));
+    assert!(!state.resources.contains_key("os"));
+
+    state.index_all_resources().unwrap();
+    assert_eq!(state.resources.len(), 3);
+    assert_eq!(
+        state.resources["os"]
+            .in_memory_source
+            .as_ref()
+            .unwrap()
+            .as_ref(),
+        b"main os"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_lookup_table_lookup_indexes_one_resource() -> Result<()> {
+    let resources = (0..10_000)
+        .map(|i| Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from(format!("module{:05}", i)),
+            in_memory_source: Some(Cow::from(format!("value = {}", i).into_bytes())),
+            ..Resource::default()
+        })
+        .collect::<Vec<_>>();
+
+    let mut data = Vec::new();
+    write_packed_resources_v3(&resources, &mut data, None)?;
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&data, ResourceSet::Main).unwrap();
+
+    // Looking up names only parses their entries, not the other 9,998.
+    state.index_resource("module04242").unwrap();
+    state.index_resource("module09999").unwrap();
+    state.index_resource("module04242").unwrap();
+    let mut names = state.resources.keys().cloned().collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["module04242", "module09999"]);
+    assert_eq!(
+        state.resources["module04242"]
+            .in_memory_source
+            .as_ref()
+            .unwrap()
+            .as_ref(),
+        b"value = 4242"
+    );
+
+    // Checking for import telemetry only looks up its marker resource.
+    assert!(!state.enable_import_telemetry().unwrap());
+    assert_eq!(state.resources.len(), 2);
+
+    state.index_all_resources().unwrap();
+    assert_eq!(state.resources.len(), 10_000);
+
+    Ok(())
+}
+
+#[test]
+fn test_load_lookup_table_after_other_resources() -> Result<()> {
+    let module = |name: &str, source: &str| Resource {
+        flavor: ResourceFlavor::Module,
+        name: Cow::Owned(name.to_string()),
+        in_memory_source: Some(Cow::Owned(source.as_bytes().to_vec())),
+        ..Resource::default()
+    };
+    let source = |state: &PythonResourcesState<u8>, name: &str| {
+        state.resources[name]
+            .in_memory_source
+            .as_ref()
+            .unwrap()
+            .to_vec()
+    };
+
+    let mut v1 = Vec::new();
+    write_packed_resources_v1(
+        &[module("os", "v1 os"), module("sys", "v1 sys")],
+        &mut v1,
+        None,
+    )?;
+    let mut v3 = Vec::new();
+    write_packed_resources_v3(
+        &[module("os", "v3 os"), module("json", "v3 json")],
+        &mut v3,
+        None,
+    )?;
+    let mut v3_later = Vec::new();
+    write_packed_resources_v3(&[module("json", "later json")], &mut v3_later, None)?;
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&v1, ResourceSet::Main).unwrap();
+    state.load_resources(&v3, ResourceSet::Main).unwrap();
+
+    // Resources of the later blob aren't parsed until indexed, but they
+    // replace the earlier ones.
+    assert_eq!(
+        state.resources.keys().cloned().collect::<Vec<_>>(),
+        vec!["sys"]
+    );
+    state.index_resource("os").unwrap();
+    assert_eq!(source(&state, "os"), b"v3 os");
+
+    // Later blobs with a lookup table take precedence over earlier ones.
+    state.load_resources(&v3_later, ResourceSet::Main).unwrap();
+    state.index_resource("json").unwrap();
+    assert_eq!(source(&state, "json"), b"later json");
+    assert_eq!(state.resources.len(), 3);
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&v1, ResourceSet::Main).unwrap();
+    state.load_resources(&v3, ResourceSet::Main).unwrap();
+    state.load_resources(&v3_later, ResourceSet::Main).unwrap();
+    state.index_all_resources().unwrap();
+    assert_eq!(state.resources.len(), 3);
+    assert_eq!(source(&state, "os"), b"v3 os");
+    assert_eq!(source(&state, "sys"), b"v1 sys");
+    assert_eq!(source(&state, "json"), b"later json");
+
+    Ok(())
+}
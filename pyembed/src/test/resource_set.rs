@@ -0,0 +1,94 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use {
+    crate::python_resources::PythonResourcesState,
+    crate::resource_set::resolve_resource_set,
+    anyhow::Result,
+    python_packed_resources::data::{Resource, ResourceFlavor, ResourceSet},
+    python_packed_resources::writer::write_packed_resources_v2,
+    std::borrow::Cow,
+    std::ffi::OsStr,
+};
+
+#[test]
+fn test_resolve_resource_set() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("pyembed-test-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir)?;
+    let marker = dir.join("RECOVERY");
+
+    assert_eq!(resolve_resource_set(None, None), ResourceSet::Main);
+    assert_eq!(
+        resolve_resource_set(Some(OsStr::new("")), Some(&marker)),
+        ResourceSet::Main
+    );
+    assert_eq!(
+        resolve_resource_set(Some(OsStr::new("0")), None),
+        ResourceSet::Main
+    );
+    assert_eq!(
+        resolve_resource_set(Some(OsStr::new("1")), None),
+        ResourceSet::Recovery
+    );
+
+    std::fs::write(&marker, b"")?;
+    assert_eq!(
+        resolve_resource_set(None, Some(&marker)),
+        ResourceSet::Recovery
+    );
+
+    std::fs::remove_dir_all(&dir)?;
+
+    Ok(())
+}
+
+#[test]
+fn test_load_resource_set() -> Result<()> {
+    let module = |name: &str, source: &[u8], set: ResourceSet| Resource {
+        flavor: ResourceFlavor::Module,
+        name: Cow::Owned(name.to_string()),
+        in_memory_source: Some(Cow::Owned(source.to_vec())),
+        resource_set: set,
+        ..Resource::default()
+    };
+
+    let resources = vec![
+        module("app", b"main()", ResourceSet::Main),
+        module("os", b"main os", ResourceSet::Main),
+        module("diagnostics", b"report()", ResourceSet::Recovery),
+        module("os", b"recovery os", ResourceSet::Recovery),
+    ];
+
+    let mut data = Vec::new();
+    write_packed_resources_v2(&resources, &mut data, None)?;
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&data, ResourceSet::Recovery).unwrap();
+
+    assert!(state.resources.get("app").is_none());
+    assert_eq!(
+        state.resources["os"]
+            .in_memory_source
+            .as_ref()
+            .unwrap()
+            .as_ref(),
+        b"recovery os"
+    );
+    assert!(state.resources.contains_key("diagnostics"));
+
+    let mut state = PythonResourcesState::default();
+    state.load_resources(&data, ResourceSet::Main).unwrap();
+
+    assert!(state.resources.get("diagnostics").is_none());
+    assert_eq!(
+        state.resources["os"]
+            .in_memory_source
+            .as_ref()
+            .unwrap()
+            .as_ref(),
+        b"main os"
+    );
+
+    Ok(())
+}
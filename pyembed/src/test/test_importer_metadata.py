@@ -193,6 +193,47 @@ class TestImporterMetadata(unittest.TestCase):
         self.assertIsInstance(requires, list)
         self.assertEqual(requires, ["foo"])
 
+    def test_find_distributions_by_name(self):
+        self._write_metadata()
+        f = self._finder_from_td()
+
+        for name in ("my_package", "my-package", "My.Package"):
+            context = importlib.metadata.DistributionFinder.Context(name=name)
+            dists = f.find_distributions(context)
+            self.assertEqual(len(dists), 1)
+            self.assertEqual(dists[0].version, "1.0")
+
+        context = importlib.metadata.DistributionFinder.Context(name="other")
+        self.assertEqual(f.find_distributions(context), [])
+
+        # A context without a name finds everything.
+        context = importlib.metadata.DistributionFinder.Context()
+        self.assertEqual(len(f.find_distributions(context)), 1)
+
+    def test_data_only_distribution(self):
+        package_path = self.td / "certbundle"
+        package_path.mkdir()
+        with (package_path / "cacert.pem").open("w", encoding="utf-8") as fh:
+            fh.write("cert\n")
+
+        dist_path = self.td / "certifi_bundle-1.0.dist-info"
+        dist_path.mkdir()
+        with (dist_path / "METADATA").open("w", encoding="utf-8") as fh:
+            fh.write("Name: certifi-bundle\n")
+            fh.write("Version: 1.0\n")
+        with (dist_path / "RECORD").open("w", encoding="utf-8") as fh:
+            fh.write("certbundle/cacert.pem,,\n")
+            fh.write("certifi_bundle-1.0.dist-info/METADATA,,\n")
+            fh.write("certifi_bundle-1.0.dist-info/RECORD,,\n")
+
+        f = self._finder_from_td()
+
+        context = importlib.metadata.DistributionFinder.Context(name="certifi-bundle")
+        dists = f.find_distributions(context)
+        self.assertEqual(len(dists), 1)
+        self.assertEqual(dists[0].metadata["Name"], "certifi-bundle")
+        self.assertEqual(dists[0].version, "1.0")
+
 
 if __name__ == "__main__":
     # Reset command arguments so test runner isn't confused.
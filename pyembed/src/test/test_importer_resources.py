@@ -44,6 +44,7 @@ class TestImporterResources(unittest.TestCase):
         self.assertIsNone(resource.in_memory_package_resources)
         self.assertIsNone(resource.in_memory_distribution_resources)
         self.assertIsNone(resource.in_memory_shared_library)
+        self.assertFalse(resource.extract_shared_library)
         self.assertIsNone(resource.shared_library_dependency_names)
         self.assertIsNone(resource.relative_path_module_source)
         self.assertIsNone(resource.relative_path_module_bytecode)
@@ -286,6 +287,17 @@ class TestImporterResources(unittest.TestCase):
         with self.assertRaises(TypeError):
             resource.in_memory_shared_library = "ELF"
 
+    def test_resource_extract_shared_library(self):
+        resource = OxidizedResource()
+
+        resource.extract_shared_library = True
+        self.assertTrue(resource.extract_shared_library)
+        resource.extract_shared_library = False
+        self.assertFalse(resource.extract_shared_library)
+
+        with self.assertRaises(TypeError):
+            del resource.extract_shared_library
+
     def test_resource_shared_library_dependency_names(self):
         resource = OxidizedResource()
 
@@ -615,6 +627,46 @@ class TestImporterResources(unittest.TestCase):
         self.assertEqual(modules["my_module"].in_memory_source, b"import io")
         self.assertEqual(modules["module_b"].in_memory_bytecode, b"dummy bytecode")
 
+    def test_serialize_shared_payloads(self):
+        f = OxidizedFinder()
+
+        source = b"import io\n" * 20
+
+        for name in ("module_a", "module_b"):
+            m = OxidizedResource()
+            m.name = name
+            m.flavor = "module"
+            m.in_memory_source = source
+            f.add_resource(m)
+
+        shared = f.serialize_indexed_resources()
+
+        f = OxidizedFinder()
+        for name, data in (("module_a", source), ("module_b", b"import os\n" * 20)):
+            m = OxidizedResource()
+            m.name = name
+            m.flavor = "module"
+            m.in_memory_source = data
+            f.add_resource(m)
+
+        # The identical payload is only stored once.
+        self.assertLess(len(shared), len(f.serialize_indexed_resources()))
+
+        f2 = OxidizedFinder(resources_data=shared)
+
+        modules = {r.name: r for r in f2.indexed_resources() if r.flavor == "module"}
+        self.assertEqual(modules["module_a"].in_memory_source, source)
+        self.assertEqual(modules["module_b"].in_memory_source, source)
+
+        # Mutating one resource doesn't affect the other.
+        m = modules["module_a"]
+        m.in_memory_source = b"import sys"
+        f2.add_resource(m)
+
+        modules = {r.name: r for r in f2.indexed_resources() if r.flavor == "module"}
+        self.assertEqual(modules["module_a"].in_memory_source, b"import sys")
+        self.assertEqual(modules["module_b"].in_memory_source, source)
+
 
 if __name__ == "__main__":
     # Reset command arguments so test runner isn't confused.
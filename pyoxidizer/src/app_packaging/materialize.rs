@@ -0,0 +1,298 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Materialize files from their data locations.
+
+Writing a file backed by another file copies its bytes, which is slow for
+large files like model weights. `materialize_file()` first attempts
+strategies that avoid copying when both files are on the same filesystem:
+
+1. Reflink the file, sharing its blocks copy-on-write. This uses the
+   `FICLONE` ioctl on Linux and `clonefile()` on macOS.
+2. Hard link the file, if the policy allows it. Both paths then refer to
+   the same file, so modifying one modifies the other.
+3. Copy the file.
+
+A strategy that fails, e.g. because the files are on different devices or
+the filesystem doesn't support it, silently falls back to the next one. The
+result of a reflink or hard link is only accepted if its size and SHA-256
+match the source.
+
+Other platforms, including Windows, always copy. ReFS block cloning isn't
+supported.
+*/
+
+use {
+    super::resource::{is_executable, set_executable},
+    anyhow::{Context, Result},
+    python_packaging::resource::DataLocation,
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::fs::File,
+    std::path::Path,
+};
+
+/// Environment variable allowing hard links when materializing files.
+///
+/// Hard links are allowed if it is set to `1`.
+pub const ALLOW_HARD_LINKS_ENV: &str = "PYOXIDIZER_ALLOW_HARD_LINKS";
+
+/// How a file was materialized.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaterializeStrategy {
+    /// The source file was reflinked.
+    Reflink,
+
+    /// The source file was hard linked.
+    HardLink,
+
+    /// The source file was copied.
+    Copy,
+
+    /// Content not backed by a file was written.
+    Write,
+}
+
+impl std::fmt::Display for MaterializeStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            MaterializeStrategy::Reflink => "reflink",
+            MaterializeStrategy::HardLink => "hard link",
+            MaterializeStrategy::Copy => "copy",
+            MaterializeStrategy::Write => "write",
+        })
+    }
+}
+
+/// Which strategies may be used to materialize files.
+///
+/// Reflinks are always attempted, since the result can't be told apart from
+/// a copy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MaterializePolicy {
+    /// Whether files may be hard linked to their source.
+    pub allow_hard_links: bool,
+}
+
+impl MaterializePolicy {
+    /// Construct an instance from the environment.
+    pub fn from_env() -> Self {
+        Self {
+            allow_hard_links: std::env::var(ALLOW_HARD_LINKS_ENV)
+                .map(|value| value == "1")
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// Write the content of a `DataLocation` to `dest`.
+///
+/// Any existing file at `dest` is replaced. It is removed rather than
+/// truncated, as it may be a hard link to the source.
+pub fn materialize_file(
+    data: &DataLocation,
+    dest: &Path,
+    executable: bool,
+    policy: &MaterializePolicy,
+) -> Result<MaterializeStrategy> {
+    remove_existing(dest)?;
+
+    if let Some(source) = data.path() {
+        if reflink(source, dest).is_ok() {
+            if verify(data, dest) {
+                if executable {
+                    set_executable(&mut std::fs::OpenOptions::new().write(true).open(dest)?)?;
+                }
+
+                return Ok(MaterializeStrategy::Reflink);
+            }
+
+            remove_existing(dest)?;
+        }
+
+        // Permissions are shared with the source, so it must already have
+        // the wanted executable bit.
+        let same_mode = std::fs::metadata(source)
+            .map(|metadata| is_executable(&metadata) == executable)
+            .unwrap_or(false);
+
+        if policy.allow_hard_links && same_mode && std::fs::hard_link(source, dest).is_ok() {
+            if verify(data, dest) {
+                return Ok(MaterializeStrategy::HardLink);
+            }
+
+            remove_existing(dest)?;
+        }
+    }
+
+    let mut fh = File::create(dest).with_context(|| format!("creating {}", dest.display()))?;
+    data.copy_to(&mut fh)
+        .with_context(|| format!("writing {}", dest.display()))?;
+    if executable {
+        set_executable(&mut fh)?;
+    }
+
+    Ok(if data.path().is_some() {
+        MaterializeStrategy::Copy
+    } else {
+        MaterializeStrategy::Write
+    })
+}
+
+fn remove_existing(path: &Path) -> Result<()> {
+    if std::fs::symlink_metadata(path).is_ok() {
+        std::fs::remove_file(path).with_context(|| format!("removing {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Whether a materialized file has the size and SHA-256 of its source.
+fn verify(data: &DataLocation, dest: &Path) -> bool {
+    let matches = || -> Result<bool> {
+        if std::fs::metadata(dest)?.len() != data.size()? {
+            return Ok(false);
+        }
+
+        let mut hasher = Sha256::new();
+        std::io::copy(&mut File::open(dest)?, &mut hasher)?;
+
+        Ok(hex::encode(hasher.finalize()) == data.sha256()?)
+    };
+
+    matches().unwrap_or(false)
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    /// `_IOW(0x94, 9, int)` from `linux/fs.h`.
+    const FICLONE: u32 = 0x4004_9409;
+
+    let src = File::open(source)?;
+    let dst = File::create(dest)?;
+
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } == 0 {
+        Ok(())
+    } else {
+        let err = std::io::Error::last_os_error();
+        drop(dst);
+        std::fs::remove_file(dest)?;
+        Err(err)
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn reflink(source: &Path, dest: &Path) -> std::io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    extern "C" {
+        fn clonefile(src: *const libc::c_char, dst: *const libc::c_char, flags: u32)
+            -> libc::c_int;
+    }
+
+    let src = CString::new(source.as_os_str().as_bytes())?;
+    let dst = CString::new(dest.as_os_str().as_bytes())?;
+
+    if unsafe { clonefile(src.as_ptr(), dst.as_ptr(), 0) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_source: &Path, _dest: &Path) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "reflinks are not supported on this platform",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_memory() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dest = td.path().join("dest");
+
+        let strategy = materialize_file(
+            &DataLocation::Memory(b"data".to_vec()),
+            &dest,
+            false,
+            &MaterializePolicy::default(),
+        )?;
+        assert_eq!(strategy, MaterializeStrategy::Write);
+        assert_eq!(std::fs::read(&dest)?, b"data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_materialize_path() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let source = td.path().join("source");
+        std::fs::write(&source, b"weights")?;
+        let data = DataLocation::from_path(&source);
+
+        // Without hard links, the file is reflinked if the filesystem
+        // supports it and copied otherwise.
+        let dest = td.path().join("copy");
+        let strategy = materialize_file(&data, &dest, false, &MaterializePolicy::default())?;
+        assert!(matches!(
+            strategy,
+            MaterializeStrategy::Reflink | MaterializeStrategy::Copy
+        ));
+        assert_eq!(std::fs::read(&dest)?, b"weights");
+
+        let policy = MaterializePolicy {
+            allow_hard_links: true,
+        };
+        let dest = td.path().join("link");
+        let strategy = materialize_file(&data, &dest, false, &policy)?;
+        assert!(matches!(
+            strategy,
+            MaterializeStrategy::Reflink | MaterializeStrategy::HardLink
+        ));
+        assert_eq!(std::fs::read(&dest)?, b"weights");
+
+        // Replacing a hard link doesn't modify its source.
+        let dest = td.path().join("replaced");
+        std::fs::hard_link(&source, &dest)?;
+        materialize_file(
+            &DataLocation::Memory(b"other".to_vec()),
+            &dest,
+            false,
+            &policy,
+        )?;
+        assert_eq!(std::fs::read(&dest)?, b"other");
+        assert_eq!(std::fs::read(&source)?, b"weights");
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_hard_link_requires_same_mode() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let source = td.path().join("source");
+        std::fs::write(&source, b"#!/bin/sh\n")?;
+
+        let policy = MaterializePolicy {
+            allow_hard_links: true,
+        };
+        let dest = td.path().join("dest");
+        let strategy = materialize_file(&DataLocation::from_path(&source), &dest, true, &policy)?;
+        assert_ne!(strategy, MaterializeStrategy::HardLink);
+        assert!(is_executable(&std::fs::metadata(&dest)?));
+        assert!(!is_executable(&std::fs::metadata(&source)?));
+
+        Ok(())
+    }
+}
@@ -3,11 +3,12 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use {
+    super::materialize::{materialize_file, MaterializePolicy, MaterializeStrategy},
     anyhow::{anyhow, Context, Result},
+    python_packaging::resource::DataLocation,
     std::collections::btree_map::Iter,
     std::collections::{BTreeMap, BTreeSet},
     std::convert::TryFrom,
-    std::io::Write,
     std::path::{Path, PathBuf},
 };
 
@@ -42,7 +43,10 @@ pub fn is_executable(_metadata: &std::fs::Metadata) -> bool {
 #[derive(Clone, Debug, PartialEq)]
 pub struct FileContent {
     /// Raw data in the file.
-    pub data: Vec<u8>,
+    ///
+    /// Files are streamed from their location when written, so large files
+    /// don't need to be held in memory.
+    pub data: DataLocation,
 
     /// Whether the file is executable.
     pub executable: bool,
@@ -56,38 +60,145 @@ impl TryFrom<&Path> for FileContent {
         let metadata = std::fs::metadata(value)?;
         let executable = is_executable(&metadata);
 
-        Ok(FileContent { data, executable })
+        Ok(FileContent {
+            data: DataLocation::Memory(data),
+            executable,
+        })
+    }
+}
+
+/// How a `FileManifest` handles a file added at a path it already has.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileConflictPolicy {
+    /// Adding a file at a path already in the manifest is an error.
+    Error,
+
+    /// Adding a file identical to the one at the path is allowed.
+    ///
+    /// Files are identical if their content and executable bit are. Adding
+    /// a different file is an error.
+    AllowIdentical,
+
+    /// The file added last replaces the one at the path.
+    Replace,
+}
+
+impl Default for FileConflictPolicy {
+    fn default() -> Self {
+        FileConflictPolicy::Error
     }
 }
 
 /// Represents a virtual tree of files.
+///
+/// Files are ordered by path. Each file can record its provenance, a
+/// description of what added it, e.g. `resource foo` or `libpython`, which
+/// is used to describe conflicts.
 #[derive(Clone, Debug, Default, PartialEq)]
 pub struct FileManifest {
     files: BTreeMap<PathBuf, FileContent>,
+    provenance: BTreeMap<PathBuf, String>,
+    conflict_policy: FileConflictPolicy,
 }
 
 impl FileManifest {
-    /// Add a file to the manifest.
+    /// Obtain how files added at a path already in the manifest are handled.
+    pub fn conflict_policy(&self) -> FileConflictPolicy {
+        self.conflict_policy
+    }
+
+    /// Set how files added at a path already in the manifest are handled.
+    pub fn set_conflict_policy(&mut self, policy: FileConflictPolicy) {
+        self.conflict_policy = policy;
+    }
+
+    /// Add a file to the manifest without recording its provenance.
     pub fn add_file(&mut self, path: &Path, content: &FileContent) -> Result<()> {
-        let path_s = path.display().to_string();
+        self.insert(path, content, None)
+    }
 
-        if path_s.contains("..") {
-            return Err(anyhow!("path cannot contain '..': {}", path.display()));
-        }
+    /// Add a file to the manifest, recording what added it.
+    pub fn add_file_from(
+        &mut self,
+        path: &Path,
+        content: &FileContent,
+        provenance: &str,
+    ) -> Result<()> {
+        self.insert(path, content, Some(provenance))
+    }
 
-        // is_absolute() on Windows doesn't check for leading /.
-        if path_s.starts_with('/') || path.is_absolute() {
-            return Err(anyhow!("path cannot be absolute: {}", path.display()));
-        }
+    /// Add a file to the manifest, replacing any file at the path.
+    ///
+    /// This ignores the conflict policy. Use it for files meant to be
+    /// superseded, like placeholders.
+    pub fn replace_file_from(
+        &mut self,
+        path: &Path,
+        content: &FileContent,
+        provenance: &str,
+    ) -> Result<()> {
+        validate_manifest_path(path)?;
 
         self.files.insert(path.to_path_buf(), content.clone());
+        self.provenance
+            .insert(path.to_path_buf(), provenance.to_string());
 
         Ok(())
     }
 
+    /// Add all files of another manifest, keeping their provenance.
+    ///
+    /// Conflicts are handled by the policy of this manifest.
     pub fn add_manifest(&mut self, other: &FileManifest) -> Result<()> {
         for (key, value) in &other.files {
-            self.add_file(key.as_path(), value)?;
+            self.insert(key.as_path(), value, other.provenance(key))?;
+        }
+
+        Ok(())
+    }
+
+    /// Obtain what added the file at a path, if it was recorded.
+    pub fn provenance(&self, path: &Path) -> Option<&str> {
+        self.provenance.get(path).map(|s| s.as_str())
+    }
+
+    fn insert(
+        &mut self,
+        path: &Path,
+        content: &FileContent,
+        provenance: Option<&str>,
+    ) -> Result<()> {
+        validate_manifest_path(path)?;
+
+        if let Some(existing) = self.files.get(path) {
+            let allowed = match self.conflict_policy {
+                FileConflictPolicy::Error => false,
+                FileConflictPolicy::AllowIdentical => {
+                    existing.executable == content.executable
+                        && existing.data.content_eq(&content.data)?
+                }
+                FileConflictPolicy::Replace => true,
+            };
+
+            if !allowed {
+                return Err(anyhow!(
+                    "{} is added by both {} and {}",
+                    path.display(),
+                    self.provenance(path).unwrap_or("an unnamed source"),
+                    provenance.unwrap_or("an unnamed source"),
+                ));
+            }
+        }
+
+        self.files.insert(path.to_path_buf(), content.clone());
+        match provenance {
+            Some(provenance) => {
+                self.provenance
+                    .insert(path.to_path_buf(), provenance.to_string());
+            }
+            None => {
+                self.provenance.remove(path);
+            }
         }
 
         Ok(())
@@ -139,6 +250,23 @@ impl FileManifest {
 
     /// Write the contents of the install manifest to a filesystem path.
     pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        self.write_to_path_with_policy(path, &MaterializePolicy::default())?;
+
+        Ok(())
+    }
+
+    /// Write the contents of the install manifest to a filesystem path.
+    ///
+    /// Files backed by other files are reflinked or hard linked instead of
+    /// copied where `policy` and the filesystem allow. Returns how each
+    /// file was materialized.
+    pub fn write_to_path_with_policy(
+        &self,
+        path: &Path,
+        policy: &MaterializePolicy,
+    ) -> Result<BTreeMap<PathBuf, MaterializeStrategy>> {
+        let mut strategies = BTreeMap::new();
+
         for (p, c) in &self.files {
             let dest_path = path.join(p);
             let parent = dest_path
@@ -148,25 +276,54 @@ impl FileManifest {
             std::fs::create_dir_all(parent)
                 .context("creating parent directory for FileManifest")?;
 
-            let mut fh = std::fs::File::create(&dest_path)?;
-            fh.write_all(&c.data)?;
-            if c.executable {
-                set_executable(&mut fh)?;
-            }
+            strategies.insert(
+                p.clone(),
+                materialize_file(&c.data, &dest_path, c.executable, policy)?,
+            );
         }
 
-        Ok(())
+        Ok(strategies)
     }
 
     /// Write the contents of the install manifest to a filesystem path,
     /// replacing any existing content at the specified path.
     pub fn replace_path(&self, path: &Path) -> Result<()> {
+        self.replace_path_with_policy(path, &MaterializePolicy::default())?;
+
+        Ok(())
+    }
+
+    /// Replace any existing content at a filesystem path with the contents
+    /// of the install manifest.
+    ///
+    /// Files are written as with `write_to_path_with_policy()`.
+    pub fn replace_path_with_policy(
+        &self,
+        path: &Path,
+        policy: &MaterializePolicy,
+    ) -> Result<BTreeMap<PathBuf, MaterializeStrategy>> {
         if path.exists() {
             std::fs::remove_dir_all(path)?;
         }
 
-        self.write_to_path(path)
+        self.write_to_path_with_policy(path, policy)
+    }
+}
+
+/// Ensure a path can be added to a `FileManifest`.
+fn validate_manifest_path(path: &Path) -> Result<()> {
+    let path_s = path.display().to_string();
+
+    if path_s.contains("..") {
+        return Err(anyhow!("path cannot contain '..': {}", path.display()));
+    }
+
+    // is_absolute() on Windows doesn't check for leading /.
+    if path_s.starts_with('/') || path.is_absolute() {
+        return Err(anyhow!("path cannot be absolute: {}", path.display()));
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
@@ -177,7 +334,7 @@ mod tests {
     fn test_add() {
         let mut v = FileManifest::default();
         let f = FileContent {
-            data: vec![],
+            data: DataLocation::Memory(vec![]),
             executable: false,
         };
 
@@ -194,7 +351,7 @@ mod tests {
     fn test_add_bad_path() {
         let mut v = FileManifest::default();
         let f = FileContent {
-            data: vec![],
+            data: DataLocation::Memory(vec![]),
             executable: false,
         };
 
@@ -205,11 +362,57 @@ mod tests {
         assert!(res.is_err());
     }
 
+    #[test]
+    fn test_add_conflict() -> Result<()> {
+        let content = |data: &[u8]| FileContent {
+            data: DataLocation::Memory(data.to_vec()),
+            executable: true,
+        };
+        let path = PathBuf::from("bin/foo.cmd");
+
+        let mut v = FileManifest::default();
+        v.add_file_from(&path, &content(b"wheel"), "resource foo")?;
+        assert_eq!(v.provenance(&path), Some("resource foo"));
+
+        // Conflicts are an error naming both sources.
+        let err = v
+            .add_file_from(&path, &content(b"launcher"), "launcher for bin/foo")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "bin/foo.cmd is added by both resource foo and launcher for bin/foo"
+        );
+        assert!(v
+            .add_file_from(&path, &content(b"wheel"), "resource bar")
+            .is_err());
+
+        v.set_conflict_policy(FileConflictPolicy::AllowIdentical);
+        v.add_file_from(&path, &content(b"wheel"), "resource bar")?;
+        assert_eq!(v.provenance(&path), Some("resource bar"));
+        assert!(v
+            .add_file_from(&path, &content(b"launcher"), "launcher for bin/foo")
+            .is_err());
+
+        v.set_conflict_policy(FileConflictPolicy::Replace);
+        v.add_file_from(&path, &content(b"launcher"), "launcher for bin/foo")?;
+        assert_eq!(v.entries().next().unwrap().1, &content(b"launcher"));
+
+        // Merging manifests keeps provenance and applies the target's policy.
+        let mut merged = FileManifest::default();
+        merged.add_file(&PathBuf::from("bin/bar"), &content(b"bar"))?;
+        merged.add_manifest(&v)?;
+        assert_eq!(merged.provenance(&path), Some("launcher for bin/foo"));
+        assert_eq!(merged.provenance(Path::new("bin/bar")), None);
+        assert!(merged.add_manifest(&v).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_relative_directories() {
         let mut v = FileManifest::default();
         let f = FileContent {
-            data: vec![],
+            data: DataLocation::Memory(vec![]),
             executable: false,
         };
 
@@ -229,7 +432,7 @@ mod tests {
     fn test_resolve_directories() {
         let mut v = FileManifest::default();
         let f = FileContent {
-            data: vec![],
+            data: DataLocation::Memory(vec![]),
             executable: false,
         };
 
@@ -246,4 +449,57 @@ mod tests {
             ]
         )
     }
+
+    #[test]
+    fn test_write_to_path() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let source = td.path().join("libfoo.so");
+        std::fs::write(&source, b"shared library")?;
+
+        let mut v = FileManifest::default();
+        v.add_file(
+            &PathBuf::from("lib/libfoo.so"),
+            &FileContent {
+                data: DataLocation::from_path(&source),
+                executable: true,
+            },
+        )?;
+        v.add_file(
+            &PathBuf::from("foo.txt"),
+            &FileContent {
+                data: DataLocation::Memory(b"foo".to_vec()),
+                executable: false,
+            },
+        )?;
+
+        let dest = td.path().join("out");
+        let strategies = v.write_to_path_with_policy(&dest, &MaterializePolicy::default())?;
+        assert_eq!(
+            strategies.get(Path::new("foo.txt")),
+            Some(&MaterializeStrategy::Write)
+        );
+        assert_ne!(
+            strategies.get(Path::new("lib/libfoo.so")),
+            Some(&MaterializeStrategy::HardLink)
+        );
+        assert_eq!(
+            std::fs::read(dest.join("lib/libfoo.so"))?,
+            b"shared library"
+        );
+        assert_eq!(std::fs::read(dest.join("foo.txt"))?, b"foo");
+        if cfg!(unix) {
+            assert!(is_executable(&std::fs::metadata(
+                dest.join("lib/libfoo.so")
+            )?));
+        }
+
+        // Files are streamed, so their content isn't held by the manifest.
+        let (_, content) = v
+            .entries()
+            .find(|(path, _)| *path == Path::new("lib/libfoo.so"))
+            .unwrap();
+        assert!(matches!(&content.data, DataLocation::Path { cache, .. } if !cache.is_populated()));
+
+        Ok(())
+    }
 }
@@ -6,9 +6,10 @@ use {
     super::analyze,
     super::environment::BUILD_SEMVER_LIGHTWEIGHT,
     super::logging,
-    super::project_building,
+    super::project_building::{self, BYTECODE_REPORT_ENV},
     super::project_layout,
     super::projectmgmt,
+    super::py_packaging::bytecode_cache::NO_BYTECODE_CACHE_ENV,
     anyhow::{anyhow, Result},
     clap::{App, AppSettings, Arg, SubCommand},
     std::path::{Path, PathBuf},
@@ -40,6 +41,13 @@ existing PyOxidizer enabled project.
 
 This command will invoke Rust's build system tool (Cargo) to build
 the project.
+
+Bytecode compiled by earlier builds is reused from build/cache unless
+--no-bytecode-cache is given. PYOXIDIZER_BYTECODE_CACHE_DIR overrides the
+cache directory.
+
+--bytecode-report N prints the N modules with the largest compiled bytecode,
+along with per-package totals, after embedded resources are generated.
 ";
 
 const INIT_RUST_PROJECT_ABOUT: &str = "\
@@ -182,6 +190,18 @@ pub fn run_cli() -> Result<()> {
                         .long("release")
                         .help("Build a release binary"),
                 )
+                .arg(
+                    Arg::with_name("no_bytecode_cache")
+                        .long("no-bytecode-cache")
+                        .help("Compile all bytecode instead of reusing it from earlier builds"),
+                )
+                .arg(
+                    Arg::with_name("bytecode_report")
+                        .long("bytecode-report")
+                        .takes_value(true)
+                        .value_name("N")
+                        .help("Print the N modules with the largest compiled bytecode"),
+                )
                 .arg(
                     Arg::with_name("path")
                         .long("path")
@@ -251,6 +271,13 @@ pub fn run_cli() -> Result<()> {
                         .required(true)
                         .value_name("PATH")
                         .help("Path to Python distribution archive to analyze"),
+                )
+                .arg(
+                    Arg::with_name("scratch_dir")
+                        .long("scratch-dir")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Directory to extract the distribution in temporarily"),
                 ),
         )
         .subcommand(
@@ -261,6 +288,40 @@ pub fn run_cli() -> Result<()> {
                         .required(true)
                         .value_name("PATH")
                         .help("Path to Python distribution to analyze"),
+                )
+                .arg(
+                    Arg::with_name("scratch_dir")
+                        .long("scratch-dir")
+                        .takes_value(true)
+                        .value_name("DIR")
+                        .help("Directory to extract the distribution in temporarily"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify-embedded-resources")
+                .about(
+                    "Verify the packed resources of a built executable against its build manifest",
+                )
+                .arg(
+                    Arg::with_name("manifest")
+                        .long("manifest")
+                        .required(true)
+                        .takes_value(true)
+                        .value_name("MANIFEST")
+                        .help("Path to the build manifest written by the build"),
+                )
+                .arg(
+                    Arg::with_name("packed_resources")
+                        .long("packed-resources")
+                        .takes_value(true)
+                        .value_name("PATH")
+                        .help("Path to a packed resources file the executable loads"),
+                )
+                .arg(
+                    Arg::with_name("path")
+                        .required(true)
+                        .value_name("PATH")
+                        .help("Path to executable to verify"),
                 ),
         )
         .get_matches();
@@ -300,6 +361,15 @@ pub fn run_cli() -> Result<()> {
                 None
             };
 
+            // Configs are also evaluated by build scripts Cargo runs, which
+            // inherit our environment.
+            if args.is_present("no_bytecode_cache") {
+                std::env::set_var(NO_BYTECODE_CACHE_ENV, "1");
+            }
+            if let Some(count) = args.value_of("bytecode_report") {
+                std::env::set_var(BYTECODE_REPORT_ENV, count);
+            }
+
             projectmgmt::build(
                 &logger_context.logger,
                 Path::new(path),
@@ -345,14 +415,16 @@ pub fn run_cli() -> Result<()> {
 
         ("python-distribution-info", Some(args)) => {
             let dist_path = args.value_of("path").unwrap();
+            let scratch_dir = args.value_of("scratch_dir").map(Path::new);
 
-            projectmgmt::python_distribution_info(dist_path)
+            projectmgmt::python_distribution_info(dist_path, scratch_dir)
         }
 
         ("python-distribution-licenses", Some(args)) => {
             let path = args.value_of("path").unwrap();
+            let scratch_dir = args.value_of("scratch_dir").map(Path::new);
 
-            projectmgmt::python_distribution_licenses(path)
+            projectmgmt::python_distribution_licenses(path, scratch_dir)
         }
 
         ("run-build-script", Some(args)) => {
@@ -380,6 +452,14 @@ pub fn run_cli() -> Result<()> {
             )
         }
 
+        ("verify-embedded-resources", Some(args)) => {
+            let path = args.value_of("path").unwrap();
+            let manifest = args.value_of("manifest").unwrap();
+            let packed_resources = args.value_of("packed_resources");
+
+            projectmgmt::verify_embedded_resources(path, manifest, packed_resources)
+        }
+
         _ => Err(anyhow!("invalid sub-command")),
     }
 }
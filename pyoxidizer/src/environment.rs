@@ -47,11 +47,13 @@ lazy_static! {
 
     /// Target triples for macOS.
     pub static ref MACOS_TARGET_TRIPLES: Vec<&'static str> = vec![
+        "aarch64-apple-darwin",
         "x86_64-apple-darwin",
     ];
 
     /// Target triples for Windows.
     pub static ref WINDOWS_TARGET_TRIPLES: Vec<&'static str> = vec![
+        "aarch64-pc-windows-msvc",
         "i686-pc-windows-gnu",
         "i686-pc-windows-msvc",
         "x86_64-pc-windows-gnu",
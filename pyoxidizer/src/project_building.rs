@@ -5,11 +5,17 @@
 use {
     crate::environment::{canonicalize_path, MINIMUM_RUST_VERSION},
     crate::project_layout::initialize_project,
-    crate::py_packaging::binary::{EmbeddedPythonContext, PythonBinaryBuilder},
+    crate::py_packaging::binary::{
+        load_cargo_metadata, EmbeddedPythonContext, PythonBinaryBuilder,
+    },
+    crate::py_packaging::build_directories::DirectoryRole,
+    crate::py_packaging::config::validate_release_config,
+    crate::py_packaging::workspace_paths::WorkspacePaths,
     crate::starlark::eval::{eval_starlark_config_file, EvalResult},
     crate::starlark::target::ResolvedTarget,
     anyhow::{anyhow, Context, Result},
     slog::warn,
+    std::collections::BTreeSet,
     std::env,
     std::fs::create_dir_all,
     std::path::{Path, PathBuf},
@@ -17,6 +23,9 @@ use {
 
 pub const HOST: &str = env!("HOST");
 
+/// Environment variable holding the number of largest bytecode modules to report.
+pub const BYTECODE_REPORT_ENV: &str = "PYOXIDIZER_BYTECODE_REPORT";
+
 /// Find a pyoxidizer.toml configuration file by walking directory ancestry.
 pub fn find_pyoxidizer_config_file(start_dir: &Path) -> Option<PathBuf> {
     for test_dir in start_dir.ancestors() {
@@ -102,8 +111,41 @@ pub fn build_executable_with_rust_project(
         .with_context(|| "creating directory for PyOxidizer build artifacts")?;
 
     // Derive and write the artifacts needed to build a binary embedding Python.
-    let embedded_data = exe.to_embedded_python_context(logger, opt_level)?;
-    embedded_data.write_files(&artifacts_path)?;
+    let embedded_data = exe.to_embedded_python_context(logger, opt_level, &BTreeSet::new())?;
+
+    for problem in validate_release_config(&embedded_data.config, release) {
+        warn!(logger, "{}", problem);
+    }
+
+    if let Ok(value) = env::var(BYTECODE_REPORT_ENV) {
+        let count = value
+            .parse::<usize>()
+            .with_context(|| format!("parsing {} value {}", BYTECODE_REPORT_ENV, value))?;
+
+        let mut table = Vec::new();
+        embedded_data
+            .bytecode_sizes
+            .write_table(&mut table, count)?;
+        for line in String::from_utf8_lossy(&table).lines() {
+            warn!(logger, "{}", line);
+        }
+    }
+
+    // Artifacts are consumed in place, so paths in them are absolute.
+    let paths = embedded_data.write_files(&artifacts_path, &WorkspacePaths::default())?;
+    for timing in &paths.write_profile.artifacts {
+        warn!(
+            logger,
+            "wrote {} ({} bytes) in {:.2}s{}",
+            timing.artifact,
+            timing.bytes,
+            timing.seconds,
+            match timing.bytes_per_second() {
+                Some(rate) => format!(" ({:.1} MB/s)", rate / 1_000_000.0),
+                None => "".to_string(),
+            }
+        );
+    }
 
     let rust_version = rustc_version::version()?;
     if rust_version.lt(&MINIMUM_RUST_VERSION) {
@@ -168,7 +210,7 @@ pub fn build_executable_with_rust_project(
 
     // Set PYTHON_SYS_EXECUTABLE so python3-sys uses our distribution's Python to configure
     // itself.
-    let python_exe_path = exe.python_exe_path();
+    let python_exe_path = exe.target_python_exe_path();
     envs.push((
         "PYTHON_SYS_EXECUTABLE",
         python_exe_path.display().to_string(),
@@ -246,7 +288,7 @@ pub fn build_python_executable(
     let env = crate::environment::resolve_environment()?;
     let pyembed_location = env.as_pyembed_location();
 
-    let temp_dir = tempdir::TempDir::new("pyoxidizer")?;
+    let temp_dir = exe.scratch_dir(DirectoryRole::Scratch, "pyoxidizer")?;
 
     // Directory needs to have name of project.
     let project_path = temp_dir.path().join(bin_name);
@@ -270,6 +312,8 @@ pub fn build_python_executable(
     // Blank out the path since it is in the temporary directory.
     build.exe_path = None;
 
+    temp_dir.succeed();
+
     Ok(build)
 }
 
@@ -332,8 +376,8 @@ pub fn build_pyembed_artifacts(
             ))?;
         }
 
-        // TODO should we normalize paths to pyoxidizer build directory in cargo_metadata.txt
-        // with the new artifacts directory?
+        // Artifacts written with relative paths are re-anchored against the
+        // artifacts directory when loaded.
 
         return Ok(());
     }
@@ -408,12 +452,10 @@ pub fn run_from_build(
         false,
     )?;
 
-    let cargo_metadata = dest_dir.join("cargo_metadata.txt");
-
-    let content =
-        std::fs::read(&cargo_metadata).context(format!("reading {}", cargo_metadata.display()))?;
-    let content = String::from_utf8(content).context("converting cargo_metadata.txt to string")?;
-    println!("{}", content);
+    let paths = WorkspacePaths::from_env(&dest_dir)?;
+    for entry in load_cargo_metadata(&dest_dir.join("cargo_metadata.txt"), &paths)? {
+        println!("{}", entry);
+    }
 
     Ok(())
 }
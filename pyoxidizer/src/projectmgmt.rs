@@ -7,7 +7,14 @@
 use {
     crate::project_building::find_pyoxidizer_config_file_env,
     crate::project_layout::{initialize_project, write_new_pyoxidizer_config_file},
+    crate::py_packaging::build_directories::{
+        BuildDirectories, DirectoryRole, DirectoryUsage, ScratchDir,
+    },
+    crate::py_packaging::resource_verification::{
+        verify_executable_resources, PackedResourcesLocation,
+    },
     crate::py_packaging::standalone_distribution::StandaloneDistribution,
+    crate::py_packaging::update_package::BuildManifest,
     crate::starlark::eval::{eval_starlark_config_file, EvalResult},
     anyhow::{anyhow, Result},
     std::fs::create_dir_all,
@@ -223,11 +230,29 @@ pub fn python_distribution_extract(dist_path: &str, dest_path: &str) -> Result<(
     Ok(())
 }
 
-pub fn python_distribution_info(dist_path: &str) -> Result<()> {
+/// Create a temporary directory to extract a distribution in.
+///
+/// The directory is created in `scratch_dir` if defined, otherwise in the
+/// system temporary directory.
+fn distribution_temp_dir(scratch_dir: Option<&Path>) -> Result<ScratchDir> {
+    let directories = BuildDirectories {
+        scratch_dir: scratch_dir.map(Path::to_path_buf),
+        ..BuildDirectories::default()
+    };
+    directories.validate()?;
+
+    directories.create(
+        DirectoryRole::Scratch,
+        "python-distribution",
+        &DirectoryUsage::default(),
+    )
+}
+
+pub fn python_distribution_info(dist_path: &str, scratch_dir: Option<&Path>) -> Result<()> {
     let fh = std::fs::File::open(Path::new(dist_path))?;
     let reader = std::io::BufReader::new(fh);
 
-    let temp_dir = tempdir::TempDir::new("python-distribution")?;
+    let temp_dir = distribution_temp_dir(scratch_dir)?;
     let temp_dir_path = temp_dir.path();
 
     let dist = StandaloneDistribution::from_tar_zst(reader, temp_dir_path)?;
@@ -293,11 +318,11 @@ pub fn python_distribution_info(dist_path: &str) -> Result<()> {
     Ok(())
 }
 
-pub fn python_distribution_licenses(path: &str) -> Result<()> {
+pub fn python_distribution_licenses(path: &str, scratch_dir: Option<&Path>) -> Result<()> {
     let fh = std::fs::File::open(Path::new(path))?;
     let reader = std::io::BufReader::new(fh);
 
-    let temp_dir = tempdir::TempDir::new("python-distribution")?;
+    let temp_dir = distribution_temp_dir(scratch_dir)?;
     let temp_dir_path = temp_dir.path();
 
     let dist = StandaloneDistribution::from_tar_zst(reader, temp_dir_path)?;
@@ -363,3 +388,30 @@ pub fn python_distribution_licenses(path: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// Verify the packed resources of an executable against a build manifest.
+pub fn verify_embedded_resources(
+    path: &str,
+    manifest_path: &str,
+    packed_resources_path: Option<&str>,
+) -> Result<()> {
+    let manifest = BuildManifest::from_path(Path::new(manifest_path))?;
+
+    match verify_executable_resources(
+        Path::new(path),
+        &manifest,
+        packed_resources_path.map(Path::new),
+    )? {
+        PackedResourcesLocation::Embedded(offset) => {
+            println!(
+                "packed resources at offset {} match {}",
+                offset, manifest_path
+            );
+        }
+        PackedResourcesLocation::External(path) => {
+            println!("{} matches {}", path.display(), manifest_path);
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,558 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Verdicts of external static analysis about packages.
+
+Static analyzers can know more than our heuristics about how packages
+behave. Their verdicts are read from a user provided JSON file and
+consulted ahead of built-in heuristics.
+
+The file has the following form:
+
+```json
+{
+    "version": 1,
+    "packages": {
+        "foo": {
+            "needs_filesystem": true,
+            "subinterpreter_safe": false
+        },
+        "foo.speedups": {
+            "license_override": ["MIT"]
+        },
+        "bar.*": {
+            "subinterpreter_safe": true
+        }
+    }
+}
+```
+
+Keys of `packages` are glob patterns matched against the names of
+resources and their parent packages. When several patterns define an
+attribute for a resource, the pattern matching the closest package wins,
+with ties broken by the sort order of the patterns. So `foo` applies to
+`foo.bar` unless a pattern matching `foo.bar` defines the attribute.
+
+The attributes are:
+
+`needs_filesystem` (bool)
+   Whether modules and package resources must be loaded from the
+   filesystem. Resources without an explicit location are installed on the
+   filesystem if the resources policy allows it. In-memory resources with
+   this verdict are reported like in-memory modules referencing `__file__`.
+   `false` waives modules referencing `__file__`.
+
+`license_override` (string or list of strings)
+   SPDX license identifiers replacing the licenses the Python distribution
+   declares for extension modules. These are used when filtering out GPL
+   licensed extension modules.
+
+`subinterpreter_safe` (bool)
+   Whether extension modules work in subinterpreters.
+
+`safe_lazy` verdicts are rejected: builds make no lazy import decisions
+for them to inform, and silently ignoring them would suggest otherwise.
+
+Verdicts disagreeing with built-in heuristics are reported as conflicts,
+but the verdict is followed. Patterns not matching any resource and
+attributes with unknown names are reported as well, so stale files are
+noticed.
+*/
+
+use {
+    super::config::{ConfigProblem, ConfigProblemSeverity},
+    super::dunder_file::DunderFileReport,
+    anyhow::{anyhow, Context, Result},
+    python_packaging::resource::PythonExtensionModuleVariants,
+    python_packaging::resource_collection::PrePackagedResource,
+    serde::Deserialize,
+    std::collections::{BTreeMap, BTreeSet},
+    std::path::Path,
+};
+
+/// Version of the verdicts file format.
+const VERDICTS_VERSION: u32 = 1;
+
+/// The contents of a verdicts file.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VerdictsFile {
+    version: u32,
+    packages: BTreeMap<String, BTreeMap<String, serde_json::Value>>,
+}
+
+/// Attributes of packages as determined by static analysis.
+///
+/// `None` means the analysis has no verdict.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageVerdict {
+    pub needs_filesystem: Option<bool>,
+    pub license_override: Option<Vec<String>>,
+    pub subinterpreter_safe: Option<bool>,
+}
+
+/// Verdicts of external static analysis, keyed by glob pattern.
+#[derive(Clone, Debug, Default)]
+pub struct AnalysisVerdicts {
+    entries: BTreeMap<String, (glob::Pattern, PackageVerdict)>,
+
+    /// Attributes with unknown names, as (pattern, attribute) tuples.
+    unknown_attributes: Vec<(String, String)>,
+}
+
+fn parse_bool(value: &serde_json::Value) -> Result<bool> {
+    value.as_bool().ok_or_else(|| anyhow!("expected a boolean"))
+}
+
+fn parse_licenses(value: &serde_json::Value) -> Result<Vec<String>> {
+    match value {
+        serde_json::Value::String(license) => Ok(vec![license.clone()]),
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|value| {
+                value
+                    .as_str()
+                    .map(|license| license.to_string())
+                    .ok_or_else(|| anyhow!("expected a list of strings"))
+            })
+            .collect(),
+        _ => Err(anyhow!("expected a string or a list of strings")),
+    }
+}
+
+impl AnalysisVerdicts {
+    /// Parse verdicts from JSON.
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        let file: VerdictsFile = serde_json::from_slice(data).context("parsing verdicts JSON")?;
+
+        if file.version != VERDICTS_VERSION {
+            return Err(anyhow!(
+                "unsupported verdicts version {}; expected {}",
+                file.version,
+                VERDICTS_VERSION
+            ));
+        }
+
+        let mut res = Self::default();
+
+        for (pattern, attributes) in file.packages {
+            let glob = glob::Pattern::new(&pattern)
+                .with_context(|| format!("parsing verdict pattern {}", pattern))?;
+            let mut verdict = PackageVerdict::default();
+
+            for (attribute, value) in &attributes {
+                let context = || format!("parsing {} verdict of {}", attribute, pattern);
+
+                match attribute.as_str() {
+                    "needs_filesystem" => {
+                        verdict.needs_filesystem = Some(parse_bool(value).with_context(context)?)
+                    }
+                    "safe_lazy" => {
+                        return Err(anyhow!(
+                            "safe_lazy verdict of {} is not supported: lazy imports aren't configurable",
+                            pattern
+                        ));
+                    }
+                    "license_override" => {
+                        verdict.license_override =
+                            Some(parse_licenses(value).with_context(context)?)
+                    }
+                    "subinterpreter_safe" => {
+                        verdict.subinterpreter_safe = Some(parse_bool(value).with_context(context)?)
+                    }
+                    _ => res
+                        .unknown_attributes
+                        .push((pattern.clone(), attribute.clone())),
+                }
+            }
+
+            res.entries.insert(pattern, (glob, verdict));
+        }
+
+        Ok(res)
+    }
+
+    /// Read verdicts from a file.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read(path).with_context(|| format!("reading verdicts {}", path.display()))?;
+
+        Self::from_json(&data).with_context(|| format!("parsing verdicts {}", path.display()))
+    }
+
+    /// Find the verdict for a resource from the closest package defining it.
+    fn lookup<'a, T>(
+        &'a self,
+        name: &str,
+        attribute: impl Fn(&'a PackageVerdict) -> Option<T>,
+    ) -> Option<T> {
+        let mut candidate = Some(name);
+
+        while let Some(current) = candidate {
+            for (pattern, verdict) in self.entries.values() {
+                if pattern.matches(current) {
+                    if let Some(value) = attribute(verdict) {
+                        return Some(value);
+                    }
+                }
+            }
+
+            candidate = current.rfind('.').map(|i| &current[0..i]);
+        }
+
+        None
+    }
+
+    /// Whether a resource must be loaded from the filesystem.
+    pub fn needs_filesystem(&self, name: &str) -> Option<bool> {
+        self.lookup(name, |verdict| verdict.needs_filesystem)
+    }
+
+    /// Licenses replacing those declared for a resource.
+    pub fn license_override(&self, name: &str) -> Option<&[String]> {
+        self.lookup(name, |verdict| verdict.license_override.as_deref())
+    }
+
+    /// Whether an extension module works in subinterpreters.
+    pub fn subinterpreter_safe(&self, name: &str) -> Option<bool> {
+        self.lookup(name, |verdict| verdict.subinterpreter_safe)
+    }
+
+    /// Replace the declared licenses of extension module variants.
+    ///
+    /// Returns the variants and descriptions of overrides disagreeing with
+    /// the declared licenses.
+    pub fn override_licenses(
+        &self,
+        variants: &PythonExtensionModuleVariants,
+    ) -> (PythonExtensionModuleVariants, BTreeSet<String>) {
+        let mut conflicts = BTreeSet::new();
+
+        let variants = variants
+            .iter()
+            .map(|em| match self.license_override(&em.name) {
+                Some(licenses) => {
+                    if em.license_public_domain == Some(true) {
+                        conflicts.insert(format!(
+                            "{}: license_override {} replaces public domain",
+                            em.name,
+                            licenses.join(", ")
+                        ));
+                    } else if em.licenses.as_deref() != Some(licenses) {
+                        conflicts.insert(format!(
+                            "{}: license_override {} replaces declared licenses {}",
+                            em.name,
+                            licenses.join(", "),
+                            match &em.licenses {
+                                Some(declared) => declared.join(", "),
+                                None => "(none)".to_string(),
+                            }
+                        ));
+                    }
+
+                    let mut em = em.clone();
+                    em.licenses = Some(licenses.to_vec());
+                    em.license_public_domain = None;
+                    em
+                }
+                None => em.clone(),
+            })
+            .collect();
+
+        (variants, conflicts)
+    }
+
+    /// Apply `needs_filesystem` verdicts to the results of verifying `__file__` usage.
+    ///
+    /// Modules referencing `__file__` with a `false` verdict are waived.
+    /// Returns descriptions of the waived modules.
+    pub fn waive_dunder_file(&self, report: &mut DunderFileReport) -> BTreeSet<String> {
+        let mut conflicts = BTreeSet::new();
+
+        for modules in report.offenders.values_mut() {
+            let waived = modules
+                .iter()
+                .filter(|name| self.needs_filesystem(name) == Some(false))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            for name in waived {
+                modules.remove(&name);
+                conflicts.insert(format!(
+                    "{}: needs_filesystem is false but the module references __file__",
+                    name
+                ));
+                report.waived.insert(name);
+            }
+        }
+
+        report.offenders.retain(|_, modules| !modules.is_empty());
+
+        conflicts
+    }
+
+    /// Find in-memory resources that must be loaded from the filesystem.
+    pub fn misplaced_resources<'a>(
+        &self,
+        resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+    ) -> BTreeSet<String> {
+        resources
+            .filter(|(name, resource)| {
+                self.needs_filesystem(name) == Some(true)
+                    && (resource.in_memory_source.is_some()
+                        || resource.in_memory_bytecode.is_some()
+                        || resource.in_memory_bytecode_opt1.is_some()
+                        || resource.in_memory_bytecode_opt2.is_some()
+                        || resource.in_memory_resources.is_some()
+                        || resource.in_memory_extension_module_shared_library.is_some())
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+
+    /// Find patterns not matching any of the given names or their parent packages.
+    pub fn unused_patterns<'a>(&self, names: impl Iterator<Item = &'a String>) -> Vec<String> {
+        let mut used = BTreeSet::new();
+
+        for name in names {
+            let mut candidate = Some(name.as_str());
+
+            while let Some(current) = candidate {
+                for (key, (pattern, _)) in &self.entries {
+                    if pattern.matches(current) {
+                        used.insert(key.clone());
+                    }
+                }
+
+                candidate = current.rfind('.').map(|i| &current[0..i]);
+            }
+        }
+
+        self.entries
+            .keys()
+            .filter(|key| !used.contains(*key))
+            .cloned()
+            .collect()
+    }
+
+    /// Attributes with unknown names, as (pattern, attribute) tuples.
+    pub fn unknown_attributes(&self) -> &[(String, String)] {
+        &self.unknown_attributes
+    }
+}
+
+/// Results of consulting analysis verdicts during a build.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerdictReport {
+    /// Descriptions of verdicts disagreeing with built-in heuristics.
+    pub conflicts: BTreeSet<String>,
+
+    /// In-memory resources with a verdict requiring the filesystem.
+    pub misplaced: BTreeSet<String>,
+
+    /// Patterns not matching any resource.
+    pub unused_patterns: Vec<String>,
+
+    /// Attributes with unknown names, as (pattern, attribute) tuples.
+    pub unknown_attributes: Vec<(String, String)>,
+}
+
+impl VerdictReport {
+    /// Convert the report to problems.
+    ///
+    /// Misplaced resources are reported with `severity`. Everything else is
+    /// a warning.
+    pub fn problems(&self, severity: ConfigProblemSeverity) -> Vec<ConfigProblem> {
+        let mut res = Vec::new();
+
+        if !self.misplaced.is_empty() {
+            res.push(ConfigProblem {
+                severity,
+                code: "analysis-needs-filesystem",
+                message: format!(
+                    "static analysis requires loading from the filesystem, but these are in memory: {}; load them from the filesystem",
+                    self.misplaced.iter().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            });
+        }
+
+        res.extend(self.conflicts.iter().map(|conflict| ConfigProblem {
+            severity: ConfigProblemSeverity::Warning,
+            code: "analysis-verdict-conflict",
+            message: format!("{}; following the analysis verdict", conflict),
+        }));
+
+        res.extend(self.unused_patterns.iter().map(|pattern| ConfigProblem {
+            severity: ConfigProblemSeverity::Warning,
+            code: "analysis-verdict-unused",
+            message: format!("verdict pattern {} doesn't match any resource", pattern),
+        }));
+
+        res.extend(
+            self.unknown_attributes
+                .iter()
+                .map(|(pattern, attribute)| ConfigProblem {
+                    severity: ConfigProblemSeverity::Warning,
+                    code: "analysis-verdict-unknown-attribute",
+                    message: format!("verdict of {} has unknown attribute {}", pattern, attribute),
+                }),
+        );
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*, python_packaging::resource::DataLocation,
+        python_packaging::resource::PythonExtensionModule,
+        python_packed_resources::data::ResourceFlavor, std::iter::FromIterator,
+    };
+
+    const VERDICTS: &[u8] = br#"{
+        "version": 1,
+        "packages": {
+            "foo": {"needs_filesystem": true},
+            "foo.inner": {"needs_filesystem": false},
+            "_ext": {"license_override": "MIT", "subinterpreter_safe": true},
+            "stale": {"needs_filesystem": true},
+            "typo": {"needs_filesytem": true}
+        }
+    }"#;
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let verdicts = AnalysisVerdicts::from_json(VERDICTS)?;
+
+        assert_eq!(verdicts.needs_filesystem("foo"), Some(true));
+        assert_eq!(verdicts.needs_filesystem("foo.bar"), Some(true));
+        assert_eq!(verdicts.needs_filesystem("foo.inner"), Some(false));
+        assert_eq!(verdicts.needs_filesystem("foo.inner.deep"), Some(false));
+        assert_eq!(verdicts.needs_filesystem("foobar"), None);
+        assert_eq!(
+            verdicts.license_override("_ext"),
+            Some(&["MIT".to_string()][..])
+        );
+        assert_eq!(verdicts.subinterpreter_safe("_ext"), Some(true));
+        assert_eq!(verdicts.subinterpreter_safe("foo"), None);
+
+        assert_eq!(
+            verdicts.unknown_attributes(),
+            &[("typo".to_string(), "needs_filesytem".to_string())]
+        );
+        assert_eq!(
+            verdicts.unused_patterns(["foo.bar".to_string(), "_ext".to_string()].iter()),
+            vec!["foo.inner", "stale", "typo"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        for data in &[
+            &br#"{"version": 2, "packages": {}}"#[..],
+            br#"{"version": 1, "packages": {}, "extra": 1}"#,
+            br#"{"version": 1, "packages": {"foo": {"needs_filesystem": "yes"}}}"#,
+            br#"{"version": 1, "packages": {"foo": {"license_override": [1]}}}"#,
+            br#"{"version": 1, "packages": {"[": {}}}"#,
+            br#"{"version": 1, "packages": {"foo": {"safe_lazy": true}}}"#,
+        ] {
+            assert!(AnalysisVerdicts::from_json(data).is_err());
+        }
+    }
+
+    #[test]
+    fn test_override_licenses() -> Result<()> {
+        let verdicts = AnalysisVerdicts::from_json(VERDICTS)?;
+
+        let variants = PythonExtensionModuleVariants::from_iter(vec![PythonExtensionModule {
+            name: "_ext".to_string(),
+            init_fn: Some("PyInit__ext".to_string()),
+            extension_file_suffix: ".so".to_string(),
+            shared_library: None,
+            object_file_data: vec![],
+            is_package: false,
+            link_libraries: vec![],
+            is_stdlib: true,
+            builtin_default: false,
+            required: false,
+            variant: None,
+            licenses: Some(vec!["GPL-3.0".to_string()]),
+            license_texts: None,
+            license_public_domain: None,
+        }]);
+
+        let (variants, conflicts) = verdicts.override_licenses(&variants);
+        assert_eq!(
+            variants.default_variant().licenses,
+            Some(vec!["MIT".to_string()])
+        );
+        assert_eq!(
+            conflicts.into_iter().collect::<Vec<_>>(),
+            vec!["_ext: license_override MIT replaces declared licenses GPL-3.0"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_placement() -> Result<()> {
+        let verdicts = AnalysisVerdicts::from_json(VERDICTS)?;
+
+        let mut report = DunderFileReport::default();
+        report.offenders.insert(
+            "foo".to_string(),
+            vec!["foo.inner".to_string(), "foo.other".to_string()]
+                .into_iter()
+                .collect(),
+        );
+
+        let conflicts = verdicts.waive_dunder_file(&mut report);
+        assert_eq!(
+            conflicts.into_iter().collect::<Vec<_>>(),
+            vec!["foo.inner: needs_filesystem is false but the module references __file__"]
+        );
+        assert_eq!(
+            report.offenders["foo"].iter().collect::<Vec<_>>(),
+            vec!["foo.other"]
+        );
+        assert!(report.waived.contains("foo.inner"));
+
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "foo.bar".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo.bar".to_string(),
+                in_memory_source: Some(DataLocation::Memory(b"import os".to_vec())),
+                ..PrePackagedResource::default()
+            },
+        );
+        resources.insert(
+            "foo.baz".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo.baz".to_string(),
+                relative_path_module_source: Some((
+                    "lib".to_string(),
+                    DataLocation::Memory(b"import os".to_vec()),
+                )),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        assert_eq!(
+            verdicts
+                .misplaced_resources(resources.iter())
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["foo.bar"]
+        );
+
+        Ok(())
+    }
+}
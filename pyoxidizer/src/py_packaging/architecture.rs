@@ -0,0 +1,476 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Resolve and validate the CPU architectures of binaries.
+
+Extension modules and the libraries they depend on are obtained from
+distributions, wheels, and build tools, which may have been built for a
+different machine than the one we're targeting. This module inspects the
+headers of ELF, PE, and Mach-O binaries (including fat Mach-O binaries
+containing multiple slices and static library archives) so architecture
+mismatches can be detected at build time instead of at run-time.
+*/
+
+use {
+    anyhow::{anyhow, Result},
+    goblin::mach::Mach,
+    goblin::Object,
+    itertools::Itertools,
+    python_packaging::resource::{DataLocation, PythonExtensionModule},
+    std::collections::BTreeSet,
+};
+
+/// A CPU architecture a binary can contain code for.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum Architecture {
+    X86,
+    X86_64,
+    Arm,
+    Aarch64,
+}
+
+impl std::fmt::Display for Architecture {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Architecture::X86 => "x86",
+            Architecture::X86_64 => "x86_64",
+            Architecture::Arm => "arm",
+            Architecture::Aarch64 => "aarch64",
+        })
+    }
+}
+
+fn format_architectures(architectures: &BTreeSet<Architecture>) -> String {
+    architectures.iter().map(|a| a.to_string()).join(", ")
+}
+
+/// Resolve the architectures a target triple requires binaries to contain.
+///
+/// The `universal2` architecture (e.g. `universal2-apple-darwin`) requires
+/// both `x86_64` and `aarch64` slices.
+pub fn target_architectures(target_triple: &str) -> Result<BTreeSet<Architecture>> {
+    let arch = target_triple.split('-').next().unwrap_or("");
+
+    Ok(match arch {
+        "x86_64" => vec![Architecture::X86_64],
+        "i386" | "i586" | "i686" => vec![Architecture::X86],
+        "aarch64" | "arm64" => vec![Architecture::Aarch64],
+        "universal2" => vec![Architecture::X86_64, Architecture::Aarch64],
+        _ if arch.starts_with("arm") || arch.starts_with("thumb") => vec![Architecture::Arm],
+        _ => {
+            return Err(anyhow!(
+                "unable to resolve architecture of target {}",
+                target_triple
+            ))
+        }
+    }
+    .into_iter()
+    .collect())
+}
+
+fn elf_architecture(machine: u16) -> Option<Architecture> {
+    match machine {
+        goblin::elf::header::EM_386 => Some(Architecture::X86),
+        goblin::elf::header::EM_X86_64 => Some(Architecture::X86_64),
+        goblin::elf::header::EM_ARM => Some(Architecture::Arm),
+        goblin::elf::header::EM_AARCH64 => Some(Architecture::Aarch64),
+        _ => None,
+    }
+}
+
+fn pe_architecture(machine: u16) -> Option<Architecture> {
+    match machine {
+        goblin::pe::header::COFF_MACHINE_X86 => Some(Architecture::X86),
+        goblin::pe::header::COFF_MACHINE_X86_64 => Some(Architecture::X86_64),
+        goblin::pe::header::COFF_MACHINE_ARMNT => Some(Architecture::Arm),
+        goblin::pe::header::COFF_MACHINE_ARM64 => Some(Architecture::Aarch64),
+        _ => None,
+    }
+}
+
+fn mach_architecture(cputype: u32) -> Option<Architecture> {
+    match cputype {
+        goblin::mach::cputype::CPU_TYPE_X86 => Some(Architecture::X86),
+        goblin::mach::cputype::CPU_TYPE_X86_64 => Some(Architecture::X86_64),
+        goblin::mach::cputype::CPU_TYPE_ARM => Some(Architecture::Arm),
+        goblin::mach::cputype::CPU_TYPE_ARM64 => Some(Architecture::Aarch64),
+        _ => None,
+    }
+}
+
+/// Resolve the architectures a binary contains code for.
+///
+/// Returns an empty set if the data isn't a binary format we know how to
+/// inspect. Fat Mach-O binaries return the architecture of every slice.
+/// Static library archives return the architectures of their members.
+pub fn binary_architectures(data: &[u8]) -> Result<BTreeSet<Architecture>> {
+    let mut res = BTreeSet::new();
+
+    let object = match Object::parse(data) {
+        Ok(object) => object,
+        Err(_) => return Ok(res),
+    };
+
+    match object {
+        Object::Elf(elf) => {
+            res.extend(elf_architecture(elf.header.e_machine));
+        }
+        Object::PE(pe) => {
+            res.extend(pe_architecture(pe.header.coff_header.machine));
+        }
+        Object::Mach(Mach::Binary(macho)) => {
+            res.extend(mach_architecture(macho.header.cputype));
+        }
+        Object::Mach(Mach::Fat(multi)) => {
+            for arch in multi.iter_arches() {
+                res.extend(mach_architecture(arch?.cputype));
+            }
+        }
+        Object::Archive(archive) => {
+            for member in archive.members() {
+                let member_data = archive.extract(member, data)?;
+                res.extend(binary_architectures(member_data)?);
+            }
+        }
+        Object::Unknown(_) => {}
+    }
+
+    Ok(res)
+}
+
+/// Verify a binary contains code for the architectures required by a target.
+///
+/// Binaries whose format we can't inspect are accepted. Fat binaries are
+/// accepted as long as they contain every slice the target requires.
+pub fn validate_binary_architecture(name: &str, data: &[u8], target_triple: &str) -> Result<()> {
+    let required = target_architectures(target_triple)?;
+    let available = binary_architectures(data)?;
+
+    if available.is_empty() || required.is_subset(&available) {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "{} contains architectures [{}] but target {} requires [{}]",
+            name,
+            format_architectures(&available),
+            target_triple,
+            format_architectures(&required)
+        ))
+    }
+}
+
+/// Extract the slice for an architecture from a fat Mach-O binary.
+///
+/// Returns `None` if the data isn't a fat Mach-O binary or the binary
+/// doesn't contain a slice for the architecture.
+pub fn thin_mach_o(data: &[u8], architecture: Architecture) -> Result<Option<Vec<u8>>> {
+    if let Ok(Object::Mach(Mach::Fat(multi))) = Object::parse(data) {
+        for arch in multi.iter_arches() {
+            let arch = arch?;
+
+            if mach_architecture(arch.cputype) == Some(architecture) {
+                return Ok(Some(arch.slice(data).to_vec()));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Obtain the binaries an extension module is composed of.
+///
+/// Each entry is a human readable name of the binary and its data location.
+fn extension_module_binaries(
+    extension_module: &PythonExtensionModule,
+) -> Vec<(String, &DataLocation)> {
+    let mut res = Vec::new();
+
+    if let Some(location) = &extension_module.shared_library {
+        res.push((
+            format!("extension module {} shared library", extension_module.name),
+            location,
+        ));
+    }
+
+    for (i, location) in extension_module.object_file_data.iter().enumerate() {
+        res.push((
+            format!(
+                "extension module {} object file {}",
+                extension_module.name, i
+            ),
+            location,
+        ));
+    }
+
+    for depends in &extension_module.link_libraries {
+        if let Some(location) = &depends.static_library {
+            res.push((
+                format!(
+                    "library {} static library (required by {})",
+                    depends.name, extension_module.name
+                ),
+                location,
+            ));
+        }
+
+        if let Some(location) = &depends.dynamic_library {
+            res.push((
+                format!(
+                    "library {} dynamic library (required by {})",
+                    depends.name, extension_module.name
+                ),
+                location,
+            ));
+        }
+    }
+
+    res
+}
+
+/// Verify all binaries of an extension module match the target architecture.
+///
+/// Targets with an unknown architecture are not validated.
+pub fn validate_extension_module_architecture(
+    extension_module: &PythonExtensionModule,
+    target_triple: &str,
+) -> Result<()> {
+    if target_architectures(target_triple).is_err() {
+        return Ok(());
+    }
+
+    for (name, location) in extension_module_binaries(extension_module) {
+        // Binaries can be large, so their content isn't cached.
+        validate_binary_architecture(&name, &location.resolve_uncached()?, target_triple)?;
+    }
+
+    Ok(())
+}
+
+/// Replace fat Mach-O binaries of an extension module with the slice for the target.
+///
+/// Targets requiring multiple or unknown architectures (e.g. `universal2`)
+/// are left alone. Returns `None` if nothing was thinned.
+pub fn thin_extension_module(
+    extension_module: &PythonExtensionModule,
+    target_triple: &str,
+) -> Result<Option<PythonExtensionModule>> {
+    let architecture = match target_architectures(target_triple) {
+        Ok(required) if required.len() == 1 => *required.iter().next().unwrap(),
+        _ => return Ok(None),
+    };
+
+    let thin = |location: &DataLocation| -> Result<Option<DataLocation>> {
+        Ok(thin_mach_o(&location.resolve_uncached()?, architecture)?.map(DataLocation::Memory))
+    };
+
+    let mut res = extension_module.clone();
+    let mut changed = false;
+
+    if let Some(location) = &extension_module.shared_library {
+        if let Some(location) = thin(location)? {
+            res.shared_library = Some(location);
+            changed = true;
+        }
+    }
+
+    for (i, location) in extension_module.object_file_data.iter().enumerate() {
+        if let Some(location) = thin(location)? {
+            res.object_file_data[i] = location;
+            changed = true;
+        }
+    }
+
+    for (i, depends) in extension_module.link_libraries.iter().enumerate() {
+        if let Some(location) = &depends.static_library {
+            if let Some(location) = thin(location)? {
+                res.link_libraries[i].static_library = Some(location);
+                changed = true;
+            }
+        }
+
+        if let Some(location) = &depends.dynamic_library {
+            if let Some(location) = thin(location)? {
+                res.link_libraries[i].dynamic_library = Some(location);
+                changed = true;
+            }
+        }
+    }
+
+    Ok(if changed { Some(res) } else { None })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        byteorder::{BigEndian, LittleEndian, WriteBytesExt},
+        python_packaging::resource::LibraryDependency,
+    };
+
+    /// Construct a minimal 64-bit Mach-O header for a CPU type.
+    fn macho(cputype: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(goblin::mach::header::MH_MAGIC_64)
+            .unwrap();
+        data.write_u32::<LittleEndian>(cputype).unwrap();
+        // cpusubtype, filetype, ncmds, sizeofcmds, flags, reserved.
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(goblin::mach::header::MH_BUNDLE)
+            .unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+        data.write_u32::<LittleEndian>(0).unwrap();
+
+        data
+    }
+
+    /// Construct a fat Mach-O binary from slices.
+    fn fat(slices: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.write_u32::<BigEndian>(goblin::mach::fat::FAT_MAGIC)
+            .unwrap();
+        data.write_u32::<BigEndian>(slices.len() as u32).unwrap();
+
+        let mut offset = 4096;
+        for slice in slices {
+            let cputype = u32::from_le_bytes([slice[4], slice[5], slice[6], slice[7]]);
+            data.write_u32::<BigEndian>(cputype).unwrap();
+            data.write_u32::<BigEndian>(0).unwrap();
+            data.write_u32::<BigEndian>(offset).unwrap();
+            data.write_u32::<BigEndian>(slice.len() as u32).unwrap();
+            data.write_u32::<BigEndian>(12).unwrap();
+            offset += 4096;
+        }
+
+        for slice in slices {
+            data.resize(((data.len() + 4095) / 4096) * 4096, 0);
+            data.extend_from_slice(slice);
+        }
+
+        data
+    }
+
+    fn extension_module(shared_library: Vec<u8>) -> PythonExtensionModule {
+        PythonExtensionModule {
+            name: "foo".to_string(),
+            init_fn: Some("PyInit_foo".to_string()),
+            extension_file_suffix: ".so".to_string(),
+            shared_library: Some(DataLocation::Memory(shared_library)),
+            object_file_data: vec![],
+            is_package: false,
+            link_libraries: vec![],
+            is_stdlib: false,
+            builtin_default: false,
+            required: false,
+            variant: None,
+            licenses: None,
+            license_texts: None,
+            license_public_domain: None,
+        }
+    }
+
+    #[test]
+    fn test_target_architectures() -> Result<()> {
+        assert_eq!(
+            target_architectures("aarch64-pc-windows-msvc")?,
+            vec![Architecture::Aarch64].into_iter().collect()
+        );
+        assert_eq!(
+            target_architectures("i686-pc-windows-msvc")?,
+            vec![Architecture::X86].into_iter().collect()
+        );
+        assert_eq!(
+            target_architectures("universal2-apple-darwin")?,
+            vec![Architecture::X86_64, Architecture::Aarch64]
+                .into_iter()
+                .collect()
+        );
+        assert!(target_architectures("wasm32-unknown-unknown").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fat_architectures() -> Result<()> {
+        let x86_64 = macho(goblin::mach::cputype::CPU_TYPE_X86_64);
+        let arm64 = macho(goblin::mach::cputype::CPU_TYPE_ARM64);
+        let universal2 = fat(&[x86_64.clone(), arm64.clone()]);
+
+        assert_eq!(
+            binary_architectures(&x86_64)?,
+            vec![Architecture::X86_64].into_iter().collect()
+        );
+        assert_eq!(
+            binary_architectures(&universal2)?,
+            vec![Architecture::X86_64, Architecture::Aarch64]
+                .into_iter()
+                .collect()
+        );
+
+        // Fat binaries are accepted as long as they contain the target slice.
+        validate_binary_architecture("foo", &universal2, "aarch64-apple-darwin")?;
+        validate_binary_architecture("foo", &universal2, "x86_64-apple-darwin")?;
+        validate_binary_architecture("foo", &universal2, "universal2-apple-darwin")?;
+
+        let err = validate_binary_architecture("foo", &x86_64, "universal2-apple-darwin")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "foo contains architectures [x86_64] but target universal2-apple-darwin requires [x86_64, aarch64]"
+        );
+
+        assert_eq!(
+            thin_mach_o(&universal2, Architecture::Aarch64)?,
+            Some(arm64)
+        );
+        assert_eq!(thin_mach_o(&universal2, Architecture::X86)?, None);
+        assert_eq!(thin_mach_o(&x86_64, Architecture::X86_64)?, None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_binary() -> Result<()> {
+        assert!(binary_architectures(b"not a binary")?.is_empty());
+        validate_binary_architecture("foo", b"not a binary", "aarch64-pc-windows-msvc")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_module_architecture() -> Result<()> {
+        let x86_64 = macho(goblin::mach::cputype::CPU_TYPE_X86_64);
+        let arm64 = macho(goblin::mach::cputype::CPU_TYPE_ARM64);
+
+        let mut em = extension_module(x86_64.clone());
+        em.link_libraries.push(LibraryDependency {
+            name: "bar".to_string(),
+            static_library: None,
+            dynamic_library: Some(DataLocation::Memory(arm64.clone())),
+            framework: false,
+            system: false,
+        });
+
+        let err = validate_extension_module_architecture(&em, "x86_64-apple-darwin")
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "library bar dynamic library (required by foo) contains architectures [aarch64] but target x86_64-apple-darwin requires [x86_64]"
+        );
+
+        let em = extension_module(fat(&[x86_64.clone(), arm64.clone()]));
+        validate_extension_module_architecture(&em, "aarch64-apple-darwin")?;
+
+        let thinned = thin_extension_module(&em, "aarch64-apple-darwin")?.unwrap();
+        assert_eq!(thinned.shared_library, Some(DataLocation::Memory(arm64)));
+        assert!(thin_extension_module(&em, "universal2-apple-darwin")?.is_none());
+        assert!(thin_extension_module(&extension_module(x86_64), "x86_64-apple-darwin")?.is_none());
+
+        Ok(())
+    }
+}
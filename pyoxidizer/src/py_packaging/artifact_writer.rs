@@ -0,0 +1,439 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Write build artifacts with progress reporting and cancellation.
+
+Artifacts like packed resources and libpython can be hundreds of megabytes.
+Writing them to slow filesystems, e.g. network mounts, can take minutes.
+`ArtifactWriter` writes artifacts in chunks. It sends `ArtifactEvent`s to an
+optional channel as it goes and checks a `CancellationToken` before every
+chunk.
+
+Artifacts are written to a temporary file next to their destination and
+renamed into place once complete, so a destination never holds a partially
+written artifact. A cancelled write leaves its temporary file behind.
+*/
+
+use {
+    anyhow::{anyhow, Context, Result},
+    serde::{Deserialize, Serialize},
+    std::fs::File,
+    std::io::Write,
+    std::path::{Path, PathBuf},
+    std::sync::atomic::{AtomicBool, Ordering},
+    std::sync::mpsc::Sender,
+    std::sync::Arc,
+    std::time::Instant,
+    uuid::Uuid,
+};
+
+/// Default number of bytes written between progress events.
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// The error of operations stopped by a `CancellationToken`.
+///
+/// It can be recovered from returned errors with `downcast_ref()`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "build cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Requests operations to stop.
+///
+/// Clones share state, so a clone can be handed to another thread to
+/// cancel operations checking the original.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Construct a token that isn't cancelled.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Request operations checking this token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Whether cancellation was requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Fail with `Cancelled` if cancellation was requested.
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(Cancelled.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// How long writing an artifact took.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ArtifactWriteTiming {
+    /// Name of the artifact, e.g. `packed resources`.
+    pub artifact: String,
+
+    /// Where the artifact was written.
+    pub path: PathBuf,
+
+    /// Size of the artifact in bytes.
+    pub bytes: u64,
+
+    /// Seconds spent writing the artifact, including renaming it into place.
+    pub seconds: f64,
+}
+
+impl ArtifactWriteTiming {
+    /// Bytes written per second.
+    ///
+    /// `None` if writing took no measurable time.
+    pub fn bytes_per_second(&self) -> Option<f64> {
+        if self.seconds > 0.0 {
+            Some(self.bytes as f64 / self.seconds)
+        } else {
+            None
+        }
+    }
+}
+
+/// Timings of the artifacts written by an `ArtifactWriter`, in write order.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ArtifactWriteProfile {
+    pub artifacts: Vec<ArtifactWriteTiming>,
+}
+
+impl ArtifactWriteProfile {
+    /// Total bytes of written artifacts.
+    pub fn total_bytes(&self) -> u64 {
+        self.artifacts.iter().map(|timing| timing.bytes).sum()
+    }
+
+    /// Total seconds spent writing artifacts.
+    pub fn total_seconds(&self) -> f64 {
+        self.artifacts.iter().map(|timing| timing.seconds).sum()
+    }
+}
+
+/// Progress of writing artifacts.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ArtifactEvent {
+    /// Writing an artifact started.
+    Started {
+        artifact: String,
+        path: PathBuf,
+        bytes: u64,
+    },
+
+    /// Some bytes of an artifact were written.
+    ///
+    /// `written` counts all bytes of the artifact written so far.
+    Progress {
+        artifact: String,
+        written: u64,
+        bytes: u64,
+    },
+
+    /// An artifact was written and renamed into place.
+    Finished(ArtifactWriteTiming),
+}
+
+/// Wraps the temporary files artifacts are written to.
+type FileWrapper = Box<dyn Fn(File) -> Box<dyn Write>>;
+
+/// Writes artifacts atomically, reporting progress and honoring cancellation.
+pub struct ArtifactWriter {
+    events: Option<Sender<ArtifactEvent>>,
+    cancellation: CancellationToken,
+    chunk_size: usize,
+    file_wrapper: Option<FileWrapper>,
+    profile: ArtifactWriteProfile,
+}
+
+impl Default for ArtifactWriter {
+    fn default() -> Self {
+        Self {
+            events: None,
+            cancellation: CancellationToken::default(),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            file_wrapper: None,
+            profile: ArtifactWriteProfile::default(),
+        }
+    }
+}
+
+impl ArtifactWriter {
+    /// Construct an instance sending events to `events` and checking `cancellation`.
+    pub fn new(events: Option<Sender<ArtifactEvent>>, cancellation: CancellationToken) -> Self {
+        Self {
+            events,
+            cancellation,
+            ..Self::default()
+        }
+    }
+
+    /// The token cancelling writes.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.cancellation
+    }
+
+    /// Set the number of bytes written between progress events and cancellation checks.
+    pub fn set_chunk_size(&mut self, chunk_size: usize) -> Result<()> {
+        if chunk_size == 0 {
+            return Err(anyhow!("artifact chunk size must be greater than 0"));
+        }
+
+        self.chunk_size = chunk_size;
+
+        Ok(())
+    }
+
+    /// Wrap the temporary files artifacts are written to.
+    ///
+    /// This allows e.g. throttling or counting writes.
+    pub fn set_file_wrapper<F>(&mut self, wrapper: F)
+    where
+        F: Fn(File) -> Box<dyn Write> + 'static,
+    {
+        self.file_wrapper = Some(Box::new(wrapper));
+    }
+
+    /// Timings of the artifacts written so far.
+    pub fn profile(&self) -> &ArtifactWriteProfile {
+        &self.profile
+    }
+
+    fn send(&self, event: ArtifactEvent) {
+        // Nobody listening isn't a reason to fail a build.
+        if let Some(events) = &self.events {
+            let _ = events.send(event);
+        }
+    }
+
+    /// Write an artifact to `path`.
+    ///
+    /// `artifact` names the artifact in events and timings. Fails with
+    /// `Cancelled` if cancellation is requested before the last chunk of the
+    /// artifact is written, in which case `path` is left untouched.
+    pub fn write(&mut self, artifact: &str, path: &Path, data: &[u8]) -> Result<()> {
+        self.cancellation.check()?;
+
+        let start = Instant::now();
+        let bytes = data.len() as u64;
+
+        self.send(ArtifactEvent::Started {
+            artifact: artifact.to_string(),
+            path: path.to_path_buf(),
+            bytes,
+        });
+
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{} has no file name", path.display()))?;
+        let temp_path = path.with_file_name(format!(
+            "{}.{}.tmp",
+            file_name.to_string_lossy(),
+            Uuid::new_v4()
+        ));
+
+        {
+            let fh = File::create(&temp_path)
+                .with_context(|| format!("creating {}", temp_path.display()))?;
+            let mut fh = match &self.file_wrapper {
+                Some(wrapper) => wrapper(fh),
+                None => Box::new(fh),
+            };
+
+            let mut written = 0;
+            for chunk in data.chunks(self.chunk_size) {
+                self.cancellation.check()?;
+
+                fh.write_all(chunk)
+                    .with_context(|| format!("writing {}", temp_path.display()))?;
+                written += chunk.len() as u64;
+
+                self.send(ArtifactEvent::Progress {
+                    artifact: artifact.to_string(),
+                    written,
+                    bytes,
+                });
+            }
+
+            fh.flush()
+                .with_context(|| format!("writing {}", temp_path.display()))?;
+        }
+
+        // The last chunk may have been written after cancellation was
+        // requested. The artifact is complete, so it is kept.
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("replacing {}", path.display()))?;
+
+        let timing = ArtifactWriteTiming {
+            artifact: artifact.to_string(),
+            path: path.to_path_buf(),
+            bytes,
+            seconds: start.elapsed().as_secs_f64(),
+        };
+        self.profile.artifacts.push(timing.clone());
+        self.send(ArtifactEvent::Finished(timing));
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::sync::mpsc::channel};
+
+    /// A writer accepting at most a few bytes per call, slowly.
+    ///
+    /// It cancels a token once `cancel_after` bytes were written.
+    struct ThrottledWriter {
+        inner: File,
+        written: usize,
+        cancel_after: Option<(usize, CancellationToken)>,
+    }
+
+    impl Write for ThrottledWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+
+            let count = self.inner.write(&buf[0..buf.len().min(4)])?;
+            self.written += count;
+
+            if let Some((limit, token)) = &self.cancel_after {
+                if self.written >= *limit {
+                    token.cancel();
+                }
+            }
+
+            Ok(count)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    fn throttled_writer(
+        events: Sender<ArtifactEvent>,
+        cancel_after: Option<usize>,
+    ) -> Result<ArtifactWriter> {
+        let token = CancellationToken::new();
+        let mut writer = ArtifactWriter::new(Some(events), token.clone());
+        writer.set_chunk_size(8)?;
+        writer.set_file_wrapper(move |inner| {
+            Box::new(ThrottledWriter {
+                inner,
+                written: 0,
+                cancel_after: cancel_after.map(|limit| (limit, token.clone())),
+            })
+        });
+
+        Ok(writer)
+    }
+
+    fn dir_entries(path: &Path) -> Result<Vec<String>> {
+        let mut names = std::fs::read_dir(path)?
+            .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+            .collect::<Result<Vec<_>>>()?;
+        names.sort();
+
+        Ok(names)
+    }
+
+    #[test]
+    fn test_write_events() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("packed-resources");
+        let (tx, rx) = channel();
+
+        let mut writer = throttled_writer(tx, None)?;
+        writer.write("packed resources", &path, &[42; 20])?;
+        drop(writer);
+
+        assert_eq!(std::fs::read(&path)?, vec![42; 20]);
+        assert_eq!(dir_entries(temp_dir.path())?, vec!["packed-resources"]);
+
+        let events = rx.iter().collect::<Vec<_>>();
+        assert_eq!(
+            events[0],
+            ArtifactEvent::Started {
+                artifact: "packed resources".to_string(),
+                path: path.clone(),
+                bytes: 20,
+            }
+        );
+        assert_eq!(
+            events[1..4]
+                .iter()
+                .map(|event| match event {
+                    ArtifactEvent::Progress { written, .. } => *written,
+                    _ => 0,
+                })
+                .collect::<Vec<_>>(),
+            vec![8, 16, 20]
+        );
+        match &events[4] {
+            ArtifactEvent::Finished(timing) => {
+                assert_eq!(timing.artifact, "packed resources");
+                assert_eq!(timing.bytes, 20);
+                // The throttled writer sleeps on every call.
+                assert!(timing.bytes_per_second().is_some());
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+        assert_eq!(events.len(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_cancelled() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let first = temp_dir.path().join("py-module-names");
+        let second = temp_dir.path().join("packed-resources");
+        let (tx, rx) = channel();
+
+        // Cancel once the second artifact is partially written.
+        let mut writer = throttled_writer(tx, Some(12))?;
+        writer.write("module names", &first, b"foo\n")?;
+        let err = writer
+            .write("packed resources", &second, &[42; 64])
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Cancelled>(), Some(&Cancelled));
+
+        // Only the temporary file of the cancelled artifact remains.
+        let entries = dir_entries(temp_dir.path())?;
+        assert_eq!(entries.len(), 2);
+        assert!(entries[0].starts_with("packed-resources.") && entries[0].ends_with(".tmp"));
+        assert_eq!(entries[1], "py-module-names");
+
+        // Nothing is written once cancelled.
+        assert!(writer
+            .write("config", &temp_dir.path().join("config.rs"), b"")
+            .is_err());
+        assert!(!temp_dir.path().join("config.rs").exists());
+
+        assert_eq!(writer.profile().artifacts.len(), 1);
+        drop(writer);
+        assert!(!rx.iter().any(
+            |event| matches!(event, ArtifactEvent::Finished(timing) if timing.path == second)
+        ));
+
+        Ok(())
+    }
+}
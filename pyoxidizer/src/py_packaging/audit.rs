@@ -0,0 +1,562 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Audit embedded distributions against known vulnerabilities.
+
+Vulnerability data is read from a user provided file in the OSV format
+(https://ossf.github.io/osv-schema/). Nothing is fetched from the network.
+Distributions are identified by the name and version in their METADATA
+and matched against `PyPI` entries of advisories using PEP 440 version
+ordering.
+
+A distribution not mentioned by the database produces no finding. This
+doesn't mean it is free of vulnerabilities, only that the database doesn't
+know about any.
+*/
+
+use {
+    super::config::{ConfigProblem, ConfigProblemSeverity},
+    anyhow::{anyhow, Context, Result},
+    python_packaging::package_metadata::{normalize_distribution_name, PythonPackageMetadata},
+    python_packaging::resource_collection::PrePackagedResource,
+    python_packaging::version::PythonVersion,
+    serde::Deserialize,
+    std::collections::BTreeSet,
+    std::path::Path,
+};
+
+/// The OSV ecosystem of Python distributions.
+const PYPI_ECOSYSTEM: &str = "PyPI";
+
+/// A package an OSV advisory applies to.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OsvPackage {
+    pub ecosystem: String,
+    pub name: String,
+}
+
+/// An event in an OSV version range.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OsvEvent {
+    pub introduced: Option<String>,
+    pub fixed: Option<String>,
+    pub last_affected: Option<String>,
+    pub limit: Option<String>,
+}
+
+/// A range of affected versions in an OSV advisory.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OsvRange {
+    #[serde(rename = "type")]
+    pub range_type: String,
+    pub events: Vec<OsvEvent>,
+}
+
+/// A severity score of an OSV advisory.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OsvSeverity {
+    #[serde(rename = "type")]
+    pub severity_type: String,
+    pub score: String,
+}
+
+/// A package affected by an OSV advisory.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OsvAffected {
+    pub package: OsvPackage,
+    pub ranges: Vec<OsvRange>,
+    pub versions: Vec<String>,
+    pub database_specific: Option<serde_json::Value>,
+}
+
+/// An advisory in the OSV format.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct OsvAdvisory {
+    pub id: String,
+    pub aliases: Vec<String>,
+    pub summary: Option<String>,
+    pub withdrawn: Option<String>,
+    pub severity: Vec<OsvSeverity>,
+    pub affected: Vec<OsvAffected>,
+    pub database_specific: Option<serde_json::Value>,
+}
+
+/// Parse OSV advisories.
+///
+/// Data can be a single advisory, an array of advisories or an object
+/// holding advisories in a `vulns` array, as returned by the OSV API.
+pub fn parse_osv_advisories(data: &[u8]) -> Result<Vec<OsvAdvisory>> {
+    let value: serde_json::Value = serde_json::from_slice(data).context("parsing OSV JSON")?;
+
+    let values = match value {
+        serde_json::Value::Array(values) => values,
+        serde_json::Value::Object(ref object) if object.contains_key("vulns") => {
+            match &object["vulns"] {
+                serde_json::Value::Array(values) => values.clone(),
+                _ => return Err(anyhow!("vulns is not an array")),
+            }
+        }
+        value => vec![value],
+    };
+
+    values
+        .into_iter()
+        .map(|value| {
+            let advisory: OsvAdvisory =
+                serde_json::from_value(value).context("parsing OSV advisory")?;
+
+            if advisory.id.is_empty() {
+                Err(anyhow!("OSV advisory has no id"))
+            } else {
+                Ok(advisory)
+            }
+        })
+        .collect()
+}
+
+/// Read OSV advisories from a file.
+pub fn load_osv_advisories(path: &Path) -> Result<Vec<OsvAdvisory>> {
+    let data = std::fs::read(path)
+        .with_context(|| format!("reading vulnerability database {}", path.display()))?;
+
+    parse_osv_advisories(&data)
+        .with_context(|| format!("parsing vulnerability database {}", path.display()))
+}
+
+/// A distribution affected by an advisory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditFinding {
+    /// Name of the distribution, as defined by its metadata.
+    pub distribution: String,
+    pub version: String,
+    /// Identifier of the advisory.
+    pub advisory: String,
+    /// Other identifiers of the advisory, like CVE IDs.
+    pub aliases: Vec<String>,
+    /// Severity of the advisory, if the database defines one.
+    pub severity: Option<String>,
+    pub summary: Option<String>,
+}
+
+/// Results of auditing distributions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditReport {
+    pub findings: Vec<AuditFinding>,
+
+    /// Descriptions of advisories that couldn't be evaluated.
+    ///
+    /// This happens when versions aren't valid PEP 440 versions.
+    pub unevaluated: Vec<String>,
+}
+
+impl AuditReport {
+    /// Convert the report to problems.
+    ///
+    /// Findings are reported with `severity`. Advisories that couldn't be
+    /// evaluated are always warnings.
+    pub fn problems(&self, severity: ConfigProblemSeverity) -> Vec<ConfigProblem> {
+        let mut res = self
+            .findings
+            .iter()
+            .map(|finding| {
+                let mut ids = vec![finding.advisory.clone()];
+                ids.extend(finding.aliases.iter().cloned());
+
+                ConfigProblem {
+                    severity,
+                    code: "vulnerable-distribution",
+                    message: format!(
+                        "{} {} is affected by {} (severity: {}){}; upgrade or remove it",
+                        finding.distribution,
+                        finding.version,
+                        ids.join(", "),
+                        finding.severity.as_deref().unwrap_or("unknown"),
+                        match &finding.summary {
+                            Some(summary) => format!(": {}", summary),
+                            None => "".to_string(),
+                        }
+                    ),
+                }
+            })
+            .collect::<Vec<_>>();
+
+        res.extend(self.unevaluated.iter().map(|message| ConfigProblem {
+            severity: ConfigProblemSeverity::Warning,
+            code: "vulnerability-audit-incomplete",
+            message: message.clone(),
+        }));
+
+        res
+    }
+}
+
+/// Obtain the severity of an advisory for an affected package.
+fn advisory_severity(advisory: &OsvAdvisory, affected: &OsvAffected) -> Option<String> {
+    let labeled = [&affected.database_specific, &advisory.database_specific]
+        .iter()
+        .filter_map(|value| value.as_ref())
+        .filter_map(|value| value.get("severity"))
+        .filter_map(|value| value.as_str())
+        .next();
+
+    match labeled {
+        Some(severity) => Some(severity.to_string()),
+        None => advisory
+            .severity
+            .first()
+            .map(|severity| format!("{} {}", severity.severity_type, severity.score)),
+    }
+}
+
+/// Whether a version is within an OSV range.
+///
+/// Events are applied in version order: `introduced` starts an affected
+/// span, `fixed` and `last_affected` end it. Versions at or above a
+/// `limit` are never affected.
+fn range_contains(range: &OsvRange, version: &PythonVersion) -> Result<bool> {
+    let parse = |v: &str| -> Result<Option<PythonVersion>> {
+        if v == "0" {
+            Ok(None)
+        } else {
+            PythonVersion::parse(v).map(Some)
+        }
+    };
+
+    let mut events = Vec::new();
+    let mut limits = Vec::new();
+
+    for event in &range.events {
+        if let Some(v) = &event.introduced {
+            events.push((parse(v)?, 0));
+        }
+        if let Some(v) = &event.fixed {
+            events.push((parse(v)?, 1));
+        }
+        if let Some(v) = &event.last_affected {
+            events.push((parse(v)?, 2));
+        }
+        if let Some(v) = &event.limit {
+            if let Some(limit) = parse(v)? {
+                limits.push(limit);
+            }
+        }
+    }
+
+    if !limits.is_empty() && limits.iter().all(|limit| version >= limit) {
+        return Ok(false);
+    }
+
+    // `None` sorts before everything, matching the meaning of "0".
+    events.sort();
+
+    let mut affected = false;
+    for (event_version, kind) in &events {
+        match (*kind, event_version) {
+            (0, None) => affected = true,
+            (0, Some(v)) if version >= v => affected = true,
+            (1, Some(v)) if version >= v => affected = false,
+            (2, Some(v)) if version > v => affected = false,
+            _ => {}
+        }
+    }
+
+    Ok(affected)
+}
+
+/// Whether an affected package entry applies to a version.
+fn affected_contains(affected: &OsvAffected, version: &str) -> Result<bool> {
+    // Explicitly listed versions only need to be equivalent.
+    let parsed = PythonVersion::parse(version);
+
+    for listed in &affected.versions {
+        let matches = match (&parsed, PythonVersion::parse(listed)) {
+            (Ok(a), Ok(b)) => *a == b,
+            _ => listed == version,
+        };
+
+        if matches {
+            return Ok(true);
+        }
+    }
+
+    let ecosystem_ranges = affected
+        .ranges
+        .iter()
+        .filter(|range| range.range_type == "ECOSYSTEM")
+        .collect::<Vec<_>>();
+
+    if ecosystem_ranges.is_empty() {
+        return Ok(false);
+    }
+
+    let parsed = parsed?;
+
+    for range in ecosystem_ranges {
+        if range_contains(range, &parsed)? {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Audit distributions against advisories.
+///
+/// `distributions` are pairs of distribution name and version.
+pub fn audit_distributions(
+    distributions: &[(String, String)],
+    advisories: &[OsvAdvisory],
+) -> AuditReport {
+    let mut report = AuditReport::default();
+
+    for (name, version) in distributions {
+        let normalized = normalize_distribution_name(name);
+
+        for advisory in advisories.iter().filter(|a| a.withdrawn.is_none()) {
+            for affected in advisory.affected.iter().filter(|affected| {
+                affected.package.ecosystem == PYPI_ECOSYSTEM
+                    && normalize_distribution_name(&affected.package.name) == normalized
+            }) {
+                match affected_contains(affected, version) {
+                    Ok(true) => {
+                        report.findings.push(AuditFinding {
+                            distribution: name.clone(),
+                            version: version.clone(),
+                            advisory: advisory.id.clone(),
+                            aliases: advisory.aliases.clone(),
+                            severity: advisory_severity(advisory, affected),
+                            summary: advisory.summary.clone(),
+                        });
+                        break;
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        report.unevaluated.push(format!(
+                            "unable to evaluate {} for {} {}: {}",
+                            advisory.id, name, version, e
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    report
+}
+
+/// Obtain the name and version of distributions in resources.
+///
+/// Distributions are identified by the METADATA or PKG-INFO file in their
+/// distribution resources. Resources without one are ignored.
+pub fn collected_distributions<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+) -> Result<Vec<(String, String)>> {
+    let mut res = BTreeSet::new();
+
+    for (name, resource) in resources {
+        for file in &["METADATA", "PKG-INFO"] {
+            let location = resource
+                .in_memory_distribution_resources
+                .as_ref()
+                .and_then(|resources| resources.get(*file))
+                .or_else(|| {
+                    resource
+                        .relative_path_distribution_resources
+                        .as_ref()
+                        .and_then(|resources| resources.get(*file))
+                        .map(|(_, location)| location)
+                });
+
+            if let Some(location) = location {
+                let data = location.resolve()?;
+                let metadata = PythonPackageMetadata::from_metadata(&data)
+                    .with_context(|| format!("parsing {} of {}", file, name))?;
+
+                if let (Some(name), Some(version)) = (metadata.name(), metadata.version()) {
+                    res.insert((name.to_string(), version.to_string()));
+                }
+
+                break;
+            }
+        }
+    }
+
+    Ok(res.into_iter().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packaging::resource::DataLocation};
+
+    const ADVISORIES: &str = r#"[
+        {
+            "id": "GHSA-1",
+            "aliases": ["CVE-2020-1"],
+            "summary": "remote code execution",
+            "affected": [{
+                "package": {"ecosystem": "PyPI", "name": "Foo_Bar"},
+                "ranges": [{
+                    "type": "ECOSYSTEM",
+                    "events": [{"introduced": "0"}, {"fixed": "1.2"}, {"introduced": "2.0a1"}, {"last_affected": "2.1"}]
+                }],
+                "database_specific": {"severity": "HIGH"}
+            }]
+        },
+        {
+            "id": "PYSEC-2",
+            "affected": [{
+                "package": {"ecosystem": "PyPI", "name": "foo-bar"},
+                "versions": ["3.0", "3.1"]
+            }],
+            "severity": [{"type": "CVSS_V3", "score": "CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H"}]
+        },
+        {
+            "id": "GHSA-3",
+            "withdrawn": "2020-01-01T00:00:00Z",
+            "affected": [{
+                "package": {"ecosystem": "PyPI", "name": "foo-bar"},
+                "ranges": [{"type": "ECOSYSTEM", "events": [{"introduced": "0"}]}]
+            }]
+        },
+        {
+            "id": "GHSA-4",
+            "affected": [{
+                "package": {"ecosystem": "npm", "name": "foo-bar"},
+                "ranges": [{"type": "ECOSYSTEM", "events": [{"introduced": "0"}]}]
+            }]
+        }
+    ]"#;
+
+    fn audit(version: &str) -> Result<AuditReport> {
+        let advisories = parse_osv_advisories(ADVISORIES.as_bytes())?;
+
+        Ok(audit_distributions(
+            &[("foo.bar".to_string(), version.to_string())],
+            &advisories,
+        ))
+    }
+
+    fn advisories(version: &str) -> Result<Vec<String>> {
+        Ok(audit(version)?
+            .findings
+            .into_iter()
+            .map(|finding| finding.advisory)
+            .collect())
+    }
+
+    #[test]
+    fn test_parse_osv_advisories() -> Result<()> {
+        assert_eq!(parse_osv_advisories(ADVISORIES.as_bytes())?.len(), 4);
+        assert_eq!(
+            parse_osv_advisories(br#"{"id": "GHSA-1"}"#)?[0].id,
+            "GHSA-1".to_string()
+        );
+        assert_eq!(
+            parse_osv_advisories(br#"{"vulns": [{"id": "GHSA-1"}, {"id": "GHSA-2"}]}"#)?.len(),
+            2
+        );
+        assert!(parse_osv_advisories(br#"{"summary": "no id"}"#).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_ranges() -> Result<()> {
+        assert_eq!(advisories("1.0")?, vec!["GHSA-1"]);
+        assert_eq!(advisories("1.2rc1")?, vec!["GHSA-1"]);
+        assert_eq!(advisories("1.2.dev0")?, vec!["GHSA-1"]);
+        assert!(advisories("1.2")?.is_empty());
+        assert!(advisories("1.2.post1")?.is_empty());
+        assert!(advisories("2.0.dev1")?.is_empty());
+        assert_eq!(advisories("2.0a1")?, vec!["GHSA-1"]);
+        assert_eq!(advisories("2.1")?, vec!["GHSA-1"]);
+        assert_eq!(advisories("2.1.0")?, vec!["GHSA-1"]);
+        assert!(advisories("2.1.post1")?.is_empty());
+        assert_eq!(advisories("3.0.0")?, vec!["PYSEC-2"]);
+        assert!(advisories("3.2")?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_audit_report() -> Result<()> {
+        let report = audit("1.0")?;
+        assert_eq!(
+            report.findings,
+            vec![AuditFinding {
+                distribution: "foo.bar".to_string(),
+                version: "1.0".to_string(),
+                advisory: "GHSA-1".to_string(),
+                aliases: vec!["CVE-2020-1".to_string()],
+                severity: Some("HIGH".to_string()),
+                summary: Some("remote code execution".to_string()),
+            }]
+        );
+
+        let problems = report.problems(ConfigProblemSeverity::Error);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, ConfigProblemSeverity::Error);
+        assert_eq!(
+            problems[0].message,
+            "foo.bar 1.0 is affected by GHSA-1, CVE-2020-1 (severity: HIGH): remote code execution; upgrade or remove it"
+        );
+
+        assert_eq!(
+            audit("3.1")?.findings[0].severity,
+            Some("CVSS_V3 CVSS:3.1/AV:N/AC:L/PR:N/UI:N/S:U/C:H/I:H/A:H".to_string())
+        );
+
+        // Versions that can't be compared are reported, not silently passed.
+        let report = audit("not-a-version")?;
+        assert!(report.findings.is_empty());
+        assert_eq!(report.unevaluated.len(), 1);
+        assert_eq!(
+            report.problems(ConfigProblemSeverity::Error)[0].severity,
+            ConfigProblemSeverity::Warning
+        );
+
+        // Distributions absent from the database have no findings.
+        let advisories = parse_osv_advisories(ADVISORIES.as_bytes())?;
+        let report = audit_distributions(&[("other".to_string(), "1.0".to_string())], &advisories);
+        assert_eq!(report, AuditReport::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collected_distributions() -> Result<()> {
+        let resource = PrePackagedResource {
+            name: "foo".to_string(),
+            in_memory_distribution_resources: Some(
+                vec![(
+                    "METADATA".to_string(),
+                    DataLocation::Memory(b"Name: Foo\nVersion: 1.0\n".to_vec()),
+                )]
+                .into_iter()
+                .collect(),
+            ),
+            ..PrePackagedResource::default()
+        };
+        let other = PrePackagedResource {
+            name: "bar".to_string(),
+            ..PrePackagedResource::default()
+        };
+
+        let resources = vec![("foo".to_string(), resource), ("bar".to_string(), other)];
+
+        assert_eq!(
+            collected_distributions(resources.iter().map(|(name, r)| (name, r)))?,
+            vec![("Foo".to_string(), "1.0".to_string())]
+        );
+
+        Ok(())
+    }
+}
@@ -7,24 +7,59 @@ Defining and manipulating binaries embedding Python.
 */
 
 use {
-    super::config::EmbeddedPythonConfig,
-    super::pyembed::{derive_python_config, write_default_python_config_rs},
+    super::artifact_writer::{ArtifactWriteProfile, ArtifactWriter},
+    super::audit::AuditReport,
+    super::build_cache::CacheStatistics,
+    super::build_clock::BuildClock,
+    super::build_directories::{DirectoryRole, ScratchDir},
+    super::bytecode_cache::BytecodeCache,
+    super::config::{ConfigProblemSeverity, EmbeddedPythonConfig, RuntimeEnvRegistry},
+    super::debug_companion::DebugCompanion,
+    super::distribution_coverage::DistributionCoverage,
+    super::entry_points::{ConsoleScript, EntryPointProvider},
+    super::install_layout::InstallLayout,
+    super::link_record::LinkRecord,
+    super::payload_storage::PayloadStorage,
+    super::pyembed::{
+        derive_python_config_settings, derive_runtime_env, ExternalPayloadsSelection,
+        ImporterConfig, ImporterFinder, PackedResourcesSource, PythonConfigSettings,
+        RecoverySelection,
+    },
+    super::resource_explanation::ResourceExplanation,
+    super::update_package::sha256_hex,
+    super::workspace_paths::{recorded_style, PathStyle, WorkspacePaths, WORKSPACE_ROOT_FILENAME},
     crate::app_packaging::resource::FileManifest,
-    anyhow::Result,
+    anyhow::{anyhow, Context, Result},
+    python_packaging::bytecode::CompilerPool,
+    python_packaging::filter_file::FilterFile,
+    python_packaging::layering::{LayerDeletion, LayerOverride, LayeredTree},
+    python_packaging::package_metadata::DirectUrl,
+    python_packaging::patch::PackagePatch,
     python_packaging::policy::PythonPackagingPolicy,
     python_packaging::resource::{
-        PythonExtensionModule, PythonModuleBytecodeFromSource, PythonModuleSource,
+        BytecodeOptimizationLevel, DigestAlgorithm, LibraryDependency, PythonExtensionModule,
+        PythonModuleBytecode, PythonModuleBytecodeFromSource, PythonModuleSource,
         PythonPackageDistributionResource, PythonPackageResource, PythonResource,
     },
-    python_packaging::resource_collection::{ConcreteResourceLocation, PrePackagedResource},
-    std::collections::HashMap,
-    std::fs::File,
-    std::io::Write,
+    python_packaging::resource_collection::{
+        BytecodeSizeReport, CollectedDistribution, CollectionMemoryStatistics, CompileReport,
+        ConcreteResourceLocation, PrePackagedResource, RecoverySetCost, RelocationReport,
+        ResourceSizeReport,
+    },
+    python_packaging::resource_usage::UsageReport,
+    python_packaging::wheel::WheelTags,
+    python_packed_resources::data::{ResourceFlavor, ResourceSet},
+    python_packed_resources::extract::PayloadKind,
+    serde::{Deserialize, Serialize},
+    std::collections::{BTreeMap, BTreeSet, HashMap},
+    std::io::{Cursor, Read, Write},
     std::path::{Path, PathBuf},
+    std::sync::Arc,
 };
 
 /// How a binary should link against libpython.
-#[derive(Clone, Copy, Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum LibpythonLinkMode {
     /// Libpython will be statically linked into the binary.
     Static,
@@ -32,6 +67,111 @@ pub enum LibpythonLinkMode {
     Dynamic,
 }
 
+/// The rule that determined how an extension module is packaged.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtensionModuleRule {
+    /// Standard library extension compiled into libpython by default.
+    StdlibBuiltinDefault,
+    /// Linking object files is the only way to package the extension.
+    BuiltinOnlyMechanism,
+    /// In-memory loading is preferred and the extension can be linked as a built-in.
+    PreferInMemoryBuiltin,
+    /// The extension's shared library is loaded from memory.
+    SharedLibraryInMemory,
+    /// The extension's shared library is installed next to the binary.
+    SharedLibraryFilesystem,
+}
+
+/// How an extension module ended up being packaged.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExtensionModuleOutcome {
+    /// Linked into libpython as a built-in.
+    Builtin,
+    /// Shared library embedded in the binary and loaded from memory.
+    InMemorySharedLibrary,
+    /// Shared library installed on the filesystem.
+    FilesystemSharedLibrary,
+}
+
+/// Records why an extension module was packaged the way it was.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ExtensionDecision {
+    /// Name of the extension module.
+    pub name: String,
+
+    /// How libpython is linked.
+    pub link_mode: LibpythonLinkMode,
+
+    /// Resources policy in effect.
+    pub resources_policy: String,
+
+    /// Location explicitly requested for the extension, if any.
+    pub requested_location: Option<String>,
+
+    /// Whether the extension is part of the standard library.
+    pub is_stdlib: bool,
+
+    /// Whether the distribution compiles the extension into libpython by default.
+    pub builtin_default: bool,
+
+    /// Whether the distribution requires the extension.
+    pub required: bool,
+
+    /// Whether object files are available for linking the extension.
+    pub has_object_files: bool,
+
+    /// Whether a shared library is available for the extension.
+    pub has_shared_library: bool,
+
+    /// Whether the distribution can load extension shared libraries from files.
+    pub can_load_standalone: bool,
+
+    /// Whether the binary can load extension shared libraries from memory.
+    pub can_load_dynamic_library_memory: bool,
+
+    /// The rule that was applied.
+    pub rule: ExtensionModuleRule,
+
+    /// The resulting packaging.
+    pub outcome: ExtensionModuleOutcome,
+
+    /// Variant of the extension module that was chosen.
+    #[serde(default)]
+    pub variant: Option<String>,
+
+    /// Variant pin of the packaging policy that selected `variant`, if any.
+    #[serde(default)]
+    pub variant_pin: Option<String>,
+}
+
+/// Records a package patch applied to collected resources.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AppliedPatch {
+    /// Name of the patch.
+    pub name: String,
+
+    /// Name of the patched distribution.
+    pub distribution: String,
+
+    /// Version of the patched distribution.
+    pub version: String,
+
+    /// Paths of patched files, relative to the distribution's root.
+    pub files: Vec<String>,
+}
+
+/// Python resources read from layered package roots.
+#[derive(Clone, Debug)]
+pub struct LayeredResources {
+    /// Resources of the merged layers.
+    pub resources: Vec<PythonResource>,
+
+    /// How the layers were merged, including files replaced or removed by later layers.
+    pub tree: LayeredTree,
+}
+
 /// Describes a generic way to build a Python binary.
 ///
 /// Binary here means an executable or library containing or linking to a
@@ -56,11 +196,25 @@ pub trait PythonBinaryBuilder {
     /// Obtain the `PythonResourcesPolicy` for the builder.
     fn python_packaging_policy(&self) -> &PythonPackagingPolicy;
 
-    /// Path to Python executable that can be used to derive info at build time.
+    /// Path to the Python executable of the distribution being embedded.
     ///
     /// The produced binary is effectively a clone of the Python distribution behind the
-    /// returned executable.
-    fn python_exe_path(&self) -> &Path;
+    /// returned executable. It may not be able to run on the build machine when
+    /// cross-compiling.
+    fn target_python_exe_path(&self) -> &Path;
+
+    /// Path to the Python executable run on the build machine to compile bytecode.
+    ///
+    /// This is `target_python_exe_path()` unless overridden with
+    /// `set_host_python_exe()`.
+    fn host_python_exe_path(&self) -> &Path;
+
+    /// Set the Python executable run on the build machine to compile bytecode.
+    ///
+    /// `None` compiles with the target distribution's executable. The
+    /// executable must write bytecode with the magic number of the target
+    /// distribution's Python version, which is verified by starting it.
+    fn set_host_python_exe(&mut self, python_exe: Option<&Path>) -> Result<()>;
 
     /// Obtain an iterator over all resource entries that will be embedded in the binary.
     ///
@@ -89,6 +243,37 @@ pub trait PythonBinaryBuilder {
         packages: &[String],
     ) -> Result<Vec<PythonResource>>;
 
+    /// Reads Python resources from directories layered on top of each other.
+    ///
+    /// Layers are ordered from the base to the topmost overlay. Files of
+    /// later layers replace files at the same path in earlier ones and
+    /// tombstones remove them, see `python_packaging::layering`. Resources
+    /// refer to the files of the layers providing them. Replaced and removed
+    /// files are recorded in the returned `LayeredTree`.
+    fn read_layered_package_roots(
+        &self,
+        logger: &slog::Logger,
+        layers: &[&Path],
+        packages: &[String],
+    ) -> Result<LayeredResources>;
+
+    /// Record how layered package roots were merged.
+    ///
+    /// Files replaced or removed by later layers are reported in the
+    /// embedded context and build manifest.
+    fn add_layered_tree(&mut self, tree: &LayeredTree) -> Result<()>;
+
+    /// Reads Python resources from a directory of bytecode-only distributions.
+    ///
+    /// Modules are reconstructed from `.pyc` files in `__pycache__` directories.
+    /// Bytecode must have been compiled for the target Python version.
+    fn read_bytecode_package_root(
+        &self,
+        logger: &slog::Logger,
+        path: &Path,
+        packages: &[String],
+    ) -> Result<Vec<PythonResource>>;
+
     /// Read Python resources from a populated virtualenv directory.
     fn read_virtualenv(&self, logger: &slog::Logger, path: &Path) -> Result<Vec<PythonResource>>;
 
@@ -104,6 +289,16 @@ pub trait PythonBinaryBuilder {
         extra_global_arguments: &[String],
     ) -> Result<Vec<PythonResource>>;
 
+    /// Resolve distribution version conflicts of resources obtained together.
+    ///
+    /// Resources should be passed through this before being added, so a
+    /// distribution already collected in another version is handled
+    /// according to the packaging policy. Returns the resources to add.
+    fn resolve_distribution_versions(
+        &mut self,
+        resources: Vec<PythonResource>,
+    ) -> Result<Vec<PythonResource>>;
+
     /// Add a `PythonModuleSource` to the resources collection.
     ///
     /// The location to load the resource from is optional. If specified, it
@@ -126,6 +321,38 @@ pub trait PythonBinaryBuilder {
         location: Option<ConcreteResourceLocation>,
     ) -> Result<()>;
 
+    /// Add bytecode of every optimization level compiled from a `PythonModuleSource`.
+    ///
+    /// The levels are compiled together, so the source is only parsed once
+    /// when the compiler supports it. The location semantics are those of
+    /// `add_python_module_bytecode_from_source()`.
+    fn add_python_module_bytecode_all_levels(
+        &mut self,
+        module: &PythonModuleSource,
+        location: Option<ConcreteResourceLocation>,
+    ) -> Result<()> {
+        for bytecode in
+            module.as_bytecode_modules(&BytecodeOptimizationLevel::all().collect::<Vec<_>>())
+        {
+            self.add_python_module_bytecode_from_source(&bytecode, location.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a `PythonModuleBytecode` to the resources collection.
+    ///
+    /// The bytecode must have been compiled for the target Python version.
+    ///
+    /// The location to load the resource from is optional. If specified, it will
+    /// be used. If not, an appropriate location based on the resources policy
+    /// will be chosen.
+    fn add_python_module_bytecode(
+        &mut self,
+        module: &PythonModuleBytecode,
+        location: Option<ConcreteResourceLocation>,
+    ) -> Result<()>;
+
     /// Add a `PythonPackageResource` to the resources collection.
     ///
     /// The location to load the resource from is optional. If specified, it will
@@ -162,8 +389,70 @@ pub trait PythonBinaryBuilder {
         location: Option<ConcreteResourceLocation>,
     ) -> Result<()>;
 
+    /// Add resources from a previously written packed resources blob.
+    ///
+    /// In-memory data is referenced from the blob without being copied.
+    /// Relative path resources are read from files relative to
+    /// `install_root`, which must be defined if such resources are present.
+    ///
+    /// The blob is validated against the Python distribution used by the
+    /// builder. `source_cache_tag` is the bytecode cache tag of the
+    /// distribution the blob was built with, as recorded in the manifest of
+    /// its build.
+    fn add_packed_resources(
+        &mut self,
+        data: &Arc<Vec<u8>>,
+        source_cache_tag: &str,
+        install_root: Option<&Path>,
+    ) -> Result<()>;
+
+    /// Add a shared library to be extracted to the filesystem at run-time.
+    ///
+    /// The library is embedded in the packed resources and written to the
+    /// configured extraction directory before Python is initialized, so
+    /// extension modules depending on it can be loaded.
+    fn add_extracted_shared_library(&mut self, library: &LibraryDependency) -> Result<()>;
+
+    /// Add a shared library file to be extracted to the filesystem at run-time.
+    ///
+    /// The library is named after the file name of `path`.
+    fn add_extracted_shared_library_path(&mut self, path: &Path) -> Result<()>;
+
+    /// Record that a resource depends on a shared library.
+    ///
+    /// Both the resource and the shared library must already be added.
+    fn add_shared_library_dependency(&mut self, name: &str, library: &str) -> Result<()>;
+
+    /// Register a patch to apply to files of a distribution.
+    ///
+    /// Patches are applied to collected module source and package resources
+    /// before bytecode is compiled, in the order they were registered. A
+    /// patch whose distribution isn't collected or whose version doesn't
+    /// match is skipped with a warning. A patch that doesn't apply is an error.
+    fn add_package_patch(&mut self, patch: PackagePatch) -> Result<()>;
+
+    /// Embed a recovery resource set next to the main one.
+    ///
+    /// The recovery set is loaded instead of the main set when `selection`
+    /// selects it at startup. Resources are added to it with
+    /// `add_recovery_resource()` under `policy`, which must keep resources
+    /// in memory. Built-in extension modules are available to both sets.
+    fn enable_recovery_set(
+        &mut self,
+        policy: &PythonPackagingPolicy,
+        selection: RecoverySelection,
+    ) -> Result<()>;
+
+    /// Add a resource to the recovery resource set.
+    ///
+    /// Only module source, bytecode and package resources and package
+    /// distribution resources can be added.
+    fn add_recovery_resource(&mut self, resource: &PythonResource) -> Result<()>;
+
     /// Filter embedded resources against names in files.
     ///
+    /// Only the main resource set is filtered.
+    ///
     /// `files` is files to read names from.
     ///
     /// `glob_patterns` is file patterns of files to read names from.
@@ -174,14 +463,198 @@ pub trait PythonBinaryBuilder {
         glob_patterns: &[&str],
     ) -> Result<()>;
 
+    /// Annotate resources as used or unused from a log of an instrumented run.
+    ///
+    /// The log records imported module names and accessed package resources.
+    /// Logs consumed by multiple calls are combined.
+    fn mark_used_from_log(&mut self, log: &Path) -> Result<UsageReport>;
+
+    /// Remove resources of the main resource set not marked as used by `mark_used_from_log()`.
+    ///
+    /// `protect` is glob patterns of resource names to keep regardless.
+    /// Modules to preload or run and extension modules required by the
+    /// distribution are always kept.
+    fn remove_unused(&mut self, logger: &slog::Logger, protect: &[&str]) -> Result<()>;
+
+    /// Compare standard library resources against the distribution.
+    ///
+    /// Reports which standard library modules and extension modules the
+    /// distribution provides are retained, grouped by top-level package.
+    fn compare_to_distribution(&self) -> Result<DistributionCoverage>;
+
+    /// Audit collected distributions against a file of OSV advisories.
+    ///
+    /// Distributions are identified by their METADATA. Those the database
+    /// doesn't mention produce no finding.
+    fn audit_vulnerabilities(&self, database: &Path) -> Result<AuditReport>;
+
+    /// Compute a filter file from a log of an instrumented run.
+    ///
+    /// The result keeps logged resources, their parent packages and shared
+    /// library dependencies and resources the binary can't run without. It
+    /// can be written out and consumed by `filter_resources_from_files()`.
+    fn filter_file_from_log(&self, log: &Path) -> Result<FilterFile>;
+
+    /// Compute a filter file from entry modules and what they import.
+    ///
+    /// Like `filter_file_from_log()` except kept modules are found by
+    /// scanning the source of entry modules for imports, transitively.
+    fn filter_file_from_entry_modules(&self, modules: &[&str]) -> Result<FilterFile>;
+
+    /// Write a payload of a resource to a file in a directory for inspection.
+    ///
+    /// The file is named after the resource and payload, e.g. source as
+    /// `<name>.py` and bytecode as a `<name>.pyc` file, compiling it if
+    /// necessary. Unknown names are an error suggesting similar names.
+    /// Returns the path of the written file.
+    fn extract_resource(&self, name: &str, payload: PayloadKind, dest: &Path) -> Result<PathBuf>;
+
+    /// Write a wheel of a package to a directory.
+    ///
+    /// The wheel holds the source and resources of `package` and its
+    /// subpackages and the metadata of the distribution owning them. If the
+    /// distribution has no metadata, `METADATA` declaring `version` is
+    /// generated. Returns the path of the written wheel.
+    fn write_wheel(
+        &self,
+        package: &str,
+        version: &str,
+        dest: &Path,
+        tags: WheelTags,
+    ) -> Result<PathBuf>;
+
+    /// Tag resources matching a glob pattern with a feature name.
+    ///
+    /// Tagged resources are only embedded if one of their tags is among the
+    /// features enabled in `to_embedded_python_context()`. Untagged
+    /// resources are always embedded. Returns the number of tagged resources.
+    fn tag_resources(&mut self, pattern: &str, tag: &str) -> Result<usize>;
+
+    /// Store the embedded payloads of resources compressed in packed resources.
+    ///
+    /// Payloads are compressed with zstd at `level` and decompressed by the
+    /// binary when loaded. Those that don't shrink are left alone. Returns
+    /// the number of bytes saved.
+    fn compress_resources(&mut self, names: &[&str], level: i32) -> Result<u64>;
+
+    /// Move all resources of a package between memory and the filesystem.
+    ///
+    /// Moving into memory is refused if a module uses `__file__` or an
+    /// extension module depends on shared libraries on the filesystem,
+    /// unless `force` is set. Nothing is moved when refusing. Resources that
+    /// can't be moved, such as extension modules the binary can't load from
+    /// the destination, are left behind and listed in the report.
+    fn relocate_package(
+        &mut self,
+        package: &str,
+        to: ConcreteResourceLocation,
+        force: bool,
+    ) -> Result<RelocationReport>;
+
+    /// Obtain the number of bytecode entries still waiting to be compiled from source.
+    fn pending_bytecode_count(&self) -> usize;
+
+    /// Compile all bytecode that is still derived from source.
+    ///
+    /// This allows compilation to be performed ahead of
+    /// `to_embedded_python_context()`. Entries that fail to compile remain
+    /// pending and are reported in the returned `CompileReport`, so the
+    /// operation can be retried. `pool` should spawn compilers with
+    /// `host_python_exe_path()`.
+    fn compile_pending_bytecode(&mut self, pool: &CompilerPool) -> Result<CompileReport>;
+
     /// Whether the binary requires the jemalloc library.
     fn requires_jemalloc(&self) -> bool;
 
+    /// Obtain records of how each extension module was packaged and why.
+    fn extension_decisions(&self) -> Vec<ExtensionDecision>;
+
+    /// Obtain statistics about payloads held in memory while collecting resources.
+    ///
+    /// Only available if the packaging policy defines a memory budget.
+    fn collection_memory_statistics(&self) -> Option<CollectionMemoryStatistics>;
+
+    /// Obtain the sizes of the payloads of added resources.
+    ///
+    /// File content isn't read, so this is cheap to call after adding
+    /// resources.
+    fn resource_size_report(&self) -> Result<ResourceSizeReport>;
+
+    /// Obtain the hex digests of the payloads of added resources.
+    ///
+    /// Keys name the resource and the payload, e.g. `foo.bar [source]`.
+    /// Manifests of equivalent builds are equal, so diffing them across
+    /// builds reveals nondeterminism. Bytecode still to be compiled from
+    /// source isn't hashed.
+    fn resource_digest_manifest(
+        &self,
+        algorithm: DigestAlgorithm,
+    ) -> Result<BTreeMap<String, String>>;
+
+    /// Explain where a resource came from, where it will live and what it depends on.
+    ///
+    /// `name` is a resource name or `<package>:<path>` for a package or
+    /// distribution resource. Returns `None` if nothing by that name was
+    /// added; `suggest_resource_names()` finds what was likely meant.
+    fn explain_resource(&self, name: &str) -> Option<ResourceExplanation>;
+
+    /// Obtain names `explain_resource()` knows that are similar to a name.
+    fn suggest_resource_names(&self, name: &str) -> Vec<String>;
+
+    /// Create a temporary directory for an operation.
+    ///
+    /// The directory is created in the build directory of `role` and is
+    /// removed according to the retention policy of the build directories.
+    fn scratch_dir(&self, role: DirectoryRole, prefix: &str) -> Result<ScratchDir>;
+
+    /// Obtain the source of times recorded by the build.
+    fn build_clock(&self) -> BuildClock;
+
+    /// Set the source of times recorded by the build.
+    fn set_build_clock(&mut self, clock: BuildClock);
+
+    /// Obtain the cache bytecode compiled from source is reused from, if any.
+    fn bytecode_cache(&self) -> Option<&BytecodeCache>;
+
+    /// Set the cache bytecode compiled from source is reused from.
+    ///
+    /// `None` compiles all bytecode from source.
+    fn set_bytecode_cache(&mut self, cache: Option<BytecodeCache>);
+
+    /// Obtain hit and miss counts of bytecode cache lookups so far.
+    fn bytecode_cache_statistics(&self) -> CacheStatistics;
+
+    /// Obtain where payloads of packed resources are stored.
+    fn payload_storage(&self) -> &PayloadStorage;
+
+    /// Set where payloads of packed resources are stored.
+    ///
+    /// Storage other than `PayloadStorage::embedded()` produces binaries
+    /// that obtain payloads at run-time.
+    fn set_payload_storage(&mut self, storage: PayloadStorage);
+
+    /// Obtain bytes written to temporary directories so far, by role.
+    ///
+    /// Directories still in use are counted once they are released.
+    fn directory_bytes_written(&self) -> BTreeMap<DirectoryRole, u64>;
+
+    /// Resolve the directories and files installed next to the binary.
+    ///
+    /// This covers resources at relative paths and other extra files of the
+    /// `EmbeddedPythonContext` produced for the same enabled features. Paths
+    /// follow the scheme documented in the `install_layout` module and are
+    /// stable given the same resources and settings. Nothing is written.
+    fn resolve_install_layout(&self, enabled_features: &BTreeSet<String>) -> Result<InstallLayout>;
+
     /// Obtain an `EmbeddedPythonContext` instance from this one.
+    ///
+    /// Resources tagged with features are only included if one of their
+    /// tags is in `enabled_features`.
     fn to_embedded_python_context(
         &self,
         logger: &slog::Logger,
         opt_level: &str,
+        enabled_features: &BTreeSet<String>,
     ) -> Result<EmbeddedPythonContext>;
 }
 
@@ -206,15 +679,201 @@ pub struct PythonLinkingInfo {
 
     /// Lines that need to be emitted from a Cargo build script.
     pub cargo_metadata: Vec<String>,
+
+    /// Describes the object files and libraries linked into `libpythonxy_data`.
+    ///
+    /// Empty when libpython isn't statically linked.
+    pub link_record: LinkRecord,
+}
+
+/// Keys of `cargo:KEY=VALUE` lines whose value is a path.
+const PATH_VALUED_CARGO_KEYS: &[&str] = &["default-python-config-rs", "rerun-if-changed"];
+
+/// Kinds of `cargo:rustc-link-search=KIND=PATH` lines.
+const LINK_SEARCH_KINDS: &[&str] = &["dependency", "crate", "native", "framework", "all"];
+
+/// Kinds of `cargo:rustc-link-lib=KIND=NAME` lines.
+const LINK_LIB_KINDS: &[&str] = &["static", "dylib", "framework"];
+
+/// Split the optional `KIND=` prefix off the value of a link directive.
+///
+/// Only known kinds are split off, so values containing `=` are kept
+/// intact. Link modifiers like `static:+whole-archive` are part of the kind.
+fn split_link_kind<'a>(value: &'a str, kinds: &[&str]) -> (Option<String>, &'a str) {
+    if let Some(pos) = value.find('=') {
+        let kind = &value[0..pos];
+
+        if kinds.contains(&kind.split(':').next().unwrap_or(kind)) {
+            return (Some(kind.to_string()), &value[pos + 1..]);
+        }
+    }
+
+    (None, value)
+}
+
+/// A line emitted by a Cargo build script.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CargoMetadataEntry {
+    /// `cargo:rustc-link-search=[KIND=]PATH`
+    LinkSearch { kind: Option<String>, path: PathBuf },
+
+    /// `cargo:rustc-link-lib=[KIND=]NAME`
+    LinkLib { kind: Option<String>, name: String },
+
+    /// Any other `cargo:KEY=VALUE` line.
+    Other { key: String, value: String },
+}
+
+impl CargoMetadataEntry {
+    /// Parse a `cargo:` line into an instance.
+    pub fn parse(line: &str) -> Result<Self> {
+        if !line.starts_with("cargo:") {
+            return Err(anyhow!(
+                "cargo metadata line missing cargo: prefix: {}",
+                line
+            ));
+        }
+
+        let mut parts = line["cargo:".len()..].splitn(2, '=');
+        let key = parts.next().unwrap_or("");
+        let value = parts
+            .next()
+            .ok_or_else(|| anyhow!("cargo metadata line missing value: {}", line))?;
+
+        Ok(match key {
+            "rustc-link-search" => {
+                let (kind, path) = split_link_kind(value, LINK_SEARCH_KINDS);
+
+                CargoMetadataEntry::LinkSearch {
+                    kind,
+                    path: PathBuf::from(path),
+                }
+            }
+            "rustc-link-lib" => {
+                let (kind, name) = split_link_kind(value, LINK_LIB_KINDS);
+
+                CargoMetadataEntry::LinkLib {
+                    kind,
+                    name: name.to_string(),
+                }
+            }
+            _ => CargoMetadataEntry::Other {
+                key: key.to_string(),
+                value: value.to_string(),
+            },
+        })
+    }
+
+    /// The filesystem path this entry references, if any.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            CargoMetadataEntry::LinkSearch { path, .. } => Some(path),
+            CargoMetadataEntry::Other { key, value }
+                if PATH_VALUED_CARGO_KEYS.contains(&key.as_str()) =>
+            {
+                Some(Path::new(value))
+            }
+            _ => None,
+        }
+    }
+
+    /// Obtain this entry with the path it references replaced.
+    fn with_path(&self, path: PathBuf) -> Self {
+        match self {
+            CargoMetadataEntry::LinkSearch { kind, .. } => CargoMetadataEntry::LinkSearch {
+                kind: kind.clone(),
+                path,
+            },
+            CargoMetadataEntry::Other { key, .. } if self.path().is_some() => {
+                CargoMetadataEntry::Other {
+                    key: key.clone(),
+                    value: format!("{}", path.display()),
+                }
+            }
+            entry => entry.clone(),
+        }
+    }
+
+    /// Whether this entry is satisfied by a possibly rewritten entry.
+    ///
+    /// Library search paths may be rewritten into a relative form, so a
+    /// relative path matching the end of our path is accepted.
+    fn satisfied_by(&self, other: &CargoMetadataEntry) -> bool {
+        match (self, other) {
+            (
+                CargoMetadataEntry::LinkSearch { path, .. },
+                CargoMetadataEntry::LinkSearch { path: other, .. },
+            ) => path == other || (other.is_relative() && path.ends_with(other)),
+            (
+                CargoMetadataEntry::LinkLib { name, .. },
+                CargoMetadataEntry::LinkLib { name: other, .. },
+            ) => name == other,
+            _ => self == other,
+        }
+    }
+}
+
+impl std::fmt::Display for CargoMetadataEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CargoMetadataEntry::LinkSearch { kind, path } => match kind {
+                Some(kind) => write!(f, "cargo:rustc-link-search={}={}", kind, path.display()),
+                None => write!(f, "cargo:rustc-link-search={}", path.display()),
+            },
+            CargoMetadataEntry::LinkLib { kind, name } => match kind {
+                Some(kind) => write!(f, "cargo:rustc-link-lib={}={}", kind, name),
+                None => write!(f, "cargo:rustc-link-lib={}", name),
+            },
+            CargoMetadataEntry::Other { key, value } => write!(f, "cargo:{}={}", key, value),
+        }
+    }
+}
+
+/// Read Cargo metadata written by `EmbeddedPythonContext::write_files()`.
+///
+/// Relative paths are re-anchored against the root of `paths`. Metadata
+/// mixing absolute and relative paths is rejected.
+pub fn load_cargo_metadata(path: &Path, paths: &WorkspacePaths) -> Result<Vec<CargoMetadataEntry>> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+    let entries = data
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(CargoMetadataEntry::parse)
+        .collect::<Result<Vec<_>>>()?;
+
+    let lines = entries
+        .iter()
+        .map(|entry| entry.to_string())
+        .collect::<Vec<_>>();
+    recorded_style(
+        entries
+            .iter()
+            .zip(lines.iter())
+            .filter_map(|(entry, line)| entry.path().map(|path| (line.as_str(), path))),
+    )
+    .with_context(|| format!("loading {}", path.display()))?;
+
+    Ok(entries
+        .iter()
+        .map(|entry| match entry.path() {
+            Some(path) => entry.with_path(paths.anchor(path)),
+            None => entry.clone(),
+        })
+        .collect())
 }
 
 /// Holds filesystem paths to resources required to build a binary embedding Python.
+#[derive(Debug)]
 pub struct EmbeddedPythonPaths {
     /// File containing a list of module names.
     pub module_names: PathBuf,
 
     /// File containing embedded resources data.
-    pub embedded_resources: PathBuf,
+    ///
+    /// `None` if packed resources are placed in a linker section.
+    pub embedded_resources: Option<PathBuf>,
 
     /// Path to library containing libpython.
     pub libpython: PathBuf,
@@ -225,8 +884,115 @@ pub struct EmbeddedPythonPaths {
     /// Path to `config.rs` derived from a `EmbeddedPythonConfig`.
     pub config_rs: PathBuf,
 
+    /// The importer settings rendered into `config_rs`.
+    pub importer: ImporterConfig,
+
     /// Path to a file containing lines needed to be emitted by a Cargo build script.
     pub cargo_metadata: PathBuf,
+
+    /// The lines written to `cargo_metadata`.
+    pub cargo_metadata_lines: Vec<String>,
+
+    /// Path to a JSON file describing the inputs linked into `libpython`.
+    pub link_record: PathBuf,
+
+    /// How long writing each file took.
+    pub write_profile: ArtifactWriteProfile,
+}
+
+/// Alignment in bytes packed resources data must have.
+///
+/// On Windows, paths are decoded from packed resources as UTF-16 in place.
+/// Heap buffers the data is normally parsed from are at least this aligned.
+pub const PACKED_RESOURCES_ALIGNMENT: usize = 8;
+
+/// How packed resources data is embedded in a binary.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case", tag = "mode")]
+pub enum PackedResourcesEmbedding {
+    /// A `packed-resources` file is written and embedded with `include_bytes!`.
+    File,
+
+    /// The binary's build places the data in a linker section.
+    ///
+    /// No file is written. The generated config references the data by
+    /// `symbol`, which the build must define at the start of the data.
+    LinkerSection { symbol: String },
+}
+
+impl Default for PackedResourcesEmbedding {
+    fn default() -> Self {
+        PackedResourcesEmbedding::File
+    }
+}
+
+impl PackedResourcesEmbedding {
+    /// Ensure a linker section symbol can be referenced from Rust.
+    fn validate(&self) -> Result<()> {
+        match self {
+            PackedResourcesEmbedding::File => Ok(()),
+            PackedResourcesEmbedding::LinkerSection { symbol } => {
+                if symbol.is_empty()
+                    || !symbol
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+                {
+                    Err(anyhow!("invalid packed resources symbol: {:?}", symbol))
+                } else {
+                    Ok(())
+                }
+            }
+        }
+    }
+}
+
+/// Packed resources data and what is needed to embed it.
+///
+/// The data is borrowed from an `EmbeddedPythonContext`, so it is never
+/// copied. Build scripts can stream it into a file included by assembly or
+/// a linker script.
+#[derive(Clone, Debug)]
+pub struct PackedResourcesBlob<'a> {
+    data: &'a [u8],
+
+    /// Alignment in bytes the data must have.
+    pub alignment: usize,
+
+    /// Hex encoded SHA-256 of the data.
+    pub sha256: String,
+}
+
+impl<'a> PackedResourcesBlob<'a> {
+    /// Size of the data in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Whether the data is empty.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Obtain a reader of the data.
+    pub fn reader(&self) -> impl Read + 'a {
+        Cursor::new(self.data)
+    }
+
+    /// Write the data to a writer.
+    ///
+    /// Returns the number of bytes written.
+    pub fn write_to(&self, writer: &mut dyn Write) -> Result<u64> {
+        Ok(std::io::copy(&mut self.reader(), writer)?)
+    }
+
+    /// Ensure embedded data matches this data.
+    pub fn verify(&self, data: &[u8]) -> Result<()> {
+        if data.len() != self.data.len() || sha256_hex(data) != self.sha256 {
+            Err(anyhow!("embedded packed resources do not match the build"))
+        } else {
+            Ok(())
+        }
+    }
 }
 
 /// Holds context necessary to embed Python in a binary.
@@ -238,35 +1004,309 @@ pub struct EmbeddedPythonContext {
     pub linking_info: PythonLinkingInfo,
 
     /// Newline delimited list of module names in resources.
+    ///
+    /// Names in `hidden_module_names` are absent.
     pub module_names: Vec<u8>,
 
+    /// Names of internal synthetic resources left out of `module_names`.
+    ///
+    /// These are still in `resources`.
+    pub hidden_module_names: BTreeSet<String>,
+
     /// Python resources to embed in the binary.
     pub resources: Vec<u8>,
 
+    /// How `resources` is embedded in the binary.
+    pub packed_resources_embedding: PackedResourcesEmbedding,
+
+    /// Names of extension modules compiled into the binary as built-ins.
+    ///
+    /// These are the extension modules registered in the interpreter's
+    /// inittab.
+    pub builtin_extension_module_names: BTreeSet<String>,
+
+    /// How each extension module in resources was packaged and why.
+    pub extension_decisions: Vec<ExtensionDecision>,
+
+    /// Features that were enabled when selecting tagged resources.
+    pub enabled_features: BTreeSet<String>,
+
+    /// Package patches applied to resources.
+    pub applied_patches: Vec<AppliedPatch>,
+
+    /// Console scripts of distributions in the binary.
+    ///
+    /// Only present if the packaging policy describes console scripts.
+    pub console_scripts: Vec<ConsoleScript>,
+
+    /// Files of layered package roots replaced by later layers.
+    pub layer_overrides: Vec<LayerOverride>,
+
+    /// Files of layered package roots removed by tombstones of later layers.
+    pub layer_deletions: Vec<LayerDeletion>,
+
+    /// Sizes of compiled bytecode of modules in `resources` and `extra_files`.
+    ///
+    /// Names in `hidden_module_names` are absent.
+    pub bytecode_sizes: BytecodeSizeReport,
+
+    /// Labels of payloads classified as tests, by resource name.
+    ///
+    /// Only resources in the binary are present.
+    pub test_payloads: BTreeMap<String, BTreeSet<String>>,
+
+    /// Entry points kept in the binary because modules load their group.
+    ///
+    /// Only present if the packaging policy protects entry point providers.
+    pub entry_point_protections: Vec<EntryPointProvider>,
+
     /// Extra files to install next to produced binary.
     pub extra_files: FileManifest,
 
+    /// Directories and files installed next to the produced binary.
+    ///
+    /// This describes `extra_files`.
+    pub install_layout: InstallLayout,
+
     /// Rust target triple for the host we are running on.
     pub host_triple: String,
 
     /// Rust target triple for the target we are building for.
     pub target_triple: String,
+
+    /// Bytecode cache tag of the Python distribution, e.g. `cpython-38`.
+    pub cache_tag: String,
+
+    /// When the build ran, in seconds since the UNIX epoch.
+    ///
+    /// This comes from the builder's `BuildClock`.
+    pub build_time: u64,
+
+    /// Files to debug the build, if the packaging policy asked for them.
+    ///
+    /// This is not part of the build. Write it with
+    /// `DebugCompanion::write_archive()`.
+    pub debug_companion: Option<DebugCompanion>,
+
+    /// How the recovery resource set is selected, if packed resources have one.
+    pub recovery_selection: Option<RecoverySelection>,
+
+    /// What the recovery resource set adds to packed resources, if there is one.
+    pub recovery_set_cost: Option<RecoverySetCost>,
+
+    /// Name of the storage backend that received packed resources payloads.
+    pub payload_backend: String,
+
+    /// How the generated config obtains payloads stored outside of `resources`.
+    ///
+    /// `None` if `resources` is self-contained.
+    pub external_payloads: Option<ExternalPayloadsSelection>,
+
+    /// Where distributions installed from a direct URL came from, by package name.
+    ///
+    /// Only resources in the binary are present.
+    pub direct_urls: BTreeMap<String, DirectUrl>,
+
+    /// Distributions whose resources are in the binary, by normalized name.
+    pub distributions: BTreeMap<String, CollectedDistribution>,
+
+    /// Scratch directories holding content referenced by `extra_files`.
+    ///
+    /// e.g. pinned file content spilled to disk. They are released when
+    /// the context is dropped.
+    pub scratch_dirs: Vec<ScratchDir>,
 }
 
 impl EmbeddedPythonContext {
     /// Write out files needed to link a binary.
-    pub fn write_files(&self, dest_dir: &Path) -> Result<EmbeddedPythonPaths> {
+    ///
+    /// `paths` controls whether paths in written files are absolute or
+    /// relative to a workspace root. `dest_dir` must be under the root if
+    /// paths are relative.
+    pub fn write_files(
+        &self,
+        dest_dir: &Path,
+        paths: &WorkspacePaths,
+    ) -> Result<EmbeddedPythonPaths> {
+        self.write_files_with_writer(dest_dir, paths, &mut ArtifactWriter::default())
+    }
+
+    /// Write out files needed to link a binary using an `ArtifactWriter`.
+    ///
+    /// `writer` reports progress of each file and stops writing if
+    /// cancelled. Files are replaced atomically, so a cancelled write
+    /// leaves previously written files intact. `paths` behaves as with
+    /// `write_files()`.
+    pub fn write_files_with_writer(
+        &self,
+        dest_dir: &Path,
+        paths: &WorkspacePaths,
+        writer: &mut ArtifactWriter,
+    ) -> Result<EmbeddedPythonPaths> {
+        self.write_artifacts(dest_dir, paths, false, |_| Ok(()), |_| Ok(()), writer)
+    }
+
+    /// Write out files needed to link a binary, adjusting Cargo metadata first.
+    ///
+    /// `hook` receives the Cargo metadata entries before they are rendered
+    /// and can filter, rewrite, or append entries. Removing the
+    /// `libpythonXY` search path or link directive is an error unless
+    /// `allow_removing_required` is set.
+    pub fn write_files_with_cargo_metadata_hook<F>(
+        &self,
+        dest_dir: &Path,
+        allow_removing_required: bool,
+        hook: F,
+    ) -> Result<EmbeddedPythonPaths>
+    where
+        F: FnOnce(&mut Vec<CargoMetadataEntry>) -> Result<()>,
+    {
+        self.write_files_with_hooks(
+            dest_dir,
+            &WorkspacePaths::default(),
+            allow_removing_required,
+            |_| Ok(()),
+            hook,
+        )
+    }
+
+    /// Obtain packed resources data for embedding it in a custom way.
+    pub fn packed_resources_blob(&self) -> PackedResourcesBlob {
+        PackedResourcesBlob {
+            data: &self.resources,
+            alignment: PACKED_RESOURCES_ALIGNMENT,
+            sha256: sha256_hex(&self.resources),
+        }
+    }
+
+    /// Where the generated config obtains packed resources data from.
+    ///
+    /// `embedded_resources_path` is the packed resources file to embed. It
+    /// is ignored if the data is placed in a linker section.
+    pub fn packed_resources_source(&self, embedded_resources_path: &Path) -> PackedResourcesSource {
+        match &self.packed_resources_embedding {
+            PackedResourcesEmbedding::File => {
+                PackedResourcesSource::Embedded(embedded_resources_path.to_path_buf())
+            }
+            PackedResourcesEmbedding::LinkerSection { symbol } => {
+                PackedResourcesSource::LinkerSection {
+                    symbol: symbol.clone(),
+                    length: self.resources.len(),
+                }
+            }
+        }
+    }
+
+    /// Obtain the `PythonConfig` written to `default_python_config.rs`.
+    ///
+    /// `embedded_resources_path` is the packed resources file the config
+    /// embeds, as with `packed_resources_source()`. Importer settings are
+    /// derived from `config` as they are by `write_files()`.
+    pub fn python_config_settings(&self, embedded_resources_path: &Path) -> PythonConfigSettings {
+        derive_python_config_settings(
+            &self.config,
+            &self.importer_config(self.packed_resources_source(embedded_resources_path)),
+        )
+    }
+
+    /// Environment variables the generated `PythonConfig` reads at run-time.
+    pub fn runtime_env(&self) -> RuntimeEnvRegistry {
+        let mut env = derive_runtime_env(&self.config);
+
+        if let Some(selection) = &self.recovery_selection {
+            selection.register_runtime_env(&mut env);
+        }
+
+        if let Some(selection) = &self.external_payloads {
+            selection.register_runtime_env(&mut env);
+        }
+
+        env
+    }
+
+    /// Derive importer settings obtaining packed resources from `source`.
+    fn importer_config(&self, source: PackedResourcesSource) -> ImporterConfig {
+        let mut importer = ImporterConfig::from_embedded_config_with_source(&self.config, source);
+        importer.recovery = self.recovery_selection.clone();
+        importer.external_payloads = self.external_payloads.clone();
+
+        importer
+    }
+
+    /// The finder each resource must be loaded by.
+    ///
+    /// All resources are indexed in packed resources, so they are served by
+    /// the in-memory finder, including those installed relative to the binary.
+    pub fn importer_placements(&self) -> BTreeMap<String, ImporterFinder> {
+        String::from_utf8_lossy(&self.module_names)
+            .lines()
+            .filter(|name| !name.is_empty())
+            .map(|name| (name.to_string(), ImporterFinder::InMemory))
+            .collect()
+    }
+
+    /// Write out files needed to link a binary, adjusting importer settings and Cargo metadata first.
+    ///
+    /// `importer_hook` receives the importer settings derived from `config`
+    /// before they are rendered into `config.rs`. The adjusted settings are
+    /// validated against `importer_placements()` and problems with severity
+    /// `Error` are an error. `cargo_hook` behaves as with
+    /// `write_files_with_cargo_metadata_hook()` and `paths` as with
+    /// `write_files()`.
+    pub fn write_files_with_hooks<I, F>(
+        &self,
+        dest_dir: &Path,
+        paths: &WorkspacePaths,
+        allow_removing_required: bool,
+        importer_hook: I,
+        cargo_hook: F,
+    ) -> Result<EmbeddedPythonPaths>
+    where
+        I: FnOnce(&mut ImporterConfig) -> Result<()>,
+        F: FnOnce(&mut Vec<CargoMetadataEntry>) -> Result<()>,
+    {
+        self.write_artifacts(
+            dest_dir,
+            paths,
+            allow_removing_required,
+            importer_hook,
+            cargo_hook,
+            &mut ArtifactWriter::default(),
+        )
+    }
+
+    fn write_artifacts<I, F>(
+        &self,
+        dest_dir: &Path,
+        paths: &WorkspacePaths,
+        allow_removing_required: bool,
+        importer_hook: I,
+        cargo_hook: F,
+        writer: &mut ArtifactWriter,
+    ) -> Result<EmbeddedPythonPaths>
+    where
+        I: FnOnce(&mut ImporterConfig) -> Result<()>,
+        F: FnOnce(&mut Vec<CargoMetadataEntry>) -> Result<()>,
+    {
+        // The writer may have written files before.
+        let first_written = writer.profile().artifacts.len();
+
         let module_names = dest_dir.join("py-module-names");
-        let mut fh = File::create(&module_names)?;
-        fh.write_all(&self.module_names)?;
+        writer.write("module names", &module_names, &self.module_names)?;
 
-        let embedded_resources = dest_dir.join("packed-resources");
-        let mut fh = File::create(&embedded_resources)?;
-        fh.write_all(&self.resources)?;
+        self.packed_resources_embedding.validate()?;
+
+        let embedded_resources = match self.packed_resources_embedding {
+            PackedResourcesEmbedding::File => {
+                let path = dest_dir.join("packed-resources");
+                writer.write("packed resources", &path, &self.resources)?;
+                Some(path)
+            }
+            PackedResourcesEmbedding::LinkerSection { .. } => None,
+        };
 
         let libpython = dest_dir.join(&self.linking_info.libpythonxy_filename);
-        let mut fh = File::create(&libpython)?;
-        fh.write_all(&self.linking_info.libpythonxy_data)?;
+        writer.write("libpython", &libpython, &self.linking_info.libpythonxy_data)?;
 
         let libpyembeddedconfig = if let Some(data) = &self.linking_info.libpyembeddedconfig_data {
             let path = dest_dir.join(
@@ -275,35 +1315,143 @@ impl EmbeddedPythonContext {
                     .as_ref()
                     .unwrap(),
             );
-            let mut fh = File::create(&path)?;
-            fh.write_all(data)?;
+            writer.write("embedded config library", &path, data)?;
             Some(path)
         } else {
             None
         };
 
-        let config_rs_data = derive_python_config(&self.config, &embedded_resources);
+        // include_bytes!() resolves relative paths against the file it is
+        // in, which is written next to the packed resources.
+        let embedded_resources_reference = match (&embedded_resources, paths.style) {
+            (Some(path), PathStyle::Absolute) => path.clone(),
+            (Some(path), PathStyle::Relative) => {
+                paths.record("packed resources", path)?;
+                PathBuf::from("packed-resources")
+            }
+            (None, _) => PathBuf::new(),
+        };
+
+        let mut importer =
+            self.importer_config(self.packed_resources_source(&embedded_resources_reference));
+        importer_hook(&mut importer)?;
+
+        let errors = importer
+            .validate(&self.importer_placements())
+            .into_iter()
+            .filter(|problem| problem.severity == ConfigProblemSeverity::Error)
+            .map(|problem| problem.to_string())
+            .collect::<Vec<_>>();
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "invalid importer configuration: {}",
+                errors.join("; ")
+            ));
+        }
+
+        let config_rs_data = derive_python_config_settings(&self.config, &importer);
         let config_rs = dest_dir.join("default_python_config.rs");
-        write_default_python_config_rs(&config_rs, &config_rs_data)?;
+        writer.write(
+            "default Python config",
+            &config_rs,
+            config_rs_data.render_default_python_config_rs().as_bytes(),
+        )?;
 
-        let mut cargo_metadata_lines = Vec::new();
-        cargo_metadata_lines.extend(self.linking_info.cargo_metadata.clone());
+        let mut entries = self
+            .linking_info
+            .cargo_metadata
+            .iter()
+            .map(|line| CargoMetadataEntry::parse(line))
+            .collect::<Result<Vec<_>>>()?;
 
         // Tell Cargo where libpythonXY is located.
-        cargo_metadata_lines.push(format!(
-            "cargo:rustc-link-search=native={}",
-            dest_dir.display()
-        ));
+        let libpython_search = CargoMetadataEntry::LinkSearch {
+            kind: Some("native".to_string()),
+            path: dest_dir.to_path_buf(),
+        };
+        entries.push(libpython_search.clone());
 
         // Give dependent crates the path to the default config file.
-        cargo_metadata_lines.push(format!(
-            "cargo:default-python-config-rs={}",
-            config_rs.display()
-        ));
+        entries.push(CargoMetadataEntry::Other {
+            key: "default-python-config-rs".to_string(),
+            value: format!("{}", config_rs.display()),
+        });
+
+        // Tell the build placing packed resources in a section what to place.
+        if let PackedResourcesEmbedding::LinkerSection { symbol } = &self.packed_resources_embedding
+        {
+            let blob = self.packed_resources_blob();
+
+            for (key, value) in &[
+                ("packed-resources-symbol", symbol.clone()),
+                ("packed-resources-length", blob.len().to_string()),
+                ("packed-resources-alignment", blob.alignment.to_string()),
+                ("packed-resources-sha256", blob.sha256.clone()),
+            ] {
+                entries.push(CargoMetadataEntry::Other {
+                    key: key.to_string(),
+                    value: value.clone(),
+                });
+            }
+        }
+
+        let mut required = vec![libpython_search];
+        required.extend(
+            entries
+                .iter()
+                .filter(|entry| match entry {
+                    CargoMetadataEntry::LinkLib { name, .. } => name == "pythonXY",
+                    _ => false,
+                })
+                .cloned(),
+        );
+
+        cargo_hook(&mut entries)?;
+
+        if !allow_removing_required {
+            for entry in &required {
+                if !entries.iter().any(|e| entry.satisfied_by(e)) {
+                    return Err(anyhow!(
+                        "cargo metadata hook removed required entry {}",
+                        entry
+                    ));
+                }
+            }
+        }
+
+        let cargo_metadata_lines = entries
+            .iter()
+            .map(|entry| {
+                Ok(match entry.path() {
+                    Some(path) => entry
+                        .with_path(paths.record(&entry.to_string(), path)?)
+                        .to_string(),
+                    None => entry.to_string(),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
 
         let cargo_metadata = dest_dir.join("cargo_metadata.txt");
-        let mut fh = File::create(&cargo_metadata)?;
-        fh.write_all(cargo_metadata_lines.join("\n").as_bytes())?;
+        writer.write(
+            "cargo metadata",
+            &cargo_metadata,
+            cargo_metadata_lines.join("\n").as_bytes(),
+        )?;
+
+        if paths.style == PathStyle::Relative {
+            writer.write(
+                "workspace root",
+                &dest_dir.join(WORKSPACE_ROOT_FILENAME),
+                format!("{}", paths.root.display()).as_bytes(),
+            )?;
+        }
+
+        let link_record = dest_dir.join("link-inputs.json");
+        writer.write(
+            "link record",
+            &link_record,
+            self.linking_info.link_record.to_json()?.as_bytes(),
+        )?;
 
         Ok(EmbeddedPythonPaths {
             module_names,
@@ -311,7 +1459,86 @@ impl EmbeddedPythonContext {
             libpython,
             libpyembeddedconfig,
             config_rs,
+            importer,
             cargo_metadata,
+            cargo_metadata_lines,
+            link_record,
+            write_profile: ArtifactWriteProfile {
+                artifacts: writer.profile().artifacts[first_written..].to_vec(),
+            },
         })
     }
 }
+
+/// Verify that the various views of resources in an `EmbeddedPythonContext` agree.
+///
+/// The module names list, the packed resources index, and the set of
+/// built-in extension modules are derived independently. Downstream tooling
+/// relies on these being consistent. This function cross-checks them and
+/// errors if a name is present in one but not the others. Hidden module
+/// names count as listed. Only the main resource set of packed resources is
+/// checked.
+pub fn verify_embedded_context(context: &EmbeddedPythonContext) -> Result<()> {
+    let listed_names = String::from_utf8(context.module_names.clone())?
+        .lines()
+        .map(|l| l.to_string())
+        .collect::<BTreeSet<_>>();
+
+    if let Some(name) = listed_names
+        .intersection(&context.hidden_module_names)
+        .next()
+    {
+        return Err(anyhow!(
+            "hidden module {} is in the module names list",
+            name
+        ));
+    }
+
+    let module_names = listed_names
+        .union(&context.hidden_module_names)
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut resource_names = BTreeSet::new();
+    let mut builtin_resource_names = BTreeSet::new();
+
+    for resource in python_packed_resources::parser::load_resources(&context.resources)
+        .map_err(|e| anyhow!("error parsing packed resources: {}", e))?
+    {
+        let resource = resource.map_err(|e| anyhow!("error parsing packed resources: {}", e))?;
+
+        if resource.resource_set != ResourceSet::Main {
+            continue;
+        }
+
+        if resource.flavor == ResourceFlavor::BuiltinExtensionModule {
+            builtin_resource_names.insert(resource.name.to_string());
+        }
+
+        resource_names.insert(resource.name.to_string());
+    }
+
+    if let Some(name) = module_names.symmetric_difference(&resource_names).next() {
+        return Err(anyhow!(
+            "module names list and packed resources disagree on {}",
+            name
+        ));
+    }
+
+    if let Some(name) = context
+        .builtin_extension_module_names
+        .symmetric_difference(&builtin_resource_names)
+        .next()
+    {
+        return Err(anyhow!(
+            "built-in extension modules and packed resources disagree on {}",
+            name
+        ));
+    }
+
+    context
+        .install_layout
+        .verify_file_manifest(&context.extra_files)?;
+
+    Ok(())
+}
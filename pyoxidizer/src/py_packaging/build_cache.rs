@@ -0,0 +1,662 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Manage persistent build caches.
+
+Builds can keep expensive artifacts in a directory shared across builds.
+The directory holds named caches (e.g. `bytecode`), each holding entries
+keyed by strings. An entry is a file or directory at `<root>/<cache>/<key>`.
+
+A manifest at `<root>/manifest.json` records the size of each entry and
+when it was last used. The manifest is only modified while holding an
+exclusive lock on `<root>/manifest.lock` and is rewritten atomically, so
+concurrent builds sharing the directory don't corrupt it.
+
+Builds access caches through a `CacheSession`. A session holds an exclusive
+lock on a file in `<root>/sessions` for as long as it is alive and records
+its id on the entries it uses. Garbage collection never removes entries
+used by a session whose lock is still held. Entries used by sessions that
+went away without cleaning up, e.g. because the build crashed, become
+eligible again.
+
+Times are obtained from a `BuildClock`, so builds with a fixed clock write
+identical manifests.
+*/
+
+use {
+    super::build_clock::BuildClock,
+    anyhow::{anyhow, Context, Result},
+    fs2::FileExt,
+    serde::{Deserialize, Serialize},
+    std::collections::{BTreeMap, BTreeSet},
+    std::fs::File,
+    std::path::{Path, PathBuf},
+    std::time::{SystemTime, UNIX_EPOCH},
+    uuid::Uuid,
+};
+
+/// An entry of a cache.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CacheEntry {
+    /// Size in bytes of the entry's content.
+    pub size: u64,
+
+    /// When the entry was last used, in seconds since the UNIX epoch.
+    pub last_used: u64,
+
+    /// Ids of sessions that have used the entry and may still be using it.
+    #[serde(default)]
+    pub sessions: BTreeSet<String>,
+}
+
+/// Records the entries of all caches in a cache directory.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CacheManifest {
+    /// Entries keyed by cache name and then entry key.
+    pub caches: BTreeMap<String, BTreeMap<String, CacheEntry>>,
+}
+
+impl CacheManifest {
+    /// The total size in bytes of all entries.
+    pub fn total_size(&self) -> u64 {
+        self.caches
+            .values()
+            .flat_map(|x| x.values())
+            .map(|x| x.size)
+            .sum()
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+        serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        // Readers never observe a partially written manifest.
+        let mut temp_path = path.to_path_buf();
+        temp_path.set_file_name(format!("manifest.{}.tmp", Uuid::new_v4()));
+
+        std::fs::write(&temp_path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("replacing {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+/// Hit and miss counts of a cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStatistics {
+    /// Number of lookups that found an entry.
+    pub hits: u64,
+
+    /// Number of lookups that didn't find an entry.
+    pub misses: u64,
+}
+
+/// Limits enforced by garbage collection.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct GcPolicy {
+    /// Maximum total size in bytes of all entries.
+    ///
+    /// Least recently used entries are removed until the limit is met.
+    pub max_size: Option<u64>,
+
+    /// Maximum time in seconds since an entry was last used.
+    pub max_age_seconds: Option<u64>,
+}
+
+/// Describes the result of garbage collection.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct GcReport {
+    /// Removed entries as `(cache, key)`.
+    pub removed: Vec<(String, String)>,
+
+    /// Size in bytes of removed entries.
+    pub bytes_removed: u64,
+
+    /// Number of entries retained because a live session uses them.
+    pub retained_in_use: usize,
+}
+
+fn now_seconds(now: SystemTime) -> u64 {
+    now.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Compute the size in bytes of a file or directory tree.
+pub fn path_size(path: &Path) -> Result<u64> {
+    let mut size = 0;
+
+    for entry in walkdir::WalkDir::new(path) {
+        let entry = entry?;
+        if entry.file_type().is_file() {
+            size += entry.metadata()?.len();
+        }
+    }
+
+    Ok(size)
+}
+
+fn remove_path(path: &Path) -> Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+    .with_context(|| format!("removing {}", path.display()))
+}
+
+/// A directory of persistent caches shared across builds.
+#[derive(Clone, Debug)]
+pub struct BuildCache {
+    root: PathBuf,
+    clock: BuildClock,
+}
+
+impl BuildCache {
+    /// Open a cache directory, creating it if necessary.
+    pub fn new(root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root.join("sessions"))
+            .with_context(|| format!("creating cache directory {}", root.display()))?;
+
+        Ok(Self {
+            root: root.to_path_buf(),
+            clock: BuildClock::default(),
+        })
+    }
+
+    /// Set the clock times recorded in the manifest are obtained from.
+    pub fn set_clock(&mut self, clock: BuildClock) {
+        self.clock = clock;
+    }
+
+    /// The directory holding the caches.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// The path where the content of an entry lives.
+    pub fn entry_path(&self, cache: &str, key: &str) -> PathBuf {
+        self.root.join(cache).join(key)
+    }
+
+    fn session_lock_path(&self, id: &str) -> PathBuf {
+        self.root.join("sessions").join(format!("{}.lock", id))
+    }
+
+    /// Read, modify, and write back the manifest while holding its lock.
+    fn update_manifest<T>(&self, f: impl FnOnce(&mut CacheManifest) -> Result<T>) -> Result<T> {
+        let lock_path = self.root.join("manifest.lock");
+        let lock = File::create(&lock_path)
+            .with_context(|| format!("creating {}", lock_path.display()))?;
+        lock.lock_exclusive()
+            .with_context(|| format!("locking {}", lock_path.display()))?;
+
+        let manifest_path = self.root.join("manifest.json");
+        let res = CacheManifest::read(&manifest_path).and_then(|mut manifest| {
+            let value = f(&mut manifest)?;
+            manifest.write(&manifest_path)?;
+            Ok(value)
+        });
+
+        lock.unlock()
+            .with_context(|| format!("unlocking {}", lock_path.display()))?;
+
+        res
+    }
+
+    /// Obtain a snapshot of the manifest.
+    pub fn manifest(&self) -> Result<CacheManifest> {
+        self.update_manifest(|manifest| Ok(manifest.clone()))
+    }
+
+    /// Start a session for a build.
+    pub fn session(&self) -> Result<CacheSession> {
+        let id = Uuid::new_v4().to_string();
+        let lock_path = self.session_lock_path(&id);
+
+        let lock = File::create(&lock_path)
+            .with_context(|| format!("creating {}", lock_path.display()))?;
+        lock.lock_exclusive()
+            .with_context(|| format!("locking {}", lock_path.display()))?;
+
+        Ok(CacheSession {
+            cache: self.clone(),
+            id,
+            lock: Some(lock),
+            statistics: BTreeMap::new(),
+        })
+    }
+
+    /// Whether the session with the given id is still alive.
+    ///
+    /// Lock files of sessions that are no longer alive are removed.
+    fn session_alive(&self, id: &str) -> bool {
+        let path = self.session_lock_path(id);
+
+        let file = match std::fs::OpenOptions::new().write(true).open(&path) {
+            Ok(file) => file,
+            Err(_) => return false,
+        };
+
+        if file.try_lock_exclusive().is_err() {
+            return true;
+        }
+
+        let _ = file.unlock();
+        let _ = std::fs::remove_file(&path);
+
+        false
+    }
+
+    /// Remove entries exceeding the limits of a policy.
+    pub fn gc(&self, policy: &GcPolicy) -> Result<GcReport> {
+        self.gc_at(policy, self.clock.now()?)
+    }
+
+    fn gc_at(&self, policy: &GcPolicy, now: SystemTime) -> Result<GcReport> {
+        let now = now_seconds(now);
+
+        self.update_manifest(|manifest| {
+            let mut report = GcReport::default();
+            let mut alive = BTreeMap::new();
+
+            // Entries not used by a live session as (last used, cache, key, size).
+            let mut candidates = Vec::new();
+
+            for (cache, entries) in manifest.caches.iter_mut() {
+                // Entries whose content went away are forgotten.
+                entries.retain(|key, _| self.entry_path(cache, key).exists());
+
+                for (key, entry) in entries.iter_mut() {
+                    entry.sessions.retain(|id| {
+                        *alive
+                            .entry(id.clone())
+                            .or_insert_with(|| self.session_alive(id))
+                    });
+
+                    if entry.sessions.is_empty() {
+                        candidates.push((entry.last_used, cache.clone(), key.clone(), entry.size));
+                    } else {
+                        report.retained_in_use += 1;
+                    }
+                }
+            }
+
+            // Least recently used first.
+            candidates.sort();
+
+            let mut total_size = manifest.total_size();
+
+            for (last_used, cache, key, size) in candidates {
+                let expired = policy
+                    .max_age_seconds
+                    .map_or(false, |max| now.saturating_sub(last_used) > max);
+                let oversized = policy.max_size.map_or(false, |max| total_size > max);
+
+                if !expired && !oversized {
+                    continue;
+                }
+
+                remove_path(&self.entry_path(&cache, &key))?;
+                if let Some(entries) = manifest.caches.get_mut(&cache) {
+                    entries.remove(&key);
+                }
+
+                total_size -= size;
+                report.bytes_removed += size;
+                report.removed.push((cache, key));
+            }
+
+            manifest.caches.retain(|_, entries| !entries.is_empty());
+
+            Ok(report)
+        })
+    }
+}
+
+/// A build's use of a `BuildCache`.
+///
+/// Entries used by the session are protected from garbage collection
+/// until the session is finished or dropped.
+pub struct CacheSession {
+    cache: BuildCache,
+    id: String,
+    lock: Option<File>,
+    statistics: BTreeMap<String, CacheStatistics>,
+}
+
+impl CacheSession {
+    /// The unique id of this session.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// The cache directory this session uses.
+    pub fn build_cache(&self) -> &BuildCache {
+        &self.cache
+    }
+
+    /// Look up an entry.
+    ///
+    /// Returns the path of the entry's content if the entry exists. The
+    /// lookup is counted as a hit or miss and a found entry is marked as
+    /// used by this session.
+    pub fn get(&mut self, cache: &str, key: &str) -> Result<Option<PathBuf>> {
+        let path = self.cache.entry_path(cache, key);
+        let id = self.id.clone();
+        let now = self.cache.clock.now_seconds()?;
+
+        let found = self.cache.update_manifest(|manifest| {
+            let entries = match manifest.caches.get_mut(cache) {
+                Some(entries) => entries,
+                None => return Ok(false),
+            };
+
+            if !path.exists() {
+                entries.remove(key);
+                return Ok(false);
+            }
+
+            Ok(match entries.get_mut(key) {
+                Some(entry) => {
+                    entry.last_used = now;
+                    entry.sessions.insert(id);
+                    true
+                }
+                None => false,
+            })
+        })?;
+
+        let statistics = self.statistics.entry(cache.to_string()).or_default();
+
+        if found {
+            statistics.hits += 1;
+            Ok(Some(path))
+        } else {
+            statistics.misses += 1;
+            Ok(None)
+        }
+    }
+
+    /// Record an entry whose content was written to `BuildCache::entry_path()`.
+    ///
+    /// Content should be written to a temporary path and renamed into
+    /// place so other builds never observe partial content.
+    pub fn insert(&mut self, cache: &str, key: &str) -> Result<PathBuf> {
+        let path = self.cache.entry_path(cache, key);
+
+        if !path.exists() {
+            return Err(anyhow!("cache entry {} does not exist", path.display()));
+        }
+
+        let entry = CacheEntry {
+            size: path_size(&path)?,
+            last_used: self.cache.clock.now_seconds()?,
+            sessions: vec![self.id.clone()].into_iter().collect(),
+        };
+
+        self.cache.update_manifest(|manifest| {
+            manifest
+                .caches
+                .entry(cache.to_string())
+                .or_default()
+                .insert(key.to_string(), entry);
+
+            Ok(())
+        })?;
+
+        Ok(path)
+    }
+
+    /// Hit and miss counts of lookups performed by this session, keyed by cache name.
+    pub fn statistics(&self) -> &BTreeMap<String, CacheStatistics> {
+        &self.statistics
+    }
+
+    /// End the session and collect garbage.
+    ///
+    /// Entries used by this session are released first, so they are
+    /// subject to the policy like any other entry.
+    pub fn finish(mut self, policy: &GcPolicy) -> Result<GcReport> {
+        self.release()?;
+
+        self.cache.gc(policy)
+    }
+
+    fn release(&mut self) -> Result<()> {
+        let lock = match self.lock.take() {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+
+        let id = self.id.clone();
+        self.cache.update_manifest(|manifest| {
+            for entry in manifest.caches.values_mut().flat_map(|x| x.values_mut()) {
+                entry.sessions.remove(&id);
+            }
+
+            Ok(())
+        })?;
+
+        lock.unlock().context("unlocking session")?;
+        let _ = std::fs::remove_file(self.cache.session_lock_path(&self.id));
+
+        Ok(())
+    }
+}
+
+impl Drop for CacheSession {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::time::Duration};
+
+    fn write_entry(session: &mut CacheSession, cache: &str, key: &str, size: usize) -> Result<()> {
+        let path = session.build_cache().entry_path(cache, key);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, vec![0; size])?;
+        session.insert(cache, key)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_statistics() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BuildCache::new(temp_dir.path())?;
+        let mut session = cache.session()?;
+
+        assert_eq!(session.get("bytecode", "foo")?, None);
+        write_entry(&mut session, "bytecode", "foo", 10)?;
+        assert_eq!(
+            session.get("bytecode", "foo")?,
+            Some(cache.entry_path("bytecode", "foo"))
+        );
+        assert_eq!(session.get("pip", "bar")?, None);
+
+        assert_eq!(
+            session.statistics().get("bytecode"),
+            Some(&CacheStatistics { hits: 1, misses: 1 })
+        );
+        assert_eq!(
+            session.statistics().get("pip"),
+            Some(&CacheStatistics { hits: 0, misses: 1 })
+        );
+
+        let manifest = cache.manifest()?;
+        assert_eq!(manifest.total_size(), 10);
+        assert!(manifest.caches["bytecode"]["foo"]
+            .sessions
+            .contains(session.id()));
+
+        // Entries whose content was removed behind our back are misses.
+        std::fs::remove_file(cache.entry_path("bytecode", "foo"))?;
+        assert_eq!(session.get("bytecode", "foo")?, None);
+        assert!(cache.manifest()?.caches["bytecode"].is_empty());
+
+        assert!(session.insert("bytecode", "missing").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_policy() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BuildCache::new(temp_dir.path())?;
+
+        let mut session = cache.session()?;
+        write_entry(&mut session, "a", "old", 10)?;
+        write_entry(&mut session, "a", "recent", 20)?;
+        write_entry(&mut session, "b", "newest", 30)?;
+        drop(session);
+
+        cache.update_manifest(|manifest| {
+            manifest
+                .caches
+                .get_mut("a")
+                .unwrap()
+                .get_mut("old")
+                .unwrap()
+                .last_used = 100;
+            manifest
+                .caches
+                .get_mut("a")
+                .unwrap()
+                .get_mut("recent")
+                .unwrap()
+                .last_used = 200;
+            manifest
+                .caches
+                .get_mut("b")
+                .unwrap()
+                .get_mut("newest")
+                .unwrap()
+                .last_used = 300;
+            assert!(manifest
+                .caches
+                .values()
+                .flat_map(|x| x.values())
+                .all(|x| x.sessions.is_empty()));
+
+            Ok(())
+        })?;
+
+        let now = UNIX_EPOCH + Duration::from_secs(400);
+
+        let report = cache.gc_at(
+            &GcPolicy {
+                max_size: None,
+                max_age_seconds: Some(250),
+            },
+            now,
+        )?;
+        assert_eq!(report.removed, vec![("a".to_string(), "old".to_string())]);
+        assert_eq!(report.bytes_removed, 10);
+        assert!(!cache.entry_path("a", "old").exists());
+
+        let report = cache.gc_at(
+            &GcPolicy {
+                max_size: Some(40),
+                max_age_seconds: None,
+            },
+            now,
+        )?;
+        assert_eq!(
+            report.removed,
+            vec![("a".to_string(), "recent".to_string())]
+        );
+
+        let manifest = cache.manifest()?;
+        assert_eq!(
+            manifest.caches.keys().cloned().collect::<Vec<_>>(),
+            vec!["b".to_string()]
+        );
+        assert_eq!(manifest.total_size(), 30);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_in_flight() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BuildCache::new(temp_dir.path())?;
+        let policy = GcPolicy {
+            max_size: Some(0),
+            max_age_seconds: None,
+        };
+
+        let mut session = cache.session()?;
+        write_entry(&mut session, "a", "foo", 10)?;
+
+        // A crashed build leaves its id behind without a live lock.
+        cache.update_manifest(|manifest| {
+            manifest.caches.entry("a".to_string()).or_default().insert(
+                "crashed".to_string(),
+                CacheEntry {
+                    size: 5,
+                    last_used: 0,
+                    sessions: vec!["dead".to_string()].into_iter().collect(),
+                },
+            );
+
+            Ok(())
+        })?;
+        std::fs::write(cache.entry_path("a", "crashed"), vec![0; 5])?;
+
+        let report = cache.gc(&policy)?;
+        assert_eq!(
+            report.removed,
+            vec![("a".to_string(), "crashed".to_string())]
+        );
+        assert_eq!(report.retained_in_use, 1);
+        assert!(cache.entry_path("a", "foo").exists());
+
+        let report = session.finish(&policy)?;
+        assert_eq!(report.removed, vec![("a".to_string(), "foo".to_string())]);
+        assert_eq!(cache.manifest()?, CacheManifest::default());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fixed_clock() -> Result<()> {
+        let mut manifests = vec![];
+
+        for _ in 0..2 {
+            let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+            let mut cache = BuildCache::new(temp_dir.path())?;
+            cache.set_clock(BuildClock::Fixed(1000));
+
+            let mut session = cache.session()?;
+            write_entry(&mut session, "bytecode", "foo", 10)?;
+            session.get("bytecode", "foo")?;
+            session.finish(&GcPolicy {
+                max_size: None,
+                max_age_seconds: Some(0),
+            })?;
+
+            assert_eq!(cache.manifest()?.caches["bytecode"]["foo"].last_used, 1000);
+            manifests.push(std::fs::read(temp_dir.path().join("manifest.json"))?);
+        }
+
+        assert_eq!(manifests[0], manifests[1]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Control the time recorded by builds.
+
+Builds record times in artifacts, such as the build time in build manifests
+and when build cache entries were last used. Reproducible builds and tests
+need these times to not depend on when the build ran.
+
+`BuildClock` is the source of every time a build records. By default it
+honors [`SOURCE_DATE_EPOCH`](https://reproducible-builds.org/specs/source-date-epoch/)
+and falls back to the system clock if it isn't set.
+*/
+
+use {
+    anyhow::{anyhow, Result},
+    serde::{Deserialize, Serialize},
+    std::time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Environment variable defining the time reproducible builds record.
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// Where builds obtain the time they record.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildClock {
+    /// The system clock.
+    Real,
+
+    /// A fixed time, in seconds since the UNIX epoch.
+    Fixed(u64),
+
+    /// The time in `SOURCE_DATE_EPOCH`, or the system clock if it isn't set.
+    SourceDateEpoch,
+}
+
+impl Default for BuildClock {
+    fn default() -> Self {
+        BuildClock::SourceDateEpoch
+    }
+}
+
+impl BuildClock {
+    /// Obtain the current time.
+    ///
+    /// Errors if `SOURCE_DATE_EPOCH` is consulted and isn't a number of seconds.
+    pub fn now(&self) -> Result<SystemTime> {
+        self.now_with_source_date_epoch(std::env::var(SOURCE_DATE_EPOCH).ok().as_deref())
+    }
+
+    /// Obtain the current time in seconds since the UNIX epoch.
+    pub fn now_seconds(&self) -> Result<u64> {
+        Ok(self
+            .now()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0))
+    }
+
+    fn now_with_source_date_epoch(&self, source_date_epoch: Option<&str>) -> Result<SystemTime> {
+        match self {
+            BuildClock::Real => Ok(SystemTime::now()),
+            BuildClock::Fixed(seconds) => Ok(UNIX_EPOCH + Duration::from_secs(*seconds)),
+            BuildClock::SourceDateEpoch => match source_date_epoch {
+                Some(value) => {
+                    let seconds = value.trim().parse::<u64>().map_err(|_| {
+                        anyhow!(
+                            "{} must be a number of seconds; got {}",
+                            SOURCE_DATE_EPOCH,
+                            value
+                        )
+                    })?;
+
+                    Ok(UNIX_EPOCH + Duration::from_secs(seconds))
+                }
+                None => Ok(SystemTime::now()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed() -> Result<()> {
+        let clock = BuildClock::Fixed(1_600_000_000);
+        assert_eq!(clock.now_seconds()?, 1_600_000_000);
+        assert_eq!(
+            clock.now_with_source_date_epoch(Some("42"))?,
+            UNIX_EPOCH + Duration::from_secs(1_600_000_000)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_date_epoch() -> Result<()> {
+        let clock = BuildClock::default();
+        assert_eq!(
+            clock.now_with_source_date_epoch(Some("42"))?,
+            UNIX_EPOCH + Duration::from_secs(42)
+        );
+
+        let before = SystemTime::now();
+        assert!(clock.now_with_source_date_epoch(None)? >= before);
+
+        assert_eq!(
+            format!(
+                "{}",
+                clock
+                    .now_with_source_date_epoch(Some("yesterday"))
+                    .unwrap_err()
+            ),
+            "SOURCE_DATE_EPOCH must be a number of seconds; got yesterday"
+        );
+
+        // The real clock ignores SOURCE_DATE_EPOCH.
+        assert!(BuildClock::Real.now_with_source_date_epoch(Some("42"))? >= before);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_deserialize() -> Result<()> {
+        assert_eq!(
+            serde_json::from_str::<BuildClock>("{\"fixed\": 5}")?,
+            BuildClock::Fixed(5)
+        );
+        assert_eq!(
+            serde_json::from_str::<BuildClock>("\"source-date-epoch\"")?,
+            BuildClock::SourceDateEpoch
+        );
+
+        Ok(())
+    }
+}
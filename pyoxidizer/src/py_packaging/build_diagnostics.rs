@@ -0,0 +1,286 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Machine-readable diagnostics of failed builds.
+
+Errors building an embedded Python context are `anyhow::Error`s like any
+other. They additionally carry a `BuildDiagnostics` describing in which
+phase the build failed, the class of the failure, the resources involved
+and the warnings emitted until then, so automation doesn't have to parse
+error messages:
+
+```ignore
+if let Some(diagnostics) = build_diagnostics(&err) {
+    println!("{}", diagnostics.to_json()?);
+}
+```
+*/
+
+use {
+    anyhow::Result,
+    serde::{Deserialize, Serialize},
+    slog::warn,
+    std::collections::BTreeSet,
+};
+
+/// A phase of building an embedded Python context.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildPhase {
+    /// Ensuring files backing resources exist.
+    VerifyFiles,
+
+    /// Pinning content, applying package patches and checking install paths.
+    PrepareResources,
+
+    /// Compiling resources.
+    Compile,
+
+    /// Validating the configuration and linting resources.
+    Validate,
+
+    /// Deriving build artifacts from compiled resources.
+    Generate,
+
+    /// Verifying the finished context.
+    Verify,
+}
+
+/// What kind of problem failed a build.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureClass {
+    /// The configuration or packaging policy was violated.
+    Policy,
+
+    /// Compiling bytecode failed.
+    Compile,
+
+    /// Reading or writing files failed.
+    Io,
+
+    /// Anything else, usually a bug.
+    Internal,
+}
+
+impl FailureClass {
+    /// Infer the class of an error raised in a phase.
+    ///
+    /// Errors caused by an I/O error are `Io`. Others are classified by the
+    /// phase they were raised in.
+    pub fn infer(phase: BuildPhase, error: &anyhow::Error) -> Self {
+        if error
+            .chain()
+            .any(|cause| cause.downcast_ref::<std::io::Error>().is_some())
+        {
+            return FailureClass::Io;
+        }
+
+        match phase {
+            BuildPhase::VerifyFiles => FailureClass::Io,
+            BuildPhase::PrepareResources | BuildPhase::Validate => FailureClass::Policy,
+            BuildPhase::Compile => FailureClass::Compile,
+            BuildPhase::Generate | BuildPhase::Verify => FailureClass::Internal,
+        }
+    }
+}
+
+/// Describes a failed build.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct BuildDiagnostics {
+    /// Phase the build failed in.
+    pub phase: BuildPhase,
+
+    /// Class of the failure.
+    pub class: FailureClass,
+
+    /// Names of resources causing the failure, if known.
+    pub resources: BTreeSet<String>,
+
+    /// Codes of problems failing the build, e.g. `dunder-file-in-memory`.
+    pub codes: BTreeSet<String>,
+
+    /// Warnings and lint findings emitted before the failure.
+    pub warnings: Vec<String>,
+
+    /// The error and its causes.
+    pub message: String,
+}
+
+impl BuildDiagnostics {
+    /// Serialize the instance to JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+/// An error carrying `BuildDiagnostics`.
+///
+/// It displays as the error it wraps and has the same causes.
+#[derive(Debug)]
+pub struct BuildFailure {
+    pub diagnostics: BuildDiagnostics,
+    error: anyhow::Error,
+}
+
+impl std::fmt::Display for BuildFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for BuildFailure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Obtain the diagnostics of a failed build, if an error has them.
+pub fn build_diagnostics(error: &anyhow::Error) -> Option<&BuildDiagnostics> {
+    error
+        .downcast_ref::<BuildFailure>()
+        .map(|failure| &failure.diagnostics)
+}
+
+/// Records the progress of a build to describe its failure.
+pub struct DiagnosticsRecorder<'a> {
+    logger: &'a slog::Logger,
+    phase: BuildPhase,
+    class: Option<FailureClass>,
+    resources: BTreeSet<String>,
+    codes: BTreeSet<String>,
+    warnings: Vec<String>,
+}
+
+impl<'a> DiagnosticsRecorder<'a> {
+    /// Construct an instance logging warnings to `logger`.
+    pub fn new(logger: &'a slog::Logger) -> Self {
+        Self {
+            logger,
+            phase: BuildPhase::VerifyFiles,
+            class: None,
+            resources: BTreeSet::new(),
+            codes: BTreeSet::new(),
+            warnings: vec![],
+        }
+    }
+
+    /// Record that the build entered a phase.
+    pub fn enter(&mut self, phase: BuildPhase) {
+        self.phase = phase;
+    }
+
+    /// Log and record a warning.
+    pub fn warn(&mut self, message: impl ToString) {
+        let message = message.to_string();
+        warn!(self.logger, "{}", message);
+        self.warnings.push(message);
+    }
+
+    /// Describe the failure about to be returned.
+    ///
+    /// This overrides the inferred class and records the resources and
+    /// problem codes causing the failure.
+    pub fn blame(
+        &mut self,
+        class: FailureClass,
+        resources: impl IntoIterator<Item = String>,
+        codes: impl IntoIterator<Item = String>,
+    ) {
+        self.class = Some(class);
+        self.resources.extend(resources);
+        self.codes.extend(codes);
+    }
+
+    /// Attach diagnostics of the recorded build to an error.
+    pub fn into_error(self, error: anyhow::Error) -> anyhow::Error {
+        let diagnostics = BuildDiagnostics {
+            phase: self.phase,
+            class: self
+                .class
+                .unwrap_or_else(|| FailureClass::infer(self.phase, &error)),
+            resources: self.resources,
+            codes: self.codes,
+            warnings: self.warnings,
+            message: format!("{:#}", error),
+        };
+
+        anyhow::Error::new(BuildFailure { diagnostics, error })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::testutil::get_logger, anyhow::Context};
+
+    #[test]
+    fn test_failure_class_infer() {
+        let io = Err::<(), _>(std::io::Error::new(std::io::ErrorKind::NotFound, "missing"))
+            .context("reading foo.py")
+            .unwrap_err();
+        let other = anyhow::anyhow!("invalid");
+
+        assert_eq!(
+            FailureClass::infer(BuildPhase::Compile, &io),
+            FailureClass::Io
+        );
+        assert_eq!(
+            FailureClass::infer(BuildPhase::Compile, &other),
+            FailureClass::Compile
+        );
+        assert_eq!(
+            FailureClass::infer(BuildPhase::Validate, &other),
+            FailureClass::Policy
+        );
+        assert_eq!(
+            FailureClass::infer(BuildPhase::Generate, &other),
+            FailureClass::Internal
+        );
+    }
+
+    #[test]
+    fn test_into_error() -> Result<()> {
+        let logger = get_logger()?;
+        let mut recorder = DiagnosticsRecorder::new(&logger);
+        recorder.warn("trimmed locales");
+        recorder.enter(BuildPhase::Validate);
+        recorder.blame(
+            FailureClass::Policy,
+            vec!["foo".to_string()],
+            vec!["dunder-file-in-memory".to_string()],
+        );
+
+        let err = recorder.into_error(
+            anyhow::anyhow!("package foo references __file__").context("invalid configuration"),
+        );
+
+        // The error is otherwise unchanged.
+        assert_eq!(err.to_string(), "invalid configuration");
+        assert_eq!(
+            format!("{:#}", err),
+            "invalid configuration: package foo references __file__"
+        );
+
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::Validate);
+        assert_eq!(diagnostics.class, FailureClass::Policy);
+        assert_eq!(diagnostics.warnings, vec!["trimmed locales".to_string()]);
+        assert_eq!(
+            diagnostics.message,
+            "invalid configuration: package foo references __file__"
+        );
+
+        let json = diagnostics.to_json()?;
+        assert!(json.contains("\"phase\": \"validate\""));
+        assert_eq!(
+            &serde_json::from_str::<BuildDiagnostics>(&json)?,
+            diagnostics
+        );
+
+        assert!(build_diagnostics(&anyhow::anyhow!("other")).is_none());
+
+        Ok(())
+    }
+}
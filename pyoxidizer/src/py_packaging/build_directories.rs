@@ -0,0 +1,375 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Control where builds write temporary and cached files.
+
+Building writes files outside the output directory: `pip install` and
+`setup.py install` install into and build in scratch directories, payloads
+over the memory budget and pinned file content are spilled to disk, and
+libpython is compiled and linked in a scratch directory. By default all of
+these go to the system temporary directory, which fails on machines where
+it is small or mounted `noexec`.
+
+`BuildDirectories` configures the directories these are created in:
+
+* The scratch directory is the root of everything temporary. Compilers and
+  `setup.py` run from it, so it must allow executing files.
+* The spill directory holds spilled payloads and pinned file content.
+* The pip build directory holds `pip install` and `setup.py install`
+  targets. It is also the temporary directory of these processes, so it
+  must allow executing files.
+* The cache directory is the default location of the persistent build cache.
+
+Unset directories fall back to the scratch directory, and an unset scratch
+directory to the system temporary directory. Configured directories must
+exist and are validated when a builder is created.
+
+Each operation creates its own uniquely named directory in the root for its
+role. A retention policy decides whether it is removed when the operation
+completes. Keeping directories of failed operations helps debugging.
+*/
+
+use {
+    super::build_cache::path_size,
+    anyhow::{anyhow, Context, Result},
+    serde::{Deserialize, Serialize},
+    std::collections::BTreeMap,
+    std::path::{Path, PathBuf},
+    std::sync::{Arc, Mutex},
+    tempdir::TempDir,
+};
+
+/// What temporary directories are used for.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum DirectoryRole {
+    /// Compiling, linking and other scratch work.
+    Scratch,
+
+    /// Payloads and file content spilled from memory.
+    Spill,
+
+    /// Installing packages with `pip` or `setup.py`.
+    PipBuild,
+}
+
+impl std::fmt::Display for DirectoryRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            DirectoryRole::Scratch => "scratch",
+            DirectoryRole::Spill => "spill",
+            DirectoryRole::PipBuild => "pip build",
+        })
+    }
+}
+
+/// Whether temporary directories are removed when their operation completes.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RetentionPolicy {
+    /// Always remove them.
+    Remove,
+
+    /// Keep them if the operation failed.
+    KeepOnFailure,
+
+    /// Never remove them.
+    Keep,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        RetentionPolicy::Remove
+    }
+}
+
+/// Directories builds write temporary and cached files in.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct BuildDirectories {
+    /// Root of temporary directories.
+    ///
+    /// The system temporary directory is used if not defined.
+    pub scratch_dir: Option<PathBuf>,
+
+    /// Default directory of the persistent build cache.
+    pub cache_dir: Option<PathBuf>,
+
+    /// Directory spilled payloads and file content are written to.
+    ///
+    /// The scratch directory is used if not defined.
+    pub spill_dir: Option<PathBuf>,
+
+    /// Directory packages are built and installed in by `pip` and `setup.py`.
+    ///
+    /// The scratch directory is used if not defined.
+    pub pip_build_dir: Option<PathBuf>,
+
+    /// Whether temporary directories are removed when their operation completes.
+    pub retention: RetentionPolicy,
+}
+
+impl BuildDirectories {
+    /// Obtain the directory temporary directories of a role are created in.
+    pub fn root(&self, role: DirectoryRole) -> PathBuf {
+        let configured = match role {
+            DirectoryRole::Scratch => None,
+            DirectoryRole::Spill => self.spill_dir.as_ref(),
+            DirectoryRole::PipBuild => self.pip_build_dir.as_ref(),
+        };
+
+        configured
+            .or_else(|| self.scratch_dir.as_ref())
+            .cloned()
+            .unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Verify configured directories exist and can be used.
+    ///
+    /// Directories must be writable. Directories files are executed from
+    /// must also allow executing files.
+    pub fn validate(&self) -> Result<()> {
+        for (name, path, executable) in &[
+            ("scratch", &self.scratch_dir, true),
+            ("cache", &self.cache_dir, false),
+            ("spill", &self.spill_dir, false),
+            ("pip build", &self.pip_build_dir, true),
+        ] {
+            if let Some(path) = path {
+                validate_directory(path, *executable)
+                    .with_context(|| format!("validating {} directory", name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Create a temporary directory for an operation.
+    ///
+    /// Bytes written to the directory are recorded in `usage` when it is
+    /// dropped.
+    pub fn create(
+        &self,
+        role: DirectoryRole,
+        prefix: &str,
+        usage: &DirectoryUsage,
+    ) -> Result<ScratchDir> {
+        let root = self.root(role);
+
+        let dir = TempDir::new_in(&root, prefix)
+            .with_context(|| format!("creating {} directory in {}", role, root.display()))?;
+
+        Ok(ScratchDir {
+            dir: Some(dir),
+            role,
+            retention: self.retention,
+            usage: usage.clone(),
+            succeeded: false,
+        })
+    }
+}
+
+fn validate_directory(path: &Path, executable: bool) -> Result<()> {
+    if !path.is_dir() {
+        return Err(anyhow!("{} is not a directory", path.display()));
+    }
+
+    let probe = TempDir::new_in(path, "pyoxidizer-probe")
+        .with_context(|| format!("{} is not writable", path.display()))?;
+
+    if executable {
+        validate_executable(probe.path())
+            .with_context(|| format!("{} doesn't allow executing files", path.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn validate_executable(dir: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let script = dir.join("probe.sh");
+    std::fs::write(&script, "#!/bin/sh\nexit 0\n")?;
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755))?;
+
+    if std::process::Command::new(&script).status()?.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} failed", script.display()))
+    }
+}
+
+#[cfg(not(unix))]
+fn validate_executable(_dir: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Bytes written to temporary directories, by role.
+///
+/// Clones share the same counts.
+#[derive(Clone, Debug, Default)]
+pub struct DirectoryUsage {
+    bytes_written: Arc<Mutex<BTreeMap<DirectoryRole, u64>>>,
+}
+
+impl DirectoryUsage {
+    /// Record bytes written to a directory of a role.
+    pub fn record(&self, role: DirectoryRole, bytes: u64) {
+        *self.bytes_written.lock().unwrap().entry(role).or_insert(0) += bytes;
+    }
+
+    /// Obtain bytes written so far, by role.
+    pub fn bytes_written(&self) -> BTreeMap<DirectoryRole, u64> {
+        self.bytes_written.lock().unwrap().clone()
+    }
+}
+
+/// A temporary directory of an operation.
+///
+/// It is removed when dropped according to the retention policy. The
+/// operation is considered failed unless `succeed()` is called.
+#[derive(Debug)]
+pub struct ScratchDir {
+    dir: Option<TempDir>,
+    role: DirectoryRole,
+    retention: RetentionPolicy,
+    usage: DirectoryUsage,
+    succeeded: bool,
+}
+
+impl ScratchDir {
+    /// Obtain the path of the directory.
+    pub fn path(&self) -> &Path {
+        self.dir
+            .as_ref()
+            .expect("directory is present until dropped")
+            .path()
+    }
+
+    /// Record that the operation using the directory succeeded and release it.
+    pub fn succeed(mut self) {
+        self.succeeded = true;
+    }
+
+    /// Record that the operation using the directory succeeded and keep holding it.
+    ///
+    /// The directory is released when the returned value is dropped.
+    pub fn into_succeeded(mut self) -> Self {
+        self.succeeded = true;
+        self
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            self.usage
+                .record(self.role, path_size(dir.path()).unwrap_or(0));
+
+            let keep = match self.retention {
+                RetentionPolicy::Remove => false,
+                RetentionPolicy::KeepOnFailure => !self.succeeded,
+                RetentionPolicy::Keep => true,
+            };
+
+            if keep {
+                dir.into_path();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_root() {
+        let directories = BuildDirectories::default();
+        assert_eq!(directories.root(DirectoryRole::Spill), std::env::temp_dir());
+
+        let directories = BuildDirectories {
+            scratch_dir: Some(PathBuf::from("/scratch")),
+            pip_build_dir: Some(PathBuf::from("/pip")),
+            ..BuildDirectories::default()
+        };
+        assert_eq!(
+            directories.root(DirectoryRole::Scratch),
+            PathBuf::from("/scratch")
+        );
+        assert_eq!(
+            directories.root(DirectoryRole::Spill),
+            PathBuf::from("/scratch")
+        );
+        assert_eq!(
+            directories.root(DirectoryRole::PipBuild),
+            PathBuf::from("/pip")
+        );
+    }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let directories = BuildDirectories {
+            scratch_dir: Some(td.path().to_path_buf()),
+            cache_dir: Some(td.path().to_path_buf()),
+            ..BuildDirectories::default()
+        };
+        directories.validate()?;
+
+        let directories = BuildDirectories {
+            spill_dir: Some(td.path().join("missing")),
+            ..BuildDirectories::default()
+        };
+        assert_eq!(
+            format!("{:#}", directories.validate().unwrap_err()),
+            format!(
+                "validating spill directory: {} is not a directory",
+                td.path().join("missing").display()
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_retention() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let usage = DirectoryUsage::default();
+
+        let mut directories = BuildDirectories {
+            scratch_dir: Some(td.path().to_path_buf()),
+            retention: RetentionPolicy::KeepOnFailure,
+            ..BuildDirectories::default()
+        };
+
+        let succeeded = directories.create(DirectoryRole::Scratch, "ok", &usage)?;
+        std::fs::write(succeeded.path().join("file"), vec![0; 10])?;
+        let succeeded_path = succeeded.path().to_path_buf();
+        succeeded.succeed();
+        assert!(!succeeded_path.exists());
+
+        let failed = directories.create(DirectoryRole::PipBuild, "failed", &usage)?;
+        std::fs::write(failed.path().join("file"), vec![0; 5])?;
+        let failed_path = failed.path().to_path_buf();
+        drop(failed);
+        assert!(failed_path.join("file").exists());
+
+        directories.retention = RetentionPolicy::Remove;
+        let failed = directories.create(DirectoryRole::Spill, "failed", &usage)?;
+        let failed_path = failed.path().to_path_buf();
+        drop(failed);
+        assert!(!failed_path.exists());
+
+        let mut expected = BTreeMap::new();
+        expected.insert(DirectoryRole::Scratch, 10);
+        expected.insert(DirectoryRole::Spill, 0);
+        expected.insert(DirectoryRole::PipBuild, 5);
+        assert_eq!(usage.bytes_written(), expected);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,510 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Reuse compiled bytecode across builds.
+
+Builds compile the same sources, most of them from the standard library,
+over and over. `CachingBytecodeCompiler` wraps a `PythonBytecodeCompiler`
+and keeps its output in the `bytecode` cache of a `BuildCache`. Entries are
+keyed by the SHA-256 of the source, the module name, the optimization level,
+the bytecode cache tag, the compiler's magic number, whether the compiler is
+reproducible and the output mode, so any change to what the compiler would
+produce misses the cache.
+
+Unused entries are evicted by age and total size when a session is finished,
+per the `GcPolicy` of the `BytecodeCache`.
+
+Builds driven by configuration files use a cache in the build directory
+unless `PYOXIDIZER_NO_BYTECODE_CACHE` is set, e.g. by
+`pyoxidizer build --no-bytecode-cache`.
+*/
+
+use {
+    super::build_cache::{BuildCache, CacheSession, GcPolicy},
+    anyhow::{anyhow, Context, Result},
+    python_packaging::bytecode::{CompileMode, PythonBytecodeCompiler},
+    python_packaging::resource::BytecodeOptimizationLevel,
+    sha2::{Digest, Sha256},
+    std::path::{Path, PathBuf},
+    uuid::Uuid,
+};
+
+/// Name of the build cache holding compiled bytecode.
+pub const BYTECODE_CACHE: &str = "bytecode";
+
+/// Environment variable defining the directory of the bytecode cache.
+pub const BYTECODE_CACHE_DIR_ENV: &str = "PYOXIDIZER_BYTECODE_CACHE_DIR";
+
+/// Environment variable disabling the bytecode cache when set to a value other than `0`.
+pub const NO_BYTECODE_CACHE_ENV: &str = "PYOXIDIZER_NO_BYTECODE_CACHE";
+
+/// Environment variable defining the maximum size in bytes of cached bytecode.
+pub const BYTECODE_CACHE_MAX_SIZE_ENV: &str = "PYOXIDIZER_BYTECODE_CACHE_MAX_SIZE";
+
+/// Environment variable defining the maximum age in seconds of unused cached bytecode.
+pub const BYTECODE_CACHE_MAX_AGE_ENV: &str = "PYOXIDIZER_BYTECODE_CACHE_MAX_AGE";
+
+/// Default maximum size of cached bytecode: 512 MiB.
+const DEFAULT_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Default maximum age of unused cached bytecode: 30 days.
+const DEFAULT_MAX_AGE_SECONDS: u64 = 30 * 24 * 60 * 60;
+
+/// A bytecode cache and the limits its entries are evicted by.
+#[derive(Clone, Debug)]
+pub struct BytecodeCache {
+    cache: BuildCache,
+    gc: GcPolicy,
+}
+
+impl BytecodeCache {
+    /// Construct an instance.
+    pub fn new(cache: BuildCache, gc: GcPolicy) -> Self {
+        Self { cache, gc }
+    }
+
+    /// Construct an instance from the environment.
+    ///
+    /// Returns `None` if `PYOXIDIZER_NO_BYTECODE_CACHE` disables the cache.
+    /// `default_dir` is the cache directory if `PYOXIDIZER_BYTECODE_CACHE_DIR`
+    /// isn't set. Entries unused for 30 days are evicted and the cache is
+    /// limited to 512 MiB unless the `PYOXIDIZER_BYTECODE_CACHE_MAX_*`
+    /// variables say otherwise.
+    pub fn from_env(default_dir: &Path) -> Result<Option<Self>> {
+        if let Some(value) = std::env::var_os(NO_BYTECODE_CACHE_ENV) {
+            if !value.is_empty() && value != "0" {
+                return Ok(None);
+            }
+        }
+
+        let dir = match std::env::var_os(BYTECODE_CACHE_DIR_ENV) {
+            Some(dir) => PathBuf::from(dir),
+            None => default_dir.to_path_buf(),
+        };
+
+        let gc = GcPolicy {
+            max_size: Some(env_u64(BYTECODE_CACHE_MAX_SIZE_ENV)?.unwrap_or(DEFAULT_MAX_SIZE)),
+            max_age_seconds: Some(
+                env_u64(BYTECODE_CACHE_MAX_AGE_ENV)?.unwrap_or(DEFAULT_MAX_AGE_SECONDS),
+            ),
+        };
+
+        Ok(Some(Self::new(BuildCache::new(&dir)?, gc)))
+    }
+
+    /// The cache directory holding bytecode.
+    pub fn build_cache(&self) -> &BuildCache {
+        &self.cache
+    }
+
+    /// Limits enforced when a session using the cache is finished.
+    pub fn gc_policy(&self) -> &GcPolicy {
+        &self.gc
+    }
+}
+
+fn env_u64(name: &str) -> Result<Option<u64>> {
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value.parse().with_context(|| {
+            format!("parsing {}: expected an integer; got {}", name, value)
+        })?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Obtain the key bytecode compiled from source is cached under.
+pub fn bytecode_cache_key(
+    source: &[u8],
+    name: &str,
+    optimize: BytecodeOptimizationLevel,
+    cache_tag: &str,
+    magic_number: u32,
+    reproducible: bool,
+    mode: CompileMode,
+) -> String {
+    let mut hasher = Sha256::new();
+
+    // Fields are length prefixed so their boundaries are unambiguous.
+    let mut update = |field: &str, value: &[u8]| {
+        hasher.update(field.as_bytes());
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    };
+
+    update("source-sha256", &Sha256::digest(source));
+    update("name", name.as_bytes());
+    update("optimize", &[i32::from(optimize) as u8]);
+    update("cache-tag", cache_tag.as_bytes());
+    update("magic-number", &magic_number.to_le_bytes());
+    update("reproducible", &[reproducible as u8]);
+    update("mode", format!("{:?}", mode).as_bytes());
+
+    hex::encode(hasher.finalize())
+}
+
+/// A `PythonBytecodeCompiler` reusing bytecode from a cache.
+///
+/// Lookups are counted in the statistics of the session.
+pub struct CachingBytecodeCompiler<'a> {
+    compiler: &'a mut dyn PythonBytecodeCompiler,
+    session: &'a mut CacheSession,
+    cache_tag: String,
+}
+
+impl<'a> CachingBytecodeCompiler<'a> {
+    /// Construct an instance caching the output of `compiler` in `session`.
+    ///
+    /// `cache_tag` is the bytecode cache tag of the Python distribution
+    /// `compiler` belongs to.
+    pub fn new(
+        compiler: &'a mut dyn PythonBytecodeCompiler,
+        session: &'a mut CacheSession,
+        cache_tag: &str,
+    ) -> Self {
+        Self {
+            compiler,
+            session,
+            cache_tag: cache_tag.to_string(),
+        }
+    }
+
+    fn store(&mut self, key: &str, bytecode: &[u8]) -> Result<()> {
+        let path = self.session.build_cache().entry_path(BYTECODE_CACHE, key);
+        let parent = path
+            .parent()
+            .ok_or_else(|| anyhow!("cache entry {} has no parent", path.display()))?;
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("creating {}", parent.display()))?;
+
+        // Concurrent builds never observe partially written entries.
+        let temp_path = parent.join(format!("{}.{}.tmp", key, Uuid::new_v4()));
+        std::fs::write(&temp_path, bytecode)
+            .with_context(|| format!("writing {}", temp_path.display()))?;
+        std::fs::rename(&temp_path, &path)
+            .with_context(|| format!("replacing {}", path.display()))?;
+
+        self.session.insert(BYTECODE_CACHE, key)?;
+
+        Ok(())
+    }
+}
+
+impl<'a> PythonBytecodeCompiler for CachingBytecodeCompiler<'a> {
+    fn get_magic_number(&self) -> u32 {
+        self.compiler.get_magic_number()
+    }
+
+    fn is_reproducible(&self) -> bool {
+        self.compiler.is_reproducible()
+    }
+
+    fn compile(
+        &mut self,
+        source: &[u8],
+        filename: &str,
+        optimize: BytecodeOptimizationLevel,
+        output_mode: CompileMode,
+    ) -> Result<Vec<u8>> {
+        let key = bytecode_cache_key(
+            source,
+            filename,
+            optimize,
+            &self.cache_tag,
+            self.compiler.get_magic_number(),
+            self.compiler.is_reproducible(),
+            output_mode,
+        );
+
+        if let Some(path) = self.session.get(BYTECODE_CACHE, &key)? {
+            return std::fs::read(&path).with_context(|| format!("reading {}", path.display()));
+        }
+
+        let bytecode = self
+            .compiler
+            .compile(source, filename, optimize, output_mode)?;
+        self.store(&key, &bytecode)
+            .with_context(|| format!("caching bytecode of {}", filename))?;
+
+        Ok(bytecode)
+    }
+
+    fn compile_levels(
+        &mut self,
+        source: &[u8],
+        filename: &str,
+        optimize_levels: &[BytecodeOptimizationLevel],
+        output_mode: CompileMode,
+    ) -> Result<Vec<Vec<u8>>> {
+        let mut res = Vec::with_capacity(optimize_levels.len());
+        let mut misses = Vec::new();
+
+        for optimize in optimize_levels {
+            let key = bytecode_cache_key(
+                source,
+                filename,
+                *optimize,
+                &self.cache_tag,
+                self.compiler.get_magic_number(),
+                self.compiler.is_reproducible(),
+                output_mode,
+            );
+
+            match self.session.get(BYTECODE_CACHE, &key)? {
+                Some(path) => res.push(Some(
+                    std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?,
+                )),
+                None => {
+                    res.push(None);
+                    misses.push((res.len() - 1, *optimize, key));
+                }
+            }
+        }
+
+        if !misses.is_empty() {
+            // Levels missing from the cache are compiled together.
+            let levels = misses
+                .iter()
+                .map(|(_, optimize, _)| *optimize)
+                .collect::<Vec<_>>();
+            let compiled = self
+                .compiler
+                .compile_levels(source, filename, &levels, output_mode)?;
+
+            for ((index, _, key), bytecode) in misses.into_iter().zip(compiled) {
+                self.store(&key, &bytecode)
+                    .with_context(|| format!("caching bytecode of {}", filename))?;
+                res[index] = Some(bytecode);
+            }
+        }
+
+        res.into_iter()
+            .map(|bytecode| bytecode.ok_or_else(|| anyhow!("compiler returned no bytecode")))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::py_packaging::build_cache::CacheStatistics,
+        python_packaging::bytecode::{PycSourceHash, PycTimestamp},
+    };
+
+    /// A compiler counting how often it is invoked.
+    struct CountingCompiler {
+        magic_number: u32,
+        invocations: usize,
+    }
+
+    impl PythonBytecodeCompiler for CountingCompiler {
+        fn get_magic_number(&self) -> u32 {
+            self.magic_number
+        }
+
+        fn compile(
+            &mut self,
+            source: &[u8],
+            filename: &str,
+            optimize: BytecodeOptimizationLevel,
+            _output_mode: CompileMode,
+        ) -> Result<Vec<u8>> {
+            self.invocations += 1;
+
+            Ok(format!(
+                "{}:{}:{}",
+                filename,
+                i32::from(optimize),
+                String::from_utf8_lossy(source)
+            )
+            .into_bytes())
+        }
+    }
+
+    #[test]
+    fn test_bytecode_cache_key() {
+        let key = |source: &[u8], name, optimize, cache_tag, magic_number, mode| {
+            bytecode_cache_key(source, name, optimize, cache_tag, magic_number, false, mode)
+        };
+
+        let base = key(
+            b"x = 1",
+            "foo",
+            BytecodeOptimizationLevel::Zero,
+            "cpython-38",
+            42,
+            CompileMode::Bytecode,
+        );
+
+        assert_eq!(
+            base,
+            key(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-38",
+                42,
+                CompileMode::Bytecode,
+            )
+        );
+
+        for other in &[
+            key(
+                b"x = 2",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-38",
+                42,
+                CompileMode::Bytecode,
+            ),
+            key(
+                b"x = 1",
+                "bar",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-38",
+                42,
+                CompileMode::Bytecode,
+            ),
+            key(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Two,
+                "cpython-38",
+                42,
+                CompileMode::Bytecode,
+            ),
+            key(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-39",
+                42,
+                CompileMode::Bytecode,
+            ),
+            key(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-38",
+                43,
+                CompileMode::Bytecode,
+            ),
+            key(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-38",
+                42,
+                CompileMode::PycCheckedHash(PycSourceHash::Source),
+            ),
+            key(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-38",
+                42,
+                CompileMode::PycTimestamp(PycTimestamp::Fixed(1)),
+            ),
+            bytecode_cache_key(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                "cpython-38",
+                42,
+                true,
+                CompileMode::Bytecode,
+            ),
+        ] {
+            assert_ne!(&base, other);
+        }
+    }
+
+    #[test]
+    fn test_caching_compiler() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BuildCache::new(temp_dir.path())?;
+        let mut session = cache.session()?;
+        let mut inner = CountingCompiler {
+            magic_number: 42,
+            invocations: 0,
+        };
+
+        {
+            let mut compiler = CachingBytecodeCompiler::new(&mut inner, &mut session, "cpython-38");
+
+            let first = compiler.compile(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+            )?;
+            let second = compiler.compile(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                CompileMode::Bytecode,
+            )?;
+            assert_eq!(first, b"foo:0:x = 1".to_vec());
+            assert_eq!(first, second);
+
+            compiler.compile(
+                b"x = 1",
+                "foo",
+                BytecodeOptimizationLevel::One,
+                CompileMode::Bytecode,
+            )?;
+        }
+
+        assert_eq!(inner.invocations, 2);
+        assert_eq!(
+            session.statistics().get(BYTECODE_CACHE),
+            Some(&CacheStatistics { hits: 1, misses: 2 })
+        );
+        drop(session);
+
+        // Later sessions reuse entries, unless the compiler changed.
+        let mut session = cache.session()?;
+        CachingBytecodeCompiler::new(&mut inner, &mut session, "cpython-38").compile(
+            b"x = 1",
+            "foo",
+            BytecodeOptimizationLevel::One,
+            CompileMode::Bytecode,
+        )?;
+        assert_eq!(inner.invocations, 2);
+
+        inner.magic_number = 43;
+        CachingBytecodeCompiler::new(&mut inner, &mut session, "cpython-38").compile(
+            b"x = 1",
+            "foo",
+            BytecodeOptimizationLevel::One,
+            CompileMode::Bytecode,
+        )?;
+        assert_eq!(inner.invocations, 3);
+
+        // Only levels missing from the cache are compiled.
+        let bytecode = CachingBytecodeCompiler::new(&mut inner, &mut session, "cpython-38")
+            .compile_levels(
+                b"x = 1",
+                "foo",
+                &[
+                    BytecodeOptimizationLevel::Zero,
+                    BytecodeOptimizationLevel::One,
+                    BytecodeOptimizationLevel::Two,
+                ],
+                CompileMode::Bytecode,
+            )?;
+        assert_eq!(
+            bytecode,
+            vec![
+                b"foo:0:x = 1".to_vec(),
+                b"foo:1:x = 1".to_vec(),
+                b"foo:2:x = 1".to_vec()
+            ]
+        );
+        assert_eq!(inner.invocations, 5);
+
+        Ok(())
+    }
+}
@@ -6,6 +6,27 @@
 Configuring a Python interpreter.
 */
 
+use {
+    super::analysis_verdicts::AnalysisVerdicts,
+    super::binary::LibpythonLinkMode,
+    python_packaging::module_util::packages_from_module_name,
+    python_packed_resources::data::Resource,
+    serde::{Deserialize, Serialize},
+    std::collections::{BTreeMap, BTreeSet},
+};
+
+/// Whether a resource has source or bytecode a module can be run from.
+fn has_module_code(resource: &Resource<u8>) -> bool {
+    resource.in_memory_source.is_some()
+        || resource.in_memory_bytecode.is_some()
+        || resource.in_memory_bytecode_opt1.is_some()
+        || resource.in_memory_bytecode_opt2.is_some()
+        || resource.relative_path_module_source.is_some()
+        || resource.relative_path_module_bytecode.is_some()
+        || resource.relative_path_module_bytecode_opt1.is_some()
+        || resource.relative_path_module_bytecode_opt2.is_some()
+}
+
 /// Determine the default raw allocator for a target triple.
 pub fn default_raw_allocator(target_triple: &str) -> RawAllocator {
     // Jemalloc doesn't work on Windows.
@@ -19,14 +40,14 @@ pub fn default_raw_allocator(target_triple: &str) -> RawAllocator {
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum RawAllocator {
     Jemalloc,
     Rust,
     System,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum RunMode {
     Noop,
     Repl,
@@ -36,15 +57,67 @@ pub enum RunMode {
 }
 
 /// How the `terminfo` database is resolved at run-time.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum TerminfoResolution {
     Dynamic,
     None,
     Static(String),
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// How hashes of `str` and `bytes` objects are randomized.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum HashRandomization {
+    /// Use a random seed for every process.
+    Random,
+    /// Use the seed from `hash_seed`.
+    Fixed,
+    /// Disable hash randomization.
+    Disabled,
+}
+
+impl Default for HashRandomization {
+    fn default() -> Self {
+        HashRandomization::Random
+    }
+}
+
+/// Isolation settings for subinterpreters created by the application.
+///
+/// Mirrors `pyembed::SubinterpreterConfig`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct SubinterpreterConfig {
+    pub use_main_obmalloc: bool,
+    pub allow_fork: bool,
+    pub allow_exec: bool,
+    pub allow_threads: bool,
+    pub allow_daemon_threads: bool,
+    pub check_multi_interp_extensions: bool,
+    pub own_gil: bool,
+}
+
+impl Default for SubinterpreterConfig {
+    fn default() -> Self {
+        SubinterpreterConfig {
+            use_main_obmalloc: false,
+            allow_fork: false,
+            allow_exec: false,
+            allow_threads: true,
+            allow_daemon_threads: false,
+            check_multi_interp_extensions: true,
+            own_gil: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
 pub struct EmbeddedPythonConfig {
+    /// Python environment variables other settings may read at run-time.
+    ///
+    /// Only used at build time to allow settings to read a variable Python
+    /// itself uses, like `write_modules_directory_env = "PYTHONPATH"`.
+    pub allowed_python_env_collisions: Vec<String>,
     pub bytes_warning: i32,
     pub ignore_environment: bool,
     pub inspect: bool,
@@ -54,14 +127,41 @@ pub struct EmbeddedPythonConfig {
     pub legacy_windows_stdio: bool,
     pub optimize_level: i64,
     pub parser_debug: bool,
+    pub preload_modules: Vec<String>,
     pub stdio_encoding_name: Option<String>,
     pub stdio_encoding_errors: Option<String>,
     pub unbuffered_stdio: bool,
     pub filesystem_importer: bool,
+    /// How hashes are randomized.
+    pub hash_randomization: HashRandomization,
+    /// Hash seed to use when `hash_randomization` is `Fixed`.
+    pub hash_seed: Option<u32>,
+    /// Path template of a file recording imports, written on exit.
+    ///
+    /// `{pid}` expands to the process ID. `None` disables import telemetry.
+    /// Only meant for internal builds gathering data to prune resources.
+    pub import_telemetry_path: Option<String>,
     pub quiet: bool,
     pub raw_allocator: RawAllocator,
     pub run_mode: RunMode,
+    /// Directory template to extract embedded shared libraries to.
+    ///
+    /// `$ORIGIN` expands to the directory of the executable and `$CACHE_DIR`
+    /// to the per-user cache directory. `None` disables extraction.
+    pub shared_library_extraction_dir: Option<String>,
     pub site_import: bool,
+    /// Directory relative to the executable reported as site-packages.
+    ///
+    /// Resources of `site_packages_shim_packages` are installed in it.
+    pub site_packages_shim: Option<String>,
+    /// Top-level packages installed in the site-packages shim directory.
+    pub site_packages_shim_packages: Vec<String>,
+    /// Settings for subinterpreters. `Some` declares the application uses them.
+    pub subinterpreters: Option<SubinterpreterConfig>,
+    /// Extension modules known to work in subinterpreters.
+    ///
+    /// Only used at build time to silence compatibility warnings.
+    pub subinterpreter_safe_extensions: Vec<String>,
     pub sys_frozen: bool,
     pub sys_meipass: bool,
     pub sys_paths: Vec<String>,
@@ -76,6 +176,7 @@ pub struct EmbeddedPythonConfig {
 impl Default for EmbeddedPythonConfig {
     fn default() -> Self {
         EmbeddedPythonConfig {
+            allowed_python_env_collisions: Vec::new(),
             bytes_warning: 0,
             ignore_environment: true,
             inspect: false,
@@ -85,6 +186,7 @@ impl Default for EmbeddedPythonConfig {
             legacy_windows_stdio: false,
             optimize_level: 0,
             parser_debug: false,
+            preload_modules: Vec::new(),
             quiet: false,
             stdio_encoding_name: None,
             stdio_encoding_errors: None,
@@ -92,7 +194,15 @@ impl Default for EmbeddedPythonConfig {
             use_hash_seed: false,
             verbose: 0,
             filesystem_importer: false,
+            hash_randomization: HashRandomization::Random,
+            hash_seed: None,
+            import_telemetry_path: None,
+            shared_library_extraction_dir: Some("$CACHE_DIR/pyoxidizer".to_string()),
             site_import: false,
+            site_packages_shim: None,
+            site_packages_shim_packages: Vec::new(),
+            subinterpreters: None,
+            subinterpreter_safe_extensions: Vec::new(),
             sys_frozen: false,
             sys_meipass: false,
             sys_paths: Vec::new(),
@@ -105,3 +215,1273 @@ impl Default for EmbeddedPythonConfig {
         }
     }
 }
+
+impl EmbeddedPythonConfig {
+    /// Obtain the hash seed the interpreter is initialized with.
+    ///
+    /// `None` means a random seed is used. A seed of 0 disables hash
+    /// randomization.
+    pub fn resolved_hash_seed(&self) -> Option<u32> {
+        match self.hash_randomization {
+            HashRandomization::Random => None,
+            HashRandomization::Fixed => self.hash_seed,
+            HashRandomization::Disabled => Some(0),
+        }
+    }
+}
+
+/// Environment variables Python reads unless `ignore_environment` is set.
+pub const PYTHON_ENV_VARS: &[&str] = &[
+    "PYTHONASYNCIODEBUG",
+    "PYTHONBREAKPOINT",
+    "PYTHONCASEOK",
+    "PYTHONCOERCECLOCALE",
+    "PYTHONDEBUG",
+    "PYTHONDEVMODE",
+    "PYTHONDONTWRITEBYTECODE",
+    "PYTHONEXECUTABLE",
+    "PYTHONFAULTHANDLER",
+    "PYTHONHASHSEED",
+    "PYTHONHOME",
+    "PYTHONINSPECT",
+    "PYTHONIOENCODING",
+    "PYTHONLEGACYWINDOWSFSENCODING",
+    "PYTHONLEGACYWINDOWSSTDIO",
+    "PYTHONMALLOC",
+    "PYTHONNOUSERSITE",
+    "PYTHONOPTIMIZE",
+    "PYTHONPATH",
+    "PYTHONPROFILEIMPORTTIME",
+    "PYTHONPYCACHEPREFIX",
+    "PYTHONSTARTUP",
+    "PYTHONTRACEMALLOC",
+    "PYTHONUNBUFFERED",
+    "PYTHONUSERBASE",
+    "PYTHONUTF8",
+    "PYTHONVERBOSE",
+    "PYTHONWARNINGS",
+];
+
+/// The setting making Python read `PYTHON_ENV_VARS`.
+pub const PYTHON_ENV_SETTING: &str = "ignore_python_env";
+
+/// Environment variables a generated `PythonConfig` reads at run-time.
+///
+/// Each variable maps to the `PythonConfig` settings that read it.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(transparent)]
+pub struct RuntimeEnvRegistry {
+    vars: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl RuntimeEnvRegistry {
+    /// Record that a setting reads an environment variable.
+    pub fn register(&mut self, name: &str, setting: &str) {
+        self.vars
+            .entry(name.to_string())
+            .or_insert_with(BTreeSet::new)
+            .insert(setting.to_string());
+    }
+
+    /// Whether no environment variables are read.
+    pub fn is_empty(&self) -> bool {
+        self.vars.is_empty()
+    }
+
+    /// Names of the environment variables read, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        self.vars.keys().map(|name| name.as_str()).collect()
+    }
+
+    /// Obtain variables and the settings reading them.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &BTreeSet<String>)> {
+        self.vars.iter()
+    }
+
+    /// Obtain the settings reading a variable.
+    pub fn settings(&self, name: &str) -> Option<&BTreeSet<String>> {
+        self.vars.get(name)
+    }
+}
+
+/// Severity of a problem with a configuration.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConfigProblemSeverity {
+    /// The configuration works but likely doesn't do what was intended.
+    Warning,
+    /// The configuration will not work.
+    Error,
+}
+
+/// Describes a problem with an `EmbeddedPythonConfig`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigProblem {
+    pub severity: ConfigProblemSeverity,
+    /// Stable identifier of the rule that detected the problem.
+    pub code: &'static str,
+    /// Explanation of the problem and how to fix it.
+    pub message: String,
+}
+
+impl std::fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+/// Validate an `EmbeddedPythonConfig` against how the binary is built.
+///
+/// `resources` are the final resources that will be embedded in the binary.
+/// They are used to determine how resources will be loaded at run-time.
+///
+/// Returns all problems found. Problems with severity `Error` should prevent
+/// the binary from being built.
+pub fn validate_embedded_python_config(
+    config: &EmbeddedPythonConfig,
+    link_mode: LibpythonLinkMode,
+    target_triple: &str,
+    supports_in_memory_extension_loading: bool,
+    resources: &BTreeMap<String, Resource<u8>>,
+) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+    let windows = target_triple.contains("pc-windows");
+
+    let mut problem = |severity, code, message: String| {
+        problems.push(ConfigProblem {
+            severity,
+            code,
+            message,
+        })
+    };
+
+    if config.raw_allocator == RawAllocator::Jemalloc && windows {
+        problem(
+            ConfigProblemSeverity::Error,
+            "jemalloc-windows",
+            "jemalloc is not supported on Windows; use the system or Rust allocator".to_string(),
+        );
+    }
+
+    let in_memory_extensions = resources
+        .values()
+        .filter(|r| r.in_memory_extension_module_shared_library.is_some())
+        .map(|r| r.name.as_ref())
+        .collect::<Vec<_>>();
+
+    if !in_memory_extensions.is_empty()
+        && (link_mode != LibpythonLinkMode::Dynamic || !supports_in_memory_extension_loading)
+    {
+        problem(
+            ConfigProblemSeverity::Error,
+            "in-memory-extension-unsupported",
+            format!(
+                "extension modules {} are loaded from memory, which requires dynamically linking a libpython that supports loading extension modules from memory; install them on the filesystem or use a distribution with this capability",
+                in_memory_extensions.join(", ")
+            ),
+        );
+    }
+
+    if let RunMode::Module { module } = &config.run_mode {
+        if !config.filesystem_importer && !resources.contains_key(module) {
+            problem(
+                ConfigProblemSeverity::Error,
+                "run-module-missing",
+                format!(
+                    "module {} to run is not packaged and the filesystem importer is disabled; package the module or enable the filesystem importer",
+                    module
+                ),
+            );
+        }
+
+        // Like `python -m`, running a module imports its parent packages
+        // first and running a package runs its __main__ module. What isn't
+        // packaged may still be found by the filesystem importer.
+        let severity = if config.filesystem_importer {
+            ConfigProblemSeverity::Warning
+        } else {
+            ConfigProblemSeverity::Error
+        };
+
+        if let Some(resource) = resources.get(module) {
+            let parents = packages_from_module_name(module);
+            let missing_parents = parents
+                .iter()
+                .filter_map(|parent| match resources.get(parent) {
+                    Some(r) if r.is_package => None,
+                    Some(_) => Some(format!("{} (a module, not a package)", parent)),
+                    None => Some(format!("{} (not packaged)", parent)),
+                })
+                .collect::<Vec<_>>();
+
+            if !missing_parents.is_empty() {
+                problem(
+                    severity,
+                    "run-module-parent-missing",
+                    format!(
+                        "module {} to run requires parent packages {}; checked resources {}; package the parent packages or run another module",
+                        module,
+                        missing_parents.join(", "),
+                        parents.iter().cloned().collect::<Vec<_>>().join(", ")
+                    ),
+                );
+            }
+
+            if resource.is_package {
+                let main = format!("{}.__main__", module);
+
+                if !resources.get(&main).map(has_module_code).unwrap_or(false) {
+                    problem(
+                        severity,
+                        "run-package-main-missing",
+                        format!(
+                            "module {} to run is a package, which requires module {} ({}/__main__.py); checked resource {} for source or bytecode in memory or relative to the binary and found none; add {}/__main__.py or run a module of the package",
+                            module,
+                            main,
+                            module.replace('.', "/"),
+                            main,
+                            module.replace('.', "/")
+                        ),
+                    );
+                }
+            } else if !has_module_code(resource) {
+                problem(
+                    severity,
+                    "run-module-code-missing",
+                    format!(
+                        "module {} to run has no code; checked resource {} for source or bytecode in memory or relative to the binary and found none; package the module's source or bytecode",
+                        module, module
+                    ),
+                );
+            }
+        }
+    }
+
+    let missing_preload_modules = config
+        .preload_modules
+        .iter()
+        .filter(|m| !resources.contains_key(*m))
+        .map(|m| m.as_str())
+        .collect::<Vec<_>>();
+
+    if !missing_preload_modules.is_empty() && !config.filesystem_importer {
+        problem(
+            ConfigProblemSeverity::Error,
+            "preload-module-missing",
+            format!(
+                "modules {} to preload are not packaged and the filesystem importer is disabled; package the modules or remove them from preload_modules",
+                missing_preload_modules.join(", ")
+            ),
+        );
+    }
+
+    let extracted_libraries = resources
+        .values()
+        .filter(|r| r.extract_shared_library)
+        .map(|r| r.name.as_ref())
+        .collect::<Vec<_>>();
+
+    if !extracted_libraries.is_empty() && config.shared_library_extraction_dir.is_none() {
+        problem(
+            ConfigProblemSeverity::Error,
+            "extracted-library-without-directory",
+            format!(
+                "shared libraries {} are extracted at run-time but no extraction directory is configured; set shared_library_extraction_dir",
+                extracted_libraries.join(", ")
+            ),
+        );
+    }
+
+    if config.use_hash_seed && config.hash_randomization != HashRandomization::Random {
+        problem(
+            ConfigProblemSeverity::Error,
+            "use-hash-seed-with-hash-randomization",
+            "use_hash_seed conflicts with hash_randomization, which sets the hash seed itself; remove use_hash_seed".to_string(),
+        );
+    }
+
+    match (config.hash_randomization, config.hash_seed) {
+        (HashRandomization::Fixed, None) => problem(
+            ConfigProblemSeverity::Error,
+            "hash-seed-missing",
+            "hash_randomization is fixed but no hash_seed is set; set hash_seed".to_string(),
+        ),
+        (HashRandomization::Random, Some(_)) | (HashRandomization::Disabled, Some(_)) => problem(
+            ConfigProblemSeverity::Warning,
+            "hash-seed-without-fixed",
+            "hash_seed has no effect unless hash_randomization is fixed".to_string(),
+        ),
+        _ => {}
+    }
+
+    if !config.sys_paths.is_empty() && !config.filesystem_importer {
+        problem(
+            ConfigProblemSeverity::Warning,
+            "sys-paths-without-filesystem-importer",
+            "sys_paths has no effect unless the filesystem importer is enabled".to_string(),
+        );
+    }
+
+    if config.write_bytecode && !config.filesystem_importer {
+        problem(
+            ConfigProblemSeverity::Warning,
+            "write-bytecode-without-filesystem-importer",
+            "write_bytecode has no effect unless the filesystem importer is enabled".to_string(),
+        );
+    }
+
+    if config.user_site_directory && !config.site_import {
+        problem(
+            ConfigProblemSeverity::Warning,
+            "user-site-without-site-import",
+            "user_site_directory has no effect unless site_import is enabled".to_string(),
+        );
+    }
+
+    if !config.site_packages_shim_packages.is_empty() && config.site_packages_shim.is_none() {
+        problem(
+            ConfigProblemSeverity::Warning,
+            "site-packages-shim-packages-without-directory",
+            "site_packages_shim_packages has no effect unless site_packages_shim is set"
+                .to_string(),
+        );
+    }
+
+    if (config.legacy_windows_fs_encoding || config.legacy_windows_stdio) && !windows {
+        problem(
+            ConfigProblemSeverity::Warning,
+            "legacy-windows-options",
+            "legacy_windows_fs_encoding and legacy_windows_stdio only have an effect on Windows; disable them".to_string(),
+        );
+    }
+
+    if config.terminfo_resolution != TerminfoResolution::None && windows {
+        problem(
+            ConfigProblemSeverity::Warning,
+            "terminfo-windows",
+            "terminfo is not used on Windows; set terminfo_resolution to none".to_string(),
+        );
+    }
+
+    if let Some(subinterpreters) = &config.subinterpreters {
+        if subinterpreters.own_gil && subinterpreters.use_main_obmalloc {
+            problem(
+                ConfigProblemSeverity::Error,
+                "subinterpreter-own-gil-shared-obmalloc",
+                "subinterpreters with their own GIL cannot share the main interpreter's object allocator; disable use_main_obmalloc".to_string(),
+            );
+        }
+
+        if !subinterpreters.use_main_obmalloc && !subinterpreters.check_multi_interp_extensions {
+            problem(
+                ConfigProblemSeverity::Error,
+                "subinterpreter-unchecked-extensions",
+                "subinterpreters with their own object allocator must reject incompatible extension modules; enable check_multi_interp_extensions".to_string(),
+            );
+        }
+    }
+
+    problems
+}
+
+/// Validate the environment variables a generated `PythonConfig` reads.
+///
+/// Settings other than `ignore_python_env` reading a variable Python itself
+/// uses is an error, unless the variable is in
+/// `allowed_python_env_collisions`. If `forbid_runtime_env` is set, reading
+/// any variable is an error.
+pub fn validate_runtime_env(
+    config: &EmbeddedPythonConfig,
+    registry: &RuntimeEnvRegistry,
+    forbid_runtime_env: bool,
+) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+
+    for (name, settings) in registry.iter() {
+        if !PYTHON_ENV_VARS.contains(&name.as_str())
+            || config.allowed_python_env_collisions.contains(name)
+        {
+            continue;
+        }
+
+        for setting in settings.iter().filter(|s| *s != PYTHON_ENV_SETTING) {
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "runtime-env-python-collision",
+                message: format!(
+                    "{} reads {}, which Python itself uses; use another variable or add it to allowed_python_env_collisions",
+                    setting, name
+                ),
+            });
+        }
+    }
+
+    if forbid_runtime_env && !registry.is_empty() {
+        let mut by_setting = BTreeMap::new();
+        for (name, settings) in registry.iter() {
+            for setting in settings {
+                by_setting
+                    .entry(setting.as_str())
+                    .or_insert_with(Vec::new)
+                    .push(name.as_str());
+            }
+        }
+
+        problems.push(ConfigProblem {
+            severity: ConfigProblemSeverity::Error,
+            code: "runtime-env-forbidden",
+            message: format!(
+                "the packaging policy forbids reading environment variables at run-time, but {}",
+                by_setting
+                    .iter()
+                    .map(|(setting, names)| format!("{} reads {}", setting, names.join(", ")))
+                    .collect::<Vec<_>>()
+                    .join("; ")
+            ),
+        });
+    }
+
+    problems
+}
+
+/// Extension modules known to be incompatible with subinterpreters.
+const SUBINTERPRETER_INCOMPATIBLE_EXTENSIONS: &[&str] = &[
+    "_curses",
+    "_curses_panel",
+    "_tkinter",
+    "numpy.core._multiarray_umath",
+    "readline",
+];
+
+/// How an extension module initializes itself.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExtensionInitPhase {
+    /// The module is created directly by its init function.
+    Single,
+    /// The init function returns a module definition (PEP 489).
+    Multi,
+    /// Initialization could not be determined.
+    Unknown,
+}
+
+/// Guess how an extension module initializes from the symbols its binary references.
+///
+/// `data` holds the shared library or object files of the extension. Init
+/// functions using multi-phase initialization return `PyModuleDef_Init()`
+/// while single-phase initialization calls `PyModule_Create2()`.
+pub fn detect_extension_init_phase(data: &[Vec<u8>]) -> ExtensionInitPhase {
+    let references = |symbol: &[u8]| {
+        data.iter()
+            .any(|d| d.windows(symbol.len()).any(|w| w == symbol))
+    };
+
+    if references(b"PyModuleDef_Init") {
+        ExtensionInitPhase::Multi
+    } else if references(b"PyModule_Create2") {
+        ExtensionInitPhase::Single
+    } else {
+        ExtensionInitPhase::Unknown
+    }
+}
+
+/// Validate an `EmbeddedPythonConfig` against the build profile.
+///
+/// Some settings are meant for debugging and testing and are suspicious
+/// in release builds.
+pub fn validate_release_config(config: &EmbeddedPythonConfig, release: bool) -> Vec<ConfigProblem> {
+    let mut problems = Vec::new();
+
+    if release && config.hash_randomization == HashRandomization::Fixed {
+        problems.push(ConfigProblem {
+            severity: ConfigProblemSeverity::Warning,
+            code: "hash-seed-fixed-release",
+            message: "hash randomization uses a fixed seed in a release build, which makes hashes predictable; use a fixed seed only for reproducible testing".to_string(),
+        });
+    }
+
+    if release && config.import_telemetry_path.is_some() {
+        problems.push(ConfigProblem {
+            severity: ConfigProblemSeverity::Warning,
+            code: "import-telemetry-release",
+            message: "import telemetry is enabled in a release build, which records imports to a file on every exit; only enable it in internal builds".to_string(),
+        });
+    }
+
+    problems
+}
+
+/// Find embedded extension modules that likely don't work in subinterpreters.
+///
+/// `extensions` maps extension module names to the shared library or object
+/// files backing them. Extensions are flagged if they are in a table of
+/// known incompatible extensions or use single-phase initialization.
+/// `subinterpreter_safe` verdicts of static analysis take precedence over
+/// these heuristics, with disagreements reported. Extensions in
+/// `subinterpreter_safe_extensions` are never flagged.
+///
+/// Nothing is checked unless the configuration declares use of
+/// subinterpreters.
+pub fn validate_subinterpreter_extensions(
+    config: &EmbeddedPythonConfig,
+    extensions: &BTreeMap<String, Vec<Vec<u8>>>,
+    verdicts: &AnalysisVerdicts,
+) -> Vec<ConfigProblem> {
+    if config.subinterpreters.is_none() {
+        return Vec::new();
+    }
+
+    let mut problems = Vec::new();
+
+    let incompatible = extensions
+        .iter()
+        .filter(|(name, _)| !config.subinterpreter_safe_extensions.contains(name))
+        .filter_map(|(name, data)| {
+            let heuristic = if SUBINTERPRETER_INCOMPATIBLE_EXTENSIONS.contains(&name.as_str()) {
+                Some("known incompatible")
+            } else if detect_extension_init_phase(data) == ExtensionInitPhase::Single {
+                Some("single-phase initialization")
+            } else {
+                None
+            };
+
+            match (verdicts.subinterpreter_safe(name), heuristic) {
+                (Some(true), Some(reason)) => {
+                    problems.push(ConfigProblem {
+                        severity: ConfigProblemSeverity::Warning,
+                        code: "analysis-verdict-conflict",
+                        message: format!(
+                            "{}: subinterpreter_safe is true but the extension module is flagged ({}); following the analysis verdict",
+                            name, reason
+                        ),
+                    });
+                    None
+                }
+                (Some(true), None) => None,
+                (Some(false), heuristic) => {
+                    if heuristic.is_none() {
+                        problems.push(ConfigProblem {
+                            severity: ConfigProblemSeverity::Warning,
+                            code: "analysis-verdict-conflict",
+                            message: format!(
+                                "{}: subinterpreter_safe is false but the extension module isn't flagged; following the analysis verdict",
+                                name
+                            ),
+                        });
+                    }
+                    Some(format!("{} (static analysis)", name))
+                }
+                (None, heuristic) => heuristic.map(|reason| format!("{} ({})", name, reason)),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if !incompatible.is_empty() {
+        problems.push(ConfigProblem {
+            severity: ConfigProblemSeverity::Warning,
+            code: "subinterpreter-incompatible-extension",
+            message: format!(
+                "extension modules {} likely fail to load in subinterpreters; add them to subinterpreter_safe_extensions if they are known to work",
+                incompatible.join(", ")
+            ),
+        });
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packed_resources::data::ResourceFlavor, std::borrow::Cow};
+
+    const LINUX: &str = "x86_64-unknown-linux-gnu";
+    const WINDOWS: &str = "x86_64-pc-windows-msvc";
+
+    fn resources(in_memory_extension: bool) -> BTreeMap<String, Resource<'static, u8>> {
+        let mut res = BTreeMap::new();
+
+        res.insert(
+            "foo".to_string(),
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Borrowed("foo"),
+                in_memory_source: Some(Cow::Borrowed(&b"import bar"[..])),
+                ..Resource::default()
+            },
+        );
+
+        if in_memory_extension {
+            res.insert(
+                "_ext".to_string(),
+                Resource {
+                    flavor: ResourceFlavor::Extension,
+                    name: Cow::Borrowed("_ext"),
+                    in_memory_extension_module_shared_library: Some(Cow::Borrowed(&b"MZ"[..])),
+                    ..Resource::default()
+                },
+            );
+        }
+
+        res
+    }
+
+    #[test]
+    fn test_default_config_valid() {
+        for target in &[LINUX, WINDOWS] {
+            for link_mode in &[LibpythonLinkMode::Static, LibpythonLinkMode::Dynamic] {
+                assert!(validate_embedded_python_config(
+                    &EmbeddedPythonConfig::default(),
+                    *link_mode,
+                    target,
+                    false,
+                    &resources(false),
+                )
+                .is_empty());
+            }
+        }
+    }
+
+    #[test]
+    fn test_rules() {
+        let default = EmbeddedPythonConfig::default();
+
+        // (config, link mode, target, in-memory extension support,
+        //  in-memory extension present, expected codes)
+        let cases: Vec<(
+            EmbeddedPythonConfig,
+            LibpythonLinkMode,
+            &str,
+            bool,
+            bool,
+            Vec<(ConfigProblemSeverity, &str)>,
+        )> = vec![
+            (
+                EmbeddedPythonConfig {
+                    raw_allocator: RawAllocator::Jemalloc,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                WINDOWS,
+                false,
+                false,
+                vec![(ConfigProblemSeverity::Error, "jemalloc-windows")],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    raw_allocator: RawAllocator::Jemalloc,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                default.clone(),
+                LibpythonLinkMode::Dynamic,
+                WINDOWS,
+                true,
+                true,
+                vec![],
+            ),
+            (
+                default.clone(),
+                LibpythonLinkMode::Static,
+                WINDOWS,
+                true,
+                true,
+                vec![(
+                    ConfigProblemSeverity::Error,
+                    "in-memory-extension-unsupported",
+                )],
+            ),
+            (
+                default.clone(),
+                LibpythonLinkMode::Dynamic,
+                LINUX,
+                false,
+                true,
+                vec![(
+                    ConfigProblemSeverity::Error,
+                    "in-memory-extension-unsupported",
+                )],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    run_mode: RunMode::Module {
+                        module: "foo".to_string(),
+                    },
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    run_mode: RunMode::Module {
+                        module: "missing".to_string(),
+                    },
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![(ConfigProblemSeverity::Error, "run-module-missing")],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    run_mode: RunMode::Module {
+                        module: "missing".to_string(),
+                    },
+                    filesystem_importer: true,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    preload_modules: vec!["foo".to_string()],
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    preload_modules: vec!["foo".to_string(), "missing".to_string()],
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![(ConfigProblemSeverity::Error, "preload-module-missing")],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    sys_paths: vec!["$ORIGIN/lib".to_string()],
+                    write_bytecode: true,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![
+                    (
+                        ConfigProblemSeverity::Warning,
+                        "sys-paths-without-filesystem-importer",
+                    ),
+                    (
+                        ConfigProblemSeverity::Warning,
+                        "write-bytecode-without-filesystem-importer",
+                    ),
+                ],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    sys_paths: vec!["$ORIGIN/lib".to_string()],
+                    write_bytecode: true,
+                    filesystem_importer: true,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    user_site_directory: true,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![(
+                    ConfigProblemSeverity::Warning,
+                    "user-site-without-site-import",
+                )],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    site_packages_shim_packages: vec!["foo".to_string()],
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![(
+                    ConfigProblemSeverity::Warning,
+                    "site-packages-shim-packages-without-directory",
+                )],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    site_packages_shim: Some("site-packages".to_string()),
+                    site_packages_shim_packages: vec!["foo".to_string()],
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    legacy_windows_stdio: true,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![(ConfigProblemSeverity::Warning, "legacy-windows-options")],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    legacy_windows_stdio: true,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                WINDOWS,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    terminfo_resolution: TerminfoResolution::Dynamic,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                WINDOWS,
+                false,
+                false,
+                vec![(ConfigProblemSeverity::Warning, "terminfo-windows")],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    terminfo_resolution: TerminfoResolution::Dynamic,
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    subinterpreters: Some(SubinterpreterConfig::default()),
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    subinterpreters: Some(SubinterpreterConfig {
+                        use_main_obmalloc: true,
+                        ..SubinterpreterConfig::default()
+                    }),
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![(
+                    ConfigProblemSeverity::Error,
+                    "subinterpreter-own-gil-shared-obmalloc",
+                )],
+            ),
+            (
+                EmbeddedPythonConfig {
+                    subinterpreters: Some(SubinterpreterConfig {
+                        check_multi_interp_extensions: false,
+                        ..SubinterpreterConfig::default()
+                    }),
+                    ..default.clone()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                false,
+                vec![(
+                    ConfigProblemSeverity::Error,
+                    "subinterpreter-unchecked-extensions",
+                )],
+            ),
+        ];
+
+        for (i, (config, link_mode, target, supports_in_memory, in_memory, expected)) in
+            cases.iter().enumerate()
+        {
+            let problems = validate_embedded_python_config(
+                config,
+                *link_mode,
+                target,
+                *supports_in_memory,
+                &resources(*in_memory),
+            );
+
+            assert_eq!(
+                problems
+                    .iter()
+                    .map(|p| (p.severity, p.code))
+                    .collect::<Vec<_>>(),
+                *expected,
+                "case {}",
+                i
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_module_package() {
+        let module = |name: &'static str, is_package: bool, code: bool| {
+            (
+                name.to_string(),
+                Resource {
+                    flavor: ResourceFlavor::Module,
+                    name: Cow::Borrowed(name),
+                    is_package,
+                    in_memory_bytecode: if code {
+                        Some(Cow::Borrowed(&b"code"[..]))
+                    } else {
+                        None
+                    },
+                    ..Resource::default()
+                },
+            )
+        };
+
+        let resources = vec![
+            module("app", true, true),
+            module("app.__main__", false, true),
+            module("app.cli", false, true),
+            module("app.cli.sub", false, true),
+            module("lib", true, true),
+            module("lib.util", false, true),
+            module("orphan.tool", false, true),
+            module("codeless", false, false),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        let problems = |module: &str, filesystem_importer: bool| {
+            validate_embedded_python_config(
+                &EmbeddedPythonConfig {
+                    run_mode: RunMode::Module {
+                        module: module.to_string(),
+                    },
+                    filesystem_importer,
+                    ..EmbeddedPythonConfig::default()
+                },
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                &resources,
+            )
+            .into_iter()
+            .map(|p| (p.severity, p.code, p.message))
+            .collect::<Vec<_>>()
+        };
+
+        // Package with __main__ and plain modules.
+        assert!(problems("app", false).is_empty());
+        assert!(problems("app.cli", false).is_empty());
+        assert!(problems("lib.util", false).is_empty());
+
+        // Package without __main__.
+        assert_eq!(
+            problems("lib", false),
+            vec![(
+                ConfigProblemSeverity::Error,
+                "run-package-main-missing",
+                "module lib to run is a package, which requires module lib.__main__ (lib/__main__.py); checked resource lib.__main__ for source or bytecode in memory or relative to the binary and found none; add lib/__main__.py or run a module of the package".to_string()
+            )]
+        );
+        assert_eq!(
+            problems("lib", true)
+                .into_iter()
+                .map(|(severity, code, _)| (severity, code))
+                .collect::<Vec<_>>(),
+            vec![(ConfigProblemSeverity::Warning, "run-package-main-missing")]
+        );
+
+        // Plain modules whose parents aren't packages.
+        assert_eq!(
+            problems("orphan.tool", false),
+            vec![(
+                ConfigProblemSeverity::Error,
+                "run-module-parent-missing",
+                "module orphan.tool to run requires parent packages orphan (not packaged); checked resources orphan; package the parent packages or run another module".to_string()
+            )]
+        );
+        assert!(problems("app.cli.sub", false)[0]
+            .2
+            .contains("requires parent packages app.cli (a module, not a package)"));
+        assert_eq!(
+            problems("app.cli.main", false)
+                .into_iter()
+                .map(|(_, code, _)| code)
+                .collect::<Vec<_>>(),
+            vec!["run-module-missing"]
+        );
+
+        // Plain modules without code.
+        assert_eq!(
+            problems("codeless", false),
+            vec![(
+                ConfigProblemSeverity::Error,
+                "run-module-code-missing",
+                "module codeless to run has no code; checked resource codeless for source or bytecode in memory or relative to the binary and found none; package the module's source or bytecode".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_extracted_library_directory() {
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "mylib".to_string(),
+            Resource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: Cow::Owned("mylib".to_string()),
+                in_memory_shared_library: Some(Cow::Owned(vec![42])),
+                extract_shared_library: true,
+                ..Resource::default()
+            },
+        );
+
+        let mut config = EmbeddedPythonConfig::default();
+        assert!(validate_embedded_python_config(
+            &config,
+            LibpythonLinkMode::Static,
+            LINUX,
+            false,
+            &resources
+        )
+        .is_empty());
+
+        config.shared_library_extraction_dir = None;
+        let problems = validate_embedded_python_config(
+            &config,
+            LibpythonLinkMode::Static,
+            LINUX,
+            false,
+            &resources,
+        );
+        assert_eq!(
+            problems
+                .iter()
+                .map(|p| (p.severity, p.code))
+                .collect::<Vec<_>>(),
+            vec![(
+                ConfigProblemSeverity::Error,
+                "extracted-library-without-directory"
+            )]
+        );
+    }
+
+    #[test]
+    fn test_hash_randomization() {
+        let resources = BTreeMap::new();
+        let codes = |config: &EmbeddedPythonConfig| {
+            validate_embedded_python_config(
+                config,
+                LibpythonLinkMode::Static,
+                LINUX,
+                false,
+                &resources,
+            )
+            .iter()
+            .map(|p| (p.severity, p.code))
+            .collect::<Vec<_>>()
+        };
+
+        let mut config = EmbeddedPythonConfig::default();
+        assert!(codes(&config).is_empty());
+        assert_eq!(config.resolved_hash_seed(), None);
+
+        config.hash_randomization = HashRandomization::Fixed;
+        assert_eq!(
+            codes(&config),
+            vec![(ConfigProblemSeverity::Error, "hash-seed-missing")]
+        );
+
+        config.hash_seed = Some(42);
+        assert!(codes(&config).is_empty());
+        assert_eq!(config.resolved_hash_seed(), Some(42));
+        assert!(validate_release_config(&config, false).is_empty());
+        assert_eq!(
+            validate_release_config(&config, true)
+                .iter()
+                .map(|p| (p.severity, p.code))
+                .collect::<Vec<_>>(),
+            vec![(ConfigProblemSeverity::Warning, "hash-seed-fixed-release")]
+        );
+
+        config.hash_randomization = HashRandomization::Disabled;
+        assert_eq!(
+            codes(&config),
+            vec![(ConfigProblemSeverity::Warning, "hash-seed-without-fixed")]
+        );
+        assert_eq!(config.resolved_hash_seed(), Some(0));
+        assert!(validate_release_config(&config, true).is_empty());
+
+        config.hash_seed = None;
+        config.use_hash_seed = true;
+        assert_eq!(
+            codes(&config),
+            vec![(
+                ConfigProblemSeverity::Error,
+                "use-hash-seed-with-hash-randomization"
+            )]
+        );
+
+        config.hash_randomization = HashRandomization::Random;
+        assert!(codes(&config).is_empty());
+    }
+
+    #[test]
+    fn test_import_telemetry_release() {
+        let mut config = EmbeddedPythonConfig::default();
+        assert!(validate_release_config(&config, true).is_empty());
+
+        config.import_telemetry_path = Some("/tmp/imports-{pid}".to_string());
+        assert!(validate_release_config(&config, false).is_empty());
+        assert_eq!(
+            validate_release_config(&config, true)
+                .iter()
+                .map(|p| (p.severity, p.code))
+                .collect::<Vec<_>>(),
+            vec![(ConfigProblemSeverity::Warning, "import-telemetry-release")]
+        );
+    }
+
+    #[test]
+    fn test_detect_extension_init_phase() {
+        assert_eq!(
+            detect_extension_init_phase(&[b"\0PyInit_foo\0PyModuleDef_Init\0".to_vec()]),
+            ExtensionInitPhase::Multi
+        );
+        assert_eq!(
+            detect_extension_init_phase(&[
+                b"\0PyInit_foo\0".to_vec(),
+                b"\0PyModule_Create2\0".to_vec()
+            ]),
+            ExtensionInitPhase::Single
+        );
+        assert_eq!(
+            detect_extension_init_phase(&[b"\0PyInit_foo\0".to_vec()]),
+            ExtensionInitPhase::Unknown
+        );
+        assert_eq!(
+            detect_extension_init_phase(&[]),
+            ExtensionInitPhase::Unknown
+        );
+    }
+
+    #[test]
+    fn test_validate_runtime_env() {
+        let mut registry = RuntimeEnvRegistry::default();
+        registry.register("PYTHONPATH", PYTHON_ENV_SETTING);
+        registry.register("HOME", "shared_library_extraction_dir");
+
+        let mut config = EmbeddedPythonConfig::default();
+        assert!(validate_runtime_env(&config, &registry, false).is_empty());
+
+        registry.register("PYTHONPATH", "write_modules_directory_env");
+        let problems = validate_runtime_env(&config, &registry, false);
+        assert_eq!(
+            problems.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            vec!["runtime-env-python-collision: write_modules_directory_env reads PYTHONPATH, which Python itself uses; use another variable or add it to allowed_python_env_collisions"]
+        );
+
+        config.allowed_python_env_collisions = vec!["PYTHONPATH".to_string()];
+        assert!(validate_runtime_env(&config, &registry, false).is_empty());
+
+        let problems = validate_runtime_env(&config, &registry, true);
+        assert_eq!(
+            problems.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            vec!["runtime-env-forbidden: the packaging policy forbids reading environment variables at run-time, but ignore_python_env reads PYTHONPATH; shared_library_extraction_dir reads HOME; write_modules_directory_env reads PYTHONPATH"]
+        );
+        assert!(validate_runtime_env(&config, &RuntimeEnvRegistry::default(), true).is_empty());
+    }
+
+    #[test]
+    fn test_subinterpreter_extensions() -> anyhow::Result<()> {
+        let mut extensions = BTreeMap::new();
+        extensions.insert("readline".to_string(), vec![]);
+        extensions.insert("single".to_string(), vec![b"PyModule_Create2".to_vec()]);
+        extensions.insert("multi".to_string(), vec![b"PyModuleDef_Init".to_vec()]);
+        extensions.insert("unknown".to_string(), vec![]);
+
+        let verdicts = AnalysisVerdicts::default();
+
+        // Nothing is checked unless subinterpreters are used.
+        assert!(validate_subinterpreter_extensions(
+            &EmbeddedPythonConfig::default(),
+            &extensions,
+            &verdicts
+        )
+        .is_empty());
+
+        let mut config = EmbeddedPythonConfig {
+            subinterpreters: Some(SubinterpreterConfig::default()),
+            ..EmbeddedPythonConfig::default()
+        };
+
+        let problems = validate_subinterpreter_extensions(&config, &extensions, &verdicts);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].severity, ConfigProblemSeverity::Warning);
+        assert_eq!(
+            problems[0].to_string(),
+            "subinterpreter-incompatible-extension: extension modules readline (known incompatible), single (single-phase initialization) likely fail to load in subinterpreters; add them to subinterpreter_safe_extensions if they are known to work"
+        );
+
+        // Analysis verdicts take precedence over the heuristics.
+        let verdicts = AnalysisVerdicts::from_json(
+            br#"{"version": 1, "packages": {"single": {"subinterpreter_safe": true}, "multi": {"subinterpreter_safe": false}}}"#,
+        )?;
+        let problems = validate_subinterpreter_extensions(&config, &extensions, &verdicts);
+        assert_eq!(
+            problems.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            vec![
+                "analysis-verdict-conflict: multi: subinterpreter_safe is false but the extension module isn't flagged; following the analysis verdict",
+                "analysis-verdict-conflict: single: subinterpreter_safe is true but the extension module is flagged (single-phase initialization); following the analysis verdict",
+                "subinterpreter-incompatible-extension: extension modules multi (static analysis), readline (known incompatible) likely fail to load in subinterpreters; add them to subinterpreter_safe_extensions if they are known to work",
+            ]
+        );
+
+        // Users can override the compatibility table and the heuristic.
+        config.subinterpreter_safe_extensions = vec![
+            "multi".to_string(),
+            "readline".to_string(),
+            "single".to_string(),
+        ];
+        assert!(validate_subinterpreter_extensions(&config, &extensions, &verdicts).is_empty());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,372 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Debug companions of production builds.
+
+Production builds commonly omit module sources and embed optimized
+bytecode, which makes crashes from the field hard to debug. A debug
+companion is a tar archive holding what was left out:
+
+* `sources/` - the source of every module in the build that has one.
+* `bytecode/` - unoptimized bytecode of those modules.
+* `extensions/` - shared libraries of extension modules as they were added,
+  before being thinned.
+* `build-manifest.json` - the manifest of the production build.
+
+Archives are named after the content digest of the production build's
+manifest, so a production build can be matched to its companion. A
+companion is derived from the production build and never feeds back into
+it.
+*/
+
+use {
+    super::artifact_writer::ArtifactWriter,
+    super::update_package::BuildManifest,
+    crate::app_packaging::resource::{FileContent, FileManifest},
+    anyhow::{Context, Result},
+    python_packaging::bytecode::{CompileMode, PycSourceHash, PythonBytecodeCompiler},
+    python_packaging::module_util::resolve_path_for_module,
+    python_packaging::resource::{BytecodeOptimizationLevel, DataLocation},
+    python_packaging::resource_collection::{PrePackagedResource, PythonModuleBytecodeProvider},
+    python_packed_resources::data::ResourceFlavor,
+    std::collections::BTreeMap,
+    std::path::{Path, PathBuf},
+};
+
+/// Filename of the build manifest within a debug companion.
+pub const COMPANION_MANIFEST_FILENAME: &str = "build-manifest.json";
+
+/// Files to debug a production build.
+#[derive(Clone, Debug)]
+pub struct DebugCompanion {
+    /// Content digest of the production build.
+    pub build_digest: String,
+
+    /// Manifest of the production build.
+    pub manifest: BuildManifest,
+
+    /// Sources, bytecode and extension modules, keyed by archive path.
+    pub files: FileManifest,
+}
+
+/// Obtain the source of a module resource, if it has one.
+///
+/// Sources are found even if the build only contains bytecode compiled
+/// from them.
+fn module_source(resource: &PrePackagedResource) -> Option<&DataLocation> {
+    if let Some(location) = &resource.in_memory_source {
+        return Some(location);
+    }
+    if let Some((_, location)) = &resource.relative_path_module_source {
+        return Some(location);
+    }
+
+    let providers = vec![
+        resource.in_memory_bytecode.as_ref(),
+        resource.in_memory_bytecode_opt1.as_ref(),
+        resource.in_memory_bytecode_opt2.as_ref(),
+        resource.relative_path_bytecode.as_ref().map(|(_, _, p)| p),
+        resource
+            .relative_path_bytecode_opt1
+            .as_ref()
+            .map(|(_, _, p)| p),
+        resource
+            .relative_path_bytecode_opt2
+            .as_ref()
+            .map(|(_, _, p)| p),
+    ];
+
+    providers.into_iter().find_map(|provider| match provider {
+        Some(PythonModuleBytecodeProvider::FromSource(location)) => Some(location),
+        _ => None,
+    })
+}
+
+impl DebugCompanion {
+    /// Construct an instance from the resources of a production build.
+    ///
+    /// `resources` are the resources in the build. `extension_binaries`
+    /// holds the archive path and shared library of extension modules, as
+    /// they were before being thinned. Only extension modules present in
+    /// `resources` are included.
+    pub fn from_resources<'a>(
+        resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+        extension_binaries: &BTreeMap<String, (PathBuf, DataLocation)>,
+        compiler: &mut dyn PythonBytecodeCompiler,
+        cache_tag: &str,
+        manifest: BuildManifest,
+    ) -> Result<Self> {
+        let mut files = FileManifest::default();
+        let bytecode_tag = BytecodeOptimizationLevel::Zero.to_bytecode_tag(cache_tag);
+
+        for (name, resource) in resources {
+            match resource.flavor {
+                ResourceFlavor::Module => {
+                    let source = match module_source(resource) {
+                        Some(source) => source.resolve()?,
+                        None => continue,
+                    };
+
+                    let bytecode = compiler
+                        .compile(
+                            &source,
+                            name,
+                            BytecodeOptimizationLevel::Zero,
+                            CompileMode::PycUncheckedHash(PycSourceHash::Source),
+                        )
+                        .with_context(|| format!("compiling {} for debug companion", name))?;
+
+                    files.add_file(
+                        &resolve_path_for_module("sources", name, resource.is_package, None),
+                        &FileContent {
+                            data: DataLocation::Memory(source),
+                            executable: false,
+                        },
+                    )?;
+                    files.add_file(
+                        &resolve_path_for_module(
+                            "bytecode",
+                            name,
+                            resource.is_package,
+                            Some(&bytecode_tag),
+                        ),
+                        &FileContent {
+                            data: DataLocation::Memory(bytecode),
+                            executable: false,
+                        },
+                    )?;
+                }
+                ResourceFlavor::Extension => {
+                    if let Some((path, data)) = extension_binaries.get(name) {
+                        files.add_file(
+                            path,
+                            &FileContent {
+                                data: data.clone(),
+                                executable: false,
+                            },
+                        )?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            build_digest: manifest.content_digest(),
+            manifest,
+            files,
+        })
+    }
+
+    /// Filename of the companion's archive.
+    pub fn archive_filename(&self) -> String {
+        format!("debug-companion-{}.tar", self.build_digest)
+    }
+
+    /// Write the companion as a tar archive in `dest_dir`.
+    ///
+    /// Archives are deterministic. Returns the path of the written archive.
+    pub fn write_archive(&self, dest_dir: &Path) -> Result<PathBuf> {
+        self.write_archive_with_writer(dest_dir, &mut ArtifactWriter::default())
+    }
+
+    /// Write the companion as a tar archive in `dest_dir` using an `ArtifactWriter`.
+    ///
+    /// The archive is assembled in memory so `writer` can report progress
+    /// and replace the archive atomically.
+    pub fn write_archive_with_writer(
+        &self,
+        dest_dir: &Path,
+        writer: &mut ArtifactWriter,
+    ) -> Result<PathBuf> {
+        std::fs::create_dir_all(dest_dir)
+            .with_context(|| format!("creating {}", dest_dir.display()))?;
+        let path = dest_dir.join(self.archive_filename());
+
+        let mut builder = tar::Builder::new(Vec::new());
+        builder.mode(tar::HeaderMode::Deterministic);
+
+        let manifest = serde_json::to_vec_pretty(&self.manifest)?;
+        let entries = self
+            .files
+            .entries()
+            .map(|(path, content)| Ok((path.clone(), content.data.resolve()?)))
+            .chain(std::iter::once(Ok((
+                PathBuf::from(COMPANION_MANIFEST_FILENAME),
+                manifest,
+            ))))
+            .collect::<Result<Vec<_>>>()?;
+
+        for (archive_path, data) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(0);
+            header.set_cksum();
+
+            builder
+                .append_data(&mut header, &archive_path, data.as_slice())
+                .with_context(|| format!("adding {} to debug companion", archive_path.display()))?;
+        }
+
+        let data = builder
+            .into_inner()
+            .context("finishing debug companion archive")?;
+        writer.write("debug companion", &path, &data)?;
+
+        Ok(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packaging::bytecode::BytecodeCompiler, std::io::Read};
+
+    #[test]
+    fn test_write_archive() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let mut files = FileManifest::default();
+        files.add_file(
+            Path::new("sources/foo/__init__.py"),
+            &FileContent {
+                data: DataLocation::Memory(b"import os".to_vec()),
+                executable: false,
+            },
+        )?;
+
+        let manifest = BuildManifest::default();
+        let companion = DebugCompanion {
+            build_digest: manifest.content_digest(),
+            manifest,
+            files,
+        };
+
+        let path = companion.write_archive(td.path())?;
+        assert_eq!(
+            path.file_name().unwrap().to_string_lossy(),
+            format!("debug-companion-{}.tar", companion.build_digest)
+        );
+
+        let mut archive = tar::Archive::new(std::fs::File::open(&path)?);
+        let mut entries = BTreeMap::new();
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let mut data = Vec::new();
+            entry.read_to_end(&mut data)?;
+            entries.insert(entry.path()?.display().to_string(), data);
+        }
+
+        assert_eq!(
+            entries.keys().cloned().collect::<Vec<_>>(),
+            vec![
+                "build-manifest.json".to_string(),
+                "sources/foo/__init__.py".to_string()
+            ]
+        );
+        assert_eq!(
+            serde_json::from_slice::<BuildManifest>(&entries["build-manifest.json"])?,
+            companion.manifest
+        );
+
+        // Writing again produces identical bytes.
+        let first = std::fs::read(&path)?;
+        companion.write_archive(td.path())?;
+        assert_eq!(std::fs::read(&path)?, first);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_resources() -> Result<()> {
+        let dist = crate::testutil::get_default_distribution()?;
+        let mut compiler = BytecodeCompiler::new(&dist.python_exe)?;
+
+        let source = DataLocation::Memory(b"x = 1\n".to_vec());
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "foo".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo".to_string(),
+                is_package: true,
+                in_memory_bytecode_opt2: Some(PythonModuleBytecodeProvider::FromSource(
+                    source.clone(),
+                )),
+                ..PrePackagedResource::default()
+            },
+        );
+        resources.insert(
+            "bar".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "bar".to_string(),
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::Provided(
+                    DataLocation::Memory(b"bytecode".to_vec()),
+                )),
+                ..PrePackagedResource::default()
+            },
+        );
+        resources.insert(
+            "ext".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: "ext".to_string(),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        let mut extension_binaries = BTreeMap::new();
+        extension_binaries.insert(
+            "ext".to_string(),
+            (
+                PathBuf::from("extensions/ext.so"),
+                DataLocation::Memory(b"fat".to_vec()),
+            ),
+        );
+        extension_binaries.insert(
+            "filtered".to_string(),
+            (
+                PathBuf::from("extensions/filtered.so"),
+                DataLocation::Memory(b"filtered".to_vec()),
+            ),
+        );
+
+        let companion = DebugCompanion::from_resources(
+            resources.iter(),
+            &extension_binaries,
+            &mut compiler,
+            &dist.cache_tag,
+            BuildManifest::default(),
+        )?;
+
+        let paths = companion
+            .files
+            .entries()
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            paths,
+            vec![
+                PathBuf::from(format!(
+                    "bytecode/foo/__pycache__/__init__.{}.pyc",
+                    dist.cache_tag
+                )),
+                PathBuf::from("extensions/ext.so"),
+                PathBuf::from("sources/foo/__init__.py"),
+            ]
+        );
+
+        // Source is recovered from bytecode compiled from it.
+        let (_, content) = companion
+            .files
+            .entries()
+            .find(|(path, _)| path.starts_with("sources"))
+            .unwrap();
+        assert_eq!(content.data, source);
+
+        Ok(())
+    }
+}
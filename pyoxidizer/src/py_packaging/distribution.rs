@@ -8,17 +8,20 @@ Defining and manipulating Python distributions.
 
 use {
     super::binary::{LibpythonLinkMode, PythonBinaryBuilder},
+    super::build_directories::BuildDirectories,
     super::config::EmbeddedPythonConfig,
     super::standalone_distribution::StandaloneDistribution,
     crate::python_distributions::PYTHON_DISTRIBUTIONS,
     anyhow::{anyhow, Context, Result},
     fs2::FileExt,
     python_packaging::bytecode::PythonBytecodeCompiler,
+    python_packaging::implementation::InterpreterImplementation,
     python_packaging::module_util::PythonModuleSuffixes,
     python_packaging::policy::PythonPackagingPolicy,
     python_packaging::resource::{
         PythonExtensionModule, PythonModuleSource, PythonPackageResource, PythonResource,
     },
+    serde::{Deserialize, Serialize},
     sha2::{Digest, Sha256},
     slog::warn,
     std::collections::HashMap,
@@ -59,7 +62,8 @@ pub fn is_stdlib_test_package(name: &str) -> bool {
 }
 
 /// Denotes how a binary should link libpython.
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BinaryLibpythonLinkMode {
     /// Use default link mode semantics.
     Default,
@@ -69,7 +73,14 @@ pub enum BinaryLibpythonLinkMode {
     Dynamic,
 }
 
-#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+impl Default for BinaryLibpythonLinkMode {
+    fn default() -> Self {
+        BinaryLibpythonLinkMode::Default
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum PythonDistributionLocation {
     Local { local_path: String, sha256: String },
     Url { url: String, sha256: String },
@@ -102,11 +113,26 @@ pub trait PythonDistribution {
     /// Obtain the cache tag to apply to Python bytecode modules.
     fn cache_tag(&self) -> &str;
 
+    /// Obtain the Python implementation of this distribution.
+    ///
+    /// Bytecode compilation, cache tags and extension module suffixes
+    /// follow this implementation.
+    fn python_implementation(&self) -> InterpreterImplementation;
+
     /// Obtain file suffixes for various Python module flavors.
     fn python_module_suffixes(&self) -> Result<PythonModuleSuffixes>;
 
     /// Create a `PythonBytecodeCompiler` from this instance.
-    fn create_bytecode_compiler(&self) -> Result<Box<dyn PythonBytecodeCompiler>>;
+    ///
+    /// Bytecode is compiled by `python_exe` if defined, otherwise by this
+    /// distribution's `python` executable. `python_exe` allows compiling
+    /// bytecode on machines that can't run the distribution's executable.
+    /// It must write bytecode with the magic number of this distribution's
+    /// Python version.
+    fn create_bytecode_compiler(
+        &self,
+        python_exe: Option<&Path>,
+    ) -> Result<Box<dyn PythonBytecodeCompiler>>;
 
     /// Construct a `PythonPackagingPolicy` derived from this instance.
     fn create_packaging_policy(&self) -> Result<PythonPackagingPolicy>;
@@ -116,7 +142,7 @@ pub trait PythonDistribution {
     /// This method is how you start the process of creating a new executable file
     /// from a Python distribution. Using the returned `PythonBinaryBuilder` instance,
     /// you can manipulate resources, etc and then eventually build a new executable
-    /// with it.
+    /// with it. Temporary files of the builder are written to `build_directories`.
     #[allow(clippy::too_many_arguments)]
     fn as_python_executable_builder(
         &self,
@@ -127,6 +153,7 @@ pub trait PythonDistribution {
         libpython_link_mode: BinaryLibpythonLinkMode,
         policy: &PythonPackagingPolicy,
         config: &EmbeddedPythonConfig,
+        build_directories: &BuildDirectories,
     ) -> Result<Box<dyn PythonBinaryBuilder>>;
 
     /// Obtain `PythonExtensionModule` instances present in this distribution.
@@ -398,7 +425,8 @@ pub fn resolve_python_distribution_from_location(
 }
 
 /// Describes the flavor of a distribution.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum DistributionFlavor {
     /// Distributions coming from the `python-build-standalone` project.
     Standalone,
@@ -0,0 +1,338 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Compare embedded standard library resources against a distribution.
+
+After filtering and pruning, it is hard to tell how much of the standard
+library survived. This module inventories the standard library modules and
+extension modules a distribution provides and reports which of them a
+collection of resources retains, grouped by top-level package.
+
+Sizes are measured with the same functions used to report unused resources,
+so the numbers in both views agree.
+*/
+
+use {
+    super::distribution::PythonDistribution,
+    anyhow::Result,
+    python_packaging::resource_collection::PrePackagedResource,
+    python_packaging::resource_usage::data_location_size,
+    python_packed_resources::data::ResourceFlavor,
+    std::collections::{BTreeMap, BTreeSet},
+    std::io::Write,
+};
+
+/// The standard library modules and extension modules of a distribution.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DistributionInventory {
+    /// Size in bytes of each module's source, keyed by module name.
+    pub modules: BTreeMap<String, u64>,
+
+    /// Size in bytes of each extension module, keyed by module name.
+    ///
+    /// If the distribution provides multiple variants of an extension
+    /// module, the first one is measured.
+    pub extension_modules: BTreeMap<String, u64>,
+}
+
+impl DistributionInventory {
+    /// Construct an instance from the standard library of a distribution.
+    pub fn from_distribution(distribution: &dyn PythonDistribution) -> Result<Self> {
+        let mut res = Self::default();
+
+        for module in distribution.source_modules()? {
+            if module.is_stdlib {
+                res.modules
+                    .insert(module.name.clone(), data_location_size(&module.source)?);
+            }
+        }
+
+        for module in distribution.iter_extension_modules() {
+            if !module.is_stdlib || res.extension_modules.contains_key(&module.name) {
+                continue;
+            }
+
+            let size = if let Some(location) = &module.shared_library {
+                data_location_size(location)?
+            } else {
+                let mut size = 0;
+                for location in &module.object_file_data {
+                    size += data_location_size(location)?;
+                }
+                size
+            };
+
+            res.extension_modules.insert(module.name.clone(), size);
+        }
+
+        Ok(res)
+    }
+
+    /// Compare resources against this inventory.
+    ///
+    /// A module is present if resources define a module of the same name.
+    /// An extension module is included if resources define a built-in or
+    /// regular extension module of the same name. Resources not in the
+    /// inventory are ignored.
+    pub fn coverage<'a>(
+        &self,
+        resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+    ) -> DistributionCoverage {
+        let mut modules = BTreeSet::new();
+        let mut extension_modules = BTreeSet::new();
+
+        for (name, resource) in resources {
+            match resource.flavor {
+                ResourceFlavor::Module => {
+                    modules.insert(name.as_str());
+                }
+                ResourceFlavor::BuiltinExtensionModule | ResourceFlavor::Extension => {
+                    extension_modules.insert(name.as_str());
+                }
+                _ => {}
+            }
+        }
+
+        let mut res = DistributionCoverage::default();
+
+        for (name, size) in &self.modules {
+            let package = res.package_mut(name);
+            package.bytes_available += size;
+
+            if modules.contains(name.as_str()) {
+                package.modules_present.insert(name.clone());
+                package.bytes_retained += size;
+            } else {
+                package.modules_absent.insert(name.clone());
+            }
+        }
+
+        for (name, size) in &self.extension_modules {
+            let package = res.package_mut(name);
+            package.bytes_available += size;
+
+            if extension_modules.contains(name.as_str()) {
+                package.extensions_included.insert(name.clone());
+                package.bytes_retained += size;
+            } else {
+                package.extensions_excluded.insert(name.clone());
+            }
+        }
+
+        res
+    }
+}
+
+/// Coverage of a standard library package.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageCoverage {
+    /// Modules that are retained.
+    pub modules_present: BTreeSet<String>,
+
+    /// Modules the distribution provides that aren't retained.
+    pub modules_absent: BTreeSet<String>,
+
+    /// Extension modules that are retained.
+    pub extensions_included: BTreeSet<String>,
+
+    /// Extension modules the distribution provides that aren't retained.
+    pub extensions_excluded: BTreeSet<String>,
+
+    /// Size in bytes of retained modules and extension modules.
+    pub bytes_retained: u64,
+
+    /// Size in bytes of all modules and extension modules in the distribution.
+    pub bytes_available: u64,
+}
+
+impl PackageCoverage {
+    /// Whether everything the distribution provides is retained.
+    pub fn is_complete(&self) -> bool {
+        self.modules_absent.is_empty() && self.extensions_excluded.is_empty()
+    }
+
+    fn merge(&mut self, other: &PackageCoverage) {
+        self.modules_present
+            .extend(other.modules_present.iter().cloned());
+        self.modules_absent
+            .extend(other.modules_absent.iter().cloned());
+        self.extensions_included
+            .extend(other.extensions_included.iter().cloned());
+        self.extensions_excluded
+            .extend(other.extensions_excluded.iter().cloned());
+        self.bytes_retained += other.bytes_retained;
+        self.bytes_available += other.bytes_available;
+    }
+}
+
+/// Coverage of a distribution's standard library.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DistributionCoverage {
+    /// Per-package coverage, keyed by top-level package name.
+    pub packages: BTreeMap<String, PackageCoverage>,
+}
+
+impl DistributionCoverage {
+    fn package_mut(&mut self, name: &str) -> &mut PackageCoverage {
+        let package = name.split('.').next().unwrap_or(name).to_string();
+
+        self.packages
+            .entry(package)
+            .or_insert_with(PackageCoverage::default)
+    }
+
+    /// Obtain coverage of all packages combined.
+    pub fn total(&self) -> PackageCoverage {
+        let mut res = PackageCoverage::default();
+
+        for package in self.packages.values() {
+            res.merge(package);
+        }
+
+        res
+    }
+
+    /// Write a human readable report.
+    ///
+    /// Packages are listed by name along with what is missing from them.
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for (name, package) in &self.packages {
+            write_coverage_line(writer, name, package)?;
+
+            if !package.modules_absent.is_empty() {
+                writeln!(
+                    writer,
+                    "  absent modules: {}",
+                    package
+                        .modules_absent
+                        .iter()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?;
+            }
+
+            if !package.extensions_excluded.is_empty() {
+                writeln!(
+                    writer,
+                    "  excluded extension modules: {}",
+                    package
+                        .extensions_excluded
+                        .iter()
+                        .cloned()
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )?;
+            }
+        }
+
+        write_coverage_line(writer, "total", &self.total())
+    }
+}
+
+fn write_coverage_line<W: Write>(
+    writer: &mut W,
+    name: &str,
+    coverage: &PackageCoverage,
+) -> Result<()> {
+    writeln!(
+        writer,
+        "{}: {}/{} modules; {}/{} extension modules; {}/{} bytes",
+        name,
+        coverage.modules_present.len(),
+        coverage.modules_present.len() + coverage.modules_absent.len(),
+        coverage.extensions_included.len(),
+        coverage.extensions_included.len() + coverage.extensions_excluded.len(),
+        coverage.bytes_retained,
+        coverage.bytes_available
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packaging::resource::DataLocation};
+
+    fn resource(name: &str, flavor: ResourceFlavor) -> (String, PrePackagedResource) {
+        (
+            name.to_string(),
+            PrePackagedResource {
+                flavor,
+                name: name.to_string(),
+                in_memory_source: Some(DataLocation::Memory(vec![42])),
+                ..PrePackagedResource::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_coverage() -> Result<()> {
+        let mut inventory = DistributionInventory::default();
+        for (name, size) in &[
+            ("json", 10),
+            ("json.decoder", 20),
+            ("json.tool", 30),
+            ("os", 40),
+        ] {
+            inventory.modules.insert(name.to_string(), *size);
+        }
+        for (name, size) in &[("_json", 50), ("_sqlite3", 60)] {
+            inventory.extension_modules.insert(name.to_string(), *size);
+        }
+
+        let resources = vec![
+            resource("json", ResourceFlavor::Module),
+            resource("json.decoder", ResourceFlavor::Module),
+            resource("os", ResourceFlavor::Module),
+            resource("_json", ResourceFlavor::BuiltinExtensionModule),
+            resource("myapp", ResourceFlavor::Module),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        let coverage = inventory.coverage(resources.iter());
+
+        assert_eq!(
+            coverage.packages.keys().cloned().collect::<Vec<_>>(),
+            vec!["_json", "_sqlite3", "json", "os"]
+        );
+
+        let json = &coverage.packages["json"];
+        assert_eq!(
+            json.modules_absent,
+            vec!["json.tool".to_string()].into_iter().collect()
+        );
+        assert_eq!(json.bytes_retained, 30);
+        assert_eq!(json.bytes_available, 60);
+        assert!(!json.is_complete());
+        assert!(coverage.packages["os"].is_complete());
+        assert!(coverage.packages["_json"].is_complete());
+        assert_eq!(
+            coverage.packages["_sqlite3"].extensions_excluded,
+            vec!["_sqlite3".to_string()].into_iter().collect()
+        );
+
+        let total = coverage.total();
+        assert_eq!(total.modules_present.len(), 3);
+        assert_eq!(total.bytes_retained, 10 + 20 + 40 + 50);
+        assert_eq!(total.bytes_available, 10 + 20 + 30 + 40 + 50 + 60);
+
+        let mut report = Vec::new();
+        coverage.write_report(&mut report)?;
+        assert_eq!(
+            String::from_utf8(report)?,
+            "_json: 0/0 modules; 1/1 extension modules; 50/50 bytes\n\
+             _sqlite3: 0/0 modules; 0/1 extension modules; 0/60 bytes\n  \
+             excluded extension modules: _sqlite3\n\
+             json: 2/3 modules; 0/0 extension modules; 30/60 bytes\n  \
+             absent modules: json.tool\n\
+             os: 1/1 modules; 0/0 extension modules; 40/40 bytes\n\
+             total: 3/4 modules; 1/2 extension modules; 120/210 bytes\n"
+        );
+
+        Ok(())
+    }
+}
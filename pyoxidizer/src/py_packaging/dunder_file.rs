@@ -0,0 +1,320 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Verify in-memory modules don't reference `__file__`.
+
+Modules imported from memory don't have `__file__` set. Modules referencing
+it should be relocated to the filesystem or explicitly waived. This module
+implements a final verification pass over resources to ensure nothing slips
+through.
+*/
+
+use {
+    super::config::{ConfigProblem, ConfigProblemSeverity},
+    anyhow::{Context, Result},
+    python_packaging::python_source::has_dunder_file,
+    python_packaging::resource::{DataLocation, FileStamp},
+    python_packaging::resource_collection::{PrePackagedResource, PythonModuleBytecodeProvider},
+    sha2::Digest,
+    std::collections::{BTreeMap, BTreeSet, HashMap},
+    std::path::PathBuf,
+};
+
+/// Identifies a source scanned for `__file__`.
+#[derive(Eq, Hash, PartialEq)]
+enum SourceKey {
+    /// A file, identified by its path, size and modification time.
+    File(PathBuf, FileStamp),
+
+    /// Content not backed by a file, identified by its SHA-256.
+    Content(Vec<u8>),
+}
+
+/// Results of verifying `__file__` usage of in-memory modules.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DunderFileReport {
+    /// In-memory modules referencing `__file__` not covered by a waiver.
+    ///
+    /// Keyed by top-level package name.
+    pub offenders: BTreeMap<String, BTreeSet<String>>,
+
+    /// In-memory modules referencing `__file__` covered by a waiver.
+    pub waived: BTreeSet<String>,
+
+    /// Waiver patterns that didn't match any module referencing `__file__`.
+    pub unused_waivers: Vec<String>,
+}
+
+impl DunderFileReport {
+    /// Convert the report to problems.
+    ///
+    /// Unwaived modules are reported with `severity`. Unused waivers are
+    /// always warnings.
+    pub fn problems(&self, severity: ConfigProblemSeverity) -> Vec<ConfigProblem> {
+        let mut res = self
+            .offenders
+            .iter()
+            .map(|(package, modules)| ConfigProblem {
+                severity,
+                code: "dunder-file-in-memory",
+                message: format!(
+                    "package {} has in-memory modules referencing __file__, which is not set at run-time: {}; load them from the filesystem or add a waiver (see https://github.com/indygreg/PyOxidizer/issues/69)",
+                    package,
+                    modules.iter().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            })
+            .collect::<Vec<_>>();
+
+        res.extend(self.unused_waivers.iter().map(|pattern| ConfigProblem {
+            severity: ConfigProblemSeverity::Warning,
+            code: "dunder-file-unused-waiver",
+            message: format!(
+                "__file__ waiver {} doesn't match any in-memory module referencing __file__",
+                pattern
+            ),
+        }));
+
+        res
+    }
+}
+
+/// Obtain the sources of a resource's in-memory module source and bytecode.
+fn in_memory_sources(resource: &PrePackagedResource) -> Vec<&DataLocation> {
+    let mut res = Vec::new();
+
+    if let Some(location) = &resource.in_memory_source {
+        res.push(location);
+    }
+
+    for provider in &[
+        &resource.in_memory_bytecode,
+        &resource.in_memory_bytecode_opt1,
+        &resource.in_memory_bytecode_opt2,
+    ] {
+        if let Some(PythonModuleBytecodeProvider::FromSource(location)) = *provider {
+            res.push(location);
+        }
+    }
+
+    res
+}
+
+/// Verify in-memory modules referencing `__file__` are waived.
+///
+/// `waivers` are glob patterns matched against module names. Files are keyed
+/// by their path, size and modification time and other sources by their
+/// content hash, so the same source backing module source and multiple
+/// bytecode optimization levels is only scanned once. Files already scanned
+/// aren't read again.
+pub fn verify_dunder_file<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+    waivers: &[String],
+) -> Result<DunderFileReport> {
+    let patterns = waivers
+        .iter()
+        .map(|pattern| {
+            glob::Pattern::new(pattern)
+                .with_context(|| format!("parsing __file__ waiver {}", pattern))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut used = vec![false; patterns.len()];
+
+    let mut cache: HashMap<SourceKey, bool> = HashMap::new();
+    let mut res = DunderFileReport::default();
+
+    for (name, resource) in resources {
+        let mut found = false;
+
+        for location in in_memory_sources(resource) {
+            let (key, source) = match location.path() {
+                Some(path) => (
+                    SourceKey::File(path.to_path_buf(), FileStamp::from_path(path)?),
+                    None,
+                ),
+                None => {
+                    let source = location.resolve()?;
+                    let digest = sha2::Sha256::digest(&source).to_vec();
+
+                    (SourceKey::Content(digest), Some(source))
+                }
+            };
+
+            let has = match cache.get(&key) {
+                Some(has) => *has,
+                None => {
+                    let source = match source {
+                        Some(source) => source,
+                        None => location.resolve()?,
+                    };
+                    let has = has_dunder_file(&source)
+                        .with_context(|| format!("scanning {} for __file__", name))?;
+                    cache.insert(key, has);
+                    has
+                }
+            };
+
+            if has {
+                found = true;
+                break;
+            }
+        }
+
+        if !found {
+            continue;
+        }
+
+        let mut waived = false;
+        for (i, pattern) in patterns.iter().enumerate() {
+            if pattern.matches(name) {
+                used[i] = true;
+                waived = true;
+            }
+        }
+
+        if waived {
+            res.waived.insert(name.clone());
+        } else {
+            let package = name.split('.').next().unwrap_or(name).to_string();
+            res.offenders
+                .entry(package)
+                .or_insert_with(BTreeSet::new)
+                .insert(name.clone());
+        }
+    }
+
+    res.unused_waivers = waivers
+        .iter()
+        .zip(used)
+        .filter(|(_, used)| !used)
+        .map(|(pattern, _)| pattern.clone())
+        .collect();
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packed_resources::data::ResourceFlavor};
+
+    fn module(name: &str, source: &[u8], in_memory: bool) -> PrePackagedResource {
+        let location = DataLocation::Memory(source.to_vec());
+
+        PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: name.to_string(),
+            in_memory_source: if in_memory {
+                Some(location.clone())
+            } else {
+                None
+            },
+            in_memory_bytecode: if in_memory {
+                Some(PythonModuleBytecodeProvider::FromSource(location.clone()))
+            } else {
+                None
+            },
+            relative_path_module_source: if in_memory {
+                None
+            } else {
+                Some(("lib".to_string(), location))
+            },
+            ..PrePackagedResource::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_dunder_file() -> Result<()> {
+        let mut resources = BTreeMap::new();
+        for (name, source, in_memory) in &[
+            ("foo", &b"import os"[..], true),
+            ("foo.a", &b"print(__file__)"[..], true),
+            ("foo.b", &b"print(__file__)"[..], true),
+            ("bar.data", &b"open(__file__)"[..], true),
+            ("baz", &b"print(__file__)"[..], true),
+            ("relocated", &b"print(__file__)"[..], false),
+        ] {
+            resources.insert(name.to_string(), module(name, source, *in_memory));
+        }
+
+        let report = verify_dunder_file(
+            resources.iter(),
+            &["bar.*".to_string(), "unused.*".to_string()],
+        )?;
+
+        let mut offenders = BTreeMap::new();
+        offenders.insert(
+            "foo".to_string(),
+            vec!["foo.a".to_string(), "foo.b".to_string()]
+                .into_iter()
+                .collect::<BTreeSet<_>>(),
+        );
+        offenders.insert(
+            "baz".to_string(),
+            vec!["baz".to_string()].into_iter().collect(),
+        );
+
+        assert_eq!(report.offenders, offenders);
+        assert_eq!(
+            report.waived,
+            vec!["bar.data".to_string()].into_iter().collect()
+        );
+        assert_eq!(report.unused_waivers, vec!["unused.*".to_string()]);
+
+        let problems = report.problems(ConfigProblemSeverity::Error);
+        assert_eq!(
+            problems
+                .iter()
+                .map(|p| (p.severity, p.code))
+                .collect::<Vec<_>>(),
+            vec![
+                (ConfigProblemSeverity::Error, "dunder-file-in-memory"),
+                (ConfigProblemSeverity::Error, "dunder-file-in-memory"),
+                (ConfigProblemSeverity::Warning, "dunder-file-unused-waiver"),
+            ]
+        );
+        assert!(problems[1].message.contains("foo.a, foo.b"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_dunder_file_files() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("shared.py");
+        std::fs::write(&path, "print(__file__)")?;
+
+        let mut resources = BTreeMap::new();
+        for name in &["foo", "bar"] {
+            let location = DataLocation::from_path_uncached(&path);
+
+            resources.insert(
+                name.to_string(),
+                PrePackagedResource {
+                    flavor: ResourceFlavor::Module,
+                    name: name.to_string(),
+                    in_memory_source: Some(location.clone()),
+                    in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(location)),
+                    ..PrePackagedResource::default()
+                },
+            );
+        }
+
+        let report = verify_dunder_file(resources.iter(), &[])?;
+        assert_eq!(
+            report.offenders.keys().collect::<Vec<_>>(),
+            vec!["bar", "foo"]
+        );
+
+        std::fs::remove_file(&path)?;
+        assert!(verify_dunder_file(resources.iter(), &[]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_dunder_file_invalid_waiver() {
+        let resources: BTreeMap<String, PrePackagedResource> = BTreeMap::new();
+        assert!(verify_dunder_file(resources.iter(), &["[".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,415 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Detect packages loading plugins through entry points.
+
+Applications using pluggy or `pkg_resources` discover plugins by reading
+the `entry_points.txt` metadata of installed distributions. Filtering away
+a plugin distribution's metadata or the modules its entry points reference
+silently disables the plugin.
+
+Detection is conservative. Consumers are modules whose source names an
+entry point group as a string literal, see
+`python_packaging::python_source::find_entry_point_groups()`. Only entry
+points in those groups are considered.
+
+Console scripts declared by distributions are also described, with a
+summary taken from the docstring of the module providing their target.
+Applications can list them from a synthesized module, see
+`console_scripts_module_source()`.
+*/
+
+use {
+    super::config::{ConfigProblem, ConfigProblemSeverity},
+    super::site_packages_shim::module_sources,
+    anyhow::{Context, Result},
+    python_packaging::package_metadata::parse_entry_points,
+    python_packaging::python_source::{
+        docstring_summary, find_entry_point_groups, module_docstring,
+    },
+    python_packaging::resource::DataLocation,
+    python_packaging::resource_collection::PrePackagedResource,
+    serde::{Deserialize, Serialize},
+    std::collections::{BTreeMap, BTreeSet},
+};
+
+/// An entry point of a distribution in a group that modules load.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct EntryPointProvider {
+    /// Group of the entry point.
+    pub group: String,
+
+    /// Name of the entry point within its group.
+    pub name: String,
+
+    /// Name of the resource holding the distribution's metadata.
+    pub distribution: String,
+
+    /// Module the entry point references.
+    pub module: String,
+
+    /// Modules loading entry points of the group.
+    pub consumers: BTreeSet<String>,
+}
+
+impl EntryPointProvider {
+    /// Names of resources the entry point needs at run-time.
+    ///
+    /// These are the resource holding the distribution's metadata and the
+    /// module the entry point references.
+    pub fn required_names(&self) -> BTreeSet<String> {
+        vec![self.distribution.clone(), self.module.clone()]
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Entry point group of console scripts.
+const CONSOLE_SCRIPTS_GROUP: &str = "console_scripts";
+
+/// A console script declared by a distribution.
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct ConsoleScript {
+    /// Name of the script.
+    pub name: String,
+
+    /// The callable the script runs, as `module:attribute`.
+    pub target: String,
+
+    /// Summary line of the docstring of the target's module.
+    ///
+    /// Empty if the module has no docstring or its source isn't collected.
+    pub summary: String,
+}
+
+/// Obtain the `entry_points.txt` metadata of a distribution resource.
+fn entry_points_location(resource: &PrePackagedResource) -> Option<&DataLocation> {
+    resource
+        .in_memory_distribution_resources
+        .as_ref()
+        .and_then(|resources| resources.get("entry_points.txt"))
+        .or_else(|| {
+            resource
+                .relative_path_distribution_resources
+                .as_ref()
+                .and_then(|resources| resources.get("entry_points.txt"))
+                .map(|(_, location)| location)
+        })
+}
+
+/// Find entry points in groups loaded by modules among `resources`.
+pub fn find_entry_point_providers<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+) -> Result<Vec<EntryPointProvider>> {
+    let resources = resources.collect::<Vec<_>>();
+
+    let mut consumers: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+    for (name, resource) in &resources {
+        for location in module_sources(resource) {
+            let source = location
+                .resolve()
+                .with_context(|| format!("scanning {} for entry point usage", name))?;
+
+            for group in find_entry_point_groups(&source) {
+                consumers.entry(group).or_default().insert(name.to_string());
+            }
+        }
+    }
+
+    if consumers.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let mut res = Vec::new();
+    for (name, resource) in &resources {
+        let location = match entry_points_location(resource) {
+            Some(location) => location,
+            None => continue,
+        };
+
+        let entry_points = parse_entry_points(&location.resolve()?)
+            .with_context(|| format!("parsing entry_points.txt of {}", name))?;
+
+        for entry_point in entry_points {
+            if let Some(group_consumers) = consumers.get(&entry_point.group) {
+                res.push(EntryPointProvider {
+                    group: entry_point.group,
+                    name: entry_point.name,
+                    distribution: name.to_string(),
+                    module: entry_point.module,
+                    consumers: group_consumers.clone(),
+                });
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Find console scripts declared by distributions among `resources`.
+///
+/// Summaries come from the source of target modules among `resources`. No
+/// Python code is executed.
+pub fn find_console_scripts<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+) -> Result<Vec<ConsoleScript>> {
+    let resources = resources.collect::<BTreeMap<_, _>>();
+
+    let mut res = Vec::new();
+    for (name, resource) in &resources {
+        let location = match entry_points_location(resource) {
+            Some(location) => location,
+            None => continue,
+        };
+
+        let entry_points = parse_entry_points(&location.resolve()?)
+            .with_context(|| format!("parsing entry_points.txt of {}", name))?;
+
+        for entry_point in entry_points {
+            if entry_point.group != CONSOLE_SCRIPTS_GROUP {
+                continue;
+            }
+
+            let source = match resources
+                .get(&entry_point.module)
+                .and_then(|resource| module_sources(resource).into_iter().next())
+            {
+                Some(location) => Some(location.resolve().with_context(|| {
+                    format!("reading source of console script {}", entry_point.name)
+                })?),
+                None => None,
+            };
+
+            let summary = source
+                .and_then(|source| module_docstring(&source))
+                .map(|docstring| docstring_summary(&docstring))
+                .unwrap_or_default();
+
+            res.push(ConsoleScript {
+                target: match &entry_point.attr {
+                    Some(attr) => format!("{}:{}", entry_point.module, attr),
+                    None => entry_point.module.clone(),
+                },
+                name: entry_point.name,
+                summary,
+            });
+        }
+    }
+
+    res.sort();
+
+    Ok(res)
+}
+
+/// Render the source of a module listing console scripts.
+///
+/// The module defines `CONSOLE_SCRIPTS`, a list of dicts with the `name`,
+/// `target` and `summary` of each script. Returns `None` without scripts,
+/// as there is nothing for the module to list.
+pub fn console_scripts_module_source(scripts: &[ConsoleScript]) -> Result<Option<String>> {
+    if scripts.is_empty() {
+        return Ok(None);
+    }
+
+    let mut source =
+        "\"\"\"Console scripts of this application.\"\"\"\n\nCONSOLE_SCRIPTS = [\n".to_string();
+
+    // JSON strings are valid Python string literals.
+    for script in scripts {
+        source.push_str(&format!(
+            "    {{\"name\": {}, \"target\": {}, \"summary\": {}}},\n",
+            serde_json::to_string(&script.name)?,
+            serde_json::to_string(&script.target)?,
+            serde_json::to_string(&script.summary)?,
+        ));
+    }
+
+    source.push_str("]\n");
+
+    Ok(Some(source))
+}
+
+/// Entry points whose consumers are kept but whose resources aren't.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EntryPointReport {
+    /// Entry points and names of their required resources that aren't kept.
+    pub filtered: Vec<(EntryPointProvider, BTreeSet<String>)>,
+}
+
+impl EntryPointReport {
+    /// Construct an instance given which resources are kept.
+    pub fn from_providers(
+        providers: &[EntryPointProvider],
+        is_kept: impl Fn(&str) -> bool,
+    ) -> Self {
+        Self {
+            filtered: providers
+                .iter()
+                .filter(|provider| provider.consumers.iter().any(|name| is_kept(name)))
+                .filter_map(|provider| {
+                    let missing = provider
+                        .required_names()
+                        .into_iter()
+                        .filter(|name| !is_kept(name))
+                        .collect::<BTreeSet<_>>();
+
+                    if missing.is_empty() {
+                        None
+                    } else {
+                        Some((provider.clone(), missing))
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    /// Convert the report to warnings.
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        self.filtered
+            .iter()
+            .map(|(provider, missing)| ConfigProblem {
+                severity: ConfigProblemSeverity::Warning,
+                code: "entry-point-provider-filtered",
+                message: format!(
+                    "entry point {} in group {} loaded by {} is missing {}; set protect_entry_point_providers to keep them",
+                    provider.name,
+                    provider.group,
+                    provider.consumers.iter().cloned().collect::<Vec<_>>().join(", "),
+                    missing.iter().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*, python_packaging::resource::DataLocation,
+        python_packed_resources::data::ResourceFlavor,
+    };
+
+    fn module(name: &str, source: &[u8]) -> PrePackagedResource {
+        PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: name.to_string(),
+            in_memory_source: Some(DataLocation::Memory(source.to_vec())),
+            ..PrePackagedResource::default()
+        }
+    }
+
+    fn distribution(name: &str, entry_points: &[u8]) -> PrePackagedResource {
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "entry_points.txt".to_string(),
+            DataLocation::Memory(entry_points.to_vec()),
+        );
+
+        PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: name.to_string(),
+            is_package: true,
+            in_memory_distribution_resources: Some(resources),
+            ..PrePackagedResource::default()
+        }
+    }
+
+    #[test]
+    fn test_find_entry_point_providers() -> Result<()> {
+        let mut resources = BTreeMap::new();
+        for resource in vec![
+            module(
+                "app.plugins",
+                b"import pkg_resources\npkg_resources.iter_entry_points('app.plugins')\n",
+            ),
+            module("plugin_a", b""),
+            distribution(
+                "plugin-a",
+                b"[app.plugins]\na = plugin_a:register\n[console_scripts]\na = plugin_a:main\n",
+            ),
+            distribution("other", b"[other.group]\nx = other\n"),
+        ] {
+            resources.insert(resource.name.clone(), resource);
+        }
+
+        let providers = find_entry_point_providers(resources.iter())?;
+        assert_eq!(
+            providers,
+            vec![EntryPointProvider {
+                group: "app.plugins".to_string(),
+                name: "a".to_string(),
+                distribution: "plugin-a".to_string(),
+                module: "plugin_a".to_string(),
+                consumers: vec!["app.plugins".to_string()].into_iter().collect(),
+            }]
+        );
+
+        let all = EntryPointReport::from_providers(&providers, |name| resources.contains_key(name));
+        assert!(all.filtered.is_empty());
+
+        let report = EntryPointReport::from_providers(&providers, |name| name != "plugin-a");
+        assert_eq!(report.filtered.len(), 1);
+        assert_eq!(
+            report.filtered[0].1,
+            vec!["plugin-a".to_string()].into_iter().collect()
+        );
+        let problems = report.problems();
+        assert_eq!(problems[0].code, "entry-point-provider-filtered");
+        assert_eq!(problems[0].severity, ConfigProblemSeverity::Warning);
+
+        // Nothing is needed once the consumer is gone.
+        let report = EntryPointReport::from_providers(&providers, |name| !name.starts_with("app"));
+        assert!(report.filtered.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_console_scripts() -> Result<()> {
+        let mut resources = BTreeMap::new();
+        for resource in vec![
+            module("tool.cli", b"\"\"\"\n  Run the tool.\n\nMore.\n\"\"\"\nimport os\n"),
+            module("tool", b"import os\n\"\"\"Not a docstring.\"\"\"\n"),
+            distribution(
+                "tool-dist",
+                b"[console_scripts]\ntool = tool.cli:main\ntool-admin = tool:admin\nraw = missing\n[gui_scripts]\ntool-gui = tool.cli:gui\n",
+            ),
+        ] {
+            resources.insert(resource.name.clone(), resource);
+        }
+
+        let scripts = find_console_scripts(resources.iter())?;
+        assert_eq!(
+            scripts,
+            vec![
+                ConsoleScript {
+                    name: "raw".to_string(),
+                    target: "missing".to_string(),
+                    summary: "".to_string(),
+                },
+                ConsoleScript {
+                    name: "tool".to_string(),
+                    target: "tool.cli:main".to_string(),
+                    summary: "Run the tool.".to_string(),
+                },
+                ConsoleScript {
+                    name: "tool-admin".to_string(),
+                    target: "tool:admin".to_string(),
+                    summary: "".to_string(),
+                },
+            ]
+        );
+
+        assert_eq!(console_scripts_module_source(&[])?, None);
+
+        let source = console_scripts_module_source(&scripts[1..2])?.unwrap();
+        assert_eq!(
+            source,
+            "\"\"\"Console scripts of this application.\"\"\"\n\nCONSOLE_SCRIPTS = [\n    {\"name\": \"tool\", \"target\": \"tool.cli:main\", \"summary\": \"Run the tool.\"},\n]\n"
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,332 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+The directories and files a build installs next to its binary.
+
+Resources at relative paths and extra files are installed relative to the
+directory holding the binary. Their paths follow a fixed scheme, so they
+can be known before anything is written:
+
+* Module source is installed at `<prefix>/<package dirs>/<module>.py`. A
+  package is installed at `<prefix>/<package dirs>/<package>/__init__.py`.
+* Module bytecode is installed in a `__pycache__` directory next to where
+  the module's source would be, named `<module>.<cache tag>.pyc`, e.g.
+  `lib/foo/__pycache__/__init__.cpython-38.pyc`. Optimized bytecode has
+  `.opt-1` or `.opt-2` before `.pyc`.
+* Package resources are installed at `<prefix>/<package dirs>/<relative name>`.
+* Distribution resources are installed at
+  `<prefix>/<name>-<version>.dist-info/<file>`, or in a `.egg-info`
+  directory for egg metadata.
+* Extension modules are installed at
+  `<prefix>/<package dirs>/<name><extension suffix>`.
+* Shared libraries are installed at `<prefix>/<library file name>`.
+* A dynamically linked libpython is installed at the root of the layout.
+
+`<package dirs>` are the components of the name of the package holding a
+resource. Parent packages missing from the collected resources are
+installed with an empty `__init__.py` and with bytecode if one of their
+children has bytecode.
+
+Script rules of the packaging policy may rewrite scripts and add launchers
+next to them. The layout lists the files script processing produces.
+
+Paths always use `/` as a directory separator.
+
+Nothing may be installed to the same path twice. Since the scheme maps
+different resources to the same path, e.g. module `foo.bar` and package
+resource `bar.py` of package `foo`, collisions are searched for before
+anything is written.
+*/
+
+use {
+    crate::app_packaging::resource::FileManifest,
+    anyhow::{anyhow, Result},
+    python_packaging::resource::DataLocation,
+    serde::{Deserialize, Serialize},
+    sha2::Digest,
+    std::collections::{BTreeMap, BTreeSet},
+    std::path::{Component, Path, PathBuf},
+};
+
+/// Obtain the key of a path in an `InstallLayout`.
+pub fn layout_key(path: &Path) -> String {
+    format!("{}", path.display()).replace('\\', "/")
+}
+
+/// The directories and files a build installs.
+///
+/// Paths are relative to the directory holding the binary.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct InstallLayout {
+    /// Directories to create.
+    ///
+    /// Parent directories are listed and sort before their children.
+    pub directories: BTreeSet<String>,
+
+    /// Files to install and whether each is executable.
+    pub files: BTreeMap<String, bool>,
+}
+
+impl InstallLayout {
+    /// Add a file and its parent directories.
+    pub fn add_file(&mut self, path: &Path, executable: bool) -> Result<()> {
+        if path
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_) | Component::CurDir))
+        {
+            return Err(anyhow!(
+                "{} can't be installed since it isn't a relative path within the install directory",
+                path.display()
+            ));
+        }
+
+        let key = layout_key(path);
+
+        if self.directories.contains(&key) {
+            return Err(anyhow!(
+                "{} is installed as both a file and a directory",
+                key
+            ));
+        }
+
+        if let Some(existing) = self.files.insert(key.clone(), executable) {
+            if existing != executable {
+                return Err(anyhow!(
+                    "{} is installed as both an executable and a regular file",
+                    key
+                ));
+            }
+        }
+
+        let mut parent = path.parent();
+        while let Some(directory) = parent {
+            if directory.as_os_str().is_empty() {
+                break;
+            }
+
+            let directory_key = layout_key(directory);
+            if self.files.contains_key(&directory_key) {
+                return Err(anyhow!(
+                    "{} is installed as both a file and a directory",
+                    directory_key
+                ));
+            }
+            self.directories.insert(directory_key);
+
+            parent = directory.parent();
+        }
+
+        Ok(())
+    }
+
+    /// Construct an instance from the files of a `FileManifest`.
+    pub fn from_file_manifest(manifest: &FileManifest) -> Result<Self> {
+        let mut layout = Self::default();
+
+        for (path, content) in manifest.entries() {
+            layout.add_file(path, content.executable)?;
+        }
+
+        Ok(layout)
+    }
+
+    /// Verify the files of a `FileManifest` are exactly those of this layout.
+    pub fn verify_file_manifest(&self, manifest: &FileManifest) -> Result<()> {
+        let actual = Self::from_file_manifest(manifest)?;
+
+        let mut problems = Vec::new();
+        for (key, executable) in &actual.files {
+            match self.files.get(key) {
+                None => problems.push(format!("{} isn't in the install layout", key)),
+                Some(expected) if expected != executable => {
+                    problems.push(format!("{} has a different executable bit", key))
+                }
+                Some(_) => {}
+            }
+        }
+        for key in self.files.keys() {
+            if !actual.files.contains_key(key) {
+                problems.push(format!(
+                    "{} is in the install layout but not installed",
+                    key
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "installed files disagree with the install layout: {}",
+                problems.join("; ")
+            ))
+        }
+    }
+}
+
+/// Something a build installs at a path.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstallSource {
+    /// Description of what is installed and where it comes from.
+    pub description: String,
+
+    /// The installed content, if known before building.
+    pub content: Option<DataLocation>,
+}
+
+/// A path more than one thing is installed at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InstallCollision {
+    /// The path, as an `InstallLayout` key.
+    pub path: String,
+
+    /// Everything installed at the path.
+    pub sources: Vec<InstallSource>,
+}
+
+impl std::fmt::Display for InstallCollision {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} is installed by {}",
+            self.path,
+            self.sources
+                .iter()
+                .map(|source| source.description.clone())
+                .collect::<Vec<_>>()
+                .join(" and ")
+        )
+    }
+}
+
+impl InstallCollision {
+    /// Whether everything installed at the path has the same known content.
+    ///
+    /// Content is compared by SHA-256.
+    pub fn is_identical(&self) -> Result<bool> {
+        let mut digests = BTreeSet::new();
+
+        for source in &self.sources {
+            match &source.content {
+                Some(location) => {
+                    digests.insert(sha2::Sha256::digest(&location.resolve()?).to_vec());
+                }
+                None => return Ok(false),
+            }
+        }
+
+        Ok(digests.len() == 1)
+    }
+}
+
+/// Find paths more than one thing is installed at.
+pub fn find_install_collisions(
+    installs: impl IntoIterator<Item = (PathBuf, InstallSource)>,
+) -> Vec<InstallCollision> {
+    let mut by_path: BTreeMap<String, Vec<InstallSource>> = BTreeMap::new();
+
+    for (path, source) in installs {
+        by_path
+            .entry(layout_key(&path))
+            .or_insert_with(Vec::new)
+            .push(source);
+    }
+
+    by_path
+        .into_iter()
+        .filter(|(_, sources)| sources.len() > 1)
+        .map(|(path, sources)| InstallCollision { path, sources })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::app_packaging::resource::FileContent};
+
+    #[test]
+    fn test_add_file() -> Result<()> {
+        let mut layout = InstallLayout::default();
+        layout.add_file(&PathBuf::from("lib/foo/__init__.py"), false)?;
+        layout.add_file(&PathBuf::from("lib/foo/__pycache__/__init__.pyc"), false)?;
+        layout.add_file(&PathBuf::from("libpython3.8.so"), true)?;
+
+        assert_eq!(
+            layout.directories.iter().collect::<Vec<_>>(),
+            vec!["lib", "lib/foo", "lib/foo/__pycache__"]
+        );
+        assert_eq!(layout.files.len(), 3);
+        assert_eq!(layout.files.get("libpython3.8.so"), Some(&true));
+
+        assert!(layout.add_file(&PathBuf::from("lib/foo"), false).is_err());
+        assert!(layout
+            .add_file(&PathBuf::from("lib/foo/__init__.py/x"), false)
+            .is_err());
+        assert!(layout
+            .add_file(&PathBuf::from("lib/foo/__init__.py"), true)
+            .is_err());
+        assert!(layout.add_file(&PathBuf::from("../escape"), false).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_file_manifest() -> Result<()> {
+        let mut layout = InstallLayout::default();
+        layout.add_file(&PathBuf::from("lib/foo.py"), false)?;
+
+        let mut manifest = FileManifest::default();
+        let content = FileContent {
+            data: DataLocation::Memory(vec![42]),
+            executable: false,
+        };
+        manifest.add_file(&PathBuf::from("lib/foo.py"), &content)?;
+        layout.verify_file_manifest(&manifest)?;
+
+        manifest.add_file(&PathBuf::from("lib/bar.py"), &content)?;
+        assert_eq!(
+            layout.verify_file_manifest(&manifest).unwrap_err().to_string(),
+            "installed files disagree with the install layout: lib/bar.py isn't in the install layout"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_install_collisions() -> Result<()> {
+        let source = |description: &str, content: Option<&[u8]>| InstallSource {
+            description: description.to_string(),
+            content: content.map(|data| DataLocation::Memory(data.to_vec())),
+        };
+
+        let collisions = find_install_collisions(vec![
+            (
+                PathBuf::from("lib/foo/bar.py"),
+                source("source of foo.bar", Some(b"a")),
+            ),
+            (
+                PathBuf::from("lib/foo/baz.py"),
+                source("source of foo.baz", Some(b"a")),
+            ),
+            (
+                PathBuf::from("lib/foo/bar.py"),
+                source("package resource bar.py of foo", Some(b"a")),
+            ),
+        ]);
+        assert_eq!(collisions.len(), 1);
+        assert_eq!(
+            collisions[0].to_string(),
+            "lib/foo/bar.py is installed by source of foo.bar and package resource bar.py of foo"
+        );
+        assert!(collisions[0].is_identical()?);
+
+        let mut collision = collisions[0].clone();
+        collision.sources[1].content = Some(DataLocation::Memory(b"b".to_vec()));
+        assert!(!collision.is_identical()?);
+        collision.sources[1].content = None;
+        assert!(!collision.is_identical()?);
+
+        Ok(())
+    }
+}
@@ -188,6 +188,7 @@ pub struct LibpythonInfo {
 /// Create a static libpython from a Python distribution.
 ///
 /// Returns a vector of cargo: lines that can be printed in build scripts.
+/// Intermediate files are written to a temporary directory in `out_dir`.
 pub fn link_libpython(
     logger: &slog::Logger,
     context: &LibPythonBuildContext,
@@ -198,7 +199,7 @@ pub fn link_libpython(
 ) -> Result<LibpythonInfo> {
     let mut cargo_metadata: Vec<String> = Vec::new();
 
-    let temp_dir = tempdir::TempDir::new("libpython")?;
+    let temp_dir = tempdir::TempDir::new_in(out_dir, "libpython")?;
     let temp_dir_path = temp_dir.path();
 
     let windows = crate::environment::WINDOWS_TARGET_TRIPLES.contains(&target_triple);
@@ -231,8 +232,7 @@ pub fn link_libpython(
             full.parent()
                 .ok_or_else(|| anyhow!("unable to resolve parent directory"))?,
         )?;
-        let data = location.resolve()?;
-        std::fs::write(&full, &data)?;
+        location.copy_to(&mut fs::File::create(&full)?)?;
     }
 
     warn!(logger, "compiling custom config.c to object file");
@@ -274,14 +274,14 @@ pub fn link_libpython(
 
     for (i, location) in context.object_files.iter().enumerate() {
         match location {
-            DataLocation::Memory(data) => {
+            DataLocation::Path { path: p, .. } => {
+                build.object(&p);
+            }
+            location => {
                 let out_path = temp_dir_path.join(format!("libpython.{}.o", i));
-                fs::write(&out_path, data)?;
+                location.copy_to(&mut fs::File::create(&out_path)?)?;
                 build.object(&out_path);
             }
-            DataLocation::Path(p) => {
-                build.object(&p);
-            }
         }
     }
 
@@ -327,11 +327,10 @@ pub fn link_libpython(
         "libpythonXY.a"
     });
 
+    // libpythonXY is written next to the other artifacts, whose directory
+    // is added to the search path then. out_dir is usually removed by the
+    // time Cargo links, so it isn't searched.
     cargo_metadata.push("cargo:rustc-link-lib=static=pythonXY".to_string());
-    cargo_metadata.push(format!(
-        "cargo:rustc-link-search=native={}",
-        out_dir.display()
-    ));
 
     for path in &context.library_search_paths {
         cargo_metadata.push(format!("cargo:rustc-link-search=native={}", path.display()));
@@ -0,0 +1,744 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Records of the inputs used to statically link libpython.
+
+When linking a custom libpython fails (duplicate or missing symbols, missing
+libraries), diagnosing the problem requires knowing which object files and
+libraries were involved and where they came from. A `LinkRecord` captures
+this for the Python core and every extension module compiled into the binary.
+It is serialized as JSON next to the other artifacts needed to build a binary
+and can be searched for the definitions and references of a symbol.
+*/
+
+use {
+    anyhow::{anyhow, Context, Result},
+    byteorder::{ByteOrder, LittleEndian},
+    python_packaging::resource::{DataLocation, LibraryDependency, PythonExtensionModule},
+    serde::{Deserialize, Serialize},
+    std::path::{Path, PathBuf},
+};
+
+/// Where a unit of linked code came from.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkUnitSource {
+    /// The Python distribution.
+    Distribution,
+
+    /// A package, such as a wheel or a locally built extension module.
+    Package,
+}
+
+/// The type of a file consumed by the linker.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkInputKind {
+    /// An object file.
+    ObjectFile,
+
+    /// A static library archive.
+    StaticLibrary,
+}
+
+/// A file consumed by the linker.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LinkInput {
+    /// The type of file.
+    pub kind: LinkInputKind,
+
+    /// The original filesystem path of the file.
+    ///
+    /// `None` if the file was synthesized in memory.
+    pub path: Option<PathBuf>,
+
+    /// The file content, if it is only available in memory.
+    ///
+    /// This isn't serialized.
+    #[serde(skip)]
+    pub data: Option<Vec<u8>>,
+}
+
+impl LinkInput {
+    /// Construct an instance from a `DataLocation`.
+    pub fn new(kind: LinkInputKind, location: &DataLocation) -> Result<Self> {
+        Ok(match location {
+            DataLocation::Path { path, .. } => Self {
+                kind,
+                path: Some(path.clone()),
+                data: None,
+            },
+            location => Self {
+                kind,
+                path: None,
+                data: Some(location.resolve()?),
+            },
+        })
+    }
+
+    /// Whether the file was synthesized in memory.
+    pub fn is_synthetic(&self) -> bool {
+        self.path.is_none()
+    }
+
+    /// Describe this input for humans.
+    pub fn describe(&self, index: usize) -> String {
+        match &self.path {
+            Some(path) => format!("{}", path.display()),
+            None => format!("<synthetic object {}>", index),
+        }
+    }
+
+    /// Obtain the content of this input.
+    ///
+    /// Returns `None` for synthetic inputs loaded from a serialized record.
+    pub fn resolve(&self) -> Result<Option<Vec<u8>>> {
+        if let Some(data) = &self.data {
+            Ok(Some(data.clone()))
+        } else if let Some(path) = &self.path {
+            Ok(Some(
+                std::fs::read(path).with_context(|| format!("reading {}", path.display()))?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// How a library is linked.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkLibraryKind {
+    /// A framework (Apple platforms).
+    Framework,
+
+    /// A library provided by the system.
+    System,
+
+    /// A static library.
+    Static,
+
+    /// A dynamic library.
+    Dynamic,
+}
+
+/// A library passed to the linker, derived from a `LibraryDependency`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LinkLibrary {
+    /// Name of the library as given to the linker.
+    pub name: String,
+
+    /// How the library is linked.
+    pub kind: LinkLibraryKind,
+}
+
+impl LinkLibrary {
+    /// Derive the library linked for a dependency.
+    ///
+    /// Returns `None` if the dependency doesn't result in linking anything.
+    pub fn from_dependency(depends: &LibraryDependency) -> Option<Self> {
+        let kind = if depends.framework {
+            LinkLibraryKind::Framework
+        } else if depends.system {
+            LinkLibraryKind::System
+        } else if depends.static_library.is_some() {
+            LinkLibraryKind::Static
+        } else if depends.dynamic_library.is_some() {
+            LinkLibraryKind::Dynamic
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            name: depends.name.clone(),
+            kind,
+        })
+    }
+}
+
+/// Everything linked on behalf of the Python core or a single extension module.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LinkUnit {
+    /// Name of the extension module.
+    ///
+    /// `None` for the Python core.
+    pub extension: Option<String>,
+
+    /// Where the unit came from.
+    pub source: LinkUnitSource,
+
+    /// Object files and static libraries consumed.
+    pub inputs: Vec<LinkInput>,
+
+    /// Libraries the linker is told to link against.
+    pub libraries: Vec<LinkLibrary>,
+}
+
+impl LinkUnit {
+    /// Construct an empty unit for the Python core.
+    pub fn core() -> Self {
+        Self {
+            extension: None,
+            source: LinkUnitSource::Distribution,
+            inputs: vec![],
+            libraries: vec![],
+        }
+    }
+
+    /// Construct a unit for an extension module compiled into libpython.
+    ///
+    /// `libraries` holds the dependencies that are actually linked, which
+    /// may be a subset of the extension's dependencies.
+    pub fn from_extension_module<'a>(
+        module: &PythonExtensionModule,
+        libraries: impl Iterator<Item = &'a LibraryDependency>,
+    ) -> Result<Self> {
+        let mut inputs = module
+            .object_file_data
+            .iter()
+            .map(|location| LinkInput::new(LinkInputKind::ObjectFile, location))
+            .collect::<Result<Vec<_>>>()?;
+        let mut links = vec![];
+
+        for depends in libraries {
+            if let Some(library) = LinkLibrary::from_dependency(depends) {
+                if library.kind == LinkLibraryKind::Static {
+                    if let Some(location) = &depends.static_library {
+                        inputs.push(LinkInput::new(LinkInputKind::StaticLibrary, location)?);
+                    }
+                }
+
+                links.push(library);
+            }
+        }
+
+        Ok(Self {
+            extension: Some(module.name.clone()),
+            source: if module.is_stdlib {
+                LinkUnitSource::Distribution
+            } else {
+                LinkUnitSource::Package
+            },
+            inputs,
+            libraries: links,
+        })
+    }
+
+    /// Describe the owner of this unit for humans.
+    pub fn owner(&self) -> &str {
+        match &self.extension {
+            Some(name) => name,
+            None => "<python core>",
+        }
+    }
+}
+
+/// Whether a linked file defines or references a symbol.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SymbolUseKind {
+    Defines,
+    References,
+}
+
+/// A use of a symbol found in a linked file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SymbolUse {
+    /// Name of the extension module owning the file. `None` for the Python core.
+    pub extension: Option<String>,
+
+    /// Description of the linked file.
+    pub input: String,
+
+    /// Archive member containing the symbol, if the file is an archive.
+    pub member: Option<String>,
+
+    /// Whether the symbol is defined or referenced.
+    pub kind: SymbolUseKind,
+}
+
+/// Result of searching a `LinkRecord` for a symbol.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SymbolSearch {
+    /// Uses of the symbol that were found.
+    pub uses: Vec<SymbolUse>,
+
+    /// Inputs that couldn't be searched and why.
+    pub unsearched: Vec<(String, String)>,
+}
+
+impl SymbolSearch {
+    /// Names of extensions defining the symbol.
+    ///
+    /// The Python core is reported as `<python core>`.
+    pub fn defined_by(&self) -> Vec<String> {
+        self.owners(SymbolUseKind::Defines)
+    }
+
+    /// Names of extensions referencing the symbol without defining it.
+    pub fn referenced_by(&self) -> Vec<String> {
+        self.owners(SymbolUseKind::References)
+    }
+
+    fn owners(&self, kind: SymbolUseKind) -> Vec<String> {
+        let mut res = self
+            .uses
+            .iter()
+            .filter(|u| u.kind == kind)
+            .map(|u| match &u.extension {
+                Some(name) => name.clone(),
+                None => "<python core>".to_string(),
+            })
+            .collect::<Vec<_>>();
+        res.sort();
+        res.dedup();
+        res
+    }
+}
+
+/// Describes everything consumed when linking libpython.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct LinkRecord {
+    /// The Python core followed by each builtin extension module.
+    pub units: Vec<LinkUnit>,
+}
+
+impl LinkRecord {
+    /// Serialize to pretty-printed JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Load an instance from a JSON file.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        serde_json::from_slice(&data).with_context(|| format!("parsing {}", path.display()))
+    }
+
+    /// Search the symbol tables of linked files for a symbol.
+    ///
+    /// `symbol` is the name reported by the linker. Symbols with a leading
+    /// underscore, as used by Mach-O and 32-bit x86 COFF, also match.
+    pub fn find_symbol(&self, symbol: &str) -> SymbolSearch {
+        let mut search = SymbolSearch::default();
+
+        for unit in &self.units {
+            for (index, input) in unit.inputs.iter().enumerate() {
+                let description = input.describe(index);
+
+                let data = match input.resolve() {
+                    Ok(Some(data)) => data,
+                    Ok(None) => {
+                        search
+                            .unsearched
+                            .push((description, "content not available".to_string()));
+                        continue;
+                    }
+                    Err(e) => {
+                        search.unsearched.push((description, format!("{:#}", e)));
+                        continue;
+                    }
+                };
+
+                match object_symbol_uses(&data, symbol) {
+                    Ok(uses) => {
+                        for (member, kind) in uses {
+                            search.uses.push(SymbolUse {
+                                extension: unit.extension.clone(),
+                                input: description.clone(),
+                                member,
+                                kind,
+                            });
+                        }
+                    }
+                    Err(e) => {
+                        search.unsearched.push((description, format!("{:#}", e)));
+                    }
+                }
+            }
+        }
+
+        search
+    }
+}
+
+fn symbol_matches(name: &str, symbol: &str) -> bool {
+    name == symbol || (name.starts_with('_') && &name[1..] == symbol)
+}
+
+/// Find uses of a symbol in an object file or static library.
+///
+/// Returns the archive member, if any, and the kind of each use.
+pub fn object_symbol_uses(
+    data: &[u8],
+    symbol: &str,
+) -> Result<Vec<(Option<String>, SymbolUseKind)>> {
+    // goblin doesn't recognize COFF object files as produced by MSVC.
+    if is_coff_object(data) {
+        return Ok(coff_symbol_uses(data, symbol)?
+            .into_iter()
+            .map(|kind| (None, kind))
+            .collect());
+    }
+
+    match goblin::Object::parse(data)? {
+        goblin::Object::Elf(elf) => Ok(elf_symbol_uses(&elf, symbol)?
+            .into_iter()
+            .map(|kind| (None, kind))
+            .collect()),
+        goblin::Object::Mach(goblin::mach::Mach::Binary(macho)) => {
+            Ok(macho_symbol_uses(&macho, symbol)?
+                .into_iter()
+                .map(|kind| (None, kind))
+                .collect())
+        }
+        goblin::Object::Archive(archive) => {
+            let mut res = vec![];
+
+            for member in archive.members() {
+                let member_data = archive.extract(member, data)?;
+
+                for (_, kind) in object_symbol_uses(member_data, symbol)
+                    .with_context(|| format!("parsing archive member {}", member))?
+                {
+                    res.push((Some(member.to_string()), kind));
+                }
+            }
+
+            Ok(res)
+        }
+        goblin::Object::Mach(goblin::mach::Mach::Fat(_)) => {
+            Err(anyhow!("searching universal Mach-O files is not supported"))
+        }
+        goblin::Object::PE(_) => Err(anyhow!("searching PE files is not supported")),
+        goblin::Object::Unknown(magic) => Err(anyhow!("unknown file magic: {:#x}", magic)),
+    }
+}
+
+fn elf_symbol_uses(elf: &goblin::elf::Elf, symbol: &str) -> Result<Vec<SymbolUseKind>> {
+    let mut res = vec![];
+
+    for sym in elf.syms.iter() {
+        if sym.st_bind() == goblin::elf::sym::STB_LOCAL {
+            continue;
+        }
+
+        let name = match elf.strtab.get(sym.st_name) {
+            Some(name) => name?,
+            None => continue,
+        };
+
+        if name == symbol {
+            res.push(
+                if sym.st_shndx == goblin::elf::section_header::SHN_UNDEF as usize {
+                    SymbolUseKind::References
+                } else {
+                    SymbolUseKind::Defines
+                },
+            );
+        }
+    }
+
+    Ok(res)
+}
+
+fn macho_symbol_uses(macho: &goblin::mach::MachO, symbol: &str) -> Result<Vec<SymbolUseKind>> {
+    let mut res = vec![];
+
+    for entry in macho.symbols() {
+        let (name, nlist) = entry?;
+
+        if !nlist.is_global() || !symbol_matches(name, symbol) {
+            continue;
+        }
+
+        res.push(if nlist.is_undefined() {
+            SymbolUseKind::References
+        } else {
+            SymbolUseKind::Defines
+        });
+    }
+
+    Ok(res)
+}
+
+/// Machine types of COFF object files: x86, ARMv7, x86-64 and ARM64.
+const COFF_MACHINES: &[u16] = &[0x14c, 0x1c4, 0x8664, 0xaa64];
+const COFF_HEADER_LENGTH: usize = 20;
+const COFF_SYMBOL_LENGTH: usize = 18;
+const IMAGE_SYM_UNDEFINED: i16 = 0;
+const IMAGE_SYM_CLASS_EXTERNAL: u8 = 2;
+
+/// Whether data is a COFF object file.
+///
+/// Object files don't have an optional header, unlike PE images.
+fn is_coff_object(data: &[u8]) -> bool {
+    data.len() >= COFF_HEADER_LENGTH
+        && COFF_MACHINES.contains(&LittleEndian::read_u16(&data[0..2]))
+        && LittleEndian::read_u16(&data[16..18]) == 0
+}
+
+fn coff_symbol_uses(data: &[u8], symbol: &str) -> Result<Vec<SymbolUseKind>> {
+    let symbols_offset = LittleEndian::read_u32(&data[8..12]) as usize;
+    let symbols_count = LittleEndian::read_u32(&data[12..16]) as usize;
+
+    let symbols_end = symbols_count
+        .checked_mul(COFF_SYMBOL_LENGTH)
+        .and_then(|length| length.checked_add(symbols_offset))
+        .filter(|end| *end <= data.len())
+        .ok_or_else(|| anyhow!("COFF symbol table out of bounds"))?;
+
+    // The string table immediately follows the symbol table.
+    let strings = &data[symbols_end..];
+
+    let mut res = vec![];
+    let mut index = 0;
+
+    while index < symbols_count {
+        let start = symbols_offset + index * COFF_SYMBOL_LENGTH;
+        let entry = &data[start..start + COFF_SYMBOL_LENGTH];
+
+        // Auxiliary records follow their symbol.
+        index += 1 + entry[17] as usize;
+
+        if entry[16] != IMAGE_SYM_CLASS_EXTERNAL {
+            continue;
+        }
+
+        if !symbol_matches(coff_symbol_name(entry, strings)?, symbol) {
+            continue;
+        }
+
+        let value = LittleEndian::read_u32(&entry[8..12]);
+        let section = LittleEndian::read_i16(&entry[12..14]);
+
+        // Undefined symbols with a value are common symbols, which are
+        // defined by the object file.
+        res.push(if section == IMAGE_SYM_UNDEFINED && value == 0 {
+            SymbolUseKind::References
+        } else {
+            SymbolUseKind::Defines
+        });
+    }
+
+    Ok(res)
+}
+
+/// Resolve the name of a COFF symbol table entry.
+///
+/// Names longer than 8 bytes are stored in the string table.
+fn coff_symbol_name<'a>(entry: &'a [u8], strings: &'a [u8]) -> Result<&'a str> {
+    let name = if entry[0..4] == [0, 0, 0, 0] {
+        let offset = LittleEndian::read_u32(&entry[4..8]) as usize;
+
+        strings
+            .get(offset..)
+            .ok_or_else(|| anyhow!("COFF symbol name out of bounds"))?
+    } else {
+        &entry[0..8]
+    };
+
+    let length = name.iter().position(|b| *b == 0).unwrap_or(name.len());
+
+    std::str::from_utf8(&name[0..length]).context("COFF symbol name isn't UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extension_unit() -> Result<()> {
+        let module = PythonExtensionModule {
+            name: "foo".to_string(),
+            init_fn: Some("PyInit_foo".to_string()),
+            extension_file_suffix: ".so".to_string(),
+            shared_library: None,
+            object_file_data: vec![
+                DataLocation::from_path(PathBuf::from("/build/foo.o")),
+                DataLocation::Memory(vec![0, 1, 2]),
+            ],
+            is_package: false,
+            link_libraries: vec![],
+            is_stdlib: false,
+            builtin_default: false,
+            required: false,
+            variant: None,
+            licenses: None,
+            license_texts: None,
+            license_public_domain: None,
+        };
+
+        let depends = vec![
+            LibraryDependency {
+                name: "ssl".to_string(),
+                static_library: Some(DataLocation::from_path(PathBuf::from("/build/libssl.a"))),
+                dynamic_library: None,
+                framework: false,
+                system: false,
+            },
+            LibraryDependency {
+                name: "m".to_string(),
+                static_library: None,
+                dynamic_library: None,
+                framework: false,
+                system: true,
+            },
+            LibraryDependency {
+                name: "nothing".to_string(),
+                static_library: None,
+                dynamic_library: None,
+                framework: false,
+                system: false,
+            },
+        ];
+
+        let unit = LinkUnit::from_extension_module(&module, depends.iter())?;
+        assert_eq!(unit.extension, Some("foo".to_string()));
+        assert_eq!(unit.source, LinkUnitSource::Package);
+        assert_eq!(unit.inputs.len(), 3);
+        assert_eq!(unit.inputs[0].path, Some(PathBuf::from("/build/foo.o")));
+        assert!(unit.inputs[1].is_synthetic());
+        assert_eq!(unit.inputs[2].kind, LinkInputKind::StaticLibrary);
+        assert_eq!(
+            unit.libraries,
+            vec![
+                LinkLibrary {
+                    name: "ssl".to_string(),
+                    kind: LinkLibraryKind::Static
+                },
+                LinkLibrary {
+                    name: "m".to_string(),
+                    kind: LinkLibraryKind::System
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_roundtrip() -> Result<()> {
+        let mut core = LinkUnit::core();
+        core.inputs.push(LinkInput::new(
+            LinkInputKind::ObjectFile,
+            &DataLocation::Memory(b"this is not an object file".to_vec()),
+        )?);
+        let record = LinkRecord { units: vec![core] };
+
+        let loaded: LinkRecord = serde_json::from_str(&record.to_json()?)?;
+        assert_eq!(loaded.units.len(), 1);
+        assert!(loaded.units[0].inputs[0].is_synthetic());
+        assert_eq!(loaded.units[0].inputs[0].data, None);
+
+        // Synthetic content is only searchable in the original record.
+        let search = record.find_symbol("foo");
+        assert!(search.uses.is_empty());
+        assert_eq!(search.unsearched.len(), 1);
+        assert!(search.unsearched[0].1.starts_with("unknown file magic"));
+
+        let search = loaded.find_symbol("foo");
+        assert_eq!(
+            search.unsearched,
+            vec![(
+                "<synthetic object 0>".to_string(),
+                "content not available".to_string()
+            )]
+        );
+
+        Ok(())
+    }
+
+    /// Build a COFF object file with symbols of a name, section and storage class.
+    fn coff_object(symbols: &[(&str, i16, u8)]) -> Vec<u8> {
+        let mut header = vec![0; COFF_HEADER_LENGTH];
+        LittleEndian::write_u16(&mut header[0..2], 0x8664);
+        LittleEndian::write_u32(&mut header[8..12], COFF_HEADER_LENGTH as u32);
+
+        let mut table = vec![];
+        let mut strings = vec![0; 4];
+        let mut count = 0;
+
+        for (name, section, class) in symbols {
+            let mut entry = vec![0; COFF_SYMBOL_LENGTH];
+
+            if name.len() <= 8 {
+                entry[0..name.len()].copy_from_slice(name.as_bytes());
+            } else {
+                LittleEndian::write_u32(&mut entry[4..8], strings.len() as u32);
+                strings.extend(name.as_bytes());
+                strings.push(0);
+            }
+
+            LittleEndian::write_i16(&mut entry[12..14], *section);
+            entry[16] = *class;
+
+            table.extend(&entry);
+            count += 1;
+
+            // Give static symbols an auxiliary record which would read as
+            // a definition of Py_None.
+            if *class != IMAGE_SYM_CLASS_EXTERNAL {
+                let start = table.len() - COFF_SYMBOL_LENGTH;
+                table[start + 17] = 1;
+
+                let mut aux = vec![0; COFF_SYMBOL_LENGTH];
+                aux[0..7].copy_from_slice(b"Py_None");
+                LittleEndian::write_i16(&mut aux[12..14], 1);
+                aux[16] = IMAGE_SYM_CLASS_EXTERNAL;
+
+                table.extend(&aux);
+                count += 1;
+            }
+        }
+
+        LittleEndian::write_u32(&mut header[12..16], count);
+        let strings_length = strings.len() as u32;
+        LittleEndian::write_u32(&mut strings[0..4], strings_length);
+
+        header.extend(table);
+        header.extend(strings);
+        header
+    }
+
+    #[test]
+    fn test_coff_symbol_uses() -> Result<()> {
+        let data = coff_object(&[
+            ("helper", 1, 3),
+            ("PyInit_foo", 1, IMAGE_SYM_CLASS_EXTERNAL),
+            ("Py_None", 0, IMAGE_SYM_CLASS_EXTERNAL),
+            ("_x86_sym", 0, IMAGE_SYM_CLASS_EXTERNAL),
+        ]);
+        assert!(is_coff_object(&data));
+
+        assert_eq!(
+            object_symbol_uses(&data, "PyInit_foo")?,
+            vec![(None, SymbolUseKind::Defines)]
+        );
+        assert_eq!(
+            object_symbol_uses(&data, "Py_None")?,
+            vec![(None, SymbolUseKind::References)]
+        );
+        assert_eq!(
+            object_symbol_uses(&data, "x86_sym")?,
+            vec![(None, SymbolUseKind::References)]
+        );
+        // Only external symbols are searched.
+        assert!(object_symbol_uses(&data, "helper")?.is_empty());
+
+        let mut truncated = data.clone();
+        LittleEndian::write_u32(&mut truncated[12..16], 1000);
+        assert!(object_symbol_uses(&truncated, "Py_None").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbol_matches() {
+        assert!(symbol_matches("PyInit_foo", "PyInit_foo"));
+        assert!(symbol_matches("_PyInit_foo", "PyInit_foo"));
+        assert!(!symbol_matches("PyInit_foo", "_PyInit_foo"));
+        assert!(!symbol_matches("PyInit_foobar", "PyInit_foo"));
+    }
+}
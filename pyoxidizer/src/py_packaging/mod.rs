@@ -8,14 +8,40 @@ Functionality for interacting with Python.
 This module tree holds functionality that is centered around Python.
 */
 
+pub mod analysis_verdicts;
+pub mod architecture;
+pub mod artifact_writer;
+pub mod audit;
 pub mod binary;
+pub mod build_cache;
+pub mod build_clock;
+pub mod build_diagnostics;
+pub mod build_directories;
+pub mod bytecode_cache;
 pub mod config;
+pub mod debug_companion;
 pub mod distribution;
+pub mod distribution_coverage;
 pub mod distutils;
+pub mod dunder_file;
+pub mod entry_points;
 pub mod filtering;
+pub mod install_layout;
 pub mod libpython;
+pub mod link_record;
 pub mod packaging_tool;
+pub mod payload_storage;
+pub mod pinning;
+pub mod pip_failure;
 pub mod pyembed;
 pub mod resource;
+pub mod resource_analysis;
+pub mod resource_explanation;
+pub mod resource_verification;
+pub mod site_packages_shim;
+pub mod spec;
 pub mod standalone_builder;
 pub mod standalone_distribution;
+pub mod syntax_compatibility;
+pub mod update_package;
+pub mod workspace_paths;
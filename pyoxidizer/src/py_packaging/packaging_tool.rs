@@ -10,11 +10,18 @@ use {
     super::binary::LibpythonLinkMode,
     super::distribution::{download_distribution, PythonDistribution},
     super::distutils::read_built_extensions,
+    super::pip_failure::parse_pip_failure,
     super::standalone_distribution::resolve_python_paths,
     crate::python_distributions::GET_PIP_PY_19,
     anyhow::{anyhow, Context, Result},
-    python_packaging::filesystem_scanning::find_python_resources,
-    python_packaging::resource::PythonResource,
+    python_packaging::bytecode::{
+        magic_number_for_python_version, python_version_from_magic_number,
+        read_bytecode_magic_number,
+    },
+    python_packaging::filesystem_scanning::{
+        find_bytecode_only_resources, find_python_resources_with_sourceless_bytecode,
+    },
+    python_packaging::resource::{DataLocation, InternPool, PythonResource},
     slog::warn,
     std::collections::HashMap,
     std::hash::BuildHasher,
@@ -65,18 +72,21 @@ pub const PIP_BOOTSTRAP_REQUIREMENTS: &str = indoc::indoc!(
 /// Since modern versions of `get-pip.py` just work in their default
 /// non-deterministic mode, hacking `get-pip.py` to do what we want was
 /// the path of least resistance.
+///
+/// `get-pip.py` runs from and installs into a directory in `scratch_dir`.
 #[allow(unused)]
 pub fn bootstrap_packaging_tools(
     logger: &slog::Logger,
     python_exe: &Path,
     cache_dir: &Path,
+    scratch_dir: &Path,
     bin_dir: &Path,
     lib_dir: &Path,
 ) -> Result<()> {
     let get_pip_py_path =
         download_distribution(&GET_PIP_PY_19.url, &GET_PIP_PY_19.sha256, cache_dir)?;
 
-    let temp_dir = tempdir::TempDir::new("pyoxidizer-bootstrap-packaging")?;
+    let temp_dir = tempdir::TempDir::new_in(scratch_dir, "pyoxidizer-bootstrap-packaging")?;
 
     // We need to hack `get-pip.py`'s source code to allow exclusive use of a
     // requirements file for installing `pip`. The `implicit_*` variables control
@@ -169,7 +179,47 @@ pub fn bootstrap_packaging_tools(
     Ok(())
 }
 
+/// Describe the Python version bytecode was compiled by.
+fn describe_bytecode_version(data: &[u8]) -> String {
+    match read_bytecode_magic_number(data) {
+        Ok(magic_number) => match python_version_from_magic_number(magic_number) {
+            Some(version) => format!("Python {}", version),
+            None => format!("unknown magic number {:#x}", magic_number),
+        },
+        Err(_) => "no valid header".to_string(),
+    }
+}
+
+/// Log how much memory sharing identical content of found resources saved.
+fn log_intern_statistics(logger: &slog::Logger, pool: &InternPool) {
+    let statistics = pool.statistics();
+
+    if statistics.duplicate_count > 0 {
+        warn!(
+            logger,
+            "shared content of {} duplicate files, saving {} bytes",
+            statistics.duplicate_count,
+            statistics.bytes_saved
+        );
+    }
+}
+
+/// Obtain the magic number of bytecode compiled for a distribution.
+///
+/// This doesn't run the distribution's interpreter, which may not run on
+/// the host when cross-compiling.
+fn distribution_magic_number(dist: &dyn PythonDistribution) -> Result<u32> {
+    let version = dist.python_major_minor_version();
+
+    magic_number_for_python_version(&version)
+        .ok_or_else(|| anyhow!("unknown bytecode magic number of Python {}", version))
+}
+
 /// Find resources installed as part of a packaging operation.
+///
+/// `.pyc` files shipped without source outside `__pycache__` directories are
+/// found as module bytecode if they were compiled for `dist`. Those compiled
+/// for other Python versions are kept as package resources with a warning.
 pub fn find_resources(
     logger: &slog::Logger,
     dist: &dyn PythonDistribution,
@@ -177,31 +227,76 @@ pub fn find_resources(
     state_dir: Option<PathBuf>,
 ) -> Result<Vec<PythonResource>> {
     let mut res = Vec::new();
+    let mut pool = InternPool::default();
+
+    let suffixes = dist.python_module_suffixes()?;
+    let magic_number = distribution_magic_number(dist)?;
 
-    for r in find_python_resources(&path, dist.cache_tag(), &dist.python_module_suffixes()?) {
+    for r in find_python_resources_with_sourceless_bytecode(
+        &path,
+        dist.cache_tag(),
+        &suffixes,
+        magic_number,
+    ) {
         let r = r?;
 
         match r {
             PythonResource::ModuleSource(_) => {
-                res.push(r.to_memory()?);
+                res.push(r.to_memory_interned(&mut pool)?);
             }
 
-            PythonResource::Resource(_) => {
-                res.push(r.to_memory()?);
+            // Bytecode in __pycache__ directories is compiled from source we
+            // compile ourselves. Only sourceless bytecode is kept.
+            PythonResource::ModuleBytecode(ref module) => {
+                let cached = match module.bytecode_location() {
+                    DataLocation::Path { path, .. } => {
+                        path.parent().and_then(|p| p.file_name())
+                            == Some(std::ffi::OsStr::new("__pycache__"))
+                    }
+                    _ => false,
+                };
+
+                if !cached {
+                    res.push(r.to_memory_interned(&mut pool)?);
+                }
+            }
+
+            PythonResource::Resource(ref resource) => {
+                if suffixes
+                    .bytecode
+                    .iter()
+                    .any(|suffix| resource.relative_name.ends_with(suffix))
+                {
+                    let data = resource.data.resolve()?;
+
+                    if read_bytecode_magic_number(&data).ok() != Some(magic_number) {
+                        warn!(
+                            logger,
+                            "{} is bytecode for {} but the target is Python {}; keeping it as a resource",
+                            resource.symbolic_name(),
+                            describe_bytecode_version(&data),
+                            dist.python_major_minor_version()
+                        );
+                    }
+                }
+
+                res.push(r.to_memory_interned(&mut pool)?);
             }
 
             PythonResource::DistributionResource(_) => {
-                res.push(r.to_memory()?);
+                res.push(r.to_memory_interned(&mut pool)?);
             }
 
             PythonResource::ExtensionModuleDynamicLibrary(_) => {
-                res.push(r.to_memory()?);
+                res.push(r.to_memory_interned(&mut pool)?);
             }
 
             _ => {}
         }
     }
 
+    log_intern_statistics(logger, &pool);
+
     if let Some(p) = state_dir {
         for ext in read_built_extensions(&p)? {
             res.push(PythonResource::ExtensionModuleStaticallyLinked(ext));
@@ -211,7 +306,41 @@ pub fn find_resources(
     dist.filter_compatible_python_resources(logger, &res)
 }
 
+/// Find resources in a directory of bytecode-only distributions.
+///
+/// Bytecode must have been compiled by the same Python version as `dist`.
+pub fn find_bytecode_resources(
+    logger: &slog::Logger,
+    dist: &dyn PythonDistribution,
+    path: &Path,
+) -> Result<Vec<PythonResource>> {
+    let magic_number = distribution_magic_number(dist)?;
+    let mut pool = InternPool::default();
+
+    let res = find_bytecode_only_resources(
+        path,
+        dist.cache_tag(),
+        &dist.python_module_suffixes()?,
+        magic_number,
+    )?
+    .iter()
+    .map(|r| r.to_memory_interned(&mut pool))
+    .collect::<Result<Vec<_>>>()?;
+    log_intern_statistics(logger, &pool);
+
+    dist.filter_compatible_python_resources(logger, &res)
+}
+
+/// Point the temporary directory of a process at a directory.
+fn set_temp_dir_env(env: &mut HashMap<String, String>, dir: &Path) {
+    for key in &["TMPDIR", "TEMP", "TMP"] {
+        env.insert(key.to_string(), format!("{}", dir.display()));
+    }
+}
+
 /// Run `pip install` and return found resources.
+///
+/// Packages are installed into and built in `build_dir`.
 pub fn pip_install<S: BuildHasher>(
     logger: &slog::Logger,
     dist: &dyn PythonDistribution,
@@ -219,18 +348,21 @@ pub fn pip_install<S: BuildHasher>(
     verbose: bool,
     install_args: &[String],
     extra_envs: &HashMap<String, String, S>,
+    build_dir: &Path,
 ) -> Result<Vec<PythonResource>> {
-    let temp_dir = tempdir::TempDir::new("pyoxidizer-pip-install")?;
-
     dist.ensure_pip(logger)?;
 
-    let mut env = dist.resolve_distutils(logger, libpython_link_mode, temp_dir.path(), &[])?;
+    let mut env = dist.resolve_distutils(logger, libpython_link_mode, build_dir, &[])?;
+
+    let temp_dir = build_dir.join("tmp");
+    std::fs::create_dir_all(&temp_dir)?;
+    set_temp_dir_env(&mut env, &temp_dir);
 
     for (key, value) in extra_envs.iter() {
         env.insert(key.clone(), value.clone());
     }
 
-    let target_dir = temp_dir.path().join("install");
+    let target_dir = build_dir.join("install");
 
     warn!(logger, "pip installing to {}", target_dir.display());
 
@@ -252,12 +384,32 @@ pub fn pip_install<S: BuildHasher>(
 
     pip_args.extend(install_args.iter().cloned());
 
-    // TODO send stderr to stdout
     let mut cmd = std::process::Command::new(&dist.python_exe_path())
         .args(&pip_args)
         .envs(&env)
         .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
         .spawn()?;
+
+    // Output is kept so failures can be diagnosed. stderr is read on its own
+    // thread so neither pipe can fill up and block pip.
+    let stderr = cmd
+        .stderr
+        .take()
+        .ok_or_else(|| anyhow!("unable to get stderr"))?;
+    let stderr_logger = logger.clone();
+    let stderr_reader = std::thread::spawn(move || -> std::io::Result<Vec<String>> {
+        let mut lines = vec![];
+        for line in BufReader::new(stderr).lines() {
+            let line = line?;
+            warn!(stderr_logger, "{}", line);
+            lines.push(line);
+        }
+
+        Ok(lines)
+    });
+
+    let mut output = vec![];
     {
         let stdout = cmd
             .stdout
@@ -266,13 +418,23 @@ pub fn pip_install<S: BuildHasher>(
         let reader = BufReader::new(stdout);
 
         for line in reader.lines() {
-            warn!(logger, "{}", line?);
+            let line = line?;
+            warn!(logger, "{}", line);
+            output.push(line);
         }
     }
 
+    output.extend(
+        stderr_reader
+            .join()
+            .map_err(|_| anyhow!("reading pip stderr panicked"))??,
+    );
+
     let status = cmd.wait().unwrap();
     if !status.success() {
-        return Err(anyhow!("error running pip"));
+        return Err(
+            anyhow::Error::new(parse_pip_failure(&output.join("\n"))).context("error running pip")
+        );
     }
 
     let state_dir = match env.get("PYOXIDIZER_DISTUTILS_STATE_DIR") {
@@ -295,6 +457,9 @@ pub fn read_virtualenv(
 }
 
 /// Run `setup.py install` against a path and return found resources.
+///
+/// The package is built in and installed into `build_dir`.
+#[allow(clippy::too_many_arguments)]
 pub fn setup_py_install<S: BuildHasher>(
     logger: &slog::Logger,
     dist: &dyn PythonDistribution,
@@ -303,6 +468,7 @@ pub fn setup_py_install<S: BuildHasher>(
     verbose: bool,
     extra_envs: &HashMap<String, String, S>,
     extra_global_arguments: &[String],
+    build_dir: &Path,
 ) -> Result<Vec<PythonResource>> {
     if !package_path.is_absolute() {
         return Err(anyhow!(
@@ -311,9 +477,7 @@ pub fn setup_py_install<S: BuildHasher>(
         ));
     }
 
-    let temp_dir = tempdir::TempDir::new("pyoxidizer-setup-py-install")?;
-
-    let target_dir_path = temp_dir.path().join("install");
+    let target_dir_path = build_dir.join("install");
     let target_dir_s = target_dir_path.display().to_string();
 
     let python_paths = resolve_python_paths(&target_dir_path, &dist.python_major_minor_version());
@@ -323,10 +487,14 @@ pub fn setup_py_install<S: BuildHasher>(
     let mut envs = dist.resolve_distutils(
         &logger,
         libpython_link_mode,
-        temp_dir.path(),
+        build_dir,
         &[&python_paths.site_packages, &python_paths.stdlib],
     )?;
 
+    let temp_dir = build_dir.join("tmp");
+    std::fs::create_dir_all(&temp_dir)?;
+    set_temp_dir_env(&mut envs, &temp_dir);
+
     for (key, value) in extra_envs {
         envs.insert(key.clone(), value.clone());
     }
@@ -392,6 +560,7 @@ mod tests {
     fn test_install_black() -> Result<()> {
         let logger = get_logger()?;
         let distribution = get_default_distribution()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
 
         let resources: Vec<PythonResource> = pip_install(
             &logger,
@@ -400,6 +569,7 @@ mod tests {
             false,
             &["black==19.10b0".to_string()],
             &HashMap::new(),
+            td.path(),
         )?;
 
         assert!(resources.iter().any(|r| r.full_name() == "appdirs"));
@@ -414,6 +584,7 @@ mod tests {
         let logger = get_logger()?;
 
         let distribution = get_default_dynamic_distribution()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
 
         let resources: Vec<PythonResource> = pip_install(
             &logger,
@@ -422,6 +593,7 @@ mod tests {
             false,
             &["cffi==1.14.0".to_string()],
             &HashMap::new(),
+            td.path(),
         )?;
 
         let ems = resources
@@ -0,0 +1,158 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Storage of packed resources payloads.
+
+By default, every payload is embedded in packed resources and binaries are
+self-contained. Very large deployments may instead keep payloads in a
+directory next to the binary or in a content-addressed object store shared
+between builds, embedding only the resources index. The runtime then
+obtains payloads by digest before importing anything.
+*/
+
+use {
+    super::pyembed::ExternalPayloadsSelection,
+    anyhow::{anyhow, Result},
+    python_packed_resources::sink::{DirectorySink, EmbeddedSink, ObjectStoreSink, PayloadSink},
+    std::path::Path,
+    std::sync::{Arc, Mutex},
+};
+
+/// Where payloads of packed resources are stored.
+///
+/// Instances are cheap to clone and clones share the underlying sink.
+#[derive(Clone)]
+pub struct PayloadStorage {
+    sink: Arc<Mutex<Box<dyn PayloadSink + Send>>>,
+    backend: String,
+    runtime: Option<ExternalPayloadsSelection>,
+}
+
+impl Default for PayloadStorage {
+    fn default() -> Self {
+        Self::embedded()
+    }
+}
+
+impl std::fmt::Debug for PayloadStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PayloadStorage")
+            .field("backend", &self.backend)
+            .field("runtime", &self.runtime)
+            .finish()
+    }
+}
+
+impl PayloadStorage {
+    fn new(sink: Box<dyn PayloadSink + Send>, runtime: Option<ExternalPayloadsSelection>) -> Self {
+        Self {
+            backend: sink.backend(),
+            sink: Arc::new(Mutex::new(sink)),
+            runtime,
+        }
+    }
+
+    /// Embed all payloads in packed resources.
+    pub fn embedded() -> Self {
+        Self::new(Box::new(EmbeddedSink), None)
+    }
+
+    /// Write payloads as files named by their digest to a directory.
+    ///
+    /// `runtime_directory` is where the application finds the directory at
+    /// run-time, e.g. `$ORIGIN/payloads`.
+    pub fn directory(dest: &Path, runtime_directory: &str) -> Result<Self> {
+        Ok(Self::new(
+            Box::new(DirectorySink::new(dest)?),
+            Some(ExternalPayloadsSelection {
+                directory: Some(runtime_directory.to_string()),
+                cache_dir: None,
+            }),
+        ))
+    }
+
+    /// Store payloads in an object store.
+    ///
+    /// The application fetches payloads with the `PayloadFetcher` it
+    /// configures at run-time, caching them in `runtime_cache_dir` if set.
+    pub fn object_store<S: ObjectStoreSink + Send + 'static>(
+        store: S,
+        runtime_cache_dir: Option<&str>,
+    ) -> Self {
+        Self::new(
+            Box::new(store),
+            Some(ExternalPayloadsSelection {
+                directory: None,
+                cache_dir: runtime_cache_dir.map(|dir| dir.to_string()),
+            }),
+        )
+    }
+
+    /// Name of the storage backend.
+    pub fn backend(&self) -> &str {
+        &self.backend
+    }
+
+    /// Whether payloads are stored outside of packed resources.
+    pub fn is_external(&self) -> bool {
+        self.runtime.is_some()
+    }
+
+    /// How the generated config obtains external payloads.
+    ///
+    /// `None` if all payloads are embedded.
+    pub fn external_payloads_selection(&self) -> Option<&ExternalPayloadsSelection> {
+        self.runtime.as_ref()
+    }
+
+    /// Call a function with the payload sink.
+    pub fn with_sink<T>(&self, f: impl FnOnce(&mut dyn PayloadSink) -> Result<T>) -> Result<T> {
+        let mut sink = self
+            .sink
+            .lock()
+            .map_err(|_| anyhow!("payload sink lock poisoned"))?;
+
+        f(sink.as_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packed_resources::sink::EMBEDDED_BACKEND};
+
+    #[test]
+    fn test_directory() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let storage = PayloadStorage::directory(&td.path().join("payloads"), "$ORIGIN/payloads")?;
+
+        assert_eq!(PayloadStorage::default().backend(), EMBEDDED_BACKEND);
+        assert!(!PayloadStorage::default().is_external());
+        assert_eq!(storage.backend(), "directory");
+        assert_eq!(
+            storage
+                .external_payloads_selection()
+                .unwrap()
+                .directory
+                .as_deref(),
+            Some("$ORIGIN/payloads")
+        );
+
+        let digest = python_packed_resources::sink::payload_digest(b"data");
+        let location = storage.with_sink(|sink| {
+            sink.store(
+                python_packed_resources::data::ResourceField::InMemorySource,
+                &digest,
+                b"data",
+            )
+        })?;
+        let location = location.unwrap();
+        assert_eq!(
+            std::fs::read(td.path().join("payloads").join(&location))?,
+            b"data"
+        );
+
+        Ok(())
+    }
+}
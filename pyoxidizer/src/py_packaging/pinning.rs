@@ -0,0 +1,187 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Pin the content of files read during a build.
+
+Resources reference files with `DataLocation::Path` and read them lazily.
+If a file changes while a build is running, stages of the build reading it
+at different times observe different content. e.g. a hash recorded for a
+file may not match the bytes that are shipped.
+
+`ContentPins` records the content and hash of a file the first time it is
+read. Later reads get the recorded content, whatever happens to the file.
+Small files are held in memory. Files larger than a threshold are copied
+to a spill directory instead, and the copies are memory mapped.
+*/
+
+use {
+    anyhow::{Context, Result},
+    python_packaging::resource::DataLocation,
+    sha2::{Digest, Sha256},
+    std::collections::BTreeMap,
+    std::path::{Path, PathBuf},
+};
+
+fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+/// Content of a file as it was first read.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PinnedContent {
+    /// Hex encoded SHA-256 of the content.
+    pub sha256: String,
+
+    /// Size of the content in bytes.
+    pub size: u64,
+
+    /// Location of the pinned content.
+    ///
+    /// This is in memory or a copy in the spill directory.
+    pub location: DataLocation,
+}
+
+/// Records the content of files on first read.
+#[derive(Clone, Debug)]
+pub struct ContentPins {
+    spill_dir: PathBuf,
+    spill_threshold: u64,
+    pins: BTreeMap<PathBuf, PinnedContent>,
+}
+
+impl ContentPins {
+    /// Construct an instance spilling content larger than `spill_threshold` to `spill_dir`.
+    ///
+    /// Spilled files are named by their hash. The directory must outlive
+    /// all uses of pinned locations.
+    pub fn new(spill_dir: &Path, spill_threshold: u64) -> Self {
+        Self {
+            spill_dir: spill_dir.to_path_buf(),
+            spill_threshold,
+            pins: BTreeMap::new(),
+        }
+    }
+
+    /// Obtain the pinned location of data.
+    ///
+    /// A file is read and pinned the first time it is seen. Locations not
+    /// backed by a file are returned as is.
+    pub fn pin(&mut self, location: &DataLocation) -> Result<DataLocation> {
+        let path = match location {
+            DataLocation::Path { path, .. } => path,
+            _ => return Ok(location.clone()),
+        };
+
+        if let Some(pinned) = self.pins.get(path) {
+            return Ok(pinned.location.clone());
+        }
+
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+        let sha256 = sha256_hex(&data);
+        let size = data.len() as u64;
+
+        let pinned_location = if size > self.spill_threshold {
+            let spill_path = self.spill_dir.join(&sha256);
+
+            // Identical content is only spilled once.
+            if !spill_path.exists() {
+                std::fs::create_dir_all(&self.spill_dir)
+                    .with_context(|| format!("creating {}", self.spill_dir.display()))?;
+                std::fs::write(&spill_path, &data)
+                    .with_context(|| format!("writing {}", spill_path.display()))?;
+            }
+
+            // SAFETY: The spill directory belongs to the build. Its files are
+            // named by the hash of their content, written once and never
+            // modified.
+            unsafe { DataLocation::from_path_mapped(spill_path)? }
+        } else {
+            DataLocation::Memory(data)
+        };
+
+        self.pins.insert(
+            path.clone(),
+            PinnedContent {
+                sha256,
+                size,
+                location: pinned_location.clone(),
+            },
+        );
+
+        Ok(pinned_location)
+    }
+
+    /// Obtain the pinned content of a file, if it has been pinned.
+    pub fn get(&self, path: &Path) -> Option<&PinnedContent> {
+        self.pins.get(path)
+    }
+
+    /// Obtain all pinned files and their content.
+    pub fn pins(&self) -> &BTreeMap<PathBuf, PinnedContent> {
+        &self.pins
+    }
+
+    /// Find pinned files whose content on disk differs from the pinned content.
+    ///
+    /// Files that can no longer be read are considered changed.
+    pub fn changed_files(&self) -> Vec<PathBuf> {
+        self.pins
+            .iter()
+            .filter(|(path, pinned)| match std::fs::read(path) {
+                Ok(data) => sha256_hex(&data) != pinned.sha256,
+                Err(_) => true,
+            })
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pin() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let small = td.path().join("small");
+        let large = td.path().join("large");
+        std::fs::write(&small, b"small")?;
+        std::fs::write(&large, b"large content")?;
+
+        let spill_dir = td.path().join("spill");
+        let mut pins = ContentPins::new(&spill_dir, 8);
+
+        let memory = DataLocation::Memory(b"memory".to_vec());
+        assert_eq!(pins.pin(&memory)?, memory);
+        assert!(pins.pins().is_empty());
+
+        assert_eq!(
+            pins.pin(&DataLocation::from_path(small.clone()))?,
+            DataLocation::Memory(b"small".to_vec())
+        );
+
+        let spilled = pins.pin(&DataLocation::from_path(large.clone()))?;
+        let sha256 = sha256_hex(b"large content");
+        assert_eq!(spilled, DataLocation::from_path(spill_dir.join(&sha256)));
+        assert!(matches!(&spilled, DataLocation::Path { cache, .. } if cache.is_mapped()));
+        assert_eq!(spilled.resolve()?, b"large content");
+        assert_eq!(pins.get(&large).unwrap().size, 13);
+        assert!(pins.changed_files().is_empty());
+
+        // Later reads observe pinned content even after the file changes.
+        std::fs::write(&small, b"changed")?;
+        std::fs::remove_file(&large)?;
+        assert_eq!(
+            pins.pin(&DataLocation::from_path(small.clone()))?,
+            DataLocation::Memory(b"small".to_vec())
+        );
+        assert_eq!(pins.pin(&DataLocation::from_path(large.clone()))?, spilled);
+        assert_eq!(pins.changed_files(), vec![large, small]);
+
+        Ok(())
+    }
+}
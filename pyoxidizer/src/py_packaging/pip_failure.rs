@@ -0,0 +1,359 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Diagnose why `pip install` failed from its output.
+
+pip reports failures as free form text interleaved with its progress
+output. `parse_pip_failure()` recognizes the common failures of resolving
+requirements and turns them into a `PipFailure`:
+
+* A requirement no available version satisfies.
+* A wheel that can't be installed on the target platform.
+* Requirements with conflicting dependencies.
+
+Other failures are reported with the last error pip printed.
+*/
+
+use serde::{Deserialize, Serialize};
+
+/// Why `pip install` failed.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PipFailure {
+    /// No available version satisfies a requirement.
+    MissingPackage {
+        /// The requirement, e.g. `foo==1.0`.
+        requirement: String,
+
+        /// Versions pip found for the package.
+        available_versions: Vec<String>,
+    },
+
+    /// A wheel isn't compatible with the target platform.
+    UnsupportedPlatform {
+        /// File name of the wheel.
+        wheel: String,
+    },
+
+    /// Requirements have conflicting dependencies.
+    ResolverConflict {
+        /// The requirements that couldn't be installed together.
+        requirements: String,
+
+        /// Dependencies causing the conflict, as reported by pip.
+        causes: Vec<String>,
+    },
+
+    /// A failure that wasn't recognized.
+    Other {
+        /// The last error pip printed.
+        message: String,
+    },
+}
+
+impl std::fmt::Display for PipFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PipFailure::MissingPackage {
+                requirement,
+                available_versions,
+            } => {
+                if available_versions.is_empty() {
+                    write!(f, "no version of {} is available", requirement)
+                } else {
+                    write!(
+                        f,
+                        "no version of {} is available (available versions: {})",
+                        requirement,
+                        available_versions.join(", ")
+                    )
+                }
+            }
+            PipFailure::UnsupportedPlatform { wheel } => {
+                write!(
+                    f,
+                    "{} is not a supported wheel on the target platform",
+                    wheel
+                )
+            }
+            PipFailure::ResolverConflict {
+                requirements,
+                causes,
+            } => {
+                write!(f, "{} have conflicting dependencies", requirements)?;
+                if !causes.is_empty() {
+                    write!(f, " ({})", causes.join("; "))?;
+                }
+
+                Ok(())
+            }
+            PipFailure::Other { message } => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for PipFailure {}
+
+/// Obtain the text following `prefix` in a line, ignoring pip's `ERROR: ` prefix.
+fn after<'a>(line: &'a str, prefix: &str) -> Option<&'a str> {
+    let line = line.trim();
+    let line = if line.starts_with("ERROR: ") {
+        &line["ERROR: ".len()..]
+    } else {
+        line
+    };
+
+    if line.starts_with(prefix) {
+        Some(&line[prefix.len()..])
+    } else {
+        None
+    }
+}
+
+/// Diagnose a failed `pip install` from its combined stdout and stderr.
+pub fn parse_pip_failure(output: &str) -> PipFailure {
+    let lines = output.lines().collect::<Vec<_>>();
+
+    for line in &lines {
+        if let Some(rest) = after(
+            line,
+            "Could not find a version that satisfies the requirement ",
+        ) {
+            let (requirement, versions) = match rest.find(" (from versions: ") {
+                Some(pos) => (
+                    &rest[0..pos],
+                    rest[pos + " (from versions: ".len()..].trim_end_matches(')'),
+                ),
+                None => (rest, "none"),
+            };
+
+            return PipFailure::MissingPackage {
+                requirement: requirement.trim().to_string(),
+                available_versions: if versions == "none" {
+                    vec![]
+                } else {
+                    versions.split(", ").map(|v| v.to_string()).collect()
+                },
+            };
+        }
+    }
+
+    for line in &lines {
+        if let Some(rest) = after(line, "No matching distribution found for ") {
+            return PipFailure::MissingPackage {
+                requirement: rest.trim().to_string(),
+                available_versions: vec![],
+            };
+        }
+    }
+
+    for line in &lines {
+        if let Some(pos) = line.find(" is not a supported wheel on this platform") {
+            return PipFailure::UnsupportedPlatform {
+                wheel: line[0..pos]
+                    .trim()
+                    .trim_start_matches("ERROR: ")
+                    .to_string(),
+            };
+        }
+    }
+
+    for (i, line) in lines.iter().enumerate() {
+        if let Some(rest) = after(line, "Cannot install ") {
+            let requirements = match rest.find(" because ") {
+                Some(pos) => &rest[0..pos],
+                None => rest.trim_end_matches('.'),
+            };
+
+            // Causes are the indented lines after "The conflict is caused by:".
+            let causes = lines[i + 1..]
+                .iter()
+                .skip_while(|l| l.trim() != "The conflict is caused by:")
+                .skip(1)
+                .take_while(|l| l.starts_with(' ') && !l.trim().is_empty())
+                .map(|l| l.trim().to_string())
+                .collect();
+
+            return PipFailure::ResolverConflict {
+                requirements: requirements.to_string(),
+                causes,
+            };
+        }
+    }
+
+    // Prefer the last error pip printed over whatever output came last.
+    let message = lines
+        .iter()
+        .rev()
+        .map(|line| line.trim())
+        .find(|line| line.starts_with("ERROR: "))
+        .map(|line| &line["ERROR: ".len()..])
+        .or_else(|| {
+            lines
+                .iter()
+                .rev()
+                .map(|line| line.trim())
+                .find(|line| !line.is_empty())
+        })
+        .unwrap_or("");
+
+    PipFailure::Other {
+        message: if message.is_empty() {
+            "pip failed without output".to_string()
+        } else {
+            message.to_string()
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Output of `pip install foo==9.9` for a package without that version.
+    const MISSING_VERSION: &str = "\
+Collecting foo==9.9
+ERROR: Could not find a version that satisfies the requirement foo==9.9 (from versions: 1.0, 1.1)
+ERROR: No matching distribution found for foo==9.9
+";
+
+    /// Output of an older pip installing a package that doesn't exist.
+    const MISSING_PACKAGE: &str = "\
+ERROR: Could not find a version that satisfies the requirement doesnotexist (from versions: none)
+ERROR: No matching distribution found for doesnotexist
+";
+
+    /// Output of installing a wheel built for another platform.
+    const UNSUPPORTED_WHEEL: &str = "\
+ERROR: foo-1.0-cp39-cp39-win_amd64.whl is not a supported wheel on this platform.
+";
+
+    /// Output of the pip 20.3 resolver given conflicting requirements.
+    const RESOLVER_CONFLICT: &str = "\
+Collecting bar==1.0
+Collecting baz==2.0
+ERROR: Cannot install bar==1.0 and baz==2.0 because these package versions have conflicting dependencies.
+
+The conflict is caused by:
+    bar 1.0 depends on six==1.14.0
+    baz 2.0 depends on six==1.15.0
+
+To fix this you could try to:
+1. loosen the range of package versions you've specified
+2. remove package versions to allow pip attempt to solve the dependency conflict
+
+ERROR: ResolutionImpossible: for help visit https://pip.pypa.io/en/latest/user_guide/#fixing-conflicting-dependencies
+";
+
+    /// Output of a failure building a package.
+    const BUILD_FAILURE: &str = "\
+Building wheels for collected packages: foo
+  Running setup.py bdist_wheel for foo ... error
+ERROR: Command errored out with exit status 1: python setup.py bdist_wheel
+";
+
+    #[test]
+    fn test_missing_package() {
+        assert_eq!(
+            parse_pip_failure(MISSING_VERSION),
+            PipFailure::MissingPackage {
+                requirement: "foo==9.9".to_string(),
+                available_versions: vec!["1.0".to_string(), "1.1".to_string()],
+            }
+        );
+
+        let failure = parse_pip_failure(MISSING_PACKAGE);
+        assert_eq!(
+            failure,
+            PipFailure::MissingPackage {
+                requirement: "doesnotexist".to_string(),
+                available_versions: vec![],
+            }
+        );
+        assert_eq!(
+            failure.to_string(),
+            "no version of doesnotexist is available"
+        );
+
+        assert_eq!(
+            parse_pip_failure("ERROR: No matching distribution found for foo\n"),
+            PipFailure::MissingPackage {
+                requirement: "foo".to_string(),
+                available_versions: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_unsupported_platform() {
+        let failure = parse_pip_failure(UNSUPPORTED_WHEEL);
+        assert_eq!(
+            failure,
+            PipFailure::UnsupportedPlatform {
+                wheel: "foo-1.0-cp39-cp39-win_amd64.whl".to_string(),
+            }
+        );
+        assert_eq!(
+            failure.to_string(),
+            "foo-1.0-cp39-cp39-win_amd64.whl is not a supported wheel on the target platform"
+        );
+    }
+
+    #[test]
+    fn test_resolver_conflict() {
+        let failure = parse_pip_failure(RESOLVER_CONFLICT);
+        assert_eq!(
+            failure,
+            PipFailure::ResolverConflict {
+                requirements: "bar==1.0 and baz==2.0".to_string(),
+                causes: vec![
+                    "bar 1.0 depends on six==1.14.0".to_string(),
+                    "baz 2.0 depends on six==1.15.0".to_string(),
+                ],
+            }
+        );
+        assert_eq!(
+            failure.to_string(),
+            "bar==1.0 and baz==2.0 have conflicting dependencies \
+             (bar 1.0 depends on six==1.14.0; baz 2.0 depends on six==1.15.0)"
+        );
+    }
+
+    #[test]
+    fn test_other() {
+        assert_eq!(
+            parse_pip_failure(BUILD_FAILURE),
+            PipFailure::Other {
+                message: "Command errored out with exit status 1: python setup.py bdist_wheel"
+                    .to_string(),
+            }
+        );
+        assert_eq!(
+            parse_pip_failure("Traceback (most recent call last):\nOSError: boom\n"),
+            PipFailure::Other {
+                message: "OSError: boom".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_pip_failure(""),
+            PipFailure::Other {
+                message: "pip failed without output".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_serialize() -> anyhow::Result<()> {
+        let failure = parse_pip_failure(UNSUPPORTED_WHEEL);
+        let json = serde_json::to_string(&failure)?;
+        assert_eq!(
+            json,
+            "{\"kind\":\"unsupported_platform\",\"wheel\":\"foo-1.0-cp39-cp39-win_amd64.whl\"}"
+        );
+        assert_eq!(serde_json::from_str::<PipFailure>(&json)?, failure);
+
+        Ok(())
+    }
+}
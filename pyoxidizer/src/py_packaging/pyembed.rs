@@ -8,143 +8,1181 @@ Functionality related to the pyembed crate.
 
 use anyhow::Result;
 use itertools::Itertools;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 
-use super::config::{EmbeddedPythonConfig, RawAllocator, RunMode, TerminfoResolution};
+use super::config::{
+    ConfigProblem, ConfigProblemSeverity, EmbeddedPythonConfig, RawAllocator, RunMode,
+    RuntimeEnvRegistry, TerminfoResolution, PYTHON_ENV_SETTING, PYTHON_ENV_VARS,
+};
+
+/// A finder the embedded interpreter can install on `sys.meta_path`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ImporterFinder {
+    /// The `oxidized_importer` finder, loading resources from packed resources data.
+    ///
+    /// Serves resources placed in memory as well as those installed
+    /// relative to the binary, as both are indexed in packed resources.
+    InMemory,
+
+    /// The standard library's path based finder, searching `sys.path`.
+    Filesystem,
+
+    /// Importing the standard library from a zip file on `sys.path`.
+    ///
+    /// Zip files are handled by a path hook of the filesystem finder.
+    ZipStdlib,
+}
+
+impl std::fmt::Display for ImporterFinder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::InMemory => "in-memory",
+            Self::Filesystem => "filesystem",
+            Self::ZipStdlib => "zip-stdlib",
+        })
+    }
+}
+
+/// Where the in-memory finder obtains packed resources data from.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackedResourcesSource {
+    /// A file embedded in the binary with `include_bytes!`.
+    Embedded(PathBuf),
+
+    /// Data placed in a linker section by the binary's build.
+    ///
+    /// `symbol` is the Rust name of the symbol at the start of the data and
+    /// `length` the size of the data in bytes.
+    LinkerSection { symbol: String, length: usize },
+}
+
+/// Environment variable `pyembed` reads to select the recovery resource set.
+pub const RECOVERY_ENV: &str = "PYEMBED_RECOVERY";
+
+/// How the generated config selects the recovery resource set at startup.
+///
+/// The recovery set is selected if `RECOVERY_ENV` is set to anything but an
+/// empty string or `0`, or if the marker file exists.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecoverySelection {
+    /// File whose existence selects the recovery set.
+    ///
+    /// `$ORIGIN` resolves to the directory of the application at run-time.
+    pub marker_file: Option<String>,
+}
+
+impl RecoverySelection {
+    /// Register the environment variables read to select the resource set.
+    pub fn register_runtime_env(&self, env: &mut RuntimeEnvRegistry) {
+        env.register(RECOVERY_ENV, "resource_set");
+    }
+
+    /// Render the Rust expression selecting the resource set.
+    fn render(&self) -> String {
+        format!(
+            "pyembed::select_resource_set({})",
+            match &self.marker_file {
+                Some(path) => format!("Some(r###\"{}\"###)", path),
+                None => "None".to_string(),
+            }
+        )
+    }
+}
+
+/// How the generated config obtains payloads stored outside of packed resources.
+///
+/// Payloads are read from `directory`, then from `cache_dir`, and are
+/// otherwise fetched by the `PayloadFetcher` the application configures at
+/// run-time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExternalPayloadsSelection {
+    /// Directory holding payloads at their logical location.
+    ///
+    /// `$ORIGIN` resolves to the directory of the application and
+    /// `$CACHE_DIR` to the per-user cache directory at run-time.
+    pub directory: Option<String>,
+
+    /// Directory to cache fetched payloads in.
+    ///
+    /// Supports the same variables as `directory`.
+    pub cache_dir: Option<String>,
+}
+
+impl ExternalPayloadsSelection {
+    /// Register the environment variables read to resolve payload directories.
+    pub fn register_runtime_env(&self, env: &mut RuntimeEnvRegistry) {
+        let reads_cache_dir = [&self.directory, &self.cache_dir]
+            .iter()
+            .filter_map(|dir| dir.as_ref())
+            .any(|dir| dir.contains("$CACHE_DIR"));
+
+        if reads_cache_dir {
+            for name in CACHE_DIR_ENV_VARS {
+                env.register(name, "external_payloads");
+            }
+        }
+    }
+
+    /// Render the Rust expression of the external payloads config.
+    fn render(&self) -> String {
+        let render_path = |path: &Option<String>| match path {
+            Some(path) => format!("Some(r###\"{}\"###.to_string())", path),
+            None => "None".to_string(),
+        };
+
+        format!(
+            "Some(pyembed::ExternalPayloadsConfig {{ directory: {}, cache_dir: {}, fetcher: None }})",
+            render_path(&self.directory),
+            render_path(&self.cache_dir)
+        )
+    }
+}
+
+/// Importer settings of an embedded interpreter.
+///
+/// This is the importer related part of the emitted `PythonConfig`. It can
+/// be inspected and adjusted before being rendered.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImporterConfig {
+    /// Enabled finders, in the order they are consulted.
+    pub finders: Vec<ImporterFinder>,
+
+    /// Sources of packed resources data for the in-memory finder.
+    pub packed_resources: Vec<PackedResourcesSource>,
+
+    /// Paths to add to `sys.path`.
+    ///
+    /// `$ORIGIN` resolves to the directory of the application at run-time.
+    pub sys_paths: Vec<String>,
+
+    /// How the recovery resource set is selected.
+    ///
+    /// `None` if packed resources have no recovery set, in which case the
+    /// main set is always loaded.
+    pub recovery: Option<RecoverySelection>,
+
+    /// How payloads stored outside of packed resources are obtained.
+    ///
+    /// `None` if packed resources are self-contained.
+    pub external_payloads: Option<ExternalPayloadsSelection>,
+}
+
+impl ImporterConfig {
+    /// Derive importer settings from an `EmbeddedPythonConfig`.
+    ///
+    /// `embedded_resources_path` is the packed resources file to embed.
+    pub fn from_embedded_config(
+        embedded: &EmbeddedPythonConfig,
+        embedded_resources_path: &Path,
+    ) -> Self {
+        Self::from_embedded_config_with_source(
+            embedded,
+            PackedResourcesSource::Embedded(embedded_resources_path.to_path_buf()),
+        )
+    }
+
+    /// Derive importer settings from an `EmbeddedPythonConfig`, obtaining packed resources from `source`.
+    pub fn from_embedded_config_with_source(
+        embedded: &EmbeddedPythonConfig,
+        source: PackedResourcesSource,
+    ) -> Self {
+        let mut finders = vec![ImporterFinder::InMemory];
+
+        if embedded.filesystem_importer {
+            finders.push(ImporterFinder::Filesystem);
+
+            if embedded.sys_paths.iter().any(|p| p.ends_with(".zip")) {
+                finders.push(ImporterFinder::ZipStdlib);
+            }
+        }
+
+        Self {
+            finders,
+            packed_resources: vec![source],
+            sys_paths: embedded.sys_paths.clone(),
+            recovery: None,
+            external_payloads: None,
+        }
+    }
+
+    /// Whether a finder is enabled.
+    pub fn is_enabled(&self, finder: ImporterFinder) -> bool {
+        self.finders.contains(&finder)
+    }
+
+    /// Whether a finder has anything to load resources from.
+    fn has_source(&self, finder: ImporterFinder) -> bool {
+        match finder {
+            ImporterFinder::InMemory => !self.packed_resources.is_empty(),
+            ImporterFinder::Filesystem => !self.sys_paths.is_empty(),
+            ImporterFinder::ZipStdlib => self.sys_paths.iter().any(|p| p.ends_with(".zip")),
+        }
+    }
+
+    /// Validate the settings.
+    ///
+    /// `placements` maps names of placed resources to the finder that
+    /// must load them.
+    ///
+    /// Returns all problems found. Problems with severity `Error` should
+    /// prevent the configuration from being rendered.
+    pub fn validate(&self, placements: &BTreeMap<String, ImporterFinder>) -> Vec<ConfigProblem> {
+        let mut problems = Vec::new();
+
+        let mut sorted = self.finders.clone();
+        sorted.sort();
+        sorted.dedup();
+
+        if sorted.len() != self.finders.len() {
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "importer-finder-duplicate",
+                message: "finders must be enabled at most once".to_string(),
+            });
+        } else if sorted != self.finders {
+            // pyembed installs its finder ahead of the standard library's.
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "importer-finder-order",
+                message: format!(
+                    "finders must be ordered {}",
+                    sorted.iter().map(|f| f.to_string()).join(", ")
+                ),
+            });
+        }
+
+        if self.is_enabled(ImporterFinder::ZipStdlib)
+            && !self.is_enabled(ImporterFinder::Filesystem)
+        {
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "importer-zip-without-filesystem",
+                message: "the zip-stdlib finder requires the filesystem finder".to_string(),
+            });
+        }
+
+        for finder in &sorted {
+            if self.has_source(*finder) {
+                continue;
+            }
+
+            // The filesystem finder still searches the interpreter's
+            // default sys.path.
+            let severity = if *finder == ImporterFinder::Filesystem {
+                ConfigProblemSeverity::Warning
+            } else {
+                ConfigProblemSeverity::Error
+            };
+
+            problems.push(ConfigProblem {
+                severity,
+                code: "importer-finder-without-source",
+                message: match finder {
+                    ImporterFinder::InMemory => {
+                        "the in-memory finder is enabled but has no packed resources".to_string()
+                    }
+                    ImporterFinder::Filesystem => {
+                        "the filesystem finder is enabled but sys_paths is empty".to_string()
+                    }
+                    ImporterFinder::ZipStdlib => {
+                        "the zip-stdlib finder is enabled but no .zip file is in sys_paths"
+                            .to_string()
+                    }
+                },
+            });
+        }
+
+        if self.recovery.is_some() && !self.is_enabled(ImporterFinder::InMemory) {
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "importer-recovery-without-in-memory",
+                message: "the recovery resource set requires the in-memory finder".to_string(),
+            });
+        }
+
+        if self.external_payloads.is_some() && !self.is_enabled(ImporterFinder::InMemory) {
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "importer-external-payloads-without-in-memory",
+                message: "external payloads require the in-memory finder".to_string(),
+            });
+        }
+
+        if self.packed_resources.len() > 1 {
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "importer-multiple-packed-resources",
+                message: "pyembed supports a single packed resources source".to_string(),
+            });
+        }
+
+        let mut unserved: BTreeMap<ImporterFinder, Vec<&str>> = BTreeMap::new();
+        for (name, finder) in placements {
+            if !self.is_enabled(*finder) {
+                unserved.entry(*finder).or_default().push(name);
+            }
+        }
+
+        for (finder, names) in unserved {
+            problems.push(ConfigProblem {
+                severity: ConfigProblemSeverity::Error,
+                code: "importer-finder-disabled",
+                message: format!(
+                    "resources are placed for the {} finder, which is disabled: {}",
+                    finder,
+                    names.join(", ")
+                ),
+            });
+        }
+
+        problems
+    }
+
+    /// Obtain the `PythonConfig` fields defined by these settings, other than `packed_resources`.
+    ///
+    /// Fields are returned in order as the field name and the Rust
+    /// expression of its value.
+    pub fn settings(&self) -> Vec<(String, String)> {
+        vec![
+            (
+                "use_custom_importlib".to_string(),
+                self.is_enabled(ImporterFinder::InMemory).to_string(),
+            ),
+            (
+                "filesystem_importer".to_string(),
+                self.is_enabled(ImporterFinder::Filesystem).to_string(),
+            ),
+            (
+                "sys_paths".to_string(),
+                format!(
+                    "[{}].to_vec()",
+                    self.sys_paths
+                        .iter()
+                        .map(|p| "\"".to_owned() + p + "\".to_string()")
+                        .join(", ")
+                ),
+            ),
+            (
+                "resource_set".to_string(),
+                match &self.recovery {
+                    Some(recovery) => recovery.render(),
+                    None => "pyembed::ResourceSet::Main".to_string(),
+                },
+            ),
+            (
+                "external_payloads".to_string(),
+                match &self.external_payloads {
+                    Some(selection) => selection.render(),
+                    None => "None".to_string(),
+                },
+            ),
+        ]
+    }
+
+    /// Render the `PythonConfig` fields defined by these settings.
+    ///
+    /// Fields are returned in order as `<name>: <value>`.
+    pub fn render_fields(&self) -> Vec<String> {
+        let mut fields = self
+            .settings()
+            .into_iter()
+            .map(|(name, value)| format!("{}: {}", name, value))
+            .collect::<Vec<_>>();
+        fields.push(format!(
+            "packed_resources: {}",
+            render_packed_resources(self.packed_resources.first())
+        ));
+
+        fields
+    }
+}
+
+/// Render the Rust expression evaluating to packed resources data.
+fn render_packed_resources(source: Option<&PackedResourcesSource>) -> String {
+    match source {
+        Some(PackedResourcesSource::Embedded(path)) => {
+            format!("include_bytes!(r#\"{}\"#)", path.display())
+        }
+        Some(PackedResourcesSource::LinkerSection { symbol, length }) => format!(
+            "unsafe {{ extern \"C\" {{ #[link_name = \"{}\"] static PACKED_RESOURCES: [u8; {}]; }} &PACKED_RESOURCES[..] }}",
+            symbol, length
+        ),
+        None => "&[]".to_string(),
+    }
+}
 
 /// Obtain the Rust source code to construct a PythonConfig instance.
 pub fn derive_python_config(
     embedded: &EmbeddedPythonConfig,
     embedded_resources_path: &PathBuf,
 ) -> String {
-    format!(
-        "pyembed::PythonConfig {{\n    \
-         standard_io_encoding: {},\n    \
-         standard_io_errors: {},\n    \
-         opt_level: {},\n    \
-         use_custom_importlib: true,\n    \
-         filesystem_importer: {},\n    \
-         sys_paths: [{}].to_vec(),\n    \
-         bytes_warning: {},\n    \
-         import_site: {},\n    \
-         import_user_site: {},\n    \
-         ignore_python_env: {},\n    \
-         inspect: {},\n    \
-         interactive: {},\n    \
-         isolated: {},\n    \
-         legacy_windows_fs_encoding: {},\n    \
-         legacy_windows_stdio: {},\n    \
-         write_bytecode: {},\n    \
-         unbuffered_stdio: {},\n    \
-         parser_debug: {},\n    \
-         quiet: {},\n    \
-         use_hash_seed: {},\n    \
-         verbose: {},\n    \
-         packed_resources: include_bytes!(r#\"{}\"#),\n    \
-         extra_extension_modules: vec![],\n    \
-         argvb: false,\n    \
-         sys_frozen: {},\n    \
-         sys_meipass: {},\n    \
-         raw_allocator: {},\n    \
-         terminfo_resolution: {},\n    \
-         write_modules_directory_env: {},\n    \
-         run: {},\n\
-         }}",
-        match &embedded.stdio_encoding_name {
-            Some(value) => format_args!("Some(\"{}\")", value).to_string(),
-            None => "None".to_owned(),
-        },
-        match &embedded.stdio_encoding_errors {
-            Some(value) => format_args!("Some(\"{}\")", value).to_string(),
-            None => "None".to_owned(),
-        },
-        embedded.optimize_level,
-        embedded.filesystem_importer,
-        &embedded
-            .sys_paths
+    derive_python_config_with_importer(
+        embedded,
+        &ImporterConfig::from_embedded_config(embedded, embedded_resources_path),
+    )
+}
+
+/// A `PythonConfig` derived from an `EmbeddedPythonConfig`, before it is rendered.
+///
+/// Settings are the Rust expressions of `PythonConfig` fields, in the order
+/// they are rendered. The packed resources data is held separately and
+/// rendered after `sys_paths`, where `PythonConfig` declares it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PythonConfigSettings {
+    /// Field names and the Rust expressions of their values.
+    pub settings: Vec<(String, String)>,
+
+    /// Where packed resources data is obtained from.
+    pub packed_resources: Option<PackedResourcesSource>,
+
+    /// Environment variables the settings read at run-time.
+    pub runtime_env: RuntimeEnvRegistry,
+}
+
+/// A difference between two `PythonConfigSettings`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PythonConfigChange {
+    /// A field only present in the new settings.
+    Added { name: String, value: String },
+
+    /// A field only present in the old settings.
+    Removed { name: String, value: String },
+
+    /// A field whose value changed.
+    Changed {
+        name: String,
+        old: String,
+        new: String,
+    },
+}
+
+impl std::fmt::Display for PythonConfigChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Added { name, value } => write!(f, "+{}: {}", name, value),
+            Self::Removed { name, value } => write!(f, "-{}: {}", name, value),
+            Self::Changed { name, old, new } => write!(f, "{}: {} -> {}", name, old, new),
+        }
+    }
+}
+
+impl PythonConfigSettings {
+    /// Obtain the Rust expression of a field.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.settings
             .iter()
-            .map(|p| "\"".to_owned() + p + "\".to_string()")
-            .collect::<Vec<String>>()
-            .join(", "),
-        embedded.bytes_warning,
-        embedded.site_import,
-        embedded.user_site_directory,
-        embedded.ignore_environment,
-        embedded.inspect,
-        embedded.interactive,
-        embedded.isolated,
-        embedded.legacy_windows_fs_encoding,
-        embedded.legacy_windows_stdio,
-        embedded.write_bytecode,
-        embedded.unbuffered_stdio,
-        embedded.parser_debug,
-        embedded.quiet,
-        embedded.use_hash_seed,
-        embedded.verbose,
-        embedded_resources_path.display(),
-        embedded.sys_frozen,
-        embedded.sys_meipass,
-        match embedded.raw_allocator {
-            RawAllocator::Jemalloc => "pyembed::PythonRawAllocator::jemalloc()",
-            RawAllocator::Rust => "pyembed::PythonRawAllocator::rust()",
-            RawAllocator::System => "pyembed::PythonRawAllocator::system()",
-        },
-        match embedded.terminfo_resolution {
-            TerminfoResolution::Dynamic => "pyembed::TerminfoResolution::Dynamic".to_string(),
-            TerminfoResolution::None => "pyembed::TerminfoResolution::None".to_string(),
-            TerminfoResolution::Static(ref v) => {
-                format!("pyembed::TerminfoResolution::Static(r###\"{}\"###", v)
-            }
-        },
-        match &embedded.write_modules_directory_env {
-            Some(path) => "Some(\"".to_owned() + &path + "\".to_string())",
-            _ => "None".to_owned(),
-        },
-        match embedded.run_mode {
-            RunMode::Noop => "pyembed::PythonRunMode::None".to_owned(),
-            RunMode::Repl => "pyembed::PythonRunMode::Repl".to_owned(),
-            RunMode::Module { ref module } => {
-                "pyembed::PythonRunMode::Module { module: \"".to_owned()
-                    + module
-                    + "\".to_string() }"
-            }
-            RunMode::Eval { ref code } => {
-                "pyembed::PythonRunMode::Eval { code: r###\"".to_owned()
-                    + code
-                    + "\"###.to_string() }"
+            .find(|(field, _)| field == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Obtain all fields in the order they are rendered.
+    ///
+    /// Unlike `settings`, this includes `packed_resources`.
+    pub fn fields(&self) -> Vec<(String, String)> {
+        let resources = (
+            "packed_resources".to_string(),
+            render_packed_resources(self.packed_resources.as_ref()),
+        );
+
+        let mut fields = Vec::with_capacity(self.settings.len() + 1);
+        let mut resources = Some(resources);
+        for (name, value) in &self.settings {
+            fields.push((name.clone(), value.clone()));
+
+            if name == "sys_paths" {
+                fields.extend(resources.take());
             }
-            RunMode::File { ref path } => {
-                "pyembed::PythonRunMode::File { path: std::path::PathBuf::new(r###\"".to_owned()
-                    + path
-                    + "\"###) }"
+        }
+        fields.extend(resources);
+
+        fields
+    }
+
+    /// Obtain the differences between these settings and `other`.
+    ///
+    /// Changes are ordered by the fields of `other`, followed by fields
+    /// only present in these settings.
+    pub fn diff(&self, other: &Self) -> Vec<PythonConfigChange> {
+        let old = self.fields();
+        let new = other.fields();
+        let old_values = old.iter().cloned().collect::<BTreeMap<_, _>>();
+        let new_values = new.iter().cloned().collect::<BTreeMap<_, _>>();
+
+        let mut changes = new
+            .into_iter()
+            .filter_map(|(name, value)| match old_values.get(&name) {
+                Some(old) if *old == value => None,
+                Some(old) => Some(PythonConfigChange::Changed {
+                    name,
+                    old: old.clone(),
+                    new: value,
+                }),
+                None => Some(PythonConfigChange::Added { name, value }),
+            })
+            .collect::<Vec<_>>();
+
+        changes.extend(
+            old.into_iter()
+                .filter(|(name, _)| !new_values.contains_key(name))
+                .map(|(name, value)| PythonConfigChange::Removed { name, value }),
+        );
+
+        changes
+    }
+
+    /// Render the Rust source code to construct a PythonConfig instance.
+    pub fn render(&self) -> String {
+        format!(
+            "pyembed::PythonConfig {{\n{}}}",
+            self.fields()
+                .iter()
+                .map(|(name, value)| format!("    {}: {},\n", name, value))
+                .join("")
+        )
+    }
+
+    /// Render a standalone .rs file containing a function for obtaining the default PythonConfig.
+    pub fn render_default_python_config_rs(&self) -> String {
+        // Ideally we would have a const struct, but we need to do some
+        // dynamic allocations. Using a function avoids having to pull in a
+        // dependency on lazy_static.
+        let indented = self
+            .render()
+            .split('\n')
+            .map(|line| "    ".to_owned() + line)
+            .join("\n");
+
+        format!(
+            "/// Obtain the default Python configuration\n\
+             ///\n\
+             /// The crate is compiled with a default Python configuration embedded\n\
+             /// in the crate. This function will return an instance of that\n\
+             /// configuration.\n\
+             pub fn default_python_config<'a>() -> pyembed::PythonConfig<'a> {{\n{}\n}}\n",
+            indented
+        )
+    }
+}
+
+/// Obtain the Rust source code to construct a PythonConfig instance with importer settings.
+///
+/// Importer related fields of `embedded` are ignored in favor of `importer`.
+pub fn derive_python_config_with_importer(
+    embedded: &EmbeddedPythonConfig,
+    importer: &ImporterConfig,
+) -> String {
+    derive_python_config_settings(embedded, importer).render()
+}
+
+/// Derive the fields of a PythonConfig instance with importer settings.
+///
+/// Importer related fields of `embedded` are ignored in favor of `importer`.
+pub fn derive_python_config_settings(
+    embedded: &EmbeddedPythonConfig,
+    importer: &ImporterConfig,
+) -> PythonConfigSettings {
+    let mut settings = vec![
+        (
+            "standard_io_encoding",
+            match &embedded.stdio_encoding_name {
+                Some(value) => format_args!("Some(\"{}\")", value).to_string(),
+                None => "None".to_owned(),
+            },
+        ),
+        (
+            "standard_io_errors",
+            match &embedded.stdio_encoding_errors {
+                Some(value) => format_args!("Some(\"{}\")", value).to_string(),
+                None => "None".to_owned(),
+            },
+        ),
+        ("opt_level", embedded.optimize_level.to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect::<Vec<_>>();
+
+    settings.extend(importer.settings());
+
+    settings.extend(
+        vec![
+            ("bytes_warning", embedded.bytes_warning.to_string()),
+            ("import_site", embedded.site_import.to_string()),
+            ("import_user_site", embedded.user_site_directory.to_string()),
+            ("ignore_python_env", embedded.ignore_environment.to_string()),
+            ("inspect", embedded.inspect.to_string()),
+            ("interactive", embedded.interactive.to_string()),
+            ("isolated", embedded.isolated.to_string()),
+            (
+                "legacy_windows_fs_encoding",
+                embedded.legacy_windows_fs_encoding.to_string(),
+            ),
+            (
+                "legacy_windows_stdio",
+                embedded.legacy_windows_stdio.to_string(),
+            ),
+            ("write_bytecode", embedded.write_bytecode.to_string()),
+            ("unbuffered_stdio", embedded.unbuffered_stdio.to_string()),
+            ("parser_debug", embedded.parser_debug.to_string()),
+            ("quiet", embedded.quiet.to_string()),
+            ("use_hash_seed", embedded.use_hash_seed.to_string()),
+            (
+                "hash_seed",
+                match embedded.resolved_hash_seed() {
+                    Some(seed) => format!("Some({})", seed),
+                    None => "None".to_owned(),
+                },
+            ),
+            ("verbose", embedded.verbose.to_string()),
+            ("extra_extension_modules", "vec![]".to_string()),
+            ("argvb", "false".to_string()),
+            ("sys_frozen", embedded.sys_frozen.to_string()),
+            ("sys_meipass", embedded.sys_meipass.to_string()),
+            (
+                "site_packages_shim",
+                match &embedded.site_packages_shim {
+                    Some(path) => format!("Some(r###\"{}\"###.to_string())", path),
+                    None => "None".to_owned(),
+                },
+            ),
+            (
+                "raw_allocator",
+                match embedded.raw_allocator {
+                    RawAllocator::Jemalloc => "pyembed::PythonRawAllocator::jemalloc()",
+                    RawAllocator::Rust => "pyembed::PythonRawAllocator::rust()",
+                    RawAllocator::System => "pyembed::PythonRawAllocator::system()",
+                }
+                .to_string(),
+            ),
+            (
+                "terminfo_resolution",
+                match embedded.terminfo_resolution {
+                    TerminfoResolution::Dynamic => {
+                        "pyembed::TerminfoResolution::Dynamic".to_string()
+                    }
+                    TerminfoResolution::None => "pyembed::TerminfoResolution::None".to_string(),
+                    TerminfoResolution::Static(ref v) => {
+                        format!("pyembed::TerminfoResolution::Static(r###\"{}\"###", v)
+                    }
+                },
+            ),
+            (
+                "write_modules_directory_env",
+                match &embedded.write_modules_directory_env {
+                    Some(path) => "Some(\"".to_owned() + &path + "\".to_string())",
+                    _ => "None".to_owned(),
+                },
+            ),
+            (
+                "import_telemetry_path",
+                match &embedded.import_telemetry_path {
+                    Some(path) => format!("Some(r###\"{}\"###.to_string())", path),
+                    None => "None".to_owned(),
+                },
+            ),
+            (
+                "preload_modules",
+                format!(
+                    "[{}].to_vec()",
+                    embedded
+                        .preload_modules
+                        .iter()
+                        .map(|m| "\"".to_owned() + m + "\".to_string()")
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                ),
+            ),
+            (
+                "subinterpreter_config",
+                match &embedded.subinterpreters {
+                    Some(c) => format!(
+                        "Some(pyembed::SubinterpreterConfig {{ \
+                         use_main_obmalloc: {}, \
+                         allow_fork: {}, \
+                         allow_exec: {}, \
+                         allow_threads: {}, \
+                         allow_daemon_threads: {}, \
+                         check_multi_interp_extensions: {}, \
+                         own_gil: {} }})",
+                        c.use_main_obmalloc,
+                        c.allow_fork,
+                        c.allow_exec,
+                        c.allow_threads,
+                        c.allow_daemon_threads,
+                        c.check_multi_interp_extensions,
+                        c.own_gil
+                    ),
+                    None => "None".to_owned(),
+                },
+            ),
+            (
+                "shared_library_extraction_dir",
+                match &embedded.shared_library_extraction_dir {
+                    Some(path) => "Some(r###\"".to_owned() + path + "\"###.to_string())",
+                    None => "None".to_owned(),
+                },
+            ),
+            (
+                "run",
+                match embedded.run_mode {
+                    RunMode::Noop => "pyembed::PythonRunMode::None".to_owned(),
+                    RunMode::Repl => "pyembed::PythonRunMode::Repl".to_owned(),
+                    RunMode::Module { ref module } => {
+                        "pyembed::PythonRunMode::Module { module: \"".to_owned()
+                            + module
+                            + "\".to_string() }"
+                    }
+                    RunMode::Eval { ref code } => {
+                        "pyembed::PythonRunMode::Eval { code: r###\"".to_owned()
+                            + code
+                            + "\"###.to_string() }"
+                    }
+                    RunMode::File { ref path } => {
+                        "pyembed::PythonRunMode::File { path: std::path::PathBuf::new(r###\""
+                            .to_owned()
+                            + path
+                            + "\"###) }"
+                    }
+                },
+            ),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value)),
+    );
+
+    let mut runtime_env = derive_runtime_env(embedded);
+    if let Some(recovery) = &importer.recovery {
+        recovery.register_runtime_env(&mut runtime_env);
+    }
+    if let Some(selection) = &importer.external_payloads {
+        selection.register_runtime_env(&mut runtime_env);
+    }
+
+    PythonConfigSettings {
+        settings,
+        packed_resources: importer.packed_resources.first().cloned(),
+        runtime_env,
+    }
+}
+
+/// Environment variables `pyembed` reads to resolve `$CACHE_DIR`.
+const CACHE_DIR_ENV_VARS: &[&str] = &["HOME", "LOCALAPPDATA", "XDG_CACHE_HOME"];
+
+/// Determine the environment variables a PythonConfig derived from `embedded` reads at run-time.
+///
+/// Variables are registered under the `PythonConfig` setting reading them.
+/// `$CACHE_DIR` is resolved from different variables on each platform, so
+/// all of them are registered.
+pub fn derive_runtime_env(embedded: &EmbeddedPythonConfig) -> RuntimeEnvRegistry {
+    let mut env = RuntimeEnvRegistry::default();
+
+    if !embedded.ignore_environment {
+        for name in PYTHON_ENV_VARS {
+            env.register(name, PYTHON_ENV_SETTING);
+        }
+    }
+
+    if let Some(name) = &embedded.write_modules_directory_env {
+        env.register(name, "write_modules_directory_env");
+    }
+
+    if embedded.terminfo_resolution == TerminfoResolution::Dynamic {
+        env.register("TERMINFO_DIRS", "terminfo_resolution");
+    }
+
+    if let Some(dir) = &embedded.shared_library_extraction_dir {
+        if dir.contains("$CACHE_DIR") {
+            for name in CACHE_DIR_ENV_VARS {
+                env.register(name, "shared_library_extraction_dir");
             }
-        },
-    )
+        }
+    }
+
+    env
 }
 
 /// Write a standalone .rs file containing a function for obtaining the default PythonConfig.
-pub fn write_default_python_config_rs(path: &Path, python_config_rs: &str) -> Result<()> {
+pub fn write_default_python_config_rs(path: &Path, config: &PythonConfigSettings) -> Result<()> {
     let mut f = File::create(&path)?;
-
-    // Ideally we would have a const struct, but we need to do some
-    // dynamic allocations. Using a function avoids having to pull in a
-    // dependency on lazy_static.
-    let indented = python_config_rs
-        .split('\n')
-        .map(|line| "    ".to_owned() + line)
-        .join("\n");
-
-    f.write_fmt(format_args!(
-        "/// Obtain the default Python configuration\n\
-         ///\n\
-         /// The crate is compiled with a default Python configuration embedded\n\
-         /// in the crate. This function will return an instance of that\n\
-         /// configuration.\n\
-         pub fn default_python_config<'a>() -> pyembed::PythonConfig<'a> {{\n{}\n}}\n",
-        indented
-    ))?;
+    f.write_all(config.render_default_python_config_rs().as_bytes())?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedded(filesystem_importer: bool, sys_paths: &[&str]) -> EmbeddedPythonConfig {
+        EmbeddedPythonConfig {
+            filesystem_importer,
+            sys_paths: sys_paths.iter().map(|p| p.to_string()).collect(),
+            ..EmbeddedPythonConfig::default()
+        }
+    }
+
+    fn codes(problems: &[ConfigProblem]) -> Vec<&'static str> {
+        problems.iter().map(|p| p.code).collect()
+    }
+
+    #[test]
+    fn test_render_in_memory_only() {
+        let importer = ImporterConfig::from_embedded_config(
+            &embedded(false, &[]),
+            Path::new("/build/packed-resources"),
+        );
+
+        assert_eq!(importer.finders, vec![ImporterFinder::InMemory]);
+        assert!(importer.validate(&BTreeMap::new()).is_empty());
+        assert_eq!(
+            importer.render_fields(),
+            vec![
+                "use_custom_importlib: true",
+                "filesystem_importer: false",
+                "sys_paths: [].to_vec()",
+                "resource_set: pyembed::ResourceSet::Main",
+                "external_payloads: None",
+                "packed_resources: include_bytes!(r#\"/build/packed-resources\"#)",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_mixed() {
+        let importer = ImporterConfig::from_embedded_config(
+            &embedded(true, &["$ORIGIN/stdlib.zip", "$ORIGIN/lib"]),
+            Path::new("/build/packed-resources"),
+        );
+
+        assert_eq!(
+            importer.finders,
+            vec![
+                ImporterFinder::InMemory,
+                ImporterFinder::Filesystem,
+                ImporterFinder::ZipStdlib
+            ]
+        );
+        assert!(importer.validate(&BTreeMap::new()).is_empty());
+        assert_eq!(
+            importer.render_fields(),
+            vec![
+                "use_custom_importlib: true",
+                "filesystem_importer: true",
+                "sys_paths: [\"$ORIGIN/stdlib.zip\".to_string(), \"$ORIGIN/lib\".to_string()].to_vec()",
+                "resource_set: pyembed::ResourceSet::Main",
+                "external_payloads: None",
+                "packed_resources: include_bytes!(r#\"/build/packed-resources\"#)",
+            ]
+        );
+
+        let config_rs =
+            derive_python_config_with_importer(&EmbeddedPythonConfig::default(), &importer);
+        assert!(config_rs.contains(
+            "    filesystem_importer: true,\n    \
+             sys_paths: [\"$ORIGIN/stdlib.zip\".to_string(), \"$ORIGIN/lib\".to_string()].to_vec(),\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_filesystem_only() {
+        let importer = ImporterConfig {
+            finders: vec![ImporterFinder::Filesystem],
+            packed_resources: vec![],
+            sys_paths: vec!["$ORIGIN/lib".to_string()],
+            recovery: None,
+            external_payloads: None,
+        };
+
+        assert!(importer.validate(&BTreeMap::new()).is_empty());
+        assert_eq!(
+            importer.render_fields(),
+            vec![
+                "use_custom_importlib: false",
+                "filesystem_importer: true",
+                "sys_paths: [\"$ORIGIN/lib\".to_string()].to_vec()",
+                "resource_set: pyembed::ResourceSet::Main",
+                "external_payloads: None",
+                "packed_resources: &[]",
+            ]
+        );
+
+        let placements = vec![("foo".to_string(), ImporterFinder::InMemory)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let problems = importer.validate(&placements);
+        assert_eq!(codes(&problems), vec!["importer-finder-disabled"]);
+        assert_eq!(
+            problems[0].message,
+            "resources are placed for the in-memory finder, which is disabled: foo"
+        );
+    }
+
+    #[test]
+    fn test_render_linker_section() {
+        let config = EmbeddedPythonConfig::default();
+        let importer = ImporterConfig::from_embedded_config_with_source(
+            &config,
+            PackedResourcesSource::LinkerSection {
+                symbol: "pyoxidizer_packed_resources".to_string(),
+                length: 42,
+            },
+        );
+        assert!(importer.validate(&BTreeMap::new()).is_empty());
+
+        let rendered = derive_python_config_settings(&config, &importer).render();
+        assert!(rendered.contains(
+            "    packed_resources: unsafe { extern \"C\" { \
+             #[link_name = \"pyoxidizer_packed_resources\"] \
+             static PACKED_RESOURCES: [u8; 42]; } &PACKED_RESOURCES[..] },\n"
+        ));
+    }
+
+    #[test]
+    fn test_render_recovery() {
+        let config = EmbeddedPythonConfig::default();
+        let mut importer =
+            ImporterConfig::from_embedded_config(&config, Path::new("/build/packed-resources"));
+        importer.recovery = Some(RecoverySelection {
+            marker_file: Some("$ORIGIN/RECOVERY".to_string()),
+        });
+        assert!(importer.validate(&BTreeMap::new()).is_empty());
+
+        let settings = derive_python_config_settings(&config, &importer);
+        assert_eq!(
+            settings.get("resource_set"),
+            Some("pyembed::select_resource_set(Some(r###\"$ORIGIN/RECOVERY\"###))")
+        );
+        assert_eq!(
+            settings
+                .runtime_env
+                .settings(RECOVERY_ENV)
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["resource_set"]
+        );
+
+        importer.recovery = Some(RecoverySelection::default());
+        assert_eq!(
+            derive_python_config_settings(&config, &importer).get("resource_set"),
+            Some("pyembed::select_resource_set(None)")
+        );
+
+        importer.finders = vec![ImporterFinder::Filesystem];
+        importer.sys_paths = vec!["$ORIGIN/lib".to_string()];
+        assert_eq!(
+            codes(&importer.validate(&BTreeMap::new())),
+            vec!["importer-recovery-without-in-memory"]
+        );
+    }
+
+    #[test]
+    fn test_render_external_payloads() {
+        let config = EmbeddedPythonConfig::default();
+        let mut importer =
+            ImporterConfig::from_embedded_config(&config, Path::new("/build/packed-resources"));
+        importer.external_payloads = Some(ExternalPayloadsSelection {
+            directory: Some("$ORIGIN/payloads".to_string()),
+            cache_dir: None,
+        });
+        assert!(importer.validate(&BTreeMap::new()).is_empty());
+
+        let settings = derive_python_config_settings(&config, &importer);
+        assert_eq!(
+            settings.get("external_payloads"),
+            Some(
+                "Some(pyembed::ExternalPayloadsConfig { \
+                 directory: Some(r###\"$ORIGIN/payloads\"###.to_string()), \
+                 cache_dir: None, fetcher: None })"
+            )
+        );
+        assert!(settings.runtime_env.settings("HOME").is_none());
+
+        importer.external_payloads = Some(ExternalPayloadsSelection {
+            directory: None,
+            cache_dir: Some("$CACHE_DIR/payloads".to_string()),
+        });
+        assert_eq!(
+            derive_python_config_settings(&config, &importer)
+                .runtime_env
+                .settings("HOME")
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["external_payloads"]
+        );
+
+        importer.finders = vec![ImporterFinder::Filesystem];
+        importer.sys_paths = vec!["$ORIGIN/lib".to_string()];
+        assert_eq!(
+            codes(&importer.validate(&BTreeMap::new())),
+            vec!["importer-external-payloads-without-in-memory"]
+        );
+    }
+
+    #[test]
+    fn test_python_config_settings() {
+        let config = EmbeddedPythonConfig::default();
+        let resources = PathBuf::from("/build/packed-resources");
+        let settings = derive_python_config_settings(
+            &config,
+            &ImporterConfig::from_embedded_config(&config, &resources),
+        );
+
+        assert_eq!(settings.get("filesystem_importer"), Some("false"));
+        assert_eq!(settings.get("packed_resources"), None);
+        assert_eq!(
+            settings.packed_resources,
+            Some(PackedResourcesSource::Embedded(resources.clone()))
+        );
+
+        let rendered = settings.render();
+        assert!(rendered.starts_with("pyembed::PythonConfig {\n    standard_io_encoding: "));
+        assert!(rendered.contains(
+            "    sys_paths: [].to_vec(),\n    \
+             packed_resources: include_bytes!(r#\"/build/packed-resources\"#),\n    \
+             resource_set: pyembed::ResourceSet::Main,\n    \
+             external_payloads: None,\n    \
+             bytes_warning: "
+        ));
+        assert!(rendered.ends_with(",\n}"));
+        assert_eq!(rendered, derive_python_config(&config, &resources));
+
+        let mut other_config = config.clone();
+        other_config.filesystem_importer = true;
+        other_config.sys_paths = vec!["$ORIGIN/lib".to_string()];
+        let other = derive_python_config_settings(
+            &other_config,
+            &ImporterConfig::from_embedded_config(
+                &other_config,
+                Path::new("/other/packed-resources"),
+            ),
+        );
+
+        assert!(settings.diff(&settings.clone()).is_empty());
+        assert_eq!(
+            settings
+                .diff(&other)
+                .iter()
+                .map(|change| change.to_string())
+                .collect::<Vec<_>>(),
+            vec![
+                "filesystem_importer: false -> true",
+                "sys_paths: [].to_vec() -> [\"$ORIGIN/lib\".to_string()].to_vec()",
+                "packed_resources: include_bytes!(r#\"/build/packed-resources\"#) -> \
+                 include_bytes!(r#\"/other/packed-resources\"#)",
+            ]
+        );
+
+        let mut removed = other.clone();
+        removed.settings.retain(|(name, _)| name != "run");
+        assert_eq!(
+            other.diff(&removed),
+            vec![PythonConfigChange::Removed {
+                name: "run".to_string(),
+                value: other.get("run").unwrap().to_string(),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_derive_runtime_env() {
+        let config = EmbeddedPythonConfig {
+            shared_library_extraction_dir: Some("$ORIGIN/lib".to_string()),
+            ..EmbeddedPythonConfig::default()
+        };
+        assert!(derive_runtime_env(&config).is_empty());
+
+        let config = EmbeddedPythonConfig {
+            ignore_environment: false,
+            terminfo_resolution: TerminfoResolution::Dynamic,
+            write_modules_directory_env: Some("PYTHONPATH".to_string()),
+            ..EmbeddedPythonConfig::default()
+        };
+        let settings = derive_python_config_settings(
+            &config,
+            &ImporterConfig::from_embedded_config(&config, Path::new("packed-resources")),
+        );
+        let env = &settings.runtime_env;
+
+        assert_eq!(
+            env.names().len(),
+            PYTHON_ENV_VARS.len() + CACHE_DIR_ENV_VARS.len() + 1
+        );
+        assert_eq!(
+            env.settings("PYTHONPATH")
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["ignore_python_env", "write_modules_directory_env"]
+        );
+        assert_eq!(
+            env.settings("TERMINFO_DIRS")
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["terminfo_resolution"]
+        );
+        assert_eq!(
+            env.settings("XDG_CACHE_HOME")
+                .unwrap()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec!["shared_library_extraction_dir"]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let path = PathBuf::from("packed-resources");
+
+        let importer = ImporterConfig {
+            finders: vec![ImporterFinder::Filesystem, ImporterFinder::InMemory],
+            packed_resources: vec![
+                PackedResourcesSource::Embedded(path.clone()),
+                PackedResourcesSource::Embedded(path.clone()),
+            ],
+            sys_paths: vec![],
+            recovery: None,
+            external_payloads: None,
+        };
+        let problems = importer.validate(&BTreeMap::new());
+        assert_eq!(
+            codes(&problems),
+            vec![
+                "importer-finder-order",
+                "importer-finder-without-source",
+                "importer-multiple-packed-resources"
+            ]
+        );
+        assert_eq!(problems[1].severity, ConfigProblemSeverity::Warning);
+
+        let importer = ImporterConfig {
+            finders: vec![
+                ImporterFinder::InMemory,
+                ImporterFinder::ZipStdlib,
+                ImporterFinder::InMemory,
+            ],
+            packed_resources: vec![],
+            sys_paths: vec![],
+            recovery: None,
+            external_payloads: None,
+        };
+        let problems = importer.validate(&BTreeMap::new());
+        assert_eq!(
+            codes(&problems),
+            vec![
+                "importer-finder-duplicate",
+                "importer-zip-without-filesystem",
+                "importer-finder-without-source",
+                "importer-finder-without-source"
+            ]
+        );
+        assert!(problems
+            .iter()
+            .all(|p| p.severity == ConfigProblemSeverity::Error));
+    }
+}
@@ -11,7 +11,7 @@ use {
     anyhow::Result,
     python_packaging::module_util::{packages_from_module_name, resolve_path_for_module},
     python_packaging::resource::{
-        PythonExtensionModule, PythonModuleSource, PythonPackageDistributionResource,
+        DataLocation, PythonExtensionModule, PythonModuleSource, PythonPackageDistributionResource,
         PythonPackageResource,
     },
 };
@@ -21,25 +21,41 @@ pub trait AddToFileManifest {
     fn add_to_file_manifest(&self, manifest: &mut FileManifest, prefix: &str) -> Result<()>;
 }
 
+/// Provenance of the empty `__init__.py` added for a package without source.
+fn package_placeholder(package: &str) -> String {
+    format!("placeholder for package {}", package)
+}
+
 impl AddToFileManifest for PythonModuleSource {
     fn add_to_file_manifest(&self, manifest: &mut FileManifest, prefix: &str) -> Result<()> {
         let content = FileContent {
-            data: self.source.resolve()?,
+            data: DataLocation::Memory(self.source.resolve()?),
             executable: false,
         };
-
-        manifest.add_file(&self.resolve_path(prefix), &content)?;
+        let path = self.resolve_path(prefix);
+        let provenance = format!("resource {}", self.name);
+
+        // The source of a package supersedes a placeholder added for one of
+        // its modules.
+        if self.is_package
+            && manifest.provenance(&path) == Some(package_placeholder(&self.name).as_str())
+        {
+            manifest.replace_file_from(&path, &content, &provenance)?;
+        } else {
+            manifest.add_file_from(&path, &content, &provenance)?;
+        }
 
         for package in packages_from_module_name(&self.name) {
             let package_path = resolve_path_for_module(prefix, &package, true, None);
 
             if !manifest.has_path(&package_path) {
-                manifest.add_file(
+                manifest.add_file_from(
                     &package_path,
                     &FileContent {
-                        data: vec![],
+                        data: DataLocation::Memory(vec![]),
                         executable: false,
                     },
+                    &package_placeholder(&package),
                 )?;
             }
         }
@@ -52,12 +68,13 @@ impl AddToFileManifest for PythonPackageResource {
     fn add_to_file_manifest(&self, manifest: &mut FileManifest, prefix: &str) -> Result<()> {
         let dest_path = self.resolve_path(prefix);
 
-        manifest.add_file(
+        manifest.add_file_from(
             &dest_path,
             &FileContent {
-                data: self.data.resolve()?,
+                data: DataLocation::Memory(self.data.resolve()?),
                 executable: false,
             },
+            &format!("resource {}", self.symbolic_name()),
         )
     }
 }
@@ -66,12 +83,13 @@ impl AddToFileManifest for PythonPackageDistributionResource {
     fn add_to_file_manifest(&self, manifest: &mut FileManifest, prefix: &str) -> Result<()> {
         let dest_path = self.resolve_path(prefix);
 
-        manifest.add_file(
+        manifest.add_file_from(
             &dest_path,
             &FileContent {
-                data: self.data.resolve()?,
+                data: DataLocation::Memory(self.data.resolve()?),
                 executable: false,
             },
+            &format!("resource {}:{}", self.package, self.name),
         )
     }
 }
@@ -79,12 +97,15 @@ impl AddToFileManifest for PythonPackageDistributionResource {
 impl AddToFileManifest for PythonExtensionModule {
     fn add_to_file_manifest(&self, manifest: &mut FileManifest, prefix: &str) -> Result<()> {
         if let Some(data) = &self.shared_library {
-            manifest.add_file(
+            manifest.add_file_from(
                 &self.resolve_path(prefix),
+                // Shared libraries can be large, so they are streamed when
+                // the manifest is written.
                 &FileContent {
-                    data: data.resolve()?,
+                    data: data.clone(),
                     executable: true,
                 },
+                &format!("resource {}", self.name),
             )
         } else {
             Ok(())
@@ -94,10 +115,7 @@ impl AddToFileManifest for PythonExtensionModule {
 
 #[cfg(test)]
 mod tests {
-    use {
-        super::*, itertools::Itertools, python_packaging::resource::DataLocation,
-        std::path::PathBuf,
-    };
+    use {super::*, itertools::Itertools, std::path::PathBuf};
 
     const DEFAULT_CACHE_TAG: &str = "cpython-37";
 
@@ -202,4 +220,36 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_source_module_add_to_manifest_replaces_placeholder() -> Result<()> {
+        let mut m = FileManifest::default();
+
+        let module = |name: &str, is_package: bool, source: &[u8]| PythonModuleSource {
+            name: name.to_string(),
+            source: DataLocation::Memory(source.to_vec()),
+            is_package,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+
+        module("root.child", false, b"").add_to_file_manifest(&mut m, ".")?;
+        let path = PathBuf::from("./root/__init__.py");
+        assert_eq!(m.provenance(&path), Some("placeholder for package root"));
+
+        module("root", true, b"import sys").add_to_file_manifest(&mut m, ".")?;
+        assert_eq!(m.provenance(&path), Some("resource root"));
+
+        // Real files aren't replaced.
+        let err = module("root", true, b"import os")
+            .add_to_file_manifest(&mut m, ".")
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "./root/__init__.py is added by both resource root and resource root"
+        );
+
+        Ok(())
+    }
 }
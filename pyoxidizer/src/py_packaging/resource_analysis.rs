@@ -0,0 +1,482 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Analyze how compressible Python resources are.
+
+Analysis reads at most a bounded sample of each piece of resource data and
+performs a fast compression pass over the sample. The resulting ratio is
+used to estimate the compressed size of the full data and to classify it.
+
+The pass uses zstd rather than zlib. Resources chosen for compression are
+stored as zstd frames, so a zstd ratio predicts what compressing them
+saves. zstd is also what the build and `pyembed` already link.
+*/
+
+use {
+    anyhow::{anyhow, Context, Result},
+    python_packaging::resource::DataLocation,
+    python_packaging::resource_collection::{PrePackagedResource, PythonModuleBytecodeProvider},
+    std::collections::BTreeMap,
+    std::io::{Read, Write},
+};
+
+/// Default maximum number of bytes to sample from each piece of data.
+pub const DEFAULT_SAMPLE_SIZE: usize = 65536;
+
+/// Compression level used to estimate compressibility.
+///
+/// This favors speed over ratio.
+const ANALYSIS_COMPRESSION_LEVEL: i32 = 1;
+
+/// Describes how much information is in data.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum EntropyClass {
+    /// Data compresses to less than half its size.
+    Low,
+    /// Data compresses somewhat.
+    Medium,
+    /// Data barely compresses, if at all.
+    High,
+}
+
+impl EntropyClass {
+    fn from_ratio(ratio: f64) -> Self {
+        if ratio < 0.5 {
+            EntropyClass::Low
+        } else if ratio < 0.9 {
+            EntropyClass::Medium
+        } else {
+            EntropyClass::High
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EntropyClass::Low => "low",
+            EntropyClass::Medium => "medium",
+            EntropyClass::High => "high",
+        }
+    }
+}
+
+/// Measurements of one or more pieces of data.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DataAnalysis {
+    /// Total size of the data in bytes.
+    pub size: u64,
+    /// Number of bytes that were sampled.
+    pub sample_size: u64,
+    /// Size of the sampled bytes after compression.
+    pub compressed_sample_size: u64,
+}
+
+impl DataAnalysis {
+    /// Ratio of compressed size to original size of the sample.
+    pub fn ratio(&self) -> f64 {
+        if self.sample_size == 0 {
+            1.0
+        } else {
+            self.compressed_sample_size as f64 / self.sample_size as f64
+        }
+    }
+
+    /// Estimated size of all data after compression.
+    pub fn estimated_compressed_size(&self) -> u64 {
+        (self.size as f64 * self.ratio().min(1.0)).ceil() as u64
+    }
+
+    /// Obtain the entropy class of the data.
+    pub fn entropy_class(&self) -> EntropyClass {
+        EntropyClass::from_ratio(self.ratio())
+    }
+
+    /// Whether compressing the data is worthwhile.
+    pub fn should_compress(&self) -> bool {
+        self.entropy_class() != EntropyClass::High
+    }
+
+    fn merge(&mut self, other: &DataAnalysis) {
+        self.size += other.size;
+        self.sample_size += other.sample_size;
+        self.compressed_sample_size += other.compressed_sample_size;
+    }
+}
+
+/// Analysis of a single resource.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ResourceAnalysis {
+    /// Name of the resource.
+    pub name: String,
+    /// Name of the top-level package the resource belongs to.
+    pub package: String,
+    /// Measurements of all data in the resource.
+    pub data: DataAnalysis,
+}
+
+/// Analysis of all resources in a top-level package.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageAnalysis {
+    /// Number of resources in the package.
+    pub resource_count: usize,
+    /// Measurements of all data in the package.
+    pub data: DataAnalysis,
+}
+
+/// Analysis of a collection of resources.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourcesAnalysis {
+    /// Per-resource analysis, keyed by resource name.
+    pub resources: BTreeMap<String, ResourceAnalysis>,
+    /// Per-package analysis, keyed by top-level package name.
+    pub packages: BTreeMap<String, PackageAnalysis>,
+}
+
+impl ResourcesAnalysis {
+    /// Measurements of all analyzed data.
+    pub fn total(&self) -> DataAnalysis {
+        let mut total = DataAnalysis::default();
+
+        for package in self.packages.values() {
+            total.merge(&package.data);
+        }
+
+        total
+    }
+
+    /// Names of resources whose data is worth compressing.
+    pub fn compression_candidates(&self) -> Vec<&str> {
+        self.resources
+            .values()
+            .filter(|r| r.data.should_compress())
+            .map(|r| r.name.as_str())
+            .collect()
+    }
+
+    /// Write a human readable report of per-package measurements.
+    pub fn write_report<W: Write>(&self, writer: &mut W) -> Result<()> {
+        let mut packages = self.packages.iter().collect::<Vec<_>>();
+        packages.sort_by(|a, b| b.1.data.size.cmp(&a.1.data.size).then(a.0.cmp(b.0)));
+
+        for (name, package) in packages {
+            writeln!(
+                writer,
+                "{}: {} resources; {} bytes; ~{} bytes compressed; {} entropy",
+                name,
+                package.resource_count,
+                package.data.size,
+                package.data.estimated_compressed_size(),
+                package.data.entropy_class().as_str()
+            )?;
+        }
+
+        let total = self.total();
+        writeln!(
+            writer,
+            "total: {} resources; {} bytes; ~{} bytes compressed; {} entropy",
+            self.resources.len(),
+            total.size,
+            total.estimated_compressed_size(),
+            total.entropy_class().as_str()
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Read the size of data and a bounded sample of its content.
+pub fn sample_data(location: &DataLocation, max_sample_size: usize) -> Result<(u64, Vec<u8>)> {
+    match location {
+        DataLocation::Path { path, .. } => {
+            let fh =
+                std::fs::File::open(path).with_context(|| format!("opening {}", path.display()))?;
+            let size = fh.metadata()?.len();
+
+            let mut sample = Vec::new();
+            fh.take(max_sample_size as u64).read_to_end(&mut sample)?;
+
+            Ok((size, sample))
+        }
+        DataLocation::Memory(data) => Ok((
+            data.len() as u64,
+            data[0..data.len().min(max_sample_size)].to_vec(),
+        )),
+        DataLocation::Slice {
+            data,
+            offset,
+            length,
+        } => {
+            let sample = offset
+                .checked_add((*length).min(max_sample_size))
+                .and_then(|end| data.get(*offset..end))
+                .ok_or_else(|| anyhow!("data slice out of bounds"))?;
+
+            Ok((*length as u64, sample.to_vec()))
+        }
+        DataLocation::CompressedMemory { size, .. }
+        | DataLocation::FileRange { length: size, .. } => {
+            let mut sample = Vec::new();
+            location
+                .reader()?
+                .take(max_sample_size as u64)
+                .read_to_end(&mut sample)?;
+
+            Ok((*size, sample))
+        }
+        DataLocation::ArchiveMember { .. } => {
+            let data = location.resolve()?;
+
+            Ok((
+                data.len() as u64,
+                data[0..data.len().min(max_sample_size)].to_vec(),
+            ))
+        }
+    }
+}
+
+/// Analyze a single piece of data.
+pub fn analyze_data(location: &DataLocation, max_sample_size: usize) -> Result<DataAnalysis> {
+    let (size, sample) = sample_data(location, max_sample_size)?;
+
+    let compressed = zstd::stream::encode_all(&sample[..], ANALYSIS_COMPRESSION_LEVEL)?;
+
+    Ok(DataAnalysis {
+        size,
+        sample_size: sample.len() as u64,
+        compressed_sample_size: compressed.len() as u64,
+    })
+}
+
+/// Obtain all pieces of data held by a resource.
+fn resource_data(resource: &PrePackagedResource) -> Vec<&DataLocation> {
+    fn provider_location(provider: &PythonModuleBytecodeProvider) -> &DataLocation {
+        match provider {
+            PythonModuleBytecodeProvider::Provided(location) => location,
+            PythonModuleBytecodeProvider::FromSource(location) => location,
+        }
+    }
+
+    let mut res = Vec::new();
+
+    res.extend(resource.in_memory_source.iter());
+    for provider in [
+        &resource.in_memory_bytecode,
+        &resource.in_memory_bytecode_opt1,
+        &resource.in_memory_bytecode_opt2,
+    ]
+    .iter()
+    {
+        res.extend(provider.iter().map(provider_location));
+    }
+    res.extend(resource.in_memory_extension_module_shared_library.iter());
+    for resources in [
+        &resource.in_memory_resources,
+        &resource.in_memory_distribution_resources,
+    ]
+    .iter()
+    {
+        if let Some(resources) = resources {
+            res.extend(resources.values());
+        }
+    }
+    res.extend(resource.in_memory_shared_library.iter());
+
+    res.extend(resource.relative_path_module_source.iter().map(|(_, l)| l));
+    for bytecode in [
+        &resource.relative_path_bytecode,
+        &resource.relative_path_bytecode_opt1,
+        &resource.relative_path_bytecode_opt2,
+    ]
+    .iter()
+    {
+        res.extend(bytecode.iter().map(|(_, _, p)| provider_location(p)));
+    }
+    res.extend(
+        resource
+            .relative_path_extension_module_shared_library
+            .iter()
+            .map(|(_, l)| l),
+    );
+    for resources in [
+        &resource.relative_path_package_resources,
+        &resource.relative_path_distribution_resources,
+    ]
+    .iter()
+    {
+        if let Some(resources) = resources {
+            res.extend(resources.values().map(|(_, l)| l));
+        }
+    }
+    res.extend(resource.relative_path_shared_library.iter().map(|(_, l)| l));
+
+    res
+}
+
+/// Analyze a collection of resources.
+///
+/// At most `max_sample_size` bytes are read from each piece of data.
+pub fn analyze_resources<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+    max_sample_size: usize,
+) -> Result<ResourcesAnalysis> {
+    let mut res = ResourcesAnalysis::default();
+
+    for (name, resource) in resources {
+        let mut data = DataAnalysis::default();
+
+        for location in resource_data(resource) {
+            data.merge(
+                &analyze_data(location, max_sample_size)
+                    .with_context(|| format!("analyzing resource {}", name))?,
+            );
+        }
+
+        let package = name.split('.').next().unwrap_or(name).to_string();
+
+        let entry = res.packages.entry(package.clone()).or_default();
+        entry.resource_count += 1;
+        entry.data.merge(&data);
+
+        res.resources.insert(
+            name.clone(),
+            ResourceAnalysis {
+                name: name.clone(),
+                package,
+                data,
+            },
+        );
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packed_resources::data::ResourceFlavor, std::sync::Arc};
+
+    /// Produce bytes that don't compress.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x1234_5678;
+
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_entropy_classes() -> Result<()> {
+        let low = analyze_data(&DataLocation::Memory(vec![b'a'; 8192]), DEFAULT_SAMPLE_SIZE)?;
+        assert_eq!(low.size, 8192);
+        assert_eq!(low.entropy_class(), EntropyClass::Low);
+        assert!(low.should_compress());
+        assert!(low.estimated_compressed_size() < 8192);
+
+        let high = analyze_data(&DataLocation::Memory(noise(8192)), DEFAULT_SAMPLE_SIZE)?;
+        assert_eq!(high.entropy_class(), EntropyClass::High);
+        assert!(!high.should_compress());
+        assert!(high.estimated_compressed_size() <= 8192);
+
+        let empty = analyze_data(&DataLocation::Memory(vec![]), DEFAULT_SAMPLE_SIZE)?;
+        assert_eq!(empty.entropy_class(), EntropyClass::High);
+        assert_eq!(empty.estimated_compressed_size(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bounded_sample() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = temp_dir.path().join("large");
+        std::fs::write(&path, vec![b'a'; 100_000])?;
+
+        let analysis = analyze_data(&DataLocation::from_path(path), 1000)?;
+        assert_eq!(analysis.size, 100_000);
+        assert_eq!(analysis.sample_size, 1000);
+
+        let data = Arc::new(noise(5000));
+        let analysis = analyze_data(
+            &DataLocation::Slice {
+                data,
+                offset: 100,
+                length: 4000,
+            },
+            1000,
+        )?;
+        assert_eq!(analysis.size, 4000);
+        assert_eq!(analysis.sample_size, 1000);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_analyze_resources() -> Result<()> {
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "foo".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo".to_string(),
+                is_package: true,
+                in_memory_source: Some(DataLocation::Memory(vec![b'a'; 4096])),
+                ..PrePackagedResource::default()
+            },
+        );
+        resources.insert(
+            "foo.bar".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo.bar".to_string(),
+                in_memory_source: Some(DataLocation::Memory(vec![b'b'; 4096])),
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
+                    DataLocation::Memory(vec![b'b'; 4096]),
+                )),
+                ..PrePackagedResource::default()
+            },
+        );
+        resources.insert(
+            "baz".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: "baz".to_string(),
+                in_memory_extension_module_shared_library: Some(DataLocation::Memory(noise(4096))),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        let analysis = analyze_resources(resources.iter(), DEFAULT_SAMPLE_SIZE)?;
+
+        assert_eq!(analysis.resources.len(), 3);
+        assert_eq!(analysis.resources["foo.bar"].package, "foo");
+        assert_eq!(analysis.resources["foo.bar"].data.size, 8192);
+
+        assert_eq!(analysis.packages.len(), 2);
+        assert_eq!(analysis.packages["foo"].resource_count, 2);
+        assert_eq!(analysis.packages["foo"].data.size, 12288);
+        assert_eq!(
+            analysis.packages["foo"].data.entropy_class(),
+            EntropyClass::Low
+        );
+        assert_eq!(
+            analysis.packages["baz"].data.entropy_class(),
+            EntropyClass::High
+        );
+        assert_eq!(analysis.total().size, 16384);
+
+        assert_eq!(analysis.compression_candidates(), vec!["foo", "foo.bar"]);
+
+        let mut report = Vec::new();
+        analysis.write_report(&mut report)?;
+        let report = String::from_utf8(report)?;
+        assert!(report.starts_with("foo: 2 resources; 12288 bytes;"));
+        assert!(report.contains("baz: 1 resources; 4096 bytes;"));
+        assert!(report.contains("total: 3 resources; 16384 bytes;"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,776 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Explain everything known about a collected resource.
+
+Questions about a module usually are: where did it come from, where will
+it live and what does it depend on. Answering them means consulting the
+resource collector, packaging decisions, sizes, distribution metadata and
+import analysis. A `ResourceExplanation` aggregates all of it for one
+resource and renders as text or JSON.
+
+Resources are named like everywhere else. Package and distribution
+resources are named `<package>:<path>`, like `PythonPackageResource`'s
+symbolic name.
+*/
+
+use {
+    super::site_packages_shim::module_sources,
+    super::syntax_compatibility::distribution_owners,
+    anyhow::Result,
+    python_packaging::package_metadata::PythonPackageMetadata,
+    python_packaging::python_source::find_imports,
+    python_packaging::resource::{BytecodeOptimizationLevel, DataLocation},
+    python_packaging::resource_collection::{
+        PrePackagedResource, PythonModuleBytecodeProvider, PythonResourceCollector,
+    },
+    python_packed_resources::data::ResourceFlavor,
+    python_packed_resources::extract::{close_matches, PayloadKind},
+    serde::Serialize,
+    std::collections::{BTreeMap, BTreeSet},
+};
+
+/// What an explained name refers to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ExplainedKind {
+    Module,
+    Package,
+    ExtensionModule,
+    SharedLibrary,
+    Distribution,
+    PackageResource,
+    DistributionResource,
+}
+
+impl std::fmt::Display for ExplainedKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Module => "module",
+            Self::Package => "package",
+            Self::ExtensionModule => "extension module",
+            Self::SharedLibrary => "shared library",
+            Self::Distribution => "distribution metadata",
+            Self::PackageResource => "package resource",
+            Self::DistributionResource => "distribution resource",
+        })
+    }
+}
+
+/// The distribution a resource belongs to.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct ExplainedDistribution {
+    /// Name of the resource holding the distribution's metadata.
+    pub resource: String,
+
+    /// Name of the distribution, per its metadata.
+    pub name: Option<String>,
+
+    /// Version of the distribution, per its metadata.
+    pub version: Option<String>,
+
+    /// The `License` header of the distribution's metadata.
+    pub license: Option<String>,
+}
+
+/// Everything known about a collected resource.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ResourceExplanation {
+    /// The explained name.
+    pub name: String,
+
+    /// What the name refers to.
+    pub kind: ExplainedKind,
+
+    /// Where the resource came from.
+    pub origins: Vec<String>,
+
+    /// How the location of the resource was decided, in order.
+    pub placement: Vec<String>,
+
+    /// Where each payload will live, by payload.
+    pub locations: BTreeMap<String, String>,
+
+    /// Optimization levels of the resource's bytecode.
+    pub bytecode_levels: Vec<i32>,
+
+    /// Sizes in bytes of payloads, by payload.
+    ///
+    /// Bytecode still to be compiled from source isn't counted.
+    pub sizes: BTreeMap<String, u64>,
+
+    /// Whether the binary can't run without the resource.
+    pub required: bool,
+
+    /// Whether the resource is part of the Python distribution.
+    pub stdlib: bool,
+
+    /// Entry points keeping the resource when filtering, as `group:name`.
+    pub protected_by: Vec<String>,
+
+    /// Features gating inclusion. Untagged resources are always included.
+    pub feature_tags: Vec<String>,
+
+    /// Payloads classified as tests.
+    pub test_payloads: Vec<String>,
+
+    /// Package patches applied to the resource.
+    pub applied_patches: Vec<String>,
+
+    /// The distribution the resource belongs to, if known.
+    pub distribution: Option<ExplainedDistribution>,
+
+    /// SPDX identifiers of licenses of the resource, if known.
+    pub licenses: Vec<String>,
+
+    /// Modules imported by the module's source.
+    ///
+    /// `None` if there is no source to analyze.
+    pub imports: Option<Vec<String>>,
+
+    /// Collected modules whose source imports the module.
+    ///
+    /// `None` if imports weren't analyzed.
+    pub importers: Option<Vec<String>>,
+}
+
+impl ResourceExplanation {
+    /// Construct an instance knowing nothing but the name and kind.
+    pub fn new(name: &str, kind: ExplainedKind) -> Self {
+        Self {
+            name: name.to_string(),
+            kind,
+            origins: vec![],
+            placement: vec![],
+            locations: BTreeMap::new(),
+            bytecode_levels: vec![],
+            sizes: BTreeMap::new(),
+            required: false,
+            stdlib: false,
+            protected_by: vec![],
+            feature_tags: vec![],
+            test_payloads: vec![],
+            applied_patches: vec![],
+            distribution: None,
+            licenses: vec![],
+            imports: None,
+            importers: None,
+        }
+    }
+
+    /// Total size in bytes of the resource's payloads.
+    pub fn total_bytes(&self) -> u64 {
+        self.sizes.values().sum()
+    }
+
+    /// Serialize the instance to JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+fn join_or_none(values: &[String]) -> String {
+    if values.is_empty() {
+        "none".to_string()
+    } else {
+        values.join(", ")
+    }
+}
+
+fn format_flag(value: bool) -> &'static str {
+    if value {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+impl std::fmt::Display for ResourceExplanation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} ({})", self.name, self.kind)?;
+        writeln!(f, "  origins: {}", join_or_none(&self.origins))?;
+
+        writeln!(f, "  placement:")?;
+        for step in &self.placement {
+            writeln!(f, "    {}", step)?;
+        }
+        writeln!(f, "  locations:")?;
+        for (payload, location) in &self.locations {
+            writeln!(f, "    {}: {}", payload, location)?;
+        }
+
+        if !self.bytecode_levels.is_empty() {
+            writeln!(
+                f,
+                "  bytecode levels: {}",
+                self.bytecode_levels
+                    .iter()
+                    .map(|level| level.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )?;
+        }
+        writeln!(
+            f,
+            "  size: {} bytes{}",
+            self.total_bytes(),
+            if self.sizes.is_empty() {
+                "".to_string()
+            } else {
+                format!(
+                    " ({})",
+                    self.sizes
+                        .iter()
+                        .map(|(payload, size)| format!("{} {}", payload, size))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            }
+        )?;
+
+        writeln!(
+            f,
+            "  required: {}; stdlib: {}",
+            format_flag(self.required),
+            format_flag(self.stdlib)
+        )?;
+        writeln!(f, "  protected by: {}", join_or_none(&self.protected_by))?;
+        writeln!(
+            f,
+            "  feature tags: {}",
+            if self.feature_tags.is_empty() {
+                "none (always included)".to_string()
+            } else {
+                self.feature_tags.join(", ")
+            }
+        )?;
+        writeln!(f, "  test payloads: {}", join_or_none(&self.test_payloads))?;
+        writeln!(
+            f,
+            "  applied patches: {}",
+            join_or_none(&self.applied_patches)
+        )?;
+
+        match &self.distribution {
+            Some(distribution) => writeln!(
+                f,
+                "  distribution: {} {} (license: {})",
+                distribution
+                    .name
+                    .as_deref()
+                    .unwrap_or(&distribution.resource),
+                distribution.version.as_deref().unwrap_or("unknown version"),
+                distribution.license.as_deref().unwrap_or("unknown")
+            )?,
+            None => writeln!(f, "  distribution: unknown")?,
+        }
+        writeln!(f, "  licenses: {}", join_or_none(&self.licenses))?;
+
+        match &self.imports {
+            Some(imports) => writeln!(f, "  imports: {}", join_or_none(imports))?,
+            None => writeln!(f, "  imports: not analyzed")?,
+        }
+        match &self.importers {
+            Some(importers) => writeln!(f, "  imported by: {}", join_or_none(importers))?,
+            None => writeln!(f, "  imported by: not analyzed")?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Obtain the kind of a collected resource by its payloads.
+fn resource_kind(resource: &PrePackagedResource) -> ExplainedKind {
+    if resource.flavor == ResourceFlavor::BuiltinExtensionModule
+        || resource.in_memory_extension_module_shared_library.is_some()
+        || resource
+            .relative_path_extension_module_shared_library
+            .is_some()
+    {
+        ExplainedKind::ExtensionModule
+    } else if resource.in_memory_shared_library.is_some()
+        || resource.relative_path_shared_library.is_some()
+    {
+        ExplainedKind::SharedLibrary
+    } else if resource.is_package {
+        ExplainedKind::Package
+    } else if resource.in_memory_source.is_none()
+        && resource.relative_path_module_source.is_none()
+        && resource.in_memory_bytecode.is_none()
+        && resource.relative_path_bytecode.is_none()
+        && (resource.in_memory_distribution_resources.is_some()
+            || resource.relative_path_distribution_resources.is_some())
+    {
+        ExplainedKind::Distribution
+    } else {
+        ExplainedKind::Module
+    }
+}
+
+/// Obtain where each payload of a collected resource will live and its size.
+///
+/// Both are keyed by payload description. Bytecode still to be compiled
+/// from source has no size.
+fn describe_payloads(
+    resource: &PrePackagedResource,
+) -> (BTreeMap<String, String>, BTreeMap<String, u64>) {
+    let mut locations = BTreeMap::new();
+    let mut sizes = BTreeMap::new();
+
+    let bytecode = |provider: &Option<PythonModuleBytecodeProvider>| match provider {
+        Some(PythonModuleBytecodeProvider::Provided(location)) => (true, Some(location.clone())),
+        Some(PythonModuleBytecodeProvider::FromSource(_)) => (true, None),
+        None => (false, None),
+    };
+
+    let mut in_memory = vec![
+        (
+            PayloadKind::Source,
+            resource.in_memory_source.is_some(),
+            resource.in_memory_source.clone(),
+        ),
+        (
+            PayloadKind::ExtensionModule,
+            resource.in_memory_extension_module_shared_library.is_some(),
+            resource.in_memory_extension_module_shared_library.clone(),
+        ),
+        (
+            PayloadKind::SharedLibrary,
+            resource.in_memory_shared_library.is_some(),
+            resource.in_memory_shared_library.clone(),
+        ),
+    ];
+    for (kind, provider) in &[
+        (PayloadKind::Bytecode, &resource.in_memory_bytecode),
+        (PayloadKind::BytecodeOpt1, &resource.in_memory_bytecode_opt1),
+        (PayloadKind::BytecodeOpt2, &resource.in_memory_bytecode_opt2),
+    ] {
+        let (present, location) = bytecode(provider);
+        in_memory.push((kind.clone(), present, location));
+    }
+    for (path, location) in resource.in_memory_resources.iter().flat_map(|x| x.iter()) {
+        in_memory.push((
+            PayloadKind::PackageResource(path.clone()),
+            true,
+            Some(location.clone()),
+        ));
+    }
+    for (path, location) in resource
+        .in_memory_distribution_resources
+        .iter()
+        .flat_map(|x| x.iter())
+    {
+        in_memory.push((
+            PayloadKind::DistributionResource(path.clone()),
+            true,
+            Some(location.clone()),
+        ));
+    }
+
+    for (kind, present, location) in in_memory {
+        if !present {
+            continue;
+        }
+        if let Some(size) = location.and_then(|location| location.size().ok()) {
+            sizes.insert(kind.to_string(), size);
+        }
+        locations.insert(kind.to_string(), "in memory".to_string());
+    }
+
+    for install in resource.relative_path_installs() {
+        if let Some(size) = install
+            .location
+            .as_ref()
+            .and_then(|location| location.size().ok())
+        {
+            sizes.insert(install.kind.to_string(), size);
+        }
+        locations.insert(install.kind.to_string(), install.path.display().to_string());
+    }
+
+    (locations, sizes)
+}
+
+/// Obtain the distribution owning a resource.
+///
+/// Distributions are identified by the RECORD files of collected
+/// distribution resources. Metadata that can't be read is ignored.
+fn owning_distribution(
+    collector: &PythonResourceCollector,
+    name: &str,
+) -> Option<ExplainedDistribution> {
+    let owners = distribution_owners(collector.iter_resources()).ok()?;
+    let top_level = name.split('.').next().unwrap_or(name);
+
+    let resource_name = if owners.values().any(|owner| owner == name) {
+        name.to_string()
+    } else {
+        owners.get(top_level)?.clone()
+    };
+
+    let resource = collector
+        .iter_resources()
+        .find(|(n, _)| **n == resource_name)
+        .map(|(_, resource)| resource)?;
+
+    let mut res = ExplainedDistribution {
+        resource: resource_name,
+        ..ExplainedDistribution::default()
+    };
+
+    for file in &["METADATA", "PKG-INFO"] {
+        let location = resource
+            .in_memory_distribution_resources
+            .as_ref()
+            .and_then(|resources| resources.get(*file))
+            .or_else(|| {
+                resource
+                    .relative_path_distribution_resources
+                    .as_ref()
+                    .and_then(|resources| resources.get(*file))
+                    .map(|(_, location)| location)
+            });
+
+        if let Some(metadata) = location
+            .and_then(|location| location.resolve().ok())
+            .and_then(|data| PythonPackageMetadata::from_metadata(&data).ok())
+        {
+            res.name = metadata.name().map(|s| s.to_string());
+            res.version = metadata.version().map(|s| s.to_string());
+            res.license = metadata.license().map(|s| s.to_string());
+            break;
+        }
+    }
+
+    Some(res)
+}
+
+/// Obtain the modules a module's source imports.
+fn module_imports(resource: &PrePackagedResource) -> Option<BTreeSet<String>> {
+    let source = module_sources(resource).first()?.resolve().ok()?;
+
+    Some(find_imports(&source, &resource.name, resource.is_package))
+}
+
+/// Explain a resource of a collector, by name.
+///
+/// `name` is the name of a collected resource or `<package>:<path>` for a
+/// package or distribution resource. Returns `None` if nothing by that
+/// name was collected.
+///
+/// Only what the collector knows is filled in. Packaging decisions are up
+/// to the caller.
+pub fn explain_collected_resource(
+    collector: &PythonResourceCollector,
+    name: &str,
+) -> Option<ResourceExplanation> {
+    let find = |name: &str| {
+        collector
+            .iter_resources()
+            .find(|(n, _)| *n == name)
+            .map(|(_, resource)| resource)
+    };
+
+    if let Some(resource) = find(name) {
+        let mut res = ResourceExplanation::new(name, resource_kind(resource));
+
+        res.origins = collector.resource_origins(name).unwrap_or_default();
+        let (locations, sizes) = describe_payloads(resource);
+        res.locations = locations;
+        res.sizes = sizes;
+        res.bytecode_levels = BytecodeOptimizationLevel::all()
+            .filter(|level| {
+                let (in_memory, relative_path) = match level {
+                    BytecodeOptimizationLevel::Zero => (
+                        resource.in_memory_bytecode.is_some(),
+                        resource.relative_path_bytecode.is_some(),
+                    ),
+                    BytecodeOptimizationLevel::One => (
+                        resource.in_memory_bytecode_opt1.is_some(),
+                        resource.relative_path_bytecode_opt1.is_some(),
+                    ),
+                    BytecodeOptimizationLevel::Two => (
+                        resource.in_memory_bytecode_opt2.is_some(),
+                        resource.relative_path_bytecode_opt2.is_some(),
+                    ),
+                };
+
+                in_memory || relative_path
+            })
+            .map(i32::from)
+            .collect();
+        res.feature_tags = resource.feature_tags.iter().cloned().collect();
+        res.test_payloads = resource.test_payloads.iter().cloned().collect();
+        res.applied_patches = resource.applied_patches.iter().cloned().collect();
+        res.distribution = owning_distribution(collector, name);
+        res.licenses = res
+            .distribution
+            .as_ref()
+            .and_then(|distribution| distribution.license.clone())
+            .into_iter()
+            .collect();
+
+        if let Some(imports) = module_imports(resource) {
+            res.imports = Some(imports.into_iter().collect());
+            res.importers = Some(
+                collector
+                    .iter_resources()
+                    .filter(|(other, resource)| {
+                        *other != name
+                            && module_imports(resource)
+                                .map(|imports| imports.contains(name))
+                                .unwrap_or(false)
+                    })
+                    .map(|(other, _)| other.clone())
+                    .collect(),
+            );
+        }
+
+        return Some(res);
+    }
+
+    // `<package>:<path>` names a package or distribution resource.
+    let mut parts = name.splitn(2, ':');
+    let (package, path) = (parts.next()?, parts.next()?);
+    let resource = find(package)?;
+
+    let (kind, location, relative_path) = if let Some(location) = resource
+        .in_memory_resources
+        .as_ref()
+        .and_then(|resources| resources.get(path))
+    {
+        (ExplainedKind::PackageResource, location, None)
+    } else if let Some((install_path, location)) = resource
+        .relative_path_package_resources
+        .as_ref()
+        .and_then(|resources| resources.get(path))
+    {
+        (ExplainedKind::PackageResource, location, Some(install_path))
+    } else if let Some(location) = resource
+        .in_memory_distribution_resources
+        .as_ref()
+        .and_then(|resources| resources.get(path))
+    {
+        (ExplainedKind::DistributionResource, location, None)
+    } else if let Some((install_path, location)) = resource
+        .relative_path_distribution_resources
+        .as_ref()
+        .and_then(|resources| resources.get(path))
+    {
+        (
+            ExplainedKind::DistributionResource,
+            location,
+            Some(install_path),
+        )
+    } else {
+        return None;
+    };
+
+    let payload = match kind {
+        ExplainedKind::PackageResource => PayloadKind::PackageResource(path.to_string()),
+        _ => PayloadKind::DistributionResource(path.to_string()),
+    }
+    .to_string();
+
+    let mut res = ResourceExplanation::new(name, kind);
+    res.origins = vec![describe_location(location)];
+    res.locations.insert(
+        payload.clone(),
+        relative_path
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|| "in memory".to_string()),
+    );
+    if let Ok(size) = location.size() {
+        res.sizes.insert(payload, size);
+    }
+    res.feature_tags = resource.feature_tags.iter().cloned().collect();
+    res.distribution = owning_distribution(collector, package);
+    res.licenses = res
+        .distribution
+        .as_ref()
+        .and_then(|distribution| distribution.license.clone())
+        .into_iter()
+        .collect();
+
+    Some(res)
+}
+
+/// Describe where data comes from.
+fn describe_location(location: &DataLocation) -> String {
+    match location {
+        DataLocation::Path { path, .. } => path.display().to_string(),
+        _ => "memory".to_string(),
+    }
+}
+
+/// Names a collector can explain.
+///
+/// These are names of collected resources and `<package>:<path>` names of
+/// their package and distribution resources.
+pub fn explainable_names(collector: &PythonResourceCollector) -> Vec<String> {
+    let mut res = vec![];
+
+    for (name, resource) in collector.iter_resources() {
+        res.push(name.clone());
+
+        for path in resource
+            .in_memory_resources
+            .iter()
+            .flat_map(|x| x.keys())
+            .chain(
+                resource
+                    .relative_path_package_resources
+                    .iter()
+                    .flat_map(|x| x.keys()),
+            )
+            .chain(
+                resource
+                    .in_memory_distribution_resources
+                    .iter()
+                    .flat_map(|x| x.keys()),
+            )
+            .chain(
+                resource
+                    .relative_path_distribution_resources
+                    .iter()
+                    .flat_map(|x| x.keys()),
+            )
+        {
+            res.push(format!("{}:{}", name, path));
+        }
+    }
+
+    res
+}
+
+/// Find names similar to a name that can't be explained.
+pub fn suggest_names<'a>(name: &str, names: impl Iterator<Item = &'a String>) -> Vec<String> {
+    close_matches(name, names.map(|name| name.as_str()))
+        .into_iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*, python_packaging::policy::PythonResourcesPolicy,
+        python_packaging::resource::PythonModuleSource,
+        python_packaging::resource_collection::ConcreteResourceLocation,
+    };
+
+    fn collector() -> Result<PythonResourceCollector> {
+        let mut collector =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, "cpython-39");
+
+        for (name, is_package, source) in &[
+            ("acme", true, &b"from . import cli\n"[..]),
+            ("acme.cli", false, &b"import os\nimport acme.util\n"[..]),
+            ("acme.util", false, &b"x = 1\n"[..]),
+        ] {
+            collector.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(source.to_vec()),
+                    is_package: *is_package,
+                    cache_tag: "cpython-39".to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
+
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "data.txt".to_string(),
+            DataLocation::Memory(b"data".to_vec()),
+        );
+        let mut distribution = BTreeMap::new();
+        distribution.insert(
+            "RECORD".to_string(),
+            DataLocation::Memory(b"acme/__init__.py,,\n".to_vec()),
+        );
+        distribution.insert(
+            "METADATA".to_string(),
+            DataLocation::Memory(
+                b"Metadata-Version: 2.1\nName: acme-tools\nVersion: 1.0\nLicense: MIT\n".to_vec(),
+            ),
+        );
+
+        collector.add_prepackaged_resource(PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "acme-tools".to_string(),
+            in_memory_distribution_resources: Some(distribution),
+            ..PrePackagedResource::default()
+        })?;
+        collector.add_prepackaged_resource(PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "acme.data".to_string(),
+            is_package: true,
+            in_memory_resources: Some(resources),
+            ..PrePackagedResource::default()
+        })?;
+
+        Ok(collector)
+    }
+
+    #[test]
+    fn test_explain_module() -> Result<()> {
+        let collector = collector()?;
+
+        let explanation = explain_collected_resource(&collector, "acme.util").unwrap();
+        assert_eq!(explanation.kind, ExplainedKind::Module);
+        assert_eq!(explanation.locations["source"], "in memory");
+        assert_eq!(explanation.sizes["source"], 6);
+        assert_eq!(explanation.imports, Some(vec![]));
+        assert_eq!(explanation.importers, Some(vec!["acme.cli".to_string()]));
+        assert_eq!(
+            explanation.distribution.as_ref().unwrap().name.as_deref(),
+            Some("acme-tools")
+        );
+        assert_eq!(explanation.licenses, vec!["MIT".to_string()]);
+
+        let text = explanation.to_string();
+        assert!(text.starts_with("acme.util (module)\n"));
+        assert!(text.contains("  distribution: acme-tools 1.0 (license: MIT)\n"));
+        assert!(text.contains("  imported by: acme.cli\n"));
+
+        let json: serde_json::Value = serde_json::from_str(&explanation.to_json()?)?;
+        assert_eq!(json["kind"], "module");
+        assert_eq!(json["importers"][0], "acme.cli");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_package_resource() -> Result<()> {
+        let collector = collector()?;
+
+        let explanation = explain_collected_resource(&collector, "acme.data:data.txt").unwrap();
+        assert_eq!(explanation.kind, ExplainedKind::PackageResource);
+        assert_eq!(explanation.sizes["package resource data.txt"], 4);
+        assert_eq!(explanation.imports, None);
+        assert_eq!(explanation.licenses, vec!["MIT".to_string()]);
+
+        let explanation = explain_collected_resource(&collector, "acme-tools:METADATA").unwrap();
+        assert_eq!(explanation.kind, ExplainedKind::DistributionResource);
+
+        assert!(explain_collected_resource(&collector, "acme.data:missing.txt").is_none());
+        assert!(explain_collected_resource(&collector, "acme.utill").is_none());
+
+        let names = explainable_names(&collector);
+        assert_eq!(
+            suggest_names("acme.utill", names.iter()),
+            vec!["acme.util".to_string()]
+        );
+        assert_eq!(
+            suggest_names("acme.data:dta.txt", names.iter()),
+            vec!["acme.data:data.txt".to_string()]
+        );
+
+        Ok(())
+    }
+}
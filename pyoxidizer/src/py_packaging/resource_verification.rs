@@ -0,0 +1,361 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Verification of the packed resources of a built binary.
+
+A build manifest records the SHA-256 and size of the packed resources
+written by a build. The packed resources are then either embedded in the
+executable when it is linked or installed as a file next to it.
+
+Embedded packed resources are located by searching the executable for the
+packed resources header of any version. Every occurrence of a header
+followed by a plausible index is a candidate. Verification succeeds if a
+candidate has the digest recorded in the manifest. Otherwise each candidate
+is reported with its offset in the executable.
+
+Verification only needs the final executable and its build manifest, so it
+can run on artifacts long after the build that produced them.
+*/
+
+use {
+    super::update_package::{sha256_hex, BuildManifest, ManifestEntry},
+    anyhow::{anyhow, Context, Result},
+    byteorder::{LittleEndian, ReadBytesExt},
+    python_packed_resources::data::{HEADER_V1, HEADER_V2, HEADER_V3, LOOKUP_TABLE_ENTRY_LENGTH},
+    std::io::Cursor,
+    std::path::{Path, PathBuf},
+};
+
+/// Key of the packed resources in a build manifest.
+const PACKED_RESOURCES_KEY: &str = "packed-resources";
+
+/// Where packed resources matching a build manifest were found.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackedResourcesLocation {
+    /// Embedded in the executable, starting at a byte offset.
+    Embedded(u64),
+
+    /// In a file distinct from the executable.
+    External(PathBuf),
+}
+
+/// A packed resources payload not matching the build manifest.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PayloadMismatch {
+    /// Byte offset of the payload in the file holding it.
+    pub offset: u64,
+
+    /// Hex encoded SHA-256 of the bytes at the offset.
+    ///
+    /// `None` if fewer bytes than recorded in the manifest follow the offset.
+    pub sha256: Option<String>,
+}
+
+impl std::fmt::Display for PayloadMismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match &self.sha256 {
+            Some(sha256) => write!(f, "offset {} has SHA-256 {}", self.offset, sha256),
+            None => write!(f, "offset {} is truncated", self.offset),
+        }
+    }
+}
+
+fn expected_packed_resources(manifest: &BuildManifest) -> Result<&ManifestEntry> {
+    manifest
+        .files
+        .get(PACKED_RESOURCES_KEY)
+        .ok_or_else(|| anyhow!("build manifest doesn't record packed resources"))
+}
+
+/// Whether data starting with a packed resources header could be packed resources.
+///
+/// Only the global header is checked. Parsing the index of arbitrary data
+/// isn't safe since its lengths are trusted.
+fn plausible_packed_resources(data: &[u8]) -> bool {
+    let has_lookup_table = data.starts_with(HEADER_V3);
+    let mut reader = Cursor::new(&data[HEADER_V1.len()..]);
+
+    // Blob section count, blob index length, resources count and resources
+    // index length.
+    let mut read_index_lengths = || -> std::io::Result<(u32, u32, u32)> {
+        reader.read_u8()?;
+        let blob_index_length = reader.read_u32::<LittleEndian>()?;
+        let resources_count = reader.read_u32::<LittleEndian>()?;
+        let resources_index_length = reader.read_u32::<LittleEndian>()?;
+
+        Ok((blob_index_length, resources_count, resources_index_length))
+    };
+
+    match read_index_lengths() {
+        Ok((blob_index_length, resources_count, resources_index_length)) => {
+            let lookup_table_length = if has_lookup_table {
+                resources_count as u64 * LOOKUP_TABLE_ENTRY_LENGTH as u64
+            } else {
+                0
+            };
+
+            let index_length = 1
+                + 4
+                + 4
+                + 4
+                + lookup_table_length
+                + blob_index_length as u64
+                + resources_index_length as u64;
+
+            index_length <= (data.len() - HEADER_V1.len()) as u64
+        }
+        Err(_) => false,
+    }
+}
+
+/// Find the offsets of data that could be packed resources.
+///
+/// Packed resources of any version are found.
+pub fn find_packed_resources_offsets(data: &[u8]) -> Vec<u64> {
+    data.windows(HEADER_V1.len())
+        .enumerate()
+        .filter(|(offset, window)| {
+            (*window == HEADER_V1 || *window == HEADER_V2 || *window == HEADER_V3)
+                && plausible_packed_resources(&data[*offset..])
+        })
+        .map(|(offset, _)| offset as u64)
+        .collect()
+}
+
+/// Verify data holds packed resources matching a build manifest.
+///
+/// Returns the offset of the matching packed resources within `data`.
+/// Errors list the offset of each candidate that doesn't match.
+pub fn verify_embedded_packed_resources(data: &[u8], manifest: &BuildManifest) -> Result<u64> {
+    let expected = expected_packed_resources(manifest)?;
+
+    let mut mismatches = Vec::new();
+
+    for offset in find_packed_resources_offsets(data) {
+        let end = offset + expected.size;
+
+        let sha256 = if end <= data.len() as u64 {
+            Some(sha256_hex(&data[offset as usize..end as usize]))
+        } else {
+            None
+        };
+
+        if sha256.as_ref() == Some(&expected.sha256) {
+            return Ok(offset);
+        }
+
+        mismatches.push(PayloadMismatch { offset, sha256 });
+    }
+
+    if mismatches.is_empty() {
+        Err(anyhow!("no packed resources found"))
+    } else {
+        Err(anyhow!(
+            "no packed resources match SHA-256 {} of the build manifest: {}",
+            expected.sha256,
+            mismatches
+                .iter()
+                .map(|m| m.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        ))
+    }
+}
+
+/// Verify a packed resources file matches a build manifest.
+pub fn verify_packed_resources_file(path: &Path, manifest: &BuildManifest) -> Result<()> {
+    let expected = expected_packed_resources(manifest)?;
+
+    let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+    if data.len() as u64 != expected.size {
+        return Err(anyhow!(
+            "{} has {} bytes; build manifest records {}",
+            path.display(),
+            data.len(),
+            expected.size
+        ));
+    }
+
+    let sha256 = sha256_hex(&data);
+    if sha256 != expected.sha256 {
+        return Err(anyhow!(
+            "{}: {}; build manifest records {}",
+            path.display(),
+            PayloadMismatch {
+                offset: 0,
+                sha256: Some(sha256)
+            },
+            expected.sha256
+        ));
+    }
+
+    Ok(())
+}
+
+/// Verify the packed resources of a built executable match a build manifest.
+///
+/// If `packed_resources_path` is defined, the executable loads its packed
+/// resources from that file and the file is verified. Otherwise the packed
+/// resources are expected to be embedded in the executable.
+pub fn verify_executable_resources(
+    executable_path: &Path,
+    manifest: &BuildManifest,
+    packed_resources_path: Option<&Path>,
+) -> Result<PackedResourcesLocation> {
+    if let Some(path) = packed_resources_path {
+        verify_packed_resources_file(path, manifest)?;
+
+        Ok(PackedResourcesLocation::External(path.to_path_buf()))
+    } else {
+        let data = std::fs::read(executable_path)
+            .with_context(|| format!("reading {}", executable_path.display()))?;
+
+        let offset = verify_embedded_packed_resources(&data, manifest)
+            .with_context(|| format!("verifying {}", executable_path.display()))?;
+
+        Ok(PackedResourcesLocation::Embedded(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::app_packaging::resource::{FileContent, FileManifest},
+        python_packaging::resource::DataLocation,
+        python_packed_resources::data::{Resource, ResourceFlavor},
+        python_packed_resources::writer::{write_packed_resources_v1, write_packed_resources_v3},
+        std::borrow::Cow,
+    };
+
+    fn packed_resources(source: &'static [u8]) -> Result<Vec<u8>> {
+        let resources = vec![Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::Borrowed("foo"),
+            in_memory_source: Some(Cow::Borrowed(source)),
+            ..Resource::default()
+        }];
+
+        let mut data = Vec::new();
+        write_packed_resources_v1(&resources, &mut data, None)?;
+
+        Ok(data)
+    }
+
+    fn manifest(packed: &[u8]) -> Result<BuildManifest> {
+        let mut layout = FileManifest::default();
+        layout.add_file(
+            Path::new(PACKED_RESOURCES_KEY),
+            &FileContent {
+                data: DataLocation::Memory(packed.to_vec()),
+                executable: false,
+            },
+        )?;
+
+        BuildManifest::from_layout(&layout)
+    }
+
+    fn executable(packed: &[u8]) -> Vec<u8> {
+        // The header alone also appears in executables, e.g. as a constant of
+        // the parser.
+        let mut data = b"\x7fELF".to_vec();
+        data.extend(HEADER_V1);
+        data.extend(b"\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff\xff");
+        data.extend(packed);
+        data.extend(b"trailing");
+
+        data
+    }
+
+    #[test]
+    fn test_verify_embedded() -> Result<()> {
+        let packed = packed_resources(b"import bar")?;
+        let manifest = manifest(&packed)?;
+
+        let data = executable(&packed);
+        assert_eq!(find_packed_resources_offsets(&data), vec![25]);
+        assert_eq!(verify_embedded_packed_resources(&data, &manifest)?, 25);
+
+        let tampered = executable(&packed_resources(b"import baz")?);
+        let err = verify_embedded_packed_resources(&tampered, &manifest).unwrap_err();
+        assert!(err.to_string().starts_with(&format!(
+            "no packed resources match SHA-256 {} of the build manifest: offset 25 has SHA-256 ",
+            manifest.files[PACKED_RESOURCES_KEY].sha256
+        )));
+
+        let truncated = &data[0..data.len() - 10];
+        assert_eq!(
+            verify_embedded_packed_resources(truncated, &manifest)
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "no packed resources match SHA-256 {} of the build manifest: offset 25 is truncated",
+                manifest.files[PACKED_RESOURCES_KEY].sha256
+            )
+        );
+
+        assert_eq!(
+            verify_embedded_packed_resources(b"\x7fELF", &manifest)
+                .unwrap_err()
+                .to_string(),
+            "no packed resources found"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_embedded_lookup_table() -> Result<()> {
+        let mut packed = Vec::new();
+        write_packed_resources_v3(
+            &[Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Borrowed("foo"),
+                in_memory_source: Some(Cow::Borrowed(b"import bar")),
+                ..Resource::default()
+            }],
+            &mut packed,
+            None,
+        )?;
+        let manifest = manifest(&packed)?;
+
+        assert_eq!(
+            verify_embedded_packed_resources(&executable(&packed), &manifest)?,
+            25
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_executable_resources() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let packed = packed_resources(b"import bar")?;
+        let manifest = manifest(&packed)?;
+
+        let exe_path = td.path().join("app");
+        std::fs::write(&exe_path, executable(&packed))?;
+        assert_eq!(
+            verify_executable_resources(&exe_path, &manifest, None)?,
+            PackedResourcesLocation::Embedded(25)
+        );
+
+        let packed_path = td.path().join("packed-resources");
+        std::fs::write(&packed_path, &packed)?;
+        assert_eq!(
+            verify_executable_resources(&exe_path, &manifest, Some(&packed_path))?,
+            PackedResourcesLocation::External(packed_path.clone())
+        );
+
+        std::fs::write(&packed_path, packed_resources(b"import baz")?)?;
+        assert!(verify_executable_resources(&exe_path, &manifest, Some(&packed_path)).is_err());
+
+        assert!(verify_executable_resources(&exe_path, &BuildManifest::default(), None).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Detect packages that query the site-packages directory at run-time.
+
+Some packages locate their files via `sysconfig.get_paths()["purelib"]` or
+`site.getsitepackages()`. These don't point anywhere useful in a binary,
+so such packages should be installed in the directory configured by
+`site_packages_shim`, which the interpreter reports as site-packages.
+*/
+
+use {
+    super::config::{ConfigProblem, ConfigProblemSeverity},
+    anyhow::{Context, Result},
+    python_packaging::python_source::uses_site_packages_paths,
+    python_packaging::resource::DataLocation,
+    python_packaging::resource_collection::{PrePackagedResource, PythonModuleBytecodeProvider},
+    std::collections::{BTreeMap, BTreeSet},
+};
+
+/// Packages querying the site-packages directory outside the shim.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SitePackagesReport {
+    /// Modules querying site-packages, keyed by top-level package name.
+    pub offenders: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl SitePackagesReport {
+    /// Convert the report to warnings.
+    pub fn problems(&self) -> Vec<ConfigProblem> {
+        self.offenders
+            .iter()
+            .map(|(package, modules)| ConfigProblem {
+                severity: ConfigProblemSeverity::Warning,
+                code: "site-packages-unshimmed",
+                message: format!(
+                    "package {} queries the site-packages directory at run-time in {}; set site_packages_shim and add it to site_packages_shim_packages",
+                    package,
+                    modules.iter().cloned().collect::<Vec<_>>().join(", ")
+                ),
+            })
+            .collect()
+    }
+}
+
+/// Obtain the module sources of a resource, wherever they are installed.
+pub(crate) fn module_sources(resource: &PrePackagedResource) -> Vec<&DataLocation> {
+    let mut res = Vec::new();
+
+    if let Some(location) = &resource.in_memory_source {
+        res.push(location);
+    }
+    if let Some((_, location)) = &resource.relative_path_module_source {
+        res.push(location);
+    }
+
+    for provider in &[
+        &resource.in_memory_bytecode,
+        &resource.in_memory_bytecode_opt1,
+        &resource.in_memory_bytecode_opt2,
+    ] {
+        if let Some(PythonModuleBytecodeProvider::FromSource(location)) = *provider {
+            res.push(location);
+        }
+    }
+
+    for entry in &[
+        &resource.relative_path_bytecode,
+        &resource.relative_path_bytecode_opt1,
+        &resource.relative_path_bytecode_opt2,
+    ] {
+        if let Some((_, _, PythonModuleBytecodeProvider::FromSource(location))) = *entry {
+            res.push(location);
+        }
+    }
+
+    res
+}
+
+/// Find modules querying site-packages whose package isn't shimmed.
+///
+/// `shim_packages` are top-level package names installed in the shim
+/// directory. They are empty if no shim is configured.
+pub fn verify_site_packages_usage<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+    shim_packages: &[String],
+) -> Result<SitePackagesReport> {
+    let mut res = SitePackagesReport::default();
+
+    for (name, resource) in resources {
+        let package = name.split('.').next().unwrap_or(name);
+        if shim_packages.iter().any(|p| p == package) {
+            continue;
+        }
+
+        for location in module_sources(resource) {
+            let source = location
+                .resolve()
+                .with_context(|| format!("scanning {} for site-packages usage", name))?;
+
+            if uses_site_packages_paths(&source) {
+                res.offenders
+                    .entry(package.to_string())
+                    .or_insert_with(BTreeSet::new)
+                    .insert(name.clone());
+                break;
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packed_resources::data::ResourceFlavor};
+
+    fn module(name: &str, source: &[u8]) -> PrePackagedResource {
+        PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: name.to_string(),
+            in_memory_source: Some(DataLocation::Memory(source.to_vec())),
+            ..PrePackagedResource::default()
+        }
+    }
+
+    #[test]
+    fn test_verify_site_packages_usage() -> Result<()> {
+        let mut resources = BTreeMap::new();
+        for (name, source) in &[
+            ("foo", &b"import os"[..]),
+            ("foo.paths", &b"import site\nsite.getsitepackages()"[..]),
+            ("bar", &b"import sysconfig\nsysconfig.get_paths()"[..]),
+            ("shimmed.data", &b"import site\nsite.getsitepackages()"[..]),
+        ] {
+            resources.insert(name.to_string(), module(name, source));
+        }
+
+        let report = verify_site_packages_usage(resources.iter(), &["shimmed".to_string()])?;
+        assert_eq!(
+            report.offenders.keys().cloned().collect::<Vec<_>>(),
+            vec!["bar".to_string(), "foo".to_string()]
+        );
+        assert_eq!(
+            report.offenders["foo"],
+            vec!["foo.paths".to_string()].into_iter().collect()
+        );
+
+        let problems = report.problems();
+        assert_eq!(problems.len(), 2);
+        assert!(problems
+            .iter()
+            .all(|p| p.severity == ConfigProblemSeverity::Warning
+                && p.code == "site-packages-unshimmed"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,2141 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Declarative specifications of Python packaging operations.
+
+A `PackagingSpec` describes everything needed to produce an
+`EmbeddedPythonContext`: which Python distribution to use, the packaging
+policy, where to acquire Python resources from, and how to filter them.
+`execute_spec()` turns a spec into calls against a `PythonBinaryBuilder`.
+
+A `MultiTargetSpec` builds for several Python distributions, e.g. 3.9 and
+3.11, from one invocation. `execute_multi_target_spec()` acquires version
+independent resources once and produces one `EmbeddedPythonContext` per
+distribution.
+*/
+
+use {
+    super::binary::{EmbeddedPythonContext, PythonBinaryBuilder},
+    super::build_cache::{BuildCache, CacheSession, CacheStatistics, GcPolicy, GcReport},
+    super::build_clock::BuildClock,
+    super::build_directories::{BuildDirectories, DirectoryRole},
+    super::bytecode_cache::{BytecodeCache, BYTECODE_CACHE},
+    super::config::EmbeddedPythonConfig,
+    super::distribution::{
+        default_distribution_location, resolve_distribution, BinaryLibpythonLinkMode,
+        DistributionFlavor, PythonDistributionLocation,
+    },
+    super::pip_failure::PipFailure,
+    super::resource_analysis::{analyze_resources, ResourcesAnalysis},
+    anyhow::{anyhow, Context, Result},
+    python_packaging::bytecode::{CompileErrorPolicy, PycInvalidationMode, PycTimestamp},
+    python_packaging::layering::LayeredTree,
+    python_packaging::locale::LocaleFilter,
+    python_packaging::policy::{
+        DistributionVersionConflictPolicy, ExtensionModuleFilter, PythonResourcesPolicy,
+    },
+    python_packaging::resource::{
+        BytecodeOptimizationLevel, PythonModuleBytecodeFromSource, PythonModuleSource,
+        PythonResource,
+    },
+    python_packaging::resource_collection::{CollectionMemoryStatistics, ResourceSizeReport},
+    python_packaging::source_transform::SourceTransforms,
+    python_packed_resources::data::IndexEncoding,
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    slog::{info, warn},
+    std::collections::{BTreeMap, BTreeSet, HashMap},
+    std::convert::TryFrom,
+    std::path::{Path, PathBuf},
+    std::time::{Duration, Instant},
+    uuid::Uuid,
+};
+
+fn default_failure_ttl_seconds() -> u64 {
+    3600
+}
+
+fn default_cache_bytecode() -> bool {
+    true
+}
+
+fn default_opt_level() -> String {
+    "0".to_string()
+}
+
+/// Describes the Python distribution to build with.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DistributionSpec {
+    /// The flavor of distribution.
+    #[serde(default)]
+    pub flavor: DistributionFlavor,
+
+    /// Where to obtain the distribution from.
+    ///
+    /// If not defined, the default distribution for the flavor and target
+    /// triple is used.
+    #[serde(default)]
+    pub location: Option<PythonDistributionLocation>,
+
+    /// Directory to extract the distribution into.
+    pub dest_dir: PathBuf,
+}
+
+/// Describes the `PythonPackagingPolicy` to use.
+///
+/// Values have the same meaning as the arguments to
+/// `PythonDistribution.to_python_executable()` in Starlark.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct PolicySpec {
+    pub resources_policy: String,
+    pub extension_module_filter: String,
+    pub preferred_extension_module_variants: HashMap<String, String>,
+    pub extension_module_variant_pins: BTreeMap<String, String>,
+    pub include_sources: bool,
+    pub include_resources: bool,
+    pub include_test: bool,
+    pub console_script_usage: bool,
+    pub collection_memory_budget: Option<u64>,
+    pub packed_resources_index: String,
+    pub pyc_invalidation_mode: String,
+    pub on_compile_error: String,
+    pub reproducible: bool,
+    pub analysis_verdicts: Option<PathBuf>,
+    pub allow_identical_install_collisions: bool,
+    pub forbid_runtime_env: bool,
+    pub debug_companion: bool,
+    pub locale_keep_list: Option<Vec<String>>,
+    pub locale_exact_match: bool,
+    pub test_classification_overrides: Option<PathBuf>,
+    pub protect_entry_point_providers: bool,
+    pub reject_editable_installs: bool,
+    pub hide_internal_resources: bool,
+    pub on_distribution_version_conflict: String,
+    pub strip_docstrings: bool,
+    pub strip_annotations: bool,
+    pub strip_type_comments: bool,
+    pub source_transform_excludes: Vec<String>,
+    pub bytecode_optimize_levels: BTreeMap<String, i32>,
+}
+
+impl Default for PolicySpec {
+    fn default() -> Self {
+        PolicySpec {
+            resources_policy: "in-memory-only".to_string(),
+            extension_module_filter: "all".to_string(),
+            preferred_extension_module_variants: HashMap::new(),
+            extension_module_variant_pins: BTreeMap::new(),
+            include_sources: true,
+            include_resources: false,
+            include_test: false,
+            console_script_usage: false,
+            collection_memory_budget: None,
+            packed_resources_index: "sequential".to_string(),
+            pyc_invalidation_mode: "unchecked-hash".to_string(),
+            on_compile_error: "abort".to_string(),
+            reproducible: false,
+            analysis_verdicts: None,
+            allow_identical_install_collisions: false,
+            forbid_runtime_env: false,
+            debug_companion: false,
+            locale_keep_list: None,
+            locale_exact_match: false,
+            test_classification_overrides: None,
+            protect_entry_point_providers: false,
+            reject_editable_installs: false,
+            hide_internal_resources: false,
+            on_distribution_version_conflict: "replace".to_string(),
+            strip_docstrings: false,
+            strip_annotations: false,
+            strip_type_comments: false,
+            source_transform_excludes: vec![],
+            bytecode_optimize_levels: BTreeMap::new(),
+        }
+    }
+}
+
+/// A step that acquires Python resources and adds them to the binary.
+///
+/// Each variant corresponds to a method on `PythonBinaryBuilder`.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AcquisitionStep {
+    /// Run `pip install` with arguments.
+    PipInstall {
+        args: Vec<String>,
+        #[serde(default)]
+        extra_envs: HashMap<String, String>,
+        #[serde(default)]
+        verbose: bool,
+    },
+
+    /// Run `pip install` against a wheel file.
+    Wheel {
+        path: PathBuf,
+        #[serde(default)]
+        extra_envs: HashMap<String, String>,
+        #[serde(default)]
+        verbose: bool,
+    },
+
+    /// Read resources for packages from a directory.
+    PackageRoot {
+        path: PathBuf,
+        packages: Vec<String>,
+        /// Whether the directory only contains bytecode in `__pycache__` directories.
+        #[serde(default)]
+        bytecode_only: bool,
+    },
+
+    /// Read resources for packages from directories layered on top of each other.
+    ///
+    /// Layers are ordered from the base to the topmost overlay.
+    LayeredPackageRoots {
+        layers: Vec<PathBuf>,
+        packages: Vec<String>,
+    },
+
+    /// Read resources from a populated virtualenv.
+    Virtualenv { path: PathBuf },
+
+    /// Run `python setup.py install` for a package.
+    SetupPyInstall {
+        package_path: PathBuf,
+        #[serde(default)]
+        extra_envs: HashMap<String, String>,
+        #[serde(default)]
+        extra_global_arguments: Vec<String>,
+        #[serde(default)]
+        verbose: bool,
+    },
+}
+
+impl AcquisitionStep {
+    /// Obtain a human readable description of this step.
+    pub fn describe(&self) -> String {
+        match self {
+            AcquisitionStep::PipInstall { args, .. } => {
+                format!("pip install {}", args.join(" "))
+            }
+            AcquisitionStep::Wheel { path, .. } => format!("wheel {}", path.display()),
+            AcquisitionStep::PackageRoot {
+                path,
+                packages,
+                bytecode_only,
+            } => format!(
+                "{}package root {} ({})",
+                if *bytecode_only { "bytecode-only " } else { "" },
+                path.display(),
+                packages.join(", ")
+            ),
+            AcquisitionStep::LayeredPackageRoots { layers, packages } => format!(
+                "layered package roots {} ({})",
+                layers
+                    .iter()
+                    .map(|path| format!("{}", path.display()))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                packages.join(", ")
+            ),
+            AcquisitionStep::Virtualenv { path } => format!("virtualenv {}", path.display()),
+            AcquisitionStep::SetupPyInstall { package_path, .. } => {
+                format!("setup.py install {}", package_path.display())
+            }
+        }
+    }
+
+    /// Whether the resources of this step depend on the Python version.
+    ///
+    /// pip selects distributions, wheels and dependencies for the
+    /// interpreter running it, so steps running pip can't be shared between
+    /// Python versions even if they only acquire pure Python resources.
+    fn is_version_dependent(&self) -> bool {
+        match self {
+            AcquisitionStep::PipInstall { .. }
+            | AcquisitionStep::Wheel { .. }
+            | AcquisitionStep::SetupPyInstall { .. } => true,
+            AcquisitionStep::PackageRoot { .. }
+            | AcquisitionStep::LayeredPackageRoots { .. }
+            | AcquisitionStep::Virtualenv { .. } => false,
+        }
+    }
+
+    /// Obtain resources for this step using a builder.
+    fn resolve_resources(
+        &self,
+        logger: &slog::Logger,
+        builder: &dyn PythonBinaryBuilder,
+    ) -> Result<StepResources> {
+        let resources = match self {
+            AcquisitionStep::PipInstall {
+                args,
+                extra_envs,
+                verbose,
+            } => builder.pip_install(logger, *verbose, args, extra_envs),
+            AcquisitionStep::Wheel {
+                path,
+                extra_envs,
+                verbose,
+            } => builder.pip_install(
+                logger,
+                *verbose,
+                &[format!("{}", path.display())],
+                extra_envs,
+            ),
+            AcquisitionStep::PackageRoot {
+                path,
+                packages,
+                bytecode_only: true,
+            } => builder.read_bytecode_package_root(logger, path, packages),
+            AcquisitionStep::PackageRoot { path, packages, .. } => {
+                builder.read_package_root(logger, path, packages)
+            }
+            AcquisitionStep::LayeredPackageRoots { layers, packages } => {
+                let layers = layers.iter().map(|path| path.as_path()).collect::<Vec<_>>();
+                let layered = builder.read_layered_package_roots(logger, &layers, packages)?;
+
+                for o in &layered.tree.overrides {
+                    info!(
+                        logger,
+                        "{} overrides {}",
+                        o.overlay.display(),
+                        o.base.display()
+                    );
+                }
+                for d in &layered.tree.deletions {
+                    info!(
+                        logger,
+                        "{} removes {}",
+                        d.tombstone.display(),
+                        d.base.display()
+                    );
+                }
+
+                return Ok(StepResources {
+                    resources: layered.resources,
+                    layered_tree: Some(layered.tree),
+                });
+            }
+            AcquisitionStep::Virtualenv { path } => builder.read_virtualenv(logger, path),
+            AcquisitionStep::SetupPyInstall {
+                package_path,
+                extra_envs,
+                extra_global_arguments,
+                verbose,
+            } => builder.setup_py_install(
+                logger,
+                package_path,
+                *verbose,
+                extra_envs,
+                extra_global_arguments,
+            ),
+        }?;
+
+        Ok(StepResources {
+            resources,
+            layered_tree: None,
+        })
+    }
+}
+
+/// Resources obtained by performing an acquisition step.
+#[derive(Clone, Debug, Default)]
+struct StepResources {
+    resources: Vec<PythonResource>,
+
+    /// How layered package roots were merged, if the step read them.
+    layered_tree: Option<LayeredTree>,
+}
+
+/// Describes filtering of resources against files containing resource names.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default)]
+pub struct FilterSpec {
+    /// Files to read resource names from.
+    pub files: Vec<PathBuf>,
+
+    /// Glob patterns of files to read resource names from.
+    pub glob_patterns: Vec<String>,
+}
+
+/// Describes the persistent build cache to use.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CacheSpec {
+    /// Directory holding the caches.
+    ///
+    /// The directory can be shared by concurrent builds. Defaults to the
+    /// cache directory of the build directories.
+    #[serde(default)]
+    pub dir: Option<PathBuf>,
+
+    /// Garbage collection to perform at the end of the build.
+    #[serde(default)]
+    pub gc: GcPolicy,
+
+    /// How long in seconds failures of `pip` steps are remembered.
+    ///
+    /// Until then, builds performing a step with identical inputs fail
+    /// immediately with the diagnosis of the previous failure.
+    #[serde(default = "default_failure_ttl_seconds")]
+    pub failure_ttl_seconds: u64,
+
+    /// Perform steps again even if they failed with identical inputs before.
+    #[serde(default)]
+    pub retry_failures: bool,
+
+    /// Reuse bytecode compiled from identical sources by earlier builds.
+    #[serde(default = "default_cache_bytecode")]
+    pub bytecode: bool,
+}
+
+/// A declarative description of a packaging operation.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PackagingSpec {
+    /// Name of the binary being produced.
+    pub name: String,
+
+    /// Rust target triple of the machine performing the build.
+    ///
+    /// Defaults to the triple this crate was built for.
+    #[serde(default)]
+    pub host_triple: Option<String>,
+
+    /// Rust target triple of the machine the binary will run on.
+    ///
+    /// Defaults to the host triple.
+    #[serde(default)]
+    pub target_triple: Option<String>,
+
+    /// Python executable compiling bytecode on the machine performing the build.
+    ///
+    /// Defaults to the distribution's executable. Set it when that can't
+    /// run on the build machine, e.g. when building for another platform.
+    /// It must be the same Python version as the distribution.
+    #[serde(default)]
+    pub host_python_exe: Option<PathBuf>,
+
+    /// The Python distribution to use.
+    pub distribution: DistributionSpec,
+
+    /// How to link libpython.
+    #[serde(default)]
+    pub libpython_link_mode: BinaryLibpythonLinkMode,
+
+    /// The packaging policy to apply.
+    #[serde(default)]
+    pub policy: PolicySpec,
+
+    /// Steps to acquire resources, performed in order.
+    #[serde(default)]
+    pub steps: Vec<AcquisitionStep>,
+
+    /// Filtering to apply after all steps have been performed.
+    #[serde(default)]
+    pub filter: Option<FilterSpec>,
+
+    /// Configuration of the embedded Python interpreter.
+    #[serde(default)]
+    pub config: EmbeddedPythonConfig,
+
+    /// Feature tags to apply to resources after filtering.
+    ///
+    /// Keys are feature names and values are glob patterns of resource
+    /// names to tag with that feature.
+    #[serde(default)]
+    pub feature_tags: BTreeMap<String, Vec<String>>,
+
+    /// Features to enable.
+    ///
+    /// Resources tagged with features are only embedded if one of their
+    /// tags is enabled.
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+
+    /// Optimization level to use when compiling libpython.
+    #[serde(default = "default_opt_level")]
+    pub opt_level: String,
+
+    /// Analyze the compressibility of resources before generating the context.
+    ///
+    /// The value is the maximum number of bytes to sample from each piece of
+    /// resource data. Analysis is skipped if not defined.
+    #[serde(default)]
+    pub analysis_sample_size: Option<usize>,
+
+    /// Compress embedded resources that the analysis finds compressible.
+    ///
+    /// Payloads of resources whose data isn't of high entropy are stored
+    /// compressed with zstd in packed resources and decompressed when the
+    /// binary loads them, instead of compressing everything or nothing.
+    /// Requires `analysis_sample_size`.
+    #[serde(default)]
+    pub compress_analyzed_resources: bool,
+
+    /// Persistent build cache to use.
+    #[serde(default)]
+    pub cache: Option<CacheSpec>,
+
+    /// Directories temporary and cached files are written to.
+    #[serde(default)]
+    pub directories: BuildDirectories,
+
+    /// Source of times recorded by the build.
+    ///
+    /// Defaults to `SOURCE_DATE_EPOCH` if set and the system clock otherwise.
+    #[serde(default)]
+    pub clock: BuildClock,
+
+    /// Root of the workspace the build runs in.
+    ///
+    /// Paths under it are recorded relative to it in cached state, so
+    /// checkouts in different directories or on different machines share
+    /// cache entries.
+    #[serde(default)]
+    pub workspace_root: Option<PathBuf>,
+}
+
+/// Describes the result of executing a `PackagingSpec`.
+#[derive(Clone, Debug, Default)]
+pub struct SpecExecutionReport {
+    /// Warnings encountered when executing the spec.
+    pub warnings: Vec<String>,
+
+    /// Each operation that was performed and how long it took.
+    pub profile: Vec<(String, Duration)>,
+
+    /// Compressibility analysis of resources, if requested.
+    pub analysis: Option<ResourcesAnalysis>,
+
+    /// Hit and miss counts of each build cache, keyed by cache name.
+    pub cache_statistics: BTreeMap<String, CacheStatistics>,
+
+    /// Result of collecting build cache garbage, if a cache is used.
+    pub cache_gc: Option<GcReport>,
+
+    /// Payloads held in memory while collecting resources, keyed by target.
+    ///
+    /// Only recorded if the policy defines a memory budget. Single target
+    /// builds use an empty key.
+    pub collection_memory: BTreeMap<String, CollectionMemoryStatistics>,
+
+    /// Sizes of the payloads of added resources, keyed by target.
+    ///
+    /// Single target builds use an empty key.
+    pub resource_sizes: BTreeMap<String, ResourceSizeReport>,
+
+    /// Bytes written to temporary directories by all targets, by role.
+    pub directory_bytes_written: BTreeMap<DirectoryRole, u64>,
+}
+
+/// Add a `PythonResource` to a builder.
+///
+/// This mirrors `PythonExecutable.add_python_resource()` in Starlark with
+/// default arguments.
+fn add_python_resource(
+    builder: &mut dyn PythonBinaryBuilder,
+    resource: &PythonResource,
+    warnings: &mut Vec<String>,
+) -> Result<()> {
+    match resource {
+        PythonResource::ModuleSource(m) => {
+            builder.add_python_module_source(m, None)?;
+            let bytecode = builder.python_packaging_policy().bytecode_request(m);
+            builder.add_python_module_bytecode_from_source(&bytecode, None)?;
+        }
+        PythonResource::ModuleBytecodeRequest(m) => {
+            builder.add_python_module_bytecode_from_source(m, None)?;
+        }
+        PythonResource::Resource(r) => {
+            builder.add_python_package_resource(r, None)?;
+        }
+        PythonResource::DistributionResource(r) => {
+            builder.add_python_package_distribution_resource(r, None)?;
+        }
+        PythonResource::ExtensionModuleDynamicLibrary(em)
+        | PythonResource::ExtensionModuleStaticallyLinked(em) => {
+            builder.add_python_extension_module(em, None)?;
+        }
+        PythonResource::ModuleBytecode(m) => {
+            builder.add_python_module_bytecode(m, None)?;
+        }
+        PythonResource::EggFile(_) => {
+            warnings.push("ignoring egg file since eggs are not supported".to_string());
+        }
+        PythonResource::PathExtension(_) => {
+            warnings.push("ignoring .pth file since path extensions are not supported".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Run `f` and record how long it took in a report.
+fn profile<T>(
+    report: &mut SpecExecutionReport,
+    label: &str,
+    f: impl FnOnce(&mut SpecExecutionReport) -> Result<T>,
+) -> Result<T> {
+    let start = Instant::now();
+    let res = f(report).context(format!("{} failed", label))?;
+    report.profile.push((label.to_string(), start.elapsed()));
+
+    Ok(res)
+}
+
+/// Prefix a profile label with the name of a target, if any.
+fn target_label(target: Option<&str>, label: &str) -> String {
+    match target {
+        Some(target) => format!("{}: {}", target, label),
+        None => label.to_string(),
+    }
+}
+
+/// Open the build cache of a spec.
+fn open_build_cache(spec: &PackagingSpec, cache: &CacheSpec) -> Result<BuildCache> {
+    let dir = cache
+        .dir
+        .as_ref()
+        .or_else(|| spec.directories.cache_dir.as_ref())
+        .ok_or_else(|| anyhow!("build cache has no directory"))?;
+
+    let mut cache = BuildCache::new(dir)?;
+    cache.set_clock(spec.clock);
+
+    Ok(cache)
+}
+
+/// Start a build cache session if the spec defines a cache.
+fn start_cache_session(spec: &PackagingSpec) -> Result<Option<CacheSession>> {
+    match &spec.cache {
+        Some(cache) => {
+            let cache = open_build_cache(spec, cache)?;
+
+            Ok(Some(
+                cache.session().context("starting build cache session")?,
+            ))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Finish a build cache session, recording statistics and garbage collection.
+fn finish_cache_session(
+    spec: &PackagingSpec,
+    session: Option<CacheSession>,
+    logger: &slog::Logger,
+    report: &mut SpecExecutionReport,
+) -> Result<()> {
+    if let (Some(session), Some(cache)) = (session, &spec.cache) {
+        for (name, statistics) in session.statistics() {
+            let total = report.cache_statistics.entry(name.clone()).or_default();
+            total.hits += statistics.hits;
+            total.misses += statistics.misses;
+        }
+
+        let gc = profile(report, "collecting build cache garbage", |_| {
+            session.finish(&cache.gc)
+        })?;
+
+        if !gc.removed.is_empty() {
+            warn!(
+                logger,
+                "removed {} build cache entries ({} bytes)",
+                gc.removed.len(),
+                gc.bytes_removed
+            );
+        }
+
+        report.cache_gc = Some(gc);
+    }
+
+    Ok(())
+}
+
+/// Obtain the host and target triples of a spec, applying defaults.
+fn spec_triples(spec: &PackagingSpec) -> (String, String) {
+    let host_triple = spec
+        .host_triple
+        .clone()
+        .unwrap_or_else(|| crate::project_building::HOST.to_string());
+    let target_triple = spec
+        .target_triple
+        .clone()
+        .unwrap_or_else(|| host_triple.clone());
+
+    (host_triple, target_triple)
+}
+
+/// zstd level of resources compressed because of `compress_analyzed_resources`.
+const ANALYZED_RESOURCES_COMPRESSION_LEVEL: i32 = 3;
+
+/// Name of the build cache holding failures of acquisition steps.
+const STEP_FAILURES_CACHE: &str = "step-failures";
+
+/// Name of the build cache holding pip's own cache.
+const PIP_CACHE: &str = "pip";
+
+/// Key of the entry of `PIP_CACHE` holding pip's cache directory.
+///
+/// pip keys its cache by URL and wheel tags itself, so all builds share one
+/// directory.
+const PIP_CACHE_KEY: &str = "cache-dir";
+
+/// A failure of an acquisition step recorded in the build cache.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+struct CachedStepFailure {
+    /// Description of the failed step.
+    step: String,
+
+    /// Why the step failed.
+    failure: PipFailure,
+
+    /// When the step failed, in seconds since the UNIX epoch.
+    failed_at: u64,
+}
+
+/// Obtain files referenced by `pip install` arguments.
+///
+/// These are the values of `-r`/`-c` options in any of their spellings and
+/// arguments naming existing files, like wheels.
+fn pip_referenced_files(args: &[String]) -> Vec<PathBuf> {
+    args.iter()
+        .map(|arg| {
+            if arg.starts_with("--") {
+                arg.splitn(2, '=').nth(1).unwrap_or(arg)
+            } else if arg.len() > 2 && (arg.starts_with("-r") || arg.starts_with("-c")) {
+                &arg[2..]
+            } else {
+                arg
+            }
+        })
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+/// Obtain the key failures of a step are cached under.
+///
+/// Only `pip` steps are cached; other steps return `None`. The key covers
+/// everything influencing what pip resolves: the step's arguments and
+/// environment, the content of files they reference, and the Python
+/// distribution and target triple of the builder. Changing any of them
+/// invalidates cached failures.
+fn step_failure_key(
+    spec: &PackagingSpec,
+    step: &AcquisitionStep,
+    builder: &dyn PythonBinaryBuilder,
+) -> Result<Option<String>> {
+    let (args, extra_envs) = match step {
+        AcquisitionStep::PipInstall {
+            args, extra_envs, ..
+        } => (args.clone(), extra_envs),
+        AcquisitionStep::Wheel {
+            path, extra_envs, ..
+        } => (vec![format!("{}", path.display())], extra_envs),
+        _ => return Ok(None),
+    };
+
+    // Paths under the workspace root are hashed relative to it.
+    let root = spec
+        .workspace_root
+        .as_ref()
+        .map(|root| format!("{}", root.display()));
+    let rebase = |value: &str| match &root {
+        Some(root) if !root.is_empty() => value.replace(root.as_str(), "$WORKSPACE"),
+        _ => value.to_string(),
+    };
+
+    let mut hasher = Sha256::new();
+    let mut update = |field: &str, value: &[u8]| {
+        // Fields are length prefixed so values can't run into each other.
+        hasher.update(field.as_bytes());
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    };
+
+    for arg in &args {
+        update("arg", rebase(arg).as_bytes());
+    }
+    for (key, value) in extra_envs.iter().collect::<BTreeMap<_, _>>() {
+        update("env", key.as_bytes());
+        update("env-value", value.as_bytes());
+    }
+    for path in pip_referenced_files(&args) {
+        let data = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        update("file", rebase(&format!("{}", path.display())).as_bytes());
+        update("file-content", &data);
+    }
+    update("target-triple", spec_triples(spec).1.as_bytes());
+    update("cache-tag", builder.cache_tag().as_bytes());
+    update(
+        "python-exe",
+        rebase(&format!("{}", builder.target_python_exe_path().display())).as_bytes(),
+    );
+
+    Ok(Some(hex::encode(hasher.finalize())))
+}
+
+/// Look up an unexpired failure of a step in the build cache.
+fn cached_step_failure(
+    spec: &PackagingSpec,
+    cache: &CacheSpec,
+    session: &mut CacheSession,
+    key: &str,
+) -> Result<Option<(PathBuf, CachedStepFailure)>> {
+    let path = match session.get(STEP_FAILURES_CACHE, key)? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let data = std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+    let failure: CachedStepFailure = match serde_json::from_slice(&data) {
+        Ok(failure) => failure,
+        // Unreadable records are treated as absent and replaced.
+        Err(_) => return Ok(None),
+    };
+
+    if spec.clock.now_seconds()?.saturating_sub(failure.failed_at) > cache.failure_ttl_seconds {
+        Ok(None)
+    } else {
+        Ok(Some((path, failure)))
+    }
+}
+
+/// Record a failure of a step in the build cache.
+fn cache_step_failure(
+    session: &mut CacheSession,
+    key: &str,
+    failure: &CachedStepFailure,
+) -> Result<()> {
+    let path = session.build_cache().entry_path(STEP_FAILURES_CACHE, key);
+    let parent = path.parent().expect("entry paths have a parent");
+    std::fs::create_dir_all(parent).with_context(|| format!("creating {}", parent.display()))?;
+
+    // Other builds never observe a partially written record.
+    let temp_path = parent.join(format!("{}.{}.tmp", key, Uuid::new_v4()));
+    std::fs::write(&temp_path, serde_json::to_vec_pretty(failure)?)
+        .with_context(|| format!("writing {}", temp_path.display()))?;
+    std::fs::rename(&temp_path, &path).with_context(|| format!("replacing {}", path.display()))?;
+
+    session.insert(STEP_FAILURES_CACHE, key)?;
+
+    Ok(())
+}
+
+/// Obtain a `pip` step using pip's cache directory in the build cache.
+///
+/// The directory is a single entry of the build cache, so downloads and
+/// wheels pip caches are subject to its garbage collection. Returns `None`
+/// for steps not running pip and for steps configuring pip's cache through
+/// `PIP_CACHE_DIR` or `PIP_NO_CACHE_DIR`.
+fn with_pip_cache(
+    step: &AcquisitionStep,
+    session: &mut CacheSession,
+) -> Result<Option<AcquisitionStep>> {
+    let mut step = step.clone();
+
+    let extra_envs = match &mut step {
+        AcquisitionStep::PipInstall { extra_envs, .. }
+        | AcquisitionStep::Wheel { extra_envs, .. } => extra_envs,
+        _ => return Ok(None),
+    };
+
+    if extra_envs.contains_key("PIP_CACHE_DIR") || extra_envs.contains_key("PIP_NO_CACHE_DIR") {
+        return Ok(None);
+    }
+
+    let path = match session.get(PIP_CACHE, PIP_CACHE_KEY)? {
+        Some(path) => path,
+        None => {
+            let path = session.build_cache().entry_path(PIP_CACHE, PIP_CACHE_KEY);
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("creating {}", path.display()))?;
+
+            session.insert(PIP_CACHE, PIP_CACHE_KEY)?
+        }
+    };
+
+    extra_envs.insert("PIP_CACHE_DIR".to_string(), format!("{}", path.display()));
+
+    Ok(Some(step))
+}
+
+/// Perform a step, letting `pip` steps use pip's cache in the build cache.
+fn perform_step(
+    step: &AcquisitionStep,
+    builder: &dyn PythonBinaryBuilder,
+    session: &mut CacheSession,
+    logger: &slog::Logger,
+) -> Result<StepResources> {
+    let pip_step = match with_pip_cache(step, session)? {
+        Some(pip_step) => pip_step,
+        None => return step.resolve_resources(logger, builder),
+    };
+
+    let res = pip_step.resolve_resources(logger, builder);
+
+    // Record the size pip left its cache at.
+    session.insert(PIP_CACHE, PIP_CACHE_KEY)?;
+
+    res
+}
+
+/// Obtain resources of a step, remembering failures in the build cache.
+///
+/// If a `pip` step failed with identical inputs within the failure TTL, it
+/// isn't performed again and the diagnosis of the previous failure is
+/// returned instead. `pip` steps keep pip's cache in the build cache.
+fn resolve_step(
+    spec: &PackagingSpec,
+    step: &AcquisitionStep,
+    builder: &dyn PythonBinaryBuilder,
+    session: &mut Option<CacheSession>,
+    logger: &slog::Logger,
+) -> Result<StepResources> {
+    let (session, cache) = match (session.as_mut(), &spec.cache) {
+        (Some(session), Some(cache)) => (session, cache),
+        _ => return step.resolve_resources(logger, builder),
+    };
+
+    let key = match step_failure_key(spec, step, builder)? {
+        Some(key) => key,
+        None => return perform_step(step, builder, session, logger),
+    };
+
+    if !cache.retry_failures {
+        if let Some((path, cached)) = cached_step_failure(spec, cache, session, &key)? {
+            return Err(anyhow!(
+                "{} failed in a previous build with identical inputs: {}; \
+                 set cache.retry_failures to perform it again or remove {}",
+                cached.step,
+                cached.failure,
+                path.display()
+            ));
+        }
+    }
+
+    match perform_step(step, builder, session, logger) {
+        Ok(resources) => {
+            let path = session.build_cache().entry_path(STEP_FAILURES_CACHE, &key);
+            if path.exists() {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("removing {}", path.display()))?;
+            }
+
+            Ok(resources)
+        }
+        Err(err) => {
+            if let Some(failure) = err.downcast_ref::<PipFailure>() {
+                let cached = CachedStepFailure {
+                    step: step.describe(),
+                    failure: failure.clone(),
+                    failed_at: spec.clock.now_seconds()?,
+                };
+
+                if let Err(cache_err) = cache_step_failure(session, &key, &cached) {
+                    warn!(
+                        logger,
+                        "unable to cache failure of {}: {:#}", cached.step, cache_err
+                    );
+                }
+            }
+
+            Err(err)
+        }
+    }
+}
+
+/// Resolve a Python distribution and create a builder from it.
+///
+/// Everything but the distribution comes from `spec`.
+fn create_builder(
+    spec: &PackagingSpec,
+    distribution: &DistributionSpec,
+    target: Option<&str>,
+    logger: &slog::Logger,
+    report: &mut SpecExecutionReport,
+) -> Result<Box<dyn PythonBinaryBuilder>> {
+    let (host_triple, target_triple) = spec_triples(spec);
+
+    let dist = profile(
+        report,
+        &target_label(target, "resolving distribution"),
+        |_| {
+            let location = match &distribution.location {
+                Some(location) => location.clone(),
+                None => default_distribution_location(&distribution.flavor, &target_triple)?,
+            };
+
+            resolve_distribution(
+                logger,
+                &distribution.flavor,
+                &location,
+                &distribution.dest_dir,
+            )
+        },
+    )?;
+
+    let mut builder = profile(
+        report,
+        &target_label(target, "creating binary builder"),
+        |_| {
+            let mut policy = dist.create_packaging_policy()?;
+            policy.set_resources_policy(PythonResourcesPolicy::try_from(
+                spec.policy.resources_policy.as_str(),
+            )?);
+            policy.set_extension_module_filter(
+                ExtensionModuleFilter::try_from(spec.policy.extension_module_filter.as_str())
+                    .map_err(|e| anyhow!(e))?,
+            );
+            policy.set_include_distribution_sources(spec.policy.include_sources);
+            policy.set_include_distribution_resources(spec.policy.include_resources);
+            policy.set_include_test(spec.policy.include_test);
+            policy.set_console_script_usage(spec.policy.console_script_usage);
+            policy.set_collection_memory_budget(spec.policy.collection_memory_budget);
+            policy.set_packed_resources_index(
+                IndexEncoding::try_from(spec.policy.packed_resources_index.as_str())
+                    .map_err(|e| anyhow!(e))?,
+            );
+            policy.set_pyc_invalidation_mode(
+                match PycInvalidationMode::try_from(spec.policy.pyc_invalidation_mode.as_str())
+                    .map_err(|e| anyhow!(e))?
+                {
+                    // Honor SOURCE_DATE_EPOCH so timestamps are reproducible too.
+                    PycInvalidationMode::Timestamp(_) => {
+                        PycInvalidationMode::Timestamp(PycTimestamp::from_env()?)
+                    }
+                    mode => mode,
+                },
+            );
+            policy.set_compile_error_policy(
+                CompileErrorPolicy::try_from(spec.policy.on_compile_error.as_str())
+                    .map_err(|e| anyhow!(e))?,
+            );
+            policy.set_reproducible_build(spec.policy.reproducible);
+            policy.set_analysis_verdicts(spec.policy.analysis_verdicts.clone());
+            policy.set_allow_identical_install_collisions(
+                spec.policy.allow_identical_install_collisions,
+            );
+            policy.set_forbid_runtime_env(spec.policy.forbid_runtime_env);
+            policy.set_debug_companion(spec.policy.debug_companion);
+            policy.set_test_classification_overrides(
+                spec.policy.test_classification_overrides.clone(),
+            );
+            policy.set_protect_entry_point_providers(spec.policy.protect_entry_point_providers);
+            policy.set_reject_editable_installs(spec.policy.reject_editable_installs);
+            policy.set_hide_internal_resources(spec.policy.hide_internal_resources);
+            policy.set_distribution_version_conflict(
+                DistributionVersionConflictPolicy::try_from(
+                    spec.policy.on_distribution_version_conflict.as_str(),
+                )
+                .map_err(|e| anyhow!(e))?,
+            );
+            policy.set_locale_filter(spec.policy.locale_keep_list.as_ref().map(|keep| {
+                LocaleFilter {
+                    keep: keep.clone(),
+                    exact_match: spec.policy.locale_exact_match,
+                }
+            }));
+            policy.set_source_transforms(SourceTransforms {
+                strip_docstrings: spec.policy.strip_docstrings,
+                strip_annotations: spec.policy.strip_annotations,
+                strip_type_comments: spec.policy.strip_type_comments,
+                excluded_modules: spec
+                    .policy
+                    .source_transform_excludes
+                    .iter()
+                    .cloned()
+                    .collect(),
+            });
+
+            for (ext, variant) in &spec.policy.preferred_extension_module_variants {
+                policy.set_preferred_extension_module_variant(ext, variant);
+            }
+            for (ext, variant) in &spec.policy.extension_module_variant_pins {
+                policy.pin_extension_module_variant(ext, variant);
+            }
+            for (prefix, level) in &spec.policy.bytecode_optimize_levels {
+                policy.set_bytecode_optimize_level(
+                    prefix,
+                    BytecodeOptimizationLevel::try_from(*level).map_err(|e| anyhow!(e))?,
+                );
+            }
+
+            dist.as_python_executable_builder(
+                logger,
+                &host_triple,
+                &target_triple,
+                &spec.name,
+                spec.libpython_link_mode.clone(),
+                &policy,
+                &spec.config,
+                &spec.directories,
+            )
+        },
+    )?;
+
+    builder.set_build_clock(spec.clock);
+    builder.set_host_python_exe(spec.host_python_exe.as_deref())?;
+
+    if let Some(cache) = spec.cache.as_ref().filter(|cache| cache.bytecode) {
+        builder.set_bytecode_cache(Some(BytecodeCache::new(
+            open_build_cache(spec, cache)?,
+            cache.gc.clone(),
+        )));
+    }
+
+    Ok(builder)
+}
+
+/// Filter, tag and analyze resources, then generate the embedded context.
+fn finish_builder(
+    spec: &PackagingSpec,
+    builder: &mut dyn PythonBinaryBuilder,
+    target: Option<&str>,
+    logger: &slog::Logger,
+    report: &mut SpecExecutionReport,
+) -> Result<EmbeddedPythonContext> {
+    if let Some(filter) = &spec.filter {
+        profile(report, &target_label(target, "filtering resources"), |_| {
+            let files = filter
+                .files
+                .iter()
+                .map(|p| p.as_path())
+                .collect::<Vec<&Path>>();
+            let glob_patterns = filter
+                .glob_patterns
+                .iter()
+                .map(|s| s.as_str())
+                .collect::<Vec<&str>>();
+
+            builder.filter_resources_from_files(logger, &files, &glob_patterns)
+        })?;
+    }
+
+    if !spec.feature_tags.is_empty() {
+        profile(
+            report,
+            &target_label(target, "tagging resources"),
+            |report| {
+                for (feature, patterns) in &spec.feature_tags {
+                    for pattern in patterns {
+                        if builder.tag_resources(pattern, feature)? == 0 {
+                            report.warnings.push(format!(
+                                "feature tag pattern {} for {} matched no resources",
+                                pattern, feature
+                            ));
+                        }
+                    }
+                }
+
+                Ok(())
+            },
+        )?;
+    }
+
+    if let Some(sample_size) = spec.analysis_sample_size {
+        let analysis = profile(report, &target_label(target, "analyzing resources"), |_| {
+            analyze_resources(builder.iter_resources(), sample_size)
+        })?;
+
+        let mut summary = Vec::new();
+        analysis.write_report(&mut summary)?;
+        for line in String::from_utf8_lossy(&summary).lines() {
+            warn!(logger, "{}", line);
+        }
+
+        if spec.compress_analyzed_resources {
+            let candidates = analysis.compression_candidates();
+            let saved = profile(
+                report,
+                &target_label(target, "compressing resources"),
+                |_| builder.compress_resources(&candidates, ANALYZED_RESOURCES_COMPRESSION_LEVEL),
+            )?;
+
+            warn!(
+                logger,
+                "{}",
+                target_label(
+                    target,
+                    &format!(
+                        "compressing {} resources saved {} bytes of packed resources",
+                        candidates.len(),
+                        saved
+                    )
+                )
+            );
+        }
+
+        report.analysis = Some(analysis);
+    } else if spec.compress_analyzed_resources {
+        return Err(anyhow!(
+            "compress_analyzed_resources requires analysis_sample_size"
+        ));
+    }
+
+    let sizes = builder.resource_size_report()?;
+    warn!(
+        logger,
+        "{}",
+        target_label(
+            target,
+            &format!(
+                "{} bytes of resource payloads ({}); largest: {}",
+                sizes.total_bytes,
+                sizes
+                    .by_kind
+                    .iter()
+                    .map(|(kind, bytes)| format!("{} {} bytes", kind, bytes))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                sizes
+                    .largest(5)
+                    .iter()
+                    .map(|(name, bytes)| format!("{} {} bytes", name, bytes))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        )
+    );
+    report
+        .resource_sizes
+        .insert(target.unwrap_or("").to_string(), sizes);
+
+    let context = profile(
+        report,
+        &target_label(target, "generating embedded context"),
+        |_| builder.to_embedded_python_context(logger, &spec.opt_level, &spec.features),
+    )?;
+
+    if let Some(statistics) = builder.collection_memory_statistics() {
+        warn!(
+            logger,
+            "{}",
+            target_label(
+                target,
+                &format!(
+                    "at most {} bytes of payloads held in memory; {} payloads ({} bytes) spilled to disk",
+                    statistics.peak_resident_bytes,
+                    statistics.spilled_count,
+                    statistics.spilled_bytes
+                )
+            )
+        );
+        report
+            .collection_memory
+            .insert(target.unwrap_or("").to_string(), statistics);
+    }
+
+    for (role, bytes) in builder.directory_bytes_written() {
+        *report.directory_bytes_written.entry(role).or_insert(0) += bytes;
+    }
+
+    if builder.bytecode_cache().is_some() {
+        let statistics = builder.bytecode_cache_statistics();
+        let total = report
+            .cache_statistics
+            .entry(BYTECODE_CACHE.to_string())
+            .or_default();
+        total.hits += statistics.hits;
+        total.misses += statistics.misses;
+    }
+
+    Ok(context)
+}
+
+/// Execute a `PackagingSpec`, producing an `EmbeddedPythonContext` and a report.
+pub fn execute_spec_with_report(
+    spec: &PackagingSpec,
+    logger: &slog::Logger,
+) -> Result<(EmbeddedPythonContext, SpecExecutionReport)> {
+    let mut report = SpecExecutionReport::default();
+
+    let mut cache_session = start_cache_session(spec)?;
+
+    let mut builder = create_builder(spec, &spec.distribution, None, logger, &mut report)?;
+
+    for (i, step) in spec.steps.iter().enumerate() {
+        let label = format!("step {} ({})", i, step.describe());
+        warn!(logger, "performing {}", label);
+
+        profile(&mut report, &label, |report| {
+            let step_resources =
+                resolve_step(spec, step, builder.as_ref(), &mut cache_session, logger)?;
+            if let Some(tree) = &step_resources.layered_tree {
+                builder.add_layered_tree(tree)?;
+            }
+            let resources = builder.resolve_distribution_versions(step_resources.resources)?;
+
+            for resource in &resources {
+                add_python_resource(builder.as_mut(), resource, &mut report.warnings)?;
+            }
+
+            Ok(())
+        })?;
+    }
+
+    let context = finish_builder(spec, builder.as_mut(), None, logger, &mut report)?;
+
+    finish_cache_session(spec, cache_session, logger, &mut report)?;
+
+    for warning in &report.warnings {
+        warn!(logger, "{}", warning);
+    }
+
+    Ok((context, report))
+}
+
+/// Execute a `PackagingSpec`, producing an `EmbeddedPythonContext`.
+pub fn execute_spec(spec: &PackagingSpec, logger: &slog::Logger) -> Result<EmbeddedPythonContext> {
+    Ok(execute_spec_with_report(spec, logger)?.0)
+}
+
+/// A Python distribution to build for in a multi-target build.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct VersionTargetSpec {
+    /// Name identifying the target, e.g. `3.9`.
+    pub name: String,
+
+    /// The Python distribution of the target.
+    pub distribution: DistributionSpec,
+}
+
+/// Describes building resources for multiple Python distributions at once.
+///
+/// Acquisition steps are performed once with `spec.distribution`. Resources
+/// that don't depend on the Python version, like pure Python modules and
+/// data files, are pooled and added to every target. Steps that acquire
+/// version specific resources, like extension modules, are performed again
+/// for each target. Bytecode is compiled by each target's distribution.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MultiTargetSpec {
+    /// Settings shared by all targets.
+    pub spec: PackagingSpec,
+
+    /// Distributions to build for.
+    pub targets: Vec<VersionTargetSpec>,
+}
+
+/// Resources acquired once for all targets of a multi-target build.
+#[derive(Clone, Debug, Default)]
+pub struct AcquisitionPool {
+    /// Version independent resources, added to every target.
+    pub shared_resources: Vec<PythonResource>,
+
+    /// Steps that acquired version specific resources.
+    ///
+    /// These are performed again with the builder of each target.
+    pub per_version_steps: Vec<AcquisitionStep>,
+
+    /// How layered package roots of pooled resources were merged.
+    pub layered_trees: Vec<LayeredTree>,
+}
+
+impl AcquisitionPool {
+    /// Names of pooled resources.
+    pub fn shared_resource_names(&self) -> BTreeSet<String> {
+        self.shared_resources
+            .iter()
+            .map(|resource| resource.full_name())
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+/// The result of building one target of a multi-target build.
+pub struct TargetBuild {
+    /// Name of the target.
+    pub name: String,
+
+    /// Bytecode cache tag of the target's Python distribution.
+    pub cache_tag: String,
+
+    /// Names of resources acquired for this target alone.
+    pub per_version_resources: BTreeSet<String>,
+
+    /// The embedded Python context of the target.
+    pub context: EmbeddedPythonContext,
+}
+
+/// The result of executing a `MultiTargetSpec`.
+pub struct MultiTargetBuild {
+    /// Names of resources acquired once and shared by all targets.
+    pub shared_resources: BTreeSet<String>,
+
+    /// Descriptions of steps performed for each target.
+    pub per_version_steps: Vec<String>,
+
+    /// Each built target, in the order they were defined.
+    pub targets: Vec<TargetBuild>,
+
+    /// Warnings and timings of the whole build.
+    pub report: SpecExecutionReport,
+}
+
+/// Whether a resource depends on the Python version it was acquired with.
+fn is_version_specific(resource: &PythonResource) -> bool {
+    match resource {
+        PythonResource::ModuleBytecode(_)
+        | PythonResource::ExtensionModuleDynamicLibrary(_)
+        | PythonResource::ExtensionModuleStaticallyLinked(_) => true,
+        PythonResource::ModuleSource(_)
+        | PythonResource::ModuleBytecodeRequest(_)
+        | PythonResource::Resource(_)
+        | PythonResource::DistributionResource(_)
+        | PythonResource::EggFile(_)
+        | PythonResource::PathExtension(_) => false,
+    }
+}
+
+/// Obtain a copy of a resource using a different bytecode cache tag.
+fn with_cache_tag(resource: &PythonResource, cache_tag: &str) -> PythonResource {
+    match resource {
+        PythonResource::ModuleSource(m) => PythonResource::ModuleSource(PythonModuleSource {
+            cache_tag: cache_tag.to_string(),
+            ..m.clone()
+        }),
+        PythonResource::ModuleBytecodeRequest(m) => {
+            PythonResource::ModuleBytecodeRequest(PythonModuleBytecodeFromSource {
+                cache_tag: cache_tag.to_string(),
+                ..m.clone()
+            })
+        }
+        resource => resource.clone(),
+    }
+}
+
+/// Perform the acquisition steps of a spec once, pooling their resources.
+///
+/// A step acquiring any version specific resource isn't pooled. It is
+/// recorded to be performed again for each target instead. Steps running
+/// pip aren't performed here at all, since what they acquire depends on
+/// the Python version of each target.
+pub fn acquire_shared_resources(
+    spec: &PackagingSpec,
+    builder: &dyn PythonBinaryBuilder,
+    cache_session: &mut Option<CacheSession>,
+    logger: &slog::Logger,
+    report: &mut SpecExecutionReport,
+) -> Result<AcquisitionPool> {
+    let mut pool = AcquisitionPool::default();
+
+    for (i, step) in spec.steps.iter().enumerate() {
+        let label = format!("step {} ({})", i, step.describe());
+
+        if step.is_version_dependent() {
+            warn!(
+                logger,
+                "{} depends on the Python version; it will be performed for each target", label
+            );
+            pool.per_version_steps.push(step.clone());
+            continue;
+        }
+
+        warn!(logger, "performing {}", label);
+
+        let step_resources = profile(report, &label, |_| {
+            resolve_step(spec, step, builder, cache_session, logger)
+        })?;
+        let resources = step_resources.resources;
+
+        if resources.iter().any(is_version_specific) {
+            warn!(
+                logger,
+                "{} acquired version specific resources; it will be performed for each target",
+                label
+            );
+            pool.per_version_steps.push(step.clone());
+        } else {
+            pool.shared_resources.extend(resources);
+            pool.layered_trees.extend(step_resources.layered_tree);
+        }
+    }
+
+    Ok(pool)
+}
+
+/// Build a target of a multi-target build from pooled resources.
+///
+/// Pooled resources are added with the builder's bytecode cache tag and
+/// per-version steps are performed with the builder.
+pub fn build_target(
+    spec: &PackagingSpec,
+    pool: &AcquisitionPool,
+    name: &str,
+    builder: &mut dyn PythonBinaryBuilder,
+    cache_session: &mut Option<CacheSession>,
+    logger: &slog::Logger,
+    report: &mut SpecExecutionReport,
+) -> Result<TargetBuild> {
+    let cache_tag = builder.cache_tag().to_string();
+
+    profile(
+        report,
+        &target_label(Some(name), "adding shared resources"),
+        |report| {
+            let resources = pool
+                .shared_resources
+                .iter()
+                .map(|resource| with_cache_tag(resource, &cache_tag))
+                .collect();
+
+            for resource in builder.resolve_distribution_versions(resources)? {
+                add_python_resource(builder, &resource, &mut report.warnings)?;
+            }
+
+            for tree in &pool.layered_trees {
+                builder.add_layered_tree(tree)?;
+            }
+
+            Ok(())
+        },
+    )?;
+
+    let mut per_version_resources = BTreeSet::new();
+    for step in &pool.per_version_steps {
+        let label = target_label(Some(name), &step.describe());
+        warn!(logger, "performing {}", label);
+
+        profile(report, &label, |report| {
+            let step_resources = resolve_step(spec, step, builder, cache_session, logger)?;
+            if let Some(tree) = &step_resources.layered_tree {
+                builder.add_layered_tree(tree)?;
+            }
+
+            for resource in builder.resolve_distribution_versions(step_resources.resources)? {
+                add_python_resource(builder, &resource, &mut report.warnings)?;
+                per_version_resources.insert(resource.full_name());
+            }
+
+            Ok(())
+        })?;
+    }
+    per_version_resources.remove("");
+
+    let context = finish_builder(spec, builder, Some(name), logger, report)?;
+
+    Ok(TargetBuild {
+        name: name.to_string(),
+        cache_tag,
+        per_version_resources,
+        context,
+    })
+}
+
+/// Execute a `MultiTargetSpec`, producing an `EmbeddedPythonContext` per target.
+pub fn execute_multi_target_spec(
+    spec: &MultiTargetSpec,
+    logger: &slog::Logger,
+) -> Result<MultiTargetBuild> {
+    if spec.targets.is_empty() {
+        return Err(anyhow!("multi-target spec defines no targets"));
+    }
+
+    let mut report = SpecExecutionReport::default();
+
+    let mut cache_session = start_cache_session(&spec.spec)?;
+
+    let pool = {
+        let builder = create_builder(
+            &spec.spec,
+            &spec.spec.distribution,
+            None,
+            logger,
+            &mut report,
+        )?;
+
+        acquire_shared_resources(
+            &spec.spec,
+            builder.as_ref(),
+            &mut cache_session,
+            logger,
+            &mut report,
+        )?
+    };
+
+    let mut targets = vec![];
+    for target in &spec.targets {
+        let mut builder = create_builder(
+            &spec.spec,
+            &target.distribution,
+            Some(target.name.as_str()),
+            logger,
+            &mut report,
+        )?;
+
+        targets.push(build_target(
+            &spec.spec,
+            &pool,
+            &target.name,
+            builder.as_mut(),
+            &mut cache_session,
+            logger,
+            &mut report,
+        )?);
+    }
+
+    finish_cache_session(&spec.spec, cache_session, logger, &mut report)?;
+
+    for warning in &report.warnings {
+        warn!(logger, "{}", warning);
+    }
+
+    Ok(MultiTargetBuild {
+        shared_resources: pool.shared_resource_names(),
+        per_version_steps: pool
+            .per_version_steps
+            .iter()
+            .map(|step| step.describe())
+            .collect(),
+        targets,
+        report,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::py_packaging::build_directories::RetentionPolicy,
+        crate::py_packaging::resource_analysis::DEFAULT_SAMPLE_SIZE,
+        crate::py_packaging::standalone_builder::{
+            tests::StandalonePythonExecutableBuilderOptions, StandalonePythonExecutableBuilder,
+        },
+        crate::testutil::*,
+        python_packaging::resource::{DataLocation, PythonPackageResource},
+        python_packed_resources::data::{ResourceField, HEADER_V2},
+        python_packed_resources::parser::load_resources,
+        std::sync::Arc,
+    };
+
+    #[test]
+    fn test_deserialize_spec() -> Result<()> {
+        let spec: PackagingSpec = serde_json::from_str(
+            r#"{
+                "name": "myapp",
+                "distribution": {"flavor": "standalone_static", "dest_dir": "dists"},
+                "policy": {"resources_policy": "filesystem-relative-only:lib"},
+                "steps": [
+                    {"type": "pip_install", "args": ["black"]},
+                    {"type": "wheel", "path": "foo.whl"},
+                    {"type": "package_root", "path": "src", "packages": ["foo"]},
+                    {"type": "layered_package_roots", "layers": ["base", "overlay"], "packages": ["foo"]}
+                ],
+                "config": {"site_import": true},
+                "directories": {"scratch_dir": "/scratch", "retention": "keep-on-failure"},
+                "clock": {"fixed": 1600000000}
+            }"#,
+        )?;
+
+        assert_eq!(
+            spec.distribution.flavor,
+            DistributionFlavor::StandaloneStatic
+        );
+        assert_eq!(spec.distribution.location, None);
+        assert_eq!(spec.libpython_link_mode, BinaryLibpythonLinkMode::Default);
+        assert_eq!(spec.policy.resources_policy, "filesystem-relative-only:lib");
+        assert_eq!(spec.policy.extension_module_filter, "all");
+        assert_eq!(spec.steps.len(), 4);
+        assert_eq!(
+            spec.steps[2],
+            AcquisitionStep::PackageRoot {
+                path: PathBuf::from("src"),
+                packages: vec!["foo".to_string()],
+                bytecode_only: false,
+            }
+        );
+        assert_eq!(
+            spec.steps[3],
+            AcquisitionStep::LayeredPackageRoots {
+                layers: vec![PathBuf::from("base"), PathBuf::from("overlay")],
+                packages: vec!["foo".to_string()],
+            }
+        );
+        assert!(spec.config.site_import);
+        assert!(spec.config.isolated);
+        assert_eq!(spec.opt_level, "0");
+        assert_eq!(spec.analysis_sample_size, None);
+        assert!(!spec.compress_analyzed_resources);
+        assert_eq!(spec.cache, None);
+        assert_eq!(
+            spec.directories,
+            BuildDirectories {
+                scratch_dir: Some(PathBuf::from("/scratch")),
+                retention: RetentionPolicy::KeepOnFailure,
+                ..BuildDirectories::default()
+            }
+        );
+        assert_eq!(spec.clock, BuildClock::Fixed(1_600_000_000));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_execute_spec_step_failure() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let spec = PackagingSpec {
+            name: "myapp".to_string(),
+            host_triple: None,
+            target_triple: None,
+            host_python_exe: None,
+            distribution: DistributionSpec {
+                flavor: DistributionFlavor::Standalone,
+                location: None,
+                dest_dir: temp_dir.path().to_path_buf(),
+            },
+            libpython_link_mode: BinaryLibpythonLinkMode::Default,
+            policy: PolicySpec {
+                resources_policy: "invalid".to_string(),
+                ..PolicySpec::default()
+            },
+            steps: vec![],
+            filter: None,
+            config: EmbeddedPythonConfig::default(),
+            feature_tags: BTreeMap::new(),
+            features: BTreeSet::new(),
+            opt_level: "0".to_string(),
+            analysis_sample_size: None,
+            compress_analyzed_resources: false,
+            cache: None,
+            directories: BuildDirectories::default(),
+            clock: BuildClock::Fixed(0),
+            workspace_root: None,
+        };
+
+        let err = execute_spec(&spec, &logger).err().unwrap();
+        assert_eq!(err.to_string(), "creating binary builder failed");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_failure_cache() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let requirements = temp_dir.path().join("requirements.txt");
+        std::fs::write(&requirements, "foo==9.9\n")?;
+
+        let mut spec = PackagingSpec {
+            name: "myapp".to_string(),
+            host_triple: None,
+            target_triple: None,
+            host_python_exe: None,
+            distribution: DistributionSpec {
+                flavor: DistributionFlavor::Standalone,
+                location: None,
+                dest_dir: temp_dir.path().to_path_buf(),
+            },
+            libpython_link_mode: BinaryLibpythonLinkMode::Default,
+            policy: PolicySpec::default(),
+            steps: vec![],
+            filter: None,
+            config: EmbeddedPythonConfig::default(),
+            feature_tags: BTreeMap::new(),
+            features: BTreeSet::new(),
+            opt_level: "0".to_string(),
+            analysis_sample_size: None,
+            compress_analyzed_resources: false,
+            cache: Some(CacheSpec {
+                dir: Some(temp_dir.path().join("cache")),
+                gc: GcPolicy::default(),
+                failure_ttl_seconds: 60,
+                retry_failures: false,
+                bytecode: true,
+            }),
+            directories: BuildDirectories::default(),
+            clock: BuildClock::Fixed(1000),
+            workspace_root: None,
+        };
+        let cache = spec.cache.clone().unwrap();
+
+        let builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        let step = AcquisitionStep::PipInstall {
+            args: vec!["-r".to_string(), format!("{}", requirements.display())],
+            extra_envs: HashMap::new(),
+            verbose: false,
+        };
+        let key = step_failure_key(&spec, &step, builder.as_ref())?.unwrap();
+
+        // Any change to the inputs of the step changes the key.
+        std::fs::write(&requirements, "foo==9.8\n")?;
+        assert_ne!(
+            step_failure_key(&spec, &step, builder.as_ref())?.unwrap(),
+            key
+        );
+        std::fs::write(&requirements, "foo==9.9\n")?;
+        assert_eq!(
+            step_failure_key(&spec, &step, builder.as_ref())?.unwrap(),
+            key
+        );
+
+        let mut other = step.clone();
+        if let AcquisitionStep::PipInstall { extra_envs, .. } = &mut other {
+            extra_envs.insert("PIP_INDEX_URL".to_string(), "http://mirror".to_string());
+        }
+        assert_ne!(
+            step_failure_key(&spec, &other, builder.as_ref())?.unwrap(),
+            key
+        );
+
+        let root = AcquisitionStep::Virtualenv {
+            path: temp_dir.path().to_path_buf(),
+        };
+        assert_eq!(step_failure_key(&spec, &root, builder.as_ref())?, None);
+
+        // Identical workspaces in different directories share keys if the
+        // workspace root is declared.
+        let other_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let other_requirements = other_dir.path().join("requirements.txt");
+        std::fs::write(&other_requirements, "foo==9.9\n")?;
+        let other_step = AcquisitionStep::PipInstall {
+            args: vec![
+                "-r".to_string(),
+                format!("{}", other_requirements.display()),
+            ],
+            extra_envs: HashMap::new(),
+            verbose: false,
+        };
+        assert_ne!(
+            step_failure_key(&spec, &other_step, builder.as_ref())?.unwrap(),
+            key
+        );
+
+        let mut rooted = spec.clone();
+        rooted.workspace_root = Some(temp_dir.path().to_path_buf());
+        let rooted_key = step_failure_key(&rooted, &step, builder.as_ref())?.unwrap();
+        rooted.workspace_root = Some(other_dir.path().to_path_buf());
+        assert_eq!(
+            step_failure_key(&rooted, &other_step, builder.as_ref())?.unwrap(),
+            rooted_key
+        );
+
+        let mut session = start_cache_session(&spec)?;
+        let failure = CachedStepFailure {
+            step: step.describe(),
+            failure: PipFailure::MissingPackage {
+                requirement: "foo==9.9".to_string(),
+                available_versions: vec!["1.0".to_string()],
+            },
+            failed_at: 1000,
+        };
+        cache_step_failure(session.as_mut().unwrap(), &key, &failure)?;
+
+        // The step isn't performed again while the failure is remembered.
+        let err = resolve_step(&spec, &step, builder.as_ref(), &mut session, &logger).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "{} failed in a previous build with identical inputs: \
+                 no version of foo==9.9 is available (available versions: 1.0); \
+                 set cache.retry_failures to perform it again or remove {}",
+                step.describe(),
+                temp_dir
+                    .path()
+                    .join("cache")
+                    .join(STEP_FAILURES_CACHE)
+                    .join(&key)
+                    .display()
+            )
+        );
+
+        spec.clock = BuildClock::Fixed(1060);
+        assert_eq!(
+            cached_step_failure(&spec, &cache, session.as_mut().unwrap(), &key)?
+                .map(|(_, failure)| failure),
+            Some(failure)
+        );
+
+        spec.clock = BuildClock::Fixed(1061);
+        assert_eq!(
+            cached_step_failure(&spec, &cache, session.as_mut().unwrap(), &key)?,
+            None
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pip_cache() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let cache = BuildCache::new(temp_dir.path())?;
+        let mut session = cache.session()?;
+
+        let step = AcquisitionStep::PipInstall {
+            args: vec!["foo".to_string()],
+            extra_envs: HashMap::new(),
+            verbose: false,
+        };
+
+        let cache_dir = cache.entry_path(PIP_CACHE, PIP_CACHE_KEY);
+        match with_pip_cache(&step, &mut session)? {
+            Some(AcquisitionStep::PipInstall { extra_envs, .. }) => assert_eq!(
+                extra_envs.get("PIP_CACHE_DIR"),
+                Some(&format!("{}", cache_dir.display()))
+            ),
+            _ => panic!("expected a pip step"),
+        }
+        assert!(cache_dir.is_dir());
+
+        // Later steps reuse the directory.
+        assert!(with_pip_cache(&step, &mut session)?.is_some());
+        assert_eq!(
+            session.statistics()[PIP_CACHE],
+            CacheStatistics { hits: 1, misses: 1 }
+        );
+
+        // What pip stores counts towards the size of the build cache.
+        std::fs::write(cache_dir.join("wheel"), vec![0; 100])?;
+        session.insert(PIP_CACHE, PIP_CACHE_KEY)?;
+        assert_eq!(cache.manifest()?.caches[PIP_CACHE][PIP_CACHE_KEY].size, 100);
+
+        // Steps configuring pip's cache themselves are left alone.
+        let mut extra_envs = HashMap::new();
+        extra_envs.insert("PIP_NO_CACHE_DIR".to_string(), "1".to_string());
+        let uncached = AcquisitionStep::Wheel {
+            path: PathBuf::from("foo.whl"),
+            extra_envs,
+            verbose: false,
+        };
+        assert!(with_pip_cache(&uncached, &mut session)?.is_none());
+
+        let virtualenv = AcquisitionStep::Virtualenv {
+            path: PathBuf::from("venv"),
+        };
+        assert!(with_pip_cache(&virtualenv, &mut session)?.is_none());
+
+        Ok(())
+    }
+    #[test]
+    fn test_multi_target() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let root = temp_dir.path().join("src");
+        std::fs::create_dir_all(root.join("foo"))?;
+        std::fs::write(root.join("foo").join("__init__.py"), "import os\n")?;
+
+        let spec = PackagingSpec {
+            name: "myapp".to_string(),
+            host_triple: None,
+            target_triple: None,
+            host_python_exe: None,
+            distribution: DistributionSpec {
+                flavor: DistributionFlavor::Standalone,
+                location: None,
+                dest_dir: temp_dir.path().to_path_buf(),
+            },
+            libpython_link_mode: BinaryLibpythonLinkMode::Default,
+            policy: PolicySpec {
+                resources_policy: "filesystem-relative-only:lib".to_string(),
+                ..PolicySpec::default()
+            },
+            steps: vec![AcquisitionStep::PackageRoot {
+                path: root,
+                packages: vec!["foo".to_string()],
+                bytecode_only: false,
+            }],
+            filter: None,
+            config: EmbeddedPythonConfig::default(),
+            feature_tags: BTreeMap::new(),
+            features: BTreeSet::new(),
+            opt_level: "0".to_string(),
+            analysis_sample_size: None,
+            compress_analyzed_resources: false,
+            cache: None,
+            directories: BuildDirectories::default(),
+            clock: BuildClock::Fixed(0),
+            workspace_root: None,
+        };
+
+        let options = StandalonePythonExecutableBuilderOptions {
+            resources_policy: PythonResourcesPolicy::FilesystemRelativeOnly("lib".to_string()),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let mut first = options.new_builder()?;
+
+        // Only one Python version is available to tests. Emulate another
+        // by changing the cache tag of the distribution.
+        let mut dist = (**get_default_distribution()?).clone();
+        dist.cache_tag = "cpython-99".to_string();
+        let mut second = StandalonePythonExecutableBuilder::from_distribution(
+            Arc::new(Box::new(dist)),
+            env!("HOST").to_string(),
+            env!("HOST").to_string(),
+            "myapp".to_string(),
+            BinaryLibpythonLinkMode::Default,
+            first.python_packaging_policy().clone(),
+            EmbeddedPythonConfig::default(),
+            BuildDirectories::default(),
+        )?;
+
+        let mut report = SpecExecutionReport::default();
+        let pool =
+            acquire_shared_resources(&spec, first.as_ref(), &mut None, &logger, &mut report)?;
+        assert!(pool.per_version_steps.is_empty());
+        assert_eq!(
+            pool.shared_resource_names(),
+            vec!["foo".to_string()].into_iter().collect()
+        );
+
+        // Steps running pip are deferred to each target without being performed.
+        let pip_spec = PackagingSpec {
+            steps: vec![AcquisitionStep::Wheel {
+                path: temp_dir.path().join("missing.whl"),
+                extra_envs: HashMap::new(),
+                verbose: false,
+            }],
+            ..spec.clone()
+        };
+        let pip_pool =
+            acquire_shared_resources(&pip_spec, first.as_ref(), &mut None, &logger, &mut report)?;
+        assert!(pip_pool.shared_resources.is_empty());
+        assert_eq!(pip_pool.per_version_steps, pip_spec.steps);
+
+        let first = build_target(
+            &spec,
+            &pool,
+            "3.8",
+            first.as_mut(),
+            &mut None,
+            &logger,
+            &mut report,
+        )?;
+        let second = build_target(
+            &spec,
+            &pool,
+            "99",
+            second.as_mut(),
+            &mut None,
+            &logger,
+            &mut report,
+        )?;
+        assert!(first.per_version_resources.is_empty());
+        assert!(second.per_version_resources.is_empty());
+        assert_ne!(first.cache_tag, second.cache_tag);
+
+        let file = |build: &TargetBuild, path: &str| {
+            build
+                .context
+                .extra_files
+                .entries()
+                .find(|(p, _)| *p == Path::new(path))
+                .map(|(_, content)| content.data.resolve().unwrap())
+        };
+
+        let source = file(&first, "lib/foo/__init__.py");
+        assert_eq!(source, Some(b"import os\n".to_vec()));
+        assert_eq!(file(&second, "lib/foo/__init__.py"), source);
+
+        let first_bytecode = format!("lib/foo/__pycache__/__init__.{}.pyc", first.cache_tag);
+        let second_bytecode = "lib/foo/__pycache__/__init__.cpython-99.pyc";
+        assert!(file(&first, &first_bytecode).is_some());
+        assert!(file(&first, second_bytecode).is_none());
+        assert!(file(&second, second_bytecode).is_some());
+        assert!(file(&second, &first_bytecode).is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_analyzed_resources() -> Result<()> {
+        let logger = get_logger()?;
+
+        let spec = PackagingSpec {
+            name: "myapp".to_string(),
+            host_triple: None,
+            target_triple: None,
+            host_python_exe: None,
+            distribution: DistributionSpec {
+                flavor: DistributionFlavor::Standalone,
+                location: None,
+                dest_dir: PathBuf::from("dists"),
+            },
+            libpython_link_mode: BinaryLibpythonLinkMode::Default,
+            policy: PolicySpec::default(),
+            steps: vec![],
+            filter: None,
+            config: EmbeddedPythonConfig::default(),
+            feature_tags: BTreeMap::new(),
+            features: BTreeSet::new(),
+            opt_level: "0".to_string(),
+            analysis_sample_size: Some(DEFAULT_SAMPLE_SIZE),
+            compress_analyzed_resources: true,
+            cache: None,
+            directories: BuildDirectories::default(),
+            clock: BuildClock::Fixed(0),
+            workspace_root: None,
+        };
+
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        // Bytes of a xorshift generator don't compress.
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let random = (0..16384)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+
+        let mut add = |package: &str, data: Vec<u8>| {
+            builder.add_python_package_resource(
+                &PythonPackageResource {
+                    leaf_package: package.to_string(),
+                    relative_name: "data".to_string(),
+                    data: DataLocation::Memory(data),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )
+        };
+        add("compressible", b"compressible ".repeat(1024))?;
+        add("incompressible", random)?;
+
+        let mut report = SpecExecutionReport::default();
+        let context = finish_builder(&spec, builder.as_mut(), None, &logger, &mut report)?;
+
+        // Compressed payloads need version 2 of packed resources.
+        assert_eq!(&context.resources[0..8], HEADER_V2);
+
+        let resources = load_resources(&context.resources)
+            .map_err(|e| anyhow!(e))?
+            .map(|resource| resource.map_err(|e| anyhow!(e)))
+            .collect::<Result<Vec<_>>>()?;
+        let resource = |name: &str| {
+            resources
+                .iter()
+                .find(|resource| resource.name == name)
+                .unwrap()
+        };
+
+        let compressible = resource("compressible");
+        assert_eq!(
+            compressible
+                .compressed_payloads
+                .iter()
+                .map(|payload| (payload.field, payload.name.as_ref()))
+                .collect::<Vec<_>>(),
+            vec![(ResourceField::InMemoryResourcesData, "data")]
+        );
+        assert!(compressible.in_memory_package_resources.as_ref().unwrap()["data"].len() < 1024);
+
+        let incompressible = resource("incompressible");
+        assert!(incompressible.compressed_payloads.is_empty());
+        assert_eq!(
+            incompressible.in_memory_package_resources.as_ref().unwrap()["data"].len(),
+            16384
+        );
+
+        Ok(())
+    }
+}
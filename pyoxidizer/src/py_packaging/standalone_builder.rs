@@ -3,34 +3,93 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use {
+    super::analysis_verdicts::{AnalysisVerdicts, VerdictReport},
+    super::architecture::{
+        target_architectures, thin_extension_module, validate_binary_architecture,
+        validate_extension_module_architecture,
+    },
+    super::audit::{
+        audit_distributions, collected_distributions, load_osv_advisories, AuditReport,
+    },
     super::binary::{
-        EmbeddedPythonContext, LibpythonLinkMode, PythonBinaryBuilder, PythonLinkingInfo,
+        verify_embedded_context, AppliedPatch, EmbeddedPythonContext, ExtensionDecision,
+        ExtensionModuleOutcome, ExtensionModuleRule, LayeredResources, LibpythonLinkMode,
+        PackedResourcesEmbedding, PythonBinaryBuilder, PythonLinkingInfo,
     },
-    super::config::{EmbeddedPythonConfig, RawAllocator},
+    super::build_cache::{CacheStatistics, GcReport},
+    super::build_clock::BuildClock,
+    super::build_diagnostics::{BuildPhase, DiagnosticsRecorder, FailureClass},
+    super::build_directories::{BuildDirectories, DirectoryRole, DirectoryUsage, ScratchDir},
+    super::bytecode_cache::{BytecodeCache, CachingBytecodeCompiler, BYTECODE_CACHE},
+    super::config::{
+        validate_embedded_python_config, validate_runtime_env, validate_subinterpreter_extensions,
+        ConfigProblemSeverity, EmbeddedPythonConfig, RawAllocator, RunMode, RuntimeEnvRegistry,
+    },
+    super::debug_companion::DebugCompanion,
     super::distribution::{BinaryLibpythonLinkMode, PythonDistribution},
+    super::distribution_coverage::{DistributionCoverage, DistributionInventory},
+    super::dunder_file::verify_dunder_file,
+    super::entry_points::{
+        console_scripts_module_source, find_console_scripts, find_entry_point_providers,
+        EntryPointProvider, EntryPointReport,
+    },
     super::filtering::{filter_btreemap, resolve_resource_names_from_files},
+    super::install_layout::{find_install_collisions, InstallLayout, InstallSource},
     super::libpython::{link_libpython, LibPythonBuildContext},
-    super::packaging_tool::{find_resources, pip_install, read_virtualenv, setup_py_install},
+    super::link_record::{
+        LinkInput, LinkInputKind, LinkLibrary, LinkLibraryKind, LinkRecord, LinkUnit,
+    },
+    super::packaging_tool::{
+        find_bytecode_resources, find_resources, pip_install, read_virtualenv, setup_py_install,
+    },
+    super::payload_storage::PayloadStorage,
+    super::pinning::ContentPins,
+    super::pyembed::{derive_runtime_env, RecoverySelection},
+    super::resource_explanation::{
+        explain_collected_resource, explainable_names, suggest_names, ResourceExplanation,
+    },
+    super::site_packages_shim::verify_site_packages_usage,
     super::standalone_distribution::StandaloneDistribution,
-    crate::app_packaging::resource::{FileContent, FileManifest},
-    anyhow::{anyhow, Result},
+    super::syntax_compatibility::scan_syntax_compatibility,
+    super::update_package::build_manifest,
+    crate::app_packaging::resource::{FileConflictPolicy, FileContent, FileManifest},
+    anyhow::{anyhow, Context, Result},
     lazy_static::lazy_static,
-    python_packaging::bytecode::BytecodeCompiler,
+    python_packaging::bytecode::{
+        python_version_from_cache_tag, BytecodeCompiler, BytecodeCompilerOptions, BytecodeHeader,
+        CompileErrorPolicy, CompilerPool, PycTimestamp, PythonBytecodeCompiler,
+    },
+    python_packaging::filter_file::FilterFile,
+    python_packaging::layering::{LayerDeletion, LayerOverride, LayeredTree},
+    python_packaging::module_util::packages_from_module_name,
+    python_packaging::patch::PackagePatch,
     python_packaging::policy::{PythonPackagingPolicy, PythonResourcesPolicy},
+    python_packaging::python_source::has_dunder_file,
     python_packaging::resource::{
-        BytecodeOptimizationLevel, DataLocation, PythonExtensionModule,
+        select_resources_in_packages, BytecodeOptimizationLevel, DataLocation, DigestAlgorithm,
+        LibraryDependency, PythonExtensionModule, PythonModuleBytecode,
         PythonModuleBytecodeFromSource, PythonModuleSource, PythonPackageDistributionResource,
         PythonPackageResource, PythonResource,
     },
     python_packaging::resource_collection::{
-        ConcreteResourceLocation, PrePackagedResource, PythonResourceCollector,
+        prepackaged_resources_from_packed_resources, BytecodeSizeReport,
+        CollectionMemoryStatistics, CompileReport, CompiledResourcesCollection,
+        ConcreteResourceLocation, PackagePatchOutcome, PrePackagedResource,
+        PythonModuleBytecodeProvider, PythonResourceCollector, RelocationConstraints,
+        RelocationReport, ResourceSizeReport, SyntheticVisibility,
     },
-    slog::warn,
-    std::collections::{BTreeMap, HashMap},
+    python_packaging::resource_usage::UsageReport,
+    python_packaging::scripts::process_scripts,
+    python_packaging::test_classification::{classify_test_resources, TestClassificationOverrides},
+    python_packaging::wheel::{write_wheel, WheelTags},
+    python_packed_resources::data::{ResourceFlavor, ResourceSet, IMPORT_TELEMETRY_RESOURCE_NAME},
+    python_packed_resources::extract::PayloadKind,
+    slog::{info, warn},
+    std::borrow::Cow,
+    std::collections::{BTreeMap, BTreeSet, HashMap},
     std::io::Write,
     std::path::{Path, PathBuf},
-    std::sync::Arc,
-    tempdir::TempDir,
+    std::sync::{Arc, Mutex},
 };
 
 lazy_static! {
@@ -52,6 +111,128 @@ fn ignored_libraries_for_target(target_triple: &str) -> Vec<&'static str> {
     }
 }
 
+/// Feature synthesizing the module listing console scripts.
+const CONSOLE_SCRIPTS_FEATURE: &str = "console script usage";
+
+/// Name of the module listing console scripts.
+const CONSOLE_SCRIPTS_MODULE: &str = "pyoxidizer_console_scripts";
+
+/// Apply package patches to collected resources.
+///
+/// Returns records of applied patches and descriptions of skipped patches.
+fn apply_package_patches(
+    collector: &mut PythonResourceCollector,
+    patches: &[PackagePatch],
+) -> Result<(Vec<AppliedPatch>, Vec<String>)> {
+    let mut applied = Vec::new();
+    let mut skipped = Vec::new();
+
+    for patch in patches {
+        match collector.apply_package_patch(patch)? {
+            PackagePatchOutcome::DistributionMissing => skipped.push(format!(
+                "patch {} not applied: distribution {} not found",
+                patch.name, patch.distribution
+            )),
+            PackagePatchOutcome::VersionMismatch(version) => skipped.push(format!(
+                "patch {} not applied: it doesn't apply to {} {}",
+                patch.name, patch.distribution, version
+            )),
+            PackagePatchOutcome::InvalidVersion(version) => skipped.push(format!(
+                "patch {} not applied: {} {} isn't a valid version",
+                patch.name, patch.distribution, version
+            )),
+            PackagePatchOutcome::Applied(version) => applied.push(AppliedPatch {
+                name: patch.name.clone(),
+                distribution: patch.distribution.clone(),
+                version,
+                files: patch.files.iter().map(|file| file.path.clone()).collect(),
+            }),
+        }
+    }
+
+    Ok((applied, skipped))
+}
+
+/// Feature synthesizing the resource enabling import telemetry.
+const IMPORT_TELEMETRY_FEATURE: &str = "import telemetry";
+
+/// Resource names claimed by features synthesizing resources.
+///
+/// Names are reserved whether or not their feature is enabled, so enabling
+/// a feature can't break a build that collected a resource of the same name.
+const SYNTHESIZED_RESOURCES: &[(&str, &str, SyntheticVisibility)] = &[
+    (
+        "oxidized_importer",
+        "the built-in oxidized_importer module",
+        SyntheticVisibility::Public,
+    ),
+    (
+        IMPORT_TELEMETRY_RESOURCE_NAME,
+        IMPORT_TELEMETRY_FEATURE,
+        SyntheticVisibility::Internal,
+    ),
+    (
+        CONSOLE_SCRIPTS_MODULE,
+        CONSOLE_SCRIPTS_FEATURE,
+        SyntheticVisibility::Public,
+    ),
+];
+
+/// Construct a resource collector applying a packaging policy.
+///
+/// `spill_dir` receives resource data exceeding the policy's memory budget.
+fn new_resources_collector(
+    policy: &PythonPackagingPolicy,
+    cache_tag: &str,
+    spill_dir: PathBuf,
+) -> Result<PythonResourceCollector> {
+    let mut collector = PythonResourceCollector::new(policy.get_resources_policy(), cache_tag);
+
+    collector.set_strict_library_dependencies(policy.strict_library_dependencies());
+    collector.set_extract_library_dependencies(policy.extract_library_dependencies());
+    collector.set_text_normalization(policy.text_normalization().cloned());
+    collector.set_locale_filter(policy.locale_filter().cloned());
+    collector.set_source_transforms(policy.source_transforms().clone());
+    collector.set_module_package_collision(policy.module_package_collision());
+    collector.set_distribution_version_conflict(policy.distribution_version_conflict());
+    collector.set_pyc_invalidation_mode(policy.pyc_invalidation_mode());
+    collector.set_reproducible(policy.reproducible_build());
+    collector.set_compile_error_policy(policy.compile_error_policy());
+    collector.set_spill_dir(Some(spill_dir));
+    collector.set_memory_budget(policy.collection_memory_budget())?;
+    for (name, feature, visibility) in SYNTHESIZED_RESOURCES {
+        collector.reserve_name(name, feature, *visibility)?;
+    }
+
+    Ok(collector)
+}
+
+/// Obtain the location of resources added without one under a packaging policy.
+fn policy_default_location(policy: &PythonPackagingPolicy) -> ConcreteResourceLocation {
+    match policy.get_resources_policy() {
+        PythonResourcesPolicy::InMemoryOnly
+        | PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(_) => {
+            ConcreteResourceLocation::InMemory
+        }
+        PythonResourcesPolicy::FilesystemRelativeOnly(prefix) => {
+            ConcreteResourceLocation::RelativePath(prefix.clone())
+        }
+    }
+}
+
+/// A secondary resource set loaded instead of the main one at run-time.
+#[derive(Clone, Debug)]
+struct RecoveryResources {
+    /// Policy to apply to added resources.
+    packaging_policy: PythonPackagingPolicy,
+
+    /// Resources of the set.
+    collector: PythonResourceCollector,
+
+    /// How the generated config selects the set.
+    selection: RecoverySelection,
+}
+
 /// A self-contained Python executable before it is compiled.
 #[derive(Clone, Debug)]
 pub struct StandalonePythonExecutableBuilder {
@@ -80,6 +261,9 @@ pub struct StandalonePythonExecutableBuilder {
     /// Python resources to be embedded in the binary.
     resources_collector: PythonResourceCollector,
 
+    /// Resources loaded instead of `resources_collector` when selected at run-time.
+    recovery: Option<RecoveryResources>,
+
     /// Holds state necessary to link libpython.
     core_build_context: LibPythonBuildContext,
 
@@ -89,11 +273,71 @@ pub struct StandalonePythonExecutableBuilder {
     /// to support filtering extensions as part of building.
     extension_build_contexts: BTreeMap<String, LibPythonBuildContext>,
 
+    /// Describes where the Python core's linked files came from.
+    core_link_unit: LinkUnit,
+
+    /// Describes where each extension's linked files came from.
+    ///
+    /// Only entries also present in `extension_build_contexts` are linked.
+    extension_link_units: BTreeMap<String, LinkUnit>,
+
+    /// Records of how each extension module was packaged and why.
+    extension_decisions: BTreeMap<String, ExtensionDecision>,
+
+    /// Shared libraries of extension modules as they were added, before thinning.
+    ///
+    /// Keyed by extension module name. Values are the path within a debug
+    /// companion and the shared library. Only recorded if the packaging
+    /// policy asks for a debug companion.
+    extension_binaries: BTreeMap<String, (PathBuf, DataLocation)>,
+
+    /// Patches to apply to resources of distributions.
+    package_patches: Vec<PackagePatch>,
+
+    /// Files of layered package roots replaced by later layers.
+    layer_overrides: Vec<LayerOverride>,
+
+    /// Files of layered package roots removed by tombstones of later layers.
+    layer_deletions: Vec<LayerDeletion>,
+
+    /// Verdicts of external static analysis about packages.
+    analysis_verdicts: AnalysisVerdicts,
+
+    /// Analysis verdicts disagreeing with heuristics when adding resources.
+    verdict_conflicts: BTreeSet<String>,
+
+    /// Packages never classified as tests.
+    test_classification_overrides: TestClassificationOverrides,
+
+    /// Entry points whose resources were kept because modules load their group.
+    entry_point_protections: Vec<EntryPointProvider>,
+
     /// Configuration of the embedded Python interpreter.
     config: EmbeddedPythonConfig,
 
-    /// Path to python executable that can be invoked at build time.
-    python_exe: PathBuf,
+    /// Path to the python executable of the target distribution.
+    target_python_exe: PathBuf,
+
+    /// Path to the python executable compiling bytecode at build time.
+    host_python_exe: PathBuf,
+
+    /// Directories temporary files are written to.
+    build_directories: BuildDirectories,
+
+    /// Bytes written to temporary directories.
+    directory_usage: DirectoryUsage,
+
+    /// Source of times recorded by the build.
+    build_clock: BuildClock,
+
+    /// Cache bytecode compiled from source is reused from.
+    bytecode_cache: Option<BytecodeCache>,
+
+    /// Hit and miss counts of bytecode cache lookups.
+    bytecode_cache_statistics: Arc<Mutex<CacheStatistics>>,
+
+    /// Where payloads of packed resources are stored.
+    payload_storage: PayloadStorage,
 }
 
 impl StandalonePythonExecutableBuilder {
@@ -106,8 +350,11 @@ impl StandalonePythonExecutableBuilder {
         link_mode: BinaryLibpythonLinkMode,
         packaging_policy: PythonPackagingPolicy,
         config: EmbeddedPythonConfig,
+        build_directories: BuildDirectories,
     ) -> Result<Box<Self>> {
-        let python_exe = distribution.python_exe.clone();
+        build_directories.validate()?;
+
+        let target_python_exe = distribution.python_exe.clone();
         let cache_tag = distribution.cache_tag.clone();
 
         let (supports_static_libpython, supports_dynamic_libpython) =
@@ -146,6 +393,22 @@ impl StandalonePythonExecutableBuilder {
         let supports_in_memory_dynamically_linked_extension_loading =
             distribution.supports_in_memory_dynamically_linked_extension_loading();
 
+        let analysis_verdicts = match packaging_policy.analysis_verdicts() {
+            Some(path) => AnalysisVerdicts::from_path(path)?,
+            None => AnalysisVerdicts::default(),
+        };
+
+        let test_classification_overrides = match packaging_policy.test_classification_overrides() {
+            Some(path) => TestClassificationOverrides::from_path(path)?,
+            None => TestClassificationOverrides::default(),
+        };
+
+        let resources_collector = new_resources_collector(
+            &packaging_policy,
+            &cache_tag,
+            build_directories.root(DirectoryRole::Spill),
+        )?;
+
         let mut builder = Box::new(Self {
             host_triple,
             target_triple,
@@ -154,14 +417,30 @@ impl StandalonePythonExecutableBuilder {
             link_mode,
             supports_in_memory_dynamically_linked_extension_loading,
             packaging_policy: packaging_policy.clone(),
-            resources_collector: PythonResourceCollector::new(
-                packaging_policy.get_resources_policy(),
-                &cache_tag,
-            ),
+            resources_collector,
+            recovery: None,
             core_build_context: LibPythonBuildContext::default(),
             extension_build_contexts: BTreeMap::new(),
+            core_link_unit: LinkUnit::core(),
+            extension_link_units: BTreeMap::new(),
+            extension_decisions: BTreeMap::new(),
+            extension_binaries: BTreeMap::new(),
+            package_patches: vec![],
+            layer_overrides: vec![],
+            layer_deletions: vec![],
+            analysis_verdicts,
+            verdict_conflicts: BTreeSet::new(),
+            test_classification_overrides,
+            entry_point_protections: vec![],
             config,
-            python_exe,
+            host_python_exe: target_python_exe.clone(),
+            target_python_exe,
+            build_directories,
+            directory_usage: DirectoryUsage::default(),
+            build_clock: BuildClock::default(),
+            bytecode_cache: None,
+            bytecode_cache_statistics: Arc::new(Mutex::new(CacheStatistics::default())),
+            payload_storage: PayloadStorage::default(),
         });
 
         builder.add_distribution_resources(&packaging_policy)?;
@@ -169,13 +448,41 @@ impl StandalonePythonExecutableBuilder {
         Ok(builder)
     }
 
+    /// Obtain the shared libraries and object files backing each extension module.
+    fn extension_module_binaries(&self) -> Result<BTreeMap<String, Vec<Vec<u8>>>> {
+        let mut res = BTreeMap::new();
+
+        for (name, context) in &self.extension_build_contexts {
+            res.insert(
+                name.clone(),
+                context
+                    .object_files
+                    .iter()
+                    .map(|location| location.resolve())
+                    .collect::<Result<Vec<_>>>()?,
+            );
+        }
+
+        for (name, resource) in self.resources_collector.iter_resources() {
+            if let Some(location) = &resource.in_memory_extension_module_shared_library {
+                res.insert(name.clone(), vec![location.resolve()?]);
+            } else if let Some((_, location)) =
+                &resource.relative_path_extension_module_shared_library
+            {
+                res.insert(name.clone(), vec![location.resolve()?]);
+            }
+        }
+
+        Ok(res)
+    }
+
     fn add_distribution_resources(&mut self, policy: &PythonPackagingPolicy) -> Result<()> {
         self.core_build_context.inittab_cflags = Some(self.distribution.inittab_cflags.clone());
 
         for (name, path) in &self.distribution.includes {
             self.core_build_context
                 .includes
-                .insert(PathBuf::from(name), DataLocation::Path(path.clone()));
+                .insert(PathBuf::from(name), DataLocation::from_path(path.clone()));
         }
 
         // Add the distribution's object files from Python core to linking context.
@@ -188,10 +495,20 @@ impl StandalonePythonExecutableBuilder {
 
             self.core_build_context
                 .object_files
-                .push(DataLocation::Path(fs_path.clone()));
+                .push(DataLocation::from_path(fs_path.clone()));
+            self.core_link_unit.inputs.push(LinkInput::new(
+                LinkInputKind::ObjectFile,
+                &DataLocation::from_path(fs_path.clone()),
+            )?);
         }
 
         for entry in &self.distribution.links_core {
+            if entry.framework || entry.system {
+                if let Some(library) = LinkLibrary::from_dependency(entry) {
+                    self.core_link_unit.libraries.push(library);
+                }
+            }
+
             if entry.framework {
                 self.core_build_context
                     .frameworks
@@ -206,8 +523,8 @@ impl StandalonePythonExecutableBuilder {
 
         for location in self.distribution.libraries.values() {
             let path = match location {
-                DataLocation::Path(p) => p,
-                DataLocation::Memory(_) => {
+                DataLocation::Path { path: p, .. } => p,
+                _ => {
                     return Err(anyhow!(
                         "cannot link libraries not backed by the filesystem"
                     ))
@@ -226,6 +543,10 @@ impl StandalonePythonExecutableBuilder {
             self.core_build_context
                 .system_libraries
                 .insert("msvcrt".to_string());
+            self.core_link_unit.libraries.push(LinkLibrary {
+                name: "msvcrt".to_string(),
+                kind: LinkLibraryKind::System,
+            });
         }
 
         if let Some(lis) = self.distribution.license_infos.get("python") {
@@ -234,10 +555,24 @@ impl StandalonePythonExecutableBuilder {
                 .insert("python".to_string(), lis.clone());
         }
 
-        for ext in self.packaging_policy.resolve_python_extension_modules(
-            self.distribution.extension_modules.values(),
-            &self.target_triple,
-        )? {
+        for warning in self
+            .packaging_policy
+            .validate_extension_module_variant_pins(self.distribution.extension_modules.values())?
+        {
+            self.resources_collector.add_warning(warning);
+        }
+
+        let mut extension_modules = Vec::new();
+        for variants in self.distribution.extension_modules.values() {
+            let (variants, conflicts) = self.analysis_verdicts.override_licenses(variants);
+            self.verdict_conflicts.extend(conflicts);
+            extension_modules.push(variants);
+        }
+
+        for ext in self
+            .packaging_policy
+            .resolve_python_extension_modules(extension_modules.iter(), &self.target_triple)?
+        {
             self.add_python_extension_module(&ext, None)?;
         }
 
@@ -246,7 +581,7 @@ impl StandalonePythonExecutableBuilder {
                 self.add_python_module_source(&source, None)?;
             }
 
-            let bytecode = source.as_bytecode_module(BytecodeOptimizationLevel::Zero);
+            let bytecode = policy.bytecode_request(&source);
 
             if policy.filter_python_resource(&bytecode.clone().into()) {
                 self.add_python_module_bytecode_from_source(&bytecode, None)?;
@@ -262,14 +597,458 @@ impl StandalonePythonExecutableBuilder {
         Ok(())
     }
 
+    /// Obtain the location of resources added without one.
+    fn default_location(&self) -> ConcreteResourceLocation {
+        policy_default_location(&self.packaging_policy)
+    }
+
+    /// Environment variables the generated config reads at run-time.
+    fn runtime_env(&self) -> RuntimeEnvRegistry {
+        let mut env = derive_runtime_env(&self.config);
+
+        if let Some(recovery) = &self.recovery {
+            recovery.selection.register_runtime_env(&mut env);
+        }
+
+        env
+    }
+
+    /// Mark resources found by a packaging operation as tests.
+    fn classify_tests(
+        &self,
+        logger: &slog::Logger,
+        mut resources: Vec<PythonResource>,
+    ) -> Result<Vec<PythonResource>> {
+        let count = classify_test_resources(&mut resources, &self.test_classification_overrides)?;
+        if count > 0 {
+            warn!(logger, "classified {} resources as tests", count);
+        }
+
+        Ok(resources)
+    }
+
+    /// Obtain the site-packages shim location of a resource, if it belongs there.
+    ///
+    /// Resources of packages in `site_packages_shim_packages` are installed
+    /// only in the shim directory, never in memory.
+    fn site_packages_shim_location(&self, name: &str) -> Option<ConcreteResourceLocation> {
+        let shim = self.config.site_packages_shim.as_ref()?;
+        let package = name.split('.').next().unwrap_or(name);
+
+        if self
+            .config
+            .site_packages_shim_packages
+            .iter()
+            .any(|p| p == package)
+        {
+            Some(ConcreteResourceLocation::RelativePath(shim.clone()))
+        } else {
+            None
+        }
+    }
+
+    /// Obtain the location of a resource added without one, consulting analysis verdicts.
+    ///
+    /// Resources that static analysis says need the filesystem are installed
+    /// on the filesystem if the resources policy allows it. `source` is the
+    /// module source the verdict is checked against, if there is one.
+    fn verdict_location(
+        &mut self,
+        name: &str,
+        source: Option<&DataLocation>,
+    ) -> Result<ConcreteResourceLocation> {
+        if let Some(location) = self.site_packages_shim_location(name) {
+            return Ok(location);
+        }
+
+        if self.analysis_verdicts.needs_filesystem(name) == Some(true) {
+            if let Some(source) = source {
+                if !has_dunder_file(&source.resolve()?)? {
+                    self.verdict_conflicts.insert(format!(
+                        "{}: needs_filesystem is true but the module doesn't reference __file__",
+                        name
+                    ));
+                }
+            }
+
+            if let PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(prefix) =
+                self.packaging_policy.get_resources_policy()
+            {
+                return Ok(ConcreteResourceLocation::RelativePath(prefix.clone()));
+            }
+        }
+
+        Ok(self.default_location())
+    }
+
+    /// Obtain names of resources to keep for entry points loaded by kept modules.
+    ///
+    /// `is_kept` tells whether a resource is kept by a filter. Entry points
+    /// in a group loaded by a kept module are recorded in the build
+    /// manifest. Returns an empty set unless the packaging policy protects
+    /// entry point providers.
+    fn entry_point_protection(
+        &mut self,
+        logger: &slog::Logger,
+        is_kept: impl Fn(&str) -> bool,
+    ) -> Result<BTreeSet<String>> {
+        let mut res = BTreeSet::new();
+
+        if !self.packaging_policy.protect_entry_point_providers() {
+            return Ok(res);
+        }
+
+        let providers = find_entry_point_providers(self.resources_collector.iter_resources())?;
+
+        for (provider, missing) in EntryPointReport::from_providers(&providers, is_kept).filtered {
+            warn!(
+                logger,
+                "keeping {} for entry point {} in group {}",
+                missing.iter().cloned().collect::<Vec<_>>().join(", "),
+                provider.name,
+                provider.group
+            );
+            res.extend(missing);
+
+            if !self.entry_point_protections.contains(&provider) {
+                self.entry_point_protections.push(provider);
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Obtain the overrides of files of layered package roots, by install path.
+    ///
+    /// Only files installed relative to the binary are present.
+    fn layer_override_installs(&self) -> BTreeMap<PathBuf, &LayerOverride> {
+        let mut res = BTreeMap::new();
+
+        if self.layer_overrides.is_empty() {
+            return res;
+        }
+
+        for (_, resource) in self.resources_collector.iter_resources() {
+            for install in resource.relative_path_installs() {
+                let path = install.location.as_ref().and_then(|l| l.path());
+
+                if let Some(o) = self
+                    .layer_overrides
+                    .iter()
+                    .find(|o| path == Some(o.overlay.as_path()))
+                {
+                    res.insert(install.path, o);
+                }
+            }
+        }
+
+        res
+    }
+
+    /// Ensure every file backing a collected resource still exists.
+    ///
+    /// Files are only read when the binary is built, so deleting the
+    /// virtualenv resources were read from breaks the build. Every missing
+    /// file is reported along with the resource owning it.
+    fn verify_all_backing_files(&self, diagnostics: &mut DiagnosticsRecorder) -> Result<()> {
+        let dangling = self.resources_collector.dangling_payloads()?;
+
+        if dangling.is_empty() {
+            return Ok(());
+        }
+
+        diagnostics.blame(FailureClass::Io, dangling.keys().cloned(), vec![]);
+
+        Err(anyhow!(
+            "files backing resources no longer exist: {}",
+            dangling
+                .iter()
+                .flat_map(|(name, payloads)| {
+                    payloads.iter().map(move |(label, location)| {
+                        format!("{} [{}] from {}", name, label, location)
+                    })
+                })
+                .collect::<Vec<_>>()
+                .join("; ")
+        ))
+    }
+
+    /// Whether a resource is left out of module names and reports.
+    fn is_hidden_resource(&self, name: &str) -> bool {
+        self.packaging_policy.hide_internal_resources()
+            && self.resources_collector.is_internal(name)
+    }
+
+    /// Obtain names of resources the binary can't run without.
+    ///
+    /// These are modules to preload or run and extension modules required
+    /// by the distribution.
+    fn required_resource_names(&self) -> BTreeSet<String> {
+        let mut res = self
+            .config
+            .preload_modules
+            .iter()
+            .cloned()
+            .collect::<BTreeSet<_>>();
+
+        // Running a module imports its parent packages and running a
+        // package runs its __main__ module.
+        if let RunMode::Module { module } = &self.config.run_mode {
+            res.extend(packages_from_module_name(module));
+            res.insert(module.clone());
+
+            if self
+                .resources_collector
+                .iter_resources()
+                .any(|(name, resource)| name == module && resource.is_package)
+            {
+                res.insert(format!("{}.__main__", module));
+            }
+        }
+
+        res.extend(
+            self.extension_decisions
+                .values()
+                .filter(|decision| decision.required)
+                .map(|decision| decision.name.clone()),
+        );
+
+        res
+    }
+
+    /// Path a dynamically linked libpython is installed at, if any.
+    fn libpython_install_path(&self) -> Option<PathBuf> {
+        if self.link_mode != LibpythonLinkMode::Dynamic {
+            return None;
+        }
+
+        self.distribution
+            .libpython_shared_library
+            .as_ref()
+            .and_then(|p| p.file_name())
+            .map(PathBuf::from)
+    }
+
+    /// Resolve the install layout of resources in a collector.
+    fn install_layout_for(
+        &self,
+        collector: &PythonResourceCollector,
+        enabled_features: &BTreeSet<String>,
+    ) -> Result<InstallLayout> {
+        let mut layout = InstallLayout::default();
+
+        for install in collector.relative_path_installs_for_features(enabled_features)? {
+            match install.location {
+                Some(location) => {
+                    for (path, _, executable) in process_scripts(
+                        &[(install.path, location, install.executable)],
+                        self.packaging_policy.script_rules(),
+                        &self.target_triple,
+                    )? {
+                        layout.add_file(&path, executable)?;
+                    }
+                }
+                // Bytecode isn't text, so script processing leaves it untouched.
+                None => layout.add_file(&install.path, install.executable)?,
+            }
+        }
+
+        if let Some(path) = self.libpython_install_path() {
+            layout.add_file(&path, false)?;
+        }
+
+        Ok(layout)
+    }
+
+    /// Verify nothing in a collector is installed at the same path as something else.
+    ///
+    /// Every path more than one thing is installed at is reported. If the
+    /// packaging policy allows it, things with identical content installed
+    /// at the same path only produce a warning.
+    fn verify_install_collisions(
+        &self,
+        logger: &slog::Logger,
+        collector: &PythonResourceCollector,
+        enabled_features: &BTreeSet<String>,
+    ) -> Result<()> {
+        let mut installs = Vec::new();
+
+        for install in collector.relative_path_installs_for_features(enabled_features)? {
+            let description = install.describe();
+
+            match install.location {
+                Some(location) => {
+                    for (path, location, _) in process_scripts(
+                        &[(install.path, location, install.executable)],
+                        self.packaging_policy.script_rules(),
+                        &self.target_triple,
+                    )? {
+                        installs.push((
+                            path,
+                            InstallSource {
+                                description: description.clone(),
+                                content: Some(location),
+                            },
+                        ));
+                    }
+                }
+                None => installs.push((
+                    install.path,
+                    InstallSource {
+                        description,
+                        content: None,
+                    },
+                )),
+            }
+        }
+
+        if let Some(path) = self.libpython_install_path() {
+            installs.push((
+                path,
+                InstallSource {
+                    description: "dynamically linked libpython".to_string(),
+                    content: None,
+                },
+            ));
+        }
+
+        let mut errors = Vec::new();
+        for collision in find_install_collisions(installs) {
+            if self.packaging_policy.allow_identical_install_collisions()
+                && collision.is_identical()?
+            {
+                warn!(logger, "{} with identical content", collision);
+            } else {
+                errors.push(collision.to_string());
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "files would overwrite each other when installed: {}",
+                errors.join("; ")
+            ))
+        }
+    }
+
+    /// Scan module sources for syntax newer than the target Python.
+    ///
+    /// This runs before compiling so a package requiring a newer Python is
+    /// reported once instead of as a compile error per module. Findings are
+    /// warnings unless the packaging policy makes them errors.
+    fn verify_syntax_compatibility(
+        &self,
+        diagnostics: &mut DiagnosticsRecorder,
+        collector: &PythonResourceCollector,
+        enabled_features: &BTreeSet<String>,
+    ) -> Result<()> {
+        let target_version = python_version_from_cache_tag(self.cache_tag()).and_then(|version| {
+            let mut parts = version.splitn(2, '.').map(|part| part.parse::<u32>().ok());
+            Some((parts.next()??, parts.next()??))
+        });
+        let target_version = match target_version {
+            Some(version) => version,
+            None => return Ok(()),
+        };
+
+        // The distribution's standard library matches its own version.
+        let resources = collector.resources_for_features(enabled_features)?;
+        let report = scan_syntax_compatibility(
+            resources
+                .iter()
+                .filter(|(name, _)| !self.distribution.py_modules.contains_key(*name)),
+            target_version,
+        )?;
+
+        if !self.packaging_policy.strict_syntax_compatibility() {
+            for problem in report.problems(ConfigProblemSeverity::Warning) {
+                diagnostics.warn(&problem);
+            }
+
+            return Ok(());
+        }
+
+        let problems = report.problems(ConfigProblemSeverity::Error);
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        diagnostics.blame(
+            FailureClass::Policy,
+            report.module_names(),
+            vec!["syntax-requires-newer-python".to_string()],
+        );
+
+        Err(anyhow!(
+            "sources use syntax the target Python doesn't support: {}",
+            problems
+                .iter()
+                .map(|problem| problem.to_string())
+                .collect::<Vec<_>>()
+                .join("; ")
+        ))
+    }
+
+    /// Warn about installed .pyc files carrying source modification times.
+    ///
+    /// Reproducible builds record `PycTimestamp::reproducible()` in timestamp
+    /// based .pyc headers. Any other time means a .pyc file wasn't compiled
+    /// for this build. This only matters if the packaging policy says the
+    /// build should be reproducible.
+    fn verify_reproducible_bytecode(
+        &self,
+        diagnostics: &mut DiagnosticsRecorder,
+        compiled_resources: &CompiledResourcesCollection,
+    ) -> Result<()> {
+        if !self.packaging_policy.reproducible_build() {
+            return Ok(());
+        }
+
+        let expected = PycTimestamp::reproducible()?.header_value();
+
+        let mut timestamped = Vec::new();
+        for (path, location, _) in &compiled_resources.extra_files {
+            if path.extension().and_then(|ext| ext.to_str()) != Some("pyc") {
+                continue;
+            }
+
+            let data = location
+                .resolve()
+                .with_context(|| format!("reading {}", path.display()))?;
+            let header = BytecodeHeader::parse(&data, Some(self.cache_tag()))
+                .with_context(|| format!("parsing header of {}", path.display()))?;
+
+            if header.modified_time().unwrap_or(expected) != expected {
+                timestamped.push(path);
+            }
+        }
+
+        if let Some(example) = timestamped.first() {
+            diagnostics.warn(format!(
+                "reproducible build requested but {} installed .pyc files carry source modification times (e.g. {}); use a hash based pyc invalidation mode or set SOURCE_DATE_EPOCH",
+                timestamped.len(),
+                example.display()
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Build a Python library suitable for linking.
     ///
     /// This will take the underlying distribution, resources, and
     /// configuration and produce a new executable binary.
+    ///
+    /// `builtin_extension_module_names` defines which extension modules to
+    /// link into the library as built-ins. Extension build contexts not in
+    /// this set are ignored.
     fn resolve_python_linking_info(
         &self,
         logger: &slog::Logger,
         opt_level: &str,
+        builtin_extension_module_names: &BTreeSet<String>,
     ) -> Result<PythonLinkingInfo> {
         let libpythonxy_filename;
         let mut cargo_metadata: Vec<String> = Vec::new();
@@ -277,10 +1056,11 @@ impl StandalonePythonExecutableBuilder {
         let libpython_filename: Option<PathBuf>;
         let libpyembeddedconfig_data: Option<Vec<u8>>;
         let libpyembeddedconfig_filename: Option<PathBuf>;
+        let mut link_record = LinkRecord::default();
 
         match self.link_mode {
             LibpythonLinkMode::Static => {
-                let temp_dir = TempDir::new("pyoxidizer-build-exe")?;
+                let temp_dir = self.scratch_dir(DirectoryRole::Scratch, "pyoxidizer-build-exe")?;
                 let temp_dir_path = temp_dir.path();
 
                 warn!(
@@ -289,8 +1069,15 @@ impl StandalonePythonExecutableBuilder {
                 );
 
                 let mut link_contexts = vec![&self.core_build_context];
-                for c in self.extension_build_contexts.values() {
-                    link_contexts.push(c);
+                link_record.units.push(self.core_link_unit.clone());
+                for (name, c) in &self.extension_build_contexts {
+                    if builtin_extension_module_names.contains(name) {
+                        link_contexts.push(c);
+
+                        if let Some(unit) = self.extension_link_units.get(name) {
+                            link_record.units.push(unit.clone());
+                        }
+                    }
                 }
 
                 let library_info = link_libpython(
@@ -313,6 +1100,8 @@ impl StandalonePythonExecutableBuilder {
                 ));
                 libpyembeddedconfig_data =
                     Some(std::fs::read(&library_info.libpyembeddedconfig_path)?);
+
+                temp_dir.succeed();
             }
 
             LibpythonLinkMode::Dynamic => {
@@ -331,8 +1120,24 @@ impl StandalonePythonExecutableBuilder {
             libpyembeddedconfig_filename,
             libpyembeddedconfig_data,
             cargo_metadata,
+            link_record,
         })
     }
+
+    /// Start a bytecode compiler using the host Python executable.
+    ///
+    /// The compiler emits reproducible bytecode if the packaging policy says
+    /// the build should be reproducible. Its files are kept in the scratch
+    /// directory.
+    fn new_bytecode_compiler(&self) -> Result<BytecodeCompiler> {
+        BytecodeCompiler::with_options(
+            &self.host_python_exe,
+            &BytecodeCompilerOptions {
+                reproducible: self.packaging_policy.reproducible_build(),
+                temp_root: Some(self.build_directories.root(DirectoryRole::Scratch)),
+            },
+        )
+    }
 }
 
 impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
@@ -356,8 +1161,24 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
         &self.packaging_policy
     }
 
-    fn python_exe_path(&self) -> &Path {
-        &self.python_exe
+    fn target_python_exe_path(&self) -> &Path {
+        &self.target_python_exe
+    }
+
+    fn host_python_exe_path(&self) -> &Path {
+        &self.host_python_exe
+    }
+
+    fn set_host_python_exe(&mut self, python_exe: Option<&Path>) -> Result<()> {
+        // Starting a compiler verifies the magic number.
+        if let Some(python_exe) = python_exe {
+            self.distribution
+                .create_bytecode_compiler(Some(python_exe))?;
+        }
+
+        self.host_python_exe = python_exe.unwrap_or(&self.target_python_exe).to_path_buf();
+
+        Ok(())
     }
 
     fn iter_resources<'a>(
@@ -373,14 +1194,21 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
         install_args: &[String],
         extra_envs: &HashMap<String, String>,
     ) -> Result<Vec<PythonResource>> {
-        pip_install(
+        let build_dir = self.scratch_dir(DirectoryRole::PipBuild, "pyoxidizer-pip-install")?;
+
+        let resources = pip_install(
             logger,
             &**self.distribution,
             self.link_mode,
             verbose,
             install_args,
             extra_envs,
-        )
+            build_dir.path(),
+        )?;
+
+        build_dir.succeed();
+
+        self.classify_tests(logger, resources)
     }
 
     fn read_package_root(
@@ -389,20 +1217,76 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
         path: &Path,
         packages: &[String],
     ) -> Result<Vec<PythonResource>> {
-        Ok(find_resources(&logger, &**self.distribution, path, None)?
-            .iter()
-            .filter_map(|x| {
-                if x.is_in_packages(packages) {
-                    Some(x.clone())
-                } else {
-                    None
-                }
-            })
-            .collect::<Vec<_>>())
+        select_resources_in_packages(
+            &self.classify_tests(
+                logger,
+                find_resources(&logger, &**self.distribution, path, None)?,
+            )?,
+            packages,
+        )
+    }
+
+    fn read_layered_package_roots(
+        &self,
+        logger: &slog::Logger,
+        layers: &[&Path],
+        packages: &[String],
+    ) -> Result<LayeredResources> {
+        let tree = LayeredTree::from_layers(layers)?;
+
+        for tombstone in &tree.unmatched_tombstones {
+            warn!(
+                logger,
+                "{} does not remove any file of an earlier layer",
+                tombstone.display()
+            );
+        }
+
+        let merged_dir =
+            self.scratch_dir(DirectoryRole::Scratch, "pyoxidizer-layered-package-roots")?;
+        tree.materialize(merged_dir.path())?;
+
+        let mut resources = self.read_package_root(logger, merged_dir.path(), packages)?;
+
+        // Resources refer to the files of the layers providing them rather
+        // than to their copies, so their provenance names the originating
+        // layer.
+        for resource in &mut resources {
+            resource.map_data_locations(&mut |location| {
+                let origin = location
+                    .path()
+                    .and_then(|path| path.strip_prefix(merged_dir.path()).ok())
+                    .and_then(|relative_path| tree.files.get(relative_path));
+
+                Ok(match origin {
+                    Some(origin) => DataLocation::from_path(origin.clone()),
+                    None => location.clone(),
+                })
+            })?;
+        }
+
+        merged_dir.succeed();
+
+        Ok(LayeredResources { resources, tree })
+    }
+
+    fn read_bytecode_package_root(
+        &self,
+        logger: &slog::Logger,
+        path: &Path,
+        packages: &[String],
+    ) -> Result<Vec<PythonResource>> {
+        select_resources_in_packages(
+            &self.classify_tests(
+                logger,
+                find_bytecode_resources(&logger, &**self.distribution, path)?,
+            )?,
+            packages,
+        )
     }
 
     fn read_virtualenv(&self, logger: &slog::Logger, path: &Path) -> Result<Vec<PythonResource>> {
-        read_virtualenv(logger, &**self.distribution, path)
+        self.classify_tests(logger, read_virtualenv(logger, &**self.distribution, path)?)
     }
 
     fn setup_py_install(
@@ -413,7 +1297,9 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
         extra_envs: &HashMap<String, String>,
         extra_global_arguments: &[String],
     ) -> Result<Vec<PythonResource>> {
-        setup_py_install(
+        let build_dir = self.scratch_dir(DirectoryRole::PipBuild, "pyoxidizer-setup-py-install")?;
+
+        let resources = setup_py_install(
             logger,
             &**self.distribution,
             self.link_mode,
@@ -421,7 +1307,20 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
             verbose,
             extra_envs,
             extra_global_arguments,
-        )
+            build_dir.path(),
+        )?;
+
+        build_dir.succeed();
+
+        self.classify_tests(logger, resources)
+    }
+
+    fn resolve_distribution_versions(
+        &mut self,
+        resources: Vec<PythonResource>,
+    ) -> Result<Vec<PythonResource>> {
+        self.resources_collector
+            .resolve_distribution_versions(resources)
     }
 
     fn add_python_module_source(
@@ -431,15 +1330,7 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
     ) -> Result<()> {
         let location = match location {
             Some(location) => location,
-            None => match self.packaging_policy.get_resources_policy().clone() {
-                PythonResourcesPolicy::InMemoryOnly
-                | PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(_) => {
-                    ConcreteResourceLocation::InMemory
-                }
-                PythonResourcesPolicy::FilesystemRelativeOnly(prefix) => {
-                    ConcreteResourceLocation::RelativePath(prefix)
-                }
-            },
+            None => self.verdict_location(&module.name, Some(&module.source))?,
         };
 
         self.resources_collector
@@ -453,21 +1344,27 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
     ) -> Result<()> {
         let location = match location {
             Some(location) => location,
-            None => match self.packaging_policy.get_resources_policy().clone() {
-                PythonResourcesPolicy::InMemoryOnly
-                | PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(_) => {
-                    ConcreteResourceLocation::InMemory
-                }
-                PythonResourcesPolicy::FilesystemRelativeOnly(prefix) => {
-                    ConcreteResourceLocation::RelativePath(prefix)
-                }
-            },
+            None => self.verdict_location(&module.name, Some(&module.source))?,
         };
 
         self.resources_collector
             .add_python_module_bytecode_from_source(module, &location)
     }
 
+    fn add_python_module_bytecode(
+        &mut self,
+        module: &PythonModuleBytecode,
+        location: Option<ConcreteResourceLocation>,
+    ) -> Result<()> {
+        let location = match location {
+            Some(location) => location,
+            None => self.verdict_location(&module.name, None)?,
+        };
+
+        self.resources_collector
+            .add_python_module_bytecode(module, &location)
+    }
+
     fn add_python_package_resource(
         &mut self,
         resource: &PythonPackageResource,
@@ -475,15 +1372,7 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
     ) -> Result<()> {
         let location = match location {
             Some(location) => location,
-            None => match self.packaging_policy.get_resources_policy().clone() {
-                PythonResourcesPolicy::InMemoryOnly
-                | PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(_) => {
-                    ConcreteResourceLocation::InMemory
-                }
-                PythonResourcesPolicy::FilesystemRelativeOnly(prefix) => {
-                    ConcreteResourceLocation::RelativePath(prefix)
-                }
-            },
+            None => self.verdict_location(&resource.leaf_package, None)?,
         };
 
         self.resources_collector
@@ -497,15 +1386,7 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
     ) -> Result<()> {
         let location = match location {
             Some(location) => location,
-            None => match self.packaging_policy.get_resources_policy().clone() {
-                PythonResourcesPolicy::InMemoryOnly
-                | PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(_) => {
-                    ConcreteResourceLocation::InMemory
-                }
-                PythonResourcesPolicy::FilesystemRelativeOnly(prefix) => {
-                    ConcreteResourceLocation::RelativePath(prefix)
-                }
-            },
+            None => self.default_location(),
         };
 
         self.resources_collector
@@ -518,6 +1399,34 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
         extension_module: &PythonExtensionModule,
         location: Option<ConcreteResourceLocation>,
     ) -> Result<()> {
+        validate_extension_module_architecture(extension_module, &self.target_triple)?;
+
+        let implementation = self.distribution.python_implementation();
+        if !implementation.is_compatible_extension_suffix(&extension_module.extension_file_suffix) {
+            return Err(anyhow!(
+                "extension module {} has suffix {}, which {} can't load",
+                extension_module.name,
+                extension_module.extension_file_suffix,
+                implementation.name()
+            ));
+        }
+
+        if self.packaging_policy.debug_companion() {
+            if let Some(data) = &extension_module.shared_library {
+                self.extension_binaries.insert(
+                    extension_module.name.clone(),
+                    (extension_module.resolve_path("extensions"), data.clone()),
+                );
+            }
+        }
+
+        let thinned = if self.packaging_policy.thin_fat_binaries() {
+            thin_extension_module(extension_module, &self.target_triple)?
+        } else {
+            None
+        };
+        let extension_module = thinned.as_ref().unwrap_or(extension_module);
+
         // Whether we can load extension modules as standalone shared library files.
         let can_load_standalone = self.distribution.is_extension_module_file_loadable();
 
@@ -572,6 +1481,14 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
                 == &PythonResourcesPolicy::InMemoryOnly
         };
 
+        let requested_location = match location {
+            Some(ConcreteResourceLocation::InMemory) => Some("in-memory".to_string()),
+            Some(ConcreteResourceLocation::RelativePath(ref prefix)) => {
+                Some(format!("filesystem-relative:{}", prefix))
+            }
+            None => None,
+        };
+
         let require_filesystem = if let Some(ConcreteResourceLocation::RelativePath(_)) = location {
             true
         } else {
@@ -586,15 +1503,18 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
         // of the following conditions are met:
         //
         // We are a stdlib extension module built into libpython core
-        let produce_builtin = if extension_module.is_stdlib && extension_module.builtin_default {
-            true
+        let builtin_rule = if extension_module.is_stdlib && extension_module.builtin_default {
+            Some(ExtensionModuleRule::StdlibBuiltinDefault)
         // Builtin linking is the only mechanism available to us.
         } else if can_link_builtin && (!can_link_standalone || !can_load_standalone) {
-            true
+            Some(ExtensionModuleRule::BuiltinOnlyMechanism)
         // We want in memory loading and we can link a builtin
+        } else if want_in_memory && can_link_builtin && !require_filesystem {
+            Some(ExtensionModuleRule::PreferInMemoryBuiltin)
         } else {
-            want_in_memory && can_link_builtin && !require_filesystem
+            None
         };
+        let produce_builtin = builtin_rule.is_some();
 
         // Reject explicit requests to load extension module from the filesystem
         // when the distribution doesn't support this.
@@ -627,7 +1547,7 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
             return Err(anyhow!("extension module {} cannot be materialized as a shared library because distribution does not support loading extension module shared libraries", extension_module.name));
         }
 
-        if produce_builtin {
+        let (rule, outcome) = if let Some(rule) = builtin_rule {
             let mut build_context = LibPythonBuildContext::default();
 
             for depends in &extension_module.link_libraries {
@@ -664,11 +1584,23 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
                 build_context.object_files.push(location.clone());
             }
 
+            let ignored = ignored_libraries_for_target(&self.target_triple);
+            let link_unit = LinkUnit::from_extension_module(
+                extension_module,
+                extension_module.link_libraries.iter().filter(|depends| {
+                    depends.framework || depends.system || !ignored.contains(&depends.name.as_str())
+                }),
+            )?;
+
             self.resources_collector
                 .add_builtin_python_extension_module(extension_module)?;
 
             self.extension_build_contexts
                 .insert(extension_module.name.clone(), build_context);
+            self.extension_link_units
+                .insert(extension_module.name.clone(), link_unit);
+
+            (rule, ExtensionModuleOutcome::Builtin)
         } else {
             // If we're not producing a builtin, we're producing a shared library
             // extension module. We currently only support extension modules that
@@ -685,20 +1617,218 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
 
             self.resources_collector
                 .add_python_extension_module(extension_module, &location)?;
+
+            match location {
+                ConcreteResourceLocation::InMemory => (
+                    ExtensionModuleRule::SharedLibraryInMemory,
+                    ExtensionModuleOutcome::InMemorySharedLibrary,
+                ),
+                ConcreteResourceLocation::RelativePath(_) => (
+                    ExtensionModuleRule::SharedLibraryFilesystem,
+                    ExtensionModuleOutcome::FilesystemSharedLibrary,
+                ),
+            }
+        };
+
+        self.extension_decisions.insert(
+            extension_module.name.clone(),
+            ExtensionDecision {
+                name: extension_module.name.clone(),
+                link_mode: self.link_mode,
+                resources_policy: self.packaging_policy.get_resources_policy().into(),
+                requested_location,
+                is_stdlib: extension_module.is_stdlib,
+                builtin_default: extension_module.builtin_default,
+                required: extension_module.required,
+                has_object_files: !extension_module.object_file_data.is_empty(),
+                has_shared_library: extension_module.shared_library.is_some(),
+                can_load_standalone,
+                can_load_dynamic_library_memory,
+                rule,
+                outcome,
+                variant: extension_module.variant.clone(),
+                variant_pin: self
+                    .packaging_policy
+                    .extension_module_variant_pins()
+                    .get(&extension_module.name)
+                    .filter(|pin| extension_module.variant.as_ref() == Some(*pin))
+                    .map(|pin| format!("{}={}", extension_module.name, pin)),
+            },
+        );
+
+        Ok(())
+    }
+
+    fn add_packed_resources(
+        &mut self,
+        data: &Arc<Vec<u8>>,
+        source_cache_tag: &str,
+        install_root: Option<&Path>,
+    ) -> Result<()> {
+        let resources = prepackaged_resources_from_packed_resources(
+            data,
+            self.cache_tag(),
+            source_cache_tag,
+            install_root,
+        )?;
+
+        // Built-in extension modules need to be linked into the binary. So
+        // they must already be known to us.
+        for resource in &resources {
+            if resource.flavor == ResourceFlavor::BuiltinExtensionModule
+                && !self.extension_build_contexts.contains_key(&resource.name)
+            {
+                return Err(anyhow!(
+                    "packed resources contain built-in extension module {} which is not available",
+                    resource.name
+                ));
+            }
+        }
+
+        for resource in resources {
+            self.resources_collector
+                .add_prepackaged_resource(resource)?;
+        }
+
+        Ok(())
+    }
+
+    fn add_extracted_shared_library(&mut self, library: &LibraryDependency) -> Result<()> {
+        if library.system || library.framework {
+            return Err(anyhow!(
+                "system library {} cannot be embedded for extraction",
+                library.name
+            ));
+        }
+
+        let data = library
+            .dynamic_library
+            .as_ref()
+            .ok_or_else(|| anyhow!("library {} has no shared library", library.name))?;
+
+        if target_architectures(&self.target_triple).is_ok() {
+            validate_binary_architecture(
+                &library.name,
+                &data.resolve_uncached()?,
+                &self.target_triple,
+            )?;
+        }
+
+        self.resources_collector
+            .add_extracted_shared_library(&library.name, data)
+    }
+
+    fn add_extracted_shared_library_path(&mut self, path: &Path) -> Result<()> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("unable to resolve file name of {}", path.display()))?
+            .to_string_lossy()
+            .to_string();
+
+        let data = DataLocation::from_path(path.to_path_buf());
+
+        if target_architectures(&self.target_triple).is_ok() {
+            validate_binary_architecture(&name, &data.resolve_uncached()?, &self.target_triple)?;
+        }
+
+        self.resources_collector
+            .add_extracted_shared_library(&name, &data)
+    }
+
+    fn add_shared_library_dependency(&mut self, name: &str, library: &str) -> Result<()> {
+        self.resources_collector
+            .add_shared_library_dependency(name, library)
+    }
+
+    fn add_package_patch(&mut self, patch: PackagePatch) -> Result<()> {
+        self.package_patches.push(patch);
+
+        Ok(())
+    }
+
+    fn add_layered_tree(&mut self, tree: &LayeredTree) -> Result<()> {
+        self.layer_overrides.extend(tree.overrides.iter().cloned());
+        self.layer_deletions.extend(tree.deletions.iter().cloned());
+
+        Ok(())
+    }
+
+    fn enable_recovery_set(
+        &mut self,
+        policy: &PythonPackagingPolicy,
+        selection: RecoverySelection,
+    ) -> Result<()> {
+        // Files installed next to the binary would be seen by both sets.
+        if !matches!(
+            policy.get_resources_policy(),
+            PythonResourcesPolicy::InMemoryOnly
+        ) {
+            return Err(anyhow!(
+                "the recovery resource set requires the in-memory-only resources policy"
+            ));
         }
 
+        self.recovery = Some(RecoveryResources {
+            packaging_policy: policy.clone(),
+            collector: new_resources_collector(
+                policy,
+                self.cache_tag(),
+                self.build_directories.root(DirectoryRole::Spill),
+            )?,
+            selection,
+        });
+
         Ok(())
     }
 
+    fn add_recovery_resource(&mut self, resource: &PythonResource) -> Result<()> {
+        let recovery = self
+            .recovery
+            .as_mut()
+            .ok_or_else(|| anyhow!("no recovery resource set is enabled"))?;
+        let location = policy_default_location(&recovery.packaging_policy);
+
+        match resource {
+            PythonResource::ModuleSource(module) => recovery
+                .collector
+                .add_python_module_source(module, &location),
+            PythonResource::ModuleBytecodeRequest(module) => recovery
+                .collector
+                .add_python_module_bytecode_from_source(module, &location),
+            PythonResource::ModuleBytecode(module) => recovery
+                .collector
+                .add_python_module_bytecode(module, &location),
+            PythonResource::Resource(resource) => recovery
+                .collector
+                .add_python_package_resource(resource, &location),
+            PythonResource::DistributionResource(resource) => recovery
+                .collector
+                .add_package_distribution_resource(resource, &location),
+            PythonResource::ExtensionModuleDynamicLibrary(em)
+            | PythonResource::ExtensionModuleStaticallyLinked(em) => Err(anyhow!(
+                "cannot add extension module {} to the recovery resource set; built-in extension modules are available to both sets",
+                em.name
+            )),
+            PythonResource::EggFile(_) | PythonResource::PathExtension(_) => Err(anyhow!(
+                "cannot add {} to the recovery resource set",
+                resource.full_name()
+            )),
+        }
+    }
+
     fn filter_resources_from_files(
         &mut self,
         logger: &slog::Logger,
         files: &[&Path],
         glob_patterns: &[&str],
     ) -> Result<()> {
-        let resource_names = resolve_resource_names_from_files(files, glob_patterns)?;
+        let mut resource_names = resolve_resource_names_from_files(files, glob_patterns)?;
 
-        warn!(logger, "filtering module entries");
+        let protected =
+            self.entry_point_protection(logger, |name| resource_names.contains(name))?;
+        resource_names.extend(protected);
+
+        warn!(logger, "filtering module entries of the main resource set");
 
         self.resources_collector.filter_resources_mut(|resource| {
             if !resource_names.contains(&resource.name) {
@@ -712,138 +1842,1089 @@ impl PythonBinaryBuilder for StandalonePythonExecutableBuilder {
         warn!(logger, "filtering embedded extension modules");
         filter_btreemap(logger, &mut self.extension_build_contexts, &resource_names);
 
+        for module in &self.config.preload_modules {
+            if !resource_names.contains(module) {
+                warn!(
+                    logger,
+                    "preloaded module {} was filtered away; the binary will fail to start unless it can be imported from the filesystem",
+                    module
+                );
+            }
+        }
+
         Ok(())
     }
 
-    fn requires_jemalloc(&self) -> bool {
-        self.config.raw_allocator == RawAllocator::Jemalloc
+    fn mark_used_from_log(&mut self, log: &Path) -> Result<UsageReport> {
+        self.resources_collector.mark_used_from_log(log)
     }
 
-    fn to_embedded_python_context(
-        &self,
-        logger: &slog::Logger,
-        opt_level: &str,
-    ) -> Result<EmbeddedPythonContext> {
-        let mut file_seen = false;
-        for module in self.resources_collector.find_dunder_file()? {
-            file_seen = true;
-            warn!(logger, "warning: {} contains __file__", module);
-        }
+    fn remove_unused(&mut self, logger: &slog::Logger, protect: &[&str]) -> Result<()> {
+        let patterns = protect
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern)
+                    .with_context(|| format!("parsing protect pattern {}", pattern))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut required = self.required_resource_names();
+
+        let kept = self
+            .resources_collector
+            .iter_resources()
+            .map(|(name, _)| name)
+            .filter(|name| {
+                self.resources_collector.is_resource_used(name) == Some(true)
+                    || patterns.iter().any(|pattern| pattern.matches(name))
+                    || required.contains(*name)
+            })
+            .cloned()
+            .collect::<BTreeSet<_>>();
+        let protected = self.entry_point_protection(logger, |name| kept.contains(name))?;
+        required.extend(protected);
+
+        let removed = self.resources_collector.remove_unused(|resource| {
+            patterns
+                .iter()
+                .any(|pattern| pattern.matches(&resource.name))
+                || required.contains(&resource.name)
+        })?;
 
-        if file_seen {
-            warn!(logger, "__file__ was encountered in some embedded modules");
-            warn!(
-                logger,
-                "PyOxidizer does not set __file__ and this may create problems at run-time"
-            );
+        for name in removed {
             warn!(
                 logger,
-                "See https://github.com/indygreg/PyOxidizer/issues/69 for more"
+                "removing unused {} from the main resource set", name
             );
+            self.extension_build_contexts.remove(&name);
         }
 
-        let compiled_resources = {
-            let mut compiler = BytecodeCompiler::new(&self.python_exe)?;
-            self.resources_collector.compile_resources(&mut compiler)?
-        };
+        Ok(())
+    }
 
-        let mut extra_files = FileManifest::default();
+    fn compare_to_distribution(&self) -> Result<DistributionCoverage> {
+        let inventory = DistributionInventory::from_distribution(&**self.distribution)?;
 
-        for (path, location, executable) in &compiled_resources.extra_files {
-            extra_files.add_file(
-                path,
-                &FileContent {
-                    data: location.resolve()?,
-                    executable: *executable,
-                },
-            )?;
-        }
+        Ok(inventory.coverage(self.resources_collector.iter_resources()))
+    }
 
-        let mut module_names = Vec::new();
+    fn audit_vulnerabilities(&self, database: &Path) -> Result<AuditReport> {
+        let advisories = load_osv_advisories(database)?;
+        let distributions = collected_distributions(self.resources_collector.iter_resources())?;
 
-        for name in compiled_resources.resources.keys() {
-            module_names.write_all(name.as_bytes())?;
-            module_names.write_all(b"\n")?;
+        Ok(audit_distributions(&distributions, &advisories))
+    }
+
+    fn filter_file_from_log(&self, log: &Path) -> Result<FilterFile> {
+        self.resources_collector
+            .filter_file_from_log(log, &self.required_resource_names())
+    }
+
+    fn filter_file_from_entry_modules(&self, modules: &[&str]) -> Result<FilterFile> {
+        self.resources_collector
+            .filter_file_from_entry_modules(modules, &self.required_resource_names())
+    }
+
+    fn extract_resource(&self, name: &str, payload: PayloadKind, dest: &Path) -> Result<PathBuf> {
+        if payload.is_bytecode() {
+            let mut compiler = self.new_bytecode_compiler()?;
+            self.resources_collector
+                .extract_resource(name, &payload, Some(&mut compiler), dest)
+        } else {
+            self.resources_collector
+                .extract_resource(name, &payload, None, dest)
         }
+    }
 
-        let mut resources = Vec::new();
-        compiled_resources.write_packed_resources_v1(&mut resources)?;
+    fn write_wheel(
+        &self,
+        package: &str,
+        version: &str,
+        dest: &Path,
+        tags: WheelTags,
+    ) -> Result<PathBuf> {
+        let contents = self.resources_collector.wheel_contents(package)?;
+        let distribution = contents
+            .distribution_name()?
+            .unwrap_or_else(|| package.to_string());
+
+        write_wheel(&distribution, version, &contents, &tags, dest)
+    }
+
+    fn relocate_package(
+        &mut self,
+        package: &str,
+        to: ConcreteResourceLocation,
+        force: bool,
+    ) -> Result<RelocationReport> {
+        let constraints = RelocationConstraints {
+            extension_modules_in_memory: self
+                .supports_in_memory_dynamically_linked_extension_loading,
+            extension_modules_filesystem: self.distribution.is_extension_module_file_loadable(),
+            extension_file_suffix: self
+                .distribution
+                .python_module_suffixes()?
+                .extension
+                .first()
+                .cloned(),
+            force,
+        };
 
-        let linking_info = self.resolve_python_linking_info(logger, opt_level)?;
+        let report = self
+            .resources_collector
+            .relocate_package(package, &to, &constraints)?;
 
-        if self.link_mode == LibpythonLinkMode::Dynamic {
-            if let Some(p) = &self.distribution.libpython_shared_library {
-                let manifest_path = Path::new(p.file_name().unwrap());
-                let content = FileContent {
-                    data: std::fs::read(&p)?,
-                    executable: false,
-                };
+        let outcome = match to {
+            ConcreteResourceLocation::InMemory => ExtensionModuleOutcome::InMemorySharedLibrary,
+            ConcreteResourceLocation::RelativePath(_) => {
+                ExtensionModuleOutcome::FilesystemSharedLibrary
+            }
+        };
 
-                extra_files.add_file(&manifest_path, &content)?;
+        for moved in &report.moved {
+            if moved.payload == PayloadKind::ExtensionModule {
+                if let Some(decision) = self.extension_decisions.get_mut(&moved.resource) {
+                    decision.outcome = outcome;
+                }
             }
         }
 
-        Ok(EmbeddedPythonContext {
-            config: self.config.clone(),
-            linking_info,
-            module_names,
-            resources,
-            extra_files,
-            host_triple: self.host_triple.clone(),
-            target_triple: self.target_triple.clone(),
-        })
+        Ok(report)
     }
-}
 
-#[cfg(test)]
-pub mod tests {
-    use {
-        super::*,
-        crate::py_packaging::distribution::{BinaryLibpythonLinkMode, DistributionFlavor},
-        crate::python_distributions::PYTHON_DISTRIBUTIONS,
-        crate::testutil::*,
-        lazy_static::lazy_static,
-        python_packaging::policy::ExtensionModuleFilter,
-        python_packed_resources::data::ResourceFlavor,
-        std::collections::BTreeSet,
-        std::iter::FromIterator,
-    };
+    fn tag_resources(&mut self, pattern: &str, tag: &str) -> Result<usize> {
+        let pattern = glob::Pattern::new(pattern)
+            .with_context(|| format!("parsing tag pattern {}", pattern))?;
 
-    lazy_static! {
-        pub static ref WINDOWS_TARGET_TRIPLES: Vec<&'static str> =
-            vec!["i686-pc-windows-msvc", "x86_64-pc-windows-msvc"];
+        let names = self
+            .resources_collector
+            .iter_resources()
+            .filter(|(name, _)| pattern.matches(name))
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
 
-        /// An extension module represented by a shared library file.
-        pub static ref EXTENSION_MODULE_SHARED_LIBRARY_ONLY: PythonExtensionModule =
-            PythonExtensionModule {
-                name: "shared_only".to_string(),
-                init_fn: Some("PyInit_shared_only".to_string()),
-                extension_file_suffix: ".so".to_string(),
-                shared_library: Some(DataLocation::Memory(vec![42])),
-                object_file_data: vec![],
-                is_package: false,
-                link_libraries: vec![],
-                is_stdlib: false,
-                builtin_default: false,
-                required: false,
-                variant: None,
-                licenses: None,
-                license_texts: None,
-                license_public_domain: None,
-            };
+        for name in &names {
+            self.resources_collector.tag_resource(name, tag)?;
+        }
 
-        /// An extension module represented by only object files.
-        pub static ref EXTENSION_MODULE_OBJECT_FILES_ONLY: PythonExtensionModule =
-            PythonExtensionModule {
-                name: "object_files_only".to_string(),
-                init_fn: Some("PyInit_object_files_only".to_string()),
-                extension_file_suffix: ".so".to_string(),
-                shared_library: None,
-                object_file_data: vec![DataLocation::Memory(vec![0]), DataLocation::Memory(vec![1])],
-                is_package: false,
-                link_libraries: vec![],
-                is_stdlib: false,
-                builtin_default: false,
+        Ok(names.len())
+    }
+
+    fn compress_resources(&mut self, names: &[&str], level: i32) -> Result<u64> {
+        let mut saved = 0;
+
+        for name in names {
+            saved += self.resources_collector.compress_resource(name, level)?;
+        }
+
+        Ok(saved)
+    }
+
+    fn pending_bytecode_count(&self) -> usize {
+        self.resources_collector.pending_bytecode_count()
+    }
+
+    fn compile_pending_bytecode(&mut self, pool: &CompilerPool) -> Result<CompileReport> {
+        // Bytecode must be compiled from patched source. Skipped patches are
+        // reported when the embedded context is produced.
+        apply_package_patches(&mut self.resources_collector, &self.package_patches)?;
+
+        let mut compiler = pool.acquire()?;
+
+        let resources_collector = &mut self.resources_collector;
+        let (report, _) = compile_with_bytecode_cache(
+            self.bytecode_cache.as_ref(),
+            self.build_clock,
+            &self.distribution.cache_tag,
+            &self.bytecode_cache_statistics,
+            &mut compiler,
+            |compiler| resources_collector.compile_pending_bytecode(compiler),
+        )?;
+
+        // A compiler that failed may be in an inconsistent state. Only reuse
+        // compilers that completed all their work.
+        if report.is_success() {
+            pool.release(compiler);
+        }
+
+        Ok(report)
+    }
+
+    fn requires_jemalloc(&self) -> bool {
+        self.config.raw_allocator == RawAllocator::Jemalloc
+    }
+
+    fn extension_decisions(&self) -> Vec<ExtensionDecision> {
+        self.extension_decisions.values().cloned().collect()
+    }
+
+    fn collection_memory_statistics(&self) -> Option<CollectionMemoryStatistics> {
+        self.resources_collector.memory_statistics()
+    }
+
+    fn resource_size_report(&self) -> Result<ResourceSizeReport> {
+        let mut report = self.resources_collector.size_report()?;
+
+        // Hidden resources still count towards totals.
+        if self.packaging_policy.hide_internal_resources() {
+            report
+                .by_resource
+                .retain(|name, _| !self.resources_collector.is_internal(name));
+        }
+
+        Ok(report)
+    }
+
+    fn resource_digest_manifest(
+        &self,
+        algorithm: DigestAlgorithm,
+    ) -> Result<BTreeMap<String, String>> {
+        self.resources_collector.digest_manifest(algorithm)
+    }
+
+    fn explain_resource(&self, name: &str) -> Option<ResourceExplanation> {
+        let mut res = explain_collected_resource(&self.resources_collector, name)?;
+
+        // Package and distribution resources are placed like their package.
+        let resource_name = name.split(':').next().unwrap_or(name);
+
+        let policy: String = self.packaging_policy.get_resources_policy().into();
+        res.placement
+            .push(format!("resources policy is {}", policy));
+        if self.site_packages_shim_location(resource_name).is_some() {
+            res.placement
+                .push("package is installed in the site-packages shim".to_string());
+        }
+        if self.analysis_verdicts.needs_filesystem(resource_name) == Some(true) {
+            res.placement
+                .push("analysis verdict requires the filesystem".to_string());
+        }
+        res.placement.extend(
+            self.verdict_conflicts
+                .iter()
+                .filter(|conflict| conflict.starts_with(&format!("{}:", resource_name)))
+                .cloned(),
+        );
+
+        if let Some(decision) = self.extension_decisions.get(name) {
+            res.placement.push(format!(
+                "extension module rule {:?} resulted in {:?}",
+                decision.rule, decision.outcome
+            ));
+            if let Some(variant) = &decision.variant {
+                res.placement
+                    .push(format!("variant {} was chosen", variant));
+            }
+            if decision.outcome == ExtensionModuleOutcome::Builtin {
+                res.locations.insert(
+                    PayloadKind::ExtensionModule.to_string(),
+                    "built into libpython".to_string(),
+                );
+            }
+            res.stdlib = decision.is_stdlib;
+            if let Some(infos) = self.distribution.license_infos.get(name) {
+                res.licenses = infos
+                    .iter()
+                    .flat_map(|info| info.licenses.iter().cloned())
+                    .collect();
+            }
+        } else if let Ok(inventory) = DistributionInventory::from_distribution(&**self.distribution)
+        {
+            res.stdlib = inventory.modules.contains_key(resource_name);
+        }
+
+        if let Some(recovery) = &self.recovery {
+            if recovery
+                .collector
+                .iter_resources()
+                .any(|(other, _)| other == resource_name)
+            {
+                res.placement
+                    .push("also in the recovery resource set".to_string());
+            }
+        }
+
+        res.required = self.required_resource_names().contains(resource_name);
+        res.protected_by = self
+            .entry_point_protections
+            .iter()
+            .filter(|provider| provider.required_names().contains(resource_name))
+            .map(|provider| format!("{}:{}", provider.group, provider.name))
+            .collect();
+        if let Some(licenses) = self.analysis_verdicts.license_override(resource_name) {
+            res.licenses = licenses.to_vec();
+        }
+
+        Some(res)
+    }
+
+    fn suggest_resource_names(&self, name: &str) -> Vec<String> {
+        suggest_names(
+            name,
+            explainable_names(&self.resources_collector)
+                .iter()
+                .filter(|name| !self.is_hidden_resource(name)),
+        )
+    }
+
+    fn build_clock(&self) -> BuildClock {
+        self.build_clock
+    }
+
+    fn set_build_clock(&mut self, clock: BuildClock) {
+        self.build_clock = clock;
+    }
+
+    fn bytecode_cache(&self) -> Option<&BytecodeCache> {
+        self.bytecode_cache.as_ref()
+    }
+
+    fn set_bytecode_cache(&mut self, cache: Option<BytecodeCache>) {
+        self.bytecode_cache = cache;
+    }
+
+    fn bytecode_cache_statistics(&self) -> CacheStatistics {
+        *self.bytecode_cache_statistics.lock().unwrap()
+    }
+
+    fn payload_storage(&self) -> &PayloadStorage {
+        &self.payload_storage
+    }
+
+    fn set_payload_storage(&mut self, storage: PayloadStorage) {
+        self.payload_storage = storage;
+    }
+
+    fn scratch_dir(&self, role: DirectoryRole, prefix: &str) -> Result<ScratchDir> {
+        self.build_directories
+            .create(role, prefix, &self.directory_usage)
+    }
+
+    fn directory_bytes_written(&self) -> BTreeMap<DirectoryRole, u64> {
+        let mut res = self.directory_usage.bytes_written();
+
+        if let Some(statistics) = self.resources_collector.memory_statistics() {
+            *res.entry(DirectoryRole::Spill).or_insert(0) += statistics.spilled_bytes;
+        }
+
+        res
+    }
+
+    fn resolve_install_layout(&self, enabled_features: &BTreeSet<String>) -> Result<InstallLayout> {
+        self.install_layout_for(&self.resources_collector, enabled_features)
+    }
+
+    fn to_embedded_python_context(
+        &self,
+        logger: &slog::Logger,
+        opt_level: &str,
+        enabled_features: &BTreeSet<String>,
+    ) -> Result<EmbeddedPythonContext> {
+        let mut diagnostics = DiagnosticsRecorder::new(logger);
+
+        self.build_embedded_python_context(logger, &mut diagnostics, opt_level, enabled_features)
+            .map_err(|e| diagnostics.into_error(e))
+    }
+}
+
+/// Call `f` with a compiler reusing bytecode from a cache, if there is one.
+///
+/// Lookups are added to `totals`. Unless `cache` is `None`, the hit and miss
+/// counts of the lookups performed by `f` and the result of evicting entries
+/// exceeding the cache's limits are returned as well.
+fn compile_with_bytecode_cache<T>(
+    cache: Option<&BytecodeCache>,
+    clock: BuildClock,
+    cache_tag: &str,
+    totals: &Mutex<CacheStatistics>,
+    compiler: &mut dyn PythonBytecodeCompiler,
+    f: impl FnOnce(&mut dyn PythonBytecodeCompiler) -> Result<T>,
+) -> Result<(T, Option<(CacheStatistics, GcReport)>)> {
+    let cache = match cache {
+        Some(cache) => cache,
+        None => return Ok((f(compiler)?, None)),
+    };
+
+    let mut build_cache = cache.build_cache().clone();
+    build_cache.set_clock(clock);
+    let mut session = build_cache
+        .session()
+        .context("starting bytecode cache session")?;
+
+    let res = f(&mut CachingBytecodeCompiler::new(
+        compiler,
+        &mut session,
+        cache_tag,
+    ));
+
+    let statistics = session
+        .statistics()
+        .get(BYTECODE_CACHE)
+        .copied()
+        .unwrap_or_default();
+    {
+        let mut totals = totals.lock().unwrap();
+        totals.hits += statistics.hits;
+        totals.misses += statistics.misses;
+    }
+
+    let value = res?;
+    let gc = session
+        .finish(cache.gc_policy())
+        .context("evicting bytecode cache entries")?;
+
+    Ok((value, Some((statistics, gc))))
+}
+
+impl StandalonePythonExecutableBuilder {
+    /// Build an `EmbeddedPythonContext`, recording progress in `diagnostics`.
+    fn build_embedded_python_context(
+        &self,
+        logger: &slog::Logger,
+        diagnostics: &mut DiagnosticsRecorder,
+        opt_level: &str,
+        enabled_features: &BTreeSet<String>,
+    ) -> Result<EmbeddedPythonContext> {
+        for warning in self.resources_collector.warnings() {
+            diagnostics.warn(warning);
+        }
+        for (package, trimmed) in self.resources_collector.trimmed_locales() {
+            diagnostics.warn(format!(
+                "trimmed {} locale files ({} bytes) from {}",
+                trimmed.files, trimmed.bytes, package
+            ));
+        }
+
+        self.verify_all_backing_files(diagnostics)?;
+
+        diagnostics.enter(BuildPhase::PrepareResources);
+
+        // Pinning replaces the locations of files, so files of layers
+        // overriding earlier ones are matched to the paths they install to
+        // beforehand.
+        let layer_override_installs = self.layer_override_installs();
+
+        // Everything below reads pinned content, so it all observes the same
+        // bytes even if files change while we run.
+        let mut resources_collector = Cow::Borrowed(&self.resources_collector);
+        let mut pinned = None;
+        if self.packaging_policy.pin_file_content() {
+            let spill_dir = self.scratch_dir(DirectoryRole::Spill, "pyoxidizer-pinned-content")?;
+            let mut pins = ContentPins::new(
+                spill_dir.path(),
+                self.packaging_policy.pin_spill_threshold(),
+            );
+            resources_collector
+                .to_mut()
+                .map_data_locations(|location| pins.pin(location))?;
+
+            pinned = Some((spill_dir, pins));
+        }
+
+        let mut applied_patches = vec![];
+        if !self.package_patches.is_empty() {
+            let (applied, skipped) =
+                apply_package_patches(resources_collector.to_mut(), &self.package_patches)?;
+            for message in skipped {
+                diagnostics.warn(message);
+            }
+            applied_patches = applied;
+        }
+
+        // The runtime only counts imports if the index flags it, so builds
+        // without telemetry don't pay for it.
+        if self.config.import_telemetry_path.is_some() {
+            resources_collector.to_mut().add_synthetic_resource(
+                IMPORT_TELEMETRY_FEATURE,
+                PrePackagedResource {
+                    flavor: ResourceFlavor::Module,
+                    name: IMPORT_TELEMETRY_RESOURCE_NAME.to_string(),
+                    ..PrePackagedResource::default()
+                },
+            )?;
+        }
+
+        let mut console_scripts = vec![];
+        if self.packaging_policy.console_script_usage() {
+            console_scripts = find_console_scripts(resources_collector.iter_resources())?;
+        }
+        if let Some(source) = console_scripts_module_source(&console_scripts)? {
+            let source = DataLocation::Memory(source.into_bytes());
+
+            resources_collector.to_mut().add_synthetic_resource(
+                CONSOLE_SCRIPTS_FEATURE,
+                PrePackagedResource {
+                    flavor: ResourceFlavor::Module,
+                    name: CONSOLE_SCRIPTS_MODULE.to_string(),
+                    in_memory_source: Some(source.clone()),
+                    in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(source)),
+                    ..PrePackagedResource::default()
+                },
+            )?;
+        }
+        let resources_collector: &PythonResourceCollector = &resources_collector;
+
+        self.verify_install_collisions(logger, resources_collector, enabled_features)?;
+
+        if self.packaging_policy.scan_syntax_compatibility() {
+            self.verify_syntax_compatibility(diagnostics, resources_collector, enabled_features)?;
+        }
+
+        diagnostics.enter(BuildPhase::Compile);
+        let (compiled_resources, compiled_recovery) = {
+            let mut compiler = self.new_bytecode_compiler()?;
+            let (compiled, cache_use) = compile_with_bytecode_cache(
+                self.bytecode_cache.as_ref(),
+                self.build_clock,
+                self.cache_tag(),
+                &self.bytecode_cache_statistics,
+                &mut compiler,
+                |compiler| {
+                    let compiled = resources_collector
+                        .compile_resources_for_features(compiler, enabled_features)?;
+                    let recovery = match &self.recovery {
+                        Some(recovery) => Some(
+                            recovery
+                                .collector
+                                .compile_resources_for_features(compiler, enabled_features)?,
+                        ),
+                        None => None,
+                    };
+
+                    Ok((compiled, recovery))
+                },
+            )?;
+
+            if let Some((statistics, gc)) = cache_use {
+                info!(
+                    logger,
+                    "bytecode cache: {} hits, {} misses", statistics.hits, statistics.misses
+                );
+                if !gc.removed.is_empty() {
+                    info!(
+                        logger,
+                        "evicted {} bytecode cache entries ({} bytes)",
+                        gc.removed.len(),
+                        gc.bytes_removed
+                    );
+                }
+            }
+
+            compiled
+        };
+
+        diagnostics.enter(BuildPhase::Validate);
+
+        // Resources the binary can't run without must not be gated away.
+        for name in self.required_resource_names() {
+            let tags = resources_collector
+                .iter_resources()
+                .find(|(n, _)| **n == name)
+                .map(|(_, resource)| &resource.feature_tags);
+
+            if let Some(tags) = tags {
+                if !compiled_resources.resources.contains_key(&name) {
+                    diagnostics.blame(FailureClass::Policy, vec![name.clone()], vec![]);
+
+                    return Err(anyhow!(
+                        "{} is required but excluded because none of its features ({}) are enabled",
+                        name,
+                        tags.iter().cloned().collect::<Vec<_>>().join(", ")
+                    ));
+                }
+            }
+        }
+
+        let direct_urls = resources_collector
+            .direct_urls()
+            .into_iter()
+            .filter(|(name, _)| compiled_resources.resources.contains_key(name))
+            .collect::<BTreeMap<_, _>>();
+
+        let distributions = resources_collector
+            .distributions()
+            .iter()
+            .filter(|(_, distribution)| {
+                compiled_resources
+                    .resources
+                    .contains_key(&distribution.name)
+            })
+            .map(|(key, distribution)| (key.clone(), distribution.clone()))
+            .collect::<BTreeMap<_, _>>();
+
+        if self.packaging_policy.reject_editable_installs() {
+            let editable = direct_urls
+                .iter()
+                .filter(|(_, direct_url)| direct_url.is_editable())
+                .collect::<Vec<_>>();
+
+            if !editable.is_empty() {
+                diagnostics.blame(
+                    FailureClass::Policy,
+                    editable.iter().map(|(name, _)| name.to_string()),
+                    vec![],
+                );
+
+                return Err(anyhow!(
+                    "editable installs are rejected by policy: {}",
+                    editable
+                        .iter()
+                        .map(|(name, direct_url)| format!("{} ({})", name, direct_url.url))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+
+        // Verify every in-memory module referencing __file__ was either
+        // relocated or explicitly waived.
+        let mut dunder_file = verify_dunder_file(
+            resources_collector
+                .iter_resources()
+                .filter(|(name, _)| compiled_resources.resources.contains_key(*name)),
+            self.packaging_policy.dunder_file_waivers(),
+        )?;
+        let dunder_file_severity = if self.packaging_policy.strict_dunder_file() {
+            ConfigProblemSeverity::Error
+        } else {
+            ConfigProblemSeverity::Warning
+        };
+
+        // Analysis verdicts take precedence over the __file__ heuristic.
+        let mut verdicts = VerdictReport {
+            conflicts: self.verdict_conflicts.clone(),
+            misplaced: self.analysis_verdicts.misplaced_resources(
+                resources_collector
+                    .iter_resources()
+                    .filter(|(name, _)| compiled_resources.resources.contains_key(*name)),
+            ),
+            unused_patterns: self.analysis_verdicts.unused_patterns(
+                resources_collector
+                    .iter_resources()
+                    .map(|(name, _)| name)
+                    .chain(self.distribution.extension_modules.keys()),
+            ),
+            unknown_attributes: self.analysis_verdicts.unknown_attributes().to_vec(),
+        };
+        verdicts
+            .conflicts
+            .extend(self.analysis_verdicts.waive_dunder_file(&mut dunder_file));
+
+        let site_packages = verify_site_packages_usage(
+            resources_collector
+                .iter_resources()
+                .filter(|(name, _)| compiled_resources.resources.contains_key(*name)),
+            if self.config.site_packages_shim.is_some() {
+                &self.config.site_packages_shim_packages
+            } else {
+                &[]
+            },
+        )?;
+
+        let entry_points = EntryPointReport::from_providers(
+            &find_entry_point_providers(resources_collector.iter_resources())?,
+            |name| compiled_resources.resources.contains_key(name),
+        );
+
+        let vulnerabilities = match self.packaging_policy.vulnerability_database() {
+            Some(path) => {
+                let advisories = load_osv_advisories(path)?;
+                let distributions = collected_distributions(
+                    resources_collector
+                        .iter_resources()
+                        .filter(|(name, _)| compiled_resources.resources.contains_key(*name)),
+                )?;
+
+                audit_distributions(&distributions, &advisories)
+            }
+            None => AuditReport::default(),
+        };
+        let vulnerability_severity = if self.packaging_policy.strict_vulnerability_audit() {
+            ConfigProblemSeverity::Error
+        } else {
+            ConfigProblemSeverity::Warning
+        };
+
+        let mut config_errors = Vec::new();
+        let mut error_codes = BTreeSet::new();
+        for problem in validate_embedded_python_config(
+            &self.config,
+            self.link_mode,
+            &self.target_triple,
+            self.supports_in_memory_dynamically_linked_extension_loading,
+            &compiled_resources.resources,
+        )
+        .into_iter()
+        .chain(validate_runtime_env(
+            &self.config,
+            &self.runtime_env(),
+            self.packaging_policy.forbid_runtime_env(),
+        ))
+        .chain(dunder_file.problems(dunder_file_severity))
+        .chain(verdicts.problems(dunder_file_severity))
+        .chain(vulnerabilities.problems(vulnerability_severity))
+        .chain(site_packages.problems())
+        .chain(entry_points.problems())
+        {
+            match problem.severity {
+                ConfigProblemSeverity::Warning => diagnostics.warn(&problem),
+                ConfigProblemSeverity::Error => {
+                    config_errors.push(problem.to_string());
+                    error_codes.insert(problem.code.to_string());
+                }
+            }
+        }
+
+        if !config_errors.is_empty() {
+            diagnostics.blame(FailureClass::Policy, vec![], error_codes);
+
+            return Err(anyhow!(
+                "invalid embedded Python configuration: {}",
+                config_errors.join("; ")
+            ));
+        }
+
+        if self.config.subinterpreters.is_some() {
+            for problem in validate_subinterpreter_extensions(
+                &self.config,
+                &self.extension_module_binaries()?,
+                &self.analysis_verdicts,
+            ) {
+                diagnostics.warn(&problem);
+            }
+        }
+
+        self.verify_reproducible_bytecode(diagnostics, &compiled_resources)?;
+
+        diagnostics.enter(BuildPhase::Generate);
+        let mut extra_files = FileManifest::default();
+        if self.packaging_policy.allow_identical_install_collisions() {
+            extra_files.set_conflict_policy(FileConflictPolicy::AllowIdentical);
+        }
+
+        for (install, owner) in compiled_resources
+            .extra_files
+            .iter()
+            .zip(&compiled_resources.extra_file_owners)
+        {
+            for (path, location, executable) in process_scripts(
+                std::slice::from_ref(install),
+                self.packaging_policy.script_rules(),
+                &self.target_triple,
+            )? {
+                // Script rules may generate files next to the script.
+                let provenance = if path == install.0 {
+                    match layer_override_installs.get(&install.0) {
+                        Some(o) => format!(
+                            "resource {} from {} overriding {}",
+                            owner,
+                            o.overlay.display(),
+                            o.base.display()
+                        ),
+                        None => format!("resource {}", owner),
+                    }
+                } else {
+                    format!(
+                        "script rule for {} of resource {}",
+                        install.0.display(),
+                        owner
+                    )
+                };
+
+                extra_files.add_file_from(
+                    &path,
+                    &FileContent {
+                        data: location,
+                        executable,
+                    },
+                    &provenance,
+                )?;
+            }
+        }
+
+        // The module names list, packed resources, and linked built-in
+        // extension modules are all derived from the compiled resources so
+        // they can't drift apart.
+        let mut module_names = Vec::new();
+        let mut hidden_module_names = BTreeSet::new();
+
+        for name in compiled_resources.resources.keys() {
+            if self.is_hidden_resource(name) {
+                hidden_module_names.insert(name.to_string());
+                continue;
+            }
+
+            module_names.write_all(name.as_bytes())?;
+            module_names.write_all(b"\n")?;
+        }
+
+        // Hidden resources are left out like they are from size reports.
+        let bytecode_sizes = BytecodeSizeReport::from_modules(
+            compiled_resources
+                .bytecode_size_report()?
+                .modules
+                .into_iter()
+                .filter(|module| !hidden_module_names.contains(&module.name))
+                .collect(),
+        );
+
+        let mut resources = Vec::new();
+        let externalized = self.payload_storage.with_sink(|sink| {
+            compiled_resources.write_packed_resources_to_sink(
+                &mut resources,
+                self.packaging_policy.packed_resources_index(),
+                compiled_recovery.as_ref(),
+                sink,
+            )
+        })?;
+        if externalized.payloads_count > 0 {
+            info!(
+                logger,
+                "{} resource payloads ({} bytes) stored by the {} payload backend",
+                externalized.payloads_count,
+                externalized.payloads_bytes,
+                self.payload_storage.backend()
+            );
+        }
+
+        let recovery_set_cost = match &compiled_recovery {
+            Some(recovery) => {
+                let cost = compiled_resources
+                    .recovery_set_cost(recovery, self.packaging_policy.packed_resources_index())?;
+                info!(
+                    logger,
+                    "recovery resource set of {} resources adds {} bytes ({} bytes on its own)",
+                    cost.resources_count,
+                    cost.incremental_bytes,
+                    cost.standalone_bytes
+                );
+
+                Some(cost)
+            }
+            None => None,
+        };
+
+        let shared_payloads =
+            compiled_resources.shared_payloads_with_recovery(compiled_recovery.as_ref());
+        if shared_payloads.shared_count > 0 {
+            info!(
+                logger,
+                "{} identical resource payloads stored once, saving {} bytes",
+                shared_payloads.shared_count,
+                shared_payloads.saved_bytes
+            );
+        }
+
+        let builtin_extension_module_names = compiled_resources
+            .resources
+            .values()
+            .filter(|r| r.flavor == ResourceFlavor::BuiltinExtensionModule)
+            .map(|r| r.name.to_string())
+            .collect::<BTreeSet<_>>();
+
+        // Extension modules may have been filtered away after they were added.
+        let extension_decisions = self
+            .extension_decisions
+            .values()
+            .filter(|decision| compiled_resources.resources.contains_key(&decision.name))
+            .cloned()
+            .collect();
+
+        let linking_info =
+            self.resolve_python_linking_info(logger, opt_level, &builtin_extension_module_names)?;
+
+        if let Some(manifest_path) = self.libpython_install_path() {
+            if let Some(p) = &self.distribution.libpython_shared_library {
+                let content = FileContent {
+                    data: DataLocation::from_path_uncached(p),
+                    executable: false,
+                };
+
+                extra_files.add_file_from(&manifest_path, &content, "libpython")?;
+            }
+        }
+
+        let install_layout = self.install_layout_for(resources_collector, enabled_features)?;
+
+        // Without libraries to extract, the interpreter doesn't need to look
+        // at all resources at start-up.
+        let mut config = self.config.clone();
+        if !compiled_resources
+            .resources
+            .values()
+            .chain(
+                compiled_recovery
+                    .iter()
+                    .flat_map(|recovery| recovery.resources.values()),
+            )
+            .any(|r| r.extract_shared_library)
+        {
+            config.shared_library_extraction_dir = None;
+        }
+
+        let mut context = EmbeddedPythonContext {
+            config,
+            linking_info,
+            module_names,
+            hidden_module_names,
+            resources,
+            packed_resources_embedding: PackedResourcesEmbedding::default(),
+            builtin_extension_module_names,
+            extension_decisions,
+            enabled_features: enabled_features.clone(),
+            applied_patches,
+            layer_overrides: self.layer_overrides.clone(),
+            layer_deletions: self.layer_deletions.clone(),
+            bytecode_sizes,
+            test_payloads: resources_collector
+                .test_payloads()
+                .into_iter()
+                .filter(|(name, _)| compiled_resources.resources.contains_key(name))
+                .collect(),
+            entry_point_protections: self.entry_point_protections.clone(),
+            console_scripts,
+            extra_files,
+            install_layout,
+            host_triple: self.host_triple.clone(),
+            target_triple: self.target_triple.clone(),
+            cache_tag: self.cache_tag().to_string(),
+            build_time: self.build_clock.now_seconds()?,
+            debug_companion: None,
+            recovery_selection: self
+                .recovery
+                .as_ref()
+                .map(|recovery| recovery.selection.clone()),
+            recovery_set_cost,
+            payload_backend: self.payload_storage.backend().to_string(),
+            external_payloads: self.payload_storage.external_payloads_selection().cloned(),
+            direct_urls,
+            distributions,
+            scratch_dirs: vec![],
+        };
+
+        if let Some((_, pins)) = &pinned {
+            for path in pins.changed_files() {
+                diagnostics.warn(format!(
+                    "{} changed during the build; its content as first read was used",
+                    path.display()
+                ));
+            }
+        }
+
+        diagnostics.enter(BuildPhase::Verify);
+        verify_embedded_context(&context)?;
+
+        // The companion is derived from the finished context so it can't
+        // influence production outputs.
+        if self.packaging_policy.debug_companion() {
+            let mut compiler = self.new_bytecode_compiler()?;
+
+            context.debug_companion = Some(DebugCompanion::from_resources(
+                resources_collector
+                    .iter_resources()
+                    .filter(|(name, _)| compiled_resources.resources.contains_key(*name)),
+                &self.extension_binaries,
+                &mut compiler,
+                self.cache_tag(),
+                build_manifest(&context)?,
+            )?);
+        }
+
+        // extra_files may reference spilled content, so the spill directory
+        // lives as long as the context.
+        if let Some((spill_dir, _)) = pinned {
+            context.scratch_dirs.push(spill_dir.into_succeeded());
+        }
+
+        // Modules that failed to compile are reported last so they stand out.
+        let fallback = match self.packaging_policy.compile_error_policy() {
+            CompileErrorPolicy::SourceOnly => "embedded only the source of",
+            _ => "skipped",
+        };
+        for error in compiled_resources.compile_errors.iter().chain(
+            compiled_recovery
+                .iter()
+                .flat_map(|recovery| recovery.compile_errors.iter()),
+        ) {
+            diagnostics.warn(format!(
+                "{} a module that failed to compile: {}",
+                fallback, error
+            ));
+        }
+
+        Ok(context)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use {
+        super::*,
+        crate::py_packaging::artifact_writer::{
+            ArtifactEvent, ArtifactWriter, CancellationToken, Cancelled,
+        },
+        crate::py_packaging::binary::{load_cargo_metadata, CargoMetadataEntry},
+        crate::py_packaging::build_cache::{BuildCache, GcPolicy},
+        crate::py_packaging::build_diagnostics::build_diagnostics,
+        crate::py_packaging::build_directories::RetentionPolicy,
+        crate::py_packaging::distribution::{BinaryLibpythonLinkMode, DistributionFlavor},
+        crate::py_packaging::entry_points::ConsoleScript,
+        crate::py_packaging::pyembed::ImporterFinder,
+        crate::py_packaging::resource_explanation::ExplainedKind,
+        crate::py_packaging::update_package::{
+            build_manifest, write_build_manifest, BuildManifest,
+        },
+        crate::py_packaging::workspace_paths::{PathStyle, WorkspacePaths},
+        crate::python_distributions::PYTHON_DISTRIBUTIONS,
+        crate::testutil::*,
+        lazy_static::lazy_static,
+        python_packaging::bytecode::{
+            compute_bytecode_header, magic_number_for_python_version, BytecodeHeaderMode,
+        },
+        python_packaging::implementation::InterpreterImplementation,
+        python_packaging::policy::ExtensionModuleFilter,
+        python_packaging::resource::PythonPackageDistributionResourceFlavor,
+        python_packaging::resource_collection::FileInstall,
+        python_packed_resources::data::{IndexEncoding, HEADER_V3},
+        std::collections::BTreeSet,
+        std::iter::FromIterator,
+    };
+
+    lazy_static! {
+        pub static ref WINDOWS_TARGET_TRIPLES: Vec<&'static str> =
+            vec!["i686-pc-windows-msvc", "x86_64-pc-windows-msvc"];
+
+        /// An extension module represented by a shared library file.
+        pub static ref EXTENSION_MODULE_SHARED_LIBRARY_ONLY: PythonExtensionModule =
+            PythonExtensionModule {
+                name: "shared_only".to_string(),
+                init_fn: Some("PyInit_shared_only".to_string()),
+                extension_file_suffix: ".so".to_string(),
+                shared_library: Some(DataLocation::Memory(vec![42])),
+                object_file_data: vec![],
+                is_package: false,
+                link_libraries: vec![],
+                is_stdlib: false,
+                builtin_default: false,
+                required: false,
+                variant: None,
+                licenses: None,
+                license_texts: None,
+                license_public_domain: None,
+            };
+
+        /// An extension module represented by only object files.
+        pub static ref EXTENSION_MODULE_OBJECT_FILES_ONLY: PythonExtensionModule =
+            PythonExtensionModule {
+                name: "object_files_only".to_string(),
+                init_fn: Some("PyInit_object_files_only".to_string()),
+                extension_file_suffix: ".so".to_string(),
+                shared_library: None,
+                object_file_data: vec![DataLocation::Memory(vec![0]), DataLocation::Memory(vec![1])],
+                is_package: false,
+                link_libraries: vec![],
+                is_stdlib: false,
+                builtin_default: false,
                 required: false,
                 variant: None,
                 licenses: None,
@@ -851,184 +2932,3299 @@ pub mod tests {
                 license_public_domain: None,
         };
 
-        /// An extension module with both a shared library and object files.
-        pub static ref EXTENSION_MODULE_SHARED_LIBRARY_AND_OBJECT_FILES: PythonExtensionModule =
-            PythonExtensionModule {
-                name: "shared_and_object_files".to_string(),
-                init_fn: Some("PyInit_shared_and_object_files".to_string()),
-                extension_file_suffix: ".so".to_string(),
-                shared_library: Some(DataLocation::Memory(b"shared".to_vec())),
-                object_file_data: vec![DataLocation::Memory(vec![0]), DataLocation::Memory(vec![1])],
+        /// An extension module with both a shared library and object files.
+        pub static ref EXTENSION_MODULE_SHARED_LIBRARY_AND_OBJECT_FILES: PythonExtensionModule =
+            PythonExtensionModule {
+                name: "shared_and_object_files".to_string(),
+                init_fn: Some("PyInit_shared_and_object_files".to_string()),
+                extension_file_suffix: ".so".to_string(),
+                shared_library: Some(DataLocation::Memory(b"shared".to_vec())),
+                object_file_data: vec![DataLocation::Memory(vec![0]), DataLocation::Memory(vec![1])],
+                is_package: false,
+                link_libraries: vec![],
+                is_stdlib: false,
+                builtin_default: false,
+                required: false,
+                variant: None,
+                licenses: None,
+                license_texts: None,
+                license_public_domain: None,
+        };
+    }
+
+    /// Defines construction options for a `StandalonePythonExecutableBuilder`.
+    ///
+    /// This is mostly intended to be used by tests, to reduce boilerplate for
+    /// constructing instances.
+    pub struct StandalonePythonExecutableBuilderOptions {
+        pub host_triple: String,
+        pub target_triple: String,
+        pub distribution_flavor: DistributionFlavor,
+        pub app_name: String,
+        pub libpython_link_mode: BinaryLibpythonLinkMode,
+        pub extension_module_filter: ExtensionModuleFilter,
+        pub resources_policy: PythonResourcesPolicy,
+        pub extension_module_variant_pins: BTreeMap<String, String>,
+        pub collection_memory_budget: Option<u64>,
+        pub packed_resources_index: IndexEncoding,
+        pub analysis_verdicts: Option<PathBuf>,
+        pub reproducible_build: bool,
+        pub bytecode_optimize_levels: BTreeMap<String, BytecodeOptimizationLevel>,
+        pub build_directories: BuildDirectories,
+    }
+
+    impl Default for StandalonePythonExecutableBuilderOptions {
+        fn default() -> Self {
+            // Grab default values from a default policy so they stay in sync.
+            let default_policy = PythonPackagingPolicy::default();
+
+            Self {
+                host_triple: env!("HOST").to_string(),
+                target_triple: env!("HOST").to_string(),
+                distribution_flavor: DistributionFlavor::Standalone,
+                app_name: "testapp".to_string(),
+                libpython_link_mode: BinaryLibpythonLinkMode::Default,
+                extension_module_filter: default_policy.get_extension_module_filter().clone(),
+                resources_policy: default_policy.get_resources_policy().clone(),
+                extension_module_variant_pins: default_policy
+                    .extension_module_variant_pins()
+                    .clone(),
+                collection_memory_budget: default_policy.collection_memory_budget(),
+                packed_resources_index: default_policy.packed_resources_index(),
+                analysis_verdicts: default_policy.analysis_verdicts().map(|p| p.to_path_buf()),
+                reproducible_build: default_policy.reproducible_build(),
+                bytecode_optimize_levels: default_policy
+                    .bytecode_optimize_level_overrides()
+                    .clone(),
+                build_directories: BuildDirectories::default(),
+            }
+        }
+    }
+
+    impl StandalonePythonExecutableBuilderOptions {
+        pub fn new_builder(&self) -> Result<Box<StandalonePythonExecutableBuilder>> {
+            let record = PYTHON_DISTRIBUTIONS
+                .find_distribution(&self.target_triple, &self.distribution_flavor)
+                .ok_or_else(|| anyhow!("could not find Python distribution"))?;
+
+            let distribution = get_distribution(&record.location)?;
+
+            let mut policy = PythonPackagingPolicy::default();
+            policy.set_extension_module_filter(self.extension_module_filter.clone());
+            policy.set_resources_policy(self.resources_policy.clone());
+            for (extension, variant) in &self.extension_module_variant_pins {
+                policy.pin_extension_module_variant(extension, variant);
+            }
+            policy.set_collection_memory_budget(self.collection_memory_budget);
+            policy.set_packed_resources_index(self.packed_resources_index);
+            policy.set_analysis_verdicts(self.analysis_verdicts.clone());
+            policy.set_reproducible_build(self.reproducible_build);
+            for (prefix, level) in &self.bytecode_optimize_levels {
+                policy.set_bytecode_optimize_level(prefix, *level);
+            }
+
+            let config = EmbeddedPythonConfig::default();
+
+            StandalonePythonExecutableBuilder::from_distribution(
+                distribution,
+                self.host_triple.clone(),
+                self.target_triple.clone(),
+                self.app_name.clone(),
+                self.libpython_link_mode.clone(),
+                policy,
+                config,
+                self.build_directories.clone(),
+            )
+        }
+    }
+
+    pub fn get_embedded(logger: &slog::Logger) -> Result<EmbeddedPythonContext> {
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let exe = options.new_builder()?;
+
+        exe.to_embedded_python_context(logger, "0", &BTreeSet::new())
+    }
+
+    fn assert_extension_builtin(
+        builder: &StandalonePythonExecutableBuilder,
+        extension: &PythonExtensionModule,
+    ) -> Result<()> {
+        assert_eq!(
+            builder.iter_resources().find_map(|(name, r)| {
+                if *name == extension.name {
+                    Some(r)
+                } else {
+                    None
+                }
+            }),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::BuiltinExtensionModule,
+                name: extension.name.clone(),
+                ..PrePackagedResource::default()
+            })
+        );
+
+        assert_eq!(
+            builder.extension_build_contexts.get(&extension.name),
+            Some(&LibPythonBuildContext {
+                object_files: extension.object_file_data.clone(),
+                init_functions: BTreeMap::from_iter(
+                    [(
+                        extension.name.to_string(),
+                        extension.init_fn.as_ref().clone().unwrap().to_string()
+                    )]
+                    .iter()
+                    .cloned()
+                ),
+                ..LibPythonBuildContext::default()
+            })
+        );
+
+        Ok(())
+    }
+
+    fn assert_extension_shared_library(
+        builder: &StandalonePythonExecutableBuilder,
+        extension: &PythonExtensionModule,
+        location: ConcreteResourceLocation,
+    ) -> Result<()> {
+        let mut entry = PrePackagedResource {
+            flavor: ResourceFlavor::Extension,
+            name: extension.name.clone(),
+            shared_library_dependency_names: Some(vec![]),
+            ..PrePackagedResource::default()
+        };
+
+        match location {
+            ConcreteResourceLocation::InMemory => {
+                assert!(extension.shared_library.is_some());
+                entry.in_memory_extension_module_shared_library =
+                    Some(extension.shared_library.as_ref().unwrap().clone());
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                assert!(extension.shared_library.is_some());
+                entry.relative_path_extension_module_shared_library = Some((
+                    PathBuf::from(prefix).join(format!(
+                        "{}{}",
+                        extension.name, extension.extension_file_suffix
+                    )),
+                    extension.shared_library.as_ref().unwrap().clone(),
+                ));
+            }
+        }
+
+        assert_eq!(
+            builder.iter_resources().find_map(|(name, r)| {
+                if *name == extension.name {
+                    Some(r)
+                } else {
+                    None
+                }
+            }),
+            Some(&entry)
+        );
+
+        // There is no build context for extensions materialized as shared libraries.
+        // This could change if we ever link shared library extension modules from
+        // object files.
+        assert_eq!(builder.extension_build_contexts.get(&extension.name), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_size_report() -> Result<()> {
+        let builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        let report = builder.resource_size_report()?;
+        assert!(report.total_bytes > 0);
+        assert_eq!(report.by_kind.values().sum::<u64>(), report.total_bytes);
+        assert_eq!(report.by_package.values().sum::<u64>(), report.total_bytes);
+        assert_eq!(report.by_resource.values().sum::<u64>(), report.total_bytes);
+
+        let largest = report.largest(3);
+        assert_eq!(largest.len(), 3);
+        assert!(largest[0].1 >= largest[1].1 && largest[1].1 >= largest[2].1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_digest_manifest() -> Result<()> {
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "digest_test".to_string(),
+                source: DataLocation::Memory(b"abc".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let manifest = builder.resource_digest_manifest(DigestAlgorithm::Sha256)?;
+        assert_eq!(
+            manifest.get("digest_test [source]").map(|s| s.as_str()),
+            Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+
+        // Equivalent builders produce equal manifests.
+        let other = builder.clone_box();
+        assert_eq!(
+            other.resource_digest_manifest(DigestAlgorithm::Sha256)?,
+            manifest
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_explain_resource() -> Result<()> {
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "explain_app".to_string(),
+                source: DataLocation::Memory(b"import json\n".to_vec()),
+                is_package: true,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+        builder.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "explain_app".to_string(),
+                relative_name: "data.txt".to_string(),
+                data: DataLocation::Memory(b"data".to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let explanation = builder.explain_resource("explain_app").unwrap();
+        assert_eq!(explanation.kind, ExplainedKind::Package);
+        assert!(!explanation.stdlib);
+        assert_eq!(
+            explanation.placement[0],
+            "resources policy is in-memory-only"
+        );
+        assert!(explanation
+            .imports
+            .as_ref()
+            .unwrap()
+            .contains(&"json".to_string()));
+
+        let explanation = builder.explain_resource("json").unwrap();
+        assert!(explanation.stdlib);
+        assert!(explanation
+            .importers
+            .as_ref()
+            .unwrap()
+            .contains(&"explain_app".to_string()));
+
+        let explanation = builder.explain_resource("explain_app:data.txt").unwrap();
+        assert_eq!(explanation.kind, ExplainedKind::PackageResource);
+        assert_eq!(explanation.total_bytes(), 4);
+
+        let decision = builder
+            .extension_decisions
+            .values()
+            .find(|decision| decision.outcome == ExtensionModuleOutcome::Builtin)
+            .unwrap()
+            .clone();
+        let explanation = builder.explain_resource(&decision.name).unwrap();
+        assert_eq!(explanation.kind, ExplainedKind::ExtensionModule);
+        assert_eq!(
+            explanation.locations["extension module"],
+            "built into libpython"
+        );
+        assert_eq!(explanation.required, decision.required);
+
+        assert!(builder.explain_resource("explain_ap").is_none());
+        assert!(builder
+            .suggest_resource_names("explain_ap")
+            .contains(&"explain_app".to_string()));
+        assert!(builder
+            .suggest_resource_names("explain_app:dat.txt")
+            .contains(&"explain_app:data.txt".to_string()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_embedded_files() -> Result<()> {
+        let logger = get_logger()?;
+        let embedded = get_embedded(&logger)?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let paths = embedded.write_files(temp_dir.path(), &WorkspacePaths::default())?;
+
+        let settings = embedded.python_config_settings(paths.embedded_resources.as_ref().unwrap());
+        assert_eq!(
+            std::fs::read_to_string(&paths.config_rs)?,
+            settings.render_default_python_config_rs()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_embedded_files_with_writer() -> Result<()> {
+        let logger = get_logger()?;
+        let embedded = get_embedded(&logger)?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut writer = ArtifactWriter::new(Some(tx), CancellationToken::new());
+        let paths = embedded.write_files_with_writer(
+            temp_dir.path(),
+            &WorkspacePaths::default(),
+            &mut writer,
+        )?;
+
+        let packed = paths
+            .write_profile
+            .artifacts
+            .iter()
+            .find(|timing| timing.artifact == "packed resources")
+            .unwrap();
+        assert_eq!(&packed.path, paths.embedded_resources.as_ref().unwrap());
+        assert_eq!(packed.bytes, embedded.resources.len() as u64);
+        assert_eq!(
+            paths.write_profile.total_bytes(),
+            writer.profile().total_bytes()
+        );
+
+        drop(writer);
+        let finished = rx
+            .iter()
+            .filter(|event| matches!(event, ArtifactEvent::Finished(_)))
+            .count();
+        assert_eq!(finished, paths.write_profile.artifacts.len());
+
+        // Cancelled writes leave existing files alone.
+        let before = std::fs::read(&paths.libpython)?;
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+        let mut writer = ArtifactWriter::new(None, cancellation);
+        let err = embedded
+            .write_files_with_writer(temp_dir.path(), &WorkspacePaths::default(), &mut writer)
+            .unwrap_err();
+        assert_eq!(err.downcast_ref::<Cancelled>(), Some(&Cancelled));
+        assert_eq!(std::fs::read(&paths.libpython)?, before);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_embedded_files_linker_section() -> Result<()> {
+        let logger = get_logger()?;
+        let mut embedded = get_embedded(&logger)?;
+        embedded.packed_resources_embedding = PackedResourcesEmbedding::LinkerSection {
+            symbol: "pyoxidizer_packed_resources".to_string(),
+        };
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let paths = embedded.write_files(temp_dir.path(), &WorkspacePaths::default())?;
+        assert_eq!(paths.embedded_resources, None);
+        assert!(!temp_dir.path().join("packed-resources").exists());
+
+        let config_rs = std::fs::read_to_string(&paths.config_rs)?;
+        assert_eq!(
+            config_rs,
+            embedded
+                .python_config_settings(Path::new("unused"))
+                .render_default_python_config_rs()
+        );
+        assert!(config_rs.contains("#[link_name = \"pyoxidizer_packed_resources\"]"));
+        assert!(!config_rs.contains("include_bytes!"));
+
+        let blob = embedded.packed_resources_blob();
+        assert_eq!(blob.len(), embedded.resources.len());
+        for line in &[
+            "cargo:packed-resources-symbol=pyoxidizer_packed_resources".to_string(),
+            format!("cargo:packed-resources-length={}", blob.len()),
+            format!("cargo:packed-resources-alignment={}", blob.alignment),
+            format!("cargo:packed-resources-sha256={}", blob.sha256),
+        ] {
+            assert!(paths.cargo_metadata_lines.contains(line));
+        }
+
+        let mut data = Vec::new();
+        assert_eq!(blob.write_to(&mut data)?, blob.len() as u64);
+        blob.verify(&data)?;
+        data.push(0);
+        assert!(blob.verify(&data).is_err());
+
+        let manifest = build_manifest(&embedded)?;
+        assert_eq!(
+            manifest.packed_resources,
+            embedded.packed_resources_embedding
+        );
+        assert_eq!(manifest.files["packed-resources"].sha256, blob.sha256);
+
+        embedded.packed_resources_embedding = PackedResourcesEmbedding::LinkerSection {
+            symbol: "\"); evil(); (\"".to_string(),
+        };
+        assert!(embedded
+            .write_files(temp_dir.path(), &WorkspacePaths::default())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_debug_companion() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+        builder.set_build_clock(BuildClock::Fixed(1_600_000_000));
+        builder.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"x = 1\n".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::Two,
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let production = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(production.debug_companion.is_none());
+
+        builder.packaging_policy.set_debug_companion(true);
+        let debug = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        // Production outputs are identical with and without a companion.
+        assert_eq!(production.resources, debug.resources);
+        assert_eq!(production.module_names, debug.module_names);
+        assert_eq!(
+            production.linking_info.libpythonxy_data,
+            debug.linking_info.libpythonxy_data
+        );
+        assert_eq!(
+            production
+                .python_config_settings(Path::new("packed-resources"))
+                .settings,
+            debug
+                .python_config_settings(Path::new("packed-resources"))
+                .settings
+        );
+        assert_eq!(build_manifest(&production)?, build_manifest(&debug)?);
+
+        let companion = debug.debug_companion.as_ref().unwrap();
+        assert_eq!(
+            companion.build_digest,
+            build_manifest(&production)?.content_digest()
+        );
+        assert_eq!(companion.manifest, build_manifest(&production)?);
+        assert!(companion.files.has_path(Path::new("sources/foo.py")));
+        assert!(companion.files.has_path(&PathBuf::from(format!(
+            "bytecode/__pycache__/foo.{}.pyc",
+            builder.cache_tag()
+        ))));
+
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let archive = companion.write_archive(temp_dir.path())?;
+        assert!(archive
+            .to_string_lossy()
+            .ends_with(&format!("debug-companion-{}.tar", companion.build_digest)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_record() -> Result<()> {
+        let logger = get_logger()?;
+        let embedded = get_embedded(&logger)?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let paths = embedded.write_files(temp_dir.path(), &WorkspacePaths::default())?;
+        let record = LinkRecord::from_path(&paths.link_record)?;
+        assert_eq!(
+            record.to_json()?,
+            embedded.linking_info.link_record.to_json()?
+        );
+
+        if embedded.linking_info.libpython_filename.is_some() {
+            assert!(record.units.is_empty());
+            return Ok(());
+        }
+
+        assert_eq!(record.units[0].extension, None);
+        assert!(!record.units[0].inputs.is_empty());
+
+        let extensions = record
+            .units
+            .iter()
+            .filter_map(|unit| unit.extension.clone())
+            .collect::<BTreeSet<_>>();
+        assert!(extensions.is_subset(&embedded.builtin_extension_module_names));
+
+        if embedded.target_triple.contains("-linux-") {
+            let search = record.find_symbol("Py_Initialize");
+            assert_eq!(search.defined_by(), vec!["<python core>".to_string()]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cargo_metadata_entry_parse() -> Result<()> {
+        for line in &[
+            "cargo:rustc-link-lib=static=pythonXY",
+            "cargo:rustc-link-lib=dl",
+            "cargo:rustc-link-search=native=/build/out",
+            "cargo:rustc-flags=-l foo",
+            "cargo:default-python-config-rs=/build/out/default_python_config.rs",
+            "cargo:rustc-link-search=native=/a=b",
+            "cargo:rustc-link-search=/a=b",
+            "cargo:rustc-link-lib=static:+whole-archive=foo",
+            "cargo:rustc-cfg=feature=\"x\"",
+            "cargo:rustc-env=KEY=a=b",
+        ] {
+            assert_eq!(CargoMetadataEntry::parse(line)?.to_string(), *line);
+        }
+
+        assert_eq!(
+            CargoMetadataEntry::parse("cargo:rustc-link-lib=static=pythonXY")?,
+            CargoMetadataEntry::LinkLib {
+                kind: Some("static".to_string()),
+                name: "pythonXY".to_string()
+            }
+        );
+
+        // Only known kinds are split off link directives.
+        assert_eq!(
+            CargoMetadataEntry::parse("cargo:rustc-link-search=native=/a=b")?,
+            CargoMetadataEntry::LinkSearch {
+                kind: Some("native".to_string()),
+                path: PathBuf::from("/a=b"),
+            }
+        );
+        assert_eq!(
+            CargoMetadataEntry::parse("cargo:rustc-link-search=/a=b")?,
+            CargoMetadataEntry::LinkSearch {
+                kind: None,
+                path: PathBuf::from("/a=b"),
+            }
+        );
+        assert_eq!(
+            CargoMetadataEntry::parse("cargo:rustc-link-lib=static:+whole-archive=foo")?,
+            CargoMetadataEntry::LinkLib {
+                kind: Some("static:+whole-archive".to_string()),
+                name: "foo".to_string()
+            }
+        );
+
+        // Other values are kept whole.
+        assert_eq!(
+            CargoMetadataEntry::parse("cargo:rustc-cfg=feature=\"x\"")?,
+            CargoMetadataEntry::Other {
+                key: "rustc-cfg".to_string(),
+                value: "feature=\"x\"".to_string(),
+            }
+        );
+        assert_eq!(
+            CargoMetadataEntry::parse("cargo:rustc-env=KEY=a=b")?,
+            CargoMetadataEntry::Other {
+                key: "rustc-env".to_string(),
+                value: "KEY=a=b".to_string(),
+            }
+        );
+
+        assert!(CargoMetadataEntry::parse("rustc-link-lib=dl").is_err());
+        assert!(CargoMetadataEntry::parse("cargo:rustc-link-lib").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_embedded_files_cargo_metadata_hook() -> Result<()> {
+        let logger = get_logger()?;
+        let embedded = get_embedded(&logger)?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dest_dir = temp_dir.path();
+
+        let relative = PathBuf::from(dest_dir.file_name().unwrap());
+
+        let paths = embedded.write_files_with_cargo_metadata_hook(dest_dir, false, |entries| {
+            for entry in entries.iter_mut() {
+                if let CargoMetadataEntry::LinkSearch { path, .. } = entry {
+                    if path == dest_dir {
+                        *path = relative.clone();
+                    }
+                }
+            }
+
+            entries.push(CargoMetadataEntry::Other {
+                key: "rustc-link-arg".to_string(),
+                value: "-Wl,--as-needed".to_string(),
+            });
+
+            Ok(())
+        })?;
+
+        assert!(paths.cargo_metadata_lines.contains(&format!(
+            "cargo:rustc-link-search=native={}",
+            relative.display()
+        )));
+        assert!(!paths.cargo_metadata_lines.contains(&format!(
+            "cargo:rustc-link-search=native={}",
+            dest_dir.display()
+        )));
+        assert_eq!(
+            paths.cargo_metadata_lines.last(),
+            Some(&"cargo:rustc-link-arg=-Wl,--as-needed".to_string())
+        );
+        assert_eq!(
+            std::fs::read_to_string(&paths.cargo_metadata)?,
+            paths.cargo_metadata_lines.join("\n")
+        );
+
+        let remove_search = |entries: &mut Vec<CargoMetadataEntry>| -> Result<()> {
+            entries.retain(|entry| match entry {
+                CargoMetadataEntry::LinkSearch { path, .. } => path != dest_dir,
+                _ => true,
+            });
+
+            Ok(())
+        };
+
+        let err = embedded
+            .write_files_with_cargo_metadata_hook(dest_dir, false, remove_search)
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "cargo metadata hook removed required entry cargo:rustc-link-search=native={}",
+                dest_dir.display()
+            )
+        );
+
+        let paths = embedded.write_files_with_cargo_metadata_hook(dest_dir, true, remove_search)?;
+        assert!(!paths.cargo_metadata_lines.contains(&format!(
+            "cargo:rustc-link-search=native={}",
+            dest_dir.display()
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_embedded_files_relative_paths() -> Result<()> {
+        let logger = get_logger()?;
+        let embedded = get_embedded(&logger)?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dest_dir = temp_dir.path().join("artifacts");
+        std::fs::create_dir(&dest_dir)?;
+
+        let workspace = WorkspacePaths::new(temp_dir.path(), PathStyle::Relative);
+
+        // Search paths of the distribution are outside the workspace.
+        let paths = embedded.write_files_with_hooks(
+            &dest_dir,
+            &workspace,
+            false,
+            |_| Ok(()),
+            |entries| {
+                entries.retain(|entry| match entry.path() {
+                    Some(path) => path.starts_with(&dest_dir),
+                    None => true,
+                });
+
+                Ok(())
+            },
+        )?;
+
+        let relative = Path::new("artifacts");
+        assert!(paths.cargo_metadata_lines.contains(&format!(
+            "cargo:rustc-link-search=native={}",
+            relative.display()
+        )));
+        assert!(paths.cargo_metadata_lines.contains(&format!(
+            "cargo:default-python-config-rs={}",
+            relative.join("default_python_config.rs").display()
+        )));
+        assert_eq!(
+            std::fs::read_to_string(dest_dir.join("workspace-root.txt"))?,
+            format!("{}", temp_dir.path().display())
+        );
+        assert!(std::fs::read_to_string(&paths.config_rs)?
+            .contains("include_bytes!(r#\"packed-resources\"#)"));
+
+        // Loading re-anchors paths against the consumer's root.
+        let consumer_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let entries = load_cargo_metadata(
+            &paths.cargo_metadata,
+            &WorkspacePaths::new(consumer_dir.path(), PathStyle::Relative),
+        )?;
+        assert!(entries.contains(&CargoMetadataEntry::LinkSearch {
+            kind: Some("native".to_string()),
+            path: consumer_dir.path().join("artifacts"),
+        }));
+
+        // Paths outside the workspace root can't be recorded relative to it.
+        let outside = WorkspacePaths::new(consumer_dir.path(), PathStyle::Relative);
+        let err = embedded.write_files(&dest_dir, &outside).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "packed resources references {}, which is outside workspace root {}",
+                dest_dir.join("packed-resources").display(),
+                consumer_dir.path().display()
+            )
+        );
+
+        // Mixing absolute and relative paths is rejected.
+        std::fs::write(
+            &paths.cargo_metadata,
+            format!(
+                "cargo:rustc-link-search=native={}\ncargo:default-python-config-rs=config.rs",
+                dest_dir.display()
+            ),
+        )?;
+        let err = load_cargo_metadata(&paths.cargo_metadata, &workspace).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "loading {}: cargo:default-python-config-rs=config.rs has relative path \
+                 config.rs while earlier entry cargo:rustc-link-search=native={} is absolute; \
+                 paths must be all absolute or all relative",
+                paths.cargo_metadata.display(),
+                dest_dir.display()
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_embedded_files_importer_hook() -> Result<()> {
+        let logger = get_logger()?;
+        let embedded = get_embedded(&logger)?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dest_dir = temp_dir.path();
+
+        let paths = embedded.write_files_with_hooks(
+            dest_dir,
+            &WorkspacePaths::default(),
+            false,
+            |importer| {
+                importer.finders.push(ImporterFinder::Filesystem);
+                importer.sys_paths.push("$ORIGIN/lib".to_string());
+
+                Ok(())
+            },
+            |_| Ok(()),
+        )?;
+
+        assert_eq!(
+            paths.importer.finders,
+            vec![ImporterFinder::InMemory, ImporterFinder::Filesystem]
+        );
+        let config_rs = std::fs::read_to_string(&paths.config_rs)?;
+        assert!(config_rs.contains("filesystem_importer: true,"));
+        assert!(config_rs.contains("sys_paths: [\"$ORIGIN/lib\".to_string()].to_vec(),"));
+
+        // Resources are served by the in-memory finder, so it can't be disabled.
+        let err = embedded
+            .write_files_with_hooks(
+                dest_dir,
+                &WorkspacePaths::default(),
+                false,
+                |importer| {
+                    importer.finders.clear();
+                    importer.packed_resources.clear();
+
+                    Ok(())
+                },
+                |_| Ok(()),
+            )
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("invalid importer configuration: importer-finder-disabled: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strict_dunder_file() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+
+        builder.packaging_policy.set_strict_dunder_file(true);
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "dunder_test".to_string(),
+                source: DataLocation::Memory(b"print(__file__)".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::Validate);
+        assert_eq!(diagnostics.class, FailureClass::Policy);
+        assert!(diagnostics.codes.contains("dunder-file-in-memory"));
+        let err = err.to_string();
+        assert!(err.contains(
+            "dunder-file-in-memory: package dunder_test has in-memory modules referencing __file__"
+        ));
+
+        builder.packaging_policy.add_dunder_file_waiver("*");
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_syntax_compatibility() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "newer_syntax".to_string(),
+                source: DataLocation::Memory(b"type Point = tuple[float, float]\n".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        // Findings are warnings by default, so compiling fails instead.
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::Compile);
+        assert!(diagnostics.warnings.iter().any(|warning| warning.contains(
+            "syntax-requires-newer-python: package newer_syntax requires Python >= 3.12"
+        )));
+
+        builder
+            .packaging_policy
+            .set_strict_syntax_compatibility(true);
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::PrepareResources);
+        assert_eq!(diagnostics.class, FailureClass::Policy);
+        assert!(diagnostics.codes.contains("syntax-requires-newer-python"));
+        assert!(diagnostics.resources.contains("newer_syntax"));
+
+        builder
+            .packaging_policy
+            .set_scan_syntax_compatibility(false);
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::Compile);
+        assert!(!diagnostics
+            .warnings
+            .iter()
+            .any(|warning| warning.contains("syntax-requires-newer-python")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reproducible_bytecode() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        let magic_number = python_version_from_cache_tag(builder.cache_tag())
+            .as_deref()
+            .and_then(magic_number_for_python_version)
+            .unwrap();
+        let pyc = |mode| -> Result<FileInstall> {
+            Ok((
+                PathBuf::from("lib/__pycache__/foo.pyc"),
+                DataLocation::Memory(
+                    [
+                        compute_bytecode_header(magic_number, mode)?,
+                        b"code".to_vec(),
+                    ]
+                    .concat(),
+                ),
+                false,
+            ))
+        };
+        let compiled = CompiledResourcesCollection {
+            resources: BTreeMap::new(),
+            extra_files: vec![
+                pyc(BytecodeHeaderMode::ModifiedTimeAndSourceSize((0, 4)))?,
+                pyc(BytecodeHeaderMode::UncheckedHash(42))?,
+                pyc(BytecodeHeaderMode::ModifiedTimeAndSourceSize((
+                    1600000000, 4,
+                )))?,
+            ],
+            extra_file_owners: vec!["foo".to_string(); 3],
+            compile_errors: vec![],
+            interior_padding: None,
+        };
+        let warnings = |builder: &StandalonePythonExecutableBuilder| -> Result<Vec<String>> {
+            let mut diagnostics = DiagnosticsRecorder::new(&logger);
+            builder.verify_reproducible_bytecode(&mut diagnostics, &compiled)?;
+            let err = diagnostics.into_error(anyhow!("done"));
+
+            Ok(build_diagnostics(&err).unwrap().warnings.clone())
+        };
+
+        // Nothing is checked unless the build should be reproducible.
+        assert!(warnings(&builder)?.is_empty());
+
+        builder.packaging_policy.set_reproducible_build(true);
+        assert_eq!(
+            warnings(&builder)?,
+            vec!["reproducible build requested but 1 installed .pyc files carry source modification times (e.g. lib/__pycache__/foo.pyc); use a hash based pyc invalidation mode or set SOURCE_DATE_EPOCH".to_string()]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reproducible_resources() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions {
+            reproducible_build: true,
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+
+        let build = || -> Result<Vec<u8>> {
+            let mut builder = options.new_builder()?;
+
+            // Frozensets of strings are ordered by their hashes.
+            builder.add_python_module_bytecode_from_source(
+                &PythonModuleBytecodeFromSource {
+                    name: "foo".to_string(),
+                    source: DataLocation::Memory(
+                        b"def f(x):\n    return x in {'alpha', 'beta', 'gamma', 'delta'}\n"
+                            .to_vec(),
+                    ),
+                    optimize_level: BytecodeOptimizationLevel::Zero,
+                    is_package: true,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+            for name in &["a.txt", "b.txt", "c.txt", "d.txt", "e.txt"] {
+                builder.add_python_package_resource(
+                    &PythonPackageResource {
+                        leaf_package: "foo".to_string(),
+                        relative_name: name.to_string(),
+                        data: DataLocation::Memory(name.as_bytes().to_vec()),
+                        is_stdlib: false,
+                        is_test: false,
+                    },
+                    None,
+                )?;
+            }
+
+            Ok(builder
+                .to_embedded_python_context(&logger, "0", &BTreeSet::new())?
+                .resources)
+        };
+
+        assert_eq!(build()?, build()?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_runtime_env_validation() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+
+        builder.config.write_modules_directory_env = Some("PYTHONPATH".to_string());
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains(
+            "runtime-env-python-collision: write_modules_directory_env reads PYTHONPATH"
+        ));
+
+        builder.config.write_modules_directory_env = None;
+        builder.packaging_policy.set_forbid_runtime_env(true);
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("runtime-env-forbidden: the packaging policy forbids reading environment variables at run-time, but shared_library_extraction_dir reads HOME, LOCALAPPDATA, XDG_CACHE_HOME"));
+
+        builder.config.shared_library_extraction_dir = None;
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(context.runtime_env().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_analysis_verdicts() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let verdicts_path = td.path().join("verdicts.json");
+        std::fs::write(
+            &verdicts_path,
+            br#"{"version": 1, "packages": {"needs_fs": {"needs_filesystem": true}, "uses_file": {"needs_filesystem": false}, "typo": {"lazy": true}}}"#,
+        )?;
+
+        let options = StandalonePythonExecutableBuilderOptions {
+            resources_policy: PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(
+                "lib".to_string(),
+            ),
+            analysis_verdicts: Some(verdicts_path),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let mut builder = options.new_builder()?;
+        builder.packaging_policy.set_strict_dunder_file(true);
+
+        for (name, source) in &[
+            ("needs_fs", &b"import os"[..]),
+            ("uses_file", b"print(__file__)"),
+        ] {
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(source.to_vec()),
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+        }
+
+        // The verdict places the module on the filesystem even though the
+        // __file__ heuristic finds nothing.
+        let resources = builder
+            .iter_resources()
+            .map(|(name, resource)| (name.clone(), resource.clone()))
+            .collect::<BTreeMap<_, _>>();
+        assert!(resources["needs_fs"].relative_path_module_source.is_some());
+        assert!(resources["uses_file"].in_memory_source.is_some());
+        assert_eq!(
+            builder.verdict_conflicts.iter().collect::<Vec<_>>(),
+            vec!["needs_fs: needs_filesystem is true but the module doesn't reference __file__"]
+        );
+        assert_eq!(
+            builder.analysis_verdicts.unknown_attributes(),
+            &[("typo".to_string(), "lazy".to_string())]
+        );
+
+        // The verdict waives the in-memory module referencing __file__.
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        // In-memory resources needing the filesystem are reported like
+        // modules referencing __file__.
+        builder
+            .packaging_policy
+            .set_resources_policy(PythonResourcesPolicy::InMemoryOnly);
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "needs_fs".to_string(),
+                source: DataLocation::Memory(b"import os".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::InMemory),
+        )?;
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("analysis-needs-filesystem: static analysis requires loading from the filesystem, but these are in memory: needs_fs"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_site_packages_shim() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions {
+            resources_policy: PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(
+                "lib".to_string(),
+            ),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let mut builder = options.new_builder()?;
+        builder.config.site_packages_shim = Some("site-packages".to_string());
+        builder.config.site_packages_shim_packages = vec!["foo".to_string()];
+
+        for (name, source) in &[
+            ("foo", &b"import os"[..]),
+            ("foo.paths", b"import site\nsite.getsitepackages()"),
+            ("bar", b"import sysconfig\nsysconfig.get_paths()"),
+        ] {
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(source.to_vec()),
+                    is_package: *name == "foo",
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+        }
+
+        // Shimmed packages are only installed in the shim directory.
+        let resources = builder
+            .iter_resources()
+            .map(|(name, resource)| (name.clone(), resource.clone()))
+            .collect::<BTreeMap<_, _>>();
+        for name in &["foo", "foo.paths"] {
+            assert_eq!(resources[*name].in_memory_source, None);
+            assert_eq!(
+                resources[*name]
+                    .relative_path_module_source
+                    .as_ref()
+                    .map(|(prefix, _)| prefix.as_str()),
+                Some("site-packages")
+            );
+        }
+        assert!(resources["bar"].in_memory_source.is_some());
+
+        let report = verify_site_packages_usage(
+            builder.iter_resources(),
+            &builder.config.site_packages_shim_packages,
+        )?;
+        assert_eq!(
+            report.offenders.keys().collect::<Vec<_>>(),
+            vec![&"bar".to_string()]
+        );
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        let settings = context.python_config_settings(Path::new("packed-resources"));
+        assert!(settings.settings.contains(&(
+            "site_packages_shim".to_string(),
+            "Some(r###\"site-packages\"###.to_string())".to_string()
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_layered_package_roots() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let base = td.path().join("base");
+        let overlay = td.path().join("overlay");
+
+        let files: &[(&Path, &str, &[u8])] = &[
+            (&base, "foo/__init__.py", b"# base"),
+            (&base, "foo/bar.py", b"# base"),
+            (&base, "foo/old.py", b"# base"),
+            (&base, "foo/data.txt", b"base"),
+            (&overlay, "foo/bar.py", b"# overlay"),
+            (&overlay, "foo/new.py", b"# overlay"),
+            (&overlay, "foo/data.txt", b"overlay"),
+            (&overlay, "foo/old.py.pyoxidizer-deleted", b""),
+        ];
+        for (root, path, data) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, data)?;
+        }
+
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        let layered = builder.read_layered_package_roots(
+            &logger,
+            &[&base, &overlay],
+            &["foo".to_string()],
+        )?;
+
+        let mut sources = BTreeMap::new();
+        let mut resources = BTreeMap::new();
+        let mut origins = BTreeMap::new();
+        for resource in &layered.resources {
+            match resource {
+                PythonResource::ModuleSource(m) => {
+                    sources.insert(m.name.clone(), m.source.resolve()?);
+                    origins.insert(m.name.clone(), m.source.path().unwrap().to_path_buf());
+                }
+                PythonResource::Resource(r) => {
+                    resources.insert(r.relative_name.clone(), r.data.resolve()?);
+                    origins.insert(
+                        r.relative_name.clone(),
+                        r.data.path().unwrap().to_path_buf(),
+                    );
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(
+            sources.keys().collect::<Vec<_>>(),
+            vec!["foo", "foo.bar", "foo.new"]
+        );
+        assert_eq!(sources["foo"], b"# base".to_vec());
+        assert_eq!(sources["foo.bar"], b"# overlay".to_vec());
+        assert_eq!(resources["data.txt"], b"overlay".to_vec());
+
+        // Resources refer to the files of the layers providing them.
+        assert_eq!(origins["foo"], base.join("foo/__init__.py"));
+        assert_eq!(origins["foo.bar"], overlay.join("foo/bar.py"));
+        assert_eq!(origins["foo.new"], overlay.join("foo/new.py"));
+        assert_eq!(origins["data.txt"], overlay.join("foo/data.txt"));
+
+        assert_eq!(
+            layered
+                .tree
+                .overrides
+                .iter()
+                .map(|o| (o.path.clone(), o.base.clone(), o.overlay.clone()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    PathBuf::from("foo/bar.py"),
+                    base.join("foo/bar.py"),
+                    overlay.join("foo/bar.py")
+                ),
+                (
+                    PathBuf::from("foo/data.txt"),
+                    base.join("foo/data.txt"),
+                    overlay.join("foo/data.txt")
+                ),
+            ]
+        );
+        assert_eq!(layered.tree.deletions.len(), 1);
+        assert_eq!(layered.tree.deletions[0].base, base.join("foo/old.py"));
+
+        // Replaced and removed files are reported by the build.
+        builder.add_layered_tree(&layered.tree)?;
+        for resource in &layered.resources {
+            if let PythonResource::Resource(r) = resource {
+                builder.add_python_package_resource(
+                    r,
+                    Some(ConcreteResourceLocation::RelativePath("lib".to_string())),
+                )?;
+            }
+        }
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert_eq!(context.layer_overrides, layered.tree.overrides);
+        assert_eq!(context.layer_deletions, layered.tree.deletions);
+        assert_eq!(
+            context
+                .extra_files
+                .provenance(Path::new("lib/foo/data.txt"))
+                .unwrap(),
+            format!(
+                "resource foo from {} overriding {}",
+                overlay.join("foo/data.txt").display(),
+                base.join("foo/data.txt").display()
+            )
+        );
+
+        let manifest = build_manifest(&context)?;
+        assert_eq!(manifest.layer_overrides, layered.tree.overrides);
+        assert_eq!(manifest.layer_deletions, layered.tree.deletions);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_test_classification() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let root = td.path();
+
+        let files: &[(&str, &[u8])] = &[
+            ("foo/__init__.py", b""),
+            ("foo/tests/__init__.py", b""),
+            ("foo/tests/data/sample.json", b"{}"),
+            ("foo/testing/__init__.py", b""),
+            ("foo-1.0.dist-info/METADATA", b"Name: foo\nVersion: 1.0\n"),
+            (
+                "foo-1.0.dist-info/RECORD",
+                b"foo/__init__.py,,\nfoo/tests/__init__.py,,\nfoo/testing/__init__.py,,\n",
+            ),
+        ];
+        for (path, data) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, data)?;
+        }
+
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        builder
+            .test_classification_overrides
+            .add_runtime_package("foo", "foo.testing");
+
+        for resource in builder.read_package_root(&logger, root, &["foo".to_string()])? {
+            match resource {
+                PythonResource::ModuleSource(m) => {
+                    assert_eq!(m.is_test, m.name == "foo.tests", "{}", m.name);
+                    builder.add_python_module_source(&m, None)?;
+                }
+                PythonResource::Resource(r) => {
+                    assert!(r.is_test);
+                    builder.add_python_package_resource(&r, None)?;
+                }
+                _ => {}
+            }
+        }
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        let mut wanted = BTreeMap::new();
+        wanted.insert(
+            "foo.tests".to_string(),
+            vec![
+                "resource data/sample.json".to_string(),
+                "source".to_string(),
+            ]
+            .into_iter()
+            .collect::<BTreeSet<_>>(),
+        );
+        assert_eq!(context.test_payloads, wanted);
+        assert_eq!(build_manifest(&context)?.test_payloads, wanted);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_install_collisions() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions {
+            resources_policy: PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(
+                "lib".to_string(),
+            ),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let mut builder = options.new_builder()?;
+        let location = ConcreteResourceLocation::RelativePath("lib".to_string());
+
+        // Module foo.bar and package resource bar.py of foo are both
+        // installed at lib/foo/bar.py.
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo.bar".to_string(),
+                source: DataLocation::Memory(b"x = 1".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(location.clone()),
+        )?;
+        builder.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "bar.py".to_string(),
+                data: DataLocation::Memory(b"x = 1".to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(location),
+        )?;
+
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::PrepareResources);
+        assert_eq!(diagnostics.class, FailureClass::Policy);
+        let err = err.to_string();
+        assert_eq!(
+            err,
+            "files would overwrite each other when installed: lib/foo/bar.py is installed by package resource bar.py of foo from memory and source of foo.bar from memory"
+        );
+
+        // Identical content can be allowed.
+        builder
+            .packaging_policy
+            .set_allow_identical_install_collisions(true);
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_file_content() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let source_path = td.path().join("pinned.py");
+        std::fs::write(&source_path, "x = 1\n")?;
+
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        builder.packaging_policy.set_pin_file_content(true);
+        // Spill everything to exercise reading pinned content from disk.
+        builder.packaging_policy.set_pin_spill_threshold(0);
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "pinned".to_string(),
+                source: DataLocation::from_path(source_path.clone()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(String::from_utf8(context.module_names)?
+            .lines()
+            .any(|name| name == "pinned"));
+
+        // The builder's own resources still reference the file.
+        assert_eq!(
+            builder
+                .resources_collector
+                .iter_resources()
+                .find(|(name, _)| *name == "pinned")
+                .unwrap()
+                .1
+                .in_memory_source,
+            Some(DataLocation::from_path(source_path))
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pin_file_content_relative_path() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let source_path = td.path().join("pinned.py");
+        std::fs::write(&source_path, "x = 1\n")?;
+
+        let options = StandalonePythonExecutableBuilderOptions {
+            build_directories: BuildDirectories {
+                spill_dir: Some(td.path().to_path_buf()),
+                ..BuildDirectories::default()
+            },
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let mut builder = options.new_builder()?;
+        builder.packaging_policy.set_pin_file_content(true);
+        builder.packaging_policy.set_pin_spill_threshold(0);
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "pinned".to_string(),
+                source: DataLocation::from_path(source_path),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::RelativePath("lib".to_string())),
+        )?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert_eq!(context.scratch_dirs.len(), 1);
+        let spill_dir = context.scratch_dirs[0].path().to_path_buf();
+        assert!(spill_dir.starts_with(td.path()));
+
+        // Spilled content outlives building the context.
+        let dest = td.path().join("dest");
+        context.extra_files.write_to_path(&dest)?;
+        assert_eq!(
+            std::fs::read(dest.join("lib").join("pinned.py"))?,
+            b"x = 1\n"
+        );
+
+        drop(context);
+        assert!(!spill_dir.exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_patches() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.add_python_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "patched".to_string(),
+                version: "1.0".to_string(),
+                name: "METADATA".to_string(),
+                data: DataLocation::Memory(b"Name: patched\nVersion: 1.0\n".to_vec()),
+            },
+            None,
+        )?;
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "patched".to_string(),
+                source: DataLocation::Memory(b"PATH = '/usr/share/patched'\n".to_vec()),
+                is_package: true,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let diff = "--- a/patched/__init__.py
++++ b/patched/__init__.py
+@@ -1 +1 @@
+-PATH = '/usr/share/patched'
++PATH = __file__
+";
+        builder.add_package_patch(PackagePatch::new("fix.patch", "patched", "<2", diff)?)?;
+        builder.add_package_patch(PackagePatch::new("new.patch", "patched", ">=2", diff)?)?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert_eq!(
+            context.applied_patches,
+            vec![AppliedPatch {
+                name: "fix.patch".to_string(),
+                distribution: "patched".to_string(),
+                version: "1.0".to_string(),
+                files: vec!["patched/__init__.py".to_string()],
+            }]
+        );
+
+        let manifest_path = temp_dir.path().join("manifest.json");
+        write_build_manifest(&context, &manifest_path)?;
+        assert_eq!(
+            BuildManifest::from_path(&manifest_path)?.patches,
+            context.applied_patches
+        );
+
+        // A patch that doesn't apply fails the build.
+        builder.add_package_patch(PackagePatch::new("fix-again.patch", "patched", "", diff)?)?;
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.starts_with("patch fix-again.patch can't be applied to patched 1.0: "));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_collection_memory_budget() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions {
+            collection_memory_budget: Some(16),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let mut builder = options.new_builder()?;
+
+        let source = b"value = 'larger than the memory budget'\n".to_vec();
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "spilled".to_string(),
+                source: DataLocation::Memory(source.clone()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let resource = builder
+            .iter_resources()
+            .find(|(name, _)| *name == "spilled")
+            .map(|(_, resource)| resource)
+            .unwrap();
+        assert!(matches!(
+            resource.in_memory_source,
+            Some(DataLocation::Path { .. })
+        ));
+
+        let statistics = builder.collection_memory_statistics().unwrap();
+        assert!(statistics.peak_resident_bytes <= 16);
+        assert!(statistics.spilled_count >= 1);
+        assert!(statistics.spilled_bytes >= source.len() as u64);
+
+        // Spilled payloads are embedded like any other.
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        let resource = python_packed_resources::parser::load_resources(&context.resources)
+            .map_err(|e| anyhow!(e))?
+            .map(|resource| resource.map_err(|e| anyhow!(e)))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .find(|resource| resource.name == "spilled")
+            .unwrap();
+        assert_eq!(resource.in_memory_source.unwrap().to_vec(), source);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_wheel() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let module = |name: &str, is_package: bool, source: &[u8]| PythonModuleSource {
+            name: name.to_string(),
+            source: DataLocation::Memory(source.to_vec()),
+            is_package,
+            cache_tag: builder.cache_tag().to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let app = module("wheelapp", true, b"VALUE = 42\n");
+        let util = module(
+            "wheelapp.util",
+            false,
+            b"import pkgutil\nDATA = pkgutil.get_data('wheelapp', 'data.txt')\n",
+        );
+        let other = module("otherapp", false, b"raise ImportError\n");
+
+        builder.add_python_module_source(&app, None)?;
+        builder.add_python_module_source(&util, None)?;
+        builder.add_python_module_source(&other, None)?;
+        builder.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "wheelapp".to_string(),
+                relative_name: "data.txt".to_string(),
+                data: DataLocation::Memory(b"hello".to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+
+        let wheel =
+            builder.write_wheel("wheelapp", "1.0", temp_dir.path(), WheelTags::default())?;
+        assert_eq!(wheel, temp_dir.path().join("wheelapp-1.0-py3-none-any.whl"));
+
+        // The wheel installs and imports with the target interpreter.
+        builder.distribution.ensure_pip(&logger)?;
+        let site_packages = temp_dir.path().join("site-packages");
+        let output = std::process::Command::new(builder.host_python_exe_path())
+            .args(&["-m", "pip", "--disable-pip-version-check", "install"])
+            .args(&["--no-deps", "--no-index", "--target"])
+            .arg(&site_packages)
+            .arg(&wheel)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert!(site_packages
+            .join("wheelapp-1.0.dist-info")
+            .join("RECORD")
+            .exists());
+        assert!(!site_packages.join("otherapp.py").exists());
+
+        let output = std::process::Command::new(builder.host_python_exe_path())
+            .args(&[
+                "-c",
+                "import wheelapp, wheelapp.util; print(wheelapp.VALUE, wheelapp.util.DATA)",
+            ])
+            .env("PYTHONPATH", &site_packages)
+            .output()?;
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "42 b'hello'"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recovery_set() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        let module = |name: &str, source: &[u8]| PythonModuleSource {
+            name: name.to_string(),
+            source: DataLocation::Memory(source.to_vec()),
+            is_package: false,
+            cache_tag: builder.cache_tag().to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let app = module("app", b"main()\n");
+        let recovery_app = module("app", b"recover()\n");
+        let diagnostics = module("diagnostics", b"report()\n");
+
+        assert!(builder
+            .add_recovery_resource(&PythonResource::ModuleSource(diagnostics.clone()))
+            .is_err());
+
+        let mut policy = builder.packaging_policy.clone();
+        policy.set_resources_policy(PythonResourcesPolicy::FilesystemRelativeOnly(
+            "lib".to_string(),
+        ));
+        assert!(builder
+            .enable_recovery_set(&policy, RecoverySelection::default())
+            .is_err());
+
+        policy.set_resources_policy(PythonResourcesPolicy::InMemoryOnly);
+        builder.enable_recovery_set(
+            &policy,
+            RecoverySelection {
+                marker_file: Some("$ORIGIN/RECOVERY".to_string()),
+            },
+        )?;
+        builder.add_python_module_source(&app, None)?;
+        builder.add_recovery_resource(&PythonResource::ModuleSource(recovery_app))?;
+        builder.add_recovery_resource(&PythonResource::ModuleSource(diagnostics))?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        // The recovery set isn't part of the main set's module names.
+        let module_names = String::from_utf8(context.module_names.clone())?;
+        assert!(module_names.lines().any(|name| name == "app"));
+        assert!(!module_names.lines().any(|name| name == "diagnostics"));
+
+        let resources = python_packed_resources::parser::load_resources(&context.resources)
+            .map_err(|e| anyhow!(e))?
+            .map(|resource| resource.map_err(|e| anyhow!(e)))
+            .collect::<Result<Vec<_>>>()?;
+        let source = |name: &str, set: ResourceSet| {
+            resources
+                .iter()
+                .find(|resource| resource.name == name && resource.resource_set == set)
+                .map(|resource| resource.in_memory_source.as_ref().unwrap().to_vec())
+        };
+        assert_eq!(source("app", ResourceSet::Main), Some(b"main()\n".to_vec()));
+        assert_eq!(
+            source("app", ResourceSet::Recovery),
+            Some(b"recover()\n".to_vec())
+        );
+        assert_eq!(source("diagnostics", ResourceSet::Main), None);
+        assert!(source("diagnostics", ResourceSet::Recovery).is_some());
+
+        let cost = context.recovery_set_cost.unwrap();
+        assert_eq!(cost.resources_count, 2);
+        assert!(cost.incremental_bytes > 0);
+        assert!(context
+            .runtime_env()
+            .settings(crate::py_packaging::pyembed::RECOVERY_ENV)
+            .is_some());
+        assert_eq!(build_manifest(&context)?.recovery_set, Some(cost));
+
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let paths = context.write_files(td.path(), &WorkspacePaths::default())?;
+        let config_rs = std::fs::read_to_string(&paths.config_rs)?;
+        assert!(config_rs.contains(
+            "resource_set: pyembed::select_resource_set(Some(r###\"$ORIGIN/RECOVERY\"###))"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_payload_storage() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        assert_eq!(builder.payload_storage().backend(), "embedded");
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "app".to_string(),
+                source: DataLocation::Memory(b"main()\n".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+        builder.set_payload_storage(PayloadStorage::directory(
+            &td.path().join("payloads"),
+            "$ORIGIN/payloads",
+        )?);
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert_eq!(context.payload_backend, "directory");
+        assert_eq!(build_manifest(&context)?.payload_backend, "directory");
+
+        let app = python_packed_resources::parser::load_resources(&context.resources)
+            .unwrap()
+            .map(|r| r.unwrap())
+            .find(|r| r.name == "app")
+            .unwrap();
+        assert!(app.in_memory_source.is_none());
+        let payload = &app.external_payloads[0];
+        assert_eq!(
+            std::fs::read(td.path().join("payloads").join(payload.location.as_ref()))?,
+            b"main()\n"
+        );
+
+        let paths = context.write_files(td.path(), &WorkspacePaths::default())?;
+        let config_rs = std::fs::read_to_string(&paths.config_rs)?;
+        assert!(config_rs.contains(
+            "external_payloads: Some(pyembed::ExternalPayloadsConfig { \
+             directory: Some(r###\"$ORIGIN/payloads\"###.to_string()), \
+             cache_dir: None, fetcher: None })"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_directories() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let directories = BuildDirectories {
+            scratch_dir: Some(td.path().join("scratch")),
+            cache_dir: Some(td.path().join("cache")),
+            spill_dir: Some(td.path().join("spill")),
+            pip_build_dir: Some(td.path().join("pip")),
+            // Keep everything so we can see where it was written.
+            retention: RetentionPolicy::Keep,
+        };
+
+        let options = StandalonePythonExecutableBuilderOptions {
+            collection_memory_budget: Some(16),
+            build_directories: directories.clone(),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        assert!(options.new_builder().is_err());
+
+        for name in &["scratch", "cache", "spill", "pip"] {
+            std::fs::create_dir(td.path().join(name))?;
+        }
+        let mut builder = options.new_builder()?;
+        builder.packaging_policy.set_pin_file_content(true);
+        builder.packaging_policy.set_pin_spill_threshold(0);
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "spilled".to_string(),
+                source: DataLocation::Memory(b"value = 'larger than the memory budget'\n".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+        match &builder
+            .iter_resources()
+            .find(|(name, _)| *name == "spilled")
+            .unwrap()
+            .1
+            .in_memory_source
+        {
+            Some(DataLocation::Path { path, .. }) => {
+                assert!(path.starts_with(td.path().join("spill")))
+            }
+            _ => panic!("expected spilled to be spilled"),
+        }
+
+        // Every temporary directory was created in its configured root.
+        let entries = |name: &str| -> Result<Vec<String>> {
+            let mut res = std::fs::read_dir(td.path().join(name))?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().to_string()))
+                .collect::<Result<Vec<_>>>()?;
+            res.sort();
+            Ok(res)
+        };
+
+        // Bytecode compilers keep their files in the scratch directory while
+        // they run.
+        {
+            let _compiler = builder.new_bytecode_compiler()?;
+            let scratch = entries("scratch")?;
+            assert_eq!(scratch.len(), 1);
+            assert!(scratch[0].starts_with("bytecode-compiler"));
+        }
+        assert!(entries("scratch")?.is_empty());
+
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        let spill = entries("spill")?;
+        assert_eq!(spill.len(), 2);
+        assert!(spill[0].starts_with("pyoxidizer-pinned-content"));
+        assert!(spill[1].starts_with("python-packaging-spill"));
+
+        let bytes_written = builder.directory_bytes_written();
+        assert!(bytes_written[&DirectoryRole::Spill] > 0);
+
+        if builder.link_mode == LibpythonLinkMode::Static {
+            let scratch = entries("scratch")?;
+            assert_eq!(scratch.len(), 1);
+            assert!(scratch[0].starts_with("pyoxidizer-build-exe"));
+            assert!(bytes_written[&DirectoryRole::Scratch] > 0);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_cache() -> Result<()> {
+        let logger = get_logger()?;
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        builder.set_bytecode_cache(Some(BytecodeCache::new(
+            BuildCache::new(td.path())?,
+            GcPolicy::default(),
+        )));
+
+        let first = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        let cold = builder.bytecode_cache_statistics();
+        assert_eq!(cold.hits, 0);
+        assert!(cold.misses > 0);
+
+        // Identical sources are compiled once.
+        let second = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        let warm = builder.bytecode_cache_statistics();
+        assert_eq!(warm.hits, cold.misses);
+        assert_eq!(warm.misses, cold.misses);
+        assert_eq!(first.resources, second.resources);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_python_module_bytecode_all_levels() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.add_python_module_bytecode_all_levels(
+            &PythonModuleSource {
+                name: "all_levels".to_string(),
+                source: DataLocation::Memory(b"\"\"\"docstring\"\"\"\nassert True\n".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::InMemory),
+        )?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        let resource = python_packed_resources::parser::load_resources(&context.resources)
+            .map_err(|e| anyhow!(e))?
+            .map(|resource| resource.map_err(|e| anyhow!(e)))
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .find(|resource| resource.name == "all_levels")
+            .unwrap();
+
+        let bytecode = resource.in_memory_bytecode.unwrap();
+        let opt1 = resource.in_memory_bytecode_opt1.unwrap();
+        let opt2 = resource.in_memory_bytecode_opt2.unwrap();
+
+        // Each level is optimized as if compiled on its own.
+        assert_ne!(bytecode, opt1);
+        assert_ne!(opt1, opt2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_optimize_level_overrides() -> Result<()> {
+        let mut options = StandalonePythonExecutableBuilderOptions::default();
+        options
+            .bytecode_optimize_levels
+            .insert("json".to_string(), BytecodeOptimizationLevel::Two);
+        options
+            .bytecode_optimize_levels
+            .insert("json.decoder".to_string(), BytecodeOptimizationLevel::One);
+        let builder = options.new_builder()?;
+
+        let resources = builder.iter_resources().collect::<BTreeMap<_, _>>();
+        let levels = |name: &str| {
+            let resource = resources[&name.to_string()];
+            (
+                resource.in_memory_bytecode.is_some(),
+                resource.in_memory_bytecode_opt1.is_some(),
+                resource.in_memory_bytecode_opt2.is_some(),
+            )
+        };
+
+        assert_eq!(levels("json"), (false, false, true));
+        assert_eq!(levels("json.encoder"), (false, false, true));
+        assert_eq!(levels("json.decoder"), (false, true, false));
+        assert_eq!(levels("email"), (true, false, false));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_packed_resources_lookup_table() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions {
+            packed_resources_index: IndexEncoding::LookupTable,
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let builder = options.new_builder()?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert_eq!(&context.resources[0..8], HEADER_V3);
+
+        let table = python_packed_resources::parser::load_lookup_table(&context.resources)
+            .map_err(|e| anyhow!(e))?;
+        assert_eq!(
+            table.len(),
+            python_packed_resources::parser::load_resources(&context.resources)
+                .map_err(|e| anyhow!(e))?
+                .expected_resources_count()
+        );
+
+        let resource = table
+            .get("encodings")
+            .map_err(|e| anyhow!(e))?
+            .expect("encodings should be in packed resources");
+        assert_eq!(resource.name, "encodings");
+        assert!(resource.is_package);
+
+        // The manifest records what the packed resources were built for, so
+        // they can seed later builds for the same Python version only.
+        let manifest = build_manifest(&context)?;
+        assert_eq!(manifest.cache_tag.as_deref(), Some(builder.cache_tag()));
+        let mut other = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        let err = other
+            .add_packed_resources(&Arc::new(context.resources.clone()), "cpython-27", None)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("packed resources were built for cpython-27"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_install_layout() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "layout.child".to_string(),
+                source: DataLocation::Memory(b"print('hello')\n".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::RelativePath("lib".to_string())),
+        )?;
+        builder.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "layout.child".to_string(),
+                source: DataLocation::Memory(b"print('hello')\n".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::RelativePath("lib".to_string())),
+        )?;
+
+        let layout = builder.resolve_install_layout(&BTreeSet::new())?;
+        let cache_tag = builder.cache_tag();
+        let mut expected = vec![
+            "lib/layout/__init__.py".to_string(),
+            format!("lib/layout/__pycache__/__init__.{}.pyc", cache_tag),
+            format!("lib/layout/__pycache__/child.{}.pyc", cache_tag),
+            "lib/layout/child.py".to_string(),
+        ];
+        if let Some(path) = builder.libpython_install_path() {
+            expected.push(path.to_string_lossy().to_string());
+        }
+        expected.sort();
+
+        assert_eq!(layout.files.keys().cloned().collect::<Vec<_>>(), expected);
+        assert_eq!(
+            layout.directories.iter().collect::<Vec<_>>(),
+            vec!["lib", "lib/layout", "lib/layout/__pycache__"]
+        );
+
+        // The layout describes exactly what is produced.
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert_eq!(context.install_layout, layout);
+        assert_eq!(
+            InstallLayout::from_file_manifest(&context.extra_files)?,
+            layout
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_module_variant_pins() -> Result<()> {
+        let builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        let (extension, variant) = builder
+            .distribution
+            .extension_modules
+            .values()
+            .map(|variants| variants.default_variant())
+            .find(|em| !em.is_minimally_required() && em.variant.is_some())
+            .map(|em| (em.name.clone(), em.variant.clone().unwrap()))
+            .unwrap();
+
+        let mut pins = BTreeMap::new();
+        pins.insert(extension.clone(), variant.clone());
+        pins.insert("no_such_extension".to_string(), "default".to_string());
+
+        let options = StandalonePythonExecutableBuilderOptions {
+            extension_module_variant_pins: pins,
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let builder = options.new_builder()?;
+
+        let decision = builder
+            .extension_decisions()
+            .into_iter()
+            .find(|d| d.name == extension)
+            .unwrap();
+        assert_eq!(decision.variant, Some(variant.clone()));
+        assert_eq!(
+            decision.variant_pin,
+            Some(format!("{}={}", extension, variant))
+        );
+        assert!(builder.resources_collector.warnings().contains(
+            &"extension module no_such_extension is pinned to variant default but the distribution doesn't provide it".to_string()
+        ));
+
+        let mut pins = BTreeMap::new();
+        pins.insert(extension.clone(), "bogus".to_string());
+        let options = StandalonePythonExecutableBuilderOptions {
+            extension_module_variant_pins: pins,
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let err = options.new_builder().err().unwrap().to_string();
+        assert!(err.contains(&format!(
+            "extension module {} has no variant bogus; available variants: {}",
+            extension, variant
+        )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_vulnerability_audit() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        builder.add_python_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "vulnerable".to_string(),
+                version: "1.0rc1".to_string(),
+                name: "METADATA".to_string(),
+                data: DataLocation::Memory(b"Name: vulnerable\nVersion: 1.0rc1\n".to_vec()),
+            },
+            None,
+        )?;
+
+        let database = temp_dir.path().join("osv.json");
+        std::fs::write(
+            &database,
+            r#"{"id": "GHSA-1", "aliases": ["CVE-2020-1"], "affected": [{"package": {"ecosystem": "PyPI", "name": "vulnerable"}, "ranges": [{"type": "ECOSYSTEM", "events": [{"introduced": "0"}, {"fixed": "1.0"}]}]}]}"#,
+        )?;
+
+        let report = builder.audit_vulnerabilities(&database)?;
+        assert_eq!(report.findings.len(), 1);
+        assert_eq!(report.findings[0].distribution, "vulnerable");
+        assert_eq!(report.findings[0].version, "1.0rc1");
+        assert_eq!(report.findings[0].aliases, vec!["CVE-2020-1".to_string()]);
+
+        // Findings are warnings unless the audit is strict.
+        builder
+            .packaging_policy
+            .set_vulnerability_database(Some(database));
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        builder
+            .packaging_policy
+            .set_strict_vulnerability_audit(true);
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap()
+            .to_string();
+        assert!(err.contains("vulnerable-distribution: vulnerable 1.0rc1 is affected by GHSA-1"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_reject_editable_installs() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.add_python_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "editable".to_string(),
+                version: "1.0".to_string(),
+                name: "direct_url.json".to_string(),
+                data: DataLocation::Memory(
+                    br#"{"url": "file:///home/dev/editable", "dir_info": {"editable": true}}"#
+                        .to_vec(),
+                ),
+            },
+            None,
+        )?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(context.direct_urls["editable"].is_editable());
+        assert_eq!(build_manifest(&context)?.direct_urls, context.direct_urls);
+
+        builder.packaging_policy.set_reject_editable_installs(true);
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert!(diagnostics.resources.contains("editable"));
+        assert!(diagnostics.message.contains(
+            "editable installs are rejected by policy: editable (file:///home/dev/editable)"
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_distribution_versions() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        for version in &["1.0", "2.0"] {
+            let resources = builder.resolve_distribution_versions(vec![
+                PythonResource::DistributionResource(PythonPackageDistributionResource {
+                    location: PythonPackageDistributionResourceFlavor::DistInfo,
+                    package: "foo".to_string(),
+                    version: version.to_string(),
+                    name: "METADATA".to_string(),
+                    data: DataLocation::Memory(b"Name: foo\n".to_vec()),
+                }),
+            ])?;
+
+            for resource in resources {
+                if let PythonResource::DistributionResource(r) = resource {
+                    builder.add_python_package_distribution_resource(&r, None)?;
+                }
+            }
+        }
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert_eq!(context.distributions["foo"].version, "2.0");
+        assert_eq!(context.distributions["foo"].discarded_versions, vec!["1.0"]);
+        assert_eq!(
+            build_manifest(&context)?.distributions,
+            context.distributions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hide_internal_resources() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.resources_collector.reserve_name(
+            "__glue__",
+            "glue",
+            SyntheticVisibility::Internal,
+        )?;
+        builder.resources_collector.add_synthetic_resource(
+            "glue",
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "__glue__".to_string(),
+                in_memory_source: Some(DataLocation::Memory(b"pass\n".to_vec())),
+                ..PrePackagedResource::default()
+            },
+        )?;
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(String::from_utf8(context.module_names.clone())?
+            .lines()
+            .any(|name| name == "__glue__"));
+        assert!(context.hidden_module_names.is_empty());
+        assert!(builder
+            .resource_size_report()?
+            .by_resource
+            .contains_key("__glue__"));
+
+        builder.packaging_policy.set_hide_internal_resources(true);
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(!String::from_utf8(context.module_names.clone())?
+            .lines()
+            .any(|name| name == "__glue__"));
+        assert!(context.hidden_module_names.contains("__glue__"));
+        let resources = python_packed_resources::parser::load_resources(&context.resources)
+            .map_err(|e| anyhow!(e))?
+            .map(|resource| resource.map_err(|e| anyhow!(e)))
+            .collect::<Result<Vec<_>>>()?;
+        assert!(resources.iter().any(|resource| resource.name == "__glue__"));
+        assert!(!builder
+            .resource_size_report()?
+            .by_resource
+            .contains_key("__glue__"));
+        assert!(!builder
+            .suggest_resource_names("__glue_")
+            .contains(&"__glue__".to_string()));
+
+        // Public synthetic resources are always listed.
+        assert!(!builder.resources_collector.is_internal("oxidized_importer"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_import_telemetry() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        let has_marker = |context: &EmbeddedPythonContext| -> Result<bool> {
+            Ok(
+                python_packed_resources::parser::load_resources(&context.resources)
+                    .map_err(|e| anyhow!(e))?
+                    .map(|resource| resource.map_err(|e| anyhow!(e)))
+                    .collect::<Result<Vec<_>>>()?
+                    .iter()
+                    .any(|resource| resource.name == IMPORT_TELEMETRY_RESOURCE_NAME),
+            )
+        };
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(!has_marker(&context)?);
+        assert_eq!(
+            context
+                .python_config_settings(Path::new("packed-resources"))
+                .get("import_telemetry_path"),
+            Some("None")
+        );
+        assert_eq!(build_manifest(&context)?.import_telemetry, None);
+
+        builder.config.import_telemetry_path = Some("/tmp/imports-{pid}".to_string());
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(has_marker(&context)?);
+        assert_eq!(
+            context
+                .python_config_settings(Path::new("packed-resources"))
+                .get("import_telemetry_path"),
+            Some("Some(r###\"/tmp/imports-{pid}\"###.to_string())")
+        );
+        assert_eq!(
+            build_manifest(&context)?.import_telemetry,
+            Some("/tmp/imports-{pid}".to_string())
+        );
+
+        // The marker is an implementation detail, so it can't be added otherwise.
+        assert!(builder
+            .add_python_module_source(
+                &PythonModuleSource {
+                    name: IMPORT_TELEMETRY_RESOURCE_NAME.to_string(),
+                    source: DataLocation::Memory(vec![]),
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_host_python_exe() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        let target = builder.target_python_exe_path().to_path_buf();
+        assert_eq!(builder.host_python_exe_path(), target);
+
+        // Any executable writing bytecode for the target Python version works.
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let host = target.clone();
+        builder.set_host_python_exe(Some(&host))?;
+        assert_eq!(builder.host_python_exe_path(), host);
+
+        builder.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "cross".to_string(),
+                source: DataLocation::Memory(b"x = 1\n".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::Zero,
                 is_package: false,
-                link_libraries: vec![],
+                cache_tag: builder.cache_tag().to_string(),
                 is_stdlib: false,
-                builtin_default: false,
-                required: false,
-                variant: None,
-                licenses: None,
-                license_texts: None,
-                license_public_domain: None,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::InMemory),
+        )?;
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        assert!(builder
+            .set_host_python_exe(Some(&temp_dir.path().join("missing")))
+            .is_err());
+        assert_eq!(builder.host_python_exe_path(), host);
+
+        builder.set_host_python_exe(None)?;
+        assert_eq!(builder.host_python_exe_path(), target);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_unused() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        for name in &["used", "keep_me", "preloaded", "prunable"] {
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(b"import os".to_vec()),
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+        }
+        builder.config.preload_modules = vec!["preloaded".to_string()];
+
+        let before = builder
+            .resources_collector
+            .iter_resources()
+            .map(|(name, _)| name.clone())
+            .collect::<BTreeSet<_>>();
+
+        let log = temp_dir.path().join("modules");
+        std::fs::write(&log, "used\nused\nunknown\n")?;
+
+        let report = builder.mark_used_from_log(&log)?;
+        assert!(report.used.contains("used"));
+        assert!(report.unknown.contains("unknown"));
+        assert!(report.unused.contains_key("prunable"));
+
+        builder.remove_unused(&logger, &["keep_*"])?;
+
+        let after = builder
+            .resources_collector
+            .iter_resources()
+            .map(|(name, _)| name.clone())
+            .collect::<BTreeSet<_>>();
+
+        for name in &["used", "keep_me", "preloaded"] {
+            assert!(after.contains(*name));
+        }
+        assert!(!after.contains("prunable"));
+
+        for decision in builder.extension_decisions() {
+            if decision.required && before.contains(&decision.name) {
+                assert!(after.contains(&decision.name));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_all_backing_files() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let mut paths = vec![];
+        for name in &["foo", "bar"] {
+            let path = temp_dir.path().join(format!("{}.py", name));
+            std::fs::write(&path, b"import os")?;
+
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::from_path(&path),
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+            paths.push(path);
+        }
+        builder.verify_all_backing_files(&mut DiagnosticsRecorder::new(&logger))?;
+
+        for path in &paths {
+            std::fs::remove_file(path)?;
+        }
+
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::VerifyFiles);
+        assert_eq!(diagnostics.class, FailureClass::Io);
+        assert_eq!(
+            diagnostics.resources,
+            vec!["bar".to_string(), "foo".to_string()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "files backing resources no longer exist: bar [source] from {}; foo [source] from {}",
+                paths[1].display(),
+                paths[0].display()
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_failure_diagnostics() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        // Slices beyond their buffer only fail once resolved.
+        builder.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "broken".to_string(),
+                source: DataLocation::Slice {
+                    data: Arc::new(b"import os".to_vec()),
+                    offset: 4,
+                    length: 100,
+                },
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::InMemory),
+        )?;
+
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::Compile);
+        assert_eq!(diagnostics.class, FailureClass::Compile);
+        assert_eq!(diagnostics.message, format!("{:#}", err));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_error_policy() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        builder.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "python2_only".to_string(),
+                source: DataLocation::Memory(b"x = 1\nprint 'hello'\n".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            Some(ConcreteResourceLocation::InMemory),
+        )?;
+
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::Compile);
+        assert!(diagnostics
+            .message
+            .contains("python2_only line 2: SyntaxError"));
+
+        builder
+            .packaging_policy
+            .set_compile_error_policy(CompileErrorPolicy::SourceOnly);
+        builder
+            .resources_collector
+            .set_compile_error_policy(CompileErrorPolicy::SourceOnly);
+        let mut diagnostics = DiagnosticsRecorder::new(&logger);
+        builder.build_embedded_python_context(&logger, &mut diagnostics, "0", &BTreeSet::new())?;
+        let err = diagnostics.into_error(anyhow!("done"));
+        assert!(build_diagnostics(&err)
+            .unwrap()
+            .warnings
+            .iter()
+            .any(|warning| warning.starts_with(
+                "embedded only the source of a module that failed to compile: python2_only line 2"
+            )));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_entry_point_protection() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let log = temp_dir.path().join("modules");
+        std::fs::write(&log, "app\n")?;
+
+        for protect in &[false, true] {
+            let mut builder = options.new_builder()?;
+            builder
+                .packaging_policy
+                .set_protect_entry_point_providers(*protect);
+
+            for (name, source) in &[
+                (
+                    "app",
+                    &b"import pluggy\npm.load_setuptools_entrypoints('app')\n"[..],
+                ),
+                ("plugin_a", b"def register(): pass\n"),
+                ("unrelated", b"import os\n"),
+            ] {
+                builder.add_python_module_source(
+                    &PythonModuleSource {
+                        name: name.to_string(),
+                        source: DataLocation::Memory(source.to_vec()),
+                        is_package: false,
+                        cache_tag: builder.cache_tag().to_string(),
+                        is_stdlib: false,
+                        is_test: false,
+                    },
+                    None,
+                )?;
+            }
+            builder.add_python_package_distribution_resource(
+                &PythonPackageDistributionResource {
+                    location: PythonPackageDistributionResourceFlavor::DistInfo,
+                    package: "plugin_dist".to_string(),
+                    version: "1.0".to_string(),
+                    name: "entry_points.txt".to_string(),
+                    data: DataLocation::Memory(b"[app]\na = plugin_a:register\n".to_vec()),
+                },
+                None,
+            )?;
+
+            builder.mark_used_from_log(&log)?;
+            builder.remove_unused(&logger, &[])?;
+
+            let names = builder
+                .iter_resources()
+                .map(|(name, _)| name.clone())
+                .collect::<BTreeSet<_>>();
+            assert!(names.contains("app"));
+            assert!(!names.contains("unrelated"));
+            assert_eq!(names.contains("plugin_a"), *protect);
+            assert_eq!(names.contains("plugin_dist"), *protect);
+
+            let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+            let manifest = build_manifest(&context)?;
+            if *protect {
+                assert_eq!(manifest.entry_point_protections.len(), 1);
+                assert_eq!(manifest.entry_point_protections[0].module, "plugin_a");
+            } else {
+                assert!(manifest.entry_point_protections.is_empty());
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_console_script_usage() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+
+        // The module is only synthesized if there are console scripts to list.
+        for (enabled, with_scripts) in &[(false, true), (true, true), (true, false)] {
+            let mut builder = options.new_builder()?;
+            builder.packaging_policy.set_console_script_usage(*enabled);
+
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: "tool".to_string(),
+                    source: DataLocation::Memory(b"\"\"\"Run the tool.\"\"\"\n".to_vec()),
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+            if *with_scripts {
+                builder.add_python_package_distribution_resource(
+                    &PythonPackageDistributionResource {
+                        location: PythonPackageDistributionResourceFlavor::DistInfo,
+                        package: "tool_dist".to_string(),
+                        version: "1.0".to_string(),
+                        name: "entry_points.txt".to_string(),
+                        data: DataLocation::Memory(
+                            b"[console_scripts]\ntool = tool:main\n".to_vec(),
+                        ),
+                    },
+                    None,
+                )?;
+            }
+
+            let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+            let manifest = build_manifest(&context)?;
+            let module_names = String::from_utf8(context.module_names.clone())?;
+
+            if *enabled && *with_scripts {
+                assert_eq!(
+                    manifest.console_scripts,
+                    vec![ConsoleScript {
+                        name: "tool".to_string(),
+                        target: "tool:main".to_string(),
+                        summary: "Run the tool.".to_string(),
+                    }]
+                );
+                assert!(module_names
+                    .lines()
+                    .any(|name| name == CONSOLE_SCRIPTS_MODULE));
+            } else {
+                assert!(manifest.console_scripts.is_empty());
+                assert!(!module_names
+                    .lines()
+                    .any(|name| name == CONSOLE_SCRIPTS_MODULE));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_resource() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let dest = temp_dir.path();
+
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+        let module = PythonModuleSource {
+            name: "app".to_string(),
+            source: DataLocation::Memory(b"print('hello')\n".to_vec()),
+            is_package: false,
+            cache_tag: builder.cache_tag().to_string(),
+            is_stdlib: false,
+            is_test: false,
         };
+        builder.add_python_module_source(&module, None)?;
+        builder.add_python_module_bytecode_from_source(
+            &module.as_bytecode_module(BytecodeOptimizationLevel::Zero),
+            None,
+        )?;
+
+        let path = builder.extract_resource("app", PayloadKind::Source, dest)?;
+        assert_eq!(path, dest.join("app.py"));
+        assert_eq!(std::fs::read(&path)?, b"print('hello')\n");
+
+        let path = builder.extract_resource("app", PayloadKind::Bytecode, dest)?;
+        assert_eq!(path, dest.join("app.pyc"));
+        assert!(std::fs::read(&path)?.len() > 16);
+
+        let err = builder
+            .extract_resource("apq", PayloadKind::Source, dest)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("resource apq not found; did you mean app"));
+
+        Ok(())
     }
 
-    /// Defines construction options for a `StandalonePythonExecutableBuilder`.
-    ///
-    /// This is mostly intended to be used by tests, to reduce boilerplate for
-    /// constructing instances.
-    pub struct StandalonePythonExecutableBuilderOptions {
-        pub host_triple: String,
-        pub target_triple: String,
-        pub distribution_flavor: DistributionFlavor,
-        pub app_name: String,
-        pub libpython_link_mode: BinaryLibpythonLinkMode,
-        pub extension_module_filter: ExtensionModuleFilter,
-        pub resources_policy: PythonResourcesPolicy,
+    #[test]
+    fn test_relocate_package() -> Result<()> {
+        let options = StandalonePythonExecutableBuilderOptions {
+            target_triple: "x86_64-unknown-linux-gnu".to_string(),
+            extension_module_filter: ExtensionModuleFilter::Minimal,
+            resources_policy: PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(
+                "lib".to_string(),
+            ),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+        let mut builder = options.new_builder()?;
+        let lib = ConcreteResourceLocation::RelativePath("lib".to_string());
+
+        for (name, is_package) in &[("app", true), ("app.util", false)] {
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(b"import os\n".to_vec()),
+                    is_package: *is_package,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                Some(ConcreteResourceLocation::RelativePath("lib".to_string())),
+            )?;
+        }
+
+        let report = builder.relocate_package("app", ConcreteResourceLocation::InMemory, false)?;
+        assert_eq!(report.moved.len(), 2);
+        assert!(report.left_behind.is_empty());
+        assert!(builder
+            .iter_resources()
+            .filter(|(name, _)| name.starts_with("app"))
+            .all(|(_, r)| r.in_memory_source.is_some() && r.relative_path_module_source.is_none()));
+
+        let report = builder.relocate_package("app", lib, false)?;
+        assert_eq!(report.moved.len(), 2);
+        assert!(builder
+            .iter_resources()
+            .filter(|(name, _)| name.starts_with("app"))
+            .all(|(_, r)| r.in_memory_source.is_none() && r.relative_path_module_source.is_some()));
+
+        // This target can't load extension modules from memory.
+        builder.add_python_extension_module(
+            &EXTENSION_MODULE_SHARED_LIBRARY_ONLY,
+            Some(ConcreteResourceLocation::RelativePath("lib".to_string())),
+        )?;
+        let report =
+            builder.relocate_package("shared_only", ConcreteResourceLocation::InMemory, true)?;
+        assert!(report.moved.is_empty());
+        assert_eq!(report.left_behind.len(), 1);
+        assert_eq!(
+            builder
+                .extension_decisions
+                .get("shared_only")
+                .unwrap()
+                .outcome,
+            ExtensionModuleOutcome::FilesystemSharedLibrary
+        );
+
+        assert!(builder
+            .relocate_package("missing", ConcreteResourceLocation::InMemory, false)
+            .is_err());
+
+        Ok(())
     }
 
-    impl Default for StandalonePythonExecutableBuilderOptions {
-        fn default() -> Self {
-            // Grab default values from a default policy so they stay in sync.
-            let default_policy = PythonPackagingPolicy::default();
+    #[test]
+    fn test_filter_file_round_trip() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
 
-            Self {
-                host_triple: env!("HOST").to_string(),
-                target_triple: env!("HOST").to_string(),
-                distribution_flavor: DistributionFlavor::Standalone,
-                app_name: "testapp".to_string(),
-                libpython_link_mode: BinaryLibpythonLinkMode::Default,
-                extension_module_filter: default_policy.get_extension_module_filter().clone(),
-                resources_policy: default_policy.get_resources_policy().clone(),
+        let new_builder = || -> Result<Box<StandalonePythonExecutableBuilder>> {
+            let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+            for (name, is_package, source) in &[
+                ("app", true, "from . import cli\n"),
+                (
+                    "app.cli",
+                    false,
+                    "import json\nfrom app.util import helper\n",
+                ),
+                ("app.util", false, "def helper():\n    pass\n"),
+                ("app.unused", false, "import sqlite3\n"),
+            ] {
+                builder.add_python_module_source(
+                    &PythonModuleSource {
+                        name: name.to_string(),
+                        source: DataLocation::Memory(source.as_bytes().to_vec()),
+                        is_package: *is_package,
+                        cache_tag: builder.cache_tag().to_string(),
+                        is_stdlib: false,
+                        is_test: false,
+                    },
+                    None,
+                )?;
+            }
+
+            Ok(builder)
+        };
+
+        let names = |builder: &StandalonePythonExecutableBuilder| {
+            builder
+                .resources_collector
+                .iter_resources()
+                .map(|(name, _)| name.clone())
+                .collect::<BTreeSet<_>>()
+        };
+
+        let mut builder = new_builder()?;
+        let before = names(&builder);
+        let filter = builder.filter_file_from_entry_modules(&["app.cli"])?;
+        let path = temp_dir.path().join("entry-filter");
+        filter.write_to_path(&path)?;
+        builder.filter_resources_from_files(&logger, &[&path], &[])?;
+
+        let after = names(&builder);
+        assert_eq!(
+            after,
+            filter
+                .names
+                .keys()
+                .filter(|name| before.contains(*name))
+                .cloned()
+                .collect::<BTreeSet<_>>()
+        );
+        for name in &["app", "app.cli", "app.util", "json", "json.decoder"] {
+            assert!(after.contains(*name));
+        }
+        assert!(!after.contains("app.unused"));
+        assert!(!after.contains("sqlite3"));
+        for name in builder.required_resource_names() {
+            if before.contains(&name) {
+                assert!(after.contains(&name));
             }
         }
+
+        let mut builder = new_builder()?;
+        let log = temp_dir.path().join("modules");
+        std::fs::write(&log, "app.util\nunknown\n")?;
+        let filter = builder.filter_file_from_log(&log)?;
+        let path = temp_dir.path().join("log-filter");
+        filter.write_to_path(&path)?;
+        builder.filter_resources_from_files(&logger, &[&path], &[])?;
+
+        let after = names(&builder);
+        let required = builder
+            .required_resource_names()
+            .into_iter()
+            .filter(|name| before.contains(name));
+        let mut expected = vec!["app".to_string(), "app.util".to_string()]
+            .into_iter()
+            .chain(required)
+            .collect::<BTreeSet<_>>();
+        for name in expected.clone() {
+            expected.extend(
+                packages_from_module_name(&name)
+                    .into_iter()
+                    .filter(|x| before.contains(x)),
+            );
+        }
+        assert_eq!(after, expected);
+
+        Ok(())
     }
 
-    impl StandalonePythonExecutableBuilderOptions {
-        pub fn new_builder(&self) -> Result<Box<StandalonePythonExecutableBuilder>> {
-            let record = PYTHON_DISTRIBUTIONS
-                .find_distribution(&self.target_triple, &self.distribution_flavor)
-                .ok_or_else(|| anyhow!("could not find Python distribution"))?;
+    #[test]
+    fn test_feature_tags() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
 
-            let distribution = get_distribution(&record.location)?;
+        for name in &["core", "telemetry", "telemetry.client"] {
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(b"import os".to_vec()),
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+        }
 
-            let mut policy = PythonPackagingPolicy::default();
-            policy.set_extension_module_filter(self.extension_module_filter.clone());
-            policy.set_resources_policy(self.resources_policy.clone());
+        assert!(builder.tag_resources("[", "telemetry").is_err());
+        assert_eq!(builder.tag_resources("telemetry*", "telemetry")?, 2);
+        assert_eq!(builder.tag_resources("missing", "telemetry")?, 0);
+
+        // Protecting a tagged resource from pruning doesn't enable it.
+        let log = temp_dir.path().join("modules");
+        std::fs::write(&log, "core\n")?;
+        builder.mark_used_from_log(&log)?;
+        builder.remove_unused(&logger, &["telemetry*"])?;
+
+        let module_names = |context: &EmbeddedPythonContext| {
+            String::from_utf8_lossy(&context.module_names)
+                .lines()
+                .map(|x| x.to_string())
+                .collect::<BTreeSet<_>>()
+        };
 
-            let config = EmbeddedPythonConfig::default();
+        let oss = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        assert!(module_names(&oss).contains("core"));
+        assert!(!module_names(&oss).contains("telemetry"));
+        assert!(!module_names(&oss).contains("telemetry.client"));
+        assert!(oss.enabled_features.is_empty());
+
+        let features: BTreeSet<String> = vec!["telemetry".to_string()].into_iter().collect();
+        let full = builder.to_embedded_python_context(&logger, "0", &features)?;
+        assert!(module_names(&full).contains("telemetry"));
+        assert!(module_names(&full).contains("telemetry.client"));
+        assert_eq!(full.enabled_features, features);
+
+        let manifest_path = temp_dir.path().join("manifest.json");
+        write_build_manifest(&full, &manifest_path)?;
+        assert_eq!(BuildManifest::from_path(&manifest_path)?.features, features);
+
+        // Required resources can't be gated away.
+        builder.config.run_mode = RunMode::Module {
+            module: "telemetry.client".to_string(),
+        };
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "telemetry.client is required but excluded because none of its features (telemetry) are enabled"
+        );
+        let diagnostics = build_diagnostics(&err).unwrap();
+        assert_eq!(diagnostics.phase, BuildPhase::Validate);
+        assert_eq!(diagnostics.class, FailureClass::Policy);
+        assert_eq!(
+            diagnostics.resources,
+            vec!["telemetry.client".to_string()].into_iter().collect()
+        );
+        builder.to_embedded_python_context(&logger, "0", &features)?;
 
-            StandalonePythonExecutableBuilder::from_distribution(
-                distribution,
-                self.host_triple.clone(),
-                self.target_triple.clone(),
-                self.app_name.clone(),
-                self.libpython_link_mode.clone(),
-                policy,
-                config,
-            )
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_package() -> Result<()> {
+        let logger = get_logger()?;
+        let mut builder = StandalonePythonExecutableBuilderOptions::default().new_builder()?;
+
+        for (name, is_package) in &[("app", true), ("app.cli", true), ("app.cli.run", false)] {
+            let module = PythonModuleSource {
+                name: name.to_string(),
+                source: DataLocation::Memory(b"pass".to_vec()),
+                is_package: *is_package,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            };
+            builder.add_python_module_source(&module, None)?;
         }
+
+        // Plain module.
+        builder.config.run_mode = RunMode::Module {
+            module: "app.cli.run".to_string(),
+        };
+        let required = builder.required_resource_names();
+        assert!(required.contains("app"));
+        assert!(required.contains("app.cli"));
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        // Package without __main__.
+        builder.config.run_mode = RunMode::Module {
+            module: "app.cli".to_string(),
+        };
+        let err = builder
+            .to_embedded_python_context(&logger, "0", &BTreeSet::new())
+            .err()
+            .unwrap();
+        assert!(err
+            .to_string()
+            .contains("requires module app.cli.__main__ (app/cli/__main__.py)"));
+
+        // Package with __main__.
+        builder.add_python_module_source(
+            &PythonModuleSource {
+                name: "app.cli.__main__".to_string(),
+                source: DataLocation::Memory(b"pass".to_vec()),
+                is_package: false,
+                cache_tag: builder.cache_tag().to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            None,
+        )?;
+        assert!(builder
+            .required_resource_names()
+            .contains("app.cli.__main__"));
+        builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+
+        Ok(())
     }
 
-    pub fn get_embedded(logger: &slog::Logger) -> Result<EmbeddedPythonContext> {
+    #[test]
+    fn test_compare_to_distribution() -> Result<()> {
         let options = StandalonePythonExecutableBuilderOptions::default();
-        let exe = options.new_builder()?;
+        let mut builder = options.new_builder()?;
 
-        exe.to_embedded_python_context(logger, "0")
-    }
+        let before = builder.compare_to_distribution()?;
+        assert!(before.packages["json"].is_complete());
 
-    fn assert_extension_builtin(
-        builder: &StandalonePythonExecutableBuilder,
-        extension: &PythonExtensionModule,
-    ) -> Result<()> {
+        builder
+            .resources_collector
+            .filter_resources_mut(|r| r.name != "json.tool")?;
+
+        let after = builder.compare_to_distribution()?;
+        let json = &after.packages["json"];
         assert_eq!(
-            builder.iter_resources().find_map(|(name, r)| {
-                if *name == extension.name {
-                    Some(r)
-                } else {
-                    None
-                }
-            }),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::BuiltinExtensionModule,
-                name: extension.name.clone(),
-                ..PrePackagedResource::default()
-            })
+            json.modules_absent,
+            vec!["json.tool".to_string()].into_iter().collect()
         );
-
+        assert!(json.bytes_retained < json.bytes_available);
         assert_eq!(
-            builder.extension_build_contexts.get(&extension.name),
-            Some(&LibPythonBuildContext {
-                object_files: extension.object_file_data.clone(),
-                init_functions: BTreeMap::from_iter(
-                    [(
-                        extension.name.to_string(),
-                        extension.init_fn.as_ref().clone().unwrap().to_string()
-                    )]
-                    .iter()
-                    .cloned()
-                ),
-                ..LibPythonBuildContext::default()
-            })
+            json.bytes_available,
+            before.packages["json"].bytes_available
+        );
+        assert_eq!(
+            after.total().bytes_available,
+            before.total().bytes_available
         );
+        assert!(after.total().bytes_retained < before.total().bytes_retained);
 
         Ok(())
     }
 
-    fn assert_extension_shared_library(
-        builder: &StandalonePythonExecutableBuilder,
-        extension: &PythonExtensionModule,
-        location: ConcreteResourceLocation,
-    ) -> Result<()> {
-        let mut entry = PrePackagedResource {
-            flavor: ResourceFlavor::Extension,
-            name: extension.name.clone(),
-            shared_library_dependency_names: Some(vec![]),
-            ..PrePackagedResource::default()
-        };
+    #[test]
+    fn test_synthesized_names_reserved() -> Result<()> {
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
 
-        match location {
-            ConcreteResourceLocation::InMemory => {
-                assert!(extension.shared_library.is_some());
-                entry.in_memory_extension_module_shared_library =
-                    Some(extension.shared_library.as_ref().unwrap().clone());
-            }
-            ConcreteResourceLocation::RelativePath(prefix) => {
-                assert!(extension.shared_library.is_some());
-                entry.relative_path_extension_module_shared_library = Some((
-                    PathBuf::from(prefix).join(format!(
-                        "{}{}",
-                        extension.name, extension.extension_file_suffix
-                    )),
-                    extension.shared_library.as_ref().unwrap().clone(),
-                ));
-            }
+        for (name, feature) in &[
+            ("oxidized_importer", "the built-in oxidized_importer module"),
+            ("__pyoxidizer_import_telemetry__", "import telemetry"),
+            ("pyoxidizer_console_scripts", "console script usage"),
+        ] {
+            let err = builder
+                .add_python_module_source(
+                    &PythonModuleSource {
+                        name: name.to_string(),
+                        source: DataLocation::Memory(vec![]),
+                        is_package: false,
+                        cache_tag: builder.cache_tag().to_string(),
+                        is_stdlib: false,
+                        is_test: false,
+                    },
+                    None,
+                )
+                .unwrap_err();
+            assert!(
+                format!("{:#}", err).contains(feature),
+                "{:#} doesn't name {}",
+                err,
+                feature
+            );
         }
 
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_module_implementation_suffix() -> Result<()> {
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+
         assert_eq!(
-            builder.iter_resources().find_map(|(name, r)| {
-                if *name == extension.name {
-                    Some(r)
-                } else {
-                    None
-                }
-            }),
-            Some(&entry)
+            builder.distribution.python_implementation(),
+            InterpreterImplementation::CPython
         );
 
-        // There is no build context for extensions materialized as shared libraries.
-        // This could change if we ever link shared library extension modules from
-        // object files.
-        assert_eq!(builder.extension_build_contexts.get(&extension.name), None);
+        let mut extension = EXTENSION_MODULE_SHARED_LIBRARY_ONLY.clone();
+        extension.extension_file_suffix = ".pypy38-pp73-x86_64-linux-gnu.so".to_string();
+        assert!(builder
+            .add_python_extension_module(&extension, None)
+            .is_err());
+
+        extension.extension_file_suffix = ".cpython-38-x86_64-linux-gnu.so".to_string();
+        builder.add_python_extension_module(&extension, None)?;
 
         Ok(())
     }
 
     #[test]
-    fn test_write_embedded_files() -> Result<()> {
+    fn test_embedded_context_consistent_after_late_filter() -> Result<()> {
         let logger = get_logger()?;
-        let embedded = get_embedded(&logger)?;
-        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+
+        let sqlite = builder
+            .distribution
+            .extension_modules
+            .get("_sqlite3")
+            .unwrap()
+            .default_variant()
+            .clone();
+        builder.add_python_extension_module(&sqlite, None)?;
+        assert!(builder.extension_build_contexts.contains_key("_sqlite3"));
+
+        // Simulate a filter that only touches the resources collection and
+        // leaves the extension build contexts behind.
+        builder
+            .resources_collector
+            .filter_resources_mut(|r| r.name != "_sqlite3")?;
+
+        let embedded = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        verify_embedded_context(&embedded)?;
+
+        let module_names = String::from_utf8(embedded.module_names.clone())?;
+        assert!(!module_names.lines().any(|l| l == "_sqlite3"));
+        assert!(!embedded.builtin_extension_module_names.contains("_sqlite3"));
+        assert!(embedded.builtin_extension_module_names.contains("_io"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_embedded_context_mismatch() -> Result<()> {
+        let logger = get_logger()?;
+        let mut embedded = get_embedded(&logger)?;
+        verify_embedded_context(&embedded)?;
 
-        embedded.write_files(temp_dir.path())?;
+        embedded.module_names.extend(b"does_not_exist\n");
+        assert_eq!(
+            verify_embedded_context(&embedded).unwrap_err().to_string(),
+            "module names list and packed resources disagree on does_not_exist"
+        );
+
+        let mut embedded = get_embedded(&logger)?;
+        embedded
+            .builtin_extension_module_names
+            .insert("does_not_exist".to_string());
+        assert_eq!(
+            verify_embedded_context(&embedded).unwrap_err().to_string(),
+            "built-in extension modules and packed resources disagree on does_not_exist"
+        );
 
         Ok(())
     }
@@ -1160,6 +6356,86 @@ pub mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_linux_extension_decisions() -> Result<()> {
+        let options = StandalonePythonExecutableBuilderOptions {
+            target_triple: "x86_64-unknown-linux-gnu".to_string(),
+            extension_module_filter: ExtensionModuleFilter::Minimal,
+            libpython_link_mode: BinaryLibpythonLinkMode::Static,
+            resources_policy: PythonResourcesPolicy::FilesystemRelativeOnly("lib".to_string()),
+            ..StandalonePythonExecutableBuilderOptions::default()
+        };
+
+        let mut builder = options.new_builder()?;
+
+        // Extensions compiled into libpython by default stay built-in.
+        let builtin_defaults = builder
+            .extension_decisions()
+            .into_iter()
+            .filter(|d| d.is_stdlib && d.builtin_default)
+            .collect::<Vec<_>>();
+        assert!(!builtin_defaults.is_empty());
+        for decision in builtin_defaults {
+            assert_eq!(decision.rule, ExtensionModuleRule::StdlibBuiltinDefault);
+            assert_eq!(decision.outcome, ExtensionModuleOutcome::Builtin);
+        }
+
+        // An extension from a wheel only has a shared library.
+        builder.add_python_extension_module(&EXTENSION_MODULE_SHARED_LIBRARY_ONLY, None)?;
+        // Object files can only be linked as a built-in.
+        builder.add_python_extension_module(&EXTENSION_MODULE_OBJECT_FILES_ONLY, None)?;
+
+        let decisions = builder
+            .extension_decisions()
+            .into_iter()
+            .map(|d| (d.name.clone(), d))
+            .collect::<BTreeMap<_, _>>();
+
+        assert_eq!(
+            decisions.get("shared_only"),
+            Some(&ExtensionDecision {
+                name: "shared_only".to_string(),
+                link_mode: LibpythonLinkMode::Static,
+                resources_policy: "filesystem-relative-only:lib".to_string(),
+                requested_location: None,
+                is_stdlib: false,
+                builtin_default: false,
+                required: false,
+                has_object_files: false,
+                has_shared_library: true,
+                can_load_standalone: true,
+                can_load_dynamic_library_memory: false,
+                rule: ExtensionModuleRule::SharedLibraryFilesystem,
+                outcome: ExtensionModuleOutcome::FilesystemSharedLibrary,
+                variant: None,
+                variant_pin: None,
+            })
+        );
+
+        assert_eq!(
+            decisions.get("object_files_only"),
+            Some(&ExtensionDecision {
+                name: "object_files_only".to_string(),
+                link_mode: LibpythonLinkMode::Static,
+                resources_policy: "filesystem-relative-only:lib".to_string(),
+                requested_location: None,
+                is_stdlib: false,
+                builtin_default: false,
+                required: false,
+                has_object_files: true,
+                has_shared_library: false,
+                can_load_standalone: true,
+                can_load_dynamic_library_memory: false,
+                rule: ExtensionModuleRule::BuiltinOnlyMechanism,
+                outcome: ExtensionModuleOutcome::Builtin,
+                variant: None,
+                variant_pin: None,
+            })
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_linux_extension_in_memory_policy() -> Result<()> {
         for libpython_link_mode in vec![
@@ -2796,4 +8072,89 @@ pub mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_build_clock() -> Result<()> {
+        let logger = get_logger()?;
+        let temp_dir = tempdir::TempDir::new("pyoxidizer-test")?;
+
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut manifests = vec![];
+
+        for i in 0..2 {
+            let mut builder = options.new_builder()?;
+            assert_eq!(builder.build_clock(), BuildClock::default());
+            builder.set_build_clock(BuildClock::Fixed(1_600_000_000));
+
+            builder.add_python_module_source(
+                &PythonModuleSource {
+                    name: "foo".to_string(),
+                    source: DataLocation::Memory(b"import os\n".to_vec()),
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+
+            let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+            assert_eq!(context.build_time, 1_600_000_000);
+
+            let manifest_path = temp_dir.path().join(format!("manifest-{}.json", i));
+            write_build_manifest(&context, &manifest_path)?;
+            manifests.push((context.resources, std::fs::read(&manifest_path)?));
+        }
+
+        // Artifacts recording times are identical across builds.
+        assert_eq!(manifests[0], manifests[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_sizes() -> Result<()> {
+        let logger = get_logger()?;
+        let options = StandalonePythonExecutableBuilderOptions::default();
+        let mut builder = options.new_builder()?;
+
+        for level in &[
+            BytecodeOptimizationLevel::Zero,
+            BytecodeOptimizationLevel::Two,
+        ] {
+            builder.add_python_module_bytecode_from_source(
+                &PythonModuleBytecodeFromSource {
+                    name: "foo.bar".to_string(),
+                    source: DataLocation::Memory(b"x = 1\n".to_vec()),
+                    optimize_level: *level,
+                    is_package: false,
+                    cache_tag: builder.cache_tag().to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                None,
+            )?;
+        }
+
+        let context = builder.to_embedded_python_context(&logger, "0", &BTreeSet::new())?;
+        let report = &context.bytecode_sizes;
+
+        let module = report
+            .modules
+            .iter()
+            .find(|module| module.name == "foo.bar")
+            .unwrap();
+        assert_eq!(module.package, "foo");
+        assert_eq!(module.levels.keys().collect::<Vec<_>>(), vec![&0, &2]);
+        assert!(report.packages.iter().any(|package| package.name == "foo"));
+
+        // The stdlib is compiled as well and modules are sorted by size.
+        assert!(report.modules.len() > 1);
+        assert!(report
+            .modules
+            .windows(2)
+            .all(|pair| pair[0].total_bytes >= pair[1].total_bytes));
+
+        Ok(())
+    }
 }
@@ -6,6 +6,7 @@
 
 use {
     super::binary::{LibpythonLinkMode, PythonBinaryBuilder},
+    super::build_directories::BuildDirectories,
     super::config::EmbeddedPythonConfig,
     super::distribution::{
         is_stdlib_test_package, resolve_python_distribution_from_location, BinaryLibpythonLinkMode,
@@ -20,6 +21,7 @@ use {
     path_dedot::ParseDot,
     python_packaging::bytecode::{BytecodeCompiler, PythonBytecodeCompiler},
     python_packaging::filesystem_scanning::{find_python_resources, walk_tree_files},
+    python_packaging::implementation::InterpreterImplementation,
     python_packaging::module_util::{is_package_from_path, PythonModuleSuffixes},
     python_packaging::policy::PythonPackagingPolicy,
     python_packaging::resource::{
@@ -88,11 +90,11 @@ impl LinkEntry {
             static_library: self
                 .path_static
                 .clone()
-                .map(|p| DataLocation::Path(python_path.join(p))),
+                .map(|p| DataLocation::from_path(python_path.join(p))),
             dynamic_library: self
                 .path_dynamic
                 .clone()
-                .map(|p| DataLocation::Path(python_path.join(p))),
+                .map(|p| DataLocation::from_path(python_path.join(p))),
             framework: self.framework.unwrap_or(false),
             system: self.system.unwrap_or(false),
         }
@@ -429,6 +431,9 @@ pub struct StandaloneDistribution {
     /// e.g. `cpython-37`.
     pub cache_tag: String,
 
+    /// Python implementation of this distribution.
+    pub python_implementation: InterpreterImplementation,
+
     /// Suffixes for Python module types.
     module_suffixes: PythonModuleSuffixes,
 }
@@ -701,7 +706,7 @@ impl StandaloneDistribution {
                 let object_file_data = entry
                     .objs
                     .iter()
-                    .map(|p| DataLocation::Path(python_path.join(p)))
+                    .map(|p| DataLocation::from_path(python_path.join(p)))
                     .collect();
                 let mut links = Vec::new();
 
@@ -743,7 +748,7 @@ impl StandaloneDistribution {
                     init_fn: Some(entry.init_fn.clone()),
                     extension_file_suffix: "".to_string(),
                     shared_library: if let Some(path) = &entry.shared_lib {
-                        Some(DataLocation::Path(python_path.join(path)))
+                        Some(DataLocation::from_path(python_path.join(path)))
                     } else {
                         None
                     },
@@ -759,7 +764,7 @@ impl StandaloneDistribution {
                         Some(
                             licenses
                                 .iter()
-                                .map(|p| DataLocation::Path(python_path.join(p)))
+                                .map(|p| DataLocation::from_path(python_path.join(p)))
                                 .collect(),
                         )
                     } else {
@@ -809,8 +814,8 @@ impl StandaloneDistribution {
                     resources.get_mut(&resource.leaf_package).unwrap().insert(
                         resource.relative_name.clone(),
                         match resource.data {
-                            DataLocation::Path(path) => path,
-                            DataLocation::Memory(_) => {
+                            DataLocation::Path { path, .. } => path,
+                            _ => {
                                 return Err(anyhow!(
                                     "should not have received in-memory resource data"
                                 ))
@@ -819,12 +824,10 @@ impl StandaloneDistribution {
                     );
                 }
                 PythonResource::ModuleSource(source) => match source.source {
-                    DataLocation::Path(path) => {
+                    DataLocation::Path { path, .. } => {
                         py_modules.insert(source.name.clone(), path);
                     }
-                    DataLocation::Memory(_) => {
-                        return Err(anyhow!("should not have received in-memory source data"))
-                    }
+                    _ => return Err(anyhow!("should not have received in-memory source data")),
                 },
                 _ => {}
             };
@@ -845,6 +848,9 @@ impl StandaloneDistribution {
 
         let inittab_object = python_path.join(pi.build_info.inittab_object);
 
+        let python_implementation =
+            InterpreterImplementation::from_name(&pi.python_implementation_name)?;
+
         Ok(Self {
             base_dir: dist_dir.to_path_buf(),
             target_triple: pi.target_triple,
@@ -881,6 +887,7 @@ impl StandaloneDistribution {
             inittab_object,
             inittab_cflags: pi.build_info.inittab_cflags,
             cache_tag: pi.python_implementation_cache_tag,
+            python_implementation,
             module_suffixes,
         })
     }
@@ -1035,12 +1042,24 @@ impl PythonDistribution for StandaloneDistribution {
         &self.cache_tag
     }
 
+    fn python_implementation(&self) -> InterpreterImplementation {
+        self.python_implementation
+    }
+
     fn python_module_suffixes(&self) -> Result<PythonModuleSuffixes> {
         Ok(self.module_suffixes.clone())
     }
 
-    fn create_bytecode_compiler(&self) -> Result<Box<dyn PythonBytecodeCompiler>> {
-        Ok(Box::new(BytecodeCompiler::new(&self.python_exe)?))
+    fn create_bytecode_compiler(
+        &self,
+        python_exe: Option<&Path>,
+    ) -> Result<Box<dyn PythonBytecodeCompiler>> {
+        Ok(Box::new(match python_exe {
+            Some(python_exe) => {
+                BytecodeCompiler::new_for_version(python_exe, &self.python_major_minor_version())?
+            }
+            None => BytecodeCompiler::new(&self.python_exe)?,
+        }))
     }
 
     fn create_packaging_policy(&self) -> Result<PythonPackagingPolicy> {
@@ -1070,6 +1089,7 @@ impl PythonDistribution for StandaloneDistribution {
         libpython_link_mode: BinaryLibpythonLinkMode,
         policy: &PythonPackagingPolicy,
         config: &EmbeddedPythonConfig,
+        build_directories: &BuildDirectories,
     ) -> Result<Box<dyn PythonBinaryBuilder>> {
         let builder = StandalonePythonExecutableBuilder::from_distribution(
             // TODO can we avoid this clone?
@@ -1080,6 +1100,7 @@ impl PythonDistribution for StandaloneDistribution {
             libpython_link_mode,
             policy.clone(),
             config.clone(),
+            build_directories.clone(),
         )?;
 
         Ok(builder as Box<dyn PythonBinaryBuilder>)
@@ -1104,7 +1125,7 @@ impl PythonDistribution for StandaloneDistribution {
 
                 Ok(PythonModuleSource {
                     name: name.clone(),
-                    source: DataLocation::Path(path.clone()),
+                    source: DataLocation::from_path(path.clone()),
                     is_package,
                     cache_tag: self.cache_tag.clone(),
                     is_stdlib: true,
@@ -1122,7 +1143,7 @@ impl PythonDistribution for StandaloneDistribution {
                 res.push(PythonPackageResource {
                     leaf_package: package.clone(),
                     relative_name: name.clone(),
-                    data: DataLocation::Path(path.clone()),
+                    data: DataLocation::from_path(path.clone()),
                     is_stdlib: true,
                     is_test: is_stdlib_test_package(&package),
                 });
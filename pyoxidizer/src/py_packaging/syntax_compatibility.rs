@@ -0,0 +1,284 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Detect packages using syntax newer than the target Python.
+
+A package whose sources use e.g. `match` statements can't be compiled for
+Python 3.9. Without this scan, that surfaces as one compile error per
+module late in the build. Scanning sources before compiling lets us report
+each offending package once, along with the Python version it requires.
+*/
+
+use {
+    super::config::{ConfigProblem, ConfigProblemSeverity},
+    super::site_packages_shim::module_sources,
+    anyhow::{Context, Result},
+    python_packaging::package_metadata::PythonPackageRecord,
+    python_packaging::resource_collection::PrePackagedResource,
+    python_packaging::syntax_features::{
+        find_syntax_features, required_python_version, SyntaxFeatureUse,
+    },
+    sha2::Digest,
+    std::collections::{BTreeMap, HashMap},
+};
+
+/// Format a `(major, minor)` version.
+fn format_version(version: (u32, u32)) -> String {
+    format!("{}.{}", version.0, version.1)
+}
+
+/// Modules of a package using syntax newer than the target Python.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PackageSyntaxUses {
+    /// The `(major, minor)` Python version the package's syntax requires.
+    pub required_version: (u32, u32),
+
+    /// Uses of syntax newer than the target, keyed by module name.
+    pub modules: BTreeMap<String, Vec<SyntaxFeatureUse>>,
+}
+
+/// Results of scanning module sources for syntax newer than the target Python.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SyntaxCompatibilityReport {
+    /// The targeted `(major, minor)` Python version.
+    pub target_version: (u32, u32),
+
+    /// Packages using newer syntax.
+    ///
+    /// Keyed by the name of the distribution installing the package, or by
+    /// top-level package name for modules not belonging to a distribution.
+    pub offenders: BTreeMap<String, PackageSyntaxUses>,
+}
+
+impl SyntaxCompatibilityReport {
+    /// Names of modules using syntax newer than the target.
+    pub fn module_names(&self) -> Vec<String> {
+        self.offenders
+            .values()
+            .flat_map(|uses| uses.modules.keys().cloned())
+            .collect()
+    }
+
+    /// Convert the report to problems, one per package.
+    pub fn problems(&self, severity: ConfigProblemSeverity) -> Vec<ConfigProblem> {
+        self.offenders
+            .iter()
+            .map(|(package, uses)| {
+                // Report the first use of each construct.
+                let mut examples: BTreeMap<_, (&String, usize)> = BTreeMap::new();
+                for (module, features) in &uses.modules {
+                    for u in features {
+                        examples.entry(u.feature).or_insert((module, u.line));
+                    }
+                }
+
+                ConfigProblem {
+                    severity,
+                    code: "syntax-requires-newer-python",
+                    message: format!(
+                        "package {} requires Python >= {} but Python {} is targeted; {} of its modules use newer syntax: {}",
+                        package,
+                        format_version(uses.required_version),
+                        format_version(self.target_version),
+                        uses.modules.len(),
+                        examples
+                            .iter()
+                            .map(|(feature, (module, line))| format!(
+                                "{} ({} line {})",
+                                feature.description(),
+                                module,
+                                line
+                            ))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Map top-level package names to the distribution installing them.
+///
+/// Distributions are identified by the RECORD file in their distribution
+/// resources. RECORD files that can't be parsed are ignored.
+pub(crate) fn distribution_owners<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)>,
+) -> Result<HashMap<String, String>> {
+    let mut res = HashMap::new();
+
+    for (name, resource) in resources {
+        let location = resource
+            .in_memory_distribution_resources
+            .as_ref()
+            .and_then(|resources| resources.get("RECORD"))
+            .or_else(|| {
+                resource
+                    .relative_path_distribution_resources
+                    .as_ref()
+                    .and_then(|resources| resources.get("RECORD"))
+                    .map(|(_, location)| location)
+            });
+
+        if let Some(location) = location {
+            let data = location
+                .resolve()
+                .with_context(|| format!("reading RECORD of {}", name))?;
+
+            if let Ok(record) = PythonPackageRecord::from_record(&data) {
+                for top_level in record.top_level_names() {
+                    res.insert(top_level, name.clone());
+                }
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Scan module sources for syntax newer than a target Python version.
+///
+/// `target_version` is a `(major, minor)` version. Sources are keyed by
+/// their content hash so the same source backing module source and multiple
+/// bytecode optimization levels is only scanned once.
+pub fn scan_syntax_compatibility<'a>(
+    resources: impl Iterator<Item = (&'a String, &'a PrePackagedResource)> + Clone,
+    target_version: (u32, u32),
+) -> Result<SyntaxCompatibilityReport> {
+    let owners = distribution_owners(resources.clone())?;
+
+    let mut cache: HashMap<Vec<u8>, Vec<SyntaxFeatureUse>> = HashMap::new();
+    let mut res = SyntaxCompatibilityReport {
+        target_version,
+        ..SyntaxCompatibilityReport::default()
+    };
+
+    for (name, resource) in resources {
+        let mut newer = Vec::new();
+
+        for location in module_sources(resource) {
+            let source = location
+                .resolve()
+                .with_context(|| format!("scanning {} for syntax features", name))?;
+            let digest = sha2::Sha256::digest(&source).to_vec();
+
+            let uses = cache
+                .entry(digest)
+                .or_insert_with(|| find_syntax_features(&source));
+
+            for u in uses.iter() {
+                if u.feature.minimum_version() > target_version
+                    && !newer
+                        .iter()
+                        .any(|n: &SyntaxFeatureUse| n.feature == u.feature)
+                {
+                    newer.push(*u);
+                }
+            }
+        }
+
+        let required = match required_python_version(&newer) {
+            Some(required) => required,
+            None => continue,
+        };
+
+        let top_level = name.split('.').next().unwrap_or(name);
+        let package = owners
+            .get(top_level)
+            .cloned()
+            .unwrap_or_else(|| top_level.to_string());
+
+        let entry = res.offenders.entry(package).or_default();
+        entry.required_version = entry.required_version.max(required);
+        newer.sort_by_key(|u| u.feature);
+        entry.modules.insert(name.clone(), newer);
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*, python_packaging::resource::DataLocation,
+        python_packaging::syntax_features::SyntaxFeature,
+        python_packed_resources::data::ResourceFlavor,
+    };
+
+    fn module(name: &str, source: &[u8]) -> PrePackagedResource {
+        PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: name.to_string(),
+            relative_path_module_source: Some((
+                "lib".to_string(),
+                DataLocation::Memory(source.to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        }
+    }
+
+    #[test]
+    fn test_scan_syntax_compatibility() -> Result<()> {
+        let mut resources = BTreeMap::new();
+        for (name, source) in &[
+            ("acme", &b"import os"[..]),
+            ("acme.cli", &b"match argv:\n    case []: pass\n"[..]),
+            ("acme.util", &b"if (n := 1):\n    pass\n"[..]),
+            ("old", &b"x = f'{y}'\n"[..]),
+            ("loose", &b"def f(a, /):\n    pass\n"[..]),
+        ] {
+            resources.insert(name.to_string(), module(name, source));
+        }
+
+        let mut record = BTreeMap::new();
+        record.insert(
+            "RECORD".to_string(),
+            DataLocation::Memory(b"acme/__init__.py,,\nacme/cli.py,,\n".to_vec()),
+        );
+        resources.insert(
+            "acme-tools".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "acme-tools".to_string(),
+                in_memory_distribution_resources: Some(record),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        let report = scan_syntax_compatibility(resources.iter(), (3, 7))?;
+
+        assert_eq!(
+            report.offenders.keys().cloned().collect::<Vec<_>>(),
+            vec!["acme-tools".to_string(), "loose".to_string()]
+        );
+        let acme = &report.offenders["acme-tools"];
+        assert_eq!(acme.required_version, (3, 10));
+        assert_eq!(
+            acme.modules["acme.cli"],
+            vec![SyntaxFeatureUse {
+                feature: SyntaxFeature::MatchStatement,
+                line: 1
+            }]
+        );
+        assert_eq!(
+            report.module_names(),
+            vec!["acme.cli", "acme.util", "loose"]
+        );
+
+        let problems = report.problems(ConfigProblemSeverity::Warning);
+        assert_eq!(problems.len(), 2);
+        assert_eq!(
+            problems[0].message,
+            "package acme-tools requires Python >= 3.10 but Python 3.7 is targeted; 2 of its modules use newer syntax: := operator (acme.util line 1), match statement (acme.cli line 1)"
+        );
+
+        // Nothing is newer than a recent target.
+        assert!(scan_syntax_compatibility(resources.iter(), (3, 12))?
+            .offenders
+            .is_empty());
+
+        Ok(())
+    }
+}
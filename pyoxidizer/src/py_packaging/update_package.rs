@@ -0,0 +1,1155 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Functionality for producing update packages between builds.
+
+An update package contains only the files of a build that differ from a
+previous build, along with a delta manifest describing which files to
+replace, which files to remove and the expected hashes of the resulting
+layout. Shipping an update package instead of a full build avoids
+re-downloading large files that didn't change.
+
+Packed resources are a single file that changes whenever any resource
+changes. So they are split into shards at boundaries determined by their
+content and only shards the previous build doesn't have are shipped.
+Boundaries only depend on nearby content, so a change to one resource
+leaves shards elsewhere in the file intact even if data after the change
+moves to different offsets.
+*/
+
+use {
+    super::binary::{
+        AppliedPatch, EmbeddedPythonContext, ExtensionDecision, PackedResourcesEmbedding,
+    },
+    super::config::{HashRandomization, RuntimeEnvRegistry},
+    super::entry_points::{ConsoleScript, EntryPointProvider},
+    super::install_layout::layout_key,
+    crate::app_packaging::resource::{is_executable, FileContent, FileManifest},
+    anyhow::{anyhow, Context, Result},
+    python_packaging::layering::{LayerDeletion, LayerOverride},
+    python_packaging::package_metadata::DirectUrl,
+    python_packaging::resource::DataLocation,
+    python_packaging::resource_collection::{CollectedDistribution, RecoverySetCost},
+    python_packed_resources::sink::EMBEDDED_BACKEND,
+    serde::{Deserialize, Serialize},
+    sha2::{Digest, Sha256},
+    std::collections::{BTreeMap, BTreeSet},
+    std::convert::TryFrom,
+    std::ops::Range,
+    std::path::{Component, Path, PathBuf},
+};
+
+/// Filename of the delta manifest within an update package.
+pub const DELTA_MANIFEST_FILENAME: &str = "delta-manifest.json";
+
+/// Directory within an update package holding file content.
+const PACKAGE_FILES_DIRECTORY: &str = "files";
+
+/// Directory within an update package holding shards, named by their hash.
+const PACKAGE_SHARDS_DIRECTORY: &str = "shards";
+
+/// Files of a build that are shipped as shards.
+const SHARDED_FILES: &[&str] = &["packed-resources"];
+
+/// Shards are at least this large, unless they end a file.
+const SHARD_MIN_SIZE: usize = 16 * 1024;
+
+/// Shards are at most this large.
+const SHARD_MAX_SIZE: usize = 256 * 1024;
+
+/// Bits of the rolling hash that must be zero at a shard boundary.
+///
+/// 16 bits result in shards of about 64 KiB beyond the minimum size.
+const SHARD_BOUNDARY_MASK: u64 = 0xffff << 48;
+
+/// Obtain the table of random values the rolling hash is derived from.
+fn gear_table() -> [u64; 256] {
+    // splitmix64 with a fixed seed, so boundaries never change.
+    let mut state = 0x5079_4f78_6964_697a_u64;
+    let mut table = [0; 256];
+
+    for value in table.iter_mut() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        *value = z ^ (z >> 31);
+    }
+
+    table
+}
+
+/// Split data into shards at boundaries determined by its content.
+///
+/// A boundary only depends on the 64 bytes preceding it, so inserting or
+/// removing data only changes the shards around the change.
+pub fn shard_ranges(data: &[u8]) -> Vec<Range<usize>> {
+    let table = gear_table();
+    let mut res = vec![];
+    let mut start = 0;
+    let mut hash = 0u64;
+
+    for (i, b) in data.iter().enumerate() {
+        hash = (hash << 1).wrapping_add(table[*b as usize]);
+        let size = i + 1 - start;
+
+        if (size >= SHARD_MIN_SIZE && hash & SHARD_BOUNDARY_MASK == 0) || size >= SHARD_MAX_SIZE {
+            res.push(start..i + 1);
+            start = i + 1;
+            hash = 0;
+        }
+    }
+
+    if start < data.len() {
+        res.push(start..data.len());
+    }
+
+    res
+}
+
+/// Obtain the path of a file or directory of a layout from its manifest key.
+///
+/// Keys come from manifests that may not be trustworthy, so anything but a
+/// normalized relative path is rejected rather than allowed to escape the
+/// layout.
+fn layout_path(root: &Path, key: &str) -> Result<PathBuf> {
+    let valid = !key.is_empty()
+        && !key.contains('\\')
+        && key
+            .split('/')
+            .all(|part| !part.is_empty() && part != "." && part != "..")
+        && Path::new(key)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+
+    if valid {
+        Ok(root.join(key))
+    } else {
+        Err(anyhow!("invalid path {} in manifest", key))
+    }
+}
+
+/// Obtain the path of a shard in an update package from its hash.
+fn shard_path(package: &Path, sha256: &str) -> Result<PathBuf> {
+    if sha256.len() == 64
+        && sha256
+            .chars()
+            .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+    {
+        Ok(package.join(PACKAGE_SHARDS_DIRECTORY).join(sha256))
+    } else {
+        Err(anyhow!("invalid shard hash {} in manifest", sha256))
+    }
+}
+
+/// Describes a single file in a build.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ManifestEntry {
+    /// Hex encoded SHA-256 of the file content.
+    pub sha256: String,
+
+    /// Size of the file in bytes.
+    pub size: u64,
+
+    /// Whether the file is executable.
+    pub executable: bool,
+}
+
+impl TryFrom<&FileContent> for ManifestEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(content: &FileContent) -> Result<Self> {
+        // Content is streamed through the hasher so large files aren't
+        // read into memory.
+        let mut hasher = Sha256::new();
+        let size = content.data.copy_to(&mut hasher)?;
+
+        Ok(Self {
+            sha256: hex::encode(hasher.finalize()),
+            size,
+            executable: content.executable,
+        })
+    }
+}
+
+/// Describes a contiguous part of a file.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Shard {
+    /// Hex encoded SHA-256 of the shard content.
+    pub sha256: String,
+
+    /// Size of the shard in bytes.
+    pub size: u64,
+}
+
+/// Split data into shards.
+fn shards(data: &[u8]) -> Vec<Shard> {
+    shard_ranges(data)
+        .into_iter()
+        .map(|range| Shard {
+            sha256: sha256_hex(&data[range.clone()]),
+            size: range.len() as u64,
+        })
+        .collect()
+}
+
+/// Describes the files in a build and how its extension modules were packaged.
+///
+/// Keys are paths relative to the build's root directory, using `/` as a
+/// directory separator.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BuildManifest {
+    pub files: BTreeMap<String, ManifestEntry>,
+
+    /// What added each file, e.g. `resource foo` or `libpython`.
+    ///
+    /// Files without recorded provenance are absent.
+    #[serde(default)]
+    pub provenance: BTreeMap<String, String>,
+
+    /// Directories holding files of the build.
+    #[serde(default)]
+    pub directories: BTreeSet<String>,
+
+    /// Shards of files shipped as shards in update packages, in file order.
+    #[serde(default)]
+    pub shards: BTreeMap<String, Vec<Shard>>,
+
+    /// How each extension module was packaged and why.
+    #[serde(default)]
+    pub extension_modules: Vec<ExtensionDecision>,
+
+    /// Features that were enabled when selecting tagged resources.
+    #[serde(default)]
+    pub features: BTreeSet<String>,
+
+    /// Package patches applied to resources.
+    #[serde(default)]
+    pub patches: Vec<AppliedPatch>,
+
+    /// Console scripts listed by the synthesized console scripts module.
+    #[serde(default)]
+    pub console_scripts: Vec<ConsoleScript>,
+
+    /// Files of layered package roots replaced by later layers.
+    #[serde(default)]
+    pub layer_overrides: Vec<LayerOverride>,
+
+    /// Files of layered package roots removed by tombstones of later layers.
+    #[serde(default)]
+    pub layer_deletions: Vec<LayerDeletion>,
+
+    /// Labels of payloads classified as tests, by resource name.
+    #[serde(default)]
+    pub test_payloads: BTreeMap<String, BTreeSet<String>>,
+
+    /// Entry points kept because modules load their group.
+    #[serde(default)]
+    pub entry_point_protections: Vec<EntryPointProvider>,
+
+    /// How packed resources are embedded in the binary.
+    #[serde(default)]
+    pub packed_resources: PackedResourcesEmbedding,
+
+    /// How hashes are randomized.
+    #[serde(default)]
+    pub hash_randomization: HashRandomization,
+
+    /// Hash seed the interpreter is initialized with.
+    ///
+    /// `None` means a random seed is used.
+    #[serde(default)]
+    pub hash_seed: Option<u32>,
+
+    /// Environment variables the interpreter configuration reads at run-time.
+    #[serde(default)]
+    pub runtime_env: RuntimeEnvRegistry,
+
+    /// When the build ran, in seconds since the UNIX epoch.
+    #[serde(default)]
+    pub build_time: u64,
+
+    /// What the recovery resource set adds to packed resources, if there is one.
+    #[serde(default)]
+    pub recovery_set: Option<RecoverySetCost>,
+
+    /// Name of the storage backend that received packed resources payloads.
+    ///
+    /// Builds with a backend other than `embedded` obtain payloads that
+    /// aren't part of the build at run-time.
+    #[serde(default = "default_payload_backend")]
+    pub payload_backend: String,
+
+    /// Where distributions installed from a direct URL came from, by package name.
+    ///
+    /// This records VCS commits and local directories the build depends on.
+    #[serde(default)]
+    pub direct_urls: BTreeMap<String, DirectUrl>,
+
+    /// Versions of distributions in the build, by normalized name.
+    ///
+    /// Versions discarded because another version of a distribution was
+    /// collected are recorded as well.
+    #[serde(default)]
+    pub distributions: BTreeMap<String, CollectedDistribution>,
+
+    /// Path template import telemetry is written to, if the build records imports.
+    ///
+    /// Builds recording imports are meant for gathering data, not for release.
+    #[serde(default)]
+    pub import_telemetry: Option<String>,
+
+    /// Bytecode cache tag of the Python distribution of the build.
+    ///
+    /// Packed resources of the build can only seed builds with this tag.
+    #[serde(default)]
+    pub cache_tag: Option<String>,
+}
+
+fn default_payload_backend() -> String {
+    EMBEDDED_BACKEND.to_string()
+}
+
+impl BuildManifest {
+    /// Construct an instance from files in a layout.
+    pub fn from_layout(layout: &FileManifest) -> Result<Self> {
+        Ok(Self {
+            files: layout
+                .entries()
+                .map(|(path, content)| Ok((layout_key(path), ManifestEntry::try_from(content)?)))
+                .collect::<Result<_>>()?,
+            provenance: layout
+                .entries()
+                .filter_map(|(path, _)| {
+                    layout
+                        .provenance(path)
+                        .map(|provenance| (layout_key(path), provenance.to_string()))
+                })
+                .collect(),
+            directories: layout
+                .relative_directories()
+                .iter()
+                .map(|path| layout_key(path))
+                .collect(),
+            shards: layout
+                .entries()
+                .filter(|(path, _)| SHARDED_FILES.contains(&layout_key(path).as_str()))
+                .map(|(path, content)| Ok((layout_key(path), shards(&content.data.resolve()?))))
+                .collect::<Result<_>>()?,
+            extension_modules: vec![],
+            features: BTreeSet::new(),
+            patches: vec![],
+            console_scripts: vec![],
+            layer_overrides: vec![],
+            layer_deletions: vec![],
+            test_payloads: BTreeMap::new(),
+            entry_point_protections: vec![],
+            packed_resources: PackedResourcesEmbedding::default(),
+            hash_randomization: HashRandomization::Random,
+            hash_seed: None,
+            runtime_env: RuntimeEnvRegistry::default(),
+            build_time: 0,
+            recovery_set: None,
+            payload_backend: default_payload_backend(),
+            direct_urls: BTreeMap::new(),
+            distributions: BTreeMap::new(),
+            import_telemetry: None,
+            cache_tag: None,
+        })
+    }
+
+    /// Obtain a digest identifying the content of the build.
+    ///
+    /// Only files are considered: builds with identical files have the
+    /// same digest.
+    pub fn content_digest(&self) -> String {
+        let mut hasher = Sha256::new();
+
+        for (path, entry) in &self.files {
+            hasher.update(path.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(entry.sha256.as_bytes());
+            hasher.update(b"\0");
+            hasher.update(entry.size.to_string().as_bytes());
+            hasher.update(if entry.executable { b"\0x\n" } else { b"\0-\n" });
+        }
+
+        hex::encode(hasher.finalize())
+    }
+
+    /// Read an instance from a JSON file.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+        serde_json::from_slice(&data)
+            .with_context(|| format!("parsing build manifest {}", path.display()))
+    }
+
+    /// Write the instance to a JSON file.
+    pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, serde_json::to_vec_pretty(self)?)
+            .with_context(|| format!("writing {}", path.display()))
+    }
+}
+
+/// Describes the changes needed to turn one build into another.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DeltaManifest {
+    /// Files that were added or changed.
+    ///
+    /// The content of these files is stored in the update package, unless
+    /// they are listed in `sharded`.
+    pub changed: BTreeMap<String, ManifestEntry>,
+
+    /// Changed files assembled from shards, in file order.
+    ///
+    /// Shards are stored in the update package if the previous build
+    /// didn't have them and are otherwise taken from the existing file.
+    #[serde(default)]
+    pub sharded: BTreeMap<String, Vec<Shard>>,
+
+    /// Files that no longer exist in the new build and should be deleted.
+    pub removed: Vec<String>,
+
+    /// Directories that no longer exist in the new build.
+    ///
+    /// Children are listed before their parents. Directories are only
+    /// deleted if they are empty after removing files.
+    #[serde(default)]
+    pub removed_directories: Vec<String>,
+
+    /// Manifest of the new build.
+    ///
+    /// After applying the update, the layout should match this exactly.
+    pub expected: BuildManifest,
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(data);
+    hex::encode(hasher.finalize())
+}
+
+/// Obtain the files an `EmbeddedPythonContext` contributes to a build.
+///
+/// Packed resources and module names use the filenames from
+/// `EmbeddedPythonContext::write_files()`. Packed resources are recorded even
+/// if placed in a linker section, so the binary can be verified against the
+/// manifest.
+pub fn embedded_context_layout(context: &EmbeddedPythonContext) -> Result<FileManifest> {
+    let mut layout = FileManifest::default();
+
+    layout.add_file_from(
+        Path::new("packed-resources"),
+        &FileContent {
+            data: DataLocation::Memory(context.resources.clone()),
+            executable: false,
+        },
+        "packed resources",
+    )?;
+    layout.add_file_from(
+        Path::new("py-module-names"),
+        &FileContent {
+            data: DataLocation::Memory(context.module_names.clone()),
+            executable: false,
+        },
+        "module names",
+    )?;
+    layout.add_manifest(&context.extra_files)?;
+
+    Ok(layout)
+}
+
+/// Obtain the manifest of an `EmbeddedPythonContext`.
+pub fn build_manifest(context: &EmbeddedPythonContext) -> Result<BuildManifest> {
+    let mut manifest = BuildManifest::from_layout(&embedded_context_layout(context)?)?;
+    manifest.directories = context.install_layout.directories.clone();
+    manifest.extension_modules = context.extension_decisions.clone();
+    manifest.features = context.enabled_features.clone();
+    manifest.patches = context.applied_patches.clone();
+    manifest.console_scripts = context.console_scripts.clone();
+    manifest.layer_overrides = context.layer_overrides.clone();
+    manifest.layer_deletions = context.layer_deletions.clone();
+    manifest.test_payloads = context.test_payloads.clone();
+    manifest.entry_point_protections = context.entry_point_protections.clone();
+    manifest.packed_resources = context.packed_resources_embedding.clone();
+    manifest.hash_randomization = context.config.hash_randomization;
+    manifest.hash_seed = context.config.resolved_hash_seed();
+    manifest.runtime_env = context.runtime_env();
+    manifest.build_time = context.build_time;
+    manifest.recovery_set = context.recovery_set_cost;
+    manifest.payload_backend = context.payload_backend.clone();
+    manifest.direct_urls = context.direct_urls.clone();
+    manifest.distributions = context.distributions.clone();
+    manifest.import_telemetry = context.config.import_telemetry_path.clone();
+    manifest.cache_tag = Some(context.cache_tag.clone());
+
+    Ok(manifest)
+}
+
+/// Write the manifest of an `EmbeddedPythonContext` to a file.
+///
+/// The written file can later be passed to `write_update_package()`.
+pub fn write_build_manifest(context: &EmbeddedPythonContext, dest: &Path) -> Result<()> {
+    build_manifest(context)?.write_to_path(dest)
+}
+
+/// Write an update package turning a build described by `old` into `new_layout`.
+pub fn write_update_package_from_layout(
+    old: &BuildManifest,
+    new_layout: &FileManifest,
+    dest: &Path,
+) -> Result<DeltaManifest> {
+    let expected = BuildManifest::from_layout(new_layout)?;
+
+    let mut changed = BTreeMap::new();
+    let mut sharded = BTreeMap::new();
+    let mut package_files = FileManifest::default();
+
+    for (path, content) in new_layout.entries() {
+        let key = layout_key(path);
+        let entry = ManifestEntry::try_from(content)?;
+
+        if old.files.get(&key) == Some(&entry) {
+            continue;
+        }
+
+        if let Some(new_shards) = expected.shards.get(&key) {
+            let old_shards = old
+                .shards
+                .get(&key)
+                .map(|shards| shards.iter().map(|shard| &shard.sha256).collect())
+                .unwrap_or_else(BTreeSet::new);
+
+            let data = content.data.resolve()?;
+            for (range, shard) in shard_ranges(&data).into_iter().zip(new_shards) {
+                if !old_shards.contains(&shard.sha256) {
+                    package_files.add_file(
+                        &Path::new(PACKAGE_SHARDS_DIRECTORY).join(&shard.sha256),
+                        &FileContent {
+                            data: DataLocation::Memory(data[range].to_vec()),
+                            executable: false,
+                        },
+                    )?;
+                }
+            }
+
+            sharded.insert(key.clone(), new_shards.clone());
+        } else {
+            package_files.add_file(&Path::new(PACKAGE_FILES_DIRECTORY).join(path), content)?;
+        }
+
+        changed.insert(key, entry);
+    }
+
+    let removed = old
+        .files
+        .keys()
+        .filter(|key| !expected.files.contains_key(*key))
+        .cloned()
+        .collect();
+
+    let removed_directories = old
+        .directories
+        .iter()
+        .rev()
+        .filter(|key| !expected.directories.contains(*key))
+        .cloned()
+        .collect();
+
+    let delta = DeltaManifest {
+        changed,
+        sharded,
+        removed,
+        removed_directories,
+        expected,
+    };
+
+    std::fs::create_dir_all(dest)
+        .with_context(|| format!("creating update package directory {}", dest.display()))?;
+    package_files.write_to_path(dest)?;
+
+    let manifest_path = dest.join(DELTA_MANIFEST_FILENAME);
+    std::fs::write(&manifest_path, serde_json::to_vec_pretty(&delta)?)
+        .with_context(|| format!("writing {}", manifest_path.display()))?;
+
+    Ok(delta)
+}
+
+/// Write an update package between a previous build and an `EmbeddedPythonContext`.
+///
+/// `old_manifest` is the path to a build manifest written by
+/// `write_build_manifest()` for the previous build.
+pub fn write_update_package(
+    old_manifest: &Path,
+    new_context: &EmbeddedPythonContext,
+    dest: &Path,
+) -> Result<DeltaManifest> {
+    let old = BuildManifest::from_path(old_manifest)?;
+
+    write_update_package_from_layout(&old, &embedded_context_layout(new_context)?, dest)
+}
+
+/// Apply an update package to a build layout in a directory.
+///
+/// Content from the package is verified against its hash before being
+/// installed.
+pub fn apply_update_package(package: &Path, layout_dir: &Path) -> Result<DeltaManifest> {
+    let manifest_path = package.join(DELTA_MANIFEST_FILENAME);
+    let data = std::fs::read(&manifest_path)
+        .with_context(|| format!("reading {}", manifest_path.display()))?;
+    let delta: DeltaManifest = serde_json::from_slice(&data)
+        .with_context(|| format!("parsing delta manifest {}", manifest_path.display()))?;
+
+    // Validate all paths before modifying anything.
+    for key in delta
+        .changed
+        .keys()
+        .chain(delta.removed.iter())
+        .chain(delta.removed_directories.iter())
+        .chain(delta.sharded.keys())
+        .chain(delta.expected.files.keys())
+    {
+        layout_path(layout_dir, key)?;
+    }
+    for shard in delta.sharded.values().flatten() {
+        shard_path(package, &shard.sha256)?;
+    }
+
+    // Sharded files are assembled first, as they may reuse shards of the
+    // files they replace.
+    let mut assembled = BTreeMap::new();
+    for (key, shards) in &delta.sharded {
+        let data = assemble_shards(package, &layout_path(layout_dir, key)?, shards)
+            .with_context(|| format!("assembling {} from shards", key))?;
+
+        assembled.insert(key.clone(), data);
+    }
+
+    for key in &delta.removed {
+        let path = layout_path(layout_dir, key)?;
+
+        if path.exists() {
+            std::fs::remove_file(&path).with_context(|| format!("removing {}", path.display()))?;
+        }
+    }
+
+    // Directories may hold files that aren't part of the build. Leave those.
+    for key in &delta.removed_directories {
+        let path = layout_path(layout_dir, key)?;
+
+        if path.is_dir()
+            && std::fs::read_dir(&path)
+                .with_context(|| format!("reading {}", path.display()))?
+                .next()
+                .is_none()
+        {
+            std::fs::remove_dir(&path).with_context(|| format!("removing {}", path.display()))?;
+        }
+    }
+
+    let mut installs = FileManifest::default();
+
+    for (key, entry) in &delta.changed {
+        let content = match assembled.remove(key) {
+            Some(data) => FileContent {
+                data: DataLocation::Memory(data),
+                executable: entry.executable,
+            },
+            None => {
+                let source = layout_path(&package.join(PACKAGE_FILES_DIRECTORY), key)?;
+                FileContent::try_from(source.as_path())
+                    .with_context(|| format!("reading {}", source.display()))?
+            }
+        };
+
+        if sha256_hex(&content.data.resolve()?) != entry.sha256 {
+            return Err(anyhow!(
+                "content of {} in update package does not match its hash",
+                key
+            ));
+        }
+
+        // Remove existing files so permissions don't carry over.
+        let dest_path = layout_path(layout_dir, key)?;
+        if dest_path.exists() {
+            std::fs::remove_file(&dest_path)
+                .with_context(|| format!("removing {}", dest_path.display()))?;
+        }
+
+        installs.add_file(
+            Path::new(key),
+            &FileContent {
+                data: content.data,
+                executable: entry.executable,
+            },
+        )?;
+    }
+
+    installs.write_to_path(layout_dir)?;
+
+    Ok(delta)
+}
+
+/// Assemble a file from shards in an update package and of the file it replaces.
+fn assemble_shards(package: &Path, existing: &Path, shards: &[Shard]) -> Result<Vec<u8>> {
+    let existing_data = if existing.exists() {
+        std::fs::read(existing).with_context(|| format!("reading {}", existing.display()))?
+    } else {
+        vec![]
+    };
+
+    let existing_shards = shard_ranges(&existing_data)
+        .into_iter()
+        .map(|range| (sha256_hex(&existing_data[range.clone()]), range))
+        .collect::<BTreeMap<_, _>>();
+
+    let mut data = Vec::with_capacity(shards.iter().map(|shard| shard.size as usize).sum());
+
+    for shard in shards {
+        if let Some(range) = existing_shards.get(&shard.sha256) {
+            data.extend_from_slice(&existing_data[range.clone()]);
+            continue;
+        }
+
+        let path = shard_path(package, &shard.sha256)?;
+        if !path.exists() {
+            return Err(anyhow!(
+                "shard {} is neither in the update package nor in the existing file",
+                shard.sha256
+            ));
+        }
+
+        let shard_data =
+            std::fs::read(&path).with_context(|| format!("reading {}", path.display()))?;
+        if sha256_hex(&shard_data) != shard.sha256 {
+            return Err(anyhow!(
+                "content of shard {} in update package does not match its hash",
+                shard.sha256
+            ));
+        }
+
+        data.extend_from_slice(&shard_data);
+    }
+
+    Ok(data)
+}
+
+fn copy_directory(source: &Path, dest: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(source) {
+        let entry = entry?;
+        let rel_path = entry.path().strip_prefix(source)?;
+        let dest_path = dest.join(rel_path);
+
+        if entry.file_type().is_dir() {
+            std::fs::create_dir_all(&dest_path)
+                .with_context(|| format!("creating {}", dest_path.display()))?;
+        } else {
+            std::fs::copy(entry.path(), &dest_path)
+                .with_context(|| format!("copying {}", entry.path().display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Verify an update package against a previous build.
+///
+/// The previous build in `old_layout` is copied to `scratch_dir` and the
+/// update package is applied to the copy. Every file of the new build must
+/// then exist with the expected hash and every removed file and directory
+/// must be gone.
+pub fn verify_update_package(old_layout: &Path, package: &Path, scratch_dir: &Path) -> Result<()> {
+    copy_directory(old_layout, scratch_dir)?;
+
+    let delta = apply_update_package(package, scratch_dir)?;
+
+    for (key, entry) in &delta.expected.files {
+        let path = layout_path(scratch_dir, key)?;
+        let content = FileContent::try_from(path.as_path())
+            .with_context(|| format!("{} missing after applying update", key))?;
+
+        if sha256_hex(&content.data.resolve()?) != entry.sha256 {
+            return Err(anyhow!(
+                "{} does not match new build after applying update",
+                key
+            ));
+        }
+
+        if cfg!(unix) && is_executable(&std::fs::metadata(&path)?) != entry.executable {
+            return Err(anyhow!(
+                "executable bit of {} does not match new build after applying update",
+                key
+            ));
+        }
+    }
+
+    for key in &delta.removed {
+        if layout_path(scratch_dir, key)?.exists() {
+            return Err(anyhow!("{} still exists after applying update", key));
+        }
+    }
+
+    for key in &delta.removed_directories {
+        if layout_path(scratch_dir, key)?.exists() {
+            return Err(anyhow!(
+                "directory {} still exists after applying update",
+                key
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::path::PathBuf};
+
+    fn layout(files: &[(&str, &[u8], bool)]) -> Result<FileManifest> {
+        let mut manifest = FileManifest::default();
+
+        for (path, data, executable) in files {
+            manifest.add_file(
+                Path::new(path),
+                &FileContent {
+                    data: DataLocation::Memory(data.to_vec()),
+                    executable: *executable,
+                },
+            )?;
+        }
+
+        Ok(manifest)
+    }
+
+    fn old_layout() -> Result<FileManifest> {
+        layout(&[
+            ("packed-resources", b"old resources", false),
+            ("py-module-names", b"foo\nbar", false),
+            ("lib/foo.py", b"import os", false),
+            ("lib/gone.py", b"import sys", false),
+            ("share/gone/data.txt", b"data", false),
+        ])
+    }
+
+    fn new_layout() -> Result<FileManifest> {
+        layout(&[
+            ("packed-resources", b"new resources", false),
+            ("py-module-names", b"foo\nbar", false),
+            ("lib/foo.py", b"import os", false),
+            ("bin/tool", b"#!/bin/sh\n", true),
+        ])
+    }
+
+    #[test]
+    fn test_build_manifest_roundtrip() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let path = td.path().join("manifest.json");
+
+        let manifest = BuildManifest::from_layout(&old_layout()?)?;
+        assert_eq!(manifest.files.len(), 5);
+        assert_eq!(manifest.files["lib/foo.py"].size, 9);
+        assert_eq!(
+            manifest.directories.iter().collect::<Vec<_>>(),
+            vec!["lib", "share", "share/gone"]
+        );
+
+        manifest.write_to_path(&path)?;
+        assert_eq!(BuildManifest::from_path(&path)?, manifest);
+
+        // Manifests predating payload storage backends embedded payloads.
+        assert_eq!(manifest.payload_backend, "embedded");
+        let old: BuildManifest = serde_json::from_str("{\"files\": {}}")?;
+        assert_eq!(old.payload_backend, "embedded");
+
+        let manifest = BuildManifest {
+            hash_randomization: HashRandomization::Fixed,
+            hash_seed: Some(42),
+            ..manifest
+        };
+        manifest.write_to_path(&path)?;
+        assert_eq!(BuildManifest::from_path(&path)?, manifest);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_manifest_provenance() -> Result<()> {
+        let mut layout = old_layout()?;
+        layout.add_file_from(
+            Path::new("bin/tool"),
+            &FileContent {
+                data: DataLocation::Memory(b"#!/bin/sh\n".to_vec()),
+                executable: true,
+            },
+            "script rule for bin/tool",
+        )?;
+
+        let manifest = BuildManifest::from_layout(&layout)?;
+        assert_eq!(
+            manifest.provenance.iter().collect::<Vec<_>>(),
+            vec![(
+                &"bin/tool".to_string(),
+                &"script rule for bin/tool".to_string()
+            )]
+        );
+        assert!(serde_json::to_string(&manifest)?
+            .contains("\"provenance\":{\"bin/tool\":\"script rule for bin/tool\"}"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_update_package() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let old_dir = td.path().join("old");
+        let package_dir = td.path().join("package");
+
+        let old = old_layout()?;
+        old.write_to_path(&old_dir)?;
+
+        let delta = write_update_package_from_layout(
+            &BuildManifest::from_layout(&old)?,
+            &new_layout()?,
+            &package_dir,
+        )?;
+
+        assert_eq!(
+            delta.changed.keys().collect::<Vec<_>>(),
+            vec!["bin/tool", "packed-resources"]
+        );
+        assert_eq!(
+            delta.removed,
+            vec!["lib/gone.py".to_string(), "share/gone/data.txt".to_string()]
+        );
+        assert_eq!(
+            delta.removed_directories,
+            vec!["share/gone".to_string(), "share".to_string()]
+        );
+        assert_eq!(delta.expected, BuildManifest::from_layout(&new_layout()?)?);
+
+        let files_dir = package_dir.join(PACKAGE_FILES_DIRECTORY);
+        assert!(package_dir.join(DELTA_MANIFEST_FILENAME).exists());
+        assert_eq!(delta.sharded["packed-resources"].len(), 1);
+        assert!(package_dir
+            .join(PACKAGE_SHARDS_DIRECTORY)
+            .join(&delta.sharded["packed-resources"][0].sha256)
+            .exists());
+        assert!(!files_dir.join("packed-resources").exists());
+        assert!(files_dir.join("bin").join("tool").exists());
+        assert!(!files_dir.join("py-module-names").exists());
+        assert!(!files_dir.join("lib").exists());
+
+        let scratch_dir = td.path().join("scratch");
+        verify_update_package(&old_dir, &package_dir, &scratch_dir)?;
+        assert!(!scratch_dir.join("share").exists());
+        assert!(scratch_dir.join("lib").is_dir());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_tampered_package() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let old_dir = td.path().join("old");
+        let package_dir = td.path().join("package");
+
+        let old = old_layout()?;
+        old.write_to_path(&old_dir)?;
+
+        let delta = write_update_package_from_layout(
+            &BuildManifest::from_layout(&old)?,
+            &new_layout()?,
+            &package_dir,
+        )?;
+
+        let tampered: PathBuf = package_dir.join(PACKAGE_FILES_DIRECTORY).join("bin/tool");
+        std::fs::write(&tampered, b"tampered")?;
+
+        let err =
+            verify_update_package(&old_dir, &package_dir, &td.path().join("scratch")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "content of bin/tool in update package does not match its hash"
+        );
+
+        let sha256 = &delta.sharded["packed-resources"][0].sha256;
+        std::fs::write(
+            package_dir.join(PACKAGE_SHARDS_DIRECTORY).join(sha256),
+            b"x",
+        )?;
+
+        let err =
+            verify_update_package(&old_dir, &package_dir, &td.path().join("scratch2")).unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            format!(
+                "assembling packed-resources from shards: content of shard {} in update package does not match its hash",
+                sha256
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_mismatched_old_layout() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let old_dir = td.path().join("old");
+        let package_dir = td.path().join("package");
+
+        let old = old_layout()?;
+
+        write_update_package_from_layout(
+            &BuildManifest::from_layout(&old)?,
+            &new_layout()?,
+            &package_dir,
+        )?;
+
+        // The package was produced against a different build than the one
+        // it is applied to, so unchanged files don't match.
+        layout(&[("lib/foo.py", b"import io", false)])?.write_to_path(&old_dir)?;
+
+        assert!(verify_update_package(&old_dir, &package_dir, &td.path().join("scratch")).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layout_path() {
+        let root = Path::new("root");
+
+        assert_eq!(
+            layout_path(root, "lib/foo.py").unwrap(),
+            root.join("lib/foo.py")
+        );
+
+        for key in &[
+            "",
+            "/etc/passwd",
+            "../outside",
+            "lib/../../outside",
+            "lib/./foo.py",
+            "lib//foo.py",
+            "lib/",
+            "lib\\..\\..\\outside",
+        ] {
+            assert!(layout_path(root, key).is_err(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn test_apply_rejects_escaping_paths() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let layout_dir = td.path().join("layout");
+        let package_dir = td.path().join("package");
+        let victim = td.path().join("victim");
+        std::fs::create_dir_all(&layout_dir)?;
+        std::fs::create_dir_all(&package_dir)?;
+        std::fs::write(&victim, b"keep me")?;
+
+        let delta = DeltaManifest {
+            removed: vec!["../victim".to_string()],
+            ..DeltaManifest::default()
+        };
+        std::fs::write(
+            package_dir.join(DELTA_MANIFEST_FILENAME),
+            serde_json::to_vec(&delta)?,
+        )?;
+
+        let err = apply_update_package(&package_dir, &layout_dir).unwrap_err();
+        assert_eq!(err.to_string(), "invalid path ../victim in manifest");
+        assert!(victim.exists());
+
+        Ok(())
+    }
+
+    /// Obtain deterministic data that doesn't compress or repeat.
+    fn noise(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shard_ranges() {
+        assert!(shard_ranges(b"").is_empty());
+        assert_eq!(shard_ranges(b"small"), vec![0..5]);
+
+        let data = noise(1024 * 1024, 42);
+        let ranges = shard_ranges(&data);
+        assert!(ranges.len() > 4);
+        assert_eq!(ranges.first().unwrap().start, 0);
+        assert_eq!(ranges.last().unwrap().end, data.len());
+        for pair in ranges.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+        }
+        for range in &ranges[0..ranges.len() - 1] {
+            assert!(range.len() >= SHARD_MIN_SIZE && range.len() <= SHARD_MAX_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_sharded_update_package() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let old_dir = td.path().join("old");
+        let package_dir = td.path().join("package");
+
+        let old_resources = noise(2 * 1024 * 1024, 42);
+
+        // A resource in the middle grows, moving everything after it.
+        let mut new_resources = old_resources.clone();
+        new_resources.splice(1024 * 1024..1024 * 1024 + 100, noise(1000, 7));
+
+        let old = layout(&[("packed-resources", &old_resources, false)])?;
+        old.write_to_path(&old_dir)?;
+        let new = layout(&[("packed-resources", &new_resources, false)])?;
+
+        let old_manifest = BuildManifest::from_layout(&old)?;
+        assert_eq!(
+            old_manifest.shards["packed-resources"]
+                .iter()
+                .map(|shard| shard.size)
+                .sum::<u64>(),
+            old_resources.len() as u64
+        );
+
+        let delta = write_update_package_from_layout(&old_manifest, &new, &package_dir)?;
+        assert_eq!(
+            delta.sharded["packed-resources"],
+            BuildManifest::from_layout(&new)?.shards["packed-resources"]
+        );
+
+        // Only shards around the change are shipped.
+        let shipped = std::fs::read_dir(package_dir.join(PACKAGE_SHARDS_DIRECTORY))?
+            .map(|entry| Ok(entry?.metadata()?.len()))
+            .collect::<Result<Vec<_>>>()?;
+        assert!(!shipped.is_empty());
+        assert!(shipped.iter().sum::<u64>() < new_resources.len() as u64 / 4);
+
+        let scratch_dir = td.path().join("scratch");
+        verify_update_package(&old_dir, &package_dir, &scratch_dir)?;
+        assert_eq!(
+            std::fs::read(scratch_dir.join("packed-resources"))?,
+            new_resources
+        );
+
+        // Applying to a build lacking the reused shards fails.
+        let other_dir = td.path().join("other");
+        layout(&[("packed-resources", b"other", false)])?.write_to_path(&other_dir)?;
+        assert!(
+            verify_update_package(&other_dir, &package_dir, &td.path().join("scratch2")).is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,263 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Record paths in build artifacts relative to a workspace root.
+
+Artifacts like `cargo_metadata.txt` reference files by path. Absolute paths
+tie artifacts to the machine and directory that produced them, so moving an
+artifacts directory, e.g. between CI stages, breaks whatever consumes it.
+
+`WorkspacePaths` declares a workspace root and whether artifacts record paths
+absolute, which is the default, or relative to the root. The root is stored
+once, in `workspace-root.txt` next to the artifacts, instead of in every
+path. Consumers re-anchor relative paths against their own root when loading
+artifacts.
+
+Artifacts must record paths consistently. Recording a path outside the root
+relative to it is an error, and content mixing absolute and relative paths
+is rejected when loaded.
+*/
+
+use {
+    anyhow::{anyhow, Result},
+    serde::{Deserialize, Serialize},
+    std::path::{Path, PathBuf},
+};
+
+/// Environment variable defining how artifacts record paths.
+///
+/// Values are `absolute` and `relative`.
+pub const ARTIFACT_PATHS_ENV: &str = "PYOXIDIZER_ARTIFACT_PATHS";
+
+/// Environment variable defining the workspace root.
+pub const WORKSPACE_ROOT_ENV: &str = "PYOXIDIZER_WORKSPACE_ROOT";
+
+/// File next to artifacts recording the root their relative paths were recorded against.
+pub const WORKSPACE_ROOT_FILENAME: &str = "workspace-root.txt";
+
+/// How artifacts record paths.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PathStyle {
+    /// Paths are absolute.
+    Absolute,
+
+    /// Paths are relative to the workspace root.
+    Relative,
+}
+
+impl Default for PathStyle {
+    fn default() -> Self {
+        PathStyle::Absolute
+    }
+}
+
+impl std::fmt::Display for PathStyle {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            PathStyle::Absolute => "absolute",
+            PathStyle::Relative => "relative",
+        })
+    }
+}
+
+impl PathStyle {
+    /// Parse an instance from its string form.
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "absolute" => Ok(PathStyle::Absolute),
+            "relative" => Ok(PathStyle::Relative),
+            _ => Err(anyhow!(
+                "invalid path style {}; expected absolute or relative",
+                value
+            )),
+        }
+    }
+
+    /// The style of a recorded path.
+    pub fn of(path: &Path) -> Self {
+        if path.is_absolute() {
+            PathStyle::Absolute
+        } else {
+            PathStyle::Relative
+        }
+    }
+}
+
+/// A workspace root and how artifacts record paths under it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WorkspacePaths {
+    /// The workspace root.
+    pub root: PathBuf,
+
+    /// How artifacts record paths.
+    pub style: PathStyle,
+}
+
+impl WorkspacePaths {
+    /// Construct an instance.
+    pub fn new(root: impl Into<PathBuf>, style: PathStyle) -> Self {
+        Self {
+            root: root.into(),
+            style,
+        }
+    }
+
+    /// Construct an instance from the environment.
+    ///
+    /// `default_root` is the root if `PYOXIDIZER_WORKSPACE_ROOT` isn't set.
+    /// Paths are absolute if `PYOXIDIZER_ARTIFACT_PATHS` isn't set.
+    pub fn from_env(default_root: &Path) -> Result<Self> {
+        let root = match std::env::var_os(WORKSPACE_ROOT_ENV) {
+            Some(root) => PathBuf::from(root),
+            None => default_root.to_path_buf(),
+        };
+
+        let style = match std::env::var(ARTIFACT_PATHS_ENV) {
+            Ok(value) => PathStyle::parse(&value)?,
+            Err(_) => PathStyle::default(),
+        };
+
+        Ok(Self::new(root, style))
+    }
+
+    /// Obtain the form of a path artifacts record.
+    ///
+    /// `entry` describes what references the path and is used in errors.
+    pub fn record(&self, entry: &str, path: &Path) -> Result<PathBuf> {
+        match self.style {
+            PathStyle::Absolute => Ok(path.to_path_buf()),
+            PathStyle::Relative => path
+                .strip_prefix(&self.root)
+                .map(|p| p.to_path_buf())
+                .map_err(|_| {
+                    anyhow!(
+                        "{} references {}, which is outside workspace root {}",
+                        entry,
+                        path.display(),
+                        self.root.display()
+                    )
+                }),
+        }
+    }
+
+    /// Re-anchor a recorded path against the root.
+    pub fn anchor(&self, path: &Path) -> PathBuf {
+        if path.is_absolute() {
+            path.to_path_buf()
+        } else {
+            self.root.join(path)
+        }
+    }
+}
+
+/// Determine how paths loaded from artifacts are recorded.
+///
+/// `entries` are pairs of an entry describing what references a path and
+/// the path. Returns `None` if there are no paths. Errors naming the first
+/// entry whose style differs from the entries before it.
+pub fn recorded_style<'a, I>(entries: I) -> Result<Option<PathStyle>>
+where
+    I: IntoIterator<Item = (&'a str, &'a Path)>,
+{
+    let mut style: Option<(PathStyle, &str)> = None;
+
+    for (entry, path) in entries {
+        let entry_style = PathStyle::of(path);
+
+        match style {
+            None => style = Some((entry_style, entry)),
+            Some((expected, first)) if expected != entry_style => {
+                return Err(anyhow!(
+                    "{} has {} path {} while earlier entry {} is {}; \
+                     paths must be all absolute or all relative",
+                    entry,
+                    entry_style,
+                    path.display(),
+                    first,
+                    expected
+                ));
+            }
+            Some(_) => {}
+        }
+    }
+
+    Ok(style.map(|(style, _)| style))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_and_anchor() -> Result<()> {
+        let root = std::env::temp_dir().join("workspace");
+        let lib = root.join("build").join("lib");
+        let outside = std::env::temp_dir().join("elsewhere");
+
+        let absolute = WorkspacePaths::new(&root, PathStyle::Absolute);
+        assert_eq!(absolute.record("lib", &lib)?, lib);
+        assert_eq!(absolute.record("outside", &outside)?, outside);
+
+        let relative = WorkspacePaths::new(&root, PathStyle::Relative);
+        let recorded = relative.record("lib", &lib)?;
+        assert_eq!(recorded, Path::new("build").join("lib"));
+
+        assert_eq!(
+            format!("{}", relative.record("outside", &outside).unwrap_err()),
+            format!(
+                "outside references {}, which is outside workspace root {}",
+                outside.display(),
+                root.display()
+            )
+        );
+
+        // Another machine re-anchors against its own root.
+        let consumer_root = std::env::temp_dir().join("other-machine");
+        let consumer = WorkspacePaths::new(&consumer_root, PathStyle::Relative);
+        assert_eq!(
+            consumer.anchor(&recorded),
+            consumer_root.join("build").join("lib")
+        );
+        assert_eq!(consumer.anchor(&lib), lib);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recorded_style() -> Result<()> {
+        let absolute = std::env::temp_dir().join("lib");
+        let relative = PathBuf::from("lib");
+
+        assert_eq!(recorded_style(vec![])?, None);
+        assert_eq!(
+            recorded_style(vec![("a", absolute.as_path()), ("b", absolute.as_path())])?,
+            Some(PathStyle::Absolute)
+        );
+        assert_eq!(
+            recorded_style(vec![("a", relative.as_path())])?,
+            Some(PathStyle::Relative)
+        );
+
+        let err =
+            recorded_style(vec![("a", absolute.as_path()), ("b", relative.as_path())]).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "b has relative path lib while earlier entry a is absolute; \
+             paths must be all absolute or all relative"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(PathStyle::parse("relative")?, PathStyle::Relative);
+        assert_eq!(PathStyle::parse("absolute")?, PathStyle::Absolute);
+        assert!(PathStyle::parse("Relative").is_err());
+
+        Ok(())
+    }
+}
@@ -15,6 +15,7 @@ use {
         required_str_arg, required_type_arg,
     },
     crate::app_packaging::glob::evaluate_glob,
+    crate::app_packaging::materialize::MaterializePolicy,
     crate::app_packaging::resource::{
         FileContent as RawFileContent, FileManifest as RawFileManifest,
     },
@@ -23,8 +24,8 @@ use {
     crate::py_packaging::resource::AddToFileManifest,
     anyhow::Result,
     itertools::Itertools,
-    python_packaging::resource::PythonModuleBytecodeFromSource,
-    slog::warn,
+    python_packaging::resource::{DataLocation, PythonModuleBytecodeFromSource},
+    slog::{info, warn},
     starlark::environment::Environment,
     starlark::values::{
         default_compare, RuntimeError, TypedValue, Value, ValueError, ValueResult,
@@ -36,7 +37,7 @@ use {
     },
     std::any::Any,
     std::cmp::Ordering,
-    std::collections::{HashMap, HashSet},
+    std::collections::{BTreeMap, HashMap, HashSet},
     std::convert::TryFrom,
     std::ops::Deref,
     std::path::Path,
@@ -97,19 +98,25 @@ impl FileManifest {
         let build = build_python_executable(logger, &exe.name(), exe, target, opt_level, release)?;
 
         let content = RawFileContent {
-            data: build.exe_data.clone(),
+            data: DataLocation::Memory(build.exe_data.clone()),
             executable: true,
         };
 
         let path = Path::new(&prefix).join(build.exe_name);
-        self.manifest.add_file(&path, &content)?;
+        self.manifest
+            .add_file_from(&path, &content, &format!("executable {}", exe.name()))?;
 
         // Add any additional files that the exe builder requires.
         let mut extra_files = RawFileManifest::default();
 
         for (path, content) in build.binary_data.extra_files.entries() {
             warn!(logger, "adding extra file {} to {}", path.display(), prefix);
-            extra_files.add_file(&Path::new(prefix).join(path), &content)?;
+            let dest_path = Path::new(prefix).join(path);
+
+            match build.binary_data.extra_files.provenance(path) {
+                Some(provenance) => extra_files.add_file_from(&dest_path, &content, provenance)?,
+                None => extra_files.add_file(&dest_path, &content)?,
+            }
         }
 
         self.manifest.add_manifest(&extra_files)?;
@@ -125,7 +132,23 @@ impl BuildTarget for FileManifest {
             "installing files to {}",
             context.output_path.display()
         );
-        self.manifest.replace_path(&context.output_path)?;
+        let strategies = self
+            .manifest
+            .replace_path_with_policy(&context.output_path, &MaterializePolicy::from_env())?;
+
+        let mut counts = BTreeMap::new();
+        for (path, strategy) in &strategies {
+            info!(&context.logger, "{} {}", strategy, path.display());
+            *counts.entry(*strategy).or_insert(0) += 1;
+        }
+        warn!(
+            &context.logger,
+            "materialized files: {}",
+            counts
+                .iter()
+                .map(|(strategy, count)| format!("{} {}", count, strategy))
+                .join(", ")
+        );
 
         // If there exists a single executable, make it the run target.
         // TODO support defining default run target in data structure.
@@ -525,7 +548,7 @@ mod tests {
         super::super::testutil::*,
         super::*,
         python_packaging::resource::{
-            DataLocation, PythonModuleSource, PythonPackageResource as RawPackageResource,
+            PythonModuleSource, PythonPackageResource as RawPackageResource,
         },
         std::path::PathBuf,
     };
@@ -572,7 +595,7 @@ mod tests {
             assert_eq!(
                 c,
                 &RawFileContent {
-                    data: vec![],
+                    data: DataLocation::Memory(vec![]),
                     executable: false,
                 }
             );
@@ -582,7 +605,7 @@ mod tests {
             assert_eq!(
                 c,
                 &RawFileContent {
-                    data: vec![],
+                    data: DataLocation::Memory(vec![]),
                     executable: false,
                 }
             );
@@ -622,7 +645,7 @@ mod tests {
             assert_eq!(
                 c,
                 &RawFileContent {
-                    data: vec![],
+                    data: DataLocation::Memory(vec![]),
                     executable: false,
                 }
             );
@@ -9,6 +9,8 @@ use {
     super::util::{
         optional_dict_arg, optional_str_arg, optional_type_arg, required_bool_arg, required_str_arg,
     },
+    crate::py_packaging::build_directories::BuildDirectories,
+    crate::py_packaging::bytecode_cache::BytecodeCache,
     crate::py_packaging::config::EmbeddedPythonConfig,
     crate::py_packaging::distribution::BinaryLibpythonLinkMode,
     crate::py_packaging::distribution::{
@@ -32,7 +34,7 @@ use {
     },
     std::any::Any,
     std::cmp::Ordering,
-    std::collections::HashMap,
+    std::collections::{BTreeMap, HashMap},
     std::convert::TryFrom,
     std::path::{Path, PathBuf},
     std::sync::Arc,
@@ -94,7 +96,7 @@ impl PythonDistribution {
 
         if let Some(dist) = &self.distribution {
             if self.compiler.is_none() {
-                self.compiler = Some(dist.create_bytecode_compiler()?);
+                self.compiler = Some(dist.create_bytecode_compiler(None)?);
             }
         }
 
@@ -250,6 +252,7 @@ impl PythonDistribution {
     ///     include_sources=true,
     ///     include_resources=true,
     ///     include_test=false,
+    ///     extension_module_variant_pins=None,
     /// )
     #[allow(
         clippy::ptr_arg,
@@ -268,6 +271,7 @@ impl PythonDistribution {
         include_sources: &Value,
         include_resources: &Value,
         include_test: &Value,
+        extension_module_variant_pins: &Value,
     ) -> ValueResult {
         let name = required_str_arg("name", &name)?;
         let resources_policy = required_str_arg("resources_policy", &resources_policy)?;
@@ -283,12 +287,19 @@ impl PythonDistribution {
         let include_sources = required_bool_arg("include_sources", &include_sources)?;
         let include_resources = required_bool_arg("include_resources", &include_resources)?;
         let include_test = required_bool_arg("include_test", &include_test)?;
+        optional_dict_arg(
+            "extension_module_variant_pins",
+            "string",
+            "string",
+            &extension_module_variant_pins,
+        )?;
 
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
         let (host_triple, target_triple) = context.downcast_apply(|x: &EnvironmentContext| {
             (x.build_host_triple.clone(), x.build_target_triple.clone())
         });
+        let build_path = context.downcast_apply(|x: &EnvironmentContext| x.build_path.clone());
 
         let resources_policy =
             PythonResourcesPolicy::try_from(resources_policy.as_str()).map_err(|e| {
@@ -328,6 +339,21 @@ impl PythonDistribution {
                 _ => panic!("type should have been validated above"),
             };
 
+        let extension_module_variant_pins = match extension_module_variant_pins.get_type() {
+            "NoneType" => BTreeMap::new(),
+            "dict" => {
+                let mut m = BTreeMap::new();
+
+                for k in extension_module_variant_pins.into_iter()? {
+                    let v = extension_module_variant_pins.at(k.clone())?.to_string();
+                    m.insert(k.to_string(), v);
+                }
+
+                m
+            }
+            _ => panic!("type should have been validated above"),
+        };
+
         self.ensure_distribution_resolved(&logger).map_err(|e| {
             RuntimeError {
                 code: "PYOXIDIZER_BUILD",
@@ -358,6 +384,10 @@ impl PythonDistribution {
             }
         }
 
+        for (ext, variant) in extension_module_variant_pins {
+            policy.pin_extension_module_variant(&ext, &variant);
+        }
+
         let config = if config.get_type() == "NoneType" {
             let v = env
                 .get("PythonInterpreterConfig")
@@ -368,27 +398,38 @@ impl PythonDistribution {
             config.downcast_apply(|c: &EmbeddedPythonConfig| c.clone())
         };
 
-        Ok(Value::new(PythonExecutable {
-            exe: dist
-                .as_python_executable_builder(
-                    &logger,
-                    &host_triple,
-                    &target_triple,
-                    &name,
-                    // TODO make configurable
-                    BinaryLibpythonLinkMode::Default,
-                    &policy,
-                    &config,
-                )
-                .map_err(|e| {
-                    RuntimeError {
-                        code: "PYOXIDIZER_BUILD",
-                        message: e.to_string(),
-                        label: "to_python_executable()".to_string(),
-                    }
-                    .into()
-                })?,
-        }))
+        let mut exe = dist
+            .as_python_executable_builder(
+                &logger,
+                &host_triple,
+                &target_triple,
+                &name,
+                // TODO make configurable
+                BinaryLibpythonLinkMode::Default,
+                &policy,
+                &config,
+                &BuildDirectories::default(),
+            )
+            .map_err(|e| {
+                RuntimeError {
+                    code: "PYOXIDIZER_BUILD",
+                    message: e.to_string(),
+                    label: "to_python_executable()".to_string(),
+                }
+                .into()
+            })?;
+
+        let bytecode_cache = BytecodeCache::from_env(&build_path.join("cache")).map_err(|e| {
+            RuntimeError {
+                code: "PYOXIDIZER_BUILD",
+                message: e.to_string(),
+                label: "to_python_executable()".to_string(),
+            }
+            .into()
+        })?;
+        exe.set_bytecode_cache(bytecode_cache);
+
+        Ok(Value::new(PythonExecutable { exe }))
     }
 
     /// PythonDistribution.extension_modules(filter="all", preferred_variants=None)
@@ -538,7 +579,8 @@ starlark_module! { python_distribution_module =>
         preferred_extension_module_variants=None,
         include_sources=true,
         include_resources=false,
-        include_test=false
+        include_test=false,
+        extension_module_variant_pins=None
     ) {
         this.downcast_apply_mut(|dist: &mut PythonDistribution| {
             dist.to_python_executable_starlark(
@@ -552,6 +594,7 @@ starlark_module! { python_distribution_module =>
                 &include_sources,
                 &include_resources,
                 &include_test,
+                &extension_module_variant_pins,
             )
         })
     }
@@ -5,6 +5,8 @@
 use {
     super::target::{BuildContext, BuildTarget, ResolvedTarget, RunMode},
     crate::py_packaging::binary::PythonBinaryBuilder,
+    crate::py_packaging::config::validate_release_config,
+    crate::py_packaging::workspace_paths::WorkspacePaths,
     anyhow::Result,
     slog::warn,
     starlark::environment::Environment,
@@ -12,7 +14,7 @@ use {
     starlark::{any, immutable, not_supported},
     std::any::Any,
     std::cmp::Ordering,
-    std::collections::HashMap,
+    std::collections::{BTreeSet, HashMap},
 };
 
 pub struct PythonEmbeddedResources {
@@ -57,11 +59,20 @@ impl BuildTarget for PythonEmbeddedResources {
             context.output_path.display()
         );
 
-        let embedded = self
-            .exe
-            .to_embedded_python_context(&context.logger, &context.opt_level)?;
+        let embedded = self.exe.to_embedded_python_context(
+            &context.logger,
+            &context.opt_level,
+            &BTreeSet::new(),
+        )?;
+
+        for problem in validate_release_config(&embedded.config, context.release) {
+            warn!(&context.logger, "{}", problem);
+        }
 
-        embedded.write_files(&context.output_path)?;
+        embedded.write_files(
+            &context.output_path,
+            &WorkspacePaths::from_env(&context.output_path)?,
+        )?;
 
         Ok(ResolvedTarget {
             run_mode: RunMode::None,
@@ -7,7 +7,7 @@ use {
     super::python_embedded_resources::PythonEmbeddedResources,
     super::python_resource::{
         python_resource_to_value, PythonExtensionModule, PythonPackageDistributionResource,
-        PythonPackageResource, PythonSourceModule,
+        PythonPackageResource, PythonPrecompiledModule, PythonSourceModule,
     },
     super::target::{BuildContext, BuildTarget, ResolvedTarget, RunMode},
     super::util::{
@@ -17,6 +17,7 @@ use {
     crate::project_building::build_python_executable,
     crate::py_packaging::binary::PythonBinaryBuilder,
     anyhow::{Context, Result},
+    python_packaging::patch::PackagePatch,
     python_packaging::resource::{
         BytecodeOptimizationLevel, DataLocation, PythonModuleBytecodeFromSource,
         PythonModuleSource as RawPythonModuleSource,
@@ -177,15 +178,17 @@ impl PythonExecutable {
         ))
     }
 
-    /// PythonExecutable.read_package_root(path, packages)
+    /// PythonExecutable.read_package_root(path, packages, bytecode_only=false)
     pub fn starlark_read_package_root(
         &self,
         env: &Environment,
         path: &Value,
         packages: &Value,
+        bytecode_only: &Value,
     ) -> ValueResult {
         let path = required_str_arg("path", &path)?;
         required_list_arg("packages", "string", &packages)?;
+        let bytecode_only = required_bool_arg("bytecode_only", &bytecode_only)?;
 
         let packages = packages
             .into_iter()?
@@ -195,17 +198,21 @@ impl PythonExecutable {
         let context = env.get("CONTEXT").expect("CONTEXT not defined");
         let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
 
-        let resources = self
-            .exe
-            .read_package_root(&logger, Path::new(&path), &packages)
-            .map_err(|e| {
-                RuntimeError {
-                    code: "PACKAGE_ROOT_ERROR",
-                    message: format!("could not find resources: {}", e),
-                    label: "read_package_root()".to_string(),
-                }
-                .into()
-            })?;
+        let resources = if bytecode_only {
+            self.exe
+                .read_bytecode_package_root(&logger, Path::new(&path), &packages)
+        } else {
+            self.exe
+                .read_package_root(&logger, Path::new(&path), &packages)
+        }
+        .map_err(|e| {
+            RuntimeError {
+                code: "PACKAGE_ROOT_ERROR",
+                message: format!("could not find resources: {}", e),
+                label: "read_package_root()".to_string(),
+            }
+            .into()
+        })?;
 
         Ok(Value::from(
             resources
@@ -573,6 +580,33 @@ impl PythonExecutable {
         Ok(Value::new(None))
     }
 
+    /// Add a `PythonPrecompiledModule` to a location.
+    fn add_precompiled_module(
+        &mut self,
+        env: &Environment,
+        module: &Value,
+        location: Option<ConcreteResourceLocation>,
+        label: &str,
+    ) -> ValueResult {
+        let context = env.get("CONTEXT").expect("CONTEXT not set");
+        let logger = context.downcast_apply(|x: &EnvironmentContext| x.logger.clone());
+
+        let m = module.downcast_apply(|m: &PythonPrecompiledModule| m.module.clone());
+        info!(&logger, "adding precompiled module {}", m.name);
+        self.exe
+            .add_python_module_bytecode(&m, location)
+            .map_err(|e| {
+                RuntimeError {
+                    code: "PYOXIDIZER_BUILD",
+                    message: e.to_string(),
+                    label: label.to_string(),
+                }
+                .into()
+            })?;
+
+        Ok(Value::new(None))
+    }
+
     /// PythonExecutable.add_in_memory_package_resource(resource)
     pub fn starlark_add_in_memory_package_resource(
         &mut self,
@@ -872,6 +906,12 @@ impl PythonExecutable {
             "PythonBytecodeModule" => {
                 self.starlark_add_in_memory_module_bytecode(env, resource, optimize_level)
             }
+            "PythonPrecompiledModule" => self.add_precompiled_module(
+                env,
+                resource,
+                Some(ConcreteResourceLocation::InMemory),
+                "add_in_memory_python_resource",
+            ),
             "PythonPackageResource" => self.starlark_add_in_memory_package_resource(env, resource),
             "PythonPackageDistributionResource" => {
                 self.starlark_add_package_distribution_resource(env, resource)
@@ -923,6 +963,14 @@ impl PythonExecutable {
                 resource,
                 optimize_level,
             ),
+            "PythonPrecompiledModule" => self.add_precompiled_module(
+                env,
+                resource,
+                Some(ConcreteResourceLocation::RelativePath(required_str_arg(
+                    "prefix", &prefix,
+                )?)),
+                "add_filesystem_relative_python_resource",
+            ),
             "PythonPackageResource" => {
                 self.starlark_add_filesystem_relative_package_resource(env, prefix, resource)
             }
@@ -967,6 +1015,9 @@ impl PythonExecutable {
             "PythonBytecodeModule" => {
                 self.starlark_add_module_bytecode(env, resource, optimize_level)
             }
+            "PythonPrecompiledModule" => {
+                self.add_precompiled_module(env, resource, None, "add_python_resource")
+            }
             "PythonPackageResource" => self.starlark_add_package_resource(env, resource),
             "PythonPackageDistributionResource" => {
                 self.starlark_add_package_distribution_resource(env, resource)
@@ -1062,6 +1113,39 @@ impl PythonExecutable {
         Ok(Value::new(None))
     }
 
+    /// PythonExecutable.add_package_patch(distribution, path, versions="")
+    pub fn starlark_add_package_patch(
+        &mut self,
+        distribution: &Value,
+        path: &Value,
+        versions: &Value,
+    ) -> ValueResult {
+        let distribution = required_str_arg("distribution", &distribution)?;
+        let path = required_str_arg("path", &path)?;
+        let versions = required_str_arg("versions", &versions)?;
+
+        let path = Path::new(&path);
+        let name = path
+            .file_name()
+            .map(|x| x.to_string_lossy().to_string())
+            .unwrap_or_else(|| path.display().to_string());
+
+        std::fs::read_to_string(path)
+            .with_context(|| format!("reading {}", path.display()))
+            .and_then(|diff| PackagePatch::new(&name, &distribution, &versions, &diff))
+            .and_then(|patch| self.exe.add_package_patch(patch))
+            .map_err(|e| {
+                RuntimeError {
+                    code: "PACKAGE_PATCH_ERROR",
+                    message: format!("error adding package patch: {:#}", e),
+                    label: "add_package_patch()".to_string(),
+                }
+                .into()
+            })?;
+
+        Ok(Value::new(None))
+    }
+
     /// PythonExecutable.to_embedded_resources()
     pub fn starlark_to_embedded_resources(&self) -> ValueResult {
         Ok(Value::new(PythonEmbeddedResources {
@@ -1135,10 +1219,11 @@ starlark_module! { python_executable_env =>
         env env,
         this,
         path,
-        packages
+        packages,
+        bytecode_only=false
     ) {
         this.downcast_apply(|exe: &PythonExecutable| {
-            exe.starlark_read_package_root(&env, &path, &packages)
+            exe.starlark_read_package_root(&env, &path, &packages, &bytecode_only)
         })
     }
 
@@ -1411,6 +1496,13 @@ starlark_module! { python_executable_env =>
         })
     }
 
+    #[allow(clippy::ptr_arg)]
+    PythonExecutable.add_package_patch(this, distribution, path, versions="") {
+        this.downcast_apply_mut(|exe: &mut PythonExecutable| {
+            exe.starlark_add_package_patch(&distribution, &path, &versions)
+        })
+    }
+
     #[allow(clippy::ptr_arg)]
     PythonExecutable.to_embedded_resources(this) {
         this.downcast_apply(|exe: &PythonExecutable| {
@@ -4,9 +4,13 @@
 
 use crate::py_packaging::config::RunMode;
 use {
-    super::util::{optional_list_arg, optional_str_arg, required_bool_arg, required_type_arg},
+    super::util::{
+        optional_list_arg, optional_str_arg, optional_type_arg, required_bool_arg,
+        required_str_arg, required_type_arg,
+    },
     crate::py_packaging::config::{
-        default_raw_allocator, EmbeddedPythonConfig, RawAllocator, TerminfoResolution,
+        default_raw_allocator, EmbeddedPythonConfig, HashRandomization, RawAllocator,
+        SubinterpreterConfig, TerminfoResolution,
     },
     starlark::environment::Environment,
     starlark::values::{
@@ -20,6 +24,7 @@ use {
     std::any::Any,
     std::cmp::Ordering,
     std::collections::HashMap,
+    std::convert::TryFrom,
 };
 
 impl TypedValue for EmbeddedPythonConfig {
@@ -67,16 +72,22 @@ impl EmbeddedPythonConfig {
         legacy_windows_stdio: &Value,
         optimize_level: &Value,
         parser_debug: &Value,
+        preload_modules: &Value,
         stdio_encoding: &Value,
         unbuffered_stdio: &Value,
         filesystem_importer: &Value,
+        hash_randomization: &Value,
+        hash_seed: &Value,
         quiet: &Value,
         run_eval: &Value,
         run_file: &Value,
         run_module: &Value,
         run_noop: &Value,
         run_repl: &Value,
+        shared_library_extraction_dir: &Value,
         site_import: &Value,
+        subinterpreters: &Value,
+        subinterpreter_safe_extensions: &Value,
         sys_frozen: &Value,
         sys_meipass: &Value,
         sys_paths: &Value,
@@ -88,6 +99,10 @@ impl EmbeddedPythonConfig {
         verbose: &Value,
         write_bytecode: &Value,
         write_modules_directory_env: &Value,
+        allowed_python_env_collisions: &Value,
+        site_packages_shim: &Value,
+        site_packages_shim_packages: &Value,
+        import_telemetry_path: &Value,
     ) -> ValueResult {
         required_type_arg("bytes_warning", "int", &bytes_warning)?;
         let ignore_environment = required_bool_arg("ignore_environment", &ignore_environment)?;
@@ -100,20 +115,33 @@ impl EmbeddedPythonConfig {
             required_bool_arg("legacy_windows_stdio", &legacy_windows_stdio)?;
         required_type_arg("optimize_level", "int", &optimize_level)?;
         let parser_debug = required_bool_arg("parser_debug", &parser_debug)?;
+        optional_list_arg("preload_modules", "string", &preload_modules)?;
         let stdio_encoding = optional_str_arg("stdio_encoding", &stdio_encoding)?;
         let unbuffered_stdio = required_bool_arg("unbuffered_stdio", &unbuffered_stdio)?;
         let filesystem_importer = required_bool_arg("filesystem_importer", &filesystem_importer)?;
+        let hash_randomization = required_str_arg("hash_randomization", &hash_randomization)?;
+        optional_type_arg("hash_seed", "int", &hash_seed)?;
         let quiet = required_bool_arg("quiet", &quiet)?;
         let run_eval = optional_str_arg("run_eval", &run_eval)?;
         let run_file = optional_str_arg("run_file", &run_file)?;
         let run_module = optional_str_arg("run_module", &run_module)?;
         let run_noop = required_bool_arg("run_noop", &run_noop)?;
         let run_repl = required_bool_arg("run_repl", &run_repl)?;
+        let shared_library_extraction_dir = optional_str_arg(
+            "shared_library_extraction_dir",
+            &shared_library_extraction_dir,
+        )?;
         let sys_frozen = required_bool_arg("sys_frozen", &sys_frozen)?;
         let sys_meipass = required_bool_arg("sys_meipass", &sys_meipass)?;
         optional_list_arg("sys_paths", "string", &sys_paths)?;
         let raw_allocator = optional_str_arg("raw_allocator", &raw_allocator)?;
         let site_import = required_bool_arg("site_importer", &site_import)?;
+        let subinterpreters = required_bool_arg("subinterpreters", &subinterpreters)?;
+        optional_list_arg(
+            "subinterpreter_safe_extensions",
+            "string",
+            &subinterpreter_safe_extensions,
+        )?;
         let terminfo_resolution = optional_str_arg("terminfo_resolution", &terminfo_resolution)?;
         let terminfo_dirs = optional_str_arg("terminfo_dirs", &terminfo_dirs)?;
         let use_hash_seed = required_bool_arg("use_hash_seed", &use_hash_seed)?;
@@ -213,6 +241,35 @@ impl EmbeddedPythonConfig {
             None => TerminfoResolution::None,
         };
 
+        let hash_randomization = match hash_randomization.as_ref() {
+            "random" => HashRandomization::Random,
+            "fixed" => HashRandomization::Fixed,
+            "disabled" => HashRandomization::Disabled,
+            _ => {
+                return Err(RuntimeError {
+                    code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                    message: "hash_randomization must be 'random', 'fixed', or 'disabled'"
+                        .to_string(),
+                    label: "hash_randomization must be 'random', 'fixed', or 'disabled'"
+                        .to_string(),
+                }
+                .into());
+            }
+        };
+
+        let hash_seed = match hash_seed.get_type() {
+            "int" => {
+                Some(
+                    u32::try_from(hash_seed.to_int().unwrap()).map_err(|_| RuntimeError {
+                        code: INCORRECT_PARAMETER_TYPE_ERROR_CODE,
+                        message: "hash_seed must be between 0 and 4294967295".to_string(),
+                        label: "hash_seed must be between 0 and 4294967295".to_string(),
+                    })?,
+                )
+            }
+            _ => None,
+        };
+
         let sys_paths = match sys_paths.get_type() {
             "list" => sys_paths
                 .into_iter()
@@ -224,7 +281,58 @@ impl EmbeddedPythonConfig {
 
         let filesystem_importer = filesystem_importer || !sys_paths.is_empty();
 
+        let preload_modules = match preload_modules.get_type() {
+            "list" => preload_modules
+                .into_iter()
+                .unwrap()
+                .map(|x| x.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        optional_list_arg(
+            "allowed_python_env_collisions",
+            "string",
+            &allowed_python_env_collisions,
+        )?;
+        let allowed_python_env_collisions = match allowed_python_env_collisions.get_type() {
+            "list" => allowed_python_env_collisions
+                .into_iter()
+                .unwrap()
+                .map(|x| x.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let subinterpreter_safe_extensions = match subinterpreter_safe_extensions.get_type() {
+            "list" => subinterpreter_safe_extensions
+                .into_iter()
+                .unwrap()
+                .map(|x| x.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let site_packages_shim = optional_str_arg("site_packages_shim", &site_packages_shim)?;
+        optional_list_arg(
+            "site_packages_shim_packages",
+            "string",
+            &site_packages_shim_packages,
+        )?;
+        let site_packages_shim_packages = match site_packages_shim_packages.get_type() {
+            "list" => site_packages_shim_packages
+                .into_iter()
+                .unwrap()
+                .map(|x| x.to_string())
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let import_telemetry_path =
+            optional_str_arg("import_telemetry_path", &import_telemetry_path)?;
+
         Ok(Value::new(EmbeddedPythonConfig {
+            allowed_python_env_collisions,
             bytes_warning: bytes_warning.to_int().unwrap() as i32,
             ignore_environment,
             inspect,
@@ -234,12 +342,25 @@ impl EmbeddedPythonConfig {
             legacy_windows_stdio,
             optimize_level: optimize_level.to_int().unwrap(),
             parser_debug,
+            preload_modules,
             quiet,
             stdio_encoding_name,
             stdio_encoding_errors,
             unbuffered_stdio,
             filesystem_importer,
+            hash_randomization,
+            hash_seed,
+            import_telemetry_path,
+            shared_library_extraction_dir,
             site_import,
+            site_packages_shim,
+            site_packages_shim_packages,
+            subinterpreters: if subinterpreters {
+                Some(SubinterpreterConfig::default())
+            } else {
+                None
+            },
+            subinterpreter_safe_extensions,
             sys_frozen,
             sys_meipass,
             sys_paths,
@@ -268,16 +389,22 @@ starlark_module! { embedded_python_config_module =>
         legacy_windows_stdio=false,
         optimize_level=0,
         parser_debug=false,
+        preload_modules=None,
         stdio_encoding=None,
         unbuffered_stdio=false,
         filesystem_importer=false,
+        hash_randomization="random",
+        hash_seed=None,
         quiet=false,
         run_eval=None,
         run_file=None,
         run_module=None,
         run_noop=false,
         run_repl=false,
+        shared_library_extraction_dir="$CACHE_DIR/pyoxidizer",
         site_import=false,
+        subinterpreters=false,
+        subinterpreter_safe_extensions=None,
         sys_frozen=false,
         sys_meipass=false,
         sys_paths=None,
@@ -288,7 +415,11 @@ starlark_module! { embedded_python_config_module =>
         user_site_directory=false,
         verbose=0,
         write_bytecode=false,
-        write_modules_directory_env=None
+        write_modules_directory_env=None,
+        allowed_python_env_collisions=None,
+        site_packages_shim=None,
+        site_packages_shim_packages=None,
+        import_telemetry_path=None
     ) {
         EmbeddedPythonConfig::starlark_new(
             &env,
@@ -301,16 +432,22 @@ starlark_module! { embedded_python_config_module =>
             &legacy_windows_stdio,
             &optimize_level,
             &parser_debug,
+            &preload_modules,
             &stdio_encoding,
             &unbuffered_stdio,
             &filesystem_importer,
+            &hash_randomization,
+            &hash_seed,
             &quiet,
             &run_eval,
             &run_file,
             &run_module,
             &run_noop,
             &run_repl,
+            &shared_library_extraction_dir,
             &site_import,
+            &subinterpreters,
+            &subinterpreter_safe_extensions,
             &sys_frozen,
             &sys_meipass,
             &sys_paths,
@@ -321,7 +458,11 @@ starlark_module! { embedded_python_config_module =>
             &user_site_directory,
             &verbose,
             &write_bytecode,
-            &write_modules_directory_env
+            &write_modules_directory_env,
+            &allowed_python_env_collisions,
+            &site_packages_shim,
+            &site_packages_shim_packages,
+            &import_telemetry_path
         )
     }
 }
@@ -336,6 +477,7 @@ mod tests {
         assert_eq!(c.get_type(), "PythonInterpreterConfig");
 
         let wanted = crate::py_packaging::config::EmbeddedPythonConfig {
+            allowed_python_env_collisions: Vec::new(),
             bytes_warning: 0,
             ignore_environment: true,
             inspect: false,
@@ -345,6 +487,7 @@ mod tests {
             legacy_windows_stdio: false,
             optimize_level: 0,
             parser_debug: false,
+            preload_modules: Vec::new(),
             quiet: false,
             use_hash_seed: false,
             verbose: 0,
@@ -352,7 +495,15 @@ mod tests {
             stdio_encoding_errors: None,
             unbuffered_stdio: false,
             filesystem_importer: false,
+            hash_randomization: HashRandomization::Random,
+            hash_seed: None,
+            import_telemetry_path: None,
+            shared_library_extraction_dir: Some("$CACHE_DIR/pyoxidizer".to_string()),
             site_import: false,
+            site_packages_shim: None,
+            site_packages_shim_packages: Vec::new(),
+            subinterpreters: None,
+            subinterpreter_safe_extensions: Vec::new(),
             sys_frozen: false,
             sys_meipass: false,
             sys_paths: Vec::new(),
@@ -389,6 +540,93 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_preload_modules() {
+        let c = starlark_ok("PythonInterpreterConfig(preload_modules=['codecs_ext', 'plugins'])");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.preload_modules, ["codecs_ext", "plugins"]);
+        });
+    }
+
+    #[test]
+    fn test_subinterpreters() {
+        let c = starlark_ok(
+            "PythonInterpreterConfig(subinterpreters=True, subinterpreter_safe_extensions=['_foo'])",
+        );
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.subinterpreters, Some(SubinterpreterConfig::default()));
+            assert_eq!(x.subinterpreter_safe_extensions, ["_foo"]);
+        });
+    }
+
+    #[test]
+    fn test_allowed_python_env_collisions() {
+        let c =
+            starlark_ok("PythonInterpreterConfig(allowed_python_env_collisions=['PYTHONPATH'])");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.allowed_python_env_collisions, ["PYTHONPATH"]);
+        });
+    }
+
+    #[test]
+    fn test_site_packages_shim() {
+        let c = starlark_ok(
+            "PythonInterpreterConfig(site_packages_shim='site-packages', site_packages_shim_packages=['certifi'])",
+        );
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.site_packages_shim, Some("site-packages".to_string()));
+            assert_eq!(x.site_packages_shim_packages, ["certifi"]);
+        });
+    }
+
+    #[test]
+    fn test_import_telemetry_path() {
+        let c = starlark_ok("PythonInterpreterConfig(import_telemetry_path='/tmp/imports-{pid}')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(
+                x.import_telemetry_path,
+                Some("/tmp/imports-{pid}".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_shared_library_extraction_dir() {
+        let c = starlark_ok("PythonInterpreterConfig(shared_library_extraction_dir='$ORIGIN/lib')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(
+                x.shared_library_extraction_dir,
+                Some("$ORIGIN/lib".to_string())
+            );
+        });
+
+        let c = starlark_ok("PythonInterpreterConfig(shared_library_extraction_dir=None)");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.shared_library_extraction_dir, None);
+        });
+    }
+
+    #[test]
+    fn test_hash_randomization() {
+        let c = starlark_ok("PythonInterpreterConfig(hash_randomization='fixed', hash_seed=42)");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.hash_randomization, HashRandomization::Fixed);
+            assert_eq!(x.hash_seed, Some(42));
+        });
+
+        let c = starlark_ok("PythonInterpreterConfig(hash_randomization='disabled')");
+        c.downcast_apply(|x: &EmbeddedPythonConfig| {
+            assert_eq!(x.hash_randomization, HashRandomization::Disabled);
+            assert_eq!(x.hash_seed, None);
+        });
+
+        let err = starlark_nok("PythonInterpreterConfig(hash_randomization='foo')");
+        assert!(err.message.starts_with("hash_randomization must be"));
+
+        let err = starlark_nok("PythonInterpreterConfig(hash_seed=-1)");
+        assert!(err.message.starts_with("hash_seed must be between"));
+    }
+
     #[test]
     fn test_stdio_encoding() {
         let c = starlark_ok("PythonInterpreterConfig(stdio_encoding='foo:strict')");
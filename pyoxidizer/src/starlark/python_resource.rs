@@ -5,7 +5,8 @@
 use {
     python_packaging::resource::{
         BytecodeOptimizationLevel, PythonExtensionModule as RawPythonExtensionModule,
-        PythonModuleBytecodeFromSource, PythonModuleSource as RawSourceModule,
+        PythonModuleBytecode as RawBytecodeModule, PythonModuleBytecodeFromSource,
+        PythonModuleSource as RawSourceModule,
         PythonPackageDistributionResource as RawDistributionResource,
         PythonPackageResource as RawPackageResource, PythonResource,
     },
@@ -134,6 +135,7 @@ impl TypedValue for PythonSourceModule {
                 Value::new(source)
             }
             "is_package" => Value::new(self.module.is_package),
+            "is_test" => Value::new(self.module.is_test),
             "location" => self.location.clone().into(),
             attr => {
                 return Err(ValueError::OperationNotSupported {
@@ -152,6 +154,7 @@ impl TypedValue for PythonSourceModule {
             "name" => true,
             "source" => true,
             "is_package" => true,
+            "is_test" => true,
             "location" => true,
             _ => false,
         })
@@ -243,6 +246,64 @@ impl TypedValue for PythonBytecodeModule {
     }
 }
 
+/// Bytecode of a module shipped without source.
+#[derive(Debug, Clone)]
+pub struct PythonPrecompiledModule {
+    pub module: RawBytecodeModule,
+}
+
+impl TypedValue for PythonPrecompiledModule {
+    immutable!();
+    any!();
+    not_supported!(
+        binop, dir_attr, function, get_hash, indexable, iterable, sequence, set_attr, to_int
+    );
+
+    fn to_str(&self) -> String {
+        format!("PythonPrecompiledModule<name={}>", self.module.name)
+    }
+
+    fn to_repr(&self) -> String {
+        self.to_str()
+    }
+
+    fn get_type(&self) -> &'static str {
+        "PythonPrecompiledModule"
+    }
+
+    fn to_bool(&self) -> bool {
+        true
+    }
+
+    fn compare(&self, other: &dyn TypedValue, _recursion: u32) -> Result<Ordering, ValueError> {
+        default_compare(self, other)
+    }
+
+    fn get_attr(&self, attribute: &str) -> ValueResult {
+        let v = match attribute {
+            "name" => Value::new(self.module.name.clone()),
+            "is_package" => Value::new(self.module.is_package),
+            attr => {
+                return Err(ValueError::OperationNotSupported {
+                    op: format!(".{}", attr),
+                    left: "PythonPrecompiledModule".to_string(),
+                    right: None,
+                })
+            }
+        };
+
+        Ok(v)
+    }
+
+    fn has_attr(&self, attribute: &str) -> Result<bool, ValueError> {
+        Ok(match attribute {
+            "name" => true,
+            "is_package" => true,
+            _ => false,
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct PythonPackageResource {
     pub data: RawPackageResource,
@@ -282,6 +343,7 @@ impl TypedValue for PythonPackageResource {
         let v = match attribute {
             "package" => Value::new(self.data.leaf_package.clone()),
             "name" => Value::new(self.data.relative_name.clone()),
+            "is_test" => Value::new(self.data.is_test),
             // TODO expose raw data
             attr => {
                 return Err(ValueError::OperationNotSupported {
@@ -299,6 +361,7 @@ impl TypedValue for PythonPackageResource {
         Ok(match attribute {
             "package" => true,
             "name" => true,
+            "is_test" => true,
             // TODO expose raw data
             _ => false,
         })
@@ -430,8 +493,8 @@ pub fn python_resource_to_value(resource: &PythonResource) -> Value {
             Value::new(PythonBytecodeModule { module: m.clone() })
         }
 
-        PythonResource::ModuleBytecode { .. } => {
-            panic!("not yet implemented");
+        PythonResource::ModuleBytecode(m) => {
+            Value::new(PythonPrecompiledModule { module: m.clone() })
         }
 
         PythonResource::Resource(data) => Value::new(PythonPackageResource { data: data.clone() }),
@@ -485,6 +548,9 @@ mod tests {
         assert!(m.has_attr("is_package").unwrap());
         assert_eq!(m.get_attr("is_package").unwrap().to_bool(), false);
 
+        assert!(m.has_attr("is_test").unwrap());
+        assert_eq!(m.get_attr("is_test").unwrap().to_bool(), false);
+
         assert!(m.has_attr("location").unwrap());
         assert_eq!(m.get_attr("location").unwrap().to_str(), "default");
 
@@ -5,13 +5,17 @@
 /*! Work with Python bytecode. */
 
 use {
+    super::implementation::InterpreterImplementation,
     super::resource::BytecodeOptimizationLevel,
-    anyhow::{anyhow, Result},
+    anyhow::{anyhow, Context, Result},
     byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt},
+    std::convert::TryFrom,
     std::fs::File,
     std::io::{BufRead, BufReader, Read, Write},
     std::path::{Path, PathBuf},
     std::process,
+    std::sync::Mutex,
+    std::time::SystemTime,
 };
 
 pub const BYTECODE_COMPILER: &[u8] = include_bytes!("bytecodecompiler.py");
@@ -21,7 +25,22 @@ pub trait PythonBytecodeCompiler {
     /// Obtain the magic number to use in the bytecode header.
     fn get_magic_number(&self) -> u32;
 
+    /// Whether compiling the same source always produces the same bytecode.
+    ///
+    /// Compilers that aren't reproducible may emit different bytes for
+    /// the same code object, e.g. because the ordering of frozensets
+    /// depends on the hash seed of the compiling process.
+    fn is_reproducible(&self) -> bool {
+        false
+    }
+
     /// Compile Python source into bytecode with an optimization level.
+    ///
+    /// Hash based .pyc modes record the source hash they carry, or the hash
+    /// of `source` as computed by Python for `PycSourceHash::Source`.
+    ///
+    /// Sources Python can't compile produce a `CompileError`, which can be
+    /// recovered from the returned error with `downcast_ref()`.
     fn compile(
         &mut self,
         source: &[u8],
@@ -29,6 +48,140 @@ pub trait PythonBytecodeCompiler {
         optimize: BytecodeOptimizationLevel,
         output_mode: CompileMode,
     ) -> Result<Vec<u8>>;
+
+    /// Compile Python source into bytecode with multiple optimization levels.
+    ///
+    /// Returns the bytecode of each level of `optimize_levels`, in order.
+    /// The default implementation compiles each level separately. Compilers
+    /// able to parse the source once for all levels should override it.
+    fn compile_levels(
+        &mut self,
+        source: &[u8],
+        filename: &str,
+        optimize_levels: &[BytecodeOptimizationLevel],
+        output_mode: CompileMode,
+    ) -> Result<Vec<Vec<u8>>> {
+        optimize_levels
+            .iter()
+            .map(|optimize| self.compile(source, filename, *optimize, output_mode))
+            .collect()
+    }
+}
+
+/// A Python source that failed to compile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompileError {
+    /// Name the source was compiled as.
+    ///
+    /// This is the module name when compiling resources.
+    pub module: String,
+
+    /// The file the source was read from, if known.
+    pub file: Option<PathBuf>,
+
+    /// Line number of the error, if reported.
+    pub line: Option<u32>,
+
+    /// The error as reported by Python, e.g. `SyntaxError: invalid syntax`.
+    pub message: String,
+}
+
+impl std::fmt::Display for CompileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.module)?;
+        if let Some(file) = &self.file {
+            write!(f, " ({})", file.display())?;
+        }
+        if let Some(line) = self.line {
+            write!(f, " line {}", line)?;
+        }
+
+        write!(f, ": {}", self.message)
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+impl CompileError {
+    /// Parse the error Python reports for a source that failed to compile.
+    ///
+    /// `output` is formatted like Python prints exceptions to stderr. The
+    /// line number comes from the `File "...", line N` line and the message
+    /// from the last line.
+    pub fn from_compiler_output(module: &str, output: &str) -> Self {
+        let line = output.lines().find_map(|line| {
+            let line = line.trim_start();
+            if !line.starts_with("File \"") {
+                return None;
+            }
+
+            let number = &line[line.rfind(", line ")? + ", line ".len()..];
+            number
+                .split(|c: char| !c.is_ascii_digit())
+                .next()?
+                .parse::<u32>()
+                .ok()
+        });
+
+        let message = output
+            .lines()
+            .rev()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty())
+            .unwrap_or("unknown error")
+            .to_string();
+
+        Self {
+            module: module.to_string(),
+            file: None,
+            line,
+            message,
+        }
+    }
+}
+
+/// What to do with a module whose source fails to compile.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompileErrorPolicy {
+    /// Fail the build.
+    Abort,
+    /// Leave the module out.
+    Skip,
+    /// Embed the module's source instead of its bytecode.
+    SourceOnly,
+}
+
+impl Default for CompileErrorPolicy {
+    fn default() -> Self {
+        CompileErrorPolicy::Abort
+    }
+}
+
+impl TryFrom<&str> for CompileErrorPolicy {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "abort" => Ok(CompileErrorPolicy::Abort),
+            "skip" => Ok(CompileErrorPolicy::Skip),
+            "source-only" => Ok(CompileErrorPolicy::SourceOnly),
+            t => Err(format!("{} is not a valid compile error policy", t)),
+        }
+    }
+}
+
+/// Options for starting a `BytecodeCompiler`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BytecodeCompilerOptions {
+    /// Whether to emit reproducible bytecode.
+    ///
+    /// See `BytecodeCompiler::new_reproducible()`.
+    pub reproducible: bool,
+
+    /// Directory in which the compiler keeps its files.
+    ///
+    /// The system temporary directory is used if not set.
+    pub temp_root: Option<PathBuf>,
 }
 
 /// An entity to perform Python bytecode compilation.
@@ -39,16 +192,180 @@ pub struct BytecodeCompiler {
 
     /// Magic number for bytecode header.
     magic_number: u32,
+
+    /// Whether the compiler emits reproducible bytecode.
+    reproducible: bool,
 }
 
 /// Output mode for BytecodeCompiler.
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum CompileMode {
     /// Emit just Python bytecode.
     Bytecode,
     /// Emit .pyc header with hash verification.
-    PycCheckedHash,
+    PycCheckedHash(PycSourceHash),
     /// Emit .pyc header with no hash verification.
-    PycUncheckedHash,
+    PycUncheckedHash(PycSourceHash),
+    /// Emit .pyc header with a modified time and the source size.
+    ///
+    /// Compilers don't know where source comes from, so they record 0 for
+    /// `PycTimestamp::Source`. `PythonModuleBytecodeFromSource::compile()`
+    /// resolves it to the modified time of the source.
+    PycTimestamp(PycTimestamp),
+}
+
+/// The source hash recorded in hash based .pyc headers, per PEP 552.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PycSourceHash {
+    /// The hash of the source, as computed by `importlib.util.source_hash()`.
+    Source,
+    /// A hash computed in advance.
+    ///
+    /// The compiler records it without hashing the source again.
+    Known(u64),
+}
+
+/// How Python decides whether a .pyc file is stale, per PEP 552.
+///
+/// This only matters for .pyc files installed next to their source.
+/// Bytecode loaded from memory has no header.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PycInvalidationMode {
+    /// Compare the modified time and size of the source.
+    ///
+    /// Headers depend on when sources were written unless the timestamp is
+    /// fixed.
+    Timestamp(PycTimestamp),
+    /// Compare the hash of the source every time the module is imported.
+    CheckedHash,
+    /// Record the hash of the source but never compare it.
+    ///
+    /// Headers only depend on the content of sources.
+    UncheckedHash,
+}
+
+impl Default for PycInvalidationMode {
+    fn default() -> Self {
+        PycInvalidationMode::UncheckedHash
+    }
+}
+
+impl TryFrom<&str> for PycInvalidationMode {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "timestamp" => Ok(PycInvalidationMode::Timestamp(PycTimestamp::Source)),
+            "checked-hash" => Ok(PycInvalidationMode::CheckedHash),
+            "unchecked-hash" => Ok(PycInvalidationMode::UncheckedHash),
+            t => Err(format!("{} is not a valid pyc invalidation mode", t)),
+        }
+    }
+}
+
+impl PycInvalidationMode {
+    /// Obtain the mode to compile a source with last modified at `source_modified`.
+    pub fn compile_mode(self, source_modified: Option<SystemTime>) -> CompileMode {
+        match self {
+            PycInvalidationMode::Timestamp(timestamp) => {
+                CompileMode::PycTimestamp(timestamp.resolve(source_modified))
+            }
+            PycInvalidationMode::CheckedHash => CompileMode::PycCheckedHash(PycSourceHash::Source),
+            PycInvalidationMode::UncheckedHash => {
+                CompileMode::PycUncheckedHash(PycSourceHash::Source)
+            }
+        }
+    }
+
+    /// Obtain the mode to use for reproducible builds.
+    ///
+    /// Timestamps of sources are replaced by `PycTimestamp::reproducible()`.
+    pub fn reproducible(self) -> Result<Self> {
+        match self {
+            PycInvalidationMode::Timestamp(PycTimestamp::Source) => {
+                Ok(PycInvalidationMode::Timestamp(PycTimestamp::reproducible()?))
+            }
+            mode => Ok(mode),
+        }
+    }
+}
+
+/// Environment variable holding a fixed time for reproducible builds.
+pub const SOURCE_DATE_EPOCH: &str = "SOURCE_DATE_EPOCH";
+
+/// The modified time recorded in timestamp based .pyc headers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PycTimestamp {
+    /// The modified time of the source.
+    ///
+    /// Sources without a modified time, like in-memory sources, record 0.
+    Source,
+    /// A fixed time in seconds since the UNIX epoch.
+    ///
+    /// Use this for reproducible builds.
+    Fixed(u64),
+}
+
+impl PycTimestamp {
+    /// Obtain the timestamp to use from the environment.
+    ///
+    /// The time in `SOURCE_DATE_EPOCH` is used if it is set. Otherwise the
+    /// modified time of sources is.
+    pub fn from_env() -> Result<Self> {
+        Self::from_source_date_epoch(std::env::var(SOURCE_DATE_EPOCH).ok().as_deref())
+    }
+
+    /// Obtain the timestamp to use given the value of `SOURCE_DATE_EPOCH`.
+    pub fn from_source_date_epoch(value: Option<&str>) -> Result<Self> {
+        match value {
+            Some(value) => value
+                .trim()
+                .parse::<u64>()
+                .map(PycTimestamp::Fixed)
+                .map_err(|_| {
+                    anyhow!(
+                        "{} must be a number of seconds; got {}",
+                        SOURCE_DATE_EPOCH,
+                        value
+                    )
+                }),
+            None => Ok(PycTimestamp::Source),
+        }
+    }
+
+    /// Obtain the timestamp to use for reproducible builds.
+    ///
+    /// The time in `SOURCE_DATE_EPOCH` is used if it is set. Otherwise 0 is,
+    /// so headers never depend on when sources were written.
+    pub fn reproducible() -> Result<Self> {
+        match Self::from_env()? {
+            PycTimestamp::Source => Ok(PycTimestamp::Fixed(0)),
+            timestamp => Ok(timestamp),
+        }
+    }
+
+    /// Resolve `Source` to the modified time of a source, if it has one.
+    pub fn resolve(self, source_modified: Option<SystemTime>) -> Self {
+        match (self, source_modified) {
+            (PycTimestamp::Source, Some(modified)) => PycTimestamp::Fixed(
+                modified
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+            ),
+            (timestamp, _) => timestamp,
+        }
+    }
+
+    /// The value of the modified time field of a .pyc header.
+    ///
+    /// Like Python, only the low 32 bits of the time are kept.
+    pub fn header_value(self) -> u32 {
+        match self {
+            PycTimestamp::Source => 0,
+            PycTimestamp::Fixed(seconds) => seconds as u32,
+        }
+    }
 }
 
 impl BytecodeCompiler {
@@ -60,7 +377,51 @@ impl BytecodeCompiler {
     /// requests and receive the compiled bytecode. The process is terminated
     /// when this object is dropped.
     pub fn new(python: &Path) -> Result<BytecodeCompiler> {
-        let temp_dir = tempdir::TempDir::new("bytecode-compiler")?;
+        Self::with_options(python, &BytecodeCompilerOptions::default())
+    }
+
+    /// Create a bytecode compiler emitting reproducible bytecode.
+    ///
+    /// The compiler process runs with `PYTHONHASHSEED=0` so the ordering of
+    /// sets and frozensets in code objects is stable. Bytecode is marshalled
+    /// without object references, whose presence depends on reference
+    /// counts in the compiler process. The bytecode loads like any other but
+    /// may be slightly larger.
+    pub fn new_reproducible(python: &Path) -> Result<BytecodeCompiler> {
+        Self::with_options(
+            python,
+            &BytecodeCompilerOptions {
+                reproducible: true,
+                ..BytecodeCompilerOptions::default()
+            },
+        )
+    }
+
+    /// Create a bytecode compiler keeping its files in a directory in `temp_root`.
+    ///
+    /// `new()` uses the system temporary directory.
+    pub fn spawn(python: &Path, temp_root: &Path) -> Result<BytecodeCompiler> {
+        Self::with_options(
+            python,
+            &BytecodeCompilerOptions {
+                temp_root: Some(temp_root.to_path_buf()),
+                ..BytecodeCompilerOptions::default()
+            },
+        )
+    }
+
+    /// Create a bytecode compiler with options.
+    pub fn with_options(
+        python: &Path,
+        options: &BytecodeCompilerOptions,
+    ) -> Result<BytecodeCompiler> {
+        let temp_root = match &options.temp_root {
+            Some(temp_root) => temp_root.clone(),
+            None => std::env::temp_dir(),
+        };
+
+        let temp_dir = tempdir::TempDir::new_in(&temp_root, "bytecode-compiler")
+            .with_context(|| format!("creating directory in {}", temp_root.display()))?;
 
         let script_path = PathBuf::from(temp_dir.path()).join("bytecodecompiler.py");
 
@@ -69,11 +430,17 @@ impl BytecodeCompiler {
             fh.write_all(BYTECODE_COMPILER)?;
         }
 
-        let mut command = process::Command::new(python)
+        let mut command = process::Command::new(python);
+        command
             .arg(script_path)
             .stdin(process::Stdio::piped())
-            .stdout(process::Stdio::piped())
-            .spawn()?;
+            .stdout(process::Stdio::piped());
+
+        if options.reproducible {
+            command.arg("--reproducible").env("PYTHONHASHSEED", "0");
+        }
+
+        let mut command = command.spawn()?;
 
         let stdin = command
             .stdin
@@ -93,8 +460,24 @@ impl BytecodeCompiler {
             _temp_dir: temp_dir,
             command,
             magic_number,
+            reproducible: options.reproducible,
         })
     }
+
+    /// Create a bytecode compiler for a Python version using another Python executable.
+    ///
+    /// This allows compiling bytecode for a distribution whose Python
+    /// executable can't run on the build machine, e.g. when cross-compiling.
+    /// `python` must write bytecode with the magic number of `version`, e.g.
+    /// `3.8`.
+    pub fn new_for_version(python: &Path, version: &str) -> Result<BytecodeCompiler> {
+        let compiler = Self::new(python)?;
+
+        verify_magic_number_for_python_version(compiler.magic_number, version)
+            .with_context(|| format!("compiling bytecode with {}", python.display()))?;
+
+        Ok(compiler)
+    }
 }
 
 impl PythonBytecodeCompiler for BytecodeCompiler {
@@ -102,6 +485,10 @@ impl PythonBytecodeCompiler for BytecodeCompiler {
         self.magic_number
     }
 
+    fn is_reproducible(&self) -> bool {
+        self.reproducible
+    }
+
     fn compile(
         self: &mut BytecodeCompiler,
         source: &[u8],
@@ -109,38 +496,111 @@ impl PythonBytecodeCompiler for BytecodeCompiler {
         optimize: BytecodeOptimizationLevel,
         output_mode: CompileMode,
     ) -> Result<Vec<u8>> {
+        let mut bytecode = self.compile_levels(source, filename, &[optimize], output_mode)?;
+
+        bytecode
+            .pop()
+            .ok_or_else(|| anyhow!("compiler returned no bytecode"))
+    }
+
+    fn compile_levels(
+        &mut self,
+        source: &[u8],
+        filename: &str,
+        optimize_levels: &[BytecodeOptimizationLevel],
+        output_mode: CompileMode,
+    ) -> Result<Vec<Vec<u8>>> {
         let stdin = self.command.stdin.as_mut().expect("failed to get stdin");
         let stdout = self.command.stdout.as_mut().expect("failed to get stdout");
 
         let mut reader = BufReader::new(stdout);
 
+        // The compiler parses the source once for all levels.
         stdin.write_all(b"compile\n")?;
         stdin.write_all(filename.len().to_string().as_bytes())?;
         stdin.write_all(b"\n")?;
         stdin.write_all(source.len().to_string().as_bytes())?;
         stdin.write_all(b"\n")?;
-        stdin.write_all(i32::from(optimize).to_string().as_bytes())?;
+        stdin.write_all(
+            optimize_levels
+                .iter()
+                .map(|optimize| i32::from(*optimize).to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+                .as_bytes(),
+        )?;
         stdin.write_all(b"\n")?;
         stdin.write_all(match output_mode {
-            CompileMode::Bytecode => b"bytecode",
-            CompileMode::PycCheckedHash => b"pyc-checked-hash",
-            CompileMode::PycUncheckedHash => b"pyc-unchecked-hash",
+            CompileMode::PycCheckedHash(PycSourceHash::Source) => b"pyc-checked-hash",
+            CompileMode::PycUncheckedHash(PycSourceHash::Source) => b"pyc-unchecked-hash",
+            // The header, if any, is added below.
+            _ => b"bytecode",
         })?;
         stdin.write_all(b"\n")?;
         stdin.write_all(filename.as_bytes())?;
         stdin.write_all(source)?;
         stdin.flush()?;
 
-        let mut len_s = String::new();
-        reader.read_line(&mut len_s)?;
+        let header_mode = match output_mode {
+            CompileMode::PycTimestamp(timestamp) => {
+                Some(BytecodeHeaderMode::ModifiedTimeAndSourceSize((
+                    timestamp.header_value(),
+                    source.len() as u32,
+                )))
+            }
+            CompileMode::PycCheckedHash(PycSourceHash::Known(hash)) => {
+                Some(BytecodeHeaderMode::CheckedHash(hash))
+            }
+            CompileMode::PycUncheckedHash(PycSourceHash::Known(hash)) => {
+                Some(BytecodeHeaderMode::UncheckedHash(hash))
+            }
+            _ => None,
+        };
+
+        let mut res = Vec::with_capacity(optimize_levels.len());
+
+        let mut status = String::new();
+        reader.read_line(&mut status)?;
 
-        let len_s = len_s.trim_end();
-        let bytecode_len = len_s.parse::<u64>().unwrap();
+        if status.trim_end() == "error" {
+            let mut len_s = String::new();
+            reader.read_line(&mut len_s)?;
+            let output_len = len_s.trim_end().parse::<u64>()?;
 
-        let mut bytecode: Vec<u8> = Vec::new();
-        reader.take(bytecode_len).read_to_end(&mut bytecode)?;
+            let mut output = Vec::new();
+            (&mut reader).take(output_len).read_to_end(&mut output)?;
 
-        Ok(bytecode)
+            return Err(CompileError::from_compiler_output(
+                filename,
+                &String::from_utf8_lossy(&output),
+            )
+            .into());
+        }
+
+        for _ in optimize_levels {
+            let mut len_s = String::new();
+            reader.read_line(&mut len_s)?;
+
+            let len_s = len_s.trim_end();
+            let bytecode_len = len_s.parse::<u64>().unwrap();
+
+            let mut bytecode: Vec<u8> = Vec::new();
+            (&mut reader)
+                .take(bytecode_len)
+                .read_to_end(&mut bytecode)?;
+
+            res.push(match header_mode {
+                Some(mode) => {
+                    let mut pyc = compute_bytecode_header(self.magic_number, mode)?;
+                    pyc.extend(bytecode);
+
+                    pyc
+                }
+                None => bytecode,
+            });
+        }
+
+        Ok(res)
     }
 }
 
@@ -154,8 +614,69 @@ impl Drop for BytecodeCompiler {
     }
 }
 
+/// A pool of `BytecodeCompiler` instances backed by the same Python executable.
+///
+/// Starting a Python process is relatively expensive. The pool allows compilers
+/// to be reused across operations instead of being spawned for each one.
+#[derive(Debug)]
+pub struct CompilerPool {
+    python_exe: PathBuf,
+    reproducible: bool,
+    compilers: Mutex<Vec<BytecodeCompiler>>,
+}
+
+impl CompilerPool {
+    /// Create a new pool that spawns compilers using the given Python executable.
+    pub fn new(python_exe: &Path) -> Self {
+        Self {
+            python_exe: python_exe.to_path_buf(),
+            reproducible: false,
+            compilers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Create a new pool of compilers emitting reproducible bytecode.
+    ///
+    /// See `BytecodeCompiler::new_reproducible()`.
+    pub fn new_reproducible(python_exe: &Path) -> Self {
+        Self {
+            reproducible: true,
+            ..Self::new(python_exe)
+        }
+    }
+
+    /// Path to the Python executable used by compilers in this pool.
+    pub fn python_exe(&self) -> &Path {
+        &self.python_exe
+    }
+
+    /// Obtain a compiler from the pool.
+    ///
+    /// An idle compiler is returned if available. Otherwise a new one is spawned.
+    pub fn acquire(&self) -> Result<BytecodeCompiler> {
+        let idle = self
+            .compilers
+            .lock()
+            .map_err(|_| anyhow!("unable to lock compiler pool"))?
+            .pop();
+
+        match idle {
+            Some(compiler) => Ok(compiler),
+            None if self.reproducible => BytecodeCompiler::new_reproducible(&self.python_exe),
+            None => BytecodeCompiler::new(&self.python_exe),
+        }
+    }
+
+    /// Return a compiler to the pool so it can be reused.
+    pub fn release(&self, compiler: BytecodeCompiler) {
+        if let Ok(mut compilers) = self.compilers.lock() {
+            compilers.push(compiler);
+        }
+    }
+}
+
 /// How to write out a .pyc bytecode header.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
 pub enum BytecodeHeaderMode {
     /// Use a file modified time plus source size.
     ModifiedTimeAndSourceSize((u32, u32)),
@@ -192,6 +713,202 @@ pub fn compute_bytecode_header(magic_number: u32, mode: BytecodeHeaderMode) -> R
     Ok(header)
 }
 
+/// Magic number ranges of Python versions, from `importlib._bootstrap_external`.
+const MAGIC_NUMBER_VERSIONS: &[(u16, u16, &str)] = &[
+    (62171, 62211, "2.7"),
+    (3000, 3131, "3.0"),
+    (3141, 3151, "3.1"),
+    (3160, 3180, "3.2"),
+    (3190, 3230, "3.3"),
+    (3250, 3310, "3.4"),
+    (3320, 3351, "3.5"),
+    (3360, 3379, "3.6"),
+    (3390, 3394, "3.7"),
+    (3400, 3413, "3.8"),
+    (3420, 3425, "3.9"),
+    (3430, 3439, "3.10"),
+];
+
+/// Read the magic number from the header of a .pyc file.
+pub fn read_bytecode_magic_number(data: &[u8]) -> Result<u32> {
+    if data.len() < 4 {
+        return Err(anyhow!("data is too short to be a bytecode file"));
+    }
+
+    Ok((&data[0..4]).read_u32::<LittleEndian>()?)
+}
+
+/// Obtain the Python version writing bytecode with a magic number.
+///
+/// Returns `None` if the magic number isn't one of a known Python version.
+pub fn python_version_from_magic_number(magic_number: u32) -> Option<&'static str> {
+    // Magic numbers are a 16-bit number followed by `\r\n`.
+    if magic_number >> 16 != 0x0a0d {
+        return None;
+    }
+
+    let number = (magic_number & 0xffff) as u16;
+
+    MAGIC_NUMBER_VERSIONS
+        .iter()
+        .find(|(first, last, _)| number >= *first && number <= *last)
+        .map(|(_, _, version)| *version)
+}
+
+/// Ensure bytecode with a magic number can be loaded by a Python version.
+///
+/// The magic number must be the one final releases of `version` write.
+pub fn verify_magic_number_for_python_version(magic_number: u32, version: &str) -> Result<()> {
+    let expected = magic_number_for_python_version(version)
+        .ok_or_else(|| anyhow!("bytecode magic number of Python {} is unknown", version))?;
+
+    if magic_number == expected {
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "bytecode magic number {:#010x} ({}) doesn't match {:#010x} of Python {}",
+            magic_number,
+            python_version_from_magic_number(magic_number).unwrap_or("unknown Python version"),
+            expected,
+            version
+        ))
+    }
+}
+
+/// Obtain the magic number of bytecode written by a CPython release.
+///
+/// This is the last magic number of the version's range, which final
+/// releases use. Returns `None` for unknown versions.
+pub fn magic_number_for_python_version(version: &str) -> Option<u32> {
+    MAGIC_NUMBER_VERSIONS
+        .iter()
+        .find(|(_, _, v)| *v == version)
+        .map(|(_, last, _)| 0x0a0d_0000 | *last as u32)
+}
+
+/// Obtain the Python version targeted by a bytecode cache tag.
+///
+/// e.g. `cpython-38` and `pypy38` both target `3.8`.
+pub fn python_version_from_cache_tag(cache_tag: &str) -> Option<String> {
+    let digits = cache_tag
+        .strip_prefix("cpython-")
+        .or_else(|| cache_tag.strip_prefix("pypy"))?;
+
+    if digits.len() < 2 || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+
+    Some(format!("{}.{}", &digits[0..1], &digits[1..]))
+}
+
+/// The header of a .pyc file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct BytecodeHeader {
+    /// The magic number identifying the bytecode format.
+    pub magic_number: u32,
+
+    /// The flags word introduced by PEP 552 in Python 3.7.
+    ///
+    /// `None` for headers of earlier versions.
+    pub flags: Option<u32>,
+
+    /// How the bytecode is validated against its source.
+    ///
+    /// Headers predating Python 3.3 don't record the source size, which
+    /// reads as 0.
+    pub mode: BytecodeHeaderMode,
+
+    /// Size in bytes of the header.
+    pub len: usize,
+}
+
+impl BytecodeHeader {
+    /// Parse the header of .pyc data.
+    ///
+    /// The header layout depends on the Python version, which is derived
+    /// from the interpreter implementation of `cache_tag` and the magic
+    /// number, see `InterpreterImplementation::bytecode_python_version()`.
+    /// Without a cache tag, bytecode is assumed to be CPython's. Errors if
+    /// the version can't be determined rather than guessing a layout.
+    pub fn parse(data: &[u8], cache_tag: Option<&str>) -> Result<Self> {
+        let magic_number = read_bytecode_magic_number(data)?;
+
+        if magic_number >> 16 != 0x0a0d {
+            return Err(anyhow!(
+                "magic number {:#010x} is not a bytecode magic number",
+                magic_number
+            ));
+        }
+
+        let implementation = cache_tag
+            .map(InterpreterImplementation::from_cache_tag)
+            .unwrap_or_default();
+        let version = implementation.bytecode_python_version(magic_number, cache_tag)?;
+        let version = {
+            let mut parts = version.splitn(2, '.').map(|part| part.parse::<u32>().ok());
+            (parts.next().flatten(), parts.next().flatten())
+        };
+        let version = match version {
+            (Some(major), Some(minor)) => (major, minor),
+            _ => return Err(anyhow!("invalid Python version of bytecode")),
+        };
+
+        // Python 3.3 added the source size and 3.7 the flags word.
+        let (len, flags) = if version >= (3, 7) {
+            if data.len() < 8 {
+                return Err(anyhow!("data is too short to be a bytecode file"));
+            }
+
+            let flags = (&data[4..8]).read_u32::<LittleEndian>()?;
+            if flags & !0b11 != 0 {
+                return Err(anyhow!("invalid bytecode flags {:#x}", flags));
+            }
+
+            (16, Some(flags))
+        } else if version >= (3, 3) {
+            (12, None)
+        } else {
+            (8, None)
+        };
+
+        if data.len() < len {
+            return Err(anyhow!("data is too short to be a bytecode file"));
+        }
+
+        let field = |offset: usize| (&data[offset..offset + 4]).read_u32::<LittleEndian>();
+
+        let mode = match (len, flags) {
+            (16, Some(flags)) if flags & 0b01 != 0 => {
+                let hash = (&data[8..16]).read_u64::<LittleEndian>()?;
+
+                if flags & 0b10 != 0 {
+                    BytecodeHeaderMode::CheckedHash(hash)
+                } else {
+                    BytecodeHeaderMode::UncheckedHash(hash)
+                }
+            }
+            (16, _) => BytecodeHeaderMode::ModifiedTimeAndSourceSize((field(8)?, field(12)?)),
+            (12, _) => BytecodeHeaderMode::ModifiedTimeAndSourceSize((field(4)?, field(8)?)),
+            _ => BytecodeHeaderMode::ModifiedTimeAndSourceSize((field(4)?, 0)),
+        };
+
+        Ok(Self {
+            magic_number,
+            flags,
+            mode,
+            len,
+        })
+    }
+
+    /// The source modified time recorded by timestamp based headers.
+    pub fn modified_time(&self) -> Option<u32> {
+        match self.mode {
+            BytecodeHeaderMode::ModifiedTimeAndSourceSize((mtime, _)) => Some(mtime),
+            BytecodeHeaderMode::CheckedHash(_) | BytecodeHeaderMode::UncheckedHash(_) => None,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -217,4 +934,223 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_pyc_invalidation_mode() -> Result<()> {
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(42);
+
+        assert_eq!(
+            PycInvalidationMode::try_from("timestamp")
+                .map_err(|e| anyhow!(e))?
+                .compile_mode(Some(modified)),
+            CompileMode::PycTimestamp(PycTimestamp::Fixed(42))
+        );
+        assert_eq!(
+            PycInvalidationMode::Timestamp(PycTimestamp::Fixed(7)).compile_mode(Some(modified)),
+            CompileMode::PycTimestamp(PycTimestamp::Fixed(7))
+        );
+        assert_eq!(
+            PycInvalidationMode::try_from("checked-hash")
+                .map_err(|e| anyhow!(e))?
+                .compile_mode(Some(modified)),
+            CompileMode::PycCheckedHash(PycSourceHash::Source)
+        );
+        assert_eq!(
+            PycInvalidationMode::default().compile_mode(None),
+            CompileMode::PycUncheckedHash(PycSourceHash::Source)
+        );
+        assert_eq!(
+            PycInvalidationMode::try_from("hash"),
+            Err("hash is not a valid pyc invalidation mode".to_string())
+        );
+
+        // Reproducible builds never record the modified time of sources.
+        assert!(matches!(
+            PycInvalidationMode::try_from("timestamp")
+                .map_err(|e| anyhow!(e))?
+                .reproducible()?,
+            PycInvalidationMode::Timestamp(PycTimestamp::Fixed(_))
+        ));
+        assert_eq!(
+            PycInvalidationMode::Timestamp(PycTimestamp::Fixed(7)).reproducible()?,
+            PycInvalidationMode::Timestamp(PycTimestamp::Fixed(7))
+        );
+        assert_eq!(
+            PycInvalidationMode::CheckedHash.reproducible()?,
+            PycInvalidationMode::CheckedHash
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_error_from_compiler_output() {
+        let error = CompileError::from_compiler_output(
+            "foo.bar",
+            "  File \"foo.bar\", line 3\n    print \"hello\"\n          ^\nSyntaxError: Missing parentheses in call to 'print'\n",
+        );
+        assert_eq!(error.line, Some(3));
+        assert_eq!(
+            error.message,
+            "SyntaxError: Missing parentheses in call to 'print'"
+        );
+        assert_eq!(
+            error.to_string(),
+            "foo.bar line 3: SyntaxError: Missing parentheses in call to 'print'"
+        );
+
+        let error = CompileError::from_compiler_output(
+            "foo",
+            "UnicodeDecodeError: 'utf-8' codec can't decode byte 0xff in position 0\n",
+        );
+        assert_eq!(error.line, None);
+        assert_eq!(
+            error.message,
+            "UnicodeDecodeError: 'utf-8' codec can't decode byte 0xff in position 0"
+        );
+
+        assert_eq!(
+            CompileErrorPolicy::try_from("source-only"),
+            Ok(CompileErrorPolicy::SourceOnly)
+        );
+        assert_eq!(
+            CompileErrorPolicy::try_from("ignore"),
+            Err("ignore is not a valid compile error policy".to_string())
+        );
+    }
+
+    #[test]
+    fn test_python_version_from_magic_number() -> Result<()> {
+        assert_eq!(read_bytecode_magic_number(b"U\r\r\n\x00")?, 168627541);
+        assert!(read_bytecode_magic_number(b"U\r").is_err());
+
+        assert_eq!(python_version_from_magic_number(168627541), Some("3.8"));
+        assert_eq!(
+            python_version_from_magic_number(u32::from_le_bytes(*b"B\r\r\n")),
+            Some("3.7")
+        );
+        assert_eq!(
+            python_version_from_magic_number(u32::from_le_bytes(*b"a\r\r\n")),
+            Some("3.9")
+        );
+
+        assert_eq!(
+            python_version_from_magic_number(u32::from_le_bytes(*b"\x03\xf3\r\n")),
+            Some("2.7")
+        );
+        assert_eq!(python_version_from_magic_number(0x1234_0d55), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_magic_number_for_python_version() -> Result<()> {
+        verify_magic_number_for_python_version(0x0a0d_0d55, "3.8")?;
+
+        assert_eq!(
+            verify_magic_number_for_python_version(0x0a0d_0d61, "3.8")
+                .unwrap_err()
+                .to_string(),
+            "bytecode magic number 0x0a0d0d61 (3.9) doesn't match 0x0a0d0d55 of Python 3.8"
+        );
+        assert!(verify_magic_number_for_python_version(0x0a0d_0d55, "4.0").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_header() -> Result<()> {
+        assert_eq!(magic_number_for_python_version("3.8"), Some(168627541));
+        assert_eq!(magic_number_for_python_version("4.0"), None);
+
+        assert_eq!(
+            python_version_from_cache_tag("cpython-38"),
+            Some("3.8".to_string())
+        );
+        assert_eq!(
+            python_version_from_cache_tag("cpython-310"),
+            Some("3.10".to_string())
+        );
+        assert_eq!(
+            python_version_from_cache_tag("pypy37"),
+            Some("3.7".to_string())
+        );
+        assert_eq!(python_version_from_cache_tag("jython-27"), None);
+
+        let header = compute_bytecode_header(168627541, BytecodeHeaderMode::CheckedHash(42))?;
+        assert_eq!(
+            BytecodeHeader::parse(&header, None)?,
+            BytecodeHeader {
+                magic_number: 168627541,
+                flags: Some(3),
+                mode: BytecodeHeaderMode::CheckedHash(42),
+                len: 16,
+            }
+        );
+        assert_eq!(BytecodeHeader::parse(&header, None)?.modified_time(), None);
+        assert!(BytecodeHeader::parse(&header[0..12], None).is_err());
+
+        let header = compute_bytecode_header(
+            168627541,
+            BytecodeHeaderMode::ModifiedTimeAndSourceSize((5, 10)),
+        )?;
+        let parsed = BytecodeHeader::parse(&header, None)?;
+        assert_eq!(
+            parsed.mode,
+            BytecodeHeaderMode::ModifiedTimeAndSourceSize((5, 10))
+        );
+        assert_eq!(parsed.modified_time(), Some(5));
+
+        // PyPy bytecode uses the layout of the version of its cache tag.
+        let mut pypy36 = b"\x00\x01\r\n".to_vec();
+        pypy36.extend(&[0; 8]);
+        assert_eq!(BytecodeHeader::parse(&pypy36, Some("pypy36"))?.len, 12);
+        assert!(BytecodeHeader::parse(&pypy36, Some("pypy38")).is_err());
+        assert!(BytecodeHeader::parse(&pypy36, Some("pypy")).is_err());
+
+        // Unknown CPython magic numbers are rejected rather than guessed.
+        assert!(BytecodeHeader::parse(&pypy36, None).is_err());
+        assert!(BytecodeHeader::parse(&pypy36, Some("cpython-36")).is_err());
+
+        let mut python27 = b"\x03\xf3\r\n".to_vec();
+        python27.extend(&[0; 4]);
+        assert_eq!(BytecodeHeader::parse(&python27, Some("cpython-38"))?.len, 8);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pyc_timestamp() -> Result<()> {
+        assert_eq!(
+            PycTimestamp::from_source_date_epoch(None)?,
+            PycTimestamp::Source
+        );
+        assert_eq!(
+            PycTimestamp::from_source_date_epoch(Some("1600000000"))?,
+            PycTimestamp::Fixed(1_600_000_000)
+        );
+        assert_eq!(
+            PycTimestamp::from_source_date_epoch(Some("yesterday"))
+                .unwrap_err()
+                .to_string(),
+            "SOURCE_DATE_EPOCH must be a number of seconds; got yesterday"
+        );
+
+        let modified = SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(42);
+        assert_eq!(
+            PycTimestamp::Source.resolve(Some(modified)),
+            PycTimestamp::Fixed(42)
+        );
+        assert_eq!(PycTimestamp::Source.resolve(None), PycTimestamp::Source);
+        // A fixed time wins over the source's.
+        assert_eq!(
+            PycTimestamp::Fixed(7).resolve(Some(modified)),
+            PycTimestamp::Fixed(7)
+        );
+
+        assert_eq!(PycTimestamp::Source.header_value(), 0);
+        assert_eq!(PycTimestamp::Fixed(0x1_0000_0005).header_value(), 5);
+
+        Ok(())
+    }
 }
@@ -7,23 +7,92 @@
 # When invoked, we start a server that listens for commands. We then
 # react to those commands and send results to the caller.
 
+import ast
 import importlib._bootstrap_external
 import importlib.util
 import marshal
 import os
 import re
 import sys
+import traceback
 
 
 RE_CODING = re.compile(b"^[ \t\f]*#.*?coding[:=][ \t]*([-_.a-zA-Z0-9]+)")
 
 
-if marshal.version != 4:
+# Other implementations (e.g. PyPy) have their own marshal versioning.
+if sys.implementation.name == "cpython" and marshal.version != 4:
     raise Exception("unexpected marshal version: %d" % marshal.version)
 
 stdin = sys.__stdin__.buffer
 stdout = sys.__stdout__.buffer
 
+# Reproducible bytecode doesn't depend on the state of this process. Marshal
+# version 4 flags objects referenced more than once so they can be shared on
+# load, which depends on reference counts here. Version 2 predates these
+# references. The caller also fixes PYTHONHASHSEED so frozensets in code
+# objects are ordered the same way every time.
+REPRODUCIBLE = "--reproducible" in sys.argv[1:]
+MARSHAL_VERSION = 2 if REPRODUCIBLE else marshal.version
+
+
+def code_to_hash_pyc(code, source_hash, checked):
+    # Like importlib._bootstrap_external._code_to_hash_pyc(), but using our
+    # marshal version.
+    data = bytearray(importlib._bootstrap_external.MAGIC_NUMBER)
+    data.extend((0b1 | checked << 1).to_bytes(4, "little"))
+    data.extend(source_hash)
+    data.extend(marshal.dumps(code, MARSHAL_VERSION))
+
+    return data
+
+
+def compile_levels(name, source, optimize_levels, output_mode):
+    # Default source encoding is UTF-8. But per PEP 263, the first or second
+    # line of source can match a regular expression to define a custom
+    # encoding. We need to detect custom encodings and use it to decode
+    # the passed bytes to str.
+    encoding = "utf-8"
+
+    for line in source.splitlines()[0:2]:
+        m = RE_CODING.match(line)
+        if m:
+            encoding = m.group(1).decode("ascii")
+            break
+
+    # Someone has set us up the BOM! According to PEP 263 the file should
+    # be interpreted as UTF-8.
+    if source.startswith(b"\xef\xbb\xbf"):
+        encoding = "utf-8"
+        source = source[3:]
+
+    source_bytes = source
+    source = source.decode(encoding)
+
+    # Parse once when compiling multiple optimization levels.
+    if len(optimize_levels) > 1:
+        source = compile(source, name, "exec", ast.PyCF_ONLY_AST)
+
+    outputs = []
+    for optimize_level in optimize_levels:
+        code = compile(source, name, "exec", optimize=optimize_level)
+        bytecode = marshal.dumps(code, MARSHAL_VERSION)
+
+        if output_mode == b"bytecode":
+            out = bytecode
+        elif output_mode == b"pyc-checked-hash":
+            source_hash = importlib.util.source_hash(source_bytes)
+            out = code_to_hash_pyc(code, source_hash, checked=True)
+        elif output_mode == b"pyc-unchecked-hash":
+            source_hash = importlib.util.source_hash(source_bytes)
+            out = code_to_hash_pyc(code, source_hash, checked=False)
+        else:
+            raise Exception("unknown output mode: %s" % output_mode)
+
+        outputs.append(out)
+
+    return outputs
+
 
 while True:
     command = stdin.readline().rstrip()
@@ -36,59 +105,38 @@ while True:
     elif command == b"compile":
         name_len = stdin.readline().rstrip()
         source_len = stdin.readline().rstrip()
-        optimize_level = stdin.readline().rstrip()
+        optimize_levels = stdin.readline().rstrip()
         output_mode = stdin.readline().rstrip()
 
         name_len = int(name_len)
         source_len = int(source_len)
-        optimize_level = int(optimize_level)
+        optimize_levels = [int(level) for level in optimize_levels.split(b",")]
 
         name = stdin.read(name_len)
         source = stdin.read(source_len)
 
         name = os.fsdecode(name)
 
-        # Default source encoding is UTF-8. But per PEP 263, the first or second
-        # line of source can match a regular expression to define a custom
-        # encoding. We need to detect custom encodings and use it to decode
-        # the passed bytes to str.
-        encoding = "utf-8"
-
-        for line in source.splitlines()[0:2]:
-            m = RE_CODING.match(line)
-            if m:
-                encoding = m.group(1).decode("ascii")
-                break
-
-        # Someone has set us up the BOM! According to PEP 263 the file should
-        # be interpreted as UTF-8.
-        if source.startswith(b"\xef\xbb\xbf"):
-            encoding = "utf-8"
-            source = source[3:]
-
-        source_bytes = source
-        source = source.decode(encoding)
-
-        code = compile(source, name, "exec", optimize=optimize_level)
-        bytecode = marshal.dumps(code)
-
-        if output_mode == b"bytecode":
-            out = bytecode
-        elif output_mode == b"pyc-checked-hash":
-            source_hash = importlib.util.source_hash(source_bytes)
-            out = importlib._bootstrap_external._code_to_hash_pyc(
-                code, source_hash, checked=True,
-            )
-        elif output_mode == b"pyc-unchecked-hash":
-            source_hash = importlib.util.source_hash(source_bytes)
-            out = importlib._bootstrap_external._code_to_hash_pyc(
-                code, source_hash, checked=False,
-            )
-        else:
-            raise Exception("unknown output mode: %s" % output_mode)
+        # Compile every level before responding so a source that fails to
+        # compile produces an error instead of partial output. The error is
+        # reported as Python would print it to stderr.
+        try:
+            outputs = compile_levels(name, source, optimize_levels, output_mode)
+        except (SyntaxError, ValueError, UnicodeDecodeError, LookupError) as e:
+            error = "".join(traceback.format_exception_only(type(e), e))
+            error = error.encode("utf-8", "replace")
+
+            stdout.write(b"error\n")
+            stdout.write(b"%d\n" % len(error))
+            stdout.write(error)
+            stdout.flush()
+            continue
+
+        stdout.write(b"ok\n")
+        for out in outputs:
+            stdout.write(b"%d\n" % len(out))
+            stdout.write(out)
 
-        stdout.write(b"%d\n" % len(out))
-        stdout.write(out)
         stdout.flush()
     else:
         raise Exception("invalid command: %s" % command)
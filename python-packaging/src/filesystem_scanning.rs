@@ -7,16 +7,20 @@ Scanning the filesystem for Python resources.
 */
 
 use {
-    crate::module_util::{is_package_from_path, PythonModuleSuffixes},
-    crate::package_metadata::PythonPackageMetadata,
+    crate::bytecode::read_bytecode_magic_number,
+    crate::module_util::{is_package_from_path, packages_from_module_name, PythonModuleSuffixes},
+    crate::package_metadata::{
+        normalize_distribution_name, PythonPackageMetadata, PythonPackageRecord,
+    },
     crate::resource::{
         BytecodeOptimizationLevel, DataLocation, PythonEggFile, PythonExtensionModule,
         PythonModuleBytecode, PythonModuleSource, PythonPackageDistributionResource,
         PythonPackageDistributionResourceFlavor, PythonPackageResource, PythonPathExtension,
         PythonResource,
     },
-    anyhow::Result,
-    std::collections::HashSet,
+    crate::version::PythonVersion,
+    anyhow::{anyhow, Context, Result},
+    std::collections::{BTreeMap, BTreeSet, HashSet},
     std::ffi::OsStr,
     std::path::{Path, PathBuf},
 };
@@ -58,9 +62,13 @@ pub struct PythonResourceIterator {
     root_path: PathBuf,
     cache_tag: String,
     suffixes: PythonModuleSuffixes,
+    sourceless_magic_number: Option<u32>,
     walkdir_result: Box<dyn Iterator<Item = walkdir::DirEntry>>,
     seen_packages: HashSet<String>,
     resources: Vec<ResourceFile>,
+    replaced_files: HashSet<PathBuf>,
+    distribution_top_level_names: BTreeSet<String>,
+    data_packages_resolved: bool,
 }
 
 impl PythonResourceIterator {
@@ -68,6 +76,7 @@ impl PythonResourceIterator {
         path: &Path,
         cache_tag: &str,
         suffixes: &PythonModuleSuffixes,
+        sourceless_magic_number: Option<u32>,
     ) -> PythonResourceIterator {
         let res = walkdir::WalkDir::new(path).sort_by(|a, b| a.file_name().cmp(b.file_name()));
 
@@ -83,19 +92,30 @@ impl PythonResourceIterator {
             }
         });
 
+        let replaced_files = find_replaced_distribution_files(path);
+        let distribution_top_level_names = find_distribution_top_level_names(path, &replaced_files);
+
         PythonResourceIterator {
             root_path: path.to_path_buf(),
             cache_tag: cache_tag.to_string(),
             suffixes: suffixes.clone(),
+            sourceless_magic_number,
             walkdir_result: Box::new(filtered),
             seen_packages: HashSet::new(),
             resources: Vec::new(),
+            replaced_files,
+            distribution_top_level_names,
+            data_packages_resolved: false,
         }
     }
 
     fn resolve_dir_entry(&mut self, entry: walkdir::DirEntry) -> Option<DirEntryItem> {
         let path = entry.path();
 
+        if self.replaced_files.contains(path) {
+            return None;
+        }
+
         let mut rel_path = path
             .strip_prefix(&self.root_path)
             .expect("unable to strip path prefix");
@@ -149,7 +169,7 @@ impl PythonResourceIterator {
                     package: package.to_string(),
                     version: version.to_string(),
                     name,
-                    data: DataLocation::Path(path.to_path_buf()),
+                    data: DataLocation::from_path(path.to_path_buf()),
                 }),
             ));
         }
@@ -240,7 +260,7 @@ impl PythonResourceIterator {
                         name: full_module_name,
                         init_fn,
                         extension_file_suffix: ext_suffix.clone(),
-                        shared_library: Some(DataLocation::Path(path.to_path_buf())),
+                        shared_library: Some(DataLocation::from_path(path.to_path_buf())),
                         object_file_data: vec![],
                         is_package: is_package_from_path(path),
                         link_libraries: vec![],
@@ -289,7 +309,7 @@ impl PythonResourceIterator {
             return Some(DirEntryItem::PythonResource(PythonResource::ModuleSource(
                 PythonModuleSource {
                     name: full_module_name,
-                    source: DataLocation::Path(path.to_path_buf()),
+                    source: DataLocation::from_path(path.to_path_buf()),
                     is_package: is_package_from_path(&path),
                     cache_tag: self.cache_tag.clone(),
                     is_stdlib: false,
@@ -304,14 +324,11 @@ impl PythonResourceIterator {
             .iter()
             .any(|ext| rel_str.ends_with(ext))
         {
-            // .pyc files should be in a __pycache__ directory.
-            if components.len() < 2 {
-                return None;
-            }
-
-            // Possibly from Python 2?
-            if components[components.len() - 2] != "__pycache__" {
-                return None;
+            // .pyc files outside a __pycache__ directory are sourceless
+            // bytecode, imported when there is no source. Or possibly from
+            // Python 2.
+            if components.len() < 2 || components[components.len() - 2] != "__pycache__" {
+                return self.resolve_sourceless_bytecode(path, rel_path, &components);
             }
 
             let package_parts = &components[0..components.len() - 2];
@@ -324,40 +341,11 @@ impl PythonResourceIterator {
                 .to_string_lossy()
                 .to_string();
 
-            let filename_parts = filename.split('.').collect::<Vec<&str>>();
-
-            if filename_parts.len() < 3 {
-                return None;
-            }
-
-            let mut remaining_filename = filename.clone();
+            let (module_name, cache_tag, optimization_level) =
+                parse_pycache_file_name(&filename, &self.suffixes)?;
 
-            // The first part is always the module name.
-            let module_name = filename_parts[0];
-            remaining_filename = remaining_filename[module_name.len() + 1..].to_string();
-
-            // The second part is the cache tag. It should match ours.
-            if filename_parts[1] != self.cache_tag {
-                return None;
-            }
-
-            // Keep the leading dot in case there is no cache tag: in this case the
-            // suffix has the leading dot and we'll need to match against that.
-            remaining_filename = remaining_filename[self.cache_tag.len()..].to_string();
-
-            // Look for optional tag, of which we only recognize opt-1, opt-2, and None.
-            let optimization_level = if filename_parts[2] == "opt-1" {
-                remaining_filename = remaining_filename[6..].to_string();
-                BytecodeOptimizationLevel::One
-            } else if filename_parts[2] == "opt-2" {
-                remaining_filename = remaining_filename[6..].to_string();
-                BytecodeOptimizationLevel::Two
-            } else {
-                BytecodeOptimizationLevel::Zero
-            };
-
-            // Only the bytecode suffix should remain.
-            if !self.suffixes.bytecode.contains(&remaining_filename) {
+            // The cache tag should match ours.
+            if cache_tag != self.cache_tag {
                 return None;
             }
 
@@ -387,11 +375,11 @@ impl PythonResourceIterator {
 
         let resource = match rel_path.extension().and_then(OsStr::to_str) {
             Some("egg") => DirEntryItem::PythonResource(PythonResource::EggFile(PythonEggFile {
-                data: DataLocation::Path(path.to_path_buf()),
+                data: DataLocation::from_path(path.to_path_buf()),
             })),
             Some("pth") => {
                 DirEntryItem::PythonResource(PythonResource::PathExtension(PythonPathExtension {
-                    data: DataLocation::Path(path.to_path_buf()),
+                    data: DataLocation::from_path(path.to_path_buf()),
                 }))
             }
             _ => {
@@ -407,6 +395,66 @@ impl PythonResourceIterator {
 
         Some(resource)
     }
+
+    /// Resolve a `.pyc` file outside a `__pycache__` directory.
+    ///
+    /// Files are ignored unless sourceless bytecode is being looked for.
+    /// Bytecode whose header doesn't have the magic number of the target,
+    /// or whose name isn't importable, becomes a resource file.
+    fn resolve_sourceless_bytecode(
+        &mut self,
+        path: &Path,
+        rel_path: &Path,
+        components: &[&str],
+    ) -> Option<DirEntryItem> {
+        let magic_number = self.sourceless_magic_number?;
+
+        let file_name = components[components.len() - 1];
+        let suffix = self
+            .suffixes
+            .bytecode
+            .iter()
+            .find(|ext| file_name.ends_with(ext.as_str()))?;
+        let module_name = &file_name[0..file_name.len() - suffix.len()];
+
+        let magic_matches = std::fs::read(path)
+            .ok()
+            .and_then(|data| read_bytecode_magic_number(&data).ok())
+            == Some(magic_number);
+
+        if !magic_matches || module_name.is_empty() || module_name.contains('.') {
+            return Some(DirEntryItem::ResourceFile(ResourceFile {
+                full_path: path.to_path_buf(),
+                relative_path: rel_path.to_path_buf(),
+            }));
+        }
+
+        let package_parts = &components[0..components.len() - 1];
+        let mut package = itertools::join(package_parts, ".");
+
+        let mut full_module_name: Vec<&str> = package_parts.to_vec();
+
+        if module_name != "__init__" {
+            full_module_name.push(module_name);
+        }
+
+        let full_module_name = itertools::join(full_module_name, ".");
+
+        if package.is_empty() {
+            package = full_module_name.clone();
+        }
+
+        self.seen_packages.insert(package);
+
+        Some(DirEntryItem::PythonResource(
+            PythonResource::ModuleBytecode(PythonModuleBytecode::from_path(
+                &full_module_name,
+                BytecodeOptimizationLevel::Zero,
+                &self.cache_tag,
+                path,
+            )),
+        ))
+    }
 }
 
 impl Iterator for PythonResourceIterator {
@@ -445,6 +493,25 @@ impl Iterator for PythonResourceIterator {
             }
         }
 
+        // Top-level directories installed by distributions that don't hold
+        // any Python code are packages only containing data. Their files are
+        // resources of that package.
+        if !self.data_packages_resolved {
+            self.data_packages_resolved = true;
+
+            for name in &self.distribution_top_level_names {
+                let prefix = format!("{}.", name);
+
+                if !self
+                    .seen_packages
+                    .iter()
+                    .any(|p| p == name || p.starts_with(&prefix))
+                {
+                    self.seen_packages.insert(name.clone());
+                }
+            }
+        }
+
         loop {
             if self.resources.is_empty() {
                 return None;
@@ -524,7 +591,7 @@ impl Iterator for PythonResourceIterator {
             return Some(Ok(PythonResource::Resource(PythonPackageResource {
                 leaf_package,
                 relative_name,
-                data: DataLocation::Path(resource.full_path),
+                data: DataLocation::from_path(resource.full_path),
                 is_stdlib: false,
                 is_test: false,
             })));
@@ -532,6 +599,164 @@ impl Iterator for PythonResourceIterator {
     }
 }
 
+/// Parse the file name of a bytecode file in a `__pycache__` directory.
+///
+/// Names have the format `<module>.<cache tag>[.<extra tag>]<suffix>`. Returns
+/// the module name, cache tag and optimization level. Returns `None` if the
+/// name doesn't have this format or has an unknown suffix.
+pub fn parse_pycache_file_name<'a>(
+    file_name: &'a str,
+    suffixes: &PythonModuleSuffixes,
+) -> Option<(&'a str, &'a str, BytecodeOptimizationLevel)> {
+    let parts = file_name.split('.').collect::<Vec<_>>();
+
+    if parts.len() < 3 {
+        return None;
+    }
+
+    let module_name = parts[0];
+    let cache_tag = parts[1];
+
+    // Keep the leading dot in case there is no extra tag: in this case the
+    // suffix has the leading dot and we'll need to match against that.
+    let mut remaining = &file_name[module_name.len() + 1 + cache_tag.len()..];
+
+    // Look for optional tag, of which we only recognize known optimization
+    // levels and None.
+    let extra_tag = format!(".{}", parts[2]);
+    let optimization_level = match BytecodeOptimizationLevel::from_extra_tag(&extra_tag) {
+        Some(level) => {
+            remaining = &remaining[extra_tag.len()..];
+            level
+        }
+        None => BytecodeOptimizationLevel::Zero,
+    };
+
+    // Only the bytecode suffix should remain.
+    if !suffixes.bytecode.iter().any(|suffix| suffix == remaining) {
+        return None;
+    }
+
+    Some((module_name, cache_tag, optimization_level))
+}
+
+/// Find files belonging to replaced versions of installed distributions.
+///
+/// Installing a new version of a distribution over an old one without
+/// uninstalling it first can leave multiple `.dist-info` directories for
+/// the same distribution in a directory, along with files the new version
+/// no longer installs. For each distribution having multiple versions, the
+/// newest version wins, with versions ordered as PEP 440 orders them. Files
+/// listed in the RECORD of older versions but not in the RECORD of the newest
+/// version are returned, along with the content of the older `.dist-info`
+/// directories.
+///
+/// Distributions whose METADATA, RECORD or version can't be parsed are
+/// ignored.
+pub fn find_replaced_distribution_files(root_path: &Path) -> HashSet<PathBuf> {
+    let mut distributions = BTreeMap::new();
+
+    let entries = match std::fs::read_dir(root_path) {
+        Ok(entries) => entries,
+        Err(_) => return HashSet::new(),
+    };
+
+    for entry in entries.filter_map(|entry| entry.ok()) {
+        let dist_path = entry.path();
+
+        if !dist_path.is_dir() || !entry.file_name().to_string_lossy().ends_with(".dist-info") {
+            continue;
+        }
+
+        let metadata = match std::fs::read(dist_path.join("METADATA"))
+            .ok()
+            .and_then(|data| PythonPackageMetadata::from_metadata(&data).ok())
+        {
+            Some(metadata) => metadata,
+            None => continue,
+        };
+
+        let record = match std::fs::read(dist_path.join("RECORD"))
+            .ok()
+            .and_then(|data| PythonPackageRecord::from_record(&data).ok())
+        {
+            Some(record) => record,
+            None => continue,
+        };
+
+        let (name, version) = match (metadata.name(), metadata.version()) {
+            (Some(name), Some(version)) => (name, version),
+            _ => continue,
+        };
+
+        let version = match PythonVersion::parse(version) {
+            Ok(version) => version,
+            Err(_) => continue,
+        };
+
+        distributions
+            .entry(normalize_distribution_name(name))
+            .or_insert_with(Vec::new)
+            .push((version, dist_path, record));
+    }
+
+    let mut res = HashSet::new();
+
+    for (_, mut versions) in distributions {
+        if versions.len() < 2 {
+            continue;
+        }
+
+        versions.sort_by(|a, b| a.0.cmp(&b.0));
+        let (_, _, newest) = versions.pop().unwrap();
+
+        let current = newest
+            .entries
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<HashSet<_>>();
+
+        for (_, dist_path, record) in versions {
+            for entry in &record.entries {
+                if !current.contains(entry.path.as_str()) {
+                    res.insert(root_path.join(&entry.path));
+                }
+            }
+
+            for entry in walk_tree_files(&dist_path) {
+                res.insert(entry.path().to_path_buf());
+            }
+        }
+    }
+
+    res
+}
+
+/// Find the top-level names installed by distributions in a directory.
+///
+/// Names are obtained from the RECORD files of `.dist-info` directories.
+/// Distributions whose RECORD is in `replaced_files` or can't be parsed are
+/// ignored.
+fn find_distribution_top_level_names(
+    root_path: &Path,
+    replaced_files: &HashSet<PathBuf>,
+) -> BTreeSet<String> {
+    let entries = match std::fs::read_dir(root_path) {
+        Ok(entries) => entries,
+        Err(_) => return BTreeSet::new(),
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".dist-info"))
+        .map(|entry| entry.path().join("RECORD"))
+        .filter(|record_path| !replaced_files.contains(record_path))
+        .filter_map(|record_path| std::fs::read(record_path).ok())
+        .filter_map(|data| PythonPackageRecord::from_record(&data).ok())
+        .flat_map(|record| record.top_level_names())
+        .collect()
+}
+
 /// Find Python resources in a directory.
 ///
 /// Given a root directory path, walk the directory and find all Python
@@ -546,7 +771,168 @@ pub fn find_python_resources(
     cache_tag: &str,
     suffixes: &PythonModuleSuffixes,
 ) -> PythonResourceIterator {
-    PythonResourceIterator::new(root_path, cache_tag, suffixes)
+    PythonResourceIterator::new(root_path, cache_tag, suffixes, None)
+}
+
+/// Find Python resources in a directory, including sourceless bytecode.
+///
+/// This is like `find_python_resources()` except `.pyc` files outside
+/// `__pycache__` directories, which some distributions ship instead of
+/// `.py` files, are also recognized. Module names are derived from their
+/// paths. Those whose header starts with `magic_number` are emitted as
+/// module bytecode. Those compiled by other Python versions can't be
+/// imported and are emitted as package resources.
+pub fn find_python_resources_with_sourceless_bytecode(
+    root_path: &Path,
+    cache_tag: &str,
+    suffixes: &PythonModuleSuffixes,
+    magic_number: u32,
+) -> PythonResourceIterator {
+    PythonResourceIterator::new(root_path, cache_tag, suffixes, Some(magic_number))
+}
+
+/// Ensure every bytecode file in a `__pycache__` directory is for `cache_tag`.
+///
+/// The resource scanner silently ignores bytecode for other interpreters.
+/// When bytecode is all we have, that would silently drop modules.
+fn check_pycache_cache_tags(
+    root_path: &Path,
+    cache_tag: &str,
+    suffixes: &PythonModuleSuffixes,
+) -> Result<()> {
+    for entry in walk_tree_files(root_path) {
+        let path = entry.path();
+
+        if path.parent().and_then(|p| p.file_name()) != Some(OsStr::new("__pycache__")) {
+            continue;
+        }
+
+        let file_name = path.file_name().unwrap().to_string_lossy();
+
+        if let Some((_, tag, _)) = parse_pycache_file_name(&file_name, suffixes) {
+            if tag != cache_tag {
+                return Err(anyhow!(
+                    "{} was compiled for {} but the target is {}",
+                    path.display(),
+                    tag,
+                    cache_tag
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Find module bytecode in the `__pycache__` directories of a tree.
+///
+/// Files named `<module>.<cache tag>[.opt-N].pyc` are turned into
+/// `PythonModuleBytecode` at the optimization level in their name. Module
+/// names are derived from the directories holding `__pycache__`, whether or
+/// not they have an `__init__` module, so modules of namespace packages are
+/// found. Bytecode compiled for a cache tag other than `cache_tag` is an
+/// error.
+pub fn find_pycache_bytecode(
+    root_path: &Path,
+    cache_tag: &str,
+    suffixes: &PythonModuleSuffixes,
+) -> Result<Vec<PythonModuleBytecode>> {
+    check_pycache_cache_tags(root_path, cache_tag, suffixes)?;
+
+    let mut modules = Vec::new();
+
+    for resource in find_python_resources(root_path, cache_tag, suffixes) {
+        if let PythonResource::ModuleBytecode(module) = resource? {
+            modules.push(module);
+        }
+    }
+
+    Ok(modules)
+}
+
+/// Find Python resources in a directory of bytecode-only distributions.
+///
+/// This is like `find_python_resources()` except it is meant for trees
+/// shipping modules as `.pyc` files in `__pycache__` directories without the
+/// corresponding `.py` files. Module names, cache tags and optimization
+/// levels are reconstructed from bytecode file names. Every bytecode file
+/// must be compiled for `cache_tag` and have a header starting with
+/// `magic_number`, otherwise an error is returned. Bytecode at every
+/// optimization level is retained.
+///
+/// Packages implied by the directory layout but lacking an `__init__` module
+/// are synthesized as empty package modules so the modules within them are
+/// importable.
+pub fn find_bytecode_only_resources(
+    root_path: &Path,
+    cache_tag: &str,
+    suffixes: &PythonModuleSuffixes,
+    magic_number: u32,
+) -> Result<Vec<PythonResource>> {
+    check_pycache_cache_tags(root_path, cache_tag, suffixes)?;
+
+    let mut resources = Vec::new();
+    let mut packages = BTreeSet::new();
+    let mut parent_packages = BTreeSet::new();
+
+    for resource in find_python_resources(root_path, cache_tag, suffixes) {
+        let resource = resource?;
+
+        let module = match &resource {
+            PythonResource::ModuleBytecode(module) => {
+                if let DataLocation::Path { path, .. } = module.bytecode_location() {
+                    let data = std::fs::read(path)
+                        .with_context(|| format!("reading {}", path.display()))?;
+
+                    if data.len() < 4 {
+                        return Err(anyhow!(
+                            "{} is too short to be a bytecode file",
+                            path.display()
+                        ));
+                    }
+
+                    let magic = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+                    if magic != magic_number {
+                        return Err(anyhow!(
+                            "{} has bytecode magic number {:#x} but the target uses {:#x}",
+                            path.display(),
+                            magic,
+                            magic_number
+                        ));
+                    }
+                }
+
+                Some((&module.name, module.is_package))
+            }
+            PythonResource::ModuleSource(module) => Some((&module.name, module.is_package)),
+            PythonResource::ExtensionModuleDynamicLibrary(module) => {
+                Some((&module.name, module.is_package))
+            }
+            _ => None,
+        };
+
+        if let Some((name, is_package)) = module {
+            if is_package {
+                packages.insert(name.clone());
+            }
+            parent_packages.extend(packages_from_module_name(name));
+        }
+
+        resources.push(resource);
+    }
+
+    for package in parent_packages.difference(&packages) {
+        resources.push(PythonResource::ModuleSource(PythonModuleSource {
+            name: package.clone(),
+            source: DataLocation::Memory(vec![]),
+            is_package: true,
+            cache_tag: cache_tag.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        }));
+    }
+
+    Ok(resources)
 }
 
 #[cfg(test)]
@@ -587,7 +973,7 @@ mod tests {
 
         write(acme_a_path.join("foo.py"), "# acme.foo")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 4);
 
@@ -595,7 +981,7 @@ mod tests {
             resources[0],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "acme".to_string(),
-                source: DataLocation::Path(acme_path.join("__init__.py")),
+                source: DataLocation::from_path(acme_path.join("__init__.py")),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -606,7 +992,7 @@ mod tests {
             resources[1],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "acme.a".to_string(),
-                source: DataLocation::Path(acme_a_path.join("__init__.py")),
+                source: DataLocation::from_path(acme_a_path.join("__init__.py")),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -617,7 +1003,7 @@ mod tests {
             resources[2],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "acme.a.foo".to_string(),
-                source: DataLocation::Path(acme_a_path.join("foo.py")),
+                source: DataLocation::from_path(acme_a_path.join("foo.py")),
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -628,7 +1014,7 @@ mod tests {
             resources[3],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "acme.bar".to_string(),
-                source: DataLocation::Path(acme_bar_path.join("__init__.py")),
+                source: DataLocation::from_path(acme_bar_path.join("__init__.py")),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -726,7 +1112,7 @@ mod tests {
         write(acme_bar_pycache_path.join("foo.cpython-38.opt-1.pyc"), "")?;
         write(acme_bar_pycache_path.join("foo.cpython-38.opt-2.pyc"), "")?;
 
-        let resources = PythonResourceIterator::new(tp, "cpython-38", &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, "cpython-38", &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 18);
 
@@ -909,7 +1295,7 @@ mod tests {
         write(acme_path.join("__init__.py"), "")?;
         write(acme_path.join("bar.py"), "")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 2);
 
@@ -917,7 +1303,7 @@ mod tests {
             resources[0],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "acme".to_string(),
-                source: DataLocation::Path(acme_path.join("__init__.py")),
+                source: DataLocation::from_path(acme_path.join("__init__.py")),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -928,7 +1314,7 @@ mod tests {
             resources[1],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "acme.bar".to_string(),
-                source: DataLocation::Path(acme_path.join("bar.py")),
+                source: DataLocation::from_path(acme_path.join("bar.py")),
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -974,8 +1360,8 @@ mod tests {
             ],
         };
 
-        let resources =
-            PythonResourceIterator::new(tp, "cpython-37", &suffixes).collect::<Result<Vec<_>>>()?;
+        let resources = PythonResourceIterator::new(tp, "cpython-37", &suffixes, None)
+            .collect::<Result<Vec<_>>>()?;
 
         assert_eq!(resources.len(), 5);
 
@@ -985,7 +1371,7 @@ mod tests {
                 name: "_cffi_backend".to_string(),
                 init_fn: Some("PyInit__cffi_backend".to_string()),
                 extension_file_suffix: ".cp37-win_amd64.pyd".to_string(),
-                shared_library: Some(DataLocation::Path(cffi_path)),
+                shared_library: Some(DataLocation::from_path(cffi_path)),
                 object_file_data: vec![],
                 is_package: false,
                 link_libraries: vec![],
@@ -1004,7 +1390,7 @@ mod tests {
                 name: "bar".to_string(),
                 init_fn: Some("PyInit_bar".to_string()),
                 extension_file_suffix: ".so".to_string(),
-                shared_library: Some(DataLocation::Path(so_path)),
+                shared_library: Some(DataLocation::from_path(so_path)),
                 object_file_data: vec![],
                 is_package: false,
                 link_libraries: vec![],
@@ -1023,7 +1409,7 @@ mod tests {
                 name: "foo".to_string(),
                 init_fn: Some("PyInit_foo".to_string()),
                 extension_file_suffix: ".pyd".to_string(),
-                shared_library: Some(DataLocation::Path(pyd_path)),
+                shared_library: Some(DataLocation::from_path(pyd_path)),
                 object_file_data: vec![],
                 is_package: false,
                 link_libraries: vec![],
@@ -1042,7 +1428,7 @@ mod tests {
                 name: "markupsafe._speedups".to_string(),
                 init_fn: Some("PyInit__speedups".to_string()),
                 extension_file_suffix: ".cpython-37m-x86_64-linux-gnu.so".to_string(),
-                shared_library: Some(DataLocation::Path(markupsafe_speedups_path)),
+                shared_library: Some(DataLocation::from_path(markupsafe_speedups_path)),
                 object_file_data: vec![],
                 is_package: false,
                 link_libraries: vec![],
@@ -1061,7 +1447,7 @@ mod tests {
                 name: "zstd".to_string(),
                 init_fn: Some("PyInit_zstd".to_string()),
                 extension_file_suffix: ".cpython-37m-x86_64-linux-gnu.so".to_string(),
-                shared_library: Some(DataLocation::Path(zstd_path)),
+                shared_library: Some(DataLocation::from_path(zstd_path)),
                 object_file_data: vec![],
                 is_package: false,
                 link_libraries: vec![],
@@ -1088,14 +1474,14 @@ mod tests {
         let egg_path = tp.join("foo-1.0-py3.7.egg");
         write(&egg_path, "")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 1);
 
         assert_eq!(
             resources[0],
             PythonResource::EggFile(PythonEggFile {
-                data: DataLocation::Path(egg_path)
+                data: DataLocation::from_path(egg_path)
             })
         );
 
@@ -1120,7 +1506,7 @@ mod tests {
         write(package_path.join("__init__.py"), "")?;
         write(package_path.join("bar.py"), "")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 2);
 
@@ -1128,7 +1514,7 @@ mod tests {
             resources[0],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "foo".to_string(),
-                source: DataLocation::Path(package_path.join("__init__.py")),
+                source: DataLocation::from_path(package_path.join("__init__.py")),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -1139,7 +1525,7 @@ mod tests {
             resources[1],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "foo.bar".to_string(),
-                source: DataLocation::Path(package_path.join("bar.py")),
+                source: DataLocation::from_path(package_path.join("bar.py")),
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -1160,14 +1546,14 @@ mod tests {
         let pth_path = tp.join("foo.pth");
         write(&pth_path, "")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 1);
 
         assert_eq!(
             resources[0],
             PythonResource::PathExtension(PythonPathExtension {
-                data: DataLocation::Path(pth_path)
+                data: DataLocation::from_path(pth_path)
             })
         );
 
@@ -1183,7 +1569,7 @@ mod tests {
         let resource_path = tp.join("resource.txt");
         write(&resource_path, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Vec<_>>();
         assert!(resources.is_empty());
 
@@ -1203,7 +1589,7 @@ mod tests {
         let resource_path = resource_dir.join("resource.txt");
         write(&resource_path, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 1);
 
@@ -1211,7 +1597,7 @@ mod tests {
             resources[0],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "foo".to_string(),
-                source: DataLocation::Path(tp.join("foo.py")),
+                source: DataLocation::from_path(tp.join("foo.py")),
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -1236,7 +1622,7 @@ mod tests {
         let resource_path = package_dir.join("resource.txt");
         write(&resource_path, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
 
         assert_eq!(resources.len(), 2);
@@ -1244,7 +1630,7 @@ mod tests {
             resources[0],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "foo".to_string(),
-                source: DataLocation::Path(module_path),
+                source: DataLocation::from_path(module_path),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -1256,7 +1642,7 @@ mod tests {
             PythonResource::Resource(PythonPackageResource {
                 leaf_package: "foo".to_string(),
                 relative_name: "resource.txt".to_string(),
-                data: DataLocation::Path(resource_path),
+                data: DataLocation::from_path(resource_path),
                 is_stdlib: false,
                 is_test: false,
             })
@@ -1280,7 +1666,7 @@ mod tests {
         let resource_path = subdir.join("resource.txt");
         write(&resource_path, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
 
         assert_eq!(resources.len(), 2);
@@ -1288,7 +1674,7 @@ mod tests {
             resources[0],
             PythonResource::ModuleSource(PythonModuleSource {
                 name: "foo".to_string(),
-                source: DataLocation::Path(module_path),
+                source: DataLocation::from_path(module_path),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -1300,7 +1686,7 @@ mod tests {
             PythonResource::Resource(PythonPackageResource {
                 leaf_package: "foo".to_string(),
                 relative_name: "resources/resource.txt".to_string(),
-                data: DataLocation::Path(resource_path),
+                data: DataLocation::from_path(resource_path),
                 is_stdlib: false,
                 is_test: false,
             })
@@ -1320,7 +1706,7 @@ mod tests {
         let resource = dist_path.join("file.txt");
         write(&resource, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert!(resources.is_empty());
 
@@ -1340,7 +1726,7 @@ mod tests {
         let resource = dist_path.join("file.txt");
         write(&resource, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert!(resources.is_empty());
 
@@ -1360,7 +1746,7 @@ mod tests {
         let resource = dist_path.join("file.txt");
         write(&resource, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert!(resources.is_empty());
 
@@ -1385,7 +1771,7 @@ mod tests {
         let subdir_resource_path = subdir.join("sub.txt");
         write(&subdir_resource_path, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 3);
 
@@ -1396,7 +1782,7 @@ mod tests {
                 package: "black".to_string(),
                 version: "1.2.3".to_string(),
                 name: "METADATA".to_string(),
-                data: DataLocation::Path(metadata_path),
+                data: DataLocation::from_path(metadata_path),
             })
         );
         assert_eq!(
@@ -1406,7 +1792,7 @@ mod tests {
                 package: "black".to_string(),
                 version: "1.2.3".to_string(),
                 name: "file.txt".to_string(),
-                data: DataLocation::Path(resource_path),
+                data: DataLocation::from_path(resource_path),
             })
         );
         assert_eq!(
@@ -1416,7 +1802,64 @@ mod tests {
                 package: "black".to_string(),
                 version: "1.2.3".to_string(),
                 name: "subdir/sub.txt".to_string(),
-                data: DataLocation::Path(subdir_resource_path),
+                data: DataLocation::from_path(subdir_resource_path),
+            })
+        );
+
+        Ok(())
+    }
+
+    /// Files of a distribution without Python code are package resources.
+    #[test]
+    fn test_data_only_distribution() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let tp = td.path();
+
+        let package_dir = tp.join("certbundle");
+        create_dir_all(package_dir.join("extra"))?;
+        write(&package_dir.join("cacert.pem"), "cert")?;
+        write(&package_dir.join("extra").join("more.pem"), "more")?;
+
+        // Not installed by any distribution, so not a package.
+        create_dir_all(tp.join("stray"))?;
+        write(&tp.join("stray").join("data.txt"), "stray")?;
+
+        let dist_path = tp.join("certifi_bundle-1.0.dist-info");
+        create_dir_all(&dist_path)?;
+        write(
+            &dist_path.join("METADATA"),
+            "Name: certifi-bundle\nVersion: 1.0\n",
+        )?;
+        write(
+            &dist_path.join("RECORD"),
+            concat!(
+                "certbundle/cacert.pem,,\n",
+                "certbundle/extra/more.pem,,\n",
+                "certifi_bundle-1.0.dist-info/METADATA,,\n",
+                "certifi_bundle-1.0.dist-info/RECORD,,\n",
+            ),
+        )?;
+
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
+            .collect::<Result<Vec<_>>>()?;
+
+        assert_eq!(
+            resources.iter().map(|r| r.full_name()).collect::<Vec<_>>(),
+            vec![
+                "certifi-bundle:METADATA",
+                "certifi-bundle:RECORD",
+                "certbundle.cacert.pem",
+                "certbundle.extra/more.pem",
+            ]
+        );
+        assert_eq!(
+            resources[3],
+            PythonResource::Resource(PythonPackageResource {
+                leaf_package: "certbundle".to_string(),
+                relative_name: "extra/more.pem".to_string(),
+                data: DataLocation::from_path(package_dir.join("extra").join("more.pem")),
+                is_stdlib: false,
+                is_test: false,
             })
         );
 
@@ -1441,7 +1884,7 @@ mod tests {
         let subdir_resource_path = subdir.join("sub.txt");
         write(&subdir_resource_path, "content")?;
 
-        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
             .collect::<Result<Vec<_>>>()?;
         assert_eq!(resources.len(), 3);
 
@@ -1452,7 +1895,7 @@ mod tests {
                 package: "black".to_string(),
                 version: "1.2.3".to_string(),
                 name: "PKG-INFO".to_string(),
-                data: DataLocation::Path(metadata_path),
+                data: DataLocation::from_path(metadata_path),
             })
         );
         assert_eq!(
@@ -1462,7 +1905,7 @@ mod tests {
                 package: "black".to_string(),
                 version: "1.2.3".to_string(),
                 name: "file.txt".to_string(),
-                data: DataLocation::Path(resource_path),
+                data: DataLocation::from_path(resource_path),
             })
         );
         assert_eq!(
@@ -1472,10 +1915,270 @@ mod tests {
                 package: "black".to_string(),
                 version: "1.2.3".to_string(),
                 name: "subdir/sub.txt".to_string(),
-                data: DataLocation::Path(subdir_resource_path),
+                data: DataLocation::from_path(subdir_resource_path),
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replaced_distribution() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let tp = td.path();
+
+        let old_dist = tp.join("Foo-1.9.dist-info");
+        create_dir_all(&old_dist)?;
+        write(old_dist.join("METADATA"), "Name: Foo\nVersion: 1.9\n")?;
+        write(
+            old_dist.join("RECORD"),
+            "foo.py,,\nfoo_compat.py,,\nFoo-1.9.dist-info/METADATA,,\nFoo-1.9.dist-info/RECORD,,\n",
+        )?;
+
+        let new_dist = tp.join("foo-1.10.dist-info");
+        create_dir_all(&new_dist)?;
+        write(new_dist.join("METADATA"), "Name: foo\nVersion: 1.10\n")?;
+        write(
+            new_dist.join("RECORD"),
+            "foo/__init__.py,,\nfoo-1.10.dist-info/METADATA,,\nfoo-1.10.dist-info/RECORD,,\n",
+        )?;
+
+        let package_dir = tp.join("foo");
+        create_dir_all(&package_dir)?;
+        write(package_dir.join("__init__.py"), "")?;
+        write(tp.join("foo.py"), "")?;
+        write(tp.join("foo_compat.py"), "")?;
+        write(tp.join("bar.py"), "")?;
+
+        let replaced = find_replaced_distribution_files(tp);
+        assert_eq!(replaced.len(), 4);
+        assert!(replaced.contains(&tp.join("foo.py")));
+        assert!(replaced.contains(&tp.join("foo_compat.py")));
+        assert!(replaced.contains(&old_dist.join("METADATA")));
+        assert!(replaced.contains(&old_dist.join("RECORD")));
+
+        let resources = PythonResourceIterator::new(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, None)
+            .collect::<Result<Vec<_>>>()?;
+
+        let names = resources
+            .iter()
+            .map(|r| match r {
+                PythonResource::ModuleSource(m) => format!("source:{}", m.name),
+                PythonResource::DistributionResource(r) => {
+                    format!("dist:{}-{}/{}", r.package, r.version, r.name)
+                }
+                _ => panic!("unexpected resource"),
             })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "source:bar",
+                "source:foo",
+                "dist:foo-1.10/METADATA",
+                "dist:foo-1.10/RECORD",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_replaced_distribution_pre_release() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let tp = td.path();
+
+        // Final releases are newer than their release candidates.
+        for (version, module) in &[("2.0", "foo.py"), ("2.0rc1", "foo_rc.py")] {
+            let dist = tp.join(format!("foo-{}.dist-info", version));
+            create_dir_all(&dist)?;
+            write(
+                dist.join("METADATA"),
+                format!("Name: foo\nVersion: {}\n", version),
+            )?;
+            write(dist.join("RECORD"), format!("{},,\n", module))?;
+            write(tp.join(module), "")?;
+        }
+
+        let replaced = find_replaced_distribution_files(tp);
+        assert!(replaced.contains(&tp.join("foo_rc.py")));
+        assert!(!replaced.contains(&tp.join("foo.py")));
+        assert!(replaced.contains(&tp.join("foo-2.0rc1.dist-info").join("METADATA")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_only_resources() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let tp = td.path();
+
+        let magic = 0x0a0d_0d42u32;
+        let mut pyc = magic.to_le_bytes().to_vec();
+        pyc.extend(&[0; 12]);
+        pyc.extend(b"code");
+
+        // acme has no __init__ at all. acme.a only has an optimized __init__.
+        let acme_pycache = tp.join("acme").join("__pycache__");
+        let acme_a_pycache = tp.join("acme").join("a").join("__pycache__");
+        create_dir_all(&acme_pycache)?;
+        create_dir_all(&acme_a_pycache)?;
+
+        write(acme_pycache.join("foo.cpython-37.pyc"), &pyc)?;
+        write(acme_pycache.join("foo.cpython-37.opt-1.pyc"), &pyc)?;
+        write(acme_pycache.join("foo.cpython-37.opt-2.pyc"), &pyc)?;
+        write(acme_a_pycache.join("__init__.cpython-37.opt-2.pyc"), &pyc)?;
+        write(acme_a_pycache.join("bar.cpython-37.pyc"), &pyc)?;
+        write(tp.join("acme").join("data.txt"), "data")?;
+
+        let resources =
+            find_bytecode_only_resources(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, magic)?;
+
+        let names = resources
+            .iter()
+            .map(|r| match r {
+                PythonResource::ModuleBytecode(m) => format!(
+                    "bytecode:{}:{}:{}",
+                    m.name,
+                    i32::from(m.optimize_level),
+                    m.is_package
+                ),
+                PythonResource::ModuleSource(m) => {
+                    assert_eq!(m.source.resolve().unwrap(), b"");
+                    format!("source:{}:{}", m.name, m.is_package)
+                }
+                PythonResource::Resource(r) => {
+                    format!("resource:{}:{}", r.leaf_package, r.relative_name)
+                }
+                _ => panic!("unexpected resource"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "bytecode:acme.foo:1:false",
+                "bytecode:acme.foo:2:false",
+                "bytecode:acme.foo:0:false",
+                "bytecode:acme.a:2:true",
+                "bytecode:acme.a.bar:0:false",
+                "resource:acme:data.txt",
+                "source:acme:true",
+            ]
+        );
+
+        let err = find_bytecode_only_resources(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, magic + 1)
+            .unwrap_err();
+        assert!(err.to_string().contains("magic number"));
+
+        write(acme_pycache.join("foo.cpython-38.pyc"), &pyc)?;
+        let err = find_bytecode_only_resources(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES, magic)
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("was compiled for cpython-38 but the target is cpython-37"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pycache_bytecode() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let tp = td.path();
+
+        // ns and ns.sub are namespace packages: neither has an __init__.
+        let ns_pycache = tp.join("ns").join("__pycache__");
+        let sub_pycache = tp.join("ns").join("sub").join("__pycache__");
+        let pkg_pycache = tp.join("pkg").join("__pycache__");
+        create_dir_all(&ns_pycache)?;
+        create_dir_all(&sub_pycache)?;
+        create_dir_all(&pkg_pycache)?;
+
+        write(ns_pycache.join("foo.cpython-37.pyc"), "")?;
+        write(sub_pycache.join("bar.cpython-37.opt-2.pyc"), "")?;
+        write(pkg_pycache.join("__init__.cpython-37.opt-1.pyc"), "")?;
+        write(pkg_pycache.join("notes.txt"), "")?;
+
+        let modules = find_pycache_bytecode(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)?;
+        assert_eq!(
+            modules
+                .iter()
+                .map(|m| format!(
+                    "{}:{}:{}",
+                    m.name,
+                    i32::from(m.optimize_level),
+                    m.is_package
+                ))
+                .collect::<Vec<_>>(),
+            vec!["ns.foo:0:false", "ns.sub.bar:2:false", "pkg:1:true"]
+        );
+
+        write(sub_pycache.join("bar.cpython-38.pyc"), "")?;
+        let err = find_pycache_bytecode(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("was compiled for cpython-38 but the target is cpython-37"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sourceless_bytecode() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let tp = td.path();
+
+        let magic = 0x0a0d_0d42u32;
+        let mut pyc = magic.to_le_bytes().to_vec();
+        pyc.extend(&[0; 12]);
+        pyc.extend(b"code");
+
+        let mut python2_pyc = b"\x03\xf3\r\n".to_vec();
+        python2_pyc.extend(&[0; 4]);
+
+        let acme_path = tp.join("acme");
+        create_dir_all(&acme_path)?;
+
+        write(acme_path.join("__init__.pyc"), &pyc)?;
+        write(acme_path.join("foo.pyc"), &pyc)?;
+        write(acme_path.join("old.pyc"), &python2_pyc)?;
+        write(acme_path.join("data.txt"), "data")?;
+        write(tp.join("top.pyc"), &pyc)?;
+
+        let describe = |r: &PythonResource| match r {
+            PythonResource::ModuleBytecode(m) => {
+                assert_eq!(m.optimize_level, BytecodeOptimizationLevel::Zero);
+                assert_eq!(m.resolve_bytecode().unwrap(), b"code");
+                format!("bytecode:{}:{}", m.name, m.is_package)
+            }
+            PythonResource::Resource(r) => {
+                format!("resource:{}:{}", r.leaf_package, r.relative_name)
+            }
+            _ => panic!("unexpected resource"),
+        };
+
+        let resources = find_python_resources_with_sourceless_bytecode(
+            tp,
+            DEFAULT_CACHE_TAG,
+            &DEFAULT_SUFFIXES,
+            magic,
+        )
+        .collect::<Result<Vec<_>>>()?;
+        assert_eq!(
+            resources.iter().map(describe).collect::<Vec<_>>(),
+            vec![
+                "bytecode:acme:true",
+                "bytecode:acme.foo:false",
+                "bytecode:top:false",
+                "resource:acme:data.txt",
+                "resource:acme:old.pyc",
+            ]
         );
 
+        // Sourceless bytecode isn't looked for by default. Without it, acme
+        // isn't a package, so its data isn't a resource either.
+        let resources = find_python_resources(tp, DEFAULT_CACHE_TAG, &DEFAULT_SUFFIXES)
+            .collect::<Result<Vec<_>>>()?;
+        assert!(resources.is_empty());
+
         Ok(())
     }
 }
@@ -0,0 +1,389 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Generate resource filter files.
+
+Filter files list the names of resources to keep, one per line. Lines
+beginning with `#` are comments. This module computes what a filter file
+should contain, either from a usage log of an instrumented run (see the
+`resource_usage` module) or from entry modules and the modules they import.
+
+Names are resource names, so a module's source and bytecode and an
+extension module are all kept by a single line. Parent packages and shared
+library dependencies of kept resources are always kept, as is a set of
+protected names the binary can't run without. Each name is preceded by a
+comment recording why it is present.
+*/
+
+use {
+    crate::module_util::packages_from_module_name,
+    crate::python_source::find_imports,
+    crate::resource::DataLocation,
+    crate::resource_collection::{PrePackagedResource, PythonModuleBytecodeProvider},
+    crate::resource_usage::UsageLog,
+    anyhow::{anyhow, Context, Result},
+    std::collections::{BTreeMap, BTreeSet, VecDeque},
+    std::fmt::{Display, Formatter},
+    std::io::Write,
+    std::path::Path,
+};
+
+/// Why a name is present in a filter file.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Provenance {
+    /// The name is protected and always kept.
+    Protected,
+
+    /// The name was recorded by a usage log.
+    Logged,
+
+    /// The name is an entry module.
+    Entry,
+
+    /// The name is imported by a module.
+    Imported(String),
+
+    /// The name is a parent package of a resource.
+    ParentPackage(String),
+
+    /// The name is a shared library required by a resource.
+    SharedLibrary(String),
+}
+
+impl Display for Provenance {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Self::Protected => write!(f, "protected"),
+            Self::Logged => write!(f, "recorded by usage log"),
+            Self::Entry => write!(f, "entry module"),
+            Self::Imported(by) => write!(f, "imported by {}", by),
+            Self::ParentPackage(of) => write!(f, "parent package of {}", of),
+            Self::SharedLibrary(of) => write!(f, "shared library required by {}", of),
+        }
+    }
+}
+
+/// The content of a resource filter file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FilterFile {
+    /// Names to keep and why they are kept.
+    pub names: BTreeMap<String, Provenance>,
+}
+
+/// Obtain the source backing a module resource, if available.
+fn module_source(resource: &PrePackagedResource) -> Option<&DataLocation> {
+    if let Some(location) = &resource.in_memory_source {
+        return Some(location);
+    }
+
+    if let Some((_, location)) = &resource.relative_path_module_source {
+        return Some(location);
+    }
+
+    let providers = [
+        resource.in_memory_bytecode.as_ref(),
+        resource.in_memory_bytecode_opt1.as_ref(),
+        resource.in_memory_bytecode_opt2.as_ref(),
+        resource.relative_path_bytecode.as_ref().map(|(_, _, p)| p),
+        resource
+            .relative_path_bytecode_opt1
+            .as_ref()
+            .map(|(_, _, p)| p),
+        resource
+            .relative_path_bytecode_opt2
+            .as_ref()
+            .map(|(_, _, p)| p),
+    ];
+
+    providers
+        .iter()
+        .copied()
+        .flatten()
+        .find_map(|provider| match provider {
+            PythonModuleBytecodeProvider::FromSource(location) => Some(location),
+            PythonModuleBytecodeProvider::Provided(_) => None,
+        })
+}
+
+impl FilterFile {
+    /// Compute a filter file from a usage log.
+    ///
+    /// Logged modules and packages whose resources were accessed are kept.
+    /// Log entries not matching a resource are ignored.
+    pub fn from_usage_log(
+        resources: &BTreeMap<String, PrePackagedResource>,
+        log: &UsageLog,
+        protected: &BTreeSet<String>,
+    ) -> Result<Self> {
+        let mut seeds = log.modules.iter().cloned().collect::<Vec<_>>();
+        seeds.extend(log.resources.iter().map(|(package, _)| package.clone()));
+
+        Self::resolve(
+            resources,
+            protected,
+            seeds
+                .into_iter()
+                .filter(|name| resources.contains_key(name))
+                .map(|name| (name, Provenance::Logged)),
+            false,
+        )
+    }
+
+    /// Compute a filter file from entry modules and what they import.
+    ///
+    /// Imports are found by scanning module source, so modules only
+    /// available as bytecode don't contribute imports. Imports of modules
+    /// not among resources are ignored.
+    pub fn from_entry_modules(
+        resources: &BTreeMap<String, PrePackagedResource>,
+        entries: &[&str],
+        protected: &BTreeSet<String>,
+    ) -> Result<Self> {
+        for name in entries {
+            if !resources.contains_key(*name) {
+                return Err(anyhow!("entry module {} not found", name));
+            }
+        }
+
+        Self::resolve(
+            resources,
+            protected,
+            entries
+                .iter()
+                .map(|name| (name.to_string(), Provenance::Entry)),
+            true,
+        )
+    }
+
+    fn resolve(
+        resources: &BTreeMap<String, PrePackagedResource>,
+        protected: &BTreeSet<String>,
+        seeds: impl Iterator<Item = (String, Provenance)>,
+        follow_imports: bool,
+    ) -> Result<Self> {
+        let mut res = Self::default();
+
+        // Breadth-first so each name is attributed to its closest origin.
+        let mut pending = protected
+            .iter()
+            .map(|name| (name.clone(), Provenance::Protected))
+            .chain(seeds)
+            .collect::<VecDeque<_>>();
+
+        while let Some((name, provenance)) = pending.pop_front() {
+            if res.names.contains_key(&name) {
+                continue;
+            }
+            res.names.insert(name.clone(), provenance);
+
+            let resource = match resources.get(&name) {
+                Some(resource) => resource,
+                None => continue,
+            };
+
+            for package in packages_from_module_name(&name) {
+                if resources.contains_key(&package) {
+                    pending.push_back((package, Provenance::ParentPackage(name.clone())));
+                }
+            }
+
+            if let Some(names) = &resource.shared_library_dependency_names {
+                for library in names {
+                    if resources.contains_key(library) {
+                        pending
+                            .push_back((library.clone(), Provenance::SharedLibrary(name.clone())));
+                    }
+                }
+            }
+
+            if follow_imports {
+                if let Some(location) = module_source(resource) {
+                    let source = location
+                        .resolve()
+                        .with_context(|| format!("reading source of {}", name))?;
+
+                    for imported in find_imports(&source, &name, resource.is_package) {
+                        if resources.contains_key(&imported) {
+                            pending.push_back((imported, Provenance::Imported(name.clone())));
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Write the filter file.
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writeln!(writer, "# Resource filter file.")?;
+        writeln!(
+            writer,
+            "# Each name is preceded by a comment recording why it is kept."
+        )?;
+
+        for (name, provenance) in &self.names {
+            writeln!(writer, "# {}", provenance)?;
+            writeln!(writer, "{}", name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the filter file to a path.
+    pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        let mut fh = std::fs::File::create(path)
+            .with_context(|| format!("creating filter file {}", path.display()))?;
+
+        self.write(&mut fh)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, python_packed_resources::data::ResourceFlavor};
+
+    fn module(name: &str, is_package: bool, source: &str) -> (String, PrePackagedResource) {
+        (
+            name.to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: name.to_string(),
+                is_package,
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
+                    DataLocation::Memory(source.as_bytes().to_vec()),
+                )),
+                ..PrePackagedResource::default()
+            },
+        )
+    }
+
+    fn resources() -> BTreeMap<String, PrePackagedResource> {
+        let mut res = vec![
+            module("app", true, "from . import cli\n"),
+            module(
+                "app.cli",
+                false,
+                "import os, json as j\nfrom .util import (helper,\n    other)\n",
+            ),
+            module("app.util", false, "from ..ext import x  # out of tree\n"),
+            module("app.util.helper", false, ""),
+            module("app.unused", false, "import sqlite3\n"),
+            module("json", true, "from .decoder import JSONDecoder\n"),
+            module("json.decoder", false, ""),
+            module("json.tool", false, ""),
+            module("os", false, ""),
+            module("encodings", true, ""),
+            module("sqlite3", true, "from _sqlite3 import *\n"),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        res.insert(
+            "_sqlite3".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: "_sqlite3".to_string(),
+                shared_library_dependency_names: Some(vec!["libsqlite3".to_string()]),
+                ..PrePackagedResource::default()
+            },
+        );
+        res.insert(
+            "libsqlite3".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "libsqlite3".to_string(),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        res
+    }
+
+    fn protected() -> BTreeSet<String> {
+        vec!["encodings".to_string()].into_iter().collect()
+    }
+
+    #[test]
+    fn test_from_entry_modules() -> Result<()> {
+        let resources = resources();
+        let filter = FilterFile::from_entry_modules(&resources, &["app.cli"], &protected())?;
+
+        assert_eq!(
+            filter.names.keys().map(|x| x.as_str()).collect::<Vec<_>>(),
+            vec![
+                "app",
+                "app.cli",
+                "app.util",
+                "app.util.helper",
+                "encodings",
+                "json",
+                "json.decoder",
+                "os"
+            ]
+        );
+        assert_eq!(filter.names["app.cli"], Provenance::Entry);
+        assert_eq!(
+            filter.names["app"],
+            Provenance::ParentPackage("app.cli".to_string())
+        );
+        assert_eq!(
+            filter.names["app.util.helper"],
+            Provenance::Imported("app.cli".to_string())
+        );
+        assert_eq!(
+            filter.names["json.decoder"],
+            Provenance::Imported("json".to_string())
+        );
+        assert_eq!(filter.names["encodings"], Provenance::Protected);
+
+        assert!(FilterFile::from_entry_modules(&resources, &["missing"], &protected()).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_usage_log() -> Result<()> {
+        let resources = resources();
+        let log = UsageLog::parse("sqlite3\n_sqlite3\nmissing\njson:data.txt\n");
+        let filter = FilterFile::from_usage_log(&resources, &log, &protected())?;
+
+        assert_eq!(
+            filter.names.keys().map(|x| x.as_str()).collect::<Vec<_>>(),
+            vec!["_sqlite3", "encodings", "json", "libsqlite3", "sqlite3"]
+        );
+        assert_eq!(filter.names["_sqlite3"], Provenance::Logged);
+        assert_eq!(
+            filter.names["libsqlite3"],
+            Provenance::SharedLibrary("_sqlite3".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write() -> Result<()> {
+        let resources = resources();
+        let log = UsageLog::parse("_sqlite3\n");
+        let filter = FilterFile::from_usage_log(&resources, &log, &protected())?;
+
+        let mut data = Vec::new();
+        filter.write(&mut data)?;
+
+        assert_eq!(
+            String::from_utf8(data)?,
+            "# Resource filter file.\n\
+             # Each name is preceded by a comment recording why it is kept.\n\
+             # recorded by usage log\n\
+             _sqlite3\n\
+             # protected\n\
+             encodings\n\
+             # shared library required by _sqlite3\n\
+             libsqlite3\n"
+        );
+
+        Ok(())
+    }
+}
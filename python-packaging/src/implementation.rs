@@ -0,0 +1,188 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Differences between Python interpreter implementations.
+
+Most packaging primitives are agnostic of the interpreter that will consume
+them. Bytecode cache tags, extension module suffixes and `.pyc` headers
+aren't. This module captures what differs so it isn't assumed elsewhere.
+*/
+
+use {
+    crate::bytecode::{python_version_from_cache_tag, python_version_from_magic_number},
+    anyhow::{anyhow, Result},
+};
+
+/// A Python interpreter implementation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InterpreterImplementation {
+    /// The reference implementation.
+    CPython,
+
+    /// PyPy.
+    PyPy,
+}
+
+impl Default for InterpreterImplementation {
+    fn default() -> Self {
+        Self::CPython
+    }
+}
+
+impl InterpreterImplementation {
+    /// All known implementations.
+    pub fn all() -> impl Iterator<Item = Self> {
+        [Self::CPython, Self::PyPy].iter().copied()
+    }
+
+    /// Resolve an implementation from its `sys.implementation.name`.
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name {
+            "cpython" => Ok(Self::CPython),
+            "pypy" => Ok(Self::PyPy),
+            _ => Err(anyhow!("unsupported Python implementation: {}", name)),
+        }
+    }
+
+    /// Resolve an implementation from a bytecode cache tag.
+    ///
+    /// e.g. `cpython-38` or `pypy38`. Tags not belonging to PyPy are
+    /// assumed to be CPython.
+    pub fn from_cache_tag(cache_tag: &str) -> Self {
+        if cache_tag.starts_with("pypy") {
+            Self::PyPy
+        } else {
+            Self::CPython
+        }
+    }
+
+    /// The `sys.implementation.name` of this implementation.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CPython => "cpython",
+            Self::PyPy => "pypy",
+        }
+    }
+
+    /// Obtain the Python version whose `.pyc` layout bytecode uses.
+    ///
+    /// CPython magic numbers identify the version writing them. PyPy's
+    /// don't, so the version targeted by the bytecode's cache tag is used
+    /// instead. Errors if the version can't be determined.
+    pub fn bytecode_python_version(
+        &self,
+        magic_number: u32,
+        cache_tag: Option<&str>,
+    ) -> Result<String> {
+        match self {
+            Self::CPython => python_version_from_magic_number(magic_number)
+                .map(|version| version.to_string())
+                .ok_or_else(|| {
+                    anyhow!(
+                        "magic number {:#010x} isn't one of a known CPython version",
+                        magic_number
+                    )
+                }),
+            Self::PyPy => cache_tag
+                .and_then(python_version_from_cache_tag)
+                .ok_or_else(|| {
+                    anyhow!(
+                        "Python version of PyPy bytecode with magic number {:#010x} is unknown",
+                        magic_number
+                    )
+                }),
+        }
+    }
+
+    /// Whether an extension module file suffix can be loaded by this implementation.
+    ///
+    /// Suffixes tagged for another implementation, such as
+    /// `.cpython-38-x86_64-linux-gnu.so` for PyPy, can't. Untagged suffixes
+    /// like `.so` can.
+    pub fn is_compatible_extension_suffix(&self, suffix: &str) -> bool {
+        let tag = suffix.trim_start_matches('.');
+
+        let cpython = tag.starts_with("cpython-") || tag.starts_with("cp3");
+        let pypy = tag.starts_with("pypy");
+
+        match self {
+            Self::CPython => !pypy,
+            Self::PyPy => !cpython,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() -> Result<()> {
+        for implementation in InterpreterImplementation::all() {
+            assert_eq!(
+                InterpreterImplementation::from_name(implementation.name())?,
+                implementation
+            );
+        }
+        assert!(InterpreterImplementation::from_name("jython").is_err());
+
+        assert_eq!(
+            InterpreterImplementation::from_cache_tag("cpython-38"),
+            InterpreterImplementation::CPython
+        );
+        assert_eq!(
+            InterpreterImplementation::from_cache_tag("pypy38"),
+            InterpreterImplementation::PyPy
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_python_version() -> Result<()> {
+        let cpython = InterpreterImplementation::CPython;
+        let pypy = InterpreterImplementation::PyPy;
+
+        assert_eq!(cpython.bytecode_python_version(0x0a0d_0d55, None)?, "3.8");
+        assert_eq!(
+            cpython.bytecode_python_version(0x0a0d_0d55, Some("cpython-37"))?,
+            "3.8"
+        );
+        assert!(cpython
+            .bytecode_python_version(0x0a0d_0100, Some("cpython-38"))
+            .is_err());
+
+        assert_eq!(
+            pypy.bytecode_python_version(0x0a0d_0100, Some("pypy36"))?,
+            "3.6"
+        );
+        assert!(pypy.bytecode_python_version(0x0a0d_0d55, None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extension_suffix() {
+        let cpython = InterpreterImplementation::CPython;
+        let pypy = InterpreterImplementation::PyPy;
+
+        for suffix in &[".so", ".pyd", ".abi3.so"] {
+            assert!(cpython.is_compatible_extension_suffix(suffix));
+            assert!(pypy.is_compatible_extension_suffix(suffix));
+        }
+
+        for suffix in &[".cpython-38-x86_64-linux-gnu.so", ".cp38-win_amd64.pyd"] {
+            assert!(cpython.is_compatible_extension_suffix(suffix));
+            assert!(!pypy.is_compatible_extension_suffix(suffix));
+        }
+
+        for suffix in &[
+            ".pypy38-pp73-x86_64-linux-gnu.so",
+            ".pypy38-pp73-win_amd64.pyd",
+        ] {
+            assert!(!cpython.is_compatible_extension_suffix(suffix));
+            assert!(pypy.is_compatible_extension_suffix(suffix));
+        }
+    }
+}
@@ -0,0 +1,341 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Merging of directory trees layered on top of each other.
+
+Layers are ordered from the base to the topmost overlay. A file in a
+layer replaces the file at the same relative path in earlier layers and
+files only present in a layer are added.
+
+A layer can also remove files of earlier layers with a tombstone: a file
+named after the removed path with `TOMBSTONE_SUFFIX` appended, e.g.
+`foo/bar.py.pyoxidizer-deleted` removes `foo/bar.py`. A tombstone named
+after a directory, e.g. `foo.pyoxidizer-deleted`, removes every file
+beneath it. Tombstones of a layer are applied before its files are
+added, so a layer can replace a directory of earlier layers wholesale.
+The content of tombstones is ignored.
+*/
+
+use {
+    crate::filesystem_scanning::walk_tree_files,
+    anyhow::{anyhow, Context, Result},
+    serde::{Deserialize, Serialize},
+    std::collections::BTreeMap,
+    std::path::{Path, PathBuf},
+};
+
+/// Suffix of file names of tombstones removing files of earlier layers.
+pub const TOMBSTONE_SUFFIX: &str = ".pyoxidizer-deleted";
+
+/// Describes a file of a layer replaced by a file of a later layer.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LayerOverride {
+    /// Path of the file relative to the layer roots.
+    pub path: PathBuf,
+
+    /// Filesystem path of the replaced file.
+    pub base: PathBuf,
+
+    /// Filesystem path of the replacing file.
+    pub overlay: PathBuf,
+}
+
+/// Describes a file of a layer removed by a tombstone of a later layer.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct LayerDeletion {
+    /// Path of the file relative to the layer roots.
+    pub path: PathBuf,
+
+    /// Filesystem path of the removed file.
+    pub base: PathBuf,
+
+    /// Filesystem path of the tombstone removing it.
+    pub tombstone: PathBuf,
+}
+
+/// The result of merging layered directory trees.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LayeredTree {
+    /// Files of the merged tree, keyed by path relative to the layer roots.
+    ///
+    /// Values are the filesystem paths of the files providing them.
+    pub files: BTreeMap<PathBuf, PathBuf>,
+
+    /// Files replaced by later layers, in the order they were replaced.
+    pub overrides: Vec<LayerOverride>,
+
+    /// Files removed by tombstones, in the order they were removed.
+    pub deletions: Vec<LayerDeletion>,
+
+    /// Filesystem paths of tombstones not removing any file.
+    pub unmatched_tombstones: Vec<PathBuf>,
+}
+
+impl LayeredTree {
+    /// Merge directory trees, from the base to the topmost overlay.
+    ///
+    /// The result only depends on the order of layers and their content.
+    /// It is an error for a path to be a file in one layer and a directory
+    /// in another unless a tombstone removes the earlier one first.
+    pub fn from_layers(layers: &[&Path]) -> Result<Self> {
+        let mut res = LayeredTree::default();
+
+        for layer in layers {
+            if !layer.is_dir() {
+                return Err(anyhow!("layer {} is not a directory", layer.display()));
+            }
+
+            let mut tombstones = Vec::new();
+            let mut files = Vec::new();
+
+            for entry in walk_tree_files(layer) {
+                let full_path = entry.path().to_path_buf();
+                let relative_path = full_path.strip_prefix(layer)?.to_path_buf();
+
+                let target = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(TOMBSTONE_SUFFIX))
+                    .filter(|name| !name.is_empty());
+
+                match target {
+                    Some(target) => {
+                        tombstones.push((relative_path.with_file_name(target), full_path));
+                    }
+                    None => files.push((relative_path, full_path)),
+                }
+            }
+
+            for (target, tombstone) in tombstones {
+                res.apply_tombstone(&target, tombstone);
+            }
+
+            for (relative_path, full_path) in files {
+                res.add_file(relative_path, full_path)?;
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Remove files at or beneath a relative path.
+    fn apply_tombstone(&mut self, target: &Path, tombstone: PathBuf) {
+        let removed = self
+            .files
+            .keys()
+            .filter(|path| path.starts_with(target))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if removed.is_empty() {
+            self.unmatched_tombstones.push(tombstone);
+            return;
+        }
+
+        for path in removed {
+            let base = self.files.remove(&path).unwrap();
+
+            self.deletions.push(LayerDeletion {
+                path,
+                base,
+                tombstone: tombstone.clone(),
+            });
+        }
+    }
+
+    /// Add a file, replacing the file at the same path if present.
+    fn add_file(&mut self, relative_path: PathBuf, full_path: PathBuf) -> Result<()> {
+        let conflict = relative_path
+            .ancestors()
+            .skip(1)
+            .find_map(|ancestor| self.files.get(ancestor))
+            .or_else(|| {
+                self.files
+                    .iter()
+                    .find(|(path, _)| path.starts_with(&relative_path) && **path != relative_path)
+                    .map(|(_, existing)| existing)
+            });
+
+        if let Some(existing) = conflict {
+            return Err(anyhow!(
+                "{} conflicts with {} of an earlier layer; one is a file where the other is a directory",
+                full_path.display(),
+                existing.display()
+            ));
+        }
+
+        if let Some(base) = self.files.insert(relative_path.clone(), full_path.clone()) {
+            self.overrides.push(LayerOverride {
+                path: relative_path,
+                base,
+                overlay: full_path,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Write the merged tree to a directory.
+    pub fn materialize(&self, dest_dir: &Path) -> Result<()> {
+        for (relative_path, full_path) in &self.files {
+            let dest_path = dest_dir.join(relative_path);
+
+            if let Some(parent) = dest_path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| format!("creating {}", parent.display()))?;
+            }
+
+            std::fs::copy(full_path, &dest_path).with_context(|| {
+                format!("copying {} to {}", full_path.display(), dest_path.display())
+            })?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_files(root: &Path, files: &[(&str, &str)]) -> Result<()> {
+        for (path, data) in files {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            std::fs::write(path, data)?;
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layered_tree() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let base = td.path().join("base");
+        let overlay = td.path().join("overlay");
+        let top = td.path().join("top");
+
+        write_files(
+            &base,
+            &[
+                ("foo/__init__.py", "base"),
+                ("foo/bar.py", "base"),
+                ("foo/baz.py", "base"),
+                ("foo/data/config.json", "base"),
+                ("legacy/__init__.py", "base"),
+                ("legacy/util.py", "base"),
+            ],
+        )?;
+        write_files(
+            &overlay,
+            &[
+                ("foo/bar.py", "overlay"),
+                ("foo/data/config.json", "overlay"),
+                ("foo/data/extra.json", "overlay"),
+                ("foo/baz.py.pyoxidizer-deleted", ""),
+                ("legacy.pyoxidizer-deleted", ""),
+                ("legacy/__init__.py", "overlay"),
+                ("missing.py.pyoxidizer-deleted", ""),
+            ],
+        )?;
+        write_files(&top, &[("foo/bar.py", "top"), ("qux/__init__.py", "top")])?;
+
+        let tree = LayeredTree::from_layers(&[&base, &overlay, &top])?;
+
+        assert_eq!(
+            tree.files,
+            [
+                ("foo/__init__.py", base.join("foo/__init__.py")),
+                ("foo/bar.py", top.join("foo/bar.py")),
+                ("foo/data/config.json", overlay.join("foo/data/config.json")),
+                ("foo/data/extra.json", overlay.join("foo/data/extra.json")),
+                ("legacy/__init__.py", overlay.join("legacy/__init__.py")),
+                ("qux/__init__.py", top.join("qux/__init__.py")),
+            ]
+            .iter()
+            .map(|(path, full_path)| (PathBuf::from(path), full_path.clone()))
+            .collect::<BTreeMap<_, _>>()
+        );
+        assert_eq!(
+            tree.overrides,
+            vec![
+                LayerOverride {
+                    path: PathBuf::from("foo/bar.py"),
+                    base: base.join("foo/bar.py"),
+                    overlay: overlay.join("foo/bar.py"),
+                },
+                LayerOverride {
+                    path: PathBuf::from("foo/data/config.json"),
+                    base: base.join("foo/data/config.json"),
+                    overlay: overlay.join("foo/data/config.json"),
+                },
+                LayerOverride {
+                    path: PathBuf::from("foo/bar.py"),
+                    base: overlay.join("foo/bar.py"),
+                    overlay: top.join("foo/bar.py"),
+                },
+            ]
+        );
+        assert_eq!(
+            tree.deletions,
+            vec![
+                LayerDeletion {
+                    path: PathBuf::from("foo/baz.py"),
+                    base: base.join("foo/baz.py"),
+                    tombstone: overlay.join("foo/baz.py.pyoxidizer-deleted"),
+                },
+                LayerDeletion {
+                    path: PathBuf::from("legacy/__init__.py"),
+                    base: base.join("legacy/__init__.py"),
+                    tombstone: overlay.join("legacy.pyoxidizer-deleted"),
+                },
+                LayerDeletion {
+                    path: PathBuf::from("legacy/util.py"),
+                    base: base.join("legacy/util.py"),
+                    tombstone: overlay.join("legacy.pyoxidizer-deleted"),
+                },
+            ]
+        );
+        assert_eq!(
+            tree.unmatched_tombstones,
+            vec![overlay.join("missing.py.pyoxidizer-deleted")]
+        );
+
+        // Merging is deterministic.
+        assert_eq!(LayeredTree::from_layers(&[&base, &overlay, &top])?, tree);
+
+        let merged = td.path().join("merged");
+        tree.materialize(&merged)?;
+        assert_eq!(std::fs::read_to_string(merged.join("foo/bar.py"))?, "top");
+        assert_eq!(
+            std::fs::read_to_string(merged.join("foo/data/config.json"))?,
+            "overlay"
+        );
+        assert!(!merged.join("foo/baz.py").exists());
+        assert!(!merged.join("legacy/util.py").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_layered_tree_file_directory_conflict() -> Result<()> {
+        let td = tempdir::TempDir::new("pyoxidizer-test")?;
+        let base = td.path().join("base");
+        let overlay = td.path().join("overlay");
+
+        write_files(&base, &[("foo/__init__.py", "")])?;
+        write_files(&overlay, &[("foo", "")])?;
+
+        assert!(LayeredTree::from_layers(&[&base, &overlay]).is_err());
+
+        write_files(&overlay, &[("foo.pyoxidizer-deleted", "")])?;
+        let tree = LayeredTree::from_layers(&[&base, &overlay])?;
+        assert_eq!(
+            tree.files.keys().collect::<Vec<_>>(),
+            vec![Path::new("foo")]
+        );
+
+        Ok(())
+    }
+}
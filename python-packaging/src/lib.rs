@@ -10,10 +10,23 @@ and packaging facilities.
 
 pub mod bytecode;
 pub mod filesystem_scanning;
+pub mod filter_file;
+pub mod implementation;
+pub mod layering;
 pub mod licensing;
+pub mod locale;
 pub mod module_util;
 pub mod package_metadata;
+pub mod patch;
 pub mod policy;
 pub mod python_source;
 pub mod resource;
 pub mod resource_collection;
+pub mod resource_usage;
+pub mod scripts;
+pub mod source_transform;
+pub mod syntax_features;
+pub mod test_classification;
+pub mod text_normalization;
+pub mod version;
+pub mod wheel;
@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Trim locale data of languages that aren't needed.
+
+Packages using gettext often ship translations for dozens of languages in
+directories like `locale/<lang>/LC_MESSAGES/<domain>.mo`. A resource is
+locale data if its path, including the directories of its package, has
+either:
+
+* A language code directly above a directory named `LC_<category>`, e.g.
+  `foo/locale/pt_BR/LC_MESSAGES/foo.mo`.
+* A language code directly below a directory named `locale` or `locales`
+  with more path following it, e.g. `foo/locales/de/messages.json`.
+
+A language code is 2 or 3 ASCII letters of base language, optionally
+followed by a region, script, encoding or modifier, e.g. `pt_BR`,
+`zh_Hant_TW`, `de_DE.UTF-8` or `sr@latin`. `-` and `_` are interchangeable
+and codes are compared case insensitively.
+
+Locale data is kept if its language is on a keep-list. Unless exact matching
+is requested, the keep-list falls back across regions the way gettext does:
+a bare language like `pt` keeps all its regional variants like `pt_BR`, and
+a regional variant like `pt_BR` keeps the bare language `pt` gettext falls
+back to. Resources that aren't locale data are always kept.
+*/
+
+/// Characters separating the base language of a language code from the rest.
+const LANGUAGE_SEPARATORS: &[char] = &['_', '@', '.'];
+
+/// Normalize a language code for comparison.
+fn normalize_language(code: &str) -> String {
+    code.replace('-', "_").to_ascii_lowercase()
+}
+
+/// Obtain the base language of a normalized language code.
+fn base_language(code: &str) -> &str {
+    code.split(LANGUAGE_SEPARATORS).next().unwrap_or(code)
+}
+
+/// Whether a path component looks like a language code.
+pub fn is_language_code(component: &str) -> bool {
+    if component.starts_with("LC_") {
+        return false;
+    }
+
+    let normalized = normalize_language(component);
+    let base = base_language(&normalized);
+
+    (2..=3).contains(&base.len())
+        && base.chars().all(|c| c.is_ascii_alphabetic())
+        && normalized[base.len()..]
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || LANGUAGE_SEPARATORS.contains(&c))
+}
+
+/// Obtain the language of locale data from its `/` delimited path.
+///
+/// Returns `None` if the path isn't locale data.
+pub fn resource_language(path: &str) -> Option<&str> {
+    let components = path.split('/').collect::<Vec<_>>();
+
+    // Only directories are considered, never the file name.
+    let directories = &components[0..components.len() - 1];
+
+    for (i, component) in directories.iter().enumerate().skip(1) {
+        if component.starts_with("LC_") && is_language_code(directories[i - 1]) {
+            return Some(directories[i - 1]);
+        }
+    }
+
+    for (i, component) in directories.iter().enumerate() {
+        if (*component == "locale" || *component == "locales")
+            && i + 1 < directories.len()
+            && is_language_code(directories[i + 1])
+        {
+            return Some(directories[i + 1]);
+        }
+    }
+
+    None
+}
+
+/// Which locale data to keep.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LocaleFilter {
+    /// Language codes to keep.
+    pub keep: Vec<String>,
+
+    /// Whether languages must match a keep-list entry exactly.
+    ///
+    /// If not set, languages fall back across regions.
+    pub exact_match: bool,
+}
+
+impl LocaleFilter {
+    /// Whether locale data of a language is kept.
+    pub fn keeps_language(&self, language: &str) -> bool {
+        let language = normalize_language(language);
+
+        self.keep.iter().any(|keep| {
+            let keep = normalize_language(keep);
+
+            if keep == language {
+                true
+            } else if self.exact_match {
+                false
+            } else {
+                // `pt` keeps `pt_BR` and `pt_BR` keeps `pt`.
+                base_language(&language) == keep || base_language(&keep) == language
+            }
+        })
+    }
+
+    /// Whether a resource is kept, by its `/` delimited path.
+    ///
+    /// Resources that aren't locale data are always kept.
+    pub fn keeps_resource(&self, path: &str) -> bool {
+        match resource_language(path) {
+            Some(language) => self.keeps_language(language),
+            None => true,
+        }
+    }
+}
+
+/// Locale data trimmed from a package.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LocaleTrimStatistics {
+    /// Number of files trimmed.
+    pub files: usize,
+
+    /// Total bytes of the files trimmed.
+    pub bytes: u64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Locale data of a package shipping multiple languages.
+    const FIXTURE: &[&str] = &[
+        "foo/locale/de/LC_MESSAGES/foo.mo",
+        "foo/locale/fr/LC_MESSAGES/foo.mo",
+        "foo/locale/pt/LC_MESSAGES/foo.mo",
+        "foo/locale/pt_BR/LC_MESSAGES/foo.mo",
+        "foo/locale/pt-PT/LC_MESSAGES/foo.mo",
+        "foo/locale/sr@latin/LC_MESSAGES/foo.mo",
+        "foo/locale/zh_Hant_TW/LC_MESSAGES/foo.mo",
+        "foo/locales/es/messages.json",
+    ];
+
+    fn kept(filter: &LocaleFilter) -> Vec<&'static str> {
+        FIXTURE
+            .iter()
+            .filter(|path| filter.keeps_resource(path))
+            .map(|path| resource_language(*path).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_resource_language() {
+        assert_eq!(
+            resource_language("foo/locale/pt_BR/LC_MESSAGES/foo.mo"),
+            Some("pt_BR")
+        );
+        assert_eq!(
+            resource_language("foo/translations/de/LC_MESSAGES/foo.mo"),
+            Some("de")
+        );
+        assert_eq!(
+            resource_language("foo/locales/es/messages.json"),
+            Some("es")
+        );
+        assert_eq!(
+            resource_language("foo/locale/de_DE.UTF-8/LC_TIME/foo.mo"),
+            Some("de_DE.UTF-8")
+        );
+
+        // The file name is never a language.
+        assert_eq!(resource_language("foo/locale/de"), None);
+        assert_eq!(resource_language("foo/locale/LC_MESSAGES/foo.mo"), None);
+        assert_eq!(resource_language("foo/locale/README/x.txt"), None);
+        assert_eq!(resource_language("foo/data/de/x.txt"), None);
+        assert_eq!(resource_language("foo.mo"), None);
+    }
+
+    #[test]
+    fn test_region_fallback() {
+        let filter = LocaleFilter {
+            keep: vec!["pt".to_string()],
+            exact_match: false,
+        };
+        assert_eq!(kept(&filter), vec!["pt", "pt_BR", "pt-PT"]);
+
+        let filter = LocaleFilter {
+            keep: vec!["pt_br".to_string(), "sr".to_string(), "ES".to_string()],
+            exact_match: false,
+        };
+        assert_eq!(kept(&filter), vec!["pt", "pt_BR", "sr@latin", "es"]);
+
+        let filter = LocaleFilter {
+            keep: vec!["zh".to_string()],
+            exact_match: false,
+        };
+        assert_eq!(kept(&filter), vec!["zh_Hant_TW"]);
+
+        assert!(filter.keeps_resource("foo/data/de/x.txt"));
+        assert!(kept(&LocaleFilter::default()).is_empty());
+    }
+
+    #[test]
+    fn test_exact_match() {
+        let filter = LocaleFilter {
+            keep: vec!["pt".to_string(), "pt-br".to_string()],
+            exact_match: true,
+        };
+        assert_eq!(kept(&filter), vec!["pt", "pt_BR"]);
+
+        let filter = LocaleFilter {
+            keep: vec!["pt_BR".to_string()],
+            exact_match: true,
+        };
+        assert_eq!(kept(&filter), vec!["pt_BR"]);
+    }
+}
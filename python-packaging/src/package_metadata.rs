@@ -5,10 +5,79 @@
 /*! Working with Python package metadata (i.e. .pkg-info directories) */
 
 use {
-    anyhow::{Context, Result},
+    anyhow::{anyhow, Context, Result},
     mailparse::parse_mail,
+    serde::{Deserialize, Serialize},
+    std::collections::{BTreeMap, BTreeSet},
 };
 
+/// An entry point declared in an `entry_points.txt` file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryPoint {
+    /// Group the entry point belongs to. e.g. `console_scripts`.
+    pub group: String,
+
+    /// Name of the entry point within its group.
+    pub name: String,
+
+    /// Module the entry point's object is defined in.
+    pub module: String,
+
+    /// Attribute path of the object within `module`, if any.
+    pub attr: Option<String>,
+}
+
+/// Parse the entry points of an `entry_points.txt` file.
+///
+/// The file is INI formatted with a section per group. Values are
+/// `module:attr [extras]`. Extras are ignored.
+pub fn parse_entry_points(data: &[u8]) -> Result<Vec<EntryPoint>> {
+    let data = std::str::from_utf8(data).context("decoding entry points as UTF-8")?;
+
+    let mut res = Vec::new();
+    let mut group = None;
+
+    for (i, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+
+        if line.starts_with('[') && line.ends_with(']') {
+            group = Some(line[1..line.len() - 1].trim().to_string());
+            continue;
+        }
+
+        let group = group
+            .as_ref()
+            .ok_or_else(|| anyhow!("line {}: entry point outside of a group", i + 1))?;
+
+        let mut parts = line.splitn(2, '=');
+        let name = parts.next().unwrap_or("").trim();
+        let value = parts
+            .next()
+            .ok_or_else(|| anyhow!("line {}: expected name = value", i + 1))?;
+        let value = value.split('[').next().unwrap_or("").trim();
+
+        let mut parts = value.splitn(2, ':');
+        let module = parts.next().unwrap_or("").trim();
+        let attr = parts.next().map(|attr| attr.trim().to_string());
+
+        if name.is_empty() || module.is_empty() {
+            return Err(anyhow!("line {}: expected name = module:attr", i + 1));
+        }
+
+        res.push(EntryPoint {
+            group: group.clone(),
+            name: name.to_string(),
+            module: module.to_string(),
+            attr,
+        });
+    }
+
+    Ok(res)
+}
+
 /// Represents a Python METADATA file.
 pub struct PythonPackageMetadata {
     headers: Vec<(String, String)>,
@@ -62,6 +131,417 @@ impl PythonPackageMetadata {
     }
 }
 
+/// Normalize a distribution name for comparison.
+///
+/// Names are compared case insensitively and with runs of `-`, `_` and `.`
+/// treated alike.
+pub fn normalize_distribution_name(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        if c == '-' || c == '_' || c == '.' {
+            if !res.ends_with('-') {
+                res.push('-');
+            }
+        } else {
+            res.push(c.to_ascii_lowercase());
+        }
+    }
+
+    res
+}
+
+/// Name of the distribution resource describing a direct URL install.
+pub const DIRECT_URL_FILENAME: &str = "direct_url.json";
+
+/// Version control information in a `direct_url.json` file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DirectUrlVcsInfo {
+    /// Name of the version control system. e.g. `git`.
+    pub vcs: String,
+
+    /// Exact commit that was installed.
+    pub commit_id: String,
+
+    /// Revision the user asked for. e.g. a branch or tag name.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_revision: Option<String>,
+}
+
+/// Archive information in a `direct_url.json` file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DirectUrlArchiveInfo {
+    /// Hashes of the archive, by algorithm.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hashes: BTreeMap<String, String>,
+}
+
+/// Local directory information in a `direct_url.json` file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DirectUrlDirInfo {
+    /// Whether the distribution was installed in editable mode.
+    #[serde(default)]
+    pub editable: bool,
+}
+
+/// Represents a `direct_url.json` file.
+///
+/// Installers write this file (PEP 610) when a distribution was installed
+/// from a URL, a VCS checkout or a local directory instead of an index.
+/// Exactly one of `vcs_info`, `archive_info` and `dir_info` is present.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct DirectUrl {
+    /// Where the distribution was installed from.
+    pub url: String,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vcs_info: Option<DirectUrlVcsInfo>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archive_info: Option<DirectUrlArchiveInfo>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub dir_info: Option<DirectUrlDirInfo>,
+
+    /// Directory within `url` holding the project.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdirectory: Option<String>,
+}
+
+impl DirectUrl {
+    /// Create an instance from data in a `direct_url.json` file.
+    pub fn from_json(data: &[u8]) -> Result<Self> {
+        let direct_url: Self = serde_json::from_slice(data).context("parsing direct_url.json")?;
+
+        let infos = [
+            direct_url.vcs_info.is_some(),
+            direct_url.archive_info.is_some(),
+            direct_url.dir_info.is_some(),
+        ];
+
+        match infos.iter().filter(|present| **present).count() {
+            1 => Ok(direct_url),
+            0 => Err(anyhow!(
+                "direct_url.json has no vcs_info, archive_info or dir_info"
+            )),
+            _ => Err(anyhow!(
+                "direct_url.json has more than one of vcs_info, archive_info and dir_info"
+            )),
+        }
+    }
+
+    /// Whether the distribution was installed in editable mode.
+    ///
+    /// Editable installs reference sources in a directory on the machine
+    /// that installed them.
+    pub fn is_editable(&self) -> bool {
+        self.dir_info
+            .as_ref()
+            .map(|info| info.editable)
+            .unwrap_or(false)
+    }
+}
+
+impl std::fmt::Display for DirectUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(vcs) = &self.vcs_info {
+            write!(f, "{} commit {} of {}", vcs.vcs, vcs.commit_id, self.url)?;
+        } else if self.archive_info.is_some() {
+            write!(f, "archive {}", self.url)?;
+        } else if self.is_editable() {
+            write!(f, "editable directory {}", self.url)?;
+        } else {
+            write!(f, "directory {}", self.url)?;
+        }
+
+        if let Some(subdirectory) = &self.subdirectory {
+            write!(f, " (subdirectory {})", subdirectory)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A hash of a file recorded in a RECORD file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordHash {
+    /// Name of the hash algorithm. e.g. `sha256`.
+    pub algorithm: String,
+
+    /// Digest encoded as URL-safe base64 without padding.
+    pub digest: String,
+}
+
+/// An entry in a RECORD file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordEntry {
+    /// Path of the file, relative to the installation root.
+    ///
+    /// Paths always use `/` as a separator.
+    pub path: String,
+
+    /// Hash of the file's content.
+    ///
+    /// Absent for files whose hash can't be known at install time, such
+    /// as RECORD itself and bytecode compiled at install time.
+    pub hash: Option<RecordHash>,
+
+    /// Size of the file in bytes.
+    pub size: Option<u64>,
+}
+
+/// Represents a Python RECORD file.
+///
+/// RECORD files are CSV files listing the files installed by a package.
+/// Each row has a path, a hash and a size.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PythonPackageRecord {
+    pub entries: Vec<RecordEntry>,
+
+    /// Problems that didn't prevent parsing but that callers may want to report.
+    pub warnings: Vec<String>,
+}
+
+/// Split CSV data into rows of fields.
+///
+/// Fields may be quoted with `"`, in which case they can contain commas,
+/// newlines and quotes escaped by doubling them. Rows are terminated by
+/// `\n` or `\r\n`. Empty rows are dropped. Rows are returned along with
+/// their 1-based row number.
+fn split_csv(data: &[u8]) -> Result<Vec<(usize, Vec<Vec<u8>>)>> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = Vec::new();
+    let mut row_number = 1;
+    let mut quoted = false;
+    let mut was_quoted = false;
+
+    let mut iter = data.iter().copied().peekable();
+
+    while let Some(c) = iter.next() {
+        if quoted {
+            if c == b'"' {
+                if iter.peek() == Some(&b'"') {
+                    iter.next();
+                    field.push(b'"');
+                } else {
+                    quoted = false;
+                }
+            } else {
+                field.push(c);
+            }
+
+            continue;
+        }
+
+        match c {
+            b'"' if field.is_empty() && !was_quoted => {
+                quoted = true;
+                was_quoted = true;
+            }
+            b'"' => {
+                return Err(anyhow!("row {}: unexpected quote", row_number));
+            }
+            b',' => {
+                row.push(std::mem::take(&mut field));
+                was_quoted = false;
+            }
+            b'\r' if iter.peek() == Some(&b'\n') => {}
+            b'\n' => {
+                row.push(std::mem::take(&mut field));
+                was_quoted = false;
+
+                if row.len() == 1 && row[0].is_empty() {
+                    row.clear();
+                } else {
+                    rows.push((row_number, std::mem::take(&mut row)));
+                }
+                row_number += 1;
+            }
+            _ if was_quoted => {
+                return Err(anyhow!(
+                    "row {}: unexpected data after quoted field",
+                    row_number
+                ));
+            }
+            _ => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(anyhow!("row {}: unterminated quoted field", row_number));
+    }
+
+    if !row.is_empty() || !field.is_empty() || was_quoted {
+        row.push(field);
+        rows.push((row_number, row));
+    }
+
+    Ok(rows)
+}
+
+/// Decode `%XX` escapes in a RECORD path.
+///
+/// A `%` that isn't followed by two hex digits is kept as is.
+fn percent_decode(data: &[u8]) -> Vec<u8> {
+    let hex = |c: u8| (c as char).to_digit(16).map(|v| v as u8);
+
+    let mut res = Vec::with_capacity(data.len());
+    let mut i = 0;
+
+    while i < data.len() {
+        if data[i] == b'%' && i + 2 < data.len() {
+            if let (Some(high), Some(low)) = (hex(data[i + 1]), hex(data[i + 2])) {
+                res.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+
+        res.push(data[i]);
+        i += 1;
+    }
+
+    res
+}
+
+impl RecordEntry {
+    /// Construct an instance from the fields of a RECORD row.
+    ///
+    /// The path is percent-decoded. Fields that aren't valid UTF-8 are
+    /// decoded lossily and a warning is pushed to `warnings`.
+    fn from_fields(fields: Vec<Vec<u8>>, warnings: &mut Vec<String>) -> Result<Self> {
+        if fields.len() != 3 {
+            return Err(anyhow!("expected 3 fields; got {}", fields.len()));
+        }
+
+        let mut fields = fields.into_iter();
+        let path = percent_decode(&fields.next().unwrap());
+
+        let mut fields =
+            std::iter::once(path)
+                .chain(fields)
+                .map(|field| match String::from_utf8(field) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        let s = String::from_utf8_lossy(e.as_bytes()).into_owned();
+                        warnings.push(format!("{} is not valid UTF-8; decoded lossily", s));
+                        s
+                    }
+                });
+
+        let path = fields.next().unwrap();
+        let hash = fields.next().unwrap();
+        let size = fields.next().unwrap();
+
+        if path.is_empty() {
+            return Err(anyhow!("empty path"));
+        }
+
+        let hash = if hash.is_empty() {
+            None
+        } else {
+            let pos = hash
+                .find('=')
+                .ok_or_else(|| anyhow!("hash {} is not of the form <algorithm>=<digest>", hash))?;
+            let (algorithm, digest) = (&hash[0..pos], &hash[pos + 1..]);
+
+            if algorithm.is_empty()
+                || digest.is_empty()
+                || !digest
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+            {
+                return Err(anyhow!("malformed hash {}", hash));
+            }
+
+            Some(RecordHash {
+                algorithm: algorithm.to_string(),
+                digest: digest.to_string(),
+            })
+        };
+
+        let size = if size.is_empty() {
+            None
+        } else {
+            Some(
+                size.parse::<u64>()
+                    .map_err(|_| anyhow!("malformed size {}", size))?,
+            )
+        };
+
+        Ok(Self {
+            path: path.replace('\\', "/"),
+            hash,
+            size,
+        })
+    }
+}
+
+impl PythonPackageRecord {
+    /// Create an instance from data in a RECORD file.
+    ///
+    /// Malformed rows are an error identifying the offending row. Fields
+    /// that aren't valid UTF-8 are decoded lossily and noted in `warnings`.
+    pub fn from_record(data: &[u8]) -> Result<PythonPackageRecord> {
+        let mut warnings = Vec::new();
+
+        let entries = split_csv(data)
+            .context("parsing RECORD file")?
+            .into_iter()
+            .map(|(row_number, fields)| {
+                let mut row_warnings = Vec::new();
+                let entry = RecordEntry::from_fields(fields, &mut row_warnings)
+                    .with_context(|| format!("parsing RECORD file row {}", row_number));
+
+                warnings.extend(
+                    row_warnings
+                        .into_iter()
+                        .map(|w| format!("RECORD file row {}: {}", row_number, w)),
+                );
+
+                entry
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(PythonPackageRecord { entries, warnings })
+    }
+
+    /// Find the entry for a path.
+    pub fn find_entry(&self, path: &str) -> Option<&RecordEntry> {
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Obtain the names of top-level packages and modules that are installed.
+    ///
+    /// Directories are included whether or not they contain Python code,
+    /// so packages only holding data are found. Metadata directories,
+    /// `__pycache__`, `.data` directories, files outside the installation
+    /// root and names that aren't valid module names are ignored.
+    pub fn top_level_names(&self) -> BTreeSet<String> {
+        self.entries
+            .iter()
+            .filter_map(|entry| {
+                let mut components = entry.path.split('/');
+                let first = components.next()?;
+
+                match components.next() {
+                    Some(_) => Some(first),
+                    None if first.ends_with(".py") => Some(&first[0..first.len() - 3]),
+                    None => None,
+                }
+            })
+            .filter(|name| {
+                !name.is_empty()
+                    && *name != "__pycache__"
+                    && !name.chars().next().unwrap().is_ascii_digit()
+                    && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+            })
+            .map(|name| name.to_string())
+            .collect()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,4 +579,231 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_parse_record() -> Result<()> {
+        let data = concat!(
+            "black.py,sha256=Y3OxU-P6Po1BBkDzWWI1P_xy29q7KzmzE0WWMqoySYY,7\r\n",
+            "\"comma,name.txt\",sha256=abc_-,12\r\n",
+            "\"say \"\"hi\"\".txt\",,\r\n",
+            "\n",
+            "black\\data\\grammar.txt,sha256=xyz,3\n",
+            "__pycache__/black.cpython-38.pyc,,\n",
+            "black-19.10b0.dist-info/RECORD,,",
+        )
+        .as_bytes();
+
+        let record = PythonPackageRecord::from_record(data)?;
+
+        assert_eq!(
+            record
+                .entries
+                .iter()
+                .map(|e| e.path.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "black.py",
+                "comma,name.txt",
+                "say \"hi\".txt",
+                "black/data/grammar.txt",
+                "__pycache__/black.cpython-38.pyc",
+                "black-19.10b0.dist-info/RECORD",
+            ]
+        );
+        assert_eq!(
+            record.entries[0].hash,
+            Some(RecordHash {
+                algorithm: "sha256".to_string(),
+                digest: "Y3OxU-P6Po1BBkDzWWI1P_xy29q7KzmzE0WWMqoySYY".to_string(),
+            })
+        );
+        assert_eq!(record.entries[0].size, Some(7));
+        assert_eq!(record.entries[1].size, Some(12));
+
+        let own = record.find_entry("black-19.10b0.dist-info/RECORD").unwrap();
+        assert_eq!(own.hash, None);
+        assert_eq!(own.size, None);
+        assert!(record.find_entry("missing").is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_top_level_names() -> Result<()> {
+        let data = concat!(
+            "black.py,,\n",
+            "blib2to3/__init__.py,,\n",
+            "certs/cacert.pem,,\n",
+            "certs/extra/more.pem,,\n",
+            "__pycache__/black.cpython-38.pyc,,\n",
+            "black-19.10b0.dist-info/RECORD,,\n",
+            "black-19.10b0.data/scripts/black,,\n",
+            "../../bin/black,,\n",
+            "README.txt,,\n",
+        )
+        .as_bytes();
+
+        assert_eq!(
+            PythonPackageRecord::from_record(data)?
+                .top_level_names()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            vec!["black", "blib2to3", "certs"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_record_quoted_newline() -> Result<()> {
+        let record = PythonPackageRecord::from_record(b"\"multi\nline\",,\nfoo,,1\n")?;
+
+        assert_eq!(record.entries[0].path, "multi\nline");
+        assert_eq!(record.entries[1].path, "foo");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_record_percent_encoded() -> Result<()> {
+        let record =
+            PythonPackageRecord::from_record(b"foo/a%20b.txt,,\nfoo/100%.txt,,\nfoo/%zz,,\n")?;
+
+        assert_eq!(record.entries[0].path, "foo/a b.txt");
+        assert_eq!(record.entries[1].path, "foo/100%.txt");
+        assert_eq!(record.entries[2].path, "foo/%zz");
+        assert!(record.warnings.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_record_invalid_utf8() -> Result<()> {
+        let record = PythonPackageRecord::from_record(b"foo,,\nb\xffr,,\nb%FFz,,1\n")?;
+
+        assert_eq!(record.entries.len(), 3);
+        assert_eq!(record.entries[1].path, "b\u{fffd}r");
+        assert_eq!(record.entries[2].path, "b\u{fffd}z");
+        assert_eq!(record.entries[2].size, Some(1));
+        assert_eq!(
+            record.warnings,
+            vec![
+                "RECORD file row 2: b\u{fffd}r is not valid UTF-8; decoded lossily",
+                "RECORD file row 3: b\u{fffd}z is not valid UTF-8; decoded lossily",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_record_malformed() {
+        for (data, message) in &[
+            (&b"foo,,\nbar\n"[..], "row 2"),
+            (&b"foo,,\nbar,,,\n"[..], "row 2"),
+            (&b"foo,md5,1\n"[..], "row 1"),
+            (&b"foo,sha256=a+b,1\n"[..], "row 1"),
+            (&b"foo,,\nbar,,\nbaz,,big\n"[..], "row 3"),
+            (&b",,\n"[..], "row 1"),
+            (&b"foo,,\n\"bar,,\n"[..], "row 2"),
+            (&b"foo,,\n\"bar\"x,,\n"[..], "row 2"),
+            (&b"fo\"o,,\n"[..], "row 1"),
+        ] {
+            let err = PythonPackageRecord::from_record(data).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains(message),
+                "{:#} doesn't contain {}",
+                err,
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_entry_points() -> Result<()> {
+        let entry_points = parse_entry_points(
+            b"[console_scripts]\nblack = black:patched_main\n\n\
+              [pytest11]\n; comment\nxdist.looponfail = xdist.looponfail\n\
+              cov = pytest_cov.plugin:Plugin [toml]\n",
+        )?;
+
+        assert_eq!(
+            entry_points,
+            vec![
+                EntryPoint {
+                    group: "console_scripts".to_string(),
+                    name: "black".to_string(),
+                    module: "black".to_string(),
+                    attr: Some("patched_main".to_string()),
+                },
+                EntryPoint {
+                    group: "pytest11".to_string(),
+                    name: "xdist.looponfail".to_string(),
+                    module: "xdist.looponfail".to_string(),
+                    attr: None,
+                },
+                EntryPoint {
+                    group: "pytest11".to_string(),
+                    name: "cov".to_string(),
+                    module: "pytest_cov.plugin".to_string(),
+                    attr: Some("Plugin".to_string()),
+                },
+            ]
+        );
+
+        assert_eq!(
+            parse_entry_points(b"foo = bar\n").unwrap_err().to_string(),
+            "line 1: entry point outside of a group"
+        );
+        assert!(parse_entry_points(b"[group]\nfoo\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_direct_url() -> Result<()> {
+        let vcs = DirectUrl::from_json(
+            br#"{"url": "https://github.com/pallets/click.git",
+                "vcs_info": {"vcs": "git", "commit_id": "abc123", "requested_revision": "main"}}"#,
+        )?;
+        assert!(!vcs.is_editable());
+        assert_eq!(
+            vcs.to_string(),
+            "git commit abc123 of https://github.com/pallets/click.git"
+        );
+
+        let editable = DirectUrl::from_json(
+            br#"{"url": "file:///home/dev/foo", "dir_info": {"editable": true}}"#,
+        )?;
+        assert!(editable.is_editable());
+        assert_eq!(
+            editable.to_string(),
+            "editable directory file:///home/dev/foo"
+        );
+
+        let archive = DirectUrl::from_json(
+            br#"{"url": "https://example.com/foo-1.0.tar.gz",
+                "archive_info": {"hashes": {"sha256": "deadbeef"}},
+                "subdirectory": "python"}"#,
+        )?;
+        assert_eq!(
+            archive.to_string(),
+            "archive https://example.com/foo-1.0.tar.gz (subdirectory python)"
+        );
+        assert_eq!(
+            DirectUrl::from_json(serde_json::to_vec(&archive)?.as_slice())?,
+            archive
+        );
+
+        assert!(DirectUrl::from_json(b"{").is_err());
+        assert!(DirectUrl::from_json(br#"{"dir_info": {}}"#).is_err());
+        assert_eq!(
+            DirectUrl::from_json(br#"{"url": "file:///foo"}"#)
+                .unwrap_err()
+                .to_string(),
+            "direct_url.json has no vcs_info, archive_info or dir_info"
+        );
+
+        Ok(())
+    }
 }
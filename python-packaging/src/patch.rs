@@ -0,0 +1,431 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Patch files of Python packages with unified diffs.
+
+Upstream packages sometimes need small fixes to work when packaged, e.g.
+a broken `__init__.py` or a hardcoded path. A `PackagePatch` holds a
+unified diff, as produced by `diff -u` or `git diff`, along with the
+distribution and versions it applies to. Paths in the diff are relative
+to the directory the distribution is installed in, e.g. `site-packages`.
+A leading `a/` or `b/` component, as emitted by `git diff`, is removed.
+
+Hunks are located by their context. If a hunk's context isn't found at the
+line the hunk header states, the remainder of the file is searched for it.
+*/
+
+use {
+    crate::version::VersionSpecifiers,
+    anyhow::{anyhow, Result},
+};
+
+/// A line of a hunk.
+#[derive(Clone, Debug, PartialEq)]
+pub enum HunkLine {
+    Context(String),
+    Remove(String),
+    Add(String),
+}
+
+/// A hunk of a unified diff.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hunk {
+    /// 1-based line number of the first line in the original file.
+    pub old_start: usize,
+    pub old_count: usize,
+    pub new_start: usize,
+    pub new_count: usize,
+    pub lines: Vec<HunkLine>,
+}
+
+impl Hunk {
+    /// The header of this hunk, e.g. `@@ -1,3 +1,4 @@`.
+    pub fn header(&self) -> String {
+        format!(
+            "@@ -{},{} +{},{} @@",
+            self.old_start, self.old_count, self.new_start, self.new_count
+        )
+    }
+
+    /// Lines of the original file covered by this hunk.
+    fn old_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(s) | HunkLine::Remove(s) => Some(s.as_str()),
+                HunkLine::Add(_) => None,
+            })
+            .collect()
+    }
+
+    /// Lines of the patched file covered by this hunk.
+    fn new_lines(&self) -> Vec<&str> {
+        self.lines
+            .iter()
+            .filter_map(|line| match line {
+                HunkLine::Context(s) | HunkLine::Add(s) => Some(s.as_str()),
+                HunkLine::Remove(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// A hunk that couldn't be applied.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HunkFailure {
+    /// 1-based index of the hunk in its file patch.
+    pub index: usize,
+    pub header: String,
+}
+
+impl std::fmt::Display for HunkFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "hunk #{} ({}) doesn't match the file content",
+            self.index, self.header
+        )
+    }
+}
+
+/// Changes to a single file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FilePatch {
+    /// Path of the patched file, using `/` as a directory separator.
+    pub path: String,
+    pub hunks: Vec<Hunk>,
+}
+
+impl FilePatch {
+    /// Apply the hunks of this patch to file content.
+    ///
+    /// Returns the patched content, or every hunk that failed to apply.
+    pub fn apply(&self, content: &str) -> std::result::Result<String, Vec<HunkFailure>> {
+        let mut lines = content.split('\n').collect::<Vec<_>>();
+        let trailing_newline = content.ends_with('\n');
+        if trailing_newline {
+            lines.pop();
+        }
+        let mut lines = lines.into_iter().map(String::from).collect::<Vec<_>>();
+
+        let mut failures = Vec::new();
+        // Lines before this have been patched and can't match later hunks.
+        let mut floor = 0;
+        let mut offset: isize = 0;
+
+        for (i, hunk) in self.hunks.iter().enumerate() {
+            let old_lines = hunk.old_lines();
+            let new_lines = hunk.new_lines();
+
+            let matches_at = |pos: usize| {
+                pos + old_lines.len() <= lines.len()
+                    && old_lines
+                        .iter()
+                        .zip(&lines[pos..])
+                        .all(|(a, b)| a.trim_end_matches('\r') == b.trim_end_matches('\r'))
+            };
+
+            // Hunks adding to an empty file have a start of 0.
+            let expected = (hunk.old_start.max(1) as isize - 1 + offset).max(floor as isize);
+            let expected = expected as usize;
+
+            let found = if matches_at(expected) {
+                Some(expected)
+            } else {
+                (floor..=lines.len()).find(|pos| matches_at(*pos))
+            };
+
+            match found {
+                Some(pos) => {
+                    lines.splice(
+                        pos..pos + old_lines.len(),
+                        new_lines.iter().map(|s| s.to_string()),
+                    );
+                    floor = pos + new_lines.len();
+                    offset += new_lines.len() as isize - old_lines.len() as isize
+                        + (pos as isize - expected as isize);
+                }
+                None => failures.push(HunkFailure {
+                    index: i + 1,
+                    header: hunk.header(),
+                }),
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(failures);
+        }
+
+        let mut res = lines.join("\n");
+        if trailing_newline || (content.is_empty() && !res.is_empty()) {
+            res.push('\n');
+        }
+
+        Ok(res)
+    }
+}
+
+/// Parse a hunk header like `@@ -1,3 +1,4 @@`.
+fn parse_hunk_header(line: &str) -> Result<(usize, usize, usize, usize)> {
+    let invalid = || anyhow!("invalid hunk header: {}", line);
+
+    let ranges = line
+        .trim_start_matches("@@")
+        .split("@@")
+        .next()
+        .ok_or_else(invalid)?;
+
+    let parse_range = |range: &str, sign: char| -> Result<(usize, usize)> {
+        if !range.starts_with(sign) {
+            return Err(invalid());
+        }
+
+        let mut parts = range[1..].splitn(2, ',');
+        let start = parts
+            .next()
+            .ok_or_else(invalid)?
+            .parse::<usize>()
+            .map_err(|_| invalid())?;
+        let count = match parts.next() {
+            Some(count) => count.parse::<usize>().map_err(|_| invalid())?,
+            None => 1,
+        };
+
+        Ok((start, count))
+    };
+
+    let mut ranges = ranges.split_whitespace();
+    let (old_start, old_count) = parse_range(ranges.next().ok_or_else(invalid)?, '-')?;
+    let (new_start, new_count) = parse_range(ranges.next().ok_or_else(invalid)?, '+')?;
+
+    Ok((old_start, old_count, new_start, new_count))
+}
+
+/// Obtain the path of a file from a `---` or `+++` line.
+fn parse_file_path(line: &str) -> String {
+    let path = line[4..].split('\t').next().unwrap_or("").trim();
+
+    if path.starts_with("a/") || path.starts_with("b/") {
+        path[2..].to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+/// Parse a unified diff into per-file patches.
+///
+/// Lines outside of file patches, like `diff --git` headers, are ignored.
+pub fn parse_unified_diff(diff: &str) -> Result<Vec<FilePatch>> {
+    let mut res: Vec<FilePatch> = Vec::new();
+    let mut lines = diff.lines().peekable();
+
+    while let Some(line) = lines.next() {
+        if line.starts_with("--- ") {
+            let next = lines
+                .next()
+                .ok_or_else(|| anyhow!("missing +++ line after {}", line))?;
+            if !next.starts_with("+++ ") {
+                return Err(anyhow!("expected +++ line after {}; got {}", line, next));
+            }
+
+            // Deleted files only have a meaningful old path.
+            let path = parse_file_path(next);
+            let path = if path == "/dev/null" {
+                parse_file_path(line)
+            } else {
+                path
+            };
+
+            res.push(FilePatch {
+                path,
+                hunks: vec![],
+            });
+        } else if line.starts_with("@@") {
+            let file = res
+                .last_mut()
+                .ok_or_else(|| anyhow!("hunk without a file header: {}", line))?;
+            let (old_start, old_count, new_start, new_count) = parse_hunk_header(line)?;
+
+            let mut hunk = Hunk {
+                old_start,
+                old_count,
+                new_start,
+                new_count,
+                lines: vec![],
+            };
+
+            let (mut old_seen, mut new_seen) = (0, 0);
+            while old_seen < old_count || new_seen < new_count {
+                let line = lines
+                    .next()
+                    .ok_or_else(|| anyhow!("truncated hunk: {}", hunk.header()))?;
+
+                let (kind, text) = if line.is_empty() {
+                    // Some tools strip the space of empty context lines.
+                    (' ', "")
+                } else {
+                    let kind = line.chars().next().unwrap();
+                    (kind, &line[kind.len_utf8()..])
+                };
+
+                match kind {
+                    ' ' => {
+                        hunk.lines.push(HunkLine::Context(text.to_string()));
+                        old_seen += 1;
+                        new_seen += 1;
+                    }
+                    '-' => {
+                        hunk.lines.push(HunkLine::Remove(text.to_string()));
+                        old_seen += 1;
+                    }
+                    '+' => {
+                        hunk.lines.push(HunkLine::Add(text.to_string()));
+                        new_seen += 1;
+                    }
+                    '\\' => {}
+                    _ => {
+                        return Err(anyhow!(
+                            "unexpected line in hunk {}: {}",
+                            hunk.header(),
+                            line
+                        ))
+                    }
+                }
+            }
+
+            // Consume a trailing "\ No newline at end of file" marker.
+            if let Some(line) = lines.peek() {
+                if line.starts_with('\\') {
+                    lines.next();
+                }
+            }
+
+            file.hunks.push(hunk);
+        }
+    }
+
+    if res.is_empty() {
+        return Err(anyhow!("no file patches found"));
+    }
+
+    Ok(res)
+}
+
+/// A patch to files of a Python distribution.
+#[derive(Clone, Debug)]
+pub struct PackagePatch {
+    /// Name identifying the patch, e.g. the file it was read from.
+    pub name: String,
+
+    /// Name of the distribution the patch applies to.
+    pub distribution: String,
+
+    /// Versions of the distribution the patch applies to.
+    pub versions: VersionSpecifiers,
+
+    /// Changes to files of the distribution.
+    pub files: Vec<FilePatch>,
+}
+
+impl PackagePatch {
+    /// Construct an instance from unified diff content.
+    ///
+    /// `versions` holds comma separated version specifiers, like `>=1.0,<2`.
+    /// An empty string matches all versions.
+    pub fn new(name: &str, distribution: &str, versions: &str, diff: &str) -> Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            distribution: distribution.to_string(),
+            versions: VersionSpecifiers::parse(versions)?,
+            files: parse_unified_diff(diff)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIFF: &str = "diff --git a/foo/__init__.py b/foo/__init__.py
+index 0123456..789abcd 100644
+--- a/foo/__init__.py
++++ b/foo/__init__.py
+@@ -1,3 +1,3 @@
+ import os
+-PATH = '/usr/share/foo'
++PATH = os.path.dirname(__file__)
+ 
+@@ -6,2 +6,3 @@ def bar():
+     pass
++    return 42
+ x = 1
+--- foo/data.txt\t2020-01-01 00:00:00
++++ foo/data.txt\t2020-01-02 00:00:00
+@@ -1 +1 @@
+-old
+\\ No newline at end of file
++new
+\\ No newline at end of file
+";
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let files = parse_unified_diff(DIFF)?;
+
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].path, "foo/__init__.py");
+        assert_eq!(files[0].hunks.len(), 2);
+        assert_eq!(files[0].hunks[0].header(), "@@ -1,3 +1,3 @@");
+        assert_eq!(
+            files[0].hunks[0].lines[3],
+            HunkLine::Context("".to_string())
+        );
+        assert_eq!(files[1].path, "foo/data.txt");
+        assert_eq!(
+            files[1].hunks[0].lines,
+            vec![
+                HunkLine::Remove("old".to_string()),
+                HunkLine::Add("new".to_string())
+            ]
+        );
+
+        assert!(parse_unified_diff("").is_err());
+        assert!(parse_unified_diff("--- a\n+++ b\n@@ -1,2 +1,2 @@\n x\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply() -> Result<()> {
+        let files = parse_unified_diff(DIFF)?;
+
+        // The second hunk is found despite the file having extra lines.
+        let original =
+            "import os\nPATH = '/usr/share/foo'\n\n# added\n# lines\ndef bar():\n    pass\nx = 1\n";
+        assert_eq!(
+            files[0].apply(original).unwrap(),
+            "import os\nPATH = os.path.dirname(__file__)\n\n# added\n# lines\ndef bar():\n    pass\n    return 42\nx = 1\n"
+        );
+
+        assert_eq!(files[1].apply("old").unwrap(), "new");
+
+        let failures = files[0]
+            .apply("import os\nPATH = '/opt/foo'\n\ndef bar():\n    pass\nx = 1\n")
+            .unwrap_err();
+        assert_eq!(
+            failures,
+            vec![HunkFailure {
+                index: 1,
+                header: "@@ -1,3 +1,3 @@".to_string()
+            }]
+        );
+        assert_eq!(
+            failures[0].to_string(),
+            "hunk #1 (@@ -1,3 +1,3 @@) doesn't match the file content"
+        );
+
+        Ok(())
+    }
+}
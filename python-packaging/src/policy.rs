@@ -7,12 +7,22 @@ Functionality for defining how Python resources should be packaged.
 */
 
 use {
+    crate::bytecode::{CompileErrorPolicy, PycInvalidationMode},
     crate::licensing::NON_GPL_LICENSES,
-    crate::resource::{PythonExtensionModule, PythonExtensionModuleVariants, PythonResource},
+    crate::locale::LocaleFilter,
+    crate::resource::{
+        BytecodeOptimizationLevel, PythonExtensionModule, PythonExtensionModuleVariants,
+        PythonModuleBytecodeFromSource, PythonModuleSource, PythonResource,
+    },
+    crate::scripts::{ScriptAction, ScriptRule},
+    crate::source_transform::SourceTransforms,
+    crate::text_normalization::TextNormalization,
     anyhow::{anyhow, Result},
-    std::collections::HashMap,
+    python_packed_resources::data::IndexEncoding,
+    std::collections::{BTreeMap, HashMap},
     std::convert::TryFrom,
     std::iter::FromIterator,
+    std::path::{Path, PathBuf},
 };
 
 /// Describes a policy for the location of Python resources.
@@ -99,6 +109,79 @@ impl TryFrom<&str> for ExtensionModuleFilter {
     }
 }
 
+/// How to resolve a module and a package having the same name.
+///
+/// This can happen when a distribution switches from a single module to a
+/// package across versions and an upgrade leaves the stale file behind.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModulePackageCollisionPolicy {
+    /// Keep the package and drop the module.
+    PreferPackage,
+
+    /// Keep the module and drop the package's code and resources.
+    PreferModule,
+
+    /// Reject the resource causing the collision.
+    Error,
+}
+
+impl TryFrom<&str> for ModulePackageCollisionPolicy {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "prefer-package" => Ok(ModulePackageCollisionPolicy::PreferPackage),
+            "prefer-module" => Ok(ModulePackageCollisionPolicy::PreferModule),
+            "error" => Ok(ModulePackageCollisionPolicy::Error),
+            t => Err(format!(
+                "{} is not a valid module/package collision policy",
+                t
+            )),
+        }
+    }
+}
+
+/// How to handle a distribution arriving while another version of it is collected.
+///
+/// This can happen when separate acquisition steps install different
+/// versions of the same distribution, e.g. with different constraints.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DistributionVersionConflictPolicy {
+    /// Remove resources of the collected version, then add the new version.
+    Replace,
+
+    /// Reject the new version.
+    Error,
+
+    /// Keep the collected version and ignore the new version.
+    KeepFirst,
+}
+
+impl Default for DistributionVersionConflictPolicy {
+    fn default() -> Self {
+        DistributionVersionConflictPolicy::Replace
+    }
+}
+
+impl TryFrom<&str> for DistributionVersionConflictPolicy {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, String> {
+        match value {
+            "replace" => Ok(DistributionVersionConflictPolicy::Replace),
+            "error" => Ok(DistributionVersionConflictPolicy::Error),
+            "keep-first" => Ok(DistributionVersionConflictPolicy::KeepFirst),
+            t => Err(format!(
+                "{} is not a valid distribution version conflict policy",
+                t
+            )),
+        }
+    }
+}
+
+/// Default size in bytes above which pinned file content is spilled to disk.
+pub const DEFAULT_PIN_SPILL_THRESHOLD: u64 = 16 * 1024 * 1024;
+
 /// Defines how Python resources should be packaged.
 #[derive(Clone, Debug)]
 pub struct PythonPackagingPolicy {
@@ -108,6 +191,9 @@ pub struct PythonPackagingPolicy {
     /// Preferred variants of extension modules.
     preferred_extension_module_variants: HashMap<String, String>,
 
+    /// Pinned variants of extension modules.
+    extension_module_variant_pins: BTreeMap<String, String>,
+
     /// Where resources should be packaged by default.
     resources_policy: PythonResourcesPolicy,
 
@@ -125,6 +211,112 @@ pub struct PythonPackagingPolicy {
     /// Policy constructors can populate this with known broken extensions to
     /// prevent the policy from allowing an extension.
     broken_extensions: HashMap<String, Vec<String>>,
+
+    /// Whether unsatisfiable library dependencies of in-memory extension modules are an error.
+    ///
+    /// If false, such libraries are installed next to the extension module
+    /// on the filesystem, if the resources policy allows it.
+    strict_library_dependencies: bool,
+
+    /// Whether library dependencies of in-memory extension modules are extracted at run-time.
+    ///
+    /// If true, such libraries are embedded in the binary and written to a
+    /// cache directory on the filesystem before Python is initialized.
+    extract_library_dependencies: bool,
+
+    /// Whether to strip fat Mach-O binaries down to the slice for the target architecture.
+    thin_fat_binaries: bool,
+
+    /// Glob patterns of in-memory modules allowed to reference `__file__`.
+    dunder_file_waivers: Vec<String>,
+
+    /// Whether unwaived in-memory modules referencing `__file__` are an error.
+    strict_dunder_file: bool,
+
+    /// Rules for processing scripts installed as files.
+    script_rules: Vec<ScriptRule>,
+
+    /// How to normalize line endings and byte order marks of text resources.
+    ///
+    /// Text resources are left alone if not defined.
+    text_normalization: Option<TextNormalization>,
+
+    /// Which locale data of package resources to keep.
+    ///
+    /// All locale data is kept if not defined.
+    locale_filter: Option<LocaleFilter>,
+
+    /// Transforms applied to module source, regardless of optimization level.
+    source_transforms: SourceTransforms,
+
+    /// How to resolve a module and a package having the same name.
+    module_package_collision: ModulePackageCollisionPolicy,
+
+    /// How to handle a second version of a collected distribution.
+    distribution_version_conflict: DistributionVersionConflictPolicy,
+
+    /// Path to a file of OSV advisories to audit distributions against.
+    vulnerability_database: Option<PathBuf>,
+
+    /// Whether distributions with known vulnerabilities are an error.
+    strict_vulnerability_audit: bool,
+
+    /// Whether to describe console scripts in a synthesized module.
+    console_script_usage: bool,
+
+    /// Whether a generated interpreter configuration reading environment variables is an error.
+    forbid_runtime_env: bool,
+
+    /// Whether module sources are scanned for syntax newer than the target Python before compiling.
+    scan_syntax_compatibility: bool,
+
+    /// Whether syntax newer than the target Python found by scanning is an error.
+    strict_syntax_compatibility: bool,
+
+    /// Whether the content of files backing resources is pinned when first read.
+    pin_file_content: bool,
+
+    /// Size in bytes above which pinned file content is spilled to disk.
+    pin_spill_threshold: u64,
+
+    /// Maximum bytes of payloads held in memory while collecting resources.
+    collection_memory_budget: Option<u64>,
+
+    /// How the index of packed resources is encoded.
+    packed_resources_index: IndexEncoding,
+
+    /// How .pyc files installed relative to the binary are invalidated.
+    pyc_invalidation_mode: PycInvalidationMode,
+
+    /// What happens to modules whose source fails to compile.
+    compile_error_policy: CompileErrorPolicy,
+
+    /// Whether the build is expected to be reproducible.
+    reproducible_build: bool,
+
+    /// Path to a file of static analysis verdicts about packages.
+    analysis_verdicts: Option<PathBuf>,
+
+    /// Whether files installed to the same path with identical content are allowed.
+    allow_identical_install_collisions: bool,
+
+    /// Whether to produce a debug companion alongside the binary.
+    debug_companion: bool,
+
+    /// Path to a file of packages never classified as tests.
+    test_classification_overrides: Option<PathBuf>,
+
+    /// Whether filtering keeps resources of entry points loaded by kept modules.
+    protect_entry_point_providers: bool,
+
+    /// Whether distributions installed in editable mode are an error.
+    reject_editable_installs: bool,
+
+    /// Whether internal synthetic resources are left out of module names and reports.
+    hide_internal_resources: bool,
+
+    /// Bytecode optimization levels of modules, keyed by package prefix.
+    bytecode_optimize_level_overrides: BTreeMap<String, BytecodeOptimizationLevel>,
 }
 
 impl Default for PythonPackagingPolicy {
@@ -132,11 +324,44 @@ impl Default for PythonPackagingPolicy {
         PythonPackagingPolicy {
             extension_module_filter: ExtensionModuleFilter::All,
             preferred_extension_module_variants: HashMap::new(),
+            extension_module_variant_pins: BTreeMap::new(),
             resources_policy: PythonResourcesPolicy::InMemoryOnly,
             include_distribution_sources: true,
             include_distribution_resources: false,
             include_test: false,
             broken_extensions: HashMap::new(),
+            strict_library_dependencies: false,
+            extract_library_dependencies: false,
+            thin_fat_binaries: false,
+            dunder_file_waivers: Vec::new(),
+            strict_dunder_file: false,
+            script_rules: Vec::new(),
+            text_normalization: None,
+            locale_filter: None,
+            source_transforms: SourceTransforms::default(),
+            module_package_collision: ModulePackageCollisionPolicy::PreferPackage,
+            distribution_version_conflict: DistributionVersionConflictPolicy::Replace,
+            vulnerability_database: None,
+            strict_vulnerability_audit: false,
+            console_script_usage: false,
+            forbid_runtime_env: false,
+            scan_syntax_compatibility: true,
+            strict_syntax_compatibility: false,
+            pin_file_content: false,
+            pin_spill_threshold: DEFAULT_PIN_SPILL_THRESHOLD,
+            collection_memory_budget: None,
+            packed_resources_index: IndexEncoding::Sequential,
+            pyc_invalidation_mode: PycInvalidationMode::UncheckedHash,
+            compile_error_policy: CompileErrorPolicy::Abort,
+            reproducible_build: false,
+            analysis_verdicts: None,
+            allow_identical_install_collisions: false,
+            debug_companion: false,
+            test_classification_overrides: None,
+            protect_entry_point_providers: false,
+            reject_editable_installs: false,
+            hide_internal_resources: false,
+            bytecode_optimize_level_overrides: BTreeMap::new(),
         }
     }
 }
@@ -160,6 +385,68 @@ impl PythonPackagingPolicy {
             .insert(extension.to_string(), variant.to_string());
     }
 
+    /// Obtain pinned variants of extension modules.
+    pub fn extension_module_variant_pins(&self) -> &BTreeMap<String, String> {
+        &self.extension_module_variant_pins
+    }
+
+    /// Pin the variant of an extension module.
+    ///
+    /// Unlike a preferred variant, a pin is validated against the variants
+    /// the distribution provides and is never replaced by another variant.
+    pub fn pin_extension_module_variant(&mut self, extension: &str, variant: &str) {
+        self.extension_module_variant_pins
+            .insert(extension.to_string(), variant.to_string());
+    }
+
+    /// Validate extension module variant pins against a distribution's extension modules.
+    ///
+    /// Pins naming a variant the extension module doesn't have are an error
+    /// listing the available variants. Returns warnings for pins on extension
+    /// modules the distribution doesn't provide.
+    pub fn validate_extension_module_variant_pins<'a>(
+        &self,
+        extensions_variants: impl Iterator<Item = &'a PythonExtensionModuleVariants>,
+    ) -> Result<Vec<String>> {
+        let available = extensions_variants
+            .filter(|variants| !variants.is_empty())
+            .map(|variants| (variants.default_variant().name.as_str(), variants))
+            .collect::<HashMap<_, _>>();
+
+        let mut errors = vec![];
+        let mut warnings = vec![];
+
+        for (extension, variant) in &self.extension_module_variant_pins {
+            match available.get(extension.as_str()) {
+                Some(variants) => {
+                    let names = variants.variant_names();
+
+                    if !names.contains(&variant.as_str()) {
+                        errors.push(format!(
+                            "extension module {} has no variant {}; available variants: {}",
+                            extension,
+                            variant,
+                            names.join(", ")
+                        ));
+                    }
+                }
+                None => warnings.push(format!(
+                    "extension module {} is pinned to variant {} but the distribution doesn't provide it",
+                    extension, variant
+                )),
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(warnings)
+        } else {
+            Err(anyhow!(
+                "invalid extension module variant pins: {}",
+                errors.join("; ")
+            ))
+        }
+    }
+
     /// Obtain the active resources policy for this instance.
     pub fn get_resources_policy(&self) -> &PythonResourcesPolicy {
         &self.resources_policy
@@ -185,6 +472,433 @@ impl PythonPackagingPolicy {
         self.include_test = include;
     }
 
+    /// Whether unsatisfiable library dependencies of in-memory extension modules are an error.
+    pub fn strict_library_dependencies(&self) -> bool {
+        self.strict_library_dependencies
+    }
+
+    /// Set whether unsatisfiable library dependencies of in-memory extension modules are an error.
+    pub fn set_strict_library_dependencies(&mut self, strict: bool) {
+        self.strict_library_dependencies = strict;
+    }
+
+    /// Whether library dependencies of in-memory extension modules are extracted at run-time.
+    pub fn extract_library_dependencies(&self) -> bool {
+        self.extract_library_dependencies
+    }
+
+    /// Set whether library dependencies of in-memory extension modules are extracted at run-time.
+    pub fn set_extract_library_dependencies(&mut self, extract: bool) {
+        self.extract_library_dependencies = extract;
+    }
+
+    /// Whether to strip fat Mach-O binaries down to the slice for the target architecture.
+    pub fn thin_fat_binaries(&self) -> bool {
+        self.thin_fat_binaries
+    }
+
+    /// Set whether to strip fat Mach-O binaries down to the slice for the target architecture.
+    pub fn set_thin_fat_binaries(&mut self, thin: bool) {
+        self.thin_fat_binaries = thin;
+    }
+
+    /// Obtain glob patterns of in-memory modules allowed to reference `__file__`.
+    pub fn dunder_file_waivers(&self) -> &[String] {
+        &self.dunder_file_waivers
+    }
+
+    /// Allow in-memory modules whose name matches a glob pattern to reference `__file__`.
+    pub fn add_dunder_file_waiver(&mut self, pattern: &str) {
+        self.dunder_file_waivers.push(pattern.to_string());
+    }
+
+    /// Whether unwaived in-memory modules referencing `__file__` are an error.
+    pub fn strict_dunder_file(&self) -> bool {
+        self.strict_dunder_file
+    }
+
+    /// Set whether unwaived in-memory modules referencing `__file__` are an error.
+    pub fn set_strict_dunder_file(&mut self, strict: bool) {
+        self.strict_dunder_file = strict;
+    }
+
+    /// Obtain how text resources are normalized, if they are.
+    pub fn text_normalization(&self) -> Option<&TextNormalization> {
+        self.text_normalization.as_ref()
+    }
+
+    /// Set how text resources are normalized.
+    ///
+    /// Module source and package resources with a text file extension have
+    /// byte order marks stripped and CRLF line endings converted to LF.
+    /// `None` leaves text resources alone.
+    pub fn set_text_normalization(&mut self, normalization: Option<TextNormalization>) {
+        self.text_normalization = normalization;
+    }
+
+    /// Obtain which locale data of package resources is kept, if it is trimmed.
+    pub fn locale_filter(&self) -> Option<&LocaleFilter> {
+        self.locale_filter.as_ref()
+    }
+
+    /// Set which locale data of package resources is kept.
+    ///
+    /// Package resources in locale directories, such as gettext catalogs
+    /// at `locale/<lang>/LC_MESSAGES/`, are dropped unless the filter keeps
+    /// their language. `None` keeps all locale data.
+    pub fn set_locale_filter(&mut self, filter: Option<LocaleFilter>) {
+        self.locale_filter = filter;
+    }
+
+    /// Obtain transforms applied to module source.
+    pub fn source_transforms(&self) -> &SourceTransforms {
+        &self.source_transforms
+    }
+
+    /// Set transforms applied to module source.
+    ///
+    /// Unlike optimization level 2, stripping docstrings this way keeps
+    /// `assert` statements. Transforms apply before bytecode is compiled,
+    /// so every optimization level is affected.
+    pub fn set_source_transforms(&mut self, transforms: SourceTransforms) {
+        self.source_transforms = transforms;
+    }
+
+    /// Obtain how a module and a package having the same name are resolved.
+    pub fn module_package_collision(&self) -> ModulePackageCollisionPolicy {
+        self.module_package_collision
+    }
+
+    /// Set how a module and a package having the same name are resolved.
+    pub fn set_module_package_collision(&mut self, policy: ModulePackageCollisionPolicy) {
+        self.module_package_collision = policy;
+    }
+
+    /// Obtain how a second version of a collected distribution is handled.
+    pub fn distribution_version_conflict(&self) -> DistributionVersionConflictPolicy {
+        self.distribution_version_conflict
+    }
+
+    /// Set how a second version of a collected distribution is handled.
+    pub fn set_distribution_version_conflict(&mut self, policy: DistributionVersionConflictPolicy) {
+        self.distribution_version_conflict = policy;
+    }
+
+    /// Obtain the path to a file of OSV advisories to audit distributions against.
+    pub fn vulnerability_database(&self) -> Option<&Path> {
+        self.vulnerability_database.as_deref()
+    }
+
+    /// Set the path to a file of OSV advisories to audit distributions against.
+    ///
+    /// Distributions aren't audited if not set.
+    pub fn set_vulnerability_database(&mut self, path: Option<PathBuf>) {
+        self.vulnerability_database = path;
+    }
+
+    /// Whether distributions with known vulnerabilities are an error.
+    pub fn strict_vulnerability_audit(&self) -> bool {
+        self.strict_vulnerability_audit
+    }
+
+    /// Set whether distributions with known vulnerabilities are an error.
+    pub fn set_strict_vulnerability_audit(&mut self, strict: bool) {
+        self.strict_vulnerability_audit = strict;
+    }
+
+    /// Whether to describe console scripts in a synthesized module.
+    pub fn console_script_usage(&self) -> bool {
+        self.console_script_usage
+    }
+
+    /// Set whether to describe console scripts in a synthesized module.
+    ///
+    /// The `pyoxidizer_console_scripts` module lists the console scripts
+    /// of packaged distributions with the summary of their target module's
+    /// docstring. The build manifest records them as well. The module is
+    /// only added if there are console scripts.
+    pub fn set_console_script_usage(&mut self, enabled: bool) {
+        self.console_script_usage = enabled;
+    }
+
+    /// Whether a generated interpreter configuration reading environment variables is an error.
+    pub fn forbid_runtime_env(&self) -> bool {
+        self.forbid_runtime_env
+    }
+
+    /// Set whether a generated interpreter configuration reading environment variables is an error.
+    pub fn set_forbid_runtime_env(&mut self, forbid: bool) {
+        self.forbid_runtime_env = forbid;
+    }
+
+    /// Whether module sources are scanned for syntax newer than the target Python before compiling.
+    pub fn scan_syntax_compatibility(&self) -> bool {
+        self.scan_syntax_compatibility
+    }
+
+    /// Set whether module sources are scanned for syntax newer than the target Python.
+    ///
+    /// The scan is best-effort and can report false positives, so findings
+    /// are warnings unless strict syntax compatibility is enabled.
+    pub fn set_scan_syntax_compatibility(&mut self, scan: bool) {
+        self.scan_syntax_compatibility = scan;
+    }
+
+    /// Whether syntax newer than the target Python found by scanning is an error.
+    pub fn strict_syntax_compatibility(&self) -> bool {
+        self.strict_syntax_compatibility
+    }
+
+    /// Set whether syntax newer than the target Python found by scanning is an error.
+    pub fn set_strict_syntax_compatibility(&mut self, strict: bool) {
+        self.strict_syntax_compatibility = strict;
+    }
+
+    /// Whether to produce a debug companion alongside the binary.
+    pub fn debug_companion(&self) -> bool {
+        self.debug_companion
+    }
+
+    /// Set whether to produce a debug companion alongside the binary.
+    ///
+    /// The companion holds module sources, unoptimized bytecode and
+    /// extension modules as they were before being thinned. Producing it
+    /// doesn't change the binary.
+    pub fn set_debug_companion(&mut self, enabled: bool) {
+        self.debug_companion = enabled;
+    }
+
+    /// Whether the content of files backing resources is pinned when first read.
+    pub fn pin_file_content(&self) -> bool {
+        self.pin_file_content
+    }
+
+    /// Set whether the content of files backing resources is pinned when first read.
+    ///
+    /// When pinned, producing a binary reads each file once, so its output
+    /// is consistent even if files change during the build.
+    pub fn set_pin_file_content(&mut self, pin: bool) {
+        self.pin_file_content = pin;
+    }
+
+    /// Obtain the size in bytes above which pinned file content is spilled to disk.
+    pub fn pin_spill_threshold(&self) -> u64 {
+        self.pin_spill_threshold
+    }
+
+    /// Set the size in bytes above which pinned file content is spilled to disk.
+    ///
+    /// Smaller content is held in memory.
+    pub fn set_pin_spill_threshold(&mut self, threshold: u64) {
+        self.pin_spill_threshold = threshold;
+    }
+
+    /// Obtain the maximum bytes of payloads held in memory while collecting resources.
+    pub fn collection_memory_budget(&self) -> Option<u64> {
+        self.collection_memory_budget
+    }
+
+    /// Set the maximum bytes of payloads held in memory while collecting resources.
+    ///
+    /// Payloads beyond the budget are spilled to a temporary directory and
+    /// read back when the binary is produced. `None` means no limit.
+    pub fn set_collection_memory_budget(&mut self, budget: Option<u64>) {
+        self.collection_memory_budget = budget;
+    }
+
+    /// Obtain how the index of packed resources is encoded.
+    pub fn packed_resources_index(&self) -> IndexEncoding {
+        self.packed_resources_index
+    }
+
+    /// Set how the index of packed resources is encoded.
+    ///
+    /// `IndexEncoding::LookupTable` lets resources be found without parsing
+    /// the entire index, at the cost of a larger index.
+    pub fn set_packed_resources_index(&mut self, encoding: IndexEncoding) {
+        self.packed_resources_index = encoding;
+    }
+
+    /// Obtain how .pyc files installed relative to the binary are invalidated.
+    pub fn pyc_invalidation_mode(&self) -> PycInvalidationMode {
+        self.pyc_invalidation_mode
+    }
+
+    /// Set how .pyc files installed relative to the binary are invalidated.
+    ///
+    /// Hash based modes make .pyc files independent of when sources were
+    /// written, which timestamp based ones only are with a fixed timestamp.
+    pub fn set_pyc_invalidation_mode(&mut self, mode: PycInvalidationMode) {
+        self.pyc_invalidation_mode = mode;
+    }
+
+    /// Obtain what happens to modules whose source fails to compile.
+    pub fn compile_error_policy(&self) -> CompileErrorPolicy {
+        self.compile_error_policy
+    }
+
+    /// Set what happens to modules whose source fails to compile.
+    ///
+    /// `CompileErrorPolicy::SourceOnly` lets packages with source Python
+    /// can't compile, e.g. Python 2 only syntax, be embedded as source.
+    pub fn set_compile_error_policy(&mut self, policy: CompileErrorPolicy) {
+        self.compile_error_policy = policy;
+    }
+
+    /// Whether the build is expected to be reproducible.
+    pub fn reproducible_build(&self) -> bool {
+        self.reproducible_build
+    }
+
+    /// Set whether the build is expected to be reproducible.
+    ///
+    /// Bytecode is compiled by reproducible compilers and timestamp based
+    /// .pyc files record `PycTimestamp::reproducible()` instead of source
+    /// modification times. It also enables warnings about outputs that
+    /// still vary between builds.
+    pub fn set_reproducible_build(&mut self, reproducible: bool) {
+        self.reproducible_build = reproducible;
+    }
+
+    /// Obtain bytecode optimization levels of modules, keyed by package prefix.
+    pub fn bytecode_optimize_level_overrides(
+        &self,
+    ) -> &BTreeMap<String, BytecodeOptimizationLevel> {
+        &self.bytecode_optimize_level_overrides
+    }
+
+    /// Set the bytecode optimization level of modules in a package.
+    ///
+    /// The override applies to the module named `prefix` and all modules
+    /// beneath it. An empty prefix matches every module.
+    pub fn set_bytecode_optimize_level(&mut self, prefix: &str, level: BytecodeOptimizationLevel) {
+        self.bytecode_optimize_level_overrides
+            .insert(prefix.to_string(), level);
+    }
+
+    /// Resolve the bytecode optimization level of a module.
+    ///
+    /// The override with the longest package prefix of `name` wins, so
+    /// `foo.bar` takes precedence over `foo` for `foo.bar.baz`. Prefixes only
+    /// match whole name components: `foo` doesn't match `foobar`. Modules
+    /// without an override are compiled at level 0.
+    pub fn resolve_bytecode_optimize_level(&self, name: &str) -> BytecodeOptimizationLevel {
+        self.bytecode_optimize_level_overrides
+            .iter()
+            .filter(|(prefix, _)| {
+                prefix.is_empty()
+                    || name == prefix.as_str()
+                    || (name.starts_with(prefix.as_str()) && name[prefix.len()..].starts_with('.'))
+            })
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(BytecodeOptimizationLevel::Zero)
+    }
+
+    /// Obtain the request to compile a module's source to bytecode.
+    ///
+    /// The optimization level is resolved by `resolve_bytecode_optimize_level()`.
+    pub fn bytecode_request(&self, module: &PythonModuleSource) -> PythonModuleBytecodeFromSource {
+        module.as_bytecode_module(self.resolve_bytecode_optimize_level(&module.name))
+    }
+
+    /// Obtain the path to a file of static analysis verdicts about packages.
+    pub fn analysis_verdicts(&self) -> Option<&Path> {
+        self.analysis_verdicts.as_deref()
+    }
+
+    /// Set the path to a file of static analysis verdicts about packages.
+    ///
+    /// Verdicts are consulted ahead of built-in heuristics when placing
+    /// resources and checking licenses and subinterpreter compatibility.
+    pub fn set_analysis_verdicts(&mut self, path: Option<PathBuf>) {
+        self.analysis_verdicts = path;
+    }
+
+    /// Obtain the path to a file of packages never classified as tests.
+    pub fn test_classification_overrides(&self) -> Option<&Path> {
+        self.test_classification_overrides.as_deref()
+    }
+
+    /// Set the path to a file of packages never classified as tests.
+    ///
+    /// Resources of installed packages are classified as tests by their
+    /// location. The file lists packages of distributions whose test-like
+    /// names hold runtime code. See the `test_classification` module.
+    pub fn set_test_classification_overrides(&mut self, path: Option<PathBuf>) {
+        self.test_classification_overrides = path;
+    }
+
+    /// Whether filtering keeps resources of entry points loaded by kept modules.
+    pub fn protect_entry_point_providers(&self) -> bool {
+        self.protect_entry_point_providers
+    }
+
+    /// Set whether filtering keeps resources of entry points loaded by kept modules.
+    ///
+    /// Modules loading plugins from an entry point group need the metadata
+    /// of distributions declaring entry points in that group, and the
+    /// modules those entry points reference. If not set, filtering them
+    /// away only produces a warning.
+    pub fn set_protect_entry_point_providers(&mut self, protect: bool) {
+        self.protect_entry_point_providers = protect;
+    }
+
+    /// Whether distributions installed in editable mode are an error.
+    pub fn reject_editable_installs(&self) -> bool {
+        self.reject_editable_installs
+    }
+
+    /// Set whether distributions installed in editable mode are an error.
+    ///
+    /// An editable install's `direct_url.json` points at a source directory
+    /// on the machine that installed it, which won't exist at run-time.
+    pub fn set_reject_editable_installs(&mut self, reject: bool) {
+        self.reject_editable_installs = reject;
+    }
+
+    /// Whether internal synthetic resources are left out of module names and reports.
+    pub fn hide_internal_resources(&self) -> bool {
+        self.hide_internal_resources
+    }
+
+    /// Set whether internal synthetic resources are left out of module names and reports.
+    ///
+    /// Features reserving a name with `SyntheticVisibility::Internal`
+    /// synthesize helper modules users may not want listed. Hidden resources
+    /// are still in packed resources so they remain importable.
+    pub fn set_hide_internal_resources(&mut self, hide: bool) {
+        self.hide_internal_resources = hide;
+    }
+
+    /// Whether files installed to the same path with identical content are allowed.
+    pub fn allow_identical_install_collisions(&self) -> bool {
+        self.allow_identical_install_collisions
+    }
+
+    /// Set whether files installed to the same path with identical content are allowed.
+    ///
+    /// Files installed to the same path are an error. If allowed, files
+    /// with identical content only produce a warning.
+    pub fn set_allow_identical_install_collisions(&mut self, allow: bool) {
+        self.allow_identical_install_collisions = allow;
+    }
+
+    /// Obtain rules for processing scripts installed as files.
+    pub fn script_rules(&self) -> &[ScriptRule] {
+        &self.script_rules
+    }
+
+    /// Register an action for scripts whose install path matches a regular expression.
+    ///
+    /// Rules are evaluated in the order they are added. The first matching
+    /// rule wins.
+    pub fn add_script_rule(&mut self, pattern: &str, action: ScriptAction) -> Result<()> {
+        self.script_rules.push(ScriptRule::new(pattern, action)?);
+
+        Ok(())
+    }
+
     /// Mark an extension as broken on a target platform, preventing it from being used.
     pub fn register_broken_extension(&mut self, target_triple: &str, extension: &str) {
         if !self.broken_extensions.contains_key(target_triple) {
@@ -239,6 +953,31 @@ impl PythonPackagingPolicy {
     ) -> Result<Vec<PythonExtensionModule>> {
         let mut res = vec![];
 
+        // Pins take precedence over preferences.
+        let mut preferences = self.preferred_extension_module_variants.clone();
+        preferences.extend(
+            self.extension_module_variant_pins
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        );
+
+        let choose = |variants: &PythonExtensionModuleVariants| -> Result<PythonExtensionModule> {
+            let chosen = variants.choose_variant(&preferences);
+
+            if let Some(pin) = self.extension_module_variant_pins.get(&chosen.name) {
+                if chosen.variant.as_deref() != Some(pin.as_str()) {
+                    return Err(anyhow!(
+                        "extension module {} is pinned to variant {}, which the extension module filter excludes; allowed variants: {}",
+                        chosen.name,
+                        pin,
+                        variants.variant_names().join(", ")
+                    ));
+                }
+            }
+
+            Ok(chosen.clone())
+        };
+
         for variants in extensions_variants {
             let name = &variants.default_variant().name;
 
@@ -264,11 +1003,7 @@ impl PythonPackagingPolicy {
                 }));
 
             if !ext_variants.is_empty() {
-                res.push(
-                    ext_variants
-                        .choose_variant(&self.preferred_extension_module_variants)
-                        .clone(),
-                );
+                res.push(choose(&ext_variants)?);
             }
 
             match self.extension_module_filter {
@@ -276,11 +1011,7 @@ impl PythonPackagingPolicy {
                 ExtensionModuleFilter::Minimal => {}
 
                 ExtensionModuleFilter::All => {
-                    res.push(
-                        variants
-                            .choose_variant(&self.preferred_extension_module_variants)
-                            .clone(),
-                    );
+                    res.push(choose(variants)?);
                 }
 
                 ExtensionModuleFilter::NoLibraries => {
@@ -295,11 +1026,7 @@ impl PythonPackagingPolicy {
                     );
 
                     if !ext_variants.is_empty() {
-                        res.push(
-                            ext_variants
-                                .choose_variant(&self.preferred_extension_module_variants)
-                                .clone(),
-                        );
+                        res.push(choose(&ext_variants)?);
                     }
                 }
 
@@ -333,11 +1060,7 @@ impl PythonPackagingPolicy {
                     );
 
                     if !ext_variants.is_empty() {
-                        res.push(
-                            ext_variants
-                                .choose_variant(&self.preferred_extension_module_variants)
-                                .clone(),
-                        );
+                        res.push(choose(&ext_variants)?);
                     }
                 }
             }
@@ -346,3 +1069,56 @@ impl PythonPackagingPolicy {
         Ok(res)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_bytecode_optimize_level() {
+        let mut policy = PythonPackagingPolicy::default();
+
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foo"),
+            BytecodeOptimizationLevel::Zero
+        );
+
+        policy.set_bytecode_optimize_level("foo", BytecodeOptimizationLevel::Two);
+        policy.set_bytecode_optimize_level("foo.bar", BytecodeOptimizationLevel::One);
+
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foo"),
+            BytecodeOptimizationLevel::Two
+        );
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foo.baz"),
+            BytecodeOptimizationLevel::Two
+        );
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foo.bar"),
+            BytecodeOptimizationLevel::One
+        );
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foo.bar.baz"),
+            BytecodeOptimizationLevel::One
+        );
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foo.barbaz"),
+            BytecodeOptimizationLevel::Two
+        );
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foobar"),
+            BytecodeOptimizationLevel::Zero
+        );
+
+        policy.set_bytecode_optimize_level("", BytecodeOptimizationLevel::One);
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foobar"),
+            BytecodeOptimizationLevel::One
+        );
+        assert_eq!(
+            policy.resolve_bytecode_optimize_level("foo.baz"),
+            BytecodeOptimizationLevel::Two
+        );
+    }
+}
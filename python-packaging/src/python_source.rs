@@ -4,11 +4,23 @@
 
 /*! Utility functions related to Python source code. */
 
-use {anyhow::Result, lazy_static::lazy_static};
+use {anyhow::Result, lazy_static::lazy_static, std::collections::BTreeSet};
 
 lazy_static! {
     static ref RE_CODING: regex::bytes::Regex =
         regex::bytes::Regex::new(r"^[ \t\f]*#.*?coding[:=][ \t]*([-_.a-zA-Z0-9]+)").unwrap();
+    static ref RE_IMPORT: regex::Regex =
+        regex::Regex::new(r"(?m)^[ \t]*import[ \t]+([^\n#;]+)").unwrap();
+    static ref RE_FROM_IMPORT: regex::Regex = regex::Regex::new(
+        r"(?m)^[ \t]*from[ \t]+(\.*[\w.]*)[ \t]+import[ \t]+(\([^)]*\)|[^\n#;]+)"
+    )
+    .unwrap();
+    static ref RE_SYSCONFIG_PATHS: regex::Regex =
+        regex::Regex::new(r"\bget_paths?[ \t]*\(").unwrap();
+    static ref RE_ENTRY_POINT_GROUP: regex::Regex = regex::Regex::new(
+        r#"\b(?:iter_entry_points|load_setuptools_entrypoints|entry_points(?:\(\)\.select)?)[ \t]*\([ \t]*(?:group[ \t]*=[ \t]*)?['"]([\w.\-]+)['"]"#
+    )
+    .unwrap();
 }
 
 /// Derive the source encoding from Python source code.
@@ -31,11 +43,8 @@ pub fn python_source_encoding(source: &[u8]) -> Vec<u8> {
     b"utf-8".to_vec()
 }
 
-/// Whether __file__ occurs in Python source code.
-pub fn has_dunder_file(source: &[u8]) -> Result<bool> {
-    // We can't just look for b"__file__ because the source file may be in
-    // encodings like UTF-16. So we need to decode to Unicode first then look for
-    // the code points.
+/// Decode Python source code to Unicode using its declared encoding.
+pub(crate) fn decode_source(source: &[u8]) -> std::borrow::Cow<str> {
     let encoding = python_source_encoding(source);
 
     let encoder = match encoding_rs::Encoding::for_label(&encoding) {
@@ -45,5 +54,335 @@ pub fn has_dunder_file(source: &[u8]) -> Result<bool> {
 
     let (source, ..) = encoder.decode(source);
 
-    Ok(source.contains("__file__"))
+    source
+}
+
+/// Whether __file__ occurs in Python source code.
+pub fn has_dunder_file(source: &[u8]) -> Result<bool> {
+    // We can't just look for b"__file__ because the source file may be in
+    // encodings like UTF-16. So we need to decode to Unicode first then look for
+    // the code points.
+    Ok(decode_source(source).contains("__file__"))
+}
+
+/// Whether Python source code possibly queries the site-packages directory.
+///
+/// This looks for `site.getsitepackages()` and for `sysconfig.get_path()`
+/// or `sysconfig.get_paths()` in sources referencing `sysconfig`. Like
+/// `has_dunder_file()`, it is a textual scan and may report false positives.
+pub fn uses_site_packages_paths(source: &[u8]) -> bool {
+    let source = decode_source(source);
+
+    source.contains("getsitepackages")
+        || (source.contains("sysconfig") && RE_SYSCONFIG_PATHS.is_match(&source))
+}
+
+/// Find entry point groups Python source code possibly loads plugins from.
+///
+/// This looks for group names passed as string literals to
+/// `pkg_resources.iter_entry_points()`, `importlib.metadata.entry_points()`
+/// and its `select()` method, and pluggy's `load_setuptools_entrypoints()`.
+/// Groups computed at run-time are missed, so an empty result doesn't mean
+/// entry points aren't consumed. Like `has_dunder_file()`, it is a textual
+/// scan and may report false positives.
+pub fn find_entry_point_groups(source: &[u8]) -> BTreeSet<String> {
+    let source = decode_source(source);
+
+    RE_ENTRY_POINT_GROUP
+        .captures_iter(&source)
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// Find names of modules possibly imported by Python source code.
+///
+/// `name` and `is_package` describe the module the source belongs to and
+/// are used to resolve relative imports. For `from X import Y`, both `X` and
+/// `X.Y` are returned since `Y` may be a submodule.
+///
+/// This is a textual scan, not a parse. Import statements in strings are
+/// reported and dynamic imports are missed. So results should be treated
+/// as candidates.
+pub fn find_imports(source: &[u8], name: &str, is_package: bool) -> BTreeSet<String> {
+    let source = decode_source(source);
+    let mut res = BTreeSet::new();
+
+    let strip_alias = |s: &str| -> String { s.split_whitespace().next().unwrap_or("").to_string() };
+
+    for captures in RE_IMPORT.captures_iter(&source) {
+        for part in captures[1].trim_end_matches('\\').split(',') {
+            let module = strip_alias(part);
+            if !module.is_empty() {
+                res.insert(module);
+            }
+        }
+    }
+
+    for captures in RE_FROM_IMPORT.captures_iter(&source) {
+        let from = &captures[1];
+        let dots = from.chars().take_while(|c| *c == '.').count();
+
+        let base = if dots == 0 {
+            from.to_string()
+        } else {
+            // A package's relative imports are relative to itself. A
+            // module's are relative to its package.
+            let mut parts = name.split('.').collect::<Vec<_>>();
+            let up = if is_package { dots - 1 } else { dots };
+
+            // Relative imports beyond the top-level package fail.
+            if up >= parts.len() {
+                continue;
+            }
+            parts.truncate(parts.len() - up);
+
+            let rest = &from[dots..];
+            if !rest.is_empty() {
+                parts.push(rest);
+            }
+
+            parts.join(".")
+        };
+
+        if !base.is_empty() {
+            res.insert(base.clone());
+        }
+
+        let names = captures[2].trim_start_matches('(').trim_end_matches(')');
+        for part in names.split(',') {
+            let imported = strip_alias(part.trim_matches(|c: char| c.is_whitespace() || c == '\\'));
+            if imported.is_empty() || imported == "*" {
+                continue;
+            }
+
+            res.insert(if base.is_empty() {
+                imported
+            } else {
+                format!("{}.{}", base, imported)
+            });
+        }
+    }
+
+    res
+}
+
+/// Skip whitespace, comments and line continuations at the start of source.
+///
+/// Newlines and comments are only skipped if `newlines` is true. Otherwise
+/// they terminate the current statement and must be preserved.
+fn skip_insignificant(mut s: &str, newlines: bool) -> &str {
+    loop {
+        let trimmed = s.trim_start_matches(|c: char| {
+            c == ' ' || c == '\t' || c == '\x0c' || (newlines && (c == '\n' || c == '\r'))
+        });
+
+        let trimmed = if trimmed.starts_with("\\\n") {
+            &trimmed[2..]
+        } else if trimmed.starts_with("\\\r\n") {
+            &trimmed[3..]
+        } else if newlines && trimmed.starts_with('#') {
+            match trimmed.find('\n') {
+                Some(pos) => &trimmed[pos..],
+                None => "",
+            }
+        } else {
+            trimmed
+        };
+
+        if trimmed.len() == s.len() {
+            return s;
+        }
+
+        s = trimmed;
+    }
+}
+
+/// Parse a string literal at the start of source.
+///
+/// Returns the value of the literal and the remaining source. Returns `None`
+/// if the source doesn't start with a literal that can be a docstring. Bytes
+/// and f-strings can't be.
+fn parse_string_literal(s: &str) -> Option<(String, &str)> {
+    let prefix_len = s
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or_else(|| s.len());
+    let prefix = &s[0..prefix_len];
+
+    if prefix.len() > 1 || !prefix.chars().all(|c| "rRuU".contains(c)) {
+        return None;
+    }
+
+    let raw = prefix.eq_ignore_ascii_case("r");
+    let s = &s[prefix_len..];
+
+    let quote = ["\"\"\"", "'''", "\"", "'"]
+        .iter()
+        .find(|quote| s.starts_with(*quote))?;
+    let body = &s[quote.len()..];
+
+    let mut value = String::new();
+    let mut chars = body.char_indices();
+
+    loop {
+        let (pos, c) = chars.next()?;
+
+        if body[pos..].starts_with(quote) {
+            return Some((value, &body[pos + quote.len()..]));
+        }
+
+        match c {
+            '\\' => {
+                let (_, escaped) = chars.next()?;
+
+                if raw {
+                    value.push('\\');
+                    value.push(escaped);
+                    continue;
+                }
+
+                // Only common escapes are decoded. Others are retained verbatim,
+                // which is good enough for documentation.
+                match escaped {
+                    '\n' => {}
+                    'n' => value.push('\n'),
+                    't' => value.push('\t'),
+                    'r' => value.push('\r'),
+                    '\\' | '\'' | '"' => value.push(escaped),
+                    other => {
+                        value.push('\\');
+                        value.push(other);
+                    }
+                }
+            }
+            '\n' if quote.len() == 1 => return None,
+            c => value.push(c),
+        }
+    }
+}
+
+/// Extract the docstring of a module from its source code.
+///
+/// The source is tokenized, not executed. The docstring is the string
+/// literal, or implicitly concatenated literals, forming the first statement.
+/// Returns `None` if the first statement is anything else, including
+/// expressions that merely start with a string literal.
+pub fn module_docstring(source: &[u8]) -> Option<String> {
+    let source = decode_source(source);
+    let mut s = skip_insignificant(source.trim_start_matches('\u{feff}'), true);
+    let mut docstring = String::new();
+
+    loop {
+        let (value, rest) = parse_string_literal(s)?;
+        docstring.push_str(&value);
+
+        s = skip_insignificant(rest, false);
+
+        if s.is_empty() || s.starts_with(|c: char| c == '\n' || c == '\r' || c == '#' || c == ';') {
+            return Some(docstring);
+        }
+    }
+}
+
+/// Obtain the summary line of a docstring.
+///
+/// This is its first non-blank line, without surrounding whitespace.
+pub fn docstring_summary(docstring: &str) -> String {
+    docstring
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or("")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_module_docstring() {
+        assert_eq!(
+            module_docstring(
+                b"#!/usr/bin/env python\n# comment\n\n\"\"\"Summary.\n\nMore.\n\"\"\"\nimport os\n"
+            ),
+            Some("Summary.\n\nMore.\n".to_string())
+        );
+        assert_eq!(
+            module_docstring(b"'single' \\\n  r'\\raw'  # comment\n"),
+            Some("single\\raw".to_string())
+        );
+        assert_eq!(
+            module_docstring(b"u\"a\\tb\\\"\"; x = 1"),
+            Some("a\tb\"".to_string())
+        );
+        assert_eq!(module_docstring(b"\"\"\"unterminated"), None);
+        assert_eq!(module_docstring(b""), None);
+        assert_eq!(
+            module_docstring(b"import os\n\"\"\"Not a docstring.\"\"\"\n"),
+            None
+        );
+        assert_eq!(module_docstring(b"\"\", \"\".join(x)\n"), None);
+        assert_eq!(module_docstring(b"\"%s\" % x\n"), None);
+        assert_eq!(module_docstring(b"b\"bytes\"\n"), None);
+        assert_eq!(module_docstring(b"f\"format\"\n"), None);
+        assert_eq!(module_docstring(b"r = 1\n"), None);
+        assert_eq!(module_docstring(b"'line\nbreak'\n"), None);
+    }
+
+    #[test]
+    fn test_uses_site_packages_paths() {
+        assert!(uses_site_packages_paths(
+            b"import site\nsite.getsitepackages()\n"
+        ));
+        assert!(uses_site_packages_paths(
+            b"import sysconfig\nsysconfig.get_paths()[\"purelib\"]\n"
+        ));
+        assert!(uses_site_packages_paths(
+            b"from sysconfig import get_path\nget_path('platlib')\n"
+        ));
+        assert!(!uses_site_packages_paths(b"def get_paths():\n    pass\n"));
+        assert!(!uses_site_packages_paths(
+            b"import sysconfig\nsysconfig.get_config_vars()\n"
+        ));
+    }
+
+    #[test]
+    fn test_find_entry_point_groups() {
+        let groups = |source: &[u8]| {
+            find_entry_point_groups(source)
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            groups(b"for ep in pkg_resources.iter_entry_points('flake8.extension'):\n"),
+            vec!["flake8.extension"]
+        );
+        assert_eq!(
+            groups(b"eps = entry_points(group=\"console_scripts\")\n"),
+            vec!["console_scripts"]
+        );
+        assert_eq!(
+            groups(b"entry_points().select(group='babel.extractors')\n"),
+            vec!["babel.extractors"]
+        );
+        assert_eq!(
+            groups(b"pm.load_setuptools_entrypoints(\"pytest11\")\n"),
+            vec!["pytest11"]
+        );
+
+        // Groups computed at run-time aren't known.
+        assert!(groups(b"iter_entry_points(self.group)\n").is_empty());
+        assert!(groups(b"entry_points()\n").is_empty());
+        assert!(groups(b"def entry_points_for(name):\n    pass\n").is_empty());
+    }
+
+    #[test]
+    fn test_docstring_summary() {
+        assert_eq!(
+            docstring_summary("\n   Summary line.\n\n   More.\n"),
+            "Summary line."
+        );
+        assert_eq!(docstring_summary(""), "");
+    }
 }
@@ -5,41 +5,1098 @@
 /*! Defines types representing Python resources. */
 
 use {
-    crate::bytecode::{CompileMode, PythonBytecodeCompiler},
+    crate::bytecode::{
+        magic_number_for_python_version, python_version_from_cache_tag,
+        python_version_from_magic_number, BytecodeHeader, CompileMode, PycTimestamp,
+        PythonBytecodeCompiler,
+    },
+    crate::implementation::InterpreterImplementation,
     crate::module_util::{
         is_package_from_path, packages_from_module_name, resolve_path_for_module,
     },
+    crate::package_metadata::PythonPackageRecord,
     crate::python_source::has_dunder_file,
+    crate::source_transform,
     anyhow::{anyhow, Context, Result},
-    std::collections::HashMap,
+    serde::{Deserialize, Deserializer, Serialize, Serializer},
+    sha2::{Digest, Sha256},
+    std::collections::{BTreeMap, HashMap, HashSet},
     std::convert::TryFrom,
     std::hash::BuildHasher,
+    std::io::{Cursor, Read, Seek, SeekFrom, Write},
     std::iter::FromIterator,
     std::path::{Path, PathBuf},
+    std::sync::{Arc, Mutex},
+    std::time::SystemTime,
 };
 
+/// An algorithm content digests are computed with.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum DigestAlgorithm {
+    /// SHA-256.
+    Sha256,
+
+    /// BLAKE3.
+    Blake3,
+}
+
+impl DigestAlgorithm {
+    /// Parse an instance from its string form.
+    pub fn parse(value: &str) -> Result<Self> {
+        match value {
+            "sha256" => Ok(DigestAlgorithm::Sha256),
+            "blake3" => Ok(DigestAlgorithm::Blake3),
+            _ => Err(anyhow!(
+                "unsupported digest algorithm {}; expected sha256 or blake3",
+                value
+            )),
+        }
+    }
+
+    /// Compute the hex digest of content read from a reader.
+    fn digest_reader(&self, reader: &mut dyn Read) -> Result<String> {
+        match self {
+            DigestAlgorithm::Sha256 => {
+                let mut hasher = Sha256::new();
+                std::io::copy(reader, &mut hasher)?;
+
+                Ok(hex::encode(hasher.finalize()))
+            }
+            DigestAlgorithm::Blake3 => {
+                let mut hasher = blake3::Hasher::new();
+                std::io::copy(reader, &mut hasher)?;
+
+                Ok(hex::encode(hasher.finalize()))
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for DigestAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            DigestAlgorithm::Sha256 => "sha256",
+            DigestAlgorithm::Blake3 => "blake3",
+        })
+    }
+}
+
+/// A file mapped into memory.
+///
+/// Reading a mapping past the end of a file truncated after it was mapped
+/// faults, e.g. with `SIGBUS` on unix. Checking the file beforehand can't
+/// rule that out, since it may be truncated right after the check. So only
+/// files the build owns are mapped: files it wrote itself, like spilled or
+/// pinned content, which nothing modifies while they are in use. Other files
+/// are read into memory.
+///
+/// The size and modification time of mapped files are still recorded when
+/// their location is constructed and checked before every access, see
+/// `ContentCache::check_stamp()`. This reports a build that broke the
+/// guarantee as an error where it can.
+struct MappedFile {
+    map: memmap::Mmap,
+}
+
+impl MappedFile {
+    /// Map a file the build owns into memory.
+    fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+        // SAFETY: Only files the build owns are mapped, as required by
+        // `DataLocation::from_path_mapped()`. They aren't modified or
+        // truncated while mapped.
+        let map = unsafe { memmap::Mmap::map(&file) }
+            .context(format!("memory mapping {}", path.display()))?;
+
+        Ok(Self { map })
+    }
+}
+
+/// Content held by a `ContentCache`.
+#[derive(Clone)]
+enum CachedContent {
+    /// Content read into memory.
+    Owned(Arc<Vec<u8>>),
+    /// A file mapped into memory.
+    Mapped(Arc<MappedFile>),
+}
+
+impl AsRef<[u8]> for CachedContent {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            CachedContent::Owned(data) => data.as_slice(),
+            CachedContent::Mapped(mapped) => &mapped.map[..],
+        }
+    }
+}
+
+/// The size and modification time of a file, recorded when it was checked.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct FileStamp {
+    /// Size of the file in bytes.
+    pub size: u64,
+
+    /// When the file was last modified, if the platform reports it.
+    pub modified: Option<SystemTime>,
+}
+
+impl FileStamp {
+    /// Obtain the stamp of a file, which must exist and not be a directory.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let metadata = match std::fs::metadata(path) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Err(anyhow!("{} does not exist", path.display()));
+            }
+            Err(e) => {
+                return Err(e).context(format!("reading metadata of {}", path.display()));
+            }
+        };
+
+        if metadata.is_dir() {
+            return Err(anyhow!("{} is a directory, not a file", path.display()));
+        }
+
+        Ok(Self {
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+}
+
+/// Content of a file read by a `DataLocation`.
+///
+/// Clones share the same content, so a file is read at most once no matter
+/// how many copies of its location resolve it. Caching can be disabled for
+/// content that must not be held in memory, like payloads spilled to disk.
+/// Large files can also be memory mapped instead of read, so they don't
+/// occupy heap memory.
+///
+/// Digests of the content are cached alongside it, since they are small.
+/// Caches that never hold content don't cache digests either, as the file
+/// may change between resolutions.
+///
+/// The cache isn't part of the identity of a location: all caches compare
+/// equal.
+#[derive(Clone)]
+pub struct ContentCache {
+    content: Option<Arc<Mutex<Option<CachedContent>>>>,
+    map_files: bool,
+    stamp: Option<FileStamp>,
+    digests: Option<Arc<Mutex<BTreeMap<DigestAlgorithm, String>>>>,
+}
+
+impl ContentCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self {
+            content: Some(Arc::new(Mutex::new(None))),
+            map_files: false,
+            stamp: None,
+            digests: Some(Arc::new(Mutex::new(BTreeMap::new()))),
+        }
+    }
+
+    /// Create a cache that never holds content or digests.
+    pub fn disabled() -> Self {
+        Self {
+            content: None,
+            map_files: false,
+            stamp: None,
+            digests: None,
+        }
+    }
+
+    /// Create an empty cache that memory maps a file instead of reading it.
+    ///
+    /// `stamp` is the stamp of the file when it was added, which every access
+    /// to the mapping is checked against.
+    fn mapped(stamp: FileStamp) -> Self {
+        Self {
+            map_files: true,
+            stamp: Some(stamp),
+            ..Self::new()
+        }
+    }
+
+    /// Whether content has been read into the cache.
+    pub fn is_populated(&self) -> bool {
+        match &self.content {
+            Some(content) => content.lock().unwrap().is_some(),
+            None => false,
+        }
+    }
+
+    /// Whether files are memory mapped instead of read.
+    pub fn is_mapped(&self) -> bool {
+        self.map_files
+    }
+
+    /// Obtain cached content, if any.
+    ///
+    /// Mapped files are checked against their recorded stamp first.
+    fn cached(&self, path: &Path) -> Result<Option<CachedContent>> {
+        let cached = match &self.content {
+            Some(content) => content.lock().unwrap().clone(),
+            None => None,
+        };
+
+        if cached.is_some() {
+            self.check_stamp(path)?;
+        }
+
+        Ok(cached)
+    }
+
+    /// Ensure a mapped file has the size and modification time it was added
+    /// with.
+    ///
+    /// Errors if the file changed, since reading its mapping could fault.
+    /// Caches that don't map files aren't checked.
+    fn check_stamp(&self, path: &Path) -> Result<()> {
+        match self.stamp {
+            Some(recorded) if self.map_files => {
+                if FileStamp::from_path(path)? != recorded {
+                    Err(anyhow!(
+                        "{} changed since it was memory mapped; files must not be modified during a build",
+                        path.display()
+                    ))
+                } else {
+                    Ok(())
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Obtain a cached digest, computing it with `compute` if it isn't cached.
+    fn get_or_digest<F>(&self, algorithm: DigestAlgorithm, compute: F) -> Result<String>
+    where
+        F: FnOnce() -> Result<String>,
+    {
+        let digests = match &self.digests {
+            Some(digests) => digests,
+            None => return compute(),
+        };
+
+        if let Some(digest) = digests.lock().unwrap().get(&algorithm) {
+            return Ok(digest.clone());
+        }
+
+        // The lock isn't held while hashing so large files don't block
+        // other clones resolving content.
+        let digest = compute()?;
+        digests.lock().unwrap().insert(algorithm, digest.clone());
+
+        Ok(digest)
+    }
+
+    /// Obtain cached content, reading or mapping it from `path` if it isn't
+    /// cached.
+    ///
+    /// Mapped files are checked against their recorded stamp first.
+    fn get_or_read(&self, path: &Path) -> Result<CachedContent> {
+        self.check_stamp(path)?;
+
+        let read = || -> Result<CachedContent> {
+            // Empty files can't be mapped on all platforms.
+            if self.map_files && self.stamp.map_or(false, |stamp| stamp.size > 0) {
+                Ok(CachedContent::Mapped(Arc::new(MappedFile::open(path)?)))
+            } else {
+                Ok(CachedContent::Owned(Arc::new(
+                    std::fs::read(path).context(format!("reading {}", path.display()))?,
+                )))
+            }
+        };
+
+        match &self.content {
+            Some(content) => {
+                let mut content = content.lock().unwrap();
+
+                match &*content {
+                    Some(data) => Ok(data.clone()),
+                    None => {
+                        let data = read()?;
+                        content.replace(data.clone());
+                        Ok(data)
+                    }
+                }
+            }
+            None => read(),
+        }
+    }
+}
+
+impl Default for ContentCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PartialEq for ContentCache {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+impl std::fmt::Debug for ContentCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match &self.content {
+            None => "ContentCache(disabled)",
+            Some(_) if self.map_files && self.is_populated() => "ContentCache(mapped)",
+            Some(_) if self.is_populated() => "ContentCache(populated)",
+            Some(_) => "ContentCache(empty)",
+        })
+    }
+}
+
+/// A view of the raw content of a `DataLocation`.
+///
+/// Obtained from `DataLocation::as_bytes()`. Dereferences to the content
+/// without copying it.
+pub struct ContentBytes<'a>(ContentBytesInner<'a>);
+
+enum ContentBytesInner<'a> {
+    Borrowed(&'a [u8]),
+    Cached(CachedContent),
+    Owned(Vec<u8>),
+}
+
+impl<'a> std::ops::Deref for ContentBytes<'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            ContentBytesInner::Borrowed(data) => *data,
+            ContentBytesInner::Cached(content) => content.as_ref(),
+            ContentBytesInner::Owned(data) => data.as_slice(),
+        }
+    }
+}
+
+impl<'a> AsRef<[u8]> for ContentBytes<'a> {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
 /// Represents an abstract location for binary data.
 ///
-/// Data can be backed by memory or by a path in the filesystem.
-#[derive(Clone, Debug, PartialEq)]
+/// Data can be backed by memory, by a file or a range of one in the
+/// filesystem or by a member of a zip archive.
+///
+/// Instances holding their content in memory (`Memory`, `Slice` and
+/// `CompressedMemory`) compare equal to each other if they have the same
+/// content, so interned or compressed copies equal their originals. Other
+/// variants only compare equal to the same variant referencing the same
+/// file or archive member. Use `content_eq()` to compare content of any
+/// variants.
+#[derive(Clone, Debug)]
 pub enum DataLocation {
-    Path(PathBuf),
+    /// A file in the filesystem.
+    ///
+    /// The file is read the first time its content is resolved. Content is
+    /// kept in `cache` for later resolutions.
+    Path {
+        path: PathBuf,
+        cache: ContentCache,
+    },
     Memory(Vec<u8>),
+    /// A range within a shared buffer.
+    ///
+    /// This allows data within a larger blob to be referenced without
+    /// copying it.
+    Slice {
+        data: Arc<Vec<u8>>,
+        offset: usize,
+        length: usize,
+    },
+    /// Content compressed with zstd.
+    ///
+    /// Content is decompressed whenever it is resolved. `size` is the size of
+    /// the decompressed content.
+    CompressedMemory {
+        data: Vec<u8>,
+        size: u64,
+    },
+    /// A member of a zip archive, such as a wheel.
+    ///
+    /// The archive is opened and the member extracted whenever content is
+    /// resolved. `member` is the `/` delimited name of the member within
+    /// the archive.
+    ArchiveMember {
+        archive: PathBuf,
+        member: String,
+    },
+    /// A range of a file in the filesystem.
+    ///
+    /// The range is read whenever content is resolved. This allows data
+    /// following a header, like the bytecode of a `.pyc` file, to be
+    /// referenced without reading it.
+    FileRange {
+        path: PathBuf,
+        offset: u64,
+        length: u64,
+    },
+}
+
+impl PartialEq for DataLocation {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (DataLocation::Path { path: a, .. }, DataLocation::Path { path: b, .. }) => a == b,
+            (DataLocation::Memory(a), DataLocation::Memory(b)) => a == b,
+            (
+                DataLocation::Slice {
+                    data: a,
+                    offset: a_offset,
+                    length: a_length,
+                },
+                DataLocation::Slice {
+                    data: b,
+                    offset: b_offset,
+                    length: b_length,
+                },
+            ) if Arc::ptr_eq(a, b) && a_offset == b_offset && a_length == b_length => true,
+            (
+                DataLocation::ArchiveMember {
+                    archive: a,
+                    member: a_member,
+                },
+                DataLocation::ArchiveMember {
+                    archive: b,
+                    member: b_member,
+                },
+            ) => a == b && a_member == b_member,
+            (
+                DataLocation::FileRange {
+                    path: a,
+                    offset: a_offset,
+                    length: a_length,
+                },
+                DataLocation::FileRange {
+                    path: b,
+                    offset: b_offset,
+                    length: b_length,
+                },
+            ) => a == b && a_offset == b_offset && a_length == b_length,
+            (DataLocation::Path { .. }, _)
+            | (_, DataLocation::Path { .. })
+            | (DataLocation::ArchiveMember { .. }, _)
+            | (_, DataLocation::ArchiveMember { .. })
+            | (DataLocation::FileRange { .. }, _)
+            | (_, DataLocation::FileRange { .. }) => false,
+            // The remaining variants hold their content in memory.
+            _ => {
+                match (self.size(), other.size()) {
+                    (Ok(a), Ok(b)) if a == b => {}
+                    _ => return false,
+                }
+
+                match (self.as_bytes(), other.as_bytes()) {
+                    (Ok(a), Ok(b)) => *a == *b,
+                    _ => false,
+                }
+            }
+        }
+    }
 }
 
 impl DataLocation {
+    /// Construct an instance backed by a file, caching its content once read.
+    pub fn from_path(path: impl Into<PathBuf>) -> Self {
+        DataLocation::Path {
+            path: path.into(),
+            cache: ContentCache::new(),
+        }
+    }
+
+    /// Construct an instance backed by a file whose content is never cached.
+    ///
+    /// Every resolution reads the file. Use this for content that must not
+    /// be held in memory.
+    pub fn from_path_uncached(path: impl Into<PathBuf>) -> Self {
+        DataLocation::Path {
+            path: path.into(),
+            cache: ContentCache::disabled(),
+        }
+    }
+
+    /// Construct an instance backed by a file that must exist.
+    ///
+    /// The file's size and modification time are recorded, so `validate()`
+    /// can later detect if it changed. Errors if the file doesn't exist or is
+    /// a directory.
+    pub fn from_path_checked(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let stamp = FileStamp::from_path(&path)?;
+
+        Ok(DataLocation::Path {
+            path,
+            cache: ContentCache {
+                stamp: Some(stamp),
+                ..ContentCache::new()
+            },
+        })
+    }
+
+    /// Construct an instance memory mapping a file that must exist.
+    ///
+    /// The mapping is made the first time content is resolved and shared by
+    /// clones, so large files don't occupy heap memory. As with
+    /// `from_path_checked()`, the file's size and modification time are
+    /// recorded. They are checked again before every access to the content,
+    /// which errors if the file changed instead of reading a stale mapping.
+    ///
+    /// # Safety
+    ///
+    /// The build must own the file: nothing may modify or truncate it while
+    /// the instance or its clones exist. Reading the mapping of a truncated
+    /// file faults. Files the build didn't write itself should be read with
+    /// `from_path()` instead.
+    pub unsafe fn from_path_mapped(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let stamp = FileStamp::from_path(&path)?;
+
+        Ok(DataLocation::Path {
+            path,
+            cache: ContentCache::mapped(stamp),
+        })
+    }
+
+    /// Write content to a new file and construct an instance memory mapping it.
+    ///
+    /// `reader` is streamed to the file, so copying a large file doesn't
+    /// occupy heap memory either. `path` must not exist and should be in a
+    /// directory the build owns. The file is made read-only and mapped as
+    /// with `from_path_mapped()`.
+    pub fn write_mapped(path: impl Into<PathBuf>, reader: &mut dyn Read) -> Result<Self> {
+        let path = path.into();
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .context(format!("creating {}", path.display()))?;
+        std::io::copy(reader, &mut file).context(format!("writing {}", path.display()))?;
+
+        let mut permissions = file.metadata()?.permissions();
+        permissions.set_readonly(true);
+        file.set_permissions(permissions)
+            .context(format!("making {} read-only", path.display()))?;
+        drop(file);
+
+        // SAFETY: The file was created by this function and belongs to the
+        // build, which doesn't modify it while it is mapped.
+        unsafe { DataLocation::from_path_mapped(path) }
+    }
+
+    /// Construct an instance backed by a member of a zip archive.
+    ///
+    /// Neither the archive nor the member need to exist until content is
+    /// resolved.
+    pub fn from_archive_member(archive: impl Into<PathBuf>, member: &str) -> Self {
+        DataLocation::ArchiveMember {
+            archive: archive.into(),
+            member: member.to_string(),
+        }
+    }
+
+    /// The filesystem path backing this instance, if any.
+    ///
+    /// Archive members and file ranges aren't backed by a path of their own.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            DataLocation::Path { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// The stamp of the backing file recorded by `from_path_checked()`.
+    pub fn stamp(&self) -> Option<FileStamp> {
+        match self {
+            DataLocation::Path { cache, .. } => cache.stamp,
+            _ => None,
+        }
+    }
+
+    /// When the content was last modified, if known.
+    ///
+    /// Files report the modification time recorded by `from_path_checked()`,
+    /// or their current one. Other variants have no modification time.
+    pub fn mtime(&self) -> Result<Option<SystemTime>> {
+        match self {
+            DataLocation::Path { path, cache } => match cache.stamp {
+                Some(stamp) => Ok(stamp.modified),
+                None => Ok(std::fs::metadata(path)
+                    .with_context(|| format!("reading metadata of {}", path.display()))?
+                    .modified()
+                    .ok()),
+            },
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether the backing file of this instance still exists.
+    ///
+    /// Unlike `validate()`, a missing file or archive member isn't an error.
+    /// Files whose content is already cached exist even if they were since
+    /// removed, as their content can still be resolved. Instances not backed
+    /// by a file always exist. Errors other than a missing file are returned.
+    pub fn try_exists(&self) -> Result<bool> {
+        match self {
+            DataLocation::Path { path, cache } => {
+                if cache.is_populated() {
+                    return Ok(true);
+                }
+
+                path_exists(path)
+            }
+            DataLocation::ArchiveMember { archive, member } => {
+                if !path_exists(archive)? {
+                    return Ok(false);
+                }
+
+                let found = open_archive(archive)?.by_name(member).is_ok();
+
+                Ok(found)
+            }
+            DataLocation::FileRange { path, .. } => path_exists(path),
+            DataLocation::Memory(_)
+            | DataLocation::Slice { .. }
+            | DataLocation::CompressedMemory { .. } => Ok(true),
+        }
+    }
+
+    /// Ensure the content of this instance can be resolved.
+    ///
+    /// The backing file, if any, must exist and not be a directory. If its
+    /// stamp was recorded, it must not have changed since. Slices and file
+    /// ranges must be within their buffer or file. Archive members must
+    /// exist in their archive.
+    pub fn validate(&self) -> Result<()> {
+        match self {
+            DataLocation::Path { path, cache } => {
+                let stamp = FileStamp::from_path(path)?;
+
+                match cache.stamp {
+                    Some(recorded) if recorded != stamp => {
+                        Err(anyhow!("{} changed since it was added", path.display()))
+                    }
+                    _ => Ok(()),
+                }
+            }
+            DataLocation::Memory(_) | DataLocation::CompressedMemory { .. } => Ok(()),
+            DataLocation::Slice { .. }
+            | DataLocation::ArchiveMember { .. }
+            | DataLocation::FileRange { .. } => self.size().map(|_| ()),
+        }
+    }
+
     /// Resolve the raw content of this instance.
+    ///
+    /// Files are only read the first time, unless caching is disabled.
     pub fn resolve(&self) -> Result<Vec<u8>> {
         match self {
-            DataLocation::Path(p) => std::fs::read(p).context(format!("reading {}", p.display())),
+            DataLocation::Path { path, cache } => Ok(cache.get_or_read(path)?.as_ref().to_vec()),
+            location => location.resolve_uncached(),
+        }
+    }
+
+    /// Resolve the raw content of this instance, bypassing any cached content.
+    ///
+    /// Files are always read and the cache is left untouched.
+    pub fn resolve_uncached(&self) -> Result<Vec<u8>> {
+        match self {
+            DataLocation::Path { path, .. } => {
+                std::fs::read(path).context(format!("reading {}", path.display()))
+            }
             DataLocation::Memory(data) => Ok(data.clone()),
+            DataLocation::Slice {
+                data,
+                offset,
+                length,
+            } => Ok(slice_bytes(data, *offset, *length)?.to_vec()),
+            DataLocation::CompressedMemory { data, .. } => {
+                zstd::decode_all(data.as_slice()).context("decompressing content")
+            }
+            DataLocation::ArchiveMember { archive, member } => {
+                let mut zip = open_archive(archive)?;
+                let mut file = find_archive_member(&mut zip, archive, member)?;
+
+                let mut data = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut data)
+                    .with_context(|| format!("extracting {} from {}", member, archive.display()))?;
+
+                Ok(data)
+            }
+            DataLocation::FileRange { path, length, .. } => {
+                let mut data = Vec::with_capacity(*length as usize);
+                self.reader()?
+                    .read_to_end(&mut data)
+                    .with_context(|| format!("reading {}", path.display()))?;
+
+                if data.len() as u64 != *length {
+                    return Err(anyhow!("range is out of bounds of {}", path.display()));
+                }
+
+                Ok(data)
+            }
         }
     }
 
+    /// Obtain a reader of the raw content of this instance.
+    ///
+    /// Files are streamed instead of being read into memory, unless their
+    /// content is already cached. Prefer this to `resolve()` for large
+    /// content. Archive members are extracted into memory first.
+    pub fn reader(&self) -> Result<Box<dyn Read + '_>> {
+        match self {
+            DataLocation::Path { path, cache } => match cache.cached(path)? {
+                Some(data) => Ok(Box::new(Cursor::new(data))),
+                None => Ok(Box::new(
+                    std::fs::File::open(path).context(format!("opening {}", path.display()))?,
+                )),
+            },
+            DataLocation::Memory(data) => Ok(Box::new(Cursor::new(data.as_slice()))),
+            DataLocation::Slice {
+                data,
+                offset,
+                length,
+            } => Ok(Box::new(Cursor::new(slice_bytes(data, *offset, *length)?))),
+            DataLocation::CompressedMemory { data, .. } => Ok(Box::new(
+                zstd::stream::read::Decoder::new(data.as_slice())
+                    .context("decompressing content")?,
+            )),
+            DataLocation::ArchiveMember { .. } => Ok(Box::new(Cursor::new(self.resolve()?))),
+            DataLocation::FileRange {
+                path,
+                offset,
+                length,
+            } => {
+                let mut fh =
+                    std::fs::File::open(path).context(format!("opening {}", path.display()))?;
+                fh.seek(SeekFrom::Start(*offset))
+                    .context(format!("seeking in {}", path.display()))?;
+
+                Ok(Box::new(fh.take(*length)))
+            }
+        }
+    }
+
+    /// Obtain the size in bytes of the raw content without reading it.
+    ///
+    /// Files use their filesystem metadata unless their content is cached.
+    /// Archive members use the size recorded in their archive.
+    pub fn size(&self) -> Result<u64> {
+        match self {
+            DataLocation::Path { path, cache } => match cache.cached(path)? {
+                Some(data) => Ok(data.as_ref().len() as u64),
+                None => Ok(std::fs::metadata(path)
+                    .context(format!("reading metadata of {}", path.display()))?
+                    .len()),
+            },
+            DataLocation::Memory(data) => Ok(data.len() as u64),
+            DataLocation::Slice {
+                data,
+                offset,
+                length,
+            } => Ok(slice_bytes(data, *offset, *length)?.len() as u64),
+            DataLocation::CompressedMemory { size, .. } => Ok(*size),
+            DataLocation::ArchiveMember { archive, member } => {
+                let mut zip = open_archive(archive)?;
+                let file = find_archive_member(&mut zip, archive, member)?;
+
+                Ok(file.size())
+            }
+            DataLocation::FileRange {
+                path,
+                offset,
+                length,
+            } => {
+                let size = std::fs::metadata(path)
+                    .context(format!("reading metadata of {}", path.display()))?
+                    .len();
+
+                if offset.checked_add(*length).map_or(true, |end| end > size) {
+                    Err(anyhow!("range is out of bounds of {}", path.display()))
+                } else {
+                    Ok(*length)
+                }
+            }
+        }
+    }
+
+    /// Obtain a view of the raw content of this instance.
+    ///
+    /// In-memory content is borrowed and memory mapped files are shared
+    /// without copying. Files that aren't mapped are resolved as with
+    /// `resolve()`, which reads uncached files into a new buffer. Compressed
+    /// content, archive members and file ranges are read into a new buffer.
+    pub fn as_bytes(&self) -> Result<ContentBytes> {
+        Ok(ContentBytes(match self {
+            DataLocation::Path { path, cache } if cache.content.is_some() => {
+                ContentBytesInner::Cached(cache.get_or_read(path)?)
+            }
+            DataLocation::Path { .. } => ContentBytesInner::Owned(self.resolve()?),
+            DataLocation::Memory(data) => ContentBytesInner::Borrowed(data),
+            DataLocation::Slice {
+                data,
+                offset,
+                length,
+            } => ContentBytesInner::Borrowed(slice_bytes(data, *offset, *length)?),
+            DataLocation::CompressedMemory { .. }
+            | DataLocation::ArchiveMember { .. }
+            | DataLocation::FileRange { .. } => ContentBytesInner::Owned(self.resolve()?),
+        }))
+    }
+
+    /// Call a function with a view of the raw content of this instance.
+    ///
+    /// Content is obtained as with `as_bytes()`.
+    pub fn with_bytes<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        Ok(f(&self.as_bytes()?))
+    }
+
+    /// Write the raw content of this instance to a writer.
+    ///
+    /// Files are streamed as with `reader()`. Returns the number of bytes
+    /// written.
+    pub fn copy_to(&self, writer: &mut dyn Write) -> Result<u64> {
+        Ok(std::io::copy(&mut self.reader()?, writer)?)
+    }
+
+    /// Compute the hex digest of the raw content of this instance.
+    ///
+    /// Content is streamed through the hasher as with `reader()`, so it
+    /// isn't copied. Digests of files are cached and shared by clones, so a
+    /// file is hashed at most once per algorithm. Files constructed with
+    /// `from_path_uncached()` and in-memory content are hashed on every
+    /// call.
+    pub fn digest(&self, algorithm: DigestAlgorithm) -> Result<String> {
+        let compute = || algorithm.digest_reader(&mut self.reader()?);
+
+        match self {
+            DataLocation::Path { path, cache } => cache
+                .get_or_digest(algorithm, compute)
+                .with_context(|| format!("hashing {}", path.display())),
+            _ => compute(),
+        }
+    }
+
+    /// Compute the hex SHA-256 digest of the raw content of this instance.
+    pub fn sha256(&self) -> Result<String> {
+        self.digest(DigestAlgorithm::Sha256)
+    }
+
     /// Resolve the instance to a Memory variant.
+    ///
+    /// Cached file content is reused. Memory mapped files are copied into an
+    /// owned buffer. Archive members are extracted once, so the archive is no
+    /// longer needed.
     pub fn to_memory(&self) -> Result<DataLocation> {
         Ok(DataLocation::Memory(self.resolve()?))
     }
+
+    /// Resolve the instance to a Slice variant sharing identical content.
+    ///
+    /// Content identical to content previously resolved by `pool` shares
+    /// its buffer.
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<DataLocation> {
+        let data = pool.resolve_interned(self)?;
+
+        Ok(DataLocation::Slice {
+            offset: 0,
+            length: data.len(),
+            data,
+        })
+    }
+
+    /// Resolve the instance to a CompressedMemory variant.
+    ///
+    /// `level` is a zstd compression level, where 0 uses zstd's default.
+    /// Content is streamed into the compressor as with `reader()`.
+    pub fn to_memory_compressed(&self, level: i32) -> Result<DataLocation> {
+        let size = self.size()?;
+        let data = zstd::encode_all(self.reader()?, level).context("compressing content")?;
+
+        Ok(DataLocation::CompressedMemory { data, size })
+    }
+
+    /// Whether the instance holds the same bytes as another.
+    ///
+    /// Unlike `==`, which compares files by path, instances are equal
+    /// regardless of where their content lives. A file and a `Memory` holding
+    /// its bytes are equal. Sizes are compared before content is read.
+    pub fn content_eq(&self, other: &DataLocation) -> Result<bool> {
+        if self == other {
+            return Ok(true);
+        }
+        if self.size()? != other.size()? {
+            return Ok(false);
+        }
+
+        Ok(*self.as_bytes()? == *other.as_bytes()?)
+    }
+}
+
+/// Statistics about content shared by an `InternPool`.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct InternStatistics {
+    /// Number of distinct buffers held.
+    pub unique_count: usize,
+
+    /// Size of distinct buffers held.
+    pub unique_bytes: u64,
+
+    /// Number of resolutions that shared an existing buffer.
+    pub duplicate_count: usize,
+
+    /// Bytes not held because an existing buffer was shared.
+    pub bytes_saved: u64,
+}
+
+/// Shares buffers of identical content resolved from `DataLocation` instances.
+///
+/// Packages commonly ship identical files, such as license texts referenced
+/// by many extension modules. Resolving them through a pool holds their
+/// content once. Content is identified by its SHA-256.
+#[derive(Clone, Debug, Default)]
+pub struct InternPool {
+    buffers: HashMap<Vec<u8>, Arc<Vec<u8>>>,
+    statistics: InternStatistics,
+}
+
+impl InternPool {
+    /// Resolve the content of a location, sharing identical content.
+    pub fn resolve_interned(&mut self, location: &DataLocation) -> Result<Arc<Vec<u8>>> {
+        Ok(self.intern(location.resolve()?))
+    }
+
+    /// Obtain a shared buffer of content.
+    pub fn intern(&mut self, data: Vec<u8>) -> Arc<Vec<u8>> {
+        let mut hasher = Sha256::new();
+        hasher.update(&data);
+        let digest = hasher.finalize().to_vec();
+
+        if let Some(existing) = self.buffers.get(&digest) {
+            self.statistics.duplicate_count += 1;
+            self.statistics.bytes_saved += data.len() as u64;
+
+            return existing.clone();
+        }
+
+        self.statistics.unique_count += 1;
+        self.statistics.unique_bytes += data.len() as u64;
+
+        let data = Arc::new(data);
+        self.buffers.insert(digest, data.clone());
+
+        data
+    }
+
+    /// Obtain statistics about shared content.
+    pub fn statistics(&self) -> InternStatistics {
+        self.statistics
+    }
+}
+
+/// Whether a path exists, treating only a missing file as non-existence.
+fn path_exists(path: &Path) -> Result<bool> {
+    match std::fs::metadata(path) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e).with_context(|| format!("reading metadata of {}", path.display())),
+    }
+}
+
+/// Obtain the bytes of a `Slice` location within its buffer.
+///
+/// Offsets and lengths can come from untrusted packed resources, so ranges
+/// that overflow or exceed the buffer are an error.
+fn slice_bytes(data: &[u8], offset: usize, length: usize) -> Result<&[u8]> {
+    offset
+        .checked_add(length)
+        .and_then(|end| data.get(offset..end))
+        .ok_or_else(|| anyhow!("slice is out of bounds of its buffer"))
+}
+
+/// Open a zip archive backing an `ArchiveMember` location.
+fn open_archive(archive: &Path) -> Result<zip::ZipArchive<std::fs::File>> {
+    let fh =
+        std::fs::File::open(archive).with_context(|| format!("opening {}", archive.display()))?;
+
+    zip::ZipArchive::new(fh).with_context(|| format!("reading zip archive {}", archive.display()))
+}
+
+/// Find a member of a zip archive opened by `open_archive()`.
+fn find_archive_member<'a>(
+    zip: &'a mut zip::ZipArchive<std::fs::File>,
+    archive: &Path,
+    member: &str,
+) -> Result<zip::read::ZipFile<'a>> {
+    zip.by_name(member)
+        .with_context(|| format!("finding {} in {}", member, archive.display()))
+}
+
+/// Compare two values holding `DataLocation`s by the content of those locations.
+///
+/// `locations` obtains every location held by a value. Values are equal if
+/// they are equal with all their locations blanked out and the locations at
+/// the same positions hold the same bytes.
+pub(crate) fn logical_eq_with<T: Clone + PartialEq>(
+    a: &T,
+    b: &T,
+    locations: fn(&mut T) -> Vec<&mut DataLocation>,
+) -> Result<bool> {
+    let mut a = a.clone();
+    let mut b = b.clone();
+
+    let take = |value: &mut T| {
+        locations(value)
+            .into_iter()
+            .map(|location| std::mem::replace(location, DataLocation::Memory(vec![])))
+            .collect::<Vec<_>>()
+    };
+    let a_locations = take(&mut a);
+    let b_locations = take(&mut b);
+
+    if a != b || a_locations.len() != b_locations.len() {
+        return Ok(false);
+    }
+
+    for (a, b) in a_locations.iter().zip(b_locations.iter()) {
+        if !a.content_eq(b)? {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+/// Instances serialize their content rather than their location, as a hex
+/// string. Files are read, and every variant deserializes to `Memory`, so
+/// serialized instances don't depend on the filesystem they came from.
+impl Serialize for DataLocation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        let data = self
+            .resolve()
+            .map_err(|e| serde::ser::Error::custom(format!("{:#}", e)))?;
+
+        serializer.serialize_str(&hex::encode(data))
+    }
+}
+
+impl<'de> Deserialize<'de> for DataLocation {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let data = String::deserialize(deserializer)?;
+
+        Ok(DataLocation::Memory(
+            hex::decode(data).map_err(serde::de::Error::custom)?,
+        ))
+    }
 }
 
 /// An optimization level for Python bytecode.
@@ -51,6 +1108,17 @@ pub enum BytecodeOptimizationLevel {
 }
 
 impl BytecodeOptimizationLevel {
+    /// Obtain all optimization levels, from lowest to highest.
+    pub fn all() -> impl Iterator<Item = BytecodeOptimizationLevel> {
+        [
+            BytecodeOptimizationLevel::Zero,
+            BytecodeOptimizationLevel::One,
+            BytecodeOptimizationLevel::Two,
+        ]
+        .iter()
+        .copied()
+    }
+
     /// Determine hte extra filename tag for bytecode files of this variant.
     pub fn to_extra_tag(&self) -> &'static str {
         match self {
@@ -59,6 +1127,21 @@ impl BytecodeOptimizationLevel {
             BytecodeOptimizationLevel::Two => ".opt-2",
         }
     }
+
+    /// Resolve the optimization level from an extra filename tag.
+    ///
+    /// This is the inverse of `to_extra_tag()`. e.g. `.opt-1` resolves to
+    /// `One` and the empty string to `Zero`.
+    pub fn from_extra_tag(tag: &str) -> Option<Self> {
+        Self::all().find(|level| level.to_extra_tag() == tag)
+    }
+
+    /// Compute the tag of bytecode files of this level for a cache tag.
+    ///
+    /// e.g. `cpython-37.opt-1`.
+    pub fn to_bytecode_tag(&self, cache_tag: &str) -> String {
+        format!("{}{}", cache_tag, self.to_extra_tag())
+    }
 }
 
 impl TryFrom<i32> for BytecodeOptimizationLevel {
@@ -112,7 +1195,10 @@ impl PythonModuleSource {
     pub fn to_memory(&self) -> Result<Self> {
         Ok(Self {
             name: self.name.clone(),
-            source: self.source.to_memory()?,
+            source: self
+                .source
+                .to_memory()
+                .with_context(|| format!("resolving source for module {}", self.name))?,
             is_package: self.is_package,
             cache_tag: self.cache_tag.clone(),
             is_stdlib: self.is_stdlib,
@@ -120,6 +1206,16 @@ impl PythonModuleSource {
         })
     }
 
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            source: self
+                .source
+                .to_memory_interned(pool)
+                .with_context(|| format!("resolving source for module {}", self.name))?,
+            ..self.clone()
+        })
+    }
+
     /// Resolve the package containing this module.
     ///
     /// If this module is a package, returns the name of self.
@@ -149,6 +1245,24 @@ impl PythonModuleSource {
         }
     }
 
+    /// Convert the instance to a BytecodeModule for each optimization level.
+    ///
+    /// Duplicate levels are only converted once.
+    pub fn as_bytecode_modules(
+        &self,
+        levels: &[BytecodeOptimizationLevel],
+    ) -> Vec<PythonModuleBytecodeFromSource> {
+        let mut res: Vec<PythonModuleBytecodeFromSource> = Vec::with_capacity(levels.len());
+
+        for level in levels {
+            if !res.iter().any(|module| module.optimize_level == *level) {
+                res.push(self.as_bytecode_module(*level));
+            }
+        }
+
+        res
+    }
+
     /// Resolve the filesystem path for this source module.
     pub fn resolve_path(&self, prefix: &str) -> PathBuf {
         resolve_path_for_module(prefix, &self.name, self.is_package, None)
@@ -158,6 +1272,41 @@ impl PythonModuleSource {
     pub fn has_dunder_file(&self) -> Result<bool> {
         has_dunder_file(&self.source.resolve()?)
     }
+
+    /// Obtain an instance whose source has no docstrings.
+    ///
+    /// See `source_transform::strip_docstrings()`.
+    pub fn strip_docstrings(&self) -> Result<Self> {
+        self.transform_source(source_transform::strip_docstrings)
+    }
+
+    /// Obtain an instance whose source has no function and local variable annotations.
+    ///
+    /// See `source_transform::strip_annotations()`.
+    pub fn strip_annotations(&self) -> Result<Self> {
+        self.transform_source(source_transform::strip_annotations)
+    }
+
+    /// Obtain an instance whose source has no `# type:` comments.
+    pub fn strip_type_comments(&self) -> Result<Self> {
+        self.transform_source(source_transform::strip_type_comments)
+    }
+
+    /// Obtain an instance with source rewritten by a transform.
+    ///
+    /// Source must be UTF-8.
+    fn transform_source(&self, transform: fn(&str) -> Result<String>) -> Result<Self> {
+        let source = self.source.resolve()?;
+        let source = std::str::from_utf8(&source)
+            .with_context(|| format!("decoding source of {}", self.name))?;
+        let source =
+            transform(source).with_context(|| format!("transforming source of {}", self.name))?;
+
+        Ok(Self {
+            source: DataLocation::Memory(source.into_bytes()),
+            ..self.clone()
+        })
+    }
 }
 
 /// Python module bytecode defined via source code.
@@ -189,7 +1338,10 @@ impl PythonModuleBytecodeFromSource {
     pub fn to_memory(&self) -> Result<Self> {
         Ok(Self {
             name: self.name.clone(),
-            source: self.source.to_memory()?,
+            source: self
+                .source
+                .to_memory()
+                .with_context(|| format!("resolving source for module {}", self.name))?,
             optimize_level: self.optimize_level,
             is_package: self.is_package,
             cache_tag: self.cache_tag.clone(),
@@ -198,12 +1350,37 @@ impl PythonModuleBytecodeFromSource {
         })
     }
 
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            source: self
+                .source
+                .to_memory_interned(pool)
+                .with_context(|| format!("resolving source for module {}", self.name))?,
+            ..self.clone()
+        })
+    }
+
     /// Compile source to bytecode using a compiler.
+    ///
+    /// With `CompileMode::PycTimestamp(PycTimestamp::Source)`, the header
+    /// records the modified time of the source. If `reproducible` is set,
+    /// it records `PycTimestamp::reproducible()` instead.
     pub fn compile(
         &self,
         compiler: &mut dyn PythonBytecodeCompiler,
         mode: CompileMode,
+        reproducible: bool,
     ) -> Result<Vec<u8>> {
+        let mode = match mode {
+            CompileMode::PycTimestamp(PycTimestamp::Source) if reproducible => {
+                CompileMode::PycTimestamp(PycTimestamp::reproducible()?)
+            }
+            CompileMode::PycTimestamp(PycTimestamp::Source) => {
+                CompileMode::PycTimestamp(PycTimestamp::Source.resolve(self.source.mtime()?))
+            }
+            mode => mode,
+        };
+
         compiler.compile(
             &self.source.resolve()?,
             &self.name,
@@ -214,13 +1391,12 @@ impl PythonModuleBytecodeFromSource {
 
     /// Resolve filesystem path to this bytecode.
     pub fn resolve_path(&self, prefix: &str) -> PathBuf {
-        let bytecode_tag = match self.optimize_level {
-            BytecodeOptimizationLevel::Zero => self.cache_tag.clone(),
-            BytecodeOptimizationLevel::One => format!("{}.opt-1", self.cache_tag),
-            BytecodeOptimizationLevel::Two => format!("{}.opt-2", self.cache_tag),
-        };
-
-        resolve_path_for_module(prefix, &self.name, self.is_package, Some(&bytecode_tag))
+        resolve_path_for_module(
+            prefix,
+            &self.name,
+            self.is_package,
+            Some(&self.optimize_level.to_bytecode_tag(&self.cache_tag)),
+        )
     }
 
     /// Whether the source for this module has __file__.
@@ -278,7 +1454,7 @@ impl PythonModuleBytecode {
     ) -> Self {
         Self {
             name: name.to_string(),
-            bytecode: DataLocation::Path(path.to_path_buf()),
+            bytecode: DataLocation::from_path(path),
             optimize_level,
             is_package: is_package_from_path(path),
             cache_tag: cache_tag.to_string(),
@@ -290,7 +1466,10 @@ impl PythonModuleBytecode {
     pub fn to_memory(&self) -> Result<Self> {
         Ok(Self {
             name: self.name.clone(),
-            bytecode: DataLocation::Memory(self.resolve_bytecode()?),
+            bytecode: DataLocation::Memory(
+                self.resolve_bytecode()
+                    .with_context(|| format!("resolving bytecode for module {}", self.name))?,
+            ),
             optimize_level: self.optimize_level,
             is_package: self.is_package,
             cache_tag: self.cache_tag.clone(),
@@ -299,20 +1478,127 @@ impl PythonModuleBytecode {
         })
     }
 
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        let data = pool.intern(
+            self.resolve_bytecode()
+                .with_context(|| format!("resolving bytecode for module {}", self.name))?,
+        );
+
+        Ok(Self {
+            bytecode: DataLocation::Slice {
+                offset: 0,
+                length: data.len(),
+                data,
+            },
+            ..self.clone()
+        })
+    }
+
+    /// The location of the bytecode data, including any `.pyc` header.
+    pub fn bytecode_location(&self) -> &DataLocation {
+        &self.bytecode
+    }
+
     /// Resolve the bytecode data for this module.
+    ///
+    /// The header of `.pyc` files is stripped. Errors if the header is
+    /// malformed or if its magic number doesn't match the Python version
+    /// of the cache tag.
     pub fn resolve_bytecode(&self) -> Result<Vec<u8>> {
+        Ok(self.resolve_bytecode_with_header()?.1)
+    }
+
+    /// Parse the `.pyc` header of this module's bytecode.
+    ///
+    /// Only bytecode backed by a `.pyc` file has a header. Errors for
+    /// other bytecode and for headers `resolve_bytecode()` rejects.
+    pub fn header(&self) -> Result<BytecodeHeader> {
+        self.resolve_bytecode_with_header()?
+            .0
+            .ok_or_else(|| anyhow!("bytecode for module {} has no .pyc header", self.name))
+    }
+
+    /// Resolve the bytecode data for this module along with its `.pyc` header.
+    ///
+    /// The header is `None` if the bytecode isn't backed by a `.pyc` file.
+    pub fn resolve_bytecode_with_header(&self) -> Result<(Option<BytecodeHeader>, Vec<u8>)> {
         match &self.bytecode {
-            DataLocation::Memory(data) => Ok(data.clone()),
-            DataLocation::Path(path) => {
-                let data = std::fs::read(path)?;
+            location @ DataLocation::Path { .. } => {
+                let data = location.resolve()?;
+                let header = self.parse_header(&data)?;
 
-                if data.len() >= 16 {
-                    Ok(data[16..data.len()].to_vec())
-                } else {
-                    Err(anyhow!("bytecode file is too short"))
-                }
+                Ok((Some(header), data[header.len..].to_vec()))
+            }
+            location => Ok((None, location.resolve()?)),
+        }
+    }
+
+    /// Obtain the location of the bytecode data for this module.
+    ///
+    /// This is the data `resolve_bytecode()` resolves, without reading it.
+    /// Unlike `bytecode_location()`, it excludes any `.pyc` header.
+    /// Only the header of `.pyc` files is read and checked, and the data
+    /// following it is referenced as a range of the file.
+    pub fn bytecode_data_location(&self) -> Result<DataLocation> {
+        match &self.bytecode {
+            DataLocation::Path { path, .. } => {
+                let mut fh = std::fs::File::open(path)
+                    .with_context(|| format!("opening {}", path.display()))?;
+                let size = fh.metadata()?.len();
+
+                // No header is longer than 16 bytes.
+                let mut data = Vec::with_capacity(16);
+                (&mut fh).take(16).read_to_end(&mut data)?;
+                let header = self.parse_header(&data)?;
+
+                Ok(DataLocation::FileRange {
+                    path: path.clone(),
+                    offset: header.len as u64,
+                    length: size - header.len as u64,
+                })
+            }
+            location => Ok(location.clone()),
+        }
+    }
+
+    /// Parse the `.pyc` header at the start of `data`.
+    ///
+    /// Errors if the header is malformed or if its magic number doesn't
+    /// match the Python version of the cache tag.
+    fn parse_header(&self, data: &[u8]) -> Result<BytecodeHeader> {
+        let target_version = python_version_from_cache_tag(&self.cache_tag);
+
+        let header = BytecodeHeader::parse(data, Some(&self.cache_tag))
+            .map_err(|e| anyhow!("invalid bytecode for module {}: {}", self.name, e))?;
+
+        // PyPy magic numbers don't identify a CPython version, so
+        // only CPython bytecode can be checked against its target.
+        let expected_magic = match InterpreterImplementation::from_cache_tag(&self.cache_tag) {
+            InterpreterImplementation::CPython => target_version
+                .as_deref()
+                .and_then(magic_number_for_python_version),
+            InterpreterImplementation::PyPy => None,
+        };
+
+        if let Some(expected_magic) = expected_magic {
+            let found_version = python_version_from_magic_number(header.magic_number);
+
+            if found_version != target_version.as_deref() {
+                return Err(anyhow!(
+                    "bytecode for module {} has magic number {:#010x} ({}) but {} expects {:#010x} (Python {})",
+                    self.name,
+                    header.magic_number,
+                    found_version
+                        .map(|version| format!("Python {}", version))
+                        .unwrap_or_else(|| "unknown Python version".to_string()),
+                    self.cache_tag,
+                    expected_magic,
+                    target_version.unwrap_or_default(),
+                ));
             }
         }
+
+        Ok(header)
     }
 
     /// Sets the bytecode for this module.
@@ -322,13 +1608,12 @@ impl PythonModuleBytecode {
 
     /// Resolve filesystem path to this bytecode.
     pub fn resolve_path(&self, prefix: &str) -> PathBuf {
-        let bytecode_tag = match self.optimize_level {
-            BytecodeOptimizationLevel::Zero => self.cache_tag.clone(),
-            BytecodeOptimizationLevel::One => format!("{}.opt-1", self.cache_tag),
-            BytecodeOptimizationLevel::Two => format!("{}.opt-2", self.cache_tag),
-        };
-
-        resolve_path_for_module(prefix, &self.name, self.is_package, Some(&bytecode_tag))
+        resolve_path_for_module(
+            prefix,
+            &self.name,
+            self.is_package,
+            Some(&self.optimize_level.to_bytecode_tag(&self.cache_tag)),
+        )
     }
 }
 
@@ -354,12 +1639,25 @@ impl PythonPackageResource {
         Ok(Self {
             leaf_package: self.leaf_package.clone(),
             relative_name: self.relative_name.clone(),
-            data: self.data.to_memory()?,
+            data: self
+                .data
+                .to_memory()
+                .with_context(|| format!("resolving resource {}", self.symbolic_name()))?,
             is_stdlib: self.is_stdlib,
             is_test: self.is_test,
         })
     }
 
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            data: self
+                .data
+                .to_memory_interned(pool)
+                .with_context(|| format!("resolving resource {}", self.symbolic_name()))?,
+            ..self.clone()
+        })
+    }
+
     pub fn symbolic_name(&self) -> String {
         format!("{}:{}", self.leaf_package, self.relative_name)
     }
@@ -423,7 +1721,24 @@ impl PythonPackageDistributionResource {
             package: self.package.clone(),
             version: self.version.clone(),
             name: self.name.clone(),
-            data: self.data.to_memory()?,
+            data: self.data.to_memory().with_context(|| {
+                format!(
+                    "resolving distribution resource {} of {}",
+                    self.name, self.package
+                )
+            })?,
+        })
+    }
+
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            data: self.data.to_memory_interned(pool).with_context(|| {
+                format!(
+                    "resolving distribution resource {} of {}",
+                    self.name, self.package
+                )
+            })?,
+            ..self.clone()
         })
     }
 
@@ -471,12 +1786,18 @@ impl LibraryDependency {
         Ok(Self {
             name: self.name.clone(),
             static_library: if let Some(data) = &self.static_library {
-                Some(data.to_memory()?)
+                Some(
+                    data.to_memory()
+                        .with_context(|| format!("resolving static library {}", self.name))?,
+                )
             } else {
                 None
             },
             dynamic_library: if let Some(data) = &self.dynamic_library {
-                Some(data.to_memory()?)
+                Some(
+                    data.to_memory()
+                        .with_context(|| format!("resolving shared library {}", self.name))?,
+                )
             } else {
                 None
             },
@@ -484,6 +1805,26 @@ impl LibraryDependency {
             system: self.system,
         })
     }
+
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            static_library: match &self.static_library {
+                Some(data) => Some(
+                    data.to_memory_interned(pool)
+                        .with_context(|| format!("resolving static library {}", self.name))?,
+                ),
+                None => None,
+            },
+            dynamic_library: match &self.dynamic_library {
+                Some(data) => Some(
+                    data.to_memory_interned(pool)
+                        .with_context(|| format!("resolving shared library {}", self.name))?,
+                ),
+                None => None,
+            },
+            ..self.clone()
+        })
+    }
 }
 
 /// Represents a Python extension module.
@@ -535,7 +1876,9 @@ impl PythonExtensionModule {
             init_fn: self.init_fn.clone(),
             extension_file_suffix: self.extension_file_suffix.clone(),
             shared_library: if let Some(data) = &self.shared_library {
-                Some(data.to_memory()?)
+                Some(data.to_memory().with_context(|| {
+                    format!("resolving shared library of extension module {}", self.name)
+                })?)
             } else {
                 None
             },
@@ -555,7 +1898,11 @@ impl PythonExtensionModule {
                 Some(
                     texts
                         .iter()
-                        .map(|t| t.to_memory())
+                        .map(|t| {
+                            t.to_memory().with_context(|| {
+                                format!("resolving license text of extension module {}", self.name)
+                            })
+                        })
                         .collect::<Result<Vec<_>, _>>()?,
                 )
             } else {
@@ -565,6 +1912,36 @@ impl PythonExtensionModule {
         })
     }
 
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            shared_library: match &self.shared_library {
+                Some(data) => Some(data.to_memory_interned(pool).with_context(|| {
+                    format!("resolving shared library of extension module {}", self.name)
+                })?),
+                None => None,
+            },
+            link_libraries: self
+                .link_libraries
+                .iter()
+                .map(|l| l.to_memory_interned(pool))
+                .collect::<Result<Vec<_>, _>>()?,
+            license_texts: match &self.license_texts {
+                Some(texts) => Some(
+                    texts
+                        .iter()
+                        .map(|t| {
+                            t.to_memory_interned(pool).with_context(|| {
+                                format!("resolving license text of extension module {}", self.name)
+                            })
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                ),
+                None => None,
+            },
+            ..self.clone()
+        })
+    }
+
     /// The file name (without parent components) this extension module should be
     /// realized with.
     pub fn file_name(&self) -> String {
@@ -648,6 +2025,14 @@ impl PythonExtensionModuleVariants {
         &self.extensions[0]
     }
 
+    /// Obtain the names of variants of an extension module.
+    pub fn variant_names(&self) -> Vec<&str> {
+        self.extensions
+            .iter()
+            .filter_map(|em| em.variant.as_deref())
+            .collect()
+    }
+
     /// Choose a variant given preferences.
     pub fn choose_variant<S: BuildHasher>(
         &self,
@@ -682,7 +2067,16 @@ pub struct PythonEggFile {
 impl PythonEggFile {
     pub fn to_memory(&self) -> Result<Self> {
         Ok(Self {
-            data: self.data.to_memory()?,
+            data: self.data.to_memory().context("resolving egg file")?,
+        })
+    }
+
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            data: self
+                .data
+                .to_memory_interned(pool)
+                .context("resolving egg file")?,
         })
     }
 }
@@ -699,7 +2093,16 @@ pub struct PythonPathExtension {
 impl PythonPathExtension {
     pub fn to_memory(&self) -> Result<Self> {
         Ok(Self {
-            data: self.data.to_memory()?,
+            data: self.data.to_memory().context("resolving path extension")?,
+        })
+    }
+
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(Self {
+            data: self
+                .data
+                .to_memory_interned(pool)
+                .context("resolving path extension")?,
         })
     }
 }
@@ -795,6 +2198,221 @@ impl PythonResource {
             PythonResource::PathExtension(e) => PythonResource::PathExtension(e.to_memory()?),
         })
     }
+
+    /// Create a new instance backed by memory shared through `pool`.
+    ///
+    /// Content identical to content of other resources converted with the
+    /// same pool shares its buffer.
+    pub fn to_memory_interned(&self, pool: &mut InternPool) -> Result<Self> {
+        Ok(match self {
+            PythonResource::ModuleSource(m) => {
+                PythonResource::ModuleSource(m.to_memory_interned(pool)?)
+            }
+            PythonResource::ModuleBytecode(m) => {
+                PythonResource::ModuleBytecode(m.to_memory_interned(pool)?)
+            }
+            PythonResource::ModuleBytecodeRequest(m) => {
+                PythonResource::ModuleBytecodeRequest(m.to_memory_interned(pool)?)
+            }
+            PythonResource::Resource(r) => PythonResource::Resource(r.to_memory_interned(pool)?),
+            PythonResource::DistributionResource(r) => {
+                PythonResource::DistributionResource(r.to_memory_interned(pool)?)
+            }
+            PythonResource::ExtensionModuleDynamicLibrary(m) => {
+                PythonResource::ExtensionModuleDynamicLibrary(m.to_memory_interned(pool)?)
+            }
+            PythonResource::ExtensionModuleStaticallyLinked(m) => {
+                PythonResource::ExtensionModuleStaticallyLinked(m.to_memory_interned(pool)?)
+            }
+            PythonResource::EggFile(e) => PythonResource::EggFile(e.to_memory_interned(pool)?),
+            PythonResource::PathExtension(e) => {
+                PythonResource::PathExtension(e.to_memory_interned(pool)?)
+            }
+        })
+    }
+
+    /// Obtain the location of the content identifying this resource.
+    ///
+    /// This is the source of bytecode requests and the shared library of
+    /// extension modules. Returns `None` for extension modules without a
+    /// shared library.
+    pub fn content_location(&self) -> Option<&DataLocation> {
+        match self {
+            PythonResource::ModuleSource(m) => Some(&m.source),
+            PythonResource::ModuleBytecode(m) => Some(&m.bytecode),
+            PythonResource::ModuleBytecodeRequest(m) => Some(&m.source),
+            PythonResource::Resource(r) => Some(&r.data),
+            PythonResource::DistributionResource(r) => Some(&r.data),
+            PythonResource::ExtensionModuleDynamicLibrary(em) => em.shared_library.as_ref(),
+            PythonResource::ExtensionModuleStaticallyLinked(em) => em.shared_library.as_ref(),
+            PythonResource::EggFile(e) => Some(&e.data),
+            PythonResource::PathExtension(e) => Some(&e.data),
+        }
+    }
+
+    /// Obtain all locations of data held by this resource.
+    ///
+    /// Unlike `content_location()`, this includes object files, libraries and
+    /// license texts of extension modules.
+    pub fn data_locations(&self) -> Vec<&DataLocation> {
+        match self {
+            PythonResource::ExtensionModuleDynamicLibrary(em)
+            | PythonResource::ExtensionModuleStaticallyLinked(em) => em
+                .shared_library
+                .iter()
+                .chain(em.object_file_data.iter())
+                .chain(em.link_libraries.iter().flat_map(|link| {
+                    link.static_library
+                        .iter()
+                        .chain(link.dynamic_library.iter())
+                }))
+                .chain(em.license_texts.iter().flatten())
+                .collect(),
+            _ => self.content_location().into_iter().collect(),
+        }
+    }
+
+    /// Obtain mutable references to every location returned by `data_locations()`.
+    fn data_locations_mut(&mut self) -> Vec<&mut DataLocation> {
+        match self {
+            PythonResource::ModuleSource(m) => vec![&mut m.source],
+            PythonResource::ModuleBytecode(m) => vec![&mut m.bytecode],
+            PythonResource::ModuleBytecodeRequest(m) => vec![&mut m.source],
+            PythonResource::Resource(r) => vec![&mut r.data],
+            PythonResource::DistributionResource(r) => vec![&mut r.data],
+            PythonResource::ExtensionModuleDynamicLibrary(em)
+            | PythonResource::ExtensionModuleStaticallyLinked(em) => em
+                .shared_library
+                .iter_mut()
+                .chain(em.object_file_data.iter_mut())
+                .chain(em.link_libraries.iter_mut().flat_map(|link| {
+                    link.static_library
+                        .iter_mut()
+                        .chain(link.dynamic_library.iter_mut())
+                }))
+                .chain(em.license_texts.iter_mut().flatten())
+                .collect(),
+            PythonResource::EggFile(e) => vec![&mut e.data],
+            PythonResource::PathExtension(e) => vec![&mut e.data],
+        }
+    }
+
+    /// Replace every location returned by `data_locations()`.
+    ///
+    /// `f` receives each location and returns its replacement.
+    pub fn map_data_locations<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&DataLocation) -> Result<DataLocation>,
+    {
+        for location in self.data_locations_mut() {
+            *location = f(location)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether this resource is logically identical to another.
+    ///
+    /// `==` compares file-backed data by path, so a module read from a file
+    /// differs from the same module held in memory. This compares the
+    /// content of data instead, reading files as needed. All other fields
+    /// must be equal.
+    pub fn logical_eq(&self, other: &PythonResource) -> Result<bool> {
+        logical_eq_with(self, other, PythonResource::data_locations_mut)
+    }
+
+    /// Ensure the data held by this resource can be resolved.
+    ///
+    /// All locations returned by `data_locations()` are validated. Every
+    /// problem is reported in a single error, so missing files can be fixed
+    /// at once instead of one at a time.
+    pub fn validate(&self) -> Result<()> {
+        let problems = self
+            .data_locations()
+            .into_iter()
+            .filter_map(|location| location.validate().err())
+            .map(|e| format!("{:#}", e))
+            .collect::<Vec<_>>();
+
+        if problems.is_empty() {
+            return Ok(());
+        }
+
+        let name = self.full_name();
+        if name.is_empty() {
+            Err(anyhow!("invalid resource data: {}", problems.join("; ")))
+        } else {
+            Err(anyhow!(
+                "invalid data for {}: {}",
+                name,
+                problems.join("; ")
+            ))
+        }
+    }
+
+    /// Compute the hex digest of the content of this resource.
+    ///
+    /// See `content_location()` for what content is hashed.
+    pub fn digest(&self, algorithm: DigestAlgorithm) -> Result<Option<String>> {
+        match self.content_location() {
+            Some(location) => {
+                Ok(Some(location.digest(algorithm).with_context(|| {
+                    format!("hashing {}", self.full_name())
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// Select resources belonging to any of the given packages.
+///
+/// Resources are selected by `PythonResource::is_in_packages()`.
+/// Distribution resources are also selected if the distribution's RECORD
+/// lists files in one of the packages. This keeps the metadata of
+/// distributions not named after their packages, including distributions
+/// only installing data.
+pub fn select_resources_in_packages(
+    resources: &[PythonResource],
+    packages: &[String],
+) -> Result<Vec<PythonResource>> {
+    let top_level_packages = packages
+        .iter()
+        .filter_map(|package| package.split('.').next())
+        .collect::<Vec<_>>();
+
+    let mut distributions = HashSet::new();
+
+    for resource in resources {
+        if let PythonResource::DistributionResource(r) = resource {
+            if r.name != "RECORD" {
+                continue;
+            }
+
+            let record = PythonPackageRecord::from_record(&r.data.resolve()?)
+                .with_context(|| format!("parsing RECORD of {}", r.package))?;
+
+            if record
+                .top_level_names()
+                .iter()
+                .any(|name| top_level_packages.contains(&name.as_str()))
+            {
+                distributions.insert(r.package.clone());
+            }
+        }
+    }
+
+    Ok(resources
+        .iter()
+        .filter(|resource| {
+            resource.is_in_packages(packages)
+                || match resource {
+                    PythonResource::DistributionResource(r) => distributions.contains(&r.package),
+                    _ => false,
+                }
+        })
+        .cloned()
+        .collect())
 }
 
 impl From<PythonModuleSource> for PythonResource {
@@ -841,10 +2459,792 @@ impl From<PythonPathExtension> for PythonResource {
 
 #[cfg(test)]
 mod tests {
-    use super::*;
+    use {
+        super::*,
+        crate::bytecode::{compute_bytecode_header, BytecodeHeaderMode},
+    };
 
     const DEFAULT_CACHE_TAG: &str = "cpython-37";
 
+    #[test]
+    fn test_module_source_transforms() -> Result<()> {
+        let module = PythonModuleSource {
+            name: "foo".to_string(),
+            source: DataLocation::Memory(
+                b"def f(x: int) -> int:  # type: ignore\n    '''f.'''\n    return x\n".to_vec(),
+            ),
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+
+        let stripped = module
+            .strip_docstrings()?
+            .strip_annotations()?
+            .strip_type_comments()?;
+        assert_eq!(
+            stripped.source.resolve()?,
+            b"def f(x) :\n    pass\n    return x\n"
+        );
+        assert_eq!(stripped.name, module.name);
+
+        let module = PythonModuleSource {
+            source: DataLocation::Memory(b"'''unterminated\n".to_vec()),
+            ..module
+        };
+        assert_eq!(
+            format!("{:#}", module.strip_docstrings().unwrap_err()),
+            "transforming source of foo: unterminated string literal on line 1"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_cache() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, b"old")?;
+
+        let location = DataLocation::from_path(&path);
+        let clone = location.clone();
+        assert_eq!(location.resolve()?, b"old");
+        assert!(matches!(&clone, DataLocation::Path { cache, .. } if cache.is_populated()));
+
+        // Resolving again, from any clone, doesn't read the file.
+        std::fs::write(&path, b"new")?;
+        assert_eq!(location.resolve()?, b"old");
+        assert_eq!(clone.resolve()?, b"old");
+        assert_eq!(clone.to_memory()?, DataLocation::Memory(b"old".to_vec()));
+        assert_eq!(location.resolve_uncached()?, b"new");
+        assert_eq!(location.resolve()?, b"old");
+
+        let uncached = DataLocation::from_path_uncached(&path);
+        assert_eq!(uncached.resolve()?, b"new");
+        assert!(matches!(&uncached, DataLocation::Path { cache, .. } if !cache.is_populated()));
+
+        // Caches don't affect equality.
+        assert_eq!(location, uncached);
+        assert_eq!(location.path(), Some(path.as_path()));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_mapped() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("weights.bin");
+
+        let location = DataLocation::write_mapped(&path, &mut Cursor::new(b"weights"))?;
+        assert!(std::fs::metadata(&path)?.permissions().readonly());
+        let clone = location.clone();
+        assert_eq!(&*location.as_bytes()?, b"weights");
+        assert!(
+            matches!(&clone, DataLocation::Path { cache, .. } if cache.is_mapped() && cache.is_populated())
+        );
+        assert_eq!(clone.with_bytes(|data| data.len())?, 7);
+        assert_eq!(clone.size()?, 7);
+        assert_eq!(
+            clone.to_memory()?,
+            DataLocation::Memory(b"weights".to_vec())
+        );
+
+        let mut written = vec![];
+        location.copy_to(&mut written)?;
+        assert_eq!(written, b"weights");
+
+        // Existing files are never mapped.
+        assert!(DataLocation::write_mapped(&path, &mut Cursor::new(b"other")).is_err());
+        assert_eq!(&*location.as_bytes()?, b"weights");
+
+        // Empty files are read, since they can't always be mapped.
+        let empty = temp_dir.path().join("empty");
+        assert!(DataLocation::write_mapped(&empty, &mut std::io::empty())?
+            .as_bytes()?
+            .is_empty());
+
+        // Existing files are mapped and checked against their recorded
+        // stamp on every access.
+        let existing = temp_dir.path().join("model.bin");
+        std::fs::write(&existing, b"model")?;
+        // SAFETY: The file is truncated below, but the mapping isn't read
+        // afterwards: every access fails the stamp check first.
+        let mapped = unsafe { DataLocation::from_path_mapped(&existing)? };
+        assert_eq!(mapped.with_bytes(|data| data.to_vec())?, b"model");
+        assert!(matches!(&mapped, DataLocation::Path { cache, .. } if cache.is_mapped()));
+
+        std::fs::write(&existing, b"mod")?;
+        let err = mapped.as_bytes().err().unwrap();
+        assert!(format!("{}", err).contains("changed since it was memory mapped"));
+        assert!(mapped.with_bytes(|data| data.len()).is_err());
+        assert!(mapped.size().is_err());
+        assert!(mapped.reader().is_err());
+        // SAFETY: Missing files aren't mapped.
+        let missing = unsafe { DataLocation::from_path_mapped(temp_dir.path().join("missing")) };
+        assert!(missing.is_err());
+
+        // Other locations are viewed without copying.
+        let memory = DataLocation::Memory(b"memory".to_vec());
+        assert_eq!(&*memory.as_bytes()?, b"memory");
+        let slice = DataLocation::Slice {
+            data: Arc::new(b"0123456789".to_vec()),
+            offset: 2,
+            length: 3,
+        };
+        assert_eq!(slice.with_bytes(|data| data.to_vec())?, b"234");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_file_range() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("data");
+        std::fs::write(&path, b"0123456789")?;
+
+        let range = DataLocation::FileRange {
+            path: path.clone(),
+            offset: 2,
+            length: 3,
+        };
+        assert_eq!(range.resolve()?, b"234");
+        assert_eq!(range.size()?, 3);
+        assert_eq!(&*range.as_bytes()?, b"234");
+        assert!(range.try_exists()?);
+        assert_eq!(range.path(), None);
+        assert_ne!(range, DataLocation::Memory(b"234".to_vec()));
+        assert!(range.content_eq(&DataLocation::Memory(b"234".to_vec()))?);
+
+        let mut written = vec![];
+        range.copy_to(&mut written)?;
+        assert_eq!(written, b"234");
+
+        // Ranges must be within the file.
+        std::fs::write(&path, b"0123")?;
+        assert!(range.validate().is_err());
+        assert!(range.resolve().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_compressed() -> Result<()> {
+        let content = b"import os\n".repeat(100);
+        let memory = DataLocation::Memory(content.clone());
+
+        let compressed = memory.to_memory_compressed(3)?;
+        assert!(
+            matches!(&compressed, DataLocation::CompressedMemory { data, size } if data.len() < content.len() && *size == 1000)
+        );
+        assert_eq!(compressed.size()?, 1000);
+        assert_eq!(compressed.resolve()?, content);
+        assert_eq!(compressed.to_memory()?, memory);
+
+        let mut streamed = vec![];
+        compressed.copy_to(&mut streamed)?;
+        assert_eq!(streamed, content);
+
+        // Equality is defined on the decompressed content.
+        assert_eq!(compressed, memory);
+        assert_eq!(memory, compressed);
+        assert_eq!(compressed, memory.to_memory_compressed(19)?);
+        assert_ne!(compressed, DataLocation::Memory(b"import os\n".to_vec()));
+
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, &content)?;
+        let file = DataLocation::from_path(&path);
+        assert_eq!(file.to_memory_compressed(0)?, memory);
+        assert_ne!(file, compressed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_eq() -> Result<()> {
+        let memory = DataLocation::Memory(b"import os".to_vec());
+        let slice = DataLocation::Slice {
+            data: Arc::new(b"#import os#".to_vec()),
+            offset: 1,
+            length: 9,
+        };
+
+        // In-memory variants compare by content.
+        assert_eq!(memory, slice);
+        assert_eq!(slice, memory);
+        assert_eq!(slice, memory.to_memory_compressed(0)?);
+        assert_eq!(
+            slice,
+            DataLocation::Slice {
+                data: Arc::new(b"import os".to_vec()),
+                offset: 0,
+                length: 9,
+            }
+        );
+        assert_ne!(
+            slice,
+            DataLocation::Slice {
+                data: Arc::new(b"#import os#".to_vec()),
+                offset: 0,
+                length: 9,
+            }
+        );
+
+        let mut pool = InternPool::default();
+        assert_eq!(memory.to_memory_interned(&mut pool)?, memory);
+
+        // Out of bounds slices only equal themselves.
+        let out_of_bounds = DataLocation::Slice {
+            data: Arc::new(b"import os".to_vec()),
+            offset: 1,
+            length: 9,
+        };
+        assert_eq!(out_of_bounds, out_of_bounds.clone());
+        assert_ne!(out_of_bounds, memory);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_content_eq() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, b"import os")?;
+
+        let file = DataLocation::from_path(&path);
+        let memory = DataLocation::Memory(b"import os".to_vec());
+        assert_ne!(file, memory);
+        assert!(file.content_eq(&memory)?);
+        assert!(memory.content_eq(&file)?);
+        assert!(memory.content_eq(&memory.to_memory_compressed(0)?)?);
+        assert!(!file.content_eq(&DataLocation::Memory(b"import re".to_vec()))?);
+        assert!(!file.content_eq(&DataLocation::Memory(b"import".to_vec()))?);
+
+        let module = |source| {
+            PythonResource::ModuleSource(PythonModuleSource {
+                name: "foo".to_string(),
+                source,
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            })
+        };
+        assert_ne!(module(file.clone()), module(memory.clone()));
+        assert!(module(file.clone()).logical_eq(&module(memory.clone()))?);
+        assert!(!module(file.clone())
+            .logical_eq(&module(DataLocation::Memory(b"import re".to_vec())))?);
+
+        // Fields other than content must still match.
+        let mut package = module(memory);
+        if let PythonResource::ModuleSource(m) = &mut package {
+            m.is_package = true;
+        }
+        assert!(!module(file).logical_eq(&package)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_memory_interned() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("LICENSE");
+        std::fs::write(&path, b"license text")?;
+        let license = DataLocation::from_path(&path);
+
+        let extension = |name: &str| {
+            PythonResource::ExtensionModuleDynamicLibrary(PythonExtensionModule {
+                name: name.to_string(),
+                init_fn: None,
+                extension_file_suffix: ".so".to_string(),
+                shared_library: Some(DataLocation::Memory(name.as_bytes().to_vec())),
+                object_file_data: vec![],
+                is_package: false,
+                link_libraries: vec![],
+                is_stdlib: false,
+                builtin_default: false,
+                required: false,
+                variant: None,
+                licenses: None,
+                license_texts: Some(vec![license.clone()]),
+                license_public_domain: None,
+            })
+        };
+        let distribution =
+            PythonResource::DistributionResource(PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "foo".to_string(),
+                version: "1.0".to_string(),
+                name: "LICENSE".to_string(),
+                data: DataLocation::Memory(b"license text".to_vec()),
+            });
+
+        let mut pool = InternPool::default();
+        let resources = [extension("foo"), extension("bar"), distribution]
+            .iter()
+            .map(|r| r.to_memory_interned(&mut pool))
+            .collect::<Result<Vec<_>>>()?;
+
+        let buffer = |r: &PythonResource| -> Arc<Vec<u8>> {
+            let location = match r {
+                PythonResource::ExtensionModuleDynamicLibrary(em) => {
+                    &em.license_texts.as_ref().unwrap()[0]
+                }
+                PythonResource::DistributionResource(r) => &r.data,
+                _ => panic!("unexpected resource"),
+            };
+
+            match location {
+                DataLocation::Slice { data, .. } => data.clone(),
+                _ => panic!("resource is not interned"),
+            }
+        };
+        assert!(Arc::ptr_eq(&buffer(&resources[0]), &buffer(&resources[1])));
+        assert!(Arc::ptr_eq(&buffer(&resources[0]), &buffer(&resources[2])));
+        assert_eq!(buffer(&resources[0]).as_slice(), b"license text");
+
+        // Content is still resolved from the original locations.
+        assert!(resources[0].logical_eq(&extension("foo"))?);
+
+        assert_eq!(
+            pool.statistics(),
+            InternStatistics {
+                unique_count: 3,
+                unique_bytes: 18,
+                duplicate_count: 2,
+                bytes_saved: 24,
+            }
+        );
+
+        Ok(())
+    }
+
+    /// A compiler emitting the modified time it was asked to record.
+    struct TimestampCompiler;
+
+    impl PythonBytecodeCompiler for TimestampCompiler {
+        fn get_magic_number(&self) -> u32 {
+            0
+        }
+
+        fn compile(
+            &mut self,
+            _source: &[u8],
+            _filename: &str,
+            _optimize: BytecodeOptimizationLevel,
+            output_mode: CompileMode,
+        ) -> Result<Vec<u8>> {
+            Ok(match output_mode {
+                CompileMode::PycTimestamp(timestamp) => {
+                    timestamp.header_value().to_le_bytes().to_vec()
+                }
+                _ => vec![],
+            })
+        }
+    }
+
+    #[test]
+    fn test_bytecode_from_source_mtime() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, b"import os")?;
+
+        let file = DataLocation::from_path(&path);
+        let modified = std::fs::metadata(&path)?.modified()?;
+        assert_eq!(file.mtime()?, Some(modified));
+        assert_eq!(
+            DataLocation::from_path_checked(&path)?.mtime()?,
+            Some(modified)
+        );
+        assert_eq!(DataLocation::Memory(b"import os".to_vec()).mtime()?, None);
+
+        let module = |source| PythonModuleBytecodeFromSource {
+            name: "foo".to_string(),
+            source,
+            optimize_level: BytecodeOptimizationLevel::Zero,
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let compile = |source, timestamp| {
+            module(source).compile(
+                &mut TimestampCompiler,
+                CompileMode::PycTimestamp(timestamp),
+                false,
+            )
+        };
+
+        assert_eq!(
+            compile(file.clone(), PycTimestamp::Source)?,
+            PycTimestamp::Source
+                .resolve(Some(modified))
+                .header_value()
+                .to_le_bytes()
+        );
+        assert_eq!(
+            compile(file.clone(), PycTimestamp::Fixed(42))?,
+            42u32.to_le_bytes()
+        );
+        assert_eq!(
+            compile(
+                DataLocation::Memory(b"import os".to_vec()),
+                PycTimestamp::Source
+            )?,
+            0u32.to_le_bytes()
+        );
+
+        // Reproducible builds ignore the modified time of the source.
+        assert_eq!(
+            module(file).compile(
+                &mut TimestampCompiler,
+                CompileMode::PycTimestamp(PycTimestamp::Source),
+                true,
+            )?,
+            PycTimestamp::reproducible()?.header_value().to_le_bytes()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_archive_member() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo-1.0-py3-none-any.whl");
+
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&path)?);
+        zip.start_file("foo/__init__.py", zip::write::FileOptions::default())?;
+        zip.write_all(b"import os")?;
+        zip.start_file("foo/data.txt", zip::write::FileOptions::default())?;
+        zip.write_all(b"data")?;
+        zip.finish()?;
+
+        let source = DataLocation::from_archive_member(&path, "foo/__init__.py");
+        source.validate()?;
+        assert_eq!(source.path(), None);
+        assert_eq!(source.size()?, 9);
+        assert_eq!(source.resolve()?, b"import os");
+        assert_eq!(
+            source.sha256()?,
+            DataLocation::Memory(b"import os".to_vec()).sha256()?
+        );
+        assert_eq!(
+            source.to_memory()?,
+            DataLocation::Memory(b"import os".to_vec())
+        );
+        assert_ne!(source, DataLocation::Memory(b"import os".to_vec()));
+        assert!(source.content_eq(&DataLocation::Memory(b"import os".to_vec()))?);
+
+        let resource = PythonPackageResource {
+            leaf_package: "foo".to_string(),
+            relative_name: "data.txt".to_string(),
+            data: DataLocation::from_archive_member(&path, "foo/data.txt"),
+            is_stdlib: false,
+            is_test: false,
+        };
+        assert_eq!(resource.data.resolve()?, b"data");
+
+        let missing = DataLocation::from_archive_member(&path, "foo/missing.py");
+        assert!(missing.validate().is_err());
+        assert!(missing.resolve().is_err());
+        assert!(
+            DataLocation::from_archive_member(temp_dir.path().join("missing.whl"), "foo")
+                .resolve()
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_reader() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("lib.so");
+        std::fs::write(&path, b"shared library")?;
+
+        let shared = Arc::new(b"0123456789".to_vec());
+        let slice = DataLocation::Slice {
+            data: shared.clone(),
+            offset: 2,
+            length: 3,
+        };
+
+        for (location, expected) in &[
+            (DataLocation::from_path(&path), &b"shared library"[..]),
+            (DataLocation::Memory(b"memory".to_vec()), &b"memory"[..]),
+            (slice, &b"234"[..]),
+        ] {
+            let mut data = vec![];
+            location.reader()?.read_to_end(&mut data)?;
+            assert_eq!(data, *expected);
+
+            let mut written = vec![];
+            assert_eq!(location.copy_to(&mut written)?, expected.len() as u64);
+            assert_eq!(written, *expected);
+        }
+
+        // Streaming doesn't populate the cache, but uses it once populated.
+        let location = DataLocation::from_path(&path);
+        location.copy_to(&mut vec![])?;
+        assert!(matches!(&location, DataLocation::Path { cache, .. } if !cache.is_populated()));
+        location.resolve()?;
+        std::fs::remove_file(&path)?;
+        let mut written = vec![];
+        location.copy_to(&mut written)?;
+        assert_eq!(written, b"shared library");
+
+        let out_of_bounds = DataLocation::Slice {
+            data: shared,
+            offset: 8,
+            length: 3,
+        };
+        assert!(out_of_bounds.reader().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_size() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("lib.so");
+        std::fs::write(&path, b"shared library")?;
+
+        let location = DataLocation::from_path(&path);
+        assert_eq!(location.size()?, 14);
+        // The size is obtained without reading the file.
+        assert!(matches!(&location, DataLocation::Path { cache, .. } if !cache.is_populated()));
+
+        assert_eq!(DataLocation::Memory(vec![0; 5]).size()?, 5);
+
+        let shared = Arc::new(b"0123456789".to_vec());
+        let slice = DataLocation::Slice {
+            data: shared.clone(),
+            offset: 2,
+            length: 3,
+        };
+        assert_eq!(slice.size()?, 3);
+        let out_of_bounds = DataLocation::Slice {
+            data: shared.clone(),
+            offset: 8,
+            length: 3,
+        };
+        assert!(out_of_bounds.size().is_err());
+
+        // Ranges read from untrusted packed resources can overflow.
+        let overflowing = DataLocation::Slice {
+            data: shared,
+            offset: usize::MAX,
+            length: 2,
+        };
+        assert!(overflowing.size().is_err());
+        assert!(overflowing.resolve().is_err());
+        assert!(overflowing.reader().is_err());
+        assert!(overflowing.as_bytes().is_err());
+        let overflowing = DataLocation::FileRange {
+            path: path.clone(),
+            offset: u64::MAX,
+            length: 2,
+        };
+        assert!(overflowing.size().is_err());
+
+        std::fs::remove_file(&path)?;
+        assert!(DataLocation::from_path(&path).size().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_checked() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, b"import os")?;
+
+        let location = DataLocation::from_path_checked(&path)?;
+        assert_eq!(location.stamp().map(|stamp| stamp.size), Some(9));
+        location.validate()?;
+
+        let missing = temp_dir.path().join("missing.py");
+        assert_eq!(
+            DataLocation::from_path_checked(&missing)
+                .err()
+                .unwrap()
+                .to_string(),
+            format!("{} does not exist", missing.display())
+        );
+        assert_eq!(
+            DataLocation::from_path_checked(temp_dir.path())
+                .err()
+                .unwrap()
+                .to_string(),
+            format!("{} is a directory, not a file", temp_dir.path().display())
+        );
+
+        std::fs::write(&path, b"import os, sys")?;
+        assert_eq!(
+            location.validate().err().unwrap().to_string(),
+            format!("{} changed since it was added", path.display())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_try_exists() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, b"import os")?;
+
+        let location = DataLocation::from_path(&path);
+        let cached = DataLocation::from_path(&path);
+        cached.resolve()?;
+        assert!(location.try_exists()?);
+
+        let archive = temp_dir.path().join("foo-1.0-py3-none-any.whl");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&archive)?);
+        zip.start_file("foo/__init__.py", zip::write::FileOptions::default())?;
+        zip.write_all(b"import os")?;
+        zip.finish()?;
+        let member = DataLocation::from_archive_member(&archive, "foo/__init__.py");
+        assert!(member.try_exists()?);
+        assert!(!DataLocation::from_archive_member(&archive, "foo/missing.py").try_exists()?);
+
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&archive)?;
+        assert!(!location.try_exists()?);
+        assert!(!member.try_exists()?);
+        // Cached content can still be resolved.
+        assert!(cached.try_exists()?);
+        assert!(DataLocation::Memory(vec![]).try_exists()?);
+
+        // Errors name the resource owning the missing file.
+        let module = PythonModuleSource {
+            name: "foo.bar".to_string(),
+            source: location,
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let err = module.to_memory().err().unwrap();
+        assert_eq!(err.to_string(), "resolving source for module foo.bar");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_validate() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let shared_library = temp_dir.path().join("foo.so");
+        std::fs::write(&shared_library, b"library")?;
+        let object = temp_dir.path().join("foo.o");
+        let license = temp_dir.path().join("LICENSE");
+
+        let mut em = PythonExtensionModule {
+            name: "foo".to_string(),
+            init_fn: Some("PyInit_foo".to_string()),
+            extension_file_suffix: ".so".to_string(),
+            shared_library: Some(DataLocation::from_path(&shared_library)),
+            object_file_data: vec![DataLocation::from_path(&object)],
+            is_package: false,
+            link_libraries: vec![],
+            is_stdlib: false,
+            builtin_default: false,
+            required: false,
+            variant: None,
+            licenses: None,
+            license_texts: Some(vec![DataLocation::from_path(&license)]),
+            license_public_domain: None,
+        };
+
+        // Every missing file is reported.
+        let resource = PythonResource::ExtensionModuleStaticallyLinked(em.clone());
+        assert_eq!(resource.data_locations().len(), 3);
+        assert_eq!(
+            resource.validate().err().unwrap().to_string(),
+            format!(
+                "invalid data for foo: {} does not exist; {} does not exist",
+                object.display(),
+                license.display()
+            )
+        );
+
+        em.object_file_data.clear();
+        em.license_texts = None;
+        PythonResource::ExtensionModuleDynamicLibrary(em).validate()?;
+
+        let source = PythonResource::ModuleSource(PythonModuleSource {
+            name: "foo".to_string(),
+            source: DataLocation::from_path(temp_dir.path()),
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        });
+        assert!(source.validate().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_location_digest() -> Result<()> {
+        const ABC_SHA256: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        const ABC_BLAKE3: &str = "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85";
+
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, b"abc")?;
+
+        let location = DataLocation::from_path(&path);
+        let clone = location.clone();
+        assert_eq!(location.sha256()?, ABC_SHA256);
+        assert_eq!(location.digest(DigestAlgorithm::Blake3)?, ABC_BLAKE3);
+        // The file is streamed, not cached.
+        assert!(matches!(&location, DataLocation::Path { cache, .. } if !cache.is_populated()));
+
+        // Uncached locations hash the file on every call.
+        let uncached = DataLocation::from_path_uncached(&path);
+        assert_eq!(uncached.sha256()?, ABC_SHA256);
+        std::fs::write(&path, b"abcd")?;
+        assert_ne!(uncached.sha256()?, ABC_SHA256);
+
+        // The digest is cached and shared by clones.
+        std::fs::remove_file(&path)?;
+        assert_eq!(clone.digest(DigestAlgorithm::Sha256)?, ABC_SHA256);
+        assert_eq!(clone.digest(DigestAlgorithm::Blake3)?, ABC_BLAKE3);
+        assert!(DataLocation::from_path(&path).sha256().is_err());
+        assert!(uncached.sha256().is_err());
+
+        assert_eq!(DataLocation::Memory(b"abc".to_vec()).sha256()?, ABC_SHA256);
+        let slice = DataLocation::Slice {
+            data: Arc::new(b"xabcx".to_vec()),
+            offset: 1,
+            length: 3,
+        };
+        assert_eq!(slice.sha256()?, ABC_SHA256);
+
+        let resource = PythonResource::Resource(PythonPackageResource {
+            leaf_package: "foo".to_string(),
+            relative_name: "data.txt".to_string(),
+            data: DataLocation::Memory(b"abc".to_vec()),
+            is_stdlib: false,
+            is_test: false,
+        });
+        assert_eq!(
+            resource.digest(DigestAlgorithm::Sha256)?,
+            Some(ABC_SHA256.to_string())
+        );
+
+        assert_eq!(DigestAlgorithm::parse("sha256")?, DigestAlgorithm::Sha256);
+        assert_eq!(DigestAlgorithm::parse("blake3")?, DigestAlgorithm::Blake3);
+        assert_eq!(
+            format!("{}", DigestAlgorithm::parse("md5").unwrap_err()),
+            "unsupported digest algorithm md5; expected sha256 or blake3"
+        );
+
+        Ok(())
+    }
+
     #[test]
     fn test_is_in_packages() {
         let source = PythonResource::ModuleSource(PythonModuleSource {
@@ -872,4 +3272,257 @@ mod tests {
         assert!(!bytecode.is_in_packages(&[]));
         assert!(!bytecode.is_in_packages(&["bar".to_string()]));
     }
+
+    #[test]
+    fn test_select_resources_in_packages() -> Result<()> {
+        let distribution_resource = |name: &str, data: &[u8]| {
+            PythonResource::DistributionResource(PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "certifi-bundle".to_string(),
+                version: "1.0".to_string(),
+                name: name.to_string(),
+                data: DataLocation::Memory(data.to_vec()),
+            })
+        };
+
+        let resources = vec![
+            PythonResource::Resource(PythonPackageResource {
+                leaf_package: "certbundle".to_string(),
+                relative_name: "cacert.pem".to_string(),
+                data: DataLocation::Memory(vec![]),
+                is_stdlib: false,
+                is_test: false,
+            }),
+            distribution_resource("METADATA", b"Name: certifi-bundle\nVersion: 1.0\n"),
+            distribution_resource(
+                "RECORD",
+                b"certbundle/cacert.pem,,\ncertifi_bundle-1.0.dist-info/RECORD,,\n",
+            ),
+            PythonResource::ModuleSource(PythonModuleSource {
+                name: "other".to_string(),
+                source: DataLocation::Memory(vec![]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            }),
+        ];
+
+        let names = |packages: &[&str]| -> Result<Vec<String>> {
+            Ok(select_resources_in_packages(
+                &resources,
+                &packages.iter().map(|p| p.to_string()).collect::<Vec<_>>(),
+            )?
+            .iter()
+            .map(|r| r.full_name())
+            .collect())
+        };
+
+        assert_eq!(
+            names(&["certbundle"])?,
+            vec![
+                "certbundle.cacert.pem",
+                "certifi-bundle:METADATA",
+                "certifi-bundle:RECORD"
+            ]
+        );
+        assert_eq!(names(&["other"])?, vec!["other"]);
+        assert!(names(&[])?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_optimization_level_extra_tag() {
+        assert_eq!(
+            BytecodeOptimizationLevel::all().collect::<Vec<_>>(),
+            vec![
+                BytecodeOptimizationLevel::Zero,
+                BytecodeOptimizationLevel::One,
+                BytecodeOptimizationLevel::Two
+            ]
+        );
+
+        for level in BytecodeOptimizationLevel::all() {
+            assert_eq!(
+                BytecodeOptimizationLevel::from_extra_tag(level.to_extra_tag()),
+                Some(level)
+            );
+        }
+
+        assert_eq!(BytecodeOptimizationLevel::from_extra_tag(".opt-3"), None);
+        assert_eq!(BytecodeOptimizationLevel::from_extra_tag("opt-1"), None);
+        assert_eq!(
+            BytecodeOptimizationLevel::One.to_bytecode_tag(DEFAULT_CACHE_TAG),
+            "cpython-37.opt-1"
+        );
+    }
+
+    fn cache_tag(implementation: InterpreterImplementation) -> &'static str {
+        match implementation {
+            InterpreterImplementation::CPython => DEFAULT_CACHE_TAG,
+            InterpreterImplementation::PyPy => "pypy37",
+        }
+    }
+
+    #[test]
+    fn test_bytecode_resolve_path() {
+        for implementation in InterpreterImplementation::all() {
+            let cache_tag = cache_tag(implementation);
+
+            let expected = |level: BytecodeOptimizationLevel, is_package: bool| {
+                let filename = match level {
+                    BytecodeOptimizationLevel::Zero => format!("{}.pyc", cache_tag),
+                    BytecodeOptimizationLevel::One => format!("{}.opt-1.pyc", cache_tag),
+                    BytecodeOptimizationLevel::Two => format!("{}.opt-2.pyc", cache_tag),
+                };
+
+                if is_package {
+                    PathBuf::from("prefix/foo/bar/__pycache__")
+                        .join(format!("__init__.{}", filename))
+                } else {
+                    PathBuf::from("prefix/foo/__pycache__").join(format!("bar.{}", filename))
+                }
+            };
+
+            for level in BytecodeOptimizationLevel::all() {
+                for is_package in &[false, true] {
+                    let bytecode =
+                        PythonModuleBytecode::new("foo.bar", level, *is_package, cache_tag, &[]);
+                    assert_eq!(
+                        bytecode.resolve_path("prefix"),
+                        expected(level, *is_package)
+                    );
+
+                    let from_source = PythonModuleBytecodeFromSource {
+                        name: "foo.bar".to_string(),
+                        source: DataLocation::Memory(vec![]),
+                        optimize_level: level,
+                        is_package: *is_package,
+                        cache_tag: cache_tag.to_string(),
+                        is_stdlib: false,
+                        is_test: false,
+                    };
+                    assert_eq!(
+                        from_source.resolve_path("prefix"),
+                        expected(level, *is_package)
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytecode_resolve_strips_header() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let magic_number = magic_number_for_python_version("3.7").unwrap();
+
+        for implementation in InterpreterImplementation::all() {
+            let header =
+                compute_bytecode_header(magic_number, BytecodeHeaderMode::UncheckedHash(42))?;
+
+            let path = temp_dir
+                .path()
+                .join(format!("{}.pyc", implementation.name()));
+            std::fs::write(&path, [header, b"bytecode".to_vec()].concat())?;
+
+            let bytecode = PythonModuleBytecode::from_path(
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                cache_tag(implementation),
+                &path,
+            );
+            assert_eq!(bytecode.resolve_bytecode()?, b"bytecode");
+
+            // Content is cached, so a fresh instance sees the rewritten file.
+            std::fs::write(&path, b"short")?;
+            assert_eq!(bytecode.resolve_bytecode()?, b"bytecode");
+            let bytecode = PythonModuleBytecode::from_path(
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                cache_tag(implementation),
+                &path,
+            );
+            assert!(bytecode.resolve_bytecode().is_err());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_resolve_validates_header() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.pyc");
+
+        let resolve = |cache_tag: &str, data: &[u8]| -> Result<Vec<u8>> {
+            std::fs::write(&path, data)?;
+            PythonModuleBytecode::from_path(
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                cache_tag,
+                &path,
+            )
+            .resolve_bytecode()
+        };
+
+        let python38 = compute_bytecode_header(
+            magic_number_for_python_version("3.8").unwrap(),
+            BytecodeHeaderMode::CheckedHash(42),
+        )?;
+
+        assert_eq!(
+            resolve(
+                "cpython-38",
+                &[python38.clone(), b"bytecode".to_vec()].concat()
+            )?,
+            b"bytecode"
+        );
+        let bytecode = PythonModuleBytecode::from_path(
+            "foo",
+            BytecodeOptimizationLevel::Zero,
+            "cpython-38",
+            &path,
+        );
+        let (header, data) = bytecode.resolve_bytecode_with_header()?;
+        assert_eq!(header, Some(bytecode.header()?));
+        assert_eq!(header.unwrap().mode, BytecodeHeaderMode::CheckedHash(42));
+        assert_eq!(data, b"bytecode");
+
+        // In-memory bytecode has no header.
+        let bytecode = PythonModuleBytecode::new(
+            "foo",
+            BytecodeOptimizationLevel::Zero,
+            false,
+            "cpython-38",
+            b"bytecode",
+        );
+        assert_eq!(bytecode.resolve_bytecode_with_header()?.0, None);
+        assert!(bytecode.header().is_err());
+        assert_eq!(
+            resolve("cpython-37", &[python38.clone(), b"bytecode".to_vec()].concat())
+                .unwrap_err()
+                .to_string(),
+            "bytecode for module foo has magic number 0x0a0d0d55 (Python 3.8) but cpython-37 expects 0x0a0d0d42 (Python 3.7)"
+        );
+        assert!(resolve("cpython-37", b"\x99\x99\x99\x99\x00\x00\x00\x00")
+            .unwrap_err()
+            .to_string()
+            .contains("is not a bytecode magic number"));
+
+        // Flags other than the hash based and check source bits are invalid.
+        let mut bad_flags = python38;
+        bad_flags[4] = 4;
+        assert_eq!(
+            resolve("cpython-38", &bad_flags).unwrap_err().to_string(),
+            "invalid bytecode for module foo: invalid bytecode flags 0x4"
+        );
+
+        // Python 3.6 headers have no flags word and are 12 bytes.
+        let mut python36 = b"3\r\r\n".to_vec();
+        python36.extend(&[0; 8]);
+        python36.extend(b"bytecode");
+        assert_eq!(resolve("cpython-36", &python36)?, b"bytecode");
+
+        Ok(())
+    }
 }
@@ -6,22 +6,51 @@
 
 use {
     crate::bytecode::{
-        compute_bytecode_header, BytecodeHeaderMode, CompileMode, PythonBytecodeCompiler,
+        compute_bytecode_header, BytecodeHeaderMode, CompileError, CompileErrorPolicy, CompileMode,
+        PycInvalidationMode, PythonBytecodeCompiler,
     },
+    crate::filter_file::FilterFile,
+    crate::implementation::InterpreterImplementation,
+    crate::locale::{LocaleFilter, LocaleTrimStatistics},
     crate::module_util::{packages_from_module_name, resolve_path_for_module},
-    crate::policy::PythonResourcesPolicy,
+    crate::package_metadata::{
+        normalize_distribution_name, DirectUrl, PythonPackageMetadata, PythonPackageRecord,
+        DIRECT_URL_FILENAME,
+    },
+    crate::patch::PackagePatch,
+    crate::policy::{
+        DistributionVersionConflictPolicy, ModulePackageCollisionPolicy, PythonResourcesPolicy,
+    },
     crate::python_source::has_dunder_file,
     crate::resource::{
-        BytecodeOptimizationLevel, DataLocation, PythonExtensionModule, PythonModuleBytecode,
-        PythonModuleBytecodeFromSource, PythonModuleSource, PythonPackageDistributionResource,
-        PythonPackageResource,
+        logical_eq_with, BytecodeOptimizationLevel, DataLocation, DigestAlgorithm,
+        PythonExtensionModule, PythonModuleBytecode, PythonModuleBytecodeFromSource,
+        PythonModuleSource, PythonPackageDistributionResource, PythonPackageResource,
+        PythonResource,
+    },
+    crate::resource_usage::{resolve_resource_usage, UsageLog, UsageReport},
+    crate::source_transform::SourceTransforms,
+    crate::text_normalization::{normalize_location, TextNormalization},
+    crate::version::PythonVersion,
+    crate::wheel::WheelContents,
+    anyhow::{anyhow, Context, Result},
+    python_packed_resources::data::{
+        BlobInteriorPadding, CompressedPayload, IndexEncoding, Resource, ResourceField,
+        ResourceFlavor, ResourceSet,
+    },
+    python_packed_resources::extract::{not_found_error, write_payload, PayloadKind},
+    python_packed_resources::sink::{
+        externalize_payloads, EmbeddedSink, ExternalizedPayloads, PayloadSink,
     },
-    anyhow::{anyhow, Result},
-    python_packed_resources::data::{Resource, ResourceFlavor},
+    serde::{Deserialize, Serialize},
     std::borrow::Cow,
     std::collections::{BTreeMap, BTreeSet, HashMap},
     std::iter::FromIterator,
-    std::path::PathBuf,
+    std::path::{Path, PathBuf},
+    std::sync::atomic::{AtomicUsize, Ordering},
+    std::sync::Arc,
+    std::time::{Duration, Instant},
+    tempdir::TempDir,
 };
 
 /// Represents a single file install.
@@ -30,8 +59,42 @@ use {
 /// should be executable.
 pub type FileInstall = (PathBuf, DataLocation, bool);
 
-/// Describes how Python module bytecode will be obtained.
+/// A file a resource installs relative to the binary.
 #[derive(Clone, Debug, PartialEq)]
+pub struct RelativePathInstall {
+    /// Relative install path.
+    pub path: PathBuf,
+
+    /// The data to install.
+    ///
+    /// The data of bytecode isn't known before compilation, so it is `None`.
+    pub location: Option<DataLocation>,
+
+    /// Whether the file should be executable.
+    pub executable: bool,
+
+    /// Name of the resource installing the file.
+    pub resource: String,
+
+    /// The payload of the resource installed.
+    pub kind: PayloadKind,
+
+    /// Where the payload comes from, for error messages.
+    pub provenance: String,
+}
+
+impl RelativePathInstall {
+    /// Describe the install for error messages.
+    pub fn describe(&self) -> String {
+        format!(
+            "{} of {} from {}",
+            self.kind, self.resource, self.provenance
+        )
+    }
+}
+
+/// Describes how Python module bytecode will be obtained.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum PythonModuleBytecodeProvider {
     /// Bytecode is already available.
     Provided(DataLocation),
@@ -45,8 +108,11 @@ pub enum PythonModuleBytecodeProvider {
 /// content are backed by a `DataLocation` instead of `Vec<u8>`, since
 /// we want data resolution to be lazy. In addition, bytecode can either be
 /// provided verbatim or via source.
-#[derive(Clone, Debug, Default, PartialEq)]
+///
+/// Serialized instances embed their content. See `DataLocation`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
 pub struct PrePackagedResource {
+    #[serde(with = "resource_flavor_serde")]
     pub flavor: ResourceFlavor,
     pub name: String,
     pub is_package: bool,
@@ -59,6 +125,7 @@ pub struct PrePackagedResource {
     pub in_memory_resources: Option<BTreeMap<String, DataLocation>>,
     pub in_memory_distribution_resources: Option<BTreeMap<String, DataLocation>>,
     pub in_memory_shared_library: Option<DataLocation>,
+    pub extract_shared_library: bool,
     pub shared_library_dependency_names: Option<Vec<String>>,
     // (prefix, source code)
     pub relative_path_module_source: Option<(String, DataLocation)>,
@@ -71,68 +138,819 @@ pub struct PrePackagedResource {
     pub relative_path_package_resources: Option<BTreeMap<String, (PathBuf, DataLocation)>>,
     pub relative_path_distribution_resources: Option<BTreeMap<String, (PathBuf, DataLocation)>>,
     pub relative_path_shared_library: Option<(String, DataLocation)>,
+    /// Names of features gating inclusion of this resource.
+    ///
+    /// An untagged resource is always included.
+    pub feature_tags: BTreeSet<String>,
+
+    /// Payloads whose text was normalized when added, e.g. `source`.
+    ///
+    /// Bytecode is listed if it is compiled from normalized source.
+    pub normalized_payloads: BTreeSet<String>,
+
+    /// Names of package patches applied to payloads of this resource.
+    pub applied_patches: BTreeSet<String>,
+
+    /// Payloads classified as tests when added, by their label, e.g. `source`.
+    ///
+    /// Labels are those of `payload_digests()`.
+    pub test_payloads: BTreeSet<String>,
+
+    /// Relative path bytecode already holding a complete .pyc file, by its label.
+    ///
+    /// Labels are those of `bytecode_label()`. Such bytecode was compiled
+    /// ahead of time with the header of the pyc invalidation mode. Other
+    /// provided bytecode gets a header when installed.
+    pub pyc_payloads: BTreeSet<String>,
+}
+
+/// Serializes a `ResourceFlavor` as its numeric value.
+mod resource_flavor_serde {
+    use {
+        python_packed_resources::data::ResourceFlavor,
+        serde::{Deserialize, Deserializer, Serializer},
+        std::convert::TryFrom,
+    };
+
+    pub fn serialize<S: Serializer>(
+        flavor: &ResourceFlavor,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let value: u8 = (*flavor).into();
+
+        serializer.serialize_u8(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<ResourceFlavor, D::Error> {
+        ResourceFlavor::try_from(u8::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Label of the bytecode payload at an optimization level.
+fn bytecode_label(optimize_level: BytecodeOptimizationLevel) -> &'static str {
+    match optimize_level {
+        BytecodeOptimizationLevel::Zero => "bytecode",
+        BytecodeOptimizationLevel::One => "bytecode-opt1",
+        BytecodeOptimizationLevel::Two => "bytecode-opt2",
+    }
 }
 
 impl PrePackagedResource {
+    /// Obtain the number of bytecode entries that still need to be compiled from source.
+    pub fn pending_bytecode_count(&self) -> usize {
+        let providers = [
+            self.in_memory_bytecode.as_ref(),
+            self.in_memory_bytecode_opt1.as_ref(),
+            self.in_memory_bytecode_opt2.as_ref(),
+            self.relative_path_bytecode.as_ref().map(|(_, _, p)| p),
+            self.relative_path_bytecode_opt1.as_ref().map(|(_, _, p)| p),
+            self.relative_path_bytecode_opt2.as_ref().map(|(_, _, p)| p),
+        ];
+
+        providers
+            .iter()
+            .filter(|p| match p {
+                Some(PythonModuleBytecodeProvider::FromSource(_)) => true,
+                _ => false,
+            })
+            .count()
+    }
+
+    /// Obtain a copy embedding source in place of bytecode compiled from source.
+    ///
+    /// Bytecode compiled for memory falls back to in-memory source and
+    /// bytecode installed relative to the binary to source installed next to
+    /// it. Provided bytecode is kept.
+    fn to_source_only(&self) -> PrePackagedResource {
+        let mut res = self.clone();
+
+        let from_source = |provider: &Option<PythonModuleBytecodeProvider>| match provider {
+            Some(PythonModuleBytecodeProvider::FromSource(location)) => Some(location.clone()),
+            _ => None,
+        };
+
+        for provider in &mut [
+            &mut res.in_memory_bytecode,
+            &mut res.in_memory_bytecode_opt1,
+            &mut res.in_memory_bytecode_opt2,
+        ] {
+            if let Some(PythonModuleBytecodeProvider::FromSource(_)) = provider {
+                **provider = None;
+            }
+        }
+        for bytecode in &mut [
+            &mut res.relative_path_bytecode,
+            &mut res.relative_path_bytecode_opt1,
+            &mut res.relative_path_bytecode_opt2,
+        ] {
+            if let Some((_, _, PythonModuleBytecodeProvider::FromSource(_))) = bytecode {
+                **bytecode = None;
+            }
+        }
+
+        if res.in_memory_source.is_none() {
+            res.in_memory_source = [
+                &self.in_memory_bytecode,
+                &self.in_memory_bytecode_opt1,
+                &self.in_memory_bytecode_opt2,
+            ]
+            .iter()
+            .find_map(|provider| from_source(*provider));
+        }
+        if res.relative_path_module_source.is_none() {
+            res.relative_path_module_source = [
+                &self.relative_path_bytecode,
+                &self.relative_path_bytecode_opt1,
+                &self.relative_path_bytecode_opt2,
+            ]
+            .iter()
+            .find_map(|bytecode| match bytecode {
+                Some((prefix, _, PythonModuleBytecodeProvider::FromSource(location))) => {
+                    Some((prefix.clone(), location.clone()))
+                }
+                _ => None,
+            });
+        }
+
+        res
+    }
+
+    /// Obtain mutable references to all bytecode providers.
+    fn bytecode_providers_mut(
+        &mut self,
+    ) -> impl Iterator<Item = &mut PythonModuleBytecodeProvider> {
+        self.in_memory_bytecode
+            .iter_mut()
+            .chain(self.in_memory_bytecode_opt1.iter_mut())
+            .chain(self.in_memory_bytecode_opt2.iter_mut())
+            .chain(self.relative_path_bytecode.iter_mut().map(|(_, _, p)| p))
+            .chain(
+                self.relative_path_bytecode_opt1
+                    .iter_mut()
+                    .map(|(_, _, p)| p),
+            )
+            .chain(
+                self.relative_path_bytecode_opt2
+                    .iter_mut()
+                    .map(|(_, _, p)| p),
+            )
+    }
+
+    /// Whether any bytecode was provided rather than compiled from source.
+    fn has_provided_bytecode(&self) -> bool {
+        self.in_memory_bytecode
+            .iter()
+            .chain(self.in_memory_bytecode_opt1.iter())
+            .chain(self.in_memory_bytecode_opt2.iter())
+            .chain(self.relative_path_bytecode.iter().map(|(_, _, p)| p))
+            .chain(self.relative_path_bytecode_opt1.iter().map(|(_, _, p)| p))
+            .chain(self.relative_path_bytecode_opt2.iter().map(|(_, _, p)| p))
+            .any(|provider| match provider {
+                PythonModuleBytecodeProvider::Provided(_) => true,
+                PythonModuleBytecodeProvider::FromSource(_) => false,
+            })
+    }
+
+    /// Obtain mutable references to the location of every payload embedded in packed resources.
+    ///
+    /// These are the in-memory payloads, except bytecode still to be compiled
+    /// from source.
+    fn embedded_payloads_mut(&mut self) -> Vec<&mut DataLocation> {
+        let bytecode = self
+            .in_memory_bytecode
+            .iter_mut()
+            .chain(self.in_memory_bytecode_opt1.iter_mut())
+            .chain(self.in_memory_bytecode_opt2.iter_mut())
+            .filter_map(|provider| match provider {
+                PythonModuleBytecodeProvider::Provided(location) => Some(location),
+                PythonModuleBytecodeProvider::FromSource(_) => None,
+            });
+
+        self.in_memory_source
+            .iter_mut()
+            .chain(self.in_memory_extension_module_shared_library.iter_mut())
+            .chain(
+                self.in_memory_resources
+                    .iter_mut()
+                    .flat_map(|x| x.values_mut()),
+            )
+            .chain(
+                self.in_memory_distribution_resources
+                    .iter_mut()
+                    .flat_map(|x| x.values_mut()),
+            )
+            .chain(self.in_memory_shared_library.iter_mut())
+            .chain(bytecode)
+            .collect()
+    }
+
+    /// Obtain mutable references to the location of every payload.
+    fn data_locations_mut(&mut self) -> Vec<&mut DataLocation> {
+        let bytecode = self
+            .in_memory_bytecode
+            .iter_mut()
+            .chain(self.in_memory_bytecode_opt1.iter_mut())
+            .chain(self.in_memory_bytecode_opt2.iter_mut())
+            .chain(self.relative_path_bytecode.iter_mut().map(|(_, _, p)| p))
+            .chain(
+                self.relative_path_bytecode_opt1
+                    .iter_mut()
+                    .map(|(_, _, p)| p),
+            )
+            .chain(
+                self.relative_path_bytecode_opt2
+                    .iter_mut()
+                    .map(|(_, _, p)| p),
+            )
+            .map(|provider| match provider {
+                PythonModuleBytecodeProvider::Provided(location)
+                | PythonModuleBytecodeProvider::FromSource(location) => location,
+            });
+
+        let locations = self
+            .in_memory_source
+            .iter_mut()
+            .chain(self.in_memory_extension_module_shared_library.iter_mut())
+            .chain(
+                self.in_memory_resources
+                    .iter_mut()
+                    .flat_map(|x| x.values_mut()),
+            )
+            .chain(
+                self.in_memory_distribution_resources
+                    .iter_mut()
+                    .flat_map(|x| x.values_mut()),
+            )
+            .chain(self.in_memory_shared_library.iter_mut())
+            .chain(self.relative_path_module_source.iter_mut().map(|(_, l)| l))
+            .chain(
+                self.relative_path_extension_module_shared_library
+                    .iter_mut()
+                    .map(|(_, l)| l),
+            )
+            .chain(
+                self.relative_path_package_resources
+                    .iter_mut()
+                    .flat_map(|x| x.values_mut())
+                    .map(|(_, l)| l),
+            )
+            .chain(
+                self.relative_path_distribution_resources
+                    .iter_mut()
+                    .flat_map(|x| x.values_mut())
+                    .map(|(_, l)| l),
+            )
+            .chain(self.relative_path_shared_library.iter_mut().map(|(_, l)| l))
+            .chain(bytecode);
+
+        locations.collect()
+    }
+
+    /// Obtain every payload with the kind of resource it belongs to and a label.
+    ///
+    /// Labels identify a payload within the resource, e.g. `source` or
+    /// `resource foo.txt`. Bytecode still to be compiled from source is
+    /// omitted.
+    fn labeled_payloads(&self) -> Vec<(ResourceSizeKind, String, &DataLocation)> {
+        let mut payloads = vec![];
+
+        for location in self
+            .in_memory_source
+            .iter()
+            .chain(self.relative_path_module_source.iter().map(|(_, l)| l))
+        {
+            payloads.push((
+                ResourceSizeKind::ModuleSource,
+                "source".to_string(),
+                location,
+            ));
+        }
+
+        for (label, provider) in self
+            .in_memory_bytecode
+            .iter()
+            .chain(self.relative_path_bytecode.iter().map(|(_, _, p)| p))
+            .map(|p| (bytecode_label(BytecodeOptimizationLevel::Zero), p))
+            .chain(
+                self.in_memory_bytecode_opt1
+                    .iter()
+                    .chain(self.relative_path_bytecode_opt1.iter().map(|(_, _, p)| p))
+                    .map(|p| (bytecode_label(BytecodeOptimizationLevel::One), p)),
+            )
+            .chain(
+                self.in_memory_bytecode_opt2
+                    .iter()
+                    .chain(self.relative_path_bytecode_opt2.iter().map(|(_, _, p)| p))
+                    .map(|p| (bytecode_label(BytecodeOptimizationLevel::Two), p)),
+            )
+        {
+            if let PythonModuleBytecodeProvider::Provided(location) = provider {
+                payloads.push((
+                    ResourceSizeKind::ModuleBytecode,
+                    label.to_string(),
+                    location,
+                ));
+            }
+        }
+
+        for (name, location) in self
+            .in_memory_resources
+            .iter()
+            .flat_map(|x| x.iter())
+            .chain(
+                self.relative_path_package_resources
+                    .iter()
+                    .flat_map(|x| x.iter())
+                    .map(|(name, (_, l))| (name, l)),
+            )
+        {
+            payloads.push((
+                ResourceSizeKind::Resource,
+                format!("resource {}", name),
+                location,
+            ));
+        }
+
+        for (name, location) in self
+            .in_memory_distribution_resources
+            .iter()
+            .flat_map(|x| x.iter())
+            .chain(
+                self.relative_path_distribution_resources
+                    .iter()
+                    .flat_map(|x| x.iter())
+                    .map(|(name, (_, l))| (name, l)),
+            )
+        {
+            payloads.push((
+                ResourceSizeKind::DistributionResource,
+                format!("distribution {}", name),
+                location,
+            ));
+        }
+
+        for location in self.in_memory_extension_module_shared_library.iter().chain(
+            self.relative_path_extension_module_shared_library
+                .iter()
+                .map(|(_, l)| l),
+        ) {
+            payloads.push((
+                ResourceSizeKind::ExtensionModule,
+                "extension module".to_string(),
+                location,
+            ));
+        }
+
+        for location in self
+            .in_memory_shared_library
+            .iter()
+            .chain(self.relative_path_shared_library.iter().map(|(_, l)| l))
+        {
+            payloads.push((
+                ResourceSizeKind::SharedLibrary,
+                "shared library".to_string(),
+                location,
+            ));
+        }
+
+        payloads
+    }
+
+    /// Obtain the size of every payload, by the kind of resource it belongs to.
+    ///
+    /// Content isn't read. Bytecode still to be compiled from source is
+    /// omitted.
+    pub fn payload_sizes(&self) -> Result<Vec<(ResourceSizeKind, u64)>> {
+        self.labeled_payloads()
+            .into_iter()
+            .map(|(kind, _, location)| Ok((kind, location.size()?)))
+            .collect()
+    }
+
+    /// Obtain the hex digest of every payload, by its label.
+    ///
+    /// Labels identify a payload within the resource, e.g. `source`,
+    /// `bytecode-opt1` or `resource foo.txt`. Bytecode still to be compiled
+    /// from source is omitted.
+    pub fn payload_digests(&self, algorithm: DigestAlgorithm) -> Result<Vec<(String, String)>> {
+        self.labeled_payloads()
+            .into_iter()
+            .map(|(_, label, location)| {
+                Ok((
+                    label.clone(),
+                    location
+                        .digest(algorithm)
+                        .with_context(|| format!("hashing {}", label))?,
+                ))
+            })
+            .collect()
+    }
+
+    /// Find payloads backed by files that no longer exist.
+    ///
+    /// Returns the label and location of each. Unlike `labeled_payloads()`,
+    /// sources bytecode is still to be compiled from are considered, labeled
+    /// `bytecode source`.
+    pub fn dangling_payloads(&self) -> Result<Vec<(String, String)>> {
+        let mut payloads = self
+            .labeled_payloads()
+            .into_iter()
+            .map(|(_, label, location)| (label, location))
+            .collect::<Vec<_>>();
+
+        for provider in self
+            .in_memory_bytecode
+            .iter()
+            .chain(self.in_memory_bytecode_opt1.iter())
+            .chain(self.in_memory_bytecode_opt2.iter())
+            .chain(self.relative_path_bytecode.iter().map(|(_, _, p)| p))
+            .chain(self.relative_path_bytecode_opt1.iter().map(|(_, _, p)| p))
+            .chain(self.relative_path_bytecode_opt2.iter().map(|(_, _, p)| p))
+        {
+            if let PythonModuleBytecodeProvider::FromSource(location) = provider {
+                if !payloads.iter().any(|(_, l)| *l == location) {
+                    payloads.push(("bytecode source".to_string(), location));
+                }
+            }
+        }
+
+        let mut res = vec![];
+        for (label, location) in payloads {
+            if !location.try_exists()? {
+                res.push((label, describe_location(location)));
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Replace the location of every payload.
+    ///
+    /// `f` receives each location and returns its replacement.
+    pub fn map_data_locations<F>(&mut self, f: &mut F) -> Result<()>
+    where
+        F: FnMut(&DataLocation) -> Result<DataLocation>,
+    {
+        for location in self.data_locations_mut() {
+            *location = f(location)?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether this resource is logically identical to another.
+    ///
+    /// Payloads are compared by content rather than location, so a payload
+    /// read from a file equals the same bytes held in memory.
+    pub fn logical_eq(&self, other: &PrePackagedResource) -> Result<bool> {
+        logical_eq_with(self, other, PrePackagedResource::data_locations_mut)
+    }
+
+    /// Obtain the data of a distribution resource, by its name in the distribution.
+    pub fn distribution_resource(&self, name: &str) -> Option<&DataLocation> {
+        self.in_memory_distribution_resources
+            .as_ref()
+            .and_then(|resources| resources.get(name))
+            .or_else(|| {
+                self.relative_path_distribution_resources
+                    .as_ref()
+                    .and_then(|resources| resources.get(name))
+                    .map(|(_, location)| location)
+            })
+    }
+
+    /// Obtain the parsed `direct_url.json` distribution resource, if present.
+    pub fn direct_url(&self) -> Result<Option<DirectUrl>> {
+        match self.distribution_resource(DIRECT_URL_FILENAME) {
+            Some(location) => Ok(Some(DirectUrl::from_json(&location.resolve()?)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record whether a payload, by its label, is classified as a test.
+    fn mark_test_payload(&mut self, label: String, is_test: bool) {
+        if is_test {
+            self.test_payloads.insert(label);
+        } else {
+            self.test_payloads.remove(&label);
+        }
+    }
+
+    /// Resolve the install path of relative path bytecode.
+    fn relative_bytecode_path(
+        &self,
+        prefix: &str,
+        cache_tag: &str,
+        optimize_level: BytecodeOptimizationLevel,
+    ) -> PathBuf {
+        resolve_path_for_module(
+            prefix,
+            &self.name,
+            self.is_package,
+            Some(&optimize_level.to_bytecode_tag(cache_tag)),
+        )
+    }
+
+    /// Obtain the files relative path payloads of this resource install.
+    ///
+    /// Paths and executable bits are those of the installs `to_resource()`
+    /// emits, without compiling bytecode.
+    pub fn relative_path_installs(&self) -> Vec<RelativePathInstall> {
+        let mut installs = Vec::new();
+
+        let mut install = |path: PathBuf,
+                           location: Option<DataLocation>,
+                           executable: bool,
+                           kind: PayloadKind,
+                           origin: &DataLocation| {
+            installs.push(RelativePathInstall {
+                path,
+                location,
+                executable,
+                resource: self.name.clone(),
+                kind,
+                provenance: describe_location(origin),
+            })
+        };
+
+        if let Some((prefix, location)) = &self.relative_path_module_source {
+            install(
+                resolve_path_for_module(prefix, &self.name, self.is_package, None),
+                Some(location.clone()),
+                false,
+                PayloadKind::Source,
+                location,
+            );
+        }
+
+        for (bytecode, optimize_level, kind) in &[
+            (
+                &self.relative_path_bytecode,
+                BytecodeOptimizationLevel::Zero,
+                PayloadKind::Bytecode,
+            ),
+            (
+                &self.relative_path_bytecode_opt1,
+                BytecodeOptimizationLevel::One,
+                PayloadKind::BytecodeOpt1,
+            ),
+            (
+                &self.relative_path_bytecode_opt2,
+                BytecodeOptimizationLevel::Two,
+                PayloadKind::BytecodeOpt2,
+            ),
+        ] {
+            if let Some((prefix, cache_tag, provider)) = bytecode {
+                let origin = match provider {
+                    PythonModuleBytecodeProvider::Provided(location) => location,
+                    PythonModuleBytecodeProvider::FromSource(location) => location,
+                };
+
+                install(
+                    self.relative_bytecode_path(prefix, cache_tag, *optimize_level),
+                    None,
+                    false,
+                    kind.clone(),
+                    origin,
+                );
+            }
+        }
+
+        if let Some((path, location)) = &self.relative_path_extension_module_shared_library {
+            install(
+                path.clone(),
+                Some(location.clone()),
+                true,
+                PayloadKind::ExtensionModule,
+                location,
+            );
+        }
+
+        if let Some(resources) = &self.relative_path_package_resources {
+            for (name, (path, location)) in resources {
+                install(
+                    path.clone(),
+                    Some(location.clone()),
+                    false,
+                    PayloadKind::PackageResource(name.clone()),
+                    location,
+                );
+            }
+        }
+
+        if let Some(resources) = &self.relative_path_distribution_resources {
+            for (name, (path, location)) in resources {
+                install(
+                    path.clone(),
+                    Some(location.clone()),
+                    false,
+                    PayloadKind::DistributionResource(name.clone()),
+                    location,
+                );
+            }
+        }
+
+        if let Some((prefix, location)) = &self.relative_path_shared_library {
+            install(
+                PathBuf::from(prefix).join(&self.name),
+                Some(location.clone()),
+                true,
+                PayloadKind::SharedLibrary,
+                location,
+            );
+        }
+
+        installs
+    }
+
     /// Convert the instance to a `Resource`.
     ///
     /// This will compile bytecode from source code using the specified compiler.
     /// It will also emit a list of file installs that must be performed for all
     /// referenced resources to function as intended.
+    ///
+    /// Installed .pyc files use unchecked hash based invalidation.
     pub fn to_resource<'a>(
         &self,
         compiler: &mut dyn PythonBytecodeCompiler,
+    ) -> Result<(Resource<'a, u8>, Vec<FileInstall>)> {
+        self.to_resource_with_pyc_invalidation(compiler, PycInvalidationMode::UncheckedHash)
+    }
+
+    /// Compile bytecode of optimization levels provided by source.
+    ///
+    /// `providers` holds the bytecode provider of each optimization level.
+    /// Levels sharing the same source and compile mode are compiled together,
+    /// so compilers can parse the source once. The returned bytecode is in
+    /// the order of `providers` and `None` for levels not compiled from source.
+    fn compile_bytecode_from_source(
+        &self,
+        compiler: &mut dyn PythonBytecodeCompiler,
+        providers: &[(
+            BytecodeOptimizationLevel,
+            Option<&PythonModuleBytecodeProvider>,
+        )],
+        compile_mode: impl Fn(&DataLocation) -> Result<CompileMode>,
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut groups: Vec<(Vec<u8>, CompileMode, Vec<usize>, &DataLocation)> = Vec::new();
+
+        for (index, (_, provider)) in providers.iter().enumerate() {
+            if let Some(PythonModuleBytecodeProvider::FromSource(location)) = provider {
+                let source = location.resolve()?;
+                let mode = compile_mode(location)?;
+
+                match groups.iter_mut().find(|(other_source, other_mode, _, _)| {
+                    *other_source == source && *other_mode == mode
+                }) {
+                    Some((_, _, indices, _)) => indices.push(index),
+                    None => groups.push((source, mode, vec![index], location)),
+                }
+            }
+        }
+
+        let mut res = vec![None; providers.len()];
+
+        for (source, mode, indices, location) in groups {
+            let levels = indices
+                .iter()
+                .map(|index| providers[*index].0)
+                .collect::<Vec<_>>();
+
+            // Compile errors name the file the source came from, if any.
+            let compiled = compiler
+                .compile_levels(&source, &self.name, &levels, mode)
+                .map_err(|e| match e.downcast::<CompileError>() {
+                    Ok(mut error) => {
+                        if let DataLocation::Path { path, .. } = location {
+                            error.file = Some(path.clone());
+                        }
+                        anyhow::Error::from(error)
+                    }
+                    Err(e) => e,
+                })?;
+
+            for (index, bytecode) in indices.into_iter().zip(compiled) {
+                res[index] = Some(bytecode);
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Convert the instance to a `Resource`, installing .pyc files invalidated per `mode`.
+    ///
+    /// .pyc files compiled from source carry the header of `mode`, as do those
+    /// compiled ahead of time by `PythonResourceCollector::compile_pending_bytecode()`.
+    /// Other provided bytecode has no source to be checked against, so it
+    /// always carries an unchecked hash based header.
+    pub fn to_resource_with_pyc_invalidation<'a>(
+        &self,
+        compiler: &mut dyn PythonBytecodeCompiler,
+        mode: PycInvalidationMode,
     ) -> Result<(Resource<'a, u8>, Vec<FileInstall>)> {
         let mut installs = Vec::new();
 
-        let resource = Resource {
+        let mut in_memory_bytecode = self.compile_bytecode_from_source(
+            compiler,
+            &[
+                (
+                    BytecodeOptimizationLevel::Zero,
+                    self.in_memory_bytecode.as_ref(),
+                ),
+                (
+                    BytecodeOptimizationLevel::One,
+                    self.in_memory_bytecode_opt1.as_ref(),
+                ),
+                (
+                    BytecodeOptimizationLevel::Two,
+                    self.in_memory_bytecode_opt2.as_ref(),
+                ),
+            ],
+            |_| Ok(CompileMode::Bytecode),
+        )?;
+        let mut relative_path_bytecode = self.compile_bytecode_from_source(
+            compiler,
+            &[
+                (
+                    BytecodeOptimizationLevel::Zero,
+                    self.relative_path_bytecode
+                        .as_ref()
+                        .map(|(_, _, provider)| provider),
+                ),
+                (
+                    BytecodeOptimizationLevel::One,
+                    self.relative_path_bytecode_opt1
+                        .as_ref()
+                        .map(|(_, _, provider)| provider),
+                ),
+                (
+                    BytecodeOptimizationLevel::Two,
+                    self.relative_path_bytecode_opt2
+                        .as_ref()
+                        .map(|(_, _, provider)| provider),
+                ),
+            ],
+            |location| Ok(mode.compile_mode(location.mtime()?)),
+        )?;
+
+        // Compressed payloads are embedded as they are and decompressed by
+        // the runtime.
+        let mut compressed_payloads = Vec::new();
+        let mut embed = |field: ResourceField, name: &str, location: &DataLocation| {
+            embedded_payload(&mut compressed_payloads, field, name, location)
+        };
+
+        let mut resource = Resource {
             flavor: self.flavor,
             name: Cow::Owned(self.name.clone()),
             is_package: self.is_package,
             is_namespace_package: self.is_namespace_package,
             in_memory_source: if let Some(location) = &self.in_memory_source {
-                Some(Cow::Owned(location.resolve()?))
+                Some(Cow::Owned(embed(
+                    ResourceField::InMemorySource,
+                    "",
+                    location,
+                )?))
             } else {
                 None
             },
             in_memory_bytecode: match &self.in_memory_bytecode {
-                Some(PythonModuleBytecodeProvider::Provided(location)) => {
-                    Some(Cow::Owned(location.resolve()?))
-                }
-                Some(PythonModuleBytecodeProvider::FromSource(location)) => {
-                    Some(Cow::Owned(compiler.compile(
-                        &location.resolve()?,
-                        &self.name,
-                        BytecodeOptimizationLevel::Zero,
-                        CompileMode::Bytecode,
+                Some(PythonModuleBytecodeProvider::Provided(location)) => Some(Cow::Owned(embed(
+                    ResourceField::InMemoryBytecode,
+                    "",
+                    location,
+                )?)),
+                Some(PythonModuleBytecodeProvider::FromSource(_)) => {
+                    Some(Cow::Owned(in_memory_bytecode[0].take().ok_or_else(
+                        || anyhow!("bytecode of {} was not compiled", self.name),
                     )?))
                 }
                 None => None,
             },
             in_memory_bytecode_opt1: match &self.in_memory_bytecode_opt1 {
-                Some(PythonModuleBytecodeProvider::Provided(location)) => {
-                    Some(Cow::Owned(location.resolve()?))
-                }
-                Some(PythonModuleBytecodeProvider::FromSource(location)) => {
-                    Some(Cow::Owned(compiler.compile(
-                        &location.resolve()?,
-                        &self.name,
-                        BytecodeOptimizationLevel::One,
-                        CompileMode::Bytecode,
+                Some(PythonModuleBytecodeProvider::Provided(location)) => Some(Cow::Owned(embed(
+                    ResourceField::InMemoryBytecodeOpt1,
+                    "",
+                    location,
+                )?)),
+                Some(PythonModuleBytecodeProvider::FromSource(_)) => {
+                    Some(Cow::Owned(in_memory_bytecode[1].take().ok_or_else(
+                        || anyhow!("bytecode of {} was not compiled", self.name),
                     )?))
                 }
                 None => None,
             },
             in_memory_bytecode_opt2: match &self.in_memory_bytecode_opt2 {
-                Some(PythonModuleBytecodeProvider::Provided(location)) => {
-                    Some(Cow::Owned(location.resolve()?))
-                }
-                Some(PythonModuleBytecodeProvider::FromSource(location)) => {
-                    Some(Cow::Owned(compiler.compile(
-                        &location.resolve()?,
-                        &self.name,
-                        BytecodeOptimizationLevel::Two,
-                        CompileMode::Bytecode,
+                Some(PythonModuleBytecodeProvider::Provided(location)) => Some(Cow::Owned(embed(
+                    ResourceField::InMemoryBytecodeOpt2,
+                    "",
+                    location,
+                )?)),
+                Some(PythonModuleBytecodeProvider::FromSource(_)) => {
+                    Some(Cow::Owned(in_memory_bytecode[2].take().ok_or_else(
+                        || anyhow!("bytecode of {} was not compiled", self.name),
                     )?))
                 }
                 None => None,
@@ -140,14 +958,21 @@ impl PrePackagedResource {
             in_memory_extension_module_shared_library: if let Some(location) =
                 &self.in_memory_extension_module_shared_library
             {
-                Some(Cow::Owned(location.resolve()?))
+                Some(Cow::Owned(embed(
+                    ResourceField::InMemoryExtensionModuleSharedLibrary,
+                    "",
+                    location,
+                )?))
             } else {
                 None
             },
             in_memory_package_resources: if let Some(resources) = &self.in_memory_resources {
                 let mut res = HashMap::new();
                 for (key, location) in resources {
-                    res.insert(Cow::Owned(key.clone()), Cow::Owned(location.resolve()?));
+                    res.insert(
+                        Cow::Owned(key.clone()),
+                        Cow::Owned(embed(ResourceField::InMemoryResourcesData, key, location)?),
+                    );
                 }
                 Some(res)
             } else {
@@ -158,17 +983,29 @@ impl PrePackagedResource {
             {
                 let mut res = HashMap::new();
                 for (key, location) in resources {
-                    res.insert(Cow::Owned(key.clone()), Cow::Owned(location.resolve()?));
+                    res.insert(
+                        Cow::Owned(key.clone()),
+                        Cow::Owned(embed(
+                            ResourceField::InMemoryDistributionResource,
+                            key,
+                            location,
+                        )?),
+                    );
                 }
                 Some(res)
             } else {
                 None
             },
             in_memory_shared_library: if let Some(location) = &self.in_memory_shared_library {
-                Some(Cow::Owned(location.resolve()?))
+                Some(Cow::Owned(embed(
+                    ResourceField::InMemorySharedLibrary,
+                    "",
+                    location,
+                )?))
             } else {
                 None
             },
+            extract_shared_library: self.extract_shared_library,
             shared_library_dependency_names: if let Some(names) =
                 &self.shared_library_dependency_names
             {
@@ -190,26 +1027,22 @@ impl PrePackagedResource {
             relative_path_module_bytecode: if let Some((prefix, cache_tag, provider)) =
                 &self.relative_path_bytecode
             {
-                let path = resolve_path_for_module(
-                    prefix,
-                    &self.name,
-                    self.is_package,
-                    Some(&format!(
-                        "{}{}",
-                        cache_tag,
-                        BytecodeOptimizationLevel::Zero.to_extra_tag()
-                    )),
-                );
+                let path =
+                    self.relative_bytecode_path(prefix, cache_tag, BytecodeOptimizationLevel::Zero);
 
                 installs.push((
                     path.clone(),
                     DataLocation::Memory(match provider {
-                        PythonModuleBytecodeProvider::FromSource(location) => compiler.compile(
-                            &location.resolve()?,
-                            &self.name,
-                            BytecodeOptimizationLevel::Zero,
-                            CompileMode::PycUncheckedHash,
-                        )?,
+                        PythonModuleBytecodeProvider::FromSource(_) => relative_path_bytecode[0]
+                            .take()
+                            .ok_or_else(|| anyhow!("bytecode of {} was not compiled", self.name))?,
+                        PythonModuleBytecodeProvider::Provided(location)
+                            if self
+                                .pyc_payloads
+                                .contains(bytecode_label(BytecodeOptimizationLevel::Zero)) =>
+                        {
+                            location.resolve()?
+                        }
                         PythonModuleBytecodeProvider::Provided(location) => {
                             let mut data = compute_bytecode_header(
                                 compiler.get_magic_number(),
@@ -230,26 +1063,22 @@ impl PrePackagedResource {
             relative_path_module_bytecode_opt1: if let Some((prefix, cache_tag, provider)) =
                 &self.relative_path_bytecode_opt1
             {
-                let path = resolve_path_for_module(
-                    prefix,
-                    &self.name,
-                    self.is_package,
-                    Some(&format!(
-                        "{}{}",
-                        cache_tag,
-                        BytecodeOptimizationLevel::One.to_extra_tag()
-                    )),
-                );
+                let path =
+                    self.relative_bytecode_path(prefix, cache_tag, BytecodeOptimizationLevel::One);
 
                 installs.push((
                     path.clone(),
                     DataLocation::Memory(match provider {
-                        PythonModuleBytecodeProvider::FromSource(location) => compiler.compile(
-                            &location.resolve()?,
-                            &self.name,
-                            BytecodeOptimizationLevel::One,
-                            CompileMode::PycUncheckedHash,
-                        )?,
+                        PythonModuleBytecodeProvider::FromSource(_) => relative_path_bytecode[1]
+                            .take()
+                            .ok_or_else(|| anyhow!("bytecode of {} was not compiled", self.name))?,
+                        PythonModuleBytecodeProvider::Provided(location)
+                            if self
+                                .pyc_payloads
+                                .contains(bytecode_label(BytecodeOptimizationLevel::One)) =>
+                        {
+                            location.resolve()?
+                        }
                         PythonModuleBytecodeProvider::Provided(location) => {
                             let mut data = compute_bytecode_header(
                                 compiler.get_magic_number(),
@@ -270,26 +1099,22 @@ impl PrePackagedResource {
             relative_path_module_bytecode_opt2: if let Some((prefix, cache_tag, provider)) =
                 &self.relative_path_bytecode_opt2
             {
-                let path = resolve_path_for_module(
-                    prefix,
-                    &self.name,
-                    self.is_package,
-                    Some(&format!(
-                        "{}{}",
-                        cache_tag,
-                        BytecodeOptimizationLevel::Two.to_extra_tag()
-                    )),
-                );
+                let path =
+                    self.relative_bytecode_path(prefix, cache_tag, BytecodeOptimizationLevel::Two);
 
                 installs.push((
                     path.clone(),
                     DataLocation::Memory(match provider {
-                        PythonModuleBytecodeProvider::FromSource(location) => compiler.compile(
-                            &location.resolve()?,
-                            &self.name,
-                            BytecodeOptimizationLevel::Two,
-                            CompileMode::PycUncheckedHash,
-                        )?,
+                        PythonModuleBytecodeProvider::FromSource(_) => relative_path_bytecode[2]
+                            .take()
+                            .ok_or_else(|| anyhow!("bytecode of {} was not compiled", self.name))?,
+                        PythonModuleBytecodeProvider::Provided(location)
+                            if self
+                                .pyc_payloads
+                                .contains(bytecode_label(BytecodeOptimizationLevel::Two)) =>
+                        {
+                            location.resolve()?
+                        }
                         PythonModuleBytecodeProvider::Provided(location) => {
                             let mut data = compute_bytecode_header(
                                 compiler.get_magic_number(),
@@ -342,7 +1167,11 @@ impl PrePackagedResource {
             } else {
                 None
             },
+            resource_set: ResourceSet::Main,
+            external_payloads: Vec::new(),
+            compressed_payloads: Vec::new(),
         };
+        resource.compressed_payloads = compressed_payloads;
 
         if let Some((prefix, location)) = &self.relative_path_shared_library {
             installs.push((
@@ -356,11 +1185,367 @@ impl PrePackagedResource {
     }
 }
 
-/// Fill in missing data on parent packages.
+/// Obtain the data of a payload embedded in packed resources.
 ///
-/// When resources are added, their parent packages could be missing
-/// data. If we simply materialized the child resources without the
-/// parents, Python's importer would get confused due to the missing
+/// `CompressedMemory` payloads are embedded compressed, recording a
+/// `CompressedPayload` for `field` and `name` in `compressed`. Other payloads
+/// are resolved.
+fn embedded_payload(
+    compressed: &mut Vec<CompressedPayload<'static>>,
+    field: ResourceField,
+    name: &str,
+    location: &DataLocation,
+) -> Result<Vec<u8>> {
+    match location {
+        DataLocation::CompressedMemory { data, size } => {
+            compressed.push(CompressedPayload {
+                field,
+                name: Cow::Owned(name.to_string()),
+                length: *size,
+            });
+
+            Ok(data.clone())
+        }
+        location => location.resolve(),
+    }
+}
+
+/// Obtain a `DataLocation` referencing data within a blob.
+///
+/// If `data` is a slice of `blob`, the returned location references the blob
+/// without copying. Otherwise the data is copied into memory.
+fn blob_data_location(blob: &Arc<Vec<u8>>, data: &[u8]) -> DataLocation {
+    let blob_start = blob.as_ptr() as usize;
+    let data_start = data.as_ptr() as usize;
+
+    if data_start >= blob_start && data_start + data.len() <= blob_start + blob.len() {
+        DataLocation::Slice {
+            data: blob.clone(),
+            offset: data_start - blob_start,
+            length: data.len(),
+        }
+    } else {
+        DataLocation::Memory(data.to_vec())
+    }
+}
+
+/// Resolve the path prefix of a relative path given the path expected without a prefix.
+fn prefix_from_relative_path(path: &Path, expected: &Path) -> Option<String> {
+    let path = path.to_string_lossy().replace('\\', "/");
+    let expected = expected.to_string_lossy().replace('\\', "/");
+
+    if path == expected {
+        Some("".to_string())
+    } else if path.ends_with(&format!("/{}", expected)) {
+        Some(path[0..path.len() - expected.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+/// Parse a packed resources blob into `PrePackagedResource` instances.
+///
+/// In-memory data is referenced from `data` without being copied. Data for
+/// relative path resources isn't stored in the blob, so it is resolved
+/// relative to `install_root`, which must be defined if the blob contains
+/// relative path resources. Files are read when their content is needed,
+/// except for the headers of bytecode files, which are checked up front.
+///
+/// `cache_tag` is the bytecode cache tag of the current Python distribution.
+/// `source_cache_tag` is the cache tag of the distribution the blob was
+/// built with, as recorded in the manifest of its build. The blob doesn't
+/// record it, and in-memory bytecode has no header identifying its Python
+/// version, so it can't be checked otherwise. Differing tags and relative
+/// path bytecode not matching the tag result in an error.
+///
+/// Only resources of the main resource set are returned.
+pub fn prepackaged_resources_from_packed_resources(
+    data: &Arc<Vec<u8>>,
+    cache_tag: &str,
+    source_cache_tag: &str,
+    install_root: Option<&Path>,
+) -> Result<Vec<PrePackagedResource>> {
+    if source_cache_tag != cache_tag {
+        return Err(anyhow!(
+            "packed resources were built for {} but the distribution uses {}",
+            source_cache_tag,
+            cache_tag
+        ));
+    }
+
+    let mut res = Vec::new();
+
+    let resolve_install_path = |path: &Path| -> Result<PathBuf> {
+        match install_root {
+            Some(root) => Ok(root.join(path)),
+            None => Err(anyhow!(
+                "packed resources reference relative path {} but no install root was provided",
+                path.display()
+            )),
+        }
+    };
+
+    let relative_bytecode = |name: &str,
+                             is_package: bool,
+                             path: &Path,
+                             optimize_level: BytecodeOptimizationLevel|
+     -> Result<(String, String, PythonModuleBytecodeProvider)> {
+        let expected = resolve_path_for_module(
+            "",
+            name,
+            is_package,
+            Some(&optimize_level.to_bytecode_tag(cache_tag)),
+        );
+
+        let prefix = prefix_from_relative_path(path, &expected).ok_or_else(|| {
+            anyhow!(
+                "bytecode path {} for {} does not match cache tag {}",
+                path.display(),
+                name,
+                cache_tag
+            )
+        })?;
+
+        let bytecode = PythonModuleBytecode::from_path(
+            name,
+            optimize_level,
+            cache_tag,
+            &resolve_install_path(path)?,
+        )
+        .bytecode_data_location()?;
+
+        Ok((
+            prefix,
+            cache_tag.to_string(),
+            PythonModuleBytecodeProvider::Provided(bytecode),
+        ))
+    };
+
+    let loader = python_packed_resources::parser::load_resources(data)
+        .map_err(|e| anyhow!("error parsing packed resources: {}", e))?;
+
+    for resource in loader {
+        let resource = resource.map_err(|e| anyhow!("error parsing packed resources: {}", e))?;
+        if resource.resource_set != ResourceSet::Main {
+            continue;
+        }
+        if let Some(payload) = resource.external_payloads.first() {
+            return Err(anyhow!(
+                "packed resources store a payload of {} externally at {}; only self-contained packed resources can be added",
+                resource.name,
+                payload.location
+            ));
+        }
+        let name = resource.name.to_string();
+
+        let mut entry = PrePackagedResource {
+            flavor: resource.flavor,
+            name: name.clone(),
+            is_package: resource.is_package,
+            is_namespace_package: resource.is_namespace_package,
+            ..PrePackagedResource::default()
+        };
+
+        if let Some(source) = &resource.in_memory_source {
+            entry.in_memory_source = Some(blob_data_location(data, source));
+        }
+        if let Some(bytecode) = &resource.in_memory_bytecode {
+            entry.in_memory_bytecode = Some(PythonModuleBytecodeProvider::Provided(
+                blob_data_location(data, bytecode),
+            ));
+        }
+        if let Some(bytecode) = &resource.in_memory_bytecode_opt1 {
+            entry.in_memory_bytecode_opt1 = Some(PythonModuleBytecodeProvider::Provided(
+                blob_data_location(data, bytecode),
+            ));
+        }
+        if let Some(bytecode) = &resource.in_memory_bytecode_opt2 {
+            entry.in_memory_bytecode_opt2 = Some(PythonModuleBytecodeProvider::Provided(
+                blob_data_location(data, bytecode),
+            ));
+        }
+        if let Some(library) = &resource.in_memory_extension_module_shared_library {
+            entry.in_memory_extension_module_shared_library =
+                Some(blob_data_location(data, library));
+        }
+        if let Some(resources) = &resource.in_memory_package_resources {
+            entry.in_memory_resources = Some(BTreeMap::from_iter(
+                resources
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), blob_data_location(data, v))),
+            ));
+        }
+        if let Some(resources) = &resource.in_memory_distribution_resources {
+            entry.in_memory_distribution_resources = Some(BTreeMap::from_iter(
+                resources
+                    .iter()
+                    .map(|(k, v)| (k.to_string(), blob_data_location(data, v))),
+            ));
+        }
+        if let Some(library) = &resource.in_memory_shared_library {
+            entry.in_memory_shared_library = Some(blob_data_location(data, library));
+        }
+        entry.extract_shared_library = resource.extract_shared_library;
+        if let Some(names) = &resource.shared_library_dependency_names {
+            entry.shared_library_dependency_names =
+                Some(names.iter().map(|x| x.to_string()).collect());
+        }
+
+        if let Some(path) = &resource.relative_path_module_source {
+            let expected = resolve_path_for_module("", &name, entry.is_package, None);
+            let prefix = prefix_from_relative_path(path, &expected).ok_or_else(|| {
+                anyhow!(
+                    "source path {} does not match module {}",
+                    path.display(),
+                    name
+                )
+            })?;
+
+            entry.relative_path_module_source = Some((
+                prefix,
+                DataLocation::from_path(resolve_install_path(&**path)?),
+            ));
+        }
+        if let Some(path) = &resource.relative_path_module_bytecode {
+            entry.relative_path_bytecode = Some(relative_bytecode(
+                &name,
+                entry.is_package,
+                &**path,
+                BytecodeOptimizationLevel::Zero,
+            )?);
+        }
+        if let Some(path) = &resource.relative_path_module_bytecode_opt1 {
+            entry.relative_path_bytecode_opt1 = Some(relative_bytecode(
+                &name,
+                entry.is_package,
+                &**path,
+                BytecodeOptimizationLevel::One,
+            )?);
+        }
+        if let Some(path) = &resource.relative_path_module_bytecode_opt2 {
+            entry.relative_path_bytecode_opt2 = Some(relative_bytecode(
+                &name,
+                entry.is_package,
+                &**path,
+                BytecodeOptimizationLevel::Two,
+            )?);
+        }
+        if let Some(path) = &resource.relative_path_extension_module_shared_library {
+            entry.relative_path_extension_module_shared_library = Some((
+                path.to_path_buf(),
+                DataLocation::from_path(resolve_install_path(&**path)?),
+            ));
+        }
+        if let Some(resources) = &resource.relative_path_package_resources {
+            let mut m = BTreeMap::new();
+            for (key, path) in resources {
+                m.insert(
+                    key.to_string(),
+                    (
+                        path.to_path_buf(),
+                        DataLocation::from_path(resolve_install_path(&**path)?),
+                    ),
+                );
+            }
+            entry.relative_path_package_resources = Some(m);
+        }
+        if let Some(resources) = &resource.relative_path_distribution_resources {
+            let mut m = BTreeMap::new();
+            for (key, path) in resources {
+                m.insert(
+                    key.to_string(),
+                    (
+                        path.to_path_buf(),
+                        DataLocation::from_path(resolve_install_path(&**path)?),
+                    ),
+                );
+            }
+            entry.relative_path_distribution_resources = Some(m);
+        }
+
+        res.push(entry);
+    }
+
+    Ok(res)
+}
+
+/// Describes the result of compiling pending bytecode in a collection.
+#[derive(Clone, Debug, Default)]
+pub struct CompileReport {
+    /// Bytecode that was compiled and how long compilation took.
+    pub compiled: Vec<(String, BytecodeOptimizationLevel, Duration)>,
+
+    /// Bytecode that failed to compile and the reason why.
+    ///
+    /// Failed entries remain pending in the collection so they can be retried.
+    pub failures: Vec<(String, BytecodeOptimizationLevel, String)>,
+}
+
+impl CompileReport {
+    /// Whether all pending bytecode compiled successfully.
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Total time spent compiling bytecode.
+    pub fn total_duration(&self) -> Duration {
+        self.compiled.iter().map(|(_, _, d)| *d).sum()
+    }
+}
+
+/// Resolve a bytecode provider to provided bytecode by compiling its source.
+///
+/// Bytecode is compiled to a complete .pyc file invalidated per `pyc_mode`,
+/// if given. Errors are recorded in the report and leave the provider
+/// untouched. Returns whether the provider was compiled.
+fn compile_bytecode_provider(
+    provider: &mut PythonModuleBytecodeProvider,
+    name: &str,
+    optimize_level: BytecodeOptimizationLevel,
+    pyc_mode: Option<PycInvalidationMode>,
+    compiler: &mut dyn PythonBytecodeCompiler,
+    report: &mut CompileReport,
+) -> bool {
+    let location = match provider {
+        PythonModuleBytecodeProvider::FromSource(location) => location,
+        PythonModuleBytecodeProvider::Provided(_) => return false,
+    };
+
+    let start = Instant::now();
+
+    let res = location.resolve().and_then(|source| {
+        let mode = match pyc_mode {
+            Some(mode) => mode.compile_mode(location.mtime()?),
+            None => CompileMode::Bytecode,
+        };
+
+        compiler.compile(&source, name, optimize_level, mode)
+    });
+
+    match res {
+        Ok(bytecode) => {
+            report
+                .compiled
+                .push((name.to_string(), optimize_level, start.elapsed()));
+            *provider = PythonModuleBytecodeProvider::Provided(DataLocation::Memory(bytecode));
+
+            true
+        }
+        Err(e) => {
+            report
+                .failures
+                .push((name.to_string(), optimize_level, format!("{:?}", e)));
+
+            false
+        }
+    }
+}
+
+/// Fill in missing data on parent packages.
+///
+/// When resources are added, their parent packages could be missing
+/// data. If we simply materialized the child resources without the
+/// parents, Python's importer would get confused due to the missing
 /// resources.
 ///
 /// This function fills in the blanks in our resources state.
@@ -519,7 +1704,7 @@ pub enum AbstractResourceLocation {
 }
 
 /// Describes the concrete location of a Python resource.
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum ConcreteResourceLocation {
     /// Resource is loaded from memory.
     InMemory,
@@ -536,6 +1721,113 @@ impl From<&ConcreteResourceLocation> for AbstractResourceLocation {
     }
 }
 
+impl std::fmt::Display for ConcreteResourceLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConcreteResourceLocation::InMemory => write!(f, "in-memory"),
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                write!(f, "filesystem-relative:{}", prefix)
+            }
+        }
+    }
+}
+
+/// What can be relocated by `PythonResourceCollector::relocate_package()`.
+#[derive(Clone, Debug, Default)]
+pub struct RelocationConstraints {
+    /// Whether extension module shared libraries can be loaded from memory.
+    pub extension_modules_in_memory: bool,
+
+    /// Whether extension module shared libraries can be loaded from the filesystem.
+    pub extension_modules_filesystem: bool,
+
+    /// Filename suffix of extension modules moved to the filesystem.
+    pub extension_file_suffix: Option<String>,
+
+    /// Move payloads that fail a safety check instead of refusing.
+    pub force: bool,
+}
+
+/// A payload moved by a relocation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelocatedPayload {
+    /// Name of the resource the payload belongs to.
+    pub resource: String,
+
+    /// The payload that was moved.
+    pub payload: PayloadKind,
+
+    /// Where the payload was.
+    pub from: String,
+
+    /// Where the payload is now.
+    pub to: String,
+}
+
+/// Describes the outcome of relocating a package.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RelocationReport {
+    /// Payloads that were moved.
+    pub moved: Vec<RelocatedPayload>,
+
+    /// Resources moved despite failing a safety check, with the reason.
+    pub forced: Vec<(String, String)>,
+
+    /// Resources or payloads that weren't moved, with the reason.
+    pub left_behind: Vec<(String, String)>,
+}
+
+impl RelocationReport {
+    fn record_move(
+        &mut self,
+        resource: &str,
+        payload: PayloadKind,
+        from: String,
+        to: &ConcreteResourceLocation,
+    ) {
+        self.moved.push(RelocatedPayload {
+            resource: resource.to_string(),
+            payload,
+            from,
+            to: to.to_string(),
+        });
+    }
+}
+
+/// Derive the metadata directory name of in-memory distribution resources.
+///
+/// The directory name embeds the distribution version, which isn't retained
+/// for in-memory resources. It is recovered from the `METADATA` or `PKG-INFO`
+/// file, if present.
+fn distribution_directory(
+    package: &str,
+    resources: &BTreeMap<String, DataLocation>,
+) -> Result<Option<String>> {
+    for (file, suffix) in &[("METADATA", "dist-info"), ("PKG-INFO", "egg-info")] {
+        if let Some(location) = resources.get(*file) {
+            let data = location.resolve()?;
+            let version = String::from_utf8_lossy(&data)
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .find(|line| line.starts_with("Version:"))
+                .map(|line| line["Version:".len()..].trim().to_string());
+
+            if let Some(version) = version {
+                return Ok(Some(format!("{}-{}.{}", package, version, suffix)));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+/// Path of a package resource relative to a prefix.
+fn package_resource_path(prefix: &str, package: &str, relative_name: &str) -> PathBuf {
+    let mut path = PathBuf::from(prefix);
+    path.extend(package.split('.'));
+    path.join(relative_name)
+}
+
 /// Represents a finalized collection of Python resources.
 ///
 /// Instances are produced from a `PythonResourceCollector` and a
@@ -544,1901 +1836,7978 @@ impl From<&ConcreteResourceLocation> for AbstractResourceLocation {
 pub struct CompiledResourcesCollection<'a> {
     pub resources: BTreeMap<String, Resource<'a, u8>>,
     pub extra_files: Vec<FileInstall>,
+
+    /// Name of the resource installing each entry of `extra_files`, in the same order.
+    pub extra_file_owners: Vec<String>,
+
+    /// Modules whose source failed to compile and were skipped or embedded as source.
+    pub compile_errors: Vec<CompileError>,
+
+    /// Padding between payloads in blob sections of written packed resources.
+    pub interior_padding: Option<BlobInteriorPadding>,
+}
+
+/// What a recovery resource set adds to packed resources.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct RecoverySetCost {
+    /// Number of resources in the recovery set.
+    pub resources_count: usize,
+
+    /// Size of packed resources holding only the recovery set.
+    pub standalone_bytes: usize,
+
+    /// Bytes the recovery set adds to packed resources of the main set.
+    ///
+    /// Payloads identical to ones of the main set are stored once, so this
+    /// is usually less than `standalone_bytes`.
+    pub incremental_bytes: usize,
 }
 
 impl<'a> CompiledResourcesCollection<'a> {
-    /// Write resources to packed resources data, version 1.
+    /// Write resources to packed resources data with a sequential index.
+    ///
+    /// This is version 1 of the format, or version 2 if payloads are shared
+    /// or resources need fields version 1 lacks.
     pub fn write_packed_resources_v1<W: std::io::Write>(&self, writer: &mut W) -> Result<()> {
-        python_packed_resources::writer::write_packed_resources_v1(
-            &self
-                .resources
-                .values()
-                .cloned()
-                .collect::<Vec<Resource<'a, u8>>>(),
-            writer,
-            None,
-        )
+        self.write_packed_resources(writer, IndexEncoding::Sequential)
     }
-}
 
-/// Type used to collect Python resources so they can be serialized.
-///
-/// We often want to turn Python resource primitives (module source,
-/// bytecode, etc) into a collection of `Resource` so they can be
-/// serialized to the *Python packed resources* format. This type
-/// exists to facilitate doing this.
-#[derive(Debug, Clone)]
-pub struct PythonResourceCollector {
-    policy: PythonResourcesPolicy,
-    resources: BTreeMap<String, PrePackagedResource>,
-    cache_tag: String,
-}
+    /// Write resources to packed resources data with an index encoding.
+    pub fn write_packed_resources<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        encoding: IndexEncoding,
+    ) -> Result<()> {
+        self.write_packed_resources_with_recovery(writer, encoding, None)
+    }
 
-impl PythonResourceCollector {
-    /// Construct a new instance of the collector.
+    /// Write resources and an optional recovery resource set to packed resources data.
     ///
-    /// The instance is associated with a resources policy to validate that
-    /// added resources conform with rules.
+    /// Resources of `recovery` are marked as belonging to the recovery set.
+    /// Payloads identical between both sets are stored once.
+    pub fn write_packed_resources_with_recovery<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        encoding: IndexEncoding,
+        recovery: Option<&CompiledResourcesCollection<'a>>,
+    ) -> Result<()> {
+        self.write_packed_resources_to_sink(writer, encoding, recovery, &mut EmbeddedSink)?;
+
+        Ok(())
+    }
+
+    /// Write packed resources data, passing resource payloads to a sink.
     ///
-    /// We also pass a Python bytecode cache tag, which is used to
-    /// derive filenames.
-    pub fn new(policy: &PythonResourcesPolicy, cache_tag: &str) -> Self {
-        Self {
-            policy: policy.clone(),
-            resources: BTreeMap::new(),
-            cache_tag: cache_tag.to_string(),
-        }
+    /// Payloads the sink stores are recorded in the index by digest and
+    /// location instead of being written to `writer`.
+    pub fn write_packed_resources_to_sink<W: std::io::Write>(
+        &self,
+        writer: &mut W,
+        encoding: IndexEncoding,
+        recovery: Option<&CompiledResourcesCollection<'a>>,
+        sink: &mut dyn PayloadSink,
+    ) -> Result<ExternalizedPayloads> {
+        let mut resources = self.packed_resources(recovery);
+        let externalized = externalize_payloads(&mut resources, sink)?;
+
+        python_packed_resources::writer::write_packed_resources(
+            &resources,
+            writer,
+            self.interior_padding,
+            encoding,
+        )?;
+
+        Ok(externalized)
     }
 
-    /// Obtain the policy for this collector.
-    pub fn get_policy(&self) -> &PythonResourcesPolicy {
-        &self.policy
+    /// Obtain identical payloads that packed resources will only store once.
+    pub fn shared_payloads(&self) -> python_packed_resources::writer::SharedPayloads {
+        self.shared_payloads_with_recovery(None)
     }
 
-    /// Validate that a resource add in the specified location is allowed.
-    pub fn check_policy(&self, location: AbstractResourceLocation) -> Result<()> {
-        match self.policy {
-            PythonResourcesPolicy::InMemoryOnly => match location {
-                AbstractResourceLocation::InMemory => Ok(()),
-                AbstractResourceLocation::RelativePath => Err(anyhow!(
-                    "in-memory-only policy does not allow relative path resources"
-                )),
-            },
-            PythonResourcesPolicy::FilesystemRelativeOnly(_) => match location {
-                AbstractResourceLocation::InMemory => Err(anyhow!(
-                    "filesystem-relative-only policy does not allow in-memory resources"
-                )),
-                AbstractResourceLocation::RelativePath => Ok(()),
-            },
-            PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(_) => Ok(()),
-        }
+    /// Obtain identical payloads that packed resources including a recovery set will only store once.
+    pub fn shared_payloads_with_recovery(
+        &self,
+        recovery: Option<&CompiledResourcesCollection<'a>>,
+    ) -> python_packed_resources::writer::SharedPayloads {
+        python_packed_resources::writer::SharedPayloads::resolve(
+            &self.packed_resources(recovery),
+            self.interior_padding,
+        )
     }
 
-    /// Apply a filter function on resources in this collection and mutate in place.
+    /// Compute what adding a recovery resource set costs in packed resources.
+    pub fn recovery_set_cost(
+        &self,
+        recovery: &CompiledResourcesCollection<'a>,
+        encoding: IndexEncoding,
+    ) -> Result<RecoverySetCost> {
+        let mut main = Vec::new();
+        self.write_packed_resources(&mut main, encoding)?;
+        let mut standalone = Vec::new();
+        CompiledResourcesCollection::default().write_packed_resources_with_recovery(
+            &mut standalone,
+            encoding,
+            Some(recovery),
+        )?;
+        let mut combined = Vec::new();
+        self.write_packed_resources_with_recovery(&mut combined, encoding, Some(recovery))?;
+
+        Ok(RecoverySetCost {
+            resources_count: recovery.resources.len(),
+            standalone_bytes: standalone.len(),
+            incremental_bytes: combined.len() - main.len(),
+        })
+    }
+
+    /// Obtain the sizes of compiled bytecode of modules.
     ///
-    /// If the filter function returns true, the item will be preserved.
-    pub fn filter_resources_mut<F>(&mut self, filter: F) -> Result<()>
-    where
-        F: Fn(&PrePackagedResource) -> bool,
-    {
-        self.resources = BTreeMap::from_iter(self.resources.iter().filter_map(|(k, v)| {
-            if filter(v) {
-                Some((k.clone(), v.clone()))
-            } else {
-                None
+    /// Bytecode embedded in packed resources and installed relative to the
+    /// binary is counted, including .pyc headers.
+    pub fn bytecode_size_report(&self) -> Result<BytecodeSizeReport> {
+        let installs = self
+            .extra_files
+            .iter()
+            .map(|(path, location, _)| (path.as_path(), location))
+            .collect::<HashMap<_, _>>();
+
+        let install_size = |path: &Option<Cow<'a, Path>>| -> Result<Option<u64>> {
+            match path {
+                Some(path) => match installs.get(&**path) {
+                    Some(location) => Ok(Some(location.size()?)),
+                    None => Err(anyhow!("{} is not installed", path.display())),
+                },
+                None => Ok(None),
             }
-        }));
+        };
 
-        Ok(())
-    }
+        let mut modules = Vec::new();
 
-    /// Obtain an iterator over the resources in this collector.
-    pub fn iter_resources(&self) -> impl Iterator<Item = (&String, &PrePackagedResource)> {
-        Box::new(self.resources.iter())
+        for (name, resource) in &self.resources {
+            let mut levels = BTreeMap::new();
+
+            for &(level, in_memory, relative_path) in &[
+                (
+                    BytecodeOptimizationLevel::Zero,
+                    &resource.in_memory_bytecode,
+                    &resource.relative_path_module_bytecode,
+                ),
+                (
+                    BytecodeOptimizationLevel::One,
+                    &resource.in_memory_bytecode_opt1,
+                    &resource.relative_path_module_bytecode_opt1,
+                ),
+                (
+                    BytecodeOptimizationLevel::Two,
+                    &resource.in_memory_bytecode_opt2,
+                    &resource.relative_path_module_bytecode_opt2,
+                ),
+            ] {
+                let size = in_memory
+                    .as_ref()
+                    .map(|data| data.len() as u64)
+                    .unwrap_or(0)
+                    + install_size(relative_path)
+                        .with_context(|| format!("obtaining bytecode size of {}", name))?
+                        .unwrap_or(0);
+
+                if in_memory.is_some() || relative_path.is_some() {
+                    levels.insert(i32::from(level), size);
+                }
+            }
+
+            if !levels.is_empty() {
+                modules.push(ModuleBytecodeSize {
+                    name: name.clone(),
+                    package: name.split('.').next().unwrap_or(name).to_string(),
+                    total_bytes: levels.values().sum(),
+                    levels,
+                });
+            }
+        }
+
+        Ok(BytecodeSizeReport::from_modules(modules))
     }
 
-    /// Add Python module source with a specific location.
-    pub fn add_python_module_source(
-        &mut self,
-        module: &PythonModuleSource,
-        location: &ConcreteResourceLocation,
-    ) -> Result<()> {
-        self.check_policy(location.into())?;
+    /// Obtain resources to write to packed resources, main set first.
+    fn packed_resources(
+        &self,
+        recovery: Option<&CompiledResourcesCollection<'a>>,
+    ) -> Vec<Resource<'a, u8>> {
+        let mut res = self.resources.values().cloned().collect::<Vec<_>>();
+
+        if let Some(recovery) = recovery {
+            res.extend(recovery.resources.values().map(|resource| Resource {
+                resource_set: ResourceSet::Recovery,
+                ..resource.clone()
+            }));
+        }
 
-        let entry = self
-            .resources
-            .entry(module.name.clone())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: module.name.clone(),
-                ..PrePackagedResource::default()
-            });
-        entry.is_package = module.is_package;
+        res
+    }
+}
 
-        match location {
-            ConcreteResourceLocation::InMemory => {
-                entry.in_memory_source = Some(module.source.clone());
-            }
-            ConcreteResourceLocation::RelativePath(prefix) => {
-                entry.relative_path_module_source =
-                    Some((prefix.to_string(), module.source.clone()));
-            }
+/// Describe where data comes from, for error messages.
+fn describe_location(location: &DataLocation) -> String {
+    match location {
+        DataLocation::Path { path, .. } => path.display().to_string(),
+        DataLocation::ArchiveMember { archive, member } => {
+            format!("{} in {}", member, archive.display())
+        }
+        DataLocation::FileRange { path, offset, .. } => {
+            format!("{} at offset {}", path.display(), offset)
         }
+        DataLocation::Memory(_)
+        | DataLocation::Slice { .. }
+        | DataLocation::CompressedMemory { .. } => "memory".to_string(),
+    }
+}
 
-        Ok(())
+/// Describe where a resource's data comes from, for error messages.
+fn resource_provenance(resource: &PrePackagedResource) -> String {
+    let mut locations = vec![
+        resource.in_memory_source.as_ref(),
+        resource
+            .relative_path_module_source
+            .as_ref()
+            .map(|(_, l)| l),
+        resource.in_memory_extension_module_shared_library.as_ref(),
+        resource
+            .relative_path_extension_module_shared_library
+            .as_ref()
+            .map(|(_, l)| l),
+        resource.in_memory_shared_library.as_ref(),
+        resource
+            .relative_path_shared_library
+            .as_ref()
+            .map(|(_, l)| l),
+    ];
+
+    let providers = [
+        resource.in_memory_bytecode.as_ref(),
+        resource.relative_path_bytecode.as_ref().map(|(_, _, p)| p),
+    ];
+
+    for provider in providers.iter().copied().flatten() {
+        match provider {
+            PythonModuleBytecodeProvider::Provided(location) => locations.push(Some(location)),
+            PythonModuleBytecodeProvider::FromSource(location) => locations.push(Some(location)),
+        }
     }
 
-    /// Add Python module bytecode to the specified location.
-    pub fn add_python_module_bytecode(
-        &mut self,
-        module: &PythonModuleBytecode,
-        location: &ConcreteResourceLocation,
-    ) -> Result<()> {
-        self.check_policy(location.into())?;
+    let kind = match resource.flavor {
+        ResourceFlavor::Module => "module",
+        ResourceFlavor::BuiltinExtensionModule => "built-in extension module",
+        ResourceFlavor::FrozenModule => "frozen module",
+        ResourceFlavor::Extension => "extension module",
+        ResourceFlavor::SharedLibrary => "shared library",
+        ResourceFlavor::None => "resource",
+    };
 
-        let entry = self
-            .resources
-            .entry(module.name.clone())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: module.name.clone(),
-                ..PrePackagedResource::default()
-            });
+    let provenance = match locations.into_iter().flatten().next() {
+        Some(location) => format!("{} from {}", kind, describe_location(location)),
+        None => kind.to_string(),
+    };
 
-        entry.is_package = module.is_package;
+    if resource.applied_patches.is_empty() {
+        provenance
+    } else {
+        format!(
+            "{} patched by {}",
+            provenance,
+            resource
+                .applied_patches
+                .iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+}
 
-        // TODO having to resolve the DataLocation here is a bit unfortunate.
-        // We could invent a better type to allow the I/O to remain lazy.
-        let bytecode = PythonModuleBytecodeProvider::Provided(DataLocation::Memory(
-            module.resolve_bytecode()?,
-        ));
+/// Result of applying a `PackagePatch` to collected resources.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PackagePatchOutcome {
+    /// The patched distribution isn't among the collected resources.
+    DistributionMissing,
 
-        match location {
-            ConcreteResourceLocation::InMemory => match module.optimize_level {
-                BytecodeOptimizationLevel::Zero => {
-                    entry.in_memory_bytecode = Some(bytecode);
-                }
-                BytecodeOptimizationLevel::One => {
-                    entry.in_memory_bytecode_opt1 = Some(bytecode);
-                }
-                BytecodeOptimizationLevel::Two => {
-                    entry.in_memory_bytecode_opt2 = Some(bytecode);
-                }
-            },
-            ConcreteResourceLocation::RelativePath(prefix) => match module.optimize_level {
-                BytecodeOptimizationLevel::Zero => {
-                    entry.relative_path_bytecode =
-                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode));
-                }
-                BytecodeOptimizationLevel::One => {
-                    entry.relative_path_bytecode_opt1 =
-                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode));
-                }
-                BytecodeOptimizationLevel::Two => {
-                    entry.relative_path_bytecode_opt2 =
-                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode));
-                }
-            },
+    /// The collected distribution has a version the patch doesn't apply to.
+    VersionMismatch(String),
+
+    /// The collected distribution has a version that isn't a valid PEP 440
+    /// version, so the patch's version range can't be evaluated.
+    InvalidVersion(String),
+
+    /// The patch was applied to the collected distribution having this version.
+    Applied(String),
+}
+
+/// How a resource present in two merged collections is resolved.
+///
+/// Also how a payload added to a resource already having one is resolved.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollisionResolution {
+    /// Keep the resource of the collection being merged into.
+    Ours,
+
+    /// Replace the resource with the one being merged.
+    Theirs,
+
+    /// Fail the merge.
+    Error,
+
+    /// Keep the resource if both have the same content, fail otherwise.
+    ContentEqualOk,
+}
+
+/// How `PythonResourceCollector::merge_collection()` resolves collisions.
+///
+/// Each kind of resource is resolved independently. Resources of different
+/// kinds having the same name always fail the merge.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MergeStrategy {
+    /// Resolution for modules, including builtin and frozen modules.
+    pub modules: CollisionResolution,
+
+    /// Resolution for extension modules.
+    pub extension_modules: CollisionResolution,
+
+    /// Resolution for shared libraries.
+    pub shared_libraries: CollisionResolution,
+}
+
+impl MergeStrategy {
+    /// Resolve collisions of every kind of resource the same way.
+    pub fn uniform(resolution: CollisionResolution) -> Self {
+        Self {
+            modules: resolution,
+            extension_modules: resolution,
+            shared_libraries: resolution,
         }
+    }
 
-        Ok(())
+    /// Obtain the resolution for resources of a flavor.
+    pub fn resolution(&self, flavor: ResourceFlavor) -> CollisionResolution {
+        match flavor {
+            ResourceFlavor::Extension | ResourceFlavor::BuiltinExtensionModule => {
+                self.extension_modules
+            }
+            ResourceFlavor::SharedLibrary => self.shared_libraries,
+            ResourceFlavor::None | ResourceFlavor::Module | ResourceFlavor::FrozenModule => {
+                self.modules
+            }
+        }
     }
+}
 
-    /// Add Python module bytecode derived from source code to the collection.
-    pub fn add_python_module_bytecode_from_source(
-        &mut self,
-        module: &PythonModuleBytecodeFromSource,
-        location: &ConcreteResourceLocation,
-    ) -> Result<()> {
-        self.check_policy(location.into())?;
+impl Default for MergeStrategy {
+    fn default() -> Self {
+        Self::uniform(CollisionResolution::Error)
+    }
+}
 
-        let entry = self
-            .resources
-            .entry(module.name.clone())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: module.name.clone(),
-                ..PrePackagedResource::default()
-            });
+/// Whether a synthetic resource is listed in user-visible outputs.
+///
+/// Internal resources are implementation details of the feature
+/// synthesizing them. They are always in packed resources so they remain
+/// importable, but can be left out of module name lists and reports.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyntheticVisibility {
+    /// Listed like any other resource.
+    Public,
+
+    /// Only listed if internal resources aren't hidden.
+    Internal,
+}
 
-        entry.is_package = module.is_package;
+impl Default for SyntheticVisibility {
+    fn default() -> Self {
+        SyntheticVisibility::Public
+    }
+}
 
-        let bytecode = PythonModuleBytecodeProvider::FromSource(module.source.clone());
+/// A resource name reserved by a feature.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ReservedName {
+    /// Describes the feature reserving the name.
+    pub feature: String,
 
-        match location {
-            ConcreteResourceLocation::InMemory => match module.optimize_level {
-                BytecodeOptimizationLevel::Zero => {
-                    entry.in_memory_bytecode = Some(bytecode);
-                }
-                BytecodeOptimizationLevel::One => {
-                    entry.in_memory_bytecode_opt1 = Some(bytecode);
-                }
-                BytecodeOptimizationLevel::Two => {
-                    entry.in_memory_bytecode_opt2 = Some(bytecode);
-                }
-            },
-            ConcreteResourceLocation::RelativePath(prefix) => match module.optimize_level {
-                BytecodeOptimizationLevel::Zero => {
-                    entry.relative_path_bytecode =
-                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode))
-                }
-                BytecodeOptimizationLevel::One => {
-                    entry.relative_path_bytecode_opt1 =
-                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode))
-                }
-                BytecodeOptimizationLevel::Two => {
-                    entry.relative_path_bytecode_opt2 =
-                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode))
-                }
-            },
-        }
+    /// Visibility of the resource the feature synthesizes under the name.
+    #[serde(default)]
+    pub visibility: SyntheticVisibility,
+}
 
-        Ok(())
+impl std::fmt::Display for ReservedName {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(&self.feature)
     }
+}
 
-    /// Add resource data to a given location.
+/// A serializable copy of the resources of a `PythonResourceCollector`.
+///
+/// Snapshots allow resources collected separately, e.g. a base platform
+/// shared by several applications, to be produced once, distributed and
+/// later merged into another collector with `merge_collection()`. Resource
+/// content is embedded when serialized, so snapshots don't reference files
+/// of the build producing them.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ResourceCollectionSnapshot {
+    /// Describes the collection in origins and error messages.
+    pub label: String,
+
+    /// Bytecode cache tag of the collection.
+    pub cache_tag: String,
+
+    /// Identifies the Python distribution resources were collected for.
+    pub distribution_fingerprint: Option<String>,
+
+    /// Collected resources, by name.
+    pub resources: BTreeMap<String, PrePackagedResource>,
+
+    /// Reserved resource names and the features reserving them.
+    pub reserved_names: BTreeMap<String, ReservedName>,
+
+    /// Where each resource came from.
+    pub origins: BTreeMap<String, Vec<String>>,
+
+    /// Collected distributions, by normalized name.
+    #[serde(default)]
+    pub distributions: BTreeMap<String, CollectedDistribution>,
+}
+
+impl ResourceCollectionSnapshot {
+    /// Read an instance from a JSON file.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("reading {}", path.display()))?;
+
+        serde_json::from_slice(&data)
+            .with_context(|| format!("parsing resource collection snapshot {}", path.display()))
+    }
+
+    /// Write the instance to a JSON file.
     ///
-    /// Resource data belongs to a Python package and has a name and bytes data.
-    pub fn add_python_package_resource(
-        &mut self,
-        resource: &PythonPackageResource,
-        location: &ConcreteResourceLocation,
-    ) -> Result<()> {
-        self.check_policy(location.into())?;
+    /// Content of every resource is read.
+    pub fn write_to_path(&self, path: &Path) -> Result<()> {
+        let data = serde_json::to_vec(self)
+            .with_context(|| format!("serializing resource collection {}", self.label))?;
 
-        let entry = self
-            .resources
-            .entry(resource.leaf_package.clone())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: resource.leaf_package.clone(),
-                ..PrePackagedResource::default()
-            });
+        std::fs::write(path, data).with_context(|| format!("writing {}", path.display()))
+    }
+}
 
-        // Adding a resource automatically makes the module a package.
-        entry.is_package = true;
+/// Describes payloads held in memory while collecting resources.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CollectionMemoryStatistics {
+    /// Bytes of payloads currently held in memory.
+    pub resident_bytes: u64,
 
-        match location {
-            ConcreteResourceLocation::InMemory => {
-                if entry.in_memory_resources.is_none() {
-                    entry.in_memory_resources = Some(BTreeMap::new());
-                }
-                entry
-                    .in_memory_resources
-                    .as_mut()
-                    .unwrap()
-                    .insert(resource.relative_name.clone(), resource.data.clone());
-            }
-            ConcreteResourceLocation::RelativePath(prefix) => {
-                if entry.relative_path_package_resources.is_none() {
-                    entry.relative_path_package_resources = Some(BTreeMap::new());
-                }
+    /// Largest number of bytes of payloads held in memory at once.
+    pub peak_resident_bytes: u64,
 
-                entry
-                    .relative_path_package_resources
-                    .as_mut()
-                    .unwrap()
-                    .insert(
-                        resource.relative_name.clone(),
-                        (resource.resolve_path(prefix), resource.data.clone()),
-                    );
-            }
-        }
+    /// Number of payloads spilled to disk.
+    pub spilled_count: usize,
 
-        Ok(())
-    }
+    /// Bytes of payloads spilled to disk.
+    pub spilled_bytes: u64,
+}
 
-    /// Add a package distribution resource to a given location.
-    pub fn add_package_distribution_resource(
-        &mut self,
-        resource: &PythonPackageDistributionResource,
-        location: &ConcreteResourceLocation,
-    ) -> Result<()> {
-        self.check_policy(location.into())?;
+/// The kind of resource a payload belongs to, by `PythonResource` variant.
+///
+/// Extension modules are counted together regardless of how they are linked.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ResourceSizeKind {
+    /// Module source code.
+    ModuleSource,
 
-        let entry = self
-            .resources
-            .entry(resource.package.clone())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: resource.package.clone(),
-                ..PrePackagedResource::default()
-            });
+    /// Module bytecode at any optimization level.
+    ModuleBytecode,
 
-        // A distribution resource makes the entity a package.
-        entry.is_package = true;
+    /// A package resource file.
+    Resource,
 
-        match location {
-            ConcreteResourceLocation::InMemory => {
-                if entry.in_memory_distribution_resources.is_none() {
-                    entry.in_memory_distribution_resources = Some(BTreeMap::new());
-                }
+    /// A file in package distribution metadata.
+    DistributionResource,
 
-                entry
-                    .in_memory_distribution_resources
-                    .as_mut()
-                    .unwrap()
-                    .insert(resource.name.clone(), resource.data.clone());
-            }
-            ConcreteResourceLocation::RelativePath(prefix) => {
-                if entry.relative_path_distribution_resources.is_none() {
-                    entry.relative_path_distribution_resources = Some(BTreeMap::new());
-                }
+    /// An extension module shared library.
+    ExtensionModule,
 
-                entry
-                    .relative_path_distribution_resources
-                    .as_mut()
-                    .unwrap()
-                    .insert(
-                        resource.name.clone(),
-                        (resource.resolve_path(prefix), resource.data.clone()),
-                    );
-            }
-        }
+    /// A shared library extension modules depend on.
+    SharedLibrary,
+}
 
-        Ok(())
+impl std::fmt::Display for ResourceSizeKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::ModuleSource => "module source",
+            Self::ModuleBytecode => "module bytecode",
+            Self::Resource => "package resources",
+            Self::DistributionResource => "distribution resources",
+            Self::ExtensionModule => "extension modules",
+            Self::SharedLibrary => "shared libraries",
+        })
     }
+}
 
-    /// Add a built-in extension module.
-    ///
-    /// Built-in extension modules are statically linked into the binary and
-    /// cannot have their location defined.
-    pub fn add_builtin_python_extension_module(
-        &mut self,
-        module: &PythonExtensionModule,
-    ) -> Result<()> {
-        let entry = self
-            .resources
-            .entry(module.name.clone())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::BuiltinExtensionModule,
-                name: module.name.clone(),
-                ..PrePackagedResource::default()
-            });
+/// Sizes of the payloads of collected resources.
+///
+/// Sizes are obtained without reading file content. Bytecode still to be
+/// compiled from source isn't counted.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResourceSizeReport {
+    /// Total bytes of all payloads.
+    pub total_bytes: u64,
 
-        entry.is_package = module.is_package;
+    /// Bytes of payloads by kind of resource.
+    pub by_kind: BTreeMap<ResourceSizeKind, u64>,
 
-        Ok(())
+    /// Bytes of payloads by top-level package.
+    pub by_package: BTreeMap<String, u64>,
+
+    /// Bytes of payloads by resource name.
+    pub by_resource: BTreeMap<String, u64>,
+
+    /// Bytes of payloads classified as tests.
+    pub test_bytes: u64,
+}
+
+impl ResourceSizeReport {
+    /// Obtain the largest resources, largest first.
+    ///
+    /// Resources of equal size are ordered by name.
+    pub fn largest(&self, count: usize) -> Vec<(&str, u64)> {
+        let mut resources = self
+            .by_resource
+            .iter()
+            .map(|(name, size)| (name.as_str(), *size))
+            .collect::<Vec<_>>();
+        resources.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        resources.truncate(count);
+
+        resources
     }
+}
 
-    /// Add a Python extension module shared library that should be imported from memory.
-    pub fn add_python_extension_module(
-        &mut self,
-        module: &PythonExtensionModule,
-        location: &ConcreteResourceLocation,
-    ) -> Result<()> {
-        self.check_policy(location.into())?;
+/// Compiled bytecode of a module.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ModuleBytecodeSize {
+    /// Name of the module.
+    pub name: String,
 
-        let data = match &module.shared_library {
-            Some(location) => location.resolve()?,
-            None => return Err(anyhow!("no shared library data present")),
-        };
+    /// Top-level package of the module.
+    pub package: String,
 
-        let mut depends = Vec::new();
+    /// Bytes of bytecode of all optimization levels.
+    pub total_bytes: u64,
 
-        for link in &module.link_libraries {
-            if let Some(shared_library) = &link.dynamic_library {
-                let library_location = match location {
-                    ConcreteResourceLocation::InMemory => ConcreteResourceLocation::InMemory,
-                    ConcreteResourceLocation::RelativePath(prefix) => {
-                        // We place the shared library next to the extension module.
-                        let path = module
-                            .resolve_path(prefix)
-                            .parent()
-                            .ok_or_else(|| anyhow!("unable to resolve parent directory"))?
-                            .to_path_buf();
-
-                        ConcreteResourceLocation::RelativePath(format!("{}", path.display()))
-                    }
-                };
+    /// Bytes of bytecode by optimization level present.
+    pub levels: BTreeMap<i32, u64>,
+}
 
-                self.add_shared_library(&link.name, shared_library, &library_location)?;
-                depends.push(link.name.to_string());
+/// Compiled bytecode of the modules of a top-level package.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct PackageBytecodeSize {
+    /// Name of the top-level package.
+    pub name: String,
+
+    /// Number of modules with bytecode.
+    pub modules_count: usize,
+
+    /// Bytes of bytecode of all optimization levels.
+    pub total_bytes: u64,
+
+    /// Bytes of bytecode by optimization level present.
+    pub levels: BTreeMap<i32, u64>,
+}
+
+/// How much compiled bytecode of each module contributes to a binary.
+///
+/// Modules and packages are sorted by size, largest first. Entries of
+/// equal size are ordered by name.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct BytecodeSizeReport {
+    /// Bytes of bytecode of all modules.
+    pub total_bytes: u64,
+
+    /// Bytecode of each module with bytecode.
+    pub modules: Vec<ModuleBytecodeSize>,
+
+    /// Bytecode aggregated by top-level package.
+    pub packages: Vec<PackageBytecodeSize>,
+}
+
+impl BytecodeSizeReport {
+    /// Construct an instance from the bytecode of modules.
+    pub fn from_modules(mut modules: Vec<ModuleBytecodeSize>) -> Self {
+        let mut packages: BTreeMap<String, PackageBytecodeSize> = BTreeMap::new();
+
+        for module in &modules {
+            let package =
+                packages
+                    .entry(module.package.clone())
+                    .or_insert_with(|| PackageBytecodeSize {
+                        name: module.package.clone(),
+                        modules_count: 0,
+                        total_bytes: 0,
+                        levels: BTreeMap::new(),
+                    });
+
+            package.modules_count += 1;
+            package.total_bytes += module.total_bytes;
+            for (level, size) in &module.levels {
+                *package.levels.entry(*level).or_insert(0) += size;
             }
         }
 
-        let entry = self
-            .resources
-            .entry(module.name.to_string())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::Extension,
-                name: module.name.to_string(),
-                ..PrePackagedResource::default()
-            });
+        let mut packages = packages.values().cloned().collect::<Vec<_>>();
 
-        if module.is_package {
-            entry.is_package = true;
-        }
+        modules.sort_by(|a, b| {
+            b.total_bytes
+                .cmp(&a.total_bytes)
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        packages.sort_by(|a, b| {
+            b.total_bytes
+                .cmp(&a.total_bytes)
+                .then_with(|| a.name.cmp(&b.name))
+        });
 
-        match location {
-            ConcreteResourceLocation::InMemory => {
-                entry.in_memory_extension_module_shared_library = Some(DataLocation::Memory(data));
-            }
-            ConcreteResourceLocation::RelativePath(prefix) => {
-                entry.relative_path_extension_module_shared_library =
-                    Some((module.resolve_path(prefix), DataLocation::Memory(data)));
-            }
+        Self {
+            total_bytes: modules.iter().map(|m| m.total_bytes).sum(),
+            modules,
+            packages,
         }
+    }
 
-        entry.shared_library_dependency_names = Some(depends);
+    /// Obtain the modules with the most bytecode, largest first.
+    pub fn largest_modules(&self, count: usize) -> &[ModuleBytecodeSize] {
+        &self.modules[0..count.min(self.modules.len())]
+    }
+
+    /// Serialize the report to JSON.
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    /// Write a human readable table of the largest modules and packages.
+    ///
+    /// At most `count` modules and packages are listed. Columns hold the
+    /// bytes of bytecode of each optimization level, `-` if absent.
+    pub fn write_table<W: std::io::Write>(&self, writer: &mut W, count: usize) -> Result<()> {
+        let modules = self
+            .largest_modules(count)
+            .iter()
+            .map(|m| (m.name.as_str(), m.total_bytes, &m.levels))
+            .collect::<Vec<_>>();
+        let packages = self
+            .packages
+            .iter()
+            .take(count)
+            .map(|p| (p.name.as_str(), p.total_bytes, &p.levels))
+            .collect::<Vec<_>>();
+
+        let width = modules
+            .iter()
+            .chain(packages.iter())
+            .map(|(name, _, _)| name.len())
+            .chain(std::iter::once("package".len()))
+            .max()
+            .unwrap_or(0);
+
+        write_bytecode_size_rows(writer, "module", &modules, width)?;
+        writeln!(writer)?;
+        write_bytecode_size_rows(writer, "package", &packages, width)?;
+        writeln!(writer)?;
+        writeln!(writer, "total: {} bytes of bytecode", self.total_bytes)?;
 
         Ok(())
     }
+}
 
-    /// Add a shared library to be loaded from a location.
-    pub fn add_shared_library(
-        &mut self,
-        name: &str,
-        data: &DataLocation,
-        location: &ConcreteResourceLocation,
-    ) -> Result<()> {
-        self.check_policy(location.into())?;
+fn write_bytecode_size_rows<W: std::io::Write>(
+    writer: &mut W,
+    heading: &str,
+    rows: &[(&str, u64, &BTreeMap<i32, u64>)],
+    width: usize,
+) -> Result<()> {
+    let levels = BytecodeOptimizationLevel::all()
+        .map(i32::from)
+        .collect::<Vec<_>>();
 
-        let entry = self
-            .resources
-            .entry(name.to_string())
-            .or_insert_with(|| PrePackagedResource {
-                flavor: ResourceFlavor::SharedLibrary,
-                name: name.to_string(),
-                ..PrePackagedResource::default()
-            });
+    let mut line = format!("{:<width$} {:>10}", heading, "total", width = width);
+    for level in &levels {
+        line.push_str(&format!(" {:>10}", format!("opt-{}", level)));
+    }
+    writeln!(writer, "{}", line)?;
+
+    for (name, total, sizes) in rows {
+        let mut line = format!("{:<width$} {:>10}", name, total, width = width);
+        for level in &levels {
+            line.push_str(&format!(
+                " {:>10}",
+                sizes
+                    .get(level)
+                    .map(|size| size.to_string())
+                    .unwrap_or_else(|| "-".to_string())
+            ));
+        }
+        writeln!(writer, "{}", line)?;
+    }
 
-        match location {
-            ConcreteResourceLocation::InMemory => {
-                entry.in_memory_shared_library = Some(data.clone());
-            }
-            ConcreteResourceLocation::RelativePath(prefix) => {
-                entry.relative_path_shared_library = Some((prefix.to_string(), data.clone()));
-            }
+    Ok(())
+}
+
+/// Used to name spilled payloads uniquely across clones of a collector.
+static SPILLED_PAYLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// Caps the bytes of payloads a collector holds in memory.
+#[derive(Debug, Clone)]
+struct MemoryBudget {
+    limit: u64,
+    spill_dir: Arc<TempDir>,
+    /// Bytes held in memory by each resource.
+    resident: BTreeMap<String, u64>,
+    statistics: CollectionMemoryStatistics,
+}
+
+impl MemoryBudget {
+    /// Write payload data to the spill directory.
+    fn spill(&mut self, data: &[u8]) -> Result<DataLocation> {
+        let path = self.spill_dir.path().join(format!(
+            "payload-{}",
+            SPILLED_PAYLOADS.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::write(&path, data).with_context(|| format!("writing {}", path.display()))?;
+
+        self.statistics.spilled_count += 1;
+        self.statistics.spilled_bytes += data.len() as u64;
+
+        Ok(DataLocation::from_path_uncached(path))
+    }
+}
+
+/// A distribution whose resources were collected.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct CollectedDistribution {
+    /// Name of the distribution, as in its distribution resources.
+    pub name: String,
+
+    /// Version of the collected resources of the distribution.
+    pub version: String,
+
+    /// Versions that arrived while another version was collected and were discarded.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub discarded_versions: Vec<String>,
+}
+
+/// Whether a RECORD lists the file defining a module.
+///
+/// Modules are defined by a file next to their parent package's
+/// `__init__` and packages by their own `__init__`. Source, bytecode and
+/// extension module files are all recognized.
+fn record_defines_module(record: &PythonPackageRecord, name: &str, is_package: bool) -> bool {
+    let stem = if is_package {
+        format!("{}/__init__.", name.replace('.', "/"))
+    } else {
+        format!("{}.", name.replace('.', "/"))
+    };
+
+    record
+        .entries
+        .iter()
+        .any(|entry| entry.path.starts_with(&stem) && !entry.path[stem.len()..].contains('/'))
+}
+
+/// Whether a resource belongs to a version of a distribution.
+///
+/// Distribution resources belong to the version they declare. Other
+/// resources belong to it if its RECORD lists them.
+fn resource_in_distribution(
+    resource: &PythonResource,
+    distribution: &str,
+    version: &str,
+    record: Option<&PythonPackageRecord>,
+) -> bool {
+    if let PythonResource::DistributionResource(r) = resource {
+        return normalize_distribution_name(&r.package)
+            == normalize_distribution_name(distribution)
+            && r.version == version;
+    }
+
+    let record = match record {
+        Some(record) => record,
+        None => return false,
+    };
+
+    match resource {
+        PythonResource::ModuleSource(m) => record_defines_module(record, &m.name, m.is_package),
+        PythonResource::ModuleBytecodeRequest(m) => {
+            record_defines_module(record, &m.name, m.is_package)
+        }
+        PythonResource::ModuleBytecode(m) => record_defines_module(record, &m.name, m.is_package),
+        PythonResource::ExtensionModuleDynamicLibrary(em)
+        | PythonResource::ExtensionModuleStaticallyLinked(em) => {
+            record_defines_module(record, &em.name, em.is_package)
+        }
+        PythonResource::Resource(r) => {
+            let path = format!("{}/{}", r.leaf_package.replace('.', "/"), r.relative_name);
+            record.find_entry(&path).is_some()
         }
+        PythonResource::DistributionResource(_)
+        | PythonResource::EggFile(_)
+        | PythonResource::PathExtension(_) => false,
+    }
+}
 
-        Ok(())
+/// Type used to collect Python resources so they can be serialized.
+///
+/// We often want to turn Python resource primitives (module source,
+/// bytecode, etc) into a collection of `Resource` so they can be
+/// serialized to the *Python packed resources* format. This type
+/// exists to facilitate doing this.
+#[derive(Debug, Clone)]
+pub struct PythonResourceCollector {
+    policy: PythonResourcesPolicy,
+    resources: BTreeMap<String, PrePackagedResource>,
+    cache_tag: String,
+    strict_library_dependencies: bool,
+    extract_library_dependencies: bool,
+    usage_log: Option<UsageLog>,
+    used_resources: Option<BTreeSet<String>>,
+    reserved_names: BTreeMap<String, ReservedName>,
+    text_normalization: Option<TextNormalization>,
+    source_transforms: SourceTransforms,
+    locale_filter: Option<LocaleFilter>,
+    trimmed_locales: BTreeMap<String, LocaleTrimStatistics>,
+    module_package_collision: ModulePackageCollisionPolicy,
+    add_collision: CollisionResolution,
+    collided_packages: BTreeSet<String>,
+    distribution_version_conflict: DistributionVersionConflictPolicy,
+    distributions: BTreeMap<String, CollectedDistribution>,
+    pyc_invalidation_mode: PycInvalidationMode,
+    reproducible: bool,
+    compile_error_policy: CompileErrorPolicy,
+    warnings: Vec<String>,
+    memory_budget: Option<MemoryBudget>,
+    spill_dir: Option<PathBuf>,
+    distribution_fingerprint: Option<String>,
+    origins: BTreeMap<String, Vec<String>>,
+}
+
+impl PythonResourceCollector {
+    /// Construct a new instance of the collector.
+    ///
+    /// The instance is associated with a resources policy to validate that
+    /// added resources conform with rules.
+    ///
+    /// We also pass a Python bytecode cache tag, which is used to
+    /// derive filenames.
+    pub fn new(policy: &PythonResourcesPolicy, cache_tag: &str) -> Self {
+        Self {
+            policy: policy.clone(),
+            resources: BTreeMap::new(),
+            cache_tag: cache_tag.to_string(),
+            strict_library_dependencies: false,
+            extract_library_dependencies: false,
+            usage_log: None,
+            used_resources: None,
+            reserved_names: BTreeMap::new(),
+            text_normalization: None,
+            source_transforms: SourceTransforms::default(),
+            locale_filter: None,
+            trimmed_locales: BTreeMap::new(),
+            module_package_collision: ModulePackageCollisionPolicy::PreferPackage,
+            add_collision: CollisionResolution::Theirs,
+            collided_packages: BTreeSet::new(),
+            distribution_version_conflict: DistributionVersionConflictPolicy::default(),
+            distributions: BTreeMap::new(),
+            pyc_invalidation_mode: PycInvalidationMode::default(),
+            reproducible: false,
+            compile_error_policy: CompileErrorPolicy::default(),
+            warnings: Vec::new(),
+            memory_budget: None,
+            spill_dir: None,
+            distribution_fingerprint: None,
+            origins: BTreeMap::new(),
+        }
     }
 
-    /// Searches for Python sources for references to __file__.
+    /// Obtain the policy for this collector.
+    pub fn get_policy(&self) -> &PythonResourcesPolicy {
+        &self.policy
+    }
+
+    /// Set whether unsatisfiable library dependencies of in-memory extension modules are an error.
     ///
-    /// __file__ usage can be problematic for in-memory modules. This method searches
-    /// for its occurrences and returns module names having it present.
-    pub fn find_dunder_file(&self) -> Result<BTreeSet<String>> {
-        let mut res = BTreeSet::new();
+    /// Shared libraries loaded from memory cannot have their library
+    /// dependencies resolved from memory. When not strict, such dependencies
+    /// are installed next to the extension module on the filesystem if the
+    /// resources policy allows it and a warning is recorded.
+    pub fn set_strict_library_dependencies(&mut self, strict: bool) {
+        self.strict_library_dependencies = strict;
+    }
 
-        for (name, module) in &self.resources {
-            if let Some(location) = &module.in_memory_source {
-                if has_dunder_file(&location.resolve()?)? {
-                    res.insert(name.clone());
-                }
-            }
+    /// Set how text resources are normalized when added.
+    ///
+    /// Module source is always text. Package resources are text if their
+    /// file extension says so. `None` leaves text resources alone.
+    pub fn set_text_normalization(&mut self, normalization: Option<TextNormalization>) {
+        self.text_normalization = normalization;
+    }
 
-            if let Some(PythonModuleBytecodeProvider::FromSource(location)) =
-                &module.in_memory_bytecode
-            {
-                if has_dunder_file(&location.resolve()?)? {
-                    res.insert(name.clone());
-                }
-            }
+    /// Set transforms applied to module source when it is added.
+    ///
+    /// They apply to source and to bytecode compiled from source.
+    pub fn set_source_transforms(&mut self, transforms: SourceTransforms) {
+        self.source_transforms = transforms;
+    }
 
-            if let Some(PythonModuleBytecodeProvider::FromSource(location)) =
-                &module.in_memory_bytecode_opt1
-            {
-                if has_dunder_file(&location.resolve()?)? {
-                    res.insert(name.clone());
-                }
-            }
+    /// Set which locale data of package resources is kept.
+    ///
+    /// Package resources that are locale data of a language the filter
+    /// doesn't keep are dropped when added. `None` keeps all locale data.
+    pub fn set_locale_filter(&mut self, filter: Option<LocaleFilter>) {
+        self.locale_filter = filter;
+    }
 
-            if let Some(PythonModuleBytecodeProvider::FromSource(location)) =
-                &module.in_memory_bytecode_opt2
-            {
-                if has_dunder_file(&location.resolve()?)? {
-                    res.insert(name.clone());
-                }
+    /// Obtain the locale data dropped from each package.
+    pub fn trimmed_locales(&self) -> &BTreeMap<String, LocaleTrimStatistics> {
+        &self.trimmed_locales
+    }
+
+    /// Normalize data being added if text normalization is enabled.
+    ///
+    /// Returns the data to add and whether it was normalized.
+    fn normalize_text(
+        &self,
+        location: &DataLocation,
+        is_text: bool,
+    ) -> Result<(DataLocation, bool)> {
+        if self.text_normalization.is_none() || !is_text {
+            return Ok((location.clone(), false));
+        }
+
+        Ok(match normalize_location(location)? {
+            Some(normalized) => (normalized, true),
+            None => (location.clone(), false),
+        })
+    }
+
+    /// Apply source transforms to module source being added.
+    ///
+    /// Source the transforms fail on is kept as is, with a warning, since
+    /// compiling it reports any actual problem.
+    fn transform_source(&mut self, name: &str, location: DataLocation) -> Result<DataLocation> {
+        if !self.source_transforms.is_enabled() {
+            return Ok(location);
+        }
+
+        match self.source_transforms.apply(name, &location.resolve()?) {
+            Ok(Some(source)) => Ok(DataLocation::Memory(source)),
+            Ok(None) => Ok(location),
+            Err(err) => {
+                self.warnings
+                    .push(format!("not transforming source of {}: {:#}", name, err));
+                Ok(location)
             }
         }
+    }
 
-        Ok(res)
+    /// Set how a module and a package having the same name are resolved.
+    pub fn set_module_package_collision(&mut self, policy: ModulePackageCollisionPolicy) {
+        self.module_package_collision = policy;
     }
 
-    /// Compiles resources into a finalized collection.
+    /// Set how a payload added to a resource already having one is resolved.
     ///
-    /// This will take all resources collected so far and convert them into
-    /// a collection of `Resource` plus extra file install rules.
+    /// This applies to module sources, package resources and distribution
+    /// resources. Payloads are compared by content, so with
+    /// `ContentEqualOk` the same file added from the filesystem and from
+    /// memory is kept once. The default, `Theirs`, replaces the payload.
+    pub fn set_add_collision(&mut self, resolution: CollisionResolution) {
+        self.add_collision = resolution;
+    }
+
+    /// Resolve adding a payload where `existing` was added before.
     ///
-    /// Missing parent packages will be added automatically.
-    pub fn compile_resources(
+    /// Returns whether `incoming` should be stored.
+    fn resolve_add_collision(
         &self,
-        compiler: &mut dyn PythonBytecodeCompiler,
-    ) -> Result<CompiledResourcesCollection> {
-        let mut input_resources = self.resources.clone();
-        populate_parent_packages(&mut input_resources)?;
+        description: impl Fn() -> String,
+        existing: Option<&DataLocation>,
+        incoming: &DataLocation,
+    ) -> Result<bool> {
+        let existing = match existing {
+            Some(existing) => existing,
+            None => return Ok(true),
+        };
 
-        let mut resources = BTreeMap::new();
-        let mut extra_files = Vec::new();
+        match self.add_collision {
+            CollisionResolution::Ours => Ok(false),
+            CollisionResolution::Theirs => Ok(true),
+            CollisionResolution::ContentEqualOk if existing.content_eq(incoming)? => Ok(false),
+            CollisionResolution::ContentEqualOk | CollisionResolution::Error => Err(anyhow!(
+                "{} from {} collides with one added from {}",
+                description(),
+                describe_location(incoming),
+                describe_location(existing)
+            )),
+        }
+    }
 
-        for (name, resource) in &input_resources {
-            let (entry, installs) = resource.to_resource(compiler)?;
+    /// Set how a second version of a collected distribution is handled.
+    ///
+    /// See `resolve_distribution_versions()`.
+    pub fn set_distribution_version_conflict(&mut self, policy: DistributionVersionConflictPolicy) {
+        self.distribution_version_conflict = policy;
+    }
 
-            for install in installs {
-                extra_files.push(install);
-            }
+    /// Obtain collected distributions, by normalized name.
+    pub fn distributions(&self) -> &BTreeMap<String, CollectedDistribution> {
+        &self.distributions
+    }
 
-            resources.insert(name.clone(), entry);
+    /// Resolve conflicts between resources to add and collected distributions.
+    ///
+    /// `resources` are resources obtained together, e.g. by one acquisition
+    /// step. A distribution among them whose version differs from the
+    /// collected version is handled according to the distribution version
+    /// conflict policy. Replacing removes the resources the RECORD of the
+    /// collected version lists, including files the new version no longer
+    /// has, along with the collected distribution resources. Keeping the
+    /// first version drops the resources the RECORD of the new version
+    /// lists. Returns the resources to add.
+    pub fn resolve_distribution_versions(
+        &mut self,
+        resources: Vec<PythonResource>,
+    ) -> Result<Vec<PythonResource>> {
+        let mut incoming = BTreeMap::new();
+        for resource in &resources {
+            if let PythonResource::DistributionResource(r) = resource {
+                incoming
+                    .entry(normalize_distribution_name(&r.package))
+                    .or_insert_with(|| (r.package.clone(), r.version.clone()));
+            }
         }
 
-        Ok(CompiledResourcesCollection {
-            resources,
-            extra_files,
-        })
+        let mut resources = resources;
+
+        for (key, (package, version)) in incoming {
+            let existing = match self.distributions.get(&key) {
+                Some(existing) if existing.version != version => existing.clone(),
+                _ => continue,
+            };
+
+            match self.distribution_version_conflict {
+                DistributionVersionConflictPolicy::Error => {
+                    return Err(anyhow!(
+                        "{} {} conflicts with version {} collected earlier",
+                        package,
+                        version,
+                        existing.version
+                    ));
+                }
+                DistributionVersionConflictPolicy::KeepFirst => {
+                    let record = resources
+                        .iter()
+                        .find_map(|resource| match resource {
+                            PythonResource::DistributionResource(r)
+                                if r.name == "RECORD"
+                                    && resource_in_distribution(
+                                        resource, &package, &version, None,
+                                    ) =>
+                            {
+                                Some(r.data.resolve().and_then(|data| {
+                                    PythonPackageRecord::from_record(&data)
+                                        .with_context(|| format!("parsing RECORD of {}", package))
+                                }))
+                            }
+                            _ => None,
+                        })
+                        .transpose()?;
+
+                    if let Some(record) = &record {
+                        self.warnings.extend(
+                            record
+                                .warnings
+                                .iter()
+                                .map(|warning| format!("{}: {}", package, warning)),
+                        );
+                    }
+
+                    resources.retain(|resource| {
+                        !resource_in_distribution(resource, &package, &version, record.as_ref())
+                    });
+
+                    self.warnings.push(format!(
+                        "ignored {} {} in favor of version {} collected earlier",
+                        package, version, existing.version
+                    ));
+                    if let Some(collected) = self.distributions.get_mut(&key) {
+                        collected.discarded_versions.push(version);
+                    }
+                }
+                DistributionVersionConflictPolicy::Replace => {
+                    self.remove_distribution(&existing.name)?;
+
+                    self.warnings.push(format!(
+                        "replaced {} {} with version {}",
+                        existing.name, existing.version, version
+                    ));
+                    self.distributions.insert(
+                        key,
+                        CollectedDistribution {
+                            name: package,
+                            version,
+                            discarded_versions: existing
+                                .discarded_versions
+                                .into_iter()
+                                .chain(std::iter::once(existing.version))
+                                .collect(),
+                        },
+                    );
+                }
+            }
+        }
+
+        Ok(resources)
+    }
+
+    /// Remove resources of a collected distribution.
+    ///
+    /// Resources its RECORD lists are removed along with its distribution
+    /// resources.
+    fn remove_distribution(&mut self, name: &str) -> Result<()> {
+        let record = match self
+            .resources
+            .get(name)
+            .and_then(|resource| resource.distribution_resource("RECORD"))
+        {
+            Some(location) => Some(
+                PythonPackageRecord::from_record(&location.resolve()?)
+                    .with_context(|| format!("parsing RECORD of {}", name))?,
+            ),
+            None => None,
+        };
+
+        if let Some(record) = &record {
+            self.warnings.extend(
+                record
+                    .warnings
+                    .iter()
+                    .map(|warning| format!("{}: {}", name, warning)),
+            );
+        }
+
+        let mut removed = match &record {
+            Some(record) => self
+                .resources
+                .values()
+                .filter(|resource| {
+                    record_defines_module(record, &resource.name, resource.is_package)
+                })
+                .map(|resource| resource.name.clone())
+                .collect::<Vec<_>>(),
+            None => vec![],
+        };
+
+        if let Some(entry) = self.resources.get_mut(name) {
+            entry.in_memory_distribution_resources = None;
+            entry.relative_path_distribution_resources = None;
+
+            if entry.data_locations_mut().is_empty() {
+                removed.push(name.to_string());
+            }
+        }
+
+        for removed in &removed {
+            self.resources.remove(removed);
+            self.origins.remove(removed);
+            self.enforce_memory_budget(removed)?;
+        }
+
+        self.enforce_memory_budget(name)
+    }
+
+    /// Set how .pyc files installed relative to the binary are invalidated.
+    ///
+    /// This determines the header of .pyc files compiled from source when
+    /// resources are compiled or extracted.
+    pub fn set_pyc_invalidation_mode(&mut self, mode: PycInvalidationMode) {
+        self.pyc_invalidation_mode = mode;
+    }
+
+    /// Set whether compiling resources must produce the same bytes every time.
+    ///
+    /// When set, bytecode can only be compiled with compilers that are
+    /// `PythonBytecodeCompiler::is_reproducible()` and timestamp based .pyc
+    /// headers record `PycTimestamp::reproducible()` instead of the modified
+    /// time of sources.
+    pub fn set_reproducible(&mut self, reproducible: bool) {
+        self.reproducible = reproducible;
+    }
+
+    /// Whether compiling resources must produce the same bytes every time.
+    pub fn is_reproducible(&self) -> bool {
+        self.reproducible
+    }
+
+    /// Ensure a compiler can compile bytecode for this collection.
+    fn verify_compiler(&self, compiler: &dyn PythonBytecodeCompiler) -> Result<()> {
+        if self.reproducible && !compiler.is_reproducible() {
+            Err(anyhow!(
+                "reproducible builds require a reproducible bytecode compiler"
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Obtain how .pyc files compiled by `compiler` are invalidated.
+    fn compile_pyc_invalidation_mode(
+        &self,
+        compiler: &dyn PythonBytecodeCompiler,
+    ) -> Result<PycInvalidationMode> {
+        self.verify_compiler(compiler)?;
+
+        if self.reproducible {
+            self.pyc_invalidation_mode.reproducible()
+        } else {
+            Ok(self.pyc_invalidation_mode)
+        }
+    }
+
+    /// Set what happens to modules whose source fails to compile.
+    ///
+    /// Modules skipped or embedded as source are recorded in the
+    /// `compile_errors` of compiled resources.
+    pub fn set_compile_error_policy(&mut self, policy: CompileErrorPolicy) {
+        self.compile_error_policy = policy;
+    }
+
+    /// Resolve a module being added with a name already used by a package, or vice versa.
+    ///
+    /// Module code collides with existing code of the same name that
+    /// disagrees on `is_package`. Depending on policy, the existing entry is
+    /// stripped of its code and package resources, the incoming code is
+    /// skipped, or an error is raised. Package resources of a package
+    /// losing to a module are dropped as well. Returns whether to add the
+    /// incoming code.
+    fn resolve_module_package_collision(
+        &mut self,
+        name: &str,
+        is_package: bool,
+        provenance: impl FnOnce() -> String,
+    ) -> Result<bool> {
+        let existing = match self.resources.get_mut(name) {
+            Some(existing) if existing.flavor == ResourceFlavor::Module => existing,
+            _ => return Ok(true),
+        };
+
+        let has_code = existing.in_memory_source.is_some()
+            || existing.relative_path_module_source.is_some()
+            || existing.in_memory_bytecode.is_some()
+            || existing.in_memory_bytecode_opt1.is_some()
+            || existing.in_memory_bytecode_opt2.is_some()
+            || existing.relative_path_bytecode.is_some()
+            || existing.relative_path_bytecode_opt1.is_some()
+            || existing.relative_path_bytecode_opt2.is_some();
+
+        if existing.is_package == is_package || !has_code {
+            return Ok(true);
+        }
+
+        let kind = |is_package: bool| if is_package { "package" } else { "module" };
+        let existing_provenance = resource_provenance(existing);
+        let incoming_provenance = provenance();
+        let description = format!(
+            "{} is both a {} ({}) and a {} ({})",
+            name,
+            kind(existing.is_package),
+            existing_provenance,
+            kind(is_package),
+            incoming_provenance
+        );
+
+        let keep_incoming = match self.module_package_collision {
+            ModulePackageCollisionPolicy::Error => return Err(anyhow!("{}", description)),
+            ModulePackageCollisionPolicy::PreferPackage => is_package,
+            ModulePackageCollisionPolicy::PreferModule => !is_package,
+        };
+
+        if keep_incoming {
+            existing.in_memory_source = None;
+            existing.relative_path_module_source = None;
+            existing.in_memory_bytecode = None;
+            existing.in_memory_bytecode_opt1 = None;
+            existing.in_memory_bytecode_opt2 = None;
+            existing.relative_path_bytecode = None;
+            existing.relative_path_bytecode_opt1 = None;
+            existing.relative_path_bytecode_opt2 = None;
+            existing.pyc_payloads.clear();
+            existing.in_memory_resources = None;
+            existing.relative_path_package_resources = None;
+            existing.is_package = is_package;
+        }
+
+        if keep_incoming != is_package {
+            self.collided_packages.insert(name.to_string());
+        } else {
+            self.collided_packages.remove(name);
+        }
+
+        self.warnings.push(format!(
+            "{}; keeping the {}",
+            description,
+            kind(if keep_incoming {
+                is_package
+            } else {
+                !is_package
+            })
+        ));
+
+        Ok(keep_incoming)
+    }
+
+    /// Set whether library dependencies of in-memory extension modules are extracted at run-time.
+    ///
+    /// If enabled, libraries that can't be loaded from memory are embedded in
+    /// the packed resources and written to the filesystem on demand before
+    /// Python is initialized. This takes precedence over installing them next
+    /// to the extension module.
+    pub fn set_extract_library_dependencies(&mut self, extract: bool) {
+        self.extract_library_dependencies = extract;
+    }
+
+    /// Obtain warnings recorded while adding resources.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Record a warning about the resources being collected.
+    pub fn add_warning(&mut self, warning: String) {
+        self.warnings.push(warning);
+    }
+
+    /// Set the fingerprint of the Python distribution resources are collected for.
+    ///
+    /// Only collections with the same fingerprint can be merged.
+    pub fn set_distribution_fingerprint(&mut self, fingerprint: Option<String>) {
+        self.distribution_fingerprint = fingerprint;
+    }
+
+    /// Obtain the fingerprint of the Python distribution resources are collected for.
+    pub fn distribution_fingerprint(&self) -> Option<&str> {
+        self.distribution_fingerprint.as_deref()
+    }
+
+    /// Describe where a resource came from.
+    ///
+    /// Resources merged from snapshots list every collection that provided
+    /// them, the one whose resource was kept first. Other resources are
+    /// described by their data.
+    ///
+    /// Distributions installed from a direct URL also list that URL.
+    pub fn resource_origins(&self, name: &str) -> Option<Vec<String>> {
+        let resource = self.resources.get(name)?;
+
+        let mut origins = match self.origins.get(name) {
+            Some(origins) => origins.clone(),
+            None => vec![resource_provenance(resource)],
+        };
+
+        if let Ok(Some(direct_url)) = resource.direct_url() {
+            origins.push(format!("distribution installed from {}", direct_url));
+        }
+
+        Some(origins)
+    }
+
+    /// Obtain a snapshot of the collected resources.
+    ///
+    /// `label` describes the collection once it is merged into another.
+    pub fn snapshot(&self, label: &str) -> ResourceCollectionSnapshot {
+        ResourceCollectionSnapshot {
+            label: label.to_string(),
+            cache_tag: self.cache_tag.clone(),
+            distribution_fingerprint: self.distribution_fingerprint.clone(),
+            resources: self.resources.clone(),
+            reserved_names: self.reserved_names.clone(),
+            origins: self
+                .resources
+                .keys()
+                .filter_map(|name| Some((name.clone(), self.resource_origins(name)?)))
+                .collect(),
+            distributions: self.distributions.clone(),
+        }
+    }
+
+    /// Merge resources collected separately into this collection.
+    ///
+    /// Both collections must have the same bytecode cache tag and
+    /// distribution fingerprint. Resources present in both are resolved by
+    /// `strategy`. Names reserved by either collection can't be held by
+    /// resources of the other. Resources are checked against this
+    /// collection's policy, and the merged set is checked for bytecode
+    /// cache tags and extension module suffixes incompatible with the
+    /// interpreter.
+    ///
+    /// Every problem is reported at once and the collection is left
+    /// unchanged if there are any. Origins of resources of both collections
+    /// are preserved, see `resource_origins()`.
+    pub fn merge_collection(
+        &mut self,
+        other: ResourceCollectionSnapshot,
+        strategy: MergeStrategy,
+    ) -> Result<()> {
+        if other.distribution_fingerprint != self.distribution_fingerprint {
+            return Err(anyhow!(
+                "can't merge {}: it was collected for distribution {} but this collection is for {}",
+                other.label,
+                other
+                    .distribution_fingerprint
+                    .as_deref()
+                    .unwrap_or("<unknown>"),
+                self.distribution_fingerprint
+                    .as_deref()
+                    .unwrap_or("<unknown>")
+            ));
+        }
+        if other.cache_tag != self.cache_tag {
+            return Err(anyhow!(
+                "can't merge {}: its bytecode cache tag {} differs from {}",
+                other.label,
+                other.cache_tag,
+                self.cache_tag
+            ));
+        }
+
+        let mut problems = Vec::new();
+
+        for (name, feature) in &other.reserved_names {
+            match self.reserved_names.get(name) {
+                Some(existing) if existing != feature => problems.push(format!(
+                    "{} reserved by {} in {} is already reserved by {}",
+                    name, feature, other.label, existing
+                )),
+                Some(_) => {}
+                None => {
+                    if let Some(resource) = self.resources.get(name) {
+                        if !other.resources.contains_key(name) {
+                            problems.push(format!(
+                                "{} ({}) collides with a name reserved by {} in {}",
+                                name,
+                                resource_provenance(resource),
+                                feature,
+                                other.label
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+
+        // Resources to add and ours to keep, with their origins.
+        let mut inserts: Vec<(PrePackagedResource, Vec<String>)> = Vec::new();
+        let mut kept: Vec<(String, Vec<String>)> = Vec::new();
+        let mut warnings = Vec::new();
+
+        for (name, theirs) in other.resources {
+            let label = &other.label;
+            let their_origins = other
+                .origins
+                .get(&name)
+                .cloned()
+                .unwrap_or_else(|| vec![resource_provenance(&theirs)])
+                .into_iter()
+                .map(|origin| format!("{}: {}", label, origin))
+                .collect::<Vec<_>>();
+
+            if let Err(e) = self.check_resource_policy(&theirs) {
+                problems.push(format!("{} from {}: {}", name, other.label, e));
+            }
+            if let Some(feature) = self.reserved_names.get(&name) {
+                if other.reserved_names.get(&name) != Some(feature) {
+                    problems.push(format!(
+                        "{} ({}) collides with a name reserved by {}",
+                        name,
+                        their_origins.join(", "),
+                        feature
+                    ));
+                }
+            }
+
+            let ours = match self.resources.get(&name) {
+                Some(ours) => ours,
+                None => {
+                    inserts.push((theirs, their_origins));
+                    continue;
+                }
+            };
+            let our_origins = self.resource_origins(&name).unwrap_or_default();
+
+            let resolution = if ours.flavor == theirs.flavor {
+                strategy.resolution(ours.flavor)
+            } else {
+                CollisionResolution::Error
+            };
+
+            match resolution {
+                CollisionResolution::Ours => {
+                    warnings.push(format!(
+                        "{} from {} was ignored in favor of {}",
+                        name,
+                        other.label,
+                        our_origins.join(", ")
+                    ));
+                    kept.push((name, our_origins.into_iter().chain(their_origins).collect()));
+                }
+                CollisionResolution::Theirs => {
+                    warnings.push(format!(
+                        "{} ({}) was replaced by {} from {}",
+                        name,
+                        our_origins.join(", "),
+                        name,
+                        other.label
+                    ));
+                    inserts.push((
+                        theirs,
+                        their_origins.into_iter().chain(our_origins).collect(),
+                    ));
+                }
+                CollisionResolution::ContentEqualOk if ours.logical_eq(&theirs)? => {
+                    kept.push((name, our_origins.into_iter().chain(their_origins).collect()));
+                }
+                CollisionResolution::ContentEqualOk | CollisionResolution::Error => {
+                    problems.push(format!(
+                        "{} ({}) collides with {} from {}",
+                        name,
+                        our_origins.join(", "),
+                        name,
+                        their_origins.join(", ")
+                    ));
+                }
+            }
+        }
+
+        let replaced = inserts
+            .iter()
+            .map(|(resource, _)| resource.name.as_str())
+            .collect::<BTreeSet<_>>();
+        let merged = self
+            .resources
+            .values()
+            .filter(|resource| !replaced.contains(resource.name.as_str()))
+            .chain(inserts.iter().map(|(resource, _)| resource));
+        for resource in merged {
+            problems.extend(self.check_interpreter_compatibility(resource));
+        }
+
+        if !problems.is_empty() {
+            return Err(anyhow!(
+                "can't merge {}: {}",
+                other.label,
+                problems.join("; ")
+            ));
+        }
+
+        for (name, feature) in other.reserved_names {
+            self.reserved_names.insert(name, feature);
+        }
+        // Distribution resources follow the resource conflict policy, so
+        // versions we already collected are kept.
+        for (name, distribution) in other.distributions {
+            self.distributions.entry(name).or_insert(distribution);
+        }
+        self.warnings.extend(warnings);
+        self.origins.extend(kept);
+
+        for (resource, origins) in inserts {
+            let name = resource.name.clone();
+            self.resources.insert(name.clone(), resource);
+            self.origins.insert(name.clone(), origins);
+            self.enforce_memory_budget(&name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Find payloads of a resource the interpreter can't load.
+    ///
+    /// Relative path bytecode must use this collection's cache tag and
+    /// extension modules must have a suffix compatible with its interpreter.
+    fn check_interpreter_compatibility(&self, resource: &PrePackagedResource) -> Vec<String> {
+        let mut problems = Vec::new();
+
+        for (_, cache_tag, _) in [
+            &resource.relative_path_bytecode,
+            &resource.relative_path_bytecode_opt1,
+            &resource.relative_path_bytecode_opt2,
+        ]
+        .iter()
+        .copied()
+        .flatten()
+        {
+            if cache_tag != &self.cache_tag {
+                problems.push(format!(
+                    "{} has bytecode for cache tag {} instead of {}",
+                    resource.name, cache_tag, self.cache_tag
+                ));
+            }
+        }
+
+        if let Some((path, _)) = &resource.relative_path_extension_module_shared_library {
+            let leaf = resource.name.rsplit('.').next().unwrap_or(&resource.name);
+            let file_name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+
+            if file_name.starts_with(leaf) {
+                let suffix = &file_name[leaf.len()..];
+                let implementation = InterpreterImplementation::from_cache_tag(&self.cache_tag);
+
+                if !implementation.is_compatible_extension_suffix(suffix) {
+                    problems.push(format!(
+                        "{} has extension module suffix {} incompatible with {}",
+                        resource.name,
+                        suffix,
+                        implementation.name()
+                    ));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Set the directory spilled payloads are written to.
+    ///
+    /// A temporary directory is created in it when a memory budget is set.
+    /// `None` uses the system temporary directory. It must be set before
+    /// the memory budget to have an effect.
+    pub fn set_spill_dir(&mut self, dir: Option<PathBuf>) {
+        self.spill_dir = dir;
+    }
+
+    /// Set the maximum bytes of payloads to hold in memory.
+    ///
+    /// Once payloads held in memory exceed the budget, further payloads are
+    /// written to a temporary directory and referenced by path. They are
+    /// read back when resources are serialized. Slices of shared buffers
+    /// aren't counted. `None` removes the budget. Payloads already spilled
+    /// stay on disk.
+    pub fn set_memory_budget(&mut self, limit: Option<u64>) -> Result<()> {
+        self.memory_budget = match limit {
+            Some(limit) => Some(MemoryBudget {
+                limit,
+                spill_dir: Arc::new(
+                    match &self.spill_dir {
+                        Some(dir) => TempDir::new_in(dir, "python-packaging-spill"),
+                        None => TempDir::new("python-packaging-spill"),
+                    }
+                    .context("creating payload spill directory")?,
+                ),
+                resident: BTreeMap::new(),
+                statistics: CollectionMemoryStatistics::default(),
+            }),
+            None => None,
+        };
+
+        self.enforce_memory_budget_all()
+    }
+
+    /// Obtain the sizes of the payloads of collected resources.
+    ///
+    /// File content isn't read.
+    pub fn size_report(&self) -> Result<ResourceSizeReport> {
+        let mut report = ResourceSizeReport::default();
+
+        for (name, resource) in &self.resources {
+            let package = name.split('.').next().unwrap_or(name);
+
+            for (kind, size) in resource
+                .payload_sizes()
+                .with_context(|| format!("obtaining payload sizes of {}", name))?
+            {
+                report.total_bytes += size;
+                *report.by_kind.entry(kind).or_insert(0) += size;
+                *report.by_package.entry(package.to_string()).or_insert(0) += size;
+                *report.by_resource.entry(name.clone()).or_insert(0) += size;
+            }
+
+            for (_, label, location) in resource.labeled_payloads() {
+                if resource.test_payloads.contains(&label) {
+                    report.test_bytes += location.size()?;
+                }
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Obtain the labels of payloads classified as tests, by resource name.
+    ///
+    /// Resources without test payloads are omitted.
+    pub fn test_payloads(&self) -> BTreeMap<String, BTreeSet<String>> {
+        self.resources
+            .iter()
+            .filter(|(_, resource)| !resource.test_payloads.is_empty())
+            .map(|(name, resource)| (name.clone(), resource.test_payloads.clone()))
+            .collect()
+    }
+
+    /// Find payloads of collected resources backed by files that no longer exist.
+    ///
+    /// Keys are names of resources with such payloads. Values hold the
+    /// label and location of each. Files go missing if, e.g., a virtualenv
+    /// that resources were read from is deleted before the build is
+    /// finished.
+    pub fn dangling_payloads(&self) -> Result<BTreeMap<String, Vec<(String, String)>>> {
+        let mut res = BTreeMap::new();
+
+        for (name, resource) in &self.resources {
+            let dangling = resource
+                .dangling_payloads()
+                .with_context(|| format!("checking payloads of {}", name))?;
+
+            if !dangling.is_empty() {
+                res.insert(name.clone(), dangling);
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Obtain the hex digests of the payloads of collected resources.
+    ///
+    /// Keys are the resource name followed by the payload label in
+    /// brackets, e.g. `foo.bar [source]`. Instances produced by equivalent
+    /// builds are equal, so differences reveal nondeterminism.
+    pub fn digest_manifest(&self, algorithm: DigestAlgorithm) -> Result<BTreeMap<String, String>> {
+        let mut manifest = BTreeMap::new();
+
+        for (name, resource) in &self.resources {
+            for (label, digest) in resource
+                .payload_digests(algorithm)
+                .with_context(|| format!("hashing payloads of {}", name))?
+            {
+                manifest.insert(format!("{} [{}]", name, label), digest);
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    /// Obtain statistics about payloads held in memory, if a budget is set.
+    pub fn memory_statistics(&self) -> Option<CollectionMemoryStatistics> {
+        self.memory_budget.as_ref().map(|budget| budget.statistics)
+    }
+
+    /// Account for the payloads of a resource, spilling them if over budget.
+    fn enforce_memory_budget(&mut self, name: &str) -> Result<()> {
+        let budget = match &mut self.memory_budget {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+
+        let previous = budget.resident.remove(name).unwrap_or(0);
+        budget.statistics.resident_bytes -= previous;
+
+        let resource = match self.resources.get_mut(name) {
+            Some(resource) => resource,
+            None => return Ok(()),
+        };
+
+        let mut resident = 0;
+        for location in resource.data_locations_mut() {
+            match location {
+                DataLocation::Memory(data) => {
+                    let size = data.len() as u64;
+
+                    if budget.statistics.resident_bytes + resident + size > budget.limit {
+                        let spilled = budget.spill(data)?;
+                        *location = spilled;
+                    } else {
+                        resident += size;
+                    }
+                }
+                // Spilling would lose the compression, which packed resources
+                // keep. So compressed payloads stay resident.
+                DataLocation::CompressedMemory { data, .. } => {
+                    resident += data.len() as u64;
+                }
+                _ => {}
+            }
+        }
+
+        budget.resident.insert(name.to_string(), resident);
+        budget.statistics.resident_bytes += resident;
+        budget.statistics.peak_resident_bytes = budget
+            .statistics
+            .peak_resident_bytes
+            .max(budget.statistics.resident_bytes);
+
+        Ok(())
+    }
+
+    /// Account for the payloads of every resource.
+    fn enforce_memory_budget_all(&mut self) -> Result<()> {
+        let names = match &self.memory_budget {
+            Some(budget) => budget
+                .resident
+                .keys()
+                .chain(self.resources.keys())
+                .cloned()
+                .collect::<BTreeSet<_>>(),
+            None => return Ok(()),
+        };
+
+        for name in names {
+            self.enforce_memory_budget(&name)?;
+        }
+
+        Ok(())
+    }
+
+    /// Validate that a resource add in the specified location is allowed.
+    pub fn check_policy(&self, location: AbstractResourceLocation) -> Result<()> {
+        match self.policy {
+            PythonResourcesPolicy::InMemoryOnly => match location {
+                AbstractResourceLocation::InMemory => Ok(()),
+                AbstractResourceLocation::RelativePath => Err(anyhow!(
+                    "in-memory-only policy does not allow relative path resources"
+                )),
+            },
+            PythonResourcesPolicy::FilesystemRelativeOnly(_) => match location {
+                AbstractResourceLocation::InMemory => Err(anyhow!(
+                    "filesystem-relative-only policy does not allow in-memory resources"
+                )),
+                AbstractResourceLocation::RelativePath => Ok(()),
+            },
+            PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(_) => Ok(()),
+        }
+    }
+
+    /// Reserve a resource name for a feature synthesizing resources.
+    ///
+    /// Resources can't be added under a reserved name except by
+    /// `add_synthetic_resource()`. `feature` describes what claims the name
+    /// and is used in error messages. `visibility` controls whether the
+    /// resource synthesized under the name is listed in user-visible
+    /// outputs. Reserving a name already claimed by another feature or held
+    /// by a resource is an error.
+    pub fn reserve_name(
+        &mut self,
+        name: &str,
+        feature: &str,
+        visibility: SyntheticVisibility,
+    ) -> Result<()> {
+        if let Some(existing) = self.reserved_names.get(name) {
+            if existing.feature != feature {
+                return Err(anyhow!(
+                    "{} can't reserve resource name {}: already reserved by {}",
+                    feature,
+                    name,
+                    existing
+                ));
+            }
+        }
+
+        if let Some(resource) = self.resources.get(name) {
+            return Err(anyhow!(
+                "{} ({}) collides with a name reserved by {}",
+                name,
+                resource_provenance(resource),
+                feature
+            ));
+        }
+
+        self.reserved_names.insert(
+            name.to_string(),
+            ReservedName {
+                feature: feature.to_string(),
+                visibility,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Obtain reserved resource names and the features reserving them.
+    pub fn reserved_names(&self) -> &BTreeMap<String, ReservedName> {
+        &self.reserved_names
+    }
+
+    /// Whether a name is reserved for an internal synthetic resource.
+    pub fn is_internal(&self, name: &str) -> bool {
+        self.reserved_names
+            .get(name)
+            .map(|reserved| reserved.visibility == SyntheticVisibility::Internal)
+            .unwrap_or(false)
+    }
+
+    /// Ensure a resource name isn't reserved.
+    ///
+    /// `provenance` describes the resource being added.
+    fn check_reserved_name(&self, name: &str, provenance: impl FnOnce() -> String) -> Result<()> {
+        match self.reserved_names.get(name) {
+            Some(feature) => Err(anyhow!(
+                "{} ({}) collides with a name reserved by {}",
+                name,
+                provenance(),
+                feature
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Add a resource synthesized by a feature.
+    ///
+    /// The resource's name must have been reserved by `feature` with
+    /// `reserve_name()`.
+    pub fn add_synthetic_resource(
+        &mut self,
+        feature: &str,
+        resource: PrePackagedResource,
+    ) -> Result<()> {
+        match self.reserved_names.get(&resource.name) {
+            Some(existing) if existing.feature == feature => {}
+            Some(existing) => {
+                return Err(anyhow!(
+                    "{} can't add resource {}: name is reserved by {}",
+                    feature,
+                    resource.name,
+                    existing
+                ))
+            }
+            None => {
+                return Err(anyhow!(
+                    "{} can't add resource {}: name isn't reserved",
+                    feature,
+                    resource.name
+                ))
+            }
+        }
+
+        let name = resource.name.clone();
+        self.resources.insert(name.clone(), resource);
+
+        self.enforce_memory_budget(&name)
+    }
+
+    /// Obtain the version of a collected distribution.
+    ///
+    /// The version is read from the distribution's `METADATA` or `PKG-INFO`
+    /// resource. Distribution names are compared after normalization.
+    pub fn distribution_version(&self, distribution: &str) -> Result<Option<String>> {
+        let wanted = normalize_distribution_name(distribution);
+
+        for resource in self.resources.values() {
+            for file in &["METADATA", "PKG-INFO"] {
+                let location = resource
+                    .in_memory_distribution_resources
+                    .as_ref()
+                    .and_then(|resources| resources.get(*file))
+                    .or_else(|| {
+                        resource
+                            .relative_path_distribution_resources
+                            .as_ref()
+                            .and_then(|resources| resources.get(*file))
+                            .map(|(_, location)| location)
+                    });
+
+                if let Some(location) = location {
+                    let metadata = PythonPackageMetadata::from_metadata(&location.resolve()?)
+                        .with_context(|| format!("parsing {} of {}", file, resource.name))?;
+
+                    if let (Some(name), Some(version)) = (metadata.name(), metadata.version()) {
+                        if normalize_distribution_name(name) == wanted {
+                            return Ok(Some(version.to_string()));
+                        }
+                    }
+
+                    break;
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Obtain the direct URLs of collected distributions, by package name.
+    ///
+    /// Packages without a `direct_url.json` distribution resource or whose
+    /// `direct_url.json` can't be parsed are absent.
+    pub fn direct_urls(&self) -> BTreeMap<String, DirectUrl> {
+        self.resources
+            .iter()
+            .filter_map(|(name, resource)| Some((name.clone(), resource.direct_url().ok()??)))
+            .collect()
+    }
+
+    /// Obtain the files of a wheel holding a package.
+    ///
+    /// Modules and package resources of `package` and its subpackages are
+    /// included. The distribution of `package` is found like
+    /// `select_resources_in_packages()` does: a distribution owns the
+    /// package if its RECORD lists files in the top-level package. A
+    /// distribution named like the top-level package also owns it. Modules
+    /// without source and extension modules can't be put in a wheel.
+    pub fn wheel_contents(&self, package: &str) -> Result<WheelContents> {
+        let top_level = package.split('.').next().unwrap_or(package);
+        let mut contents = WheelContents::default();
+        let mut distributions = BTreeMap::new();
+
+        for (name, resource) in &self.resources {
+            let mut dist_info = BTreeMap::new();
+            if let Some(resources) = &resource.in_memory_distribution_resources {
+                dist_info.extend(resources.clone());
+            }
+            if let Some(resources) = &resource.relative_path_distribution_resources {
+                dist_info.extend(
+                    resources
+                        .iter()
+                        .map(|(file, (_, location))| (file.clone(), location.clone())),
+                );
+            }
+
+            if !dist_info.is_empty() {
+                let owns_package = match dist_info.get("RECORD") {
+                    Some(location) => PythonPackageRecord::from_record(&location.resolve()?)
+                        .with_context(|| format!("parsing RECORD of {}", name))?
+                        .top_level_names()
+                        .contains(top_level),
+                    None => false,
+                };
+
+                if owns_package
+                    || normalize_distribution_name(name) == normalize_distribution_name(top_level)
+                {
+                    distributions.insert(name.clone(), dist_info);
+                }
+            }
+
+            if name != package && !name.starts_with(&format!("{}.", package)) {
+                continue;
+            }
+
+            if resource.flavor != ResourceFlavor::Module {
+                return Err(anyhow!(
+                    "{} can't be put in a wheel: only modules and their resources can",
+                    name
+                ));
+            }
+
+            // Bytecode compiled from source carries the source.
+            let bytecode = [
+                resource.in_memory_bytecode.as_ref(),
+                resource.in_memory_bytecode_opt1.as_ref(),
+                resource.in_memory_bytecode_opt2.as_ref(),
+                resource.relative_path_bytecode.as_ref().map(|(_, _, p)| p),
+                resource
+                    .relative_path_bytecode_opt1
+                    .as_ref()
+                    .map(|(_, _, p)| p),
+                resource
+                    .relative_path_bytecode_opt2
+                    .as_ref()
+                    .map(|(_, _, p)| p),
+            ];
+            let source = resource
+                .in_memory_source
+                .as_ref()
+                .or_else(|| {
+                    resource
+                        .relative_path_module_source
+                        .as_ref()
+                        .map(|(_, location)| location)
+                })
+                .or_else(|| {
+                    bytecode
+                        .iter()
+                        .flatten()
+                        .find_map(|provider| match provider {
+                            PythonModuleBytecodeProvider::FromSource(location) => Some(location),
+                            PythonModuleBytecodeProvider::Provided(_) => None,
+                        })
+                });
+
+            match source {
+                Some(location) => {
+                    contents.files.insert(
+                        resolve_path_for_module("", name, resource.is_package, None)
+                            .to_string_lossy()
+                            .replace('\\', "/"),
+                        location.clone(),
+                    );
+                }
+                None if bytecode.iter().any(|provider| provider.is_some()) => {
+                    return Err(anyhow!(
+                        "{} can't be put in a wheel: it has bytecode but no source",
+                        name
+                    ));
+                }
+                None => {}
+            }
+
+            let package_resources = resource.in_memory_resources.iter().flatten().chain(
+                resource
+                    .relative_path_package_resources
+                    .iter()
+                    .flatten()
+                    .map(|(file, (_, location))| (file, location)),
+            );
+
+            for (file, location) in package_resources {
+                contents.files.insert(
+                    format!("{}/{}", name.replace('.', "/"), file),
+                    location.clone(),
+                );
+            }
+        }
+
+        if contents.files.is_empty() {
+            return Err(anyhow!("no resources belong to package {}", package));
+        }
+
+        if distributions.len() > 1 {
+            return Err(anyhow!(
+                "package {} belongs to multiple distributions: {}",
+                package,
+                distributions.keys().cloned().collect::<Vec<_>>().join(", ")
+            ));
+        }
+
+        if let Some((_, dist_info)) = distributions.into_iter().next() {
+            contents.dist_info = dist_info;
+        }
+
+        Ok(contents)
+    }
+
+    /// Find the payload a file of a `PackagePatch` refers to.
+    ///
+    /// Returns the name of the resource, the relative name of the package
+    /// resource or `None` for module source, and the payload's location.
+    fn find_patch_target(&self, path: &str) -> Option<(String, Option<String>, DataLocation)> {
+        if path.ends_with(".py") {
+            let module = &path[0..path.len() - 3];
+            let module = if module.ends_with("/__init__") {
+                &module[0..module.len() - "/__init__".len()]
+            } else {
+                module
+            };
+            let name = module.replace('/', ".");
+
+            if let Some(entry) = self.resources.get(&name) {
+                let location = entry.in_memory_source.as_ref().or_else(|| {
+                    entry
+                        .relative_path_module_source
+                        .as_ref()
+                        .map(|(_, location)| location)
+                });
+
+                if let Some(location) = location {
+                    return Some((name, None, location.clone()));
+                }
+            }
+        }
+
+        // Resources are relative to their leaf-most package.
+        let components = path.split('/').collect::<Vec<_>>();
+        for i in (1..components.len()).rev() {
+            let package = components[0..i].join(".");
+            let relative_name = components[i..].join("/");
+
+            if let Some(entry) = self.resources.get(&package) {
+                let location = entry
+                    .in_memory_resources
+                    .as_ref()
+                    .and_then(|resources| resources.get(&relative_name))
+                    .or_else(|| {
+                        entry
+                            .relative_path_package_resources
+                            .as_ref()
+                            .and_then(|resources| resources.get(&relative_name))
+                            .map(|(_, location)| location)
+                    });
+
+                if let Some(location) = location {
+                    return Some((package, Some(relative_name), location.clone()));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// Apply a patch to collected resources of a distribution.
+    ///
+    /// Module source and package resources can be patched. Bytecode to be
+    /// compiled from patched source is compiled from the patched source, so
+    /// patches must be applied before bytecode is compiled.
+    ///
+    /// Patching is all or nothing. If a patched file is missing or one of its
+    /// hunks doesn't apply, nothing is modified and the error describes every
+    /// failure along with the version of the distribution that is present.
+    /// Files already carrying the patch are left alone, so applying a patch
+    /// again has no effect.
+    pub fn apply_package_patch(&mut self, patch: &PackagePatch) -> Result<PackagePatchOutcome> {
+        let version = match self.distribution_version(&patch.distribution)? {
+            Some(version) => version,
+            None => return Ok(PackagePatchOutcome::DistributionMissing),
+        };
+
+        let parsed = match PythonVersion::parse(&version) {
+            Ok(parsed) => parsed,
+            Err(_) => return Ok(PackagePatchOutcome::InvalidVersion(version)),
+        };
+
+        if !patch.versions.contains(&parsed) {
+            return Ok(PackagePatchOutcome::VersionMismatch(version));
+        }
+
+        let mut failures = Vec::new();
+        let mut changes = Vec::new();
+
+        for file in &patch.files {
+            let (name, relative_name, location) = match self.find_patch_target(&file.path) {
+                Some(target) => target,
+                None => {
+                    failures.push(format!("{}: target file missing", file.path));
+                    continue;
+                }
+            };
+
+            let entry = &self.resources[&name];
+            if entry.applied_patches.contains(&patch.name) {
+                continue;
+            }
+
+            if relative_name.is_none() && entry.has_provided_bytecode() {
+                failures.push(format!(
+                    "{}: bytecode was compiled before the patch was applied",
+                    file.path
+                ));
+                continue;
+            }
+
+            let content = match String::from_utf8(location.resolve()?) {
+                Ok(content) => content,
+                Err(_) => {
+                    failures.push(format!("{}: not a UTF-8 text file", file.path));
+                    continue;
+                }
+            };
+
+            match file.apply(&content) {
+                Ok(patched) => changes.push((name, relative_name, patched.into_bytes())),
+                Err(hunks) => {
+                    failures.extend(hunks.iter().map(|hunk| format!("{}: {}", file.path, hunk)))
+                }
+            }
+        }
+
+        if !failures.is_empty() {
+            return Err(anyhow!(
+                "patch {} can't be applied to {} {}: {}",
+                patch.name,
+                patch.distribution,
+                version,
+                failures.join("; ")
+            ));
+        }
+
+        for (name, relative_name, data) in changes {
+            let entry = self.resources.get_mut(&name).unwrap();
+            let patched = DataLocation::Memory(data);
+
+            match relative_name {
+                None => {
+                    if let Some(source) = entry.in_memory_source.as_mut() {
+                        *source = patched.clone();
+                    }
+                    if let Some((_, source)) = entry.relative_path_module_source.as_mut() {
+                        *source = patched.clone();
+                    }
+                    for provider in entry.bytecode_providers_mut() {
+                        if let PythonModuleBytecodeProvider::FromSource(source) = provider {
+                            *source = patched.clone();
+                        }
+                    }
+                }
+                Some(relative_name) => {
+                    if let Some(data) = entry
+                        .in_memory_resources
+                        .as_mut()
+                        .and_then(|resources| resources.get_mut(&relative_name))
+                    {
+                        *data = patched.clone();
+                    }
+                    if let Some((_, data)) = entry
+                        .relative_path_package_resources
+                        .as_mut()
+                        .and_then(|resources| resources.get_mut(&relative_name))
+                    {
+                        *data = patched.clone();
+                    }
+                }
+            }
+
+            entry.applied_patches.insert(patch.name.clone());
+        }
+
+        self.enforce_memory_budget_all()?;
+
+        Ok(PackagePatchOutcome::Applied(version))
+    }
+
+    /// Replace the location of every payload of collected resources.
+    ///
+    /// `f` receives each location and returns its replacement.
+    pub fn map_data_locations<F>(&mut self, mut f: F) -> Result<()>
+    where
+        F: FnMut(&DataLocation) -> Result<DataLocation>,
+    {
+        for resource in self.resources.values_mut() {
+            resource.map_data_locations(&mut f)?;
+        }
+
+        self.enforce_memory_budget_all()
+    }
+
+    /// Apply a filter function on resources in this collection and mutate in place.
+    ///
+    /// If the filter function returns true, the item will be preserved.
+    pub fn filter_resources_mut<F>(&mut self, filter: F) -> Result<()>
+    where
+        F: Fn(&PrePackagedResource) -> bool,
+    {
+        self.resources = BTreeMap::from_iter(self.resources.iter().filter_map(|(k, v)| {
+            if filter(v) {
+                Some((k.clone(), v.clone()))
+            } else {
+                None
+            }
+        }));
+
+        self.enforce_memory_budget_all()
+    }
+
+    /// Annotate resources as used or unused from a log of an instrumented run.
+    ///
+    /// See the `resource_usage` module for the log format. Logs consumed by
+    /// multiple calls are combined, so runs of different test suites can
+    /// each contribute.
+    pub fn mark_used_from_log(&mut self, log: &Path) -> Result<UsageReport> {
+        let log = UsageLog::from_path(log)?;
+
+        let merged = self.usage_log.get_or_insert_with(UsageLog::default);
+        merged.merge(&log);
+
+        let report = resolve_resource_usage(&self.resources, merged)?;
+        self.used_resources = Some(report.used.clone());
+
+        Ok(report)
+    }
+
+    /// Compute a filter file keeping resources recorded by a usage log.
+    ///
+    /// `protected` is names to keep regardless. Unlike `mark_used_from_log()`,
+    /// this doesn't modify the collector.
+    pub fn filter_file_from_log(
+        &self,
+        log: &Path,
+        protected: &BTreeSet<String>,
+    ) -> Result<FilterFile> {
+        FilterFile::from_usage_log(&self.resources, &UsageLog::from_path(log)?, protected)
+    }
+
+    /// Compute a filter file keeping entry modules and what they import.
+    ///
+    /// `protected` is names to keep regardless.
+    pub fn filter_file_from_entry_modules(
+        &self,
+        entries: &[&str],
+        protected: &BTreeSet<String>,
+    ) -> Result<FilterFile> {
+        FilterFile::from_entry_modules(&self.resources, entries, protected)
+    }
+
+    /// Write a payload of a resource to a file in a directory.
+    ///
+    /// Bytecode is written as a `.pyc` file, compiling it from source if
+    /// necessary, and requires `compiler`. Returns the path of the written
+    /// file.
+    pub fn extract_resource(
+        &self,
+        name: &str,
+        payload: &PayloadKind,
+        compiler: Option<&mut dyn PythonBytecodeCompiler>,
+        dest: &Path,
+    ) -> Result<PathBuf> {
+        let resource = self.resources.get(name).ok_or_else(|| {
+            not_found_error(
+                &format!("resource {}", name),
+                name,
+                self.resources.keys().map(|x| x.as_str()),
+            )
+        })?;
+
+        let missing = || anyhow!("{} has no {}", name, payload);
+
+        let data = match payload {
+            PayloadKind::Source => resource
+                .in_memory_source
+                .as_ref()
+                .or_else(|| resource.relative_path_module_source.as_ref().map(|x| &x.1))
+                .ok_or_else(missing)?
+                .resolve()?,
+            PayloadKind::Bytecode | PayloadKind::BytecodeOpt1 | PayloadKind::BytecodeOpt2 => {
+                let (provider, relative, level) = match payload {
+                    PayloadKind::Bytecode => (
+                        &resource.in_memory_bytecode,
+                        &resource.relative_path_bytecode,
+                        BytecodeOptimizationLevel::Zero,
+                    ),
+                    PayloadKind::BytecodeOpt1 => (
+                        &resource.in_memory_bytecode_opt1,
+                        &resource.relative_path_bytecode_opt1,
+                        BytecodeOptimizationLevel::One,
+                    ),
+                    _ => (
+                        &resource.in_memory_bytecode_opt2,
+                        &resource.relative_path_bytecode_opt2,
+                        BytecodeOptimizationLevel::Two,
+                    ),
+                };
+
+                // Relative path bytecode compiled ahead of time is already a .pyc file.
+                let is_pyc =
+                    provider.is_none() && resource.pyc_payloads.contains(bytecode_label(level));
+                let provider = provider
+                    .as_ref()
+                    .or_else(|| relative.as_ref().map(|x| &x.2))
+                    .ok_or_else(missing)?;
+                let compiler = compiler.ok_or_else(|| {
+                    anyhow!("a bytecode compiler is required to extract bytecode")
+                })?;
+                let mode = self.compile_pyc_invalidation_mode(compiler)?;
+
+                match provider {
+                    PythonModuleBytecodeProvider::FromSource(location) => compiler.compile(
+                        &location.resolve()?,
+                        name,
+                        level,
+                        mode.compile_mode(location.mtime()?),
+                    )?,
+                    PythonModuleBytecodeProvider::Provided(location) if is_pyc => {
+                        location.resolve()?
+                    }
+                    PythonModuleBytecodeProvider::Provided(location) => {
+                        let mut data = compute_bytecode_header(
+                            compiler.get_magic_number(),
+                            BytecodeHeaderMode::UncheckedHash(0),
+                        )?;
+                        data.extend(location.resolve()?);
+
+                        data
+                    }
+                }
+            }
+            PayloadKind::ExtensionModule => resource
+                .in_memory_extension_module_shared_library
+                .as_ref()
+                .or_else(|| {
+                    resource
+                        .relative_path_extension_module_shared_library
+                        .as_ref()
+                        .map(|x| &x.1)
+                })
+                .ok_or_else(missing)?
+                .resolve()?,
+            PayloadKind::PackageResource(path) => {
+                let location = resource
+                    .in_memory_resources
+                    .as_ref()
+                    .and_then(|x| x.get(path))
+                    .or_else(|| {
+                        resource
+                            .relative_path_package_resources
+                            .as_ref()
+                            .and_then(|x| x.get(path))
+                            .map(|x| &x.1)
+                    });
+
+                match location {
+                    Some(location) => location.resolve()?,
+                    None => {
+                        let candidates = resource
+                            .in_memory_resources
+                            .iter()
+                            .flat_map(|x| x.keys())
+                            .chain(
+                                resource
+                                    .relative_path_package_resources
+                                    .iter()
+                                    .flat_map(|x| x.keys()),
+                            )
+                            .map(|x| x.as_str());
+
+                        return Err(not_found_error(
+                            &format!("package resource {} of {}", path, name),
+                            path,
+                            candidates,
+                        ));
+                    }
+                }
+            }
+            PayloadKind::DistributionResource(path) => resource
+                .in_memory_distribution_resources
+                .as_ref()
+                .and_then(|x| x.get(path))
+                .or_else(|| {
+                    resource
+                        .relative_path_distribution_resources
+                        .as_ref()
+                        .and_then(|x| x.get(path))
+                        .map(|x| &x.1)
+                })
+                .ok_or_else(missing)?
+                .resolve()?,
+            PayloadKind::SharedLibrary => resource
+                .in_memory_shared_library
+                .as_ref()
+                .or_else(|| resource.relative_path_shared_library.as_ref().map(|x| &x.1))
+                .ok_or_else(missing)?
+                .resolve()?,
+        };
+
+        write_payload(dest, &payload.file_name(name), &data)
+    }
+
+    /// Whether a resource was used according to consumed usage logs.
+    ///
+    /// Returns `None` if no usage log has been consumed.
+    pub fn is_resource_used(&self, name: &str) -> Option<bool> {
+        self.used_resources.as_ref().map(|x| x.contains(name))
+    }
+
+    /// Remove resources not marked as used.
+    ///
+    /// Resources for which `protect` returns true are kept, along with
+    /// their parent packages and shared libraries they depend on. Returns
+    /// the names of removed resources.
+    pub fn remove_unused<F>(&mut self, protect: F) -> Result<Vec<String>>
+    where
+        F: Fn(&PrePackagedResource) -> bool,
+    {
+        let used = self
+            .used_resources
+            .as_ref()
+            .ok_or_else(|| anyhow!("resource usage is not known; consume a usage log first"))?;
+
+        let mut keep = BTreeSet::new();
+        let mut pending = self
+            .resources
+            .values()
+            .filter(|r| used.contains(&r.name) || protect(r))
+            .map(|r| r.name.clone())
+            .collect::<Vec<_>>();
+
+        while let Some(name) = pending.pop() {
+            if !keep.insert(name.clone()) {
+                continue;
+            }
+
+            pending.extend(packages_from_module_name(&name));
+
+            if let Some(names) = self
+                .resources
+                .get(&name)
+                .and_then(|r| r.shared_library_dependency_names.as_ref())
+            {
+                pending.extend(names.iter().cloned());
+            }
+        }
+
+        let removed = self
+            .resources
+            .keys()
+            .filter(|name| !keep.contains(*name))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for name in &removed {
+            self.resources.remove(name);
+            self.enforce_memory_budget(name)?;
+        }
+
+        Ok(removed)
+    }
+
+    /// Tag a resource with a feature name.
+    ///
+    /// Tagged resources are only packaged if one of their tags is enabled.
+    /// See `resources_for_features()`.
+    pub fn tag_resource(&mut self, name: &str, tag: &str) -> Result<()> {
+        let entry = self
+            .resources
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("resource {} not found", name))?;
+
+        entry.feature_tags.insert(tag.to_string());
+
+        Ok(())
+    }
+
+    /// Compress the payloads of a resource embedded in packed resources with zstd.
+    ///
+    /// In-memory payloads, wherever their content lives, are replaced by
+    /// `CompressedMemory` ones at compression `level` when that makes them
+    /// smaller. They are stored compressed in packed resources and
+    /// decompressed by the runtime when loaded. Payloads installed relative
+    /// to the binary and bytecode still to be compiled are left alone.
+    /// Returns the number of bytes saved.
+    pub fn compress_resource(&mut self, name: &str, level: i32) -> Result<u64> {
+        let entry = self
+            .resources
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("resource {} not found", name))?;
+
+        let mut saved = 0;
+
+        for location in entry.embedded_payloads_mut() {
+            if !matches!(location, DataLocation::CompressedMemory { .. }) {
+                let compressed = location
+                    .to_memory_compressed(level)
+                    .with_context(|| format!("compressing {}", name))?;
+
+                if let DataLocation::CompressedMemory {
+                    data: compressed_data,
+                    size,
+                } = &compressed
+                {
+                    if compressed_data.len() as u64 >= *size {
+                        continue;
+                    }
+                    saved += size - compressed_data.len() as u64;
+                }
+
+                *location = compressed;
+            }
+        }
+
+        self.enforce_memory_budget(name)?;
+
+        Ok(saved)
+    }
+
+    /// Obtain the resources to package when a set of features is enabled.
+    ///
+    /// Untagged resources are always included. A tagged resource is included
+    /// if any of its tags is enabled. It is an error for an included
+    /// resource to depend on an excluded one, either as a parent package or
+    /// as a shared library.
+    pub fn resources_for_features(
+        &self,
+        enabled: &BTreeSet<String>,
+    ) -> Result<BTreeMap<String, PrePackagedResource>> {
+        let resources = self
+            .resources
+            .iter()
+            .filter(|(_, r)| r.feature_tags.is_empty() || !r.feature_tags.is_disjoint(enabled))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect::<BTreeMap<_, _>>();
+
+        for (name, resource) in &resources {
+            let dependencies = packages_from_module_name(name).into_iter().chain(
+                resource
+                    .shared_library_dependency_names
+                    .iter()
+                    .flatten()
+                    .cloned(),
+            );
+
+            for dependency in dependencies {
+                if self.resources.contains_key(&dependency) && !resources.contains_key(&dependency)
+                {
+                    return Err(anyhow!(
+                        "{} depends on {}, which is excluded because none of its features ({}) are enabled",
+                        name,
+                        dependency,
+                        self.resources[&dependency]
+                            .feature_tags
+                            .iter()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                }
+            }
+        }
+
+        Ok(resources)
+    }
+
+    /// Obtain an iterator over the resources in this collector.
+    pub fn iter_resources(&self) -> impl Iterator<Item = (&String, &PrePackagedResource)> {
+        Box::new(self.resources.iter())
+    }
+
+    /// Add Python module source with a specific location.
+    pub fn add_python_module_source(
+        &mut self,
+        module: &PythonModuleSource,
+        location: &ConcreteResourceLocation,
+    ) -> Result<()> {
+        self.check_policy(location.into())?;
+
+        self.check_reserved_name(&module.name, || {
+            format!("module source from {}", describe_location(&module.source))
+        })?;
+
+        if !self.resolve_module_package_collision(&module.name, module.is_package, || {
+            format!("module from {}", describe_location(&module.source))
+        })? {
+            return Ok(());
+        }
+
+        let (source, normalized) = self.normalize_text(&module.source, true)?;
+        let source = self.transform_source(&module.name, source)?;
+
+        let existing = self
+            .resources
+            .get(&module.name)
+            .and_then(|entry| match location {
+                ConcreteResourceLocation::InMemory => entry.in_memory_source.as_ref(),
+                ConcreteResourceLocation::RelativePath(_) => entry
+                    .relative_path_module_source
+                    .as_ref()
+                    .map(|(_, source)| source),
+            });
+        if !self.resolve_add_collision(
+            || format!("source of {}", module.name),
+            existing,
+            &source,
+        )? {
+            return Ok(());
+        }
+
+        let entry = self
+            .resources
+            .entry(module.name.clone())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: module.name.clone(),
+                ..PrePackagedResource::default()
+            });
+        entry.is_package = module.is_package;
+
+        if normalized {
+            entry.normalized_payloads.insert("source".to_string());
+        }
+        entry.mark_test_payload("source".to_string(), module.is_test);
+
+        match location {
+            ConcreteResourceLocation::InMemory => {
+                entry.in_memory_source = Some(source);
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                entry.relative_path_module_source = Some((prefix.to_string(), source));
+            }
+        }
+
+        self.enforce_memory_budget(&module.name)
+    }
+
+    /// Add Python module bytecode to the specified location.
+    pub fn add_python_module_bytecode(
+        &mut self,
+        module: &PythonModuleBytecode,
+        location: &ConcreteResourceLocation,
+    ) -> Result<()> {
+        self.check_policy(location.into())?;
+
+        self.check_reserved_name(&module.name, || {
+            format!(
+                "module bytecode from {}",
+                describe_location(module.bytecode_location())
+            )
+        })?;
+
+        if !self.resolve_module_package_collision(&module.name, module.is_package, || {
+            format!(
+                "module from {}",
+                describe_location(module.bytecode_location())
+            )
+        })? {
+            return Ok(());
+        }
+
+        let entry = self
+            .resources
+            .entry(module.name.clone())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: module.name.clone(),
+                ..PrePackagedResource::default()
+            });
+
+        entry.is_package = module.is_package;
+        entry.mark_test_payload(
+            bytecode_label(module.optimize_level).to_string(),
+            module.is_test,
+        );
+        entry
+            .pyc_payloads
+            .remove(bytecode_label(module.optimize_level));
+
+        // TODO having to resolve the DataLocation here is a bit unfortunate.
+        // We could invent a better type to allow the I/O to remain lazy.
+        let bytecode = PythonModuleBytecodeProvider::Provided(DataLocation::Memory(
+            module.resolve_bytecode()?,
+        ));
+
+        match location {
+            ConcreteResourceLocation::InMemory => match module.optimize_level {
+                BytecodeOptimizationLevel::Zero => {
+                    entry.in_memory_bytecode = Some(bytecode);
+                }
+                BytecodeOptimizationLevel::One => {
+                    entry.in_memory_bytecode_opt1 = Some(bytecode);
+                }
+                BytecodeOptimizationLevel::Two => {
+                    entry.in_memory_bytecode_opt2 = Some(bytecode);
+                }
+            },
+            ConcreteResourceLocation::RelativePath(prefix) => match module.optimize_level {
+                BytecodeOptimizationLevel::Zero => {
+                    entry.relative_path_bytecode =
+                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode));
+                }
+                BytecodeOptimizationLevel::One => {
+                    entry.relative_path_bytecode_opt1 =
+                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode));
+                }
+                BytecodeOptimizationLevel::Two => {
+                    entry.relative_path_bytecode_opt2 =
+                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode));
+                }
+            },
+        }
+
+        self.enforce_memory_budget(&module.name)
+    }
+
+    /// Add Python module bytecode derived from source code to the collection.
+    pub fn add_python_module_bytecode_from_source(
+        &mut self,
+        module: &PythonModuleBytecodeFromSource,
+        location: &ConcreteResourceLocation,
+    ) -> Result<()> {
+        self.check_policy(location.into())?;
+
+        self.check_reserved_name(&module.name, || {
+            format!(
+                "module bytecode compiled from {}",
+                describe_location(&module.source)
+            )
+        })?;
+
+        if !self.resolve_module_package_collision(&module.name, module.is_package, || {
+            format!("module from {}", describe_location(&module.source))
+        })? {
+            return Ok(());
+        }
+
+        // Compile what is embedded as source.
+        let (source, normalized) = self.normalize_text(&module.source, true)?;
+        let source = self.transform_source(&module.name, source)?;
+
+        let entry = self
+            .resources
+            .entry(module.name.clone())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: module.name.clone(),
+                ..PrePackagedResource::default()
+            });
+
+        entry.is_package = module.is_package;
+
+        if normalized {
+            entry.normalized_payloads.insert(
+                match module.optimize_level {
+                    BytecodeOptimizationLevel::Zero => PayloadKind::Bytecode,
+                    BytecodeOptimizationLevel::One => PayloadKind::BytecodeOpt1,
+                    BytecodeOptimizationLevel::Two => PayloadKind::BytecodeOpt2,
+                }
+                .to_string(),
+            );
+        }
+
+        entry.mark_test_payload(
+            bytecode_label(module.optimize_level).to_string(),
+            module.is_test,
+        );
+        entry
+            .pyc_payloads
+            .remove(bytecode_label(module.optimize_level));
+
+        let bytecode = PythonModuleBytecodeProvider::FromSource(source);
+
+        match location {
+            ConcreteResourceLocation::InMemory => match module.optimize_level {
+                BytecodeOptimizationLevel::Zero => {
+                    entry.in_memory_bytecode = Some(bytecode);
+                }
+                BytecodeOptimizationLevel::One => {
+                    entry.in_memory_bytecode_opt1 = Some(bytecode);
+                }
+                BytecodeOptimizationLevel::Two => {
+                    entry.in_memory_bytecode_opt2 = Some(bytecode);
+                }
+            },
+            ConcreteResourceLocation::RelativePath(prefix) => match module.optimize_level {
+                BytecodeOptimizationLevel::Zero => {
+                    entry.relative_path_bytecode =
+                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode))
+                }
+                BytecodeOptimizationLevel::One => {
+                    entry.relative_path_bytecode_opt1 =
+                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode))
+                }
+                BytecodeOptimizationLevel::Two => {
+                    entry.relative_path_bytecode_opt2 =
+                        Some((prefix.to_string(), module.cache_tag.clone(), bytecode))
+                }
+            },
+        }
+
+        self.enforce_memory_budget(&module.name)
+    }
+
+    /// Add resource data to a given location.
+    ///
+    /// Resource data belongs to a Python package and has a name and bytes data.
+    pub fn add_python_package_resource(
+        &mut self,
+        resource: &PythonPackageResource,
+        location: &ConcreteResourceLocation,
+    ) -> Result<()> {
+        self.check_policy(location.into())?;
+
+        self.check_reserved_name(&resource.leaf_package, || {
+            format!(
+                "package resource {} from {}",
+                resource.symbolic_name(),
+                describe_location(&resource.data)
+            )
+        })?;
+
+        // The package lost a collision with a module of the same name.
+        if self.collided_packages.contains(&resource.leaf_package) {
+            return Ok(());
+        }
+
+        if let Some(filter) = &self.locale_filter {
+            let path = format!(
+                "{}/{}",
+                resource.leaf_package.replace('.', "/"),
+                resource.relative_name
+            );
+
+            if !filter.keeps_resource(&path) {
+                let size = resource.data.resolve()?.len() as u64;
+                let statistics = self
+                    .trimmed_locales
+                    .entry(resource.leaf_package.clone())
+                    .or_insert_with(LocaleTrimStatistics::default);
+                statistics.files += 1;
+                statistics.bytes += size;
+
+                return Ok(());
+            }
+        }
+
+        let is_text = self
+            .text_normalization
+            .as_ref()
+            .map_or(false, |x| x.is_text_resource(&resource.relative_name));
+        let (data, normalized) = self.normalize_text(&resource.data, is_text)?;
+
+        let existing =
+            self.resources
+                .get(&resource.leaf_package)
+                .and_then(|entry| match location {
+                    ConcreteResourceLocation::InMemory => entry
+                        .in_memory_resources
+                        .as_ref()
+                        .and_then(|x| x.get(&resource.relative_name)),
+                    ConcreteResourceLocation::RelativePath(_) => entry
+                        .relative_path_package_resources
+                        .as_ref()
+                        .and_then(|x| x.get(&resource.relative_name))
+                        .map(|(_, data)| data),
+                });
+        if !self.resolve_add_collision(
+            || format!("package resource {}", resource.symbolic_name()),
+            existing,
+            &data,
+        )? {
+            return Ok(());
+        }
+
+        let entry = self
+            .resources
+            .entry(resource.leaf_package.clone())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: resource.leaf_package.clone(),
+                ..PrePackagedResource::default()
+            });
+
+        // Adding a resource automatically makes the module a package.
+        entry.is_package = true;
+
+        if normalized {
+            entry
+                .normalized_payloads
+                .insert(PayloadKind::PackageResource(resource.relative_name.clone()).to_string());
+        }
+        entry.mark_test_payload(
+            format!("resource {}", resource.relative_name),
+            resource.is_test,
+        );
+
+        match location {
+            ConcreteResourceLocation::InMemory => {
+                if entry.in_memory_resources.is_none() {
+                    entry.in_memory_resources = Some(BTreeMap::new());
+                }
+                entry
+                    .in_memory_resources
+                    .as_mut()
+                    .unwrap()
+                    .insert(resource.relative_name.clone(), data);
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                if entry.relative_path_package_resources.is_none() {
+                    entry.relative_path_package_resources = Some(BTreeMap::new());
+                }
+
+                entry
+                    .relative_path_package_resources
+                    .as_mut()
+                    .unwrap()
+                    .insert(
+                        resource.relative_name.clone(),
+                        (resource.resolve_path(prefix), data),
+                    );
+            }
+        }
+
+        self.enforce_memory_budget(&resource.leaf_package)
+    }
+
+    /// Add a package distribution resource to a given location.
+    pub fn add_package_distribution_resource(
+        &mut self,
+        resource: &PythonPackageDistributionResource,
+        location: &ConcreteResourceLocation,
+    ) -> Result<()> {
+        self.check_policy(location.into())?;
+
+        self.check_reserved_name(&resource.package, || {
+            format!(
+                "distribution resource {} from {}",
+                resource.name,
+                describe_location(&resource.data)
+            )
+        })?;
+
+        self.track_distribution_version(resource)?;
+
+        let existing = self
+            .resources
+            .get(&resource.package)
+            .and_then(|entry| match location {
+                ConcreteResourceLocation::InMemory => entry
+                    .in_memory_distribution_resources
+                    .as_ref()
+                    .and_then(|x| x.get(&resource.name)),
+                ConcreteResourceLocation::RelativePath(_) => entry
+                    .relative_path_distribution_resources
+                    .as_ref()
+                    .and_then(|x| x.get(&resource.name))
+                    .map(|(_, data)| data),
+            });
+        if !self.resolve_add_collision(
+            || {
+                format!(
+                    "distribution resource {} of {}",
+                    resource.name, resource.package
+                )
+            },
+            existing,
+            &resource.data,
+        )? {
+            return Ok(());
+        }
+
+        let entry = self
+            .resources
+            .entry(resource.package.clone())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: resource.package.clone(),
+                ..PrePackagedResource::default()
+            });
+
+        // A distribution resource makes the entity a package.
+        entry.is_package = true;
+
+        match location {
+            ConcreteResourceLocation::InMemory => {
+                if entry.in_memory_distribution_resources.is_none() {
+                    entry.in_memory_distribution_resources = Some(BTreeMap::new());
+                }
+
+                entry
+                    .in_memory_distribution_resources
+                    .as_mut()
+                    .unwrap()
+                    .insert(resource.name.clone(), resource.data.clone());
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                if entry.relative_path_distribution_resources.is_none() {
+                    entry.relative_path_distribution_resources = Some(BTreeMap::new());
+                }
+
+                entry
+                    .relative_path_distribution_resources
+                    .as_mut()
+                    .unwrap()
+                    .insert(
+                        resource.name.clone(),
+                        (resource.resolve_path(prefix), resource.data.clone()),
+                    );
+            }
+        }
+
+        // A malformed direct_url.json only loses provenance, so it
+        // shouldn't prevent packaging the distribution.
+        if resource.name == DIRECT_URL_FILENAME {
+            if let Err(err) = resource
+                .data
+                .resolve()
+                .and_then(|data| DirectUrl::from_json(&data))
+            {
+                self.warnings.push(format!(
+                    "ignoring {} of {}: {:#}",
+                    DIRECT_URL_FILENAME, resource.package, err
+                ));
+            }
+        }
+
+        self.enforce_memory_budget(&resource.package)
+    }
+
+    /// Record the version of the distribution a distribution resource belongs to.
+    ///
+    /// Conflicting versions should have been resolved by
+    /// `resolve_distribution_versions()`. Otherwise, the resources of both
+    /// versions are mixed unless the policy rejects conflicts.
+    fn track_distribution_version(
+        &mut self,
+        resource: &PythonPackageDistributionResource,
+    ) -> Result<()> {
+        let collected = self
+            .distributions
+            .entry(normalize_distribution_name(&resource.package))
+            .or_insert_with(|| CollectedDistribution {
+                name: resource.package.clone(),
+                version: resource.version.clone(),
+                discarded_versions: vec![],
+            });
+
+        if collected.version == resource.version {
+            return Ok(());
+        }
+
+        if self.distribution_version_conflict == DistributionVersionConflictPolicy::Error {
+            return Err(anyhow!(
+                "{} {} conflicts with version {} collected earlier",
+                resource.package,
+                resource.version,
+                collected.version
+            ));
+        }
+
+        self.warnings.push(format!(
+            "{} {} added over version {}; files of the earlier version may remain",
+            resource.package, resource.version, collected.version
+        ));
+        collected.discarded_versions.push(std::mem::replace(
+            &mut collected.version,
+            resource.version.clone(),
+        ));
+
+        Ok(())
+    }
+
+    /// Add a built-in extension module.
+    ///
+    /// Built-in extension modules are statically linked into the binary and
+    /// cannot have their location defined.
+    pub fn add_builtin_python_extension_module(
+        &mut self,
+        module: &PythonExtensionModule,
+    ) -> Result<()> {
+        self.check_reserved_name(&module.name, || "built-in extension module".to_string())?;
+
+        let entry = self
+            .resources
+            .entry(module.name.clone())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::BuiltinExtensionModule,
+                name: module.name.clone(),
+                ..PrePackagedResource::default()
+            });
+
+        entry.is_package = module.is_package;
+
+        Ok(())
+    }
+
+    /// Add a Python extension module shared library that should be imported from memory.
+    pub fn add_python_extension_module(
+        &mut self,
+        module: &PythonExtensionModule,
+        location: &ConcreteResourceLocation,
+    ) -> Result<()> {
+        self.check_policy(location.into())?;
+        self.check_reserved_name(&module.name, || match &module.shared_library {
+            Some(location) => format!("extension module from {}", describe_location(location)),
+            None => "extension module".to_string(),
+        })?;
+
+        let data = match &module.shared_library {
+            Some(location) => location.resolve()?,
+            None => return Err(anyhow!("no shared library data present")),
+        };
+
+        // We place shared libraries next to the extension module.
+        let library_directory = |prefix: &str| -> Result<String> {
+            Ok(format!(
+                "{}",
+                module
+                    .resolve_path(prefix)
+                    .parent()
+                    .ok_or_else(|| anyhow!("unable to resolve parent directory"))?
+                    .display()
+            ))
+        };
+
+        // Library dependencies of an extension module loaded from memory
+        // can't be resolved from memory. System libraries are resolved by the
+        // operating system. Everything else needs to be materialized on the
+        // filesystem.
+        if let ConcreteResourceLocation::InMemory = location {
+            let unsatisfied = module
+                .link_libraries
+                .iter()
+                .filter(|link| link.dynamic_library.is_some() && !link.system && !link.framework)
+                .map(|link| link.name.as_str())
+                .collect::<Vec<_>>();
+
+            if !unsatisfied.is_empty()
+                && self.strict_library_dependencies
+                && !self.extract_library_dependencies
+            {
+                return Err(anyhow!(
+                    "extension module {} depends on libraries that cannot be loaded from memory: {}",
+                    module.name,
+                    unsatisfied.join(", ")
+                ));
+            }
+        }
+
+        let mut depends = Vec::new();
+
+        for link in &module.link_libraries {
+            if let Some(shared_library) = &link.dynamic_library {
+                let library_location = match location {
+                    ConcreteResourceLocation::InMemory if link.system || link.framework => {
+                        ConcreteResourceLocation::InMemory
+                    }
+                    ConcreteResourceLocation::InMemory if self.extract_library_dependencies => {
+                        self.add_extracted_shared_library(&link.name, shared_library)?;
+                        depends.push(link.name.to_string());
+                        continue;
+                    }
+                    ConcreteResourceLocation::InMemory => match &self.policy {
+                        PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative(prefix) => {
+                            let directory = library_directory(prefix)?;
+
+                            self.warnings.push(format!(
+                                "library {} required by in-memory extension module {} cannot be loaded from memory; installing to {}",
+                                link.name, module.name, directory
+                            ));
+
+                            ConcreteResourceLocation::RelativePath(directory)
+                        }
+                        _ => {
+                            self.warnings.push(format!(
+                                "library {} required by in-memory extension module {} cannot be loaded from memory and resources policy does not allow installing it on the filesystem; the extension module will likely fail to load",
+                                link.name, module.name
+                            ));
+
+                            ConcreteResourceLocation::InMemory
+                        }
+                    },
+                    ConcreteResourceLocation::RelativePath(prefix) => {
+                        ConcreteResourceLocation::RelativePath(library_directory(prefix)?)
+                    }
+                };
+
+                self.add_shared_library(&link.name, shared_library, &library_location)?;
+                depends.push(link.name.to_string());
+            }
+        }
+
+        let entry = self
+            .resources
+            .entry(module.name.to_string())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: module.name.to_string(),
+                ..PrePackagedResource::default()
+            });
+
+        if module.is_package {
+            entry.is_package = true;
+        }
+
+        match location {
+            ConcreteResourceLocation::InMemory => {
+                entry.in_memory_extension_module_shared_library = Some(DataLocation::Memory(data));
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                entry.relative_path_extension_module_shared_library =
+                    Some((module.resolve_path(prefix), DataLocation::Memory(data)));
+            }
+        }
+
+        entry.shared_library_dependency_names = Some(depends);
+
+        self.enforce_memory_budget(&module.name)
+    }
+
+    /// Add a shared library to be loaded from a location.
+    pub fn add_shared_library(
+        &mut self,
+        name: &str,
+        data: &DataLocation,
+        location: &ConcreteResourceLocation,
+    ) -> Result<()> {
+        self.check_policy(location.into())?;
+
+        self.check_reserved_name(name, || {
+            format!("shared library from {}", describe_location(data))
+        })?;
+
+        let entry = self
+            .resources
+            .entry(name.to_string())
+            .or_insert_with(|| PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: name.to_string(),
+                ..PrePackagedResource::default()
+            });
+
+        match location {
+            ConcreteResourceLocation::InMemory => {
+                entry.in_memory_shared_library = Some(data.clone());
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                entry.relative_path_shared_library = Some((prefix.to_string(), data.clone()));
+            }
+        }
+
+        self.enforce_memory_budget(name)
+    }
+
+    /// Add a shared library to be extracted from memory to the filesystem at run-time.
+    ///
+    /// The library data is embedded in the packed resources. At run-time, it
+    /// is written to a cache directory, keyed by its content, and loaded
+    /// before Python is initialized. The extracted file name is the resource
+    /// name, so it should match what dependent binaries expect to load.
+    pub fn add_extracted_shared_library(&mut self, name: &str, data: &DataLocation) -> Result<()> {
+        self.add_shared_library(name, data, &ConcreteResourceLocation::InMemory)?;
+
+        if let Some(entry) = self.resources.get_mut(name) {
+            entry.extract_shared_library = true;
+        }
+
+        Ok(())
+    }
+
+    /// Record that a resource depends on a shared library.
+    ///
+    /// Both the resource and the shared library must already be present.
+    pub fn add_shared_library_dependency(&mut self, name: &str, library: &str) -> Result<()> {
+        match self.resources.get(library) {
+            Some(resource) if resource.flavor == ResourceFlavor::SharedLibrary => {}
+            _ => return Err(anyhow!("shared library {} not found", library)),
+        }
+
+        let entry = self
+            .resources
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("resource {} not found", name))?;
+
+        let names = entry
+            .shared_library_dependency_names
+            .get_or_insert_with(Vec::new);
+
+        if !names.iter().any(|x| x == library) {
+            names.push(library.to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Add a `PrePackagedResource` to the collection.
+    ///
+    /// An existing resource with the same name is replaced.
+    pub fn add_prepackaged_resource(&mut self, resource: PrePackagedResource) -> Result<()> {
+        self.check_resource_policy(&resource)?;
+        self.check_reserved_name(&resource.name, || resource_provenance(&resource))?;
+
+        let name = resource.name.clone();
+        self.resources.insert(name.clone(), resource);
+
+        self.enforce_memory_budget(&name)
+    }
+
+    /// Validate that the locations of a resource's payloads are allowed.
+    fn check_resource_policy(&self, resource: &PrePackagedResource) -> Result<()> {
+        let in_memory = resource.in_memory_source.is_some()
+            || resource.in_memory_bytecode.is_some()
+            || resource.in_memory_bytecode_opt1.is_some()
+            || resource.in_memory_bytecode_opt2.is_some()
+            || resource.in_memory_extension_module_shared_library.is_some()
+            || resource.in_memory_resources.is_some()
+            || resource.in_memory_distribution_resources.is_some()
+            || resource.in_memory_shared_library.is_some();
+        let relative_path = resource.relative_path_module_source.is_some()
+            || resource.relative_path_bytecode.is_some()
+            || resource.relative_path_bytecode_opt1.is_some()
+            || resource.relative_path_bytecode_opt2.is_some()
+            || resource
+                .relative_path_extension_module_shared_library
+                .is_some()
+            || resource.relative_path_package_resources.is_some()
+            || resource.relative_path_distribution_resources.is_some()
+            || resource.relative_path_shared_library.is_some();
+
+        if in_memory {
+            self.check_policy(AbstractResourceLocation::InMemory)?;
+        }
+        if relative_path {
+            self.check_policy(AbstractResourceLocation::RelativePath)?;
+        }
+
+        Ok(())
+    }
+
+    /// Populate this collection from a packed resources blob.
+    ///
+    /// See `prepackaged_resources_from_packed_resources()` for the meaning
+    /// of arguments.
+    pub fn add_packed_resources(
+        &mut self,
+        data: &Arc<Vec<u8>>,
+        source_cache_tag: &str,
+        install_root: Option<&Path>,
+    ) -> Result<()> {
+        for resource in prepackaged_resources_from_packed_resources(
+            data,
+            &self.cache_tag,
+            source_cache_tag,
+            install_root,
+        )? {
+            self.add_prepackaged_resource(resource)?;
+        }
+
+        Ok(())
+    }
+
+    /// Obtain the number of bytecode entries that still need to be compiled from source.
+    pub fn pending_bytecode_count(&self) -> usize {
+        self.resources
+            .values()
+            .map(|r| r.pending_bytecode_count())
+            .sum()
+    }
+
+    /// Compile all bytecode in this collection that is still derived from source.
+    ///
+    /// Successfully compiled entries are replaced by the compiled bytecode, making
+    /// subsequent calls to `compile_resources()` cheaper. Bytecode installed
+    /// relative to the binary is compiled to the .pyc file it installs, invalidated
+    /// per the collection's pyc invalidation mode. Entries that fail to compile are
+    /// recorded in the returned report and remain pending.
+    pub fn compile_pending_bytecode(
+        &mut self,
+        compiler: &mut dyn PythonBytecodeCompiler,
+    ) -> Result<CompileReport> {
+        let pyc_mode = self.compile_pyc_invalidation_mode(compiler)?;
+
+        let mut report = CompileReport::default();
+
+        for (name, resource) in self.resources.iter_mut() {
+            for (provider, optimize_level) in vec![
+                (
+                    &mut resource.in_memory_bytecode,
+                    BytecodeOptimizationLevel::Zero,
+                ),
+                (
+                    &mut resource.in_memory_bytecode_opt1,
+                    BytecodeOptimizationLevel::One,
+                ),
+                (
+                    &mut resource.in_memory_bytecode_opt2,
+                    BytecodeOptimizationLevel::Two,
+                ),
+            ] {
+                if let Some(provider) = provider {
+                    compile_bytecode_provider(
+                        provider,
+                        name,
+                        optimize_level,
+                        None,
+                        compiler,
+                        &mut report,
+                    );
+                }
+            }
+
+            for (relative, optimize_level) in vec![
+                (
+                    &mut resource.relative_path_bytecode,
+                    BytecodeOptimizationLevel::Zero,
+                ),
+                (
+                    &mut resource.relative_path_bytecode_opt1,
+                    BytecodeOptimizationLevel::One,
+                ),
+                (
+                    &mut resource.relative_path_bytecode_opt2,
+                    BytecodeOptimizationLevel::Two,
+                ),
+            ] {
+                if let Some((_, _, provider)) = relative {
+                    if compile_bytecode_provider(
+                        provider,
+                        name,
+                        optimize_level,
+                        Some(pyc_mode),
+                        compiler,
+                        &mut report,
+                    ) {
+                        resource
+                            .pyc_payloads
+                            .insert(bytecode_label(optimize_level).to_string());
+                    }
+                }
+            }
+        }
+
+        self.enforce_memory_budget_all()?;
+
+        Ok(report)
+    }
+
+    /// Searches for Python sources for references to __file__.
+    ///
+    /// __file__ usage can be problematic for in-memory modules. This method searches
+    /// for its occurrences and returns module names having it present.
+    pub fn find_dunder_file(&self) -> Result<BTreeSet<String>> {
+        let mut res = BTreeSet::new();
+
+        for (name, module) in &self.resources {
+            if let Some(location) = &module.in_memory_source {
+                if has_dunder_file(&location.resolve()?)? {
+                    res.insert(name.clone());
+                }
+            }
+
+            if let Some(PythonModuleBytecodeProvider::FromSource(location)) =
+                &module.in_memory_bytecode
+            {
+                if has_dunder_file(&location.resolve()?)? {
+                    res.insert(name.clone());
+                }
+            }
+
+            if let Some(PythonModuleBytecodeProvider::FromSource(location)) =
+                &module.in_memory_bytecode_opt1
+            {
+                if has_dunder_file(&location.resolve()?)? {
+                    res.insert(name.clone());
+                }
+            }
+
+            if let Some(PythonModuleBytecodeProvider::FromSource(location)) =
+                &module.in_memory_bytecode_opt2
+            {
+                if has_dunder_file(&location.resolve()?)? {
+                    res.insert(name.clone());
+                }
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Move all resources of a package to another location.
+    ///
+    /// The package and its sub-modules have their source, bytecode, package
+    /// resources, distribution resources and extension module shared libraries
+    /// moved between memory and the filesystem. Moves are re-validated:
+    /// modules using `__file__` and extension modules depending on shared
+    /// libraries on the filesystem can't safely be moved into memory. Unless
+    /// `constraints.force` is set, such a failure aborts the relocation and
+    /// nothing is moved. Payloads that can't be moved at all are left behind
+    /// and recorded in the returned report.
+    pub fn relocate_package(
+        &mut self,
+        package: &str,
+        to: &ConcreteResourceLocation,
+        constraints: &RelocationConstraints,
+    ) -> Result<RelocationReport> {
+        self.check_policy(to.into())?;
+
+        let child_prefix = format!("{}.", package);
+        let names = self
+            .resources
+            .keys()
+            .filter(|name| *name == package || name.starts_with(&child_prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if names.is_empty() {
+            return Err(anyhow!("package {} not found", package));
+        }
+
+        let mut report = RelocationReport::default();
+        let mut refused = vec![];
+        let mut relocated = vec![];
+
+        for name in names {
+            let mut entry = self.resources[&name].clone();
+
+            let unsafe_reason = match entry.flavor {
+                ResourceFlavor::Module => self.relocate_module(&mut entry, to, &mut report)?,
+                ResourceFlavor::Extension => {
+                    self.relocate_extension(&mut entry, to, constraints, &mut report)?
+                }
+                ResourceFlavor::BuiltinExtensionModule => {
+                    report.left_behind.push((
+                        name.clone(),
+                        "built-in extension modules are linked into the binary".to_string(),
+                    ));
+                    None
+                }
+                _ => {
+                    report.left_behind.push((
+                        name.clone(),
+                        format!("{} cannot be relocated", resource_provenance(&entry)),
+                    ));
+                    None
+                }
+            };
+
+            if let Some(reason) = unsafe_reason {
+                if constraints.force {
+                    report.forced.push((name.clone(), reason));
+                } else {
+                    refused.push(format!("{}: {}", name, reason));
+                    continue;
+                }
+            }
+
+            relocated.push(entry);
+        }
+
+        if !refused.is_empty() {
+            return Err(anyhow!(
+                "refusing to relocate package {} to {}: {}; relocation can be forced",
+                package,
+                to,
+                refused.join("; ")
+            ));
+        }
+
+        for entry in relocated {
+            self.resources.insert(entry.name.clone(), entry);
+        }
+
+        Ok(report)
+    }
+
+    /// Move the payloads of a module resource.
+    ///
+    /// Returns why the move is unsafe, if it is.
+    fn relocate_module(
+        &self,
+        entry: &mut PrePackagedResource,
+        to: &ConcreteResourceLocation,
+        report: &mut RelocationReport,
+    ) -> Result<Option<String>> {
+        let name = entry.name.clone();
+        let mut unsafe_reason = None;
+
+        match to {
+            ConcreteResourceLocation::InMemory => {
+                let mut sources = vec![];
+                if let Some((_, location)) = &entry.relative_path_module_source {
+                    sources.push(location);
+                }
+                for (_, _, provider) in [
+                    &entry.relative_path_bytecode,
+                    &entry.relative_path_bytecode_opt1,
+                    &entry.relative_path_bytecode_opt2,
+                ]
+                .iter()
+                .copied()
+                .flatten()
+                {
+                    if let PythonModuleBytecodeProvider::FromSource(location) = provider {
+                        sources.push(location);
+                    }
+                }
+
+                for location in sources {
+                    if has_dunder_file(&location.resolve()?)? {
+                        unsafe_reason = Some(
+                            "module uses __file__, which isn't defined for in-memory modules"
+                                .to_string(),
+                        );
+                        break;
+                    }
+                }
+
+                if let Some((prefix, location)) = entry.relative_path_module_source.take() {
+                    entry.in_memory_source = Some(location);
+                    report.record_move(
+                        &name,
+                        PayloadKind::Source,
+                        ConcreteResourceLocation::RelativePath(prefix).to_string(),
+                        to,
+                    );
+                }
+
+                for (payload, optimize_level, relative, in_memory) in vec![
+                    (
+                        PayloadKind::Bytecode,
+                        BytecodeOptimizationLevel::Zero,
+                        &mut entry.relative_path_bytecode,
+                        &mut entry.in_memory_bytecode,
+                    ),
+                    (
+                        PayloadKind::BytecodeOpt1,
+                        BytecodeOptimizationLevel::One,
+                        &mut entry.relative_path_bytecode_opt1,
+                        &mut entry.in_memory_bytecode_opt1,
+                    ),
+                    (
+                        PayloadKind::BytecodeOpt2,
+                        BytecodeOptimizationLevel::Two,
+                        &mut entry.relative_path_bytecode_opt2,
+                        &mut entry.in_memory_bytecode_opt2,
+                    ),
+                ] {
+                    if let Some((prefix, _, mut provider)) = relative.take() {
+                        // In-memory bytecode has no .pyc header.
+                        if entry.pyc_payloads.remove(bytecode_label(optimize_level)) {
+                            if let PythonModuleBytecodeProvider::Provided(location) = &provider {
+                                provider = PythonModuleBytecodeProvider::Provided(
+                                    DataLocation::Memory(location.resolve()?[16..].to_vec()),
+                                );
+                            }
+                        }
+                        *in_memory = Some(provider);
+                        report.record_move(
+                            &name,
+                            payload,
+                            ConcreteResourceLocation::RelativePath(prefix).to_string(),
+                            to,
+                        );
+                    }
+                }
+
+                if let Some(resources) = entry.relative_path_package_resources.take() {
+                    let in_memory = entry.in_memory_resources.get_or_insert_with(BTreeMap::new);
+                    for (resource, (path, location)) in resources {
+                        report.record_move(
+                            &name,
+                            PayloadKind::PackageResource(resource.clone()),
+                            path.display().to_string(),
+                            to,
+                        );
+                        in_memory.insert(resource, location);
+                    }
+                }
+
+                if let Some(resources) = entry.relative_path_distribution_resources.take() {
+                    let in_memory = entry
+                        .in_memory_distribution_resources
+                        .get_or_insert_with(BTreeMap::new);
+                    for (resource, (path, location)) in resources {
+                        report.record_move(
+                            &name,
+                            PayloadKind::DistributionResource(resource.clone()),
+                            path.display().to_string(),
+                            to,
+                        );
+                        in_memory.insert(resource, location);
+                    }
+                }
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                if let Some(location) = entry.in_memory_source.take() {
+                    entry.relative_path_module_source = Some((prefix.clone(), location));
+                    report.record_move(
+                        &name,
+                        PayloadKind::Source,
+                        ConcreteResourceLocation::InMemory.to_string(),
+                        to,
+                    );
+                }
+
+                for (payload, in_memory, relative) in vec![
+                    (
+                        PayloadKind::Bytecode,
+                        &mut entry.in_memory_bytecode,
+                        &mut entry.relative_path_bytecode,
+                    ),
+                    (
+                        PayloadKind::BytecodeOpt1,
+                        &mut entry.in_memory_bytecode_opt1,
+                        &mut entry.relative_path_bytecode_opt1,
+                    ),
+                    (
+                        PayloadKind::BytecodeOpt2,
+                        &mut entry.in_memory_bytecode_opt2,
+                        &mut entry.relative_path_bytecode_opt2,
+                    ),
+                ] {
+                    if let Some(provider) = in_memory.take() {
+                        *relative = Some((prefix.clone(), self.cache_tag.clone(), provider));
+                        report.record_move(
+                            &name,
+                            payload,
+                            ConcreteResourceLocation::InMemory.to_string(),
+                            to,
+                        );
+                    }
+                }
+
+                if let Some(resources) = entry.in_memory_resources.take() {
+                    let relative = entry
+                        .relative_path_package_resources
+                        .get_or_insert_with(BTreeMap::new);
+                    for (resource, location) in resources {
+                        report.record_move(
+                            &name,
+                            PayloadKind::PackageResource(resource.clone()),
+                            ConcreteResourceLocation::InMemory.to_string(),
+                            to,
+                        );
+                        relative.insert(
+                            resource.clone(),
+                            (package_resource_path(prefix, &name, &resource), location),
+                        );
+                    }
+                }
+
+                if let Some(resources) = entry.in_memory_distribution_resources.take() {
+                    let directory = match entry
+                        .relative_path_distribution_resources
+                        .as_ref()
+                        .and_then(|x| x.values().next())
+                        .and_then(|(path, _)| path.parent())
+                        .and_then(|path| path.file_name())
+                    {
+                        Some(directory) => Some(directory.to_string_lossy().to_string()),
+                        None => distribution_directory(&name, &resources)?,
+                    };
+
+                    match directory {
+                        Some(directory) => {
+                            let relative = entry
+                                .relative_path_distribution_resources
+                                .get_or_insert_with(BTreeMap::new);
+                            for (resource, location) in resources {
+                                report.record_move(
+                                    &name,
+                                    PayloadKind::DistributionResource(resource.clone()),
+                                    ConcreteResourceLocation::InMemory.to_string(),
+                                    to,
+                                );
+                                relative.insert(
+                                    resource.clone(),
+                                    (
+                                        PathBuf::from(prefix).join(&directory).join(&resource),
+                                        location,
+                                    ),
+                                );
+                            }
+                        }
+                        None => {
+                            for resource in resources.keys() {
+                                let payload = PayloadKind::DistributionResource(resource.clone());
+                                report.left_behind.push((
+                                    format!("{} ({})", name, payload),
+                                    "the distribution's version is unknown because it has no METADATA or PKG-INFO".to_string(),
+                                ));
+                            }
+                            entry.in_memory_distribution_resources = Some(resources);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(unsafe_reason)
+    }
+
+    /// Move the shared library of an extension module resource.
+    ///
+    /// Returns why the move is unsafe, if it is.
+    fn relocate_extension(
+        &self,
+        entry: &mut PrePackagedResource,
+        to: &ConcreteResourceLocation,
+        constraints: &RelocationConstraints,
+        report: &mut RelocationReport,
+    ) -> Result<Option<String>> {
+        let name = entry.name.clone();
+
+        match to {
+            ConcreteResourceLocation::InMemory => {
+                if entry
+                    .relative_path_extension_module_shared_library
+                    .is_none()
+                {
+                    return Ok(None);
+                }
+
+                if !constraints.extension_modules_in_memory {
+                    report.left_behind.push((
+                        name,
+                        "extension modules cannot be loaded from memory by this binary".to_string(),
+                    ));
+                    return Ok(None);
+                }
+
+                let filesystem_libraries = entry
+                    .shared_library_dependency_names
+                    .iter()
+                    .flatten()
+                    .filter(|library| match self.resources.get(*library) {
+                        Some(resource) => resource.relative_path_shared_library.is_some(),
+                        None => false,
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                let (path, location) = entry
+                    .relative_path_extension_module_shared_library
+                    .take()
+                    .unwrap();
+                entry.in_memory_extension_module_shared_library = Some(location);
+                report.record_move(
+                    &name,
+                    PayloadKind::ExtensionModule,
+                    path.display().to_string(),
+                    to,
+                );
+
+                if filesystem_libraries.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some(format!(
+                        "extension module depends on shared libraries installed on the filesystem: {}",
+                        filesystem_libraries.join(", ")
+                    )))
+                }
+            }
+            ConcreteResourceLocation::RelativePath(prefix) => {
+                if entry.in_memory_extension_module_shared_library.is_none() {
+                    return Ok(None);
+                }
+
+                if !constraints.extension_modules_filesystem {
+                    report.left_behind.push((
+                        name,
+                        "extension modules cannot be loaded from the filesystem by this binary"
+                            .to_string(),
+                    ));
+                    return Ok(None);
+                }
+
+                let suffix = match &constraints.extension_file_suffix {
+                    Some(suffix) => suffix,
+                    None => {
+                        report
+                            .left_behind
+                            .push((name, "extension module file suffix is unknown".to_string()));
+                        return Ok(None);
+                    }
+                };
+
+                let mut path = PathBuf::from(prefix);
+                let mut parts = name.split('.').collect::<Vec<_>>();
+                let leaf = parts.pop().unwrap();
+                path.extend(parts);
+                path.push(format!("{}{}", leaf, suffix));
+
+                let location = entry
+                    .in_memory_extension_module_shared_library
+                    .take()
+                    .unwrap();
+                entry.relative_path_extension_module_shared_library = Some((path, location));
+                report.record_move(
+                    &name,
+                    PayloadKind::ExtensionModule,
+                    ConcreteResourceLocation::InMemory.to_string(),
+                    to,
+                );
+
+                Ok(None)
+            }
+        }
+    }
+
+    /// Compiles resources into a finalized collection.
+    ///
+    /// This will take all resources collected so far and convert them into
+    /// a collection of `Resource` plus extra file install rules.
+    ///
+    /// Missing parent packages will be added automatically.
+    pub fn compile_resources(
+        &self,
+        compiler: &mut dyn PythonBytecodeCompiler,
+    ) -> Result<CompiledResourcesCollection> {
+        let pyc_invalidation_mode = self.compile_pyc_invalidation_mode(compiler)?;
+
+        compile_prepackaged_resources(
+            self.resources.clone(),
+            compiler,
+            pyc_invalidation_mode,
+            self.compile_error_policy,
+        )
+    }
+
+    /// Obtain the files relative path resources install for a set of enabled features.
+    ///
+    /// Like `compile_resources_for_features()`, missing parent packages are
+    /// added. Nothing is compiled. See `PrePackagedResource::relative_path_installs()`.
+    pub fn relative_path_installs_for_features(
+        &self,
+        enabled: &BTreeSet<String>,
+    ) -> Result<Vec<RelativePathInstall>> {
+        let mut resources = self.resources_for_features(enabled)?;
+        populate_parent_packages(&mut resources)?;
+
+        Ok(resources
+            .values()
+            .flat_map(|resource| resource.relative_path_installs())
+            .collect())
+    }
+
+    /// Compiles resources for a set of enabled features.
+    ///
+    /// This is like `compile_resources()` except resources are selected by
+    /// `resources_for_features()`.
+    pub fn compile_resources_for_features(
+        &self,
+        compiler: &mut dyn PythonBytecodeCompiler,
+        enabled: &BTreeSet<String>,
+    ) -> Result<CompiledResourcesCollection> {
+        let pyc_invalidation_mode = self.compile_pyc_invalidation_mode(compiler)?;
+
+        compile_prepackaged_resources(
+            self.resources_for_features(enabled)?,
+            compiler,
+            pyc_invalidation_mode,
+            self.compile_error_policy,
+        )
+    }
+}
+
+/// Compile a set of `PrePackagedResource` into a finalized collection.
+fn compile_prepackaged_resources<'a>(
+    mut input_resources: BTreeMap<String, PrePackagedResource>,
+    compiler: &mut dyn PythonBytecodeCompiler,
+    pyc_invalidation_mode: PycInvalidationMode,
+    compile_error_policy: CompileErrorPolicy,
+) -> Result<CompiledResourcesCollection<'a>> {
+    populate_parent_packages(&mut input_resources)?;
+
+    let mut resources = BTreeMap::new();
+    let mut extra_files = Vec::new();
+    let mut extra_file_owners = Vec::new();
+    let mut compile_errors = Vec::new();
+
+    for (name, resource) in &input_resources {
+        let (entry, installs) =
+            match resource.to_resource_with_pyc_invalidation(compiler, pyc_invalidation_mode) {
+                Ok(res) => res,
+                Err(e) => match (compile_error_policy, e.downcast_ref::<CompileError>()) {
+                    (CompileErrorPolicy::Skip, Some(error)) => {
+                        compile_errors.push(error.clone());
+                        continue;
+                    }
+                    (CompileErrorPolicy::SourceOnly, Some(error)) => {
+                        compile_errors.push(error.clone());
+                        resource
+                            .to_source_only()
+                            .to_resource_with_pyc_invalidation(compiler, pyc_invalidation_mode)?
+                    }
+                    _ => return Err(e),
+                },
+            };
+
+        for install in installs {
+            extra_file_owners.push(name.clone());
+            extra_files.push(install);
+        }
+
+        resources.insert(name.clone(), entry);
+    }
+
+    Ok(CompiledResourcesCollection {
+        resources,
+        extra_files,
+        extra_file_owners,
+        compile_errors,
+        interior_padding: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::bytecode::{magic_number_for_python_version, PycSourceHash, PycTimestamp},
+        crate::resource::{LibraryDependency, PythonPackageDistributionResourceFlavor},
+        std::convert::TryFrom,
+    };
+
+    const DEFAULT_CACHE_TAG: &str = "cpython-37";
+
+    /// A bytecode compiler emitting `bc<level>` followed by the source.
+    ///
+    /// It can be configured to behave like Python in ways tests care about
+    /// and records what it is asked to compile.
+    pub struct FakeBytecodeCompiler {
+        magic_number: u32,
+        reproducible: bool,
+        /// Whether to prefix output of .pyc modes with the header of the mode.
+        ///
+        /// The source hash of hash based headers is the length of the source.
+        pyc_headers: bool,
+        /// Whether to reject sources using Python 2 print statements.
+        python3: bool,
+        /// Output modes of each compiled source.
+        modes: Vec<CompileMode>,
+        /// Optimization levels of each `compile_levels()` call.
+        calls: Vec<Vec<BytecodeOptimizationLevel>>,
+    }
+
+    impl Default for FakeBytecodeCompiler {
+        fn default() -> Self {
+            Self {
+                magic_number: 42,
+                reproducible: false,
+                pyc_headers: false,
+                python3: false,
+                modes: vec![],
+                calls: vec![],
+            }
+        }
+    }
+
+    impl PythonBytecodeCompiler for FakeBytecodeCompiler {
+        fn get_magic_number(&self) -> u32 {
+            self.magic_number
+        }
+
+        fn is_reproducible(&self) -> bool {
+            self.reproducible
+        }
+
+        fn compile(
+            &mut self,
+            source: &[u8],
+            filename: &str,
+            optimize: BytecodeOptimizationLevel,
+            output_mode: CompileMode,
+        ) -> Result<Vec<u8>> {
+            self.modes.push(output_mode);
+
+            if self.python3 && source.starts_with(b"print '") {
+                return Err(CompileError::from_compiler_output(
+                    filename,
+                    &format!(
+                        "  File \"{}\", line 1\nSyntaxError: Missing parentheses in call to 'print'\n",
+                        filename
+                    ),
+                )
+                .into());
+            }
+
+            let source_hash = |hash| match hash {
+                PycSourceHash::Source => source.len() as u64,
+                PycSourceHash::Known(hash) => hash,
+            };
+
+            let mut res = match output_mode {
+                CompileMode::Bytecode => vec![],
+                _ if !self.pyc_headers => vec![],
+                CompileMode::PycCheckedHash(hash) => compute_bytecode_header(
+                    self.magic_number,
+                    BytecodeHeaderMode::CheckedHash(source_hash(hash)),
+                )?,
+                CompileMode::PycUncheckedHash(hash) => compute_bytecode_header(
+                    self.magic_number,
+                    BytecodeHeaderMode::UncheckedHash(source_hash(hash)),
+                )?,
+                CompileMode::PycTimestamp(timestamp) => compute_bytecode_header(
+                    self.magic_number,
+                    BytecodeHeaderMode::ModifiedTimeAndSourceSize((
+                        timestamp.header_value(),
+                        source.len() as u32,
+                    )),
+                )?,
+            };
+
+            res.extend(match optimize {
+                BytecodeOptimizationLevel::Zero => b"bc0",
+                BytecodeOptimizationLevel::One => b"bc1",
+                BytecodeOptimizationLevel::Two => b"bc2",
+            });
+
+            res.extend(source);
+
+            Ok(res)
+        }
+
+        fn compile_levels(
+            &mut self,
+            source: &[u8],
+            filename: &str,
+            optimize_levels: &[BytecodeOptimizationLevel],
+            output_mode: CompileMode,
+        ) -> Result<Vec<Vec<u8>>> {
+            self.calls.push(optimize_levels.to_vec());
+
+            optimize_levels
+                .iter()
+                .map(|level| self.compile(source, filename, *level, output_mode))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_resource_policy_from_str() -> Result<()> {
+        assert_eq!(
+            PythonResourcesPolicy::try_from("in-memory-only")?,
+            PythonResourcesPolicy::InMemoryOnly
+        );
+        assert_eq!(
+            PythonResourcesPolicy::try_from("filesystem-relative-only:lib")?,
+            PythonResourcesPolicy::FilesystemRelativeOnly("lib".to_string())
+        );
+        assert_eq!(
+            PythonResourcesPolicy::try_from("prefer-in-memory-fallback-filesystem-relative:lib")?,
+            PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("lib".to_string())
+        );
+        assert_eq!(
+            PythonResourcesPolicy::try_from("foo")
+                .unwrap_err()
+                .to_string(),
+            "invalid value for Python Resources Policy: foo"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_basic() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            is_package: true,
+            is_namespace_package: true,
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                is_package: true,
+                is_namespace_package: true,
+                ..Resource::default()
+            }
+        );
+
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_source: Some(DataLocation::Memory(b"source".to_vec())),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_source: Some(Cow::Owned(b"source".to_vec())),
+                ..Resource::default()
+            }
+        );
+
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_bytecode_provided() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_bytecode: Some(PythonModuleBytecodeProvider::Provided(
+                DataLocation::Memory(b"bytecode".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_bytecode: Some(Cow::Owned(b"bytecode".to_vec())),
+                ..Resource::default()
+            }
+        );
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_bytecode_from_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
+                DataLocation::Memory(b"source".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_bytecode: Some(Cow::Owned(b"bc0source".to_vec())),
+                ..Resource::default()
+            }
+        );
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_bytecode_opt1_provided() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_bytecode_opt1: Some(PythonModuleBytecodeProvider::Provided(
+                DataLocation::Memory(b"bytecode".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_bytecode_opt1: Some(Cow::Owned(b"bytecode".to_vec())),
+                ..Resource::default()
+            }
+        );
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_bytecode_opt1_from_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_bytecode_opt1: Some(PythonModuleBytecodeProvider::FromSource(
+                DataLocation::Memory(b"source".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1source".to_vec())),
+                ..Resource::default()
+            }
+        );
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_bytecode_opt2_provided() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_bytecode_opt2: Some(PythonModuleBytecodeProvider::Provided(
+                DataLocation::Memory(b"bytecode".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_bytecode_opt2: Some(Cow::Owned(b"bytecode".to_vec())),
+                ..Resource::default()
+            }
+        );
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_bytecode_opt2_from_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_bytecode_opt2: Some(PythonModuleBytecodeProvider::FromSource(
+                DataLocation::Memory(b"source".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_bytecode_opt2: Some(Cow::Owned(b"bc2source".to_vec())),
+                ..Resource::default()
+            }
+        );
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_extension_module_shared_library() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_extension_module_shared_library: Some(DataLocation::Memory(
+                b"library".to_vec(),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_extension_module_shared_library: Some(Cow::Owned(b"library".to_vec())),
+                ..Resource::default()
+            }
+        );
+
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_package_resources() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let mut resources = BTreeMap::new();
+        resources.insert("foo".to_string(), DataLocation::Memory(b"value".to_vec()));
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_resources: Some(resources),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        let mut resources = HashMap::new();
+        resources.insert(Cow::Owned("foo".to_string()), Cow::Owned(b"value".to_vec()));
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_package_resources: Some(resources),
+                ..Resource::default()
+            }
+        );
+
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_distribution_resources() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let mut resources = BTreeMap::new();
+        resources.insert("foo".to_string(), DataLocation::Memory(b"value".to_vec()));
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_distribution_resources: Some(resources),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        let mut resources = HashMap::new();
+        resources.insert(Cow::Owned("foo".to_string()), Cow::Owned(b"value".to_vec()));
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_distribution_resources: Some(resources),
+                ..Resource::default()
+            }
+        );
+
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_in_memory_shared_library() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            in_memory_shared_library: Some(DataLocation::Memory(b"library".to_vec())),
+            shared_library_dependency_names: Some(vec!["foo".to_string(), "bar".to_string()]),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                in_memory_shared_library: Some(Cow::Owned(b"library".to_vec())),
+                shared_library_dependency_names: Some(vec![
+                    Cow::Owned("foo".to_string()),
+                    Cow::Owned("bar".to_string())
+                ]),
+                ..Resource::default()
+            }
+        );
+
+        assert!(installs.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_module_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            relative_path_module_source: Some((
+                "prefix".to_string(),
+                DataLocation::Memory(b"source".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                relative_path_module_source: Some(Cow::Owned(PathBuf::from("prefix/module.py"))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/module.py"),
+                DataLocation::Memory(b"source".to_vec()),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_module_bytecode_provided() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "foo.bar".to_string(),
+            relative_path_bytecode: Some((
+                "prefix".to_string(),
+                "tag".to_string(),
+                PythonModuleBytecodeProvider::Provided(DataLocation::Memory(b"bytecode".to_vec())),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo.bar".to_string()),
+                relative_path_module_bytecode: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/__pycache__/bar.tag.pyc"
+                ))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/foo/__pycache__/bar.tag.pyc"),
+                DataLocation::Memory(
+                    b"\x2a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00bytecode"
+                        .to_vec()
+                ),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_module_bytecode_from_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "foo.bar".to_string(),
+            relative_path_bytecode: Some((
+                "prefix".to_string(),
+                "tag".to_string(),
+                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(b"source".to_vec())),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo.bar".to_string()),
+                relative_path_module_bytecode: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/__pycache__/bar.tag.pyc"
+                ))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/foo/__pycache__/bar.tag.pyc"),
+                DataLocation::Memory(b"bc0source".to_vec()),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_pyc_invalidation() -> Result<()> {
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "foo".to_string(),
+            relative_path_bytecode: Some((
+                "prefix".to_string(),
+                "tag".to_string(),
+                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(b"source".to_vec())),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let mut compiler = FakeBytecodeCompiler::default();
+        pre.to_resource(&mut compiler)?;
+        pre.to_resource_with_pyc_invalidation(&mut compiler, PycInvalidationMode::CheckedHash)?;
+        // In-memory sources have no modified time.
+        pre.to_resource_with_pyc_invalidation(
+            &mut compiler,
+            PycInvalidationMode::Timestamp(PycTimestamp::Source),
+        )?;
+
+        assert_eq!(
+            compiler.modes,
+            vec![
+                CompileMode::PycUncheckedHash(PycSourceHash::Source),
+                CompileMode::PycCheckedHash(PycSourceHash::Source),
+                CompileMode::PycTimestamp(PycTimestamp::Source),
+            ]
+        );
+
+        let mut collector = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
+            "tag",
+        );
+        collector.set_pyc_invalidation_mode(PycInvalidationMode::CheckedHash);
+        collector.resources.insert("foo".to_string(), pre);
+
+        let mut compiler = FakeBytecodeCompiler::default();
+        collector.compile_resources(&mut compiler)?;
+        assert_eq!(
+            compiler.modes,
+            vec![CompileMode::PycCheckedHash(PycSourceHash::Source)]
+        );
+
+        // Reproducible collections need reproducible compilers and don't
+        // record source modified times.
+        collector.set_pyc_invalidation_mode(PycInvalidationMode::Timestamp(PycTimestamp::Source));
+        collector.set_reproducible(true);
+        assert_eq!(
+            collector
+                .compile_resources(&mut FakeBytecodeCompiler::default())
+                .unwrap_err()
+                .to_string(),
+            "reproducible builds require a reproducible bytecode compiler"
+        );
+
+        let mut compiler = FakeBytecodeCompiler {
+            reproducible: true,
+            ..FakeBytecodeCompiler::default()
+        };
+        collector.compile_resources(&mut compiler)?;
+        assert_eq!(
+            compiler.modes,
+            vec![CompileMode::PycTimestamp(PycTimestamp::reproducible()?)]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_module_bytecode_opt1_provided() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "foo.bar".to_string(),
+            relative_path_bytecode_opt1: Some((
+                "prefix".to_string(),
+                "tag".to_string(),
+                PythonModuleBytecodeProvider::Provided(DataLocation::Memory(b"bytecode".to_vec())),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo.bar".to_string()),
+                relative_path_module_bytecode_opt1: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/__pycache__/bar.tag.opt-1.pyc"
+                ))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-1.pyc"),
+                DataLocation::Memory(
+                    b"\x2a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00bytecode"
+                        .to_vec()
+                ),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_module_bytecode_opt1_from_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "foo.bar".to_string(),
+            relative_path_bytecode_opt1: Some((
+                "prefix".to_string(),
+                "tag".to_string(),
+                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(b"source".to_vec())),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo.bar".to_string()),
+                relative_path_module_bytecode_opt1: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/__pycache__/bar.tag.opt-1.pyc"
+                ))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-1.pyc"),
+                DataLocation::Memory(b"bc1source".to_vec()),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_module_bytecode_opt2_provided() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "foo.bar".to_string(),
+            relative_path_bytecode_opt2: Some((
+                "prefix".to_string(),
+                "tag".to_string(),
+                PythonModuleBytecodeProvider::Provided(DataLocation::Memory(b"bytecode".to_vec())),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo.bar".to_string()),
+                relative_path_module_bytecode_opt2: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/__pycache__/bar.tag.opt-2.pyc"
+                ))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-2.pyc"),
+                DataLocation::Memory(
+                    b"\x2a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00bytecode"
+                        .to_vec()
+                ),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_module_bytecode_opt2_from_source() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "foo.bar".to_string(),
+            relative_path_bytecode_opt2: Some((
+                "prefix".to_string(),
+                "tag".to_string(),
+                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(b"source".to_vec())),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo.bar".to_string()),
+                relative_path_module_bytecode_opt2: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/__pycache__/bar.tag.opt-2.pyc"
+                ))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-2.pyc"),
+                DataLocation::Memory(b"bc2source".to_vec()),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_extension_module_shared_library() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            relative_path_extension_module_shared_library: Some((
+                PathBuf::from("prefix/ext.so"),
+                DataLocation::Memory(b"data".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                relative_path_extension_module_shared_library: Some(Cow::Owned(PathBuf::from(
+                    "prefix/ext.so"
+                ))),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/ext.so"),
+                DataLocation::Memory(b"data".to_vec()),
+                true
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_package_resources() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "foo.txt".to_string(),
+            (
+                PathBuf::from("module/foo.txt"),
+                DataLocation::Memory(b"data".to_vec()),
+            ),
+        );
+        resources.insert(
+            "bar.txt".to_string(),
+            (
+                PathBuf::from("module/bar.txt"),
+                DataLocation::Memory(b"bar".to_vec()),
+            ),
+        );
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            is_package: true,
+            relative_path_package_resources: Some(resources),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        let mut resources = HashMap::new();
+        resources.insert(
+            Cow::Owned("foo.txt".to_string()),
+            Cow::Owned(PathBuf::from("module/foo.txt")),
+        );
+        resources.insert(
+            Cow::Owned("bar.txt".to_string()),
+            Cow::Owned(PathBuf::from("module/bar.txt")),
+        );
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                is_package: true,
+                relative_path_package_resources: Some(resources),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![
+                (
+                    PathBuf::from("module/bar.txt"),
+                    DataLocation::Memory(b"bar".to_vec()),
+                    false
+                ),
+                (
+                    PathBuf::from("module/foo.txt"),
+                    DataLocation::Memory(b"data".to_vec()),
+                    false
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_distribution_resources() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let mut resources = BTreeMap::new();
+        resources.insert(
+            "foo.txt".to_string(),
+            (
+                PathBuf::from("foo.txt"),
+                DataLocation::Memory(b"data".to_vec()),
+            ),
+        );
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "module".to_string(),
+            relative_path_distribution_resources: Some(resources),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        let mut resources = HashMap::new();
+        resources.insert(
+            Cow::Owned("foo.txt".to_string()),
+            Cow::Owned(PathBuf::from("foo.txt")),
+        );
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("module".to_string()),
+                relative_path_distribution_resources: Some(resources),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("foo.txt"),
+                DataLocation::Memory(b"data".to_vec()),
+                false
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resource_conversion_relative_path_shared_library() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let pre = PrePackagedResource {
+            flavor: ResourceFlavor::SharedLibrary,
+            name: "libfoo".to_string(),
+            relative_path_shared_library: Some((
+                "prefix".to_string(),
+                DataLocation::Memory(b"data".to_vec()),
+            )),
+            ..PrePackagedResource::default()
+        };
+
+        let (resource, installs) = pre.to_resource(&mut compiler)?;
+
+        assert_eq!(
+            resource,
+            Resource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: Cow::Owned("libfoo".to_string()),
+                ..Resource::default()
+            }
+        );
+
+        assert_eq!(
+            installs,
+            vec![(
+                PathBuf::from("prefix/libfoo"),
+                DataLocation::Memory(b"data".to_vec()),
+                true
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_populate_parent_packages_in_memory_source() -> Result<()> {
+        let mut h = BTreeMap::new();
+        h.insert(
+            "root.parent.child".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root.parent.child".to_string(),
+                in_memory_source: Some(DataLocation::Memory(vec![42])),
+                is_package: true,
+                ..PrePackagedResource::default()
+            },
+        );
+
+        populate_parent_packages(&mut h)?;
+
+        assert_eq!(h.len(), 3);
+        assert_eq!(
+            h.get("root.parent"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root.parent".to_string(),
+                is_package: true,
+                in_memory_source: Some(DataLocation::Memory(vec![])),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            h.get("root"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root".to_string(),
+                is_package: true,
+                in_memory_source: Some(DataLocation::Memory(vec![])),
+                ..PrePackagedResource::default()
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_populate_parent_packages_relative_path_source() -> Result<()> {
+        let mut h = BTreeMap::new();
+        h.insert(
+            "root.parent.child".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root.parent.child".to_string(),
+                relative_path_module_source: Some((
+                    "prefix".to_string(),
+                    DataLocation::Memory(vec![42]),
+                )),
+                is_package: true,
+                ..PrePackagedResource::default()
+            },
+        );
+
+        populate_parent_packages(&mut h)?;
+
+        assert_eq!(h.len(), 3);
+        assert_eq!(
+            h.get("root.parent"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root.parent".to_string(),
+                is_package: true,
+                relative_path_module_source: Some((
+                    "prefix".to_string(),
+                    DataLocation::Memory(vec![])
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            h.get("root"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root".to_string(),
+                is_package: true,
+                relative_path_module_source: Some((
+                    "prefix".to_string(),
+                    DataLocation::Memory(vec![])
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_populate_parent_packages_in_memory_bytecode() -> Result<()> {
+        let mut h = BTreeMap::new();
+        h.insert(
+            "root.parent.child".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root.parent.child".to_string(),
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
+                    DataLocation::Memory(vec![42]),
+                )),
+                is_package: true,
+                ..PrePackagedResource::default()
+            },
+        );
+
+        populate_parent_packages(&mut h)?;
+
+        assert_eq!(h.len(), 3);
+        assert_eq!(
+            h.get("root.parent"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root.parent".to_string(),
+                is_package: true,
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
+                    DataLocation::Memory(vec![])
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            h.get("root"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root".to_string(),
+                is_package: true,
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
+                    DataLocation::Memory(vec![])
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_populate_parent_packages_distribution_extension_module() -> Result<()> {
+        let mut h = BTreeMap::new();
+        h.insert(
+            "foo.bar".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::BuiltinExtensionModule,
+                name: "foo.bar".to_string(),
+                relative_path_extension_module_shared_library: Some((
+                    PathBuf::from("prefix/foo/bar.so"),
+                    DataLocation::Memory(vec![42]),
+                )),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        populate_parent_packages(&mut h)?;
+
+        assert_eq!(
+            h.get("foo"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo".to_string(),
+                is_package: true,
+                ..PrePackagedResource::default()
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_populate_parent_packages_relative_extension_module() -> Result<()> {
+        let mut h = BTreeMap::new();
+        h.insert(
+            "foo.bar".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: "foo.bar".to_string(),
+                relative_path_extension_module_shared_library: Some((
+                    PathBuf::from("prefix/foo/bar.so"),
+                    DataLocation::Memory(vec![42]),
+                )),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        populate_parent_packages(&mut h)?;
+
+        assert_eq!(h.len(), 2);
+
+        assert_eq!(
+            h.get("foo"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo".to_string(),
+                is_package: true,
+                ..PrePackagedResource::default()
+            })
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_module_package_collision() -> Result<()> {
+        let module = PythonModuleSource {
+            name: "foo".to_string(),
+            source: DataLocation::from_path(PathBuf::from("/site/foo.py")),
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let package = PythonModuleSource {
+            name: "foo".to_string(),
+            source: DataLocation::from_path(PathBuf::from("/site/foo/__init__.py")),
+            is_package: true,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let resource = PythonPackageResource {
+            leaf_package: "foo".to_string(),
+            relative_name: "data.txt".to_string(),
+            data: DataLocation::Memory(vec![42]),
+            is_stdlib: false,
+            is_test: false,
+        };
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(&module, &ConcreteResourceLocation::InMemory)?;
+        r.add_python_module_source(&package, &ConcreteResourceLocation::InMemory)?;
+        r.add_python_package_resource(&resource, &ConcreteResourceLocation::InMemory)?;
+
+        let entry = r.resources.get("foo").unwrap();
+        assert!(entry.is_package);
+        assert_eq!(
+            entry.in_memory_source,
+            Some(DataLocation::from_path(PathBuf::from(
+                "/site/foo/__init__.py"
+            )))
+        );
+        assert!(entry.in_memory_resources.is_some());
+        assert_eq!(
+            r.warnings(),
+            &["foo is both a module (module from /site/foo.py) and a package (module from /site/foo/__init__.py); keeping the package".to_string()]
+        );
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_module_package_collision(ModulePackageCollisionPolicy::PreferModule);
+        r.add_python_module_source(&package, &ConcreteResourceLocation::InMemory)?;
+        r.add_python_package_resource(&resource, &ConcreteResourceLocation::InMemory)?;
+        r.add_python_module_source(&module, &ConcreteResourceLocation::InMemory)?;
+        r.add_python_package_resource(&resource, &ConcreteResourceLocation::InMemory)?;
+
+        let entry = r.resources.get("foo").unwrap();
+        assert!(!entry.is_package);
+        assert_eq!(
+            entry.in_memory_source,
+            Some(DataLocation::from_path(PathBuf::from("/site/foo.py")))
+        );
+        assert!(entry.in_memory_resources.is_none());
+        assert_eq!(r.warnings().len(), 1);
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_module_package_collision(ModulePackageCollisionPolicy::Error);
+        r.add_python_module_source(&module, &ConcreteResourceLocation::InMemory)?;
+        let err = r
+            .add_python_module_source(&package, &ConcreteResourceLocation::InMemory)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "foo is both a module (module from /site/foo.py) and a package (module from /site/foo/__init__.py)"
+        );
+
+        // Adding the same kind again is not a collision.
+        r.add_python_module_source(&module, &ConcreteResourceLocation::InMemory)?;
+        assert!(r.warnings().is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_collision() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("foo.py");
+        std::fs::write(&path, b"import os")?;
+
+        let module = |source| PythonModuleSource {
+            name: "foo".to_string(),
+            source,
+            is_package: true,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let resource = |data| PythonPackageResource {
+            leaf_package: "foo".to_string(),
+            relative_name: "data.txt".to_string(),
+            data,
+            is_stdlib: false,
+            is_test: false,
+        };
+        let location = ConcreteResourceLocation::InMemory;
+
+        // The same file added from the filesystem and from memory is kept once.
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_add_collision(CollisionResolution::ContentEqualOk);
+        r.add_python_module_source(&module(DataLocation::from_path(&path)), &location)?;
+        r.add_python_module_source(
+            &module(DataLocation::Memory(b"import os".to_vec())),
+            &location,
+        )?;
+        r.add_python_package_resource(&resource(DataLocation::from_path(&path)), &location)?;
+        r.add_python_package_resource(
+            &resource(DataLocation::Memory(b"import os".to_vec())),
+            &location,
+        )?;
+
+        let entry = r.resources.get("foo").unwrap();
+        assert_eq!(entry.in_memory_source, Some(DataLocation::from_path(&path)));
+        assert_eq!(
+            entry.in_memory_resources.as_ref().unwrap()["data.txt"],
+            DataLocation::from_path(&path)
+        );
+
+        // Different content still collides.
+        let err = r
+            .add_python_module_source(
+                &module(DataLocation::Memory(b"import re".to_vec())),
+                &location,
+            )
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "source of foo from memory collides with one added from {}",
+                path.display()
+            )
+        );
+        assert!(r
+            .add_python_package_resource(
+                &resource(DataLocation::Memory(b"import re".to_vec())),
+                &location
+            )
+            .is_err());
+
+        // By default, payloads added later replace earlier ones.
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(&module(DataLocation::from_path(&path)), &location)?;
+        r.add_python_module_source(
+            &module(DataLocation::Memory(b"import re".to_vec())),
+            &location,
+        )?;
+        assert_eq!(
+            r.resources.get("foo").unwrap().in_memory_source,
+            Some(DataLocation::Memory(b"import re".to_vec()))
+        );
+
+        Ok(())
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use {
-        super::*,
-        crate::resource::{LibraryDependency, PythonPackageDistributionResourceFlavor},
-        std::convert::TryFrom,
-    };
 
-    const DEFAULT_CACHE_TAG: &str = "cpython-37";
+    #[test]
+    fn test_reserved_names() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
 
-    pub struct FakeBytecodeCompiler {
-        magic_number: u32,
-    }
+        r.reserve_name("synthetic", "feature A", SyntheticVisibility::Public)?;
+        r.reserve_name("synthetic", "feature A", SyntheticVisibility::Public)?;
+        assert!(r
+            .reserve_name("synthetic", "feature B", SyntheticVisibility::Public)
+            .is_err());
+        assert_eq!(
+            r.reserved_names().get("synthetic"),
+            Some(&ReservedName {
+                feature: "feature A".to_string(),
+                visibility: SyntheticVisibility::Public,
+            })
+        );
+        assert!(!r.is_internal("synthetic"));
 
-    impl PythonBytecodeCompiler for FakeBytecodeCompiler {
-        fn get_magic_number(&self) -> u32 {
-            self.magic_number
-        }
+        let module = PythonModuleSource {
+            name: "synthetic".to_string(),
+            source: DataLocation::from_path(PathBuf::from("/src/synthetic.py")),
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
+        };
+        let err = r
+            .add_python_module_source(&module, &ConcreteResourceLocation::InMemory)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "synthetic (module source from /src/synthetic.py) collides with a name reserved by feature A"
+        );
 
-        fn compile(
-            &mut self,
-            source: &[u8],
-            _filename: &str,
-            optimize: BytecodeOptimizationLevel,
-            _output_mode: CompileMode,
-        ) -> Result<Vec<u8>> {
-            let mut res = Vec::new();
+        let err = r
+            .add_python_package_resource(
+                &PythonPackageResource {
+                    leaf_package: "synthetic".to_string(),
+                    relative_name: "data.txt".to_string(),
+                    data: DataLocation::Memory(vec![42]),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("package resource synthetic:data.txt"));
+
+        assert!(r
+            .add_prepackaged_resource(PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "synthetic".to_string(),
+                ..PrePackagedResource::default()
+            })
+            .is_err());
+        assert!(!r.resources.contains_key("synthetic"));
 
-            res.extend(match optimize {
-                BytecodeOptimizationLevel::Zero => b"bc0",
-                BytecodeOptimizationLevel::One => b"bc1",
-                BytecodeOptimizationLevel::Two => b"bc2",
-            });
+        let synthetic = PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: "synthetic".to_string(),
+            in_memory_source: Some(DataLocation::Memory(vec![42])),
+            ..PrePackagedResource::default()
+        };
+        assert!(r
+            .add_synthetic_resource("feature B", synthetic.clone())
+            .is_err());
+        r.add_synthetic_resource("feature A", synthetic)?;
+        assert!(r.resources.contains_key("synthetic"));
+
+        let unreserved = PrePackagedResource {
+            name: "unreserved".to_string(),
+            ..PrePackagedResource::default()
+        };
+        assert!(r.add_synthetic_resource("feature A", unreserved).is_err());
 
-            res.extend(source);
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "user".to_string(),
+                ..module
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        let err = r
+            .reserve_name("user", "feature C", SyntheticVisibility::Public)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "user (module from /src/synthetic.py) collides with a name reserved by feature C"
+        );
 
-            Ok(res)
-        }
+        Ok(())
     }
 
     #[test]
-    fn test_resource_policy_from_str() -> Result<()> {
-        assert_eq!(
-            PythonResourcesPolicy::try_from("in-memory-only")?,
-            PythonResourcesPolicy::InMemoryOnly
-        );
-        assert_eq!(
-            PythonResourcesPolicy::try_from("filesystem-relative-only:lib")?,
-            PythonResourcesPolicy::FilesystemRelativeOnly("lib".to_string())
-        );
+    fn test_add_in_memory_source_module() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(vec![42]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+
+        assert!(r.resources.contains_key("foo"));
         assert_eq!(
-            PythonResourcesPolicy::try_from("prefer-in-memory-fallback-filesystem-relative:lib")?,
-            PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("lib".to_string())
+            r.resources.get("foo"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo".to_string(),
+                is_package: false,
+                in_memory_source: Some(DataLocation::Memory(vec![42])),
+                ..PrePackagedResource::default()
+            })
         );
+
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let resources = r.compile_resources(&mut compiler)?;
+
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            PythonResourcesPolicy::try_from("foo")
-                .unwrap_err()
-                .to_string(),
-            "invalid value for Python Resources Policy: foo"
+            resources.resources.get("foo"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo".to_string()),
+                in_memory_source: Some(Cow::Owned(vec![42])),
+                ..Resource::default()
+            })
         );
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_basic() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_add_in_memory_source_module_parents() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "root.parent.child".to_string(),
+                source: DataLocation::Memory(vec![42]),
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            is_package: true,
-            is_namespace_package: true,
-            ..PrePackagedResource::default()
-        };
+        assert_eq!(r.resources.len(), 1);
+        assert_eq!(
+            r.resources.get("root.parent.child"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "root.parent.child".to_string(),
+                is_package: true,
+                in_memory_source: Some(DataLocation::Memory(vec![42])),
+                ..PrePackagedResource::default()
+            })
+        );
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let resources = r.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 3);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("root"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
+                name: Cow::Owned("root".to_string()),
                 is_package: true,
-                is_namespace_package: true,
+                in_memory_source: Some(Cow::Owned(vec![])),
                 ..Resource::default()
-            }
+            })
+        );
+        assert_eq!(
+            resources.resources.get("root.parent"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("root.parent".to_string()),
+                is_package: true,
+                in_memory_source: Some(Cow::Owned(vec![])),
+                ..Resource::default()
+            })
+        );
+        assert_eq!(
+            resources.resources.get("root.parent.child"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("root.parent.child".to_string()),
+                is_package: true,
+                in_memory_source: Some(Cow::Owned(vec![42])),
+                ..Resource::default()
+            })
         );
 
-        assert!(installs.is_empty());
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_add_relative_path_source_module() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo.bar".to_string(),
+                source: DataLocation::Memory(vec![42]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_source: Some(DataLocation::Memory(b"source".to_vec())),
-            ..PrePackagedResource::default()
-        };
+        assert_eq!(r.resources.len(), 1);
+        assert_eq!(
+            r.resources.get("foo.bar"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo.bar".to_string(),
+                is_package: false,
+                relative_path_module_source: Some((
+                    "prefix".to_string(),
+                    DataLocation::Memory(vec![42])
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let mut compiler = FakeBytecodeCompiler::default();
 
+        let resources = r.compile_resources(&mut compiler)?;
+
+        assert_eq!(resources.resources.len(), 2);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("foo"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_source: Some(Cow::Owned(b"source".to_vec())),
+                name: Cow::Owned("foo".to_string()),
+                is_package: true,
+                relative_path_module_source: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/__init__.py"
+                ))),
                 ..Resource::default()
-            }
+            })
+        );
+        assert_eq!(
+            resources.resources.get("foo.bar"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("foo.bar".to_string()),
+                relative_path_module_source: Some(Cow::Owned(PathBuf::from("prefix/foo/bar.py"))),
+                ..Resource::default()
+            })
+        );
+        assert_eq!(
+            resources.extra_files,
+            vec![
+                (
+                    PathBuf::from("prefix/foo/__init__.py"),
+                    DataLocation::Memory(vec![]),
+                    false
+                ),
+                (
+                    PathBuf::from("prefix/foo/bar.py"),
+                    DataLocation::Memory(vec![42]),
+                    false
+                )
+            ]
+        );
+        assert_eq!(
+            resources.extra_file_owners,
+            vec!["foo".to_string(), "foo.bar".to_string()]
         );
-
-        assert!(installs.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_bytecode_provided() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_bytecode: Some(PythonModuleBytecodeProvider::Provided(
-                DataLocation::Memory(b"bytecode".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
-
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+    fn test_add_in_memory_bytecode_module() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_bytecode(
+            &PythonModuleBytecode::new(
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                false,
+                DEFAULT_CACHE_TAG,
+                &[42],
+            ),
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
+        assert!(r.resources.contains_key("foo"));
         assert_eq!(
-            resource,
-            Resource {
+            r.resources.get("foo"),
+            Some(&PrePackagedResource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_bytecode: Some(Cow::Owned(b"bytecode".to_vec())),
-                ..Resource::default()
-            }
+                name: "foo".to_string(),
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::Provided(
+                    DataLocation::Memory(vec![42])
+                )),
+                is_package: false,
+                ..PrePackagedResource::default()
+            })
         );
-        assert!(installs.is_empty());
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_resource_conversion_in_memory_bytecode_from_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
-                DataLocation::Memory(b"source".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let resources = r.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("foo"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_bytecode: Some(Cow::Owned(b"bc0source".to_vec())),
+                name: Cow::Owned("foo".to_string()),
+                in_memory_bytecode: Some(Cow::Owned(vec![42])),
                 ..Resource::default()
-            }
+            })
         );
-        assert!(installs.is_empty());
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_bytecode_opt1_provided() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_bytecode_opt1: Some(PythonModuleBytecodeProvider::Provided(
-                DataLocation::Memory(b"bytecode".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
-
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+    fn test_add_in_memory_bytecode_module_from_source() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(vec![42]),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
+        assert!(r.resources.contains_key("foo"));
         assert_eq!(
-            resource,
-            Resource {
+            r.resources.get("foo"),
+            Some(&PrePackagedResource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_bytecode_opt1: Some(Cow::Owned(b"bytecode".to_vec())),
-                ..Resource::default()
-            }
+                name: "foo".to_string(),
+                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
+                    DataLocation::Memory(vec![42])
+                )),
+                is_package: false,
+                ..PrePackagedResource::default()
+            })
         );
-        assert!(installs.is_empty());
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_resource_conversion_in_memory_bytecode_opt1_from_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_bytecode_opt1: Some(PythonModuleBytecodeProvider::FromSource(
-                DataLocation::Memory(b"source".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let resources = r.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("foo"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1source".to_vec())),
+                name: Cow::Owned("foo".to_string()),
+                in_memory_bytecode: Some(Cow::Owned(b"bc0\x2a".to_vec())),
                 ..Resource::default()
-            }
+            })
         );
-        assert!(installs.is_empty());
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_bytecode_opt2_provided() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_bytecode_opt2: Some(PythonModuleBytecodeProvider::Provided(
-                DataLocation::Memory(b"bytecode".to_vec()),
-            )),
-            ..PrePackagedResource::default()
+    fn test_add_bytecode_all_levels_from_source() -> Result<()> {
+        let source = PythonModuleSource {
+            name: "foo".to_string(),
+            source: DataLocation::Memory(b"import os".to_vec()),
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
         };
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let modules = source.as_bytecode_modules(&[
+            BytecodeOptimizationLevel::Zero,
+            BytecodeOptimizationLevel::One,
+            BytecodeOptimizationLevel::Two,
+            BytecodeOptimizationLevel::One,
+        ]);
+        assert_eq!(modules.len(), 3);
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        for module in &modules {
+            r.add_python_module_bytecode_from_source(module, &ConcreteResourceLocation::InMemory)?;
+        }
 
+        let mut compiler = FakeBytecodeCompiler::default();
+        let resources = r.compile_resources(&mut compiler)?;
+
+        // All levels are compiled from a single request.
         assert_eq!(
-            resource,
-            Resource {
+            compiler.calls,
+            vec![vec![
+                BytecodeOptimizationLevel::Zero,
+                BytecodeOptimizationLevel::One,
+                BytecodeOptimizationLevel::Two
+            ]]
+        );
+        assert_eq!(
+            resources.resources.get("foo"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_bytecode_opt2: Some(Cow::Owned(b"bytecode".to_vec())),
+                name: Cow::Owned("foo".to_string()),
+                in_memory_bytecode: Some(Cow::Owned(b"bc0import os".to_vec())),
+                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1import os".to_vec())),
+                in_memory_bytecode_opt2: Some(Cow::Owned(b"bc2import os".to_vec())),
                 ..Resource::default()
-            }
+            })
         );
-        assert!(installs.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_bytecode_opt2_from_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_bytecode_opt2: Some(PythonModuleBytecodeProvider::FromSource(
-                DataLocation::Memory(b"source".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
-
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+    fn test_add_in_memory_bytecode_module_parents() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "root.parent.child".to_string(),
+                source: DataLocation::Memory(vec![42]),
+                optimize_level: BytecodeOptimizationLevel::One,
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
+        assert_eq!(r.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
+            r.resources.get("root.parent.child"),
+            Some(&PrePackagedResource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_bytecode_opt2: Some(Cow::Owned(b"bc2source".to_vec())),
-                ..Resource::default()
-            }
+                name: "root.parent.child".to_string(),
+                in_memory_bytecode_opt1: Some(PythonModuleBytecodeProvider::FromSource(
+                    DataLocation::Memory(vec![42])
+                )),
+                is_package: true,
+                ..PrePackagedResource::default()
+            })
         );
-        assert!(installs.is_empty());
-
-        Ok(())
-    }
-
-    #[test]
-    fn test_resource_conversion_in_memory_extension_module_shared_library() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_extension_module_shared_library: Some(DataLocation::Memory(
-                b"library".to_vec(),
-            )),
-            ..PrePackagedResource::default()
-        };
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let resources = r.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 3);
+        assert_eq!(
+            resources.resources.get("root"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("root".to_string()),
+                is_package: true,
+                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1".to_vec())),
+                ..Resource::default()
+            })
+        );
+        assert_eq!(
+            resources.resources.get("root.parent"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Owned("root.parent".to_string()),
+                is_package: true,
+                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1".to_vec())),
+                ..Resource::default()
+            })
+        );
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("root.parent.child"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_extension_module_shared_library: Some(Cow::Owned(b"library".to_vec())),
+                name: Cow::Owned("root.parent.child".to_string()),
+                is_package: true,
+                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1\x2a".to_vec())),
                 ..Resource::default()
-            }
+            })
         );
-
-        assert!(installs.is_empty());
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_package_resources() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let mut resources = BTreeMap::new();
-        resources.insert("foo".to_string(), DataLocation::Memory(b"value".to_vec()));
+    fn test_add_in_memory_package_resource() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "resource.txt".to_string(),
+                data: DataLocation::Memory(vec![42]),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_resources: Some(resources),
-            ..PrePackagedResource::default()
-        };
+        assert_eq!(r.resources.len(), 1);
+        assert_eq!(
+            r.resources.get("foo"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo".to_string(),
+                is_package: true,
+                in_memory_resources: Some(BTreeMap::from_iter(
+                    [("resource.txt".to_string(), DataLocation::Memory(vec![42]))]
+                        .iter()
+                        .cloned()
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        let mut resources = HashMap::new();
-        resources.insert(Cow::Owned("foo".to_string()), Cow::Owned(b"value".to_vec()));
+        let resources = r.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("foo"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_package_resources: Some(resources),
+                name: Cow::Owned("foo".to_string()),
+                is_package: true,
+                in_memory_package_resources: Some(HashMap::from_iter(
+                    [(Cow::Owned("resource.txt".to_string()), Cow::Owned(vec![42]))]
+                        .iter()
+                        .cloned()
+                )),
                 ..Resource::default()
-            }
+            })
         );
-
-        assert!(installs.is_empty());
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_distribution_resources() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let mut resources = BTreeMap::new();
-        resources.insert("foo".to_string(), DataLocation::Memory(b"value".to_vec()));
+    fn test_add_relative_path_package_resource() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "resource.txt".to_string(),
+                data: DataLocation::Memory(vec![42]),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_distribution_resources: Some(resources),
-            ..PrePackagedResource::default()
-        };
+        assert_eq!(r.resources.len(), 1);
+        assert_eq!(
+            r.resources.get("foo"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "foo".to_string(),
+                is_package: true,
+                relative_path_package_resources: Some(BTreeMap::from_iter(
+                    [(
+                        "resource.txt".to_string(),
+                        (
+                            PathBuf::from("prefix/foo/resource.txt"),
+                            DataLocation::Memory(vec![42])
+                        )
+                    )]
+                    .iter()
+                    .cloned()
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        let mut resources = HashMap::new();
-        resources.insert(Cow::Owned("foo".to_string()), Cow::Owned(b"value".to_vec()));
+        let resources = r.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("foo"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_distribution_resources: Some(resources),
+                name: Cow::Owned("foo".to_string()),
+                is_package: true,
+                relative_path_package_resources: Some(HashMap::from_iter(
+                    [(
+                        Cow::Owned("resource.txt".to_string()),
+                        Cow::Owned(PathBuf::from("prefix/foo/resource.txt")),
+                    )]
+                    .iter()
+                    .cloned()
+                )),
                 ..Resource::default()
-            }
+            })
+        );
+        assert_eq!(
+            resources.extra_files,
+            vec![(
+                PathBuf::from("prefix/foo/resource.txt"),
+                DataLocation::Memory(vec![42]),
+                false
+            ),]
         );
-
-        assert!(installs.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_in_memory_shared_library() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_add_in_memory_package_distribution_resource() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "mypackage".to_string(),
+                version: "1.0".to_string(),
+                name: "resource.txt".to_string(),
+                data: DataLocation::Memory(vec![42]),
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            in_memory_shared_library: Some(DataLocation::Memory(b"library".to_vec())),
-            shared_library_dependency_names: Some(vec!["foo".to_string(), "bar".to_string()]),
-            ..PrePackagedResource::default()
-        };
+        assert_eq!(r.resources.len(), 1);
+        assert_eq!(
+            r.resources.get("mypackage"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "mypackage".to_string(),
+                is_package: true,
+                in_memory_distribution_resources: Some(BTreeMap::from_iter(
+                    [("resource.txt".to_string(), DataLocation::Memory(vec![42]))]
+                        .iter()
+                        .cloned()
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let resources = r.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("mypackage"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                in_memory_shared_library: Some(Cow::Owned(b"library".to_vec())),
-                shared_library_dependency_names: Some(vec![
-                    Cow::Owned("foo".to_string()),
-                    Cow::Owned("bar".to_string())
-                ]),
+                name: Cow::Owned("mypackage".to_string()),
+                is_package: true,
+                in_memory_distribution_resources: Some(HashMap::from_iter(
+                    [(Cow::Owned("resource.txt".to_string()), Cow::Owned(vec![42]))]
+                        .iter()
+                        .cloned()
+                )),
                 ..Resource::default()
-            }
+            })
         );
-
-        assert!(installs.is_empty());
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_module_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_direct_urls() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            relative_path_module_source: Some((
-                "prefix".to_string(),
-                DataLocation::Memory(b"source".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
+        for (package, data) in &[
+            (
+                "editable",
+                br#"{"url": "file:///home/dev/editable", "dir_info": {"editable": true}}"#.to_vec(),
+            ),
+            ("broken", b"{\"url\": ".to_vec()),
+        ] {
+            r.add_package_distribution_resource(
+                &PythonPackageDistributionResource {
+                    location: PythonPackageDistributionResourceFlavor::DistInfo,
+                    package: package.to_string(),
+                    version: "1.0".to_string(),
+                    name: "direct_url.json".to_string(),
+                    data: DataLocation::Memory(data.clone()),
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        // Malformed files are ignored with a warning.
+        assert_eq!(r.warnings().len(), 1);
+        assert!(r.warnings()[0].starts_with("ignoring direct_url.json of broken: "));
 
-        assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                relative_path_module_source: Some(Cow::Owned(PathBuf::from("prefix/module.py"))),
-                ..Resource::default()
-            }
-        );
+        let direct_urls = r.direct_urls();
+        assert_eq!(direct_urls.keys().collect::<Vec<_>>(), vec!["editable"]);
+        assert!(direct_urls["editable"].is_editable());
 
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/module.py"),
-                DataLocation::Memory(b"source".to_vec()),
-                false
-            )]
+            r.resource_origins("editable"),
+            Some(vec![
+                "module".to_string(),
+                "distribution installed from editable directory file:///home/dev/editable"
+                    .to_string()
+            ])
+        );
+        assert_eq!(
+            r.resource_origins("broken"),
+            Some(vec!["module".to_string()])
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_module_bytecode_provided() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_resolve_distribution_versions() -> Result<()> {
+        let install = |version: &str, modules: &[&str]| -> Vec<PythonResource> {
+            let record = modules
+                .iter()
+                .map(|path| format!("{},,\n", path))
+                .collect::<String>();
+
+            let mut resources = modules
+                .iter()
+                .filter_map(|path| {
+                    let name = path.strip_suffix(".py")?;
+                    let is_package = name.ends_with("/__init__");
+                    let name = name.trim_end_matches("/__init__").replace('/', ".");
+
+                    Some(PythonResource::ModuleSource(PythonModuleSource {
+                        name,
+                        source: DataLocation::Memory(version.as_bytes().to_vec()),
+                        is_package,
+                        cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                        is_stdlib: false,
+                        is_test: false,
+                    }))
+                })
+                .collect::<Vec<_>>();
+            resources.push(PythonResource::Resource(PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "data.txt".to_string(),
+                data: DataLocation::Memory(version.as_bytes().to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            }));
+            resources.push(PythonResource::DistributionResource(
+                PythonPackageDistributionResource {
+                    location: PythonPackageDistributionResourceFlavor::DistInfo,
+                    package: "foo".to_string(),
+                    version: version.to_string(),
+                    name: "RECORD".to_string(),
+                    data: DataLocation::Memory(record.into_bytes()),
+                },
+            ));
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "foo.bar".to_string(),
-            relative_path_bytecode: Some((
-                "prefix".to_string(),
-                "tag".to_string(),
-                PythonModuleBytecodeProvider::Provided(DataLocation::Memory(b"bytecode".to_vec())),
-            )),
-            ..PrePackagedResource::default()
+            resources
+        };
+        let v1 = install("1.0", &["foo/__init__.py", "foo/old.py", "foo/data.txt"]);
+        let v2 = install("2.0", &["foo/__init__.py", "foo/new.py", "foo/data.txt"]);
+
+        let add = |r: &mut PythonResourceCollector, resources: Vec<PythonResource>| -> Result<()> {
+            for resource in r.resolve_distribution_versions(resources)? {
+                match resource {
+                    PythonResource::ModuleSource(m) => {
+                        r.add_python_module_source(&m, &ConcreteResourceLocation::InMemory)?
+                    }
+                    PythonResource::Resource(m) => {
+                        r.add_python_package_resource(&m, &ConcreteResourceLocation::InMemory)?
+                    }
+                    PythonResource::DistributionResource(m) => r
+                        .add_package_distribution_resource(
+                            &m,
+                            &ConcreteResourceLocation::InMemory,
+                        )?,
+                    _ => panic!("unexpected resource"),
+                }
+            }
+
+            Ok(())
         };
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        add(&mut r, v1.clone())?;
+        add(&mut r, v2.clone())?;
 
+        // Files only the first version had are gone.
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo.bar".to_string()),
-                relative_path_module_bytecode: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/__pycache__/bar.tag.pyc"
-                ))),
-                ..Resource::default()
+            r.resources.keys().collect::<Vec<_>>(),
+            vec!["foo", "foo.new"]
+        );
+        assert_eq!(
+            r.resources["foo"].in_memory_source,
+            Some(DataLocation::Memory(b"2.0".to_vec()))
+        );
+        assert_eq!(
+            r.resources["foo"].in_memory_resources.as_ref().unwrap()["data.txt"],
+            DataLocation::Memory(b"2.0".to_vec())
+        );
+        assert_eq!(
+            r.distributions()["foo"],
+            CollectedDistribution {
+                name: "foo".to_string(),
+                version: "2.0".to_string(),
+                discarded_versions: vec!["1.0".to_string()],
             }
         );
+        assert_eq!(
+            r.warnings(),
+            &["replaced foo 1.0 with version 2.0".to_string()]
+        );
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_distribution_version_conflict(DistributionVersionConflictPolicy::KeepFirst);
+        add(&mut r, v1.clone())?;
+        add(&mut r, v2.clone())?;
 
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/foo/__pycache__/bar.tag.pyc"),
-                DataLocation::Memory(
-                    b"\x2a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00bytecode"
-                        .to_vec()
-                ),
-                false
-            )]
+            r.resources.keys().collect::<Vec<_>>(),
+            vec!["foo", "foo.old"]
+        );
+        assert_eq!(
+            r.resources["foo"].in_memory_source,
+            Some(DataLocation::Memory(b"1.0".to_vec()))
         );
+        assert_eq!(r.distributions()["foo"].version, "1.0");
+        assert_eq!(r.distributions()["foo"].discarded_versions, vec!["2.0"]);
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_distribution_version_conflict(DistributionVersionConflictPolicy::Error);
+        add(&mut r, v1)?;
+        assert_eq!(
+            format!("{}", add(&mut r, v2.clone()).unwrap_err()),
+            "foo 2.0 conflicts with version 1.0 collected earlier"
+        );
+
+        // Resources added without resolving conflicts are still checked.
+        for resource in v2 {
+            if let PythonResource::DistributionResource(m) = resource {
+                assert!(r
+                    .add_package_distribution_resource(&m, &ConcreteResourceLocation::InMemory)
+                    .is_err());
+            }
+        }
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_module_bytecode_from_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_add_relative_path_package_distribution_resource() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        r.add_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "mypackage".to_string(),
+                version: "1.0".to_string(),
+                name: "resource.txt".to_string(),
+                data: DataLocation::Memory(vec![42]),
+            },
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "foo.bar".to_string(),
-            relative_path_bytecode: Some((
-                "prefix".to_string(),
-                "tag".to_string(),
-                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(b"source".to_vec())),
-            )),
-            ..PrePackagedResource::default()
-        };
+        assert_eq!(r.resources.len(), 1);
+        assert_eq!(
+            r.resources.get("mypackage"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Module,
+                name: "mypackage".to_string(),
+                is_package: true,
+                relative_path_distribution_resources: Some(BTreeMap::from_iter(
+                    [(
+                        "resource.txt".to_string(),
+                        (
+                            PathBuf::from("prefix/mypackage-1.0.dist-info/resource.txt"),
+                            DataLocation::Memory(vec![42])
+                        )
+                    )]
+                    .iter()
+                    .cloned()
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let mut compiler = FakeBytecodeCompiler::default();
 
+        let resources = r.compile_resources(&mut compiler)?;
+
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
+            resources.resources.get("mypackage"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo.bar".to_string()),
-                relative_path_module_bytecode: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/__pycache__/bar.tag.pyc"
-                ))),
+                name: Cow::Owned("mypackage".to_string()),
+                is_package: true,
+                relative_path_distribution_resources: Some(HashMap::from_iter(
+                    [(
+                        Cow::Owned("resource.txt".to_string()),
+                        Cow::Owned(PathBuf::from("prefix/mypackage-1.0.dist-info/resource.txt")),
+                    )]
+                    .iter()
+                    .cloned()
+                )),
                 ..Resource::default()
-            }
+            })
         );
-
         assert_eq!(
-            installs,
+            resources.extra_files,
             vec![(
-                PathBuf::from("prefix/foo/__pycache__/bar.tag.pyc"),
-                DataLocation::Memory(b"bc0source".to_vec()),
+                PathBuf::from("prefix/mypackage-1.0.dist-info/resource.txt"),
+                DataLocation::Memory(vec![42]),
                 false
-            )]
+            ),]
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_module_bytecode_opt1_provided() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_add_builtin_python_extension_module() -> Result<()> {
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "foo.bar".to_string(),
-            relative_path_bytecode_opt1: Some((
-                "prefix".to_string(),
-                "tag".to_string(),
-                PythonModuleBytecodeProvider::Provided(DataLocation::Memory(b"bytecode".to_vec())),
-            )),
-            ..PrePackagedResource::default()
+        let em = PythonExtensionModule {
+            name: "_io".to_string(),
+            init_fn: Some("PyInit__io".to_string()),
+            extension_file_suffix: "".to_string(),
+            shared_library: None,
+            object_file_data: vec![],
+            is_package: false,
+            link_libraries: vec![],
+            is_stdlib: true,
+            builtin_default: true,
+            required: true,
+            variant: None,
+            licenses: None,
+            license_texts: None,
+            license_public_domain: None,
         };
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
-
+        c.add_builtin_python_extension_module(&em)?;
+        assert_eq!(c.resources.len(), 1);
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo.bar".to_string()),
-                relative_path_module_bytecode_opt1: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/__pycache__/bar.tag.opt-1.pyc"
-                ))),
-                ..Resource::default()
-            }
+            c.resources.get("_io"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::BuiltinExtensionModule,
+                name: "_io".to_string(),
+                ..PrePackagedResource::default()
+            })
         );
 
+        let mut compiler = FakeBytecodeCompiler::default();
+
+        let resources = c.compile_resources(&mut compiler)?;
+
+        assert_eq!(resources.resources.len(), 1);
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-1.pyc"),
-                DataLocation::Memory(
-                    b"\x2a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00bytecode"
-                        .to_vec()
-                ),
-                false
-            )]
+            resources.resources.get("_io"),
+            Some(&Resource {
+                flavor: ResourceFlavor::BuiltinExtensionModule,
+                name: Cow::Owned("_io".to_string()),
+                ..Resource::default()
+            })
         );
+        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_module_bytecode_opt1_from_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_add_in_memory_python_extension_module_shared_library() -> Result<()> {
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
+        let em = PythonExtensionModule {
+            name: "myext".to_string(),
+            init_fn: Some("PyInit__myext".to_string()),
+            extension_file_suffix: ".so".to_string(),
+            shared_library: Some(DataLocation::Memory(vec![42])),
+            object_file_data: vec![],
+            is_package: false,
+            link_libraries: vec![LibraryDependency {
+                name: "foo".to_string(),
+                static_library: None,
+                dynamic_library: Some(DataLocation::Memory(vec![40])),
+                framework: false,
+                system: false,
+            }],
+            is_stdlib: false,
+            builtin_default: false,
+            required: false,
+            variant: None,
+            licenses: None,
+            license_texts: None,
+            license_public_domain: None,
+        };
+
+        c.add_python_extension_module(&em, &ConcreteResourceLocation::InMemory)?;
+        assert_eq!(c.resources.len(), 2);
+        assert_eq!(
+            c.resources.get("myext"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: "myext".to_string(),
+                in_memory_extension_module_shared_library: Some(DataLocation::Memory(vec![42])),
+                shared_library_dependency_names: Some(vec!["foo".to_string()]),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            c.resources.get("foo"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "foo".to_string(),
+                in_memory_shared_library: Some(DataLocation::Memory(vec![40])),
+                ..PrePackagedResource::default()
+            })
+        );
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "foo.bar".to_string(),
-            relative_path_bytecode_opt1: Some((
-                "prefix".to_string(),
-                "tag".to_string(),
-                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(b"source".to_vec())),
-            )),
-            ..PrePackagedResource::default()
-        };
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        let resources = c.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 2);
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo.bar".to_string()),
-                relative_path_module_bytecode_opt1: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/__pycache__/bar.tag.opt-1.pyc"
-                ))),
+            resources.resources.get("myext"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Extension,
+                name: Cow::Owned("myext".to_string()),
+                in_memory_extension_module_shared_library: Some(Cow::Owned(vec![42])),
+                shared_library_dependency_names: Some(vec![Cow::Owned("foo".to_string())]),
                 ..Resource::default()
-            }
+            })
         );
-
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-1.pyc"),
-                DataLocation::Memory(b"bc1source".to_vec()),
-                false
-            )]
+            resources.resources.get("foo"),
+            Some(&Resource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: Cow::Owned("foo".to_string()),
+                in_memory_shared_library: Some(Cow::Owned(vec![40])),
+                ..Resource::default()
+            })
         );
 
+        assert!(resources.extra_files.is_empty());
+
         Ok(())
     }
 
-    #[test]
-    fn test_resource_conversion_relative_path_module_bytecode_opt2_provided() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "foo.bar".to_string(),
-            relative_path_bytecode_opt2: Some((
-                "prefix".to_string(),
-                "tag".to_string(),
-                PythonModuleBytecodeProvider::Provided(DataLocation::Memory(b"bytecode".to_vec())),
-            )),
-            ..PrePackagedResource::default()
-        };
-
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+    fn extension_with_library_dependencies() -> PythonExtensionModule {
+        PythonExtensionModule {
+            name: "foo.myext".to_string(),
+            init_fn: Some("PyInit_myext".to_string()),
+            extension_file_suffix: ".so".to_string(),
+            shared_library: Some(DataLocation::Memory(vec![42])),
+            object_file_data: vec![],
+            is_package: false,
+            link_libraries: vec![
+                LibraryDependency {
+                    name: "mylib".to_string(),
+                    static_library: None,
+                    dynamic_library: Some(DataLocation::Memory(vec![40])),
+                    framework: false,
+                    system: false,
+                },
+                LibraryDependency {
+                    name: "otherlib".to_string(),
+                    static_library: None,
+                    dynamic_library: Some(DataLocation::Memory(vec![41])),
+                    framework: false,
+                    system: false,
+                },
+                LibraryDependency {
+                    name: "syslib".to_string(),
+                    static_library: None,
+                    dynamic_library: Some(DataLocation::Memory(vec![43])),
+                    framework: false,
+                    system: true,
+                },
+            ],
+            is_stdlib: false,
+            builtin_default: false,
+            required: false,
+            variant: None,
+            licenses: None,
+            license_texts: None,
+            license_public_domain: None,
+        }
+    }
 
-        assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo.bar".to_string()),
-                relative_path_module_bytecode_opt2: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/__pycache__/bar.tag.opt-2.pyc"
-                ))),
-                ..Resource::default()
-            }
+    #[test]
+    fn test_add_in_memory_python_extension_module_library_dependencies_strict() -> Result<()> {
+        let mut c = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
         );
+        c.set_strict_library_dependencies(true);
 
+        let res = c.add_python_extension_module(
+            &extension_with_library_dependencies(),
+            &ConcreteResourceLocation::InMemory,
+        );
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-2.pyc"),
-                DataLocation::Memory(
-                    b"\x2a\x00\x00\x00\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00bytecode"
-                        .to_vec()
-                ),
-                false
-            )]
+            res.err().unwrap().to_string(),
+            "extension module foo.myext depends on libraries that cannot be loaded from memory: mylib, otherlib"
         );
+        assert!(c.resources.is_empty());
+        assert!(c.warnings().is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_module_bytecode_opt2_from_source() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_add_in_memory_python_extension_module_library_dependencies_lenient() -> Result<()> {
+        let mut c = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "foo.bar".to_string(),
-            relative_path_bytecode_opt2: Some((
-                "prefix".to_string(),
-                "tag".to_string(),
-                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(b"source".to_vec())),
-            )),
-            ..PrePackagedResource::default()
-        };
+        c.add_python_extension_module(
+            &extension_with_library_dependencies(),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert_eq!(c.resources.len(), 4);
+        assert_eq!(
+            c.resources.get("foo.myext"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: "foo.myext".to_string(),
+                in_memory_extension_module_shared_library: Some(DataLocation::Memory(vec![42])),
+                shared_library_dependency_names: Some(vec![
+                    "mylib".to_string(),
+                    "otherlib".to_string(),
+                    "syslib".to_string()
+                ]),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            c.resources.get("mylib"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "mylib".to_string(),
+                relative_path_shared_library: Some((
+                    "prefix/foo".to_string(),
+                    DataLocation::Memory(vec![40])
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            c.resources.get("otherlib"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "otherlib".to_string(),
+                relative_path_shared_library: Some((
+                    "prefix/foo".to_string(),
+                    DataLocation::Memory(vec![41])
+                )),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            c.resources.get("syslib"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "syslib".to_string(),
+                in_memory_shared_library: Some(DataLocation::Memory(vec![43])),
+                ..PrePackagedResource::default()
+            })
+        );
+        assert_eq!(
+            c.warnings(),
+            &[
+                "library mylib required by in-memory extension module foo.myext cannot be loaded from memory; installing to prefix/foo".to_string(),
+                "library otherlib required by in-memory extension module foo.myext cannot be loaded from memory; installing to prefix/foo".to_string(),
+            ]
+        );
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        // Without a filesystem fallback, there's nowhere to put the libraries.
+        // They remain in memory.
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
 
+        c.add_python_extension_module(
+            &extension_with_library_dependencies(),
+            &ConcreteResourceLocation::InMemory,
+        )?;
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo.bar".to_string()),
-                relative_path_module_bytecode_opt2: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/__pycache__/bar.tag.opt-2.pyc"
-                ))),
-                ..Resource::default()
-            }
+            c.resources.get("mylib"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "mylib".to_string(),
+                in_memory_shared_library: Some(DataLocation::Memory(vec![40])),
+                ..PrePackagedResource::default()
+            })
         );
-
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/foo/__pycache__/bar.tag.opt-2.pyc"),
-                DataLocation::Memory(b"bc2source".to_vec()),
-                false
-            )]
+            c.warnings(),
+            &[
+                "library mylib required by in-memory extension module foo.myext cannot be loaded from memory and resources policy does not allow installing it on the filesystem; the extension module will likely fail to load".to_string(),
+                "library otherlib required by in-memory extension module foo.myext cannot be loaded from memory and resources policy does not allow installing it on the filesystem; the extension module will likely fail to load".to_string(),
+            ]
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_extension_module_shared_library() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            relative_path_extension_module_shared_library: Some((
-                PathBuf::from("prefix/ext.so"),
-                DataLocation::Memory(b"data".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
-
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+    fn test_add_in_memory_python_extension_module_library_dependencies_extract() -> Result<()> {
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        c.set_strict_library_dependencies(true);
+        c.set_extract_library_dependencies(true);
 
+        c.add_python_extension_module(
+            &extension_with_library_dependencies(),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert_eq!(c.resources.len(), 4);
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                relative_path_extension_module_shared_library: Some(Cow::Owned(PathBuf::from(
-                    "prefix/ext.so"
-                ))),
-                ..Resource::default()
-            }
+            c.resources
+                .get("foo.myext")
+                .unwrap()
+                .shared_library_dependency_names,
+            Some(vec![
+                "mylib".to_string(),
+                "otherlib".to_string(),
+                "syslib".to_string()
+            ])
         );
-
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/ext.so"),
-                DataLocation::Memory(b"data".to_vec()),
-                true
-            )]
+            c.resources.get("mylib"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "mylib".to_string(),
+                in_memory_shared_library: Some(DataLocation::Memory(vec![40])),
+                extract_shared_library: true,
+                ..PrePackagedResource::default()
+            })
         );
+        assert!(!c.resources.get("syslib").unwrap().extract_shared_library);
+        assert!(c.warnings().is_empty());
+
+        let (resource, installs) = c
+            .resources
+            .get("mylib")
+            .unwrap()
+            .to_resource(&mut FakeBytecodeCompiler::default())?;
+        assert!(resource.extract_shared_library);
+        assert!(installs.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_package_resources() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let mut resources = BTreeMap::new();
-        resources.insert(
-            "foo.txt".to_string(),
-            (
-                PathBuf::from("module/foo.txt"),
-                DataLocation::Memory(b"data".to_vec()),
-            ),
-        );
-        resources.insert(
-            "bar.txt".to_string(),
-            (
-                PathBuf::from("module/bar.txt"),
-                DataLocation::Memory(b"bar".to_vec()),
-            ),
-        );
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            is_package: true,
-            relative_path_package_resources: Some(resources),
-            ..PrePackagedResource::default()
+    fn test_text_normalization() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_text_normalization(Some(TextNormalization::default()));
+
+        let module = PythonModuleSource {
+            name: "foo".to_string(),
+            source: DataLocation::Memory(b"\xef\xbb\xbfimport os\r\n".to_vec()),
+            is_package: false,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
         };
+        r.add_python_module_source(&module, &ConcreteResourceLocation::InMemory)?;
+        r.add_python_module_bytecode_from_source(
+            &module.as_bytecode_module(BytecodeOptimizationLevel::Zero),
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        for (name, data) in &[
+            ("data.txt", &b"a\r\nb\r\n"[..]),
+            ("image.png", &b"a\r\nb\r\n"[..]),
+            ("blob.txt", &b"a\r\n\x00"[..]),
+        ] {
+            r.add_python_package_resource(
+                &PythonPackageResource {
+                    leaf_package: "foo".to_string(),
+                    relative_name: name.to_string(),
+                    data: DataLocation::Memory(data.to_vec()),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
 
-        let mut resources = HashMap::new();
-        resources.insert(
-            Cow::Owned("foo.txt".to_string()),
-            Cow::Owned(PathBuf::from("module/foo.txt")),
-        );
-        resources.insert(
-            Cow::Owned("bar.txt".to_string()),
-            Cow::Owned(PathBuf::from("module/bar.txt")),
+        let entry = r.resources.get("foo").unwrap();
+        let normalized = DataLocation::Memory(b"import os\n".to_vec());
+        assert_eq!(entry.in_memory_source, Some(normalized.clone()));
+        assert_eq!(
+            entry.in_memory_bytecode,
+            Some(PythonModuleBytecodeProvider::FromSource(normalized))
         );
 
+        let resources = entry.in_memory_resources.as_ref().unwrap();
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                is_package: true,
-                relative_path_package_resources: Some(resources),
-                ..Resource::default()
-            }
+            resources.get("data.txt"),
+            Some(&DataLocation::Memory(b"a\nb\n".to_vec()))
+        );
+        assert_eq!(
+            resources.get("image.png"),
+            Some(&DataLocation::Memory(b"a\r\nb\r\n".to_vec()))
+        );
+        assert_eq!(
+            resources.get("blob.txt"),
+            Some(&DataLocation::Memory(b"a\r\n\x00".to_vec()))
         );
 
         assert_eq!(
-            installs,
-            vec![
-                (
-                    PathBuf::from("module/bar.txt"),
-                    DataLocation::Memory(b"bar".to_vec()),
-                    false
-                ),
-                (
-                    PathBuf::from("module/foo.txt"),
-                    DataLocation::Memory(b"data".to_vec()),
-                    false
-                ),
-            ]
+            entry.normalized_payloads,
+            vec!["bytecode", "package resource data.txt", "source"]
+                .into_iter()
+                .map(|x| x.to_string())
+                .collect()
         );
 
+        // Normalization is opt-in.
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(&module, &ConcreteResourceLocation::InMemory)?;
+        let entry = r.resources.get("foo").unwrap();
+        assert_eq!(entry.in_memory_source, Some(module.source.clone()));
+        assert!(entry.normalized_payloads.is_empty());
+
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_distribution_resources() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+    fn test_source_transforms() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_source_transforms(SourceTransforms {
+            strip_docstrings: true,
+            excluded_modules: vec!["docopt".to_string()].into_iter().collect(),
+            ..SourceTransforms::default()
+        });
+
+        for name in &["foo", "docopt", "broken"] {
+            let module = PythonModuleSource {
+                name: name.to_string(),
+                source: DataLocation::Memory(if *name == "broken" {
+                    b"'''unterminated\n".to_vec()
+                } else {
+                    b"'''Usage: foo'''\nimport os\n".to_vec()
+                }),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            };
+            r.add_python_module_source(&module, &ConcreteResourceLocation::InMemory)?;
+            r.add_python_module_bytecode_from_source(
+                &module.as_bytecode_module(BytecodeOptimizationLevel::Zero),
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
 
-        let mut resources = BTreeMap::new();
-        resources.insert(
-            "foo.txt".to_string(),
-            (
-                PathBuf::from("foo.txt"),
-                DataLocation::Memory(b"data".to_vec()),
-            ),
+        let stripped = DataLocation::Memory(b"\nimport os\n".to_vec());
+        let entry = r.resources.get("foo").unwrap();
+        assert_eq!(entry.in_memory_source, Some(stripped.clone()));
+        assert_eq!(
+            entry.in_memory_bytecode,
+            Some(PythonModuleBytecodeProvider::FromSource(stripped))
         );
 
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::Module,
-            name: "module".to_string(),
-            relative_path_distribution_resources: Some(resources),
-            ..PrePackagedResource::default()
-        };
-
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
-
-        let mut resources = HashMap::new();
-        resources.insert(
-            Cow::Owned("foo.txt".to_string()),
-            Cow::Owned(PathBuf::from("foo.txt")),
+        let entry = r.resources.get("docopt").unwrap();
+        assert_eq!(
+            entry.in_memory_source,
+            Some(DataLocation::Memory(
+                b"'''Usage: foo'''\nimport os\n".to_vec()
+            ))
         );
 
+        // Source the transforms can't handle is kept.
+        let entry = r.resources.get("broken").unwrap();
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("module".to_string()),
-                relative_path_distribution_resources: Some(resources),
-                ..Resource::default()
-            }
+            entry.in_memory_source,
+            Some(DataLocation::Memory(b"'''unterminated\n".to_vec()))
         );
-
         assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("foo.txt"),
-                DataLocation::Memory(b"data".to_vec()),
-                false
-            )]
+            r.warnings(),
+            &[
+                "not transforming source of broken: unterminated string literal on line 1"
+                    .to_string(),
+                "not transforming source of broken: unterminated string literal on line 1"
+                    .to_string()
+            ]
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_resource_conversion_relative_path_shared_library() -> Result<()> {
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let pre = PrePackagedResource {
-            flavor: ResourceFlavor::SharedLibrary,
-            name: "libfoo".to_string(),
-            relative_path_shared_library: Some((
-                "prefix".to_string(),
-                DataLocation::Memory(b"data".to_vec()),
-            )),
-            ..PrePackagedResource::default()
-        };
+    fn test_locale_filter() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_locale_filter(Some(LocaleFilter {
+            keep: vec!["pt".to_string()],
+            exact_match: false,
+        }));
 
-        let (resource, installs) = pre.to_resource(&mut compiler)?;
+        for (package, name) in &[
+            ("foo", "locale/de/LC_MESSAGES/foo.mo"),
+            ("foo", "locale/pt/LC_MESSAGES/foo.mo"),
+            ("foo", "locale/pt_BR/LC_MESSAGES/foo.mo"),
+            ("foo", "data.txt"),
+            ("foo.locale", "fr/LC_MESSAGES/foo.mo"),
+            ("bar", "locales/de/messages.json"),
+        ] {
+            r.add_python_package_resource(
+                &PythonPackageResource {
+                    leaf_package: package.to_string(),
+                    relative_name: name.to_string(),
+                    data: DataLocation::Memory(vec![42; 10]),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
 
         assert_eq!(
-            resource,
-            Resource {
-                flavor: ResourceFlavor::SharedLibrary,
-                name: Cow::Owned("libfoo".to_string()),
-                ..Resource::default()
-            }
+            r.resources
+                .get("foo")
+                .unwrap()
+                .in_memory_resources
+                .as_ref()
+                .unwrap()
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![
+                "data.txt",
+                "locale/pt/LC_MESSAGES/foo.mo",
+                "locale/pt_BR/LC_MESSAGES/foo.mo"
+            ]
         );
-
-        assert_eq!(
-            installs,
-            vec![(
-                PathBuf::from("prefix/libfoo"),
-                DataLocation::Memory(b"data".to_vec()),
-                true
-            )]
+        assert!(!r.resources.contains_key("foo.locale"));
+        assert!(!r.resources.contains_key("bar"));
+
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            "bar".to_string(),
+            LocaleTrimStatistics {
+                files: 1,
+                bytes: 10,
+            },
+        );
+        expected.insert(
+            "foo".to_string(),
+            LocaleTrimStatistics {
+                files: 1,
+                bytes: 10,
+            },
+        );
+        expected.insert(
+            "foo.locale".to_string(),
+            LocaleTrimStatistics {
+                files: 1,
+                bytes: 10,
+            },
         );
+        assert_eq!(r.trimmed_locales(), &expected);
 
         Ok(())
     }
 
     #[test]
-    fn test_populate_parent_packages_in_memory_source() -> Result<()> {
-        let mut h = BTreeMap::new();
-        h.insert(
-            "root.parent.child".to_string(),
-            PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent.child".to_string(),
-                in_memory_source: Some(DataLocation::Memory(vec![42])),
-                is_package: true,
-                ..PrePackagedResource::default()
+    fn test_extract_resource() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let dest = temp_dir.path();
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo.bar".to_string(),
+                source: DataLocation::Memory(b"import os".to_vec()),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
             },
-        );
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo.bar".to_string(),
+                source: DataLocation::Memory(b"import os".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::One,
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
-        populate_parent_packages(&mut h)?;
+        let path = r.extract_resource("foo.bar", &PayloadKind::Source, None, dest)?;
+        assert_eq!(path, dest.join("foo.bar.py"));
+        assert_eq!(std::fs::read(&path)?, b"import os");
 
-        assert_eq!(h.len(), 3);
+        let mut compiler = FakeBytecodeCompiler::default();
+        let path = r.extract_resource(
+            "foo.bar",
+            &PayloadKind::BytecodeOpt1,
+            Some(&mut compiler),
+            dest,
+        )?;
+        assert_eq!(path, dest.join("foo.bar.opt-1.pyc"));
+        assert_eq!(std::fs::read(&path)?, b"bc1import os");
+
+        assert!(r
+            .extract_resource("foo.bar", &PayloadKind::BytecodeOpt1, None, dest)
+            .is_err());
         assert_eq!(
-            h.get("root.parent"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent".to_string(),
-                is_package: true,
-                in_memory_source: Some(DataLocation::Memory(vec![])),
-                ..PrePackagedResource::default()
-            })
+            r.extract_resource("foo.bar", &PayloadKind::Bytecode, None, dest)
+                .unwrap_err()
+                .to_string(),
+            "foo.bar has no bytecode"
         );
         assert_eq!(
-            h.get("root"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root".to_string(),
-                is_package: true,
-                in_memory_source: Some(DataLocation::Memory(vec![])),
-                ..PrePackagedResource::default()
-            })
+            r.extract_resource("foo.baz", &PayloadKind::Source, None, dest)
+                .unwrap_err()
+                .to_string(),
+            "resource foo.baz not found; did you mean foo.bar?"
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_populate_parent_packages_relative_path_source() -> Result<()> {
-        let mut h = BTreeMap::new();
-        h.insert(
-            "root.parent.child".to_string(),
-            PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent.child".to_string(),
-                relative_path_module_source: Some((
-                    "prefix".to_string(),
-                    DataLocation::Memory(vec![42]),
-                )),
-                is_package: true,
-                ..PrePackagedResource::default()
-            },
-        );
+    fn test_remove_unused() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
 
-        populate_parent_packages(&mut h)?;
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
 
-        assert_eq!(h.len(), 3);
+        for name in &["foo", "foo.bar", "foo.unused", "protected", "unused"] {
+            c.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(vec![42]),
+                    is_package: false,
+                    cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
+        c.add_extracted_shared_library("libprotected", &DataLocation::Memory(vec![40]))?;
+        c.add_shared_library_dependency("protected", "libprotected")?;
+
+        assert!(c.remove_unused(|_| false).is_err());
+        assert_eq!(c.is_resource_used("foo"), None);
+
+        let log = temp_dir.path().join("modules");
+        std::fs::write(&log, "foo.bar\nfoo.bar\nnot_packaged\n")?;
+
+        let report = c.mark_used_from_log(&log)?;
+        assert_eq!(report.unused_count(), 4);
         assert_eq!(
-            h.get("root.parent"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent".to_string(),
-                is_package: true,
-                relative_path_module_source: Some((
-                    "prefix".to_string(),
-                    DataLocation::Memory(vec![])
-                )),
-                ..PrePackagedResource::default()
-            })
+            report.unknown,
+            vec!["not_packaged".to_string()].into_iter().collect()
         );
+        assert_eq!(c.is_resource_used("foo"), Some(true));
+        assert_eq!(c.is_resource_used("foo.unused"), Some(false));
+
+        let removed = c.remove_unused(|r| r.name == "protected")?;
         assert_eq!(
-            h.get("root"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root".to_string(),
-                is_package: true,
-                relative_path_module_source: Some((
-                    "prefix".to_string(),
-                    DataLocation::Memory(vec![])
-                )),
-                ..PrePackagedResource::default()
-            })
+            removed,
+            vec!["foo.unused".to_string(), "unused".to_string()]
+        );
+        assert_eq!(
+            c.resources.keys().cloned().collect::<Vec<_>>(),
+            vec!["foo", "foo.bar", "libprotected", "protected"]
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_populate_parent_packages_in_memory_bytecode() -> Result<()> {
-        let mut h = BTreeMap::new();
-        h.insert(
-            "root.parent.child".to_string(),
-            PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent.child".to_string(),
-                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
-                    DataLocation::Memory(vec![42]),
-                )),
-                is_package: true,
-                ..PrePackagedResource::default()
-            },
+    fn test_resources_for_features() -> Result<()> {
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
+        for name in &["core", "telemetry", "telemetry.client", "plugin", "other"] {
+            c.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(vec![42]),
+                    is_package: false,
+                    cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
+        c.add_extracted_shared_library("libplugin", &DataLocation::Memory(vec![40]))?;
+        c.add_shared_library_dependency("plugin", "libplugin")?;
+
+        assert!(c.tag_resource("missing", "oss").is_err());
+
+        c.tag_resource("telemetry", "telemetry")?;
+        c.tag_resource("telemetry.client", "telemetry")?;
+        c.tag_resource("plugin", "plugins")?;
+        c.tag_resource("plugin", "all")?;
+        c.tag_resource("libplugin", "plugins")?;
+
+        fn names(c: &PythonResourceCollector, enabled: &[&str]) -> Result<Vec<String>> {
+            let enabled = enabled.iter().map(|x| x.to_string()).collect();
+            Ok(c.resources_for_features(&enabled)?
+                .keys()
+                .cloned()
+                .collect())
+        }
+
+        assert_eq!(names(&c, &[])?, vec!["core", "other"]);
+        assert_eq!(
+            names(&c, &["telemetry", "plugins", "unknown"])?,
+            vec![
+                "core",
+                "libplugin",
+                "other",
+                "plugin",
+                "telemetry",
+                "telemetry.client"
+            ]
         );
 
-        populate_parent_packages(&mut h)?;
+        // A resource is included if any tag is enabled. But its library
+        // dependency is gated on a different feature.
+        let err = names(&c, &["all"]).err().unwrap().to_string();
+        assert!(err.starts_with("plugin depends on libplugin"));
+        assert!(err.contains("(plugins)"));
 
-        assert_eq!(h.len(), 3);
+        // Included resources can't have their parent package excluded.
+        c.tag_resource("telemetry", "never")?;
+        c.tag_resource("telemetry.client", "oss")?;
         assert_eq!(
-            h.get("root.parent"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent".to_string(),
-                is_package: true,
-                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
-                    DataLocation::Memory(vec![])
-                )),
-                ..PrePackagedResource::default()
-            })
+            names(&c, &["oss"]).err().unwrap().to_string(),
+            "telemetry.client depends on telemetry, which is excluded because none of its features (never, telemetry) are enabled"
         );
+
+        // Compiling without a feature set includes every resource.
+        let mut compiler = FakeBytecodeCompiler::default();
+        assert_eq!(c.compile_resources(&mut compiler)?.resources.len(), 6);
         assert_eq!(
-            h.get("root"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root".to_string(),
-                is_package: true,
-                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
-                    DataLocation::Memory(vec![])
-                )),
-                ..PrePackagedResource::default()
-            })
+            c.compile_resources_for_features(&mut compiler, &BTreeSet::new())?
+                .resources
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec!["core", "other"]
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_populate_parent_packages_distribution_extension_module() -> Result<()> {
-        let mut h = BTreeMap::new();
-        h.insert(
-            "foo.bar".to_string(),
-            PrePackagedResource {
-                flavor: ResourceFlavor::BuiltinExtensionModule,
-                name: "foo.bar".to_string(),
-                relative_path_extension_module_shared_library: Some((
-                    PathBuf::from("prefix/foo/bar.so"),
-                    DataLocation::Memory(vec![42]),
-                )),
-                ..PrePackagedResource::default()
+    fn test_add_shared_library_dependency() -> Result<()> {
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
+        c.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(vec![42]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
             },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+
+        assert_eq!(
+            c.add_shared_library_dependency("foo", "mylib")
+                .err()
+                .unwrap()
+                .to_string(),
+            "shared library mylib not found"
         );
 
-        populate_parent_packages(&mut h)?;
+        c.add_extracted_shared_library("mylib", &DataLocation::Memory(vec![40]))?;
+        c.add_shared_library_dependency("foo", "mylib")?;
+        c.add_shared_library_dependency("foo", "mylib")?;
 
         assert_eq!(
-            h.get("foo"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "foo".to_string(),
-                is_package: true,
-                ..PrePackagedResource::default()
-            })
+            c.resources
+                .get("foo")
+                .unwrap()
+                .shared_library_dependency_names,
+            Some(vec!["mylib".to_string()])
         );
+        assert!(c.add_shared_library_dependency("missing", "mylib").is_err());
 
         Ok(())
     }
 
     #[test]
-    fn test_populate_parent_packages_relative_extension_module() -> Result<()> {
-        let mut h = BTreeMap::new();
-        h.insert(
-            "foo.bar".to_string(),
-            PrePackagedResource {
+    fn test_add_relative_path_python_extension_module() -> Result<()> {
+        let mut c = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+
+        let em = PythonExtensionModule {
+            name: "foo.bar".to_string(),
+            init_fn: None,
+            extension_file_suffix: ".so".to_string(),
+            shared_library: Some(DataLocation::Memory(vec![42])),
+            object_file_data: vec![],
+            is_package: false,
+            link_libraries: vec![LibraryDependency {
+                name: "mylib".to_string(),
+                static_library: None,
+                dynamic_library: Some(DataLocation::Memory(vec![40])),
+                framework: false,
+                system: false,
+            }],
+            is_stdlib: false,
+            builtin_default: false,
+            required: false,
+            variant: None,
+            licenses: None,
+            license_texts: None,
+            license_public_domain: None,
+        };
+
+        c.add_python_extension_module(
+            &em,
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
+        assert_eq!(c.resources.len(), 2);
+        assert_eq!(
+            c.resources.get("foo.bar"),
+            Some(&PrePackagedResource {
                 flavor: ResourceFlavor::Extension,
                 name: "foo.bar".to_string(),
+                is_package: false,
                 relative_path_extension_module_shared_library: Some((
                     PathBuf::from("prefix/foo/bar.so"),
-                    DataLocation::Memory(vec![42]),
+                    DataLocation::Memory(vec![42])
                 )),
+                shared_library_dependency_names: Some(vec!["mylib".to_string()]),
                 ..PrePackagedResource::default()
-            },
+            })
+        );
+        assert_eq!(
+            c.resources.get("mylib"),
+            Some(&PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "mylib".to_string(),
+                relative_path_shared_library: Some((
+                    "prefix/foo".to_string(),
+                    DataLocation::Memory(vec![40])
+                )),
+                ..PrePackagedResource::default()
+            })
         );
 
-        populate_parent_packages(&mut h)?;
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        assert_eq!(h.len(), 2);
+        let resources = c.compile_resources(&mut compiler)?;
 
+        assert_eq!(resources.resources.len(), 3);
         assert_eq!(
-            h.get("foo"),
-            Some(&PrePackagedResource {
+            resources.resources.get("foo"),
+            Some(&Resource {
                 flavor: ResourceFlavor::Module,
-                name: "foo".to_string(),
+                name: Cow::Owned("foo".to_string()),
                 is_package: true,
-                ..PrePackagedResource::default()
+                ..Resource::default()
+            })
+        );
+        assert_eq!(
+            resources.resources.get("foo.bar"),
+            Some(&Resource {
+                flavor: ResourceFlavor::Extension,
+                name: Cow::Owned("foo.bar".to_string()),
+                is_package: false,
+                relative_path_extension_module_shared_library: Some(Cow::Owned(PathBuf::from(
+                    "prefix/foo/bar.so"
+                ))),
+                shared_library_dependency_names: Some(vec![Cow::Owned("mylib".to_string())]),
+                ..Resource::default()
+            })
+        );
+        assert_eq!(
+            resources.resources.get("mylib"),
+            Some(&Resource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: Cow::Owned("mylib".to_string()),
+                ..Resource::default()
             })
         );
 
+        assert_eq!(
+            resources.extra_files,
+            vec![
+                (
+                    PathBuf::from("prefix/foo/bar.so"),
+                    DataLocation::Memory(vec![42]),
+                    true
+                ),
+                (
+                    PathBuf::from("prefix/foo/mylib"),
+                    DataLocation::Memory(vec![40]),
+                    true
+                )
+            ]
+        );
+
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_source_module() -> Result<()> {
+    fn test_find_dunder_file() -> Result<()> {
         let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        assert_eq!(r.find_dunder_file()?.len(), 0);
+
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo.bar".to_string(),
+                source: DataLocation::Memory(vec![]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert_eq!(r.find_dunder_file()?.len(), 0);
+
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "baz".to_string(),
+                source: DataLocation::Memory(Vec::from("import foo; if __file__ == 'ignored'")),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert_eq!(r.find_dunder_file()?.len(), 1);
+        assert!(r.find_dunder_file()?.contains("baz"));
+
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "bytecode".to_string(),
+                source: DataLocation::Memory(Vec::from("import foo; if __file__")),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert_eq!(r.find_dunder_file()?.len(), 2);
+        assert!(r.find_dunder_file()?.contains("bytecode"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relocate_package() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("lib".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        let relative = ConcreteResourceLocation::RelativePath("lib".to_string());
+
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"import os".to_vec()),
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &relative,
+        )?;
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo.bar".to_string(),
+                source: DataLocation::Memory(b"import sys".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &relative,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "data.txt".to_string(),
+                data: DataLocation::Memory(vec![42]),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &relative,
+        )?;
+        r.add_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "foo".to_string(),
+                version: "1.0".to_string(),
+                name: "RECORD".to_string(),
+                data: DataLocation::Memory(vec![]),
+            },
+            &relative,
+        )?;
+        r.add_python_extension_module(
+            &PythonExtensionModule {
+                name: "foo._speedups".to_string(),
+                init_fn: Some("PyInit__speedups".to_string()),
+                extension_file_suffix: ".so".to_string(),
+                shared_library: Some(DataLocation::Memory(vec![1])),
+                object_file_data: vec![],
+                is_package: false,
+                link_libraries: vec![],
+                is_stdlib: false,
+                builtin_default: false,
+                required: false,
+                variant: None,
+                licenses: None,
+                license_texts: None,
+                license_public_domain: None,
+            },
+            &relative,
+        )?;
         r.add_python_module_source(
             &PythonModuleSource {
-                name: "foo".to_string(),
-                source: DataLocation::Memory(vec![42]),
+                name: "foobar".to_string(),
+                source: DataLocation::Memory(vec![]),
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
                 is_test: false,
             },
-            &ConcreteResourceLocation::InMemory,
+            &relative,
         )?;
 
-        assert!(r.resources.contains_key("foo"));
+        assert!(r
+            .relocate_package(
+                "missing",
+                &ConcreteResourceLocation::InMemory,
+                &RelocationConstraints::default()
+            )
+            .is_err());
+
+        let report = r.relocate_package(
+            "foo",
+            &ConcreteResourceLocation::InMemory,
+            &RelocationConstraints::default(),
+        )?;
         assert_eq!(
-            r.resources.get("foo"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "foo".to_string(),
-                is_package: false,
-                in_memory_source: Some(DataLocation::Memory(vec![42])),
-                ..PrePackagedResource::default()
-            })
+            report
+                .moved
+                .iter()
+                .map(|m| (m.resource.as_str(), m.payload.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("foo", "source".to_string()),
+                ("foo", "package resource data.txt".to_string()),
+                ("foo", "distribution resource RECORD".to_string()),
+                ("foo.bar", "bytecode".to_string()),
+            ]
         );
+        assert_eq!(report.moved[0].from, "filesystem-relative:lib");
+        assert_eq!(report.moved[0].to, "in-memory");
+        assert_eq!(
+            report.left_behind,
+            vec![(
+                "foo._speedups".to_string(),
+                "extension modules cannot be loaded from memory by this binary".to_string()
+            )]
+        );
+        assert!(report.forced.is_empty());
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let resources = r.compile_resources(&mut compiler)?;
+        let foo = r.resources.get("foo").unwrap();
+        assert_eq!(
+            foo.in_memory_source,
+            Some(DataLocation::Memory(b"import os".to_vec()))
+        );
+        assert!(foo.relative_path_module_source.is_none());
+        assert!(foo.relative_path_package_resources.is_none());
+        assert!(foo
+            .in_memory_distribution_resources
+            .as_ref()
+            .unwrap()
+            .contains_key("RECORD"));
+        assert!(r
+            .resources
+            .get("foo.bar")
+            .unwrap()
+            .in_memory_bytecode
+            .is_some());
+        assert!(r
+            .resources
+            .get("foobar")
+            .unwrap()
+            .relative_path_module_source
+            .is_some());
+
+        let report = r.relocate_package(
+            "foo",
+            &ConcreteResourceLocation::RelativePath("other".to_string()),
+            &RelocationConstraints {
+                extension_modules_filesystem: true,
+                extension_file_suffix: Some(".so".to_string()),
+                ..RelocationConstraints::default()
+            },
+        )?;
+        assert_eq!(report.moved.len(), 3);
 
-        assert_eq!(resources.resources.len(), 1);
+        let foo = r.resources.get("foo").unwrap();
+        assert!(foo.in_memory_source.is_none());
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo".to_string()),
-                in_memory_source: Some(Cow::Owned(vec![42])),
-                ..Resource::default()
-            })
+            foo.relative_path_module_source,
+            Some((
+                "other".to_string(),
+                DataLocation::Memory(b"import os".to_vec())
+            ))
+        );
+        assert_eq!(
+            foo.relative_path_package_resources
+                .as_ref()
+                .unwrap()
+                .get("data.txt")
+                .unwrap()
+                .0,
+            PathBuf::from("other").join("foo").join("data.txt")
+        );
+        assert_eq!(
+            report.left_behind,
+            vec![(
+                "foo (distribution resource RECORD)".to_string(),
+                "the distribution's version is unknown because it has no METADATA or PKG-INFO"
+                    .to_string()
+            )]
+        );
+        assert_eq!(
+            r.resources
+                .get("foo.bar")
+                .unwrap()
+                .relative_path_bytecode
+                .as_ref()
+                .unwrap()
+                .0,
+            "other"
         );
-        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_source_module_parents() -> Result<()> {
-        let mut r =
-            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+    fn test_relocate_package_checks() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("lib".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+
         r.add_python_module_source(
             &PythonModuleSource {
-                name: "root.parent.child".to_string(),
-                source: DataLocation::Memory(vec![42]),
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"print(__file__)".to_vec()),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
                 is_test: false,
             },
+            &ConcreteResourceLocation::RelativePath("lib".to_string()),
+        )?;
+        r.add_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "foo".to_string(),
+                version: "1.0".to_string(),
+                name: "METADATA".to_string(),
+                data: DataLocation::Memory(b"Name: foo\nVersion: 1.0\n\nVersion: 2".to_vec()),
+            },
             &ConcreteResourceLocation::InMemory,
         )?;
 
-        assert_eq!(r.resources.len(), 1);
-        assert_eq!(
-            r.resources.get("root.parent.child"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent.child".to_string(),
-                is_package: true,
-                in_memory_source: Some(DataLocation::Memory(vec![42])),
-                ..PrePackagedResource::default()
-            })
-        );
-
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let resources = r.compile_resources(&mut compiler)?;
+        let err = r
+            .relocate_package(
+                "foo",
+                &ConcreteResourceLocation::InMemory,
+                &RelocationConstraints::default(),
+            )
+            .unwrap_err();
+        assert!(err.to_string().contains("foo: module uses __file__"));
+        assert!(r
+            .resources
+            .get("foo")
+            .unwrap()
+            .relative_path_module_source
+            .is_some());
 
-        assert_eq!(resources.resources.len(), 3);
-        assert_eq!(
-            resources.resources.get("root"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("root".to_string()),
-                is_package: true,
-                in_memory_source: Some(Cow::Owned(vec![])),
-                ..Resource::default()
-            })
-        );
-        assert_eq!(
-            resources.resources.get("root.parent"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("root.parent".to_string()),
-                is_package: true,
-                in_memory_source: Some(Cow::Owned(vec![])),
-                ..Resource::default()
-            })
-        );
+        let report = r.relocate_package(
+            "foo",
+            &ConcreteResourceLocation::InMemory,
+            &RelocationConstraints {
+                force: true,
+                ..RelocationConstraints::default()
+            },
+        )?;
+        assert_eq!(report.moved.len(), 1);
+        assert_eq!(report.forced.len(), 1);
+        assert!(r.resources.get("foo").unwrap().in_memory_source.is_some());
+
+        let report = r.relocate_package(
+            "foo",
+            &ConcreteResourceLocation::RelativePath("lib".to_string()),
+            &RelocationConstraints::default(),
+        )?;
+        assert_eq!(report.moved.len(), 2);
+        assert!(report.left_behind.is_empty());
         assert_eq!(
-            resources.resources.get("root.parent.child"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("root.parent.child".to_string()),
-                is_package: true,
-                in_memory_source: Some(Cow::Owned(vec![42])),
-                ..Resource::default()
-            })
+            r.resources
+                .get("foo")
+                .unwrap()
+                .relative_path_distribution_resources
+                .as_ref()
+                .unwrap()
+                .get("METADATA")
+                .unwrap()
+                .0,
+            PathBuf::from("lib")
+                .join("foo-1.0.dist-info")
+                .join("METADATA")
         );
 
-        assert!(resources.extra_files.is_empty());
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(vec![]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert!(r
+            .relocate_package(
+                "foo",
+                &ConcreteResourceLocation::RelativePath("lib".to_string()),
+                &RelocationConstraints::default()
+            )
+            .is_err());
 
         Ok(())
     }
 
     #[test]
-    fn test_add_relative_path_source_module() -> Result<()> {
+    fn test_compile_pending_bytecode() -> Result<()> {
         let mut r = PythonResourceCollector::new(
-            &PythonResourcesPolicy::FilesystemRelativeOnly("".to_string()),
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("".to_string()),
             DEFAULT_CACHE_TAG,
         );
-        r.add_python_module_source(
-            &PythonModuleSource {
-                name: "foo.bar".to_string(),
+        assert_eq!(r.pending_bytecode_count(), 0);
+
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
                 source: DataLocation::Memory(vec![42]),
+                optimize_level: BytecodeOptimizationLevel::Zero,
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
                 is_test: false,
             },
-            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+            &ConcreteResourceLocation::InMemory,
         )?;
-
-        assert_eq!(r.resources.len(), 1);
-        assert_eq!(
-            r.resources.get("foo.bar"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "foo.bar".to_string(),
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(vec![42]),
+                optimize_level: BytecodeOptimizationLevel::One,
                 is_package: false,
-                relative_path_module_source: Some((
-                    "prefix".to_string(),
-                    DataLocation::Memory(vec![42])
-                )),
-                ..PrePackagedResource::default()
-            })
-        );
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
+        r.add_python_module_bytecode(
+            &PythonModuleBytecode::new(
+                "bar",
+                BytecodeOptimizationLevel::Zero,
+                false,
+                DEFAULT_CACHE_TAG,
+                &[1],
+            ),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert_eq!(r.pending_bytecode_count(), 2);
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        let mut compiler = FakeBytecodeCompiler::default();
+        let report = r.compile_pending_bytecode(&mut compiler)?;
 
-        let resources = r.compile_resources(&mut compiler)?;
+        assert!(report.is_success());
+        assert_eq!(report.compiled.len(), 2);
+        assert_eq!(r.pending_bytecode_count(), 0);
 
-        assert_eq!(resources.resources.len(), 2);
+        let entry = r.resources.get("foo").unwrap();
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo".to_string()),
-                is_package: true,
-                relative_path_module_source: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/__init__.py"
-                ))),
-                ..Resource::default()
-            })
+            entry.in_memory_bytecode,
+            Some(PythonModuleBytecodeProvider::Provided(
+                DataLocation::Memory(b"bc0\x2a".to_vec())
+            ))
         );
         assert_eq!(
-            resources.resources.get("foo.bar"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo.bar".to_string()),
-                relative_path_module_source: Some(Cow::Owned(PathBuf::from("prefix/foo/bar.py"))),
-                ..Resource::default()
-            })
+            entry.relative_path_bytecode_opt1,
+            Some((
+                "prefix".to_string(),
+                DEFAULT_CACHE_TAG.to_string(),
+                PythonModuleBytecodeProvider::Provided(DataLocation::Memory(b"bc1\x2a".to_vec()))
+            ))
         );
         assert_eq!(
-            resources.extra_files,
-            vec![
-                (
-                    PathBuf::from("prefix/foo/__init__.py"),
-                    DataLocation::Memory(vec![]),
-                    false
-                ),
-                (
-                    PathBuf::from("prefix/foo/bar.py"),
-                    DataLocation::Memory(vec![42]),
-                    false
-                )
-            ]
+            entry.pyc_payloads,
+            BTreeSet::from_iter(vec!["bytecode-opt1".to_string()])
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_bytecode_module() -> Result<()> {
-        let mut r =
-            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
-        r.add_python_module_bytecode(
-            &PythonModuleBytecode::new(
-                "foo",
-                BytecodeOptimizationLevel::Zero,
-                false,
-                DEFAULT_CACHE_TAG,
-                &[42],
-            ),
-            &ConcreteResourceLocation::InMemory,
-        )?;
+    fn test_compile_pending_bytecode_relative_path_pyc() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let dest = temp_dir.path();
 
-        assert!(r.resources.contains_key("foo"));
-        assert_eq!(
-            r.resources.get("foo"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "foo".to_string(),
-                in_memory_bytecode: Some(PythonModuleBytecodeProvider::Provided(
-                    DataLocation::Memory(vec![42])
-                )),
-                is_package: false,
-                ..PrePackagedResource::default()
-            })
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
         );
+        r.set_pyc_invalidation_mode(PycInvalidationMode::CheckedHash);
+
+        for optimize_level in &[
+            BytecodeOptimizationLevel::Zero,
+            BytecodeOptimizationLevel::One,
+        ] {
+            r.add_python_module_bytecode_from_source(
+                &PythonModuleBytecodeFromSource {
+                    name: "foo".to_string(),
+                    source: DataLocation::Memory(b"source".to_vec()),
+                    optimize_level: *optimize_level,
+                    is_package: false,
+                    cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+            )?;
+        }
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        let mut compiler = FakeBytecodeCompiler {
+            pyc_headers: true,
+            ..FakeBytecodeCompiler::default()
+        };
 
-        let resources = r.compile_resources(&mut compiler)?;
+        let mut pyc = compute_bytecode_header(42, BytecodeHeaderMode::CheckedHash(6))?;
+        pyc.extend(b"bc0source");
 
-        assert_eq!(resources.resources.len(), 1);
+        let wanted = r.compile_resources(&mut compiler)?.extra_files;
+        assert!(wanted.contains(&(
+            PathBuf::from("prefix/__pycache__/foo.cpython-37.pyc"),
+            DataLocation::Memory(pyc.clone()),
+            false
+        )));
+
+        // Compiling ahead of time installs the same .pyc files.
+        let report = r.compile_pending_bytecode(&mut compiler)?;
+        assert!(report.is_success());
+        assert_eq!(r.pending_bytecode_count(), 0);
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo".to_string()),
-                in_memory_bytecode: Some(Cow::Owned(vec![42])),
-                ..Resource::default()
-            })
+            r.resources["foo"].pyc_payloads,
+            BTreeSet::from_iter(vec!["bytecode".to_string(), "bytecode-opt1".to_string()])
+        );
+        assert_eq!(r.compile_resources(&mut compiler)?.extra_files, wanted);
+
+        let path = r.extract_resource("foo", &PayloadKind::Bytecode, Some(&mut compiler), dest)?;
+        assert_eq!(std::fs::read(&path)?, pyc);
+
+        // Bytecode moved into memory loses its .pyc header.
+        r.relocate_package(
+            "foo",
+            &ConcreteResourceLocation::InMemory,
+            &RelocationConstraints::default(),
+        )?;
+        let entry = &r.resources["foo"];
+        assert!(entry.pyc_payloads.is_empty());
+        assert_eq!(
+            entry.in_memory_bytecode,
+            Some(PythonModuleBytecodeProvider::Provided(
+                DataLocation::Memory(b"bc0source".to_vec())
+            ))
         );
-        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_bytecode_module_from_source() -> Result<()> {
+    fn test_compile_pending_bytecode_failure() -> Result<()> {
         let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
         r.add_python_module_bytecode_from_source(
             &PythonModuleBytecodeFromSource {
                 name: "foo".to_string(),
-                source: DataLocation::Memory(vec![42]),
+                source: DataLocation::from_path(PathBuf::from("/does/not/exist.py")),
                 optimize_level: BytecodeOptimizationLevel::Zero,
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
@@ -2448,48 +9817,96 @@ mod tests {
             &ConcreteResourceLocation::InMemory,
         )?;
 
-        assert!(r.resources.contains_key("foo"));
-        assert_eq!(
-            r.resources.get("foo"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "foo".to_string(),
-                in_memory_bytecode: Some(PythonModuleBytecodeProvider::FromSource(
-                    DataLocation::Memory(vec![42])
-                )),
-                is_package: false,
-                ..PrePackagedResource::default()
-            })
+        let mut compiler = FakeBytecodeCompiler::default();
+        let report = r.compile_pending_bytecode(&mut compiler)?;
+
+        assert!(!report.is_success());
+        assert_eq!(report.failures.len(), 1);
+        assert_eq!(report.failures[0].0, "foo");
+        assert_eq!(r.pending_bytecode_count(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compile_error_policy() -> Result<()> {
+        let mut compiler = FakeBytecodeCompiler {
+            python3: true,
+            ..FakeBytecodeCompiler::default()
+        };
+
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("".to_string()),
+            DEFAULT_CACHE_TAG,
         );
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        for (name, source, location) in &[
+            ("good", &b"x = 1"[..], ConcreteResourceLocation::InMemory),
+            ("bad", b"print 'hi'", ConcreteResourceLocation::InMemory),
+            (
+                "bad_fs",
+                b"print 'hi'",
+                ConcreteResourceLocation::RelativePath("".to_string()),
+            ),
+        ] {
+            r.add_python_module_bytecode_from_source(
+                &PythonModuleBytecodeFromSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(source.to_vec()),
+                    optimize_level: BytecodeOptimizationLevel::Zero,
+                    is_package: false,
+                    cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                location,
+            )?;
+        }
+
+        let err = r.compile_resources(&mut compiler).err().unwrap();
+        let error = err.downcast_ref::<CompileError>().unwrap();
+        assert_eq!(error.module, "bad");
+        assert_eq!(error.line, Some(1));
 
-        let resources = r.compile_resources(&mut compiler)?;
+        r.set_compile_error_policy(CompileErrorPolicy::Skip);
+        let compiled = r.compile_resources(&mut compiler)?;
+        assert_eq!(compiled.resources.keys().collect::<Vec<_>>(), vec!["good"]);
+        assert!(compiled.extra_files.is_empty());
+        assert_eq!(
+            compiled
+                .compile_errors
+                .iter()
+                .map(|error| error.module.as_str())
+                .collect::<Vec<_>>(),
+            vec!["bad", "bad_fs"]
+        );
 
-        assert_eq!(resources.resources.len(), 1);
+        r.set_compile_error_policy(CompileErrorPolicy::SourceOnly);
+        let compiled = r.compile_resources(&mut compiler)?;
+        assert_eq!(compiled.compile_errors.len(), 2);
+        let bad = &compiled.resources["bad"];
+        assert_eq!(bad.in_memory_source.as_deref(), Some(&b"print 'hi'"[..]));
+        assert!(bad.in_memory_bytecode.is_none());
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo".to_string()),
-                in_memory_bytecode: Some(Cow::Owned(b"bc0\x2a".to_vec())),
-                ..Resource::default()
-            })
+            compiled
+                .extra_files
+                .iter()
+                .map(|(path, _, _)| path.clone())
+                .collect::<Vec<_>>(),
+            vec![PathBuf::from("bad_fs.py")]
         );
-        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_bytecode_module_parents() -> Result<()> {
+    fn test_add_packed_resources_in_memory() -> Result<()> {
         let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
-        r.add_python_module_bytecode_from_source(
-            &PythonModuleBytecodeFromSource {
-                name: "root.parent.child".to_string(),
-                source: DataLocation::Memory(vec![42]),
-                optimize_level: BytecodeOptimizationLevel::One,
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"source".to_vec()),
                 is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
@@ -2497,559 +9914,1322 @@ mod tests {
             },
             &ConcreteResourceLocation::InMemory,
         )?;
+        r.add_python_module_bytecode(
+            &PythonModuleBytecode::new(
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                true,
+                DEFAULT_CACHE_TAG,
+                b"bytecode",
+            ),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "resource.txt".to_string(),
+                data: DataLocation::Memory(b"data".to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
-        assert_eq!(r.resources.len(), 1);
+        let mut compiler = FakeBytecodeCompiler::default();
+        let mut packed = Vec::new();
+        r.compile_resources(&mut compiler)?
+            .write_packed_resources_v1(&mut packed)?;
+        let packed = Arc::new(packed);
+
+        let mut loaded =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        loaded.add_packed_resources(&packed, DEFAULT_CACHE_TAG, None)?;
+
+        let entry = loaded.resources.get("foo").unwrap();
+        assert!(entry.is_package);
+        match &entry.in_memory_source {
+            Some(DataLocation::Slice { data, .. }) => assert!(Arc::ptr_eq(data, &packed)),
+            _ => panic!("expected source to reference packed resources"),
+        }
         assert_eq!(
-            r.resources.get("root.parent.child"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "root.parent.child".to_string(),
-                in_memory_bytecode_opt1: Some(PythonModuleBytecodeProvider::FromSource(
-                    DataLocation::Memory(vec![42])
-                )),
-                is_package: true,
-                ..PrePackagedResource::default()
-            })
+            entry.in_memory_source.as_ref().unwrap().resolve()?,
+            b"source".to_vec()
+        );
+        assert_eq!(
+            entry.in_memory_resources.as_ref().unwrap()["resource.txt"].resolve()?,
+            b"data".to_vec()
+        );
+        assert_eq!(loaded.pending_bytecode_count(), 0);
+
+        // Re-serializing produces identical output.
+        let mut repacked = Vec::new();
+        loaded
+            .compile_resources(&mut compiler)?
+            .write_packed_resources_v1(&mut repacked)?;
+        assert_eq!(&repacked, packed.as_ref());
+
+        // Packed resources with a lookup table load the same resources.
+        let mut packed_v3 = Vec::new();
+        r.compile_resources(&mut compiler)?
+            .write_packed_resources(&mut packed_v3, IndexEncoding::LookupTable)?;
+        let mut loaded_v3 =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        loaded_v3.add_packed_resources(&Arc::new(packed_v3), DEFAULT_CACHE_TAG, None)?;
+        let mut repacked = Vec::new();
+        loaded_v3
+            .compile_resources(&mut compiler)?
+            .write_packed_resources_v1(&mut repacked)?;
+        assert_eq!(&repacked, packed.as_ref());
+
+        // Policy is enforced.
+        let mut relative = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        assert!(relative
+            .add_packed_resources(&packed, DEFAULT_CACHE_TAG, None)
+            .is_err());
+
+        // Packed resources built for another Python version are rejected.
+        let mut other_tag =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, "cpython-38");
+        assert_eq!(
+            other_tag
+                .add_packed_resources(&packed, DEFAULT_CACHE_TAG, None)
+                .unwrap_err()
+                .to_string(),
+            "packed resources were built for cpython-37 but the distribution uses cpython-38"
         );
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        Ok(())
+    }
 
-        let resources = r.compile_resources(&mut compiler)?;
+    #[test]
+    fn test_recovery_set() -> Result<()> {
+        let module = |name: &str, source: &[u8]| -> Result<PythonResourceCollector> {
+            let mut r = PythonResourceCollector::new(
+                &PythonResourcesPolicy::InMemoryOnly,
+                DEFAULT_CACHE_TAG,
+            );
+            r.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(source.to_vec()),
+                    is_package: false,
+                    cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+            Ok(r)
+        };
 
-        assert_eq!(resources.resources.len(), 3);
+        let shared = vec![42; 1000];
+        let mut compiler = FakeBytecodeCompiler::default();
+        let main_collector = module("os", &shared)?;
+        let main = main_collector.compile_resources(&mut compiler)?;
+        let mut recovery_collector = module("os", &shared)?;
+        recovery_collector.add_python_module_source(
+            &PythonModuleSource {
+                name: "diagnostics".to_string(),
+                source: DataLocation::Memory(b"print('recovering')".to_vec()),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        let recovery = recovery_collector.compile_resources(&mut compiler)?;
+
+        let mut packed = Vec::new();
+        main.write_packed_resources_with_recovery(
+            &mut packed,
+            IndexEncoding::Sequential,
+            Some(&recovery),
+        )?;
+        let loaded = python_packed_resources::parser::load_resources(&packed)
+            .unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
         assert_eq!(
-            resources.resources.get("root"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("root".to_string()),
-                is_package: true,
-                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1".to_vec())),
-                ..Resource::default()
-            })
+            loaded
+                .iter()
+                .map(|r| (&*r.name, r.resource_set))
+                .collect::<Vec<_>>(),
+            vec![
+                ("os", ResourceSet::Main),
+                ("diagnostics", ResourceSet::Recovery),
+                ("os", ResourceSet::Recovery),
+            ]
         );
+
+        // Only the main set is read back into a collector.
+        let mut reloaded =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        reloaded.add_packed_resources(&Arc::new(packed.clone()), DEFAULT_CACHE_TAG, None)?;
+        assert_eq!(reloaded.resources.keys().collect::<Vec<_>>(), vec!["os"]);
+
+        // The source of os is stored once for both sets.
         assert_eq!(
-            resources.resources.get("root.parent"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("root.parent".to_string()),
-                is_package: true,
-                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1".to_vec())),
-                ..Resource::default()
-            })
+            main.shared_payloads_with_recovery(Some(&recovery))
+                .shared_count,
+            1
         );
+
+        // Savings account for the padding packed resources are written with.
+        let padded = CompiledResourcesCollection {
+            interior_padding: Some(BlobInteriorPadding::Null),
+            ..main.clone()
+        };
+        let mut padded_packed = Vec::new();
+        padded.write_packed_resources_with_recovery(
+            &mut padded_packed,
+            IndexEncoding::Sequential,
+            Some(&recovery),
+        )?;
+        assert!(padded_packed.len() > packed.len());
         assert_eq!(
-            resources.resources.get("root.parent.child"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("root.parent.child".to_string()),
-                is_package: true,
-                in_memory_bytecode_opt1: Some(Cow::Owned(b"bc1\x2a".to_vec())),
-                ..Resource::default()
-            })
+            padded
+                .shared_payloads_with_recovery(Some(&recovery))
+                .saved_bytes,
+            main.shared_payloads_with_recovery(Some(&recovery))
+                .saved_bytes
+                + 1
         );
-        assert!(resources.extra_files.is_empty());
+        let cost = main.recovery_set_cost(&recovery, IndexEncoding::Sequential)?;
+        assert_eq!(cost.resources_count, 2);
+        assert!(cost.standalone_bytes > shared.len());
+        assert!(cost.incremental_bytes < cost.standalone_bytes - shared.len() / 2);
+
+        let mut main_only = Vec::new();
+        main.write_packed_resources(&mut main_only, IndexEncoding::Sequential)?;
+        assert_eq!(main_only.len() + cost.incremental_bytes, packed.len());
 
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_package_resource() -> Result<()> {
+    fn test_write_packed_resources_to_sink() -> Result<()> {
+        #[derive(Default)]
+        struct MemoryStore {
+            objects: BTreeMap<String, Vec<u8>>,
+        }
+
+        impl python_packed_resources::sink::ObjectStoreSink for MemoryStore {
+            fn backend_name(&self) -> String {
+                "memory".to_string()
+            }
+
+            fn put(&mut self, digest_hex: &str, data: &[u8]) -> Result<String> {
+                self.objects.insert(digest_hex.to_string(), data.to_vec());
+                Ok(digest_hex.to_string())
+            }
+        }
+
         let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
-        r.add_python_package_resource(
-            &PythonPackageResource {
-                leaf_package: "foo".to_string(),
-                relative_name: "resource.txt".to_string(),
-                data: DataLocation::Memory(vec![42]),
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(vec![42; 1000]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
                 is_test: false,
             },
             &ConcreteResourceLocation::InMemory,
         )?;
+        let mut compiler = FakeBytecodeCompiler::default();
+        let compiled = r.compile_resources(&mut compiler)?;
 
-        assert_eq!(r.resources.len(), 1);
+        let mut embedded = Vec::new();
+        compiled.write_packed_resources(&mut embedded, IndexEncoding::Sequential)?;
+
+        let mut store = MemoryStore::default();
+        let mut packed = Vec::new();
+        let externalized = compiled.write_packed_resources_to_sink(
+            &mut packed,
+            IndexEncoding::Sequential,
+            None,
+            &mut store,
+        )?;
+        assert_eq!(externalized.payloads_count, 1);
+        assert_eq!(externalized.payloads_bytes, 1000);
+        assert_eq!(store.objects.values().next(), Some(&vec![42; 1000]));
+        assert!(packed.len() < embedded.len() - 800);
+
+        let loaded = python_packed_resources::parser::load_resources(&packed)
+            .unwrap()
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(loaded[0].in_memory_source.is_none());
         assert_eq!(
-            r.resources.get("foo"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "foo".to_string(),
-                is_package: true,
-                in_memory_resources: Some(BTreeMap::from_iter(
-                    [("resource.txt".to_string(), DataLocation::Memory(vec![42]))]
-                        .iter()
-                        .cloned()
-                )),
-                ..PrePackagedResource::default()
-            })
+            loaded[0].external_payloads[0].location,
+            *store.objects.keys().next().unwrap()
         );
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        // Packed resources that aren't self-contained can't be reused.
+        let mut reloaded =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        assert!(reloaded
+            .add_packed_resources(&Arc::new(packed), DEFAULT_CACHE_TAG, None)
+            .is_err());
 
-        let resources = r.compile_resources(&mut compiler)?;
+        Ok(())
+    }
 
-        assert_eq!(resources.resources.len(), 1);
+    #[test]
+    fn test_add_packed_resources_relative_path() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        r.add_python_module_bytecode(
+            &PythonModuleBytecode::new(
+                "foo",
+                BytecodeOptimizationLevel::Zero,
+                true,
+                DEFAULT_CACHE_TAG,
+                b"package",
+            ),
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
+        r.add_python_module_bytecode(
+            &PythonModuleBytecode::new(
+                "foo.bar",
+                BytecodeOptimizationLevel::Zero,
+                false,
+                DEFAULT_CACHE_TAG,
+                b"bytecode",
+            ),
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
+
+        // Bytecode read back from files must match the cache tag.
+        let mut compiler = FakeBytecodeCompiler {
+            magic_number: magic_number_for_python_version("3.7").unwrap(),
+        };
+        let compiled = r.compile_resources(&mut compiler)?;
+        let mut packed = Vec::new();
+        compiled.write_packed_resources_v1(&mut packed)?;
+        let packed = Arc::new(packed);
+
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        for (path, location, _) in &compiled.extra_files {
+            let dest = temp_dir.path().join(path);
+            std::fs::create_dir_all(dest.parent().unwrap())?;
+            std::fs::write(&dest, location.resolve()?)?;
+        }
+
+        let mut loaded = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo".to_string()),
-                is_package: true,
-                in_memory_package_resources: Some(HashMap::from_iter(
-                    [(Cow::Owned("resource.txt".to_string()), Cow::Owned(vec![42]))]
-                        .iter()
-                        .cloned()
-                )),
-                ..Resource::default()
-            })
+            loaded
+                .add_packed_resources(&packed, DEFAULT_CACHE_TAG, None)
+                .unwrap_err()
+                .to_string(),
+            "packed resources reference relative path prefix/foo/__pycache__/__init__.cpython-37.pyc but no install root was provided"
         );
-        assert!(resources.extra_files.is_empty());
+
+        loaded.add_packed_resources(&packed, DEFAULT_CACHE_TAG, Some(temp_dir.path()))?;
+        let (prefix, cache_tag, provider) = loaded
+            .resources
+            .get("foo.bar")
+            .unwrap()
+            .relative_path_bytecode
+            .clone()
+            .unwrap();
+        assert_eq!(prefix, "prefix");
+        assert_eq!(cache_tag, DEFAULT_CACHE_TAG);
+
+        // Bytecode is referenced in its file rather than read.
+        let bytecode = match provider {
+            PythonModuleBytecodeProvider::Provided(location) => location,
+            _ => panic!("expected provided bytecode"),
+        };
+        assert!(matches!(
+            &bytecode,
+            DataLocation::FileRange {
+                offset: 16,
+                length: 8,
+                ..
+            }
+        ));
+        assert_eq!(bytecode.resolve()?, b"bytecode");
+
+        // Bytecode headers are still checked up front.
+        let pyc = temp_dir
+            .path()
+            .join("prefix/foo/__pycache__/__init__.cpython-37.pyc");
+        let mut data = std::fs::read(&pyc)?;
+        data[0..4].copy_from_slice(
+            &magic_number_for_python_version("3.8")
+                .unwrap()
+                .to_le_bytes(),
+        );
+        std::fs::write(&pyc, &data)?;
+        let mut mismatched = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        assert!(mismatched
+            .add_packed_resources(&packed, DEFAULT_CACHE_TAG, Some(temp_dir.path()))
+            .is_err());
+
+        let mut other_tag = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
+            "cpython-38",
+        );
+        assert!(other_tag
+            .add_packed_resources(&packed, "cpython-38", Some(temp_dir.path()))
+            .is_err());
 
         Ok(())
     }
 
     #[test]
-    fn test_add_relative_path_package_resource() -> Result<()> {
+    fn test_map_data_locations() -> Result<()> {
         let mut r = PythonResourceCollector::new(
-            &PythonResourcesPolicy::FilesystemRelativeOnly("".to_string()),
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("prefix".to_string()),
             DEFAULT_CACHE_TAG,
         );
+
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::from_path(PathBuf::from("/site/foo/__init__.py")),
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
+                source: DataLocation::from_path(PathBuf::from("/site/foo/__init__.py")),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        )?;
         r.add_python_package_resource(
             &PythonPackageResource {
                 leaf_package: "foo".to_string(),
-                relative_name: "resource.txt".to_string(),
-                data: DataLocation::Memory(vec![42]),
+                relative_name: "data.txt".to_string(),
+                data: DataLocation::from_path(PathBuf::from("/site/foo/data.txt")),
                 is_stdlib: false,
                 is_test: false,
             },
             &ConcreteResourceLocation::RelativePath("prefix".to_string()),
         )?;
 
-        assert_eq!(r.resources.len(), 1);
-        assert_eq!(
-            r.resources.get("foo"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "foo".to_string(),
-                is_package: true,
-                relative_path_package_resources: Some(BTreeMap::from_iter(
-                    [(
-                        "resource.txt".to_string(),
-                        (
-                            PathBuf::from("prefix/foo/resource.txt"),
-                            DataLocation::Memory(vec![42])
-                        )
-                    )]
-                    .iter()
-                    .cloned()
-                )),
-                ..PrePackagedResource::default()
+        r.map_data_locations(|location| {
+            Ok(match location {
+                DataLocation::Path { path, .. } => {
+                    DataLocation::Memory(path.to_string_lossy().as_bytes().to_vec())
+                }
+                location => location.clone(),
             })
-        );
-
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        })?;
 
-        let resources = r.compile_resources(&mut compiler)?;
-
-        assert_eq!(resources.resources.len(), 1);
+        let entry = r.resources.get("foo").unwrap();
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo".to_string()),
-                is_package: true,
-                relative_path_package_resources: Some(HashMap::from_iter(
-                    [(
-                        Cow::Owned("resource.txt".to_string()),
-                        Cow::Owned(PathBuf::from("prefix/foo/resource.txt")),
-                    )]
-                    .iter()
-                    .cloned()
-                )),
-                ..Resource::default()
-            })
+            entry.in_memory_source,
+            Some(DataLocation::Memory(b"/site/foo/__init__.py".to_vec()))
         );
         assert_eq!(
-            resources.extra_files,
-            vec![(
-                PathBuf::from("prefix/foo/resource.txt"),
-                DataLocation::Memory(vec![42]),
-                false
-            ),]
+            entry.relative_path_bytecode,
+            Some((
+                "prefix".to_string(),
+                DEFAULT_CACHE_TAG.to_string(),
+                PythonModuleBytecodeProvider::FromSource(DataLocation::Memory(
+                    b"/site/foo/__init__.py".to_vec()
+                ))
+            ))
+        );
+        assert_eq!(
+            entry
+                .relative_path_package_resources
+                .as_ref()
+                .unwrap()
+                .get("data.txt")
+                .unwrap()
+                .1,
+            DataLocation::Memory(b"/site/foo/data.txt".to_vec())
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_package_distribution_resource() -> Result<()> {
+    fn test_apply_package_patch() -> Result<()> {
         let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
         r.add_package_distribution_resource(
             &PythonPackageDistributionResource {
                 location: PythonPackageDistributionResourceFlavor::DistInfo,
-                package: "mypackage".to_string(),
-                version: "1.0".to_string(),
-                name: "resource.txt".to_string(),
-                data: DataLocation::Memory(vec![42]),
+                package: "Foo".to_string(),
+                version: "1.2".to_string(),
+                name: "METADATA".to_string(),
+                data: DataLocation::Memory(b"Name: Foo\nVersion: 1.2\n".to_vec()),
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"import os\nPATH = '/usr/share/foo'\n".to_vec()),
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"import os\nPATH = '/usr/share/foo'\n".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "data/config.txt".to_string(),
+                data: DataLocation::Memory(b"debug = true\n".to_vec()),
+                is_stdlib: false,
+                is_test: false,
             },
             &ConcreteResourceLocation::InMemory,
         )?;
 
-        assert_eq!(r.resources.len(), 1);
+        assert_eq!(r.distribution_version("foo")?, Some("1.2".to_string()));
+        assert_eq!(r.distribution_version("bar")?, None);
+
+        let diff = "--- a/foo/__init__.py
++++ b/foo/__init__.py
+@@ -1,2 +1,2 @@
+ import os
+-PATH = '/usr/share/foo'
++PATH = os.path.dirname(__file__)
+--- a/foo/data/config.txt
++++ b/foo/data/config.txt
+@@ -1 +1 @@
+-debug = true
++debug = false
+";
+
+        let patch = PackagePatch::new("fix-path.patch", "bar", "", diff)?;
         assert_eq!(
-            r.resources.get("mypackage"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Module,
-                name: "mypackage".to_string(),
-                is_package: true,
-                in_memory_distribution_resources: Some(BTreeMap::from_iter(
-                    [("resource.txt".to_string(), DataLocation::Memory(vec![42]))]
-                        .iter()
-                        .cloned()
-                )),
-                ..PrePackagedResource::default()
-            })
+            r.apply_package_patch(&patch)?,
+            PackagePatchOutcome::DistributionMissing
         );
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        let patch = PackagePatch::new("fix-path.patch", "foo", ">=2", diff)?;
+        assert_eq!(
+            r.apply_package_patch(&patch)?,
+            PackagePatchOutcome::VersionMismatch("1.2".to_string())
+        );
 
-        let resources = r.compile_resources(&mut compiler)?;
+        let bad = PackagePatch::new(
+            "bad.patch",
+            "foo",
+            "",
+            "--- a/foo/__init__.py
++++ b/foo/__init__.py
+@@ -1,2 +1,2 @@
+ import sys
+-PATH = '/usr/share/foo'
++PATH = ''
+--- a/foo/missing.py
++++ b/foo/missing.py
+@@ -1 +1 @@
+-a
++b
+",
+        )?;
+        let err = r.apply_package_patch(&bad).unwrap_err().to_string();
+        assert!(err.starts_with("patch bad.patch can't be applied to foo 1.2: "));
+        assert!(err.contains("foo/__init__.py: hunk #1"));
+        assert!(err.contains("foo/missing.py: target file missing"));
+        assert!(r.resources["foo"].applied_patches.is_empty());
 
-        assert_eq!(resources.resources.len(), 1);
+        let patch = PackagePatch::new("fix-path.patch", "foo", ">=1,<2", diff)?;
         assert_eq!(
-            resources.resources.get("mypackage"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("mypackage".to_string()),
-                is_package: true,
-                in_memory_distribution_resources: Some(HashMap::from_iter(
-                    [(Cow::Owned("resource.txt".to_string()), Cow::Owned(vec![42]))]
-                        .iter()
-                        .cloned()
-                )),
-                ..Resource::default()
-            })
+            r.apply_package_patch(&patch)?,
+            PackagePatchOutcome::Applied("1.2".to_string())
         );
-        assert!(resources.extra_files.is_empty());
 
-        Ok(())
-    }
+        let patched = b"import os\nPATH = os.path.dirname(__file__)\n".to_vec();
+        let entry = &r.resources["foo"];
+        assert_eq!(
+            entry.in_memory_source,
+            Some(DataLocation::Memory(patched.clone()))
+        );
+        assert_eq!(
+            entry.in_memory_bytecode,
+            Some(PythonModuleBytecodeProvider::FromSource(
+                DataLocation::Memory(patched)
+            ))
+        );
+        assert_eq!(
+            entry.in_memory_resources.as_ref().unwrap()["data/config.txt"],
+            DataLocation::Memory(b"debug = false\n".to_vec())
+        );
+        assert!(entry.applied_patches.contains("fix-path.patch"));
+        assert!(resource_provenance(entry).ends_with(" patched by fix-path.patch"));
 
-    #[test]
-    fn test_add_relative_path_package_distribution_resource() -> Result<()> {
-        let mut r = PythonResourceCollector::new(
-            &PythonResourcesPolicy::FilesystemRelativeOnly("".to_string()),
-            DEFAULT_CACHE_TAG,
+        // Applying again is a no-op.
+        assert_eq!(
+            r.apply_package_patch(&patch)?,
+            PackagePatchOutcome::Applied("1.2".to_string())
         );
+
         r.add_package_distribution_resource(
             &PythonPackageDistributionResource {
                 location: PythonPackageDistributionResourceFlavor::DistInfo,
-                package: "mypackage".to_string(),
-                version: "1.0".to_string(),
-                name: "resource.txt".to_string(),
-                data: DataLocation::Memory(vec![42]),
+                package: "Bar".to_string(),
+                version: "latest".to_string(),
+                name: "METADATA".to_string(),
+                data: DataLocation::Memory(b"Name: Bar\nVersion: latest\n".to_vec()),
             },
-            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+            &ConcreteResourceLocation::InMemory,
         )?;
-
-        assert_eq!(r.resources.len(), 1);
+        let patch = PackagePatch::new("fix-path.patch", "bar", ">=1", diff)?;
         assert_eq!(
-            r.resources.get("mypackage"),
-            Some(&PrePackagedResource {
+            r.apply_package_patch(&patch)?,
+            PackagePatchOutcome::InvalidVersion("latest".to_string())
+        );
+
+        Ok(())
+    }
+
+    fn snapshot_collector(
+        fingerprint: &str,
+        modules: &[(&str, &[u8])],
+    ) -> Result<PythonResourceCollector> {
+        let mut c =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        c.set_distribution_fingerprint(Some(fingerprint.to_string()));
+
+        for (name, source) in modules {
+            c.add_python_module_source(
+                &PythonModuleSource {
+                    name: name.to_string(),
+                    source: DataLocation::Memory(source.to_vec()),
+                    is_package: false,
+                    cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
+
+        Ok(c)
+    }
+
+    #[test]
+    fn test_merge_collection() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let path = temp_dir.path().join("base.json");
+
+        let mut base = snapshot_collector("dist-1", &[("platform", b"base"), ("common", b"same")])?;
+        base.reserve_name("__manifest__", "manifest", SyntheticVisibility::Internal)?;
+        base.add_synthetic_resource(
+            "manifest",
+            PrePackagedResource {
                 flavor: ResourceFlavor::Module,
-                name: "mypackage".to_string(),
-                is_package: true,
-                relative_path_distribution_resources: Some(BTreeMap::from_iter(
-                    [(
-                        "resource.txt".to_string(),
-                        (
-                            PathBuf::from("prefix/mypackage-1.0.dist-info/resource.txt"),
-                            DataLocation::Memory(vec![42])
-                        )
-                    )]
-                    .iter()
-                    .cloned()
-                )),
+                name: "__manifest__".to_string(),
+                in_memory_source: Some(DataLocation::Memory(b"{}".to_vec())),
                 ..PrePackagedResource::default()
-            })
+            },
+        )?;
+
+        // Snapshots round trip through a file, embedding content.
+        base.snapshot("base").write_to_path(&path)?;
+        let snapshot = ResourceCollectionSnapshot::from_path(&path)?;
+        assert_eq!(snapshot, base.snapshot("base"));
+
+        let mut app = snapshot_collector("dist-1", &[("app", b"app"), ("common", b"same")])?;
+        app.merge_collection(
+            snapshot.clone(),
+            MergeStrategy::uniform(CollisionResolution::ContentEqualOk),
+        )?;
+        assert_eq!(
+            app.resources.keys().collect::<Vec<_>>(),
+            vec!["__manifest__", "app", "common", "platform"]
+        );
+        assert_eq!(
+            app.resource_origins("common"),
+            Some(vec![
+                "module from memory".to_string(),
+                "base: module from memory".to_string()
+            ])
+        );
+        assert_eq!(
+            app.resource_origins("platform"),
+            Some(vec!["base: module from memory".to_string()])
+        );
+        assert_eq!(
+            app.reserved_names().get("__manifest__").unwrap().feature,
+            "manifest"
         );
+        assert!(app.is_internal("__manifest__"));
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        // Content is compared regardless of where it lives.
+        let common = temp_dir.path().join("common.py");
+        std::fs::write(&common, b"same")?;
+        let mut app = snapshot_collector("dist-1", &[])?;
+        app.add_python_module_source(
+            &PythonModuleSource {
+                name: "common".to_string(),
+                source: DataLocation::from_path(&common),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        app.merge_collection(
+            snapshot.clone(),
+            MergeStrategy::uniform(CollisionResolution::ContentEqualOk),
+        )?;
+        assert!(app.resources.contains_key("platform"));
+
+        // Collisions with different content are resolved per strategy.
+        let mut app = snapshot_collector("dist-1", &[("common", b"different")])?;
+        let err = app
+            .merge_collection(
+                snapshot.clone(),
+                MergeStrategy::uniform(CollisionResolution::ContentEqualOk),
+            )
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "can't merge base: common (module from memory) collides with common from base: module from memory"
+        );
+        // A failed merge leaves the collection unchanged.
+        assert_eq!(app.resources.keys().collect::<Vec<_>>(), vec!["common"]);
 
-        let resources = r.compile_resources(&mut compiler)?;
+        app.merge_collection(
+            snapshot.clone(),
+            MergeStrategy::uniform(CollisionResolution::Ours),
+        )?;
+        assert_eq!(
+            app.resources["common"].in_memory_source,
+            Some(DataLocation::Memory(b"different".to_vec()))
+        );
 
-        assert_eq!(resources.resources.len(), 1);
+        let mut app = snapshot_collector("dist-1", &[("common", b"different")])?;
+        app.merge_collection(
+            snapshot.clone(),
+            MergeStrategy {
+                modules: CollisionResolution::Theirs,
+                ..MergeStrategy::default()
+            },
+        )?;
         assert_eq!(
-            resources.resources.get("mypackage"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("mypackage".to_string()),
-                is_package: true,
-                relative_path_distribution_resources: Some(HashMap::from_iter(
-                    [(
-                        Cow::Owned("resource.txt".to_string()),
-                        Cow::Owned(PathBuf::from("prefix/mypackage-1.0.dist-info/resource.txt")),
-                    )]
-                    .iter()
-                    .cloned()
-                )),
-                ..Resource::default()
-            })
+            app.resources["common"].in_memory_source,
+            Some(DataLocation::Memory(b"same".to_vec()))
         );
         assert_eq!(
-            resources.extra_files,
-            vec![(
-                PathBuf::from("prefix/mypackage-1.0.dist-info/resource.txt"),
-                DataLocation::Memory(vec![42]),
-                false
-            ),]
+            app.resource_origins("common"),
+            Some(vec![
+                "base: module from memory".to_string(),
+                "module from memory".to_string()
+            ])
+        );
+        assert_eq!(app.warnings().len(), 1);
+
+        // Reserved names of either side can't be taken by the other.
+        let mut claimed = snapshot_collector("dist-1", &[("__manifest__", b"mine")])?;
+        assert!(claimed
+            .merge_collection(snapshot, MergeStrategy::default())
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_collection_mismatch() -> Result<()> {
+        let base = snapshot_collector("dist-1", &[("platform", b"base")])?;
+
+        let mut app = snapshot_collector("dist-2", &[])?;
+        assert_eq!(
+            app.merge_collection(base.snapshot("base"), MergeStrategy::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            "can't merge base: it was collected for distribution dist-1 but this collection is for dist-2"
+        );
+
+        let mut app =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, "cpython-38");
+        app.set_distribution_fingerprint(Some("dist-1".to_string()));
+        assert!(app
+            .merge_collection(base.snapshot("base"), MergeStrategy::default())
+            .is_err());
+
+        // The policy of the collection being merged into applies.
+        let mut app = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("lib".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        app.set_distribution_fingerprint(Some("dist-1".to_string()));
+        assert!(app
+            .merge_collection(base.snapshot("base"), MergeStrategy::default())
+            .is_err());
+
+        // Extension modules must be loadable by the interpreter.
+        let mut pypy = base.snapshot("base");
+        pypy.resources.insert(
+            "ext".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::Extension,
+                name: "ext".to_string(),
+                relative_path_extension_module_shared_library: Some((
+                    PathBuf::from("lib/ext.pypy37-pp73-x86_64-linux-gnu.so"),
+                    DataLocation::Memory(vec![]),
+                )),
+                ..PrePackagedResource::default()
+            },
+        );
+        let mut app = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("lib".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        app.set_distribution_fingerprint(Some("dist-1".to_string()));
+        assert_eq!(
+            app.merge_collection(pypy, MergeStrategy::default())
+                .err()
+                .unwrap()
+                .to_string(),
+            "can't merge base: ext has extension module suffix .pypy37-pp73-x86_64-linux-gnu.so incompatible with cpython"
         );
 
         Ok(())
     }
 
     #[test]
-    fn test_add_builtin_python_extension_module() -> Result<()> {
-        let mut c =
+    fn test_memory_budget() -> Result<()> {
+        let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_memory_budget(Some(10))?;
 
-        let em = PythonExtensionModule {
-            name: "_io".to_string(),
-            init_fn: Some("PyInit__io".to_string()),
-            extension_file_suffix: "".to_string(),
-            shared_library: None,
-            object_file_data: vec![],
+        let module = |name: &str, source: &[u8]| PythonModuleSource {
+            name: name.to_string(),
+            source: DataLocation::Memory(source.to_vec()),
             is_package: false,
-            link_libraries: vec![],
-            is_stdlib: true,
-            builtin_default: true,
-            required: true,
-            variant: None,
-            licenses: None,
-            license_texts: None,
-            license_public_domain: None,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test: false,
         };
 
-        c.add_builtin_python_extension_module(&em)?;
-        assert_eq!(c.resources.len(), 1);
+        r.add_python_module_source(
+            &module("foo", b"source"),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_module_source(
+            &module("bar", b"second"),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "resource.txt".to_string(),
+                data: DataLocation::Memory(b"data".to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+
         assert_eq!(
-            c.resources.get("_io"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::BuiltinExtensionModule,
-                name: "_io".to_string(),
-                ..PrePackagedResource::default()
+            r.memory_statistics(),
+            Some(CollectionMemoryStatistics {
+                resident_bytes: 10,
+                peak_resident_bytes: 10,
+                spilled_count: 1,
+                spilled_bytes: 6,
             })
         );
+        assert!(matches!(
+            r.resources.get("foo").unwrap().in_memory_source,
+            Some(DataLocation::Memory(_))
+        ));
+        let spilled = match &r.resources.get("bar").unwrap().in_memory_source {
+            Some(DataLocation::Path { path, .. }) => path.clone(),
+            _ => panic!("expected bar to be spilled"),
+        };
+        assert_eq!(std::fs::read(&spilled)?, b"second".to_vec());
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        // Removing resources frees their share of the budget.
+        r.filter_resources_mut(|resource| resource.name != "foo")?;
+        assert_eq!(r.memory_statistics().unwrap().resident_bytes, 0);
 
-        let resources = c.compile_resources(&mut compiler)?;
+        // Spilled payloads are read back when serializing.
+        let mut compiler = FakeBytecodeCompiler::default();
+        let mut packed = Vec::new();
+        r.compile_resources(&mut compiler)?
+            .write_packed_resources_v1(&mut packed)?;
 
-        assert_eq!(resources.resources.len(), 1);
+        let mut loaded =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        loaded.add_packed_resources(&Arc::new(packed), DEFAULT_CACHE_TAG, None)?;
         assert_eq!(
-            resources.resources.get("_io"),
-            Some(&Resource {
-                flavor: ResourceFlavor::BuiltinExtensionModule,
-                name: Cow::Owned("_io".to_string()),
-                ..Resource::default()
-            })
+            loaded.resources["bar"]
+                .in_memory_source
+                .as_ref()
+                .unwrap()
+                .resolve()?,
+            b"second".to_vec()
         );
-        assert!(resources.extra_files.is_empty());
+
+        r.set_memory_budget(None)?;
+        assert_eq!(r.memory_statistics(), None);
 
         Ok(())
     }
 
     #[test]
-    fn test_add_in_memory_python_extension_module_shared_library() -> Result<()> {
-        let mut c =
+    fn test_compress_resource() -> Result<()> {
+        let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.set_memory_budget(Some(10_000))?;
 
-        let em = PythonExtensionModule {
-            name: "myext".to_string(),
-            init_fn: Some("PyInit__myext".to_string()),
-            extension_file_suffix: ".so".to_string(),
-            shared_library: Some(DataLocation::Memory(vec![42])),
-            object_file_data: vec![],
-            is_package: false,
-            link_libraries: vec![LibraryDependency {
+        let source = vec![b'#'; 4096];
+        r.add_python_module_source(
+            &PythonModuleSource {
                 name: "foo".to_string(),
-                static_library: None,
-                dynamic_library: Some(DataLocation::Memory(vec![40])),
-                framework: false,
-                system: false,
-            }],
-            is_stdlib: false,
-            builtin_default: false,
-            required: false,
-            variant: None,
-            licenses: None,
-            license_texts: None,
-            license_public_domain: None,
-        };
+                source: DataLocation::Memory(source.clone()),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "tiny.txt".to_string(),
+                data: DataLocation::Memory(b"x".to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert_eq!(r.memory_statistics().unwrap().resident_bytes, 4097);
 
-        c.add_python_extension_module(&em, &ConcreteResourceLocation::InMemory)?;
-        assert_eq!(c.resources.len(), 2);
+        let saved = r.compress_resource("foo", 0)?;
+        assert!(saved > 4000);
+
+        let entry = r.resources.get("foo").unwrap();
+        let location = entry.in_memory_source.as_ref().unwrap();
+        assert!(matches!(location, DataLocation::CompressedMemory { .. }));
+        assert_eq!(location.resolve()?, source);
+
+        // Data that doesn't shrink stays as it is.
+        assert!(matches!(
+            entry.in_memory_resources.as_ref().unwrap()["tiny.txt"],
+            DataLocation::Memory(_)
+        ));
+        let compressed_size = match location {
+            DataLocation::CompressedMemory { data, .. } => data.len() as u64,
+            _ => unreachable!(),
+        };
         assert_eq!(
-            c.resources.get("myext"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Extension,
-                name: "myext".to_string(),
-                in_memory_extension_module_shared_library: Some(DataLocation::Memory(vec![42])),
-                shared_library_dependency_names: Some(vec!["foo".to_string()]),
-                ..PrePackagedResource::default()
-            })
+            r.memory_statistics().unwrap().resident_bytes,
+            1 + compressed_size
         );
-        assert_eq!(
-            c.resources.get("foo"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::SharedLibrary,
+
+        assert!(r.compress_resource("missing", 0).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compress_resource_packed() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let data_path = temp_dir.path().join("data.json");
+        let data = b"{\"key\": \"value\"}\n".repeat(512);
+        std::fs::write(&data_path, &data)?;
+
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+        r.add_python_module_source(
+            &PythonModuleSource {
                 name: "foo".to_string(),
-                in_memory_shared_library: Some(DataLocation::Memory(vec![40])),
-                ..PrePackagedResource::default()
-            })
-        );
+                source: DataLocation::Memory(vec![b'#'; 4096]),
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "data.json".to_string(),
+                data: DataLocation::from_path(&data_path),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
+        let mut compiler = FakeBytecodeCompiler::default();
 
-        let resources = c.compile_resources(&mut compiler)?;
+        let mut uncompressed = Vec::new();
+        r.compile_resources(&mut compiler)?
+            .write_packed_resources_v1(&mut uncompressed)?;
 
-        assert_eq!(resources.resources.len(), 2);
+        // Payloads backed by files are compressed too.
+        r.compress_resource("foo", 0)?;
+        assert!(matches!(
+            r.resources["foo"].in_memory_resources.as_ref().unwrap()["data.json"],
+            DataLocation::CompressedMemory { .. }
+        ));
+
+        let mut compressed = Vec::new();
+        r.compile_resources(&mut compiler)?
+            .write_packed_resources_v1(&mut compressed)?;
+        assert!(compressed.len() + 8000 < uncompressed.len());
+
+        // Payloads are stored compressed and flagged as such.
+        let loaded = python_packed_resources::parser::load_resources(&compressed)
+            .map_err(|e| anyhow!(e))?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!(e))?;
+        assert_eq!(loaded.len(), 1);
+        let resource = &loaded[0];
         assert_eq!(
-            resources.resources.get("myext"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Extension,
-                name: Cow::Owned("myext".to_string()),
-                in_memory_extension_module_shared_library: Some(Cow::Owned(vec![42])),
-                shared_library_dependency_names: Some(vec![Cow::Owned("foo".to_string())]),
-                ..Resource::default()
-            })
+            resource.compressed_payloads,
+            vec![
+                CompressedPayload {
+                    field: ResourceField::InMemorySource,
+                    name: Cow::from(""),
+                    length: 4096,
+                },
+                CompressedPayload {
+                    field: ResourceField::InMemoryResourcesData,
+                    name: Cow::from("data.json"),
+                    length: data.len() as u64,
+                },
+            ]
         );
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::SharedLibrary,
-                name: Cow::Owned("foo".to_string()),
-                in_memory_shared_library: Some(Cow::Owned(vec![40])),
-                ..Resource::default()
-            })
+            zstd::decode_all(&resource.in_memory_source.as_ref().unwrap()[..])?,
+            vec![b'#'; 4096]
+        );
+        assert_eq!(
+            zstd::decode_all(
+                &resource.in_memory_package_resources.as_ref().unwrap()["data.json"][..]
+            )?,
+            data
         );
-
-        assert!(resources.extra_files.is_empty());
 
         Ok(())
     }
 
     #[test]
-    fn test_add_relative_path_python_extension_module() -> Result<()> {
-        let mut c = PythonResourceCollector::new(
-            &PythonResourcesPolicy::FilesystemRelativeOnly("prefix".to_string()),
-            DEFAULT_CACHE_TAG,
-        );
+    fn test_size_report() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let source_path = temp_dir.path().join("bar.py");
+        std::fs::write(&source_path, vec![b'#'; 20])?;
 
-        let em = PythonExtensionModule {
-            name: "foo.bar".to_string(),
-            init_fn: None,
-            extension_file_suffix: ".so".to_string(),
-            shared_library: Some(DataLocation::Memory(vec![42])),
-            object_file_data: vec![],
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
+        let module = |name: &str, source: DataLocation| PythonModuleSource {
+            name: name.to_string(),
+            source,
             is_package: false,
-            link_libraries: vec![LibraryDependency {
-                name: "mylib".to_string(),
-                static_library: None,
-                dynamic_library: Some(DataLocation::Memory(vec![40])),
-                framework: false,
-                system: false,
-            }],
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
             is_stdlib: false,
-            builtin_default: false,
-            required: false,
-            variant: None,
-            licenses: None,
-            license_texts: None,
-            license_public_domain: None,
+            is_test: false,
         };
 
-        c.add_python_extension_module(
-            &em,
-            &ConcreteResourceLocation::RelativePath("prefix".to_string()),
+        r.add_python_module_source(
+            &module("foo", DataLocation::Memory(vec![0; 5])),
+            &ConcreteResourceLocation::InMemory,
         )?;
-        assert_eq!(c.resources.len(), 2);
+        r.add_python_module_source(
+            &module("foo.bar", DataLocation::from_path(&source_path)),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "baz".to_string(),
+                relative_name: "resource.txt".to_string(),
+                data: DataLocation::Memory(vec![0; 7]),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+
+        let report = r.size_report()?;
+        assert_eq!(report.total_bytes, 32);
         assert_eq!(
-            c.resources.get("foo.bar"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::Extension,
-                name: "foo.bar".to_string(),
-                is_package: false,
-                relative_path_extension_module_shared_library: Some((
-                    PathBuf::from("prefix/foo/bar.so"),
-                    DataLocation::Memory(vec![42])
-                )),
-                shared_library_dependency_names: Some(vec!["mylib".to_string()]),
-                ..PrePackagedResource::default()
-            })
+            report.by_kind.clone().into_iter().collect::<Vec<_>>(),
+            vec![
+                (ResourceSizeKind::ModuleSource, 25),
+                (ResourceSizeKind::Resource, 7)
+            ]
         );
         assert_eq!(
-            c.resources.get("mylib"),
-            Some(&PrePackagedResource {
-                flavor: ResourceFlavor::SharedLibrary,
-                name: "mylib".to_string(),
-                relative_path_shared_library: Some((
-                    "prefix/foo".to_string(),
-                    DataLocation::Memory(vec![40])
-                )),
-                ..PrePackagedResource::default()
-            })
+            report.by_package.clone().into_iter().collect::<Vec<_>>(),
+            vec![("baz".to_string(), 7), ("foo".to_string(), 25)]
+        );
+        assert_eq!(report.largest(2), vec![("foo.bar", 20), ("baz", 7)]);
+
+        // File content isn't read.
+        assert!(matches!(
+            &r.resources["foo.bar"].in_memory_source,
+            Some(DataLocation::Path { cache, .. }) if !cache.is_populated()
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_bytecode_size_report() -> Result<()> {
+        let module = |name: &'static str, opt0: Option<usize>, opt2: Option<usize>| {
+            (
+                name.to_string(),
+                Resource {
+                    flavor: ResourceFlavor::Module,
+                    name: Cow::Borrowed(name),
+                    in_memory_bytecode: opt0.map(|size| Cow::Owned(vec![0; size])),
+                    in_memory_bytecode_opt2: opt2.map(|size| Cow::Owned(vec![0; size])),
+                    ..Resource::default()
+                },
+            )
+        };
+
+        let mut resources = vec![
+            module("foo", Some(10), None),
+            module("foo.bar", Some(30), Some(20)),
+            module("baz", Some(40), None),
+            module("nobytecode", None, None),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        resources.insert(
+            "qux".to_string(),
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Borrowed("qux"),
+                relative_path_module_bytecode_opt1: Some(Cow::Owned(PathBuf::from(
+                    "lib/__pycache__/qux.cpython-37.opt-1.pyc",
+                ))),
+                ..Resource::default()
+            },
         );
 
-        let mut compiler = FakeBytecodeCompiler { magic_number: 42 };
-
-        let resources = c.compile_resources(&mut compiler)?;
+        let compiled = CompiledResourcesCollection {
+            resources,
+            extra_files: vec![(
+                PathBuf::from("lib/__pycache__/qux.cpython-37.opt-1.pyc"),
+                DataLocation::Memory(vec![0; 5]),
+                false,
+            )],
+            extra_file_owners: vec!["qux".to_string()],
+            compile_errors: vec![],
+            interior_padding: None,
+        };
 
-        assert_eq!(resources.resources.len(), 3);
+        let report = compiled.bytecode_size_report()?;
+        assert_eq!(report.total_bytes, 105);
         assert_eq!(
-            resources.resources.get("foo"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Module,
-                name: Cow::Owned("foo".to_string()),
-                is_package: true,
-                ..Resource::default()
-            })
+            report
+                .modules
+                .iter()
+                .map(|m| (m.name.as_str(), m.total_bytes))
+                .collect::<Vec<_>>(),
+            vec![("foo.bar", 50), ("baz", 40), ("foo", 10), ("qux", 5)]
         );
         assert_eq!(
-            resources.resources.get("foo.bar"),
-            Some(&Resource {
-                flavor: ResourceFlavor::Extension,
-                name: Cow::Owned("foo.bar".to_string()),
-                is_package: false,
-                relative_path_extension_module_shared_library: Some(Cow::Owned(PathBuf::from(
-                    "prefix/foo/bar.so"
-                ))),
-                shared_library_dependency_names: Some(vec![Cow::Owned("mylib".to_string())]),
-                ..Resource::default()
-            })
+            report.modules[0].levels,
+            vec![(0, 30), (2, 20)].into_iter().collect()
         );
         assert_eq!(
-            resources.resources.get("mylib"),
-            Some(&Resource {
-                flavor: ResourceFlavor::SharedLibrary,
-                name: Cow::Owned("mylib".to_string()),
-                ..Resource::default()
-            })
+            report
+                .packages
+                .iter()
+                .map(|p| (p.name.as_str(), p.modules_count, p.total_bytes))
+                .collect::<Vec<_>>(),
+            vec![("foo", 2, 60), ("baz", 1, 40), ("qux", 1, 5)]
         );
+        assert_eq!(report.largest_modules(2).len(), 2);
+        assert_eq!(report.largest_modules(10).len(), 4);
 
+        let parsed: BytecodeSizeReport = serde_json::from_str(&report.to_json()?)?;
+        assert_eq!(parsed, report);
+
+        let mut table = Vec::new();
+        report.write_table(&mut table, 2)?;
         assert_eq!(
-            resources.extra_files,
+            String::from_utf8(table)?,
+            "module       total      opt-0      opt-1      opt-2\n\
+             foo.bar         50         30          -         20\n\
+             baz             40         40          -          -\n\
+             \n\
+             package      total      opt-0      opt-1      opt-2\n\
+             foo             60         40          -         20\n\
+             baz             40         40          -          -\n\
+             \n\
+             total: 105 bytes of bytecode\n"
+        );
+
+        // Bytecode installed next to the binary must be in extra files.
+        let mut missing = compiled;
+        missing.extra_files.clear();
+        assert!(missing.bytecode_size_report().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_test_payloads() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
+        let module = |is_test| PythonModuleSource {
+            name: "foo.tests".to_string(),
+            source: DataLocation::Memory(vec![0; 5]),
+            is_package: true,
+            cache_tag: DEFAULT_CACHE_TAG.to_string(),
+            is_stdlib: false,
+            is_test,
+        };
+        let resource = |relative_name: &str, is_test| PythonPackageResource {
+            leaf_package: "foo.tests".to_string(),
+            relative_name: relative_name.to_string(),
+            data: DataLocation::Memory(vec![0; 7]),
+            is_stdlib: false,
+            is_test,
+        };
+
+        r.add_python_module_source(&module(true), &ConcreteResourceLocation::InMemory)?;
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo.tests".to_string(),
+                source: DataLocation::Memory(vec![0; 5]),
+                optimize_level: BytecodeOptimizationLevel::One,
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: true,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &resource("fixture.json", true),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &resource("runtime.json", false),
+            &ConcreteResourceLocation::InMemory,
+        )?;
+
+        let mut wanted = BTreeMap::new();
+        wanted.insert(
+            "foo.tests".to_string(),
+            vec!["bytecode-opt1", "resource fixture.json", "source"]
+                .into_iter()
+                .map(|s| s.to_string())
+                .collect::<BTreeSet<_>>(),
+        );
+        assert_eq!(r.test_payloads(), wanted);
+
+        // Bytecode still to be compiled isn't counted.
+        let report = r.size_report()?;
+        assert_eq!(report.total_bytes, 19);
+        assert_eq!(report.test_bytes, 12);
+
+        // Adding a payload again replaces its classification.
+        r.add_python_module_source(&module(false), &ConcreteResourceLocation::InMemory)?;
+        assert!(!r.test_payloads()["foo.tests"].contains("source"));
+        assert_eq!(r.size_report()?.test_bytes, 7);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_digest_manifest() -> Result<()> {
+        let mut r =
+            PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
+
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"abc".to_vec()),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
+                source: DataLocation::Memory(b"abc".to_vec()),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "empty.txt".to_string(),
+                data: DataLocation::Memory(vec![]),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+
+        // Bytecode still to be compiled isn't hashed.
+        let manifest = r.digest_manifest(DigestAlgorithm::Sha256)?;
+        assert_eq!(
+            manifest.into_iter().collect::<Vec<_>>(),
             vec![
                 (
-                    PathBuf::from("prefix/foo/bar.so"),
-                    DataLocation::Memory(vec![42]),
-                    true
+                    "foo [resource empty.txt]".to_string(),
+                    "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string()
                 ),
                 (
-                    PathBuf::from("prefix/foo/mylib"),
-                    DataLocation::Memory(vec![40]),
-                    true
-                )
+                    "foo [source]".to_string(),
+                    "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string()
+                ),
             ]
         );
 
@@ -3057,52 +11237,287 @@ mod tests {
     }
 
     #[test]
-    fn test_find_dunder_file() -> Result<()> {
+    fn test_dangling_payloads() -> Result<()> {
+        let temp_dir = tempdir::TempDir::new("python-packaging-test")?;
+        let source = temp_dir.path().join("foo.py");
+        let data = temp_dir.path().join("data.txt");
+        std::fs::write(&source, b"import os")?;
+        std::fs::write(&data, b"data")?;
+
         let mut r =
             PythonResourceCollector::new(&PythonResourcesPolicy::InMemoryOnly, DEFAULT_CACHE_TAG);
-        assert_eq!(r.find_dunder_file()?.len(), 0);
+        r.add_python_module_bytecode_from_source(
+            &PythonModuleBytecodeFromSource {
+                name: "foo".to_string(),
+                source: DataLocation::from_path(&source),
+                optimize_level: BytecodeOptimizationLevel::Zero,
+                is_package: true,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "data.txt".to_string(),
+                data: DataLocation::from_path(&data),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &ConcreteResourceLocation::InMemory,
+        )?;
+        assert!(r.dangling_payloads()?.is_empty());
+
+        std::fs::remove_file(&source)?;
+        assert_eq!(
+            r.dangling_payloads()?.into_iter().collect::<Vec<_>>(),
+            vec![(
+                "foo".to_string(),
+                vec![("bytecode source".to_string(), source.display().to_string())]
+            )]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_relative_path_installs() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::FilesystemRelativeOnly("lib".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        let location = ConcreteResourceLocation::RelativePath("lib".to_string());
 
         r.add_python_module_source(
             &PythonModuleSource {
                 name: "foo.bar".to_string(),
-                source: DataLocation::Memory(vec![]),
+                source: DataLocation::Memory(vec![42]),
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
                 is_test: false,
             },
-            &ConcreteResourceLocation::InMemory,
+            &location,
         )?;
-        assert_eq!(r.find_dunder_file()?.len(), 0);
+        for optimize_level in &[
+            BytecodeOptimizationLevel::Zero,
+            BytecodeOptimizationLevel::One,
+            BytecodeOptimizationLevel::Two,
+        ] {
+            r.add_python_module_bytecode_from_source(
+                &PythonModuleBytecodeFromSource {
+                    name: "foo.bar".to_string(),
+                    source: DataLocation::Memory(vec![42]),
+                    optimize_level: *optimize_level,
+                    is_package: false,
+                    cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                    is_stdlib: false,
+                    is_test: false,
+                },
+                &location,
+            )?;
+        }
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "foo".to_string(),
+                relative_name: "data/config.txt".to_string(),
+                data: DataLocation::Memory(vec![42]),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &location,
+        )?;
+        r.add_package_distribution_resource(
+            &PythonPackageDistributionResource {
+                location: PythonPackageDistributionResourceFlavor::DistInfo,
+                package: "foo".to_string(),
+                version: "1.0".to_string(),
+                name: "METADATA".to_string(),
+                data: DataLocation::Memory(vec![42]),
+            },
+            &location,
+        )?;
+
+        let installs = r.relative_path_installs_for_features(&BTreeSet::new())?;
+
+        // These paths are the documented layout. Changing them breaks
+        // installers that pre-create or sign install trees.
+        assert_eq!(
+            installs
+                .iter()
+                .map(|install| (
+                    install.path.to_string_lossy().replace('\\', "/"),
+                    install.executable
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                ("lib/foo/__init__.py".to_string(), false),
+                (
+                    format!("lib/foo/__pycache__/__init__.{}.pyc", DEFAULT_CACHE_TAG),
+                    false
+                ),
+                (
+                    format!(
+                        "lib/foo/__pycache__/__init__.{}.opt-1.pyc",
+                        DEFAULT_CACHE_TAG
+                    ),
+                    false
+                ),
+                (
+                    format!(
+                        "lib/foo/__pycache__/__init__.{}.opt-2.pyc",
+                        DEFAULT_CACHE_TAG
+                    ),
+                    false
+                ),
+                ("lib/foo/data/config.txt".to_string(), false),
+                ("lib/foo-1.0.dist-info/METADATA".to_string(), false),
+                ("lib/foo/bar.py".to_string(), false),
+                (
+                    format!("lib/foo/__pycache__/bar.{}.pyc", DEFAULT_CACHE_TAG),
+                    false
+                ),
+                (
+                    format!("lib/foo/__pycache__/bar.{}.opt-1.pyc", DEFAULT_CACHE_TAG),
+                    false
+                ),
+                (
+                    format!("lib/foo/__pycache__/bar.{}.opt-2.pyc", DEFAULT_CACHE_TAG),
+                    false
+                ),
+            ]
+        );
+
+        // Compiling installs exactly the planned files.
+        let mut compiler = FakeBytecodeCompiler::default();
+        let compiled = r.compile_resources(&mut compiler)?;
+        assert_eq!(
+            compiled
+                .extra_files
+                .iter()
+                .map(|(path, _, executable)| (path.clone(), *executable))
+                .collect::<Vec<_>>(),
+            installs
+                .iter()
+                .map(|install| (install.path.clone(), install.executable))
+                .collect::<Vec<_>>()
+        );
+
+        assert_eq!(
+            installs[4].kind,
+            PayloadKind::PackageResource("data/config.txt".to_string())
+        );
+        assert_eq!(
+            installs[4].describe(),
+            "package resource data/config.txt of foo from memory"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_wheel_contents() -> Result<()> {
+        let mut r = PythonResourceCollector::new(
+            &PythonResourcesPolicy::PreferInMemoryFallbackFilesystemRelative("lib".to_string()),
+            DEFAULT_CACHE_TAG,
+        );
+        let relative = ConcreteResourceLocation::RelativePath("lib".to_string());
 
         r.add_python_module_source(
             &PythonModuleSource {
-                name: "baz".to_string(),
-                source: DataLocation::Memory(Vec::from("import foo; if __file__ == 'ignored'")),
-                is_package: false,
+                name: "app".to_string(),
+                source: DataLocation::Memory(b"import app.util\n".to_vec()),
+                is_package: true,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
                 is_test: false,
             },
             &ConcreteResourceLocation::InMemory,
         )?;
-        assert_eq!(r.find_dunder_file()?.len(), 1);
-        assert!(r.find_dunder_file()?.contains("baz"));
-
         r.add_python_module_bytecode_from_source(
             &PythonModuleBytecodeFromSource {
-                name: "bytecode".to_string(),
-                source: DataLocation::Memory(Vec::from("import foo; if __file__")),
+                name: "app.util".to_string(),
+                source: DataLocation::Memory(b"import os\n".to_vec()),
                 optimize_level: BytecodeOptimizationLevel::Zero,
                 is_package: false,
                 cache_tag: DEFAULT_CACHE_TAG.to_string(),
                 is_stdlib: false,
                 is_test: false,
             },
+            &relative,
+        )?;
+        r.add_python_package_resource(
+            &PythonPackageResource {
+                leaf_package: "app".to_string(),
+                relative_name: "data/config.txt".to_string(),
+                data: DataLocation::Memory(b"debug = true\n".to_vec()),
+                is_stdlib: false,
+                is_test: false,
+            },
+            &relative,
+        )?;
+        r.add_python_module_source(
+            &PythonModuleSource {
+                name: "other".to_string(),
+                source: DataLocation::Memory(vec![]),
+                is_package: false,
+                cache_tag: DEFAULT_CACHE_TAG.to_string(),
+                is_stdlib: false,
+                is_test: false,
+            },
             &ConcreteResourceLocation::InMemory,
         )?;
-        assert_eq!(r.find_dunder_file()?.len(), 2);
-        assert!(r.find_dunder_file()?.contains("bytecode"));
+        for (name, data) in &[
+            ("METADATA", &b"Name: my-app\nVersion: 1.0\n"[..]),
+            (
+                "RECORD",
+                b"app/__init__.py,,\nmy_app-1.0.dist-info/RECORD,,\n",
+            ),
+        ] {
+            r.add_package_distribution_resource(
+                &PythonPackageDistributionResource {
+                    location: PythonPackageDistributionResourceFlavor::DistInfo,
+                    package: "my-app".to_string(),
+                    version: "1.0".to_string(),
+                    name: name.to_string(),
+                    data: DataLocation::Memory(data.to_vec()),
+                },
+                &ConcreteResourceLocation::InMemory,
+            )?;
+        }
+
+        let contents = r.wheel_contents("app")?;
+        assert_eq!(
+            contents.files.keys().collect::<Vec<_>>(),
+            vec!["app/__init__.py", "app/data/config.txt", "app/util.py"]
+        );
+        assert_eq!(
+            contents.files["app/util.py"],
+            DataLocation::Memory(b"import os\n".to_vec())
+        );
+        assert_eq!(
+            contents.dist_info.keys().collect::<Vec<_>>(),
+            vec!["METADATA", "RECORD"]
+        );
+        assert_eq!(contents.distribution_name()?, Some("my-app".to_string()));
+
+        // Subpackages are selected on their own but keep the distribution.
+        let contents = r.wheel_contents("app.util")?;
+        assert_eq!(
+            contents.files.keys().collect::<Vec<_>>(),
+            vec!["app/util.py"]
+        );
+        assert_eq!(contents.dist_info.len(), 2);
+
+        let contents = r.wheel_contents("other")?;
+        assert!(contents.dist_info.is_empty());
+
+        assert_eq!(
+            r.wheel_contents("missing").unwrap_err().to_string(),
+            "no resources belong to package missing"
+        );
 
         Ok(())
     }
@@ -0,0 +1,550 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Determine which resources are used from logs of instrumented runs.
+
+Static analysis can't tell which modules an application actually needs.
+Instead, an instrumented run of the application (e.g. its test suite)
+records what it imports and accesses. This module consumes such a log and
+classifies resources as used or unused, so unused resources can be pruned.
+
+Logs are text with one entry per line. Empty lines and lines beginning with
+`#` are ignored. A line of the form `<package>:<path>` records an access of
+the package resource `<path>` belonging to `<package>`. Any other line is the
+name of an imported module. This means files written by the
+`write_modules_directory_env` interpreter setting are valid logs.
+
+Import telemetry dumps written by binaries built with
+`import_telemetry_path` are valid logs as well. They are recognized by their
+first line, `IMPORT_TELEMETRY_HEADER`, and parsed by `ImportTelemetry`.
+*/
+
+use {
+    crate::module_util::packages_from_module_name,
+    crate::resource::DataLocation,
+    crate::resource_collection::{PrePackagedResource, PythonModuleBytecodeProvider},
+    anyhow::{anyhow, Context, Result},
+    python_packed_resources::data::{ResourceFlavor, IMPORT_TELEMETRY_HEADER},
+    std::collections::{BTreeMap, BTreeSet},
+    std::path::Path,
+};
+
+/// Modules and package resources recorded by an instrumented run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsageLog {
+    /// Names of imported modules.
+    pub modules: BTreeSet<String>,
+
+    /// Accessed package resources as `(package, path)`.
+    pub resources: BTreeSet<(String, String)>,
+}
+
+impl UsageLog {
+    /// Parse a usage log from its text.
+    ///
+    /// Duplicate entries are collapsed.
+    pub fn parse(data: &str) -> Self {
+        let mut res = Self::default();
+
+        for line in data.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(pos) = line.find(':') {
+                res.resources
+                    .insert((line[0..pos].to_string(), line[pos + 1..].to_string()));
+            } else {
+                res.modules.insert(line.to_string());
+            }
+        }
+
+        res
+    }
+
+    /// Read a usage log from a file.
+    ///
+    /// Import telemetry dumps are converted with `ImportTelemetry::to_usage_log()`.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading usage log {}", path.display()))?;
+
+        if data.starts_with(IMPORT_TELEMETRY_HEADER) {
+            Ok(ImportTelemetry::parse(&data)
+                .with_context(|| format!("parsing import telemetry {}", path.display()))?
+                .to_usage_log())
+        } else {
+            Ok(Self::parse(&data))
+        }
+    }
+
+    /// Merge entries from another log into this one.
+    pub fn merge(&mut self, other: &UsageLog) {
+        self.modules.extend(other.modules.iter().cloned());
+        self.resources.extend(other.resources.iter().cloned());
+    }
+}
+
+/// Counters recorded by a binary with import telemetry enabled.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ImportTelemetry {
+    /// How often each module was imported.
+    pub modules: BTreeMap<String, u64>,
+
+    /// How often each package resource was read, keyed by `(package, name)`.
+    pub resources: BTreeMap<(String, String), u64>,
+}
+
+impl ImportTelemetry {
+    /// Parse an import telemetry dump.
+    ///
+    /// See `IMPORT_TELEMETRY_HEADER` for the format. Counts of repeated
+    /// entries, e.g. from concatenated dumps, are summed.
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut lines = data.lines();
+        if lines.next() != Some(IMPORT_TELEMETRY_HEADER) {
+            return Err(anyhow!(
+                "import telemetry doesn't start with {}",
+                IMPORT_TELEMETRY_HEADER
+            ));
+        }
+
+        let mut res = Self::default();
+
+        for (index, line) in lines.enumerate() {
+            if line.is_empty() || line == IMPORT_TELEMETRY_HEADER {
+                continue;
+            }
+
+            let fields = line.split('\t').collect::<Vec<_>>();
+            let count = fields
+                .get(1)
+                .and_then(|count| count.parse::<u64>().ok())
+                .ok_or_else(|| anyhow!("line {}: invalid count: {}", index + 2, line))?;
+
+            match (fields[0], &fields[2..]) {
+                ("module", [name]) => {
+                    *res.modules.entry(name.to_string()).or_default() += count;
+                }
+                ("resource", [package, name]) => {
+                    *res.resources
+                        .entry((package.to_string(), name.to_string()))
+                        .or_default() += count;
+                }
+                _ => return Err(anyhow!("line {}: unrecognized entry: {}", index + 2, line)),
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Read an import telemetry dump from a file.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .with_context(|| format!("reading import telemetry {}", path.display()))?;
+
+        Self::parse(&data).with_context(|| format!("parsing import telemetry {}", path.display()))
+    }
+
+    /// Obtain the modules and package resources that were used at least once.
+    pub fn to_usage_log(&self) -> UsageLog {
+        UsageLog {
+            modules: self
+                .modules
+                .iter()
+                .filter(|(_, count)| **count > 0)
+                .map(|(name, _)| name.clone())
+                .collect(),
+            resources: self
+                .resources
+                .iter()
+                .filter(|(_, count)| **count > 0)
+                .map(|(key, _)| key.clone())
+                .collect(),
+        }
+    }
+}
+
+/// A resource that wasn't used.
+#[derive(Clone, Debug, PartialEq)]
+pub struct UnusedResource {
+    /// Name of the resource.
+    pub name: String,
+
+    /// Flavor of the resource.
+    pub flavor: ResourceFlavor,
+
+    /// Size in bytes of all data attached to the resource.
+    pub size: u64,
+}
+
+/// Classification of resources from a usage log.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UsageReport {
+    /// Names of used resources.
+    pub used: BTreeSet<String>,
+
+    /// Unused resources, keyed by top-level package name.
+    pub unused: BTreeMap<String, Vec<UnusedResource>>,
+
+    /// Entries of the log not matching any resource.
+    pub unknown: BTreeSet<String>,
+}
+
+impl UsageReport {
+    /// The number of unused resources.
+    pub fn unused_count(&self) -> usize {
+        self.unused.values().map(|x| x.len()).sum()
+    }
+
+    /// The total size in bytes of unused resources.
+    pub fn unused_size(&self) -> u64 {
+        self.unused.values().flatten().map(|x| x.size).sum()
+    }
+
+    /// The size in bytes of unused resources in each top-level package.
+    pub fn unused_package_sizes(&self) -> BTreeMap<String, u64> {
+        self.unused
+            .iter()
+            .map(|(package, resources)| (package.clone(), resources.iter().map(|x| x.size).sum()))
+            .collect()
+    }
+}
+
+/// Compute the size in bytes of all data attached to a resource.
+pub fn resource_size(resource: &PrePackagedResource) -> Result<u64> {
+    let mut locations: Vec<&DataLocation> = Vec::new();
+
+    let providers = [
+        resource.in_memory_bytecode.as_ref(),
+        resource.in_memory_bytecode_opt1.as_ref(),
+        resource.in_memory_bytecode_opt2.as_ref(),
+        resource.relative_path_bytecode.as_ref().map(|(_, _, p)| p),
+        resource
+            .relative_path_bytecode_opt1
+            .as_ref()
+            .map(|(_, _, p)| p),
+        resource
+            .relative_path_bytecode_opt2
+            .as_ref()
+            .map(|(_, _, p)| p),
+    ];
+
+    for provider in providers.iter().copied().flatten() {
+        match provider {
+            PythonModuleBytecodeProvider::FromSource(location) => locations.push(location),
+            PythonModuleBytecodeProvider::Provided(location) => locations.push(location),
+        }
+    }
+
+    locations.extend(resource.in_memory_source.iter());
+    locations.extend(resource.in_memory_extension_module_shared_library.iter());
+    locations.extend(resource.in_memory_resources.iter().flat_map(|x| x.values()));
+    locations.extend(
+        resource
+            .in_memory_distribution_resources
+            .iter()
+            .flat_map(|x| x.values()),
+    );
+    locations.extend(resource.in_memory_shared_library.iter());
+    locations.extend(resource.relative_path_module_source.iter().map(|(_, l)| l));
+    locations.extend(
+        resource
+            .relative_path_extension_module_shared_library
+            .iter()
+            .map(|(_, l)| l),
+    );
+    locations.extend(
+        resource
+            .relative_path_package_resources
+            .iter()
+            .flat_map(|x| x.values().map(|(_, l)| l)),
+    );
+    locations.extend(
+        resource
+            .relative_path_distribution_resources
+            .iter()
+            .flat_map(|x| x.values().map(|(_, l)| l)),
+    );
+    locations.extend(resource.relative_path_shared_library.iter().map(|(_, l)| l));
+
+    let mut size = 0;
+    for location in locations {
+        size += data_location_size(location)?;
+    }
+
+    Ok(size)
+}
+
+/// Compute the size in bytes of data without reading it.
+pub fn data_location_size(location: &DataLocation) -> Result<u64> {
+    Ok(match location {
+        DataLocation::Path { path, .. } => std::fs::metadata(path)
+            .with_context(|| format!("reading metadata of {}", path.display()))?
+            .len(),
+        DataLocation::Memory(data) => data.len() as u64,
+        DataLocation::Slice { length, .. } => *length as u64,
+        DataLocation::CompressedMemory { size, .. } => *size,
+        DataLocation::ArchiveMember { .. } => location.size()?,
+        DataLocation::FileRange { length, .. } => *length,
+    })
+}
+
+/// Whether a package resource exists.
+fn has_package_resource(resource: &PrePackagedResource, path: &str) -> bool {
+    resource
+        .in_memory_resources
+        .as_ref()
+        .map_or(false, |x| x.contains_key(path))
+        || resource
+            .relative_path_package_resources
+            .as_ref()
+            .map_or(false, |x| x.contains_key(path))
+}
+
+/// Classify resources as used or unused from a usage log.
+///
+/// A resource is used if it is imported, one of its package resources is
+/// accessed, it is a parent package of a used resource, or a used resource
+/// depends on it as a shared library. Log entries not matching a resource are tolerated
+/// and reported as unknown.
+pub fn resolve_resource_usage(
+    resources: &BTreeMap<String, PrePackagedResource>,
+    log: &UsageLog,
+) -> Result<UsageReport> {
+    let mut res = UsageReport::default();
+    let mut pending = Vec::new();
+
+    for name in &log.modules {
+        if resources.contains_key(name) {
+            pending.push(name.clone());
+        } else {
+            res.unknown.insert(name.clone());
+        }
+    }
+
+    for (package, path) in &log.resources {
+        match resources.get(package) {
+            Some(resource) if has_package_resource(resource, path) => {
+                pending.push(package.clone());
+            }
+            _ => {
+                res.unknown.insert(format!("{}:{}", package, path));
+            }
+        }
+    }
+
+    while let Some(name) = pending.pop() {
+        if !res.used.insert(name.clone()) {
+            continue;
+        }
+
+        pending.extend(
+            packages_from_module_name(&name)
+                .into_iter()
+                .filter(|x| resources.contains_key(x)),
+        );
+
+        if let Some(names) = resources
+            .get(&name)
+            .and_then(|x| x.shared_library_dependency_names.as_ref())
+        {
+            pending.extend(names.iter().filter(|x| resources.contains_key(*x)).cloned());
+        }
+    }
+
+    for (name, resource) in resources {
+        if res.used.contains(name) {
+            continue;
+        }
+
+        let package = name.split('.').next().unwrap_or(name).to_string();
+
+        res.unused
+            .entry(package)
+            .or_insert_with(Vec::new)
+            .push(UnusedResource {
+                name: name.clone(),
+                flavor: resource.flavor,
+                size: resource_size(resource)?,
+            });
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(name: &str, size: usize) -> PrePackagedResource {
+        PrePackagedResource {
+            flavor: ResourceFlavor::Module,
+            name: name.to_string(),
+            in_memory_source: Some(DataLocation::Memory(vec![42; size])),
+            ..PrePackagedResource::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_usage_log() {
+        let log = UsageLog::parse("# comment\nfoo\n\nfoo.bar\nfoo\nfoo:data.txt\n  baz  \n");
+
+        assert_eq!(
+            log.modules,
+            vec!["baz".to_string(), "foo".to_string(), "foo.bar".to_string()]
+                .into_iter()
+                .collect()
+        );
+        assert_eq!(
+            log.resources,
+            vec![("foo".to_string(), "data.txt".to_string())]
+                .into_iter()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_parse_import_telemetry() -> Result<()> {
+        let telemetry = ImportTelemetry::parse(&format!(
+            "{}\nmodule\t2\tfoo\nmodule\t0\tfoo.unused\nresource\t1\tfoo\tdata.txt\n\n{}\nmodule\t3\tfoo\n",
+            IMPORT_TELEMETRY_HEADER, IMPORT_TELEMETRY_HEADER
+        ))?;
+
+        assert_eq!(telemetry.modules.get("foo"), Some(&5));
+        assert_eq!(telemetry.modules.get("foo.unused"), Some(&0));
+        assert_eq!(
+            telemetry
+                .resources
+                .get(&("foo".to_string(), "data.txt".to_string())),
+            Some(&1)
+        );
+
+        let log = telemetry.to_usage_log();
+        assert_eq!(log.modules, vec!["foo".to_string()].into_iter().collect());
+        assert_eq!(
+            log.resources,
+            vec![("foo".to_string(), "data.txt".to_string())]
+                .into_iter()
+                .collect()
+        );
+
+        assert!(ImportTelemetry::parse("module\t1\tfoo\n").is_err());
+        assert_eq!(
+            ImportTelemetry::parse(&format!("{}\nmodule\tmany\tfoo\n", IMPORT_TELEMETRY_HEADER))
+                .unwrap_err()
+                .to_string(),
+            "line 2: invalid count: module\tmany\tfoo"
+        );
+        assert!(
+            ImportTelemetry::parse(&format!("{}\nmodule\t1\n", IMPORT_TELEMETRY_HEADER)).is_err()
+        );
+
+        let td = tempdir::TempDir::new("python-packaging-test")?;
+        let path = td.path().join("telemetry");
+        std::fs::write(
+            &path,
+            format!("{}\nmodule\t1\tfoo.bar\n", IMPORT_TELEMETRY_HEADER),
+        )?;
+        assert_eq!(
+            UsageLog::from_path(&path)?.modules,
+            vec!["foo.bar".to_string()].into_iter().collect()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_resource_usage() -> Result<()> {
+        let mut resources = BTreeMap::new();
+        for (name, size) in &[
+            ("foo", 1),
+            ("foo.bar", 2),
+            ("foo.bar.baz", 3),
+            ("foo.unused", 4),
+            ("other", 5),
+            ("other.sub", 6),
+        ] {
+            resources.insert(name.to_string(), module(name, *size));
+        }
+
+        let mut data = module("data", 7);
+        data.in_memory_resources = Some(
+            vec![("file.txt".to_string(), DataLocation::Memory(vec![0; 8]))]
+                .into_iter()
+                .collect(),
+        );
+        resources.insert("data".to_string(), data);
+
+        let mut ext = module("ext", 9);
+        ext.shared_library_dependency_names = Some(vec!["libfoo".to_string()]);
+        resources.insert("ext".to_string(), ext);
+
+        resources.insert(
+            "libfoo".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "libfoo".to_string(),
+                in_memory_shared_library: Some(DataLocation::Memory(vec![0; 10])),
+                ..PrePackagedResource::default()
+            },
+        );
+        resources.insert(
+            "libunused".to_string(),
+            PrePackagedResource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: "libunused".to_string(),
+                in_memory_shared_library: Some(DataLocation::Memory(vec![0; 11])),
+                ..PrePackagedResource::default()
+            },
+        );
+
+        let log = UsageLog::parse(
+            "foo.bar.baz\nfoo.bar.baz\next\nmissing\ndata:file.txt\ndata:missing.txt\n",
+        );
+        let report = resolve_resource_usage(&resources, &log)?;
+
+        assert_eq!(
+            report.used,
+            vec!["data", "ext", "foo", "foo.bar", "foo.bar.baz", "libfoo"]
+                .into_iter()
+                .map(|x| x.to_string())
+                .collect()
+        );
+        assert_eq!(
+            report.unknown,
+            vec!["data:missing.txt".to_string(), "missing".to_string()]
+                .into_iter()
+                .collect()
+        );
+
+        assert_eq!(report.unused.len(), 3);
+        assert_eq!(
+            report.unused.get("foo"),
+            Some(&vec![UnusedResource {
+                name: "foo.unused".to_string(),
+                flavor: ResourceFlavor::Module,
+                size: 4,
+            }])
+        );
+        assert_eq!(
+            report
+                .unused
+                .get("other")
+                .unwrap()
+                .iter()
+                .map(|x| x.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["other", "other.sub"]
+        );
+        assert_eq!(report.unused_count(), 4);
+        assert_eq!(report.unused_size(), 4 + 5 + 6 + 11);
+        assert_eq!(report.unused_package_sizes().get("other"), Some(&11));
+
+        Ok(())
+    }
+}
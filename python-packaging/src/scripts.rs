@@ -0,0 +1,356 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Functionality for processing scripts installed alongside Python resources.
+
+Scripts installed by Python packaging tools reference the interpreter that
+was used at build time in their shebang. That interpreter doesn't exist
+where the scripts are eventually installed. The functionality in this
+module rewrites scripts so they run with an interpreter relative to the
+script's location.
+*/
+
+use {
+    crate::resource::DataLocation,
+    crate::resource_collection::FileInstall,
+    anyhow::{anyhow, Context, Result},
+    regex::Regex,
+    std::path::Path,
+};
+
+/// Describes how to process a script.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScriptAction {
+    /// Install the script without modifications.
+    Passthrough,
+
+    /// Rewrite the shebang so the script is executed by a launcher.
+    ///
+    /// The `String` is the path to the launcher, relative to the directory
+    /// the script is installed in.
+    RewriteShebang(String),
+
+    /// Strip the shebang and generate launchers for the target platform.
+    ///
+    /// The script content is installed as `<name>-script.py`. On Windows,
+    /// `<name>.cmd` and `<name>.ps1` launchers are generated. On other
+    /// platforms, `<name>` is replaced by a shell wrapper.
+    ///
+    /// The `String` is the path to the interpreter, relative to the directory
+    /// the script is installed in.
+    Launchers(String),
+}
+
+/// Associates scripts whose install path matches a pattern with an action.
+#[derive(Clone, Debug)]
+pub struct ScriptRule {
+    /// Regular expression matched against the install path of a file.
+    ///
+    /// Paths are normalized to use `/` as a directory separator.
+    pub pattern: Regex,
+
+    /// Action to apply to matching scripts.
+    pub action: ScriptAction,
+}
+
+impl ScriptRule {
+    /// Construct a new instance from a regular expression string.
+    pub fn new(pattern: &str, action: ScriptAction) -> Result<Self> {
+        Ok(Self {
+            pattern: Regex::new(pattern)
+                .with_context(|| format!("parsing script pattern {}", pattern))?,
+            action,
+        })
+    }
+}
+
+/// Whether data looks like a text script that can be processed.
+fn is_text_script(data: &[u8]) -> bool {
+    !data.contains(&0) && std::str::from_utf8(data).is_ok()
+}
+
+/// Split script data into its shebang line and the remaining content.
+fn split_shebang(data: &[u8]) -> (Option<&[u8]>, &[u8]) {
+    if !data.starts_with(b"#!") {
+        return (None, data);
+    }
+
+    match data.iter().position(|c| *c == b'\n') {
+        Some(pos) => (Some(&data[0..pos]), &data[pos + 1..]),
+        None => (Some(data), &[]),
+    }
+}
+
+/// Process a single script according to an action.
+///
+/// Returns the files to install in place of the script. Binary scripts are
+/// always returned untouched.
+pub fn process_script(
+    install: &FileInstall,
+    action: &ScriptAction,
+    target_triple: &str,
+) -> Result<Vec<FileInstall>> {
+    let path = &install.0;
+
+    if *action == ScriptAction::Passthrough {
+        return Ok(vec![install.clone()]);
+    }
+
+    let data = install.1.resolve()?;
+
+    if !is_text_script(&data) {
+        return Ok(vec![install.clone()]);
+    }
+
+    let (shebang, body) = split_shebang(&data);
+
+    match action {
+        ScriptAction::Passthrough => Ok(vec![install.clone()]),
+        ScriptAction::RewriteShebang(launcher) => {
+            // Scripts without a shebang aren't meant to be executed directly.
+            if shebang.is_none() {
+                return Ok(vec![install.clone()]);
+            }
+
+            // The shell executes the launcher relative to the script. Python
+            // sees a string literal and ignores it.
+            let mut script = format!(
+                "#!/bin/sh\n'''exec' \"$(dirname -- \"$0\")/{}\" \"$0\" \"$@\"\n' '''\n",
+                launcher
+            )
+            .into_bytes();
+            script.extend_from_slice(body);
+
+            Ok(vec![(path.clone(), DataLocation::Memory(script), true)])
+        }
+        ScriptAction::Launchers(interpreter) => {
+            let name = path
+                .file_name()
+                .ok_or_else(|| anyhow!("unable to resolve script name from {}", path.display()))?
+                .to_string_lossy()
+                .to_string();
+            let parent = path.parent().unwrap_or_else(|| Path::new(""));
+            let script_name = format!("{}-script.py", name);
+
+            let mut res = vec![(
+                parent.join(&script_name),
+                DataLocation::Memory(body.to_vec()),
+                false,
+            )];
+
+            if target_triple.contains("pc-windows") {
+                let interpreter = interpreter.replace('/', "\\");
+
+                res.push((
+                    parent.join(format!("{}.cmd", name)),
+                    DataLocation::Memory(
+                        format!("@\"%~dp0{}\" \"%~dp0{}\" %*\r\n", interpreter, script_name)
+                            .into_bytes(),
+                    ),
+                    true,
+                ));
+                res.push((
+                    parent.join(format!("{}.ps1", name)),
+                    DataLocation::Memory(
+                        format!(
+                            "& \"$PSScriptRoot\\{}\" \"$PSScriptRoot\\{}\" @args\r\nexit $LASTEXITCODE\r\n",
+                            interpreter, script_name
+                        )
+                        .into_bytes(),
+                    ),
+                    true,
+                ));
+            } else {
+                res.push((
+                    path.clone(),
+                    DataLocation::Memory(
+                        format!(
+                            "#!/bin/sh\nexec \"$(dirname -- \"$0\")/{}\" \"$(dirname -- \"$0\")/{}\" \"$@\"\n",
+                            interpreter, script_name
+                        )
+                        .into_bytes(),
+                    ),
+                    true,
+                ));
+            }
+
+            Ok(res)
+        }
+    }
+}
+
+/// Process scripts in a set of files to install.
+///
+/// The first rule whose pattern matches a file's install path determines
+/// how that file is processed. Files not matching any rule are returned
+/// untouched.
+pub fn process_scripts(
+    files: &[FileInstall],
+    rules: &[ScriptRule],
+    target_triple: &str,
+) -> Result<Vec<FileInstall>> {
+    let mut res = Vec::new();
+
+    for install in files {
+        let path = format!("{}", install.0.display()).replace('\\', "/");
+
+        match rules.iter().find(|rule| rule.pattern.is_match(&path)) {
+            Some(rule) => res.extend(
+                process_script(install, &rule.action, target_triple)
+                    .with_context(|| format!("processing script {}", path))?,
+            ),
+            None => res.push(install.clone()),
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, std::path::PathBuf};
+
+    fn script(path: &str, data: &[u8], executable: bool) -> FileInstall {
+        (
+            PathBuf::from(path),
+            DataLocation::Memory(data.to_vec()),
+            executable,
+        )
+    }
+
+    #[test]
+    fn test_rewrite_shebang() -> Result<()> {
+        let res = process_script(
+            &script(
+                "bin/foo",
+                b"#!/build/venv/bin/python3\nimport foo\nfoo.main()\n",
+                false,
+            ),
+            &ScriptAction::RewriteShebang("../python".to_string()),
+            "x86_64-unknown-linux-gnu",
+        )?;
+
+        assert_eq!(
+            res,
+            vec![script(
+                "bin/foo",
+                b"#!/bin/sh\n'''exec' \"$(dirname -- \"$0\")/../python\" \"$0\" \"$@\"\n' '''\nimport foo\nfoo.main()\n",
+                true
+            )]
+        );
+
+        // Scripts without a shebang are left alone.
+        let original = script("bin/foo", b"import foo\n", false);
+        let res = process_script(
+            &original,
+            &ScriptAction::RewriteShebang("../python".to_string()),
+            "x86_64-unknown-linux-gnu",
+        )?;
+        assert_eq!(res, vec![original]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_binary_passthrough() -> Result<()> {
+        let original = script("bin/foo", b"#!\x7fELF\x00\x01\x02", true);
+
+        for action in &[
+            ScriptAction::RewriteShebang("../python".to_string()),
+            ScriptAction::Launchers("../python".to_string()),
+        ] {
+            let res = process_script(&original, action, "x86_64-unknown-linux-gnu")?;
+            assert_eq!(res, vec![original.clone()]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_launchers_posix() -> Result<()> {
+        let res = process_script(
+            &script("bin/foo", b"#!/usr/bin/python\nimport foo\n", false),
+            &ScriptAction::Launchers("../python".to_string()),
+            "x86_64-apple-darwin",
+        )?;
+
+        assert_eq!(
+            res,
+            vec![
+                script("bin/foo-script.py", b"import foo\n", false),
+                script(
+                    "bin/foo",
+                    b"#!/bin/sh\nexec \"$(dirname -- \"$0\")/../python\" \"$(dirname -- \"$0\")/foo-script.py\" \"$@\"\n",
+                    true
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_launchers_windows() -> Result<()> {
+        let res = process_script(
+            &script("Scripts/foo", b"#!python\r\nimport foo\r\n", false),
+            &ScriptAction::Launchers("../python.exe".to_string()),
+            "x86_64-pc-windows-msvc",
+        )?;
+
+        assert_eq!(
+            res,
+            vec![
+                script("Scripts/foo-script.py", b"import foo\r\n", false),
+                script(
+                    "Scripts/foo.cmd",
+                    b"@\"%~dp0..\\python.exe\" \"%~dp0foo-script.py\" %*\r\n",
+                    true
+                ),
+                script(
+                    "Scripts/foo.ps1",
+                    b"& \"$PSScriptRoot\\..\\python.exe\" \"$PSScriptRoot\\foo-script.py\" @args\r\nexit $LASTEXITCODE\r\n",
+                    true
+                ),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_process_scripts_rules() -> Result<()> {
+        let files = vec![
+            script("lib/bin/foo", b"#!/usr/bin/python\nimport foo\n", false),
+            script("lib/bin/bar", b"#!/usr/bin/python\nimport bar\n", false),
+            script("lib/foo/data.txt", b"#!not a script\n", false),
+        ];
+
+        let rules = vec![
+            ScriptRule::new("^lib/bin/bar$", ScriptAction::Passthrough)?,
+            ScriptRule::new(
+                "^lib/bin/",
+                ScriptAction::RewriteShebang("../../python".to_string()),
+            )?,
+        ];
+
+        let res = process_scripts(&files, &rules, "x86_64-unknown-linux-gnu")?;
+
+        assert_eq!(res.len(), 3);
+        assert_eq!(
+            res[0],
+            script(
+                "lib/bin/foo",
+                b"#!/bin/sh\n'''exec' \"$(dirname -- \"$0\")/../../python\" \"$0\" \"$@\"\n' '''\nimport foo\n",
+                true
+            )
+        );
+        assert_eq!(res[1], files[1]);
+        assert_eq!(res[2], files[2]);
+
+        assert!(ScriptRule::new("(", ScriptAction::Passthrough).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,930 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Shrink Python module source without changing its behavior.
+
+`BytecodeOptimizationLevel::Two` strips docstrings but also drops `assert`
+statements and `if __debug__:` blocks. The transforms here rewrite source
+code to remove docstrings, annotations and type comments independently, so
+they can be combined with any optimization level.
+
+Transforms preserve line numbers so tracebacks stay accurate. Removed text
+spanning several lines leaves blank lines behind. Docstrings of classes and
+functions are replaced by `pass`, so bodies remain valid. Module docstrings
+are removed outright, so `from __future__` imports stay first.
+
+Source is tokenized, not parsed. Only UTF-8 source is transformed.
+*/
+
+use {
+    crate::python_source::python_source_encoding,
+    anyhow::{anyhow, Result},
+    std::collections::BTreeSet,
+};
+
+/// Names starting compound statements.
+const HEADER_KEYWORDS: &[&str] = &[
+    "async", "case", "class", "def", "elif", "else", "except", "finally", "for", "if", "match",
+    "try", "while", "with",
+];
+
+/// Names that can't start an annotated assignment.
+const KEYWORDS: &[&str] = &[
+    "False", "None", "True", "and", "as", "assert", "async", "await", "break", "class", "continue",
+    "def", "del", "elif", "else", "except", "finally", "for", "from", "global", "if", "import",
+    "in", "is", "lambda", "nonlocal", "not", "or", "pass", "raise", "return", "try", "while",
+    "with", "yield",
+];
+
+/// Operators longer than one character, longest first.
+const OPERATORS: &[&str] = &[
+    "**=", "//=", ">>=", "<<=", "...", "->", ":=", "**", "//", "==", "!=", "<=", ">=", "<<", ">>",
+    "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "@=",
+];
+
+/// Transforms applied to module source as it is collected.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SourceTransforms {
+    /// Remove docstrings of modules, classes and functions.
+    pub strip_docstrings: bool,
+
+    /// Remove annotations of function parameters, return values and local variables.
+    ///
+    /// Annotations of module and class variables are kept because
+    /// `dataclasses`, `typing.NamedTuple` and others read them at run-time.
+    pub strip_annotations: bool,
+
+    /// Remove `# type:` comments.
+    pub strip_type_comments: bool,
+
+    /// Names of modules left alone, along with their submodules.
+    ///
+    /// This is for modules relying on docstrings or annotations at
+    /// run-time, such as parsers generated from docstrings.
+    pub excluded_modules: BTreeSet<String>,
+}
+
+impl SourceTransforms {
+    /// Whether any transform is enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.strip_docstrings || self.strip_annotations || self.strip_type_comments
+    }
+
+    /// Whether a module is left alone.
+    pub fn is_excluded(&self, name: &str) -> bool {
+        self.excluded_modules.iter().any(|excluded| {
+            name == excluded
+                || (name.starts_with(excluded.as_str()) && name[excluded.len()..].starts_with('.'))
+        })
+    }
+
+    /// Apply enabled transforms to the source of a module.
+    ///
+    /// Returns `None` if the module is excluded, its source isn't UTF-8 or
+    /// nothing was removed.
+    pub fn apply(&self, name: &str, source: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.is_enabled() || self.is_excluded(name) {
+            return Ok(None);
+        }
+
+        let original = match std::str::from_utf8(source) {
+            Ok(source) if is_utf8_encoding(&python_source_encoding(source.as_bytes())) => source,
+            _ => return Ok(None),
+        };
+
+        let mut transformed = original.to_string();
+        if self.strip_docstrings {
+            transformed = strip_docstrings(&transformed)?;
+        }
+        if self.strip_annotations {
+            transformed = strip_annotations(&transformed)?;
+        }
+        if self.strip_type_comments {
+            transformed = strip_type_comments(&transformed)?;
+        }
+
+        Ok(if transformed == original {
+            None
+        } else {
+            Some(transformed.into_bytes())
+        })
+    }
+}
+
+/// Whether a PEP 263 encoding declaration names UTF-8.
+fn is_utf8_encoding(encoding: &[u8]) -> bool {
+    let encoding = String::from_utf8_lossy(encoding).to_ascii_lowercase();
+
+    encoding == "utf-8" || encoding.ends_with("utf-8") || encoding.ends_with("utf8")
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TokenKind {
+    Name,
+    Number,
+    String,
+    Op,
+    Comment,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token {
+    kind: TokenKind,
+    start: usize,
+    end: usize,
+    /// Number of brackets the token is in. Brackets are outside themselves.
+    depth: usize,
+}
+
+/// The tokens of a statement, or of statements separated by `;`.
+///
+/// Comments aren't part of logical lines.
+struct LogicalLine {
+    /// Width of the indentation of the first physical line.
+    indent: usize,
+    tokens: Vec<Token>,
+}
+
+/// The 1-based number of the line containing an offset.
+fn line_number(source: &str, offset: usize) -> usize {
+    source[..offset].matches('\n').count() + 1
+}
+
+/// Width of the whitespace preceding an offset on its line.
+///
+/// Tabs advance to the next multiple of 8, like Python does.
+fn indentation(source: &str, offset: usize) -> usize {
+    let line_start = source[..offset].rfind('\n').map(|pos| pos + 1).unwrap_or(0);
+
+    source[line_start..offset]
+        .chars()
+        .fold(0, |width, c| match c {
+            '\t' => (width / 8 + 1) * 8,
+            _ => width + 1,
+        })
+}
+
+fn is_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b >= 0x80
+}
+
+/// Find the end of a string literal whose quote starts at `pos`.
+fn scan_string(source: &str, pos: usize, is_fstring: bool) -> Result<usize> {
+    let bytes = source.as_bytes();
+    let quote = bytes[pos];
+    let triple = bytes[pos..].starts_with(&[quote, quote, quote]);
+
+    let mut i = pos + if triple { 3 } else { 1 };
+    // Replacement fields of f-strings may contain strings with any quotes.
+    let mut braces = 0;
+
+    loop {
+        let b = match bytes.get(i) {
+            Some(b) => *b,
+            None => {
+                return Err(anyhow!(
+                    "unterminated string literal on line {}",
+                    line_number(source, pos)
+                ))
+            }
+        };
+
+        // Backslashes escape quotes, but not braces of f-strings.
+        if b == b'\\' && !(is_fstring && matches!(bytes.get(i + 1), Some(b'{') | Some(b'}'))) {
+            i += 2;
+            continue;
+        }
+
+        if braces == 0 {
+            if triple && bytes[i..].starts_with(&[quote, quote, quote]) {
+                return Ok(i + 3);
+            } else if !triple && b == quote {
+                return Ok(i + 1);
+            } else if !triple && (b == b'\n' || b == b'\r') {
+                return Err(anyhow!(
+                    "unterminated string literal on line {}",
+                    line_number(source, pos)
+                ));
+            }
+        }
+
+        if is_fstring {
+            if b == b'{' && braces == 0 && bytes.get(i + 1) == Some(&b'{') {
+                i += 2;
+                continue;
+            } else if b == b'{' {
+                braces += 1;
+            } else if b == b'}' && braces > 0 {
+                braces -= 1;
+            } else if braces > 0 && (b == b'"' || b == b'\'') {
+                i = scan_string(source, i, false)?;
+                continue;
+            }
+        }
+
+        i += 1;
+    }
+}
+
+/// Find the kind and end of the token starting at `pos`.
+fn scan_token(source: &str, pos: usize) -> Result<(TokenKind, usize)> {
+    let bytes = source.as_bytes();
+    let b = bytes[pos];
+
+    if is_name_byte(b) && !b.is_ascii_digit() {
+        let end = bytes[pos..]
+            .iter()
+            .position(|b| !is_name_byte(*b))
+            .map(|len| pos + len)
+            .unwrap_or_else(|| bytes.len());
+        let name = &source[pos..end];
+
+        let is_prefix = name.len() <= 2 && name.chars().all(|c| "rRbBuUfF".contains(c));
+        if is_prefix && matches!(bytes.get(end), Some(b'"') | Some(b'\'')) {
+            let is_fstring = name.contains(&['f', 'F'][..]);
+            return Ok((TokenKind::String, scan_string(source, end, is_fstring)?));
+        }
+
+        return Ok((TokenKind::Name, end));
+    }
+
+    if b == b'"' || b == b'\'' {
+        return Ok((TokenKind::String, scan_string(source, pos, false)?));
+    }
+
+    if b.is_ascii_digit()
+        || (b == b'.' && matches!(bytes.get(pos + 1), Some(b) if b.is_ascii_digit()))
+    {
+        let is_hex = source[pos..].starts_with("0x") || source[pos..].starts_with("0X");
+
+        let mut end = pos;
+        while let Some(b) = bytes.get(end) {
+            let exponent_sign = (*b == b'+' || *b == b'-')
+                && !is_hex
+                && end > pos
+                && (bytes[end - 1] == b'e' || bytes[end - 1] == b'E');
+
+            if b.is_ascii_alphanumeric() || *b == b'_' || *b == b'.' || exponent_sign {
+                end += 1;
+            } else {
+                break;
+            }
+        }
+
+        return Ok((TokenKind::Number, end));
+    }
+
+    let len = OPERATORS
+        .iter()
+        .find(|op| source[pos..].starts_with(*op))
+        .map(|op| op.len())
+        .unwrap_or_else(|| source[pos..].chars().next().map_or(1, |c| c.len_utf8()));
+
+    Ok((TokenKind::Op, pos + len))
+}
+
+/// Split source into logical lines and comments.
+fn tokenize(source: &str) -> Result<(Vec<LogicalLine>, Vec<Token>)> {
+    let bytes = source.as_bytes();
+
+    let mut lines = vec![];
+    let mut comments = vec![];
+    let mut current: Option<LogicalLine> = None;
+    let mut depth = 0;
+    let mut pos = 0;
+
+    while pos < bytes.len() {
+        match bytes[pos] {
+            b' ' | b'\t' | b'\x0c' => pos += 1,
+            b'\\' if source[pos + 1..].starts_with('\n') || source[pos + 1..].starts_with('\r') => {
+                pos += if source[pos + 1..].starts_with("\r\n") {
+                    3
+                } else {
+                    2
+                };
+            }
+            b'\n' | b'\r' => {
+                // Newlines in brackets don't end logical lines.
+                if depth == 0 {
+                    if let Some(line) = current.take() {
+                        lines.push(line);
+                    }
+                }
+
+                pos += 1;
+            }
+            b'#' => {
+                let end = bytes[pos..]
+                    .iter()
+                    .position(|b| *b == b'\n' || *b == b'\r')
+                    .map(|len| pos + len)
+                    .unwrap_or_else(|| bytes.len());
+
+                comments.push(Token {
+                    kind: TokenKind::Comment,
+                    start: pos,
+                    end,
+                    depth,
+                });
+                pos = end;
+            }
+            _ => {
+                let (kind, end) = scan_token(source, pos)?;
+
+                let token_depth = if kind != TokenKind::Op {
+                    depth
+                } else {
+                    match &source[pos..end] {
+                        "(" | "[" | "{" => {
+                            depth += 1;
+                            depth - 1
+                        }
+                        ")" | "]" | "}" => {
+                            depth = depth.saturating_sub(1);
+                            depth
+                        }
+                        _ => depth,
+                    }
+                };
+
+                current
+                    .get_or_insert_with(|| LogicalLine {
+                        indent: indentation(source, pos),
+                        tokens: vec![],
+                    })
+                    .tokens
+                    .push(Token {
+                        kind,
+                        start: pos,
+                        end,
+                        depth: token_depth,
+                    });
+                pos = end;
+            }
+        }
+    }
+
+    if let Some(line) = current.take() {
+        lines.push(line);
+    }
+
+    Ok((lines, comments))
+}
+
+fn text<'a>(source: &'a str, token: &Token) -> &'a str {
+    &source[token.start..token.end]
+}
+
+/// The line breaks in a range of source, preserving line numbers once it is removed.
+///
+/// Line breaks removed from the middle of a statement outside of brackets
+/// must be `continued` with a backslash.
+fn line_breaks(source: &str, start: usize, end: usize, continued: bool) -> String {
+    let mut res = String::new();
+    let mut chars = source[start..end].chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '\n' && c != '\r' {
+            continue;
+        }
+
+        if continued {
+            res.push('\\');
+        }
+        res.push(c);
+
+        if c == '\r' && chars.peek() == Some(&'\n') {
+            res.push(chars.next().unwrap());
+        }
+    }
+
+    res
+}
+
+/// A replacement of a range of source.
+struct Edit {
+    start: usize,
+    end: usize,
+    replacement: String,
+}
+
+impl Edit {
+    /// Remove a range of source, keeping its line breaks.
+    ///
+    /// See `line_breaks()` for when they must be `continued`.
+    fn remove(source: &str, start: usize, end: usize, continued: bool) -> Self {
+        Self {
+            start,
+            end,
+            replacement: line_breaks(source, start, end, continued),
+        }
+    }
+
+    /// Replace a statement by `pass`, keeping its line breaks.
+    fn pass(source: &str, start: usize, end: usize) -> Self {
+        Self {
+            start,
+            end,
+            replacement: format!("pass{}", line_breaks(source, start, end, false)),
+        }
+    }
+}
+
+/// Apply edits to source. Edits overlapping an earlier one are ignored.
+fn apply_edits(source: &str, mut edits: Vec<Edit>) -> String {
+    edits.sort_by_key(|edit| edit.start);
+
+    let mut res = String::with_capacity(source.len());
+    let mut pos = 0;
+
+    for edit in edits {
+        if edit.start < pos {
+            continue;
+        }
+
+        res.push_str(&source[pos..edit.start]);
+        res.push_str(&edit.replacement);
+        pos = edit.end;
+    }
+
+    res.push_str(&source[pos..]);
+
+    res
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Block {
+    Module,
+    Class,
+    Function,
+    Other,
+}
+
+/// Where a logical line is.
+struct LineContext {
+    /// Innermost class or function containing the line, or the module.
+    scope: Block,
+    /// Block directly containing the line.
+    parent: Block,
+    /// Whether the line is the first statement of its block.
+    first: bool,
+}
+
+/// Find the block a compound statement header opens and the index of its colon.
+fn header(source: &str, line: &LogicalLine) -> Option<(Block, usize)> {
+    let first = text(source, line.tokens.first()?);
+    if !HEADER_KEYWORDS.contains(&first) {
+        return None;
+    }
+
+    let colon = line
+        .tokens
+        .iter()
+        .position(|t| t.depth == 0 && text(source, t) == ":")?;
+
+    let keyword = if first == "async" {
+        line.tokens.get(1).map_or("", |t| text(source, t))
+    } else {
+        first
+    };
+
+    Some((
+        match keyword {
+            "def" => Block::Function,
+            "class" => Block::Class,
+            _ => Block::Other,
+        },
+        colon,
+    ))
+}
+
+/// Determine where each logical line is from indentation.
+fn line_contexts(source: &str, lines: &[LogicalLine]) -> Vec<LineContext> {
+    // Indentation of the body of open blocks, innermost last.
+    let mut stack: Vec<(usize, Block)> = vec![];
+    let mut pending = None;
+    let mut res = Vec::with_capacity(lines.len());
+
+    for (i, line) in lines.iter().enumerate() {
+        let first = if let Some(block) = pending.take() {
+            stack.push((line.indent, block));
+            true
+        } else {
+            while matches!(stack.last(), Some((indent, _)) if *indent > line.indent) {
+                stack.pop();
+            }
+            i == 0 && stack.is_empty()
+        };
+
+        res.push(LineContext {
+            scope: stack
+                .iter()
+                .rev()
+                .map(|(_, block)| *block)
+                .find(|block| *block == Block::Class || *block == Block::Function)
+                .unwrap_or(Block::Module),
+            parent: stack.last().map_or(Block::Module, |(_, block)| *block),
+            first,
+        });
+
+        // A header ending a line opens an indented block.
+        if let Some((block, colon)) = header(source, line) {
+            if colon == line.tokens.len() - 1 {
+                pending = Some(block);
+            }
+        }
+    }
+
+    res
+}
+
+/// Whether tokens form a docstring.
+///
+/// Docstrings are string literals, possibly implicitly concatenated.
+/// Bytes and f-strings don't count.
+fn is_docstring(source: &str, tokens: &[Token]) -> bool {
+    !tokens.is_empty()
+        && tokens.iter().all(|t| {
+            let literal = text(source, t);
+
+            t.kind == TokenKind::String
+                && !literal
+                    .chars()
+                    .take_while(|c| *c != '"' && *c != '\'')
+                    .any(|c| "bBfF".contains(c))
+        })
+}
+
+/// Remove docstrings of the module and of classes and functions.
+pub fn strip_docstrings(source: &str) -> Result<String> {
+    let (lines, _) = tokenize(source)?;
+    let mut edits = vec![];
+
+    for (line, context) in lines.iter().zip(line_contexts(source, &lines)) {
+        // Bodies of one-line classes and functions follow the header.
+        if let Some((block, colon)) = header(source, line) {
+            let body = &line.tokens[colon + 1..];
+
+            if block != Block::Other && is_docstring(source, body) {
+                edits.push(Edit::pass(source, body[0].start, body[body.len() - 1].end));
+            }
+
+            continue;
+        }
+
+        if !context.first || context.parent == Block::Other || !is_docstring(source, &line.tokens) {
+            continue;
+        }
+
+        let start = line.tokens[0].start;
+        let end = line.tokens[line.tokens.len() - 1].end;
+
+        edits.push(if context.parent == Block::Module {
+            Edit::remove(source, start, end, false)
+        } else {
+            Edit::pass(source, start, end)
+        });
+    }
+
+    Ok(apply_edits(source, edits))
+}
+
+/// Remove the annotation of a parameter, given its tokens.
+fn strip_parameter_annotation(source: &str, tokens: &[Token], edits: &mut Vec<Edit>) {
+    let name = tokens
+        .iter()
+        .position(|t| !matches!(text(source, t), "*" | "**"))
+        .unwrap_or(tokens.len());
+
+    if tokens.get(name).map(|t| t.kind) != Some(TokenKind::Name)
+        || tokens.get(name + 1).map(|t| text(source, t)) != Some(":")
+    {
+        return;
+    }
+
+    let end = tokens[name + 2..]
+        .iter()
+        .position(|t| t.depth == 1 && text(source, t) == "=")
+        .map_or(tokens.len(), |len| name + 2 + len);
+
+    if end > name + 2 {
+        edits.push(Edit::remove(
+            source,
+            tokens[name + 1].start,
+            tokens[end - 1].end,
+            false,
+        ));
+    }
+}
+
+/// Remove annotations of a function signature, given tokens of its header before the colon.
+fn strip_signature_annotations(source: &str, tokens: &[Token], edits: &mut Vec<Edit>) {
+    let open = match tokens
+        .iter()
+        .position(|t| t.depth == 0 && text(source, t) == "(")
+    {
+        Some(open) => open,
+        None => return,
+    };
+    let close = match tokens[open..]
+        .iter()
+        .position(|t| t.depth == 0 && text(source, t) == ")")
+    {
+        Some(len) => open + len,
+        None => return,
+    };
+
+    // Parameters are separated by commas directly in the parentheses,
+    // except those separating parameters of lambdas in default values.
+    let mut lambdas = 0;
+    let mut start = open + 1;
+    for i in open + 1..=close {
+        let t = &tokens[i];
+
+        if i < close && t.depth == 1 {
+            match text(source, t) {
+                "lambda" => lambdas += 1,
+                ":" if lambdas > 0 => lambdas -= 1,
+                _ => {}
+            }
+        }
+
+        if i == close || (t.depth == 1 && lambdas == 0 && text(source, t) == ",") {
+            strip_parameter_annotation(source, &tokens[start..i], edits);
+            start = i + 1;
+        }
+    }
+
+    if let Some(arrow) = tokens[close + 1..]
+        .iter()
+        .position(|t| t.depth == 0 && text(source, t) == "->")
+    {
+        edits.push(Edit::remove(
+            source,
+            tokens[close + 1 + arrow].start,
+            tokens[tokens.len() - 1].end,
+            true,
+        ));
+    }
+}
+
+/// Remove the annotation of an annotated assignment statement.
+///
+/// Annotations without a value are kept since they make the name local,
+/// which `nonlocal` statements of nested functions may rely on.
+fn strip_variable_annotation(source: &str, tokens: &[Token], edits: &mut Vec<Edit>) {
+    if tokens[0].kind != TokenKind::Name
+        || KEYWORDS.contains(&text(source, &tokens[0]))
+        || tokens
+            .iter()
+            .any(|t| t.depth == 0 && text(source, t) == ";")
+    {
+        return;
+    }
+
+    // Targets are names followed by attribute references and subscripts.
+    let mut colon = 1;
+    loop {
+        match tokens.get(colon).map(|t| text(source, t)) {
+            Some(".") if tokens.get(colon + 1).map(|t| t.kind) == Some(TokenKind::Name) => {
+                colon += 2;
+            }
+            Some("[") => {
+                match tokens[colon + 1..]
+                    .iter()
+                    .position(|t| t.depth == tokens[colon].depth && text(source, t) == "]")
+                {
+                    Some(len) => colon += len + 2,
+                    None => return,
+                }
+            }
+            Some(":") => break,
+            _ => return,
+        }
+    }
+
+    let annotation = &tokens[colon + 1..];
+    if let Some(len) = annotation
+        .iter()
+        .position(|t| t.depth == 0 && text(source, t) == "=")
+    {
+        if len > 0 {
+            edits.push(Edit::remove(
+                source,
+                tokens[colon].start,
+                annotation[len - 1].end,
+                true,
+            ));
+        }
+    }
+}
+
+/// Remove annotations of functions and of their local variables.
+///
+/// Annotations of local variables are never evaluated, unlike those of
+/// module and class variables, which are kept. Annotations without a value
+/// are kept too, since they make names local.
+pub fn strip_annotations(source: &str) -> Result<String> {
+    let (lines, _) = tokenize(source)?;
+    let mut edits = vec![];
+
+    for (line, context) in lines.iter().zip(line_contexts(source, &lines)) {
+        if let Some((block, colon)) = header(source, line) {
+            if block == Block::Function {
+                strip_signature_annotations(source, &line.tokens[..colon], &mut edits);
+            }
+        } else if context.scope == Block::Function {
+            strip_variable_annotation(source, &line.tokens, &mut edits);
+        }
+    }
+
+    Ok(apply_edits(source, edits))
+}
+
+/// Remove `# type:` comments, including `# type: ignore`.
+pub fn strip_type_comments(source: &str) -> Result<String> {
+    let (_, comments) = tokenize(source)?;
+
+    let edits = comments
+        .iter()
+        .filter(|t| text(source, t)[1..].trim_start().starts_with("type:"))
+        .map(|t| Edit {
+            // Whitespace before the comment goes along with it.
+            start: source[..t.start]
+                .trim_end_matches(&[' ', '\t', '\x0c'][..])
+                .len(),
+            end: t.end,
+            replacement: String::new(),
+        })
+        .collect();
+
+    Ok(apply_edits(source, edits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_lines_preserved(before: &str, after: &str) {
+        assert_eq!(before.lines().count(), after.lines().count(), "{}", after);
+    }
+
+    #[test]
+    fn test_strip_docstrings() -> Result<()> {
+        let source = concat!(
+            "\"\"\"Module.\n",
+            "\n",
+            "More.\"\"\"\n",
+            "from __future__ import annotations\n",
+            "\n",
+            "class A:\n",
+            "    'A.' \\\n",
+            "    \"continued\"\n",
+            "\n",
+            "    def f(self, x=\"\"\"not a docstring\"\"\"):\n",
+            "        r'''f.'''  # comment\n",
+            "        return '''kept'''\n",
+            "\n",
+            "async def g(): \"g.\"\n",
+            "def h():\n",
+            "    rf'\\{{{x}'\n",
+            "    if x:\n",
+            "        'not a docstring'\n",
+            "    b'bytes'\n",
+        );
+
+        let stripped = strip_docstrings(source)?;
+        assert_eq!(
+            stripped,
+            concat!(
+                "\n",
+                "\n",
+                "\n",
+                "from __future__ import annotations\n",
+                "\n",
+                "class A:\n",
+                "    pass\n",
+                "\n",
+                "\n",
+                "    def f(self, x=\"\"\"not a docstring\"\"\"):\n",
+                "        pass  # comment\n",
+                "        return '''kept'''\n",
+                "\n",
+                "async def g(): pass\n",
+                "def h():\n",
+                "    rf'\\{{{x}'\n",
+                "    if x:\n",
+                "        'not a docstring'\n",
+                "    b'bytes'\n",
+            )
+        );
+        assert_lines_preserved(source, &stripped);
+
+        assert_eq!(
+            strip_docstrings("x = 1\n'not a docstring'\n")?,
+            "x = 1\n'not a docstring'\n"
+        );
+        assert!(strip_docstrings("'''unterminated\n").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_annotations() -> Result<()> {
+        let source = concat!(
+            "x: int = 1\n",
+            "\n",
+            "class A:\n",
+            "    y: str\n",
+            "\n",
+            "    def f(self, a: int, *args: str, b: dict[str, int] = {}, c=lambda d, e: d,\n",
+            "          **kwargs: 'Any') -> Optional[\n",
+            "        int]:\n",
+            "        local: List[int] = []\n",
+            "        self.attr: int = 1\n",
+            "        declared: int\n",
+            "        lookup = {'a': 1}\n",
+            "        f = lambda: 0\n",
+            "        return local[0]\n",
+            "\n",
+            "def g(a, /, b: int, *, c: int = 1): return a\n",
+        );
+
+        let stripped = strip_annotations(source)?;
+        assert_eq!(
+            stripped,
+            concat!(
+                "x: int = 1\n",
+                "\n",
+                "class A:\n",
+                "    y: str\n",
+                "\n",
+                "    def f(self, a, *args, b = {}, c=lambda d, e: d,\n",
+                "          **kwargs) \\\n",
+                ":\n",
+                "        local = []\n",
+                "        self.attr = 1\n",
+                "        declared: int\n",
+                "        lookup = {'a': 1}\n",
+                "        f = lambda: 0\n",
+                "        return local[0]\n",
+                "\n",
+                "def g(a, /, b, *, c = 1): return a\n",
+            )
+        );
+        assert_lines_preserved(source, &stripped);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_strip_type_comments() -> Result<()> {
+        let source = concat!(
+            "x = []  # type: List[int]\n",
+            "def f(a,  # type: int\n",
+            "      ):\n",
+            "    # type: (...) -> None\n",
+            "    return '# type: kept'  # regular comment\n",
+        );
+
+        assert_eq!(
+            strip_type_comments(source)?,
+            concat!(
+                "x = []\n",
+                "def f(a,\n",
+                "      ):\n",
+                "\n",
+                "    return '# type: kept'  # regular comment\n",
+            )
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_source_transforms() -> Result<()> {
+        let mut transforms = SourceTransforms {
+            strip_docstrings: true,
+            excluded_modules: vec!["ply".to_string()].into_iter().collect(),
+            ..SourceTransforms::default()
+        };
+
+        assert_eq!(transforms.apply("foo", b"'doc'\n")?, Some(b"\n".to_vec()));
+        assert_eq!(transforms.apply("foo", b"x = 1\n")?, None);
+        assert_eq!(transforms.apply("ply", b"'doc'\n")?, None);
+        assert_eq!(transforms.apply("ply.yacc", b"'doc'\n")?, None);
+        assert_eq!(transforms.apply("plyer", b"'doc'\n")?, Some(b"\n".to_vec()));
+        assert_eq!(
+            transforms.apply("foo", b"# -*- coding: latin-1 -*-\n'doc'\n")?,
+            None
+        );
+
+        transforms.strip_docstrings = false;
+        assert!(!transforms.is_enabled());
+        assert_eq!(transforms.apply("foo", b"'doc'\n")?, None);
+
+        Ok(())
+    }
+}
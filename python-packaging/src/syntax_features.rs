@@ -0,0 +1,502 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Detect Python syntax requiring a minimum Python version.
+
+Sources are tokenized and constructs introduced by recent Python versions,
+such as `match` statements, are recognized from their tokens. This is a
+best-effort scan meant to explain compile failures up front: it covers the
+major constructs only and, since the soft keywords `match` and `type` are
+recognized heuristically, may report false positives.
+*/
+
+use crate::python_source::decode_source;
+
+/// A syntax construct not available in all Python 3 versions.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum SyntaxFeature {
+    /// `async def`, `async for` and `async with`.
+    AsyncSyntax,
+    /// The `@` binary operator.
+    MatrixMultiplication,
+    /// `f"..."` string literals.
+    FormattedString,
+    /// Underscores in numeric literals, e.g. `1_000`.
+    NumericUnderscores,
+    /// The `:=` operator.
+    AssignmentExpression,
+    /// `/` in parameter lists.
+    PositionalOnlyParameters,
+    /// `match` statements.
+    MatchStatement,
+    /// `except*` clauses.
+    ExceptionGroups,
+    /// `type X = ...` statements.
+    TypeAliasStatement,
+    /// `def f[T]()` and `class C[T]`.
+    TypeParameters,
+}
+
+impl SyntaxFeature {
+    /// Human readable name of the construct.
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::AsyncSyntax => "async syntax",
+            Self::MatrixMultiplication => "@ operator",
+            Self::FormattedString => "f-string",
+            Self::NumericUnderscores => "underscore in numeric literal",
+            Self::AssignmentExpression => ":= operator",
+            Self::PositionalOnlyParameters => "positional-only parameter",
+            Self::MatchStatement => "match statement",
+            Self::ExceptionGroups => "except* clause",
+            Self::TypeAliasStatement => "type statement",
+            Self::TypeParameters => "type parameter list",
+        }
+    }
+
+    /// The `(major, minor)` Python version introducing the construct.
+    pub fn minimum_version(self) -> (u32, u32) {
+        match self {
+            Self::AsyncSyntax | Self::MatrixMultiplication => (3, 5),
+            Self::FormattedString | Self::NumericUnderscores => (3, 6),
+            Self::AssignmentExpression | Self::PositionalOnlyParameters => (3, 8),
+            Self::MatchStatement => (3, 10),
+            Self::ExceptionGroups => (3, 11),
+            Self::TypeAliasStatement | Self::TypeParameters => (3, 12),
+        }
+    }
+}
+
+/// The first use of a syntax construct in source code.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SyntaxFeatureUse {
+    pub feature: SyntaxFeature,
+    /// 1-based line number of the use.
+    pub line: usize,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum TokenKind<'a> {
+    Name(&'a str),
+    Number(&'a str),
+    /// A string literal with its prefix, e.g. `rb`.
+    String(&'a str),
+    Op(&'a str),
+    /// End of a logical line or statement.
+    Newline,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Token<'a> {
+    kind: TokenKind<'a>,
+    line: usize,
+}
+
+const STRING_PREFIXES: &[&str] = &["r", "u", "b", "br", "rb", "f", "fr", "rf"];
+
+const OPERATORS: &[&str] = &[
+    "**=", "//=", ">>=", "<<=", "...", "**", "//", "<<", ">>", "<=", ">=", "==", "!=", "->", ":=",
+    "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "@=",
+];
+
+/// Tokenize Python source code.
+///
+/// Only what's needed to recognize syntax constructs is distinguished.
+/// Indentation isn't tracked and invalid input is tokenized leniently.
+fn tokenize(source: &str) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let bytes = source.as_bytes();
+    let mut pos = 0;
+    let mut line = 1;
+    let mut depth = 0usize;
+
+    let push_newline = |tokens: &mut Vec<Token>, line| {
+        if !matches!(
+            tokens.last(),
+            None | Some(Token {
+                kind: TokenKind::Newline,
+                ..
+            })
+        ) {
+            tokens.push(Token {
+                kind: TokenKind::Newline,
+                line,
+            });
+        }
+    };
+
+    // Scan a string literal whose opening quote is at `start`, returning
+    // the position after it and the number of newlines it spans.
+    let scan_string = |start: usize| -> (usize, usize) {
+        let quote = bytes[start];
+        let triple =
+            bytes.len() >= start + 3 && bytes[start + 1] == quote && bytes[start + 2] == quote;
+        let mut i = start + if triple { 3 } else { 1 };
+        let mut newlines = 0;
+
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\\' => {
+                    if bytes.get(i + 1) == Some(&b'\n') {
+                        newlines += 1;
+                    }
+                    i += 2;
+                    continue;
+                }
+                b'\n' if !triple => break,
+                b'\n' => newlines += 1,
+                c if c == quote => {
+                    if !triple {
+                        return (i + 1, newlines);
+                    } else if bytes.get(i + 1) == Some(&quote) && bytes.get(i + 2) == Some(&quote) {
+                        return (i + 3, newlines);
+                    }
+                }
+                _ => {}
+            }
+
+            i += 1;
+        }
+
+        (i.min(bytes.len()), newlines)
+    };
+
+    while pos < bytes.len() {
+        let c = bytes[pos];
+
+        match c {
+            b' ' | b'\t' | b'\x0c' | b'\r' => pos += 1,
+            b'\n' => {
+                if depth == 0 {
+                    push_newline(&mut tokens, line);
+                }
+                line += 1;
+                pos += 1;
+            }
+            b'#' => {
+                pos = source[pos..]
+                    .find('\n')
+                    .map(|i| pos + i)
+                    .unwrap_or_else(|| bytes.len());
+            }
+            b'\\' => {
+                // Line continuation.
+                if source[pos + 1..].starts_with('\n') {
+                    line += 1;
+                    pos += 2;
+                } else if source[pos + 1..].starts_with("\r\n") {
+                    line += 1;
+                    pos += 3;
+                } else {
+                    pos += 1;
+                }
+            }
+            b'\'' | b'"' => {
+                let (end, newlines) = scan_string(pos);
+                tokens.push(Token {
+                    kind: TokenKind::String(""),
+                    line,
+                });
+                line += newlines;
+                pos = end;
+            }
+            b'0'..=b'9' => {
+                let start = pos;
+                let mut prev = c;
+                pos += 1;
+
+                while pos < bytes.len() {
+                    let c = bytes[pos];
+                    let exponent_sign = (c == b'+' || c == b'-')
+                        && (prev == b'e' || prev == b'E')
+                        && !source[start..pos].starts_with("0x")
+                        && !source[start..pos].starts_with("0X");
+
+                    if c.is_ascii_alphanumeric() || c == b'_' || c == b'.' || exponent_sign {
+                        prev = c;
+                        pos += 1;
+                    } else {
+                        break;
+                    }
+                }
+
+                tokens.push(Token {
+                    kind: TokenKind::Number(&source[start..pos]),
+                    line,
+                });
+            }
+            b'.' if bytes.get(pos + 1).map_or(false, |c| c.is_ascii_digit()) => {
+                let start = pos;
+                pos += 1;
+                while pos < bytes.len()
+                    && (bytes[pos].is_ascii_alphanumeric() || bytes[pos] == b'_')
+                {
+                    pos += 1;
+                }
+
+                tokens.push(Token {
+                    kind: TokenKind::Number(&source[start..pos]),
+                    line,
+                });
+            }
+            c if c == b'_' || c.is_ascii_alphabetic() || c >= 0x80 => {
+                let start = pos;
+                while pos < bytes.len()
+                    && (bytes[pos] == b'_'
+                        || bytes[pos].is_ascii_alphanumeric()
+                        || bytes[pos] >= 0x80)
+                {
+                    pos += 1;
+                }
+                let name = &source[start..pos];
+
+                if (bytes.get(pos) == Some(&b'\'') || bytes.get(pos) == Some(&b'"'))
+                    && STRING_PREFIXES.contains(&name.to_ascii_lowercase().as_str())
+                {
+                    let (end, newlines) = scan_string(pos);
+                    tokens.push(Token {
+                        kind: TokenKind::String(name),
+                        line,
+                    });
+                    line += newlines;
+                    pos = end;
+                } else {
+                    tokens.push(Token {
+                        kind: TokenKind::Name(name),
+                        line,
+                    });
+                }
+            }
+            _ => {
+                // Non-ASCII characters are handled as names, so this is
+                // always an ASCII character.
+                let len = OPERATORS
+                    .iter()
+                    .find(|op| source[pos..].starts_with(*op))
+                    .map(|op| op.len())
+                    .unwrap_or(1);
+                let op = &source[pos..pos + len];
+
+                match op {
+                    "(" | "[" | "{" => depth += 1,
+                    ")" | "]" | "}" => depth = depth.saturating_sub(1),
+                    _ => {}
+                }
+
+                if op == ";" {
+                    push_newline(&mut tokens, line);
+                } else {
+                    tokens.push(Token {
+                        kind: TokenKind::Op(op),
+                        line,
+                    });
+                }
+
+                pos += len;
+            }
+        }
+    }
+
+    push_newline(&mut tokens, line);
+
+    tokens
+}
+
+/// Find syntax constructs not available in all Python 3 versions.
+///
+/// Returns the first use of each construct found, ordered by feature.
+pub fn find_syntax_features(source: &[u8]) -> Vec<SyntaxFeatureUse> {
+    let source = decode_source(source);
+    let tokens = tokenize(source.trim_start_matches('\u{feff}'));
+    let mut found: Vec<SyntaxFeatureUse> = Vec::new();
+
+    let mut record = |feature: SyntaxFeature, line: usize| {
+        if !found.iter().any(|u| u.feature == feature) {
+            found.push(SyntaxFeatureUse { feature, line });
+        }
+    };
+
+    let kind = |i: usize| tokens.get(i).map(|t| t.kind);
+    let mut statement_start = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        let at_statement_start = i == statement_start;
+        let previous = if i > 0 { kind(i - 1) } else { None };
+        let next = kind(i + 1);
+
+        match token.kind {
+            TokenKind::Newline => {
+                statement_start = i + 1;
+            }
+            TokenKind::Op(":=") => record(SyntaxFeature::AssignmentExpression, token.line),
+            TokenKind::Op("@") | TokenKind::Op("@=") if !at_statement_start => {
+                record(SyntaxFeature::MatrixMultiplication, token.line)
+            }
+            TokenKind::Op("/")
+                if previous == Some(TokenKind::Op(","))
+                    && matches!(
+                        next,
+                        Some(TokenKind::Op(","))
+                            | Some(TokenKind::Op(")"))
+                            | Some(TokenKind::Op(":"))
+                    ) =>
+            {
+                record(SyntaxFeature::PositionalOnlyParameters, token.line)
+            }
+            TokenKind::String(prefix) if prefix.to_ascii_lowercase().contains('f') => {
+                record(SyntaxFeature::FormattedString, token.line)
+            }
+            TokenKind::Number(number) if number.contains('_') => {
+                record(SyntaxFeature::NumericUnderscores, token.line)
+            }
+            TokenKind::Name("async")
+                if matches!(
+                    next,
+                    Some(TokenKind::Name("def"))
+                        | Some(TokenKind::Name("for"))
+                        | Some(TokenKind::Name("with"))
+                ) =>
+            {
+                record(SyntaxFeature::AsyncSyntax, token.line)
+            }
+            TokenKind::Name("def") | TokenKind::Name("class")
+                if matches!(next, Some(TokenKind::Name(_)))
+                    && kind(i + 2) == Some(TokenKind::Op("[")) =>
+            {
+                record(SyntaxFeature::TypeParameters, token.line)
+            }
+            TokenKind::Name("except") if at_statement_start && next == Some(TokenKind::Op("*")) => {
+                record(SyntaxFeature::ExceptionGroups, token.line)
+            }
+            TokenKind::Name("type")
+                if at_statement_start
+                    && matches!(next, Some(TokenKind::Name(_)))
+                    && matches!(
+                        kind(i + 2),
+                        Some(TokenKind::Op("=")) | Some(TokenKind::Op("["))
+                    ) =>
+            {
+                record(SyntaxFeature::TypeAliasStatement, token.line)
+            }
+            TokenKind::Name("match") if at_statement_start => {
+                // `match` is a soft keyword. A match statement is a compound
+                // statement ending in `:`, which assignments and annotations
+                // to names called match aren't.
+                let end = tokens[i..]
+                    .iter()
+                    .position(|t| t.kind == TokenKind::Newline)
+                    .map(|p| i + p)
+                    .unwrap_or_else(|| tokens.len());
+
+                let subject = !matches!(
+                    next,
+                    None | Some(TokenKind::Newline)
+                        | Some(TokenKind::Op("="))
+                        | Some(TokenKind::Op("."))
+                        | Some(TokenKind::Op(":"))
+                        | Some(TokenKind::Op(","))
+                );
+
+                if subject && end > i + 1 && kind(end - 1) == Some(TokenKind::Op(":")) {
+                    record(SyntaxFeature::MatchStatement, token.line);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    found.sort_by_key(|u| u.feature);
+
+    found
+}
+
+/// Obtain the Python version required by syntax constructs.
+///
+/// Returns `None` if no construct has a requirement.
+pub fn required_python_version(uses: &[SyntaxFeatureUse]) -> Option<(u32, u32)> {
+    uses.iter().map(|u| u.feature.minimum_version()).max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn features(source: &str) -> Vec<(SyntaxFeature, usize)> {
+        find_syntax_features(source.as_bytes())
+            .into_iter()
+            .map(|u| (u.feature, u.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_find_syntax_features() {
+        assert_eq!(features("import os\nx = 1 + 2\n"), vec![]);
+
+        assert_eq!(
+            features("def f(a, /, b):\n    if (n := len(a)) > 1:\n        return f'{n}'\n"),
+            vec![
+                (SyntaxFeature::FormattedString, 3),
+                (SyntaxFeature::AssignmentExpression, 2),
+                (SyntaxFeature::PositionalOnlyParameters, 1),
+            ]
+        );
+
+        assert_eq!(
+            features("x = 1\n\nmatch command.split():\n    case [action]:\n        pass\n"),
+            vec![(SyntaxFeature::MatchStatement, 3)]
+        );
+
+        assert_eq!(
+            features("async def f():\n    return a @ b\n\n@decorator\ndef g(): pass\n"),
+            vec![
+                (SyntaxFeature::AsyncSyntax, 1),
+                (SyntaxFeature::MatrixMultiplication, 2)
+            ]
+        );
+
+        assert_eq!(
+            features("try:\n    pass\nexcept* ValueError:\n    pass\ntype Alias = int\nclass C[T]: pass\nn = 1_000\n"),
+            vec![
+                (SyntaxFeature::NumericUnderscores, 7),
+                (SyntaxFeature::ExceptionGroups, 3),
+                (SyntaxFeature::TypeAliasStatement, 5),
+                (SyntaxFeature::TypeParameters, 6),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_syntax_features_false_positives() {
+        // Names called match and type, as used by the re module and others.
+        assert_eq!(
+            features("match = re.match(p, s)\nif match:\n    pass\nmatch.group(0)\ntype = 'x'\nprint(type(x))\nmatch: int = 3\n"),
+            vec![]
+        );
+
+        // Constructs in strings and comments aren't reported.
+        assert_eq!(
+            features(
+                "s = \"x := 1\"\n# match x:\nd = '''\nmatch x:\n'''\ne = 1e-5\nh = 0xdeadbeef\n"
+            ),
+            vec![]
+        );
+
+        // Lines are counted through multi-line strings and continuations.
+        assert_eq!(
+            features("s = '''\n\n'''\nx = (1,\n     2)\ny = \\\n  f'{x}'\n"),
+            vec![(SyntaxFeature::FormattedString, 7)]
+        );
+    }
+
+    #[test]
+    fn test_required_python_version() {
+        assert_eq!(required_python_version(&[]), None);
+        assert_eq!(
+            required_python_version(&find_syntax_features(
+                b"f'{x}'\nmatch x:\n    case 1: pass\n"
+            )),
+            Some((3, 10))
+        );
+    }
+}
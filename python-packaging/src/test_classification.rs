@@ -0,0 +1,377 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Classify resources of installed packages as tests.
+
+Packages commonly ship their test suites alongside their code. Resources
+are classified as tests by their location:
+
+* Modules in a package named `test`, `tests` or `testing`, and resources
+  of such packages.
+* Modules whose name starts with `test_`.
+* Resources in a `test_data`, `testdata` or `test_fixtures` directory, or
+  in a directory named like a test package.
+
+Defaults are deliberately conservative: a directory merely named
+`fixtures` or `data` isn't a test, as packages like Django load fixtures
+at run-time.
+
+Some packages have a directory named like a test package that holds
+runtime code. Override files list such packages per distribution. Each
+line names a distribution followed by packages whose modules and resources
+are never tests:
+
+```text
+# Comments start with #.
+hypothesis hypothesis.testing
+```
+*/
+
+use {
+    crate::package_metadata::{normalize_distribution_name, PythonPackageRecord},
+    crate::resource::PythonResource,
+    anyhow::{anyhow, Context, Result},
+    std::collections::{BTreeMap, BTreeSet},
+    std::path::Path,
+};
+
+/// Names of packages holding tests.
+const TEST_PACKAGES: &[&str] = &["test", "tests", "testing"];
+
+/// Names of directories holding test data, in addition to `TEST_PACKAGES`.
+const TEST_DATA_DIRECTORIES: &[&str] = &["test_data", "testdata", "test_fixtures"];
+
+/// Whether a module name denotes a test.
+pub fn is_test_module_name(name: &str) -> bool {
+    let components = name.split('.').collect::<Vec<_>>();
+
+    components.iter().any(|c| TEST_PACKAGES.contains(c))
+        || components
+            .last()
+            .map_or(false, |leaf| leaf.starts_with("test_"))
+}
+
+/// Whether a package resource denotes test data.
+///
+/// `relative_name` is the `/` delimited path of the resource within
+/// `leaf_package`.
+pub fn is_test_resource(leaf_package: &str, relative_name: &str) -> bool {
+    if is_test_module_name(leaf_package) {
+        return true;
+    }
+
+    let directories = relative_name.split('/').collect::<Vec<_>>();
+
+    directories[0..directories.len() - 1]
+        .iter()
+        .any(|d| TEST_PACKAGES.contains(d) || TEST_DATA_DIRECTORIES.contains(d))
+}
+
+/// Packages whose resources are never classified as tests.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TestClassificationOverrides {
+    /// Packages holding runtime code, keyed by normalized distribution name.
+    runtime_packages: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl TestClassificationOverrides {
+    /// Parse overrides from the content of an override file.
+    pub fn parse(data: &str) -> Result<Self> {
+        let mut res = Self::default();
+
+        for (i, line) in data.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let distribution = words.next().unwrap();
+            let packages = words.collect::<Vec<_>>();
+
+            if packages.is_empty() {
+                return Err(anyhow!(
+                    "line {}: expected packages after distribution {}",
+                    i + 1,
+                    distribution
+                ));
+            }
+
+            for package in packages {
+                res.add_runtime_package(distribution, package);
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Read overrides from a file.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let data =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+
+        Self::parse(&data)
+            .with_context(|| format!("parsing test classification overrides {}", path.display()))
+    }
+
+    /// Declare that a package of a distribution holds runtime code.
+    pub fn add_runtime_package(&mut self, distribution: &str, package: &str) {
+        self.runtime_packages
+            .entry(normalize_distribution_name(distribution))
+            .or_insert_with(BTreeSet::new)
+            .insert(package.to_string());
+    }
+
+    /// Whether a module or package installed by a distribution holds runtime code.
+    pub fn is_runtime(&self, distribution: &str, name: &str) -> bool {
+        self.runtime_packages
+            .get(&normalize_distribution_name(distribution))
+            .map_or(false, |packages| {
+                packages.iter().any(|package| {
+                    name == package
+                        || (name.starts_with(package.as_str())
+                            && name[package.len()..].starts_with('.'))
+                })
+            })
+    }
+}
+
+/// Mark resources found in a directory as tests.
+///
+/// `resources` are those found by scanning a directory. Distributions are
+/// identified by their RECORD files among `resources`, so overrides apply
+/// to packages they installed. Resources already marked as tests stay
+/// marked. Returns the number of resources newly marked.
+pub fn classify_test_resources(
+    resources: &mut [PythonResource],
+    overrides: &TestClassificationOverrides,
+) -> Result<usize> {
+    let mut owners = BTreeMap::new();
+    for resource in resources.iter() {
+        if let PythonResource::DistributionResource(r) = resource {
+            if r.name != "RECORD" {
+                continue;
+            }
+
+            let data = r
+                .data
+                .resolve()
+                .with_context(|| format!("reading RECORD of {}", r.package))?;
+
+            // Distributions whose RECORD can't be parsed are ignored when
+            // scanning as well.
+            if let Ok(record) = PythonPackageRecord::from_record(&data) {
+                for name in record.top_level_names() {
+                    owners.insert(name, r.package.clone());
+                }
+            }
+        }
+    }
+
+    let is_runtime = |name: &str| {
+        let top_level = name.split('.').next().unwrap_or(name);
+
+        owners.get(top_level).map_or(false, |distribution| {
+            overrides.is_runtime(distribution, name)
+        })
+    };
+
+    let mut count = 0;
+    for resource in resources.iter_mut() {
+        let (is_test, classified) = match resource {
+            PythonResource::ModuleSource(m) => (
+                &mut m.is_test,
+                is_test_module_name(&m.name) && !is_runtime(&m.name),
+            ),
+            PythonResource::ModuleBytecode(m) => (
+                &mut m.is_test,
+                is_test_module_name(&m.name) && !is_runtime(&m.name),
+            ),
+            PythonResource::ModuleBytecodeRequest(m) => (
+                &mut m.is_test,
+                is_test_module_name(&m.name) && !is_runtime(&m.name),
+            ),
+            PythonResource::Resource(r) => (
+                &mut r.is_test,
+                is_test_resource(&r.leaf_package, &r.relative_name) && !is_runtime(&r.leaf_package),
+            ),
+            _ => continue,
+        };
+
+        if classified && !*is_test {
+            *is_test = true;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::resource::{
+            DataLocation, PythonModuleSource, PythonPackageDistributionResource,
+            PythonPackageDistributionResourceFlavor, PythonPackageResource,
+        },
+    };
+
+    fn module(name: &str) -> PythonResource {
+        PythonResource::ModuleSource(PythonModuleSource {
+            name: name.to_string(),
+            source: DataLocation::Memory(vec![]),
+            is_package: false,
+            cache_tag: "cpython-37".to_string(),
+            is_stdlib: false,
+            is_test: false,
+        })
+    }
+
+    fn resource(leaf_package: &str, relative_name: &str) -> PythonResource {
+        PythonResource::Resource(PythonPackageResource {
+            leaf_package: leaf_package.to_string(),
+            relative_name: relative_name.to_string(),
+            data: DataLocation::Memory(vec![]),
+            is_stdlib: false,
+            is_test: false,
+        })
+    }
+
+    fn record(package: &str, paths: &[&str]) -> PythonResource {
+        let data = paths
+            .iter()
+            .map(|path| format!("{},,\n", path))
+            .collect::<String>();
+
+        PythonResource::DistributionResource(PythonPackageDistributionResource {
+            location: PythonPackageDistributionResourceFlavor::DistInfo,
+            package: package.to_string(),
+            version: "1.0".to_string(),
+            name: "RECORD".to_string(),
+            data: DataLocation::Memory(data.into_bytes()),
+        })
+    }
+
+    fn is_test(resource: &PythonResource) -> bool {
+        match resource {
+            PythonResource::ModuleSource(m) => m.is_test,
+            PythonResource::Resource(r) => r.is_test,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn test_is_test_module_name() {
+        // Layouts of numpy, django and pandas.
+        assert!(is_test_module_name("numpy.core.tests.test_multiarray"));
+        assert!(is_test_module_name("numpy.testing"));
+        assert!(is_test_module_name("django.test.utils"));
+        assert!(is_test_module_name("pandas.tests"));
+        assert!(is_test_module_name("foo.test_utils"));
+
+        assert!(!is_test_module_name("numpy.core.multiarray"));
+        assert!(!is_test_module_name("_pytest.python"));
+        assert!(!is_test_module_name("testfixtures"));
+        assert!(!is_test_module_name("foo.latest"));
+        assert!(!is_test_module_name("foo.contest"));
+        assert!(!is_test_module_name("foo.testutils"));
+    }
+
+    #[test]
+    fn test_is_test_resource() {
+        assert!(is_test_resource("jsonschema.tests", "draft7/schema.json"));
+        assert!(is_test_resource("scipy.io", "tests/data/example.mat"));
+        assert!(is_test_resource("foo", "test_data/sample.json"));
+        assert!(is_test_resource("foo", "testdata/sample.json"));
+
+        // Django loads fixtures at run-time.
+        assert!(!is_test_resource(
+            "django.contrib.sites",
+            "fixtures/sites.json"
+        ));
+        assert!(!is_test_resource("certifi", "cacert.pem"));
+        assert!(!is_test_resource("foo", "tests"));
+        assert!(!is_test_resource("foo", "data/test_data.json"));
+    }
+
+    #[test]
+    fn test_parse_overrides() -> Result<()> {
+        let overrides = TestClassificationOverrides::parse(
+            "# Runtime code.\n\nHypothesis hypothesis.testing hypothesis.extra.tests\n",
+        )?;
+
+        assert!(overrides.is_runtime("hypothesis", "hypothesis.testing"));
+        assert!(overrides.is_runtime("hypothesis", "hypothesis.testing.strategies"));
+        assert!(overrides.is_runtime("Hypothesis", "hypothesis.extra.tests"));
+        assert!(!overrides.is_runtime("hypothesis", "hypothesis.testing_old"));
+        assert!(!overrides.is_runtime("hypothesis", "hypothesis.tests"));
+        assert!(!overrides.is_runtime("other", "hypothesis.testing"));
+
+        let err = TestClassificationOverrides::parse("hypothesis\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "line 1: expected packages after distribution hypothesis"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_classify_test_resources() -> Result<()> {
+        let mut resources = vec![
+            record(
+                "hypothesis",
+                &[
+                    "hypothesis/__init__.py",
+                    "hypothesis/testing/strategies.py",
+                    "hypothesis/tests/test_core.py",
+                ],
+            ),
+            record("numpy", &["numpy/__init__.py", "numpy/testing/__init__.py"]),
+            module("hypothesis"),
+            module("hypothesis.testing.strategies"),
+            module("hypothesis.tests.test_core"),
+            resource("hypothesis.testing", "data/examples.txt"),
+            module("numpy"),
+            module("numpy.testing"),
+            resource("numpy.core", "tests/data/umath.csv"),
+            // Not installed by a distribution, e.g. from read_package_root().
+            module("app.tests"),
+        ];
+
+        let mut overrides = TestClassificationOverrides::default();
+        overrides.add_runtime_package("hypothesis", "hypothesis.testing");
+        // Only applies to packages installed by the distribution.
+        overrides.add_runtime_package("other", "numpy.testing");
+
+        assert_eq!(classify_test_resources(&mut resources, &overrides)?, 4);
+
+        let tests = resources
+            .iter()
+            .filter(|r| is_test(r))
+            .map(|r| match r {
+                PythonResource::Resource(r) => r.symbolic_name(),
+                r => r.full_name(),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tests,
+            vec![
+                "hypothesis.tests.test_core",
+                "numpy.testing",
+                "numpy.core:tests/data/umath.csv",
+                "app.tests",
+            ]
+        );
+
+        // Classified resources aren't counted again.
+        assert_eq!(classify_test_resources(&mut resources, &overrides)?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Normalize line endings and byte order marks of text resources.
+
+Packages built on Windows sometimes ship module source and text data with
+CRLF line endings and UTF-8 byte order marks. This inflates size and makes
+builds from different platforms differ. Normalization strips a leading
+UTF-8 BOM and converts CRLF to LF. Lone CR characters are left alone.
+
+Module source is always text. Package resources are only treated as text
+if their file extension is known to be text. Data containing a NUL byte is
+assumed to be binary and is never modified.
+*/
+
+use {crate::resource::DataLocation, anyhow::Result};
+
+/// File extensions of package resources treated as text by default.
+pub const DEFAULT_TEXT_RESOURCE_EXTENSIONS: &[&str] = &[
+    "cfg", "csv", "html", "ini", "json", "md", "pyi", "rst", "toml", "txt", "xml", "yaml", "yml",
+];
+
+const UTF8_BOM: &[u8] = b"\xef\xbb\xbf";
+
+/// Settings for normalizing text resources.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TextNormalization {
+    /// File extensions of package resources to treat as text, without a leading `.`.
+    ///
+    /// Extensions are compared case insensitively.
+    pub resource_extensions: Vec<String>,
+}
+
+impl Default for TextNormalization {
+    fn default() -> Self {
+        Self {
+            resource_extensions: DEFAULT_TEXT_RESOURCE_EXTENSIONS
+                .iter()
+                .map(|x| x.to_string())
+                .collect(),
+        }
+    }
+}
+
+impl TextNormalization {
+    /// Whether a package resource is treated as text, by its relative path.
+    pub fn is_text_resource(&self, path: &str) -> bool {
+        let file_name = path.rsplit('/').next().unwrap_or(path);
+
+        match file_name.rfind('.') {
+            Some(pos) => {
+                let extension = &file_name[pos + 1..];
+                self.resource_extensions
+                    .iter()
+                    .any(|x| x.eq_ignore_ascii_case(extension))
+            }
+            None => false,
+        }
+    }
+}
+
+/// Whether data appears to be binary.
+pub fn is_binary(data: &[u8]) -> bool {
+    data.contains(&0)
+}
+
+/// Normalize text data.
+///
+/// Returns `None` if the data is binary or already normalized.
+pub fn normalize_text(data: &[u8]) -> Option<Vec<u8>> {
+    if is_binary(data) {
+        return None;
+    }
+
+    let body = if data.starts_with(UTF8_BOM) {
+        &data[UTF8_BOM.len()..]
+    } else {
+        data
+    };
+
+    let mut res = Vec::with_capacity(body.len());
+    let mut iter = body.iter().peekable();
+    while let Some(c) = iter.next() {
+        if *c == b'\r' && iter.peek() == Some(&&b'\n') {
+            continue;
+        }
+        res.push(*c);
+    }
+
+    if res.len() == data.len() {
+        None
+    } else {
+        Some(res)
+    }
+}
+
+/// Normalize text data at a location.
+///
+/// Returns the location of normalized data if normalization changed anything.
+pub fn normalize_location(location: &DataLocation) -> Result<Option<DataLocation>> {
+    Ok(normalize_text(&location.resolve()?).map(DataLocation::Memory))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize_text() {
+        assert_eq!(normalize_text(b"a\nb\n"), None);
+        assert_eq!(normalize_text(b"a\r\nb\r\n"), Some(b"a\nb\n".to_vec()));
+        assert_eq!(normalize_text(b"\xef\xbb\xbfa\n"), Some(b"a\n".to_vec()));
+        assert_eq!(
+            normalize_text(b"\xef\xbb\xbfa\r\nb\rc"),
+            Some(b"a\nb\rc".to_vec())
+        );
+        assert_eq!(normalize_text(b"a\r\n\x00"), None);
+        assert_eq!(normalize_text(b"\xff\xfea\x00\r\x00\n\x00"), None);
+    }
+
+    #[test]
+    fn test_is_text_resource() {
+        let normalization = TextNormalization::default();
+
+        assert!(normalization.is_text_resource("data.txt"));
+        assert!(normalization.is_text_resource("sub/dir/Config.JSON"));
+        assert!(!normalization.is_text_resource("image.png"));
+        assert!(!normalization.is_text_resource("txt"));
+        assert!(!normalization.is_text_resource("txt.d/blob"));
+
+        let normalization = TextNormalization {
+            resource_extensions: vec!["dat".to_string()],
+        };
+        assert!(normalization.is_text_resource("foo.dat"));
+        assert!(!normalization.is_text_resource("foo.txt"));
+    }
+}
@@ -0,0 +1,607 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Parse and compare Python package versions.
+
+This implements the version scheme defined by PEP 440, including its
+normalization rules: alternate spellings of pre-releases (`alpha`, `c`,
+`preview`, etc), implicit post-releases (`1.0-1`) and optional separators
+are all accepted. Versions compare the way pip compares them, so
+`1.0.dev0 < 1.0a1 < 1.0 < 1.0.post1` and `1.0 == 1.0.0`.
+
+Version specifiers like `>=1.0,<2` select ranges of versions.
+*/
+
+use {
+    anyhow::{anyhow, Result},
+    std::cmp::Ordering,
+};
+
+/// Kind of a pre-release.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum PreReleaseKind {
+    Alpha,
+    Beta,
+    ReleaseCandidate,
+}
+
+impl std::fmt::Display for PreReleaseKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            PreReleaseKind::Alpha => "a",
+            PreReleaseKind::Beta => "b",
+            PreReleaseKind::ReleaseCandidate => "rc",
+        })
+    }
+}
+
+/// A segment of a local version label.
+///
+/// Numeric segments sort after alphanumeric ones.
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum LocalSegment {
+    Alphanumeric(String),
+    Numeric(u64),
+}
+
+impl std::fmt::Display for LocalSegment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LocalSegment::Alphanumeric(s) => f.write_str(s),
+            LocalSegment::Numeric(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+/// A PEP 440 version.
+#[derive(Clone, Debug)]
+pub struct PythonVersion {
+    pub epoch: u64,
+    pub release: Vec<u64>,
+    pub pre: Option<(PreReleaseKind, u64)>,
+    pub post: Option<u64>,
+    pub dev: Option<u64>,
+    /// Local version label. Empty if not present.
+    pub local: Vec<LocalSegment>,
+}
+
+/// Consumes a version string from front to back.
+struct Cursor<'a> {
+    s: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn is_empty(&self) -> bool {
+        self.s.is_empty()
+    }
+
+    fn number(&mut self) -> Option<u64> {
+        let digits = self.s.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+
+        let value = self.s[0..digits].parse::<u64>().ok()?;
+        self.s = &self.s[digits..];
+
+        Some(value)
+    }
+
+    fn separator(&mut self) -> bool {
+        if self.s.starts_with('.') || self.s.starts_with('-') || self.s.starts_with('_') {
+            self.s = &self.s[1..];
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Consume the first of `words` the input starts with, if any.
+    fn word<'w>(&mut self, words: &[&'w str]) -> Option<&'w str> {
+        let word = *words.iter().find(|w| self.s.starts_with(**w))?;
+        self.s = &self.s[word.len()..];
+
+        Some(word)
+    }
+
+    /// Consume an optionally separated labeled number, like `.post1` or `rc-2`.
+    ///
+    /// The number defaults to 0 if missing. Nothing is consumed if no label matches.
+    fn labeled_number<'w>(&mut self, labels: &[&'w str]) -> Option<(&'w str, u64)> {
+        let saved = self.s;
+        self.separator();
+
+        match self.word(labels) {
+            Some(label) => {
+                let before_separator = self.s;
+                self.separator();
+
+                let number = match self.number() {
+                    Some(number) => number,
+                    None => {
+                        self.s = before_separator;
+                        0
+                    }
+                };
+
+                Some((label, number))
+            }
+            None => {
+                self.s = saved;
+                None
+            }
+        }
+    }
+}
+
+impl PythonVersion {
+    /// Parse a version string.
+    pub fn parse(version: &str) -> Result<Self> {
+        let normalized = version.trim().to_ascii_lowercase();
+        let invalid = || anyhow!("{} is not a valid PEP 440 version", version);
+
+        let (public, local) = match normalized.find('+') {
+            Some(pos) => (&normalized[0..pos], Some(&normalized[pos + 1..])),
+            None => (normalized.as_str(), None),
+        };
+
+        let public = if public.starts_with('v') {
+            &public[1..]
+        } else {
+            public
+        };
+
+        let (epoch, public) = match public.find('!') {
+            Some(pos) => (
+                public[0..pos].parse::<u64>().map_err(|_| invalid())?,
+                &public[pos + 1..],
+            ),
+            None => (0, public),
+        };
+
+        let mut cursor = Cursor { s: public };
+
+        let mut release = vec![cursor.number().ok_or_else(invalid)?];
+        while cursor.s.starts_with('.') {
+            let saved = cursor.s;
+            cursor.s = &cursor.s[1..];
+
+            match cursor.number() {
+                Some(number) => release.push(number),
+                None => {
+                    cursor.s = saved;
+                    break;
+                }
+            }
+        }
+
+        let pre = cursor
+            .labeled_number(&["alpha", "beta", "preview", "pre", "rc", "a", "b", "c"])
+            .map(|(label, number)| {
+                let kind = match label {
+                    "alpha" | "a" => PreReleaseKind::Alpha,
+                    "beta" | "b" => PreReleaseKind::Beta,
+                    _ => PreReleaseKind::ReleaseCandidate,
+                };
+
+                (kind, number)
+            });
+
+        let post = match cursor.labeled_number(&["post", "rev", "r"]) {
+            Some((_, number)) => Some(number),
+            None if cursor.s.starts_with('-')
+                && cursor.s[1..].starts_with(|c: char| c.is_ascii_digit()) =>
+            {
+                cursor.s = &cursor.s[1..];
+                Some(cursor.number().ok_or_else(invalid)?)
+            }
+            None => None,
+        };
+
+        let dev = cursor.labeled_number(&["dev"]).map(|(_, number)| number);
+
+        if !cursor.is_empty() {
+            return Err(invalid());
+        }
+
+        let local = match local {
+            Some(local) => local
+                .split(|c| c == '.' || c == '-' || c == '_')
+                .map(|segment| {
+                    if segment.is_empty() || !segment.chars().all(|c| c.is_ascii_alphanumeric()) {
+                        Err(invalid())
+                    } else if segment.chars().all(|c| c.is_ascii_digit()) {
+                        segment
+                            .parse::<u64>()
+                            .map(LocalSegment::Numeric)
+                            .map_err(|_| invalid())
+                    } else {
+                        Ok(LocalSegment::Alphanumeric(segment.to_string()))
+                    }
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => Vec::new(),
+        };
+
+        Ok(Self {
+            epoch,
+            release,
+            pre,
+            post,
+            dev,
+            local,
+        })
+    }
+
+    /// Whether this is a pre-release or development release.
+    pub fn is_prerelease(&self) -> bool {
+        self.pre.is_some() || self.dev.is_some()
+    }
+
+    /// Whether this is a post-release.
+    pub fn is_postrelease(&self) -> bool {
+        self.post.is_some()
+    }
+
+    /// Obtain the public version without pre, post and development releases.
+    fn base_version(&self) -> Self {
+        Self {
+            epoch: self.epoch,
+            release: self.release.clone(),
+            pre: None,
+            post: None,
+            dev: None,
+            local: Vec::new(),
+        }
+    }
+
+    /// Obtain the key versions are ordered by.
+    #[allow(clippy::type_complexity)]
+    fn sort_key(
+        &self,
+    ) -> (
+        u64,
+        &[u64],
+        (i8, u8, u64),
+        (u8, u64),
+        (u8, u64),
+        &[LocalSegment],
+    ) {
+        let mut release = self.release.as_slice();
+        while release.len() > 1 && release[release.len() - 1] == 0 {
+            release = &release[0..release.len() - 1];
+        }
+
+        // A development release of a final release sorts before its pre-releases.
+        let pre = match (self.pre, self.post, self.dev) {
+            (Some((kind, number)), _, _) => (0, kind as u8, number),
+            (None, None, Some(_)) => (-1, 0, 0),
+            (None, _, _) => (1, 0, 0),
+        };
+
+        let post = match self.post {
+            Some(number) => (1, number),
+            None => (0, 0),
+        };
+
+        let dev = match self.dev {
+            Some(number) => (0, number),
+            None => (1, 0),
+        };
+
+        (self.epoch, release, pre, post, dev, &self.local)
+    }
+}
+
+impl std::fmt::Display for PythonVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.epoch != 0 {
+            write!(f, "{}!", self.epoch)?;
+        }
+
+        let release = self
+            .release
+            .iter()
+            .map(|n| n.to_string())
+            .collect::<Vec<_>>();
+        f.write_str(&release.join("."))?;
+
+        if let Some((kind, number)) = self.pre {
+            write!(f, "{}{}", kind, number)?;
+        }
+        if let Some(number) = self.post {
+            write!(f, ".post{}", number)?;
+        }
+        if let Some(number) = self.dev {
+            write!(f, ".dev{}", number)?;
+        }
+
+        if !self.local.is_empty() {
+            let local = self.local.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+            write!(f, "+{}", local.join("."))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl PartialEq for PythonVersion {
+    fn eq(&self, other: &Self) -> bool {
+        self.sort_key() == other.sort_key()
+    }
+}
+
+impl Eq for PythonVersion {}
+
+impl PartialOrd for PythonVersion {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for PythonVersion {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.sort_key().cmp(&other.sort_key())
+    }
+}
+
+/// Operator of a version specifier.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SpecifierOperator {
+    Equal,
+    NotEqual,
+    LessThan,
+    LessThanEqual,
+    GreaterThan,
+    GreaterThanEqual,
+    Compatible,
+}
+
+/// A single version specifier, like `>=1.0` or `==1.4.*`.
+#[derive(Clone, Debug)]
+pub struct VersionSpecifier {
+    pub operator: SpecifierOperator,
+    pub version: PythonVersion,
+    /// Whether only a release prefix is compared, like in `==1.4.*`.
+    pub wildcard: bool,
+}
+
+impl VersionSpecifier {
+    /// Parse a specifier.
+    pub fn parse(specifier: &str) -> Result<Self> {
+        let specifier = specifier.trim();
+        let invalid = || anyhow!("{} is not a valid version specifier", specifier);
+
+        let operators = [
+            ("~=", SpecifierOperator::Compatible),
+            ("==", SpecifierOperator::Equal),
+            ("!=", SpecifierOperator::NotEqual),
+            ("<=", SpecifierOperator::LessThanEqual),
+            (">=", SpecifierOperator::GreaterThanEqual),
+            ("<", SpecifierOperator::LessThan),
+            (">", SpecifierOperator::GreaterThan),
+        ];
+
+        let (prefix, operator) = operators
+            .iter()
+            .find(|(prefix, _)| specifier.starts_with(prefix))
+            .ok_or_else(invalid)?;
+        let version = specifier[prefix.len()..].trim();
+
+        let wildcard = version.ends_with(".*");
+        let version = if wildcard {
+            &version[0..version.len() - 2]
+        } else {
+            version
+        };
+
+        if wildcard
+            && *operator != SpecifierOperator::Equal
+            && *operator != SpecifierOperator::NotEqual
+        {
+            return Err(invalid());
+        }
+
+        let version = PythonVersion::parse(version)?;
+
+        if *operator == SpecifierOperator::Compatible && version.release.len() < 2 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            operator: *operator,
+            version,
+            wildcard,
+        })
+    }
+
+    /// Whether the release of `version` starts with `prefix`.
+    fn has_release_prefix(version: &PythonVersion, epoch: u64, prefix: &[u64]) -> bool {
+        version.epoch == epoch
+            && prefix
+                .iter()
+                .enumerate()
+                .all(|(i, n)| version.release.get(i).copied().unwrap_or(0) == *n)
+    }
+
+    /// Whether a version matches this specifier.
+    pub fn contains(&self, version: &PythonVersion) -> bool {
+        // Local labels of candidates are ignored unless the specifier has one.
+        let mut candidate = version.clone();
+        if self.version.local.is_empty() {
+            candidate.local.clear();
+        }
+
+        match self.operator {
+            SpecifierOperator::Equal | SpecifierOperator::NotEqual => {
+                let equal = if self.wildcard {
+                    Self::has_release_prefix(&candidate, self.version.epoch, &self.version.release)
+                } else {
+                    candidate == self.version
+                };
+
+                equal == (self.operator == SpecifierOperator::Equal)
+            }
+            // Exclusive comparisons don't match pre-releases of the version
+            // they exclude, so `<2` doesn't match `2.0a1`.
+            SpecifierOperator::LessThan => {
+                candidate < self.version
+                    && (self.version.is_prerelease()
+                        || !candidate.is_prerelease()
+                        || candidate.base_version() != self.version.base_version())
+            }
+            SpecifierOperator::LessThanEqual => candidate <= self.version,
+            // Nor post-releases, so `>1.0` doesn't match `1.0.post1`.
+            SpecifierOperator::GreaterThan => {
+                candidate > self.version
+                    && (self.version.is_postrelease()
+                        || !candidate.is_postrelease()
+                        || candidate.base_version() != self.version.base_version())
+            }
+            SpecifierOperator::GreaterThanEqual => candidate >= self.version,
+            SpecifierOperator::Compatible => {
+                let release = &self.version.release;
+
+                candidate >= self.version
+                    && Self::has_release_prefix(
+                        &candidate,
+                        self.version.epoch,
+                        &release[0..release.len() - 1],
+                    )
+            }
+        }
+    }
+}
+
+/// A set of comma separated version specifiers, like `>=1.0,<2`.
+///
+/// A version matches if it matches every specifier. An empty set matches
+/// every version.
+#[derive(Clone, Debug, Default)]
+pub struct VersionSpecifiers(pub Vec<VersionSpecifier>);
+
+impl VersionSpecifiers {
+    /// Parse comma separated specifiers.
+    pub fn parse(specifiers: &str) -> Result<Self> {
+        Ok(Self(
+            specifiers
+                .split(',')
+                .filter(|s| !s.trim().is_empty())
+                .map(VersionSpecifier::parse)
+                .collect::<Result<Vec<_>>>()?,
+        ))
+    }
+
+    /// Whether a version matches all specifiers.
+    pub fn contains(&self, version: &PythonVersion) -> bool {
+        self.0.iter().all(|specifier| specifier.contains(version))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v(s: &str) -> PythonVersion {
+        PythonVersion::parse(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_normalization() {
+        assert_eq!(v("1.0").to_string(), "1.0");
+        assert_eq!(v("v1.0").to_string(), "1.0");
+        assert_eq!(v("1!2.0").to_string(), "1!2.0");
+        assert_eq!(v("1.0ALPHA1").to_string(), "1.0a1");
+        assert_eq!(v("1.0-beta.2").to_string(), "1.0b2");
+        assert_eq!(v("1.0c3").to_string(), "1.0rc3");
+        assert_eq!(v("1.0preview").to_string(), "1.0rc0");
+        assert_eq!(v("1.0-1").to_string(), "1.0.post1");
+        assert_eq!(v("1.0rev2").to_string(), "1.0.post2");
+        assert_eq!(v("1.0.post").to_string(), "1.0.post0");
+        assert_eq!(v("1.0-dev3").to_string(), "1.0.dev3");
+        assert_eq!(v("1.0a1.post2.dev3").to_string(), "1.0a1.post2.dev3");
+        assert_eq!(v("1.0+Ubuntu-1").to_string(), "1.0+ubuntu.1");
+
+        assert!(PythonVersion::parse("").is_err());
+        assert!(PythonVersion::parse("foo").is_err());
+        assert!(PythonVersion::parse("1.0.").is_err());
+        assert!(PythonVersion::parse("1.0+").is_err());
+        assert!(PythonVersion::parse("1.0junk").is_err());
+    }
+
+    #[test]
+    fn test_ordering() {
+        let ordered = [
+            "1.0.dev0",
+            "1.0a1.dev0",
+            "1.0a1",
+            "1.0a1.post1.dev0",
+            "1.0a1.post1",
+            "1.0b1",
+            "1.0rc1",
+            "1.0",
+            "1.0+local",
+            "1.0+1",
+            "1.0.post1.dev0",
+            "1.0.post1",
+            "1.0.1",
+            "1.1.dev0",
+            "1.10",
+            "1!0.1",
+        ];
+
+        for pair in ordered.windows(2) {
+            assert!(v(pair[0]) < v(pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+
+        assert_eq!(v("1.0"), v("1.0.0"));
+        assert_eq!(v("1.0a1"), v("1.0alpha1"));
+        assert!(v("1.0rc1").is_prerelease());
+        assert!(v("1.0.dev1").is_prerelease());
+        assert!(!v("1.0.post1").is_prerelease());
+        assert!(v("1.0.post1").is_postrelease());
+    }
+
+    #[test]
+    fn test_specifiers() -> Result<()> {
+        let matches = |specifiers: &str, version: &str| -> Result<bool> {
+            Ok(VersionSpecifiers::parse(specifiers)?.contains(&v(version)))
+        };
+
+        assert!(matches("", "1.0")?);
+        assert!(matches(">=1.0,<2", "1.5")?);
+        assert!(!matches(">=1.0,<2", "2.0")?);
+        assert!(!matches(">=1.0, <2", "0.9")?);
+        assert!(matches("==1.0", "1.0.0")?);
+        assert!(matches("==1.0", "1.0+local")?);
+        assert!(!matches("==1.0+local", "1.0")?);
+        assert!(matches("!=1.0", "1.0.1")?);
+        assert!(matches("==1.4.*", "1.4.2")?);
+        assert!(matches("==1.4.*", "1.4")?);
+        assert!(!matches("==1.4.*", "1.40")?);
+        assert!(matches("!=1.4.*", "1.5")?);
+        assert!(matches("~=1.4.2", "1.4.5")?);
+        assert!(!matches("~=1.4.2", "1.5.0")?);
+        assert!(matches("~=1.4", "1.9")?);
+        assert!(!matches("~=1.4", "2.0")?);
+        assert!(matches("<=1.0", "1.0")?);
+        assert!(!matches(">1.0", "1.0.post1")?);
+        assert!(matches(">1.0.post1", "1.0.post2")?);
+        assert!(matches(">1.0", "1.1.post1")?);
+        assert!(matches(">=1.0", "1.0.post1")?);
+        assert!(!matches("<2", "2.0a1")?);
+        assert!(!matches("<2", "2.0.dev0")?);
+        assert!(matches("<2", "1.9a1")?);
+        assert!(matches("<2.0b1", "2.0a1")?);
+        assert!(matches("<=2", "2.0a1")?);
+
+        assert!(VersionSpecifiers::parse("1.0").is_err());
+        assert!(VersionSpecifiers::parse(">=1.*").is_err());
+        assert!(VersionSpecifiers::parse("~=1").is_err());
+        assert!(VersionSpecifiers::parse("==foo").is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,429 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*!
+Writing wheels from collected resources.
+
+A wheel is a zip archive of files to install into `site-packages` plus a
+`.dist-info` directory describing the distribution. See PEP 427. Wheels
+are written from the resources of a package so an application embedded in
+a binary can also be published for `pip install`.
+*/
+
+use {
+    crate::package_metadata::PythonPackageMetadata,
+    crate::resource::DataLocation,
+    anyhow::{anyhow, Context, Result},
+    sha2::{Digest, Sha256},
+    std::collections::BTreeMap,
+    std::io::Write,
+    std::path::{Path, PathBuf},
+};
+
+/// Files of a `.dist-info` directory describing an installation rather than a distribution.
+///
+/// They are dropped from existing metadata. `RECORD` and `WHEEL` are
+/// generated instead.
+const INSTALLATION_FILES: &[&str] = &[
+    "INSTALLER",
+    "RECORD",
+    "REQUESTED",
+    "WHEEL",
+    "direct_url.json",
+];
+
+/// Compatibility tags of a wheel, e.g. `py3-none-any`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WheelTags {
+    /// Python tag, e.g. `py3` or `cp38`.
+    pub python: String,
+
+    /// ABI tag, e.g. `none` or `cp38`.
+    pub abi: String,
+
+    /// Platform tag, e.g. `any` or `manylinux2014_x86_64`.
+    pub platform: String,
+}
+
+impl Default for WheelTags {
+    fn default() -> Self {
+        Self {
+            python: "py3".to_string(),
+            abi: "none".to_string(),
+            platform: "any".to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for WheelTags {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}-{}", self.python, self.abi, self.platform)
+    }
+}
+
+impl WheelTags {
+    /// Whether the wheel holds pure Python code.
+    pub fn is_pure(&self) -> bool {
+        self.abi == "none" && self.platform == "any"
+    }
+}
+
+/// Files to put in a wheel.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WheelContents {
+    /// Files installed into `site-packages`, by path relative to it.
+    ///
+    /// Paths use `/` as a directory separator.
+    pub files: BTreeMap<String, DataLocation>,
+
+    /// Files of an existing `.dist-info` directory, by file name.
+    ///
+    /// Empty if the distribution has no metadata, in which case `METADATA`
+    /// is generated.
+    pub dist_info: BTreeMap<String, DataLocation>,
+}
+
+impl WheelContents {
+    /// Obtain the distribution name recorded in existing `METADATA`, if any.
+    pub fn distribution_name(&self) -> Result<Option<String>> {
+        match self.dist_info.get("METADATA") {
+            Some(location) => Ok(PythonPackageMetadata::from_metadata(&location.resolve()?)
+                .context("parsing METADATA")?
+                .name()
+                .map(|name| name.to_string())),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Escape a distribution name for wheel filenames and `.dist-info` directories.
+fn escape_name(name: &str) -> String {
+    let mut res = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        if c == '-' || c == '_' || c == '.' {
+            if !res.ends_with('_') {
+                res.push('_');
+            }
+        } else {
+            res.push(c.to_ascii_lowercase());
+        }
+    }
+
+    res
+}
+
+/// Encode data as URL-safe base64 without padding, as used by RECORD hashes.
+fn urlsafe_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut res = String::with_capacity((data.len() * 4 + 2) / 3);
+
+    for chunk in data.chunks(3) {
+        let value = chunk
+            .iter()
+            .chain(std::iter::repeat(&0))
+            .take(3)
+            .fold(0u32, |value, byte| (value << 8) | u32::from(*byte));
+
+        for i in 0..=chunk.len() {
+            res.push(ALPHABET[((value >> (18 - 6 * i)) & 0x3f) as usize] as char);
+        }
+    }
+
+    res
+}
+
+/// Quote a RECORD field if necessary.
+fn record_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') || value.contains('\n') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Write a wheel of a distribution to a directory.
+///
+/// `METADATA` from `contents.dist_info` is used verbatim and must declare
+/// `version`. Without it, minimal metadata naming the distribution and
+/// version is generated. `WHEEL` and `RECORD` are always generated. Files
+/// are written in a stable order with fixed timestamps, so equal contents
+/// produce identical wheels.
+///
+/// Returns the path of the written wheel.
+pub fn write_wheel(
+    distribution: &str,
+    version: &str,
+    contents: &WheelContents,
+    tags: &WheelTags,
+    dest: &Path,
+) -> Result<PathBuf> {
+    let escaped_version = version.replace('-', "_");
+    let dist_info = format!(
+        "{}-{}.dist-info",
+        escape_name(distribution),
+        escaped_version
+    );
+
+    let metadata = match contents.dist_info.get("METADATA") {
+        Some(location) => {
+            let data = location.resolve()?;
+            let found = PythonPackageMetadata::from_metadata(&data)
+                .context("parsing METADATA")?
+                .version()
+                .map(|version| version.to_string());
+
+            if found.as_deref() != Some(version) {
+                return Err(anyhow!(
+                    "METADATA of {} declares version {}; expected {}",
+                    distribution,
+                    found.unwrap_or_else(|| "<none>".to_string()),
+                    version
+                ));
+            }
+
+            data
+        }
+        None => format!(
+            "Metadata-Version: 2.1\nName: {}\nVersion: {}\n",
+            distribution, version
+        )
+        .into_bytes(),
+    };
+
+    let mut entries = Vec::new();
+
+    for (path, location) in &contents.files {
+        if path.split('/').next() == Some(dist_info.as_str()) {
+            return Err(anyhow!("{} conflicts with the wheel metadata", path));
+        }
+
+        entries.push((
+            path.clone(),
+            location
+                .resolve()
+                .with_context(|| format!("reading {}", path))?,
+        ));
+    }
+
+    for (name, location) in &contents.dist_info {
+        if name == "METADATA" || INSTALLATION_FILES.contains(&name.as_str()) {
+            continue;
+        }
+
+        entries.push((
+            format!("{}/{}", dist_info, name),
+            location
+                .resolve()
+                .with_context(|| format!("reading {}", name))?,
+        ));
+    }
+
+    entries.push((format!("{}/METADATA", dist_info), metadata));
+    entries.push((
+        format!("{}/WHEEL", dist_info),
+        format!(
+            "Wheel-Version: 1.0\nGenerator: python-packaging\nRoot-Is-Purelib: {}\nTag: {}\n",
+            tags.is_pure(),
+            tags
+        )
+        .into_bytes(),
+    ));
+
+    let record_path = format!("{}/RECORD", dist_info);
+    let mut record = String::new();
+    for (path, data) in &entries {
+        record.push_str(&format!(
+            "{},sha256={},{}\n",
+            record_field(path),
+            urlsafe_base64(&Sha256::digest(data)),
+            data.len()
+        ));
+    }
+    record.push_str(&format!("{},,\n", record_field(&record_path)));
+    entries.push((record_path, record.into_bytes()));
+
+    let path = dest.join(format!(
+        "{}-{}-{}.whl",
+        escape_name(distribution),
+        escaped_version,
+        tags
+    ));
+    let fh =
+        std::fs::File::create(&path).with_context(|| format!("creating {}", path.display()))?;
+    let mut zip = zip::ZipWriter::new(fh);
+    let options = zip::write::FileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(0o644);
+
+    for (name, data) in &entries {
+        zip.start_file(name.as_str(), options)?;
+        zip.write_all(data)?;
+    }
+
+    zip.finish()
+        .with_context(|| format!("writing {}", path.display()))?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, crate::package_metadata::PythonPackageRecord, std::io::Read};
+
+    fn read_wheel(path: &Path) -> Result<BTreeMap<String, Vec<u8>>> {
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(path)?)?;
+        let mut res = BTreeMap::new();
+
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            res.insert(file.name().to_string(), data);
+        }
+
+        Ok(res)
+    }
+
+    #[test]
+    fn test_urlsafe_base64() {
+        assert_eq!(urlsafe_base64(b""), "");
+        assert_eq!(urlsafe_base64(b"f"), "Zg");
+        assert_eq!(urlsafe_base64(b"fo"), "Zm8");
+        assert_eq!(urlsafe_base64(b"foo"), "Zm9v");
+        assert_eq!(urlsafe_base64(&[0xfb, 0xff]), "-_8");
+        assert_eq!(
+            urlsafe_base64(&Sha256::digest(b"")),
+            "47DEQpj8HBSa-_TImW-5JCeuQeRkm5NMpJWZG3hSuFU"
+        );
+    }
+
+    #[test]
+    fn test_write_wheel() -> Result<()> {
+        let td = tempdir::TempDir::new("python-packaging-test")?;
+
+        let mut contents = WheelContents::default();
+        contents.files.insert(
+            "foo/__init__.py".to_string(),
+            DataLocation::Memory(b"import sys\n".to_vec()),
+        );
+        contents
+            .files
+            .insert("foo/data,1.txt".to_string(), DataLocation::Memory(vec![]));
+
+        let path = write_wheel(
+            "Foo-Bar",
+            "1.0",
+            &contents,
+            &WheelTags::default(),
+            td.path(),
+        )?;
+        assert_eq!(path, td.path().join("foo_bar-1.0-py3-none-any.whl"));
+
+        let files = read_wheel(&path)?;
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            vec![
+                "foo/__init__.py",
+                "foo/data,1.txt",
+                "foo_bar-1.0.dist-info/METADATA",
+                "foo_bar-1.0.dist-info/RECORD",
+                "foo_bar-1.0.dist-info/WHEEL",
+            ]
+        );
+        assert_eq!(
+            files["foo_bar-1.0.dist-info/METADATA"],
+            b"Metadata-Version: 2.1\nName: Foo-Bar\nVersion: 1.0\n".to_vec()
+        );
+        assert!(
+            String::from_utf8_lossy(&files["foo_bar-1.0.dist-info/WHEEL"])
+                .contains("Root-Is-Purelib: true\nTag: py3-none-any\n")
+        );
+
+        // RECORD lists every file with its hash and size.
+        let record = PythonPackageRecord::from_record(&files["foo_bar-1.0.dist-info/RECORD"])?;
+        assert_eq!(record.entries.len(), files.len());
+        for (name, data) in &files {
+            let entry = record.find_entry(name).unwrap();
+            if name.ends_with("/RECORD") {
+                assert!(entry.hash.is_none());
+            } else {
+                assert_eq!(
+                    entry.hash.as_ref().unwrap().digest,
+                    urlsafe_base64(&Sha256::digest(data))
+                );
+                assert_eq!(entry.size, Some(data.len() as u64));
+            }
+        }
+
+        // Writing again produces the same wheel.
+        let data = std::fs::read(&path)?;
+        write_wheel(
+            "Foo-Bar",
+            "1.0",
+            &contents,
+            &WheelTags::default(),
+            td.path(),
+        )?;
+        assert_eq!(std::fs::read(&path)?, data);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_wheel_existing_metadata() -> Result<()> {
+        let td = tempdir::TempDir::new("python-packaging-test")?;
+
+        let mut contents = WheelContents::default();
+        contents.files.insert(
+            "foo.py".to_string(),
+            DataLocation::Memory(b"import sys\n".to_vec()),
+        );
+        for (name, data) in &[
+            (
+                "METADATA",
+                &b"Metadata-Version: 2.1\nName: foo\nVersion: 1.0\n"[..],
+            ),
+            ("entry_points.txt", b"[console_scripts]\nfoo = foo:main\n"),
+            ("INSTALLER", b"pip\n"),
+            ("RECORD", b"foo.py,,\n"),
+        ] {
+            contents
+                .dist_info
+                .insert(name.to_string(), DataLocation::Memory(data.to_vec()));
+        }
+        assert_eq!(contents.distribution_name()?, Some("foo".to_string()));
+
+        let tags = WheelTags {
+            python: "cp38".to_string(),
+            abi: "cp38".to_string(),
+            platform: "linux_x86_64".to_string(),
+        };
+        let path = write_wheel("foo", "1.0", &contents, &tags, td.path())?;
+        assert_eq!(path, td.path().join("foo-1.0-cp38-cp38-linux_x86_64.whl"));
+
+        let files = read_wheel(&path)?;
+        assert_eq!(
+            files.keys().collect::<Vec<_>>(),
+            vec![
+                "foo-1.0.dist-info/METADATA",
+                "foo-1.0.dist-info/RECORD",
+                "foo-1.0.dist-info/WHEEL",
+                "foo-1.0.dist-info/entry_points.txt",
+                "foo.py",
+            ]
+        );
+        assert!(String::from_utf8_lossy(&files["foo-1.0.dist-info/WHEEL"])
+            .contains("Root-Is-Purelib: false\n"));
+
+        let err = write_wheel("foo", "2.0", &contents, &tags, td.path()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "METADATA of foo declares version 1.0; expected 2.0"
+        );
+
+        Ok(())
+    }
+}
@@ -12,6 +12,66 @@ use {
 /// Header value for version 1 of resources payload.
 pub const HEADER_V1: &[u8] = b"pyembed\x01";
 
+/// Header value for version 2 of resources payload.
+///
+/// Version 2 adds shared, external and compressed payloads, extracted shared
+/// libraries and the recovery resource set to version 1.
+pub const HEADER_V2: &[u8] = b"pyembed\x02";
+
+/// Header value for version 3 of resources payload.
+///
+/// Version 3 adds a lookup table to version 2.
+pub const HEADER_V3: &[u8] = b"pyembed\x03";
+
+/// Length in bytes of an entry in the lookup table of version 3.
+pub const LOOKUP_TABLE_ENTRY_LENGTH: usize = 16;
+
+/// Name of the resource flagging that the runtime should record imports.
+///
+/// The resource carries no data. Its presence in the index enables import
+/// telemetry, which is written to the path configured at run-time. Without
+/// it, nothing is recorded.
+pub const IMPORT_TELEMETRY_RESOURCE_NAME: &str = "__pyoxidizer_import_telemetry__";
+
+/// First line of import telemetry dumps.
+///
+/// Each following line is either `module\t<count>\t<name>`, recording how
+/// often a module was imported, or `resource\t<count>\t<package>\t<name>`,
+/// recording how often a package resource was read.
+pub const IMPORT_TELEMETRY_HEADER: &str = "# pyoxidizer import telemetry v1";
+
+/// How the resources index of packed resources is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum IndexEncoding {
+    /// Resources are found by reading the index sequentially.
+    ///
+    /// This is version 1 of the format, or version 2 if resources use fields
+    /// version 1 lacks.
+    #[default]
+    Sequential,
+
+    /// The index is preceded by a sorted lookup table of resource names.
+    ///
+    /// Resources can be found by binary search without reading the index.
+    /// This is version 3 of the format.
+    LookupTable,
+}
+
+impl TryFrom<&str> for IndexEncoding {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "sequential" => Ok(IndexEncoding::Sequential),
+            "lookup-table" => Ok(IndexEncoding::LookupTable),
+            t => Err(format!(
+                "{} is not a valid packed resources index encoding",
+                t
+            )),
+        }
+    }
+}
+
 /// Defines the type of a resource.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum ResourceFlavor {
@@ -58,6 +118,27 @@ impl TryFrom<u8> for ResourceFlavor {
     }
 }
 
+/// A named set of resources within packed resources data.
+///
+/// Packed resources hold the main resource set and optionally a recovery
+/// set, which an application loads instead when its main resources can't
+/// start it.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub enum ResourceSet {
+    #[default]
+    Main,
+    Recovery,
+}
+
+impl std::fmt::Display for ResourceSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ResourceSet::Main => "main",
+            ResourceSet::Recovery => "recovery",
+        })
+    }
+}
+
 /// Defines interior padding mechanism between entries in blob sections.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum BlobInteriorPadding {
@@ -147,6 +228,12 @@ pub enum ResourceField {
     RelativeFilesystemExtensionModuleSharedLibrary = 0x13,
     RelativeFilesystemPackageResources = 0x14,
     RelativeFilesystemDistributionResource = 0x15,
+    SharedPayload = 0x16,
+    ExtractSharedLibrary = 0x17,
+    BlobOffset = 0x18,
+    RecoverySet = 0x19,
+    ExternalPayload = 0x1a,
+    CompressedPayload = 0x1b,
 }
 
 impl Into<u8> for ResourceField {
@@ -174,6 +261,12 @@ impl Into<u8> for ResourceField {
             ResourceField::RelativeFilesystemExtensionModuleSharedLibrary => 0x13,
             ResourceField::RelativeFilesystemPackageResources => 0x14,
             ResourceField::RelativeFilesystemDistributionResource => 0x15,
+            ResourceField::SharedPayload => 0x16,
+            ResourceField::ExtractSharedLibrary => 0x17,
+            ResourceField::BlobOffset => 0x18,
+            ResourceField::RecoverySet => 0x19,
+            ResourceField::ExternalPayload => 0x1a,
+            ResourceField::CompressedPayload => 0x1b,
             ResourceField::EndOfEntry => 0xff,
         }
     }
@@ -206,12 +299,113 @@ impl TryFrom<u8> for ResourceField {
             0x13 => Ok(ResourceField::RelativeFilesystemExtensionModuleSharedLibrary),
             0x14 => Ok(ResourceField::RelativeFilesystemPackageResources),
             0x15 => Ok(ResourceField::RelativeFilesystemDistributionResource),
+            0x16 => Ok(ResourceField::SharedPayload),
+            0x17 => Ok(ResourceField::ExtractSharedLibrary),
+            0x18 => Ok(ResourceField::BlobOffset),
+            0x19 => Ok(ResourceField::RecoverySet),
+            0x1a => Ok(ResourceField::ExternalPayload),
+            0x1b => Ok(ResourceField::CompressedPayload),
             0xff => Ok(ResourceField::EndOfEntry),
             _ => Err("invalid field type"),
         }
     }
 }
 
+impl ResourceField {
+    /// The first version of the packed resources format having the field.
+    pub fn format_version(self) -> u8 {
+        match self {
+            ResourceField::SharedPayload
+            | ResourceField::ExtractSharedLibrary
+            | ResourceField::RecoverySet
+            | ResourceField::ExternalPayload
+            | ResourceField::CompressedPayload => 2,
+            ResourceField::BlobOffset => 3,
+            _ => 1,
+        }
+    }
+
+    /// Whether the field holds a single in-memory payload.
+    ///
+    /// Payloads of these fields can be shared between resources and stored
+    /// outside of packed resources data.
+    pub fn is_single_payload(self) -> bool {
+        matches!(
+            self,
+            ResourceField::InMemorySource
+                | ResourceField::InMemoryBytecode
+                | ResourceField::InMemoryBytecodeOpt1
+                | ResourceField::InMemoryBytecodeOpt2
+                | ResourceField::InMemoryExtensionModuleSharedLibrary
+                | ResourceField::InMemorySharedLibrary
+        )
+    }
+}
+
+/// Length in bytes of the digest of an external payload.
+pub const EXTERNAL_PAYLOAD_DIGEST_LENGTH: usize = 32;
+
+/// A resource payload stored outside of packed resources data.
+///
+/// The payload is identified by the SHA-256 digest of its content. `location`
+/// is a logical location interpreted by whatever stored the payload, e.g. a
+/// file name in a directory or a key in an object store. Runtimes are
+/// expected to fetch the payload and verify its digest before using it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExternalPayload<'a> {
+    /// The resource field the payload is data for.
+    pub field: ResourceField,
+
+    /// Length in bytes of the payload.
+    pub length: u64,
+
+    /// SHA-256 digest of the payload.
+    pub digest: [u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH],
+
+    /// Logical location of the payload.
+    pub location: Cow<'a, str>,
+}
+
+impl<'a> ExternalPayload<'a> {
+    pub fn to_owned(&self) -> ExternalPayload<'static> {
+        ExternalPayload {
+            field: self.field,
+            length: self.length,
+            digest: self.digest,
+            location: Cow::Owned(self.location.clone().into_owned()),
+        }
+    }
+}
+
+/// A resource payload stored compressed with zstd.
+///
+/// The data of the field in the resource is a zstd frame. Runtimes are
+/// expected to decompress it before using it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompressedPayload<'a> {
+    /// The resource field the payload is data for.
+    pub field: ResourceField,
+
+    /// Name of the payload within a field holding several payloads.
+    ///
+    /// This is the name of a package resource or distribution file. Empty
+    /// for fields holding a single payload.
+    pub name: Cow<'a, str>,
+
+    /// Length in bytes of the decompressed payload.
+    pub length: u64,
+}
+
+impl<'a> CompressedPayload<'a> {
+    pub fn to_owned(&self) -> CompressedPayload<'static> {
+        CompressedPayload {
+            field: self.field,
+            name: Cow::Owned(self.name.clone().into_owned()),
+            length: self.length,
+        }
+    }
+}
+
 /// Represents an embedded resource and all its metadata.
 #[derive(Clone, Debug, PartialEq)]
 pub struct Resource<'a, X: 'a>
@@ -259,6 +453,12 @@ where
     /// In-memory loading of shared libraries is not supported on all platforms.
     pub in_memory_shared_library: Option<Cow<'a, [X]>>,
 
+    /// Whether the in-memory shared library should be extracted to the filesystem.
+    ///
+    /// Extracted libraries are written to a cache directory and loaded from
+    /// there before Python is initialized.
+    pub extract_shared_library: bool,
+
     /// Sequence of names of shared libraries this resource depends on.
     pub shared_library_dependency_names: Option<Vec<Cow<'a, str>>>,
 
@@ -282,6 +482,23 @@ where
 
     /// Mapping of Python package distribution files to relative filesystem paths for those resources.
     pub relative_path_distribution_resources: Option<HashMap<Cow<'a, str>, Cow<'a, Path>>>,
+
+    /// Resource set the resource belongs to.
+    ///
+    /// A name can occur once in each set.
+    pub resource_set: ResourceSet,
+
+    /// Payloads of in-memory fields stored outside of packed resources data.
+    ///
+    /// A field with an external payload has no in-memory data until the
+    /// payload is fetched and assigned to it.
+    pub external_payloads: Vec<ExternalPayload<'a>>,
+
+    /// Payloads of in-memory fields stored compressed.
+    ///
+    /// The data of a field with a compressed payload must be decompressed
+    /// before it is used.
+    pub compressed_payloads: Vec<CompressedPayload<'a>>,
 }
 
 impl<'a, X> Default for Resource<'a, X>
@@ -302,6 +519,7 @@ where
             in_memory_package_resources: None,
             in_memory_distribution_resources: None,
             in_memory_shared_library: None,
+            extract_shared_library: false,
             shared_library_dependency_names: None,
             relative_path_module_source: None,
             relative_path_module_bytecode: None,
@@ -310,6 +528,9 @@ where
             relative_path_extension_module_shared_library: None,
             relative_path_package_resources: None,
             relative_path_distribution_resources: None,
+            resource_set: ResourceSet::Main,
+            external_payloads: Vec::new(),
+            compressed_payloads: Vec::new(),
         }
     }
 }
@@ -327,6 +548,46 @@ impl<'a, X> Resource<'a, X>
 where
     [X]: ToOwned<Owned = Vec<X>>,
 {
+    /// Obtain the data of an in-memory field holding a single payload.
+    ///
+    /// Returns `None` for fields that don't hold a single payload.
+    pub fn in_memory_payload(&self, field: ResourceField) -> Option<&Cow<'a, [X]>> {
+        match field {
+            ResourceField::InMemorySource => self.in_memory_source.as_ref(),
+            ResourceField::InMemoryBytecode => self.in_memory_bytecode.as_ref(),
+            ResourceField::InMemoryBytecodeOpt1 => self.in_memory_bytecode_opt1.as_ref(),
+            ResourceField::InMemoryBytecodeOpt2 => self.in_memory_bytecode_opt2.as_ref(),
+            ResourceField::InMemoryExtensionModuleSharedLibrary => {
+                self.in_memory_extension_module_shared_library.as_ref()
+            }
+            ResourceField::InMemorySharedLibrary => self.in_memory_shared_library.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Replace the data of an in-memory field holding a single payload.
+    ///
+    /// Returns the previous data.
+    pub fn set_in_memory_payload(
+        &mut self,
+        field: ResourceField,
+        data: Option<Cow<'a, [X]>>,
+    ) -> Result<Option<Cow<'a, [X]>>, &'static str> {
+        let slot = match field {
+            ResourceField::InMemorySource => &mut self.in_memory_source,
+            ResourceField::InMemoryBytecode => &mut self.in_memory_bytecode,
+            ResourceField::InMemoryBytecodeOpt1 => &mut self.in_memory_bytecode_opt1,
+            ResourceField::InMemoryBytecodeOpt2 => &mut self.in_memory_bytecode_opt2,
+            ResourceField::InMemoryExtensionModuleSharedLibrary => {
+                &mut self.in_memory_extension_module_shared_library
+            }
+            ResourceField::InMemorySharedLibrary => &mut self.in_memory_shared_library,
+            _ => return Err("resource field doesn't hold a single payload"),
+        };
+
+        Ok(std::mem::replace(slot, data))
+    }
+
     pub fn to_owned(&self) -> Resource<'static, X> {
         Resource {
             flavor: self.flavor,
@@ -375,6 +636,7 @@ where
                 .in_memory_source
                 .as_ref()
                 .map(|value| Cow::Owned(value.clone().into_owned())),
+            extract_shared_library: self.extract_shared_library,
             shared_library_dependency_names: self.shared_library_dependency_names.as_ref().map(
                 |value| Vec::from_iter(value.iter().map(|x| Cow::Owned(x.clone().into_owned()))),
             ),
@@ -419,6 +681,17 @@ where
                         )
                     }))
                 }),
+            resource_set: self.resource_set,
+            external_payloads: self
+                .external_payloads
+                .iter()
+                .map(|payload| payload.to_owned())
+                .collect(),
+            compressed_payloads: self
+                .compressed_payloads
+                .iter()
+                .map(|payload| payload.to_owned())
+                .collect(),
         }
     }
 }
@@ -0,0 +1,510 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Materialize individual resource payloads as files.
+
+Inspecting a single resource shouldn't require taking packed resources
+data apart by hand. This module writes one payload of a resource to a file
+named the way Python would name it: module source as `.py`, bytecode as a
+`.pyc` file that can be loaded or disassembled, and resource files
+verbatim.
+*/
+
+use {
+    crate::data::Resource,
+    crate::parser::{load_lookup_table, load_resources},
+    anyhow::{anyhow, Context, Result},
+    byteorder::{LittleEndian, WriteBytesExt},
+    std::borrow::Cow,
+    std::fmt::{Display, Formatter},
+    std::path::{Path, PathBuf},
+};
+
+/// A payload of a resource.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PayloadKind {
+    /// Python module source code.
+    Source,
+
+    /// Python module bytecode at optimization level 0.
+    Bytecode,
+
+    /// Python module bytecode at optimization level 1.
+    BytecodeOpt1,
+
+    /// Python module bytecode at optimization level 2.
+    BytecodeOpt2,
+
+    /// Extension module shared library.
+    ExtensionModule,
+
+    /// A package resource file, by its path relative to the package.
+    PackageResource(String),
+
+    /// A distribution resource file, by its path relative to the metadata directory.
+    DistributionResource(String),
+
+    /// A shared library.
+    SharedLibrary,
+}
+
+impl Display for PayloadKind {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Self::Source => write!(f, "source"),
+            Self::Bytecode => write!(f, "bytecode"),
+            Self::BytecodeOpt1 => write!(f, "opt-1 bytecode"),
+            Self::BytecodeOpt2 => write!(f, "opt-2 bytecode"),
+            Self::ExtensionModule => write!(f, "extension module"),
+            Self::PackageResource(path) => write!(f, "package resource {}", path),
+            Self::DistributionResource(path) => write!(f, "distribution resource {}", path),
+            Self::SharedLibrary => write!(f, "shared library"),
+        }
+    }
+}
+
+impl PayloadKind {
+    /// Whether the payload is bytecode.
+    pub fn is_bytecode(&self) -> bool {
+        matches!(
+            self,
+            Self::Bytecode | Self::BytecodeOpt1 | Self::BytecodeOpt2
+        )
+    }
+
+    /// The name of the file to write this payload of a resource to.
+    ///
+    /// Modules keep their full dotted name so payloads of different
+    /// modules don't collide. Resource files keep their file name.
+    pub fn file_name(&self, name: &str) -> String {
+        match self {
+            Self::Source => format!("{}.py", name),
+            Self::Bytecode => format!("{}.pyc", name),
+            Self::BytecodeOpt1 => format!("{}.opt-1.pyc", name),
+            Self::BytecodeOpt2 => format!("{}.opt-2.pyc", name),
+            Self::ExtensionModule => {
+                format!("{}{}", name, if cfg!(windows) { ".pyd" } else { ".so" })
+            }
+            Self::PackageResource(path) | Self::DistributionResource(path) => {
+                path.rsplit('/').next().unwrap_or(path).to_string()
+            }
+            Self::SharedLibrary => name.to_string(),
+        }
+    }
+}
+
+/// Compute a `.pyc` header for bytecode stored without one.
+///
+/// The header declares an unchecked hash, so the bytecode is loaded
+/// without consulting source.
+pub fn pyc_header(magic_number: u32) -> Vec<u8> {
+    let mut header = Vec::with_capacity(16);
+
+    header.write_u32::<LittleEndian>(magic_number).unwrap();
+    header.write_u32::<LittleEndian>(1).unwrap();
+    header.write_u64::<LittleEndian>(0).unwrap();
+
+    header
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(current)
+            };
+            previous = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Find candidates similar to a name.
+///
+/// Returns up to 5 candidates, most similar first.
+pub fn close_matches<'a>(name: &str, candidates: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let threshold = std::cmp::max(2, name.chars().count() / 3);
+
+    let mut matches = candidates
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= threshold)
+        .collect::<Vec<_>>();
+    matches.sort();
+
+    matches
+        .into_iter()
+        .take(5)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Obtain an error for a name not among candidates, suggesting close matches.
+///
+/// `description` describes what wasn't found, e.g. `resource foo`.
+pub fn not_found_error<'a>(
+    description: &str,
+    name: &str,
+    candidates: impl Iterator<Item = &'a str>,
+) -> anyhow::Error {
+    let matches = close_matches(name, candidates);
+
+    if matches.is_empty() {
+        anyhow!("{} not found", description)
+    } else {
+        anyhow!(
+            "{} not found; did you mean {}?",
+            description,
+            matches.join(", ")
+        )
+    }
+}
+
+/// Write payload data to a file in a directory.
+///
+/// File names are derived from resource names in packed resources, which
+/// may come from anywhere. So names that aren't a single path component,
+/// and would write outside of `dest`, are rejected.
+///
+/// The directory is created if necessary. Returns the path of the written file.
+pub fn write_payload(dest: &Path, file_name: &str, data: &[u8]) -> Result<PathBuf> {
+    if file_name.is_empty()
+        || file_name == "."
+        || file_name == ".."
+        || file_name.contains(['/', '\\', '\0'])
+        || Path::new(file_name).is_absolute()
+        || Path::new(file_name).components().count() != 1
+    {
+        return Err(anyhow!(
+            "refusing to write payload to file name {:?}",
+            file_name
+        ));
+    }
+
+    std::fs::create_dir_all(dest).with_context(|| format!("creating {}", dest.display()))?;
+
+    let path = dest.join(file_name);
+    std::fs::write(&path, data).with_context(|| format!("writing {}", path.display()))?;
+
+    Ok(path)
+}
+
+/// Obtain a payload of a resource parsed from packed resources.
+///
+/// Bytecode is returned as stored, without a `.pyc` header. Payloads
+/// installed relative to the binary aren't part of packed resources and
+/// can't be obtained.
+pub fn resource_payload<'a>(
+    resource: &'a Resource<'a, u8>,
+    payload: &PayloadKind,
+) -> Result<&'a [u8]> {
+    let (data, relative_path): (Option<&Cow<[u8]>>, Option<&Cow<Path>>) = match payload {
+        PayloadKind::Source => (
+            resource.in_memory_source.as_ref(),
+            resource.relative_path_module_source.as_ref(),
+        ),
+        PayloadKind::Bytecode => (
+            resource.in_memory_bytecode.as_ref(),
+            resource.relative_path_module_bytecode.as_ref(),
+        ),
+        PayloadKind::BytecodeOpt1 => (
+            resource.in_memory_bytecode_opt1.as_ref(),
+            resource.relative_path_module_bytecode_opt1.as_ref(),
+        ),
+        PayloadKind::BytecodeOpt2 => (
+            resource.in_memory_bytecode_opt2.as_ref(),
+            resource.relative_path_module_bytecode_opt2.as_ref(),
+        ),
+        PayloadKind::ExtensionModule => (
+            resource.in_memory_extension_module_shared_library.as_ref(),
+            resource
+                .relative_path_extension_module_shared_library
+                .as_ref(),
+        ),
+        PayloadKind::PackageResource(path) => {
+            let data = resource
+                .in_memory_package_resources
+                .as_ref()
+                .and_then(|x| x.get(path.as_str()));
+            let relative_path = resource
+                .relative_path_package_resources
+                .as_ref()
+                .and_then(|x| x.get(path.as_str()));
+
+            if data.is_none() && relative_path.is_none() {
+                let candidates = resource
+                    .in_memory_package_resources
+                    .iter()
+                    .flat_map(|x| x.keys())
+                    .chain(
+                        resource
+                            .relative_path_package_resources
+                            .iter()
+                            .flat_map(|x| x.keys()),
+                    )
+                    .map(|x| x.as_ref());
+
+                return Err(not_found_error(
+                    &format!("package resource {} of {}", path, resource.name),
+                    path,
+                    candidates,
+                ));
+            }
+
+            (data, relative_path)
+        }
+        PayloadKind::DistributionResource(path) => (
+            resource
+                .in_memory_distribution_resources
+                .as_ref()
+                .and_then(|x| x.get(path.as_str())),
+            resource
+                .relative_path_distribution_resources
+                .as_ref()
+                .and_then(|x| x.get(path.as_str())),
+        ),
+        PayloadKind::SharedLibrary => (resource.in_memory_shared_library.as_ref(), None),
+    };
+
+    match (data, relative_path) {
+        (Some(data), _) => Ok(data.as_ref()),
+        (None, Some(path)) => Err(anyhow!(
+            "{} of {} is installed at {} and isn't in packed resources",
+            payload,
+            resource.name,
+            path.display()
+        )),
+        (None, None) => Err(anyhow!("{} has no {}", resource.name, payload)),
+    }
+}
+
+/// Obtain a payload of a resource in packed resources data along with its file name.
+///
+/// Bytecode is prefixed with a `.pyc` header using `magic_number`.
+pub fn extract_resource_data(
+    data: &[u8],
+    name: &str,
+    payload: &PayloadKind,
+    magic_number: u32,
+) -> Result<(String, Vec<u8>)> {
+    let file_data = |resource: &Resource<u8>| -> Result<(String, Vec<u8>)> {
+        let content = resource_payload(resource, payload)?;
+
+        let content = if payload.is_bytecode() {
+            let mut pyc = pyc_header(magic_number);
+            pyc.extend_from_slice(content);
+            pyc
+        } else {
+            content.to_vec()
+        };
+
+        Ok((payload.file_name(name), content))
+    };
+
+    // Packed resources with a lookup table don't need to be read in full.
+    if let Ok(table) = load_lookup_table(data) {
+        if let Some(resource) = table.get(name).map_err(|e| anyhow!(e))? {
+            return file_data(&resource);
+        }
+    }
+
+    let mut names = Vec::new();
+
+    for resource in load_resources(data).map_err(|e| anyhow!(e))? {
+        let resource = resource.map_err(|e| anyhow!(e))?;
+
+        if resource.name != name {
+            names.push(resource.name);
+            continue;
+        }
+
+        return file_data(&resource);
+    }
+
+    Err(not_found_error(
+        &format!("resource {}", name),
+        name,
+        names.iter().map(|x| x.as_ref()),
+    ))
+}
+
+/// Write a payload of a resource in packed resources data to a directory.
+///
+/// Bytecode is written as a `.pyc` file with a header using `magic_number`.
+/// Returns the path of the written file.
+pub fn extract_resource(
+    data: &[u8],
+    name: &str,
+    payload: &PayloadKind,
+    magic_number: u32,
+    dest: &Path,
+) -> Result<PathBuf> {
+    let (file_name, content) = extract_resource_data(data, name, payload, magic_number)?;
+
+    write_payload(dest, &file_name, &content)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::data::{IndexEncoding, ResourceFlavor},
+        crate::writer::write_packed_resources,
+        std::collections::HashMap,
+    };
+
+    fn packed(encoding: IndexEncoding) -> Result<Vec<u8>> {
+        let resources = vec![
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("foo.bar"),
+                is_package: true,
+                in_memory_source: Some(Cow::from(b"import os".to_vec())),
+                in_memory_bytecode: Some(Cow::from(b"bytecode".to_vec())),
+                in_memory_package_resources: Some(
+                    vec![(Cow::from("data/file.txt"), Cow::from(b"data".to_vec()))]
+                        .into_iter()
+                        .collect::<HashMap<_, _>>(),
+                ),
+                relative_path_module_bytecode_opt1: Some(Cow::from(Path::new(
+                    "lib/foo/bar/__pycache__/__init__.opt-1.pyc",
+                ))),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("foo.baz"),
+                ..Resource::default()
+            },
+        ];
+
+        let mut data = Vec::new();
+        write_packed_resources(&resources, &mut data, None, encoding)?;
+
+        Ok(data)
+    }
+
+    #[test]
+    fn test_close_matches() {
+        let candidates = ["foo.bar", "foo.baz", "foo.bar.qux", "json"];
+
+        assert_eq!(
+            close_matches("foo.bra", candidates.iter().copied()),
+            vec!["foo.bar", "foo.baz"]
+        );
+        assert!(close_matches("sqlite3", candidates.iter().copied()).is_empty());
+    }
+
+    #[test]
+    fn test_extract_resource_data() -> Result<()> {
+        for encoding in &[IndexEncoding::Sequential, IndexEncoding::LookupTable] {
+            let data = packed(*encoding)?;
+
+            assert_eq!(
+                extract_resource_data(&data, "foo.bar", &PayloadKind::Source, 42)?,
+                ("foo.bar.py".to_string(), b"import os".to_vec())
+            );
+
+            let header = pyc_header(42);
+            assert_eq!(header.len(), 16);
+            assert_eq!(
+                extract_resource_data(&data, "foo.bar", &PayloadKind::Bytecode, 42)?,
+                (
+                    "foo.bar.pyc".to_string(),
+                    [header, b"bytecode".to_vec()].concat()
+                )
+            );
+
+            assert_eq!(
+                extract_resource_data(
+                    &data,
+                    "foo.bar",
+                    &PayloadKind::PackageResource("data/file.txt".to_string()),
+                    42
+                )?,
+                ("file.txt".to_string(), b"data".to_vec())
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_extract_resource_errors() -> Result<()> {
+        let data = packed(IndexEncoding::Sequential)?;
+
+        let err = |name: &str, payload: PayloadKind| {
+            extract_resource_data(&data, name, &payload, 42)
+                .unwrap_err()
+                .to_string()
+        };
+
+        assert_eq!(
+            err("foo.bra", PayloadKind::Source),
+            "resource foo.bra not found; did you mean foo.bar, foo.baz?"
+        );
+        assert_eq!(
+            err("sqlite3", PayloadKind::Source),
+            "resource sqlite3 not found"
+        );
+        assert_eq!(err("foo.baz", PayloadKind::Source), "foo.baz has no source");
+        assert_eq!(
+            err("foo.bar", PayloadKind::BytecodeOpt1),
+            "opt-1 bytecode of foo.bar is installed at \
+             lib/foo/bar/__pycache__/__init__.opt-1.pyc and isn't in packed resources"
+        );
+        assert_eq!(
+            err(
+                "foo.bar",
+                PayloadKind::PackageResource("data/file.tx".to_string())
+            ),
+            "package resource data/file.tx of foo.bar not found; did you mean data/file.txt?"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_payload_rejects_traversal() -> Result<()> {
+        let dest = std::env::temp_dir().join(format!(
+            "python-packed-resources-test-{}",
+            std::process::id()
+        ));
+
+        for name in &["", ".", "..", "../escape.py", "/etc/passwd", "a\\b", "C:x"] {
+            if *name == "C:x" && !cfg!(windows) {
+                continue;
+            }
+
+            assert!(write_payload(&dest, name, b"data").is_err(), "{}", name);
+        }
+
+        let resources = vec![Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from("../../escape"),
+            in_memory_source: Some(Cow::from(b"import os".to_vec())),
+            ..Resource::default()
+        }];
+        let mut data = Vec::new();
+        write_packed_resources(&resources, &mut data, None, IndexEncoding::Sequential)?;
+
+        assert!(extract_resource(&data, "../../escape", &PayloadKind::Source, 42, &dest).is_err());
+        assert!(!dest.join("../../escape.py").exists());
+
+        let path = write_payload(&dest, "foo.py", b"data")?;
+        assert_eq!(path, dest.join("foo.py"));
+        std::fs::remove_dir_all(&dest)?;
+
+        Ok(())
+    }
+}
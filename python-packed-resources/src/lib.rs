@@ -25,7 +25,9 @@ be used outside the PyOxidizer project.
 */
 
 pub mod data;
+pub mod extract;
 #[allow(unused)]
 pub mod parser;
+pub mod sink;
 pub mod specifications;
 pub mod writer;
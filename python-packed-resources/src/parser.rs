@@ -6,10 +6,13 @@
 
 use {
     super::data::{
-        BlobInteriorPadding, BlobSectionField, Resource, ResourceField, ResourceFlavor, HEADER_V1,
+        BlobInteriorPadding, BlobSectionField, CompressedPayload, ExternalPayload, Resource,
+        ResourceField, ResourceFlavor, ResourceSet, EXTERNAL_PAYLOAD_DIGEST_LENGTH, HEADER_V1,
+        HEADER_V2, HEADER_V3, LOOKUP_TABLE_ENTRY_LENGTH,
     },
-    byteorder::{LittleEndian, ReadBytesExt},
+    byteorder::{ByteOrder, LittleEndian, ReadBytesExt},
     std::borrow::Cow,
+    std::cell::Cell,
     std::collections::{HashMap, HashSet},
     std::convert::TryFrom,
     std::ffi::OsStr,
@@ -33,6 +36,7 @@ struct BlobSection {
 /// Holds state used to read an individual blob section.
 #[derive(Clone, Copy, Debug)]
 struct BlobSectionReadState {
+    start: usize,
     offset: usize,
     interior_padding: BlobInteriorPadding,
 }
@@ -41,6 +45,8 @@ pub type PythonPackageResources<'a> = HashMap<&'a str, &'a [u8]>;
 
 pub struct ResourceParserIterator<'a> {
     done: bool,
+    /// Version of the packed resources format being read.
+    version: u8,
     data: &'a [u8],
     reader: Cursor<&'a [u8]>,
     blob_sections: [Option<BlobSectionReadState>; 256],
@@ -76,6 +82,50 @@ impl<'a> ResourceParserIterator<'a> {
         blob
     }
 
+    /// Resolve a slice to a blob stored by another resource.
+    ///
+    /// Unlike `resolve_blob_data()`, this doesn't advance the blob section offset.
+    fn resolve_shared_blob_data(
+        &self,
+        resource_field: ResourceField,
+        offset: usize,
+        length: usize,
+    ) -> Result<&'a [u8], &'static str> {
+        let state = self.blob_sections[resource_field as usize]
+            .as_ref()
+            .ok_or("blob section for shared payload not found")?;
+
+        let start = state
+            .start
+            .checked_add(offset)
+            .ok_or("shared payload offset out of bounds")?;
+        let end = start
+            .checked_add(length)
+            .ok_or("shared payload length out of bounds")?;
+
+        self.data
+            .get(start..end)
+            .ok_or("shared payload out of bounds")
+    }
+
+    /// Set the position of a blob section to an offset from its start.
+    fn seek_blob_section(
+        &mut self,
+        resource_field: ResourceField,
+        offset: usize,
+    ) -> Result<(), &'static str> {
+        let state = self.blob_sections[resource_field as usize]
+            .as_mut()
+            .ok_or("blob section for blob offset not found")?;
+
+        state.offset = state
+            .start
+            .checked_add(offset)
+            .ok_or("blob offset out of bounds")?;
+
+        Ok(())
+    }
+
     #[cfg(unix)]
     fn resolve_path(&mut self, resource_field: ResourceField, length: usize) -> Cow<'a, Path> {
         let path_str = OsStr::from_bytes(self.resolve_blob_data(resource_field, length));
@@ -106,6 +156,10 @@ impl<'a> ResourceParserIterator<'a> {
 
             let field_type = ResourceField::try_from(field_type)?;
 
+            if field_type.format_version() > self.version {
+                return Err("resource field requires a newer packed resources version");
+            }
+
             match field_type {
                 ResourceField::EndOfIndex => {
                     self.done = true;
@@ -297,6 +351,163 @@ impl<'a> ResourceParserIterator<'a> {
                         Some(Cow::Borrowed(self.resolve_blob_data(field_type, l)));
                 }
 
+                ResourceField::SharedPayload => {
+                    let target = self
+                        .reader
+                        .read_u8()
+                        .map_err(|_| "failed reading shared payload field")?;
+                    let target = ResourceField::try_from(target)?;
+                    let offset = self
+                        .reader
+                        .read_u64::<LittleEndian>()
+                        .map_err(|_| "failed reading shared payload offset")?
+                        as usize;
+                    let l = self
+                        .reader
+                        .read_u64::<LittleEndian>()
+                        .map_err(|_| "failed reading shared payload length")?
+                        as usize;
+
+                    let data = Some(Cow::Borrowed(
+                        self.resolve_shared_blob_data(target, offset, l)?,
+                    ));
+
+                    match target {
+                        ResourceField::InMemorySource => {
+                            current_resource.in_memory_source = data;
+                        }
+                        ResourceField::InMemoryBytecode => {
+                            current_resource.in_memory_bytecode = data;
+                        }
+                        ResourceField::InMemoryBytecodeOpt1 => {
+                            current_resource.in_memory_bytecode_opt1 = data;
+                        }
+                        ResourceField::InMemoryBytecodeOpt2 => {
+                            current_resource.in_memory_bytecode_opt2 = data;
+                        }
+                        ResourceField::InMemoryExtensionModuleSharedLibrary => {
+                            current_resource.in_memory_extension_module_shared_library = data;
+                        }
+                        ResourceField::InMemorySharedLibrary => {
+                            current_resource.in_memory_shared_library = data;
+                        }
+                        _ => return Err("resource field does not support shared payloads"),
+                    }
+                }
+
+                ResourceField::ExtractSharedLibrary => {
+                    current_resource.extract_shared_library = true;
+                }
+
+                ResourceField::RecoverySet => {
+                    current_resource.resource_set = ResourceSet::Recovery;
+                }
+
+                ResourceField::ExternalPayload => {
+                    let target = self
+                        .reader
+                        .read_u8()
+                        .map_err(|_| "failed reading external payload field")?;
+                    let target = ResourceField::try_from(target)?;
+                    let length = self
+                        .reader
+                        .read_u64::<LittleEndian>()
+                        .map_err(|_| "failed reading external payload length")?;
+                    let mut digest = [0; EXTERNAL_PAYLOAD_DIGEST_LENGTH];
+                    self.reader
+                        .read_exact(&mut digest)
+                        .map_err(|_| "failed reading external payload digest")?;
+                    let l = self
+                        .reader
+                        .read_u16::<LittleEndian>()
+                        .map_err(|_| "failed reading external payload location length")?
+                        as usize;
+
+                    let start = self.reader.position() as usize;
+                    let location = self
+                        .data
+                        .get(start..start + l)
+                        .ok_or("external payload location out of bounds")?;
+                    let location = std::str::from_utf8(location)
+                        .map_err(|_| "external payload location is not valid UTF-8")?;
+                    self.reader.set_position((start + l) as u64);
+
+                    if !target.is_single_payload() {
+                        return Err("resource field does not support external payloads");
+                    }
+                    if current_resource.in_memory_payload(target).is_some() {
+                        return Err("resource field has in-memory and external payloads");
+                    }
+
+                    current_resource.external_payloads.push(ExternalPayload {
+                        field: target,
+                        length,
+                        digest,
+                        location: Cow::Borrowed(location),
+                    });
+                }
+
+                ResourceField::CompressedPayload => {
+                    let target = self
+                        .reader
+                        .read_u8()
+                        .map_err(|_| "failed reading compressed payload field")?;
+                    let target = ResourceField::try_from(target)?;
+                    let length = self
+                        .reader
+                        .read_u64::<LittleEndian>()
+                        .map_err(|_| "failed reading compressed payload length")?;
+                    let l = self
+                        .reader
+                        .read_u16::<LittleEndian>()
+                        .map_err(|_| "failed reading compressed payload name length")?
+                        as usize;
+
+                    let start = self.reader.position() as usize;
+                    let name = self
+                        .data
+                        .get(start..start + l)
+                        .ok_or("compressed payload name out of bounds")?;
+                    let name = std::str::from_utf8(name)
+                        .map_err(|_| "compressed payload name is not valid UTF-8")?;
+                    self.reader.set_position((start + l) as u64);
+
+                    let named = matches!(
+                        target,
+                        ResourceField::InMemoryResourcesData
+                            | ResourceField::InMemoryDistributionResource
+                    );
+                    if !named && !target.is_single_payload() {
+                        return Err("resource field does not support compressed payloads");
+                    }
+                    if named == name.is_empty() {
+                        return Err("compressed payload name doesn't match its resource field");
+                    }
+
+                    current_resource
+                        .compressed_payloads
+                        .push(CompressedPayload {
+                            field: target,
+                            name: Cow::Borrowed(name),
+                            length,
+                        });
+                }
+
+                ResourceField::BlobOffset => {
+                    let target = self
+                        .reader
+                        .read_u8()
+                        .map_err(|_| "failed reading blob offset field")?;
+                    let target = ResourceField::try_from(target)?;
+                    let offset = self
+                        .reader
+                        .read_u64::<LittleEndian>()
+                        .map_err(|_| "failed reading blob offset")?
+                        as usize;
+
+                    self.seek_blob_section(target, offset)?;
+                }
+
                 ResourceField::SharedLibraryDependencyNames => {
                     let names_count = self
                         .reader
@@ -476,13 +687,25 @@ pub fn load_resources<'a>(data: &'a [u8]) -> Result<ResourceParserIterator<'a>,
     let header = &data[0..8];
 
     if header == HEADER_V1 {
-        load_resources_v1(&data[8..])
+        load_resources_index(&data[8..], 1)
+    } else if header == HEADER_V2 {
+        load_resources_index(&data[8..], 2)
+    } else if header == HEADER_V3 {
+        load_resources_index(&data[8..], 3)
     } else {
         Err("unrecognized file format")
     }
 }
 
-fn load_resources_v1<'a>(data: &'a [u8]) -> Result<ResourceParserIterator<'a>, &'static str> {
+/// Load the index of packed resources of a format version.
+///
+/// `data` is the packed resources data following the magic header. The
+/// lookup table of version 3 is skipped. Resource fields newer than `version`
+/// are rejected.
+fn load_resources_index<'a>(
+    data: &'a [u8],
+    version: u8,
+) -> Result<ResourceParserIterator<'a>, &'static str> {
     let mut reader = Cursor::new(data);
 
     let blob_section_count = reader
@@ -499,6 +722,15 @@ fn load_resources_v1<'a>(data: &'a [u8]) -> Result<ResourceParserIterator<'a>, &
         .map_err(|_| "failed reading resources index length")?
         as usize;
 
+    let lookup_table_length = if version >= 3 {
+        resources_count
+            .checked_mul(LOOKUP_TABLE_ENTRY_LENGTH)
+            .ok_or("lookup table length out of bounds")?
+    } else {
+        0
+    };
+    reader.set_position(reader.position() + lookup_table_length as u64);
+
     let mut current_blob_field = None;
     let mut current_blob_raw_payload_length = None;
     let mut current_blob_interior_padding = None;
@@ -577,6 +809,7 @@ fn load_resources_v1<'a>(data: &'a [u8]) -> Result<ResourceParserIterator<'a>, &
     let blob_start_offset: usize =
             // Global header.
             1 + 4 + 4 + 4
+            + lookup_table_length
             + blob_index_length
             + resources_index_length
         ;
@@ -586,6 +819,7 @@ fn load_resources_v1<'a>(data: &'a [u8]) -> Result<ResourceParserIterator<'a>, &
     for section in &blob_sections {
         let section_start_offset = blob_start_offset + current_blob_offset;
         blob_offsets[section.resource_field as usize] = Some(BlobSectionReadState {
+            start: section_start_offset,
             offset: section_start_offset,
             interior_padding: match section.interior_padding {
                 Some(padding) => padding,
@@ -597,6 +831,7 @@ fn load_resources_v1<'a>(data: &'a [u8]) -> Result<ResourceParserIterator<'a>, &
 
     Ok(ResourceParserIterator {
         done: resources_index_length == 0 || resources_count == 0,
+        version,
         data,
         reader,
         blob_sections: blob_offsets,
@@ -605,12 +840,191 @@ fn load_resources_v1<'a>(data: &'a [u8]) -> Result<ResourceParserIterator<'a>, &
     })
 }
 
+/// Finds resources in version 3 packed resources through its lookup table.
+///
+/// Creating an instance only reads the global header and the blob index.
+/// Resources are found by binary search over the lookup table and only the
+/// index entry of a found resource is read.
+#[derive(Debug)]
+pub struct ResourcesLookupTable<'a> {
+    /// Packed resources data following the magic header.
+    data: &'a [u8],
+
+    /// Lookup table entries.
+    entries: &'a [u8],
+
+    /// Blob section read state after reading the blob index.
+    blob_sections: [Option<BlobSectionReadState>; 256],
+
+    /// Number of names compared to find resources.
+    comparisons: Cell<usize>,
+}
+
+impl<'a> ResourcesLookupTable<'a> {
+    /// The number of resources.
+    pub fn len(&self) -> usize {
+        self.entries.len() / LOOKUP_TABLE_ENTRY_LENGTH
+    }
+
+    /// Whether there are no resources.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of names compared to find resources so far.
+    pub fn comparisons(&self) -> usize {
+        self.comparisons.get()
+    }
+
+    /// Obtain the name of the resource at a position in the lookup table.
+    ///
+    /// Names are sorted by their bytes.
+    pub fn name(&self, index: usize) -> Result<&'a str, &'static str> {
+        let entry = self
+            .entries
+            .get(index * LOOKUP_TABLE_ENTRY_LENGTH..(index + 1) * LOOKUP_TABLE_ENTRY_LENGTH)
+            .ok_or("lookup table index out of bounds")?;
+
+        let offset = LittleEndian::read_u64(&entry[0..8]) as usize;
+        let length = LittleEndian::read_u32(&entry[8..12]) as usize;
+
+        let name = self
+            .data
+            .get(offset..offset.checked_add(length).ok_or("name out of bounds")?)
+            .ok_or("name out of bounds")?;
+
+        std::str::from_utf8(name).map_err(|_| "name is not valid UTF-8")
+    }
+
+    /// Find a resource of the main resource set by name.
+    pub fn get(&self, name: &str) -> Result<Option<Resource<'a, u8>>, &'static str> {
+        self.get_in_set(name, ResourceSet::Main)
+    }
+
+    /// Find a resource of a resource set by name.
+    pub fn get_in_set(
+        &self,
+        name: &str,
+        set: ResourceSet,
+    ) -> Result<Option<Resource<'a, u8>>, &'static str> {
+        let mut low = 0;
+        let mut high = self.len();
+
+        while low < high {
+            let middle = low + (high - low) / 2;
+
+            self.comparisons.set(self.comparisons.get() + 1);
+
+            match self.name(middle)?.as_bytes().cmp(name.as_bytes()) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return self.resource_in_set(middle, set),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read the resource of a set among the entries named like the one at a position.
+    ///
+    /// A name occurs at most once per set, so it has at most two adjacent
+    /// entries.
+    fn resource_in_set(
+        &self,
+        index: usize,
+        set: ResourceSet,
+    ) -> Result<Option<Resource<'a, u8>>, &'static str> {
+        let name = self.name(index)?;
+        let first = if index > 0 && self.name(index - 1)? == name {
+            index - 1
+        } else {
+            index
+        };
+
+        for candidate in first..std::cmp::min(first + 2, self.len()) {
+            if self.name(candidate)? != name {
+                continue;
+            }
+
+            let resource = self.resource(candidate)?;
+            if resource.resource_set == set {
+                return Ok(Some(resource));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Read the resource at a position in the lookup table.
+    fn resource(&self, index: usize) -> Result<Resource<'a, u8>, &'static str> {
+        let entry = &self.entries
+            [index * LOOKUP_TABLE_ENTRY_LENGTH..(index + 1) * LOOKUP_TABLE_ENTRY_LENGTH];
+        let entry_offset = LittleEndian::read_u32(&entry[12..16]) as u64;
+
+        let mut reader = Cursor::new(self.data);
+        reader.set_position(entry_offset);
+
+        let mut parser = ResourceParserIterator {
+            done: false,
+            version: 3,
+            data: self.data,
+            reader,
+            blob_sections: self.blob_sections,
+            claimed_resources_count: 1,
+            read_resources_count: 0,
+        };
+
+        let resource = parser
+            .parse_next()?
+            .ok_or("lookup table entry doesn't reference a resource")?;
+
+        if resource.name != self.name(index)? {
+            return Err("lookup table entry references another resource");
+        }
+
+        Ok(resource)
+    }
+}
+
+/// Load the lookup table of version 3 packed resources.
+///
+/// Errors if the data isn't version 3 packed resources.
+pub fn load_lookup_table<'a>(data: &'a [u8]) -> Result<ResourcesLookupTable<'a>, &'static str> {
+    if data.len() < HEADER_V3.len() {
+        return Err("error reading 8 byte header");
+    }
+
+    if !data.starts_with(HEADER_V3) {
+        return Err("packed resources have no lookup table");
+    }
+
+    let data = &data[8..];
+    let parser = load_resources_index(data, 3)?;
+
+    let entries_length = parser
+        .claimed_resources_count
+        .checked_mul(LOOKUP_TABLE_ENTRY_LENGTH)
+        .ok_or("lookup table length out of bounds")?;
+    let entries = data
+        .get(13..13 + entries_length)
+        .ok_or("lookup table out of bounds")?;
+
+    Ok(ResourcesLookupTable {
+        data,
+        entries,
+        blob_sections: parser.blob_sections,
+        comparisons: Cell::new(0),
+    })
+}
+
 #[cfg(test)]
 mod tests {
     use {
         super::*,
         crate::data::{BlobInteriorPadding, Resource},
-        crate::writer::write_packed_resources_v1,
+        crate::writer::{
+            write_packed_resources_v1, write_packed_resources_v2, write_packed_resources_v3,
+        },
         std::collections::BTreeMap,
     };
 
@@ -628,11 +1042,20 @@ mod tests {
         let res = load_resources(data);
         assert_eq!(res.err(), Some("unrecognized file format"));
 
-        let data = b"pyembed\x02";
+        let data = b"pyembed\x04";
         let res = load_resources(data);
         assert_eq!(res.err(), Some("unrecognized file format"));
     }
 
+    #[test]
+    fn test_truncated_v3() {
+        let data = b"pyembed\x03";
+        let res = load_resources(data);
+        assert_eq!(res.err(), Some("failed reading blob section count"));
+
+        assert!(load_lookup_table(data).is_err());
+    }
+
     #[test]
     fn test_no_indices() {
         let data = b"pyembed\x01\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
@@ -1055,6 +1478,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_extract_shared_library() {
+        let resource = Resource {
+            name: Cow::from("foo"),
+            in_memory_shared_library: Some(Cow::from(b"library".to_vec())),
+            extract_shared_library: true,
+            ..Resource::default()
+        };
+
+        let mut data = Vec::new();
+        write_packed_resources_v2(&[resource], &mut data, None).unwrap();
+        let resources = load_resources(&data)
+            .unwrap()
+            .collect::<Result<Vec<Resource<u8>>, &'static str>>()
+            .unwrap();
+
+        assert_eq!(resources.len(), 1);
+
+        assert_eq!(
+            &resources[0],
+            &Resource {
+                name: Cow::from("foo"),
+                in_memory_shared_library: Some(Cow::Borrowed(&data[data.len() - 7..data.len()])),
+                extract_shared_library: true,
+                ..Resource::default()
+            }
+        );
+    }
+
     #[test]
     fn test_shared_library_dependency_names() {
         let names = vec![Cow::from("depends"), Cow::from("libfoo")];
@@ -1343,6 +1795,7 @@ mod tests {
             in_memory_package_resources: Some(in_memory_resources),
             in_memory_distribution_resources: Some(in_memory_distribution),
             in_memory_shared_library: Some(Cow::from(b"library".to_vec())),
+            extract_shared_library: true,
             shared_library_dependency_names: Some(vec![Cow::from("libfoo"), Cow::from("depends")]),
             relative_path_module_source: Some(Cow::from(Path::new("source_path"))),
             relative_path_module_bytecode: Some(Cow::from(Path::new("bytecode_path"))),
@@ -1351,10 +1804,13 @@ mod tests {
             relative_path_extension_module_shared_library: Some(Cow::from(Path::new("em_path"))),
             relative_path_package_resources: Some(relative_path_resources),
             relative_path_distribution_resources: Some(relative_path_distribution),
+            resource_set: ResourceSet::Recovery,
+            external_payloads: Vec::new(),
+            compressed_payloads: Vec::new(),
         };
 
         let mut data = Vec::new();
-        write_packed_resources_v1(&[resource], &mut data, None).unwrap();
+        write_packed_resources_v2(&[resource], &mut data, None).unwrap();
         let resources = load_resources(&data)
             .unwrap()
             .collect::<Result<Vec<Resource<u8>>, &'static str>>()
@@ -1403,6 +1859,7 @@ mod tests {
             entry.in_memory_shared_library.as_ref().unwrap().as_ref(),
             b"library"
         );
+        assert!(entry.extract_shared_library);
         assert_eq!(
             entry.shared_library_dependency_names.as_ref().unwrap(),
             &vec!["libfoo", "depends"]
@@ -1451,6 +1908,7 @@ mod tests {
             distribution.get("resource.txt"),
             Some(&Cow::Borrowed(Path::new("package/resource.txt")))
         );
+        assert_eq!(entry.resource_set, ResourceSet::Recovery);
     }
 
     #[test]
@@ -1479,4 +1937,462 @@ mod tests {
 
         assert_eq!(resources, loaded);
     }
+
+    #[test]
+    fn test_shared_payloads() {
+        let source = vec![b'x'; 100];
+
+        let resources: Vec<Resource<u8>> = vec![
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("foo"),
+                in_memory_source: Some(Cow::from(source.clone())),
+                in_memory_bytecode: Some(Cow::from(b"foo bytecode".to_vec())),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("bar"),
+                in_memory_source: Some(Cow::from(source.clone())),
+                in_memory_bytecode: Some(Cow::from(b"bar bytecode".to_vec())),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("baz"),
+                in_memory_source: Some(Cow::from(source)),
+                ..Resource::default()
+            },
+        ];
+
+        for padding in &[None, Some(BlobInteriorPadding::Null)] {
+            let mut data = Vec::new();
+            write_packed_resources_v2(&resources, &mut data, *padding).unwrap();
+            let loaded = load_resources(&data)
+                .unwrap()
+                .collect::<Result<Vec<Resource<u8>>, &'static str>>()
+                .unwrap();
+
+            assert_eq!(resources, loaded);
+
+            // All resources reference the same bytes.
+            let first = loaded[0].in_memory_source.as_ref().unwrap().as_ptr();
+            assert_eq!(loaded[1].in_memory_source.as_ref().unwrap().as_ptr(), first);
+            assert_eq!(loaded[2].in_memory_source.as_ref().unwrap().as_ptr(), first);
+        }
+    }
+
+    #[test]
+    fn test_shared_payload_copy_on_write() {
+        let source = vec![b'x'; 100];
+
+        let resources: Vec<Resource<u8>> = vec![
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("foo"),
+                in_memory_source: Some(Cow::from(source.clone())),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("bar"),
+                in_memory_source: Some(Cow::from(source.clone())),
+                ..Resource::default()
+            },
+        ];
+
+        let mut data = Vec::new();
+        write_packed_resources_v2(&resources, &mut data, None).unwrap();
+        let mut loaded = load_resources(&data)
+            .unwrap()
+            .collect::<Result<Vec<Resource<u8>>, &'static str>>()
+            .unwrap();
+
+        loaded[0].in_memory_source.as_mut().unwrap().to_mut()[0] = b'y';
+
+        assert_eq!(loaded[0].in_memory_source.as_ref().unwrap()[0], b'y');
+        assert!(matches!(loaded[1].in_memory_source, Some(Cow::Borrowed(_))));
+        assert_eq!(
+            loaded[1].in_memory_source.as_ref().unwrap().as_ref(),
+            source.as_slice()
+        );
+    }
+
+    #[test]
+    fn test_shared_payload_invalid_field() {
+        let resources: Vec<Resource<u8>> = vec![Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from("foo"),
+            ..Resource::default()
+        }];
+
+        let mut data = Vec::new();
+        write_packed_resources_v2(&resources, &mut data, None).unwrap();
+
+        // Replace the end of entry marker with a shared payload referencing
+        // the module name section, which can't be shared.
+        let end_of_entry = data.len() - 5;
+        assert_eq!(data[end_of_entry], ResourceField::EndOfEntry.into());
+        let mut entry: Vec<u8> = vec![
+            ResourceField::SharedPayload.into(),
+            ResourceField::ModuleName.into(),
+        ];
+        entry.extend_from_slice(&[0; 16]);
+        data.splice(end_of_entry..end_of_entry + 1, entry);
+
+        // Account for the larger resources index in the header.
+        let index_length = u32::from_le_bytes([data[17], data[18], data[19], data[20]]) + 17;
+        data[17..21].copy_from_slice(&index_length.to_le_bytes());
+
+        let res = load_resources(&data)
+            .unwrap()
+            .collect::<Result<Vec<Resource<u8>>, &'static str>>();
+        assert_eq!(
+            res.unwrap_err(),
+            "resource field does not support shared payloads"
+        );
+    }
+
+    #[test]
+    fn test_compressed_payloads() {
+        let mut package_resources = HashMap::new();
+        package_resources.insert(Cow::from("data.txt"), Cow::from(b"compressed".to_vec()));
+        package_resources.insert(Cow::from("other.txt"), Cow::from(b"other".to_vec()));
+
+        let resources: Vec<Resource<u8>> = vec![Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from("foo"),
+            in_memory_source: Some(Cow::from(b"compressed source".to_vec())),
+            in_memory_package_resources: Some(package_resources),
+            compressed_payloads: vec![
+                CompressedPayload {
+                    field: ResourceField::InMemorySource,
+                    name: Cow::from(""),
+                    length: 100,
+                },
+                CompressedPayload {
+                    field: ResourceField::InMemoryResourcesData,
+                    name: Cow::from("data.txt"),
+                    length: 200,
+                },
+            ],
+            ..Resource::default()
+        }];
+
+        let mut data = Vec::new();
+        write_packed_resources_v2(&resources, &mut data, None).unwrap();
+        let loaded = load_resources(&data)
+            .unwrap()
+            .collect::<Result<Vec<Resource<u8>>, &'static str>>()
+            .unwrap();
+
+        assert_eq!(resources, loaded);
+
+        let invalid: Vec<Resource<u8>> = vec![Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from("foo"),
+            compressed_payloads: vec![CompressedPayload {
+                field: ResourceField::InMemorySource,
+                name: Cow::from("data.txt"),
+                length: 100,
+            }],
+            ..Resource::default()
+        }];
+
+        let mut data = Vec::new();
+        write_packed_resources_v2(&invalid, &mut data, None).unwrap();
+        let res = load_resources(&data)
+            .unwrap()
+            .collect::<Result<Vec<Resource<u8>>, &'static str>>();
+        assert_eq!(
+            res.unwrap_err(),
+            "compressed payload name doesn't match its resource field"
+        );
+    }
+
+    #[test]
+    fn test_v2_fields_in_v1() {
+        let module = |name: &str| Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::from(name.to_string()),
+            in_memory_source: Some(Cow::from(vec![b'x'; 100])),
+            ..Resource::default()
+        };
+
+        let cases: Vec<(ResourceField, Vec<Resource<u8>>)> = vec![
+            (
+                ResourceField::SharedPayload,
+                vec![module("foo"), module("bar")],
+            ),
+            (
+                ResourceField::ExtractSharedLibrary,
+                vec![Resource {
+                    flavor: ResourceFlavor::SharedLibrary,
+                    name: Cow::from("libfoo.so"),
+                    in_memory_shared_library: Some(Cow::from(b"library".to_vec())),
+                    extract_shared_library: true,
+                    ..Resource::default()
+                }],
+            ),
+            (
+                ResourceField::RecoverySet,
+                vec![Resource {
+                    resource_set: ResourceSet::Recovery,
+                    ..module("foo")
+                }],
+            ),
+            (
+                ResourceField::ExternalPayload,
+                vec![Resource {
+                    flavor: ResourceFlavor::Module,
+                    name: Cow::from("foo"),
+                    external_payloads: vec![ExternalPayload {
+                        field: ResourceField::InMemorySource,
+                        length: 100,
+                        digest: [0; EXTERNAL_PAYLOAD_DIGEST_LENGTH],
+                        location: Cow::from("foo.py"),
+                    }],
+                    ..Resource::default()
+                }],
+            ),
+            (
+                ResourceField::CompressedPayload,
+                vec![Resource {
+                    compressed_payloads: vec![CompressedPayload {
+                        field: ResourceField::InMemorySource,
+                        name: Cow::from(""),
+                        length: 200,
+                    }],
+                    ..module("foo")
+                }],
+            ),
+        ];
+
+        for (field, resources) in cases {
+            let mut data = Vec::new();
+            write_packed_resources_v2(&resources, &mut data, None).unwrap();
+            assert_eq!(&data[0..8], HEADER_V2, "{:?}", field);
+
+            load_resources(&data)
+                .unwrap()
+                .collect::<Result<Vec<Resource<u8>>, &'static str>>()
+                .unwrap();
+
+            // The same data labeled version 1 is rejected.
+            data[7] = 0x01;
+            let res = load_resources(&data)
+                .unwrap()
+                .collect::<Result<Vec<Resource<u8>>, &'static str>>();
+            assert_eq!(
+                res.unwrap_err(),
+                "resource field requires a newer packed resources version",
+                "{:?}",
+                field
+            );
+        }
+    }
+
+    fn lookup_table_resources() -> Vec<Resource<'static, u8>> {
+        let mut package_resources = HashMap::new();
+        package_resources.insert(Cow::from("data.txt"), Cow::from(b"data".to_vec()));
+        package_resources.insert(Cow::from("other.txt"), Cow::from(b"other".to_vec()));
+
+        vec![
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("zeta"),
+                in_memory_source: Some(Cow::from(vec![b'x'; 100])),
+                in_memory_bytecode: Some(Cow::from(b"zeta bytecode".to_vec())),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("alpha"),
+                is_package: true,
+                in_memory_source: Some(Cow::from(vec![b'x'; 100])),
+                in_memory_package_resources: Some(package_resources),
+                relative_path_module_bytecode: Some(Cow::from(Path::new("alpha.pyc"))),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::SharedLibrary,
+                name: Cow::from("libfoo.so"),
+                in_memory_shared_library: Some(Cow::from(b"library".to_vec())),
+                shared_library_dependency_names: Some(vec![Cow::from("libbar.so")]),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("mid"),
+                in_memory_bytecode: Some(Cow::from(b"mid bytecode".to_vec())),
+                ..Resource::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_lookup_table() {
+        let resources = lookup_table_resources();
+
+        for padding in &[None, Some(BlobInteriorPadding::Null)] {
+            let mut v1 = Vec::new();
+            write_packed_resources_v1(&resources, &mut v1, *padding).unwrap();
+            let mut v3 = Vec::new();
+            write_packed_resources_v3(&resources, &mut v3, *padding).unwrap();
+
+            assert_eq!(&v3[0..8], HEADER_V3);
+            assert!(load_lookup_table(&v1).is_err());
+
+            // The sequential reader handles all versions.
+            let loaded = load_resources(&v3)
+                .unwrap()
+                .collect::<Result<Vec<Resource<u8>>, &'static str>>()
+                .unwrap();
+            assert_eq!(loaded, resources);
+
+            let table = load_lookup_table(&v3).unwrap();
+            assert_eq!(table.len(), 4);
+            assert_eq!(
+                (0..table.len())
+                    .map(|i| table.name(i).unwrap())
+                    .collect::<Vec<_>>(),
+                vec!["alpha", "libfoo.so", "mid", "zeta"]
+            );
+
+            for resource in &resources {
+                assert_eq!(table.get(&resource.name).unwrap().as_ref(), Some(resource));
+            }
+            assert_eq!(table.get("missing").unwrap(), None);
+            assert_eq!(table.get("").unwrap(), None);
+        }
+    }
+
+    #[test]
+    fn test_lookup_table_empty() {
+        let resources: Vec<Resource<u8>> = vec![];
+
+        let mut data = Vec::new();
+        write_packed_resources_v3(&resources, &mut data, None).unwrap();
+
+        assert_eq!(load_resources(&data).unwrap().count(), 0);
+
+        let table = load_lookup_table(&data).unwrap();
+        assert!(table.is_empty());
+        assert_eq!(table.get("foo").unwrap(), None);
+    }
+
+    #[test]
+    fn test_lookup_table_recovery_set() {
+        let resources = vec![
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("app"),
+                in_memory_bytecode: Some(Cow::from(b"app".to_vec())),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("os"),
+                in_memory_bytecode: Some(Cow::from(b"os main".to_vec())),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("diagnostics"),
+                in_memory_bytecode: Some(Cow::from(b"diagnostics".to_vec())),
+                resource_set: ResourceSet::Recovery,
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from("os"),
+                in_memory_bytecode: Some(Cow::from(b"os recovery".to_vec())),
+                resource_set: ResourceSet::Recovery,
+                ..Resource::default()
+            },
+        ];
+
+        let mut data = Vec::new();
+        write_packed_resources_v3(&resources, &mut data, None).unwrap();
+
+        let loaded = load_resources(&data)
+            .unwrap()
+            .collect::<Result<Vec<Resource<u8>>, &'static str>>()
+            .unwrap();
+        assert_eq!(loaded, resources);
+
+        let table = load_lookup_table(&data).unwrap();
+        assert_eq!(table.get("os").unwrap().as_ref(), Some(&resources[1]));
+        assert_eq!(
+            table
+                .get_in_set("os", ResourceSet::Recovery)
+                .unwrap()
+                .as_ref(),
+            Some(&resources[3])
+        );
+        assert_eq!(table.get("app").unwrap().as_ref(), Some(&resources[0]));
+        assert_eq!(
+            table.get_in_set("app", ResourceSet::Recovery).unwrap(),
+            None
+        );
+        assert_eq!(table.get("diagnostics").unwrap(), None);
+        assert_eq!(
+            table
+                .get_in_set("diagnostics", ResourceSet::Recovery)
+                .unwrap()
+                .as_ref(),
+            Some(&resources[2])
+        );
+    }
+
+    /// Compares the work needed to find resources with each index encoding.
+    #[test]
+    fn test_lookup_table_work() {
+        let count = 20_000;
+
+        let resources = (0..count)
+            .map(|i| Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::from(format!("package{:05}.module", i)),
+                in_memory_bytecode: Some(Cow::from(format!("bytecode {}", i).into_bytes())),
+                ..Resource::default()
+            })
+            .collect::<Vec<Resource<u8>>>();
+
+        let mut v1 = Vec::new();
+        write_packed_resources_v1(&resources, &mut v1, None).unwrap();
+        let mut v3 = Vec::new();
+        write_packed_resources_v3(&resources, &mut v3, None).unwrap();
+
+        let wanted = (0..count)
+            .step_by(997)
+            .map(|i| format!("package{:05}.module", i))
+            .collect::<Vec<_>>();
+
+        // Without a lookup table, every entry preceding a resource is parsed.
+        let mut sequential_parsed = 0;
+        for name in &wanted {
+            for resource in load_resources(&v1).unwrap() {
+                sequential_parsed += 1;
+                if resource.unwrap().name == name.as_str() {
+                    break;
+                }
+            }
+        }
+
+        // With a lookup table, only the entry of a found resource is parsed.
+        let table = load_lookup_table(&v3).unwrap();
+        for name in &wanted {
+            let resource = table.get(name).unwrap().unwrap();
+            assert_eq!(resource, resources[name[7..12].parse::<usize>().unwrap()]);
+        }
+
+        // Binary search over 20,000 names needs at most 15 comparisons.
+        assert!(table.comparisons() <= 15 * wanted.len());
+        assert!(sequential_parsed > 100 * table.comparisons());
+
+        // The lookup table costs 16 bytes per resource and a 10 byte blob
+        // offset for each blob section a resource's entry reads.
+        assert_eq!(v3.len() - v1.len(), count * (16 + 2 * 10));
+    }
 }
@@ -0,0 +1,324 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+/*! Storage of resource payloads outside of packed resources data.
+
+By default, every payload of a resource is embedded in packed resources
+data. A [`PayloadSink`] can instead store payloads elsewhere, in which case
+the resource records an [`ExternalPayload`] holding the payload's digest and
+a logical location and the runtime fetches the payload on demand.
+*/
+
+use {
+    super::data::{ExternalPayload, Resource, ResourceField, EXTERNAL_PAYLOAD_DIGEST_LENGTH},
+    anyhow::{Context, Result},
+    sha2::{Digest, Sha256},
+    std::borrow::Cow,
+    std::path::{Path, PathBuf},
+};
+
+/// Name of the backend embedding payloads in packed resources data.
+pub const EMBEDDED_BACKEND: &str = "embedded";
+
+/// Name of the backend writing payloads to a directory.
+pub const DIRECTORY_BACKEND: &str = "directory";
+
+/// Resource fields whose payloads can be stored outside of packed resources.
+///
+/// In-memory shared libraries are always embedded: they are extracted and
+/// loaded before the runtime sets up payload fetching.
+pub const EXTERNALIZABLE_FIELDS: &[ResourceField] = &[
+    ResourceField::InMemorySource,
+    ResourceField::InMemoryBytecode,
+    ResourceField::InMemoryBytecodeOpt1,
+    ResourceField::InMemoryBytecodeOpt2,
+    ResourceField::InMemoryExtensionModuleSharedLibrary,
+];
+
+/// Compute the digest identifying a payload.
+pub fn payload_digest(data: &[u8]) -> [u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH] {
+    let mut digest = [0; EXTERNAL_PAYLOAD_DIGEST_LENGTH];
+    digest.copy_from_slice(&Sha256::digest(data));
+
+    digest
+}
+
+/// Format a payload digest as a lowercase hex string.
+pub fn digest_hex(digest: &[u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH]) -> String {
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Receives resource payloads as packed resources are written.
+pub trait PayloadSink {
+    /// Name of the storage backend.
+    ///
+    /// This is recorded in build metadata.
+    fn backend(&self) -> String;
+
+    /// Store a payload.
+    ///
+    /// Returns the logical location of the stored payload or `None` if the
+    /// payload should be embedded in packed resources data.
+    fn store(
+        &mut self,
+        field: ResourceField,
+        digest: &[u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH],
+        data: &[u8],
+    ) -> Result<Option<String>>;
+}
+
+/// Embeds every payload in packed resources data.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EmbeddedSink;
+
+impl PayloadSink for EmbeddedSink {
+    fn backend(&self) -> String {
+        EMBEDDED_BACKEND.to_string()
+    }
+
+    fn store(
+        &mut self,
+        _field: ResourceField,
+        _digest: &[u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH],
+        _data: &[u8],
+    ) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
+
+/// Writes payloads to files in a directory.
+///
+/// Files are named by the hex digest of their content, which is also the
+/// recorded location. Payloads already in the directory aren't written
+/// again, so a directory can be shared between builds.
+#[derive(Clone, Debug)]
+pub struct DirectorySink {
+    root: PathBuf,
+}
+
+impl DirectorySink {
+    /// Construct an instance writing to a directory, creating it if needed.
+    pub fn new(root: &Path) -> Result<Self> {
+        std::fs::create_dir_all(root)
+            .with_context(|| format!("creating payload directory {}", root.display()))?;
+
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    /// The directory payloads are written to.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl PayloadSink for DirectorySink {
+    fn backend(&self) -> String {
+        DIRECTORY_BACKEND.to_string()
+    }
+
+    fn store(
+        &mut self,
+        _field: ResourceField,
+        digest: &[u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH],
+        data: &[u8],
+    ) -> Result<Option<String>> {
+        let name = digest_hex(digest);
+        let path = self.root.join(&name);
+
+        let existing = std::fs::metadata(&path).map(|m| m.len()).ok();
+        if existing != Some(data.len() as u64) {
+            // Write to a temporary file first so a concurrent build never
+            // sees a partial payload.
+            let temp_path = self
+                .root
+                .join(format!("{}.tmp{}", name, std::process::id()));
+            std::fs::write(&temp_path, data)
+                .with_context(|| format!("writing payload {}", temp_path.display()))?;
+            std::fs::rename(&temp_path, &path)
+                .with_context(|| format!("renaming payload to {}", path.display()))?;
+        }
+
+        Ok(Some(name))
+    }
+}
+
+/// A content-addressed object store holding payloads.
+///
+/// Implement this to store payloads in a store of your choosing. Every
+/// implementation is a [`PayloadSink`] storing all payloads it receives.
+pub trait ObjectStoreSink {
+    /// Name of the object store backend.
+    fn backend_name(&self) -> String;
+
+    /// Store a payload under its hex digest.
+    ///
+    /// Returns the key of the stored object, which runtimes pass to their
+    /// payload fetcher.
+    fn put(&mut self, digest_hex: &str, data: &[u8]) -> Result<String>;
+}
+
+impl<T: ObjectStoreSink> PayloadSink for T {
+    fn backend(&self) -> String {
+        self.backend_name()
+    }
+
+    fn store(
+        &mut self,
+        _field: ResourceField,
+        digest: &[u8; EXTERNAL_PAYLOAD_DIGEST_LENGTH],
+        data: &[u8],
+    ) -> Result<Option<String>> {
+        Ok(Some(self.put(&digest_hex(digest), data)?))
+    }
+}
+
+/// Describes payloads moved out of resources.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ExternalizedPayloads {
+    /// Number of payloads stored outside of packed resources.
+    pub payloads_count: usize,
+
+    /// Combined length in bytes of payloads stored outside of packed resources.
+    pub payloads_bytes: u64,
+}
+
+/// Pass payloads of resources to a sink, recording stored ones as external.
+///
+/// Fields whose payload the sink stores lose their in-memory data and gain
+/// an `ExternalPayload` entry instead.
+pub fn externalize_payloads(
+    resources: &mut [Resource<u8>],
+    sink: &mut dyn PayloadSink,
+) -> Result<ExternalizedPayloads> {
+    let mut res = ExternalizedPayloads::default();
+
+    for resource in resources.iter_mut() {
+        for field in EXTERNALIZABLE_FIELDS {
+            let data = match resource.in_memory_payload(*field) {
+                Some(data) => data,
+                None => continue,
+            };
+
+            let digest = payload_digest(data);
+            let length = data.len() as u64;
+            let location = sink
+                .store(*field, &digest, data)
+                .with_context(|| format!("storing payload of {}", resource.name))?;
+
+            if let Some(location) = location {
+                resource
+                    .set_in_memory_payload(*field, None)
+                    .map_err(anyhow::Error::msg)?;
+                resource.external_payloads.push(ExternalPayload {
+                    field: *field,
+                    length,
+                    digest,
+                    location: Cow::Owned(location),
+                });
+
+                res.payloads_count += 1;
+                res.payloads_bytes += length;
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        crate::{data::ResourceFlavor, parser::load_resources, writer::write_packed_resources_v2},
+        std::collections::BTreeMap,
+    };
+
+    #[derive(Default)]
+    struct MemoryStore {
+        objects: BTreeMap<String, Vec<u8>>,
+    }
+
+    impl ObjectStoreSink for MemoryStore {
+        fn backend_name(&self) -> String {
+            "memory".to_string()
+        }
+
+        fn put(&mut self, digest_hex: &str, data: &[u8]) -> Result<String> {
+            self.objects.insert(digest_hex.to_string(), data.to_vec());
+
+            Ok(format!("objects/{}", digest_hex))
+        }
+    }
+
+    fn resources() -> Vec<Resource<'static, u8>> {
+        vec![
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Borrowed("foo"),
+                in_memory_source: Some(Cow::Borrowed(b"import bar")),
+                in_memory_bytecode: Some(Cow::Borrowed(b"bytecode")),
+                ..Resource::default()
+            },
+            Resource {
+                flavor: ResourceFlavor::Module,
+                name: Cow::Borrowed("bar"),
+                in_memory_source: Some(Cow::Borrowed(b"import bar")),
+                in_memory_shared_library: Some(Cow::Borrowed(b"library")),
+                ..Resource::default()
+            },
+        ]
+    }
+
+    #[test]
+    fn test_embedded_sink() -> Result<()> {
+        let expected = resources();
+        let mut resources = resources();
+        let report = externalize_payloads(&mut resources, &mut EmbeddedSink)?;
+
+        assert_eq!(report, ExternalizedPayloads::default());
+        assert_eq!(resources, expected);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_object_store_sink() -> Result<()> {
+        let mut store = MemoryStore::default();
+        let mut resources = resources();
+        let report = externalize_payloads(&mut resources, &mut store)?;
+
+        assert_eq!(store.backend(), "memory");
+        assert_eq!(report.payloads_count, 3);
+        assert_eq!(report.payloads_bytes, 10 + 8 + 10);
+        // Identical payloads are one object.
+        assert_eq!(store.objects.len(), 2);
+
+        assert!(resources[0].in_memory_source.is_none());
+        assert!(resources[0].in_memory_bytecode.is_none());
+        // Shared libraries are always embedded.
+        assert!(resources[1].in_memory_shared_library.is_some());
+
+        let source = &resources[0].external_payloads[0];
+        assert_eq!(source.field, ResourceField::InMemorySource);
+        assert_eq!(source.digest, payload_digest(b"import bar"));
+        assert_eq!(
+            source.location,
+            format!("objects/{}", digest_hex(&source.digest))
+        );
+
+        let mut data = Vec::new();
+        write_packed_resources_v2(&resources, &mut data, None)?;
+        let loaded = load_resources(&data)
+            .map_err(anyhow::Error::msg)?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(anyhow::Error::msg)?;
+
+        assert_eq!(loaded, resources);
+        assert!(!data.windows(10).any(|w| w == b"import bar"));
+
+        Ok(())
+    }
+}
@@ -87,6 +87,52 @@ of different sections holding data for different fields for different resources.
 But there is no internal structure or separators: all the individual
 blobs are just laid out next to each other.
 
+## `pyembed\x02`
+
+Version 2 of the embedded resources data.
+
+Version 2 has the layout of version 1. It adds the following resource
+fields, which readers of version 1 don't know:
+
+* `0x16` - Shared payload.
+* `0x17` - Extract shared library.
+* `0x19` - Recovery set.
+* `0x1a` - External payload.
+* `0x1b` - Compressed payload.
+
+Writers should only produce version 2 when resources use these fields, so
+version 1 readers can read everything else. Readers must reject these
+fields in data labeled version 1.
+
+## `pyembed\x03`
+
+Version 3 of the embedded resources data.
+
+Version 3 is version 2 with a *lookup table* between the *global header*
+and the *blob index*. The lookup table allows finding a resource by name
+with a binary search, without reading the resources index.
+
+The lookup table consists of `resources_count` fixed-width entries of 16
+bytes, sorted by the bytes of the resource name. Each entry consists of:
+
+* A `u64` holding the offset of the resource name.
+* A `u32` holding the length in bytes of the resource name.
+* A `u32` holding the offset of the resource's entry in the resources index.
+
+Offsets are relative to the start of the global header. Resource names
+are not stored in the lookup table: they are read from the resource name
+blob section, which serves as the string table of the lookup table.
+
+In version 1, the position of a resource's data within a blob section is
+only known after reading the entries of all preceding resources. So every
+resource entry in version 3 begins with a *blob offset* field (`0x18`) for
+each blob section the entry reads data from. An entry referenced by the
+lookup table can then be read on its own. The blob offset field is only
+valid in version 3.
+
+A reader not interested in the lookup table can skip it and read the
+rest of the data like version 2.
+
 ## Blob Field Types
 
 The Blob Index allows attributing a sparse set of metadata with every blob
@@ -130,6 +176,10 @@ with every resource. A `u8` indicates what metadata is being conveyed. Some
 field types have additional metadata following this `[u8]` further defining
 the field. The values of each defined metadata type follow.
 
+Fields `0x00` - `0x15` and `0xff` are valid in all versions. Fields `0x16`,
+`0x17`, `0x19`, `0x1a` and `0x1b` require version 2 or later. Field `0x18`
+requires version 3.
+
 `0x00` - End of index. Special type to denote the end of an index.
 
 `0x01` - Start of resource entry. Signals the beginning of a new resource. From
@@ -226,6 +276,52 @@ The number of files being described is contained in a `u32` that immediately
 follows this byte. Following this `u32` is an array of `(u16, u32)` denoting
 the distribution file name and filesystem path to that distribution file.
 
+`0x16` - Shared payload. Defines a field whose data is stored elsewhere in a
+blob section instead of at the current position of that section. This allows
+identical payloads to be stored once and referenced by multiple resources.
+A `u8` denoting the resource field being defined immediately follows this
+byte. Following it are a `u64` holding the offset of the data from the start
+of that field's blob section and a `u64` holding the length of the data.
+Reading a shared payload does not advance the current position within the
+blob section. Only the in-memory source, bytecode, extension module shared
+library, and shared library fields (`0x06` - `0x0a` and `0x0d`) can be
+shared.
+
+`0x17` - Extract shared library. If present, the in-memory shared library
+should be written to the filesystem and loaded from there instead of being
+loaded from memory. This field has no payload.
+
+`0x18` - Blob offset. Sets the current position within a blob section. A
+`u8` denoting the resource field of the blob section immediately follows
+this byte. Following it is a `u64` holding the offset from the start of
+that field's blob section. Subsequent data for that field is read from
+this position.
+
+`0x19` - Recovery set. If present, the resource belongs to the recovery
+resource set rather than the main one. Readers load one of the sets, so a
+name may occur once in each. This field has no payload.
+
+`0x1a` - External payload. Defines a field whose data is not stored in
+the packed resources data at all. A `u8` denoting the resource field being
+defined immediately follows this byte. Following it are a `u64` holding the
+length of the data, the 32 byte SHA-256 digest of the data, and a `u16`
+holding the length of a UTF-8 *location*, followed by the location itself.
+Unlike other fields, the location is stored in the index entry rather than
+in a blob section. The location is interpreted by whatever stored the data,
+e.g. a file name or an object store key. Readers must verify the digest of
+data they obtain. The same fields as for shared payloads can be external,
+and a field can't have both in-memory data and an external payload.
+
+`0x1b` - Compressed payload. Declares that the data of a field is a zstd
+frame rather than the data itself. A `u8` denoting the resource field
+immediately follows this byte. Following it are a `u64` holding the length
+of the decompressed data and a `u16` holding the length of a UTF-8 *name*,
+followed by the name itself. For the in-memory package resources and
+distribution resources fields (`0x0b` and `0x0c`), the name identifies the
+resource or distribution file whose data is compressed. For the fields that
+can be shared (`0x06` - `0x0a` and `0x0d`) the name is empty. Other fields
+can't be compressed. Readers must decompress the data before using it.
+
 ## Resource Flavors
 
 The data format allows defining different types/flavors of resources.
@@ -288,14 +384,20 @@ reader has the data structure in memory, we don't want to require it
 to copy memory in order to reference entries. In Rust speak, we should
 be able to hold `&[u8]` references everywhere.
 
+Parsing the resources index takes time proportional to the number of
+resources. Version 3 trades 16 bytes per resource for the lookup table
+plus 10 bytes per blob section a resource reads from for finding a
+resource in logarithmic time.
+
 There is no checksumming of the data because we don't want to incur
 I/O overhead to read the entire blob. It could be added as an optional
 feature.
 
-A potential area for optimization is use of general compression. Various
-fields should compress well - either in streaming mode or by utilizing
-compression dictionaries. Compression would undermine 0-copy, of course.
-But in environments where we want to optimize for size, it could be
-desirable.
+Individual payloads can be stored compressed with zstd. zstd decompresses
+several times faster than zlib at similar ratios, which matters as payloads
+are decompressed while a program starts. Compression undermines 0-copy for
+those payloads, as readers decompress them into new buffers. So producers should only compress payloads where size matters more
+than load time. Compressing whole fields, in streaming mode or with
+compression dictionaries, could compress better but isn't supported.
 
 */
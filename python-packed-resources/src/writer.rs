@@ -5,10 +5,13 @@
 /*! Serializing of structures into packed resources blobs. */
 
 use {
-    super::data::{BlobInteriorPadding, BlobSectionField, Resource, ResourceField, HEADER_V1},
+    super::data::{
+        BlobInteriorPadding, BlobSectionField, IndexEncoding, Resource, ResourceField, ResourceSet,
+        EXTERNAL_PAYLOAD_DIGEST_LENGTH, HEADER_V1, HEADER_V2, HEADER_V3, LOOKUP_TABLE_ENTRY_LENGTH,
+    },
     anyhow::{anyhow, Context, Result},
     byteorder::{LittleEndian, WriteBytesExt},
-    std::collections::BTreeMap,
+    std::collections::{BTreeMap, HashMap},
     std::convert::TryFrom,
     std::io::Write,
     std::path::Path,
@@ -47,6 +50,186 @@ fn path_to_bytes(p: &Path) -> Vec<u8> {
     }
 }
 
+/// Resource fields whose payloads can be shared between resources.
+const SHAREABLE_FIELDS: &[ResourceField] = &[
+    ResourceField::InMemorySource,
+    ResourceField::InMemoryBytecode,
+    ResourceField::InMemoryBytecodeOpt1,
+    ResourceField::InMemoryBytecodeOpt2,
+    ResourceField::InMemoryExtensionModuleSharedLibrary,
+    ResourceField::InMemorySharedLibrary,
+];
+
+/// Length of a shared payload index entry.
+///
+/// Field marker, resource field, u64 offset, and u64 length.
+const SHARED_PAYLOAD_INDEX_LENGTH: usize = 18;
+
+/// Resource fields whose payloads are stored in blob sections.
+///
+/// Blob sections are written in this order.
+const BLOB_FIELDS: &[ResourceField] = &[
+    ResourceField::ModuleName,
+    ResourceField::InMemorySource,
+    ResourceField::InMemoryBytecode,
+    ResourceField::InMemoryBytecodeOpt1,
+    ResourceField::InMemoryBytecodeOpt2,
+    ResourceField::InMemoryExtensionModuleSharedLibrary,
+    ResourceField::InMemoryResourcesData,
+    ResourceField::InMemoryDistributionResource,
+    ResourceField::InMemorySharedLibrary,
+    ResourceField::SharedLibraryDependencyNames,
+    ResourceField::RelativeFilesystemModuleSource,
+    ResourceField::RelativeFilesystemModuleBytecode,
+    ResourceField::RelativeFilesystemModuleBytecodeOpt1,
+    ResourceField::RelativeFilesystemModuleBytecodeOpt2,
+    ResourceField::RelativeFilesystemExtensionModuleSharedLibrary,
+    ResourceField::RelativeFilesystemPackageResources,
+    ResourceField::RelativeFilesystemDistributionResource,
+];
+
+/// Length of a blob offset index entry.
+///
+/// Field marker, resource field, and u64 offset.
+const BLOB_OFFSET_INDEX_LENGTH: usize = 10;
+
+/// Length of the regular index entry of a shareable field.
+fn shareable_field_index_length(field: ResourceField) -> usize {
+    match field {
+        ResourceField::InMemorySharedLibrary => 9,
+        _ => 5,
+    }
+}
+
+/// Obtain the payload of a shareable field.
+fn shareable_field_payload<'b>(
+    resource: &'b Resource<u8>,
+    field: ResourceField,
+) -> Option<&'b [u8]> {
+    let data = match field {
+        ResourceField::InMemorySource => &resource.in_memory_source,
+        ResourceField::InMemoryBytecode => &resource.in_memory_bytecode,
+        ResourceField::InMemoryBytecodeOpt1 => &resource.in_memory_bytecode_opt1,
+        ResourceField::InMemoryBytecodeOpt2 => &resource.in_memory_bytecode_opt2,
+        ResourceField::InMemoryExtensionModuleSharedLibrary => {
+            &resource.in_memory_extension_module_shared_library
+        }
+        ResourceField::InMemorySharedLibrary => &resource.in_memory_shared_library,
+        _ => return None,
+    };
+
+    data.as_ref().map(|x| x.as_ref())
+}
+
+fn write_shared_payload_index<W: Write>(
+    dest: &mut W,
+    field: ResourceField,
+    offset: u64,
+    length: usize,
+) -> Result<()> {
+    dest.write_u8(ResourceField::SharedPayload.into())
+        .context("writing shared payload field")?;
+    dest.write_u8(field.into())
+        .context("writing shared payload resource field")?;
+    dest.write_u64::<LittleEndian>(offset)
+        .context("writing shared payload offset")?;
+    dest.write_u64::<LittleEndian>(length as u64)
+        .context("writing shared payload length")?;
+
+    Ok(())
+}
+
+/// Obtain the entries of a map of named payloads, sorted by name.
+///
+/// `HashMap` iteration order varies between processes. Entries are written
+/// in name order so identical resources always serialize to identical bytes.
+fn sorted_entries<K: Ord, V>(map: &HashMap<K, V>) -> Vec<(&K, &V)> {
+    let mut entries = map.iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+
+    entries
+}
+
+/// Describes payloads that are stored once and referenced by multiple resources.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SharedPayloads {
+    /// Blob section offsets of payloads stored by another resource.
+    ///
+    /// Keyed by resource index and field.
+    references: BTreeMap<(usize, ResourceField), u64>,
+
+    /// Number of fields referencing a payload stored by another resource.
+    pub shared_count: usize,
+
+    /// Number of bytes saved by sharing payloads.
+    ///
+    /// This accounts for the larger index entries of shared payloads.
+    pub saved_bytes: usize,
+}
+
+impl SharedPayloads {
+    /// Find identical payloads in resources that can be stored once.
+    ///
+    /// Payloads are only shared between the same field of different
+    /// resources and only when they are larger than the index overhead of
+    /// referencing them.
+    pub fn resolve<'a, T: AsRef<Resource<'a, u8>>>(
+        resources: &[T],
+        interior_padding: Option<BlobInteriorPadding>,
+    ) -> Self {
+        let padding = match interior_padding {
+            Some(BlobInteriorPadding::Null) => 1,
+            _ => 0,
+        };
+
+        let mut res = Self::default();
+
+        for field in SHAREABLE_FIELDS {
+            let overhead = SHARED_PAYLOAD_INDEX_LENGTH - shareable_field_index_length(*field);
+            let mut offsets: HashMap<&[u8], u64> = HashMap::new();
+            let mut offset = 0;
+
+            for (i, resource) in resources.iter().enumerate() {
+                let data = match shareable_field_payload(resource.as_ref(), *field) {
+                    Some(data) => data,
+                    None => continue,
+                };
+
+                if data.len() + padding > overhead {
+                    if let Some(existing) = offsets.get(data) {
+                        res.references.insert((i, *field), *existing);
+                        res.shared_count += 1;
+                        res.saved_bytes += data.len() + padding - overhead;
+                        continue;
+                    }
+
+                    offsets.insert(data, offset);
+                }
+
+                offset += (data.len() + padding) as u64;
+            }
+        }
+
+        res
+    }
+
+    /// Obtain shared payload offsets for the resource at an index.
+    fn resource_references(&self, index: usize) -> BTreeMap<ResourceField, u64> {
+        SHAREABLE_FIELDS
+            .iter()
+            .filter_map(|field| {
+                self.references
+                    .get(&(index, *field))
+                    .map(|offset| (*field, *offset))
+            })
+            .collect()
+    }
+
+    fn is_shared(&self, index: usize, field: ResourceField) -> bool {
+        self.references.contains_key(&(index, field))
+    }
+}
+
 #[derive(Debug)]
 struct BlobSection {
     resource_field: ResourceField,
@@ -130,6 +313,7 @@ where
             || self.relative_path_extension_module_shared_library.is_some()
             || self.relative_path_package_resources.is_some()
             || self.relative_path_distribution_resources.is_some()
+            || !self.external_payloads.is_empty()
     }
 
     /// Compute length of index entry for version 1 payload format.
@@ -188,6 +372,26 @@ where
             index += 9;
         }
 
+        if self.extract_shared_library {
+            index += 1;
+        }
+
+        if self.resource_set == ResourceSet::Recovery {
+            index += 1;
+        }
+
+        for payload in &self.external_payloads {
+            // Field marker, resource field, u64 length, digest, u16 location
+            // length, and location.
+            index += 12 + EXTERNAL_PAYLOAD_DIGEST_LENGTH + payload.location.len();
+        }
+
+        for payload in &self.compressed_payloads {
+            // Field marker, resource field, u64 length, u16 name length, and
+            // name.
+            index += 12 + payload.name.len();
+        }
+
         if let Some(names) = &self.shared_library_dependency_names {
             index += 3 + 2 * names.len();
         }
@@ -367,6 +571,12 @@ where
                     0
                 }
             }
+            ResourceField::SharedPayload => 0,
+            ResourceField::ExtractSharedLibrary => 0,
+            ResourceField::RecoverySet => 0,
+            ResourceField::ExternalPayload => 0,
+            ResourceField::CompressedPayload => 0,
+            ResourceField::BlobOffset => 0,
         }
     }
 
@@ -496,6 +706,12 @@ where
                     0
                 }
             }
+            ResourceField::SharedPayload => 0,
+            ResourceField::ExtractSharedLibrary => 0,
+            ResourceField::RecoverySet => 0,
+            ResourceField::ExternalPayload => 0,
+            ResourceField::CompressedPayload => 0,
+            ResourceField::BlobOffset => 0,
         };
 
         let overhead = match padding {
@@ -508,12 +724,36 @@ where
 
     /// Write the version 1 index entry for a module instance.
     pub fn write_index_v1<W: Write>(&self, dest: &mut W) -> Result<()> {
+        self.write_index_entry(dest, &BTreeMap::new(), &BTreeMap::new())
+    }
+
+    /// Write the index entry for a module instance.
+    ///
+    /// `shared` holds blob section offsets of fields whose payloads are
+    /// stored by another resource. `blob_offsets` holds blob section offsets
+    /// of this resource's payloads that are recorded in the entry, making
+    /// it readable without reading preceding entries.
+    fn write_index_entry<W: Write>(
+        &self,
+        dest: &mut W,
+        shared: &BTreeMap<ResourceField, u64>,
+        blob_offsets: &BTreeMap<ResourceField, u64>,
+    ) -> Result<()> {
         let name_len =
             u16::try_from(self.name.as_bytes().len()).context("converting name to u16")?;
 
         dest.write_u8(ResourceField::StartOfEntry.into())
             .context("writing start of index entry")?;
 
+        for (field, offset) in blob_offsets {
+            dest.write_u8(ResourceField::BlobOffset.into())
+                .context("writing blob offset field")?;
+            dest.write_u8((*field).into())
+                .context("writing blob offset resource field")?;
+            dest.write_u64::<LittleEndian>(*offset)
+                .context("writing blob offset")?;
+        }
+
         dest.write_u8(ResourceField::Flavor.into())
             .context("writing flavor field")?;
         dest.write_u8(self.flavor.into())
@@ -536,48 +776,93 @@ where
         }
 
         if let Some(source) = &self.in_memory_source {
-            let l =
-                u32::try_from(source.len()).context("converting in-memory source length to u32")?;
-            dest.write_u8(ResourceField::InMemorySource.into())
-                .context("writing in-memory source length field")?;
-            dest.write_u32::<LittleEndian>(l)
-                .context("writing in-memory source length")?;
+            if let Some(offset) = shared.get(&ResourceField::InMemorySource) {
+                write_shared_payload_index(
+                    dest,
+                    ResourceField::InMemorySource,
+                    *offset,
+                    source.len(),
+                )?;
+            } else {
+                let l = u32::try_from(source.len())
+                    .context("converting in-memory source length to u32")?;
+                dest.write_u8(ResourceField::InMemorySource.into())
+                    .context("writing in-memory source length field")?;
+                dest.write_u32::<LittleEndian>(l)
+                    .context("writing in-memory source length")?;
+            }
         }
 
         if let Some(bytecode) = &self.in_memory_bytecode {
-            let l = u32::try_from(bytecode.len())
-                .context("converting in-memory bytecode length to u32")?;
-            dest.write_u8(ResourceField::InMemoryBytecode.into())
-                .context("writing in-memory bytecode length field")?;
-            dest.write_u32::<LittleEndian>(l)
-                .context("writing in-memory bytecode length")?;
+            if let Some(offset) = shared.get(&ResourceField::InMemoryBytecode) {
+                write_shared_payload_index(
+                    dest,
+                    ResourceField::InMemoryBytecode,
+                    *offset,
+                    bytecode.len(),
+                )?;
+            } else {
+                let l = u32::try_from(bytecode.len())
+                    .context("converting in-memory bytecode length to u32")?;
+                dest.write_u8(ResourceField::InMemoryBytecode.into())
+                    .context("writing in-memory bytecode length field")?;
+                dest.write_u32::<LittleEndian>(l)
+                    .context("writing in-memory bytecode length")?;
+            }
         }
 
         if let Some(bytecode) = &self.in_memory_bytecode_opt1 {
-            let l = u32::try_from(bytecode.len())
-                .context("converting in-memory bytecode opt 1 length to u32")?;
-            dest.write_u8(ResourceField::InMemoryBytecodeOpt1.into())
-                .context("writing in-memory bytecode opt 1 length field")?;
-            dest.write_u32::<LittleEndian>(l)
-                .context("writing in-memory bytecode opt 1 length")?;
+            if let Some(offset) = shared.get(&ResourceField::InMemoryBytecodeOpt1) {
+                write_shared_payload_index(
+                    dest,
+                    ResourceField::InMemoryBytecodeOpt1,
+                    *offset,
+                    bytecode.len(),
+                )?;
+            } else {
+                let l = u32::try_from(bytecode.len())
+                    .context("converting in-memory bytecode opt 1 length to u32")?;
+                dest.write_u8(ResourceField::InMemoryBytecodeOpt1.into())
+                    .context("writing in-memory bytecode opt 1 length field")?;
+                dest.write_u32::<LittleEndian>(l)
+                    .context("writing in-memory bytecode opt 1 length")?;
+            }
         }
 
         if let Some(bytecode) = &self.in_memory_bytecode_opt2 {
-            let l = u32::try_from(bytecode.len())
-                .context("converting in-memory bytecode opt 2 length to u32")?;
-            dest.write_u8(ResourceField::InMemoryBytecodeOpt2.into())
-                .context("writing in-memory bytecode opt 2 field")?;
-            dest.write_u32::<LittleEndian>(l)
-                .context("writing in-memory bytecode opt 2 length")?;
+            if let Some(offset) = shared.get(&ResourceField::InMemoryBytecodeOpt2) {
+                write_shared_payload_index(
+                    dest,
+                    ResourceField::InMemoryBytecodeOpt2,
+                    *offset,
+                    bytecode.len(),
+                )?;
+            } else {
+                let l = u32::try_from(bytecode.len())
+                    .context("converting in-memory bytecode opt 2 length to u32")?;
+                dest.write_u8(ResourceField::InMemoryBytecodeOpt2.into())
+                    .context("writing in-memory bytecode opt 2 field")?;
+                dest.write_u32::<LittleEndian>(l)
+                    .context("writing in-memory bytecode opt 2 length")?;
+            }
         }
 
         if let Some(library) = &self.in_memory_extension_module_shared_library {
-            let l = u32::try_from(library.len())
-                .context("converting in-memory library length to u32")?;
-            dest.write_u8(ResourceField::InMemoryExtensionModuleSharedLibrary.into())
-                .context("writing in-memory extension module shared library field")?;
-            dest.write_u32::<LittleEndian>(l)
-                .context("writing in-memory extension module shared library length")?;
+            if let Some(offset) = shared.get(&ResourceField::InMemoryExtensionModuleSharedLibrary) {
+                write_shared_payload_index(
+                    dest,
+                    ResourceField::InMemoryExtensionModuleSharedLibrary,
+                    *offset,
+                    library.len(),
+                )?;
+            } else {
+                let l = u32::try_from(library.len())
+                    .context("converting in-memory library length to u32")?;
+                dest.write_u8(ResourceField::InMemoryExtensionModuleSharedLibrary.into())
+                    .context("writing in-memory extension module shared library field")?;
+                dest.write_u32::<LittleEndian>(l)
+                    .context("writing in-memory extension module shared library length")?;
+            }
         }
 
         if let Some(resources) = &self.in_memory_package_resources {
@@ -588,7 +873,7 @@ where
             dest.write_u32::<LittleEndian>(l)
                 .context("writing in-memory resources data length")?;
 
-            for (name, value) in resources.iter() {
+            for (name, value) in sorted_entries(resources) {
                 let name_length = u16::try_from(name.as_bytes().len())
                     .context("converting resource name length to u16")?;
                 dest.write_u16::<LittleEndian>(name_length)
@@ -606,7 +891,7 @@ where
             dest.write_u32::<LittleEndian>(l)
                 .context("writing in-memory package distribution length")?;
 
-            for (name, value) in metadata {
+            for (name, value) in sorted_entries(metadata) {
                 let name_length = u16::try_from(name.as_bytes().len())
                     .context("converting distribution name length to u16")?;
                 dest.write_u16::<LittleEndian>(name_length)
@@ -617,12 +902,63 @@ where
         }
 
         if let Some(library) = &self.in_memory_shared_library {
-            let l = u64::try_from(library.len())
-                .context("converting in-memory shared library length to u64")?;
-            dest.write_u8(ResourceField::InMemorySharedLibrary.into())
-                .context("writing in-memory shared library field")?;
-            dest.write_u64::<LittleEndian>(l)
-                .context("writing in-memory shared library length")?;
+            if let Some(offset) = shared.get(&ResourceField::InMemorySharedLibrary) {
+                write_shared_payload_index(
+                    dest,
+                    ResourceField::InMemorySharedLibrary,
+                    *offset,
+                    library.len(),
+                )?;
+            } else {
+                let l = u64::try_from(library.len())
+                    .context("converting in-memory shared library length to u64")?;
+                dest.write_u8(ResourceField::InMemorySharedLibrary.into())
+                    .context("writing in-memory shared library field")?;
+                dest.write_u64::<LittleEndian>(l)
+                    .context("writing in-memory shared library length")?;
+            }
+        }
+
+        if self.extract_shared_library {
+            dest.write_u8(ResourceField::ExtractSharedLibrary.into())
+                .context("writing extract shared library field")?;
+        }
+
+        if self.resource_set == ResourceSet::Recovery {
+            dest.write_u8(ResourceField::RecoverySet.into())
+                .context("writing recovery set field")?;
+        }
+
+        for payload in &self.external_payloads {
+            let l = u16::try_from(payload.location.len())
+                .context("converting external payload location length to u16")?;
+            dest.write_u8(ResourceField::ExternalPayload.into())
+                .context("writing external payload field")?;
+            dest.write_u8(payload.field.into())
+                .context("writing external payload resource field")?;
+            dest.write_u64::<LittleEndian>(payload.length)
+                .context("writing external payload length")?;
+            dest.write_all(&payload.digest)
+                .context("writing external payload digest")?;
+            dest.write_u16::<LittleEndian>(l)
+                .context("writing external payload location length")?;
+            dest.write_all(payload.location.as_bytes())
+                .context("writing external payload location")?;
+        }
+
+        for payload in &self.compressed_payloads {
+            let l = u16::try_from(payload.name.len())
+                .context("converting compressed payload name length to u16")?;
+            dest.write_u8(ResourceField::CompressedPayload.into())
+                .context("writing compressed payload field")?;
+            dest.write_u8(payload.field.into())
+                .context("writing compressed payload resource field")?;
+            dest.write_u64::<LittleEndian>(payload.length)
+                .context("writing compressed payload length")?;
+            dest.write_u16::<LittleEndian>(l)
+                .context("writing compressed payload name length")?;
+            dest.write_all(payload.name.as_bytes())
+                .context("writing compressed payload name")?;
         }
 
         if let Some(names) = &self.shared_library_dependency_names {
@@ -694,7 +1030,7 @@ where
             dest.write_u32::<LittleEndian>(l)
                 .context("writing relative path resources resources data length")?;
 
-            for (name, path) in resources.iter() {
+            for (name, path) in sorted_entries(resources) {
                 let name_length = u16::try_from(name.as_bytes().len())
                     .context("converting resource name length to u16")?;
                 let path_length = u32::try_from(path_bytes_length(path))
@@ -714,7 +1050,7 @@ where
             dest.write_u32::<LittleEndian>(l)
                 .context("writing relative path distribution data length")?;
 
-            for (name, path) in metadata.iter() {
+            for (name, path) in sorted_entries(metadata) {
                 let name_length = u16::try_from(name.as_bytes().len())
                     .context("converting resource name length to u16")?;
                 let path_length = u32::try_from(path_bytes_length(path))
@@ -733,14 +1069,119 @@ where
     }
 }
 
+/// Obtain the first packed resources format version able to hold a resource.
+///
+/// Shared payloads also require version 2 but are chosen by the writer.
+fn resource_format_version(resource: &Resource<u8>) -> u8 {
+    if resource.extract_shared_library
+        || resource.resource_set == ResourceSet::Recovery
+        || !resource.external_payloads.is_empty()
+        || !resource.compressed_payloads.is_empty()
+    {
+        ResourceField::ExtractSharedLibrary.format_version()
+    } else {
+        1
+    }
+}
+
 /// Write packed resources data, version 1.
 ///
-/// See the `specifications` module for the format.
-#[allow(clippy::cognitive_complexity)]
+/// Payloads aren't shared. Errors if a resource needs fields introduced by
+/// version 2. See the `specifications` module for the format.
 pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
     modules: &[T],
     dest: &mut W,
     interior_padding: Option<BlobInteriorPadding>,
+) -> Result<()> {
+    if let Some(module) = modules
+        .iter()
+        .find(|module| resource_format_version(module.as_ref()) > 1)
+    {
+        return Err(anyhow!(
+            "resource {} requires packed resources version 2",
+            module.as_ref().name
+        ));
+    }
+
+    write_packed_resources_version(
+        modules,
+        dest,
+        interior_padding,
+        1,
+        &SharedPayloads::default(),
+    )
+}
+
+/// Write packed resources data, version 2.
+///
+/// Version 2 adds shared, external and compressed payloads, extracted shared
+/// libraries and the recovery resource set to version 1. See the
+/// `specifications` module for the format.
+pub fn write_packed_resources_v2<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
+    modules: &[T],
+    dest: &mut W,
+    interior_padding: Option<BlobInteriorPadding>,
+) -> Result<()> {
+    let shared = SharedPayloads::resolve(modules, interior_padding);
+
+    write_packed_resources_version(modules, dest, interior_padding, 2, &shared)
+}
+
+/// Write packed resources data, version 3.
+///
+/// Version 3 adds a lookup table to version 2. See the `specifications`
+/// module for the format.
+pub fn write_packed_resources_v3<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
+    modules: &[T],
+    dest: &mut W,
+    interior_padding: Option<BlobInteriorPadding>,
+) -> Result<()> {
+    let shared = SharedPayloads::resolve(modules, interior_padding);
+
+    write_packed_resources_version(modules, dest, interior_padding, 3, &shared)
+}
+
+/// Write packed resources data with an index encoding.
+///
+/// `IndexEncoding::Sequential` writes version 1, or version 2 if payloads
+/// are shared or resources need fields introduced by version 2.
+/// `IndexEncoding::LookupTable` writes version 3.
+pub fn write_packed_resources<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
+    modules: &[T],
+    dest: &mut W,
+    interior_padding: Option<BlobInteriorPadding>,
+    encoding: IndexEncoding,
+) -> Result<()> {
+    let shared = SharedPayloads::resolve(modules, interior_padding);
+
+    let version = match encoding {
+        IndexEncoding::Sequential => {
+            if shared.shared_count == 0
+                && modules
+                    .iter()
+                    .all(|module| resource_format_version(module.as_ref()) == 1)
+            {
+                1
+            } else {
+                2
+            }
+        }
+        IndexEncoding::LookupTable => 3,
+    };
+
+    write_packed_resources_version(modules, dest, interior_padding, version, &shared)
+}
+
+/// Write packed resources data of a format version.
+///
+/// Callers ensure resources only need fields of the version.
+#[allow(clippy::cognitive_complexity)]
+fn write_packed_resources_version<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
+    modules: &[T],
+    dest: &mut W,
+    interior_padding: Option<BlobInteriorPadding>,
+    version: u8,
+    shared: &SharedPayloads,
 ) -> Result<()> {
     let mut blob_sections = BTreeMap::new();
 
@@ -751,27 +1192,55 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
     // 1 for end of index field.
     let mut module_index_length = 1;
 
-    let process_field = |blob_sections: &mut BTreeMap<ResourceField, BlobSection>,
-                         resource: &Resource<u8>,
-                         field: ResourceField| {
-        let padding = match &interior_padding {
-            Some(padding) => *padding,
-            None => BlobInteriorPadding::None,
-        };
+    let padding = match &interior_padding {
+        Some(padding) => *padding,
+        None => BlobInteriorPadding::None,
+    };
+
+    // Offsets of each resource's payloads within their blob sections. Only
+    // recorded in the index with a lookup table.
+    let mut blob_offsets = vec![BTreeMap::new(); modules.len()];
+
+    // Offsets of each resource's entry within the resources index.
+    let mut entry_offsets = Vec::with_capacity(modules.len());
+
+    for (index, module) in modules.iter().enumerate() {
+        let module = module.as_ref();
+
+        entry_offsets.push(module_index_length - 1);
+
+        for field in BLOB_FIELDS {
+            // Shared payloads are stored by another resource.
+            if shared.is_shared(index, *field) {
+                continue;
+            }
 
-        let l = resource.field_blob_length(field)
-            + resource.field_blob_interior_padding_length(field, padding);
-        if l > 0 {
-            blob_sections
-                .entry(field)
-                .or_insert_with(|| BlobSection {
-                    resource_field: field,
+            let l = module.field_blob_length(*field)
+                + module.field_blob_interior_padding_length(*field, padding);
+            if l > 0 {
+                let section = blob_sections.entry(*field).or_insert_with(|| BlobSection {
+                    resource_field: *field,
                     raw_payload_length: 0,
                     interior_padding,
-                })
-                .raw_payload_length += l;
+                });
+
+                if version >= 3 {
+                    blob_offsets[index].insert(*field, section.raw_payload_length as u64);
+                }
+
+                section.raw_payload_length += l;
+            }
         }
-    };
+
+        module_index_length += module.index_v1_length();
+
+        for field in shared.resource_references(index).keys() {
+            module_index_length +=
+                SHARED_PAYLOAD_INDEX_LENGTH - shareable_field_index_length(*field);
+        }
+
+        module_index_length += BLOB_OFFSET_INDEX_LENGTH * blob_offsets[index].len();
+    }
 
     let add_interior_padding = |dest: &mut W| -> Result<()> {
         if interior_padding == Some(BlobInteriorPadding::Null) {
@@ -781,97 +1250,62 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
         Ok(())
     };
 
-    for module in modules {
-        let module = module.as_ref();
-        module_index_length += module.index_v1_length();
-
-        process_field(&mut blob_sections, module, ResourceField::ModuleName);
-        process_field(&mut blob_sections, module, ResourceField::InMemorySource);
-        process_field(&mut blob_sections, module, ResourceField::InMemoryBytecode);
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::InMemoryBytecodeOpt1,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::InMemoryBytecodeOpt2,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::InMemoryExtensionModuleSharedLibrary,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::InMemoryResourcesData,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::InMemoryDistributionResource,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::InMemorySharedLibrary,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::SharedLibraryDependencyNames,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::RelativeFilesystemModuleSource,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::RelativeFilesystemModuleBytecode,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::RelativeFilesystemModuleBytecodeOpt1,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::RelativeFilesystemModuleBytecodeOpt2,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::RelativeFilesystemExtensionModuleSharedLibrary,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::RelativeFilesystemPackageResources,
-        );
-        process_field(
-            &mut blob_sections,
-            module,
-            ResourceField::RelativeFilesystemDistributionResource,
-        );
-    }
-
     for section in blob_sections.values() {
         blob_section_count += 1;
         blob_index_length += section.index_v1_length();
     }
 
-    dest.write_all(HEADER_V1)?;
+    dest.write_all(match version {
+        1 => HEADER_V1,
+        2 => HEADER_V2,
+        _ => HEADER_V3,
+    })?;
 
     dest.write_u8(blob_section_count)?;
     dest.write_u32::<LittleEndian>(blob_index_length as u32)?;
     dest.write_u32::<LittleEndian>(modules.len() as u32)?;
     dest.write_u32::<LittleEndian>(module_index_length as u32)?;
 
+    if version >= 3 {
+        let lookup_table_length = LOOKUP_TABLE_ENTRY_LENGTH * modules.len();
+        let resources_index_start = 13 + lookup_table_length + blob_index_length;
+
+        // Resource names are read from the module name blob section, which
+        // is the first blob section.
+        let names_start = resources_index_start + module_index_length;
+
+        let mut entries = modules
+            .iter()
+            .enumerate()
+            .map(|(index, module)| {
+                let name_offset = blob_offsets[index]
+                    .get(&ResourceField::ModuleName)
+                    .copied()
+                    .unwrap_or(0);
+
+                (
+                    module.as_ref().name.as_bytes(),
+                    module.as_ref().resource_set,
+                    names_start as u64 + name_offset,
+                    resources_index_start + entry_offsets[index],
+                )
+            })
+            .collect::<Vec<_>>();
+        // Entries of a name in the main and recovery sets are adjacent.
+        entries.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+        for (name, _, name_offset, entry_offset) in entries {
+            dest.write_u64::<LittleEndian>(name_offset)
+                .context("writing lookup table name offset")?;
+            dest.write_u32::<LittleEndian>(name.len() as u32)
+                .context("writing lookup table name length")?;
+            dest.write_u32::<LittleEndian>(
+                u32::try_from(entry_offset).context("converting index entry offset to u32")?,
+            )
+            .context("writing lookup table entry offset")?;
+        }
+    }
+
     // Write the blob index.
     for section in blob_sections.values() {
         section.write_index_v1(dest)?;
@@ -879,8 +1313,12 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
     dest.write_u8(ResourceField::EndOfIndex.into())?;
 
     // Write the resources index.
-    for module in modules {
-        module.as_ref().write_index_v1(dest)?;
+    for (index, module) in modules.iter().enumerate() {
+        module.as_ref().write_index_entry(
+            dest,
+            &shared.resource_references(index),
+            &blob_offsets[index],
+        )?;
     }
     dest.write_u8(ResourceField::EndOfIndex.into())?;
 
@@ -890,35 +1328,55 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
         add_interior_padding(dest)?;
     }
 
-    for module in modules {
+    for (index, module) in modules.iter().enumerate() {
+        if shared.is_shared(index, ResourceField::InMemorySource) {
+            continue;
+        }
+
         if let Some(data) = &module.as_ref().in_memory_source {
             dest.write_all(data)?;
             add_interior_padding(dest)?;
         }
     }
 
-    for module in modules {
+    for (index, module) in modules.iter().enumerate() {
+        if shared.is_shared(index, ResourceField::InMemoryBytecode) {
+            continue;
+        }
+
         if let Some(data) = &module.as_ref().in_memory_bytecode {
             dest.write_all(data)?;
             add_interior_padding(dest)?;
         }
     }
 
-    for module in modules {
+    for (index, module) in modules.iter().enumerate() {
+        if shared.is_shared(index, ResourceField::InMemoryBytecodeOpt1) {
+            continue;
+        }
+
         if let Some(data) = &module.as_ref().in_memory_bytecode_opt1 {
             dest.write_all(data)?;
             add_interior_padding(dest)?;
         }
     }
 
-    for module in modules {
+    for (index, module) in modules.iter().enumerate() {
+        if shared.is_shared(index, ResourceField::InMemoryBytecodeOpt2) {
+            continue;
+        }
+
         if let Some(data) = &module.as_ref().in_memory_bytecode_opt2 {
             dest.write_all(data)?;
             add_interior_padding(dest)?;
         }
     }
 
-    for module in modules {
+    for (index, module) in modules.iter().enumerate() {
+        if shared.is_shared(index, ResourceField::InMemoryExtensionModuleSharedLibrary) {
+            continue;
+        }
+
         if let Some(data) = &module.as_ref().in_memory_extension_module_shared_library {
             dest.write_all(data)?;
             add_interior_padding(dest)?;
@@ -927,7 +1385,7 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
 
     for module in modules {
         if let Some(resources) = &module.as_ref().in_memory_package_resources {
-            for (key, value) in resources.iter() {
+            for (key, value) in sorted_entries(resources) {
                 dest.write_all(key.as_bytes())?;
                 add_interior_padding(dest)?;
                 dest.write_all(value)?;
@@ -938,7 +1396,7 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
 
     for module in modules {
         if let Some(resources) = &module.as_ref().in_memory_distribution_resources {
-            for (key, value) in resources {
+            for (key, value) in sorted_entries(resources) {
                 dest.write_all(key.as_bytes())?;
                 add_interior_padding(dest)?;
                 dest.write_all(value)?;
@@ -947,7 +1405,11 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
         }
     }
 
-    for module in modules {
+    for (index, module) in modules.iter().enumerate() {
+        if shared.is_shared(index, ResourceField::InMemorySharedLibrary) {
+            continue;
+        }
+
         if let Some(data) = &module.as_ref().in_memory_shared_library {
             dest.write_all(data)?;
             add_interior_padding(dest)?;
@@ -1003,7 +1465,7 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
 
     for module in modules {
         if let Some(resources) = &module.as_ref().relative_path_package_resources {
-            for (key, path) in resources.iter() {
+            for (key, path) in sorted_entries(resources) {
                 dest.write_all(key.as_bytes())?;
                 add_interior_padding(dest)?;
                 dest.write_all(&path_to_bytes(path))?;
@@ -1014,7 +1476,7 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
 
     for module in modules {
         if let Some(resources) = &module.as_ref().relative_path_distribution_resources {
-            for (key, path) in resources {
+            for (key, path) in sorted_entries(resources) {
                 dest.write_all(key.as_bytes())?;
                 add_interior_padding(dest)?;
                 dest.write_all(&path_to_bytes(path))?;
@@ -1028,7 +1490,7 @@ pub fn write_packed_resources_v1<'a, T: AsRef<Resource<'a, u8>>, W: Write>(
 
 #[cfg(test)]
 mod tests {
-    use {super::*, crate::data::ResourceFlavor, std::borrow::Cow};
+    use {super::*, crate::data::ResourceFlavor, std::borrow::Cow, std::iter::FromIterator};
 
     #[test]
     fn test_write_empty() -> Result<()> {
@@ -1097,4 +1559,135 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_shared_payloads() -> Result<()> {
+        let module = |name: &str, source: Vec<u8>| Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::Owned(name.to_string()),
+            in_memory_source: Some(Cow::Owned(source)),
+            ..Resource::default()
+        };
+
+        let identical = vec![module("foo", vec![0; 100]), module("bar", vec![0; 100])];
+        let distinct = vec![module("foo", vec![0; 100]), module("bar", vec![1; 100])];
+
+        let shared = SharedPayloads::resolve(&identical, None);
+        assert_eq!(shared.shared_count, 1);
+        // The payload minus the larger index entry.
+        assert_eq!(shared.saved_bytes, 100 - 13);
+        assert_eq!(
+            SharedPayloads::resolve(&distinct, None),
+            SharedPayloads::default()
+        );
+
+        let mut identical_data = Vec::new();
+        write_packed_resources_v2(&identical, &mut identical_data, None)?;
+        let mut distinct_data = Vec::new();
+        write_packed_resources_v2(&distinct, &mut distinct_data, None)?;
+
+        assert_eq!(
+            distinct_data.len() - identical_data.len(),
+            shared.saved_bytes
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_shared_payloads_small() -> Result<()> {
+        let module = |name: &str| Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::Owned(name.to_string()),
+            in_memory_source: Some(Cow::Owned(b"import io".to_vec())),
+            ..Resource::default()
+        };
+
+        // Referencing a payload smaller than the index overhead would grow the data.
+        let resources = vec![module("foo"), module("bar")];
+        assert_eq!(
+            SharedPayloads::resolve(&resources, None),
+            SharedPayloads::default()
+        );
+
+        let mut data = Vec::new();
+        write_packed_resources_v2(&resources, &mut data, None)?;
+        assert_eq!(data.windows(9).filter(|w| *w == b"import io").count(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_version_selection() -> Result<()> {
+        let module = |name: &str, source: Vec<u8>| Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::Owned(name.to_string()),
+            in_memory_source: Some(Cow::Owned(source)),
+            ..Resource::default()
+        };
+
+        let header = |resources: &[Resource<u8>], encoding| -> Result<Vec<u8>> {
+            let mut data = Vec::new();
+            write_packed_resources(resources, &mut data, None, encoding)?;
+            Ok(data[0..8].to_vec())
+        };
+
+        let distinct = vec![module("foo", vec![0; 100]), module("bar", vec![1; 100])];
+        let identical = vec![module("foo", vec![0; 100]), module("bar", vec![0; 100])];
+        let recovery = vec![Resource {
+            resource_set: ResourceSet::Recovery,
+            ..module("foo", vec![0; 100])
+        }];
+
+        assert_eq!(header(&distinct, IndexEncoding::Sequential)?, HEADER_V1);
+        assert_eq!(header(&identical, IndexEncoding::Sequential)?, HEADER_V2);
+        assert_eq!(header(&recovery, IndexEncoding::Sequential)?, HEADER_V2);
+        assert_eq!(header(&distinct, IndexEncoding::LookupTable)?, HEADER_V3);
+
+        // Version 1 doesn't share payloads and can't hold version 2 fields.
+        let mut v1 = Vec::new();
+        write_packed_resources_v1(&identical, &mut v1, None)?;
+        let mut v2 = Vec::new();
+        write_packed_resources_v2(&identical, &mut v2, None)?;
+        assert_eq!(
+            v1.len() - v2.len(),
+            SharedPayloads::resolve(&identical, None).saved_bytes
+        );
+        assert!(write_packed_resources_v1(&recovery, &mut Vec::new(), None).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_package_resources_sorted() -> Result<()> {
+        let package = |names: &[&'static str]| Resource {
+            flavor: ResourceFlavor::Module,
+            name: Cow::Borrowed("foo"),
+            in_memory_package_resources: Some(HashMap::from_iter(
+                names
+                    .iter()
+                    .map(|name| (Cow::Borrowed(*name), Cow::Borrowed(name.as_bytes()))),
+            )),
+            relative_path_package_resources: Some(HashMap::from_iter(
+                names
+                    .iter()
+                    .map(|name| (Cow::Borrowed(*name), Cow::Borrowed(Path::new(*name)))),
+            )),
+            ..Resource::default()
+        };
+
+        let names = ["a", "b", "c", "d", "e", "f", "g", "h"];
+        let mut reversed = names;
+        reversed.reverse();
+
+        let mut data = Vec::new();
+        write_packed_resources_v1(&[package(&names)], &mut data, None)?;
+        let mut reversed_data = Vec::new();
+        write_packed_resources_v1(&[package(&reversed)], &mut reversed_data, None)?;
+
+        // Entries are written in name order regardless of how maps iterate.
+        assert_eq!(data, reversed_data);
+
+        Ok(())
+    }
 }